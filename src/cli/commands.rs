@@ -1,4 +1,6 @@
+use crate::core::{Network, SelectionStrategy};
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 use std::str::FromStr;
 
 /// Fee priority levels for transactions
@@ -60,9 +62,85 @@ impl FromStr for FeeModeArg {
     }
 }
 
+/// How a command's result is printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Sentences for a person at a terminal
+    #[default]
+    Text,
+    /// A single JSON document on stdout, for scripts
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "Invalid output format: {s}. Valid options: text, json"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Text => write!(f, "text"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 #[command(name = "architect-chain")]
 pub struct Opt {
+    #[arg(
+        long = "datadir",
+        global = true,
+        value_name = "PATH",
+        help = "Directory for the chain database and wallet file [default: $ARCHITECT_DATADIR or the working directory]"
+    )]
+    pub datadir: Option<PathBuf>,
+    #[arg(
+        long = "wallet",
+        global = true,
+        value_name = "PATH",
+        help = "Wallet file to use [default: wallet.dat in the data directory, or in data/node_$NODE_ID/ when NODE_ID is set]"
+    )]
+    pub wallet: Option<PathBuf>,
+    #[arg(
+        long = "config",
+        global = true,
+        value_name = "PATH",
+        help = "Read node settings from this TOML file [default: architect.toml in the data directory, if present]"
+    )]
+    pub config: Option<PathBuf>,
+    #[arg(
+        long = "network",
+        global = true,
+        value_name = "NAME",
+        help = "Network to run on, which a datadir has to have been created for (mainnet, testnet, regtest) [default: mainnet]"
+    )]
+    pub network: Option<Network>,
+    #[arg(
+        long = "allow-network-mismatch",
+        global = true,
+        help = "Use wallet keys with a chain other than the one they were created for, e.g. to recover coins"
+    )]
+    pub allow_network_mismatch: bool,
+    // Not global like the flags above: `createrawtransaction` already has an `--output`
+    // of its own, so this one goes before the command
+    #[arg(
+        long = "output",
+        value_name = "FORMAT",
+        default_value = "text",
+        help = "Print the result as text, or as one JSON object on stdout with logs kept on stderr (text, json)"
+    )]
+    pub output: OutputFormat,
     #[command(subcommand)]
     pub command: Command,
 }
@@ -71,53 +149,987 @@ pub struct Opt {
 pub enum Command {
     #[command(name = "createblockchain", about = "Create a new blockchain")]
     Createblockchain {
-        #[arg(help = "The address to send genesis block reward to")]
-        address: String,
+        #[arg(
+            required_unless_present = "genesis_file",
+            conflicts_with = "genesis_file",
+            help = "The address to send genesis block reward to"
+        )]
+        address: Option<String>,
+        #[arg(
+            long = "genesis-file",
+            value_name = "PATH",
+            help = "Build the genesis block from a file written by creategenesis, like every other node of the network"
+        )]
+        genesis_file: Option<PathBuf>,
+    },
+    #[command(
+        name = "creategenesis",
+        about = "Write a genesis file every node of a new network builds the same genesis block from"
+    )]
+    CreateGenesis {
+        #[arg(
+            long = "out",
+            value_name = "PATH",
+            help = "Path of the genesis file to write"
+        )]
+        out: PathBuf,
+        #[arg(long = "message", help = "Text recorded in the genesis coinbase")]
+        message: String,
+        #[arg(
+            long = "allocate",
+            value_name = "ADDRESS:SATOSHIS",
+            required = true,
+            help = "Pay SATOSHIS to ADDRESS in the genesis block (repeatable)"
+        )]
+        allocate: Vec<String>,
+        #[arg(
+            long = "timestamp",
+            value_name = "MS",
+            help = "Block timestamp in milliseconds since the epoch [default: now]"
+        )]
+        timestamp: Option<i64>,
+        #[arg(
+            long = "difficulty",
+            help = "Genesis difficulty [default: the initial difficulty]"
+        )]
+        difficulty: Option<u32>,
     },
     #[command(name = "createwallet", about = "Create a new wallet")]
-    Createwallet,
+    Createwallet {
+        #[arg(long = "label", help = "Label to attach to the new address")]
+        label: Option<String>,
+        #[arg(long = "notes", help = "Free-form notes to keep with the new key")]
+        notes: Option<String>,
+    },
+    #[command(
+        name = "newaddress",
+        about = "Generate a new receiving address in the wallet file"
+    )]
+    NewAddress {
+        #[arg(long = "label", help = "Label to attach to the new address")]
+        label: Option<String>,
+        #[arg(long = "notes", help = "Free-form notes to keep with the new key")]
+        notes: Option<String>,
+    },
+    #[command(
+        name = "paymentrequest",
+        about = "Print a payment URI asking for coins to an address"
+    )]
+    PaymentRequest {
+        #[arg(help = "The address to be paid")]
+        address: String,
+        #[arg(long = "amount", help = "Requested amount in coins, e.g. 1.5")]
+        amount: Option<String>,
+        #[arg(long = "message", help = "What the payment is for")]
+        message: Option<String>,
+    },
     #[command(
         name = "getbalance",
         about = "Get the wallet balance of the target address"
     )]
     GetBalance {
-        #[arg(help = "The wallet address")]
+        #[arg(required_unless_present = "whole_wallet", help = "The wallet address")]
+        address: Option<String>,
+        #[arg(
+            long = "whole-wallet",
+            conflicts_with_all = ["address", "include_pending"],
+            help = "Add up every address in the wallet file instead, change addresses included"
+        )]
+        whole_wallet: bool,
+        #[arg(
+            long = "include-pending",
+            help = "Also show what unconfirmed transactions pay in and spend"
+        )]
+        include_pending: bool,
+        #[arg(
+            long = "remote",
+            requires = "address",
+            conflicts_with = "whole_wallet",
+            help = "Query a running node at ADDR instead of reading the local database; needed for pending transactions, which only its memory pool holds"
+        )]
+        remote: Option<String>,
+    },
+    #[command(
+        name = "listunspent",
+        about = "List the unspent outputs of an address, as createrawtransaction --input takes them"
+    )]
+    ListUnspent {
+        #[arg(help = "The address")]
         address: String,
+        #[arg(
+            long = "min-value",
+            default_value_t = 0,
+            help = "Leave out outputs worth less than this, in satoshis"
+        )]
+        min_value: u64,
+        #[arg(long = "json", help = "Print the outputs as JSON")]
+        json: bool,
+    },
+    #[command(
+        name = "importaddress",
+        about = "Track an address whose key is kept elsewhere (watch-only)"
+    )]
+    ImportAddress {
+        #[arg(help = "The address to track")]
+        address: String,
+        #[arg(long = "label", help = "Label to attach to the address")]
+        label: Option<String>,
+        #[arg(
+            long = "birthday-height",
+            value_name = "HEIGHT",
+            help = "Nothing below this block pays the address, so the rescan starts there"
+        )]
+        birthday_height: Option<usize>,
+        #[arg(
+            long = "no-rescan",
+            help = "Don't look through the chain for the address's past transactions"
+        )]
+        no_rescan: bool,
+    },
+    #[command(
+        name = "rescan",
+        about = "Look through the chain for the past transactions of wallet addresses"
+    )]
+    Rescan {
+        #[arg(help = "Addresses to rescan for [default: every address in the wallet file]")]
+        addresses: Vec<String>,
+        #[arg(
+            long = "from-height",
+            value_name = "HEIGHT",
+            help = "First block to look at [default: the earliest birthday of the addresses]"
+        )]
+        from_height: Option<usize>,
+    },
+    #[command(
+        name = "history",
+        about = "List the transactions that paid an address or spent from it, oldest first"
+    )]
+    History {
+        #[arg(required_unless_present = "whole_wallet", help = "The address")]
+        address: Option<String>,
+        #[arg(
+            long = "whole-wallet",
+            conflicts_with = "address",
+            help = "Every transaction of the wallet file's addresses, netted per transaction"
+        )]
+        whole_wallet: bool,
+        #[arg(long = "json", help = "Print the history as JSON")]
+        json: bool,
     },
     #[command(name = "listaddresses", about = "Print local wallet addresses")]
-    ListAddresses,
+    ListAddresses {
+        #[arg(long = "with-balance", help = "Also print the balance of each address")]
+        with_balance: bool,
+        #[arg(
+            long = "with-usage",
+            help = "Also print how often each address was paid and spent from, flagging reuse"
+        )]
+        with_usage: bool,
+    },
+    #[command(
+        name = "account",
+        about = "Group wallet addresses into named accounts and spend from them together"
+    )]
+    Account {
+        #[command(subcommand)]
+        action: AccountCommand,
+    },
+    #[command(
+        name = "backupwallet",
+        about = "Write every wallet key and label to a backup file"
+    )]
+    BackupWallet {
+        #[arg(long = "out", help = "Path of the backup file to write")]
+        out: PathBuf,
+        #[arg(long = "password", help = "Encrypt the backup with this password")]
+        password: Option<String>,
+    },
+    #[command(name = "restorewallet", about = "Restore wallets from a backup file")]
+    RestoreWallet {
+        #[arg(long = "in", help = "Path of the backup file to read")]
+        input: PathBuf,
+        #[arg(long = "password", help = "Password of an encrypted backup")]
+        password: Option<String>,
+        #[arg(
+            long = "merge",
+            help = "Add the backed up addresses to the wallet file instead of replacing it"
+        )]
+        merge: bool,
+    },
+    #[command(
+        name = "setwalletpolicy",
+        about = "Limit what send pays from an address in one transaction"
+    )]
+    SetWalletPolicy {
+        #[arg(help = "Wallet address the policy applies to")]
+        address: String,
+        #[arg(
+            long = "confirm-above",
+            value_name = "SATOSHIS",
+            help = "Ask for the amount to be typed back before sending more than this"
+        )]
+        confirm_above: Option<u64>,
+        #[arg(
+            long = "max-per-tx",
+            value_name = "SATOSHIS",
+            help = "Refuse to send more than this in one transaction"
+        )]
+        max_per_tx: Option<u64>,
+        #[arg(
+            long = "clear",
+            help = "Drop the address's current limits before applying the given ones"
+        )]
+        clear: bool,
+    },
+    #[command(
+        name = "usechangeaddresses",
+        about = "Send the change of every payment to a new wallet address, or back to the sender"
+    )]
+    UseChangeAddresses {
+        #[arg(
+            action = clap::ArgAction::Set,
+            help = "true to create a change address for each payment, false to pay change back"
+        )]
+        enabled: bool,
+    },
+    #[command(
+        name = "setcoinselection",
+        about = "Choose how payments from this wallet file pick their coins"
+    )]
+    SetCoinSelection {
+        #[arg(
+            required_unless_present = "clear",
+            help = "largest, smallest, oldest or bnb (an exact match without change, else largest)"
+        )]
+        strategy: Option<SelectionStrategy>,
+        #[arg(
+            long = "clear",
+            conflicts_with = "strategy",
+            help = "Go back to taking coins in chainstate order"
+        )]
+        clear: bool,
+    },
     #[command(name = "send", about = "Send transaction between addresses")]
     Send {
-        #[arg(help = "Source wallet address")]
+        #[arg(
+            required_unless_present = "from_draft",
+            help = "Source wallet address (or label with --from-label); a watch-only one gets an unsigned transaction printed"
+        )]
+        from: Option<String>,
+        #[arg(
+            required_unless_present = "from_draft",
+            help = "Destination wallet address (or hex public key with --to-pubkey, or payment URI with --uri)"
+        )]
+        to: Option<String>,
+        #[arg(
+            required_unless_present_any = ["all", "from_draft"],
+            help = "Amount to send (in satoshis); with --uri, 0 takes the requested amount"
+        )]
+        amount: Option<u64>,
+        #[arg(
+            long = "all",
+            conflicts_with_all = ["amount", "uri", "to_pubkey", "memo_hex", "allow_high_fee"],
+            help = "Send the whole balance, taking the fee out of it and leaving no change"
+        )]
+        all: bool,
+        #[arg(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+        /// Deprecated positional form of `--mine`, where 1 means mine
+        #[arg(hide = true)]
+        legacy_mine: Option<usize>,
+        #[arg(
+            long = "priority",
+            help = "Transaction priority (low, normal, high, urgent)"
+        )]
+        priority: Option<FeePriorityArg>,
+        #[arg(
+            long = "fee",
+            value_name = "SATOSHIS",
+            conflicts_with_all = ["priority", "to_pubkey", "memo_hex", "allow_high_fee"],
+            help = "Pay exactly this fee instead of pricing it by priority"
+        )]
+        fee: Option<u64>,
+        #[arg(
+            long = "from-label",
+            help = "Treat the source as a wallet label instead of an address"
+        )]
+        from_label: bool,
+        #[arg(
+            long = "from-account",
+            conflicts_with_all = ["from_label", "all", "fee", "to_pubkey", "memo_hex", "allow_high_fee"],
+            help = "Treat the source as an account name and spend from all of its addresses"
+        )]
+        from_account: bool,
+        #[arg(
+            long = "to-pubkey",
+            help = "Pay straight to a hex public key (P2PK) instead of an address"
+        )]
+        to_pubkey: bool,
+        #[arg(
+            long = "uri",
+            conflicts_with = "to_pubkey",
+            help = "Treat the destination as a payment URI (architect:ADDRESS?amount=...)"
+        )]
+        uri: bool,
+        #[arg(
+            long = "memo-hex",
+            value_name = "HEX",
+            conflicts_with = "to_pubkey",
+            help = "Record up to 80 bytes of data (hex) on chain in an unspendable output"
+        )]
+        memo_hex: Option<String>,
+        #[arg(
+            long = "allow-high-fee",
+            conflicts_with_all = ["to_pubkey", "memo_hex"],
+            help = "Pay the fee even if it is above the maximum transaction fee"
+        )]
+        allow_high_fee: bool,
+        #[arg(
+            long = "yes",
+            help = "Don't ask before sending more than the wallet policy's confirm threshold"
+        )]
+        yes: bool,
+        #[arg(
+            long = "save-draft",
+            value_name = "NAME",
+            help = "Keep the unsigned payment of a watch-only address as a wallet draft"
+        )]
+        save_draft: Option<String>,
+        #[arg(
+            long = "coin-selection",
+            value_name = "STRATEGY",
+            conflicts_with = "all",
+            help = "Pick coins largest, smallest or oldest first, or bnb to avoid change; the wallet's setcoinselection default otherwise"
+        )]
+        coin_selection: Option<SelectionStrategy>,
+        #[arg(
+            long = "from-draft",
+            value_name = "NAME",
+            conflicts_with_all = [
+                "from", "to", "amount", "all", "mine", "legacy_mine", "priority", "fee",
+                "from_label", "from_account", "to_pubkey", "uri", "memo_hex", "allow_high_fee",
+                "save_draft", "coin_selection",
+            ],
+            help = "Send the signed wallet draft NAME instead of building a payment"
+        )]
+        from_draft: Option<String>,
+        #[arg(
+            long = "node",
+            conflicts_with_all = ["mine", "legacy_mine"],
+            help = "Node to send the payment to (defaults to the central node)"
+        )]
+        node: Option<String>,
+    },
+    #[command(
+        name = "bumpfee",
+        about = "Replace a pending transaction with a higher-fee version"
+    )]
+    BumpFee {
+        #[arg(help = "Transaction id (hex) of the pending transaction")]
+        txid: String,
+        #[arg(
+            long = "priority",
+            default_value = "high",
+            help = "Fee priority for the replacement (low, normal, high, urgent)"
+        )]
+        priority: FeePriorityArg,
+        #[arg(
+            long = "node",
+            help = "Node holding the pending transaction (defaults to the central node)"
+        )]
+        node: Option<String>,
+    },
+    #[command(
+        name = "abandontransaction",
+        about = "Drop a pending transaction of yours from a node's memory pool so its inputs can be spent again"
+    )]
+    AbandonTransaction {
+        #[arg(help = "Transaction id (hex) of the pending transaction")]
+        txid: String,
+        #[arg(
+            long = "node",
+            help = "Node holding the pending transaction (defaults to the central node)"
+        )]
+        node: Option<String>,
+        #[arg(
+            long = "local",
+            conflicts_with = "node",
+            help = "Drop it from the memory pool the stopped local node saved instead of a running node's"
+        )]
+        local: bool,
+    },
+    #[command(
+        name = "consolidate",
+        about = "Merge the smallest outputs of an address into one output back to it"
+    )]
+    Consolidate {
+        #[arg(help = "Wallet address whose outputs to merge")]
+        address: String,
+        #[arg(
+            long = "max-inputs",
+            default_value_t = 50,
+            help = "Merge at most this many outputs, smallest first"
+        )]
+        max_inputs: usize,
+        #[arg(
+            long = "priority",
+            default_value = "normal",
+            help = "Fee priority (low, normal, high, urgent)"
+        )]
+        priority: FeePriorityArg,
+        #[arg(long = "mine", help = "Mine immediately on the same node")]
+        mine: bool,
+    },
+    #[command(
+        name = "createmultisig",
+        about = "Print the address that M of the given addresses spend from together"
+    )]
+    CreateMultisig {
+        #[arg(help = "How many of the keys have to sign a spend")]
+        required: u8,
+        #[arg(
+            required = true,
+            help = "The addresses whose keys share the multisig address"
+        )]
+        addresses: Vec<String>,
+    },
+    #[command(
+        name = "sendmultisig",
+        about = "Start a payment from a multisig address and print it for the signers (hex)"
+    )]
+    SendMultisig {
+        #[arg(help = "Source multisig address")]
         from: String,
         #[arg(help = "Destination wallet address")]
         to: String,
         #[arg(help = "Amount to send (in satoshis)")]
         amount: u64,
-        #[arg(help = "Mine immediately on the same node")]
-        mine: usize,
         #[arg(
             long = "priority",
             help = "Transaction priority (low, normal, high, urgent)"
         )]
         priority: Option<FeePriorityArg>,
     },
-    #[command(name = "printchain", about = "Print all blocks in the blockchain")]
-    Printchain,
-    #[command(name = "reindexutxo", about = "Rebuild UTXO index set")]
+    #[command(
+        name = "signpartial",
+        about = "Add a wallet's signatures to a partially signed multisig payment"
+    )]
+    SignPartial {
+        #[arg(help = "The partially signed transaction (hex)")]
+        transaction: String,
+        #[arg(help = "Address of the wallet to sign with")]
+        signer: String,
+        #[arg(
+            long = "save-draft",
+            value_name = "NAME",
+            help = "Also keep the result as a wallet draft"
+        )]
+        save_draft: Option<String>,
+    },
+    #[command(
+        name = "combinepartial",
+        about = "Merge the signatures of partially signed copies and send the payment once complete"
+    )]
+    CombinePartial {
+        #[arg(
+            required = true,
+            help = "Partially signed copies of one transaction (hex)"
+        )]
+        transactions: Vec<String>,
+        #[arg(
+            long = "mine",
+            help = "Mine the payment on this node, paying the block reward to this address"
+        )]
+        mine: Option<String>,
+        #[arg(
+            long = "node",
+            help = "Node to send the payment to (defaults to the central node)"
+        )]
+        node: Option<String>,
+    },
+    #[command(
+        name = "createrawtransaction",
+        about = "Assemble an unsigned transaction from outpoints and outputs, without a wallet or chain (hex)"
+    )]
+    CreateRawTransaction {
+        #[arg(
+            long = "input",
+            required = true,
+            help = "An output to spend, as txid:vout"
+        )]
+        inputs: Vec<String>,
+        #[arg(
+            long = "output",
+            required = true,
+            help = "A payment, as address:amount in satoshis"
+        )]
+        outputs: Vec<String>,
+        #[arg(
+            long = "save-draft",
+            value_name = "NAME",
+            help = "Also keep the transaction as a wallet draft"
+        )]
+        save_draft: Option<String>,
+    },
+    #[command(
+        name = "decoderawtransaction",
+        about = "Show what a raw transaction spends and pays, without a chain"
+    )]
+    DecodeRawTransaction {
+        #[arg(help = "The transaction (hex)")]
+        transaction: String,
+        #[arg(long = "json", help = "Print the transaction as JSON")]
+        json: bool,
+    },
+    #[command(
+        name = "gettransaction",
+        about = "Show which main-chain block holds a transaction and how many confirmations it has"
+    )]
+    GetTransaction {
+        #[arg(help = "Transaction id (hex)")]
+        txid: String,
+        #[arg(
+            long = "remote",
+            help = "Query a running node at ADDR instead of reading the local database"
+        )]
+        remote: Option<String>,
+    },
+    #[command(
+        name = "signrawtransaction",
+        about = "Sign every input of a raw transaction with one wallet, the rest going to fees (hex)"
+    )]
+    SignRawTransaction {
+        #[arg(help = "The unsigned transaction (hex)")]
+        transaction: String,
+        #[arg(long = "wallet-address", help = "Address of the wallet to sign with")]
+        wallet_address: String,
+    },
+    #[command(
+        name = "sendrawtransaction",
+        about = "Send a signed raw transaction to a node, or mine it here"
+    )]
+    SendRawTransaction {
+        #[arg(help = "The signed transaction (hex)")]
+        transaction: String,
+        #[arg(
+            long = "mine",
+            help = "Mine the transaction on this node, paying the block reward to this address"
+        )]
+        mine: Option<String>,
+        #[arg(
+            long = "node",
+            help = "Node to send the transaction to (defaults to the central node)"
+        )]
+        node: Option<String>,
+    },
+    #[command(
+        name = "draft",
+        about = "Keep unfinished transactions in the wallet file and resume them later"
+    )]
+    Draft {
+        #[command(subcommand)]
+        action: DraftCommand,
+    },
+    #[command(
+        name = "printchain",
+        about = "Print blocks of the main chain, newest first"
+    )]
+    Printchain {
+        #[arg(long = "json", help = "Print the blocks as a JSON array")]
+        json: bool,
+        #[arg(long = "from-height", help = "Lowest height to print")]
+        from_height: Option<usize>,
+        #[arg(
+            long = "to-height",
+            help = "Highest height to print (defaults to the tip)"
+        )]
+        to_height: Option<usize>,
+        #[arg(
+            long = "limit",
+            help = "Print at most N blocks, counting down from the highest height"
+        )]
+        limit: Option<usize>,
+        #[arg(
+            long = "remote",
+            help = "Query a running node at ADDR instead of reading the local database"
+        )]
+        remote: Option<String>,
+    },
+    #[command(name = "exportchain", about = "Export the full blockchain to a file")]
+    ExportChain {
+        #[arg(help = "Path of the export file to write")]
+        path: String,
+    },
+    #[command(
+        name = "importchain",
+        about = "Import a blockchain from an export file"
+    )]
+    ImportChain {
+        #[arg(help = "Path of the export file to read")]
+        path: String,
+        #[arg(long = "force", help = "Overwrite an existing blockchain")]
+        force: bool,
+        #[arg(
+            long = "assume-valid",
+            help = "Skip signature checks below the latest checkpoint"
+        )]
+        assume_valid: bool,
+    },
+    #[command(
+        name = "exportsnapshot",
+        about = "Write the chainstate at the tip, with the headers below it, to a snapshot file"
+    )]
+    ExportSnapshot {
+        #[arg(help = "Path of the snapshot file to write")]
+        path: String,
+    },
+    #[command(
+        name = "importsnapshot",
+        about = "Start this chain at a snapshot's tip without replaying the blocks below it"
+    )]
+    ImportSnapshot {
+        #[arg(
+            help = "Path of the snapshot file to read; the chain has to exist with the \
+                      snapshot's genesis block, e.g. from createblockchain --genesis-file"
+        )]
+        path: String,
+    },
+    #[command(
+        name = "watchaddress",
+        about = "Have the node report payments to and from ADDRESS in notifications.log"
+    )]
+    WatchAddress {
+        #[arg(help = "Address to watch")]
+        address: String,
+    },
+    #[command(name = "unwatchaddress", about = "Stop watching ADDRESS")]
+    UnwatchAddress {
+        #[arg(help = "Address to stop watching")]
+        address: String,
+    },
+    #[command(name = "listwatched", about = "List the addresses the node watches")]
+    ListWatched,
+    #[command(
+        name = "reindexutxo",
+        about = "Rebuild the UTXO set and the transaction index"
+    )]
     Reindexutxo,
+    #[command(
+        name = "migratedb",
+        about = "Rewrite stored blocks in the current serialization format"
+    )]
+    MigrateDb,
+    #[command(
+        name = "verifychain",
+        about = "Check the database for damage, exiting non-zero if any is found"
+    )]
+    VerifyChain {
+        #[arg(
+            long = "level",
+            default_value_t = 3,
+            value_parser = clap::value_parser!(u32).range(1..=3),
+            help = "1: block linkage, 2: also proof of work, merkle roots and timestamps, 3: also transactions and the UTXO set"
+        )]
+        level: u32,
+    },
     #[command(name = "startnode", about = "Start a blockchain node")]
     StartNode {
         #[arg(help = "Enable mining mode and send reward to ADDRESS")]
         miner: Option<String>,
+        #[arg(
+            long = "genesis-file",
+            value_name = "PATH",
+            help = "Start a new node's chain from this genesis file, and refuse a database that started elsewhere"
+        )]
+        genesis_file: Option<PathBuf>,
+        #[arg(
+            long = "assume-valid",
+            help = "Skip signature checks below the latest checkpoint while syncing"
+        )]
+        assume_valid: bool,
+        #[arg(
+            long = "metrics-addr",
+            help = "Serve Prometheus metrics over HTTP on ADDR, e.g. 127.0.0.1:9100"
+        )]
+        metrics_addr: Option<String>,
+        #[arg(
+            long = "explorer-addr",
+            help = "Serve a read-only JSON block explorer over HTTP on ADDR, e.g. 127.0.0.1:8080"
+        )]
+        explorer_addr: Option<String>,
+        #[arg(
+            long = "block-interval-secs",
+            help = "Also mine every N seconds while transactions are pending (off by default)"
+        )]
+        block_interval_secs: Option<u64>,
+        #[arg(
+            long = "tx-threshold",
+            help = "Mine as soon as N transactions are pending (default 10)"
+        )]
+        tx_threshold: Option<usize>,
+        #[arg(
+            long = "mining-threads",
+            value_parser = clap::value_parser!(u32).range(1..),
+            help = "Threads searching for proof-of-work (default: one per CPU core)"
+        )]
+        mining_threads: Option<u32>,
+        #[arg(
+            long = "dns-seed",
+            value_name = "HOST",
+            help = "Also ask HOST for peers while too few are known (repeatable)"
+        )]
+        dns_seed: Vec<String>,
+        #[arg(
+            long = "allow-peer",
+            value_name = "ADDR[/PREFIX]",
+            help = "Only talk to peers in this address or CIDR range, e.g. 192.168.1.0/24 (repeatable)"
+        )]
+        allow_peer: Vec<String>,
+        #[arg(
+            long = "notify-cmd",
+            value_name = "COMMAND",
+            help = "Run COMMAND for every payment to or from a watched address, with the notification as JSON on stdin"
+        )]
+        notify_cmd: Option<String>,
+        #[arg(
+            long = "prune",
+            value_name = "KEEP_BLOCKS",
+            help = "Delete the bodies of blocks buried more than KEEP_BLOCKS (at least 10) below the tip"
+        )]
+        prune: Option<usize>,
+        #[arg(
+            long = "sync-only",
+            help = "Sync from peers, then exit once caught up (nonzero if no peer answers)"
+        )]
+        sync_only: bool,
+        #[arg(
+            long = "sync-settle-secs",
+            value_name = "SECS",
+            default_value_t = 10,
+            requires = "sync_only",
+            help = "With --sync-only, how long to stay within a block of the best peer before exiting"
+        )]
+        sync_settle_secs: u64,
+    },
+    #[command(
+        name = "nodestatus",
+        about = "Show chain, mempool, peer and fee status of a node"
+    )]
+    NodeStatus {
+        #[arg(long = "json", help = "Print the status as JSON")]
+        json: bool,
+        #[arg(
+            long = "remote",
+            help = "Query a running node at ADDR instead of reading the local database"
+        )]
+        remote: Option<String>,
+        #[arg(
+            long = "trace",
+            requires = "remote",
+            help = "Also show the packages the node sent and received last"
+        )]
+        trace: bool,
+    },
+    #[command(name = "banpeer", about = "Refuse connections from an address")]
+    BanPeer {
+        #[arg(help = "IP address to ban, with or without a port")]
+        address: String,
+        #[arg(
+            long = "duration",
+            value_name = "SECS",
+            default_value_t = 86_400,
+            help = "How long the ban lasts"
+        )]
+        duration: u64,
+        #[arg(
+            long = "remote",
+            help = "Ban on a running node at ADDR instead of in the local database"
+        )]
+        remote: Option<String>,
+    },
+    #[command(name = "unbanpeer", about = "Lift the ban on an address")]
+    UnbanPeer {
+        #[arg(help = "IP address to let back in, with or without a port")]
+        address: String,
+        #[arg(
+            long = "remote",
+            help = "Unban on a running node at ADDR instead of in the local database"
+        )]
+        remote: Option<String>,
+    },
+    #[command(name = "listbans", about = "List the addresses banned right now")]
+    ListBans {
+        #[arg(long = "json", help = "Print the bans as JSON")]
+        json: bool,
+        #[arg(
+            long = "remote",
+            help = "Query a running node at ADDR instead of reading the local database"
+        )]
+        remote: Option<String>,
+    },
+    #[command(
+        name = "getpeerinfo",
+        about = "Show a running node's open connections and the traffic on each"
+    )]
+    GetPeerInfo {
+        #[arg(long = "json", help = "Print the peers as JSON")]
+        json: bool,
+        #[arg(long = "node", help = "Node to ask (defaults to the central node)")]
+        node: Option<String>,
+    },
+    #[command(
+        name = "disconnectpeer",
+        about = "Close a running node's connections with a peer"
+    )]
+    DisconnectPeer {
+        #[arg(help = "Peer address with a port, or an IP address for every connection from it")]
+        address: String,
+        #[arg(
+            long = "ban",
+            value_name = "SECS",
+            help = "Also ban the peer's IP address for this long"
+        )]
+        ban: Option<u64>,
+        #[arg(
+            long = "node",
+            help = "Node to disconnect the peer from (defaults to the central node)"
+        )]
+        node: Option<String>,
+    },
+    #[command(
+        name = "getblocktemplate",
+        about = "Show the next block for an external miner to find a nonce for"
+    )]
+    GetBlockTemplate {
+        #[arg(help = "Address the coinbase pays")]
+        miner_address: String,
+        #[arg(long = "json", help = "Print the template as JSON")]
+        json: bool,
+        #[arg(
+            long = "remote",
+            help = "Ask a running node at ADDR, which fills the block from its memory pool"
+        )]
+        remote: Option<String>,
+    },
+    #[command(
+        name = "addcheckpoint",
+        about = "Pin the block hash at a height (regtest only)"
+    )]
+    AddCheckpoint {
+        #[arg(help = "Block height to pin")]
+        height: usize,
+        #[arg(help = "Hash the block at that height must have")]
+        hash: String,
+    },
+    #[command(
+        name = "generate",
+        about = "Mine COUNT blocks to ADDRESS right away (regtest only)"
+    )]
+    Generate {
+        #[arg(help = "Number of blocks to mine")]
+        count: usize,
+        #[arg(help = "Address the coinbases pay")]
+        address: String,
+        #[arg(
+            long = "dev",
+            help = "Confirm this is a development chain; every block is mined at the minimum difficulty"
+        )]
+        dev: bool,
+    },
+    #[command(
+        name = "dumpconfig",
+        about = "Print the effective node configuration as TOML"
+    )]
+    DumpConfig,
+    #[command(
+        name = "supply",
+        about = "Show the coin supply and the current block subsidy"
+    )]
+    Supply,
+    #[command(
+        name = "chainstats",
+        about = "Show block times, transactions, fees and sizes over recent blocks"
+    )]
+    ChainStats {
+        #[arg(
+            long = "window",
+            default_value_t = 100,
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Number of blocks up to the tip to cover"
+        )]
+        window: u64,
+        #[arg(
+            long = "all",
+            conflicts_with = "window",
+            help = "Cover the whole chain instead"
+        )]
+        all: bool,
+        #[arg(long = "json", help = "Print the statistics as JSON")]
+        json: bool,
+    },
+    #[command(
+        name = "miningstats",
+        about = "Show who mined recent blocks, and what mining this node's own blocks took"
+    )]
+    MiningStats {
+        #[arg(
+            long = "window",
+            default_value_t = 100,
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Number of blocks up to the tip to cover"
+        )]
+        window: u64,
+        #[arg(long = "json", help = "Print the statistics as JSON")]
+        json: bool,
+    },
+    #[command(
+        name = "getdifficulty",
+        about = "Show the current difficulty, the next retarget and the hashrate recent blocks imply"
+    )]
+    GetDifficulty {
+        #[arg(
+            long = "window",
+            default_value_t = 100,
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Number of blocks up to the tip to average the block time and hashrate over"
+        )]
+        window: u64,
+        #[arg(long = "json", help = "Print the figures as JSON")]
+        json: bool,
     },
     #[command(
         name = "estimatefee",
-        about = "Estimate transaction fee for given priority"
+        about = "Estimate transaction fee for given priority, or what a payment would cost"
     )]
     EstimateFee {
-        #[arg(help = "Transaction priority (low, normal, high, urgent)")]
-        priority: FeePriorityArg,
+        #[arg(
+            long = "priority",
+            conflicts_with = "legacy_priority",
+            help = "Transaction priority (low, normal, high, urgent), normal unless given"
+        )]
+        priority: Option<FeePriorityArg>,
+        /// Deprecated positional form of `--priority`
+        #[arg(hide = true)]
+        legacy_priority: Option<FeePriorityArg>,
+        #[arg(
+            long = "from",
+            requires = "amount",
+            help = "Address the payment would spend from; its coins are selected as send would"
+        )]
+        from: Option<String>,
+        #[arg(
+            long = "amount",
+            requires = "from",
+            help = "Amount the payment would send (in satoshis)"
+        )]
+        amount: Option<u64>,
+        #[arg(
+            long = "coin-selection",
+            value_name = "STRATEGY",
+            requires = "from",
+            help = "Select the coins largest, smallest or oldest first, or bnb; the wallet's setcoinselection default otherwise"
+        )]
+        coin_selection: Option<SelectionStrategy>,
     },
     #[command(name = "feestatus", about = "Show current fee system status")]
     FeeStatus,
@@ -127,3 +1139,96 @@ pub enum Command {
         mode: FeeModeArg,
     },
 }
+
+impl Command {
+    /// Whether the command's own `--json` flag is set, which predates `--output json` and
+    /// asks for the same thing
+    pub fn prints_json(&self) -> bool {
+        matches!(
+            self,
+            Command::ListUnspent { json: true, .. }
+                | Command::History { json: true, .. }
+                | Command::DecodeRawTransaction { json: true, .. }
+                | Command::Printchain { json: true, .. }
+                | Command::NodeStatus { json: true, .. }
+                | Command::ListBans { json: true, .. }
+                | Command::GetPeerInfo { json: true, .. }
+                | Command::GetBlockTemplate { json: true, .. }
+                | Command::ChainStats { json: true, .. }
+                | Command::MiningStats { json: true, .. }
+                | Command::GetDifficulty { json: true, .. }
+        )
+    }
+}
+
+/// What `account` does
+#[derive(Subcommand, Debug)]
+pub enum AccountCommand {
+    #[command(name = "create", about = "Start an account with no addresses")]
+    Create {
+        #[arg(help = "Name of the account")]
+        name: String,
+    },
+    #[command(
+        name = "assign",
+        about = "Add a wallet address to an account; an address can only be in one"
+    )]
+    Assign {
+        #[arg(help = "Name of the account")]
+        name: String,
+        #[arg(help = "Wallet address to add")]
+        address: String,
+        #[arg(
+            long = "change",
+            help = "Send the change of the account's payments to this address"
+        )]
+        change: bool,
+    },
+    #[command(name = "remove", about = "Take an address out of an account")]
+    Remove {
+        #[arg(help = "Name of the account")]
+        name: String,
+        #[arg(help = "Address to take out")]
+        address: String,
+    },
+    #[command(name = "list", about = "Print every account and its addresses")]
+    List,
+    #[command(
+        name = "balance",
+        about = "Print the combined balance of an account's addresses"
+    )]
+    Balance {
+        #[arg(help = "Name of the account")]
+        name: String,
+    },
+}
+
+/// What `draft` does
+#[derive(Subcommand, Debug)]
+pub enum DraftCommand {
+    #[command(
+        name = "save",
+        about = "Keep a transaction under a name, recording how far it is signed"
+    )]
+    Save {
+        #[arg(help = "Name of the draft")]
+        name: String,
+        #[arg(help = "The transaction (hex)")]
+        transaction: String,
+    },
+    #[command(
+        name = "list",
+        about = "Print every draft, flagging those whose inputs the chain has spent"
+    )]
+    List,
+    #[command(name = "show", about = "Print a draft's transaction (hex)")]
+    Show {
+        #[arg(help = "Name of the draft")]
+        name: String,
+    },
+    #[command(name = "delete", about = "Forget a draft")]
+    Delete {
+        #[arg(help = "Name of the draft")]
+        name: String,
+    },
+}
@@ -4,5 +4,9 @@
 //! for the blockchain application.
 
 pub mod commands;
+pub mod responses;
 
-pub use commands::{Command, FeeModeArg, FeePriorityArg, Opt};
+pub use commands::{
+    AccountCommand, Command, DraftCommand, FeeModeArg, FeePriorityArg, Opt, OutputFormat,
+};
+pub use responses::{CommandResponse, ErrorResponse};
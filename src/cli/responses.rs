@@ -0,0 +1,1819 @@
+//! What each command reports back
+//!
+//! Every command ends in one of these values. With `--output text` its `Display` is what
+//! gets printed, and with `--output json` its JSON form, so the two always carry the same
+//! facts. Amounts are in satoshis. Commands that had a `--json` flag of their own keep the
+//! JSON shape that flag always printed.
+
+use crate::config::ConfigFile;
+use crate::core::fees::{FeePriority, RecentFeeRates};
+use crate::core::monetary::conversions::{format_amount, format_satoshis};
+use crate::core::monetary::Amount;
+use crate::core::{
+    AddressTx, BlockSummary, BlockTemplate, ChainStats, ChainVerificationReport,
+    DecodedTransaction, DifficultyReport, FeeStatistics, MiningReport, MiningStats,
+    PaymentDirection, RescanReport, SelectionStrategy, TransactionPreview, UsageStats,
+};
+use crate::error::{BlockchainError, Result};
+use crate::network::{Ban, NodeStatus, PeerInfo, TraceEntry};
+use crate::storage::UnspentOutput;
+use crate::utils::current_timestamp;
+use crate::wallet::{convert_address, DraftState, ADDRESS_VERSION};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// The result of a command, printable either way
+pub trait CommandResponse: fmt::Display {
+    fn to_json(&self) -> serde_json::Result<String>;
+
+    /// Why the command failed even though it has a result to show, as `verifychain` does
+    /// when it finds a bad block
+    fn failure(&self) -> Option<String> {
+        None
+    }
+}
+
+macro_rules! command_responses {
+    ($($response:ty),* $(,)?) => {
+        $(
+            impl CommandResponse for $response {
+                fn to_json(&self) -> serde_json::Result<String> {
+                    serde_json::to_string_pretty(self)
+                }
+            }
+        )*
+    };
+}
+
+command_responses!(
+    CreateBlockchainResponse,
+    CreateGenesisResponse,
+    CreateWalletResponse,
+    PaymentRequestResponse,
+    BalanceResponse,
+    WalletBalanceResponse,
+    ListUnspentResponse,
+    ImportAddressResponse,
+    RescanResponse,
+    HistoryResponse,
+    ListAddressesResponse,
+    AccountCreatedResponse,
+    AddressAssignedResponse,
+    AddressRemovedResponse,
+    AccountListResponse,
+    AccountBalanceResponse,
+    BackupWalletResponse,
+    RestoreWalletResponse,
+    SendResponse,
+    TransactionHexResponse,
+    WalletPolicyResponse,
+    ChangeAddressesResponse,
+    CoinSelectionResponse,
+    BumpFeeResponse,
+    AbandonTransactionResponse,
+    ConsolidateResponse,
+    MultisigAddressResponse,
+    SentResponse,
+    DraftSavedResponse,
+    DraftEntry,
+    DraftListResponse,
+    DraftDeletedResponse,
+    DecodedTransaction,
+    TransactionStatusResponse,
+    PrintchainResponse,
+    ExportChainResponse,
+    ImportChainResponse,
+    ExportSnapshotResponse,
+    ImportSnapshotResponse,
+    WatchAddressResponse,
+    UnwatchAddressResponse,
+    ListWatchedResponse,
+    ReindexResponse,
+    MigrateDbResponse,
+    NodeStoppedResponse,
+    NodeStatusResponse,
+    BanPeerResponse,
+    UnbanPeerResponse,
+    BanListResponse,
+    PeerListResponse,
+    DisconnectPeerResponse,
+    BlockTemplate,
+    CheckpointResponse,
+    GenerateResponse,
+    DumpConfigResponse,
+    SupplyResponse,
+    ChainStats,
+    MiningStatsResponse,
+    DifficultyReport,
+    EstimateFeeResponse,
+    PaymentFeeEstimateResponse,
+    FeeStatusResponse,
+    FeeModeResponse,
+);
+
+impl CommandResponse for ChainVerificationReport {
+    fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    // A failed check has to show in the exit code so scripts can act on it
+    fn failure(&self) -> Option<String> {
+        (!self.is_ok()).then(|| "Chain verification failed".to_string())
+    }
+}
+
+/// A failed command in `--output json` mode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    /// The `BlockchainError` variant behind the failure, or `Other` for anything else
+    pub kind: String,
+    pub message: String,
+}
+
+impl ErrorResponse {
+    /// Describe `error`, taking the kind from the first `BlockchainError` in its chain of
+    /// sources so a hint wrapped around one keeps its kind
+    pub fn new(error: &(dyn std::error::Error + 'static)) -> ErrorResponse {
+        let mut kind = "Other";
+        let mut source = Some(error);
+        while let Some(current) = source {
+            if let Some(blockchain_error) = current.downcast_ref::<BlockchainError>() {
+                kind = blockchain_error.kind();
+                break;
+            }
+            source = current.source();
+        }
+        ErrorResponse {
+            error: ErrorDetail {
+                kind: kind.to_string(),
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreateBlockchainResponse {
+    pub genesis_hash: String,
+}
+
+impl fmt::Display for CreateBlockchainResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Done!")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreateGenesisResponse {
+    pub genesis_hash: String,
+    pub path: PathBuf,
+}
+
+impl fmt::Display for CreateGenesisResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Wrote genesis block {} to {}",
+            self.genesis_hash,
+            self.path.display()
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CreateWalletResponse {
+    pub address: String,
+    pub label: Option<String>,
+}
+
+impl fmt::Display for CreateWalletResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Your new address: {}", self.address)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentRequestResponse {
+    pub uri: String,
+}
+
+impl fmt::Display for PaymentRequestResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.uri)
+    }
+}
+
+/// The pending amounts are only there with `--include-pending`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceResponse {
+    pub address: String,
+    pub confirmed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_in: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_out: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available: Option<u64>,
+}
+
+impl fmt::Display for BalanceResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Balance of {}: {}", self.address, self.confirmed)?;
+        if let (Some(pending_in), Some(pending_out), Some(available)) =
+            (self.pending_in, self.pending_out, self.available)
+        {
+            writeln!(f)?;
+            writeln!(f, "Pending incoming: {pending_in}")?;
+            writeln!(f, "Pending outgoing: {pending_out}")?;
+            write!(f, "Available to spend: {available}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The confirmed balance of every address with a key in the wallet file together
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalletBalanceResponse {
+    pub addresses: usize,
+    pub confirmed: u64,
+}
+
+impl fmt::Display for WalletBalanceResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Balance of the wallet's {} addresses: {}",
+            self.addresses, self.confirmed
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ListUnspentResponse {
+    #[serde(skip)]
+    pub address: String,
+    pub unspent: Vec<UnspentOutput>,
+}
+
+impl fmt::Display for ListUnspentResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.unspent.is_empty() {
+            return write!(f, "No unspent outputs for {}", self.address);
+        }
+        for (i, output) in self.unspent.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let mut notes = format!("{} confirmations", output.confirmations);
+            if output.is_coinbase {
+                notes.push_str(", coinbase");
+            }
+            if !output.spendable_now {
+                notes.push_str(", not yet mature");
+            }
+            write!(
+                f,
+                "{} {} satoshis ({notes})",
+                output.outpoint,
+                output.value.to_satoshis()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `rescan` is missing with `--no-rescan`, and `rescan_skipped` says why a rescan that was
+/// asked for couldn't run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportAddressResponse {
+    pub address: String,
+    pub rescan: Option<RescanReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rescan_skipped: Option<String>,
+}
+
+impl fmt::Display for ImportAddressResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(reason) = &self.rescan_skipped {
+            write!(f, "Not rescanning: {reason}")?;
+        }
+        if let Some(report) = &self.rescan {
+            write_rescan_report(f, report)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_rescan_report(f: &mut fmt::Formatter<'_>, report: &RescanReport) -> fmt::Result {
+    write!(
+        f,
+        "Rescanned blocks {} to {}: {} transactions found",
+        report.from_height, report.to_height, report.entries
+    )?;
+    if report.pruned_blocks > 0 {
+        write!(
+            f,
+            "\n{} of those blocks were pruned and could not be read",
+            report.pruned_blocks
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RescanResponse {
+    /// How many addresses the chain was searched for
+    pub addresses: usize,
+    #[serde(flatten)]
+    pub report: RescanReport,
+}
+
+impl fmt::Display for RescanResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Searched for {} addresses. ", self.addresses)?;
+        write_rescan_report(f, &self.report)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HistoryResponse {
+    #[serde(skip)]
+    pub address: String,
+    pub entries: Vec<AddressTx>,
+}
+
+impl fmt::Display for HistoryResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.entries.is_empty() {
+            return write!(f, "No transactions for {}", self.address);
+        }
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let direction = match entry.direction {
+                PaymentDirection::Incoming => "received",
+                PaymentDirection::Outgoing => "sent",
+            };
+            write!(
+                f,
+                "{} {} {direction} {} satoshis",
+                entry.height, entry.txid, entry.amount
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListAddressesResponse {
+    pub addresses: Vec<AddressEntry>,
+}
+
+/// `balance` is only read with `--with-balance`, `usage` with `--with-usage`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressEntry {
+    pub address: String,
+    pub label: Option<String>,
+    pub watch_only: bool,
+    /// Created to take the change of a payment
+    #[serde(default)]
+    pub change: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<UsageStats>,
+}
+
+impl fmt::Display for ListAddressesResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, entry) in self.addresses.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let label = entry.label.as_deref().unwrap_or("-");
+            let address = &entry.address;
+            let mut notes = String::new();
+            if entry.watch_only {
+                notes.push_str(" watch-only");
+            }
+            if entry.change {
+                notes.push_str(" change");
+            }
+            if let Some(usage) = entry.usage {
+                notes.push_str(&format!(
+                    " received {}, spent from {}",
+                    usage.times_received, usage.times_spent_from
+                ));
+                if usage.times_spent_from > 0 {
+                    notes.push_str(" (spent from before, avoid reusing it)");
+                }
+            }
+            match entry.balance {
+                Some(balance) => write!(f, "{label:<16} {address:<36} {balance}{notes}")?,
+                None if notes.is_empty() => write!(f, "{label:<16} {address}")?,
+                None => write!(f, "{label:<16} {address:<36}{notes}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountCreatedResponse {
+    pub account: String,
+}
+
+impl fmt::Display for AccountCreatedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Created account '{}'", self.account)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressAssignedResponse {
+    pub account: String,
+    pub address: String,
+    /// Whether the address now takes the account's change
+    pub change_address: bool,
+}
+
+impl fmt::Display for AddressAssignedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Assigned {} to account '{}'", self.address, self.account)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressRemovedResponse {
+    pub account: String,
+    pub address: String,
+    /// Coins still held by the address, which no longer count toward the account
+    pub remaining_balance: u64,
+}
+
+impl fmt::Display for AddressRemovedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Removed {} from account '{}'",
+            self.address, self.account
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountListResponse {
+    pub accounts: Vec<AccountEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountEntry {
+    pub name: String,
+    pub addresses: Vec<String>,
+    pub change_address: Option<String>,
+}
+
+impl fmt::Display for AccountListResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        for account in &self.accounts {
+            lines.push(format!("{}:", account.name));
+            for address in &account.addresses {
+                let change = if account.change_address.as_ref() == Some(address) {
+                    " (change)"
+                } else {
+                    ""
+                };
+                lines.push(format!("  {address}{change}"));
+            }
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountBalanceResponse {
+    pub account: String,
+    pub balance: u64,
+}
+
+impl fmt::Display for AccountBalanceResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Balance of '{}': {}", self.account, self.balance)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupWalletResponse {
+    pub wallets: u64,
+    pub path: PathBuf,
+    pub encrypted: bool,
+}
+
+impl fmt::Display for BackupWalletResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Backed up {} wallets to {}{}",
+            self.wallets,
+            self.path.display(),
+            if self.encrypted { " (encrypted)" } else { "" }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreWalletResponse {
+    /// Addresses taken from the backup
+    pub restored: usize,
+    /// Addresses the backup holds
+    pub backed_up: u64,
+    pub merged: bool,
+    pub wallet_file: PathBuf,
+    /// Where the replaced wallet file was copied, if there was one
+    pub safety_copy: Option<PathBuf>,
+}
+
+impl fmt::Display for RestoreWalletResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.merged {
+            write!(
+                f,
+                "Merged {} of {} backed up wallets into {}",
+                self.restored,
+                self.backed_up,
+                self.wallet_file.display()
+            )?;
+        } else {
+            write!(
+                f,
+                "Restored {} wallets to {}",
+                self.restored,
+                self.wallet_file.display()
+            )?;
+        }
+        if let Some(copy) = &self.safety_copy {
+            write!(
+                f,
+                "\nThe previous wallet file was copied to {}",
+                copy.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A payment made by `send`; `broadcast` is false when it was mined right here into
+/// `mined_block` instead
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SendResponse {
+    pub txid: String,
+    /// The rest of a sweep too large for one transaction
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub other_txids: Vec<String>,
+    pub to: String,
+    pub amount: u64,
+    pub fee: u64,
+    pub broadcast: bool,
+    pub mined_block: Option<String>,
+    /// Set for `send --all`, where the fee comes out of the amount
+    pub swept: bool,
+    /// Senders that were already spent from before this payment
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reuse_warnings: Vec<String>,
+    /// Inputs spent by all of the payment's transactions
+    pub input_count: usize,
+    /// How the coins were picked, `None` for chainstate order
+    pub coin_selection: Option<SelectionStrategy>,
+}
+
+impl fmt::Display for SendResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.swept {
+            writeln!(
+                f,
+                "Swept {} to {}, paying a {} fee",
+                format_amount(self.amount),
+                self.to,
+                format_amount(self.fee)
+            )?;
+            if !self.other_txids.is_empty() {
+                writeln!(
+                    f,
+                    "Too many coins for one transaction, so {} were sent",
+                    self.other_txids.len() + 1
+                )?;
+            }
+        }
+        for warning in &self.reuse_warnings {
+            writeln!(f, "warning: {warning}")?;
+        }
+        for txid in std::iter::once(&self.txid).chain(&self.other_txids) {
+            writeln!(f, "Transaction {txid}")?;
+        }
+        if let Some(block) = &self.mined_block {
+            writeln!(f, "Mined in block {block}")?;
+        }
+        write!(f, "Success!")
+    }
+}
+
+/// A transaction handed back as hex, to be signed, combined or sent elsewhere
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionHexResponse {
+    pub transaction: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signed_inputs: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_signatures: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<u64>,
+}
+
+impl TransactionHexResponse {
+    pub fn new(transaction: String) -> TransactionHexResponse {
+        TransactionHexResponse {
+            transaction,
+            signed_inputs: None,
+            missing_signatures: None,
+            fee: None,
+        }
+    }
+}
+
+impl fmt::Display for TransactionHexResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.transaction)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalletPolicyResponse {
+    pub address: String,
+    pub confirm_above: Option<u64>,
+    pub max_per_tx: Option<u64>,
+}
+
+impl fmt::Display for WalletPolicyResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |limit: Option<u64>| {
+            limit.map_or("none".to_string(), |satoshis| {
+                format!("{satoshis} satoshis")
+            })
+        };
+        writeln!(f, "Spend policy for {}:", self.address)?;
+        writeln!(f, "  Confirm above: {}", describe(self.confirm_above))?;
+        write!(f, "  Limit per transaction: {}", describe(self.max_per_tx))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeAddressesResponse {
+    pub use_change_addresses: bool,
+}
+
+impl fmt::Display for ChangeAddressesResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.use_change_addresses {
+            write!(
+                f,
+                "Change now goes to a new wallet address for every payment"
+            )
+        } else {
+            write!(f, "Change now goes back to the address that paid")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoinSelectionResponse {
+    pub coin_selection: Option<SelectionStrategy>,
+}
+
+impl fmt::Display for CoinSelectionResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.coin_selection {
+            Some(strategy) => write!(
+                f,
+                "Payments now pick their coins by the {strategy} strategy unless --coin-selection says otherwise"
+            ),
+            None => write!(
+                f,
+                "Payments now pick their coins in chainstate order unless --coin-selection says otherwise"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BumpFeeResponse {
+    pub replaced: String,
+    pub txid: String,
+    pub old_fee: u64,
+    pub new_fee: u64,
+}
+
+impl fmt::Display for BumpFeeResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Replaced {} with {} (fee {} -> {})",
+            self.replaced, self.txid, self.old_fee, self.new_fee
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AbandonTransactionResponse {
+    pub txid: String,
+    /// Outputs it spent, which are free for another transaction now
+    pub inputs_freed: usize,
+}
+
+impl fmt::Display for AbandonTransactionResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Abandoned {}, its {} input(s) can be spent again",
+            self.txid, self.inputs_freed
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsolidateResponse {
+    pub txid: String,
+    /// The rest of a consolidation too large for one transaction
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub other_txids: Vec<String>,
+    /// Outputs merged
+    pub inputs: usize,
+    /// What the single output left holds
+    pub amount: u64,
+    pub fee: u64,
+    pub broadcast: bool,
+    pub mined_block: Option<String>,
+}
+
+impl fmt::Display for ConsolidateResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Consolidated {} outputs into {}, paying a {} fee",
+            self.inputs,
+            format_amount(self.amount),
+            format_amount(self.fee)
+        )?;
+        if !self.other_txids.is_empty() {
+            write!(f, " across {} transactions", self.other_txids.len() + 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultisigAddressResponse {
+    pub address: String,
+    pub required: u8,
+    pub keys: usize,
+}
+
+impl fmt::Display for MultisigAddressResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Multisig address ({} of {}): {}",
+            self.required, self.keys, self.address
+        )
+    }
+}
+
+/// A finished transaction sent on, by `combinepartial`, `sendrawtransaction` or
+/// `send --from-draft`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SentResponse {
+    pub txid: String,
+    pub broadcast: bool,
+    pub mined_block: Option<String>,
+}
+
+impl fmt::Display for SentResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sent {}", self.txid)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DraftSavedResponse {
+    pub name: String,
+    pub state: DraftState,
+    /// Bytes the transaction serializes to
+    pub size: usize,
+}
+
+impl fmt::Display for DraftSavedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Saved draft '{}' ({}, {} bytes)",
+            self.name, self.state, self.size
+        )
+    }
+}
+
+/// One draft as `draft list` and `draft show` print it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DraftEntry {
+    pub name: String,
+    pub state: DraftState,
+    /// Milliseconds since the epoch
+    pub saved_at: i64,
+    pub size: usize,
+    /// Inputs, as txid:vout, the main chain has spent since the draft was saved
+    pub spent_inputs: Vec<String>,
+    /// The transaction (hex), only shown by `draft show`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<String>,
+}
+
+impl DraftEntry {
+    /// A draft that spends what the chain already spent can never be sent
+    pub fn is_stale(&self) -> bool {
+        !self.spent_inputs.is_empty()
+    }
+}
+
+impl fmt::Display for DraftEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}, {} bytes", self.name, self.state, self.size)?;
+        if self.is_stale() {
+            write!(f, ", STALE (spent: {})", self.spent_inputs.join(", "))?;
+        }
+        if let Some(transaction) = &self.transaction {
+            write!(f, "\n{transaction}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DraftListResponse {
+    pub drafts: Vec<DraftEntry>,
+}
+
+impl fmt::Display for DraftListResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.drafts.is_empty() {
+            return write!(f, "No drafts");
+        }
+        let lines: Vec<String> = self.drafts.iter().map(ToString::to_string).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DraftDeletedResponse {
+    pub name: String,
+}
+
+impl fmt::Display for DraftDeletedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Deleted draft '{}'", self.name)
+    }
+}
+
+/// Where a transaction stands on the main chain; everything but `txid` is missing while it
+/// isn't on it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionStatusResponse {
+    pub txid: String,
+    pub block: Option<String>,
+    pub confirmations: Option<u64>,
+    #[serde(rename = "final")]
+    pub is_final: bool,
+    /// Blocks still to come before the transaction is final
+    pub blocks_to_final: Option<u64>,
+}
+
+impl fmt::Display for TransactionStatusResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transaction {}", self.txid)?;
+        let Some(confirmations) = self.confirmations else {
+            return write!(
+                f,
+                "\n  Not on the main chain (unknown, pending or reorged out)"
+            );
+        };
+        if let Some(block) = &self.block {
+            write!(f, "\n  Block: {block}")?;
+        }
+        write!(f, "\n  Confirmations: {confirmations}")?;
+        match self.blocks_to_final {
+            Some(blocks) if !self.is_final => {
+                write!(f, "\n  Final: no, after {blocks} more blocks")
+            }
+            _ => write!(f, "\n  Final: yes"),
+        }
+    }
+}
+
+/// Blocks tip first, as `printchain --json` always printed them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PrintchainResponse {
+    pub blocks: Vec<BlockSummary>,
+}
+
+impl fmt::Display for PrintchainResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let blocks: Vec<String> = self.blocks.iter().map(ToString::to_string).collect();
+        write!(f, "{}", blocks.join("\n"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportChainResponse {
+    pub path: String,
+    pub blocks: u64,
+    pub tip: String,
+}
+
+impl fmt::Display for ExportChainResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Exported {} blocks to {} (tip: {})",
+            self.blocks, self.path, self.tip
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportChainResponse {
+    pub height: usize,
+    pub tip: String,
+}
+
+impl fmt::Display for ImportChainResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Done! Imported chain at height {} (tip: {})",
+            self.height, self.tip
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportSnapshotResponse {
+    pub path: String,
+    pub height: usize,
+    pub tip: String,
+    /// Transactions with unspent outputs in the snapshot
+    pub transactions: u64,
+    /// Hex sha256 the snapshot's contents are checked against when imported
+    pub hash: String,
+}
+
+impl fmt::Display for ExportSnapshotResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Exported a snapshot at height {} to {} (tip: {})",
+            self.height, self.path, self.tip
+        )?;
+        writeln!(
+            f,
+            "Transactions with unspent outputs: {}",
+            self.transactions
+        )?;
+        write!(f, "Hash: {}", self.hash)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportSnapshotResponse {
+    pub height: usize,
+    pub tip: String,
+}
+
+impl fmt::Display for ImportSnapshotResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Done! Loaded the snapshot at height {} (tip: {}); blocks below it are only kept as headers",
+            self.height, self.tip
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchAddressResponse {
+    pub address: String,
+    /// False when the address was already watched
+    pub newly_watched: bool,
+    pub notifications: PathBuf,
+}
+
+impl fmt::Display for WatchAddressResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.newly_watched {
+            writeln!(f, "Now watching {}", self.address)?;
+        } else {
+            writeln!(f, "{} is already watched", self.address)?;
+        }
+        write!(f, "Notifications go to {}", self.notifications.display())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnwatchAddressResponse {
+    pub address: String,
+    pub was_watched: bool,
+}
+
+impl fmt::Display for UnwatchAddressResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.was_watched {
+            write!(f, "Stopped watching {}", self.address)
+        } else {
+            write!(f, "{} was not watched", self.address)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListWatchedResponse {
+    pub addresses: Vec<String>,
+}
+
+impl fmt::Display for ListWatchedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addresses.join("\n"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReindexResponse {
+    pub transactions: usize,
+    /// Transactions that still hold unspent outputs
+    pub with_unspent_outputs: u64,
+}
+
+impl fmt::Display for ReindexResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Done! Indexed {} transactions; {} of them hold unspent outputs.",
+            self.transactions, self.with_unspent_outputs
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrateDbResponse {
+    pub rewritten: usize,
+    pub format_version: u8,
+}
+
+impl fmt::Display for MigrateDbResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Done! Rewrote {} blocks in format version {}.",
+            self.rewritten, self.format_version
+        )
+    }
+}
+
+/// What `startnode` leaves behind once the node shuts down; only a `--sync-only` node
+/// reports the height it reached
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStoppedResponse {
+    pub synced_height: Option<usize>,
+}
+
+impl fmt::Display for NodeStoppedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.synced_height {
+            Some(height) => write!(f, "Synced to height {height}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The status alone, or with `--trace` both the status and the recent messages
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeStatusResponse {
+    pub status: NodeStatus,
+    pub messages: Option<Vec<TraceEntry>>,
+}
+
+impl Serialize for NodeStatusResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Traced<'a> {
+            status: &'a NodeStatus,
+            messages: &'a [TraceEntry],
+        }
+        match &self.messages {
+            None => self.status.serialize(serializer),
+            Some(messages) => Traced {
+                status: &self.status,
+                messages,
+            }
+            .serialize(serializer),
+        }
+    }
+}
+
+impl fmt::Display for NodeStatusResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if let Some(messages) = &self.messages {
+            write!(f, "Recent messages ({}):", messages.len())?;
+            for entry in messages {
+                write!(f, "\n  {entry}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BanEntry {
+    pub address: IpAddr,
+    pub ban: Ban,
+}
+
+/// The addresses banned, in the `{address, ban}` form `listbans --json` always printed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BanListResponse {
+    pub bans: Vec<BanEntry>,
+    // Remaining times are counted from when the list was read, in milliseconds
+    #[serde(skip)]
+    read_at: i64,
+}
+
+impl BanListResponse {
+    pub fn new(bans: Vec<(IpAddr, Ban)>) -> Result<BanListResponse> {
+        Ok(BanListResponse {
+            bans: bans
+                .into_iter()
+                .map(|(address, ban)| BanEntry { address, ban })
+                .collect(),
+            read_at: current_timestamp()?,
+        })
+    }
+}
+
+impl fmt::Display for BanListResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.bans.is_empty() {
+            return write!(f, "No addresses are banned");
+        }
+        write!(f, "Banned addresses ({}):", self.bans.len())?;
+        for BanEntry { address, ban } in &self.bans {
+            let remaining = (ban.banned_until - self.read_at).max(0) / 1000;
+            write!(f, "\n  {address}  {remaining}s left  {}", ban.reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BanPeerResponse {
+    pub address: String,
+    pub duration_secs: u64,
+    pub bans: BanListResponse,
+}
+
+impl fmt::Display for BanPeerResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Banned {} for {}s", self.address, self.duration_secs)?;
+        write!(f, "{}", self.bans)
+    }
+}
+
+/// `was_banned` is only known for the local ban list
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnbanPeerResponse {
+    pub address: String,
+    pub was_banned: Option<bool>,
+    pub bans: BanListResponse,
+}
+
+impl fmt::Display for UnbanPeerResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.was_banned == Some(false) {
+            writeln!(f, "{} was not banned", self.address)?;
+        }
+        write!(f, "{}", self.bans)
+    }
+}
+
+/// The connections a running node has open, as a plain list in JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PeerListResponse {
+    pub peers: Vec<PeerInfo>,
+}
+
+impl fmt::Display for PeerListResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.peers.is_empty() {
+            return write!(f, "No peers connected");
+        }
+        write!(f, "Connected peers ({}):", self.peers.len())?;
+        for peer in &self.peers {
+            write!(f, "\n{}", peer.to_string().trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisconnectPeerResponse {
+    pub address: String,
+    /// The connections the node closed
+    pub closed: Vec<String>,
+    pub ban_secs: Option<u64>,
+}
+
+impl fmt::Display for DisconnectPeerResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ban_secs) = self.ban_secs {
+            writeln!(f, "Banned {} for {ban_secs}s", self.address)?;
+        }
+        if self.closed.is_empty() {
+            return write!(f, "No connection with {} was open", self.address);
+        }
+        write!(f, "Closed {}", self.closed.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointResponse {
+    pub height: usize,
+    pub hash: String,
+}
+
+impl fmt::Display for CheckpointResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Done! Checkpoint added at height {}: {}",
+            self.height, self.hash
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerateResponse {
+    /// Hashes of the new blocks, oldest first
+    pub blocks: Vec<String>,
+    pub height: usize,
+}
+
+impl fmt::Display for GenerateResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for hash in &self.blocks {
+            writeln!(f, "{hash}")?;
+        }
+        write!(f, "Height: {}", self.height)
+    }
+}
+
+/// Every setting as a node started now would use it, defaults included
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DumpConfigResponse {
+    pub config_file: Option<PathBuf>,
+    pub data_dir: PathBuf,
+    pub settings: ConfigFile,
+    // The settings are shown as the TOML a config file would hold
+    #[serde(skip)]
+    toml: String,
+}
+
+impl DumpConfigResponse {
+    pub fn new(
+        config_file: Option<PathBuf>,
+        data_dir: PathBuf,
+        settings: ConfigFile,
+    ) -> Result<DumpConfigResponse> {
+        Ok(DumpConfigResponse {
+            toml: settings.to_toml()?,
+            config_file,
+            data_dir,
+            settings,
+        })
+    }
+}
+
+impl fmt::Display for DumpConfigResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.config_file {
+            Some(path) => writeln!(f, "# Settings from {}", path.display())?,
+            None => writeln!(f, "# No config file, built-in defaults")?,
+        }
+        writeln!(f, "# Data directory: {}", self.data_dir.display())?;
+        write!(f, "{}", self.toml)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SupplyResponse {
+    pub height: usize,
+    pub total_supply: u64,
+    pub next_subsidy: u64,
+    /// Height of the next halving
+    pub next_halving: usize,
+}
+
+impl fmt::Display for SupplyResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Height:          {}", self.height)?;
+        writeln!(
+            f,
+            "Total supply:    {} satoshis ({})",
+            self.total_supply,
+            format_satoshis(self.total_supply)
+        )?;
+        writeln!(f, "Next subsidy:    {} satoshis", self.next_subsidy)?;
+        write!(
+            f,
+            "Next halving:    height {} ({} blocks away)",
+            self.next_halving,
+            self.next_halving - self.height
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MiningStatsResponse {
+    pub from_height: usize,
+    pub to_height: usize,
+    pub blocks: Vec<MinedBlockEntry>,
+    /// Blocks in the window per miner, most first
+    pub leaderboard: Vec<MinerEntry>,
+}
+
+/// `attempts`, `elapsed_ms` and `hashrate` are only known for blocks this node mined
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MinedBlockEntry {
+    pub height: usize,
+    pub hash: String,
+    pub difficulty: u32,
+    pub miner: Option<String>,
+    pub attempts: Option<u64>,
+    pub elapsed_ms: Option<u64>,
+    /// Hashes per second
+    pub hashrate: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MinerEntry {
+    pub miner: String,
+    pub blocks: usize,
+}
+
+impl MiningStatsResponse {
+    pub fn new(report: MiningReport) -> Result<MiningStatsResponse> {
+        let address = |pub_key_hash: &[u8]| convert_address(ADDRESS_VERSION, pub_key_hash);
+        Ok(MiningStatsResponse {
+            from_height: report.from_height,
+            to_height: report.to_height,
+            blocks: report
+                .blocks
+                .into_iter()
+                .map(|block| {
+                    Ok(MinedBlockEntry {
+                        height: block.height,
+                        hash: block.hash,
+                        difficulty: block.difficulty,
+                        miner: block
+                            .miner_pub_key_hash
+                            .as_deref()
+                            .map(address)
+                            .transpose()?,
+                        attempts: block.stats.as_ref().map(|stats| stats.attempts),
+                        elapsed_ms: block.stats.as_ref().map(|stats| stats.elapsed_ms),
+                        hashrate: block.stats.as_ref().and_then(MiningStats::hashrate),
+                    })
+                })
+                .collect::<Result<_>>()?,
+            leaderboard: report
+                .blocks_by_miner
+                .into_iter()
+                .map(|(pub_key_hash, blocks)| {
+                    Ok(MinerEntry {
+                        miner: address(&pub_key_hash)?,
+                        blocks,
+                    })
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl fmt::Display for MiningStatsResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Blocks {} to {}:", self.from_height, self.to_height)?;
+        for block in &self.blocks {
+            let miner = block.miner.as_deref().unwrap_or("unknown");
+            write!(
+                f,
+                "{:>8}  {}  difficulty {:<3} {miner}",
+                block.height, block.hash, block.difficulty
+            )?;
+            if let (Some(attempts), Some(elapsed_ms)) = (block.attempts, block.elapsed_ms) {
+                write!(f, "  {attempts} attempts in {elapsed_ms} ms")?;
+                if let Some(hashrate) = block.hashrate {
+                    write!(f, " ({hashrate:.0} H/s)")?;
+                }
+            }
+            writeln!(f)?;
+        }
+        write!(f, "Blocks per miner:")?;
+        for entry in &self.leaderboard {
+            write!(f, "\n{:>8}  {}", entry.blocks, entry.miner)?;
+        }
+        Ok(())
+    }
+}
+
+/// A fee at one priority
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriorityFee {
+    pub priority: String,
+    pub fee: u64,
+}
+
+impl PriorityFee {
+    fn new(priority: FeePriority, fee: Amount) -> PriorityFee {
+        PriorityFee {
+            priority: priority.to_string(),
+            fee: fee.to_satoshis(),
+        }
+    }
+}
+
+/// Without a payment to price, the estimate for a priority and what a typical payment
+/// would pay at each
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EstimateFeeResponse {
+    pub priority: String,
+    pub fee: u64,
+    /// Fees of a payment with 2 inputs and 2 outputs, lowest priority first
+    pub typical_fees: Vec<PriorityFee>,
+    /// Only in dynamic mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub congestion_multiplier: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mempool_size: Option<usize>,
+}
+
+impl EstimateFeeResponse {
+    /// `typical_fees` in `FeePriority::ALL` order
+    pub fn new(
+        priority: FeePriority,
+        fee: Amount,
+        typical_fees: &[Amount],
+        statistics: Option<&FeeStatistics>,
+    ) -> EstimateFeeResponse {
+        EstimateFeeResponse {
+            priority: priority.to_string(),
+            fee: fee.to_satoshis(),
+            typical_fees: FeePriority::ALL
+                .into_iter()
+                .zip(typical_fees)
+                .map(|(priority, fee)| PriorityFee::new(priority, *fee))
+                .collect(),
+            congestion_multiplier: statistics.map(|stats| stats.current_congestion_multiplier),
+            mempool_size: statistics.map(|stats| stats.mempool_size),
+        }
+    }
+}
+
+impl fmt::Display for EstimateFeeResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Estimated fee for {} priority: {}",
+            self.priority,
+            format_amount(self.fee)
+        )?;
+        write!(f, "\nA typical payment (2 inputs, 2 outputs) would pay:")?;
+        for entry in &self.typical_fees {
+            write!(f, "\n{:>8}  {}", entry.priority, format_amount(entry.fee))?;
+        }
+        if let (Some(multiplier), Some(mempool_size)) =
+            (self.congestion_multiplier, self.mempool_size)
+        {
+            write!(
+                f,
+                "\nCongestion multiplier: {multiplier:.2}x with {mempool_size} transactions in the mempool"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What a payment from an address would cost, its coins selected as `send` would select them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentFeeEstimateResponse {
+    pub from: String,
+    pub amount: u64,
+    pub priority: String,
+    pub input_count: usize,
+    pub output_count: usize,
+    /// Estimated size in bytes once signed
+    pub size: usize,
+    pub fee: u64,
+    /// The payment's fee at every priority, lowest first
+    pub fees: Vec<PriorityFee>,
+    /// The amount and the fee
+    pub total_debit: u64,
+    pub balance: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+impl PaymentFeeEstimateResponse {
+    /// `previews` holds the payment previewed at each priority, `priority`'s among them
+    pub fn new(
+        from: String,
+        priority: FeePriority,
+        previews: &[(FeePriority, TransactionPreview)],
+    ) -> Result<PaymentFeeEstimateResponse> {
+        let (_, preview) = previews
+            .iter()
+            .find(|(previewed, _)| *previewed == priority)
+            .ok_or_else(|| {
+                BlockchainError::Transaction(format!("No preview at {priority} priority"))
+            })?;
+        let warning = (!preview.sufficient).then(|| {
+            format!(
+                "the payment and its fee come to {}, {} more than the balance of {}",
+                format_amount(preview.total_debit().to_satoshis()),
+                format_amount(
+                    preview
+                        .total_debit()
+                        .saturating_sub(preview.balance)
+                        .to_satoshis()
+                ),
+                format_amount(preview.balance.to_satoshis())
+            )
+        });
+        Ok(PaymentFeeEstimateResponse {
+            from,
+            amount: preview.amount.to_satoshis(),
+            priority: priority.to_string(),
+            input_count: preview.input_count,
+            output_count: preview.output_count,
+            size: preview.size,
+            fee: preview.fee.to_satoshis(),
+            fees: previews
+                .iter()
+                .map(|(priority, preview)| PriorityFee::new(*priority, preview.fee))
+                .collect(),
+            total_debit: preview.total_debit().to_satoshis(),
+            balance: preview.balance.to_satoshis(),
+            warning,
+        })
+    }
+}
+
+impl fmt::Display for PaymentFeeEstimateResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Sending {} from {} at {} priority",
+            format_amount(self.amount),
+            self.from,
+            self.priority
+        )?;
+        writeln!(
+            f,
+            "  {} inputs, {} outputs, about {} bytes",
+            self.input_count, self.output_count, self.size
+        )?;
+        writeln!(f, "  Fee: {}", format_amount(self.fee))?;
+        writeln!(
+            f,
+            "  Total debit: {} of a {} balance",
+            format_amount(self.total_debit),
+            format_amount(self.balance)
+        )?;
+        write!(f, "Fee by priority:")?;
+        for entry in &self.fees {
+            write!(f, "\n{:>8}  {}", entry.priority, format_amount(entry.fee))?;
+        }
+        if let Some(warning) = &self.warning {
+            write!(f, "\nwarning: {warning}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Dynamic fees come with their statistics, fixed fees with only the recent rates
+#[derive(Debug, Clone, Serialize)]
+pub struct FeeStatusResponse {
+    pub summary: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<FeeStatistics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent_rates: Option<RecentFeeRates>,
+}
+
+impl fmt::Display for FeeStatusResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Fee System Status:")?;
+        writeln!(f, "  {}", self.summary)?;
+        writeln!(f)?;
+        if let Some(statistics) = &self.statistics {
+            write!(f, "{statistics}")?;
+        } else if let Some(rates) = &self.recent_rates {
+            writeln!(f, "Recent Confirmed Fee Rates:")?;
+            write!(f, "{rates}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeModeResponse {
+    pub configuration: String,
+}
+
+impl fmt::Display for FeeModeResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Fee mode updated successfully")?;
+        write!(f, "New configuration: {}", self.configuration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::monetary::Amount;
+
+    #[derive(Debug)]
+    struct Wrapped(BlockchainError);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}. Try again later", self.0)
+        }
+    }
+
+    impl std::error::Error for Wrapped {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn test_error_kind_comes_from_the_wrapped_blockchain_error() {
+        let error = Wrapped(BlockchainError::InsufficientFundsDetailed {
+            amount: Amount::from_satoshis(10),
+            fee: Amount::from_satoshis(1),
+            required: Amount::from_satoshis(11),
+            available: Amount::from_satoshis(5),
+        });
+        let response = ErrorResponse::new(&error);
+        assert_eq!(response.error.kind, "InsufficientFunds");
+        assert!(response.error.message.ends_with("Try again later"));
+
+        let plain: Box<dyn std::error::Error> = "Amount must be positive".into();
+        assert_eq!(ErrorResponse::new(plain.as_ref()).error.kind, "Other");
+    }
+
+    #[test]
+    fn test_balance_leaves_out_pending_amounts_it_was_not_asked_for() {
+        let balance = BalanceResponse {
+            address: "addr".to_string(),
+            confirmed: 42,
+            pending_in: None,
+            pending_out: None,
+            available: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&balance).unwrap(),
+            serde_json::json!({ "address": "addr", "confirmed": 42 })
+        );
+        assert_eq!(balance.to_string(), "Balance of addr: 42");
+    }
+
+    #[test]
+    fn test_payment_estimate_warns_only_when_the_balance_falls_short() {
+        let preview = |fee: u64, sufficient: bool| TransactionPreview {
+            input_count: 2,
+            output_count: 1,
+            size: 168,
+            fee: Amount::from_satoshis(fee),
+            amount: Amount::from_satoshis(1_995),
+            balance: Amount::from_satoshis(2_000),
+            sufficient,
+        };
+        let previews = [
+            (FeePriority::Low, preview(5, true)),
+            (FeePriority::Normal, preview(5, true)),
+            (FeePriority::High, preview(8, false)),
+            (FeePriority::Urgent, preview(12, false)),
+        ];
+
+        let normal =
+            PaymentFeeEstimateResponse::new("addr".to_string(), FeePriority::Normal, &previews)
+                .unwrap();
+        assert_eq!((normal.fee, normal.total_debit), (5, 2_000));
+        assert_eq!(normal.warning, None);
+        let fees: Vec<u64> = normal.fees.iter().map(|entry| entry.fee).collect();
+        assert_eq!(fees, vec![5, 5, 8, 12]);
+
+        let urgent =
+            PaymentFeeEstimateResponse::new("addr".to_string(), FeePriority::Urgent, &previews)
+                .unwrap();
+        assert_eq!(urgent.total_debit, 2_007);
+        let warning = urgent.warning.unwrap();
+        assert!(warning.contains("0.00000007"), "{warning}");
+    }
+
+    #[test]
+    fn test_send_names_every_transaction_and_the_block_it_was_mined_in() {
+        let mut sent = SendResponse {
+            txid: "aa".to_string(),
+            other_txids: vec![],
+            to: "addr".to_string(),
+            amount: 1_000,
+            fee: 3,
+            broadcast: true,
+            mined_block: None,
+            swept: false,
+            reuse_warnings: vec![],
+            input_count: 2,
+            coin_selection: Some(SelectionStrategy::BranchAndBound),
+        };
+        assert_eq!(sent.to_string(), "Transaction aa\nSuccess!");
+        let json = serde_json::to_value(&sent).unwrap();
+        assert_eq!(json["txid"], "aa");
+        assert_eq!(json["input_count"], 2);
+        assert_eq!(json["coin_selection"], "bnb");
+
+        sent.other_txids = vec!["bb".to_string()];
+        sent.broadcast = false;
+        sent.mined_block = Some("00ff".to_string());
+        assert_eq!(
+            sent.to_string(),
+            "Transaction aa\nTransaction bb\nMined in block 00ff\nSuccess!"
+        );
+    }
+
+    #[test]
+    fn test_peer_list_prints_each_connection_and_is_a_list_in_json() {
+        let mut listed = PeerListResponse { peers: vec![] };
+        assert_eq!(listed.to_string(), "No peers connected");
+        assert_eq!(
+            serde_json::to_value(&listed).unwrap(),
+            serde_json::json!([])
+        );
+
+        listed.peers.push(PeerInfo {
+            address: "10.0.0.1:51000".to_string(),
+            direction: crate::network::Direction::Inbound,
+            pooled: false,
+            listen_address: None,
+            version: None,
+            best_height: None,
+            connected_since: 1_760_000_000_000,
+            last_message: None,
+            bytes_sent: 0,
+            bytes_received: 90,
+            messages_sent: Default::default(),
+            messages_received: [("GetStatus".to_string(), 1)].into(),
+            misbehavior_score: 0,
+        });
+        let printed = listed.to_string();
+        assert!(printed.starts_with("Connected peers (1):\n10.0.0.1:51000 (inbound, ephemeral)"));
+        assert!(
+            printed.contains("Received:          GetStatus=1"),
+            "{printed}"
+        );
+        let json = serde_json::to_string(&listed).unwrap();
+        assert_eq!(
+            serde_json::from_str::<PeerListResponse>(&json).unwrap(),
+            listed
+        );
+    }
+}
@@ -0,0 +1,590 @@
+//! The node configuration file
+//!
+//! Settings can live in an `architect.toml` in the data directory, or in any file passed
+//! with `--config`:
+//!
+//! ```toml
+//! [node]
+//! listen_addr = "127.0.0.1:2001"
+//! finality_depth = 6
+//! max_tx_inputs = 1000
+//! verify_threads = 4
+//!
+//! [mining]
+//! address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"
+//! threads = 4
+//! block_interval_secs = 30
+//! tx_threshold = 10
+//!
+//! [fees]
+//! mode = "dynamic"
+//! dynamic.base_fee = 2000
+//! max_consolidation_fee_percent = 10
+//! min_relay_fee_rate = 1
+//! allow_free_transactions = false
+//!
+//! [network]
+//! seeds = ["seed.example.org"]
+//! max_peers = 8
+//! max_outbound_connections = 16
+//! max_message_size = 5242880
+//! max_inv_items = 50000
+//! allow_unsigned_peers = false
+//! allow_unversioned_network = true
+//! allowed_peers = ["192.168.1.0/24", "10.0.0.5"]
+//! ```
+//!
+//! Every key is optional. Command line flags win over the file, and the file wins over the
+//! built-in defaults. A value that doesn't make sense is reported with the line it is on.
+
+use crate::core::{
+    DynamicFeeConfig, FeeMode, MAX_TRANSACTION_FEE, MAX_TRANSACTION_SIZE, MIN_TRANSACTION_FEE,
+};
+use crate::error::{BlockchainError, Result};
+use crate::network::Netmask;
+use crate::wallet::validate_address;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use toml::Spanned;
+
+/// Name of the configuration file looked for in the data directory
+pub const CONFIG_FILE_NAME: &str = "architect.toml";
+
+/// The settings a configuration file gives, all of them optional
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConfigFile {
+    pub node: NodeSection,
+    pub mining: MiningSection,
+    pub fees: FeesSection,
+    pub network: NetworkSection,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NodeSection {
+    /// Address the node listens on, `host:port`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen_addr: Option<String>,
+    /// Confirmations after which a transaction counts as final
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finality_depth: Option<usize>,
+    /// Most inputs a transaction may spend to be built, pooled or relayed here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tx_inputs: Option<usize>,
+    /// Threads verifying the signatures of a block's transactions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_threads: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MiningSection {
+    /// Mine and send rewards to this address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_interval_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_threshold: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeModeName {
+    Fixed,
+    Dynamic,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct FeesSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<FeeModeName>,
+    /// Fee of every transaction in fixed mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed_amount: Option<u64>,
+    #[serde(skip_serializing_if = "DynamicFeesSection::is_empty")]
+    pub dynamic: DynamicFeesSection,
+    /// Largest fee `consolidate` pays, as a percentage of the value it merges
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_consolidation_fee_percent: Option<u64>,
+    /// Satoshis per byte a transaction has to pay to be pooled or relayed here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_relay_fee_rate: Option<u64>,
+    /// Whether small transactions paying no fee are pooled and relayed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_free_transactions: Option<bool>,
+}
+
+impl FeesSection {
+    /// The fee mode this section asks for, if it names one
+    pub fn fee_mode(&self) -> Option<FeeMode> {
+        match self.mode? {
+            FeeModeName::Fixed => Some(FeeMode::Fixed {
+                amount: self.fixed_amount.unwrap_or(MIN_TRANSACTION_FEE),
+            }),
+            FeeModeName::Dynamic => {
+                let mut config = DynamicFeeConfig::with_base_fee(
+                    self.dynamic.base_fee.unwrap_or(MIN_TRANSACTION_FEE),
+                );
+                if let Some(max_fee) = self.dynamic.max_fee {
+                    config.max_fee = max_fee;
+                }
+                Some(FeeMode::Dynamic { config })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DynamicFeesSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_fee: Option<u64>,
+    /// Defaults to ten times the base fee
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fee: Option<u64>,
+}
+
+impl DynamicFeesSection {
+    fn is_empty(&self) -> bool {
+        self.base_fee.is_none() && self.max_fee.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NetworkSection {
+    /// DNS seeds asked for peers on top of the network's own
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub seeds: Vec<String>,
+    /// Most peers connected at once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_peers: Option<usize>,
+    /// Most connections to peers kept open between messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_outbound_connections: Option<usize>,
+    /// Most bytes read of a single package from a peer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_size: Option<usize>,
+    /// Most hashes taken in a single inventory package from a peer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_inv_items: Option<usize>,
+    /// Whether gossip from peers that don't sign their packages is taken
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_unsigned_peers: Option<bool>,
+    /// Whether peers whose version message carries no network magic are taken
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_unversioned_network: Option<bool>,
+    /// The only addresses peers may connect from or be dialed at, none meaning any
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_peers: Vec<Netmask>,
+}
+
+impl ConfigFile {
+    /// Read and check the configuration file at `path`
+    pub fn load(path: &Path) -> Result<ConfigFile> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            BlockchainError::Config(format!(
+                "Failed to read config file {}: {e}",
+                path.display()
+            ))
+        })?;
+        Self::parse(&text, &path.display().to_string())
+    }
+
+    /// Parse configuration file text; `origin` names the file in error messages
+    pub fn parse(text: &str, origin: &str) -> Result<ConfigFile> {
+        let raw: RawConfigFile = toml::from_str(text)
+            .map_err(|e| BlockchainError::Config(format!("Invalid config file {origin}: {e}")))?;
+        let check = Checker { text, origin };
+
+        let node = NodeSection {
+            listen_addr: check.value(
+                raw.node.listen_addr,
+                "node.listen_addr",
+                |addr| match addr.rsplit_once(':') {
+                    Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+                    _ => Err(format!("'{addr}' is not a host:port address")),
+                },
+            )?,
+            finality_depth: check.value(
+                raw.node.finality_depth,
+                "node.finality_depth",
+                at_least_one,
+            )?,
+            max_tx_inputs: check.value(
+                raw.node.max_tx_inputs,
+                "node.max_tx_inputs",
+                at_least_one,
+            )?,
+            verify_threads: check.value(
+                raw.node.verify_threads,
+                "node.verify_threads",
+                at_least_one,
+            )?,
+        };
+        let mining = MiningSection {
+            address: check.value(raw.mining.address, "mining.address", |address| {
+                if validate_address(address).is_ok() {
+                    Ok(())
+                } else {
+                    Err(format!("'{address}' is not a valid address"))
+                }
+            })?,
+            threads: check.value(raw.mining.threads, "mining.threads", at_least_one)?,
+            block_interval_secs: check.value(
+                raw.mining.block_interval_secs,
+                "mining.block_interval_secs",
+                |secs| {
+                    if *secs == 0 {
+                        Err("must be at least 1".to_string())
+                    } else {
+                        Ok(())
+                    }
+                },
+            )?,
+            tx_threshold: check.value(
+                raw.mining.tx_threshold,
+                "mining.tx_threshold",
+                at_least_one,
+            )?,
+        };
+
+        let mode = raw.fees.mode.as_ref().map(|mode| *mode.get_ref());
+        if let Some(amount) = &raw.fees.fixed_amount {
+            if mode == Some(FeeModeName::Dynamic) {
+                return Err(check.error(
+                    amount.span(),
+                    "fees.fixed_amount",
+                    "only applies with mode = \"fixed\"",
+                ));
+            }
+        }
+        let dynamic_span = raw
+            .fees
+            .dynamic
+            .base_fee
+            .as_ref()
+            .or(raw.fees.dynamic.max_fee.as_ref())
+            .map(Spanned::span);
+        if let Some(span) = dynamic_span.clone() {
+            if mode != Some(FeeModeName::Dynamic) {
+                return Err(check.error(
+                    span,
+                    "fees.dynamic",
+                    "only applies with mode = \"dynamic\"",
+                ));
+            }
+        }
+        let fees = FeesSection {
+            mode,
+            fixed_amount: raw.fees.fixed_amount.map(Spanned::into_inner),
+            dynamic: DynamicFeesSection {
+                base_fee: raw.fees.dynamic.base_fee.map(Spanned::into_inner),
+                max_fee: raw.fees.dynamic.max_fee.map(Spanned::into_inner),
+            },
+            max_consolidation_fee_percent: check.value(
+                raw.fees.max_consolidation_fee_percent,
+                "fees.max_consolidation_fee_percent",
+                |percent| {
+                    if (1..=100).contains(percent) {
+                        Ok(())
+                    } else {
+                        Err("must be between 1 and 100".to_string())
+                    }
+                },
+            )?,
+            min_relay_fee_rate: check.value(
+                raw.fees.min_relay_fee_rate,
+                "fees.min_relay_fee_rate",
+                |rate| {
+                    // Past it the largest transactions couldn't pay without the sender
+                    // insisting on the fee
+                    let max_rate = MAX_TRANSACTION_FEE / MAX_TRANSACTION_SIZE as u64;
+                    if *rate > max_rate {
+                        Err(format!("must be at most {max_rate} satoshis per byte"))
+                    } else {
+                        Ok(())
+                    }
+                },
+            )?,
+            allow_free_transactions: raw.fees.allow_free_transactions.map(Spanned::into_inner),
+        };
+        if let (Some(FeeModeName::Dynamic), Some(span)) = (mode, dynamic_span) {
+            if let Some(FeeMode::Dynamic { config }) = fees.fee_mode() {
+                config
+                    .validate()
+                    .map_err(|e| check.error(span, "fees.dynamic", &config_message(e)))?;
+            }
+        }
+
+        let network = NetworkSection {
+            seeds: match raw.network.seeds {
+                Some(seeds) => {
+                    let span = seeds.span();
+                    let seeds = seeds.into_inner();
+                    if seeds.iter().any(|seed| seed.trim().is_empty()) {
+                        return Err(check.error(span, "network.seeds", "seeds must not be empty"));
+                    }
+                    seeds
+                }
+                None => Vec::new(),
+            },
+            max_peers: check.value(raw.network.max_peers, "network.max_peers", at_least_one)?,
+            max_outbound_connections: check.value(
+                raw.network.max_outbound_connections,
+                "network.max_outbound_connections",
+                at_least_one,
+            )?,
+            max_message_size: check.value(
+                raw.network.max_message_size,
+                "network.max_message_size",
+                at_least_one,
+            )?,
+            max_inv_items: check.value(
+                raw.network.max_inv_items,
+                "network.max_inv_items",
+                at_least_one,
+            )?,
+            allow_unsigned_peers: raw.network.allow_unsigned_peers,
+            allow_unversioned_network: raw.network.allow_unversioned_network,
+            allowed_peers: match raw.network.allowed_peers {
+                Some(allowed) => {
+                    let span = allowed.span();
+                    allowed
+                        .into_inner()
+                        .iter()
+                        .map(|net| {
+                            net.parse::<Netmask>().map_err(|_| {
+                                let msg = format!("{net} is not an address or CIDR range");
+                                check.error(span.clone(), "network.allowed_peers", &msg)
+                            })
+                        })
+                        .collect::<Result<Vec<_>>>()?
+                }
+                None => Vec::new(),
+            },
+        };
+
+        Ok(ConfigFile {
+            node,
+            mining,
+            fees,
+            network,
+        })
+    }
+
+    /// The fee mode the `[fees]` section asks for, if it names one
+    pub fn fee_mode(&self) -> Option<FeeMode> {
+        self.fees.fee_mode()
+    }
+
+    /// The file as TOML, leaving out settings it doesn't give
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self)
+            .map_err(|e| BlockchainError::Config(format!("Failed to write config as TOML: {e}")))
+    }
+}
+
+fn at_least_one(value: &usize) -> std::result::Result<(), String> {
+    if *value == 0 {
+        Err("must be at least 1".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// The fee config says what's wrong, the prefix of its error adds nothing here
+fn config_message(error: BlockchainError) -> String {
+    match error {
+        BlockchainError::Config(msg) => msg,
+        other => other.to_string(),
+    }
+}
+
+struct Checker<'a> {
+    text: &'a str,
+    origin: &'a str,
+}
+
+impl Checker<'_> {
+    fn value<T>(
+        &self,
+        value: Option<Spanned<T>>,
+        key: &str,
+        check: impl Fn(&T) -> std::result::Result<(), String>,
+    ) -> Result<Option<T>> {
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        check(value.get_ref()).map_err(|msg| self.error(value.span(), key, &msg))?;
+        Ok(Some(value.into_inner()))
+    }
+
+    fn error(&self, span: Range<usize>, key: &str, msg: &str) -> BlockchainError {
+        let line = self.text[..span.start.min(self.text.len())]
+            .matches('\n')
+            .count()
+            + 1;
+        BlockchainError::Config(format!(
+            "Invalid config file {}, line {line}: {key}: {msg}",
+            self.origin
+        ))
+    }
+}
+
+// What the file says before checking, with the positions errors point at
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfigFile {
+    node: RawNodeSection,
+    mining: RawMiningSection,
+    fees: RawFeesSection,
+    network: RawNetworkSection,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawNodeSection {
+    listen_addr: Option<Spanned<String>>,
+    finality_depth: Option<Spanned<usize>>,
+    max_tx_inputs: Option<Spanned<usize>>,
+    verify_threads: Option<Spanned<usize>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawMiningSection {
+    address: Option<Spanned<String>>,
+    threads: Option<Spanned<usize>>,
+    block_interval_secs: Option<Spanned<u64>>,
+    tx_threshold: Option<Spanned<usize>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawFeesSection {
+    mode: Option<Spanned<FeeModeName>>,
+    fixed_amount: Option<Spanned<u64>>,
+    dynamic: RawDynamicFeesSection,
+    max_consolidation_fee_percent: Option<Spanned<u64>>,
+    min_relay_fee_rate: Option<Spanned<u64>>,
+    allow_free_transactions: Option<Spanned<bool>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawDynamicFeesSection {
+    base_fee: Option<Spanned<u64>>,
+    max_fee: Option<Spanned<u64>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawNetworkSection {
+    seeds: Option<Spanned<Vec<String>>>,
+    max_peers: Option<Spanned<usize>>,
+    max_outbound_connections: Option<Spanned<usize>>,
+    max_message_size: Option<Spanned<usize>>,
+    max_inv_items: Option<Spanned<usize>>,
+    allow_unsigned_peers: Option<bool>,
+    allow_unversioned_network: Option<bool>,
+    allowed_peers: Option<Spanned<Vec<String>>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    const MINER: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    #[test]
+    fn test_command_line_beats_file_beats_default() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        fs::write(
+            &path,
+            format!(
+                "[mining]\naddress = \"{MINER}\"\nthreads = 3\ntx_threshold = 4\n\
+                 block_interval_secs = 30\n\n[fees]\nmode = \"dynamic\"\ndynamic.base_fee = 2\n\
+                 min_relay_fee_rate = 3\nallow_free_transactions = true\n\n[network]\nseeds = [\"seed.example.org\"]\n"
+            ),
+        )
+        .unwrap();
+        let file = ConfigFile::load(&path).unwrap();
+
+        let config = Config::new();
+        assert_eq!(config.get_mining_threads(), None);
+        config.apply_file(&file);
+        // What the command line sets afterwards wins
+        config.set_mining_threads(6);
+        config.set_transaction_threshold(2);
+
+        assert_eq!(config.get_mining_threads(), Some(6));
+        assert_eq!(config.get_transaction_threshold(), Some(2));
+        // What it leaves alone comes from the file
+        assert_eq!(config.get_mining_addr().as_deref(), Some(MINER));
+        assert_eq!(config.get_block_interval(), Some(Duration::from_secs(30)));
+        assert_eq!(config.get_dns_seeds(), vec!["seed.example.org".to_string()]);
+        assert_eq!(config.get_min_relay_fee_rate(), Some(3));
+        assert!(config.allow_free_transactions());
+        // And what neither gives keeps its default
+        assert_eq!(config.get_max_peers(), None);
+
+        match file.fee_mode() {
+            Some(FeeMode::Dynamic { config }) => {
+                assert_eq!(config.base_fee, 2);
+                assert_eq!(config.max_fee, 20);
+            }
+            other => panic!("expected dynamic fees, got {other:?}"),
+        }
+        assert_eq!(ConfigFile::default().fee_mode().map(|_| ()), None);
+    }
+
+    #[test]
+    fn test_bad_files_point_at_the_line() {
+        let error = |text: &str| {
+            ConfigFile::parse(text, "test.toml")
+                .unwrap_err()
+                .to_string()
+        };
+
+        let bad_address =
+            error("[node]\nlisten_addr = \"127.0.0.1:2001\"\n\n[mining]\naddress = \"nope\"\n");
+        assert!(bad_address.contains("line 5"), "{bad_address}");
+        assert!(bad_address.contains("mining.address"), "{bad_address}");
+
+        let malformed = error("[mining]\nthreads = = 2\n");
+        assert!(malformed.contains("line 2"), "{malformed}");
+
+        let unknown = error("[mining]\nthread = 2\n");
+        assert!(unknown.contains("thread"), "{unknown}");
+
+        let mismatched = error("[fees]\nmode = \"fixed\"\ndynamic.base_fee = 2\n");
+        assert!(mismatched.contains("line 3"), "{mismatched}");
+        assert!(mismatched.contains("fees.dynamic"), "{mismatched}");
+
+        let bad_fees =
+            error("[fees]\nmode = \"dynamic\"\n\n[fees.dynamic]\nbase_fee = 5\nmax_fee = 1\n");
+        assert!(bad_fees.contains("line 5"), "{bad_fees}");
+
+        assert!(error("[network]\nmax_peers = 0\n").contains("network.max_peers"));
+        assert!(error("[network]\nmax_outbound_connections = 0\n")
+            .contains("network.max_outbound_connections"));
+        assert!(error("[network]\nmax_message_size = 0\n").contains("network.max_message_size"));
+        let bad_range = error("[network]\nallowed_peers = [\"10.0.0.0/8\", \"10.0.0.0/40\"]\n");
+        assert!(bad_range.contains("network.allowed_peers"), "{bad_range}");
+        assert!(bad_range.contains("10.0.0.0/40"), "{bad_range}");
+        assert!(error("[fees]\nmax_consolidation_fee_percent = 101\n")
+            .contains("fees.max_consolidation_fee_percent"));
+        assert!(error("[node]\nlisten_addr = \"localhost\"\n").contains("node.listen_addr"));
+        assert!(error("[node]\nfinality_depth = 0\n").contains("node.finality_depth"));
+        assert!(error("[mining]\nblock_interval_secs = 0\n").contains("mining.block_interval_secs"));
+        let bad_rate = error("[fees]\nmode = \"fixed\"\n\nmin_relay_fee_rate = 11\n");
+        assert!(bad_rate.contains("line 4"), "{bad_rate}");
+        assert!(bad_rate.contains("fees.min_relay_fee_rate"), "{bad_rate}");
+    }
+}
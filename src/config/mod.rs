@@ -1,10 +1,13 @@
 //! Configuration management
 //!
 //! This module handles basic configuration settings for the blockchain node,
-//! including network addresses and mining settings.
+//! including network addresses and mining settings, and the optional TOML file
+//! they can be read from.
 //!
 //! Simplified to focus on essential blockchain configuration only.
 
+pub mod file;
 pub mod settings;
 
+pub use file::{ConfigFile, CONFIG_FILE_NAME};
 pub use settings::{Config, GLOBAL_CONFIG};
@@ -1,7 +1,14 @@
+use crate::config::ConfigFile;
+use crate::core::Network;
+use crate::error::{BlockchainError, Result};
+use crate::network::Netmask;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::path::PathBuf;
 use std::sync::RwLock;
+use std::time::Duration;
 
 pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(Config::new);
 
@@ -10,6 +17,31 @@ static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2001";
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
 const NODE_ID_KEY: &str = "NODE_ID";
+const TRANSACTION_THRESHOLD_KEY: &str = "TRANSACTION_THRESHOLD";
+const BLOCK_INTERVAL_KEY: &str = "BLOCK_INTERVAL_SECS";
+const MINING_THREADS_KEY: &str = "MINING_THREADS";
+const DATA_DIR_KEY: &str = "DATA_DIR";
+const DNS_SEEDS_KEY: &str = "DNS_SEEDS";
+const MAX_PEERS_KEY: &str = "MAX_PEERS";
+const MAX_OUTBOUND_CONNECTIONS_KEY: &str = "MAX_OUTBOUND_CONNECTIONS";
+const MAX_MESSAGE_SIZE_KEY: &str = "MAX_MESSAGE_SIZE";
+const MAX_INV_ITEMS_KEY: &str = "MAX_INV_ITEMS";
+const ALLOW_UNSIGNED_PEERS_KEY: &str = "ALLOW_UNSIGNED_PEERS";
+const ALLOWED_PEERS_KEY: &str = "ALLOWED_PEERS";
+const CONFIG_FILE_KEY: &str = "CONFIG_FILE";
+const MAX_CONSOLIDATION_FEE_PERCENT_KEY: &str = "MAX_CONSOLIDATION_FEE_PERCENT";
+const FINALITY_DEPTH_KEY: &str = "FINALITY_DEPTH";
+const WALLET_FILE_KEY: &str = "WALLET_FILE";
+const MAX_TRANSACTION_INPUTS_KEY: &str = "MAX_TRANSACTION_INPUTS";
+const MIN_RELAY_FEE_RATE_KEY: &str = "MIN_RELAY_FEE_RATE";
+const ALLOW_FREE_TRANSACTIONS_KEY: &str = "ALLOW_FREE_TRANSACTIONS";
+const VERIFY_THREADS_KEY: &str = "VERIFY_THREADS";
+const ALLOW_NETWORK_MISMATCH_KEY: &str = "ALLOW_NETWORK_MISMATCH";
+const NETWORK_KEY: &str = "NETWORK";
+const ALLOW_UNVERSIONED_NETWORK_KEY: &str = "ALLOW_UNVERSIONED_NETWORK";
+
+/// Environment variable naming the data directory when `--datadir` isn't given
+pub const DATA_DIR_ENV: &str = "ARCHITECT_DATADIR";
 
 pub struct Config {
     inner: RwLock<HashMap<String, String>>,
@@ -21,6 +53,19 @@ impl Default for Config {
     }
 }
 
+// A clone is an independent copy: later changes to either config don't show up in the other
+impl Clone for Config {
+    fn clone(&self) -> Self {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        Config {
+            inner: RwLock::new(inner.clone()),
+        }
+    }
+}
+
 impl Config {
     pub fn new() -> Config {
         let mut node_addr = String::from(DEFAULT_NODE_ADDR);
@@ -36,6 +81,10 @@ impl Config {
             map.insert(String::from(NODE_ID_KEY), node_id);
         }
 
+        if let Some(data_dir) = env::var(DATA_DIR_ENV).ok().filter(|dir| !dir.is_empty()) {
+            map.insert(String::from(DATA_DIR_KEY), data_dir);
+        }
+
         Config {
             inner: RwLock::new(map),
         }
@@ -103,6 +152,572 @@ impl Config {
         inner.get(NODE_ID_KEY).cloned()
     }
 
+    /// Mine as soon as this many transactions are waiting, instead of the node's default
+    pub fn set_transaction_threshold(&self, threshold: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(TRANSACTION_THRESHOLD_KEY),
+            threshold.to_string(),
+        );
+    }
+
+    pub fn get_transaction_threshold(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(TRANSACTION_THRESHOLD_KEY)
+            .and_then(|threshold| threshold.parse().ok())
+    }
+
+    /// Also mine every `interval` while any transaction is waiting, in whole seconds
+    pub fn set_block_interval(&self, interval: Duration) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(BLOCK_INTERVAL_KEY),
+            interval.as_secs().to_string(),
+        );
+    }
+
+    /// The timed mining interval, if timed mining is on
+    pub fn get_block_interval(&self) -> Option<Duration> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(BLOCK_INTERVAL_KEY)
+            .and_then(|secs| secs.parse().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Run proof-of-work on this many threads
+    pub fn set_mining_threads(&self, threads: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(MINING_THREADS_KEY), threads.to_string());
+    }
+
+    /// The proof-of-work thread count, if one was configured
+    pub fn get_mining_threads(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(MINING_THREADS_KEY)
+            .and_then(|threads| threads.parse().ok())
+            .filter(|threads| *threads > 0)
+    }
+
+    /// Keep the chain database, wallet file and wallet backups under `dir`
+    ///
+    /// This takes precedence over `ARCHITECT_DATADIR`.
+    pub fn set_data_dir(&self, dir: PathBuf) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(DATA_DIR_KEY),
+            dir.to_string_lossy().into_owned(),
+        );
+    }
+
+    /// The configured data directory, if one was given
+    pub fn get_data_dir(&self) -> Option<PathBuf> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner.get(DATA_DIR_KEY).map(PathBuf::from)
+    }
+
+    /// The directory node files resolve against: the configured data directory, created
+    /// if it doesn't exist yet, or else the working directory
+    pub fn data_dir(&self) -> Result<PathBuf> {
+        let Some(dir) = self.get_data_dir() else {
+            return Ok(env::current_dir()?);
+        };
+        fs::create_dir_all(&dir).map_err(|e| {
+            BlockchainError::Config(format!(
+                "Failed to create data directory {}: {e}",
+                dir.display()
+            ))
+        })?;
+        Ok(dir)
+    }
+
+    /// Keep the wallets in this file, wherever the data directory is
+    pub fn set_wallet_file(&self, path: PathBuf) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(WALLET_FILE_KEY),
+            path.to_string_lossy().into_owned(),
+        );
+    }
+
+    /// The wallet file given with `--wallet`, if any
+    pub fn get_wallet_file(&self) -> Option<PathBuf> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner.get(WALLET_FILE_KEY).map(PathBuf::from)
+    }
+
+    /// Also ask these DNS seeds for peers
+    pub fn set_dns_seeds(&self, seeds: Vec<String>) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(DNS_SEEDS_KEY), seeds.join(","));
+    }
+
+    pub fn get_dns_seeds(&self) -> Vec<String> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(DNS_SEEDS_KEY)
+            .map(|seeds| {
+                seeds
+                    .split(',')
+                    .filter(|seed| !seed.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Connect to at most this many peers at once
+    pub fn set_max_peers(&self, max_peers: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(MAX_PEERS_KEY), max_peers.to_string());
+    }
+
+    pub fn get_max_peers(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(MAX_PEERS_KEY)
+            .and_then(|max_peers| max_peers.parse().ok())
+            .filter(|max_peers| *max_peers > 0)
+    }
+
+    /// Keep at most this many connections to peers open between messages
+    pub fn set_max_outbound_connections(&self, max_connections: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(MAX_OUTBOUND_CONNECTIONS_KEY),
+            max_connections.to_string(),
+        );
+    }
+
+    pub fn get_max_outbound_connections(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(MAX_OUTBOUND_CONNECTIONS_KEY)
+            .and_then(|max_connections| max_connections.parse().ok())
+            .filter(|max_connections| *max_connections > 0)
+    }
+
+    /// Read at most this many bytes of a single package from a peer
+    pub fn set_max_message_size(&self, max_message_size: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(MAX_MESSAGE_SIZE_KEY),
+            max_message_size.to_string(),
+        );
+    }
+
+    pub fn get_max_message_size(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(MAX_MESSAGE_SIZE_KEY)
+            .and_then(|max_message_size| max_message_size.parse().ok())
+            .filter(|max_message_size| *max_message_size > 0)
+    }
+
+    /// Take at most this many hashes in a single inventory package from a peer
+    pub fn set_max_inv_items(&self, max_inv_items: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(MAX_INV_ITEMS_KEY), max_inv_items.to_string());
+    }
+
+    pub fn get_max_inv_items(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(MAX_INV_ITEMS_KEY)
+            .and_then(|max_inv_items| max_inv_items.parse().ok())
+            .filter(|max_inv_items| *max_inv_items > 0)
+    }
+
+    /// Whether to take gossip from peers that don't sign their packages
+    pub fn set_allow_unsigned_peers(&self, allow: bool) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(ALLOW_UNSIGNED_PEERS_KEY), allow.to_string());
+    }
+
+    /// Unsigned peers are allowed unless the config says otherwise, since older nodes
+    /// never sign
+    pub fn allow_unsigned_peers(&self) -> bool {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(ALLOW_UNSIGNED_PEERS_KEY)
+            .and_then(|allow| allow.parse().ok())
+            .unwrap_or(true)
+    }
+
+    /// Run on `network`, see `core::network`
+    pub fn set_network(&self, network: Network) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(NETWORK_KEY), network.name().to_string());
+    }
+
+    /// Mainnet unless `--network` says otherwise
+    pub fn get_network(&self) -> Network {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(NETWORK_KEY)
+            .and_then(|network| network.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether to take peers whose version message carries no network magic
+    pub fn set_allow_unversioned_network(&self, allow: bool) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(ALLOW_UNVERSIONED_NETWORK_KEY),
+            allow.to_string(),
+        );
+    }
+
+    /// Peers without a network magic are taken unless the config says otherwise, since
+    /// older nodes never send one
+    pub fn allow_unversioned_network(&self) -> bool {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(ALLOW_UNVERSIONED_NETWORK_KEY)
+            .and_then(|allow| allow.parse().ok())
+            .unwrap_or(true)
+    }
+
+    /// Only deal with peers inside these ranges; an empty list allows every address
+    pub fn set_allowed_peers(&self, allowed_peers: &[Netmask]) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        let joined: Vec<String> = allowed_peers.iter().map(Netmask::to_string).collect();
+        inner.insert(String::from(ALLOWED_PEERS_KEY), joined.join(","));
+    }
+
+    pub fn get_allowed_peers(&self) -> Vec<Netmask> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(ALLOWED_PEERS_KEY)
+            .map(|allowed| {
+                allowed
+                    .split(',')
+                    .filter_map(|net| net.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Refuse consolidations whose fee is more than `percent` of the value they merge
+    pub fn set_max_consolidation_fee_percent(&self, percent: u64) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(MAX_CONSOLIDATION_FEE_PERCENT_KEY),
+            percent.to_string(),
+        );
+    }
+
+    pub fn get_max_consolidation_fee_percent(&self) -> Option<u64> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(MAX_CONSOLIDATION_FEE_PERCENT_KEY)
+            .and_then(|percent| percent.parse().ok())
+    }
+
+    /// Count transactions as final once they have `depth` confirmations
+    pub fn set_finality_depth(&self, depth: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(FINALITY_DEPTH_KEY), depth.to_string());
+    }
+
+    pub fn get_finality_depth(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(FINALITY_DEPTH_KEY)
+            .and_then(|depth| depth.parse().ok())
+    }
+
+    /// Refuse to build, pool or relay transactions spending more than `max_inputs` outputs
+    pub fn set_max_transaction_inputs(&self, max_inputs: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(MAX_TRANSACTION_INPUTS_KEY),
+            max_inputs.to_string(),
+        );
+    }
+
+    pub fn get_max_transaction_inputs(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(MAX_TRANSACTION_INPUTS_KEY)
+            .and_then(|max_inputs| max_inputs.parse().ok())
+    }
+
+    /// Verify the signatures of a block's transactions on this many threads
+    pub fn set_verify_threads(&self, threads: usize) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(VERIFY_THREADS_KEY), threads.to_string());
+    }
+
+    /// The block verification thread count, if one was configured
+    pub fn get_verify_threads(&self) -> Option<usize> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(VERIFY_THREADS_KEY)
+            .and_then(|threads| threads.parse().ok())
+            .filter(|threads| *threads > 0)
+    }
+
+    /// Pool and relay only transactions paying at least `rate` satoshis per byte
+    pub fn set_min_relay_fee_rate(&self, rate: u64) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(MIN_RELAY_FEE_RATE_KEY), rate.to_string());
+    }
+
+    pub fn get_min_relay_fee_rate(&self) -> Option<u64> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(MIN_RELAY_FEE_RATE_KEY)
+            .and_then(|rate| rate.parse().ok())
+    }
+
+    /// Whether to pool and relay small transactions that pay no fee
+    pub fn set_allow_free_transactions(&self, allow: bool) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(ALLOW_FREE_TRANSACTIONS_KEY), allow.to_string());
+    }
+
+    /// Free transactions are refused unless the config says otherwise
+    pub fn allow_free_transactions(&self) -> bool {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(ALLOW_FREE_TRANSACTIONS_KEY)
+            .and_then(|allow| allow.parse().ok())
+            .unwrap_or(false)
+    }
+
+    /// Use wallet keys with a chain other than the one they were created for
+    pub fn set_allow_network_mismatch(&self, allow: bool) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(String::from(ALLOW_NETWORK_MISMATCH_KEY), allow.to_string());
+    }
+
+    /// Keys created for another chain are refused unless `--allow-network-mismatch` is given
+    pub fn allow_network_mismatch(&self) -> bool {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner
+            .get(ALLOW_NETWORK_MISMATCH_KEY)
+            .and_then(|allow| allow.parse().ok())
+            .unwrap_or(false)
+    }
+
+    /// Remember which configuration file the settings came from
+    pub fn set_config_file(&self, path: PathBuf) {
+        let mut inner = self
+            .inner
+            .write()
+            .expect("Failed to acquire write lock on config - this should never happen");
+        inner.insert(
+            String::from(CONFIG_FILE_KEY),
+            path.to_string_lossy().into_owned(),
+        );
+    }
+
+    pub fn get_config_file(&self) -> Option<PathBuf> {
+        let inner = self
+            .inner
+            .read()
+            .expect("Failed to acquire read lock on config - this should never happen");
+        inner.get(CONFIG_FILE_KEY).map(PathBuf::from)
+    }
+
+    /// Take every setting `file` gives
+    ///
+    /// Command line flags are applied afterwards and so win over the file. The file's
+    /// `node.listen_addr` doesn't replace a `NODE_ADDRESS` from the environment, which is
+    /// how the command line sets the address. The fee mode isn't kept here; it goes to the
+    /// fee calculator through `ConfigFile::fee_mode`.
+    pub fn apply_file(&self, file: &ConfigFile) {
+        if let Some(addr) = &file.node.listen_addr {
+            if env::var(NODE_ADDRESS_KEY).is_err() {
+                self.set_node_addr(addr.clone());
+            }
+        }
+        if let Some(depth) = file.node.finality_depth {
+            self.set_finality_depth(depth);
+        }
+        if let Some(max_inputs) = file.node.max_tx_inputs {
+            self.set_max_transaction_inputs(max_inputs);
+        }
+        if let Some(threads) = file.node.verify_threads {
+            self.set_verify_threads(threads);
+        }
+        if let Some(address) = &file.mining.address {
+            self.set_mining_addr(address.clone());
+        }
+        if let Some(threads) = file.mining.threads {
+            self.set_mining_threads(threads);
+        }
+        if let Some(secs) = file.mining.block_interval_secs {
+            self.set_block_interval(Duration::from_secs(secs));
+        }
+        if let Some(threshold) = file.mining.tx_threshold {
+            self.set_transaction_threshold(threshold);
+        }
+        if !file.network.seeds.is_empty() {
+            self.set_dns_seeds(file.network.seeds.clone());
+        }
+        if let Some(max_peers) = file.network.max_peers {
+            self.set_max_peers(max_peers);
+        }
+        if let Some(max_connections) = file.network.max_outbound_connections {
+            self.set_max_outbound_connections(max_connections);
+        }
+        if let Some(max_message_size) = file.network.max_message_size {
+            self.set_max_message_size(max_message_size);
+        }
+        if let Some(max_inv_items) = file.network.max_inv_items {
+            self.set_max_inv_items(max_inv_items);
+        }
+        if let Some(allow) = file.network.allow_unsigned_peers {
+            self.set_allow_unsigned_peers(allow);
+        }
+        if let Some(allow) = file.network.allow_unversioned_network {
+            self.set_allow_unversioned_network(allow);
+        }
+        if !file.network.allowed_peers.is_empty() {
+            self.set_allowed_peers(&file.network.allowed_peers);
+        }
+        if let Some(percent) = file.fees.max_consolidation_fee_percent {
+            self.set_max_consolidation_fee_percent(percent);
+        }
+        if let Some(rate) = file.fees.min_relay_fee_rate {
+            self.set_min_relay_fee_rate(rate);
+        }
+        if let Some(allow) = file.fees.allow_free_transactions {
+            self.set_allow_free_transactions(allow);
+        }
+    }
+
     /// Extract node ID from address (e.g., "127.0.0.1:2001" -> "2001")
     pub fn extract_node_id_from_addr(&self) -> String {
         let addr = self.get_node_addr();
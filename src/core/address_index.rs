@@ -0,0 +1,428 @@
+//! Per-address transaction history
+//!
+//! The chainstate says what an address holds now, not how it got there. Reading an
+//! address's past means walking the main chain, so the result is kept in the node's
+//! database: `addr_tx_index` maps a public key hash to every main-chain transaction that
+//! paid it or spent from it, oldest first. `Blockchain::rescan_for_addresses` fills it in
+//! for addresses imported after their first payments, starting from a height the caller
+//! picks, e.g. the one a key was created at. Once an address is in the index, every tip
+//! change keeps its history current, reorgs included.
+//!
+//! Spends are recognised by looking up the output they spend in the transaction index,
+//! as for watched addresses. On a pruned chain the blocks whose bodies are gone can't be
+//! read, and are counted in the `RescanReport` instead.
+
+use crate::core::watch::PaymentDirection;
+use crate::core::{Block, Blockchain};
+use crate::error::{BlockchainError, Result};
+use crate::utils::{deserialize, serialize};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+use std::collections::HashMap;
+
+/// A rescan reports its progress every this many blocks
+pub const RESCAN_PROGRESS_INTERVAL: usize = 1_000;
+
+// Public key hash -> Vec<AddressTx>, oldest first
+const ADDR_TX_INDEX_TREE: &str = "addr_tx_index";
+
+// What a scan found, by public key hash
+type Histories = HashMap<Vec<u8>, Vec<AddressTx>>;
+
+/// Coins one main-chain transaction moved to or from an address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct AddressTx {
+    pub txid: String,
+    pub height: usize,
+    pub direction: PaymentDirection,
+    /// Satoshis paid to the address, or spent from it
+    pub amount: u64,
+}
+
+/// How often an address was paid and spent from on the main chain, one count per
+/// transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageStats {
+    pub times_received: usize,
+    pub times_spent_from: usize,
+}
+
+impl UsageStats {
+    /// Count the entries of an address's history
+    pub fn from_history(history: &[AddressTx]) -> UsageStats {
+        let mut usage = UsageStats::default();
+        for entry in history {
+            match entry.direction {
+                PaymentDirection::Incoming => usage.times_received += 1,
+                PaymentDirection::Outgoing => usage.times_spent_from += 1,
+            }
+        }
+        usage
+    }
+
+    /// What to tell someone about to spend from `address` again, if it was spent from
+    /// before
+    ///
+    /// Every spend reveals the address's public key and ties its payments together, so
+    /// only the first one keeps them private.
+    pub fn reuse_warning(&self, address: &str) -> Option<String> {
+        (self.times_spent_from > 0).then(|| {
+            format!(
+                "{address} was already spent from {} time(s); reusing an address links all \
+                 of its payments, consider moving its coins to a new one",
+                self.times_spent_from
+            )
+        })
+    }
+}
+
+/// What `Blockchain::rescan_for_addresses` went through
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RescanReport {
+    pub from_height: usize,
+    /// The best height when the rescan ran
+    pub to_height: usize,
+    pub blocks_scanned: usize,
+    /// Blocks in the range whose bodies were pruned, and so couldn't be scanned
+    pub pruned_blocks: usize,
+    /// History entries found for the addresses, over all of them
+    pub entries: usize,
+}
+
+/// The `addr_tx_index` tree of a blockchain's database
+pub struct AddressIndex {
+    tree: Tree,
+}
+
+impl AddressIndex {
+    pub fn open(blockchain: &Blockchain) -> Result<AddressIndex> {
+        let tree = blockchain
+            .get_db()
+            .open_tree(ADDR_TX_INDEX_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open address index: {e}")))?;
+        Ok(AddressIndex { tree })
+    }
+
+    /// The indexed history of `pub_key_hash`, `None` if it was never rescanned
+    pub fn history(&self, pub_key_hash: &[u8]) -> Result<Option<Vec<AddressTx>>> {
+        self.tree
+            .get(pub_key_hash)
+            .map_err(|e| BlockchainError::Database(format!("Failed to read address index: {e}")))?
+            .map(|bytes| deserialize(&bytes))
+            .transpose()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    fn pub_key_hashes(&self) -> Result<Vec<Vec<u8>>> {
+        self.tree
+            .iter()
+            .keys()
+            .map(|key| {
+                key.map(|key| key.to_vec()).map_err(|e| {
+                    BlockchainError::Database(format!("Failed to read address index: {e}"))
+                })
+            })
+            .collect()
+    }
+
+    fn store(&self, pub_key_hash: &[u8], history: &[AddressTx]) -> Result<()> {
+        self.tree
+            .insert(pub_key_hash, serialize(&history.to_vec())?)
+            .map_err(|e| {
+                BlockchainError::Database(format!("Failed to update address index: {e}"))
+            })?;
+        Ok(())
+    }
+
+    // Replace the history of each address from `from_height` up with `found`
+    fn replace_from(
+        &self,
+        pub_key_hashes: &[Vec<u8>],
+        from_height: usize,
+        mut found: Histories,
+    ) -> Result<()> {
+        for pub_key_hash in pub_key_hashes {
+            let mut history = self.history(pub_key_hash)?.unwrap_or_default();
+            history.retain(|entry| entry.height < from_height);
+            history.extend(found.remove(pub_key_hash).unwrap_or_default());
+            self.store(pub_key_hash, &history)?;
+        }
+        Ok(())
+    }
+}
+
+/// The history of `pub_key_hashes` in the main-chain blocks from `from_height` up
+///
+/// `progress` is called with the height reached every `RESCAN_PROGRESS_INTERVAL` blocks.
+pub(crate) fn scan(
+    blockchain: &Blockchain,
+    pub_key_hashes: &[Vec<u8>],
+    from_height: usize,
+    progress: impl Fn(usize),
+) -> Result<(Histories, RescanReport)> {
+    let best_height = blockchain.get_best_height()?;
+    let mut report = RescanReport {
+        from_height,
+        to_height: best_height,
+        ..RescanReport::default()
+    };
+    let mut found = Histories::new();
+    for height in from_height..=best_height {
+        match blockchain.get_block_at_height(height)? {
+            Some(block) => {
+                scan_block(blockchain, pub_key_hashes, &block, &mut found)?;
+                report.blocks_scanned += 1;
+            }
+            None => report.pruned_blocks += 1,
+        }
+        if (height - from_height + 1).is_multiple_of(RESCAN_PROGRESS_INTERVAL) {
+            progress(height);
+        }
+    }
+    report.entries = found.values().map(Vec::len).sum();
+    Ok((found, report))
+}
+
+/// Scan for `pub_key_hashes` and record what was found in the address index
+pub(crate) fn rescan(
+    blockchain: &Blockchain,
+    pub_key_hashes: &[Vec<u8>],
+    from_height: usize,
+    progress: impl Fn(usize),
+) -> Result<RescanReport> {
+    let (found, report) = scan(blockchain, pub_key_hashes, from_height, progress)?;
+    AddressIndex::open(blockchain)?.replace_from(pub_key_hashes, from_height, found)?;
+    Ok(report)
+}
+
+// Add what `block` moves to or from `pub_key_hashes` to `found`, one entry per
+// transaction, address and direction
+fn scan_block(
+    blockchain: &Blockchain,
+    pub_key_hashes: &[Vec<u8>],
+    block: &Block,
+    found: &mut Histories,
+) -> Result<()> {
+    for tx in block.get_transactions() {
+        let txid = HEXLOWER.encode(tx.get_id());
+        let mut moved: Vec<(&Vec<u8>, PaymentDirection, u64)> = Vec::new();
+        let mut add = |pub_key_hash: &[u8], direction: PaymentDirection, amount: u64| {
+            let Some(pub_key_hash) = pub_key_hashes.iter().find(|hash| *hash == pub_key_hash)
+            else {
+                return;
+            };
+            match moved
+                .iter_mut()
+                .find(|(hash, dir, _)| *hash == pub_key_hash && *dir == direction)
+            {
+                Some((_, _, total)) => *total += amount,
+                None => moved.push((pub_key_hash, direction, amount)),
+            }
+        };
+
+        if !tx.is_coinbase() {
+            for input in tx.get_vin() {
+                let spent = match blockchain.find_transaction(input.get_txid()) {
+                    Ok(prev) => {
+                        prev.and_then(|prev| prev.get_vout().get(input.get_vout()).cloned())
+                    }
+                    // The spent output's block was pruned; nothing left to match it against
+                    Err(BlockchainError::Pruned(_)) => None,
+                    Err(e) => return Err(e),
+                };
+                if let Some(output) = spent.filter(|output| !output.is_data_carrier()) {
+                    add(
+                        &output.get_pub_key_hash(),
+                        PaymentDirection::Outgoing,
+                        output.get_value().to_satoshis(),
+                    );
+                }
+            }
+        }
+        for output in tx.get_vout() {
+            if !output.is_data_carrier() {
+                add(
+                    &output.get_pub_key_hash(),
+                    PaymentDirection::Incoming,
+                    output.get_value().to_satoshis(),
+                );
+            }
+        }
+
+        for (pub_key_hash, direction, amount) in moved {
+            found
+                .entry(pub_key_hash.clone())
+                .or_default()
+                .push(AddressTx {
+                    txid: txid.clone(),
+                    height: block.get_height(),
+                    direction,
+                    amount,
+                });
+        }
+    }
+    Ok(())
+}
+
+// Tip changes end up here, like they do for watched addresses. The blocks that left the
+// main chain take their entries with them, and those that joined it add theirs.
+pub(crate) fn update_for_tip_change(
+    blockchain: &Blockchain,
+    connected: &[String],
+    disconnected: &[(String, usize)],
+) -> Result<()> {
+    let index = AddressIndex::open(blockchain)?;
+    if index.is_empty() {
+        return Ok(());
+    }
+    let mut blocks = Vec::with_capacity(connected.len());
+    for hash in connected {
+        let block = blockchain
+            .get_block(hash)?
+            .ok_or_else(|| BlockchainError::Database(format!("Block {hash} not found")))?;
+        blocks.push(block);
+    }
+    let lowest = disconnected
+        .iter()
+        .map(|(_, height)| *height)
+        .chain(blocks.iter().map(Block::get_height))
+        .min();
+    let Some(lowest) = lowest else {
+        return Ok(());
+    };
+
+    let pub_key_hashes = index.pub_key_hashes()?;
+    let mut found = HashMap::new();
+    for block in &blocks {
+        scan_block(blockchain, &pub_key_hashes, block, &mut found)?;
+    }
+    index.replace_from(&pub_key_hashes, lowest, found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, FeePriority, NodeContext, Transaction, WatchList};
+    use crate::storage::UTXOSet;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::{address_pub_key_hash, Wallets};
+    use tempfile::tempdir;
+
+    const MINER: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    // The history of `address` as watching it from the start would have reported it
+    fn history_from_notifications(blockchain: &Blockchain, address: &str) -> Vec<AddressTx> {
+        let watch_list = WatchList::open(blockchain).unwrap();
+        watch_list.watch(address).unwrap();
+        let mut history = Vec::new();
+        for height in 0..=blockchain.get_best_height().unwrap() {
+            let block = blockchain.get_block_at_height(height).unwrap().unwrap();
+            for tx in block.get_transactions() {
+                let notifications = watch_list
+                    .notifications_for(blockchain, tx, Some(height))
+                    .unwrap();
+                history.extend(notifications.into_iter().map(|n| AddressTx {
+                    txid: n.txid,
+                    height,
+                    direction: n.direction,
+                    amount: n.amount,
+                }));
+            }
+        }
+        watch_list.unwatch(address).unwrap();
+        history
+    }
+
+    #[test]
+    fn test_rescan_finds_the_history_of_an_imported_address() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let other = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &other,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 1 }).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe().unwrap();
+
+        // 30 blocks of the owner being paid, spending and mining
+        for height in 1..=30 {
+            let (from, to) = if height % 3 == 0 {
+                (&owner, &other)
+            } else {
+                (&other, &owner)
+            };
+            let payment = Transaction::new_utxo_transaction_with_context(
+                from,
+                to,
+                1_000 + height,
+                FeePriority::Normal,
+                &utxo_set,
+                &context,
+            )
+            .unwrap();
+            let miner = if height % 5 == 0 { &owner } else { MINER };
+            let block = blockchain
+                .mine_block_with_context(&[payment], miner, &context)
+                .unwrap();
+            utxo_set.update_safe(&block).unwrap();
+        }
+        assert_eq!(blockchain.get_best_height().unwrap(), 30);
+
+        // A wallet that only learns of the owner now
+        let mut fresh = Wallets::new_with_path(&temp_dir.path().join("fresh.dat"));
+        fresh.add_watch_only(&owner, None).unwrap();
+        let pub_key_hash = address_pub_key_hash(&owner).unwrap();
+        let index = AddressIndex::open(&blockchain).unwrap();
+        assert_eq!(index.history(&pub_key_hash).unwrap(), None);
+
+        let progress = std::cell::Cell::new(0);
+        let report = blockchain
+            .rescan_for_addresses(std::slice::from_ref(&pub_key_hash), 0, |_| {
+                progress.set(progress.get() + 1)
+            })
+            .unwrap();
+        assert_eq!(report.blocks_scanned, 31);
+        assert_eq!(report.pruned_blocks, 0);
+        assert_eq!(progress.get(), 0);
+
+        let history = index.history(&pub_key_hash).unwrap().unwrap();
+        assert_eq!(history, history_from_notifications(&blockchain, &owner));
+        assert_eq!(report.entries, history.len());
+        assert!(history
+            .iter()
+            .any(|entry| entry.direction == PaymentDirection::Outgoing));
+        assert_eq!(blockchain.address_history(&pub_key_hash).unwrap(), history);
+
+        // A birthday skips what came before it
+        blockchain
+            .rescan_for_addresses(std::slice::from_ref(&pub_key_hash), 20, |_| {})
+            .unwrap();
+        assert_eq!(index.history(&pub_key_hash).unwrap().unwrap(), history);
+        let other_hash = address_pub_key_hash(&other).unwrap();
+        let report = blockchain
+            .rescan_for_addresses(std::slice::from_ref(&other_hash), 20, |_| {})
+            .unwrap();
+        assert_eq!(report.blocks_scanned, 11);
+        let late = index.history(&other_hash).unwrap().unwrap();
+        assert!(!late.is_empty());
+        assert!(late.iter().all(|entry| entry.height >= 20));
+
+        // New blocks join the history of indexed addresses
+        let block = blockchain
+            .mine_block_with_context(&[], &owner, &context)
+            .unwrap();
+        let history = index.history(&pub_key_hash).unwrap().unwrap();
+        assert_eq!(history.last().unwrap().height, block.get_height());
+        assert_eq!(history, history_from_notifications(&blockchain, &owner));
+    }
+}
@@ -1,17 +1,22 @@
-use crate::core::{MerkleTree, ProofOfWork, Transaction};
+use crate::core::{Amount, MerkleTree, PowSolution, ProofOfWork, Transaction};
 use crate::error::{BlockchainError, Result};
-use crate::utils::{current_timestamp, deserialize, serialize};
+use crate::utils::{
+    current_timestamp, deserialize, deserialize_versioned, serialize, serialize_as,
+    serialize_versioned,
+};
 use log::info;
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 
 // I need to set reasonable limits for my blockchain to prevent abuse
-const MAX_BLOCK_SIZE: usize = 1_000_000; // 1MB maximum block size
-const MAX_TRANSACTIONS_PER_BLOCK: usize = 4000; // Maximum transactions per block
-const MAX_TRANSACTION_SIZE: usize = 100_000; // 100KB maximum transaction size
-const MAX_FUTURE_TIME: i64 = 2 * 60 * 60; // 2 hours maximum future time
-                                          // I'll implement coinbase maturity later when needed
-                                          // const MIN_COINBASE_MATURITY: usize = 100; // Coinbase outputs mature after 100 blocks
+pub const MAX_BLOCK_SIZE: usize = 1_000_000; // 1MB maximum block size
+/// How many of the newest blocks the median time past is taken over
+pub const MEDIAN_TIME_SPAN: usize = 11;
+pub(crate) const MAX_TRANSACTIONS_PER_BLOCK: usize = 4000; // Maximum transactions per block
+pub const MAX_TRANSACTION_SIZE: usize = 100_000; // 100KB maximum transaction size
+const MAX_FUTURE_TIME: i64 = 2 * 60 * 60 * 1000; // 2 hours maximum future time, in ms
+                                                 // I'll implement coinbase maturity later when needed
+                                                 // const MIN_COINBASE_MATURITY: usize = 100; // Coinbase outputs mature after 100 blocks
 
 #[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub struct Block {
@@ -25,12 +30,70 @@ pub struct Block {
     merkle_root: Vec<u8>, // Merkle root of all transactions
 }
 
+/// Everything about a block except its transactions, as sent to light clients
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct BlockHeader {
+    pub timestamp: i64,
+    pub pre_block_hash: String,
+    pub hash: String,
+    pub nonce: i64,
+    pub height: usize,
+    pub difficulty: u32,
+    pub merkle_root: Vec<u8>,
+    /// Number of transactions in the full block
+    pub transaction_count: usize,
+}
+
+impl BlockHeader {
+    pub fn deserialize(bytes: &[u8]) -> Result<BlockHeader> {
+        deserialize::<BlockHeader>(bytes)
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        serialize(self)
+    }
+}
+
 impl Block {
     pub fn new_block(
         pre_block_hash: String,
         transactions: &[Transaction],
         height: usize,
         difficulty: u32,
+    ) -> Result<Block> {
+        let mut block = Self::new_template(pre_block_hash, transactions, height, difficulty)?;
+        block.run_proof_of_work();
+        Ok(block)
+    }
+
+    /// Build a block without running proof-of-work
+    ///
+    /// The template has an empty hash until a nonce is found for it, see `core::miner`. It
+    /// is stamped with the local clock; a node's own blocks come from
+    /// `Blockchain::new_block_template`, which uses the network's.
+    pub fn new_template(
+        pre_block_hash: String,
+        transactions: &[Transaction],
+        height: usize,
+        difficulty: u32,
+    ) -> Result<Block> {
+        Self::new_template_at(
+            current_timestamp()?,
+            pre_block_hash,
+            transactions,
+            height,
+            difficulty,
+        )
+    }
+
+    // Same as `new_template`, for a template whose timestamp was already handed out, e.g.
+    // to an external miner
+    pub(crate) fn new_template_at(
+        timestamp: i64,
+        pre_block_hash: String,
+        transactions: &[Transaction],
+        height: usize,
+        difficulty: u32,
     ) -> Result<Block> {
         if transactions.is_empty() {
             return Err(BlockchainError::InvalidBlock(
@@ -48,8 +111,8 @@ impl Block {
         // Calculate Merkle root for the transactions
         let merkle_root = Self::calculate_merkle_root(transactions)?;
 
-        let mut block = Block {
-            timestamp: current_timestamp()?,
+        Ok(Block {
+            timestamp,
             pre_block_hash,
             hash: String::new(),
             transactions: transactions.to_vec(),
@@ -57,24 +120,55 @@ impl Block {
             height,
             difficulty,
             merkle_root,
-        };
+        })
+    }
 
-        info!("Starting proof-of-work for block at height {height} with difficulty {difficulty}");
-        let pow = ProofOfWork::new_proof_of_work(block.clone());
-        let (nonce, hash) = pow.run();
-        block.nonce = nonce;
-        block.hash = hash.clone();
-        info!("Proof-of-work completed for block: {hash} (difficulty: {difficulty})");
+    // Mine a template on the current thread
+    pub(crate) fn run_proof_of_work(&mut self) -> PowSolution {
+        self.run_proof_of_work_on(1)
+    }
 
-        Ok(block)
+    // Mine a template, blocking until one of `threads` workers finds the nonce
+    pub(crate) fn run_proof_of_work_on(&mut self, threads: usize) -> PowSolution {
+        info!(
+            "Starting proof-of-work for block at height {} with difficulty {} on {threads} thread(s)",
+            self.height, self.difficulty
+        );
+        let pow = ProofOfWork::for_block(self);
+        let solution = if threads > 1 {
+            // Only an exhausted nonce space leaves the workers empty-handed, and then the
+            // single-threaded search ends the same way it always has
+            pow.run_parallel(threads).unwrap_or_else(|| pow.run())
+        } else {
+            pow.run()
+        };
+        self.set_proof(solution.nonce, solution.hash.clone());
+        info!(
+            "Proof-of-work completed for block: {} (difficulty: {})",
+            self.hash, self.difficulty
+        );
+        solution
+    }
+
+    // Record a nonce found by proof-of-work together with the hash it produced
+    pub(crate) fn set_proof(&mut self, nonce: i64, hash: String) {
+        self.nonce = nonce;
+        self.hash = hash;
     }
 
+    /// Read a block in any format up to `FORMAT_VERSION`, see `utils::serialization`
     pub fn deserialize(bytes: &[u8]) -> Result<Block> {
-        deserialize::<Block>(bytes)
+        deserialize_versioned::<Block>(bytes)
     }
 
+    /// The block in the current format version, the way it's stored and sent
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        serialize(self)
+        serialize_versioned(self)
+    }
+
+    /// The block in format `version`, for peers that only read older formats
+    pub fn serialize_as(&self, version: u8) -> Result<Vec<u8>> {
+        serialize_as(self, version)
     }
 
     pub fn get_transactions(&self) -> &[Transaction] {
@@ -109,6 +203,41 @@ impl Block {
         &self.merkle_root
     }
 
+    pub fn get_header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            pre_block_hash: self.pre_block_hash.clone(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            height: self.height,
+            difficulty: self.difficulty,
+            merkle_root: self.merkle_root.clone(),
+            transaction_count: self.transactions.len(),
+        }
+    }
+
+    /// What's left of a block whose body was pruned: the header fields and no transactions
+    pub(crate) fn from_pruned_header(header: BlockHeader) -> Block {
+        Block {
+            timestamp: header.timestamp,
+            pre_block_hash: header.pre_block_hash,
+            hash: header.hash,
+            transactions: Vec::new(),
+            nonce: header.nonce,
+            height: header.height,
+            difficulty: header.difficulty,
+            merkle_root: header.merkle_root,
+        }
+    }
+
+    /// Whether this block came back from storage without its pruned transactions
+    ///
+    /// A stored block always has at least its coinbase, so an empty one can only be a
+    /// pruned block's header.
+    pub fn is_pruned(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
     pub fn get_nonce(&self) -> i64 {
         self.nonce
     }
@@ -146,10 +275,16 @@ impl Block {
         // Calculate Merkle root for the transactions
         let merkle_root = Self::calculate_merkle_root(transactions)?;
 
+        // No proof-of-work here, but the hash still has to be unique so test blocks can be
+        // stored side by side in a database
+        let mut hash_input = format!("{pre_block_hash}:{height}:{timestamp}:").into_bytes();
+        hash_input.extend_from_slice(&merkle_root);
+        let hash = data_encoding::HEXLOWER.encode(&crate::utils::sha256_digest(&hash_input));
+
         Ok(Block {
             timestamp,
             pre_block_hash,
-            hash: "test_hash".to_string(), // Test hash
+            hash,
             transactions: transactions.to_vec(),
             nonce: 0,
             height,
@@ -158,6 +293,21 @@ impl Block {
         })
     }
 
+    /// Overwrite the nonce, invalidating the proof of work (for testing only)
+    #[cfg(test)]
+    pub fn set_nonce(&mut self, nonce: i64) {
+        self.nonce = nonce;
+    }
+
+    /// Swap in `transactions` and the Merkle root they give, invalidating the proof of work
+    /// (for testing only)
+    #[cfg(test)]
+    pub fn set_transactions(&mut self, transactions: &[Transaction]) -> Result<()> {
+        self.merkle_root = Self::calculate_merkle_root(transactions)?;
+        self.transactions = transactions.to_vec();
+        Ok(())
+    }
+
     /// Calculate Merkle root for a list of transactions
     fn calculate_merkle_root(transactions: &[Transaction]) -> Result<Vec<u8>> {
         let transaction_hashes: Vec<Vec<u8>> =
@@ -199,7 +349,7 @@ impl Block {
     }
 
     // I need to validate that a block meets all the constraints I've set
-    fn validate_block_constraints(transactions: &[Transaction]) -> Result<()> {
+    pub(crate) fn validate_block_constraints(transactions: &[Transaction]) -> Result<()> {
         // Check transaction count limit
         if transactions.len() > MAX_TRANSACTIONS_PER_BLOCK {
             return Err(BlockchainError::InvalidBlock(format!(
@@ -222,6 +372,11 @@ impl Block {
             }
 
             total_size += tx_size;
+
+            // No output can be worth more coins than will ever exist
+            transaction.check_money_range().map_err(|e| {
+                BlockchainError::InvalidBlock(format!("Transaction {i} breaks the supply cap: {e}"))
+            })?;
         }
 
         // Check total block size
@@ -235,9 +390,22 @@ impl Block {
     }
 
     // I need to validate a complete block including timestamp and other rules
-    pub fn validate_block(&self, prev_block_timestamp: Option<i64>) -> Result<bool> {
+    //
+    // `median_time_past` is that of the parent, see `Blockchain::median_time_past`; None for
+    // a genesis block. The future limit is checked against the local clock, see
+    // `validate_block_at` for a node that knows the network's
+    pub fn validate_block(&self, median_time_past: Option<i64>) -> Result<bool> {
+        self.validate_block_at(median_time_past, current_timestamp()?)
+    }
+
+    // Same as `validate_block`, with `network_time` the node's network-adjusted time
+    pub(crate) fn validate_block_at(
+        &self,
+        median_time_past: Option<i64>,
+        network_time: i64,
+    ) -> Result<bool> {
         // Validate timestamp
-        if !self.validate_timestamp(prev_block_timestamp)? {
+        if !self.validate_timestamp(median_time_past, network_time)? {
             return Ok(false);
         }
 
@@ -273,10 +441,26 @@ impl Block {
         Ok(true)
     }
 
-    // I need to validate the block timestamp to prevent time-based attacks
-    fn validate_timestamp(&self, prev_block_timestamp: Option<i64>) -> Result<bool> {
-        let current_time = current_timestamp()?;
+    /// The part of `validate_block` a pruned block still allows: timestamp and proof of work
+    ///
+    /// The merkle root can't be recomputed without the transactions, but it is part of the
+    /// hashed header, so the proof of work still covers it.
+    pub fn validate_pruned_header(&self, median_time_past: Option<i64>) -> Result<bool> {
+        if !self.validate_timestamp(median_time_past, current_timestamp()?)? {
+            return Ok(false);
+        }
+        if !ProofOfWork::validate(self) {
+            log::error!("Block proof of work validation failed");
+            return Ok(false);
+        }
+        Ok(true)
+    }
 
+    // I need to validate the block timestamp to prevent time-based attacks
+    //
+    // `current_time` is the node's network-adjusted time when it has one, so a node whose
+    // own clock is off still agrees with its peers
+    fn validate_timestamp(&self, median_time_past: Option<i64>, current_time: i64) -> Result<bool> {
         // Block timestamp cannot be too far in the future
         if self.timestamp > current_time + MAX_FUTURE_TIME {
             log::error!(
@@ -288,15 +472,14 @@ impl Block {
             return Ok(false);
         }
 
-        // Block timestamp must be after previous block (if provided)
-        // I'll be more lenient here to maintain compatibility with existing tests
-        if let Some(prev_timestamp) = prev_block_timestamp {
-            // Allow equal timestamps for compatibility, but warn about it
-            if self.timestamp < prev_timestamp {
+        // Miners' clocks disagree a little, so a block only has to be no older than the
+        // median of the blocks before it rather than its parent
+        if let Some(median_time_past) = median_time_past {
+            if self.timestamp < median_time_past {
                 log::error!(
-                    "Block timestamp must not be before previous block: {} < {}",
+                    "Block timestamp must not be before the median time past: {} < {}",
                     self.timestamp,
-                    prev_timestamp
+                    median_time_past
                 );
                 return Ok(false);
             }
@@ -311,16 +494,16 @@ impl Block {
     }
 
     // I want to be able to get the total transaction fees in this block
-    pub fn get_total_fees(&self) -> u64 {
+    pub fn get_total_fees(&self) -> Amount {
         self.transactions
             .iter()
             .skip(1) // Skip coinbase transaction
             .map(|tx| tx.get_fee())
-            .sum()
+            .fold(Amount::ZERO, Amount::saturating_add)
     }
 
     // I want to validate that coinbase reward is correct
-    pub fn validate_coinbase_reward(&self, expected_reward: u64) -> Result<bool> {
+    pub fn validate_coinbase_reward(&self, expected_reward: Amount) -> Result<bool> {
         if self.transactions.is_empty() {
             return Err(BlockchainError::InvalidBlock(
                 "Block has no transactions".to_string(),
@@ -334,20 +517,17 @@ impl Block {
             ));
         }
 
-        let coinbase_value = coinbase.get_output_value()?;
-        if coinbase_value != expected_reward {
-            log::error!("Invalid coinbase reward: {coinbase_value} (expected: {expected_reward})");
-            return Ok(false);
-        }
-
-        Ok(true)
+        // Outputs too large to add up can't be any reward
+        coinbase.get_output_value()?;
+        Ok(coinbase.verify_coinbase_reward(expected_reward))
     }
 }
 
 impl From<Block> for IVec {
     fn from(b: Block) -> Self {
-        let bytes =
-            serialize(&b).expect("Block serialization should never fail for IVec conversion");
+        let bytes = b
+            .serialize()
+            .expect("Block serialization should never fail for IVec conversion");
         Self::from(bytes)
     }
 }
@@ -0,0 +1,363 @@
+//! Picking the transactions of the next block
+//!
+//! A block over `MAX_BLOCK_SIZE` or `MAX_TRANSACTIONS_PER_BLOCK` is only refused once its
+//! proof-of-work is done, so everything that mines from a memory pool asks a
+//! `BlockAssembler` what to include first. It takes the highest fee rates first and counts
+//! every transaction's serialized size, plus `TRANSACTION_OVERHEAD`, against a size target
+//! that leaves room for the coinbase. Transactions that don't fit stay in the pool for the
+//! next block.
+//!
+//! Every transaction in a block is checked against the chain below it, so one spending an
+//! output of another pending transaction can't share its parent's block. Such children
+//! are held back until the parent is mined.
+
+use crate::core::block::MAX_TRANSACTIONS_PER_BLOCK;
+use crate::core::{Blockchain, Transaction, MAX_BLOCK_SIZE, MAX_TRANSACTION_SIZE};
+use crate::error::Result;
+use std::collections::HashSet;
+
+/// How much of a block the assembler fills unless told otherwise, a little under
+/// `MAX_BLOCK_SIZE`
+pub const DEFAULT_BLOCK_SIZE_TARGET: usize = MAX_BLOCK_SIZE / 100 * 99;
+/// Bytes counted for each transaction on top of its own serialization
+pub const TRANSACTION_OVERHEAD: usize = 8;
+
+/// Chooses which pending transactions go into the next block on `blockchain`
+pub struct BlockAssembler<'a> {
+    blockchain: &'a Blockchain,
+    size_target: usize,
+    max_transactions: usize,
+}
+
+impl<'a> BlockAssembler<'a> {
+    pub fn new(blockchain: &'a Blockchain) -> BlockAssembler<'a> {
+        BlockAssembler {
+            blockchain,
+            size_target: DEFAULT_BLOCK_SIZE_TARGET,
+            max_transactions: MAX_TRANSACTIONS_PER_BLOCK,
+        }
+    }
+
+    /// Fill blocks up to `bytes`, coinbase included; never more than `MAX_BLOCK_SIZE`
+    pub fn with_size_target(mut self, bytes: usize) -> Self {
+        self.size_target = bytes.min(MAX_BLOCK_SIZE);
+        self
+    }
+
+    /// Put at most `count` transactions in a block, coinbase included; never more than
+    /// the consensus limit
+    pub fn with_max_transactions(mut self, count: usize) -> Self {
+        self.max_transactions = count.min(MAX_TRANSACTIONS_PER_BLOCK);
+        self
+    }
+
+    pub fn size_target(&self) -> usize {
+        self.size_target
+    }
+
+    /// The transactions among `candidates` for a block paying `miner_address`, in block
+    /// order
+    ///
+    /// Candidates that no longer verify or spend an output another one already took are
+    /// left out, as are coinbases.
+    pub fn select(
+        &self,
+        miner_address: &str,
+        candidates: Vec<Transaction>,
+    ) -> Result<Vec<Transaction>> {
+        self.select_after(miner_address, &[], candidates)
+    }
+
+    /// Like `select`, for a block that starts with `chosen`
+    ///
+    /// `chosen` count against the limits but are not checked themselves. Candidates
+    /// spending the same outputs as them, or outputs they create, are left out.
+    pub fn select_after(
+        &self,
+        miner_address: &str,
+        chosen: &[Transaction],
+        candidates: Vec<Transaction>,
+    ) -> Result<Vec<Transaction>> {
+        let coinbase_size = Transaction::new_coinbase_tx_with_reward(miner_address, u64::MAX)?
+            .serialize()?
+            .len();
+        let mut used = coinbase_size + TRANSACTION_OVERHEAD;
+        for tx in chosen {
+            used += tx.serialize()?.len() + TRANSACTION_OVERHEAD;
+        }
+        let mut remaining = self.size_target.saturating_sub(used);
+        let mut room = self.max_transactions.saturating_sub(1 + chosen.len());
+
+        let pending: HashSet<&[u8]> = chosen
+            .iter()
+            .chain(&candidates)
+            .map(|tx| tx.get_id())
+            .collect();
+        let mut ranked = Vec::with_capacity(candidates.len());
+        for tx in &candidates {
+            if tx.is_coinbase() || self.has_pending_parent(tx, &pending)? {
+                continue;
+            }
+            ranked.push((tx.serialize()?.len(), tx));
+        }
+        // By fee rate, comparing fee * size crosswise so rates stay exact
+        ranked.sort_by(|(a_size, a), (b_size, b)| {
+            (u128::from(b.get_fee().to_satoshis()) * *a_size as u128)
+                .cmp(&(u128::from(a.get_fee().to_satoshis()) * *b_size as u128))
+                .then_with(|| a.get_id().cmp(b.get_id()))
+        });
+
+        let mut selected: Vec<Transaction> = Vec::new();
+        for (size, tx) in ranked {
+            if room == 0 {
+                break;
+            }
+            let counted = size + TRANSACTION_OVERHEAD;
+            if counted > remaining || size > MAX_TRANSACTION_SIZE {
+                continue;
+            }
+            // A replacement and the transaction it replaced can both still be candidates;
+            // the better paying one came first
+            if chosen
+                .iter()
+                .chain(&selected)
+                .any(|other| tx.conflicts_with(other))
+                || !tx.verify(self.blockchain)
+            {
+                continue;
+            }
+            remaining -= counted;
+            room -= 1;
+            selected.push(tx.clone());
+        }
+        Ok(selected)
+    }
+
+    // Whether `tx` spends an output of a transaction in `pending` that isn't on the chain
+    fn has_pending_parent(&self, tx: &Transaction, pending: &HashSet<&[u8]>) -> Result<bool> {
+        for input in tx.get_vin() {
+            if pending.contains(input.get_txid())
+                && !self
+                    .blockchain
+                    .is_output_unspent(input.get_txid(), input.get_vout())?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, FeePriority, NodeContext, TXOutput};
+    use crate::storage::{MemoryPool, UTXOSet};
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use data_encoding::HEXLOWER;
+    use tempfile::tempdir;
+
+    const MINER: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    // Mine `transactions` on top of the tip, keeping the chainstate and `pool` in step
+    fn mine(blockchain: &Blockchain, pool: &MemoryPool, transactions: &[Transaction]) {
+        let context = NodeContext::new(FeeMode::Fixed { amount: 1 }).unwrap();
+        let block = blockchain
+            .mine_block_with_context(transactions, MINER, &context)
+            .unwrap();
+        UTXOSet::new(blockchain.clone())
+            .update_safe(&block)
+            .unwrap();
+        for tx in transactions {
+            pool.remove(&HEXLOWER.encode(tx.get_id()));
+        }
+    }
+
+    fn fees(transactions: &[Transaction]) -> Vec<u64> {
+        transactions
+            .iter()
+            .map(|tx| tx.get_fee().to_satoshis())
+            .collect()
+    }
+
+    #[test]
+    fn test_overfull_pool_spreads_over_blocks_by_fee_rate() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let senders: Vec<String> = (0..4).map(|_| wallets.create_wallet().unwrap()).collect();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &senders[0],
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let funding = NodeContext::new(FeeMode::Fixed { amount: 1 }).unwrap();
+        for sender in &senders[1..] {
+            blockchain.generate_blocks(1, sender, &funding).unwrap();
+        }
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let pool = MemoryPool::new();
+        for (sender, fee) in senders.iter().zip([10, 40, 20, 30]) {
+            let context = NodeContext::new(FeeMode::Fixed { amount: fee }).unwrap();
+            let tx = Transaction::new_utxo_transaction_with_context(
+                sender,
+                MINER,
+                1_000,
+                FeePriority::Normal,
+                &utxo_set,
+                &context,
+            )
+            .unwrap();
+            pool.add(tx);
+        }
+
+        // Room for the coinbase and two of the four payments
+        let tx_size = pool.get_all()[0].serialize().unwrap().len();
+        let coinbase_size = Transaction::new_coinbase_tx_with_reward(MINER, u64::MAX)
+            .unwrap()
+            .serialize()
+            .unwrap()
+            .len();
+        let assembler = BlockAssembler::new(&blockchain).with_size_target(
+            coinbase_size + TRANSACTION_OVERHEAD + 2 * (tx_size + TRANSACTION_OVERHEAD) + 1,
+        );
+
+        let first = assembler.select(MINER, pool.get_all()).unwrap();
+        assert_eq!(fees(&first), vec![40, 30]);
+        mine(&blockchain, &pool, &first);
+        let second = assembler.select(MINER, pool.get_all()).unwrap();
+        assert_eq!(fees(&second), vec![20, 10]);
+        mine(&blockchain, &pool, &second);
+        assert!(pool.is_empty());
+        assert_eq!(blockchain.get_best_height().unwrap(), 5);
+
+        // The transaction count is capped the same way, the coinbase taking one slot
+        pool.add(
+            Transaction::new_utxo_transaction_with_context(
+                &senders[1],
+                MINER,
+                1_000,
+                FeePriority::Normal,
+                &utxo_set,
+                &funding,
+            )
+            .unwrap(),
+        );
+        let capped = BlockAssembler::new(&blockchain).with_max_transactions(1);
+        assert!(capped.select(MINER, pool.get_all()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_child_waits_for_its_parent_to_be_mined() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let middle = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &sender,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe().unwrap();
+
+        let context = NodeContext::new(FeeMode::Fixed { amount: 5 }).unwrap();
+        let parent = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &middle,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        // Spends the parent's payment at a far higher fee rate
+        let mut child = Transaction::new_raw(
+            &[(parent.get_id().to_vec(), 0)],
+            vec![TXOutput::new(900, MINER).unwrap()],
+        )
+        .unwrap();
+        child
+            .sign_with_outputs(
+                wallets.get_wallet(&middle).unwrap(),
+                &parent.get_vout()[..1],
+            )
+            .unwrap();
+
+        // Listed child first, as a pool might hand them out
+        let pool = MemoryPool::new();
+        pool.add(child.clone());
+        pool.add(parent.clone());
+        let assembler = BlockAssembler::new(&blockchain);
+        let first = assembler.select(MINER, pool.get_all()).unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].get_id(), parent.get_id());
+        mine(&blockchain, &pool, &first);
+
+        let second = assembler.select(MINER, pool.get_all()).unwrap();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].get_id(), child.get_id());
+        mine(&blockchain, &pool, &second);
+        assert_eq!(blockchain.get_best_height().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_replaced_spend_of_a_confirmed_payment_is_left_out() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let middle = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &sender,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe().unwrap();
+        let context = NodeContext::new(FeeMode::Fixed { amount: 5 }).unwrap();
+        let pay = |from: &str, to: &str, amount: u64| {
+            Transaction::new_utxo_transaction_with_context(
+                from,
+                to,
+                amount,
+                FeePriority::Normal,
+                &utxo_set,
+                &context,
+            )
+            .unwrap()
+        };
+
+        // `middle` ends up holding an ordinary payment output rather than a coinbase
+        let pool = MemoryPool::new();
+        let payment = pay(&sender, &middle, 5_000);
+        mine(&blockchain, &pool, std::slice::from_ref(&payment));
+        let original = pay(&middle, MINER, 1_000);
+        assert_eq!(original.get_vin()[0].get_txid(), payment.get_id());
+        let replacement = original
+            .bump_fee(original.get_fee().to_satoshis() + 20, &blockchain, &wallets)
+            .unwrap();
+        assert!(replacement.conflicts_with(&original));
+
+        pool.add_safe(original.clone()).unwrap();
+        assert_eq!(pool.add_safe(replacement.clone()).unwrap().len(), 1);
+        assert_eq!(pool.len(), 1);
+
+        // Even handed both, as a pool reloaded from an older save might, only one goes in
+        let assembler = BlockAssembler::new(&blockchain);
+        let candidates = vec![original.clone(), replacement.clone()];
+        let selected = assembler.select(MINER, candidates).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].get_id(), replacement.get_id());
+        let selected = assembler
+            .select_after(MINER, &[original], vec![replacement])
+            .unwrap();
+        assert!(selected.is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+//! Work handed out to miners outside the node
+//!
+//! `Blockchain::build_block_template` picks transactions from a memory pool, pays the
+//! miner with a coinbase and fixes everything the proof-of-work hashes except the nonce.
+//! An external miner searches for a nonce that meets `difficulty` and hands the template
+//! back with it, or the finished block, to `Blockchain::submit_block`.
+
+use crate::core::{Amount, Block, ProofOfWork, Transaction};
+use crate::error::{BlockchainError, Result};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The next block on top of a tip, complete but for its nonce
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct BlockTemplate {
+    pub pre_block_hash: String,
+    pub height: usize,
+    pub difficulty: u32,
+    /// Milliseconds since the epoch; part of the hashed header, so it can't be changed
+    pub timestamp: i64,
+    /// Pays the miner the block subsidy plus `total_fees`
+    pub coinbase: Transaction,
+    /// The transactions after the coinbase, in block order
+    pub transactions: Vec<Transaction>,
+    pub total_fees: Amount,
+    pub merkle_root: Vec<u8>,
+}
+
+impl BlockTemplate {
+    pub(crate) fn from_block(block: &Block) -> Result<BlockTemplate> {
+        let (coinbase, transactions) = block.get_transactions().split_first().ok_or_else(|| {
+            BlockchainError::InvalidBlock("Block template has no coinbase".to_string())
+        })?;
+        Ok(BlockTemplate {
+            pre_block_hash: block.get_pre_block_hash(),
+            height: block.get_height(),
+            difficulty: block.get_difficulty(),
+            timestamp: block.get_timestamp(),
+            coinbase: coinbase.clone(),
+            transactions: transactions.to_vec(),
+            total_fees: block.get_total_fees(),
+            merkle_root: block.get_merkle_root().to_vec(),
+        })
+    }
+
+    /// The block this template describes, with `nonce` and the hash it produces
+    ///
+    /// Whether the hash meets the difficulty target is checked on submission, so a miner
+    /// can call this for every nonce it tries.
+    pub fn solve(&self, nonce: i64) -> Result<Block> {
+        let mut transactions = Vec::with_capacity(self.transactions.len() + 1);
+        transactions.push(self.coinbase.clone());
+        transactions.extend_from_slice(&self.transactions);
+
+        let mut block = Block::new_template_at(
+            self.timestamp,
+            self.pre_block_hash.clone(),
+            &transactions,
+            self.height,
+            self.difficulty,
+        )?;
+        if block.get_merkle_root() != self.merkle_root.as_slice() {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Merkle root of the template at height {} does not match its transactions",
+                self.height
+            )));
+        }
+
+        block.set_proof(nonce, String::new());
+        let hash = ProofOfWork::compute_hash(&block);
+        block.set_proof(nonce, hash);
+        Ok(block)
+    }
+}
+
+impl fmt::Display for BlockTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Block template for height {}", self.height)?;
+        writeln!(f, "  Previous block: {}", self.pre_block_hash)?;
+        writeln!(f, "  Difficulty:     {}", self.difficulty)?;
+        writeln!(f, "  Timestamp:      {}", self.timestamp)?;
+        writeln!(
+            f,
+            "  Merkle root:    {}",
+            HEXLOWER.encode(&self.merkle_root)
+        )?;
+        writeln!(
+            f,
+            "  Coinbase:       {} paying {} satoshis",
+            HEXLOWER.encode(self.coinbase.get_id()),
+            self.coinbase
+                .get_vout()
+                .iter()
+                .map(|o| o.get_value())
+                .fold(Amount::ZERO, Amount::saturating_add)
+                .to_satoshis()
+        )?;
+        writeln!(
+            f,
+            "  Transactions:   {} paying {} satoshis in fees",
+            self.transactions.len(),
+            self.total_fees.to_satoshis()
+        )?;
+        for tx in &self.transactions {
+            writeln!(
+                f,
+                "    {} (fee {})",
+                HEXLOWER.encode(tx.get_id()),
+                tx.get_fee().to_satoshis()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A block found from a template: either the template and its winning nonce, or the
+/// block the miner assembled itself
+#[derive(Debug, Clone)]
+pub enum BlockSubmission {
+    Solved { template: BlockTemplate, nonce: i64 },
+    Block(Block),
+}
+
+impl BlockSubmission {
+    pub fn into_block(self) -> Result<Block> {
+        match self {
+            BlockSubmission::Solved { template, nonce } => template.solve(nonce),
+            BlockSubmission::Block(block) => Ok(block),
+        }
+    }
+}
+
+impl From<Block> for BlockSubmission {
+    fn from(block: Block) -> Self {
+        BlockSubmission::Block(block)
+    }
+}
+
+impl From<(BlockTemplate, i64)> for BlockSubmission {
+    fn from((template, nonce): (BlockTemplate, i64)) -> Self {
+        BlockSubmission::Solved { template, nonce }
+    }
+}
@@ -2,19 +2,188 @@
 // I'm using Sled as an embedded database to store blocks and maintain the chain
 // The blockchain follows Bitcoin's design with UTXO model and proof-of-work consensus
 
-use crate::core::{Block, DifficultyAdjustment, FeeCalculator, TXOutput, Transaction};
-use crate::error::{BlockchainError, Result};
+use crate::config::GLOBAL_CONFIG;
+use crate::core::address_index::{self, AddressIndex, AddressTx, RescanReport, UsageStats};
+use crate::core::chain_stats::{self, ChainStats};
+use crate::core::checkpoints::{self, Checkpoint};
+use crate::core::fees::{BlockFeeRecord, FeeHistory, UnifiedFeeCalculator};
+use crate::core::hashrate::{self, DifficultyReport};
+use crate::core::mining_stats::{self, MiningReport, MiningStats};
+use crate::core::monetary;
+use crate::core::spend_view::SpendView;
+use crate::core::verify::{self, ChainVerificationReport};
+use crate::core::watch;
+use crate::core::{
+    work_for_difficulty, Amount, Block, BlockAssembler, BlockHeader, BlockSubmission,
+    BlockTemplate, ChainEvent, DifficultyAdjustment, EventBus, FeeCalculator, GenesisConfig,
+    Network, NetworkMagic, NodeContext, PowSolution, ProofOfWork, RetargetRules, TXOutput,
+    Transaction, MEDIAN_TIME_SPAN,
+};
+use crate::error::{BlockchainError, Result, STALE_TIP};
+use crate::network::metrics::METRICS;
+use crate::storage::utxo_set::{
+    chainstate_entries, decode_entry, CHAINSTATE_VERSION, CHAINSTATE_VERSION_KEY, UTXO_TREE,
+};
+use crate::storage::{MemoryPool, UTXOSet};
+use crate::utils::serialization::format_version;
+use crate::utils::{deserialize, network_adjusted_time, serialize, FORMAT_VERSION};
 use data_encoding::HEXLOWER;
-use log::info;
-use sled::{Db, Tree};
-use std::collections::HashMap;
-use std::env::current_dir;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use log::{info, warn};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, Transactional, Tree};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 
 // I use these constants to organize my database storage
 const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash"; // Key to store the hash of the latest block
 const BLOCKS_TREE: &str = "blocks"; // Tree name for storing all blocks
+const BLOCK_HEIGHTS_TREE: &str = "block_heights"; // Main-chain index: height -> block hash
+const CHECKPOINTS_TREE: &str = "checkpoints"; // Checkpoints added at runtime: height -> block hash
+const CHAIN_WORK_TREE: &str = "chain_work"; // Cumulative work: block hash -> big-endian u128
+const TX_INDEX_TREE: &str = "tx_index"; // Main-chain transactions: txid -> IndexedTransaction
+const HEADERS_TREE: &str = "headers"; // Pruned blocks: block hash -> BlockHeader
+const PRUNED_TXIDS_TREE: &str = "pruned_txids"; // Forgotten transactions: txid -> block hash
+const BLOCK_FORMAT_KEY: &str = "block_format_version"; // Format `migratedb` last rewrote blocks in
+const SNAPSHOT_HEIGHT_KEY: &str = "snapshot_height"; // Height `importsnapshot` loaded the chainstate at
+pub(crate) const NETWORK_KEY: &str = "network"; // Name of the network the database was created for
+const NETWORK_MAGIC_KEY: &str = "network_magic"; // That network's magic, see `core::network`
+
+/// Blocks with an unknown parent kept in memory until the parent arrives
+///
+/// Past this, the oldest orphan is dropped to make room; peers resend whatever is still
+/// missing once the gap is filled.
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+
+/// Blocks below the tip that pruning always keeps, whatever it's asked for
+///
+/// Coinbase maturity isn't enforced yet, but the youngest coinbases and the blocks a short
+/// reorg would replace stay whole.
+pub const COINBASE_MATURITY_WINDOW: usize = 10;
+
+/// Confirmations after which a transaction counts as final, unless configured otherwise
+pub const DEFAULT_FINALITY_DEPTH: usize = 6;
+
+/// Where a transaction stands on the main chain, as `gettransaction` reports it
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+pub struct TransactionStatus {
+    /// Hash of the main-chain block holding it; `None` while it isn't on the main chain
+    pub block: Option<String>,
+    pub confirmations: Option<u64>,
+    /// Blocks still to come before it is final
+    pub blocks_to_final: Option<u64>,
+}
+
+impl TransactionStatus {
+    pub fn is_final(&self) -> bool {
+        self.blocks_to_final == Some(0)
+    }
+}
+
+// What the transaction index keeps for every main-chain transaction. The transaction is
+// stored whole so a lookup never has to read its block; the block hash tells which block
+// put it there when blocks leave the main chain.
+#[derive(serde::Serialize, serde::Deserialize, bincode::Encode, bincode::Decode)]
+struct IndexedTransaction {
+    block_hash: String,
+    transaction: Transaction,
+}
+
+/// Batches of at most this many transactions are verified on the calling thread, where
+/// spawning threads would cost more than it saves
+pub const PARALLEL_VERIFICATION_THRESHOLD: usize = 4;
+
+#[cfg(test)]
+thread_local! {
+    // Blocks read by chain walks on this thread, so tests can tell a lookup from a scan
+    static BLOCKS_ITERATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    // Batches this thread handed to verification threads
+    static PARALLEL_VERIFICATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// The first transaction of a batch that failed verification, and what the lookups found
+// wrong with it; checks that only log leave no reason
+#[derive(Debug, PartialEq, Eq)]
+struct InvalidTransaction {
+    index: usize,
+    reason: Option<String>,
+}
+
+impl std::fmt::Display for InvalidTransaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transaction {} failed verification", self.index)?;
+        if let Some(reason) = &self.reason {
+            write!(f, ": {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+// How the main chain moved when the tip changed: the hashes of the blocks that joined it,
+// lowest first, and the hash and height of those that left it, highest first
+#[derive(Default)]
+struct TipChange {
+    connected: Vec<String>,
+    disconnected: Vec<(String, usize)>,
+}
+
+// This is what happened to a block handed to validate_and_add_block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockAddResult {
+    /// The block extended the best chain and is the new tip
+    TipAdvanced,
+    /// The block was valid and stored, but on a side branch
+    ForkStored,
+    /// The block was already in the database
+    AlreadyKnown,
+    /// The block's parent is unknown, so it waits in memory until the parent is stored
+    Orphan,
+}
+
+// What blocks are checked with: the fee calculator their coinbase reward comes from, the
+// threads their transactions are verified on and how far the network's clock is from ours
+struct BlockChecks {
+    fee_calculator: UnifiedFeeCalculator,
+    threads: usize,
+    time_offset: i64,
+}
+
+impl BlockChecks {
+    // A running node's own
+    fn of(context: &NodeContext) -> BlockChecks {
+        BlockChecks {
+            fee_calculator: context.fee_calculator(),
+            threads: context.verification_threads(),
+            time_offset: context.network_time_offset(),
+        }
+    }
+
+    // The global ones, for callers without a node
+    fn global() -> BlockChecks {
+        BlockChecks {
+            fee_calculator: FeeCalculator::snapshot(),
+            threads: GLOBAL_CONFIG
+                .get_verify_threads()
+                .unwrap_or_else(Blockchain::default_verification_threads),
+            // Without peers there is no network clock to follow
+            time_offset: 0,
+        }
+    }
+}
+
+/// Transactions of blocks that left the main chain, see `Blockchain::queue_reorged_in`
+pub type ReorgQueue = Arc<Mutex<Vec<Transaction>>>;
 
 // This is my main blockchain structure that holds the entire chain state
 #[derive(Clone)]
@@ -23,6 +192,27 @@ pub struct Blockchain {
     tip_hash: Arc<RwLock<String>>, // Hash of the most recent block in the chain
     db: Db,                        // The Sled database instance that stores all my blocks
     db_path: PathBuf,              // Path to the database file on disk
+    // When set, I skip signature checks for blocks below the latest checkpoint
+    assume_valid_below_checkpoints: Arc<AtomicBool>,
+    // Shell command that gets every payment notification, see `core::watch`
+    notify_command: Arc<RwLock<Option<String>>>,
+    // How many blocks below the tip keep their bodies when pruning is on
+    prune_keep_blocks: Arc<RwLock<Option<usize>>>,
+    // Confirmations that make a transaction final; the configured depth when unset
+    finality_depth: Arc<RwLock<Option<usize>>>,
+    // Development chains mine and validate every block at this difficulty
+    forced_difficulty: Arc<RwLock<Option<u32>>>,
+    // Where blocks joining and leaving the main chain are announced, see `core::events`
+    events: EventBus,
+    // Blocks waiting for their parent, oldest first, see `MAX_ORPHAN_BLOCKS`
+    orphans: Arc<Mutex<Vec<Block>>>,
+    // The unspent outputs at the last block validated, see `SpendView`
+    spend_view: Arc<Mutex<Option<SpendView>>>,
+    // Where the fees of blocks joining the main chain are recorded, see `record_fees_in`
+    fee_history: Arc<RwLock<Option<Arc<RwLock<FeeHistory>>>>>,
+    // Where the transactions of blocks leaving the main chain are queued, see
+    // `queue_reorged_in`
+    reorged_out: Arc<RwLock<Option<ReorgQueue>>>,
 }
 
 impl Blockchain {
@@ -51,15 +241,21 @@ impl Blockchain {
         Self::new_blockchain_with_path(&db_path)
     }
 
-    // I use this to get the default database path (./data/)
-    fn default_db_path() -> Result<String> {
-        Ok(current_dir()?.join("data").to_string_lossy().to_string())
+    // I use this to get the default database path (<datadir>/data/, the datadir being the
+    // working directory unless --datadir or ARCHITECT_DATADIR says otherwise)
+    pub fn default_db_path() -> Result<String> {
+        Ok(GLOBAL_CONFIG
+            .data_dir()?
+            .join("data")
+            .to_string_lossy()
+            .to_string())
     }
 
-    // I use this to get a node-specific database path (./data/node_2001/)
+    // I use this to get a node-specific database path (<datadir>/data/node_2001/)
     // This allows multiple nodes to run on the same machine with isolated databases
-    fn node_db_path(node_id: &str) -> Result<String> {
-        Ok(current_dir()?
+    pub fn node_db_path(node_id: &str) -> Result<String> {
+        Ok(GLOBAL_CONFIG
+            .data_dir()?
             .join("data")
             .join(format!("node_{node_id}"))
             .to_string_lossy()
@@ -70,8 +266,7 @@ impl Blockchain {
     pub fn create_blockchain_with_path(genesis_address: &str, db_path: &str) -> Result<Blockchain> {
         let path = PathBuf::from(db_path);
         // I open the Sled database at the specified path
-        let db = sled::open(&path)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open database: {e}")))?;
+        let db = Self::open_database(&path)?;
         // I create a tree specifically for storing blocks
         let blocks_tree = db
             .open_tree(BLOCKS_TREE)
@@ -96,17 +291,150 @@ impl Blockchain {
         };
 
         // I return the new blockchain instance
-        Ok(Blockchain {
-            tip_hash: Arc::new(RwLock::new(tip_hash)),
+        let blockchain = Blockchain {
+            tip_hash: Arc::new(RwLock::new(tip_hash.clone())),
+            db,
+            db_path: path,
+            assume_valid_below_checkpoints: Arc::new(AtomicBool::new(false)),
+            notify_command: Arc::new(RwLock::new(None)),
+            prune_keep_blocks: Arc::new(RwLock::new(None)),
+            finality_depth: Arc::new(RwLock::new(None)),
+            forced_difficulty: Arc::new(RwLock::new(None)),
+            events: EventBus::new(),
+            orphans: Arc::new(Mutex::new(Vec::new())),
+            spend_view: Arc::new(Mutex::new(None)),
+            fee_history: Arc::new(RwLock::new(None)),
+            reorged_out: Arc::new(RwLock::new(None)),
+        };
+        blockchain.open_on_network(&tip_hash, GLOBAL_CONFIG.get_network())?;
+        Ok(blockchain)
+    }
+
+    // When I'm bootstrapping from an exported chain file, the database has to start from that
+    // file's genesis block instead of a freshly mined one, otherwise nothing would link up
+    pub fn create_blockchain_from_genesis(
+        genesis: &Block,
+        db_path: &str,
+        force: bool,
+    ) -> Result<Blockchain> {
+        let path = PathBuf::from(db_path);
+        let db = Self::open_database(&path)?;
+        let blocks_tree = db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+
+        let existing_tip = blocks_tree
+            .get(TIP_BLOCK_HASH_KEY)
+            .map_err(|e| BlockchainError::Database(format!("Failed to get tip hash: {e}")))?;
+
+        if existing_tip.is_some() {
+            if !force {
+                return Err(BlockchainError::Database(format!(
+                    "Database at {db_path} already contains a blockchain (use --force to overwrite)"
+                )));
+            }
+            // I only wipe the old chain when the caller explicitly asked for it
+            info!("Clearing existing blockchain at {db_path} before import");
+            blocks_tree.clear().map_err(|e| {
+                BlockchainError::Database(format!("Failed to clear blocks tree: {e}"))
+            })?;
+            // The old blocks are gone, so the height index can't tell which entries were theirs
+            for tree in [TX_INDEX_TREE, HEADERS_TREE, PRUNED_TXIDS_TREE] {
+                db.open_tree(tree)
+                    .and_then(|tree| tree.clear())
+                    .map_err(|e| {
+                        BlockchainError::Database(format!("Failed to clear {tree} tree: {e}"))
+                    })?;
+            }
+            db.remove(SNAPSHOT_HEIGHT_KEY).map_err(|e| {
+                BlockchainError::Database(format!("Failed to clear snapshot height: {e}"))
+            })?;
+            // The magic comes from the genesis block being replaced
+            for key in [NETWORK_KEY, NETWORK_MAGIC_KEY] {
+                db.remove(key).map_err(|e| {
+                    BlockchainError::Database(format!("Failed to clear {key}: {e}"))
+                })?;
+            }
+        }
+
+        Self::update_blocks_tree(&blocks_tree, genesis)?;
+
+        let blockchain = Blockchain {
+            tip_hash: Arc::new(RwLock::new(String::from(genesis.get_hash()))),
             db,
             db_path: path,
+            assume_valid_below_checkpoints: Arc::new(AtomicBool::new(false)),
+            notify_command: Arc::new(RwLock::new(None)),
+            prune_keep_blocks: Arc::new(RwLock::new(None)),
+            finality_depth: Arc::new(RwLock::new(None)),
+            forced_difficulty: Arc::new(RwLock::new(None)),
+            events: EventBus::new(),
+            orphans: Arc::new(Mutex::new(Vec::new())),
+            spend_view: Arc::new(Mutex::new(None)),
+            fee_history: Arc::new(RwLock::new(None)),
+            reorged_out: Arc::new(RwLock::new(None)),
+        };
+        blockchain.open_on_network(genesis.get_hash(), GLOBAL_CONFIG.get_network())?;
+        Ok(blockchain)
+    }
+
+    // When every node of a network has to start from the same genesis block, I build it
+    // from the shared configuration instead of mining a fresh one. An existing database is
+    // only opened if it started from that very block.
+    pub fn create_blockchain_with_genesis(config: &GenesisConfig) -> Result<Blockchain> {
+        Self::create_blockchain_with_genesis_path(config, &Self::default_db_path()?)
+    }
+
+    pub fn create_blockchain_with_genesis_path(
+        config: &GenesisConfig,
+        db_path: &str,
+    ) -> Result<Blockchain> {
+        let genesis = config.build_block()?;
+        if !Self::has_blockchain_at(db_path)? {
+            info!(
+                "Creating genesis block {} from its configuration",
+                genesis.get_hash()
+            );
+            return Self::create_blockchain_from_genesis(&genesis, db_path, false);
+        }
+
+        let blockchain = Self::new_blockchain_with_path(db_path)?;
+        let expected = blockchain.get_genesis_hash()?;
+        if genesis.get_hash() != expected {
+            return Err(BlockchainError::DifferentNetwork {
+                genesis: genesis.get_hash().to_string(),
+                expected,
+            });
+        }
+        Ok(blockchain)
+    }
+
+    // A database another handle holds is reported as locked straight away. sled has no
+    // read-only mode to share the database with a running node, so that report points at
+    // `--remote`, which asks the node instead.
+    fn open_database(path: impl AsRef<Path>) -> Result<Db> {
+        sled::open(path.as_ref()).map_err(|e| match e {
+            // sled only says so in the message
+            sled::Error::Io(e) if e.to_string().starts_with("could not acquire lock") => {
+                BlockchainError::DatabaseLocked(path.as_ref().display().to_string())
+            }
+            e => BlockchainError::Database(format!("Failed to open database: {e}")),
         })
     }
 
+    pub(crate) fn has_blockchain_at(db_path: &str) -> Result<bool> {
+        let db = Self::open_database(db_path)?;
+        let blocks_tree = db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+        blocks_tree
+            .contains_key(TIP_BLOCK_HASH_KEY)
+            .map_err(|e| BlockchainError::Database(format!("Failed to get tip hash: {e}")))
+    }
+
     pub fn new_blockchain_with_path(db_path: &str) -> Result<Blockchain> {
         let path = PathBuf::from(db_path);
-        let db = sled::open(&path)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open database: {e}")))?;
+        let db = Self::open_database(&path)?;
         let blocks_tree = db
             .open_tree(BLOCKS_TREE)
             .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
@@ -123,11 +451,94 @@ impl Blockchain {
         let tip_hash = String::from_utf8(tip_bytes.to_vec())
             .map_err(|e| BlockchainError::Database(format!("Invalid tip hash format: {e}")))?;
 
-        Ok(Blockchain {
-            tip_hash: Arc::new(RwLock::new(tip_hash)),
+        let blockchain = Blockchain {
+            tip_hash: Arc::new(RwLock::new(tip_hash.clone())),
             db,
             db_path: path,
-        })
+            assume_valid_below_checkpoints: Arc::new(AtomicBool::new(false)),
+            notify_command: Arc::new(RwLock::new(None)),
+            prune_keep_blocks: Arc::new(RwLock::new(None)),
+            finality_depth: Arc::new(RwLock::new(None)),
+            forced_difficulty: Arc::new(RwLock::new(None)),
+            events: EventBus::new(),
+            orphans: Arc::new(Mutex::new(Vec::new())),
+            spend_view: Arc::new(Mutex::new(None)),
+            fee_history: Arc::new(RwLock::new(None)),
+            reorged_out: Arc::new(RwLock::new(None)),
+        };
+        // Databases written before the height index existed get it built here on first open
+        blockchain.open_on_network(&tip_hash, GLOBAL_CONFIG.get_network())?;
+        Ok(blockchain)
+    }
+
+    // Refuses a database created for a network other than `network` before touching it.
+    // One that doesn't say which network it is for, a new one or one from before networks
+    // were recorded, is recorded as being for `network` once its indexes are up to date.
+    fn open_on_network(&self, tip_hash: &str, network: Network) -> Result<()> {
+        if let Some(recorded) = self.get_network()? {
+            if recorded != network {
+                return Err(BlockchainError::WrongNetwork {
+                    datadir: recorded.to_string(),
+                    expected: network.to_string(),
+                });
+            }
+            return self.update_indexes(tip_hash);
+        }
+        self.update_indexes(tip_hash)?;
+        self.record_network(network)
+    }
+
+    // Records the database as being for `network`, along with that network's magic
+    pub(crate) fn record_network(&self, network: Network) -> Result<()> {
+        let magic = network.magic(&self.get_genesis_hash()?);
+        self.db
+            .insert(NETWORK_KEY, network.name())
+            .and_then(|_| self.db.insert(NETWORK_MAGIC_KEY, &magic.0))
+            .map_err(|e| BlockchainError::Database(format!("Failed to record network: {e}")))?;
+        Ok(())
+    }
+
+    /// The network this database was created for, `None` until it is first opened by a
+    /// release that records it
+    pub fn get_network(&self) -> Result<Option<Network>> {
+        let recorded = self
+            .db
+            .get(NETWORK_KEY)
+            .map_err(|e| BlockchainError::Database(format!("Failed to read network: {e}")))?;
+        recorded
+            .map(|name| String::from_utf8_lossy(&name).parse())
+            .transpose()
+    }
+
+    /// The magic of the network this database was created for, see `get_network`
+    pub fn get_network_magic(&self) -> Result<Option<NetworkMagic>> {
+        let recorded = self
+            .db
+            .get(NETWORK_MAGIC_KEY)
+            .map_err(|e| BlockchainError::Database(format!("Failed to read network: {e}")))?;
+        Ok(recorded
+            .and_then(|bytes| bytes.as_ref().try_into().ok())
+            .map(NetworkMagic))
+    }
+
+    // Brings the height and transaction indexes up to date with `tip_hash` on open. Even a
+    // chain holding only its genesis block indexes the genesis coinbase, so an empty
+    // transaction index means the database predates it and it has to be built from scratch.
+    fn update_indexes(&self, tip_hash: &str) -> Result<()> {
+        // Blocks found here were already on disk, so nobody gets notified about them
+        self.update_height_index(tip_hash)?;
+        if self.open_tx_index_tree()?.is_empty() {
+            let count = self.reindex_transactions()?;
+            info!("Built the transaction index: {count} transactions");
+        }
+        // After the transaction index, which a pruned chain's chainstate is converted with
+        let utxo_set = UTXOSet::new(self.clone());
+        utxo_set.upgrade()?;
+        // The database is usable with a stale chainstate, which the node reindexes later
+        if let Err(e) = utxo_set.catch_up() {
+            warn!("Failed to bring the chainstate up to the tip: {e}");
+        }
+        Ok(())
     }
 
     fn update_blocks_tree(blocks_tree: &Tree, block: &Block) -> Result<()> {
@@ -170,20 +581,295 @@ impl Blockchain {
         *tip_hash = String::from(new_tip_hash)
     }
 
+    /// Skip transaction signature checks for blocks below the latest checkpoint
+    ///
+    /// Structure, proof-of-work, balances and double spends are still checked. This makes
+    /// syncing and importing a long chain much faster, at the price of trusting that the
+    /// checkpointed history was signed correctly.
+    pub fn set_assume_valid_below_checkpoints(&self, enabled: bool) {
+        self.assume_valid_below_checkpoints
+            .store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn assumes_valid_below_checkpoints(&self) -> bool {
+        self.assume_valid_below_checkpoints.load(Ordering::SeqCst)
+    }
+
+    /// Also pass every payment notification to `command`, run by the shell with the
+    /// notification as JSON on stdin
+    pub fn set_notify_command(&self, command: Option<String>) {
+        *self
+            .notify_command
+            .write()
+            .expect("Failed to acquire write lock on notify command - this should never happen") =
+            command;
+    }
+
+    pub fn get_notify_command(&self) -> Option<String> {
+        self.notify_command
+            .read()
+            .expect("Failed to acquire read lock on notify command - this should never happen")
+            .clone()
+    }
+
+    /// Keep only the bodies of the `keep_blocks` blocks below the tip, see `prune_blocks`
+    ///
+    /// `None` turns pruning off again; blocks already pruned stay pruned.
+    pub fn set_prune_keep_blocks(&self, keep_blocks: Option<usize>) {
+        *self
+            .prune_keep_blocks
+            .write()
+            .expect("Failed to acquire write lock on prune depth - this should never happen") =
+            keep_blocks;
+    }
+
+    pub fn get_prune_keep_blocks(&self) -> Option<usize> {
+        *self
+            .prune_keep_blocks
+            .read()
+            .expect("Failed to acquire read lock on prune depth - this should never happen")
+    }
+
+    /// Count transactions as final at `depth` confirmations instead of the configured depth
+    pub fn set_finality_depth(&self, depth: usize) {
+        *self
+            .finality_depth
+            .write()
+            .expect("Failed to acquire write lock on finality depth - this should never happen") =
+            Some(depth.max(1));
+    }
+
+    /// `node.finality_depth` from the config, `DEFAULT_FINALITY_DEPTH` if it gives none
+    pub fn get_finality_depth(&self) -> usize {
+        self.finality_depth
+            .read()
+            .expect("Failed to acquire read lock on finality depth - this should never happen")
+            .or_else(|| GLOBAL_CONFIG.get_finality_depth())
+            .unwrap_or(DEFAULT_FINALITY_DEPTH)
+    }
+
+    /// Use `difficulty` for every block instead of `DifficultyAdjustment`, for development
+    /// chains only
+    ///
+    /// Blocks are validated against the forced difficulty too, so a chain built this way
+    /// only checks out while it's forced.
+    pub fn set_force_difficulty(&self, difficulty: Option<u32>) {
+        *self.forced_difficulty.write().expect(
+            "Failed to acquire write lock on forced difficulty - this should never happen",
+        ) = difficulty;
+    }
+
+    pub fn get_forced_difficulty(&self) -> Option<u32> {
+        *self
+            .forced_difficulty
+            .read()
+            .expect("Failed to acquire read lock on forced difficulty - this should never happen")
+    }
+
+    /// The bus this chain announces connected and disconnected blocks on
+    ///
+    /// Clones of a `Blockchain` share it; opening the database again starts a new one.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Notify watched addresses about `tx`, which just entered the memory pool
+    pub fn notify_unconfirmed(&self, tx: &Transaction) {
+        watch::notify_unconfirmed(self, tx);
+    }
+
     // When I want to mine a block without collecting fees (backward compatibility)
     pub fn mine_block(&self, transactions: &[Transaction]) -> Result<Block> {
         // This method is kept for backward compatibility
         // For fee-enabled mining, I use mine_block_with_fees instead
-        self.mine_block_internal(transactions, None)
+        self.mine_block_internal(
+            transactions,
+            None,
+            &BlockChecks::global(),
+            Self::configured_mining_threads(),
+        )
     }
 
-    // When I want to mine a block and collect transaction fees for a miner
+    // When I want to mine a block and collect transaction fees for a miner, rewarding
+    // them with the global fee calculator
     pub fn mine_block_with_fees(
         &self,
         transactions: &[Transaction],
         miner_address: &str,
     ) -> Result<Block> {
-        self.mine_block_internal(transactions, Some(miner_address))
+        self.mine_block_internal(
+            transactions,
+            Some(miner_address),
+            &BlockChecks::global(),
+            Self::configured_mining_threads(),
+        )
+    }
+
+    // Same as mine_block_with_fees, but the reward comes from this node's own fee calculator
+    pub fn mine_block_with_context(
+        &self,
+        transactions: &[Transaction],
+        miner_address: &str,
+        context: &NodeContext,
+    ) -> Result<Block> {
+        self.mine_block_internal(
+            transactions,
+            Some(miner_address),
+            &BlockChecks::of(context),
+            context.miner().get_threads(),
+        )
+    }
+
+    /// Mine `count` blocks to `miner_address` one after the other, for development chains
+    ///
+    /// Each block takes what a `BlockAssembler` picks from the node's memory pool, and the
+    /// chainstate follows every block. Mined transactions leave the pool. Pair this with
+    /// `set_force_difficulty` to mine without waiting.
+    pub fn generate_blocks(
+        &self,
+        count: usize,
+        miner_address: &str,
+        context: &NodeContext,
+    ) -> Result<Vec<Block>> {
+        let utxo_set = UTXOSet::new(self.clone());
+        if utxo_set.is_empty()? {
+            utxo_set.reindex_safe()?;
+        }
+        let assembler = BlockAssembler::new(self);
+
+        let mut blocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            let transactions = assembler.select(miner_address, context.memory_pool().get_all())?;
+            let block = self.mine_block_with_context(&transactions, miner_address, context)?;
+            utxo_set.update_safe(&block)?;
+            for tx in &transactions {
+                context.memory_pool().remove(&HEXLOWER.encode(tx.get_id()));
+            }
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+
+    /// Mine `transaction` right away, filling the rest of the block from the node's memory
+    /// pool
+    ///
+    /// Pool transactions are picked by a `BlockAssembler`, after `transaction` and leaving
+    /// out any that spend the same outputs or the ones it creates. The proof-of-work runs
+    /// on the node's miner and the reward comes from its fee calculator, like
+    /// `mine_block_with_context`. Mined transactions leave the pool and the chainstate
+    /// follows the block.
+    pub fn mine_transaction_with_context(
+        &self,
+        transaction: &Transaction,
+        miner_address: &str,
+        context: &NodeContext,
+    ) -> Result<Block> {
+        let mempool = context.memory_pool();
+        let mut transactions = vec![transaction.clone()];
+        transactions.extend(BlockAssembler::new(self).select_after(
+            miner_address,
+            std::slice::from_ref(transaction),
+            mempool.get_all(),
+        )?);
+
+        let block = self.mine_block_with_context(&transactions, miner_address, context)?;
+        UTXOSet::new(self.clone()).update_safe(&block)?;
+        for tx in &transactions {
+            mempool.remove(&HEXLOWER.encode(tx.get_id()));
+        }
+        Ok(block)
+    }
+
+    // The global config only names a thread count when the CLI asked for one; library
+    // callers and tests keep mining on their own thread
+    fn configured_mining_threads() -> usize {
+        GLOBAL_CONFIG.get_mining_threads().unwrap_or(1)
+    }
+
+    /// Build the next block on top of the tip without mining it
+    ///
+    /// The coinbase pays `miner_address` the subsidy plus the fees of `transactions`,
+    /// computed with this node's fee calculator. Hand the template to a `Miner` to find its
+    /// proof-of-work.
+    pub fn new_block_template(
+        &self,
+        transactions: &[Transaction],
+        miner_address: &str,
+        context: &NodeContext,
+    ) -> Result<Block> {
+        self.assemble_block(transactions, Some(miner_address), &BlockChecks::of(context))
+    }
+
+    /// Build work for an external miner from the transactions waiting in `mempool`
+    ///
+    /// A `BlockAssembler` picks them, the highest fee rates first and as many as fit next
+    /// to the coinbase; transactions that no longer verify or spend an output already taken
+    /// are left out.
+    /// The reward comes from the global fee calculator, like in `validate_and_add_block`.
+    pub fn build_block_template(
+        &self,
+        miner_address: &str,
+        mempool: &MemoryPool,
+    ) -> Result<BlockTemplate> {
+        self.build_block_template_with_checks(miner_address, mempool, &BlockChecks::global())
+    }
+
+    /// Same as `build_block_template`, from the node's own memory pool and fee calculator
+    pub fn build_block_template_with_context(
+        &self,
+        miner_address: &str,
+        context: &NodeContext,
+    ) -> Result<BlockTemplate> {
+        self.build_block_template_with_checks(
+            miner_address,
+            context.memory_pool(),
+            &BlockChecks::of(context),
+        )
+    }
+
+    fn build_block_template_with_checks(
+        &self,
+        miner_address: &str,
+        mempool: &MemoryPool,
+        checks: &BlockChecks,
+    ) -> Result<BlockTemplate> {
+        let transactions = BlockAssembler::new(self).select(miner_address, mempool.get_all())?;
+        let block = self.assemble_block(&transactions, Some(miner_address), checks)?;
+        BlockTemplate::from_block(&block)
+    }
+
+    /// Check the proof-of-work of a block found from a template and make it the new tip
+    ///
+    /// The block then goes through the same checks as the node's own blocks. One built on a
+    /// tip that has moved on since fails with a stale-tip error, and the miner should ask
+    /// for a fresh template.
+    pub fn submit_block(&self, submission: impl Into<BlockSubmission>) -> Result<()> {
+        self.submit_block_with_checks(submission.into(), &BlockChecks::global())
+    }
+
+    /// Same as `submit_block`, checking the reward with the node's own fee calculator
+    pub fn submit_block_with_context(
+        &self,
+        submission: impl Into<BlockSubmission>,
+        context: &NodeContext,
+    ) -> Result<()> {
+        self.submit_block_with_checks(submission.into(), &BlockChecks::of(context))
+    }
+
+    fn submit_block_with_checks(
+        &self,
+        submission: BlockSubmission,
+        checks: &BlockChecks,
+    ) -> Result<()> {
+        let block = submission.into_block()?;
+        if ProofOfWork::compute_hash(&block) != block.get_hash() || !ProofOfWork::validate(&block) {
+            METRICS.blocks_rejected.inc();
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Submitted block {} does not meet its proof-of-work target",
+                block.get_hash()
+            )));
+        }
+        self.extend_tip(&block, checks)
     }
 
     // This is the core mining logic that does the actual work
@@ -191,26 +877,64 @@ impl Blockchain {
         &self,
         transactions: &[Transaction],
         miner_address: Option<&str>,
+        checks: &BlockChecks,
+        threads: usize,
+    ) -> Result<Block> {
+        let mut block = self.assemble_block(transactions, miner_address, checks)?;
+        let difficulty = block.get_difficulty();
+        let solution = block.run_proof_of_work_on(threads);
+
+        // My own blocks go through the same checks as blocks from peers, and only land if
+        // nothing took the tip while I was mining
+        self.extend_tip(&block, checks)?;
+        self.record_mining_stats(&block, &solution);
+        let block_hash = block.get_hash();
+
+        if miner_address.is_some() {
+            let total_fees = FeeCalculator::calculate_total_fees(block.get_transactions().iter());
+            info!(
+                "Successfully mined block: {block_hash} (difficulty: {difficulty}, fees: {})",
+                monetary::conversions::format_amount(total_fees.to_satoshis())
+            );
+        } else {
+            info!("Successfully mined block: {block_hash} (difficulty: {difficulty})");
+        }
+
+        Ok(block)
+    }
+
+    // I check the transactions, add the coinbase and pick the difficulty here, leaving only
+    // the proof-of-work to the caller
+    fn assemble_block(
+        &self,
+        transactions: &[Transaction],
+        miner_address: Option<&str>,
+        checks: &BlockChecks,
     ) -> Result<Block> {
-        // First, I validate all transactions to make sure they're legitimate
-        for (i, transaction) in transactions.iter().enumerate() {
-            if !transaction.verify(self) {
-                return Err(BlockchainError::Transaction(format!(
-                    "Invalid transaction at index {i}"
+        // With a miner address I write the block's only coinbase myself, so one passed in
+        // could only be an attempt to pay out a second reward
+        if miner_address.is_some() {
+            if let Some(i) = transactions.iter().position(Transaction::is_coinbase) {
+                return Err(BlockchainError::CoinbaseNotAllowed(format!(
+                    "transaction {i} of the block template is a coinbase, the miner adds its own"
                 )));
             }
         }
 
-        // Critical: I need to check for double-spending within this block
-        // This prevents the same UTXO from being spent multiple times in one block
-        self.check_for_double_spending(transactions)?;
+        // First, I validate all transactions to make sure they're legitimate, which also
+        // keeps the same UTXO from being spent twice in this block
+        self.verify_transactions_on(transactions, checks.threads)?;
 
-        // I get the current blockchain height to determine the next block's height
-        let best_height = self.get_best_height()?;
-        let next_height = best_height + 1;
+        // I read the tip once, so height, difficulty and parent hash all describe the same
+        // block even if another thread moves the tip meanwhile
+        let tip_hash = self.get_tip_hash();
+        let parent = self
+            .get_block(&tip_hash)?
+            .ok_or_else(|| BlockchainError::Database(format!("Tip block {tip_hash} not found")))?;
+        let next_height = parent.get_height() + 1;
 
         // I calculate the appropriate difficulty for this block based on recent mining times
-        let difficulty = self.calculate_next_difficulty(next_height)?;
+        let difficulty = self.calculate_next_difficulty_for_parent(&parent)?;
 
         // I prepare the list of transactions that will go into this block
         let mut block_transactions = Vec::new();
@@ -220,13 +944,17 @@ impl Blockchain {
             // I calculate the total fees from all transactions in this block
             let total_fees = FeeCalculator::calculate_total_fees(transactions.iter());
             // I calculate the total reward (base reward + fees) for the miner
-            let coinbase_reward = FeeCalculator::calculate_coinbase_reward(total_fees);
+            let coinbase_reward = checks
+                .fee_calculator
+                .calculate_coinbase_reward(total_fees, next_height);
 
             info!(
-                "Mining block with {} total fees collected ({})",
-                total_fees,
-                if total_fees > 0 {
-                    format!("{:.8} coins", FeeCalculator::satoshis_to_coins(total_fees))
+                "Mining block with {} collected",
+                if total_fees > Amount::ZERO {
+                    format!(
+                        "{} in fees",
+                        monetary::conversions::format_amount(total_fees.to_satoshis())
+                    )
                 } else {
                     "no fees".to_string()
                 }
@@ -240,6 +968,8 @@ impl Blockchain {
 
         // I add all the user transactions to the block
         block_transactions.extend_from_slice(transactions);
+        // A block over the limits would only be refused after its proof-of-work
+        Block::validate_block_constraints(&block_transactions)?;
 
         info!(
             "Mining block at height {} with {} transactions (difficulty: {})",
@@ -248,504 +978,3722 @@ impl Blockchain {
             difficulty
         );
 
-        let block = Block::new_block(
-            self.get_tip_hash(),
+        // A clock behind the chain's recent blocks mustn't stamp one they'd reject
+        let timestamp =
+            network_adjusted_time(checks.time_offset)?.max(self.median_time_past(&parent)?);
+        Block::new_template_at(
+            timestamp,
+            tip_hash,
             &block_transactions,
             next_height,
             difficulty,
-        )?;
-
-        // The block I just created should be valid since I control the mining process
-        // I'll validate it during sync/verification instead of here
-        let block_hash = block.get_hash();
-
-        let blocks_tree = self
-            .db
-            .open_tree(BLOCKS_TREE)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
-        Self::update_blocks_tree(&blocks_tree, &block)?;
-        self.set_tip_hash(block_hash);
-
-        if miner_address.is_some() {
-            let total_fees = FeeCalculator::calculate_total_fees(transactions.iter());
-            info!("Successfully mined block: {block_hash} (difficulty: {difficulty}, fees: {total_fees} satoshis)");
-        } else {
-            info!("Successfully mined block: {block_hash} (difficulty: {difficulty})");
-        }
-
-        Ok(block)
+        )
     }
 
+    /// Walk the chain from the tip back to genesis
     pub fn iterator(&self) -> BlockchainIterator {
         BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
     }
 
-    /// Calculate the next difficulty based on recent block times
+    /// Walk towards genesis starting at the stored block `block_hash`
+    pub fn iterator_from(&self, block_hash: &str) -> BlockchainIterator {
+        BlockchainIterator::new(block_hash.to_string(), self.db.clone())
+    }
+
+    /// Walk the main chain from genesis up to the current tip, see `BlockchainHeightIterator`
+    pub fn iter_from_genesis(&self) -> Result<BlockchainHeightIterator> {
+        Ok(BlockchainHeightIterator {
+            heights: self.open_heights_tree()?,
+            trees: BlockTrees::open(&self.db)?,
+            front: 0,
+            back: self.get_best_height()? + 1,
+        })
+    }
+
+    /// Calculate the difficulty required for the main-chain block at `height`
+    ///
+    /// The result only depends on the main-chain blocks below `height`, so it's the same no
+    /// matter how far the tip has moved on since.
     pub fn calculate_next_difficulty(&self, height: usize) -> Result<u32> {
+        if let Some(difficulty) = self.get_forced_difficulty() {
+            return Ok(difficulty);
+        }
         // For early blocks, use initial difficulty
         if height < DifficultyAdjustment::get_adjustment_period() {
             return Ok(DifficultyAdjustment::get_initial_difficulty());
         }
 
-        // Get recent blocks for difficulty calculation
-        let recent_blocks = self.get_recent_blocks(DifficultyAdjustment::get_adjustment_period())?;
-
-        // Use the difficulty adjustment algorithm
-        DifficultyAdjustment::calculate_next_difficulty(&recent_blocks, height)
+        let parent = self.get_block_at_height(height - 1)?.ok_or_else(|| {
+            BlockchainError::InvalidBlock(format!(
+                "Cannot calculate difficulty for height {height}: no main-chain block at height {}",
+                height - 1
+            ))
+        })?;
+        self.calculate_next_difficulty_for_parent(&parent)
     }
 
-    /// Get the most recent N blocks from the blockchain
-    fn get_recent_blocks(&self, count: usize) -> Result<Vec<Block>> {
-        let mut blocks = Vec::new();
-        let mut iterator = self.iterator();
+    /// Calculate the difficulty required for a block built on top of `parent`
+    ///
+    /// The window is collected by walking back from `parent` itself rather than from the
+    /// tip, so this also gives the right answer for blocks on a competing fork.
+    pub fn calculate_next_difficulty_for_parent(&self, parent: &Block) -> Result<u32> {
+        let height = parent.get_height() + 1;
+        let window_len = self.difficulty_window_len(height)?;
+        let mut window = vec![parent.clone()];
+        while window.len() < window_len {
+            let oldest = window.last().expect("Window always contains the parent");
+            let prev_hash = oldest.get_pre_block_hash();
+            let prev = self.get_block(&prev_hash)?.ok_or_else(|| {
+                BlockchainError::InvalidBlock(format!(
+                    "Missing ancestor {prev_hash} while building difficulty window for height {height}"
+                ))
+            })?;
+            window.push(prev);
+        }
+        window.reverse();
+        self.calculate_next_difficulty_after(&window)
+    }
 
-        // Collect the requested number of blocks
-        for _ in 0..count {
-            if let Some(block) = iterator.next() {
-                blocks.push(block);
-            } else {
-                break;
+    /// Calculate the difficulty required for the block after the last of `below`
+    ///
+    /// `below` holds the blocks under it in height order, reaching back at least as far
+    /// as the retarget window, for chains that aren't stored yet like a snapshot's.
+    pub(crate) fn calculate_next_difficulty_after(&self, below: &[Block]) -> Result<u32> {
+        let parent = below.last().ok_or_else(|| {
+            BlockchainError::InvalidBlock("No parent to calculate difficulty from".to_string())
+        })?;
+        let height = parent.get_height() + 1;
+        let window_len = self.difficulty_window_len(height)?;
+        let window = below
+            .len()
+            .checked_sub(window_len)
+            .map(|start| &below[start..])
+            .ok_or_else(|| {
+                BlockchainError::InvalidBlock(format!(
+                    "Difficulty window for height {height} needs {window_len} blocks, got {}",
+                    below.len()
+                ))
+            })?;
+        if let Some(difficulty) = self.get_forced_difficulty() {
+            return Ok(difficulty);
+        }
+        match self.retarget_rules_at(height)? {
+            Some(rules) => {
+                DifficultyAdjustment::calculate_next_difficulty_under(rules, window, height)
             }
+            None => DifficultyAdjustment::calculate_next_difficulty(window, height),
         }
+    }
 
-        // Reverse to get chronological order (oldest first)
-        blocks.reverse();
-        Ok(blocks)
+    // The rules the difficulty at `height` is retargeted under, None off a boundary
+    fn retarget_rules_at(&self, height: usize) -> Result<Option<RetargetRules>> {
+        let period = DifficultyAdjustment::get_adjustment_period();
+        if self.get_forced_difficulty().is_some()
+            || height < period
+            || !height.is_multiple_of(period)
+        {
+            return Ok(None);
+        }
+        let network = self
+            .get_network()?
+            .unwrap_or_else(|| GLOBAL_CONFIG.get_network());
+        Ok(Some(RetargetRules::at(network, height)))
     }
 
-    // ( K -> txid_hex, V -> Vec<TXOutput )
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
+    // How many blocks below `height` its difficulty depends on. At a boundary H they are
+    // exactly the blocks at heights DifficultyAdjustment::window_start(rules, H) ..= H - 1,
+    // elsewhere just the parent.
+    fn difficulty_window_len(&self, height: usize) -> Result<usize> {
+        Ok(match self.retarget_rules_at(height)? {
+            Some(rules) => height - DifficultyAdjustment::window_start(rules, height),
+            None => 1,
+        })
+    }
 
-        let mut iterator = self.iterator();
-        while let Some(block) = iterator.next() {
-            'outer: for tx in block.get_transactions() {
-                let txid_hex = HEXLOWER.encode(tx.get_id());
-                for (idx, out) in tx.get_vout().iter().enumerate() {
-                    if let Some(outs) = spent_txos.get(txid_hex.as_str()) {
-                        for spend_out_idx in outs {
-                            if idx.eq(spend_out_idx) {
-                                continue 'outer;
-                            }
-                        }
-                    }
-                    if let Some(utxo_list) = utxo.get_mut(txid_hex.as_str()) {
-                        utxo_list.push(out.clone());
-                    } else {
-                        utxo.insert(txid_hex.clone(), vec![out.clone()]);
-                    }
-                }
-                if tx.is_coinbase() {
-                    continue;
-                }
+    /// Hash of the block every chain this one syncs with has to start from
+    pub fn get_genesis_hash(&self) -> Result<String> {
+        self.get_block_hash_at_height(0)?
+            .ok_or_else(|| BlockchainError::Database("No genesis block indexed".to_string()))
+    }
 
-                for txin in tx.get_vin() {
-                    let txid_hex = HEXLOWER.encode(txin.get_txid());
-                    if let Some(spent_list) = spent_txos.get_mut(txid_hex.as_str()) {
-                        spent_list.push(txin.get_vout());
-                    } else {
-                        spent_txos.insert(txid_hex, vec![txin.get_vout()]);
-                    }
-                }
-            }
+    /// Get the hash of the main-chain block at a given height
+    pub fn get_block_hash_at_height(&self, height: usize) -> Result<Option<String>> {
+        let heights_tree = self.open_heights_tree()?;
+        let hash = heights_tree
+            .get(Self::height_key(height))
+            .map_err(|e| BlockchainError::Database(format!("Failed to read height index: {e}")))?;
+
+        match hash {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes.to_vec()).map_err(|e| {
+                BlockchainError::Database(format!("Invalid hash in height index: {e}"))
+            })?)),
+            None => Ok(None),
         }
-        utxo
     }
 
-    pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
-        let mut iterator = self.iterator();
-        while let Some(block) = iterator.next() {
-            for transaction in block.get_transactions() {
-                if txid.eq(transaction.get_id()) {
-                    return Some(transaction.clone());
-                }
-            }
+    /// Get the main-chain block at a given height
+    pub fn get_block_at_height(&self, height: usize) -> Result<Option<Block>> {
+        match self.get_block_hash_at_height(height)? {
+            Some(hash) => self.get_block(&hash),
+            None => Ok(None),
         }
-        None
     }
 
-    pub fn add_block(&self, block: &Block) -> Result<()> {
-        let block_tree = self
-            .db
-            .open_tree(BLOCKS_TREE)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
-
-        if block_tree
-            .get(block.get_hash())
-            .map_err(|e| {
-                BlockchainError::Database(format!("Failed to check block existence: {e}"))
-            })?
-            .is_some()
-        {
-            return Ok(()); // Block already exists
+    /// The median timestamp of `block` and the blocks before it, `MEDIAN_TIME_SPAN` in all
+    ///
+    /// A child of `block` may not be timestamped before it. Near genesis there are fewer
+    /// blocks to take the median of.
+    pub fn median_time_past(&self, block: &Block) -> Result<i64> {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let height = block.get_height();
+        if self.get_block_hash_at_height(height)?.as_deref() == Some(block.get_hash()) {
+            let first = (height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+            for ancestor in first..=height {
+                if let Some(ancestor) = self.get_block_at_height(ancestor)? {
+                    timestamps.push(ancestor.get_timestamp());
+                }
+            }
+        } else {
+            // A side branch isn't in the height index, so its blocks are followed by hash
+            timestamps.push(block.get_timestamp());
+            let mut parent_hash = block.get_pre_block_hash();
+            while timestamps.len() < MEDIAN_TIME_SPAN {
+                let Some(parent) = self.get_block(&parent_hash)? else {
+                    break;
+                };
+                timestamps.push(parent.get_timestamp());
+                parent_hash = parent.get_pre_block_hash();
+            }
         }
 
-        let block_data = block.serialize()?;
-
-        block_tree
-            .transaction(|tx_db| {
-                tx_db.insert(block.get_hash(), block_data.as_slice())?;
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
 
-                let tip_block_bytes = tx_db.get(self.get_tip_hash())?.ok_or_else(|| {
-                    sled::Error::Io(std::io::Error::new(
-                        std::io::ErrorKind::NotFound,
-                        "Tip hash not found",
-                    ))
-                })?;
-                let tip_block = Block::deserialize(tip_block_bytes.as_ref()).map_err(|_| {
-                    sled::Error::Io(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Failed to deserialize tip block",
-                    ))
-                })?;
+    /// Main-chain blocks from height `from` to `to` (both inclusive), lowest first
+    ///
+    /// `to` is capped at the best height, so an open-ended range can pass `usize::MAX`.
+    pub fn blocks_in_range(&self, from: usize, to: usize) -> Result<Vec<Block>> {
+        let to = to.min(self.get_best_height()?);
+        if from > to {
+            return Ok(Vec::new());
+        }
 
-                if block.get_height() > tip_block.get_height() {
-                    tx_db.insert(TIP_BLOCK_HASH_KEY, block.get_hash())?;
-                    self.set_tip_hash(block.get_hash());
-                }
-                Ok(())
+        (from..=to)
+            .map(|height| {
+                self.get_block_at_height(height)?.ok_or_else(|| {
+                    BlockchainError::Database(format!("No main-chain block at height {height}"))
+                })
             })
-            .map_err(|e: sled::transaction::TransactionError| {
-                BlockchainError::Database(format!("Failed to add block: {e}"))
-            })?;
+            .collect()
+    }
 
-        Ok(())
+    fn open_heights_tree(&self) -> Result<Tree> {
+        self.db.open_tree(BLOCK_HEIGHTS_TREE).map_err(|e| {
+            BlockchainError::Database(format!("Failed to open block heights tree: {e}"))
+        })
     }
 
-    pub fn get_best_height(&self) -> Result<usize> {
-        let block_tree = self
-            .db
-            .open_tree(BLOCKS_TREE)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
-        let tip_block_bytes = block_tree
-            .get(self.get_tip_hash())
-            .map_err(|e| BlockchainError::Database(format!("Failed to get tip block: {e}")))?
-            .ok_or_else(|| BlockchainError::Database("Tip hash not found".to_string()))?;
-        let tip_block = Block::deserialize(tip_block_bytes.as_ref())?;
-        Ok(tip_block.get_height())
+    // Big-endian so the sled tree iterates in height order
+    fn height_key(height: usize) -> [u8; 8] {
+        (height as u64).to_be_bytes()
     }
 
-    pub fn get_block_by_bytes(&self, block_hash: &[u8]) -> Result<Option<Block>> {
-        let block_tree = self
-            .db
-            .open_tree(BLOCKS_TREE)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+    // I keep the height index pointing at the main chain whenever the tip changes. Walking
+    // back from the new tip stops at the first height that already maps to the right block,
+    // so extending the chain costs one write while a reorg rewrites only the switched branch.
+    // The transaction index follows along: blocks that leave the main chain are unindexed
+    // before the ones joining it are indexed, so a transaction both branches share stays.
+    //
+    // Returns the hashes of the blocks that joined the main chain, lowest first.
+    fn update_height_index(&self, tip_hash: &str) -> Result<TipChange> {
+        let heights_tree = self.open_heights_tree()?;
+        let tip_block = self
+            .get_block(tip_hash)?
+            .ok_or_else(|| BlockchainError::Database(format!("Tip block {tip_hash} not found")))?;
 
-        if let Some(block_bytes) = block_tree
-            .get(block_hash)
-            .map_err(|e| BlockchainError::Database(format!("Failed to get block: {e}")))?
-        {
-            let block = Block::deserialize(block_bytes.as_ref())?;
-            return Ok(Some(block));
+        // Anything above the new tip no longer belongs to the main chain
+        let stale: Vec<(sled::IVec, sled::IVec)> = heights_tree
+            .range(Self::height_key(tip_block.get_height() + 1)..)
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|e| BlockchainError::Database(format!("Failed to scan height index: {e}")))?;
+        // With the height each one was indexed at, since removed blocks can't tell
+        let mut disconnected: Vec<(usize, sled::IVec)> = Vec::new();
+        for (key, hash) in stale {
+            heights_tree.remove(&key).map_err(|e| {
+                BlockchainError::Database(format!("Failed to update height index: {e}"))
+            })?;
+            let height = key
+                .as_ref()
+                .try_into()
+                .map_or(0, |bytes| u64::from_be_bytes(bytes) as usize);
+            disconnected.push((height, hash));
+        }
+
+        let mut connected: Vec<(_, String)> = Vec::new();
+        let mut current = Some(tip_block);
+        while let Some(block) = current {
+            let key = Self::height_key(block.get_height());
+            let indexed = heights_tree.get(key).map_err(|e| {
+                BlockchainError::Database(format!("Failed to read height index: {e}"))
+            })?;
+            if indexed.as_deref() == Some(block.get_hash().as_bytes()) {
+                break;
+            }
+            disconnected.extend(indexed.map(|hash| (block.get_height(), hash)));
+
+            let prev_hash = block.get_pre_block_hash();
+            connected.push((key, block.get_hash().to_string()));
+            current = if prev_hash == "None" {
+                None
+            } else {
+                self.get_block(&prev_hash)?
+            };
+        }
+
+        let tx_index = self.open_tx_index_tree()?;
+        let mut change = TipChange::default();
+        disconnected.sort_by_key(|(height, _)| std::cmp::Reverse(*height));
+        for (height, hash) in disconnected {
+            // A block removed from storage unindexed its own transactions on the way out
+            if let Some(block) = self.get_block_by_bytes(&hash)? {
+                Self::unindex_transactions(&tx_index, &block)?;
+            }
+            change
+                .disconnected
+                .push((String::from_utf8_lossy(&hash).into_owned(), height));
+        }
+        connected.reverse();
+        for (key, hash) in &connected {
+            heights_tree.insert(key, hash.as_bytes()).map_err(|e| {
+                BlockchainError::Database(format!("Failed to update height index: {e}"))
+            })?;
+            let block = self
+                .get_block(hash)?
+                .ok_or_else(|| BlockchainError::Database(format!("Block {hash} not found")))?;
+            Self::index_transactions(&tx_index, &block)?;
         }
-        Ok(None)
+        change.connected = connected.into_iter().map(|(_, hash)| hash).collect();
+        Ok(change)
     }
 
-    pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
-        let mut iterator = self.iterator();
-        let mut blocks = vec![];
-        while let Some(block) = iterator.next() {
-            blocks.push(block.get_hash_bytes());
+    // Only the mining statistics miss the block if this fails, so it doesn't fail the block
+    fn index_block_miner(&self, block: &Block) {
+        if let Err(e) = mining_stats::index_miner(self, block) {
+            warn!(
+                "Failed to index the miner of block {}: {e}",
+                block.get_hash()
+            );
         }
-        blocks
     }
 
-    /// Check if a block exists in the blockchain
-    pub fn block_exists(&self, block_hash: &str) -> Result<bool> {
-        let block_tree = self
-            .db
-            .open_tree(BLOCKS_TREE)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+    /// Keep what mining `block` took, now that this node mined and committed it
+    ///
+    /// A failure is only logged, as the block itself is already stored.
+    pub fn record_mining_stats(&self, block: &Block, solution: &PowSolution) {
+        if let Err(e) = mining_stats::record(self, block, solution) {
+            warn!(
+                "Failed to record the mining stats of block {}: {e}",
+                block.get_hash()
+            );
+        }
+    }
 
-        let exists = block_tree
-            .get(block_hash)
-            .map_err(|e| {
-                BlockchainError::Database(format!("Failed to check block existence: {e}"))
-            })?
-            .is_some();
+    /// What it took this node to mine the block `hash`, `None` for blocks it didn't mine
+    pub fn get_mining_stats(&self, hash: &str) -> Result<Option<MiningStats>> {
+        mining_stats::get(self, hash)
+    }
 
-        Ok(exists)
+    /// Hashes of the stored blocks whose coinbase pays `pub_key_hash`, including blocks no
+    /// longer on the main chain, in no particular order
+    pub fn blocks_mined_by(&self, pub_key_hash: &[u8]) -> Result<Vec<String>> {
+        mining_stats::blocks_mined_by(self, pub_key_hash)
     }
 
-    /// Get a block by hash (string version)
-    pub fn get_block(&self, block_hash: &str) -> Result<Option<Block>> {
-        let block_tree = self
-            .db
-            .open_tree(BLOCKS_TREE)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+    /// Miners and mining stats of the last `window` main-chain blocks, see
+    /// `core::mining_stats`
+    pub fn get_mining_report(&self, window: usize) -> Result<MiningReport> {
+        mining_stats::mining_report(self, window)
+    }
 
-        if let Some(block_bytes) = block_tree
-            .get(block_hash)
-            .map_err(|e| BlockchainError::Database(format!("Failed to get block: {e}")))?
+    // Addresses I have rescanned for keep their history current; losing an update only
+    // costs a stale history, which the next rescan puts right
+    fn update_address_index(&self, change: &TipChange) {
+        if let Err(e) =
+            address_index::update_for_tip_change(self, &change.connected, &change.disconnected)
         {
-            let block = Block::deserialize(block_bytes.as_ref())?;
-            return Ok(Some(block));
+            warn!("Failed to update the address index: {e}");
         }
-        Ok(None)
     }
 
-    /// Get the height of a specific block
-    pub fn get_block_height(&self, block_hash: &str) -> Result<usize> {
-        if let Some(block) = self.get_block(block_hash)? {
-            Ok(block.get_height())
-        } else {
-            Err(BlockchainError::InvalidBlock(format!(
-                "Block not found: {block_hash}"
-            )))
+    // Announce what `update_height_index` changed: the blocks leaving the main chain newest
+    // first, then every block joining it with its transactions
+    fn publish_tip_change(&self, change: &TipChange) {
+        if !self.events.has_subscribers() {
+            return;
+        }
+        for (hash, height) in &change.disconnected {
+            self.events.publish(ChainEvent::BlockDisconnected {
+                hash: hash.clone(),
+                height: *height,
+            });
+        }
+        for hash in &change.connected {
+            let block = match self.get_block(hash) {
+                Ok(Some(block)) => block,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to read block {hash} for its events: {e}");
+                    continue;
+                }
+            };
+            self.events.publish(ChainEvent::BlockConnected {
+                hash: hash.clone(),
+                height: block.get_height(),
+            });
+            for tx in block.get_transactions() {
+                self.events.publish(ChainEvent::TxConfirmed {
+                    txid: HEXLOWER.encode(tx.get_id()),
+                    block_hash: hash.clone(),
+                });
+            }
         }
     }
 
-    /// Check if a block is in the main chain
-    pub fn is_in_main_chain(&self, block_hash: &str) -> Result<bool> {
-        // Walk back from current tip to see if we encounter this block
-        let mut current_hash = self.get_tip_hash();
+    fn open_tx_index_tree(&self) -> Result<Tree> {
+        self.db.open_tree(TX_INDEX_TREE).map_err(|e| {
+            BlockchainError::Database(format!("Failed to open transaction index: {e}"))
+        })
+    }
 
-        while current_hash != "None" {
-            // Genesis block has "None" as previous hash
-            if current_hash == block_hash {
-                return Ok(true);
-            }
+    fn index_transactions(tx_index: &Tree, block: &Block) -> Result<()> {
+        for transaction in block.get_transactions() {
+            let entry = serialize(&IndexedTransaction {
+                block_hash: block.get_hash().to_string(),
+                transaction: transaction.clone(),
+            })?;
+            tx_index.insert(transaction.get_id(), entry).map_err(|e| {
+                BlockchainError::Database(format!("Failed to update transaction index: {e}"))
+            })?;
+        }
+        Ok(())
+    }
 
-            if let Some(block) = self.get_block(&current_hash)? {
-                current_hash = block.get_pre_block_hash();
-            } else {
-                break;
+    // Only entries `block` itself put there go, in case another block holds the same txid
+    fn unindex_transactions(tx_index: &Tree, block: &Block) -> Result<()> {
+        for transaction in block.get_transactions() {
+            let indexed = Self::read_tx_index(tx_index, transaction.get_id())?;
+            if indexed.is_some_and(|entry| entry.block_hash == block.get_hash()) {
+                tx_index.remove(transaction.get_id()).map_err(|e| {
+                    BlockchainError::Database(format!("Failed to update transaction index: {e}"))
+                })?;
             }
         }
-
-        Ok(false)
+        Ok(())
     }
 
-    /// Remove a block from the blockchain (for reorganization)
-    pub fn remove_block(&self, block_hash: &str) -> Result<()> {
-        let block_tree = self
-            .db
-            .open_tree(BLOCKS_TREE)
-            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
-
-        // Get the block to find its parent
-        let block = self.get_block(block_hash)?.ok_or_else(|| {
-            BlockchainError::InvalidBlock(format!("Cannot remove non-existent block: {block_hash}"))
+    fn read_tx_index(tx_index: &Tree, txid: &[u8]) -> Result<Option<IndexedTransaction>> {
+        let bytes = tx_index.get(txid).map_err(|e| {
+            BlockchainError::Database(format!("Failed to read transaction index: {e}"))
         })?;
+        bytes.map(|bytes| deserialize(&bytes)).transpose()
+    }
 
-        // Remove the block from storage
-        block_tree
-            .remove(block_hash)
-            .map_err(|e| BlockchainError::Database(format!("Failed to remove block: {e}")))?;
-
-        // Update tip if this was the tip block
-        if self.get_tip_hash() == block_hash {
-            let new_tip = block.get_pre_block_hash();
-            block_tree
-                .insert(TIP_BLOCK_HASH_KEY, new_tip.as_bytes())
-                .map_err(|e| BlockchainError::Database(format!("Failed to update tip: {e}")))?;
-            self.set_tip_hash(&new_tip);
+    /// Rebuild the transaction index from the main chain, returning how many
+    /// transactions it holds
+    ///
+    /// Opening a database that predates the index does this automatically; `reindexutxo`
+    /// does it again along with the chainstate.
+    pub fn reindex_transactions(&self) -> Result<usize> {
+        // The pruned blocks can't give back the transactions they still have unspent outputs in
+        if self.is_pruned()? {
+            return Err(BlockchainError::Pruned(
+                "the transaction index can't be rebuilt from a pruned chain".to_string(),
+            ));
         }
+        let tx_index = self.open_tx_index_tree()?;
+        tx_index.clear().map_err(|e| {
+            BlockchainError::Database(format!("Failed to clear transaction index: {e}"))
+        })?;
 
-        Ok(())
+        let mut count = 0;
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.try_next()? {
+            Self::index_transactions(&tx_index, &block)?;
+            count += block.get_transactions().len();
+        }
+        Ok(count)
     }
 
-    /// Synchronize blockchain with another node's blockchain
-    pub fn sync_with_peer(&self, peer_blocks: &[Block]) -> Result<bool> {
-        let mut updated = false;
-
-        // Sort blocks by height to process in order
-        let mut sorted_blocks = peer_blocks.to_vec();
-        sorted_blocks.sort_by_key(|b| b.get_height());
+    /// Rewrite stored blocks still in an older format in `FORMAT_VERSION`, returning how
+    /// many were rewritten
+    ///
+    /// Older formats still load, so this only spares a later release from reading them.
+    /// Hashes and txids don't depend on the format, so nothing else in the database moves.
+    pub fn migrate_block_format(&self) -> Result<usize> {
+        let blocks_tree = self
+            .db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
 
-        for block in sorted_blocks {
-            // Check if we already have this block
-            if !self.block_exists(block.get_hash())? {
-                // Validate the block before adding
-                if self.validate_block_for_sync(&block)? {
-                    // Check for fork resolution
-                    if self.should_reorganize(&block)? {
-                        self.reorganize_to_block(&block)?;
-                        updated = true;
-                    } else {
-                        self.add_block(&block)?;
-                        updated = true;
-                    }
-                    info!("Synchronized block: {}", block.get_hash());
-                }
+        let mut migrated = 0;
+        for item in blocks_tree.iter() {
+            let (key, bytes) = item.map_err(|e| {
+                BlockchainError::Database(format!("Failed to iterate blocks tree: {e}"))
+            })?;
+            if key.as_ref() == TIP_BLOCK_HASH_KEY.as_bytes()
+                || format_version(&bytes)? == FORMAT_VERSION
+            {
+                continue;
             }
+            let block = Block::deserialize(&bytes)?;
+            blocks_tree
+                .insert(key, block.serialize()?)
+                .map_err(|e| BlockchainError::Database(format!("Failed to rewrite block: {e}")))?;
+            migrated += 1;
         }
 
-        Ok(updated)
+        self.db
+            .insert(BLOCK_FORMAT_KEY, &[FORMAT_VERSION])
+            .map_err(|e| {
+                BlockchainError::Database(format!("Failed to record block format: {e}"))
+            })?;
+        self.db
+            .flush()
+            .map_err(|e| BlockchainError::Database(format!("Failed to flush database: {e}")))?;
+        Ok(migrated)
     }
 
-    /// Check if we should reorganize to a new block (simple longest chain rule)
-    fn should_reorganize(&self, new_block: &Block) -> Result<bool> {
-        let current_height = self.get_best_height()?;
-        Ok(new_block.get_height() > current_height)
+    /// Whether the block at `height` may carry transaction ids from before they left out
+    /// keys, see `Network::trimmed_txid_height`
+    pub fn allows_legacy_txids(&self, height: usize) -> Result<bool> {
+        let network = self
+            .get_network()?
+            .unwrap_or_else(|| GLOBAL_CONFIG.get_network());
+        Ok(height < network.trimmed_txid_height())
     }
 
-    /// Reorganize blockchain to a new block (simple implementation)
-    fn reorganize_to_block(&self, new_block: &Block) -> Result<()> {
-        // For simplicity, we'll just add the block if it extends the chain
-        // In a full implementation, this would handle complex reorganizations
-        self.add_block(new_block)
+    /// The format `migratedb` last rewrote this database's blocks in, 0 if it never ran
+    pub fn get_block_format_version(&self) -> Result<u8> {
+        let recorded = self
+            .db
+            .get(BLOCK_FORMAT_KEY)
+            .map_err(|e| BlockchainError::Database(format!("Failed to read block format: {e}")))?;
+        Ok(recorded
+            .and_then(|bytes| bytes.first().copied())
+            .unwrap_or(0))
     }
 
-    /// Validate a block for synchronization
-    fn validate_block_for_sync(&self, block: &Block) -> Result<bool> {
-        // Check if previous block exists (unless it's genesis)
-        if block.get_pre_block_hash() != "None"
-            && !self.block_exists(&block.get_pre_block_hash())?
-        {
-            return Ok(false); // Previous block not found
-        }
-
-        // Get previous block timestamp for validation
-        let prev_timestamp = if block.get_pre_block_hash() != "None" {
-            self.get_block(&block.get_pre_block_hash())?
-                .map(|prev_block| prev_block.get_timestamp())
-        } else {
-            None
-        };
-
-        // Use comprehensive block validation
-        if !block.validate_block(prev_timestamp)? {
-            return Ok(false); // Block validation failed
-        }
-
-        // Validate all transactions in the block
-        for transaction in block.get_transactions() {
-            if !transaction.verify(self) {
-                return Ok(false); // Invalid transaction
+    /// Pin the main-chain block at `height` to `hash`
+    ///
+    /// The checkpoint is stored in this database. It can't contradict a built-in
+    /// checkpoint or the block this chain already has at that height.
+    pub fn add_checkpoint(&self, height: usize, hash: &str) -> Result<()> {
+        if let Some(existing) = self.get_checkpoint(height)? {
+            if existing != hash {
+                return Err(BlockchainError::Config(format!(
+                    "Height {height} is already checkpointed to {existing}"
+                )));
             }
         }
-
-        // Validate coinbase reward if this isn't genesis
-        if block.get_height() > 0 {
-            let total_fees = block.get_total_fees();
-            let expected_reward = crate::core::FeeCalculator::calculate_coinbase_reward(total_fees);
-            if !block.validate_coinbase_reward(expected_reward)? {
-                return Ok(false); // Invalid coinbase reward
+        if let Some(local) = self.get_block_hash_at_height(height)? {
+            if local != hash {
+                return Err(BlockchainError::Config(format!(
+                    "Checkpoint {hash} contradicts block {local} already on the chain at height {height}"
+                )));
             }
         }
 
-        Ok(true)
+        self.open_checkpoints_tree()?
+            .insert(Self::height_key(height), hash.as_bytes())
+            .map_err(|e| BlockchainError::Database(format!("Failed to store checkpoint: {e}")))?;
+        info!("Added checkpoint at height {height}: {hash}");
+        Ok(())
     }
 
-    // This is critical - I need to prevent double-spending within a single block
-    // Someone could try to spend the same UTXO multiple times in different transactions
-    fn check_for_double_spending(&self, transactions: &[Transaction]) -> Result<()> {
-        use std::collections::HashSet;
-        let mut spent_outputs: HashSet<(Vec<u8>, usize)> = HashSet::new();
+    /// All checkpoints, built-in and stored, in height order
+    pub fn get_checkpoints(&self) -> Result<Vec<Checkpoint>> {
+        let mut checkpoints = checkpoints::builtin_checkpoints();
+        for entry in self.open_checkpoints_tree()?.iter() {
+            let (key, value) = entry.map_err(|e| {
+                BlockchainError::Database(format!("Failed to read checkpoints: {e}"))
+            })?;
+            let height_bytes: [u8; 8] = key.as_ref().try_into().map_err(|_| {
+                BlockchainError::Database("Invalid height in checkpoints tree".to_string())
+            })?;
+            let hash = String::from_utf8(value.to_vec()).map_err(|e| {
+                BlockchainError::Database(format!("Invalid hash in checkpoints tree: {e}"))
+            })?;
+            checkpoints.push((u64::from_be_bytes(height_bytes) as usize, hash));
+        }
+        checkpoints.sort();
+        checkpoints.dedup();
+        Ok(checkpoints)
+    }
 
-        for (tx_index, transaction) in transactions.iter().enumerate() {
-            // I skip coinbase transactions since they don't spend existing outputs
-            if transaction.is_coinbase() {
-                continue;
-            }
+    /// The checkpointed hash at `height`, if there is one
+    pub fn get_checkpoint(&self, height: usize) -> Result<Option<String>> {
+        Ok(self
+            .get_checkpoints()?
+            .into_iter()
+            .find(|(checkpoint_height, _)| *checkpoint_height == height)
+            .map(|(_, hash)| hash))
+    }
 
-            // I check each input to see if it's already been spent in this block
-            for input in transaction.get_vin() {
-                let output_reference = (input.get_txid().to_vec(), input.get_vout());
-
-                // If I've already seen this output being spent, that's a double-spend!
-                if spent_outputs.contains(&output_reference) {
-                    return Err(BlockchainError::Transaction(format!(
-                        "Double-spending detected in transaction {}: output {}:{} already spent in this block",
-                        tx_index,
-                        HEXLOWER.encode(input.get_txid()),
-                        input.get_vout()
-                    )));
-                }
+    /// The highest checkpoint, if any
+    pub fn get_last_checkpoint(&self) -> Result<Option<Checkpoint>> {
+        Ok(self.get_checkpoints()?.pop())
+    }
 
-                // I mark this output as spent
-                spent_outputs.insert(output_reference);
+    /// Whether `block` claims a checkpointed height with a different hash
+    pub fn is_checkpoint_conflict(&self, block: &Block) -> bool {
+        match self.get_checkpoint(block.get_height()) {
+            Ok(Some(hash)) => hash != block.get_hash(),
+            Ok(None) => false,
+            Err(e) => {
+                // Without the checkpoints I can't vouch for the block either way
+                warn!("Could not read checkpoints, treating block as conflicting: {e}");
+                true
             }
         }
+    }
 
-        // If I get here, no double-spending was detected
-        Ok(())
+    fn open_checkpoints_tree(&self) -> Result<Tree> {
+        self.db
+            .open_tree(CHECKPOINTS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open checkpoints tree: {e}")))
     }
 
-    // I also need to check if an output has already been spent in the blockchain
-    pub fn is_output_spent(&self, txid: &[u8], vout: usize) -> bool {
-        // I iterate through all blocks to see if this output has been spent
-        let mut iterator = self.iterator();
-        while let Some(block) = iterator.next() {
-            for transaction in block.get_transactions() {
-                // I skip coinbase transactions
-                if transaction.is_coinbase() {
+    // ( K -> txid_hex, V -> Vec<(vout, TXOutput)> )
+    pub fn find_utxo(&self) -> HashMap<String, Vec<(usize, TXOutput)>> {
+        self.find_utxo_from(&self.get_tip_hash())
+    }
+
+    // The unspent outputs as of the main-chain block `tip_hash`
+    pub(crate) fn find_utxo_from(&self, tip_hash: &str) -> HashMap<String, Vec<(usize, TXOutput)>> {
+        let mut utxo: HashMap<String, Vec<(usize, TXOutput)>> = HashMap::new();
+        let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
+
+        for block in self.iterator_from(tip_hash) {
+            for tx in block.get_transactions() {
+                let txid_hex = HEXLOWER.encode(tx.get_id());
+                for (idx, out) in tx.get_vout().iter().enumerate() {
+                    // I only skip the spent output itself; the rest of the transaction's
+                    // outputs and its inputs still have to be recorded. Data outputs can
+                    // never be spent, so they don't belong in the set at all.
+                    if out.is_data_carrier()
+                        || spent_txos
+                            .get(txid_hex.as_str())
+                            .is_some_and(|spent| spent.contains(&idx))
+                    {
+                        continue;
+                    }
+                    if let Some(utxo_list) = utxo.get_mut(txid_hex.as_str()) {
+                        utxo_list.push((idx, out.clone()));
+                    } else {
+                        utxo.insert(txid_hex.clone(), vec![(idx, out.clone())]);
+                    }
+                }
+                if tx.is_coinbase() {
                     continue;
                 }
 
-                // I check if any input spends the output I'm looking for
-                for input in transaction.get_vin() {
-                    if input.get_txid() == txid && input.get_vout() == vout {
-                        return true; // This output has been spent
+                for txin in tx.get_vin() {
+                    let txid_hex = HEXLOWER.encode(txin.get_txid());
+                    if let Some(spent_list) = spent_txos.get_mut(txid_hex.as_str()) {
+                        spent_list.push(txin.get_vout());
+                    } else {
+                        spent_txos.insert(txid_hex, vec![txin.get_vout()]);
                     }
                 }
             }
         }
-        false // This output hasn't been spent yet
+        utxo
     }
 
-    // I want to be able to validate that a transaction's inputs haven't been spent
-    pub fn validate_transaction_inputs(&self, transaction: &Transaction) -> Result<bool> {
-        if transaction.is_coinbase() {
-            return Ok(true); // Coinbase transactions don't have real inputs to validate
+    /// The main-chain transaction with id `txid`, looked up in the transaction index
+    ///
+    /// Transactions only in the memory pool or on a side branch aren't found. A
+    /// transaction whose outputs were all spent before its block was pruned is gone for
+    /// good, and asking for it is a `Pruned` error rather than `None`.
+    pub fn find_transaction(&self, txid: &[u8]) -> Result<Option<Transaction>> {
+        if let Some(entry) = Self::read_tx_index(&self.open_tx_index_tree()?, txid)? {
+            return Ok(Some(entry.transaction));
         }
+        let pruned_in = self.open_pruned_txids_tree()?.get(txid).map_err(|e| {
+            BlockchainError::Database(format!("Failed to read pruned transactions: {e}"))
+        })?;
+        match pruned_in {
+            Some(block_hash) => Err(BlockchainError::Pruned(format!(
+                "transaction {} was in block {}, whose body has been pruned",
+                HEXLOWER.encode(txid),
+                String::from_utf8_lossy(&block_hash)
+            ))),
+            None => Ok(None),
+        }
+    }
 
-        for input in transaction.get_vin() {
-            // I check if this input has already been spent
-            if self.is_output_spent(input.get_txid(), input.get_vout()) {
-                return Err(BlockchainError::Transaction(format!(
-                    "Input already spent: {}:{}",
-                    HEXLOWER.encode(input.get_txid()),
-                    input.get_vout()
-                )));
-            }
-
-            // I also verify that the referenced transaction exists
-            if self.find_transaction(input.get_txid()).is_none() {
-                return Err(BlockchainError::Transaction(format!(
-                    "Referenced transaction not found: {}",
-                    HEXLOWER.encode(input.get_txid())
-                )));
+    /// Find the main-chain history of `pub_key_hashes` from `from_height` up and keep it
+    /// in the address index, see `address_index`
+    ///
+    /// Whatever the index held for those addresses below `from_height` stays, the rest is
+    /// replaced. `progress` is called with the height reached every
+    /// `RESCAN_PROGRESS_INTERVAL` blocks.
+    pub fn rescan_for_addresses(
+        &self,
+        pub_key_hashes: &[Vec<u8>],
+        from_height: usize,
+        progress: impl Fn(usize),
+    ) -> Result<RescanReport> {
+        address_index::rescan(self, pub_key_hashes, from_height, progress)
+    }
+
+    /// Every main-chain transaction paying or spending from `pub_key_hash`, oldest first
+    ///
+    /// An address that was rescanned for is read from the address index; any other one
+    /// costs a walk over the whole chain.
+    pub fn address_history(&self, pub_key_hash: &[u8]) -> Result<Vec<AddressTx>> {
+        if let Some(history) = AddressIndex::open(self)?.history(pub_key_hash)? {
+            return Ok(history);
+        }
+        let pub_key_hash = pub_key_hash.to_vec();
+        let (mut found, _) =
+            address_index::scan(self, std::slice::from_ref(&pub_key_hash), 0, |_| {})?;
+        Ok(found.remove(&pub_key_hash).unwrap_or_default())
+    }
+
+    /// How many main-chain transactions paid `pub_key_hash` and spent from it, read from
+    /// its history
+    pub fn address_usage(&self, pub_key_hash: &[u8]) -> Result<UsageStats> {
+        Ok(UsageStats::from_history(
+            &self.address_history(pub_key_hash)?,
+        ))
+    }
+
+    /// Hash of the main-chain block holding transaction `txid`, from the transaction index
+    pub fn find_transaction_block(&self, txid: &[u8]) -> Result<Option<String>> {
+        Ok(Self::read_tx_index(&self.open_tx_index_tree()?, txid)?.map(|entry| entry.block_hash))
+    }
+
+    /// How many blocks confirm main-chain transaction `txid`: 1 in the tip's block, one
+    /// more for every block on top
+    ///
+    /// `None` for a transaction that is unknown, only in the memory pool or only on a side
+    /// branch. A reorg taking its block off the main chain takes it out of the index, so
+    /// it goes back to `None` until a block on the new branch holds it.
+    pub fn get_confirmations(&self, txid: &[u8]) -> Result<Option<u64>> {
+        let block_hash = match self.find_transaction_block(txid)? {
+            Some(block_hash) => block_hash,
+            None => {
+                let pruned_in = self.open_pruned_txids_tree()?.get(txid).map_err(|e| {
+                    BlockchainError::Database(format!("Failed to read pruned transactions: {e}"))
+                })?;
+                match pruned_in {
+                    Some(block_hash) => String::from_utf8_lossy(&block_hash).into_owned(),
+                    None => return Ok(None),
+                }
+            }
+        };
+        let Some(block) = self.get_block(&block_hash)? else {
+            return Ok(None);
+        };
+        let height = block.get_height();
+        if self.get_block_hash_at_height(height)?.as_deref() != Some(block_hash.as_str()) {
+            return Ok(None);
+        }
+        Ok(Some((self.get_best_height()? + 1 - height) as u64))
+    }
+
+    /// Where main-chain transaction `txid` stands, counting finality to `get_finality_depth`
+    pub fn get_transaction_status(&self, txid: &[u8]) -> Result<TransactionStatus> {
+        let confirmations = self.get_confirmations(txid)?;
+        let block = match confirmations {
+            Some(_) => self.find_transaction_block(txid)?,
+            None => None,
+        };
+        let finality_depth = self.get_finality_depth() as u64;
+        Ok(TransactionStatus {
+            block,
+            confirmations,
+            blocks_to_final: confirmations
+                .map(|confirmations| finality_depth.saturating_sub(confirmations)),
+        })
+    }
+
+    /// Whether `txid` has at least `get_finality_depth` confirmations
+    pub fn is_final(&self, txid: &[u8]) -> Result<bool> {
+        Ok(self
+            .get_confirmations(txid)?
+            .is_some_and(|confirmations| confirmations >= self.get_finality_depth() as u64))
+    }
+
+    /// Whether any block body has been pruned from this database
+    pub fn is_pruned(&self) -> Result<bool> {
+        Ok(!self.open_headers_tree()?.is_empty())
+    }
+
+    /// Prune to the depth set with `set_prune_keep_blocks`, if any, logging what happened
+    ///
+    /// Meant to run whenever the chainstate has caught up with a new tip.
+    pub fn prune_if_enabled(&self) {
+        let Some(keep_blocks) = self.get_prune_keep_blocks() else {
+            return;
+        };
+        match self.prune_blocks(keep_blocks) {
+            Ok(0) => {}
+            Ok(pruned) => info!("Pruned {pruned} block(s), keeping {keep_blocks} below the tip"),
+            Err(e) => warn!("Failed to prune blocks: {e}"),
+        }
+    }
+
+    /// Delete the bodies of main-chain blocks buried more than `keep_blocks` below the
+    /// tip, returning how many were pruned
+    ///
+    /// A pruned block leaves its header behind, so the chain can still be walked and its
+    /// proof of work checked, and `get_block` returns it without transactions. Its
+    /// transactions that still have unspent outputs stay in the transaction index so
+    /// those outputs can be spent; the rest are forgotten. The chainstate decides which is
+    /// which, so nothing is pruned until it has been built, and a pruned chain can't
+    /// rebuild it.
+    ///
+    /// Genesis, blocks at or below the last checkpoint and the
+    /// `COINBASE_MATURITY_WINDOW` blocks below the tip are never pruned.
+    pub fn prune_blocks(&self, keep_blocks: usize) -> Result<usize> {
+        let utxo_tree = self
+            .db
+            .open_tree(UTXO_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
+        if chainstate_entries(&utxo_tree).next().is_none() {
+            info!("Not pruning until the chainstate has been built");
+            return Ok(0);
+        }
+
+        let keep_blocks = keep_blocks.max(COINBASE_MATURITY_WINDOW);
+        let lowest = match self.get_last_checkpoint()? {
+            Some((height, _)) => height + 1,
+            None => 1,
+        };
+        let Some(highest) = self.get_best_height()?.checked_sub(keep_blocks + 1) else {
+            return Ok(0);
+        };
+
+        // Pruning always works upwards from `lowest`, so the first block found pruned on
+        // the way down means everything below it is pruned too
+        let blocks_tree = self
+            .db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+        let mut prunable = Vec::new();
+        for height in (lowest..=highest).rev() {
+            let hash = self.get_block_hash_at_height(height)?.ok_or_else(|| {
+                BlockchainError::Database(format!("No main-chain block at height {height}"))
+            })?;
+            let Some(bytes) = blocks_tree
+                .get(&hash)
+                .map_err(|e| BlockchainError::Database(format!("Failed to get block: {e}")))?
+            else {
+                break;
+            };
+            prunable.push(Block::deserialize(&bytes)?);
+        }
+
+        let headers_tree = self.open_headers_tree()?;
+        let tx_index = self.open_tx_index_tree()?;
+        let pruned_txids = self.open_pruned_txids_tree()?;
+        for block in prunable.iter().rev() {
+            let header = block.get_header().serialize()?;
+            let mut forgotten = Vec::new();
+            for tx in block.get_transactions() {
+                let unspent = utxo_tree.contains_key(tx.get_id()).map_err(|e| {
+                    BlockchainError::Database(format!("Failed to read UTXO tree: {e}"))
+                })?;
+                let indexed_here = Self::read_tx_index(&tx_index, tx.get_id())?
+                    .is_some_and(|entry| entry.block_hash == block.get_hash());
+                if !unspent && indexed_here {
+                    forgotten.push(tx.get_id().to_vec());
+                }
+            }
+
+            // The header goes in as the body goes out, so the block is never missing
+            (&blocks_tree, &headers_tree, &tx_index, &pruned_txids)
+                .transaction(|(tx_blocks, tx_headers, tx_index, tx_pruned)| {
+                    tx_headers.insert(block.get_hash(), header.as_slice())?;
+                    for txid in &forgotten {
+                        tx_index.remove(txid.as_slice())?;
+                        tx_pruned.insert(txid.as_slice(), block.get_hash())?;
+                    }
+                    tx_blocks.remove(block.get_hash())?;
+                    Ok(())
+                })
+                .map_err(|e: TransactionError| {
+                    BlockchainError::Database(format!(
+                        "Failed to prune block {}: {e}",
+                        block.get_hash()
+                    ))
+                })?;
+        }
+        Ok(prunable.len())
+    }
+
+    fn open_headers_tree(&self) -> Result<Tree> {
+        self.db
+            .open_tree(HEADERS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open headers tree: {e}")))
+    }
+
+    fn open_pruned_txids_tree(&self) -> Result<Tree> {
+        self.db.open_tree(PRUNED_TXIDS_TREE).map_err(|e| {
+            BlockchainError::Database(format!("Failed to open pruned transactions tree: {e}"))
+        })
+    }
+
+    /// The height `importsnapshot` loaded this chain's chainstate at, if it did
+    ///
+    /// Everything below it came as headers only, so the chain behaves as pruned there.
+    pub fn get_snapshot_height(&self) -> Result<Option<usize>> {
+        let recorded = self.db.get(SNAPSHOT_HEIGHT_KEY).map_err(|e| {
+            BlockchainError::Database(format!("Failed to read snapshot height: {e}"))
+        })?;
+        Ok(recorded
+            .and_then(|bytes| bytes.as_ref().try_into().ok())
+            .map(|bytes| u64::from_be_bytes(bytes) as usize))
+    }
+
+    // Make `tip` the tip of a chain whose blocks below it are only known by `headers`, as
+    // a snapshot hands them over, see `storage::snapshot`. The headers go in like pruned
+    // blocks', `transactions`, the ones the chainstate still has outputs of, are indexed
+    // in the blocks they name, and the chainstate's entries are replaced by `chainstate`.
+    // All of it is one sled transaction, so a failure on the way leaves the old tip and
+    // chainstate in place.
+    pub(crate) fn install_snapshot(
+        &self,
+        headers: &[BlockHeader],
+        tip: &Block,
+        transactions: &[(String, Transaction)],
+        chainstate: &[(Vec<u8>, Vec<u8>)],
+    ) -> Result<()> {
+        let mut new_headers = Vec::new();
+        for header in headers {
+            if !self.block_exists(&header.hash)? {
+                new_headers.push((header.hash.as_bytes(), header.serialize()?));
+            }
+        }
+        let mut indexed = Vec::with_capacity(transactions.len());
+        for (block_hash, transaction) in transactions {
+            let entry = serialize(&IndexedTransaction {
+                block_hash: block_hash.clone(),
+                transaction: transaction.clone(),
+            })?;
+            indexed.push((transaction.get_id(), entry));
+        }
+        let tip_data = tip.serialize()?;
+
+        let headers_tree = self.open_headers_tree()?;
+        let tx_index = self.open_tx_index_tree()?;
+        let blocks_tree = self
+            .db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+        let utxo_tree = self
+            .db
+            .open_tree(UTXO_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
+        let replaced: Vec<sled::IVec> = utxo_tree
+            .iter()
+            .keys()
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|e| BlockchainError::Database(format!("Failed to iterate UTXO tree: {e}")))?;
+
+        let db: &Tree = &self.db;
+        (db, &blocks_tree, &headers_tree, &tx_index, &utxo_tree)
+            .transaction(|(tx_db, tx_blocks, tx_headers, tx_index, tx_utxo)| {
+                for (hash, header) in &new_headers {
+                    tx_headers.insert(*hash, header.as_slice())?;
+                }
+                for (txid, entry) in &indexed {
+                    tx_index.insert(*txid, entry.as_slice())?;
+                }
+                for key in &replaced {
+                    tx_utxo.remove(key)?;
+                }
+                for (key, value) in chainstate {
+                    tx_utxo.insert(key.as_slice(), value.as_slice())?;
+                }
+                tx_db.insert(CHAINSTATE_VERSION_KEY, &[CHAINSTATE_VERSION])?;
+                tx_db.insert(
+                    SNAPSHOT_HEIGHT_KEY,
+                    &(tip.get_height() as u64).to_be_bytes(),
+                )?;
+                tx_blocks.insert(tip.get_hash(), tip_data.as_slice())?;
+                tx_blocks.insert(TIP_BLOCK_HASH_KEY, tip.get_hash())?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError| {
+                BlockchainError::Database(format!("Failed to install snapshot: {e}"))
+            })?;
+
+        // The height index follows the tip, and is brought up to it again on open if this
+        // fails
+        self.sync_tip_from_db()?;
+        self.update_height_index(tip.get_hash())?;
+        self.db
+            .flush()
+            .map_err(|e| BlockchainError::Database(format!("Failed to flush database: {e}")))?;
+        Ok(())
+    }
+
+    /// Validate a block against the chain and store it
+    ///
+    /// This is the entry point for every block from outside the database: blocks from
+    /// peers, blocks being synced and blocks this node mined itself.
+    ///
+    /// The expected coinbase reward comes from the global fee calculator.
+    pub fn validate_and_add_block(&self, block: &Block) -> Result<BlockAddResult> {
+        self.validate_and_add_block_with_checks(block, &BlockChecks::global())
+    }
+
+    /// Validate a block against the chain and store it, using the node's own fee calculator
+    pub fn validate_and_add_block_with_context(
+        &self,
+        block: &Block,
+        context: &NodeContext,
+    ) -> Result<BlockAddResult> {
+        self.validate_and_add_block_with_checks(block, &BlockChecks::of(context))
+    }
+
+    // Storing a block can make orphans waiting on it connectable, and one of those may
+    // become the tip even when the block itself is only a fork
+    fn validate_and_add_block_with_checks(
+        &self,
+        block: &Block,
+        checks: &BlockChecks,
+    ) -> Result<BlockAddResult> {
+        let result = self.validate_and_store(block, checks)?;
+        if matches!(
+            result,
+            BlockAddResult::TipAdvanced | BlockAddResult::ForkStored
+        ) && self.connect_orphans(block.get_hash(), checks)
+        {
+            return Ok(BlockAddResult::TipAdvanced);
+        }
+        Ok(result)
+    }
+
+    fn validate_and_store(&self, block: &Block, checks: &BlockChecks) -> Result<BlockAddResult> {
+        if self.block_exists(block.get_hash())? {
+            return Ok(BlockAddResult::AlreadyKnown);
+        }
+        // Nothing can be checked against a parent I don't have, so it waits as an orphan
+        let prev_hash = block.get_pre_block_hash();
+        if prev_hash != "None" && !self.block_exists(&prev_hash)? {
+            return self.store_block(block);
+        }
+
+        if let Err(e) = self.validate_block_against_chain(block, checks) {
+            METRICS.blocks_rejected.inc();
+            return Err(e);
+        }
+        let result = self.store_block(block)?;
+        METRICS.blocks_accepted.inc();
+        Ok(result)
+    }
+
+    // I validate and store the orphans waiting on `parent_hash`, then the ones waiting on
+    // those, and report whether any of them became the tip
+    fn connect_orphans(&self, parent_hash: &str, checks: &BlockChecks) -> bool {
+        let mut parents = vec![parent_hash.to_string()];
+        let mut tip_advanced = false;
+        while let Some(parent) = parents.pop() {
+            for orphan in self.take_orphans(&parent) {
+                match self.validate_and_store(&orphan, checks) {
+                    Ok(result) => {
+                        tip_advanced |= result == BlockAddResult::TipAdvanced;
+                        parents.push(orphan.get_hash().to_string());
+                    }
+                    Err(e) => warn!("Dropping orphan block {}: {e}", orphan.get_hash()),
+                }
+            }
+        }
+        tip_advanced
+    }
+
+    fn add_orphan(&self, block: &Block) {
+        let mut orphans = self
+            .orphans
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if orphans
+            .iter()
+            .any(|orphan| orphan.get_hash() == block.get_hash())
+        {
+            return;
+        }
+        if orphans.len() >= MAX_ORPHAN_BLOCKS {
+            orphans.remove(0);
+        }
+        orphans.push(block.clone());
+    }
+
+    fn take_orphans(&self, parent_hash: &str) -> Vec<Block> {
+        let mut orphans = self
+            .orphans
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (children, rest) = std::mem::take(&mut *orphans)
+            .into_iter()
+            .partition(|orphan| orphan.get_pre_block_hash() == parent_hash);
+        *orphans = rest;
+        children
+    }
+
+    /// Number of blocks waiting in memory for their parent
+    pub fn orphan_count(&self) -> usize {
+        self.orphans
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+
+    /// Validate a block this node mined and make it the new tip
+    ///
+    /// Unlike `validate_and_add_block_with_context`, the block is never stored as a fork:
+    /// if its parent is no longer the tip, e.g. because a peer's block arrived during the
+    /// proof-of-work, this fails with a `Mining` error for which `is_stale_tip` holds and
+    /// the caller should rebuild the template on the new tip.
+    pub fn add_mined_block_with_context(&self, block: &Block, context: &NodeContext) -> Result<()> {
+        self.extend_tip(block, &BlockChecks::of(context))
+    }
+
+    fn extend_tip(&self, block: &Block, checks: &BlockChecks) -> Result<()> {
+        if let Err(e) = self.validate_block_against_chain(block, checks) {
+            METRICS.blocks_rejected.inc();
+            return Err(e);
+        }
+        self.commit_on_tip(block)?;
+        METRICS.blocks_accepted.inc();
+        Ok(())
+    }
+
+    // The tip check and both writes happen in one sled transaction, so a block stored by
+    // another thread in the meantime can't be overwritten
+    fn commit_on_tip(&self, block: &Block) -> Result<()> {
+        let block_tree = self
+            .db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+        let work_tree = self.open_chain_work_tree()?;
+        let block_data = block.serialize()?;
+        let chain_work = self.chain_work_with(block)?;
+        let parent_hash = block.get_pre_block_hash();
+
+        (&block_tree, &work_tree)
+            .transaction(|(tx_db, tx_work)| {
+                let stored_tip = tx_db.get(TIP_BLOCK_HASH_KEY)?;
+                if stored_tip.as_deref() != Some(parent_hash.as_bytes()) {
+                    let current = stored_tip
+                        .map(|tip| String::from_utf8_lossy(&tip).into_owned())
+                        .unwrap_or_default();
+                    return Err(ConflictableTransactionError::Abort(current));
+                }
+                tx_db.insert(block.get_hash(), block_data.as_slice())?;
+                tx_work.insert(block.get_hash(), &chain_work.to_be_bytes())?;
+                tx_db.insert(TIP_BLOCK_HASH_KEY, block.get_hash())?;
+                Ok(())
+            })
+            .map_err(|e: TransactionError<String>| match e {
+                TransactionError::Abort(current) => BlockchainError::Mining(format!(
+                    "{STALE_TIP}: block {} builds on {parent_hash}, but the tip is now {current}",
+                    block.get_hash()
+                )),
+                TransactionError::Storage(e) => {
+                    BlockchainError::Database(format!("Failed to add block: {e}"))
+                }
+            })?;
+
+        self.sync_tip_from_db()?;
+        let change = self.update_height_index(&self.get_tip_hash())?;
+        self.publish_tip_change(&change);
+        watch::notify_tip_change(self, &change.connected, &change.disconnected);
+        self.update_address_index(&change);
+        self.record_connected_fees(&change.connected);
+        self.queue_reorged_transactions(&change.disconnected);
+        self.index_block_miner(block);
+        Ok(())
+    }
+
+    /// Record the fees of every block joining the main chain from now on in `history`
+    pub fn record_fees_in(&self, history: Arc<RwLock<FeeHistory>>) {
+        *self
+            .fee_history
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(history);
+    }
+
+    // Add the blocks that just joined the main chain, lowest first, to the fee history
+    fn record_connected_fees(&self, block_hashes: &[String]) {
+        let Some(history) = self
+            .fee_history
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+        else {
+            return;
+        };
+        let records: Result<Vec<BlockFeeRecord>> = block_hashes
+            .iter()
+            .map(|hash| {
+                let block = self
+                    .get_block(hash)?
+                    .ok_or_else(|| BlockchainError::Database(format!("Block {hash} not found")))?;
+                BlockFeeRecord::from_block(&block)
+            })
+            .collect();
+        match records {
+            Ok(records) => {
+                let mut history = history
+                    .write()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                for record in records {
+                    history.record(record);
+                }
+            }
+            Err(e) => warn!("Failed to record block fees: {e}"),
+        }
+    }
+
+    /// Queue the transactions of every block leaving the main chain from now on in `queue`,
+    /// lowest block first, for a memory pool to take back
+    pub fn queue_reorged_in(&self, queue: ReorgQueue) {
+        *self
+            .reorged_out
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(queue);
+    }
+
+    // Queue the non-coinbase transactions of the blocks that just left the main chain,
+    // which come highest first
+    fn queue_reorged_transactions(&self, disconnected: &[(String, usize)]) {
+        if disconnected.is_empty() {
+            return;
+        }
+        let Some(queue) = self
+            .reorged_out
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+        else {
+            return;
+        };
+        let mut queue = queue
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (hash, _) in disconnected.iter().rev() {
+            match self.get_block(hash) {
+                Ok(Some(block)) => queue.extend(
+                    block
+                        .get_transactions()
+                        .iter()
+                        .filter(|tx| !tx.is_coinbase())
+                        .cloned(),
+                ),
+                Ok(None) => warn!("Block {hash} left the main chain but is no longer stored"),
+                Err(e) => warn!("Failed to read block {hash} for its transactions: {e}"),
+            }
+        }
+    }
+
+    // Copying the stored tip under the write lock keeps two writers that commit one after
+    // the other from leaving the older tip in memory
+    fn sync_tip_from_db(&self) -> Result<()> {
+        let block_tree = self
+            .db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+        let mut tip_hash = self
+            .tip_hash
+            .write()
+            .expect("Failed to acquire write lock on tip_hash - this should never happen");
+        let stored_tip = block_tree
+            .get(TIP_BLOCK_HASH_KEY)
+            .map_err(|e| BlockchainError::Database(format!("Failed to read tip hash: {e}")))?
+            .ok_or_else(|| BlockchainError::Database("Tip hash not found".to_string()))?;
+        *tip_hash = String::from_utf8_lossy(&stored_tip).into_owned();
+        Ok(())
+    }
+
+    /// Store a block without validating it
+    ///
+    /// Only for blocks that are already trusted, e.g. in tests; everything else has to go
+    /// through `validate_and_add_block`.
+    pub fn add_block(&self, block: &Block) -> Result<()> {
+        self.store_block(block).map(|_| ())
+    }
+
+    // I run every consensus check that needs the parent block or chain state. Spends are
+    // checked against the outputs unspent at the parent, see `SpendView`.
+    fn validate_block_against_chain(&self, block: &Block, checks: &BlockChecks) -> Result<()> {
+        let invalid = |reason: String| {
+            Err(BlockchainError::InvalidBlock(format!(
+                "Block {} rejected: {reason}",
+                block.get_hash()
+            )))
+        };
+
+        let prev_hash = block.get_pre_block_hash();
+        if prev_hash == "None" {
+            // Ours is already known, so this genesis block starts another network's chain
+            return Err(BlockchainError::DifferentNetwork {
+                genesis: block.get_hash().to_string(),
+                expected: self.get_genesis_hash()?,
+            });
+        }
+        let Some(parent) = self.get_block(&prev_hash)? else {
+            return invalid(format!("previous block {prev_hash} is unknown"));
+        };
+
+        if block.get_height() != parent.get_height() + 1 {
+            return invalid(format!(
+                "height {} does not follow parent height {}",
+                block.get_height(),
+                parent.get_height()
+            ));
+        }
+
+        if self.is_checkpoint_conflict(block) {
+            return invalid(format!(
+                "height {} is checkpointed to a different block",
+                block.get_height()
+            ));
+        }
+        // Once my chain has reached the latest checkpoint, everything up to it is settled
+        let last_checkpoint = self.get_last_checkpoint()?;
+        if let Some((checkpoint_height, checkpoint_hash)) = &last_checkpoint {
+            if block.get_height() <= *checkpoint_height
+                && self.get_block_hash_at_height(*checkpoint_height)?.as_ref()
+                    == Some(checkpoint_hash)
+            {
+                return invalid(format!(
+                    "forks below the checkpoint at height {checkpoint_height} are not accepted"
+                ));
+            }
+        }
+
+        // Timestamps, size limits, merkle root, proof of work and coinbase placement
+        let median_time_past = self.median_time_past(&parent)?;
+        if !block.validate_block_at(
+            Some(median_time_past),
+            network_adjusted_time(checks.time_offset)?,
+        )? {
+            return invalid("block validation failed".to_string());
+        }
+
+        let expected_difficulty = self.calculate_next_difficulty_for_parent(&parent)?;
+        if block.get_difficulty() != expected_difficulty {
+            return invalid(format!(
+                "difficulty {} does not match expected {expected_difficulty}",
+                block.get_difficulty()
+            ));
+        }
+
+        // Only the scheduled subsidy plus the fees actually paid; the balance check on each
+        // transaction below makes sure the declared fees are real
+        let expected_reward = checks
+            .fee_calculator
+            .calculate_coinbase_reward(block.get_total_fees(), block.get_height());
+        if !block.validate_coinbase_reward(expected_reward)? {
+            return invalid(format!(
+                "coinbase does not pay the expected reward of {}",
+                expected_reward.to_satoshis()
+            ));
+        }
+
+        let skip_signatures = self.assumes_valid_below_checkpoints()
+            && last_checkpoint.is_some_and(|(height, _)| block.get_height() < height);
+        let legacy_ids = self.allows_legacy_txids(block.get_height())?;
+        // The view is kept between blocks, so the next one in a batch or on a branch only
+        // steps it from its parent. One that failed halfway through a move is dropped.
+        let mut view = self
+            .spend_view
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let moved = match view.as_mut() {
+            Some(existing) => existing.move_to(self, &prev_hash),
+            None => SpendView::at(self, &prev_hash).map(|built| *view = Some(built)),
+        };
+        if let Err(e) = moved {
+            *view = None;
+            return Err(e);
+        }
+        let view = view.as_ref().expect("the view was just moved or built");
+        if let Some(failure) = self.first_invalid_transaction(
+            view,
+            block.get_transactions(),
+            !skip_signatures,
+            legacy_ids,
+            checks.threads,
+        )? {
+            return invalid(failure.to_string());
+        }
+
+        Ok(())
+    }
+
+    // I write the block and move the tip in one sled transaction. A block whose parent
+    // isn't stored yet is kept as an orphan instead.
+    fn store_block(&self, block: &Block) -> Result<BlockAddResult> {
+        let block_tree = self
+            .db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+
+        if block_tree
+            .get(block.get_hash())
+            .map_err(|e| {
+                BlockchainError::Database(format!("Failed to check block existence: {e}"))
+            })?
+            .is_some()
+        {
+            return Ok(BlockAddResult::AlreadyKnown);
+        }
+        // Its chain work can't be known yet, so it waits in memory for the parent
+        let prev_hash = block.get_pre_block_hash();
+        if prev_hash != "None" && !self.block_exists(&prev_hash)? {
+            self.add_orphan(block);
+            return Ok(BlockAddResult::Orphan);
+        }
+
+        let work_tree = self.open_chain_work_tree()?;
+        let block_data = block.serialize()?;
+        let chain_work = self.chain_work_with(block)?;
+        // The transaction can only read cached work, so the tip's has to be there by now
+        self.get_chain_work(&self.get_tip_hash())?;
+
+        let tip_advanced = (&block_tree, &work_tree)
+            .transaction(|(tx_db, tx_work)| {
+                tx_db.insert(block.get_hash(), block_data.as_slice())?;
+                tx_work.insert(block.get_hash(), &chain_work.to_be_bytes())?;
+
+                // The stored tip, not my in-memory copy, which another thread may not have
+                // updated yet
+                let not_found = |what: &str| {
+                    sled::Error::Io(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("{what} not found"),
+                    ))
+                };
+                let tip_hash = tx_db
+                    .get(TIP_BLOCK_HASH_KEY)?
+                    .ok_or_else(|| not_found("Tip hash"))?;
+                let tip_work = tx_work
+                    .get(&tip_hash)?
+                    .and_then(|bytes| Self::decode_chain_work(&bytes))
+                    .ok_or_else(|| not_found("Chain work of the tip"))?;
+
+                // The heaviest chain wins, not the longest; on a tie the first one seen stays
+                if chain_work > tip_work {
+                    tx_db.insert(TIP_BLOCK_HASH_KEY, block.get_hash())?;
+                    return Ok(true);
+                }
+                Ok(false)
+            })
+            .map_err(|e: sled::transaction::TransactionError| {
+                BlockchainError::Database(format!("Failed to add block: {e}"))
+            })?;
+        self.index_block_miner(block);
+
+        if tip_advanced {
+            // Only after the transaction commits, since sled may retry the closure
+            self.sync_tip_from_db()?;
+            let change = self.update_height_index(&self.get_tip_hash())?;
+            self.publish_tip_change(&change);
+            watch::notify_tip_change(self, &change.connected, &change.disconnected);
+            self.update_address_index(&change);
+            self.record_connected_fees(&change.connected);
+            self.queue_reorged_transactions(&change.disconnected);
+            Ok(BlockAddResult::TipAdvanced)
+        } else {
+            Ok(BlockAddResult::ForkStored)
+        }
+    }
+
+    /// Re-check the stored main chain at `level` 1 to 3, see `core::verify`
+    ///
+    /// Damage is reported in the returned report; an error means the check itself couldn't
+    /// run, e.g. because of an out-of-range level or an unreadable database.
+    pub fn verify_chain(&self, level: u32) -> Result<ChainVerificationReport> {
+        verify::verify_chain(self, level)
+    }
+
+    /// Statistics over the last `window` main-chain blocks, see `core::chain_stats`
+    ///
+    /// The window takes at least the tip and at most the whole chain.
+    pub fn get_chain_stats(&self, window: usize) -> Result<ChainStats> {
+        chain_stats::chain_stats(self, window)
+    }
+
+    /// The same statistics over every block from genesis to the tip
+    pub fn get_lifetime_chain_stats(&self) -> Result<ChainStats> {
+        chain_stats::chain_stats(self, usize::MAX)
+    }
+
+    /// The difficulty the next block has to meet
+    pub fn get_current_difficulty(&self) -> Result<u32> {
+        self.calculate_next_difficulty(self.get_best_height()? + 1)
+    }
+
+    /// Hashes per second the last `window` main-chain blocks imply, see `core::hashrate`
+    ///
+    /// Zero when the window holds a single block, as it does on a chain of only genesis.
+    pub fn estimate_network_hashrate(&self, window: usize) -> Result<f64> {
+        let blocks = hashrate::recent_blocks(self, window)?;
+        Ok(hashrate::window_rate(&blocks).map_or(0.0, |rate| rate.hashrate))
+    }
+
+    /// The current difficulty, the next retarget and the rate of the last `window` blocks
+    pub fn get_difficulty_report(&self, window: usize) -> Result<DifficultyReport> {
+        hashrate::difficulty_report(self, window)
+    }
+
+    pub fn get_best_height(&self) -> Result<usize> {
+        let block_tree = self
+            .db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+        let tip_block_bytes = block_tree
+            .get(self.get_tip_hash())
+            .map_err(|e| BlockchainError::Database(format!("Failed to get tip block: {e}")))?
+            .ok_or_else(|| BlockchainError::Database("Tip hash not found".to_string()))?;
+        let tip_block = Block::deserialize(tip_block_bytes.as_ref())?;
+        Ok(tip_block.get_height())
+    }
+
+    /// Total number of coins created by the main chain so far
+    ///
+    /// Fees only move existing coins around, so this is the sum of the block subsidies
+    /// from genesis up to the tip.
+    pub fn get_total_supply(&self) -> Result<u64> {
+        Ok(monetary::total_subsidy_through(self.get_best_height()?))
+    }
+
+    /// Get a block by the bytes of its hash
+    ///
+    /// A pruned block comes back as its header, see `Block::is_pruned`.
+    pub fn get_block_by_bytes(&self, block_hash: &[u8]) -> Result<Option<Block>> {
+        read_block(&self.db, block_hash)
+    }
+
+    pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
+        self.iterator()
+            .map(|block| block.get_hash_bytes())
+            .collect()
+    }
+
+    /// Check if a block exists in the blockchain, pruned or not
+    pub fn block_exists(&self, block_hash: &str) -> Result<bool> {
+        let exists = |tree: &str| {
+            self.db
+                .open_tree(tree)
+                .and_then(|tree| tree.contains_key(block_hash))
+                .map_err(|e| {
+                    BlockchainError::Database(format!("Failed to check block existence: {e}"))
+                })
+        };
+        Ok(exists(BLOCKS_TREE)? || exists(HEADERS_TREE)?)
+    }
+
+    /// Get a block by hash (string version)
+    ///
+    /// A pruned block comes back as its header, see `Block::is_pruned`.
+    pub fn get_block(&self, block_hash: &str) -> Result<Option<Block>> {
+        read_block(&self.db, block_hash.as_bytes())
+    }
+
+    /// Total work of the chain ending at `block_hash`, see `work_for_difficulty`
+    ///
+    /// Chain work is cached per block as blocks are stored. Blocks stored before the cache
+    /// existed get theirs filled in the first time it's asked for.
+    pub fn get_chain_work(&self, block_hash: &str) -> Result<u128> {
+        let work_tree = self.open_chain_work_tree()?;
+
+        // I walk back to the closest block whose work is known, then add up from there
+        let mut uncached = Vec::new();
+        let mut hash = block_hash.to_string();
+        let mut work = loop {
+            if hash == "None" {
+                break 0;
+            }
+            let cached = work_tree.get(&hash).map_err(|e| {
+                BlockchainError::Database(format!("Failed to read chain work: {e}"))
+            })?;
+            if let Some(work) = cached.and_then(|bytes| Self::decode_chain_work(&bytes)) {
+                break work;
+            }
+            let block = self
+                .get_block(&hash)?
+                .ok_or_else(|| BlockchainError::Database(format!("Block {hash} not found")))?;
+            hash = block.get_pre_block_hash();
+            uncached.push(block);
+        };
+
+        for block in uncached.iter().rev() {
+            work = work.saturating_add(work_for_difficulty(block.get_difficulty()));
+            work_tree
+                .insert(block.get_hash(), &work.to_be_bytes())
+                .map_err(|e| {
+                    BlockchainError::Database(format!("Failed to cache chain work: {e}"))
+                })?;
+        }
+        Ok(work)
+    }
+
+    // The work of `block`'s parent chain plus its own
+    fn chain_work_with(&self, block: &Block) -> Result<u128> {
+        let parent_work = self.get_chain_work(&block.get_pre_block_hash())?;
+        Ok(parent_work.saturating_add(work_for_difficulty(block.get_difficulty())))
+    }
+
+    fn decode_chain_work(bytes: &[u8]) -> Option<u128> {
+        Some(u128::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    fn open_chain_work_tree(&self) -> Result<Tree> {
+        self.db
+            .open_tree(CHAIN_WORK_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open chain work tree: {e}")))
+    }
+
+    /// Get the height of a specific block
+    pub fn get_block_height(&self, block_hash: &str) -> Result<usize> {
+        if let Some(block) = self.get_block(block_hash)? {
+            Ok(block.get_height())
+        } else {
+            Err(BlockchainError::InvalidBlock(format!(
+                "Block not found: {block_hash}"
+            )))
+        }
+    }
+
+    /// Check if a block is in the main chain
+    pub fn is_in_main_chain(&self, block_hash: &str) -> Result<bool> {
+        // Walk back from current tip to see if we encounter this block
+        let mut current_hash = self.get_tip_hash();
+
+        while current_hash != "None" {
+            // Genesis block has "None" as previous hash
+            if current_hash == block_hash {
+                return Ok(true);
+            }
+
+            if let Some(block) = self.get_block(&current_hash)? {
+                current_hash = block.get_pre_block_hash();
+            } else {
+                break;
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Remove a block from the blockchain (for reorganization)
+    pub fn remove_block(&self, block_hash: &str) -> Result<()> {
+        let block_tree = self
+            .db
+            .open_tree(BLOCKS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open blocks tree: {e}")))?;
+
+        // Get the block to find its parent
+        let block = self.get_block(block_hash)?.ok_or_else(|| {
+            BlockchainError::InvalidBlock(format!("Cannot remove non-existent block: {block_hash}"))
+        })?;
+
+        // Its transactions leave the index first, since the height index can't find them
+        // once the block itself is gone
+        Self::unindex_transactions(&self.open_tx_index_tree()?, &block)?;
+
+        // Remove the block from storage
+        block_tree
+            .remove(block_hash)
+            .map_err(|e| BlockchainError::Database(format!("Failed to remove block: {e}")))?;
+        self.open_headers_tree()?
+            .remove(block_hash)
+            .map_err(|e| BlockchainError::Database(format!("Failed to remove header: {e}")))?;
+        self.open_chain_work_tree()?
+            .remove(block_hash)
+            .map_err(|e| BlockchainError::Database(format!("Failed to remove chain work: {e}")))?;
+
+        // Update tip if this was the tip block
+        if self.get_tip_hash() == block_hash {
+            let new_tip = block.get_pre_block_hash();
+            block_tree
+                .insert(TIP_BLOCK_HASH_KEY, new_tip.as_bytes())
+                .map_err(|e| BlockchainError::Database(format!("Failed to update tip: {e}")))?;
+            self.set_tip_hash(&new_tip);
+            let change = self.update_height_index(&new_tip)?;
+            self.publish_tip_change(&change);
+        }
+
+        Ok(())
+    }
+
+    /// Synchronize blockchain with another node's blockchain
+    ///
+    /// Invalid blocks are skipped; returns whether any block was stored. The peer's blocks
+    /// only become the main chain if they carry more work than the current tip.
+    pub fn sync_with_peer(&self, peer_blocks: &[Block]) -> Result<bool> {
+        let mut updated = false;
+
+        // Sort blocks by height to process in order
+        let mut sorted_blocks = peer_blocks.to_vec();
+        sorted_blocks.sort_by_key(|b| b.get_height());
+
+        for block in sorted_blocks {
+            match self.validate_and_add_block(&block) {
+                Ok(BlockAddResult::AlreadyKnown | BlockAddResult::Orphan) => {}
+                Ok(result) => {
+                    updated = true;
+                    info!("Synchronized block: {} ({result:?})", block.get_hash());
+                }
+                Err(e @ (BlockchainError::InvalidBlock(_) | BlockchainError::Transaction(_))) => {
+                    warn!("Skipping block {} from peer: {e}", block.get_hash());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(updated)
+    }
+
+    /// Verify `transactions` as the body of a new block on top of the main chain
+    ///
+    /// The outputs every input spends are looked up first, in order, which also catches an
+    /// output spent twice in the batch. Ids, balances and signatures are then checked with
+    /// nothing left to read, on `node.verify_threads` threads once there are more than
+    /// `PARALLEL_VERIFICATION_THRESHOLD` transactions. However many threads run, the error
+    /// names the lowest index that fails.
+    pub fn verify_transactions_parallel(&self, transactions: &[Transaction]) -> Result<()> {
+        self.verify_transactions_on(transactions, BlockChecks::global().threads)
+    }
+
+    fn verify_transactions_on(&self, transactions: &[Transaction], threads: usize) -> Result<()> {
+        match self.first_invalid_transaction(
+            &SpendView::at(self, &self.get_tip_hash())?,
+            transactions,
+            true,
+            false,
+            threads,
+        )? {
+            Some(failure) => Err(BlockchainError::Transaction(failure.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Threads block verification runs on when `node.verify_threads` names none: one per
+    /// core
+    pub fn default_verification_threads() -> usize {
+        thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    }
+
+    // The lookups stop at the first transaction they can't resolve, so only the ones
+    // before it are verified; any of those failing comes first
+    fn first_invalid_transaction(
+        &self,
+        view: &SpendView,
+        transactions: &[Transaction],
+        check_signatures: bool,
+        allow_legacy_ids: bool,
+        threads: usize,
+    ) -> Result<Option<InvalidTransaction>> {
+        let mut spent_in_batch: HashSet<(&[u8], usize)> = HashSet::new();
+        let mut spent_outputs: Vec<Vec<TXOutput>> = Vec::with_capacity(transactions.len());
+        let mut unresolved = None;
+        'resolve: for (index, transaction) in transactions.iter().enumerate() {
+            // A coinbase spends nothing, but one already in the chain has its reward
+            // checked against its own block
+            if transaction.is_coinbase() {
+                if !transaction.verify_coinbase_in_chain(self) {
+                    unresolved = Some(InvalidTransaction {
+                        index,
+                        reason: None,
+                    });
+                    break;
+                }
+                spent_outputs.push(Vec::new());
+                continue;
+            }
+
+            let mut outputs = Vec::with_capacity(transaction.get_vin().len());
+            for input in transaction.get_vin() {
+                let (txid, vout) = (input.get_txid(), input.get_vout());
+                if !spent_in_batch.insert((txid, vout)) {
+                    unresolved = Some(InvalidTransaction {
+                        index,
+                        reason: Some(format!(
+                            "output {}:{vout} is spent twice in the batch",
+                            HEXLOWER.encode(txid)
+                        )),
+                    });
+                    break 'resolve;
+                }
+                match view.find(self, txid, vout)? {
+                    Some(output) => outputs.push(output),
+                    None => {
+                        let reason = match self.check_input_unspent(txid, vout) {
+                            Err(BlockchainError::Transaction(reason)) => reason,
+                            Err(e) => return Err(e),
+                            Ok(()) => format!(
+                                "output {}:{vout} is already spent on this branch",
+                                HEXLOWER.encode(txid)
+                            ),
+                        };
+                        unresolved = Some(InvalidTransaction {
+                            index,
+                            reason: Some(reason),
+                        });
+                        break 'resolve;
+                    }
+                }
+            }
+            spent_outputs.push(outputs);
+        }
+
+        let resolved = spent_outputs.len();
+        let verifies = |index: usize| {
+            transactions[index].verify_spending(
+                &spent_outputs[index],
+                check_signatures,
+                allow_legacy_ids,
+            )
+        };
+        let failed = if threads <= 1 || transactions.len() <= PARALLEL_VERIFICATION_THRESHOLD {
+            (0..resolved).find(|&index| !verifies(index))
+        } else {
+            #[cfg(test)]
+            PARALLEL_VERIFICATIONS.with(|count| count.set(count.get() + 1));
+
+            // Each thread takes a contiguous run and stops early once a lower index has
+            // failed, as only the lowest one is reported
+            let lowest_failed = AtomicUsize::new(resolved);
+            let chunk = resolved.div_ceil(threads).max(1);
+            thread::scope(|scope| {
+                for start in (0..resolved).step_by(chunk) {
+                    let (verifies, lowest_failed) = (&verifies, &lowest_failed);
+                    scope.spawn(move || {
+                        for index in start..(start + chunk).min(resolved) {
+                            if index >= lowest_failed.load(Ordering::Relaxed) {
+                                return;
+                            }
+                            if !verifies(index) {
+                                lowest_failed.fetch_min(index, Ordering::Relaxed);
+                                return;
+                            }
+                        }
+                    });
+                }
+            });
+            Some(lowest_failed.into_inner()).filter(|&index| index < resolved)
+        };
+
+        Ok(failed
+            .map(|index| InvalidTransaction {
+                index,
+                reason: None,
+            })
+            .or(unresolved))
+    }
+
+    /// Whether output `vout` of `txid` can no longer be spent from the main chain
+    ///
+    /// This asks the chainstate: an output is unspent exactly when it's still there, so
+    /// the answer is only as current as the last chainstate update. Outputs of transactions
+    /// that are only in the memory pool aren't in the chainstate yet and count as spent
+    /// here, as do outputs that never existed and data outputs; callers that accept
+    /// unconfirmed parents have to look in the pool themselves.
+    pub fn is_output_spent(&self, txid: &[u8], vout: usize) -> bool {
+        match self.is_output_unspent(txid, vout) {
+            Ok(unspent) => !unspent,
+            Err(e) => {
+                warn!("Failed to look up output in the chainstate: {e}");
+                true
+            }
+        }
+    }
+
+    pub(crate) fn is_output_unspent(&self, txid: &[u8], vout: usize) -> Result<bool> {
+        Ok(self.find_unspent_output(txid, vout)?.is_some())
+    }
+
+    /// Output `vout` of `txid`, if the main chain has it and it's still unspent
+    pub fn find_unspent_output(&self, txid: &[u8], vout: usize) -> Result<Option<TXOutput>> {
+        let utxo_tree = self
+            .db
+            .open_tree(UTXO_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
+        let Some(outs_bytes) = utxo_tree
+            .get(txid)
+            .map_err(|e| BlockchainError::Database(format!("Failed to get UTXO: {e}")))?
+        else {
+            return Ok(None);
+        };
+
+        Ok(decode_entry(&outs_bytes)?
+            .into_iter()
+            .find(|(idx, _)| *idx == vout)
+            .map(|(_, out)| out))
+    }
+
+    /// Check that output `vout` of `txid` can still be spent from the main chain
+    ///
+    /// The chainstate answers for unspent outputs; for the rest the transaction index tells
+    /// a spent output from one that never existed, so the error says which it was.
+    pub fn check_input_unspent(&self, txid: &[u8], vout: usize) -> Result<()> {
+        if self.is_output_unspent(txid, vout)? {
+            return Ok(());
+        }
+        let outpoint = format!("{}:{vout}", HEXLOWER.encode(txid));
+        let Some(prev_tx) = self.find_transaction(txid)? else {
+            return Err(BlockchainError::Transaction(format!(
+                "Referenced transaction not found: {}",
+                HEXLOWER.encode(txid)
+            )));
+        };
+        match prev_tx.get_vout().get(vout) {
+            None => Err(BlockchainError::Transaction(format!(
+                "Referenced output does not exist: {outpoint}"
+            ))),
+            Some(output) if output.is_data_carrier() => Err(BlockchainError::Transaction(format!(
+                "Referenced output is a data output and can't be spent: {outpoint}"
+            ))),
+            Some(_) => Err(BlockchainError::Transaction(format!(
+                "Input already spent: {outpoint}"
+            ))),
+        }
+    }
+
+    // I want to be able to validate that a transaction's inputs haven't been spent. Spends
+    // are looked up in the chainstate, so whoever adds blocks has to keep it current.
+    pub fn validate_transaction_inputs(&self, transaction: &Transaction) -> Result<bool> {
+        if transaction.is_coinbase() {
+            return Ok(true); // Coinbase transactions don't have real inputs to validate
+        }
+
+        for input in transaction.get_vin() {
+            self.check_input_unspent(input.get_txid(), input.get_vout())?;
+        }
+
+        Ok(true)
+    }
+}
+
+// A stored block, or the header of a pruned one
+fn read_block(db: &Db, block_hash: &[u8]) -> Result<Option<Block>> {
+    BlockTrees::open(db)?.read(block_hash)
+}
+
+// The trees a block is read from, opened once by whoever reads many blocks in a row
+struct BlockTrees {
+    blocks: Tree,
+    headers: Tree,
+}
+
+impl BlockTrees {
+    fn open(db: &Db) -> Result<BlockTrees> {
+        let open = |name: &str| {
+            db.open_tree(name)
+                .map_err(|e| BlockchainError::Database(format!("Failed to open {name} tree: {e}")))
+        };
+        Ok(BlockTrees {
+            blocks: open(BLOCKS_TREE)?,
+            headers: open(HEADERS_TREE)?,
+        })
+    }
+
+    fn read(&self, block_hash: &[u8]) -> Result<Option<Block>> {
+        let read = |tree: &Tree| {
+            tree.get(block_hash)
+                .map_err(|e| BlockchainError::Database(format!("Failed to get block: {e}")))
+        };
+        if let Some(bytes) = read(&self.blocks)? {
+            return Block::deserialize(&bytes).map(Some);
+        }
+        match read(&self.headers)? {
+            Some(bytes) => Ok(Some(Block::from_pruned_header(BlockHeader::deserialize(
+                &bytes,
+            )?))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Walks the chain from a block back to genesis, following previous-block hashes
+///
+/// `try_next` tells a database error or a missing ancestor apart from the end of the
+/// chain. The `Iterator` impl just stops at the first error, for callers that don't care.
+pub struct BlockchainIterator {
+    db: Db,
+    trees: Option<BlockTrees>,
+    current_hash: String,
+}
+
+impl Iterator for BlockchainIterator {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().ok().flatten()
+    }
+}
+
+impl BlockchainIterator {
+    fn new(tip_hash: String, db: Db) -> BlockchainIterator {
+        BlockchainIterator {
+            db,
+            trees: None,
+            current_hash: tip_hash,
+        }
+    }
+
+    /// The next block towards genesis, or `None` once genesis has been returned
+    pub fn try_next(&mut self) -> Result<Option<Block>> {
+        // Genesis has "None" as its previous hash
+        if self.current_hash == "None" {
+            return Ok(None);
+        }
+        #[cfg(test)]
+        BLOCKS_ITERATED.with(|count| count.set(count.get() + 1));
+        let trees = match &self.trees {
+            Some(trees) => trees,
+            None => self.trees.insert(BlockTrees::open(&self.db)?),
+        };
+        let block = trees.read(self.current_hash.as_bytes())?.ok_or_else(|| {
+            BlockchainError::Database(format!(
+                "Block {} is missing from the database",
+                self.current_hash
+            ))
+        })?;
+        self.current_hash = block.get_pre_block_hash();
+        Ok(Some(block))
+    }
+}
+
+/// Main-chain blocks in height order, looked up through the height index
+///
+/// Iterates genesis first, and tip first with `rev`. The range is fixed when the iterator
+/// is made, so blocks mined meanwhile aren't picked up. Like `BlockchainIterator`, the
+/// `Iterator` impls stop at the first error, which `try_next` and `try_next_back` return.
+pub struct BlockchainHeightIterator {
+    heights: Tree,
+    trees: BlockTrees,
+    // The heights not yet returned, from `front` up to `back` exclusive
+    front: usize,
+    back: usize,
+}
+
+impl BlockchainHeightIterator {
+    /// How many blocks are left to return from either end
+    pub fn remaining(&self) -> usize {
+        self.back - self.front
+    }
+
+    pub fn try_next(&mut self) -> Result<Option<Block>> {
+        if self.front >= self.back {
+            return Ok(None);
+        }
+        let block = self.read_at(self.front)?;
+        self.front += 1;
+        Ok(Some(block))
+    }
+
+    pub fn try_next_back(&mut self) -> Result<Option<Block>> {
+        if self.front >= self.back {
+            return Ok(None);
+        }
+        let block = self.read_at(self.back - 1)?;
+        self.back -= 1;
+        Ok(Some(block))
+    }
+
+    fn read_at(&self, height: usize) -> Result<Block> {
+        let missing =
+            || BlockchainError::Database(format!("No main-chain block at height {height}"));
+        let hash = self
+            .heights
+            .get(Blockchain::height_key(height))
+            .map_err(|e| BlockchainError::Database(format!("Failed to read height index: {e}")))?
+            .ok_or_else(missing)?;
+        self.trees.read(&hash)?.ok_or_else(missing)
+    }
+}
+
+impl Iterator for BlockchainHeightIterator {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.try_next();
+        if next.is_err() {
+            self.front = self.back;
+        }
+        next.ok().flatten()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining()))
+    }
+}
+
+impl DoubleEndedIterator for BlockchainHeightIterator {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let next = self.try_next_back();
+        if next.is_err() {
+            self.back = self.front;
+        }
+        next.ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{block_subsidy, MAX_MONEY};
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    // Extends the chain with test blocks whose difficulty comes from the chain itself.
+    // Blocks up to height 9 arrive every 10s, later ones every 300s.
+    fn extend_chain(blockchain: &Blockchain, up_to_height: usize) {
+        let mut tip = blockchain
+            .get_block(&blockchain.get_tip_hash())
+            .unwrap()
+            .unwrap();
+        while tip.get_height() < up_to_height {
+            let height = tip.get_height() + 1;
+            let interval = if height < 10 { 10_000 } else { 300_000 };
+            let difficulty = blockchain.calculate_next_difficulty(height).unwrap();
+            let coinbase = Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap();
+            let block = Block::new_test_block(
+                tip.get_timestamp() + interval,
+                tip.get_hash().to_string(),
+                &[coinbase],
+                height,
+                difficulty,
+            )
+            .unwrap();
+            blockchain.add_block(&block).unwrap();
+            tip = block;
+        }
+    }
+
+    #[test]
+    fn test_difficulty_around_adjustment_boundaries() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        extend_chain(&blockchain, 8);
+        assert_eq!(blockchain.calculate_next_difficulty(9).unwrap(), 4);
+
+        // Heights 0..=9 were mined far too fast
+        extend_chain(&blockchain, 9);
+        assert_eq!(blockchain.calculate_next_difficulty(10).unwrap(), 6);
+
+        // Off the boundary the parent's difficulty carries over
+        extend_chain(&blockchain, 10);
+        assert_eq!(blockchain.calculate_next_difficulty(11).unwrap(), 6);
+        extend_chain(&blockchain, 18);
+        assert_eq!(blockchain.calculate_next_difficulty(19).unwrap(), 6);
+
+        // Heights 10..=19 were mined far too slowly
+        extend_chain(&blockchain, 19);
+        assert_eq!(blockchain.calculate_next_difficulty(20).unwrap(), 4);
+        extend_chain(&blockchain, 20);
+        assert_eq!(blockchain.calculate_next_difficulty(21).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_difficulty_does_not_depend_on_tip() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        extend_chain(&blockchain, 9);
+        let at_ten = blockchain.calculate_next_difficulty(10).unwrap();
+        extend_chain(&blockchain, 19);
+        let at_twenty = blockchain.calculate_next_difficulty(20).unwrap();
+
+        extend_chain(&blockchain, 21);
+        assert_eq!(blockchain.calculate_next_difficulty(10).unwrap(), at_ten);
+        assert_eq!(blockchain.calculate_next_difficulty(20).unwrap(), at_twenty);
+        assert_eq!(
+            blockchain
+                .get_block_at_height(20)
+                .unwrap()
+                .unwrap()
+                .get_difficulty(),
+            at_twenty
+        );
+    }
+
+    #[test]
+    fn test_height_index_follows_tip() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        extend_chain(&blockchain, 3);
+
+        let tip_hash = blockchain.get_tip_hash();
+        assert_eq!(
+            blockchain.get_block_hash_at_height(3).unwrap(),
+            Some(tip_hash.clone())
+        );
+        assert_eq!(blockchain.get_block_hash_at_height(4).unwrap(), None);
+
+        let tip_coinbase = blockchain
+            .get_block(&tip_hash)
+            .unwrap()
+            .unwrap()
+            .get_transactions()[0]
+            .get_id()
+            .to_vec();
+        assert!(blockchain
+            .find_transaction(&tip_coinbase)
+            .unwrap()
+            .is_some());
+
+        blockchain.remove_block(&tip_hash).unwrap();
+        assert_eq!(blockchain.get_block_hash_at_height(3).unwrap(), None);
+        assert!(blockchain.get_block_hash_at_height(2).unwrap().is_some());
+        assert!(blockchain
+            .find_transaction(&tip_coinbase)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_iteration_from_either_end_agrees() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        extend_chain(&blockchain, 29);
+
+        let hashes = |blocks: Vec<Block>| -> Vec<String> {
+            blocks.iter().map(|b| b.get_hash().to_string()).collect()
+        };
+        let mut backward = hashes(blockchain.iterator().collect());
+        let forward = hashes(blockchain.iter_from_genesis().unwrap().collect());
+        assert_eq!(forward.len(), 30);
+        assert_eq!(
+            hashes(blockchain.iter_from_genesis().unwrap().rev().collect()),
+            backward
+        );
+        backward.reverse();
+        assert_eq!(forward, backward);
+
+        // Both ends of one iterator meet without handing out a block twice
+        let mut blocks = blockchain.iter_from_genesis().unwrap();
+        assert_eq!(blocks.next().unwrap().get_height(), 0);
+        assert_eq!(blocks.next_back().unwrap().get_height(), 29);
+        assert_eq!(blocks.remaining(), 28);
+        assert_eq!(blocks.count(), 28);
+    }
+
+    #[test]
+    fn test_migration_rewrites_unversioned_blocks() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        extend_chain(&blockchain, 9);
+        let blocks_tree = blockchain.db.open_tree(BLOCKS_TREE).unwrap();
+        let stored = |height: usize| {
+            let hash = blockchain
+                .get_block_hash_at_height(height)
+                .unwrap()
+                .unwrap();
+            blocks_tree.get(hash).unwrap().unwrap()
+        };
+
+        // As a database written before blocks carried a format version
+        let blocks: Vec<Block> = blockchain.iter_from_genesis().unwrap().collect();
+        let encoded = |blocks: &[Block]| -> Vec<Vec<u8>> {
+            blocks
+                .iter()
+                .map(|block| block.serialize().unwrap())
+                .collect()
+        };
+        let expected = encoded(&blocks);
+        for block in &blocks {
+            blocks_tree
+                .insert(block.get_hash(), block.serialize_as(0).unwrap())
+                .unwrap();
+        }
+        assert_eq!(format_version(&stored(4)).unwrap(), 0);
+        let loaded: Vec<Block> = blockchain.iter_from_genesis().unwrap().collect();
+        assert_eq!(encoded(&loaded), expected);
+        assert_eq!(blockchain.get_block_format_version().unwrap(), 0);
+
+        assert_eq!(blockchain.migrate_block_format().unwrap(), 10);
+        assert!((0..=9).all(|height| format_version(&stored(height)).unwrap() == FORMAT_VERSION));
+        assert_eq!(
+            blockchain.get_block_format_version().unwrap(),
+            FORMAT_VERSION
+        );
+        let loaded: Vec<Block> = blockchain.iter_from_genesis().unwrap().collect();
+        assert_eq!(encoded(&loaded), expected);
+        assert!(blockchain.verify_chain(1).unwrap().is_ok());
+
+        assert_eq!(blockchain.migrate_block_format().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_unreadable_block_is_an_error_not_the_end_of_the_chain() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        extend_chain(&blockchain, 9);
+
+        let corrupted = blockchain.get_block_hash_at_height(5).unwrap().unwrap();
+        blockchain
+            .db
+            .open_tree(BLOCKS_TREE)
+            .unwrap()
+            .insert(corrupted.as_bytes(), &[0xff; 7])
+            .unwrap();
+
+        let mut iterator = blockchain.iterator();
+        for height in (6..=9).rev() {
+            assert_eq!(iterator.try_next().unwrap().unwrap().get_height(), height);
+        }
+        assert!(iterator.try_next().is_err());
+        let mut blocks = blockchain.iter_from_genesis().unwrap();
+        for _ in 0..5 {
+            blocks.try_next().unwrap().unwrap();
+        }
+        assert!(blocks.try_next().is_err());
+
+        // The lossy iterators just stop there
+        assert_eq!(blockchain.iterator().count(), 4);
+        assert_eq!(blockchain.iter_from_genesis().unwrap().count(), 5);
+        assert!(blockchain.reindex_transactions().is_err());
+    }
+
+    #[test]
+    fn test_database_held_open_elsewhere_is_reported_as_locked() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let db_path = db_path.to_str().unwrap();
+        let _running = Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path).unwrap();
+
+        let Err(error) = Blockchain::new_blockchain_with_path(db_path) else {
+            panic!("opened a database another handle holds");
+        };
+        assert!(
+            matches!(error, BlockchainError::DatabaseLocked(_)),
+            "{error}"
+        );
+        assert!(error
+            .to_string()
+            .contains("database is locked by a running node — use --remote or stop the node"));
+    }
+
+    #[test]
+    fn test_database_only_opens_on_the_network_it_was_created_for() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        let network = GLOBAL_CONFIG.get_network();
+        assert_eq!(blockchain.get_network().unwrap(), Some(network));
+        assert_eq!(
+            blockchain.get_network_magic().unwrap(),
+            Some(network.magic(&blockchain.get_genesis_hash().unwrap()))
+        );
+
+        let tip_hash = blockchain.get_tip_hash();
+        blockchain.open_on_network(&tip_hash, network).unwrap();
+        let other = Network::ALL
+            .into_iter()
+            .find(|other| *other != network)
+            .unwrap();
+        let error = blockchain.open_on_network(&tip_hash, other).unwrap_err();
+        assert!(
+            matches!(error, BlockchainError::WrongNetwork { .. }),
+            "{error}"
+        );
+        assert!(error
+            .to_string()
+            .contains(&format!("This datadir belongs to network {network}")));
+
+        // A database from before networks were recorded takes the one it's opened on
+        blockchain.db.remove(NETWORK_KEY).unwrap();
+        blockchain.open_on_network(&tip_hash, other).unwrap();
+        assert_eq!(blockchain.get_network().unwrap(), Some(other));
+    }
+
+    #[test]
+    fn test_transaction_index_matches_chain_scan() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let db_path = db_path.to_str().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path).unwrap();
+        extend_chain(&blockchain, 100);
+
+        let mut chain_txs = Vec::new();
+        for block in blockchain.iterator() {
+            chain_txs.extend(block.get_transactions().iter().cloned());
+        }
+        assert_eq!(chain_txs.len(), 101);
+
+        let scan = |txid: &[u8]| chain_txs.iter().find(|tx| tx.get_id() == txid).cloned();
+        let lookups_match = |blockchain: &Blockchain| {
+            BLOCKS_ITERATED.with(|count| count.set(0));
+            for tx in chain_txs.iter().chain([&Transaction::default()]) {
+                let found = blockchain.find_transaction(tx.get_id()).unwrap();
+                assert_eq!(
+                    found.map(|tx| tx.serialize().unwrap()),
+                    scan(tx.get_id()).map(|tx| tx.serialize().unwrap())
+                );
             }
+            assert_eq!(BLOCKS_ITERATED.with(|count| count.get()), 0);
+        };
+        lookups_match(&blockchain);
+
+        // Spent-ness comes from the chainstate, also without walking the chain
+        crate::storage::UTXOSet::new(blockchain.clone())
+            .reindex_safe()
+            .unwrap();
+        BLOCKS_ITERATED.with(|count| count.set(0));
+        let coinbase = chain_txs[50].get_id();
+        assert!(!blockchain.is_output_spent(coinbase, 0));
+        assert!(blockchain.is_output_spent(coinbase, 1));
+        assert!(blockchain.is_output_spent(&[7; 32], 0));
+        assert_eq!(BLOCKS_ITERATED.with(|count| count.get()), 0);
+
+        // As in a database written before the index existed
+        blockchain.open_tx_index_tree().unwrap().clear().unwrap();
+        drop(blockchain);
+        let reopened = Blockchain::new_blockchain_with_path(db_path).unwrap();
+        lookups_match(&reopened);
+    }
+
+    // `length` unvalidated blocks at `difficulty` on top of `parent`, returning the last
+    fn build_fork(
+        blockchain: &Blockchain,
+        parent: &Block,
+        length: usize,
+        difficulty: u32,
+    ) -> Block {
+        let mut tip = parent.clone();
+        for _ in 0..length {
+            let coinbase = Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap();
+            let block = Block::new_test_block(
+                tip.get_timestamp() + 10_000,
+                tip.get_hash().to_string(),
+                &[coinbase],
+                tip.get_height() + 1,
+                difficulty,
+            )
+            .unwrap();
+            blockchain.add_block(&block).unwrap();
+            tip = block;
         }
+        tip
+    }
 
-        Ok(true)
+    #[test]
+    fn test_heavier_shorter_fork_wins() {
+        for easy_first in [true, false] {
+            let temp_dir = tempdir().unwrap();
+            let db_path = temp_dir.path().join("test_blockchain");
+            let blockchain =
+                Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                    .unwrap();
+            let genesis = blockchain
+                .get_block(&blockchain.get_tip_hash())
+                .unwrap()
+                .unwrap();
+            let genesis_work = blockchain.get_chain_work(genesis.get_hash()).unwrap();
+            assert_eq!(genesis_work, work_for_difficulty(genesis.get_difficulty()));
+
+            let (easy_tip, hard_tip) = if easy_first {
+                let easy = build_fork(&blockchain, &genesis, 5, 1);
+                assert_eq!(blockchain.get_tip_hash(), easy.get_hash());
+                (easy, build_fork(&blockchain, &genesis, 3, 6))
+            } else {
+                let hard = build_fork(&blockchain, &genesis, 3, 6);
+                (build_fork(&blockchain, &genesis, 5, 1), hard)
+            };
+
+            assert_eq!(
+                blockchain.get_chain_work(easy_tip.get_hash()).unwrap(),
+                genesis_work + 5 * 2
+            );
+            assert_eq!(
+                blockchain.get_chain_work(hard_tip.get_hash()).unwrap(),
+                genesis_work + 3 * 64
+            );
+            assert_eq!(blockchain.get_tip_hash(), hard_tip.get_hash());
+            assert_eq!(blockchain.get_best_height().unwrap(), 3);
+            // Only the winning branch's transactions are indexed
+            assert!(blockchain
+                .find_transaction(hard_tip.get_transactions()[0].get_id())
+                .unwrap()
+                .is_some());
+            assert!(blockchain
+                .find_transaction(easy_tip.get_transactions()[0].get_id())
+                .unwrap()
+                .is_none());
+            assert_eq!(
+                blockchain.get_block_hash_at_height(3).unwrap(),
+                Some(hard_tip.get_hash().to_string())
+            );
+        }
     }
-}
 
-pub struct BlockchainIterator {
-    db: Db,
-    current_hash: String,
-}
+    #[test]
+    fn test_confirmations_follow_the_main_chain_through_a_reorg() {
+        use crate::core::watch::{Notification, NotificationEvent, WatchList, NOTIFICATIONS_LOG};
+        use crate::core::{FeeMode, FeePriority, MIN_TRANSACTION_FEE};
+        use crate::storage::UTXOSet;
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
 
-impl Iterator for BlockchainIterator {
-    type Item = Block;
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let mut wallets = Wallets::new();
+        let payer = wallets.create_wallet().unwrap();
+        let blockchain =
+            Blockchain::create_blockchain_with_path(&payer, db_path.to_str().unwrap()).unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        blockchain.set_finality_depth(2);
+        WatchList::open(&blockchain).unwrap().watch(&payer).unwrap();
+        let genesis = blockchain.get_block_at_height(0).unwrap().unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe().unwrap();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).ok()?;
-        let data = block_tree.get(self.current_hash.clone()).ok()??;
-        let block = Block::deserialize(data.to_vec().as_slice()).ok()?;
-        self.current_hash = block.get_pre_block_hash().clone();
-        Some(block)
+        let context = NodeContext::new(FeeMode::Fixed {
+            amount: MIN_TRANSACTION_FEE,
+        })
+        .unwrap();
+        context.follow_reorgs(&blockchain);
+        let payment = Transaction::new_utxo_transaction_with_context(
+            &payer,
+            TEST_ADDRESS,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        let txid = payment.get_id().to_vec();
+        assert_eq!(blockchain.get_confirmations(&txid).unwrap(), None);
+
+        blockchain
+            .mine_block_with_fees(std::slice::from_ref(&payment), TEST_ADDRESS)
+            .unwrap();
+        assert_eq!(blockchain.get_confirmations(&txid).unwrap(), Some(1));
+        assert!(!blockchain.is_final(&txid).unwrap());
+        blockchain.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        assert_eq!(blockchain.get_confirmations(&txid).unwrap(), Some(2));
+        assert!(blockchain.is_final(&txid).unwrap());
+
+        // A heavier branch from genesis leaves the payment out
+        build_fork(&blockchain, &genesis, 3, 1);
+        assert_eq!(blockchain.get_best_height().unwrap(), 3);
+        assert_eq!(blockchain.get_confirmations(&txid).unwrap(), None);
+        assert!(!blockchain.is_final(&txid).unwrap());
+        let genesis_reward = genesis.get_transactions()[0].get_id();
+        assert_eq!(
+            blockchain.get_confirmations(genesis_reward).unwrap(),
+            Some(4)
+        );
+
+        // The payment is pending again, though the fork wasn't added as a peer's block
+        let txid_hex = HEXLOWER.encode(&txid);
+        utxo_set.reindex_safe().unwrap();
+        context.return_reorged_transactions(&blockchain);
+        assert!(context.memory_pool().contains(&txid_hex));
+
+        let events: Vec<(NotificationEvent, Option<usize>, bool)> =
+            std::fs::read_to_string(db_path.join(NOTIFICATIONS_LOG))
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Notification>(line).unwrap())
+                .filter(|n| n.txid == txid_hex && n.address == payer)
+                .map(|n| (n.event, n.height, n.confirmed))
+                .collect();
+        // One outgoing and one change entry for each event, the last for its return
+        assert_eq!(
+            events,
+            vec![
+                (NotificationEvent::Payment, Some(1), true),
+                (NotificationEvent::Payment, Some(1), true),
+                (NotificationEvent::ConfirmedFinal, Some(1), true),
+                (NotificationEvent::ConfirmedFinal, Some(1), true),
+                (NotificationEvent::ReorgedOut, Some(1), false),
+                (NotificationEvent::ReorgedOut, Some(1), false),
+                (NotificationEvent::Payment, None, false),
+                (NotificationEvent::Payment, None, false),
+            ]
+        );
     }
-}
 
-impl BlockchainIterator {
-    fn new(tip_hash: String, db: Db) -> BlockchainIterator {
-        BlockchainIterator {
-            current_hash: tip_hash,
-            db,
+    #[test]
+    fn test_chain_work_is_rebuilt_when_missing() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        extend_chain(&blockchain, 4);
+        let tip_hash = blockchain.get_tip_hash();
+        let work = blockchain.get_chain_work(&tip_hash).unwrap();
+
+        // As in a database written before chain work was cached
+        blockchain.open_chain_work_tree().unwrap().clear().unwrap();
+        assert_eq!(blockchain.get_chain_work(&tip_hash).unwrap(), work);
+        extend_chain(&blockchain, 5);
+        assert!(
+            blockchain
+                .get_chain_work(&blockchain.get_tip_hash())
+                .unwrap()
+                > work
+        );
+    }
+
+    fn next_valid_block(blockchain: &Blockchain, coinbase: Transaction) -> Block {
+        let tip = blockchain
+            .get_block(&blockchain.get_tip_hash())
+            .unwrap()
+            .unwrap();
+        let difficulty = blockchain
+            .calculate_next_difficulty_for_parent(&tip)
+            .unwrap();
+        Block::new_block(
+            tip.get_hash().to_string(),
+            &[coinbase],
+            tip.get_height() + 1,
+            difficulty,
+        )
+        .unwrap()
+    }
+
+    // A mined block on `parent` stamped `timestamp`
+    fn block_at(blockchain: &Blockchain, parent: &Block, timestamp: i64) -> Block {
+        let difficulty = blockchain
+            .calculate_next_difficulty_for_parent(parent)
+            .unwrap();
+        let mut block = Block::new_template_at(
+            timestamp,
+            parent.get_hash().to_string(),
+            &[Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap()],
+            parent.get_height() + 1,
+            difficulty,
+        )
+        .unwrap();
+        block.run_proof_of_work();
+        block
+    }
+
+    #[test]
+    fn test_timestamps_are_checked_against_median_time_past_and_network_time() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        let genesis = blockchain.get_block_at_height(0).unwrap().unwrap();
+        let start = genesis.get_timestamp();
+        let first = block_at(&blockchain, &genesis, start + 10_000);
+        blockchain.validate_and_add_block(&first).unwrap();
+        let second = block_at(&blockchain, &first, start + 20_000);
+        blockchain.validate_and_add_block(&second).unwrap();
+        assert_eq!(
+            blockchain.median_time_past(&second).unwrap(),
+            start + 10_000
+        );
+
+        // Three hours ahead of the network is too far, whatever the chain says
+        let ahead = block_at(
+            &blockchain,
+            &second,
+            network_adjusted_time(0).unwrap() + 3 * 3_600_000,
+        );
+        assert!(blockchain.validate_and_add_block(&ahead).is_err());
+        // Older than the median of the blocks before it
+        let stale = block_at(&blockchain, &second, start + 5_000);
+        assert!(blockchain.validate_and_add_block(&stale).is_err());
+
+        // No older than the parent isn't required, only no older than the median
+        let level = block_at(&blockchain, &second, start + 20_000);
+        assert_eq!(
+            blockchain.validate_and_add_block(&level).unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+        let earlier = block_at(&blockchain, &second, start + 15_000);
+        assert_eq!(
+            blockchain.validate_and_add_block(&earlier).unwrap(),
+            BlockAddResult::ForkStored
+        );
+        // The side branch's median is taken along its own blocks
+        assert_eq!(
+            blockchain.median_time_past(&earlier).unwrap(),
+            start + 15_000
+        );
+
+        // The block three hours ahead is fine for a node whose peers put the network's clock
+        // that far ahead; another node in the same process keeps its own clock
+        let ahead_context = NodeContext::default();
+        ahead_context.set_network_time_offset(2 * 3_600_000);
+        assert_eq!(NodeContext::default().network_time_offset(), 0);
+        assert!(blockchain
+            .validate_and_add_block_with_context(&ahead, &NodeContext::default())
+            .is_err());
+        assert_eq!(
+            blockchain
+                .validate_and_add_block_with_context(&ahead, &ahead_context)
+                .unwrap(),
+            BlockAddResult::ForkStored
+        );
+    }
+
+    #[test]
+    fn test_validate_and_add_block_accepts_valid_block() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let block = next_valid_block(
+            &blockchain,
+            Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap(),
+        );
+        assert_eq!(
+            blockchain.validate_and_add_block(&block).unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+
+        // Seeing the same block again is harmless
+        assert_eq!(
+            blockchain.validate_and_add_block(&block).unwrap(),
+            BlockAddResult::AlreadyKnown
+        );
+    }
+
+    #[test]
+    fn test_orphan_blocks_wait_for_their_parent() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        let genesis = blockchain.get_block_at_height(0).unwrap().unwrap();
+        let start = genesis.get_timestamp();
+        let first = block_at(&blockchain, &genesis, start + 10_000);
+        let second = block_at(&blockchain, &first, start + 20_000);
+        let third = block_at(&blockchain, &second, start + 30_000);
+
+        // Children arriving before their parent are held, not rejected
+        assert_eq!(
+            blockchain.validate_and_add_block(&third).unwrap(),
+            BlockAddResult::Orphan
+        );
+        assert_eq!(
+            blockchain.validate_and_add_block(&second).unwrap(),
+            BlockAddResult::Orphan
+        );
+        assert_eq!(blockchain.orphan_count(), 2);
+        assert_eq!(blockchain.get_tip_hash(), genesis.get_hash());
+
+        // The parent connects both
+        assert_eq!(
+            blockchain.validate_and_add_block(&first).unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+        assert_eq!(blockchain.orphan_count(), 0);
+        assert_eq!(blockchain.get_tip_hash(), third.get_hash());
+        assert_eq!(blockchain.get_best_height().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_validate_and_add_block_stores_fork() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let first = next_valid_block(
+            &blockchain,
+            Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap(),
+        );
+        let competing = next_valid_block(
+            &blockchain,
+            Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap(),
+        );
+        blockchain.validate_and_add_block(&first).unwrap();
+
+        assert_eq!(
+            blockchain.validate_and_add_block(&competing).unwrap(),
+            BlockAddResult::ForkStored
+        );
+        assert_eq!(blockchain.get_tip_hash(), first.get_hash());
+    }
+
+    #[test]
+    fn test_validate_and_add_block_rejects_invalid_pow() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let mut block = next_valid_block(
+            &blockchain,
+            Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap(),
+        );
+        let mut nonce = block.get_nonce();
+        while crate::core::ProofOfWork::validate(&block) {
+            nonce += 1;
+            block.set_nonce(nonce);
+        }
+
+        assert!(blockchain.validate_and_add_block(&block).is_err());
+        assert!(!blockchain.block_exists(block.get_hash()).unwrap());
+        assert_eq!(blockchain.get_best_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_validate_and_add_block_rejects_outputs_above_max_money() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let coinbase =
+            Transaction::new_coinbase_tx_with_reward(TEST_ADDRESS, MAX_MONEY + 1).unwrap();
+        let block = next_valid_block(&blockchain, coinbase);
+
+        let err = blockchain.validate_and_add_block(&block).unwrap_err();
+        assert!(err.to_string().contains("supply cap"), "{err}");
+        assert_eq!(blockchain.get_best_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_validate_and_add_block_rejects_wrong_coinbase_reward() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let expected = FeeCalculator::calculate_coinbase_reward(Amount::ZERO, 1);
+        assert_eq!(expected, block_subsidy(1));
+        let coinbase =
+            Transaction::new_coinbase_tx_with_reward(TEST_ADDRESS, expected.to_satoshis() + 1)
+                .unwrap();
+        let block = next_valid_block(&blockchain, coinbase);
+
+        assert!(blockchain.validate_and_add_block(&block).is_err());
+        assert_eq!(blockchain.get_best_height().unwrap(), 0);
+
+        // Claiming exactly the subsidy is fine
+        let coinbase = Transaction::new_coinbase_tx_with_reward(TEST_ADDRESS, expected).unwrap();
+        let block = next_valid_block(&blockchain, coinbase);
+        assert_eq!(
+            blockchain.validate_and_add_block(&block).unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+    }
+
+    #[test]
+    fn test_mining_refuses_a_supplied_coinbase() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let coinbase = Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap();
+        assert!(matches!(
+            blockchain.mine_block_with_fees(&[coinbase], TEST_ADDRESS),
+            Err(BlockchainError::CoinbaseNotAllowed(_))
+        ));
+        assert_eq!(blockchain.get_best_height().unwrap(), 0);
+
+        // The coinbase a block was mined with checks out against that block
+        let block = blockchain.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        assert!(block.get_transactions()[0].verify(&blockchain));
+        let genesis = blockchain.get_block_at_height(0).unwrap().unwrap();
+        assert!(genesis.get_transactions()[0].verify(&blockchain));
+    }
+
+    #[test]
+    fn test_total_supply_counts_subsidies() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        assert_eq!(blockchain.get_total_supply().unwrap(), block_subsidy(0));
+
+        blockchain.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        blockchain.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        assert_eq!(
+            blockchain.get_total_supply().unwrap(),
+            block_subsidy(0) + block_subsidy(1) + block_subsidy(2)
+        );
+    }
+
+    #[test]
+    fn test_validate_and_add_block_rejects_wrong_difficulty() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let easy_block = Block::new_block(
+            blockchain.get_tip_hash(),
+            &[Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap()],
+            1,
+            1,
+        )
+        .unwrap();
+        assert!(blockchain.validate_and_add_block(&easy_block).is_err());
+    }
+
+    fn blocks_above_genesis(blockchain: &Blockchain) -> Vec<Block> {
+        let height = blockchain.get_best_height().unwrap();
+        (1..=height)
+            .map(|h| blockchain.get_block_at_height(h).unwrap().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_sync_rejects_fork_contradicting_checkpoint() {
+        let temp_dir = tempdir().unwrap();
+        let path = |name: &str| temp_dir.path().join(name).to_str().unwrap().to_string();
+
+        let honest =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, &path("honest")).unwrap();
+        let genesis = honest.get_block_at_height(0).unwrap().unwrap();
+        let forked =
+            Blockchain::create_blockchain_from_genesis(&genesis, &path("forked"), false).unwrap();
+        for _ in 0..3 {
+            honest.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+            forked.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        }
+        let honest_blocks = blocks_above_genesis(&honest);
+        let forked_blocks = blocks_above_genesis(&forked);
+
+        let node =
+            Blockchain::create_blockchain_from_genesis(&genesis, &path("node"), false).unwrap();
+        node.add_checkpoint(2, honest_blocks[1].get_hash()).unwrap();
+        assert!(node.is_checkpoint_conflict(&forked_blocks[1]));
+        assert!(!node.is_checkpoint_conflict(&honest_blocks[1]));
+
+        // The fork is cut off at the checkpointed height
+        node.sync_with_peer(&forked_blocks).unwrap();
+        assert_eq!(node.get_best_height().unwrap(), 1);
+        assert!(!node.block_exists(forked_blocks[1].get_hash()).unwrap());
+
+        assert!(node.sync_with_peer(&honest_blocks).unwrap());
+        assert_eq!(node.get_tip_hash(), honest.get_tip_hash());
+
+        // Past the checkpoint, new forks below it are refused even at unpinned heights
+        let late_fork = Block::new_block(
+            genesis.get_hash().to_string(),
+            &[
+                Transaction::new_coinbase_tx_with_collected_fees(TEST_ADDRESS, Amount::ZERO, 1)
+                    .unwrap(),
+            ],
+            1,
+            node.calculate_next_difficulty(1).unwrap(),
+        )
+        .unwrap();
+        assert!(node.validate_and_add_block(&late_fork).is_err());
+
+        // A checkpoint can't contradict the chain the node already has
+        assert!(node.add_checkpoint(3, forked_blocks[2].get_hash()).is_err());
+    }
+
+    #[test]
+    fn test_sync_below_checkpoint_skips_signatures_when_assuming_valid() {
+        use crate::core::{FeeMode, FeePriority};
+        use crate::storage::UTXOSet;
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let path = |name: &str| temp_dir.path().join(name).to_str().unwrap().to_string();
+
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let source = Blockchain::create_blockchain_with_path(&sender, &path("source")).unwrap();
+        let utxo_set = UTXOSet::new(source.clone());
+        utxo_set.reindex();
+
+        // A block whose only flaw is a bad signature, stored without validation
+        let context = NodeContext::new(FeeMode::Fixed { amount: 2 }).unwrap();
+        let mut tx = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &recipient,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        tx.corrupt_signatures();
+        let coinbase =
+            Transaction::new_coinbase_tx_with_collected_fees(TEST_ADDRESS, tx.get_fee(), 1)
+                .unwrap();
+        let unsigned_block = Block::new_block(
+            source.get_tip_hash(),
+            &[coinbase, tx],
+            1,
+            source.calculate_next_difficulty(1).unwrap(),
+        )
+        .unwrap();
+        source.add_block(&unsigned_block).unwrap();
+        source.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        let blocks = blocks_above_genesis(&source);
+
+        let genesis = source.get_block_at_height(0).unwrap().unwrap();
+        let node =
+            Blockchain::create_blockchain_from_genesis(&genesis, &path("node"), false).unwrap();
+        node.add_checkpoint(2, &source.get_tip_hash()).unwrap();
+
+        assert!(!node.sync_with_peer(&blocks).unwrap());
+        assert_eq!(node.get_best_height().unwrap(), 0);
+
+        node.set_assume_valid_below_checkpoints(true);
+        assert!(node.sync_with_peer(&blocks).unwrap());
+        assert_eq!(node.get_tip_hash(), source.get_tip_hash());
+    }
+
+    fn mine_template(blockchain: &Blockchain, context: &NodeContext) -> Block {
+        let mut block = blockchain
+            .new_block_template(&[], TEST_ADDRESS, context)
+            .unwrap();
+        block.run_proof_of_work();
+        block
+    }
+
+    #[test]
+    fn test_external_miner_solves_template() {
+        use crate::core::{FeeMode, FeePriority};
+        use crate::storage::{MemoryPool, UTXOSet};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let blockchain =
+            Blockchain::create_blockchain_with_path(&sender, db_path.to_str().unwrap()).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+
+        let context = NodeContext::new(FeeMode::Fixed { amount: 2 }).unwrap();
+        let tx = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &recipient,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        let mempool = MemoryPool::new();
+        mempool.add(tx.clone());
+
+        let template = blockchain
+            .build_block_template(TEST_ADDRESS, &mempool)
+            .unwrap();
+        assert_eq!(template.pre_block_hash, blockchain.get_tip_hash());
+        assert_eq!(template.height, 1);
+        assert_eq!(template.transactions.len(), 1);
+        assert_eq!(template.transactions[0].get_id(), tx.get_id());
+        assert_eq!(template.total_fees, tx.get_fee());
+        let reward = block_subsidy(1) + tx.get_fee().to_satoshis();
+        assert_eq!(template.coinbase.get_output_value().unwrap(), reward);
+
+        // The search an external miner would run
+        let solves = |nonce: i64| ProofOfWork::validate(&template.solve(nonce).unwrap());
+        let nonce = (0..).find(|nonce| solves(*nonce)).unwrap();
+        let wrong_nonce = (0..).find(|nonce| !solves(*nonce)).unwrap();
+        assert!(matches!(
+            blockchain.submit_block((template.clone(), wrong_nonce)),
+            Err(BlockchainError::InvalidBlock(_))
+        ));
+        assert_eq!(blockchain.get_best_height().unwrap(), 0);
+
+        blockchain.submit_block((template.clone(), nonce)).unwrap();
+        let tip = blockchain
+            .get_block(&blockchain.get_tip_hash())
+            .unwrap()
+            .unwrap();
+        assert_eq!(tip.get_height(), 1);
+        assert_eq!(tip.get_nonce(), nonce);
+        let coinbase_id = HEXLOWER.encode(template.coinbase.get_id());
+        let paid: u64 = blockchain.find_utxo()[&coinbase_id]
+            .iter()
+            .map(|(_, output)| output.get_value().to_satoshis())
+            .sum();
+        assert_eq!(paid, reward);
+
+        // The same work can't be submitted twice
+        assert!(blockchain
+            .submit_block(template.solve(nonce).unwrap())
+            .is_err());
+        assert_eq!(blockchain.get_tip_hash(), tip.get_hash());
+    }
+
+    #[test]
+    fn test_mined_block_on_stale_tip_is_refused() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        let context = NodeContext::default();
+
+        // A peer's block for the same height lands while mine is still being solved
+        let mined = mine_template(&blockchain, &context);
+        let network_block = mine_template(&blockchain, &context);
+        assert_eq!(
+            blockchain
+                .validate_and_add_block_with_context(&network_block, &context)
+                .unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+
+        let err = blockchain
+            .add_mined_block_with_context(&mined, &context)
+            .unwrap_err();
+        assert!(err.is_stale_tip(), "unexpected error: {err}");
+        assert!(!blockchain.block_exists(mined.get_hash()).unwrap());
+        assert_eq!(blockchain.get_tip_hash(), network_block.get_hash());
+
+        // Rebuilt on the new tip it goes through
+        let retried = mine_template(&blockchain, &context);
+        blockchain
+            .add_mined_block_with_context(&retried, &context)
+            .unwrap();
+        assert_eq!(blockchain.get_best_height().unwrap(), 2);
+        assert_eq!(
+            blockchain.get_block_hash_at_height(1).unwrap().as_deref(),
+            Some(network_block.get_hash())
+        );
+        assert_eq!(
+            blockchain.get_block_hash_at_height(2).unwrap().as_deref(),
+            Some(retried.get_hash())
+        );
+    }
+
+    #[test]
+    fn test_concurrent_mining_and_network_block_keep_chain_consistent() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        let context = Arc::new(NodeContext::default());
+
+        for _ in 0..3 {
+            let network_block = mine_template(&blockchain, &context);
+            let start = Arc::new(std::sync::Barrier::new(2));
+
+            let miner = {
+                let (blockchain, context, start) =
+                    (blockchain.clone(), Arc::clone(&context), Arc::clone(&start));
+                std::thread::spawn(move || {
+                    start.wait();
+                    // One retry is enough, since only one competing block is in flight
+                    for _ in 0..2 {
+                        let block = mine_template(&blockchain, &context);
+                        match blockchain.add_mined_block_with_context(&block, &context) {
+                            Ok(()) => return block,
+                            Err(e) => assert!(e.is_stale_tip(), "unexpected error: {e}"),
+                        }
+                    }
+                    panic!("mined block was stale twice");
+                })
+            };
+            let network = {
+                let (blockchain, context, block) = (
+                    blockchain.clone(),
+                    Arc::clone(&context),
+                    network_block.clone(),
+                );
+                std::thread::spawn(move || {
+                    start.wait();
+                    blockchain
+                        .validate_and_add_block_with_context(&block, &context)
+                        .unwrap()
+                })
+            };
+            let mined = miner.join().unwrap();
+            network.join().unwrap();
+
+            // Nothing was lost, and the mined block ends up on top of the main chain
+            assert!(blockchain.block_exists(network_block.get_hash()).unwrap());
+            assert_eq!(blockchain.get_tip_hash(), mined.get_hash());
+        }
+
+        // The stored tip, the in-memory tip and the height index all agree
+        let stored_tip = blockchain
+            .get_db()
+            .open_tree(BLOCKS_TREE)
+            .unwrap()
+            .get(TIP_BLOCK_HASH_KEY)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored_tip.as_ref(), blockchain.get_tip_hash().as_bytes());
+        let mut hash = blockchain.get_tip_hash();
+        while let Some(block) = blockchain.get_block(&hash).unwrap() {
+            assert_eq!(
+                blockchain
+                    .get_block_hash_at_height(block.get_height())
+                    .unwrap()
+                    .as_deref(),
+                Some(block.get_hash())
+            );
+            hash = block.get_pre_block_hash();
+        }
+        assert_eq!(hash, "None");
+    }
+
+    #[test]
+    fn test_pruning_keeps_headers_and_unspent_transactions() {
+        use crate::core::{FeeMode, FeePriority};
+        use crate::storage::UTXOSet;
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let miner = wallets.create_wallet().unwrap();
+        let payer = wallets.create_wallet().unwrap();
+        let payee = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &miner,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+
+        // The payer's only coin is spent whole, so nothing of its coinbase is left once
+        // height 1 is pruned, while the payment's output stays unspent
+        let funding = blockchain
+            .mine_block_with_context(&[], &payer, &context)
+            .unwrap();
+        utxo_set.reindex_safe().unwrap();
+        let payment = Transaction::new_utxo_transaction_with_context(
+            &payer,
+            &payee,
+            block_subsidy(1) - 3,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        blockchain
+            .mine_block_with_context(std::slice::from_ref(&payment), &miner, &context)
+            .unwrap();
+        while blockchain.get_best_height().unwrap() < 49 {
+            blockchain
+                .mine_block_with_context(&[], &miner, &context)
+                .unwrap();
+        }
+        utxo_set.reindex_safe().unwrap();
+
+        let balances = || {
+            [&miner, &payer, &payee].map(|address| {
+                let pub_key_hash = TXOutput::new(1, address)
+                    .unwrap()
+                    .get_pub_key_hash()
+                    .to_vec();
+                utxo_set
+                    .find_utxo(&pub_key_hash)
+                    .iter()
+                    .map(|output| output.get_value().to_satoshis())
+                    .sum::<u64>()
+            })
+        };
+        let tree_len = |name: &str| blockchain.get_db().open_tree(name).unwrap().len();
+        let balances_before = balances();
+        let blocks_before = tree_len(BLOCKS_TREE);
+
+        // Heights 1 to 38 are buried more than 10 blocks deep; genesis always stays
+        assert_eq!(blockchain.prune_blocks(10).unwrap(), 38);
+        assert_eq!(tree_len(BLOCKS_TREE), blocks_before - 38);
+        assert_eq!(tree_len(HEADERS_TREE), 38);
+        assert!(blockchain.is_pruned().unwrap());
+        // Asking for less than the maturity window still keeps it
+        assert_eq!(blockchain.prune_blocks(0).unwrap(), 0);
+
+        let pruned = blockchain.get_block_at_height(20).unwrap().unwrap();
+        assert!(pruned.is_pruned());
+        assert_eq!(blockchain.get_block_height(pruned.get_hash()).unwrap(), 20);
+        for height in [0, 39, 49] {
+            assert!(!blockchain
+                .get_block_at_height(height)
+                .unwrap()
+                .unwrap()
+                .is_pruned());
+        }
+
+        for level in 1..=2 {
+            let report = blockchain.verify_chain(level).unwrap();
+            assert!(report.is_ok(), "{report}");
+            assert_eq!(report.blocks_checked, 50);
+        }
+        assert!(matches!(
+            blockchain.verify_chain(3),
+            Err(BlockchainError::Pruned(_))
+        ));
+        assert_eq!(balances(), balances_before);
+
+        assert!(matches!(
+            blockchain.find_transaction(funding.get_transactions()[0].get_id()),
+            Err(BlockchainError::Pruned(_))
+        ));
+        assert!(blockchain
+            .find_transaction(payment.get_id())
+            .unwrap()
+            .is_some());
+        assert!(!blockchain.is_output_spent(payment.get_id(), 0));
+        assert!(matches!(
+            utxo_set.reindex_safe(),
+            Err(BlockchainError::Pruned(_))
+        ));
+    }
+
+    #[test]
+    fn test_generate_mines_pending_transactions_at_forced_difficulty() {
+        use crate::core::{FeeMode, FeePriority};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let funder = wallets.create_wallet().unwrap();
+        let miner = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &funder,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+
+        let first = blockchain.generate_blocks(5, &miner, &context).unwrap();
+        assert_eq!(first.len(), 5);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let pending = Transaction::new_utxo_transaction_with_context(
+            &funder,
+            TEST_ADDRESS,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        context.memory_pool().add(pending.clone());
+        let rest = blockchain.generate_blocks(10, &miner, &context).unwrap();
+
+        assert_eq!(blockchain.get_best_height().unwrap(), 15);
+        assert!(first
+            .iter()
+            .chain(&rest)
+            .all(|block| block.get_difficulty() == 1));
+        assert_eq!(rest[0].get_transactions()[1].get_id(), pending.get_id());
+        assert!(rest[1..]
+            .iter()
+            .all(|block| block.get_transactions().len() == 1));
+        assert!(context.memory_pool().is_empty());
+
+        // The chainstate followed along, and only the fee adds to the subsidies
+        let pub_key_hash = TXOutput::new(1, &miner)
+            .unwrap()
+            .get_pub_key_hash()
+            .to_vec();
+        let balance: u64 = utxo_set
+            .find_utxo(&pub_key_hash)
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .sum();
+        let subsidies: u64 = (1..=15).map(block_subsidy).sum();
+        assert_eq!(balance, subsidies + pending.get_fee().to_satoshis());
+    }
+
+    #[test]
+    fn test_events_follow_a_transaction_from_pool_to_reorg() {
+        use crate::core::{FeeMode, FeePriority, DEFAULT_EVENT_BUFFER};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let funder = Wallets::new().create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &funder,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        context
+            .memory_pool()
+            .set_event_bus(blockchain.events().clone());
+        let (_, events) = blockchain.events().subscribe(DEFAULT_EVENT_BUFFER);
+        // Never read, so it fills up after the first event
+        let (_, stalled) = blockchain.events().subscribe(1);
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe().unwrap();
+        let pending = Transaction::new_utxo_transaction_with_context(
+            &funder,
+            TEST_ADDRESS,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        context.memory_pool().add(pending.clone());
+        let block = blockchain
+            .generate_blocks(1, TEST_ADDRESS, &context)
+            .unwrap()
+            .remove(0);
+
+        let txid = |tx: &Transaction| HEXLOWER.encode(tx.get_id());
+        let hash = block.get_hash().to_string();
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                ChainEvent::TxAccepted {
+                    txid: txid(&pending)
+                },
+                ChainEvent::BlockConnected {
+                    hash: hash.clone(),
+                    height: 1
+                },
+                ChainEvent::TxConfirmed {
+                    txid: txid(&block.get_transactions()[0]),
+                    block_hash: hash.clone()
+                },
+                ChainEvent::TxConfirmed {
+                    txid: txid(&pending),
+                    block_hash: hash.clone()
+                },
+            ]
+        );
+
+        // Mining carries on past the stalled subscriber
+        let more = blockchain
+            .generate_blocks(2, TEST_ADDRESS, &context)
+            .unwrap();
+        assert_eq!(blockchain.get_best_height().unwrap(), 3);
+        assert_eq!(stalled.try_iter().count(), 1);
+        assert_eq!(blockchain.events().dropped_events(), 7);
+
+        // One heavier block on genesis takes all three off the main chain, newest first
+        events.try_iter().for_each(drop);
+        let genesis = blockchain.get_block_at_height(0).unwrap().unwrap();
+        let fork = build_fork(&blockchain, &genesis, 1, 6);
+        let mut expected: Vec<ChainEvent> = more
+            .iter()
+            .rev()
+            .chain([&block])
+            .map(|block| ChainEvent::BlockDisconnected {
+                hash: block.get_hash().to_string(),
+                height: block.get_height(),
+            })
+            .collect();
+        expected.push(ChainEvent::BlockConnected {
+            hash: fork.get_hash().to_string(),
+            height: 1,
+        });
+        expected.push(ChainEvent::TxConfirmed {
+            txid: txid(&fork.get_transactions()[0]),
+            block_hash: fork.get_hash().to_string(),
+        });
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_mining_a_sent_transaction_takes_pending_ones_along() {
+        use crate::core::{FeeMode, FeePriority};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let senders: Vec<String> = (0..3).map(|_| wallets.create_wallet().unwrap()).collect();
+        let recipient = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &senders[0],
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        for sender in &senders[1..] {
+            blockchain.generate_blocks(1, sender, &context).unwrap();
         }
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let pay = |from: &str| {
+            Transaction::new_utxo_transaction_with_context(
+                from,
+                &recipient,
+                1_000,
+                FeePriority::Normal,
+                &utxo_set,
+                &context,
+            )
+            .unwrap()
+        };
+        let mempool = context.memory_pool();
+        mempool.add(pay(&senders[0]));
+        mempool.add(pay(&senders[1]));
+        let sent = pay(&senders[2]);
+
+        let block = blockchain
+            .mine_transaction_with_context(&sent, &senders[2], &context)
+            .unwrap();
+        let transactions = block.get_transactions();
+        assert_eq!(transactions.len(), 4);
+        assert!(transactions[0].is_coinbase());
+        assert_eq!(transactions[1].get_id(), sent.get_id());
+        assert!(mempool.is_empty());
+
+        // The chainstate already holds the block's outputs
+        let pub_key_hash = TXOutput::new(1, &recipient)
+            .unwrap()
+            .get_pub_key_hash()
+            .to_vec();
+        let received: u64 = utxo_set
+            .find_utxo(&pub_key_hash)
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .sum();
+        assert_eq!(received, 3_000);
+    }
+
+    #[test]
+    fn test_spends_are_checked_against_the_block_s_own_branch() {
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let wallet = wallets.get_wallet(&owner).unwrap().clone();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &owner,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+
+        let spend = |source: &Transaction, fee: u64| {
+            let output = source.get_vout()[0].clone();
+            let value = output.get_value().to_satoshis() - fee;
+            let mut tx = Transaction::new_raw(
+                &[(source.get_id().to_vec(), 0)],
+                vec![TXOutput::new(value, &owner).unwrap()],
+            )
+            .unwrap();
+            tx.sign_with_outputs(&wallet, &[output]).unwrap();
+            tx
+        };
+        let block_on = |parent: &Block, transactions: &[Transaction]| {
+            let height = parent.get_height() + 1;
+            let fees = transactions
+                .iter()
+                .fold(Amount::ZERO, |sum, tx| sum.saturating_add(tx.get_fee()));
+            let reward = Amount::from(block_subsidy(height)).saturating_add(fees);
+            let coinbase = Transaction::new_coinbase_tx_with_reward(TEST_ADDRESS, reward).unwrap();
+            let mut block = Block::new_template_at(
+                parent.get_timestamp() + 10_000,
+                parent.get_hash().to_string(),
+                &[&[coinbase], transactions].concat(),
+                height,
+                blockchain
+                    .calculate_next_difficulty_for_parent(parent)
+                    .unwrap(),
+            )
+            .unwrap();
+            block.run_proof_of_work();
+            block
+        };
+
+        // The main chain spends the genesis reward, and the chainstate follows it
+        let genesis = blockchain.get_block_at_height(0).unwrap().unwrap();
+        let reward = genesis.get_transactions()[0].clone();
+        let paid_on_main = spend(&reward, 1_000);
+        let main = block_on(&genesis, std::slice::from_ref(&paid_on_main));
+        assert_eq!(
+            blockchain.validate_and_add_block(&main).unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+        utxo_set.update_safe(&main).unwrap();
+
+        // On a competing branch the reward is still there to spend
+        let paid_on_fork = spend(&reward, 2_000);
+        let fork = block_on(&genesis, std::slice::from_ref(&paid_on_fork));
+        assert_eq!(
+            blockchain.validate_and_add_block(&fork).unwrap(),
+            BlockAddResult::ForkStored
+        );
+
+        // Built on the fork, only the fork's own payment can be spent
+        let respent_main = block_on(&fork, &[spend(&paid_on_main, 1_000)]);
+        assert!(blockchain.validate_and_add_block(&respent_main).is_err());
+        let next_on_fork = spend(&paid_on_fork, 1_000);
+        let fork_tip = block_on(&fork, std::slice::from_ref(&next_on_fork));
+        assert_eq!(
+            blockchain.validate_and_add_block(&fork_tip).unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+
+        // The chainstate is still on the old branch, yet the next block checks out
+        let after = block_on(&fork_tip, &[spend(&next_on_fork, 1_000)]);
+        assert_eq!(
+            blockchain.validate_and_add_block(&after).unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+        assert_eq!(blockchain.get_best_height().unwrap(), 3);
     }
 
-    #[allow(clippy::should_implement_trait)]
-    pub fn next(&mut self) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).ok()?;
-        let data = block_tree.get(self.current_hash.clone()).ok()??;
-        let block = Block::deserialize(data.to_vec().as_slice()).ok()?;
-        self.current_hash = block.get_pre_block_hash().clone();
-        Some(block)
+    #[test]
+    fn test_block_transactions_verify_alike_on_one_thread_or_many() {
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let wallet = wallets.get_wallet(&owner).unwrap().clone();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &owner,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+
+        // Split the genesis reward into 200 coins, then spend each in its own transaction
+        let genesis = blockchain.get_block_at_height(0).unwrap().unwrap();
+        let reward = genesis.get_transactions()[0].get_id().to_vec();
+        let change = genesis.get_transactions()[0].get_vout()[0]
+            .get_value()
+            .to_satoshis()
+            - 200 * 10_000
+            - 1_000;
+        let coins: Vec<TXOutput> = (0..200)
+            .map(|_| TXOutput::new(10_000, &owner).unwrap())
+            .chain([TXOutput::new(change, &owner).unwrap()])
+            .collect();
+        let mut funding = Transaction::new_raw(&[(reward, 0)], coins).unwrap();
+        funding.sign_raw(&wallet, &blockchain).unwrap();
+        let funded = blockchain
+            .mine_block_with_fees(std::slice::from_ref(&funding), TEST_ADDRESS)
+            .unwrap();
+        utxo_set.update_safe(&funded).unwrap();
+        let spends: Vec<Transaction> = (0..200)
+            .map(|vout| {
+                let paid = TXOutput::new(9_000, TEST_ADDRESS).unwrap();
+                let mut spend =
+                    Transaction::new_raw(&[(funding.get_id().to_vec(), vout)], vec![paid]).unwrap();
+                spend.sign_raw(&wallet, &blockchain).unwrap();
+                spend
+            })
+            .collect();
+
+        let view = SpendView::at(&blockchain, &blockchain.get_tip_hash()).unwrap();
+        let first_invalid = |transactions: &[Transaction], threads: usize| {
+            blockchain
+                .first_invalid_transaction(&view, transactions, true, false, threads)
+                .unwrap()
+                .map(|failure| failure.index)
+        };
+        let parallel_runs = || PARALLEL_VERIFICATIONS.with(|count| count.get());
+        PARALLEL_VERIFICATIONS.with(|count| count.set(0));
+
+        assert_eq!(first_invalid(&spends, 1), None);
+        assert_eq!(parallel_runs(), 0);
+        assert_eq!(first_invalid(&spends, 8), None);
+        assert_eq!(parallel_runs(), 1);
+        // A small batch stays on this thread however many are allowed
+        assert_eq!(
+            first_invalid(&spends[..PARALLEL_VERIFICATION_THRESHOLD], 8),
+            None
+        );
+        assert_eq!(parallel_runs(), 1);
+
+        let mut forged = spends.clone();
+        forged[137].corrupt_signatures();
+        forged[190].corrupt_signatures();
+        assert_eq!(first_invalid(&forged, 1), Some(137));
+        assert_eq!(first_invalid(&forged, 8), Some(137));
+        assert_eq!(first_invalid(&forged, 3), Some(137));
+
+        // A coin spent twice is caught while the outputs are looked up, after which a
+        // bad signature earlier in the batch still comes first
+        let mut doubled = spends.clone();
+        doubled[150] = doubled[20].clone();
+        let failure = blockchain
+            .first_invalid_transaction(&view, &doubled, true, false, 8)
+            .unwrap()
+            .unwrap();
+        assert_eq!(failure.index, 150);
+        assert!(failure.reason.unwrap().contains("spent twice"));
+        doubled[137].corrupt_signatures();
+        assert_eq!(first_invalid(&doubled, 8), Some(137));
+
+        let error = blockchain
+            .mine_block_with_fees(&forged, TEST_ADDRESS)
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("transaction 137 failed verification"));
+        let block = blockchain
+            .mine_block_with_fees(&spends, TEST_ADDRESS)
+            .unwrap();
+        assert_eq!(block.get_transactions().len(), 201);
     }
 }
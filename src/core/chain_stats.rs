@@ -0,0 +1,313 @@
+//! Aggregate statistics over the main chain
+//!
+//! `Blockchain::get_chain_stats` covers the last few blocks up to the tip and
+//! `Blockchain::get_lifetime_chain_stats` the whole chain; both read one block at a time
+//! through the height index. Block intervals are the differences between consecutive
+//! timestamps inside the range, so a range of one block has none. A pruned block counts
+//! towards the intervals and the difficulty, which its header still carries, but not
+//! towards transactions, fees, payouts or sizes, which needed its body.
+
+use crate::core::{Block, Blockchain};
+use crate::error::{BlockchainError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Aggregates over a range of main-chain blocks ending at the tip
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainStats {
+    pub from_height: usize,
+    pub to_height: usize,
+    pub block_count: usize,
+    /// `None` when the range holds a single block
+    pub average_block_interval_ms: Option<f64>,
+    pub median_block_interval_ms: Option<i64>,
+    pub average_difficulty: f64,
+    /// Including coinbases
+    pub total_transactions: usize,
+    pub average_transactions_per_block: f64,
+    /// In satoshis, like the payouts
+    pub total_fees: u64,
+    pub total_coinbase_payout: u64,
+    /// Serialized size in bytes
+    pub average_block_size: f64,
+    pub largest_block: Option<LargestBlock>,
+    /// Blocks in the range whose bodies are pruned, left out of the body-based figures
+    pub pruned_blocks: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LargestBlock {
+    pub height: usize,
+    pub hash: String,
+    pub size: usize,
+}
+
+pub(crate) fn chain_stats(blockchain: &Blockchain, window: usize) -> Result<ChainStats> {
+    let tip = blockchain.get_best_height()?;
+    let block_count = window.clamp(1, tip + 1);
+    let from_height = tip + 1 - block_count;
+
+    let mut intervals = Vec::with_capacity(block_count - 1);
+    let mut previous_timestamp: Option<i64> = None;
+    let mut difficulty_sum = 0u64;
+    let mut bodies = 0usize;
+    let mut total_transactions = 0usize;
+    let mut total_fees = 0u64;
+    let mut total_coinbase_payout = 0u64;
+    let mut total_size = 0usize;
+    let mut largest_block: Option<LargestBlock> = None;
+
+    for height in from_height..=tip {
+        let block = blockchain.get_block_at_height(height)?.ok_or_else(|| {
+            BlockchainError::Database(format!("No main-chain block at height {height}"))
+        })?;
+        if let Some(previous) = previous_timestamp {
+            // Clocks may disagree a little, which must not wrap around
+            intervals.push((block.get_timestamp() - previous).max(0));
+        }
+        previous_timestamp = Some(block.get_timestamp());
+        difficulty_sum += u64::from(block.get_difficulty());
+
+        if block.is_pruned() {
+            continue;
+        }
+        bodies += 1;
+        total_transactions += block.get_transactions().len();
+        total_fees += block.get_total_fees().to_satoshis();
+        total_coinbase_payout += coinbase_payout(&block);
+        let size = block.serialize()?.len();
+        total_size += size;
+        if largest_block
+            .as_ref()
+            .is_none_or(|largest| size > largest.size)
+        {
+            largest_block = Some(LargestBlock {
+                height,
+                hash: block.get_hash().to_string(),
+                size,
+            });
+        }
+    }
+
+    let (average_block_interval_ms, median_block_interval_ms) = interval_stats(&mut intervals);
+    let per_body = |total: f64| {
+        if bodies == 0 {
+            0.0
+        } else {
+            total / bodies as f64
+        }
+    };
+
+    Ok(ChainStats {
+        from_height,
+        to_height: tip,
+        block_count,
+        average_block_interval_ms,
+        median_block_interval_ms,
+        average_difficulty: difficulty_sum as f64 / block_count as f64,
+        total_transactions,
+        average_transactions_per_block: per_body(total_transactions as f64),
+        total_fees,
+        total_coinbase_payout,
+        average_block_size: per_body(total_size as f64),
+        largest_block,
+        pruned_blocks: block_count - bodies,
+    })
+}
+
+/// Average and median of `intervals`, or `None`s when there are none
+fn interval_stats(intervals: &mut [i64]) -> (Option<f64>, Option<i64>) {
+    if intervals.is_empty() {
+        return (None, None);
+    }
+    let average = intervals.iter().sum::<i64>() as f64 / intervals.len() as f64;
+    intervals.sort_unstable();
+    let middle = intervals.len() / 2;
+    let median = if intervals.len().is_multiple_of(2) {
+        (intervals[middle - 1] + intervals[middle]) / 2
+    } else {
+        intervals[middle]
+    };
+    (Some(average), Some(median))
+}
+
+fn coinbase_payout(block: &Block) -> u64 {
+    block
+        .get_transactions()
+        .iter()
+        .filter(|tx| tx.is_coinbase())
+        .flat_map(|tx| tx.get_vout())
+        .map(|output| output.get_value().to_satoshis())
+        .sum()
+}
+
+impl fmt::Display for ChainStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Blocks:               {} (heights {} to {})",
+            self.block_count, self.from_height, self.to_height
+        )?;
+        match (
+            self.average_block_interval_ms,
+            self.median_block_interval_ms,
+        ) {
+            (Some(average), Some(median)) => writeln!(
+                f,
+                "Block interval:       {average:.0} ms average, {median} ms median"
+            )?,
+            _ => writeln!(f, "Block interval:       n/a (a single block)")?,
+        }
+        writeln!(f, "Average difficulty:   {:.2}", self.average_difficulty)?;
+        writeln!(
+            f,
+            "Transactions:         {} ({:.2} per block)",
+            self.total_transactions, self.average_transactions_per_block
+        )?;
+        writeln!(f, "Fees collected:       {} satoshis", self.total_fees)?;
+        writeln!(
+            f,
+            "Coinbase payout:      {} satoshis",
+            self.total_coinbase_payout
+        )?;
+        writeln!(
+            f,
+            "Average block size:   {:.0} bytes",
+            self.average_block_size
+        )?;
+        if let Some(largest) = &self.largest_block {
+            writeln!(
+                f,
+                "Largest block:        {} bytes at height {} ({})",
+                largest.size, largest.height, largest.hash
+            )?;
+        }
+        if self.pruned_blocks > 0 {
+            writeln!(
+                f,
+                "Pruned blocks:        {} (left out of transactions, fees and sizes)",
+                self.pruned_blocks
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{block_subsidy, FeeMode, FeePriority, NodeContext, Transaction};
+    use crate::storage::UTXOSet;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    #[test]
+    fn test_stats_over_a_window_and_the_whole_chain() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let senders: Vec<String> = (0..9).map(|_| wallets.create_wallet().unwrap()).collect();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &senders[0],
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+
+        let genesis_only = blockchain.get_chain_stats(100).unwrap();
+        assert_eq!((genesis_only.from_height, genesis_only.block_count), (0, 1));
+        assert_eq!(genesis_only.average_block_interval_ms, None);
+        assert_eq!(genesis_only.median_block_interval_ms, None);
+        assert_eq!(genesis_only.total_transactions, 1);
+
+        // Heights 1 to 8 fund one sender each, then every sender pays once in
+        // heights 9 to 20, between none and three payments a block
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        for sender in &senders[1..] {
+            blockchain.generate_blocks(1, sender, &context).unwrap();
+        }
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let payments_per_block = [1, 0, 2, 0, 3, 0, 1, 0, 2, 0, 0, 0];
+        let mut payers = senders.iter();
+        for payments in payments_per_block {
+            for payer in payers.by_ref().take(payments) {
+                let tx = Transaction::new_utxo_transaction_with_context(
+                    payer,
+                    TEST_ADDRESS,
+                    1_000,
+                    FeePriority::Normal,
+                    &utxo_set,
+                    &context,
+                )
+                .unwrap();
+                context.memory_pool().add(tx);
+            }
+            // Timestamps are in milliseconds, so this spaces the blocks out
+            sleep(Duration::from_millis(20));
+            blockchain
+                .generate_blocks(1, TEST_ADDRESS, &context)
+                .unwrap();
+        }
+        assert_eq!(blockchain.get_best_height().unwrap(), 20);
+
+        let lifetime = blockchain.get_lifetime_chain_stats().unwrap();
+        assert_eq!(lifetime, blockchain.get_chain_stats(1_000).unwrap());
+        assert_eq!((lifetime.from_height, lifetime.to_height), (0, 20));
+        assert_eq!(lifetime.block_count, 21);
+        assert_eq!(lifetime.total_transactions, 21 + 9);
+        assert_eq!(lifetime.total_fees, 9 * 3);
+        let subsidies: u64 = (0..=20).map(block_subsidy).sum();
+        assert_eq!(lifetime.total_coinbase_payout, subsidies + 9 * 3);
+        assert_eq!(lifetime.pruned_blocks, 0);
+
+        let heights = 0..=20;
+        let blocks: Vec<Block> = heights
+            .map(|height| blockchain.get_block_at_height(height).unwrap().unwrap())
+            .collect();
+        let difficulties: u64 = blocks.iter().map(|b| u64::from(b.get_difficulty())).sum();
+        assert_eq!(lifetime.average_difficulty, difficulties as f64 / 21.0);
+        let sizes: Vec<usize> = blocks
+            .iter()
+            .map(|b| b.serialize().unwrap().len())
+            .collect();
+        assert_eq!(
+            lifetime.average_block_size,
+            sizes.iter().sum::<usize>() as f64 / 21.0
+        );
+        // The block with three payments beats the genesis block and every other one
+        let largest = lifetime.largest_block.unwrap();
+        assert_eq!(largest.height, 13);
+        assert_eq!(largest.size, *sizes.iter().max().unwrap());
+        assert_eq!(largest.hash, blocks[13].get_hash());
+
+        let span = blocks[20].get_timestamp() - blocks[0].get_timestamp();
+        assert_eq!(lifetime.average_block_interval_ms, Some(span as f64 / 20.0));
+
+        // The last five blocks hold payments only at height 17
+        let window = blockchain.get_chain_stats(5).unwrap();
+        assert_eq!((window.from_height, window.to_height), (16, 20));
+        assert_eq!(window.total_transactions, 5 + 2);
+        assert_eq!(window.average_transactions_per_block, 7.0 / 5.0);
+        assert_eq!(window.total_fees, 2 * 3);
+        assert_eq!(window.average_difficulty, 1.0);
+        assert!(window.median_block_interval_ms.unwrap() >= 20);
+        assert_eq!(window.largest_block.as_ref().unwrap().height, 17);
+
+        let json = serde_json::to_value(&window).unwrap();
+        assert_eq!(json["block_count"], 5);
+        assert_eq!(json["largest_block"]["height"], 17);
+    }
+
+    #[test]
+    fn test_interval_stats_with_identical_and_skewed_timestamps() {
+        assert_eq!(interval_stats(&mut []), (None, None));
+        assert_eq!(interval_stats(&mut [0, 0, 0]), (Some(0.0), Some(0)));
+        // An even count takes the mean of the middle two
+        assert_eq!(interval_stats(&mut [40, 0, 10, 30]), (Some(20.0), Some(20)));
+    }
+}
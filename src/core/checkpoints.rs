@@ -0,0 +1,23 @@
+//! Known-good main-chain blocks
+//!
+//! A checkpoint pins the block hash at one height. Blocks claiming a checkpointed height
+//! with any other hash are rejected, and once the local chain has passed a checkpoint no
+//! fork below it is accepted any more. Checkpoints come from `BUILTIN_CHECKPOINTS` plus
+//! whatever was added to a database with `Blockchain::add_checkpoint`.
+
+/// A `(height, block hash)` pair
+pub type Checkpoint = (usize, String);
+
+/// Checkpoints compiled into every node
+///
+/// Empty for now: every development chain starts from its own genesis block, so there is
+/// no shared history to pin yet.
+pub const BUILTIN_CHECKPOINTS: &[(usize, &str)] = &[];
+
+/// The built-in checkpoints as owned pairs
+pub fn builtin_checkpoints() -> Vec<Checkpoint> {
+    BUILTIN_CHECKPOINTS
+        .iter()
+        .map(|(height, hash)| (*height, hash.to_string()))
+        .collect()
+}
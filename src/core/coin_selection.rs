@@ -0,0 +1,328 @@
+//! Which of its senders' coins a payment spends
+//!
+//! Without a strategy, `TransactionBuilder` takes coins in the order the chainstate keeps
+//! them, which is no order at all as far as the wallet is concerned. A `SelectionStrategy`
+//! orders every coin of every sender first and takes them from the front until the payment
+//! and its fee are covered, so it converges with the builder's fee loop like the chainstate
+//! order does.
+//!
+//! Whatever the strategy, a coinbase that isn't `COINBASE_MATURITY_WINDOW` blocks deep yet
+//! is only taken once every mature coin is, since a short reorg could still take it away
+//! along with the payment. And with a strategy the change has to be worth more than
+//! `DUST_THRESHOLD`, otherwise it goes to the fee instead of into an output nobody will
+//! ever find worth spending.
+
+use crate::core::Amount;
+use crate::error::{BlockchainError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Most subsets `BranchAndBound` looks at before it gives up on a changeless payment
+pub const MAX_BRANCH_AND_BOUND_TRIES: usize = 100_000;
+
+/// How a payment orders its senders' coins before taking them
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+pub enum SelectionStrategy {
+    /// Fewest inputs, so the smallest transaction and fee
+    #[serde(rename = "largest")]
+    LargestFirst,
+    /// Most inputs, spending dust while fees are low
+    #[serde(rename = "smallest")]
+    SmallestFirst,
+    /// Most confirmations first, so no coin sits unspent forever
+    #[serde(rename = "oldest")]
+    OldestFirst,
+    /// A set of coins paying the amount and fee without change if one can be found within
+    /// `MAX_BRANCH_AND_BOUND_TRIES`, otherwise `LargestFirst`
+    #[serde(rename = "bnb")]
+    BranchAndBound,
+}
+
+impl SelectionStrategy {
+    pub const ALL: [SelectionStrategy; 4] = [
+        SelectionStrategy::LargestFirst,
+        SelectionStrategy::SmallestFirst,
+        SelectionStrategy::OldestFirst,
+        SelectionStrategy::BranchAndBound,
+    ];
+
+    /// What `--coin-selection` calls it
+    pub fn name(&self) -> &'static str {
+        match self {
+            SelectionStrategy::LargestFirst => "largest",
+            SelectionStrategy::SmallestFirst => "smallest",
+            SelectionStrategy::OldestFirst => "oldest",
+            SelectionStrategy::BranchAndBound => "bnb",
+        }
+    }
+
+    /// Put `coins` in the order this strategy spends them, mature ones first
+    pub(crate) fn order(&self, coins: &mut [Coin]) {
+        // Stable, so coins the strategy can't tell apart stay in chainstate order
+        match self {
+            SelectionStrategy::LargestFirst | SelectionStrategy::BranchAndBound => {
+                coins.sort_by_key(|coin| (!coin.mature, std::cmp::Reverse(coin.value)))
+            }
+            SelectionStrategy::SmallestFirst => {
+                coins.sort_by_key(|coin| (!coin.mature, coin.value))
+            }
+            SelectionStrategy::OldestFirst => {
+                coins.sort_by_key(|coin| (!coin.mature, std::cmp::Reverse(coin.confirmations)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for SelectionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SelectionStrategy {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<SelectionStrategy> {
+        SelectionStrategy::ALL
+            .into_iter()
+            .find(|strategy| strategy.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                BlockchainError::Transaction(format!(
+                    "Unknown coin selection {s}, expected largest, smallest, oldest or bnb"
+                ))
+            })
+    }
+}
+
+/// A coin one of a payment's senders could spend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Coin {
+    /// Index of the sender among the payment's
+    pub spender: usize,
+    pub txid_hex: String,
+    pub vout: usize,
+    pub value: Amount,
+    pub confirmations: u64,
+    /// False for a coinbase short of `COINBASE_MATURITY_WINDOW` confirmations
+    pub mature: bool,
+}
+
+/// The first of `coins` that together reach `target`, or all of them if they fall short
+pub(crate) fn take_until(coins: &[Coin], target: Amount) -> Result<(Amount, &[Coin])> {
+    let mut accumulated = Amount::ZERO;
+    for (taken, coin) in coins.iter().enumerate() {
+        if accumulated >= target {
+            return Ok((accumulated, &coins[..taken]));
+        }
+        accumulated = accumulated.checked_add(coin.value)?;
+    }
+    Ok((accumulated, coins))
+}
+
+/// Positions in `coins` of a set whose total lies within `window(count)`, the inclusive
+/// bounds on what `count` inputs may add up to
+///
+/// `coins` should be largest first, so sets of few inputs are tried before many. The
+/// search assumes both bounds grow with the count, which they do as long as the fee does
+/// with the size, and gives up after `MAX_BRANCH_AND_BOUND_TRIES` sets.
+pub(crate) fn branch_and_bound(
+    coins: &[Coin],
+    window: impl Fn(usize) -> Result<(Amount, Amount)>,
+) -> Result<Option<Vec<usize>>> {
+    let values: Vec<u64> = coins.iter().map(|coin| coin.value.to_satoshis()).collect();
+    let mut windows = Vec::with_capacity(values.len() + 1);
+    for count in 0..=values.len() {
+        let (low, high) = window(count)?;
+        windows.push((low.to_satoshis(), high.to_satoshis()));
+    }
+    // What the coins from each position on add up to
+    let mut remaining = vec![0u64; values.len() + 1];
+    for position in (0..values.len()).rev() {
+        remaining[position] = remaining[position + 1].saturating_add(values[position]);
+    }
+
+    let mut search = Search {
+        values: &values,
+        windows: &windows,
+        remaining: &remaining,
+        picked: Vec::new(),
+        tries: 0,
+    };
+    Ok(search.from(0, 0).then_some(search.picked))
+}
+
+struct Search<'a> {
+    values: &'a [u64],
+    windows: &'a [(u64, u64)],
+    remaining: &'a [u64],
+    picked: Vec<usize>,
+    tries: usize,
+}
+
+impl Search<'_> {
+    // Whether the picked coins, with some of those from `position` on, fit their window;
+    // `picked` holds the set when they do
+    fn from(&mut self, position: usize, total: u64) -> bool {
+        self.tries += 1;
+        if self.tries > MAX_BRANCH_AND_BOUND_TRIES {
+            return false;
+        }
+        let count = self.picked.len();
+        let (low, high) = self.windows[count];
+        if count > 0 && (low..=high).contains(&total) {
+            return true;
+        }
+        // No more coins can get the total down into a window, nor these ones up to it
+        let most = self.windows[self.windows.len() - 1].1;
+        if total > most
+            || position == self.values.len()
+            || total.saturating_add(self.remaining[position]) < low
+        {
+            return false;
+        }
+
+        self.picked.push(position);
+        if self.from(position + 1, total.saturating_add(self.values[position])) {
+            return true;
+        }
+        self.picked.pop();
+        if self.tries > MAX_BRANCH_AND_BOUND_TRIES {
+            return false;
+        }
+        // Leaving this coin out and taking an equal one instead is the same set again
+        let mut next = position + 1;
+        while next < self.values.len() && self.values[next] == self.values[position] {
+            next += 1;
+        }
+        self.from(next, total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coin(value: u64, confirmations: u64, mature: bool) -> Coin {
+        Coin {
+            spender: 0,
+            txid_hex: format!("{value:064x}"),
+            vout: confirmations as usize,
+            value: Amount::from_satoshis(value),
+            confirmations,
+            mature,
+        }
+    }
+
+    fn values(coins: &[Coin]) -> Vec<u64> {
+        coins.iter().map(|coin| coin.value.to_satoshis()).collect()
+    }
+
+    #[test]
+    fn test_strategies_order_mature_coins_first() {
+        let coins = vec![
+            coin(500, 3, true),
+            coin(9_000, 1, false),
+            coin(100, 7, true),
+            coin(2_000, 5, true),
+        ];
+        let ordered = |strategy: SelectionStrategy| {
+            let mut coins = coins.clone();
+            strategy.order(&mut coins);
+            values(&coins)
+        };
+        assert_eq!(
+            ordered(SelectionStrategy::LargestFirst),
+            [2_000, 500, 100, 9_000]
+        );
+        assert_eq!(
+            ordered(SelectionStrategy::SmallestFirst),
+            [100, 500, 2_000, 9_000]
+        );
+        assert_eq!(
+            ordered(SelectionStrategy::OldestFirst),
+            [100, 2_000, 500, 9_000]
+        );
+
+        let (total, taken) = take_until(&coins, Amount::from(9_100)).unwrap();
+        assert_eq!((total, taken.len()), (Amount::from(9_500), 2));
+        let (total, taken) = take_until(&coins, Amount::from(1_000_000)).unwrap();
+        assert_eq!((total, taken.len()), (Amount::from(11_600), 4));
+    }
+
+    #[test]
+    fn test_strategies_parse_from_their_names() {
+        for strategy in SelectionStrategy::ALL {
+            assert_eq!(
+                strategy.name().parse::<SelectionStrategy>().unwrap(),
+                strategy
+            );
+            let json = serde_json::to_string(&strategy).unwrap();
+            assert_eq!(json, format!("\"{}\"", strategy.name()));
+        }
+        assert!("random".parse::<SelectionStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_branch_and_bound_finds_a_set_within_the_window() {
+        let coins: Vec<Coin> = [5_000, 3_000, 2_000, 2_000, 1_000]
+            .into_iter()
+            .map(|value| coin(value, 1, true))
+            .collect();
+        // Each input costs 10 and the window is 5 wide
+        let window = |target: u64| {
+            move |count: usize| {
+                let low = Amount::from_satoshis(target + 10 * count as u64);
+                Ok((low, low.checked_add(Amount::from(5))?))
+            }
+        };
+        // 3_000 + 1_000 pays 3_980 and two inputs, where 5_000 alone overshoots
+        let picked = branch_and_bound(&coins, window(3_980)).unwrap().unwrap();
+        assert_eq!(picked, [1, 4]);
+        let picked = branch_and_bound(&coins, window(8_970)).unwrap().unwrap();
+        assert_eq!(picked, [0, 1, 4]);
+        // Every total is a multiple of a thousand, which 2_500 and its fee never are
+        assert_eq!(branch_and_bound(&coins, window(2_500)).unwrap(), None);
+        assert_eq!(branch_and_bound(&[], window(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_branch_and_bound_gives_up_after_its_tries() {
+        // Forty equal coins can't pay an odd amount, and leaving out equal coins only
+        // once keeps the search well inside its bound
+        let coins: Vec<Coin> = (0..40).map(|_| coin(2, 1, true)).collect();
+        let window = |_: usize| Ok((Amount::from(41), Amount::from(41)));
+        assert_eq!(branch_and_bound(&coins, window).unwrap(), None);
+
+        // Distinct even values leave it nothing to skip, and countless sets come close to
+        // an odd half of their total without reaching it, so the search runs into its bound
+        let values: Vec<u64> = (0..40).rev().map(|n| 2 * (1_000 + n)).collect();
+        let target = values.iter().sum::<u64>() / 2 + 1;
+        assert_eq!(target % 2, 1);
+        let windows = vec![(target, target); values.len() + 1];
+        let mut remaining = vec![0u64; values.len() + 1];
+        for position in (0..values.len()).rev() {
+            remaining[position] = remaining[position + 1] + values[position];
+        }
+        let mut search = Search {
+            values: &values,
+            windows: &windows,
+            remaining: &remaining,
+            picked: Vec::new(),
+            tries: 0,
+        };
+        assert!(!search.from(0, 0));
+        assert_eq!(search.tries, MAX_BRANCH_AND_BOUND_TRIES + 1);
+    }
+}
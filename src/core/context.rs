@@ -0,0 +1,942 @@
+//! Per-node state that used to live in process-wide statics
+//!
+//! A `NodeContext` owns the memory pool, fee calculator and configuration of one node, so
+//! several nodes (or several tests) can run in the same process without sharing them. The
+//! CLI still goes through the globals; `NodeContext::from_globals` bridges the two.
+
+use crate::config::{Config, GLOBAL_CONFIG};
+use crate::core::fees::{
+    FeeCalculator, FeeHistory, FeeMode, FeePriority, FeeStatistics, RelayFeePolicy,
+    UnifiedFeeCalculator,
+};
+use crate::core::{Amount, Blockchain, Miner, MiningHandle, Transaction};
+use crate::error::Result;
+use crate::network::{
+    MessageTrace, NodeIdentity, OutboundConnections, PeerConnections, PeerId, SyncState,
+    WireFormat, DEFAULT_MAX_OUTBOUND_CONNECTIONS,
+};
+use crate::storage::{BlockInTransit, MemoryPool};
+use crate::utils::{BloomFilter, FORMAT_VERSION};
+use crate::wallet::SharedWallets;
+use data_encoding::HEXLOWER;
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+/// How long a transaction asked for with GetData is waited for before another peer's
+/// announcement may ask again
+pub const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// Txids remembered as announced to one peer; past it the peer's record starts over, which
+// only costs it a repeated announcement
+const MAX_ANNOUNCED_TXS_PER_PEER: usize = 10_000;
+/// Most light clients with a bloom filter loaded at once; further ones are sent whole blocks
+pub const MAX_PEER_FILTERS: usize = 125;
+/// Most peers announced to at once; further ones still sync but hear of nothing new
+pub const MAX_RELAY_PEERS: usize = 125;
+/// Most peers whose formats are remembered at once; further ones are written the oldest
+/// block format, as JSON, which every node reads
+pub const MAX_PEER_FORMATS: usize = 1_000;
+/// How long a key keeps an address it announced without announcing it again
+pub const PEER_CLAIM_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+// Addresses claimed by keys at once; past it the claim renewed longest ago is dropped
+const MAX_PEER_CLAIMS: usize = 10_000;
+
+/// Everything a node needs besides its blockchain database
+pub struct NodeContext {
+    memory_pool: MemoryPool,
+    blocks_in_transit: BlockInTransit,
+    fee_calculator: RwLock<UnifiedFeeCalculator>,
+    config: Config,
+    miner: Miner,
+    /// The block this node is currently mining, if any
+    mining_job: Mutex<Option<MiningHandle>>,
+    /// Bloom filters loaded by light clients, by the connection they loaded them over
+    peer_filters: RwLock<HashMap<SocketAddr, BloomFilter>>,
+    /// Newest block and transaction format each peer advertised in its version message
+    peer_format_versions: RwLock<HashMap<String, u8>>,
+    /// How each peer takes its packages, as negotiated through its version message
+    peer_wire_formats: RwLock<HashMap<String, WireFormat>>,
+    /// Peers on this node's network, which new blocks and transactions are announced to
+    relay_peers: RwLock<HashSet<String>>,
+    /// Transactions already announced to each relay peer, so its next handshake doesn't
+    /// hear about them again
+    announced_txs: RwLock<HashMap<String, HashSet<Vec<u8>>>>,
+    /// Transactions asked for with GetData that haven't arrived or been reported missing,
+    /// with the peer asked and when
+    requested_txs: Mutex<HashMap<Vec<u8>, (String, Instant)>>,
+    /// The key this node signs the packages it sends with, if it has one
+    identity: Option<NodeIdentity>,
+    /// Which key first announced itself from each listening address, and when it last did
+    peer_identities: RwLock<HashMap<String, (PeerId, Instant)>>,
+    /// The packages this node sent and received last
+    message_trace: MessageTrace,
+    /// Connections this node opened to peers, reused by every package sent their way
+    outbound_connections: OutboundConnections,
+    /// Every connection being read, with what went over it
+    peer_connections: PeerConnections,
+    /// Shared with the thread reindexing the chainstate in the background, if one runs
+    chainstate_rebuild: Arc<Mutex<ChainstateRebuild>>,
+    /// How far the node got catching up with its peers
+    sync_state: Mutex<SyncState>,
+    /// How far the network's clock is ahead of this machine's (ms), the median offset of
+    /// the peers' clocks, see `utils::network_adjusted_time`
+    network_time_offset: AtomicI64,
+    /// The wallet file payments built for this node sign with, loaded on first use
+    wallets: OnceLock<SharedWallets>,
+    /// Fee rates recent blocks confirmed, which dynamic estimates blend in; shared with
+    /// the chain it follows, see `follow_fee_history`
+    fee_history: Arc<RwLock<FeeHistory>>,
+    /// Transactions of blocks that left the chain's main chain, waiting to go back to the
+    /// memory pool; shared with the chain, see `follow_reorgs`
+    reorged_out: Arc<Mutex<Vec<Transaction>>>,
+}
+
+/// A background chainstate reindex, see `NodeContext::chainstate_rebuild`
+#[derive(Debug, Default)]
+pub struct ChainstateRebuild {
+    pub running: bool,
+    /// A block arrived during the current pass, so another one is needed
+    pub again: bool,
+}
+
+impl Default for NodeContext {
+    fn default() -> Self {
+        Self::with_components(
+            MemoryPool::new(),
+            UnifiedFeeCalculator::default(),
+            Config::new(),
+        )
+    }
+}
+
+impl NodeContext {
+    /// Create a context with an empty memory pool, default config and the given fee mode
+    pub fn new(fee_mode: FeeMode) -> Result<NodeContext> {
+        Ok(Self::with_components(
+            MemoryPool::new(),
+            UnifiedFeeCalculator::new(fee_mode)?,
+            Config::new(),
+        ))
+    }
+
+    /// Create a context from individually built components
+    pub fn with_components(
+        memory_pool: MemoryPool,
+        fee_calculator: UnifiedFeeCalculator,
+        config: Config,
+    ) -> NodeContext {
+        // Proof-of-work stays on one thread unless the config asks for more
+        let miner = config
+            .get_mining_threads()
+            .map_or_else(Miner::default, Miner::new);
+        let outbound_connections = OutboundConnections::new(
+            config
+                .get_max_outbound_connections()
+                .unwrap_or(DEFAULT_MAX_OUTBOUND_CONNECTIONS),
+        );
+        NodeContext {
+            memory_pool,
+            blocks_in_transit: BlockInTransit::new(),
+            fee_calculator: RwLock::new(fee_calculator),
+            config,
+            miner,
+            mining_job: Mutex::new(None),
+            peer_filters: RwLock::new(HashMap::new()),
+            peer_format_versions: RwLock::new(HashMap::new()),
+            peer_wire_formats: RwLock::new(HashMap::new()),
+            relay_peers: RwLock::new(HashSet::new()),
+            announced_txs: RwLock::new(HashMap::new()),
+            requested_txs: Mutex::new(HashMap::new()),
+            identity: None,
+            peer_identities: RwLock::new(HashMap::new()),
+            message_trace: MessageTrace::new(),
+            outbound_connections,
+            peer_connections: PeerConnections::new(),
+            chainstate_rebuild: Arc::new(Mutex::new(ChainstateRebuild::default())),
+            sync_state: Mutex::new(SyncState::new(0, Instant::now())),
+            network_time_offset: AtomicI64::new(0),
+            wallets: OnceLock::new(),
+            fee_history: Arc::new(RwLock::new(FeeHistory::default())),
+            reorged_out: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Use `miner` for this node's proof-of-work instead of the one the config describes
+    pub fn with_miner(mut self, miner: Miner) -> NodeContext {
+        self.miner = miner;
+        self
+    }
+
+    /// Sign every package this node sends with `identity`
+    pub fn with_identity(mut self, identity: NodeIdentity) -> NodeContext {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Sign this node's payments with `wallets` rather than the configured wallet file
+    pub fn with_wallets(mut self, wallets: SharedWallets) -> NodeContext {
+        self.wallets = OnceLock::from(wallets);
+        self
+    }
+
+    /// The one handle on the wallet file every payment this node builds signs with
+    ///
+    /// Unless `with_wallets` gave it one, it is the file `wallet_path` resolves to, read
+    /// the first time it's asked for.
+    pub fn wallets(&self) -> &SharedWallets {
+        self.wallets.get_or_init(SharedWallets::new)
+    }
+
+    pub fn identity(&self) -> Option<&NodeIdentity> {
+        self.identity.as_ref()
+    }
+
+    /// Create a context from the current global fee calculator and config
+    ///
+    /// Both are copied, so changes made to the globals afterwards don't reach the context.
+    /// The memory pool starts out empty.
+    pub fn from_globals() -> NodeContext {
+        Self::with_components(
+            MemoryPool::new(),
+            FeeCalculator::snapshot(),
+            GLOBAL_CONFIG.clone(),
+        )
+    }
+
+    pub fn memory_pool(&self) -> &MemoryPool {
+        &self.memory_pool
+    }
+
+    pub fn blocks_in_transit(&self) -> &BlockInTransit {
+        &self.blocks_in_transit
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Threads this node verifies block transactions on: `node.verify_threads`, else one
+    /// per core
+    pub fn verification_threads(&self) -> usize {
+        self.config
+            .get_verify_threads()
+            .unwrap_or_else(Blockchain::default_verification_threads)
+    }
+
+    pub fn miner(&self) -> &Miner {
+        &self.miner
+    }
+
+    pub fn message_trace(&self) -> &MessageTrace {
+        &self.message_trace
+    }
+
+    pub fn outbound_connections(&self) -> &OutboundConnections {
+        &self.outbound_connections
+    }
+
+    pub fn peer_connections(&self) -> &PeerConnections {
+        &self.peer_connections
+    }
+
+    /// Slot holding the node's current mining job
+    ///
+    /// Keep the guard while checking and replacing the job, so two handlers can't both
+    /// start one.
+    pub fn mining_job(&self) -> MutexGuard<'_, Option<MiningHandle>> {
+        // A handle is only replaced wholesale, so a poisoned slot is still consistent
+        self.mining_job
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Peer heights and download progress, see `SyncState`
+    pub fn sync_state(&self) -> MutexGuard<'_, SyncState> {
+        // Every update leaves the state whole, so a poisoned lock is still usable
+        self.sync_state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// How far the network's clock is from the local one (ms), which this node's block
+    /// timestamps are stamped and checked with
+    pub fn network_time_offset(&self) -> i64 {
+        self.network_time_offset.load(Ordering::Relaxed)
+    }
+
+    pub fn set_network_time_offset(&self, offset: i64) {
+        self.network_time_offset.store(offset, Ordering::Relaxed);
+    }
+
+    /// State of the background chainstate reindex, for the thread running it to share
+    pub fn chainstate_rebuild(&self) -> Arc<Mutex<ChainstateRebuild>> {
+        Arc::clone(&self.chainstate_rebuild)
+    }
+
+    /// Whether the chainstate is being reindexed in the background
+    pub fn is_rebuilding_chainstate(&self) -> bool {
+        self.chainstate_rebuild
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .running
+    }
+
+    /// Send only transactions matching `filter` to the light client connected from `peer`,
+    /// or false if `MAX_PEER_FILTERS` other connections already have one
+    pub fn set_peer_filter(&self, peer: SocketAddr, filter: BloomFilter) -> bool {
+        // Filters are replaced wholesale, so a poisoned map is still consistent
+        let mut filters = self
+            .peer_filters
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if filters.len() >= MAX_PEER_FILTERS && !filters.contains_key(&peer) {
+            return false;
+        }
+        filters.insert(peer, filter);
+        true
+    }
+
+    /// The filter loaded over the connection from `peer`, if it is a light client
+    pub fn get_peer_filter(&self, peer: SocketAddr) -> Option<BloomFilter> {
+        self.peer_filters
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&peer)
+            .cloned()
+    }
+
+    /// Drop the filter of the connection from `peer`, which closed
+    pub fn remove_peer_filter(&self, peer: SocketAddr) {
+        self.peer_filters
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&peer);
+    }
+
+    /// Remember that the peer at `addr` reads formats up to `version`
+    ///
+    /// Returns false, leaving the peer at the oldest format, once `MAX_PEER_FORMATS`
+    /// peers are remembered.
+    pub fn set_peer_format_version(&self, addr: &str, version: u8) -> bool {
+        let mut versions = self
+            .peer_format_versions
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if versions.len() >= MAX_PEER_FORMATS && !versions.contains_key(addr) {
+            return false;
+        }
+        versions.insert(addr.to_string(), version);
+        true
+    }
+
+    /// The newest format both this node and the peer at `addr` read, 0 until the peer
+    /// sends a version message
+    pub fn peer_format_version(&self, addr: &str) -> u8 {
+        self.peer_format_versions
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(addr)
+            .map_or(0, |&version| version.min(FORMAT_VERSION))
+    }
+
+    /// Write packages to the peer at `addr` in `format` from now on
+    ///
+    /// Returns false, leaving the peer on JSON, once `MAX_PEER_FORMATS` peers are
+    /// remembered.
+    pub fn set_peer_wire_format(&self, addr: &str, format: WireFormat) -> bool {
+        let mut formats = self
+            .peer_wire_formats
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if formats.len() >= MAX_PEER_FORMATS && !formats.contains_key(addr) {
+            return false;
+        }
+        formats.insert(addr.to_string(), format);
+        true
+    }
+
+    /// How to write packages to the peer at `addr`, JSON until its version said otherwise
+    pub fn peer_wire_format(&self, addr: &str) -> WireFormat {
+        self.peer_wire_formats
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(addr)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Announce new blocks and transactions to the peer at `addr` from now on
+    ///
+    /// Returns false, leaving the peer out, once `MAX_RELAY_PEERS` are announced to.
+    pub fn add_relay_peer(&self, addr: &str) -> bool {
+        let mut peers = self
+            .relay_peers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if peers.len() >= MAX_RELAY_PEERS && !peers.contains(addr) {
+            return false;
+        }
+        peers.insert(addr.to_string());
+        true
+    }
+
+    /// Stop announcing to the peer at `addr`, which couldn't be reached
+    ///
+    /// What it was told and the formats it reads are forgotten too, so it hears about the
+    /// memory pool again when it comes back, and says again what it reads.
+    pub fn remove_relay_peer(&self, addr: &str) {
+        self.relay_peers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(addr);
+        self.peer_format_versions
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(addr);
+        self.peer_wire_formats
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(addr);
+        self.announced_txs
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(addr);
+    }
+
+    /// The ones among `txids` not yet announced to the peer at `addr`, which count as
+    /// announced from now on
+    ///
+    /// Only relay peers are remembered, see `add_relay_peer`, so there are never more than
+    /// `MAX_RELAY_PEERS` records; any other peer is new to all of `txids`.
+    pub fn unannounced_txs(&self, addr: &str, txids: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let relay_peers = self
+            .relay_peers
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !relay_peers.contains(addr) {
+            return txids.to_vec();
+        }
+        let mut announced = self
+            .announced_txs
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let known = announced.entry(addr.to_string()).or_default();
+        if known.len() + txids.len() > MAX_ANNOUNCED_TXS_PER_PEER {
+            known.clear();
+        }
+        txids
+            .iter()
+            .filter(|txid| known.insert(txid.to_vec()))
+            .cloned()
+            .collect()
+    }
+
+    /// Note that transaction `txid` is being asked for from `peer`, or false if it already
+    /// is and `TX_REQUEST_TIMEOUT` hasn't passed since
+    pub fn request_tx(&self, txid: &[u8], peer: &str) -> bool {
+        let mut requested = self
+            .requested_txs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Instant::now();
+        // Peers that never answered don't get to keep their requests around
+        requested.retain(|_, (_, asked)| now.duration_since(*asked) < TX_REQUEST_TIMEOUT);
+        if requested.contains_key(txid) {
+            return false;
+        }
+        requested.insert(txid.to_vec(), (peer.to_string(), now));
+        true
+    }
+
+    /// Stop waiting for transaction `txid`, which arrived or which the peer didn't have
+    pub fn forget_tx_request(&self, txid: &[u8]) {
+        self.requested_txs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(txid);
+    }
+
+    /// Whether transaction `txid` was asked for and is still being waited for
+    pub fn is_tx_requested(&self, txid: &[u8]) -> bool {
+        self.requested_txs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(txid)
+            .is_some_and(|(_, asked)| asked.elapsed() < TX_REQUEST_TIMEOUT)
+    }
+
+    /// The peer transaction `txid` is being waited for from, if it still is
+    pub fn tx_requested_from(&self, txid: &[u8]) -> Option<String> {
+        self.requested_txs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(txid)
+            .filter(|(_, asked)| asked.elapsed() < TX_REQUEST_TIMEOUT)
+            .map(|(peer, _)| peer.clone())
+    }
+
+    /// The peers new blocks and transactions are announced to, in no particular order
+    pub fn relay_peers(&self) -> Vec<String> {
+        self.relay_peers
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Check that a version message from `addr` may be believed, claiming the address for
+    /// `identity` if it signed the message
+    ///
+    /// The first key announcing an address keeps it: other keys, and unsigned messages,
+    /// claiming it afterwards are refused. A claim its key doesn't renew within
+    /// `PEER_CLAIM_TIMEOUT` lapses, so an address can change hands.
+    pub fn claim_peer_addr(&self, addr: &str, identity: Option<&PeerId>) -> bool {
+        self.claim_peer_addr_at(addr, identity, Instant::now())
+    }
+
+    fn claim_peer_addr_at(&self, addr: &str, identity: Option<&PeerId>, now: Instant) -> bool {
+        let mut identities = self
+            .peer_identities
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if identities
+            .get(addr)
+            .is_some_and(|(_, renewed)| now.duration_since(*renewed) >= PEER_CLAIM_TIMEOUT)
+        {
+            identities.remove(addr);
+        }
+        match (identities.get(addr), identity) {
+            (None, None) => true,
+            (None, Some(identity)) => {
+                if identities.len() >= MAX_PEER_CLAIMS {
+                    let oldest = identities
+                        .iter()
+                        .min_by_key(|(_, (_, renewed))| *renewed)
+                        .map(|(addr, _)| addr.clone());
+                    if let Some(oldest) = oldest {
+                        identities.remove(&oldest);
+                    }
+                }
+                identities.insert(addr.to_string(), (identity.clone(), now));
+                true
+            }
+            (Some((owner, _)), Some(identity)) if owner == identity => {
+                identities.insert(addr.to_string(), (identity.clone(), now));
+                true
+            }
+            (Some(_), _) => false,
+        }
+    }
+
+    /// Copy of this node's fee calculator
+    pub fn fee_calculator(&self) -> UnifiedFeeCalculator {
+        self.read_calculator().clone()
+    }
+
+    /// Calculate a fee for this node's current memory pool size
+    pub fn calculate_fee(&self, transaction_size: usize, priority: Option<FeePriority>) -> Amount {
+        self.read_calculator().calculate_fee_with_mempool_size(
+            transaction_size,
+            priority,
+            self.memory_pool.len(),
+        )
+    }
+
+    /// Estimate the fee for a priority from this node's memory pool and the fee history
+    pub fn estimate_fee(&self, priority: FeePriority) -> Amount {
+        self.read_calculator().estimate_fee_with_mempool_size(
+            priority,
+            self.memory_pool.len(),
+            &self.fee_history(),
+        )
+    }
+
+    /// Copy of the fee rates recent blocks confirmed
+    pub fn fee_history(&self) -> FeeHistory {
+        self.fee_history
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Load the fee history of `blockchain` and keep it current as blocks join its main
+    /// chain
+    pub fn follow_fee_history(&self, blockchain: &Blockchain) -> Result<()> {
+        // Blocks connected while loading are recorded twice, which replaces them
+        blockchain.record_fees_in(Arc::clone(&self.fee_history));
+        let loaded = FeeHistory::from_chain(blockchain)?;
+        *self
+            .fee_history
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = loaded;
+        Ok(())
+    }
+
+    /// Validate a fee against this node's fee mode and memory pool size
+    pub fn validate_fee(
+        &self,
+        fee: impl Into<Amount>,
+        priority: Option<FeePriority>,
+    ) -> Result<()> {
+        self.read_calculator()
+            .validate_fee_with_mempool_size(fee, priority, self.memory_pool.len())
+    }
+
+    /// The fee this node wants before pooling or relaying a transaction
+    pub fn relay_fee_policy(&self) -> RelayFeePolicy {
+        RelayFeePolicy::from_config(&self.config)
+    }
+
+    /// Put `tx` in the memory pool if its fee meets the relay policy, returning the pending
+    /// transactions it replaced
+    ///
+    /// Only what peers and users offer goes through here; a block carrying a transaction
+    /// this refuses is still valid.
+    pub fn admit_transaction(
+        &self,
+        tx: Transaction,
+        allow_high_fee: bool,
+    ) -> Result<Vec<Transaction>> {
+        // A coinbase pays no fee, but the pool has a better reason to refuse it
+        if !tx.is_coinbase() {
+            self.relay_fee_policy().check(&tx)?;
+        }
+        if allow_high_fee {
+            self.memory_pool.add_allowing_high_fee(tx)
+        } else {
+            self.memory_pool.add_safe(tx)
+        }
+    }
+
+    /// Re-admit the memory pool saved in `blockchain`'s database, returning how many
+    /// transactions made it back
+    ///
+    /// They meet the relay policy like any offered transaction, so one saved under a looser
+    /// policy before a restart stays out.
+    pub fn restore_memory_pool(&self, blockchain: &Blockchain) -> Result<usize> {
+        self.memory_pool
+            .load_from(blockchain, &self.relay_fee_policy())
+    }
+
+    /// Collect the transactions of every block that leaves `blockchain`'s main chain from
+    /// now on, however it was replaced, for `return_reorged_transactions`
+    pub fn follow_reorgs(&self, blockchain: &Blockchain) {
+        blockchain.queue_reorged_in(Arc::clone(&self.reorged_out));
+    }
+
+    /// Put the transactions reorgs took off the main chain back in the memory pool, oldest
+    /// first, unless the new branch holds them or spent their inputs or they fall short
+    /// of the relay policy
+    ///
+    /// The inputs are checked in `blockchain`'s chainstate, so while it's being rebuilt the
+    /// transactions wait for the next call.
+    pub fn return_reorged_transactions(&self, blockchain: &Blockchain) {
+        if self
+            .chainstate_rebuild
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .running
+        {
+            return;
+        }
+        let reorged = std::mem::take(
+            &mut *self
+                .reorged_out
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        for tx in reorged {
+            if !matches!(blockchain.find_transaction_block(tx.get_id()), Ok(None))
+                || blockchain.validate_transaction_inputs(&tx).is_err()
+            {
+                continue;
+            }
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            match self.admit_transaction(tx.clone(), false) {
+                Ok(_) => {
+                    info!(
+                        "Returned transaction {txid_hex} from an orphaned block to the memory pool"
+                    );
+                    blockchain.notify_unconfirmed(&tx);
+                }
+                Err(e) => info!("Not returning transaction {txid_hex} to the memory pool: {e}"),
+            }
+        }
+    }
+
+    pub fn calculate_coinbase_reward(
+        &self,
+        collected_fees: impl Into<Amount>,
+        height: usize,
+    ) -> Amount {
+        self.read_calculator()
+            .calculate_coinbase_reward(collected_fees, height)
+    }
+
+    pub fn get_fee_mode(&self) -> FeeMode {
+        self.read_calculator().get_mode().clone()
+    }
+
+    pub fn switch_fee_mode(&self, new_mode: FeeMode) -> Result<()> {
+        self.write_calculator().switch_mode(new_mode)
+    }
+
+    /// Fee statistics for this node's memory pool and the fee history (only available in
+    /// dynamic mode)
+    pub fn get_fee_statistics(&self) -> Option<FeeStatistics> {
+        self.read_calculator()
+            .get_fee_statistics_with_mempool_size(self.memory_pool.len(), &self.fee_history())
+    }
+
+    pub fn get_fee_config_summary(&self) -> String {
+        self.read_calculator().get_config_summary()
+    }
+
+    // Mode switches replace the calculator wholesale, so a poisoned lock still holds a
+    // usable calculator
+    fn read_calculator(&self) -> RwLockReadGuard<'_, UnifiedFeeCalculator> {
+        self.fee_calculator.read().unwrap_or_else(|poisoned| {
+            log::error!("Fee calculator lock was poisoned, continuing with its last state");
+            poisoned.into_inner()
+        })
+    }
+
+    fn write_calculator(&self) -> RwLockWriteGuard<'_, UnifiedFeeCalculator> {
+        self.fee_calculator.write().unwrap_or_else(|poisoned| {
+            log::error!("Fee calculator lock was poisoned, continuing with its last state");
+            poisoned.into_inner()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fees::DynamicFeeConfig;
+    use crate::core::{Blockchain, Transaction, INITIAL_BLOCK_REWARD};
+    use crate::storage::UTXOSet;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_contexts_with_different_fee_modes_coexist() {
+        let cheap = NodeContext::new(FeeMode::Fixed { amount: 2 }).unwrap();
+        let dynamic = NodeContext::new(FeeMode::Dynamic {
+            config: DynamicFeeConfig::with_base_fee(5),
+        })
+        .unwrap();
+
+        assert_eq!(cheap.calculate_fee(100, None), 2);
+        assert_eq!(dynamic.calculate_fee(100, Some(FeePriority::Normal)), 5);
+
+        // Switching one context leaves the other alone
+        cheap.switch_fee_mode(FeeMode::Fixed { amount: 4 }).unwrap();
+        assert_eq!(cheap.calculate_fee(100, None), 4);
+        assert_eq!(dynamic.calculate_fee(100, Some(FeePriority::Normal)), 5);
+        assert!(dynamic.get_fee_statistics().is_some());
+        assert!(cheap.get_fee_statistics().is_none());
+    }
+
+    #[test]
+    fn test_dynamic_fees_follow_own_memory_pool() {
+        let mode = FeeMode::Dynamic {
+            config: DynamicFeeConfig::with_base_fee(5),
+        };
+        let congested = NodeContext::new(mode.clone()).unwrap();
+        let quiet = NodeContext::new(mode).unwrap();
+
+        for _ in 0..40 {
+            congested.memory_pool().add(Transaction::new_placeholder_tx(
+                "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            ));
+        }
+
+        let normal = Some(FeePriority::Normal);
+        assert!(congested.calculate_fee(100, normal) > quiet.calculate_fee(100, normal));
+        assert_eq!(quiet.memory_pool().len(), 0);
+    }
+
+    #[test]
+    fn test_transactions_are_announced_and_requested_once() {
+        let context = NodeContext::default();
+        let txids = vec![vec![1; 32], vec![2; 32]];
+        assert!(context.add_relay_peer("127.0.0.1:2001"));
+        assert!(context.add_relay_peer("127.0.0.1:2002"));
+
+        assert_eq!(context.unannounced_txs("127.0.0.1:2001", &txids), txids);
+        assert!(context.unannounced_txs("127.0.0.1:2001", &txids).is_empty());
+        assert_eq!(context.unannounced_txs("127.0.0.1:2002", &txids), txids);
+        // Nothing is remembered for peers that aren't relayed to
+        assert_eq!(context.unannounced_txs("127.0.0.1:2003", &txids), txids);
+        assert_eq!(context.unannounced_txs("127.0.0.1:2003", &txids), txids);
+
+        // Only the first announcement of a transaction is followed up until it arrives
+        assert!(context.request_tx(&txids[0], "127.0.0.1:2001"));
+        assert!(!context.request_tx(&txids[0], "127.0.0.1:2002"));
+        assert_eq!(
+            context.tx_requested_from(&txids[0]).as_deref(),
+            Some("127.0.0.1:2001")
+        );
+        context.forget_tx_request(&txids[0]);
+        assert!(!context.is_tx_requested(&txids[0]));
+        assert!(context.request_tx(&txids[0], "127.0.0.1:2002"));
+    }
+
+    #[test]
+    fn test_peer_filters_are_capped_and_dropped_with_their_connection() {
+        let context = NodeContext::default();
+        let peer = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+        let filter = || BloomFilter::new(128, 5, 42).unwrap();
+
+        for port in 0..MAX_PEER_FILTERS as u16 {
+            assert!(context.set_peer_filter(peer(port), filter()));
+        }
+        let latecomer = peer(MAX_PEER_FILTERS as u16);
+        assert!(!context.set_peer_filter(latecomer, filter()));
+        // Replacing a loaded filter is always allowed
+        assert!(context.set_peer_filter(peer(0), filter()));
+
+        context.remove_peer_filter(peer(0));
+        assert!(context.get_peer_filter(peer(0)).is_none());
+        assert!(context.set_peer_filter(latecomer, filter()));
+        assert!(context.get_peer_filter(latecomer).is_some());
+    }
+
+    #[test]
+    fn test_relay_peers_are_capped() {
+        let context = NodeContext::default();
+        let peer = |port: usize| format!("127.0.0.1:{port}");
+
+        for port in 0..MAX_RELAY_PEERS {
+            assert!(context.add_relay_peer(&peer(port)));
+        }
+        assert!(!context.add_relay_peer(&peer(MAX_RELAY_PEERS)));
+        assert!(context.add_relay_peer(&peer(0)));
+
+        context.remove_relay_peer(&peer(0));
+        assert!(context.add_relay_peer(&peer(MAX_RELAY_PEERS)));
+        assert_eq!(context.relay_peers().len(), MAX_RELAY_PEERS);
+    }
+
+    #[test]
+    fn test_peer_formats_are_capped() {
+        let context = NodeContext::default();
+        let peer = |port: usize| format!("127.0.0.1:{port}");
+
+        for port in 0..MAX_PEER_FORMATS {
+            assert!(context.set_peer_format_version(&peer(port), FORMAT_VERSION));
+            assert!(context.set_peer_wire_format(&peer(port), WireFormat::Bincode));
+        }
+        let latecomer = peer(MAX_PEER_FORMATS);
+        assert!(!context.set_peer_format_version(&latecomer, FORMAT_VERSION));
+        assert!(!context.set_peer_wire_format(&latecomer, WireFormat::Bincode));
+        assert_eq!(context.peer_format_version(&latecomer), 0);
+        assert_eq!(context.peer_wire_format(&latecomer), WireFormat::Json);
+
+        // A peer that can't be reached any more makes room
+        context.remove_relay_peer(&peer(0));
+        assert_eq!(context.peer_wire_format(&peer(0)), WireFormat::Json);
+        assert!(context.set_peer_wire_format(&latecomer, WireFormat::Bincode));
+    }
+
+    #[test]
+    fn test_verification_threads_come_from_own_config() {
+        let context = NodeContext::default();
+        assert_eq!(
+            context.verification_threads(),
+            Blockchain::default_verification_threads()
+        );
+        context.config().set_verify_threads(3);
+        assert_eq!(context.verification_threads(), 3);
+        assert_eq!(
+            NodeContext::default().verification_threads(),
+            Blockchain::default_verification_threads()
+        );
+    }
+
+    #[test]
+    fn test_address_claims_lapse_and_are_capped() {
+        let context = NodeContext::default();
+        let owner = PeerId::from_public_key(b"owner");
+        let other = PeerId::from_public_key(b"other");
+        let start = Instant::now();
+
+        assert!(context.claim_peer_addr_at("10.0.0.1:2001", Some(&owner), start));
+        let renewed = start + PEER_CLAIM_TIMEOUT / 2;
+        assert!(context.claim_peer_addr_at("10.0.0.1:2001", Some(&owner), renewed));
+        assert!(!context.claim_peer_addr_at("10.0.0.1:2001", Some(&other), renewed));
+        // Renewing pushed the lapse back
+        assert!(!context.claim_peer_addr_at(
+            "10.0.0.1:2001",
+            Some(&other),
+            start + PEER_CLAIM_TIMEOUT
+        ));
+        assert!(context.claim_peer_addr_at(
+            "10.0.0.1:2001",
+            Some(&other),
+            renewed + PEER_CLAIM_TIMEOUT
+        ));
+
+        for port in 0..MAX_PEER_CLAIMS {
+            let addr = format!("10.0.1.1:{port}");
+            assert!(context.claim_peer_addr_at(&addr, Some(&owner), start));
+        }
+        assert_eq!(
+            context.peer_identities.read().unwrap().len(),
+            MAX_PEER_CLAIMS
+        );
+        // The newest claim stays put
+        assert!(!context.claim_peer_addr_at("10.0.0.1:2001", None, renewed));
+    }
+
+    #[test]
+    fn test_transactions_and_blocks_use_context_fees() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let blockchain =
+            Blockchain::create_blockchain_with_path(&sender, db_path.to_str().unwrap()).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+
+        let cheap = NodeContext::new(FeeMode::Fixed { amount: 2 }).unwrap();
+        let pricey = NodeContext::new(FeeMode::Fixed { amount: 9 }).unwrap();
+
+        let build = |context: &NodeContext| {
+            Transaction::new_utxo_transaction_with_context(
+                &sender,
+                &recipient,
+                1_000,
+                FeePriority::Normal,
+                &utxo_set,
+                context,
+            )
+            .unwrap()
+        };
+        assert_eq!(build(&pricey).get_fee(), 9);
+        let tx = build(&cheap);
+        assert_eq!(tx.get_fee(), 2);
+
+        // Only the context following the chain sees the block's fees
+        cheap.follow_fee_history(&blockchain).unwrap();
+        let block = blockchain
+            .mine_block_with_context(&[tx], &sender, &cheap)
+            .unwrap();
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+        let coinbase = &block.get_transactions()[0];
+        assert_eq!(coinbase.get_vout()[0].get_value(), INITIAL_BLOCK_REWARD + 2);
+        let recorded: Vec<_> = cheap.fee_history().recent(1).cloned().collect();
+        assert_eq!(recorded[0].height, block.get_height());
+        assert_eq!(recorded[0].fee_rates.len(), 1);
+        assert!(pricey.fee_history().is_empty());
+    }
+}
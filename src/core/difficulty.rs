@@ -1,6 +1,6 @@
-use crate::core::Block;
+use crate::core::{Block, Network};
 use crate::error::{BlockchainError, Result};
-use log::info;
+use log::{info, warn};
 
 // Difficulty adjustment constants
 const TARGET_BLOCK_TIME: u64 = 120_000; // 2 minutes in milliseconds
@@ -8,15 +8,67 @@ const DIFFICULTY_ADJUSTMENT_PERIOD: usize = 10; // Adjust every 10 blocks
 const INITIAL_DIFFICULTY: u32 = 4; // Starting difficulty
 const MIN_DIFFICULTY: u32 = 1; // Minimum difficulty
 const MAX_DIFFICULTY: u32 = 12; // Maximum difficulty
+/// Most a retarget moves the difficulty either way; each step doubles or halves the work,
+/// so this caps an adjustment at ×4 / ÷4
+const MAX_ADJUSTMENT_STEPS: u32 = 2;
+/// Shortest and longest time a single block counts for in a retarget, in milliseconds
+const MIN_BLOCK_INTERVAL: u64 = 1;
+const MAX_BLOCK_INTERVAL: u64 = 10 * TARGET_BLOCK_TIME;
+
+/// How a retarget measures the time its window took
+///
+/// A network switches from the first to the second at its `clamped_retarget_height`, so
+/// the blocks below it keep the difficulty they were mined at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetargetRules {
+    /// From the first to the last of the `period` blocks below the boundary, which has to
+    /// be a positive span
+    FirstToLast,
+    /// Over the `period` intervals up to the boundary, each clamped between
+    /// `MIN_BLOCK_INTERVAL` and `MAX_BLOCK_INTERVAL`, moving the difficulty at most
+    /// `MAX_ADJUSTMENT_STEPS`
+    ClampedIntervals,
+}
+
+impl RetargetRules {
+    /// The rules `network` retargets the block at `height` by
+    pub fn at(network: Network, height: usize) -> RetargetRules {
+        if height >= network.clamped_retarget_height() {
+            RetargetRules::ClampedIntervals
+        } else {
+            RetargetRules::FirstToLast
+        }
+    }
+}
 
 /// Difficulty adjustment algorithm for maintaining consistent block times
 pub struct DifficultyAdjustment;
 
 impl DifficultyAdjustment {
-    /// Calculate the next difficulty based on recent block times
+    /// Calculate the next difficulty based on recent block times, under
+    /// `RetargetRules::ClampedIntervals`
     pub fn calculate_next_difficulty(
         recent_blocks: &[Block],
         current_height: usize,
+    ) -> Result<u32> {
+        Self::calculate_next_difficulty_under(
+            RetargetRules::ClampedIntervals,
+            recent_blocks,
+            current_height,
+        )
+    }
+
+    /// Calculate the next difficulty based on recent block times, under `rules`
+    ///
+    /// At a boundary H, `recent_blocks` are the blocks from `window_start` to H - 1. With
+    /// clamped intervals past the first boundary that's H - period - 1 ..= H - 1, so the
+    /// window spans `period` block intervals; at the first there is no block before
+    /// genesis, so the window is heights 0 ..= period - 1 and the target time is scaled to
+    /// the one interval fewer it spans.
+    pub fn calculate_next_difficulty_under(
+        rules: RetargetRules,
+        recent_blocks: &[Block],
+        current_height: usize,
     ) -> Result<u32> {
         // Genesis block and early blocks use initial difficulty
         if current_height < DIFFICULTY_ADJUSTMENT_PERIOD {
@@ -24,7 +76,7 @@ impl DifficultyAdjustment {
         }
 
         // Only adjust difficulty at specific intervals
-        if current_height % DIFFICULTY_ADJUSTMENT_PERIOD != 0 {
+        if !current_height.is_multiple_of(DIFFICULTY_ADJUSTMENT_PERIOD) {
             // Return the difficulty of the most recent block
             return Ok(recent_blocks
                 .last()
@@ -32,21 +84,44 @@ impl DifficultyAdjustment {
                 .unwrap_or(INITIAL_DIFFICULTY));
         }
 
-        // Need exactly DIFFICULTY_ADJUSTMENT_PERIOD blocks for calculation
-        if recent_blocks.len() != DIFFICULTY_ADJUSTMENT_PERIOD {
+        // The window must be exactly these heights, otherwise two nodes looking at different
+        // slices of the chain would disagree on the difficulty
+        let window_start = Self::window_start(rules, current_height);
+        let window_len = current_height - window_start;
+        if recent_blocks.len() != window_len {
             return Err(BlockchainError::InvalidBlock(format!(
-                "Need {} blocks for difficulty adjustment, got {}",
-                DIFFICULTY_ADJUSTMENT_PERIOD,
+                "Need {window_len} blocks for difficulty adjustment, got {}",
                 recent_blocks.len()
             )));
         }
+        for (offset, block) in recent_blocks.iter().enumerate() {
+            if block.get_height() != window_start + offset {
+                return Err(BlockchainError::InvalidBlock(format!(
+                    "Difficulty window for height {current_height} must cover heights {}..={}, found block at height {} in position {offset}",
+                    window_start,
+                    current_height - 1,
+                    block.get_height()
+                )));
+            }
+        }
 
-        let actual_time_span = Self::calculate_time_span(recent_blocks)?;
-        let target_time_span = TARGET_BLOCK_TIME * DIFFICULTY_ADJUSTMENT_PERIOD as u64;
         let current_difficulty = recent_blocks
             .last()
             .expect("Recent blocks should not be empty at this point")
             .get_difficulty();
+        let (actual_time_span, target_time_span) = match rules {
+            RetargetRules::FirstToLast => (
+                Self::first_to_last_span(recent_blocks)?,
+                TARGET_BLOCK_TIME * DIFFICULTY_ADJUSTMENT_PERIOD as u64,
+            ),
+            RetargetRules::ClampedIntervals => {
+                let Some(span) = Self::calculate_time_span(recent_blocks) else {
+                    warn!("Keeping difficulty {current_difficulty} at height {current_height}: no block intervals to measure");
+                    return Ok(current_difficulty);
+                };
+                (span, TARGET_BLOCK_TIME * (recent_blocks.len() - 1) as u64)
+            }
+        };
 
         let new_difficulty =
             Self::adjust_difficulty(current_difficulty, actual_time_span, target_time_span);
@@ -56,30 +131,71 @@ impl DifficultyAdjustment {
         Ok(new_difficulty)
     }
 
-    /// Calculate the time span between the first and last block
-    fn calculate_time_span(blocks: &[Block]) -> Result<u64> {
-        if blocks.len() < 2 {
+    /// First height at or after `height` whose block is mined at a recalculated difficulty
+    pub fn next_retarget_height(height: usize) -> usize {
+        height
+            .div_ceil(DIFFICULTY_ADJUSTMENT_PERIOD)
+            .max(1)
+            .saturating_mul(DIFFICULTY_ADJUSTMENT_PERIOD)
+    }
+
+    /// Height of the first block in the window for the boundary at `height`
+    pub fn window_start(rules: RetargetRules, height: usize) -> usize {
+        match rules {
+            RetargetRules::FirstToLast => height.saturating_sub(DIFFICULTY_ADJUSTMENT_PERIOD),
+            RetargetRules::ClampedIntervals => {
+                height.saturating_sub(DIFFICULTY_ADJUSTMENT_PERIOD + 1)
+            }
+        }
+    }
+
+    /// The time from the first block to the last, which must be later
+    fn first_to_last_span(blocks: &[Block]) -> Result<u64> {
+        let (Some(first), Some(last)) = (blocks.first(), blocks.last()) else {
             return Err(BlockchainError::InvalidBlock(
                 "Need at least 2 blocks to calculate time span".to_string(),
             ));
-        }
-
-        let first_timestamp = blocks
-            .first()
-            .expect("Blocks should not be empty for time span calculation")
-            .get_timestamp();
-        let last_timestamp = blocks
-            .last()
-            .expect("Blocks should not be empty for time span calculation")
-            .get_timestamp();
-
-        if last_timestamp <= first_timestamp {
+        };
+        if last.get_timestamp() <= first.get_timestamp() {
             return Err(BlockchainError::InvalidBlock(
                 "Invalid block timestamps: last block is not newer than first".to_string(),
             ));
         }
+        Ok((last.get_timestamp() - first.get_timestamp()) as u64)
+    }
 
-        Ok((last_timestamp - first_timestamp) as u64)
+    /// Sum of the intervals between consecutive blocks, each clamped between
+    /// `MIN_BLOCK_INTERVAL` and `MAX_BLOCK_INTERVAL`
+    ///
+    /// A block stamped in the future, or no later than its parent, only skews its own two
+    /// intervals, and never by more than the clamp allows. None without any interval.
+    pub(crate) fn calculate_time_span(blocks: &[Block]) -> Option<u64> {
+        if blocks.len() < 2 {
+            return None;
+        }
+        let mut clamped = 0;
+        let span = blocks
+            .windows(2)
+            .map(|pair| {
+                let interval = pair[1]
+                    .get_timestamp()
+                    .saturating_sub(pair[0].get_timestamp());
+                let interval = u64::try_from(interval).unwrap_or(0);
+                let bounded = interval.clamp(MIN_BLOCK_INTERVAL, MAX_BLOCK_INTERVAL);
+                if bounded != interval {
+                    clamped += 1;
+                }
+                bounded
+            })
+            .sum();
+        if clamped > 0 {
+            warn!(
+                "{clamped} of {} block intervals ending at height {} were out of range and clamped",
+                blocks.len() - 1,
+                blocks[blocks.len() - 1].get_height()
+            );
+        }
+        Some(span)
     }
 
     /// Adjust difficulty based on actual vs target time
@@ -105,8 +221,13 @@ impl DifficultyAdjustment {
             current_difficulty
         };
 
-        // Clamp difficulty to valid range
-        new_difficulty.clamp(MIN_DIFFICULTY, MAX_DIFFICULTY)
+        // No more than ×4 either way, and within the valid range
+        new_difficulty
+            .clamp(
+                current_difficulty.saturating_sub(MAX_ADJUSTMENT_STEPS),
+                current_difficulty.saturating_add(MAX_ADJUSTMENT_STEPS),
+            )
+            .clamp(MIN_DIFFICULTY, MAX_DIFFICULTY)
     }
 
     /// Get the initial difficulty for genesis block
@@ -114,6 +235,16 @@ impl DifficultyAdjustment {
         INITIAL_DIFFICULTY
     }
 
+    /// Get the lowest difficulty a block can have
+    pub fn get_min_difficulty() -> u32 {
+        MIN_DIFFICULTY
+    }
+
+    /// Get the highest difficulty a block can have
+    pub fn get_max_difficulty() -> u32 {
+        MAX_DIFFICULTY
+    }
+
     /// Get the adjustment period
     pub fn get_adjustment_period() -> usize {
         DIFFICULTY_ADJUSTMENT_PERIOD
@@ -184,25 +315,137 @@ mod tests {
 
     #[test]
     fn test_difficulty_adjustment_slow_blocks() {
-        let blocks = vec![
-            create_test_block(0, 0, 4),
-            create_test_block(1, 200_000, 4),   // 200 seconds
-            create_test_block(2, 400_000, 4),   // 200 seconds
-            create_test_block(3, 600_000, 4),   // 200 seconds
-            create_test_block(4, 800_000, 4),   // 200 seconds
-            create_test_block(5, 1_000_000, 4), // 200 seconds
-            create_test_block(6, 1_200_000, 4), // 200 seconds
-            create_test_block(7, 1_400_000, 4), // 200 seconds
-            create_test_block(8, 1_600_000, 4), // 200 seconds
-            create_test_block(9, 1_800_000, 4), // 200 seconds
-        ];
+        let blocks: Vec<Block> = (0..10)
+            .map(|h| create_test_block(h, h as i64 * 180_000, 4)) // 180 seconds apart
+            .collect();
 
-        // Total time: 1800 seconds, target: 1200 seconds (10 * 120)
-        // Ratio: 1800/1200 = 1.5, exactly at boundary, should keep current difficulty
+        // Total time: 1620 seconds over 9 intervals, target: 1080 seconds (9 * 120)
+        // Ratio: 1620/1080 = 1.5, exactly at boundary, should keep current difficulty
         let result = DifficultyAdjustment::calculate_next_difficulty(&blocks, 10).unwrap();
         assert_eq!(result, 4); // No change at exactly 1.5
     }
 
+    // A window of ten blocks `spacing` ms apart, at difficulty 8, for the boundary at 10
+    fn spaced_window(spacing: i64) -> Vec<Block> {
+        (0..10)
+            .map(|h| create_test_block(h, h as i64 * spacing, 8))
+            .collect()
+    }
+
+    #[test]
+    fn test_odd_timestamps_give_bounded_difficulties() {
+        let bounded = |blocks: &[Block]| {
+            let difficulty = DifficultyAdjustment::calculate_next_difficulty(blocks, 10).unwrap();
+            assert!((6..=10).contains(&difficulty), "difficulty {difficulty}");
+            difficulty
+        };
+
+        // Every block in the same millisecond counts as the fastest window possible
+        assert_eq!(bounded(&spaced_window(0)), 10);
+        // Normal fast and slow windows
+        assert_eq!(bounded(&spaced_window(30_000)), 10);
+        assert_eq!(bounded(&spaced_window(100_000)), 8);
+        assert_eq!(bounded(&spaced_window(400_000)), 6);
+
+        // One block stamped far in the future only stretches the interval before it, to
+        // ten target times, and the ratio of 1.9 costs a single step
+        let mut blocks = spaced_window(120_000);
+        blocks[5] = create_test_block(5, i64::MAX / 2, 8);
+        assert_eq!(bounded(&blocks), 7);
+        // And one stamped before its parent doesn't make the span negative
+        let mut blocks = spaced_window(120_000);
+        blocks[5] = create_test_block(5, -1_000_000, 8);
+        bounded(&blocks);
+    }
+
+    #[test]
+    fn test_adjustment_is_capped_at_four_times_the_work() {
+        // Difficulty counts bits of work, so two steps are a factor of four
+        let current = 8;
+        assert_eq!(
+            DifficultyAdjustment::adjust_difficulty(current, 1, u64::MAX),
+            current + MAX_ADJUSTMENT_STEPS
+        );
+        assert_eq!(
+            DifficultyAdjustment::adjust_difficulty(current, u64::MAX, 1),
+            current - MAX_ADJUSTMENT_STEPS
+        );
+        assert_eq!(1u128 << MAX_ADJUSTMENT_STEPS, 4);
+    }
+
+    #[test]
+    fn test_window_past_the_first_boundary_spans_a_full_period() {
+        // Heights 9..=19, the block before the window included, 100s apart: ten intervals
+        // of 1000s in all against a target of 1200s, within range
+        let blocks: Vec<Block> = (9..20)
+            .map(|h| create_test_block(h, h as i64 * 100_000, 8))
+            .collect();
+        assert_eq!(
+            DifficultyAdjustment::calculate_next_difficulty(&blocks, 20).unwrap(),
+            8
+        );
+        // The ten blocks 10..=19 alone are one short
+        assert!(DifficultyAdjustment::calculate_next_difficulty(&blocks[1..], 20).is_err());
+    }
+
+    #[test]
+    fn test_first_to_last_rules_hold_below_the_activation_height() {
+        assert_eq!(
+            RetargetRules::at(Network::Mainnet, 10),
+            RetargetRules::FirstToLast
+        );
+        let activation = Network::Mainnet.clamped_retarget_height();
+        assert_eq!(
+            RetargetRules::at(Network::Mainnet, activation),
+            RetargetRules::ClampedIntervals
+        );
+        assert_eq!(
+            RetargetRules::at(Network::Regtest, 10),
+            RetargetRules::ClampedIntervals
+        );
+
+        // Heights 10..=19 only, the span from the first to the last: 900s against 1200s
+        let blocks: Vec<Block> = (10..20)
+            .map(|h| create_test_block(h, h as i64 * 100_000, 8))
+            .collect();
+        let legacy = |blocks: &[Block]| {
+            DifficultyAdjustment::calculate_next_difficulty_under(
+                RetargetRules::FirstToLast,
+                blocks,
+                20,
+            )
+        };
+        assert_eq!(legacy(&blocks).unwrap(), 8);
+        // A window that didn't move forward in time is refused rather than clamped
+        let mut stalled = blocks.clone();
+        stalled[9] = create_test_block(19, 1_000_000, 8);
+        assert!(legacy(&stalled).is_err());
+        // The clamped window starts a block earlier
+        assert!(legacy(&spaced_window(10_000)).is_err());
+    }
+
+    #[test]
+    fn test_window_must_match_boundary_heights() {
+        // Blocks 1..=10 are the wrong window for height 10 (it needs 0..=9)
+        let blocks: Vec<Block> = (1..=10)
+            .map(|h| create_test_block(h, h as i64 * 10_000, 4))
+            .collect();
+
+        assert!(DifficultyAdjustment::calculate_next_difficulty(&blocks, 10).is_err());
+    }
+
+    #[test]
+    fn test_next_retarget_height_around_boundaries() {
+        // Genesis and the early blocks wait for the first boundary
+        for height in 0..=10 {
+            assert_eq!(DifficultyAdjustment::next_retarget_height(height), 10);
+        }
+        assert_eq!(DifficultyAdjustment::next_retarget_height(11), 20);
+        assert_eq!(DifficultyAdjustment::next_retarget_height(19), 20);
+        assert_eq!(DifficultyAdjustment::next_retarget_height(20), 20);
+        assert_eq!(DifficultyAdjustment::next_retarget_height(21), 30);
+    }
+
     #[test]
     fn test_difficulty_bounds() {
         // Test minimum difficulty bound
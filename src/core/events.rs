@@ -0,0 +1,216 @@
+//! Chain and memory pool events for code embedding the crate
+//!
+//! Every `Blockchain` publishes on an `EventBus`: a `BlockConnected` for each block that
+//! joins the main chain, followed by a `TxConfirmed` for each of its transactions, and a
+//! `BlockDisconnected` for each block a reorg takes off it, newest first. A `MemoryPool`
+//! publishes `TxAccepted` and `TxEvicted`. `Server::with_context` hands the node's pool
+//! the chain's bus, so one subscription sees both in the order they happened.
+//!
+//! Publishing never waits for a subscriber. A channel subscription has a bounded buffer,
+//! and events that find it full are dropped and counted; callbacks run on the publishing
+//! thread and must return quickly. Subscriptions whose receiver is gone are removed on the
+//! next event.
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// How many events a channel subscription buffers unless asked otherwise
+pub const DEFAULT_EVENT_BUFFER: usize = 1_024;
+
+/// Something that happened to the chain or the memory pool; hashes and txids are hex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChainEvent {
+    BlockConnected {
+        hash: String,
+        height: usize,
+    },
+    BlockDisconnected {
+        hash: String,
+        height: usize,
+    },
+    TxAccepted {
+        txid: String,
+    },
+    TxConfirmed {
+        txid: String,
+        block_hash: String,
+    },
+    TxEvicted {
+        txid: String,
+        reason: EvictionReason,
+    },
+}
+
+/// Why a transaction left the memory pool without being mined
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionReason {
+    /// A transaction spending the same outputs paid more, see `MemoryPool::add_safe`
+    Replaced { by: String },
+    /// The new tip spent its inputs or otherwise made it invalid
+    Invalidated,
+    /// Its sender gave up on it, see `MemoryPool::abandon`
+    Abandoned,
+}
+
+impl fmt::Display for EvictionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvictionReason::Replaced { by } => write!(f, "replaced by {by}"),
+            EvictionReason::Invalidated => f.write_str("invalidated by the new tip"),
+            EvictionReason::Abandoned => f.write_str("abandoned by its sender"),
+        }
+    }
+}
+
+/// Identifies a subscription for `EventBus::unsubscribe`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+/// Where a subscription's events go
+#[derive(Clone)]
+pub enum EventSink {
+    Channel(SyncSender<ChainEvent>),
+    Callback(Arc<dyn Fn(&ChainEvent) + Send + Sync>),
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    sinks: Vec<(SubscriptionId, EventSink)>,
+}
+
+/// Fans events out to subscribers; clones share the same subscriptions
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Subscribers>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        Self::default()
+    }
+
+    /// Receive events on a channel buffering up to `capacity` of them
+    pub fn subscribe(&self, capacity: usize) -> (SubscriptionId, Receiver<ChainEvent>) {
+        let (sender, receiver) = sync_channel(capacity.max(1));
+        (self.add_sink(EventSink::Channel(sender)), receiver)
+    }
+
+    /// Call `callback` with every event, on whichever thread publishes it
+    pub fn subscribe_callback(
+        &self,
+        callback: impl Fn(&ChainEvent) + Send + Sync + 'static,
+    ) -> SubscriptionId {
+        self.add_sink(EventSink::Callback(Arc::new(callback)))
+    }
+
+    pub fn add_sink(&self, sink: EventSink) -> SubscriptionId {
+        let mut subscribers = self.lock();
+        let id = SubscriptionId(subscribers.next_id);
+        subscribers.next_id += 1;
+        subscribers.sinks.push((id, sink));
+        id
+    }
+
+    /// Stop delivering to `id`; false if it wasn't subscribed
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.lock();
+        let before = subscribers.sinks.len();
+        subscribers.sinks.retain(|(sink_id, _)| *sink_id != id);
+        subscribers.sinks.len() != before
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.lock().sinks.is_empty()
+    }
+
+    /// Events dropped so far because a subscriber's buffer was full
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn publish(&self, event: ChainEvent) {
+        // Callbacks run without the lock held, so they may subscribe or unsubscribe
+        let sinks: Vec<(SubscriptionId, EventSink)> = self.lock().sinks.clone();
+        let mut gone = Vec::new();
+        for (id, sink) in sinks {
+            match sink {
+                EventSink::Channel(sender) => match sender.try_send(event.clone()) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        debug!("Dropped {event:?}: subscriber is not keeping up");
+                    }
+                    Err(TrySendError::Disconnected(_)) => gone.push(id),
+                },
+                EventSink::Callback(callback) => callback(&event),
+            }
+        }
+        if !gone.is_empty() {
+            self.lock().sinks.retain(|(id, _)| !gone.contains(id));
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Subscribers> {
+        // A panicking callback never runs under the lock, so poisoning leaves nothing torn
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted(txid: &str) -> ChainEvent {
+        ChainEvent::TxAccepted {
+            txid: txid.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_subscribers_get_events_until_they_unsubscribe() {
+        let bus = EventBus::new();
+        let (first, events) = bus.subscribe(DEFAULT_EVENT_BUFFER);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        let callback = bus.subscribe_callback(move |event| log.lock().unwrap().push(event.clone()));
+
+        bus.publish(accepted("aa"));
+        assert!(bus.unsubscribe(first));
+        assert!(!bus.unsubscribe(first));
+        bus.publish(accepted("bb"));
+
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![accepted("aa")]);
+        assert_eq!(*seen.lock().unwrap(), vec![accepted("aa"), accepted("bb")]);
+        assert!(bus.unsubscribe(callback));
+        assert!(!bus.has_subscribers());
+    }
+
+    #[test]
+    fn test_full_or_abandoned_channels_never_block() {
+        let bus = EventBus::new();
+        let (_, slow) = bus.subscribe(2);
+        let (_, abandoned) = bus.subscribe(2);
+        drop(abandoned);
+
+        for txid in ["aa", "bb", "cc", "dd"] {
+            bus.publish(accepted(txid));
+        }
+        assert_eq!(
+            slow.try_iter().collect::<Vec<_>>(),
+            vec![accepted("aa"), accepted("bb")]
+        );
+        assert_eq!(bus.dropped_events(), 2);
+        // The abandoned subscription went away with the first event it couldn't take
+        assert_eq!(bus.lock().sinks.len(), 1);
+    }
+}
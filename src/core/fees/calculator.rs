@@ -1,7 +1,10 @@
 use crate::core::fees::{
     dynamic::{DynamicFeeCalculator, DynamicFeeConfig, FeePriority, FeeStatistics},
     fixed::FixedFeeCalculator,
+    history::FeeHistory,
 };
+use crate::core::monetary::conversions::format_amount;
+use crate::core::monetary::{Amount, MIN_TRANSACTION_FEE};
 use crate::error::{BlockchainError, Result};
 use log::info;
 use serde::{Deserialize, Serialize};
@@ -17,7 +20,10 @@ pub enum FeeMode {
 
 impl Default for FeeMode {
     fn default() -> Self {
-        FeeMode::Fixed { amount: 1 } // Default to legacy fixed fee for backward compatibility
+        // The smallest fee the relay policy lets through, so a node's own payments clear it
+        FeeMode::Fixed {
+            amount: MIN_TRANSACTION_FEE,
+        }
     }
 }
 
@@ -48,7 +54,10 @@ impl UnifiedFeeCalculator {
             FeeMode::Fixed { amount } => {
                 self.fixed_calculator = Some(FixedFeeCalculator::new(*amount));
                 self.dynamic_calculator = None;
-                info!("Initialized fixed fee calculator with {amount} coins");
+                info!(
+                    "Initialized fixed fee calculator with {}",
+                    format_amount(*amount)
+                );
             }
             FeeMode::Dynamic { config } => {
                 self.dynamic_calculator = Some(DynamicFeeCalculator::new(config.clone())?);
@@ -59,41 +68,25 @@ impl UnifiedFeeCalculator {
         Ok(())
     }
 
-    /// Calculate transaction fee
-    pub fn calculate_fee(&self, transaction_size: usize, priority: Option<FeePriority>) -> u64 {
-        match &self.mode {
-            FeeMode::Fixed { .. } => {
-                if let Some(ref calculator) = self.fixed_calculator {
-                    calculator.calculate_fee(transaction_size, priority)
-                } else {
-                    1 // Fallback to default
-                }
-            }
-            FeeMode::Dynamic { .. } => {
-                if let Some(ref calculator) = self.dynamic_calculator {
-                    let priority = priority.unwrap_or(FeePriority::Normal);
-                    let mempool_size = crate::storage::GLOBAL_MEMORY_POOL.len();
-                    calculator.calculate_fee(priority, mempool_size)
-                } else {
-                    1 // Fallback to default
-                }
-            }
-        }
+    /// Calculate transaction fee against the global memory pool
+    pub fn calculate_fee(&self, transaction_size: usize, priority: Option<FeePriority>) -> Amount {
+        let mempool_size = crate::storage::GLOBAL_MEMORY_POOL.len();
+        self.calculate_fee_with_mempool_size(transaction_size, priority, mempool_size)
     }
 
-    /// Calculate fee with explicit mempool size (for testing)
+    /// Calculate fee for a memory pool holding `mempool_size` transactions
     pub fn calculate_fee_with_mempool_size(
         &self,
         transaction_size: usize,
         priority: Option<FeePriority>,
         mempool_size: usize,
-    ) -> u64 {
+    ) -> Amount {
         match &self.mode {
             FeeMode::Fixed { .. } => {
                 if let Some(ref calculator) = self.fixed_calculator {
                     calculator.calculate_fee(transaction_size, priority)
                 } else {
-                    1
+                    Amount::from_satoshis(1) // Fallback to default
                 }
             }
             FeeMode::Dynamic { .. } => {
@@ -101,28 +94,62 @@ impl UnifiedFeeCalculator {
                     let priority = priority.unwrap_or(FeePriority::Normal);
                     calculator.calculate_fee(priority, mempool_size)
                 } else {
-                    1
+                    Amount::from_satoshis(1) // Fallback to default
                 }
             }
         }
     }
 
     /// Estimate fee for a given priority
-    pub fn estimate_fee(&self, priority: FeePriority) -> u64 {
+    pub fn estimate_fee(&self, priority: FeePriority) -> Amount {
         match &self.mode {
-            FeeMode::Fixed { amount } => *amount,
+            FeeMode::Fixed { amount } => Amount::from(*amount),
             FeeMode::Dynamic { .. } => {
                 if let Some(ref calculator) = self.dynamic_calculator {
                     calculator.estimate_fee(priority)
                 } else {
-                    1
+                    Amount::from_satoshis(1)
                 }
             }
         }
     }
 
-    /// Validate a fee amount
-    pub fn validate_fee(&self, fee: u64, priority: Option<FeePriority>) -> Result<()> {
+    /// Estimate fee for a given priority, memory pool size and fee history
+    pub fn estimate_fee_with_mempool_size(
+        &self,
+        priority: FeePriority,
+        mempool_size: usize,
+        history: &FeeHistory,
+    ) -> Amount {
+        match &self.mode {
+            FeeMode::Fixed { amount } => Amount::from(*amount),
+            FeeMode::Dynamic { .. } => {
+                if let Some(ref calculator) = self.dynamic_calculator {
+                    calculator.estimate_fee_with_history(priority, mempool_size, history)
+                } else {
+                    Amount::from_satoshis(1)
+                }
+            }
+        }
+    }
+
+    /// Validate a fee amount against the global memory pool
+    pub fn validate_fee(
+        &self,
+        fee: impl Into<Amount>,
+        priority: Option<FeePriority>,
+    ) -> Result<()> {
+        let mempool_size = crate::storage::GLOBAL_MEMORY_POOL.len();
+        self.validate_fee_with_mempool_size(fee, priority, mempool_size)
+    }
+
+    /// Validate a fee amount for a memory pool holding `mempool_size` transactions
+    pub fn validate_fee_with_mempool_size(
+        &self,
+        fee: impl Into<Amount>,
+        priority: Option<FeePriority>,
+        mempool_size: usize,
+    ) -> Result<()> {
         match &self.mode {
             FeeMode::Fixed { .. } => {
                 if let Some(ref calculator) = self.fixed_calculator {
@@ -134,7 +161,6 @@ impl UnifiedFeeCalculator {
             FeeMode::Dynamic { .. } => {
                 if let Some(ref calculator) = self.dynamic_calculator {
                     let priority = priority.unwrap_or(FeePriority::Normal);
-                    let mempool_size = crate::storage::GLOBAL_MEMORY_POOL.len();
                     calculator.validate_fee(fee, priority, mempool_size)
                 } else {
                     Ok(())
@@ -143,21 +169,29 @@ impl UnifiedFeeCalculator {
         }
     }
 
-    /// Calculate coinbase reward
-    pub fn calculate_coinbase_reward(&self, collected_fees: u64) -> u64 {
+    /// Calculate the coinbase reward for a block at `height`
+    pub fn calculate_coinbase_reward(
+        &self,
+        collected_fees: impl Into<Amount>,
+        height: usize,
+    ) -> Amount {
         match &self.mode {
             FeeMode::Fixed { .. } => {
                 if let Some(ref calculator) = self.fixed_calculator {
-                    calculator.calculate_coinbase_reward(collected_fees)
+                    calculator.calculate_coinbase_reward(collected_fees, height)
                 } else {
-                    crate::core::INITIAL_BLOCK_REWARD + collected_fees // Use proper monetary constant
+                    // Use proper monetary schedule
+                    Amount::from(crate::core::block_subsidy(height))
+                        .saturating_add(collected_fees.into())
                 }
             }
             FeeMode::Dynamic { .. } => {
                 if let Some(ref calculator) = self.dynamic_calculator {
-                    calculator.calculate_coinbase_reward(collected_fees)
+                    calculator.calculate_coinbase_reward(collected_fees, height)
                 } else {
-                    crate::core::INITIAL_BLOCK_REWARD + collected_fees // Use proper monetary constant
+                    // Use proper monetary schedule
+                    Amount::from(crate::core::block_subsidy(height))
+                        .saturating_add(collected_fees.into())
                 }
             }
         }
@@ -186,17 +220,24 @@ impl UnifiedFeeCalculator {
         matches!(self.mode, FeeMode::Fixed { .. })
     }
 
-    /// Get fee statistics (only available for dynamic mode)
+    /// Get fee statistics against the global memory pool, without a fee history (only
+    /// available for dynamic mode)
     pub fn get_fee_statistics(&self) -> Option<FeeStatistics> {
+        let mempool_size = crate::storage::GLOBAL_MEMORY_POOL.len();
+        self.get_fee_statistics_with_mempool_size(mempool_size, &FeeHistory::default())
+    }
+
+    /// Get fee statistics for a memory pool holding `mempool_size` transactions
+    pub fn get_fee_statistics_with_mempool_size(
+        &self,
+        mempool_size: usize,
+        history: &FeeHistory,
+    ) -> Option<FeeStatistics> {
         match &self.mode {
-            FeeMode::Dynamic { .. } => {
-                if let Some(ref calculator) = self.dynamic_calculator {
-                    let mempool_size = crate::storage::GLOBAL_MEMORY_POOL.len();
-                    Some(calculator.get_fee_statistics(mempool_size))
-                } else {
-                    None
-                }
-            }
+            FeeMode::Dynamic { .. } => self
+                .dynamic_calculator
+                .as_ref()
+                .map(|calculator| calculator.get_fee_statistics(mempool_size, history)),
             FeeMode::Fixed { .. } => None,
         }
     }
@@ -205,12 +246,14 @@ impl UnifiedFeeCalculator {
     pub fn get_config_summary(&self) -> String {
         match &self.mode {
             FeeMode::Fixed { amount } => {
-                format!("Fixed fee: {amount} coins")
+                format!("Fixed fee: {}", format_amount(*amount))
             }
             FeeMode::Dynamic { config } => {
                 format!(
-                    "Dynamic fees: base {} coins, max {} coins, threshold {} transactions",
-                    config.base_fee, config.max_fee, config.congestion_threshold
+                    "Dynamic fees: base {}, max {}, threshold {} transactions",
+                    format_amount(config.base_fee),
+                    format_amount(config.max_fee),
+                    config.congestion_threshold
                 )
             }
         }
@@ -241,7 +284,7 @@ impl UnifiedFeeCalculator {
                 if let Some(ref mut calculator) = self.fixed_calculator {
                     calculator.set_fee_amount(new_amount);
                 }
-                info!("Updated fixed fee to {new_amount} coins");
+                info!("Updated fixed fee to {}", format_amount(new_amount));
                 Ok(())
             }
             FeeMode::Dynamic { .. } => Err(BlockchainError::Config(
@@ -294,8 +337,8 @@ impl LegacyFeeCalculator {
     }
 
     /// Legacy coinbase reward calculation
-    pub fn calculate_coinbase_reward(collected_fees: u64) -> u64 {
-        crate::core::INITIAL_BLOCK_REWARD + collected_fees // Base reward + fees
+    pub fn calculate_coinbase_reward(collected_fees: u64, height: usize) -> u64 {
+        crate::core::block_subsidy(height) + collected_fees // Subsidy + fees
     }
 }
 
@@ -370,22 +413,55 @@ mod tests {
     #[test]
     fn test_coinbase_reward_calculation() {
         let calculator = UnifiedFeeCalculator::new(FeeMode::Fixed { amount: 1 }).unwrap();
-        let reward = calculator.calculate_coinbase_reward(5);
+        let reward = calculator.calculate_coinbase_reward(5, 0);
         assert_eq!(reward, crate::core::INITIAL_BLOCK_REWARD + 5); // Base reward + 5 fees
     }
 
+    #[test]
+    fn test_coinbase_reward_follows_halving_schedule() {
+        use crate::core::{block_subsidy, HALVING_INTERVAL, INITIAL_BLOCK_REWARD};
+
+        let fixed = UnifiedFeeCalculator::new(FeeMode::Fixed { amount: 1 }).unwrap();
+        let dynamic = UnifiedFeeCalculator::new(FeeMode::Dynamic {
+            config: DynamicFeeConfig::default(),
+        })
+        .unwrap();
+
+        for calculator in [&fixed, &dynamic] {
+            for (height, subsidy) in [
+                (0, INITIAL_BLOCK_REWARD),
+                (HALVING_INTERVAL - 1, INITIAL_BLOCK_REWARD),
+                (HALVING_INTERVAL, INITIAL_BLOCK_REWARD / 2),
+                (2 * HALVING_INTERVAL, INITIAL_BLOCK_REWARD / 4),
+            ] {
+                assert_eq!(block_subsidy(height), subsidy);
+                assert_eq!(calculator.calculate_coinbase_reward(7, height), subsidy + 7);
+            }
+        }
+    }
+
     #[test]
     fn test_config_summary() {
         let fixed_calculator = UnifiedFeeCalculator::new(FeeMode::Fixed { amount: 2 }).unwrap();
         let summary = fixed_calculator.get_config_summary();
-        assert!(summary.contains("Fixed fee: 2 coins"));
+        assert_eq!(summary, "Fixed fee: 0.00000002 coins (2 satoshis)");
 
         let dynamic_calculator = UnifiedFeeCalculator::new(FeeMode::Dynamic {
             config: DynamicFeeConfig::default(),
         })
         .unwrap();
         let summary = dynamic_calculator.get_config_summary();
-        assert!(summary.contains("Dynamic fees"));
+        let config = DynamicFeeConfig::default();
+        assert_eq!(
+            summary,
+            format!(
+                "Dynamic fees: base {}, max {}, threshold {} transactions",
+                format_amount(config.base_fee),
+                format_amount(config.max_fee),
+                config.congestion_threshold
+            )
+        );
+        assert!(summary.contains(&format!("({} satoshis)", config.max_fee)));
     }
 
     #[test]
@@ -394,7 +470,7 @@ mod tests {
         assert_eq!(LegacyFeeCalculator::calculate_fee(100, 2).unwrap(), 200);
         assert!(LegacyFeeCalculator::validate_fee_rate(10).is_ok());
         assert_eq!(
-            LegacyFeeCalculator::calculate_coinbase_reward(5),
+            LegacyFeeCalculator::calculate_coinbase_reward(5, 0),
             crate::core::INITIAL_BLOCK_REWARD + 5
         );
     }
@@ -1,3 +1,6 @@
+use crate::core::fees::history::{FeeHistory, RecentFeeRates};
+use crate::core::monetary::conversions::format_amount;
+use crate::core::monetary::{halved_subsidy, Amount, MIN_TRANSACTION_FEE};
 use crate::error::{BlockchainError, Result};
 use log::{info, warn};
 use serde::{Deserialize, Serialize};
@@ -13,6 +16,16 @@ pub enum FeePriority {
     Urgent,
 }
 
+impl FeePriority {
+    /// Every priority, lowest first
+    pub const ALL: [FeePriority; 4] = [
+        FeePriority::Low,
+        FeePriority::Normal,
+        FeePriority::High,
+        FeePriority::Urgent,
+    ];
+}
+
 impl std::fmt::Display for FeePriority {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -49,7 +62,7 @@ pub struct DynamicFeeConfig {
     pub congestion_threshold: usize,
     /// Multipliers for different priority levels
     pub priority_multipliers: HashMap<FeePriority, f64>,
-    /// Base coinbase reward
+    /// Coinbase subsidy before any halving
     pub coinbase_reward: u64,
 }
 
@@ -115,7 +128,7 @@ impl DynamicFeeConfig {
 
 impl Default for DynamicFeeConfig {
     fn default() -> Self {
-        Self::with_base_fee(1)
+        Self::with_base_fee(MIN_TRANSACTION_FEE)
     }
 }
 
@@ -133,7 +146,7 @@ impl DynamicFeeCalculator {
     }
 
     /// Calculate fee based on priority and current mempool size
-    pub fn calculate_fee(&self, priority: FeePriority, mempool_size: usize) -> u64 {
+    pub fn calculate_fee(&self, priority: FeePriority, mempool_size: usize) -> Amount {
         let base = self.config.base_fee as f64;
         let priority_multiplier = self.get_priority_multiplier(priority);
         let congestion_multiplier = self.calculate_congestion_multiplier(mempool_size);
@@ -145,11 +158,11 @@ impl DynamicFeeCalculator {
         let capped_fee = final_fee.max(self.config.base_fee).min(self.config.max_fee);
 
         info!(
-            "Calculated fee: {} coins (priority: {}, mempool: {}, base: {}, priority_mult: {:.2}, congestion_mult: {:.2})",
-            capped_fee, priority, mempool_size, self.config.base_fee, priority_multiplier, congestion_multiplier
+            "Calculated fee: {} (priority: {}, mempool: {}, base: {}, priority_mult: {:.2}, congestion_mult: {:.2})",
+            format_amount(capped_fee), priority, mempool_size, format_amount(self.config.base_fee), priority_multiplier, congestion_multiplier
         );
 
-        capped_fee
+        Amount::from_satoshis(capped_fee)
     }
 
     /// Get priority multiplier for a given priority level
@@ -173,26 +186,50 @@ impl DynamicFeeCalculator {
         }
     }
 
-    /// Estimate fee for a given priority (uses current mempool size)
-    pub fn estimate_fee(&self, priority: FeePriority) -> u64 {
+    /// Estimate fee for a given priority from the current mempool size alone
+    ///
+    /// The fee history lives with a node, see `NodeContext::estimate_fee`.
+    pub fn estimate_fee(&self, priority: FeePriority) -> Amount {
         // Get current mempool size from global memory pool
         let mempool_size = crate::storage::GLOBAL_MEMORY_POOL.len();
-        self.calculate_fee(priority, mempool_size)
+        self.estimate_fee_with_history(priority, mempool_size, &FeeHistory::default())
     }
 
-    /// Validate that a fee is appropriate for the given conditions
-    pub fn validate_fee(&self, fee: u64, priority: FeePriority, mempool_size: usize) -> Result<()> {
-        let expected_fee = self.calculate_fee(priority, mempool_size);
-
-        // Allow some tolerance for fee validation (±10%)
-        let tolerance = ((expected_fee as f64 * 0.1).max(0.0)) as u64; // Ensure non-negative
-        let min_acceptable = expected_fee.saturating_sub(tolerance);
-        let max_acceptable = expected_fee + tolerance;
+    /// Estimate fee by blending the mempool-based fee with what recent blocks confirmed
+    ///
+    /// The two count equally. Without fee-paying blocks in `history` only the mempool
+    /// estimate is left.
+    pub fn estimate_fee_with_history(
+        &self,
+        priority: FeePriority,
+        mempool_size: usize,
+        history: &FeeHistory,
+    ) -> Amount {
+        let current = self.calculate_fee(priority, mempool_size).to_satoshis();
+        let Some(historical) = history.estimate_fee(priority) else {
+            return Amount::from_satoshis(current);
+        };
+        let blended = current.saturating_add(historical).div_ceil(2);
+        Amount::from_satoshis(blended.clamp(self.config.base_fee, self.config.max_fee))
+    }
 
-        if fee < min_acceptable || fee > max_acceptable {
-            warn!("Fee validation failed: provided {fee}, expected {expected_fee} (±{tolerance})");
+    /// Validate that a fee pays at least what the given conditions ask, less 10%
+    ///
+    /// Paying more is always fine; it only gets the transaction mined sooner.
+    pub fn validate_fee(
+        &self,
+        fee: impl Into<Amount>,
+        priority: FeePriority,
+        mempool_size: usize,
+    ) -> Result<()> {
+        let fee = fee.into().to_satoshis();
+        let expected_fee = self.calculate_fee(priority, mempool_size).to_satoshis();
+        let min_acceptable = expected_fee - expected_fee / 10;
+
+        if fee < min_acceptable {
+            warn!("Fee validation failed: provided {fee}, expected at least {min_acceptable}");
             return Err(BlockchainError::Transaction(format!(
-                "Invalid fee: provided {fee}, expected {expected_fee} (±{tolerance})"
+                "Invalid fee: provided {fee}, expected at least {min_acceptable}"
             )));
         }
 
@@ -200,8 +237,13 @@ impl DynamicFeeCalculator {
     }
 
     /// Calculate coinbase reward with collected fees
-    pub fn calculate_coinbase_reward(&self, collected_fees: u64) -> u64 {
-        self.config.coinbase_reward + collected_fees
+    pub fn calculate_coinbase_reward(
+        &self,
+        collected_fees: impl Into<Amount>,
+        height: usize,
+    ) -> Amount {
+        Amount::from(halved_subsidy(self.config.coinbase_reward, height))
+            .saturating_add(collected_fees.into())
     }
 
     /// Get current configuration
@@ -218,7 +260,7 @@ impl DynamicFeeCalculator {
     }
 
     /// Get fee statistics for monitoring
-    pub fn get_fee_statistics(&self, mempool_size: usize) -> FeeStatistics {
+    pub fn get_fee_statistics(&self, mempool_size: usize, history: &FeeHistory) -> FeeStatistics {
         FeeStatistics {
             base_fee: self.config.base_fee,
             max_fee: self.config.max_fee,
@@ -227,22 +269,22 @@ impl DynamicFeeCalculator {
             congestion_threshold: self.config.congestion_threshold,
             estimated_fees: {
                 let mut fees = HashMap::new();
-                for priority in [
-                    FeePriority::Low,
-                    FeePriority::Normal,
-                    FeePriority::High,
-                    FeePriority::Urgent,
-                ] {
-                    fees.insert(priority, self.calculate_fee(priority, mempool_size));
+                for priority in FeePriority::ALL {
+                    fees.insert(
+                        priority,
+                        self.estimate_fee_with_history(priority, mempool_size, history)
+                            .to_satoshis(),
+                    );
                 }
                 fees
             },
+            recent_fee_rates: history.recent_rates(),
         }
     }
 }
 
 /// Fee statistics for monitoring and display
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FeeStatistics {
     pub base_fee: u64,
     pub max_fee: u64,
@@ -250,13 +292,14 @@ pub struct FeeStatistics {
     pub mempool_size: usize,
     pub congestion_threshold: usize,
     pub estimated_fees: HashMap<FeePriority, u64>,
+    pub recent_fee_rates: RecentFeeRates,
 }
 
 impl std::fmt::Display for FeeStatistics {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Fee Statistics:")?;
-        writeln!(f, "  Base Fee: {} coins", self.base_fee)?;
-        writeln!(f, "  Max Fee: {} coins", self.max_fee)?;
+        writeln!(f, "  Base Fee: {}", format_amount(self.base_fee))?;
+        writeln!(f, "  Max Fee: {}", format_amount(self.max_fee))?;
         writeln!(f, "  Mempool Size: {} transactions", self.mempool_size)?;
         writeln!(
             f,
@@ -269,16 +312,20 @@ impl std::fmt::Display for FeeStatistics {
             self.current_congestion_multiplier
         )?;
         writeln!(f, "  Estimated Fees:")?;
-        for (priority, fee) in &self.estimated_fees {
-            writeln!(f, "    {priority}: {fee} coins")?;
+        for priority in FeePriority::ALL {
+            if let Some(fee) = self.estimated_fees.get(&priority) {
+                writeln!(f, "    {priority}: {}", format_amount(*fee))?;
+            }
         }
-        Ok(())
+        writeln!(f, "  Recent Confirmed Fee Rates:")?;
+        write!(f, "{}", self.recent_fee_rates)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::fees::history::BlockFeeRecord;
 
     fn create_test_config() -> DynamicFeeConfig {
         DynamicFeeConfig {
@@ -368,15 +415,25 @@ mod tests {
             .is_ok());
 
         // Fee within tolerance should be valid
-        let tolerance = (expected_fee as f64 * 0.05) as u64; // 5% tolerance
+        let tolerance = (expected_fee.to_satoshis() as f64 * 0.05) as u64; // 5% tolerance
+        assert!(calculator
+            .validate_fee(
+                expected_fee.to_satoshis() + tolerance,
+                FeePriority::Normal,
+                10
+            )
+            .is_ok());
+
+        // Overpaying is never a problem
+        let overpaid = expected_fee.to_satoshis() * 2;
         assert!(calculator
-            .validate_fee(expected_fee + tolerance, FeePriority::Normal, 10)
+            .validate_fee(overpaid, FeePriority::Normal, 10)
             .is_ok());
 
-        // Fee outside tolerance should be invalid
-        let large_deviation = expected_fee * 2;
+        // Underpaying by more than the tolerance is
+        let underpaid = expected_fee.to_satoshis() / 2;
         assert!(calculator
-            .validate_fee(large_deviation, FeePriority::Normal, 10)
+            .validate_fee(underpaid, FeePriority::Normal, 10)
             .is_err());
     }
 
@@ -399,12 +456,113 @@ mod tests {
     #[test]
     fn test_fee_statistics() {
         let calculator = DynamicFeeCalculator::new(create_test_config()).unwrap();
-        let stats = calculator.get_fee_statistics(15);
+        let stats = calculator.get_fee_statistics(15, &FeeHistory::default());
 
         assert_eq!(stats.base_fee, 1);
         assert_eq!(stats.max_fee, 10);
         assert_eq!(stats.mempool_size, 15);
         assert!(stats.current_congestion_multiplier > 1.0);
         assert_eq!(stats.estimated_fees.len(), 4);
+        assert_eq!(stats.recent_fee_rates.blocks, 0);
+    }
+
+    #[test]
+    fn test_fee_statistics_print_coins_and_satoshis_alike() {
+        use crate::core::monetary::SATOSHIS_PER_COIN;
+
+        let calculator = DynamicFeeCalculator::new(DynamicFeeConfig {
+            base_fee: 12_345,
+            max_fee: 250_000_000,
+            ..create_test_config()
+        })
+        .unwrap();
+        let stats = calculator.get_fee_statistics(15, &FeeHistory::default());
+        let printed = stats.to_string();
+
+        // Every fee line names both units, and the two agree
+        let mut fees = Vec::new();
+        for line in printed.lines().filter(|line| line.contains("coins")) {
+            let (coins, rest) = line
+                .rsplit_once(": ")
+                .unwrap()
+                .1
+                .split_once(" coins (")
+                .unwrap();
+            let satoshis: u64 = rest.split_once(' ').unwrap().0.parse().unwrap();
+            assert!(rest.starts_with(&format!("{satoshis} satoshi")), "{line}");
+            let coins: f64 = coins.parse().unwrap();
+            assert_eq!((coins * SATOSHIS_PER_COIN as f64).round() as u64, satoshis);
+            fees.push(satoshis);
+        }
+        let mut expected = vec![stats.base_fee, stats.max_fee];
+        expected.extend(FeePriority::ALL.map(|priority| stats.estimated_fees[&priority]));
+        assert_eq!(fees, expected);
+        assert!(printed.contains("Base Fee: 0.00012345 coins (12345 satoshis)"));
+        assert!(printed.contains("Max Fee: 2.50000000 coins (250000000 satoshis)"));
+    }
+
+    // Ten blocks, each confirming one 1000 byte transaction, paying 10 to 100 satoshis
+    fn history_paying_10_to_100() -> FeeHistory {
+        let mut history = FeeHistory::default();
+        for height in 1..=10 {
+            history.record(BlockFeeRecord::new(
+                height,
+                &[(height as u64 * 10, 1_000)],
+                5_000,
+            ));
+        }
+        history
+    }
+
+    #[test]
+    fn test_estimates_blend_in_confirmed_fee_rates() {
+        let mut config = create_test_config();
+        config.max_fee = 1_000;
+        let calculator = DynamicFeeCalculator::new(config).unwrap();
+        let history = history_paying_10_to_100();
+        let estimate = |priority| calculator.estimate_fee_with_history(priority, 0, &history);
+
+        // Half the mempool fee of 1, 1, 2 and 3 satoshis, half the 10th, 50th, 75th and
+        // 90th percentile of what got confirmed
+        assert_eq!(estimate(FeePriority::Low), 6);
+        assert_eq!(estimate(FeePriority::Normal), 26);
+        assert_eq!(estimate(FeePriority::High), 41);
+        assert_eq!(estimate(FeePriority::Urgent), 47);
+        // The fee cap still applies
+        let capped = DynamicFeeCalculator::new(create_test_config()).unwrap();
+        assert_eq!(
+            capped.estimate_fee_with_history(FeePriority::Urgent, 0, &history),
+            10
+        );
+
+        let stats = calculator.get_fee_statistics(0, &history);
+        assert_eq!(stats.estimated_fees[&FeePriority::Urgent], 47);
+        assert_eq!(stats.recent_fee_rates.transactions, 10);
+        assert!(stats.to_string().contains("Recent Confirmed Fee Rates"));
+    }
+
+    #[test]
+    fn test_estimates_without_fee_history_use_the_mempool() {
+        let calculator = DynamicFeeCalculator::new(create_test_config()).unwrap();
+        let empty = FeeHistory::default();
+        let mut coinbase_only = FeeHistory::default();
+        for height in 1..=10 {
+            coinbase_only.record(BlockFeeRecord::new(height, &[], 300));
+        }
+
+        for priority in FeePriority::ALL {
+            let current = calculator.calculate_fee(priority, 30);
+            assert_eq!(
+                calculator.estimate_fee_with_history(priority, 30, &empty),
+                current
+            );
+            assert_eq!(
+                calculator.estimate_fee_with_history(priority, 30, &coinbase_only),
+                current
+            );
+        }
+        let stats = calculator.get_fee_statistics(30, &coinbase_only);
+        assert_eq!(stats.recent_fee_rates.blocks, 10);
+        assert!(stats.to_string().contains("None of them paid fees"));
     }
 }
@@ -1,12 +1,14 @@
+use crate::core::monetary::conversions::format_amount;
+use crate::core::monetary::{halved_subsidy, Amount, MIN_TRANSACTION_FEE};
 use crate::error::{BlockchainError, Result};
 use log::info;
 
 /// Legacy fixed fee calculator for backward compatibility
 #[derive(Debug, Clone)]
 pub struct FixedFeeCalculator {
-    /// Fixed fee amount in coins
+    /// Fixed fee amount in satoshis
     pub fee_amount: u64,
-    /// Base coinbase reward
+    /// Coinbase subsidy before any halving
     pub coinbase_reward: u64,
 }
 
@@ -24,26 +26,33 @@ impl FixedFeeCalculator {
         &self,
         _transaction_size: usize,
         _priority: Option<crate::core::fees::FeePriority>,
-    ) -> u64 {
-        info!("Using fixed fee: {} coins", self.fee_amount);
-        self.fee_amount
+    ) -> Amount {
+        info!("Using fixed fee: {}", format_amount(self.fee_amount));
+        Amount::from_satoshis(self.fee_amount)
     }
 
     /// Validate fee amount (always valid for fixed fees)
-    pub fn validate_fee(&self, fee: u64) -> Result<()> {
+    pub fn validate_fee(&self, fee: impl Into<Amount>) -> Result<()> {
+        let fee = fee.into();
         if fee == self.fee_amount {
             Ok(())
         } else {
             Err(BlockchainError::Transaction(format!(
                 "Invalid fee: expected {}, got {}",
-                self.fee_amount, fee
+                format_amount(self.fee_amount),
+                format_amount(fee.to_satoshis())
             )))
         }
     }
 
-    /// Calculate coinbase reward with collected fees
-    pub fn calculate_coinbase_reward(&self, collected_fees: u64) -> u64 {
-        self.coinbase_reward + collected_fees
+    /// Calculate the coinbase reward at `height`: the halved subsidy plus collected fees
+    pub fn calculate_coinbase_reward(
+        &self,
+        collected_fees: impl Into<Amount>,
+        height: usize,
+    ) -> Amount {
+        Amount::from(halved_subsidy(self.coinbase_reward, height))
+            .saturating_add(collected_fees.into())
     }
 
     /// Get the fixed fee amount
@@ -54,13 +63,13 @@ impl FixedFeeCalculator {
     /// Set a new fixed fee amount
     pub fn set_fee_amount(&mut self, amount: u64) {
         self.fee_amount = amount;
-        info!("Updated fixed fee to: {amount} coins");
+        info!("Updated fixed fee to: {}", format_amount(amount));
     }
 }
 
 impl Default for FixedFeeCalculator {
     fn default() -> Self {
-        Self::new(1) // Default 1 coin fee for educational purposes
+        Self::new(MIN_TRANSACTION_FEE) // The smallest fee the relay policy takes
     }
 }
 
@@ -88,7 +97,7 @@ mod tests {
     #[test]
     fn test_coinbase_reward() {
         let calculator = FixedFeeCalculator::new(1);
-        let reward = calculator.calculate_coinbase_reward(5);
+        let reward = calculator.calculate_coinbase_reward(5, 0);
         assert_eq!(reward, crate::core::INITIAL_BLOCK_REWARD + 5); // Base reward + 5 fees
     }
 
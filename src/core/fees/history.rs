@@ -0,0 +1,315 @@
+//! Fee rates confirmed by recent blocks
+//!
+//! On a quiet network the memory pool is nearly always empty, so its size says little about
+//! what fees actually get mined. A `FeeHistory` keeps, for each of the last
+//! `FEE_HISTORY_BLOCKS` blocks on the best chain, the fee rates of its non-coinbase
+//! transactions and how full it was. Fee estimates take a percentile of the rates confirmed
+//! in the last `ESTIMATE_WINDOW` blocks, higher for more urgent priorities.
+//!
+//! Rates are in satoshis per 1000 bytes of serialized transaction.
+
+use crate::core::fees::FeePriority;
+use crate::core::{Block, Blockchain, MAX_BLOCK_SIZE};
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// How many blocks a history keeps
+pub const FEE_HISTORY_BLOCKS: usize = 100;
+/// How many of the newest blocks estimates are based on
+pub const ESTIMATE_WINDOW: usize = 10;
+
+/// The fees one block confirmed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockFeeRecord {
+    pub height: usize,
+    /// Fee rates of the block's non-coinbase transactions, lowest first
+    pub fee_rates: Vec<f64>,
+    /// Median serialized size of those transactions, `None` if there were none
+    pub median_tx_size: Option<usize>,
+    /// Serialized size of all the block's transactions as a fraction of `MAX_BLOCK_SIZE`
+    pub fullness: f64,
+}
+
+impl BlockFeeRecord {
+    /// Record a block whose non-coinbase transactions paid `(fee, size)` and whose
+    /// transactions take `block_size` bytes in all
+    pub fn new(height: usize, transactions: &[(u64, usize)], block_size: usize) -> BlockFeeRecord {
+        let mut fee_rates: Vec<f64> = transactions
+            .iter()
+            .filter(|(_, size)| *size > 0)
+            .map(|(fee, size)| *fee as f64 * 1000.0 / *size as f64)
+            .collect();
+        fee_rates.sort_by(f64::total_cmp);
+        let mut sizes: Vec<usize> = transactions.iter().map(|(_, size)| *size).collect();
+        sizes.sort_unstable();
+
+        BlockFeeRecord {
+            height,
+            fee_rates,
+            median_tx_size: sizes.get(sizes.len() / 2).copied(),
+            fullness: block_size as f64 / MAX_BLOCK_SIZE as f64,
+        }
+    }
+
+    pub fn from_block(block: &Block) -> Result<BlockFeeRecord> {
+        let mut transactions = Vec::new();
+        let mut block_size = 0;
+        for tx in block.get_transactions() {
+            let size = tx.serialize()?.len();
+            block_size += size;
+            if !tx.is_coinbase() {
+                transactions.push((tx.get_fee().to_satoshis(), size));
+            }
+        }
+        Ok(Self::new(block.get_height(), &transactions, block_size))
+    }
+
+    pub fn min_rate(&self) -> Option<f64> {
+        self.fee_rates.first().copied()
+    }
+
+    pub fn median_rate(&self) -> Option<f64> {
+        self.fee_rates.get(self.fee_rates.len() / 2).copied()
+    }
+
+    pub fn max_rate(&self) -> Option<f64> {
+        self.fee_rates.last().copied()
+    }
+}
+
+/// The fee records of the newest blocks on the best chain
+#[derive(Debug, Clone)]
+pub struct FeeHistory {
+    records: BTreeMap<usize, BlockFeeRecord>,
+    capacity: usize,
+}
+
+/// What the last `ESTIMATE_WINDOW` blocks confirmed, as `feestatus` shows it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecentFeeRates {
+    pub blocks: usize,
+    /// Non-coinbase transactions in those blocks
+    pub transactions: usize,
+    /// `None` while there were no such transactions, as for the rates below
+    pub min_rate: Option<f64>,
+    pub median_rate: Option<f64>,
+    pub max_rate: Option<f64>,
+    /// The rate each priority's estimate aims for
+    pub target_rates: HashMap<FeePriority, f64>,
+    pub average_fullness: f64,
+}
+
+impl Default for FeeHistory {
+    fn default() -> Self {
+        Self::new(FEE_HISTORY_BLOCKS)
+    }
+}
+
+impl FeeHistory {
+    /// An empty history keeping at most `capacity` blocks
+    pub fn new(capacity: usize) -> FeeHistory {
+        FeeHistory {
+            records: BTreeMap::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// The history of the last `FEE_HISTORY_BLOCKS` blocks on `blockchain`
+    pub fn from_chain(blockchain: &Blockchain) -> Result<FeeHistory> {
+        let mut history = FeeHistory::default();
+        let best_height = blockchain.get_best_height()?;
+        let first = (best_height + 1).saturating_sub(history.capacity);
+        for height in first..=best_height {
+            if let Some(block) = blockchain.get_block_at_height(height)? {
+                history.record(BlockFeeRecord::from_block(&block)?);
+            }
+        }
+        Ok(history)
+    }
+
+    /// Add the block that just became the tip
+    ///
+    /// Records at its height or above belonged to a branch it replaced and are dropped.
+    pub fn record(&mut self, record: BlockFeeRecord) {
+        self.records.split_off(&record.height);
+        self.records.insert(record.height, record);
+        while self.records.len() > self.capacity {
+            self.records.pop_first();
+        }
+    }
+
+    pub fn record_block(&mut self, block: &Block) -> Result<()> {
+        self.record(BlockFeeRecord::from_block(block)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// The newest `window` records, oldest first
+    pub fn recent(&self, window: usize) -> impl Iterator<Item = &BlockFeeRecord> {
+        self.records
+            .values()
+            .skip(self.records.len().saturating_sub(window))
+    }
+
+    /// The `percentile`th (0 to 100) of the fee rates confirmed in the newest `window` blocks
+    pub fn percentile_rate(&self, percentile: f64, window: usize) -> Option<f64> {
+        let mut rates: Vec<f64> = self
+            .recent(window)
+            .flat_map(|record| record.fee_rates.iter().copied())
+            .collect();
+        if rates.is_empty() {
+            return None;
+        }
+        rates.sort_by(f64::total_cmp);
+        // Nearest rank, so every percentile is a rate some transaction paid
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * rates.len() as f64).ceil() as usize;
+        Some(rates[rank.saturating_sub(1)])
+    }
+
+    /// The rate an estimate for `priority` aims for, `None` without fee-paying history
+    pub fn target_rate(&self, priority: FeePriority) -> Option<f64> {
+        self.percentile_rate(Self::priority_percentile(priority), ESTIMATE_WINDOW)
+    }
+
+    /// What a typical recent transaction would pay at `priority`'s target rate
+    pub fn estimate_fee(&self, priority: FeePriority) -> Option<u64> {
+        let rate = self.target_rate(priority)?;
+        let mut sizes: Vec<usize> = self
+            .recent(ESTIMATE_WINDOW)
+            .filter_map(|record| record.median_tx_size)
+            .collect();
+        sizes.sort_unstable();
+        let size = *sizes.get(sizes.len() / 2)?;
+        Some((rate * size as f64 / 1000.0).ceil() as u64)
+    }
+
+    pub fn recent_rates(&self) -> RecentFeeRates {
+        let records: Vec<&BlockFeeRecord> = self.recent(ESTIMATE_WINDOW).collect();
+        let target_rates = FeePriority::ALL
+            .iter()
+            .filter_map(|priority| Some((*priority, self.target_rate(*priority)?)))
+            .collect();
+        let fullness: f64 = records.iter().map(|record| record.fullness).sum();
+
+        RecentFeeRates {
+            blocks: records.len(),
+            transactions: records.iter().map(|record| record.fee_rates.len()).sum(),
+            min_rate: self.percentile_rate(0.0, ESTIMATE_WINDOW),
+            median_rate: self.percentile_rate(50.0, ESTIMATE_WINDOW),
+            max_rate: self.percentile_rate(100.0, ESTIMATE_WINDOW),
+            target_rates,
+            average_fullness: if records.is_empty() {
+                0.0
+            } else {
+                fullness / records.len() as f64
+            },
+        }
+    }
+
+    fn priority_percentile(priority: FeePriority) -> f64 {
+        match priority {
+            FeePriority::Low => 10.0,
+            FeePriority::Normal => 50.0,
+            FeePriority::High => 75.0,
+            FeePriority::Urgent => 90.0,
+        }
+    }
+}
+
+impl fmt::Display for RecentFeeRates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.blocks == 0 {
+            return writeln!(f, "    No blocks recorded yet");
+        }
+        writeln!(
+            f,
+            "    {} transactions in the last {} blocks, {:.2}% full on average",
+            self.transactions,
+            self.blocks,
+            self.average_fullness * 100.0
+        )?;
+        let (Some(min), Some(median), Some(max)) = (self.min_rate, self.median_rate, self.max_rate)
+        else {
+            return writeln!(f, "    None of them paid fees");
+        };
+        writeln!(
+            f,
+            "    Min {min:.1}, median {median:.1}, max {max:.1} satoshis/kB"
+        )?;
+        for priority in FeePriority::ALL {
+            if let Some(rate) = self.target_rates.get(&priority) {
+                writeln!(f, "    {priority} targets {rate:.1} satoshis/kB")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A block at `height` with one 1000 byte transaction paying `fee`
+    fn paid(height: usize, fee: u64) -> BlockFeeRecord {
+        BlockFeeRecord::new(height, &[(fee, 1_000)], 2_000)
+    }
+
+    #[test]
+    fn test_block_record_summarizes_its_rates() {
+        let record = BlockFeeRecord::new(7, &[(30, 1_000), (5, 500), (100, 250)], 10_000);
+        assert_eq!(record.min_rate(), Some(10.0));
+        assert_eq!(record.median_rate(), Some(30.0));
+        assert_eq!(record.max_rate(), Some(400.0));
+        assert_eq!(record.median_tx_size, Some(500));
+        assert_eq!(record.fullness, 0.01);
+
+        let coinbase_only = BlockFeeRecord::new(8, &[], 300);
+        assert_eq!(coinbase_only.median_rate(), None);
+        assert_eq!(coinbase_only.median_tx_size, None);
+    }
+
+    #[test]
+    fn test_history_keeps_the_newest_blocks_of_the_best_chain() {
+        let mut history = FeeHistory::new(3);
+        for height in 1..=5 {
+            history.record(paid(height, height as u64));
+        }
+        let heights: Vec<usize> = history.recent(10).map(|record| record.height).collect();
+        assert_eq!(heights, vec![3, 4, 5]);
+
+        // A reorg back to height 4 replaces the old branch's blocks from there
+        history.record(paid(4, 40));
+        let heights: Vec<usize> = history.recent(10).map(|record| record.height).collect();
+        assert_eq!(heights, vec![3, 4]);
+        assert_eq!(history.percentile_rate(100.0, 10), Some(40_000.0 / 1_000.0));
+    }
+
+    #[test]
+    fn test_percentiles_follow_the_priority() {
+        let mut history = FeeHistory::default();
+        assert_eq!(history.target_rate(FeePriority::Urgent), None);
+        assert_eq!(history.recent_rates().blocks, 0);
+
+        // Older blocks fall outside the estimate window
+        history.record(paid(1, 1_000));
+        for height in 2..=11 {
+            history.record(paid(height, (height as u64 - 1) * 10));
+        }
+        assert_eq!(history.target_rate(FeePriority::Low), Some(10.0));
+        assert_eq!(history.target_rate(FeePriority::Normal), Some(50.0));
+        assert_eq!(history.target_rate(FeePriority::High), Some(80.0));
+        assert_eq!(history.target_rate(FeePriority::Urgent), Some(90.0));
+        assert_eq!(history.estimate_fee(FeePriority::Urgent), Some(90));
+
+        let recent = history.recent_rates();
+        assert_eq!(recent.transactions, 10);
+        assert_eq!(recent.min_rate, Some(10.0));
+        assert_eq!(recent.max_rate, Some(100.0));
+        assert!(recent.to_string().contains("median 50.0"), "{recent}");
+    }
+}
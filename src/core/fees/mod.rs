@@ -3,6 +3,8 @@
 //! This module provides both fixed and dynamic fee calculation capabilities:
 //! - Fixed fees: Legacy system with constant fee amounts
 //! - Dynamic fees: New system that adjusts fees based on network congestion and priority
+//! - Fee history: The fee rates recent blocks confirmed, which dynamic estimates blend in
+//! - Relay policy: The fee a node wants before it pools or relays a transaction
 //!
 //! The system maintains complete backward compatibility while providing enhanced
 //! fee market functionality.
@@ -10,12 +12,17 @@
 pub mod calculator;
 pub mod dynamic;
 pub mod fixed;
+pub mod history;
+pub mod policy;
 
 // Re-export main types for convenience
 pub use calculator::{FeeMode, LegacyFeeCalculator, UnifiedFeeCalculator};
 pub use dynamic::{DynamicFeeCalculator, DynamicFeeConfig, FeePriority, FeeStatistics};
 pub use fixed::FixedFeeCalculator;
+pub use history::{BlockFeeRecord, FeeHistory, RecentFeeRates};
+pub use policy::{RelayFeePolicy, DEFAULT_MIN_RELAY_FEE_RATE, MAX_FREE_TRANSACTION_SIZE};
 
+use crate::core::monetary::Amount;
 use crate::error::{BlockchainError, Result};
 use once_cell::sync::Lazy;
 use std::sync::RwLock;
@@ -29,29 +36,29 @@ pub struct FeeCalculator;
 
 impl FeeCalculator {
     /// Calculate fee using the global fee calculator
-    pub fn calculate_fee(transaction_size: usize, priority: Option<FeePriority>) -> u64 {
+    pub fn calculate_fee(transaction_size: usize, priority: Option<FeePriority>) -> Amount {
         match GLOBAL_FEE_CALCULATOR.read() {
             Ok(calculator) => calculator.calculate_fee(transaction_size, priority),
             Err(_) => {
                 log::error!("Failed to acquire fee calculator lock, using default fee");
-                1 // Fallback to default fee
+                Amount::from_satoshis(1) // Fallback to default fee
             }
         }
     }
 
     /// Estimate fee for a given priority
-    pub fn estimate_fee(priority: FeePriority) -> u64 {
+    pub fn estimate_fee(priority: FeePriority) -> Amount {
         match GLOBAL_FEE_CALCULATOR.read() {
             Ok(calculator) => calculator.estimate_fee(priority),
             Err(_) => {
                 log::error!("Failed to acquire fee calculator lock, using default fee");
-                1
+                Amount::from_satoshis(1)
             }
         }
     }
 
     /// Validate a fee amount
-    pub fn validate_fee(fee: u64, priority: Option<FeePriority>) -> Result<()> {
+    pub fn validate_fee(fee: impl Into<Amount>, priority: Option<FeePriority>) -> Result<()> {
         match GLOBAL_FEE_CALCULATOR.read() {
             Ok(calculator) => calculator.validate_fee(fee, priority),
             Err(_) => {
@@ -63,13 +70,15 @@ impl FeeCalculator {
         }
     }
 
-    /// Calculate coinbase reward
-    pub fn calculate_coinbase_reward(collected_fees: u64) -> u64 {
+    /// Calculate the coinbase reward for a block at `height`
+    pub fn calculate_coinbase_reward(collected_fees: impl Into<Amount>, height: usize) -> Amount {
         match GLOBAL_FEE_CALCULATOR.read() {
-            Ok(calculator) => calculator.calculate_coinbase_reward(collected_fees),
+            Ok(calculator) => calculator.calculate_coinbase_reward(collected_fees, height),
             Err(_) => {
                 log::error!("Failed to acquire fee calculator lock, using default reward");
-                crate::core::INITIAL_BLOCK_REWARD + collected_fees // Default calculation
+                // Default calculation
+                Amount::from(crate::core::block_subsidy(height))
+                    .saturating_add(collected_fees.into())
             }
         }
     }
@@ -122,6 +131,17 @@ impl FeeCalculator {
         }
     }
 
+    /// Copy of the global calculator's current state, for code that takes one explicitly
+    pub fn snapshot() -> UnifiedFeeCalculator {
+        match GLOBAL_FEE_CALCULATOR.read() {
+            Ok(calculator) => calculator.clone(),
+            Err(_) => {
+                log::error!("Failed to acquire fee calculator lock, using default calculator");
+                UnifiedFeeCalculator::default()
+            }
+        }
+    }
+
     /// Update dynamic fee configuration
     pub fn update_dynamic_config(config: DynamicFeeConfig) -> Result<()> {
         match GLOBAL_FEE_CALCULATOR.write() {
@@ -216,33 +236,47 @@ impl FeeCalculator {
         base_size + input_size + output_size + fee_size
     }
 
+    /// Size estimate for a transaction whose outputs also carry `data_len` bytes of data
+    ///
+    /// `output_count` includes the data outputs.
+    pub fn estimate_transaction_size_with_data(
+        input_count: usize,
+        output_count: usize,
+        data_len: usize,
+    ) -> usize {
+        Self::estimate_transaction_size(input_count, output_count) + data_len
+    }
+
     /// Legacy total fees calculation
-    pub fn calculate_total_fees<'a, I>(transactions: I) -> u64
+    pub fn calculate_total_fees<'a, I>(transactions: I) -> Amount
     where
         I: Iterator<Item = &'a crate::core::Transaction>,
     {
         transactions
             .filter(|tx| !tx.is_coinbase())
             .map(|tx| tx.get_fee())
-            .sum()
-    }
-
-    /// Legacy satoshi conversion functions
-    pub fn satoshis_to_coins(satoshis: u64) -> f64 {
-        satoshis as f64 / 100_000_000.0
-    }
-
-    pub fn coins_to_satoshis(coins: f64) -> u64 {
-        (coins * 100_000_000.0) as u64
+            .fold(Amount::ZERO, Amount::saturating_add)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::MIN_TRANSACTION_FEE;
+    use std::sync::{Mutex, MutexGuard};
+
+    // These tests reconfigure the one global calculator, so they have to take turns
+    static GLOBAL_CALCULATOR_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_global_calculator() -> MutexGuard<'static, ()> {
+        GLOBAL_CALCULATOR_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 
     #[test]
     fn test_global_fee_calculator_fixed_mode() {
+        let _guard = lock_global_calculator();
         // Initialize with fixed mode
         FeeCalculator::initialize(FeeMode::Fixed { amount: 2 }).unwrap();
 
@@ -253,6 +287,7 @@ mod tests {
 
     #[test]
     fn test_global_fee_calculator_dynamic_mode() {
+        let _guard = lock_global_calculator();
         // Initialize with dynamic mode
         let config = DynamicFeeConfig::default();
         FeeCalculator::initialize(FeeMode::Dynamic { config }).unwrap();
@@ -267,6 +302,7 @@ mod tests {
 
     #[test]
     fn test_fee_mode_switching() {
+        let _guard = lock_global_calculator();
         // Start with fixed mode
         FeeCalculator::initialize(FeeMode::Fixed { amount: 1 }).unwrap();
         assert!(!FeeCalculator::is_dynamic_enabled());
@@ -284,13 +320,15 @@ mod tests {
 
     #[test]
     fn test_coinbase_reward_calculation() {
+        let _guard = lock_global_calculator();
         FeeCalculator::initialize(FeeMode::Fixed { amount: 1 }).unwrap();
-        let reward = FeeCalculator::calculate_coinbase_reward(5);
+        let reward = FeeCalculator::calculate_coinbase_reward(5, 0);
         assert_eq!(reward, crate::core::INITIAL_BLOCK_REWARD + 5); // Base reward + 5 fees
     }
 
     #[test]
     fn test_fee_validation() {
+        let _guard = lock_global_calculator();
         FeeCalculator::initialize(FeeMode::Fixed { amount: 2 }).unwrap();
         assert!(FeeCalculator::validate_fee(2, None).is_ok());
         assert!(FeeCalculator::validate_fee(1, None).is_err());
@@ -306,13 +344,15 @@ mod tests {
 
     #[test]
     fn test_config_summary() {
+        let _guard = lock_global_calculator();
         FeeCalculator::initialize(FeeMode::Fixed { amount: 5 }).unwrap();
         let summary = FeeCalculator::get_config_summary();
-        assert!(summary.contains("Fixed fee: 5 coins"));
+        assert_eq!(summary, "Fixed fee: 0.00000005 coins (5 satoshis)");
     }
 
     #[test]
     fn test_fee_statistics_dynamic_mode() {
+        let _guard = lock_global_calculator();
         let config = DynamicFeeConfig::default();
         FeeCalculator::initialize(FeeMode::Dynamic { config }).unwrap();
 
@@ -320,12 +360,13 @@ mod tests {
         assert!(stats.is_some());
 
         let stats = stats.unwrap();
-        assert_eq!(stats.base_fee, 1);
+        assert_eq!(stats.base_fee, MIN_TRANSACTION_FEE);
         assert!(stats.estimated_fees.contains_key(&FeePriority::Normal));
     }
 
     #[test]
     fn test_fee_statistics_fixed_mode() {
+        let _guard = lock_global_calculator();
         FeeCalculator::initialize(FeeMode::Fixed { amount: 1 }).unwrap();
 
         let stats = FeeCalculator::get_fee_statistics();
@@ -0,0 +1,150 @@
+//! What a node asks of a transaction's fee before pooling or relaying it
+//!
+//! Consensus only asks that a transaction's inputs pay for its outputs plus the fee it
+//! declares, so a block may carry transactions paying any fee, none included. Which
+//! transactions a node spends memory and bandwidth on is its own business: that is this
+//! policy, set by the `[fees]` section of its config and never applied to blocks.
+//!
+//! A transaction has to pay `min_relay_fee_rate` satoshis for each byte it serializes to,
+//! and never less than `MIN_TRANSACTION_FEE`. Both fee modes price a whole transaction
+//! rather than its bytes, so an operator raising the rate should make sure what the node's
+//! own fee mode asks still clears it. A transaction paying nothing is only taken if
+//! `allow_free_transactions` is set and it is no bigger than `MAX_FREE_TRANSACTION_SIZE`.
+
+use crate::config::Config;
+use crate::core::monetary::{Amount, MIN_TRANSACTION_FEE};
+use crate::core::Transaction;
+use crate::error::{BlockchainError, Result};
+
+/// Satoshis per byte a transaction pays to be relayed unless the config says otherwise
+///
+/// None: both fee modes price whole transactions, so by default a transaction only has to
+/// pay the `MIN_TRANSACTION_FEE` that `RelayFeePolicy::min_fee` never goes below.
+pub const DEFAULT_MIN_RELAY_FEE_RATE: u64 = 0;
+
+/// Largest transaction, in bytes, relayed without a fee when free transactions are allowed
+pub const MAX_FREE_TRANSACTION_SIZE: usize = 1_000;
+
+/// The fee a node wants from a transaction before it pools or relays it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayFeePolicy {
+    /// Satoshis per serialized byte
+    pub min_fee_rate: u64,
+    pub allow_free_transactions: bool,
+}
+
+impl Default for RelayFeePolicy {
+    fn default() -> Self {
+        RelayFeePolicy {
+            min_fee_rate: DEFAULT_MIN_RELAY_FEE_RATE,
+            allow_free_transactions: false,
+        }
+    }
+}
+
+impl RelayFeePolicy {
+    /// The policy `config` sets
+    pub fn from_config(config: &Config) -> RelayFeePolicy {
+        RelayFeePolicy {
+            min_fee_rate: config
+                .get_min_relay_fee_rate()
+                .unwrap_or(DEFAULT_MIN_RELAY_FEE_RATE),
+            allow_free_transactions: config.allow_free_transactions(),
+        }
+    }
+
+    /// The smallest fee relayed for a transaction of `size` bytes that pays one at all
+    pub fn min_fee(&self, size: usize) -> Amount {
+        Amount::from_satoshis(
+            self.min_fee_rate
+                .saturating_mul(size as u64)
+                .max(MIN_TRANSACTION_FEE),
+        )
+    }
+
+    /// Refuse `tx` unless its fee meets the policy
+    pub fn check(&self, tx: &Transaction) -> Result<()> {
+        self.check_fee(tx.get_fee(), tx.serialize()?.len())
+    }
+
+    /// Refuse a fee of `fee` for a transaction of `size` bytes unless it meets the policy
+    pub fn check_fee(&self, fee: Amount, size: usize) -> Result<()> {
+        if fee == Amount::ZERO {
+            if self.allow_free_transactions && size <= MAX_FREE_TRANSACTION_SIZE {
+                return Ok(());
+            }
+            return Err(BlockchainError::FeeTooLow {
+                fee: 0,
+                min_fee: self.min_fee(size).to_satoshis(),
+            });
+        }
+        let min_fee = self.min_fee(size);
+        if fee < min_fee {
+            return Err(BlockchainError::FeeTooLow {
+                fee: fee.to_satoshis(),
+                min_fee: min_fee.to_satoshis(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn payment(fee: u64) -> Transaction {
+        let mut tx = Transaction::new_placeholder_tx(TEST_ADDRESS);
+        tx.set_fee(fee);
+        tx
+    }
+
+    #[test]
+    fn test_fee_has_to_cover_the_rate() {
+        // Fees are encoded as varints, so the size is taken with one as wide as those below
+        let size = payment(1 << 20).serialize().unwrap().len() as u64;
+        let rate = MIN_TRANSACTION_FEE;
+        let policy = RelayFeePolicy {
+            min_fee_rate: rate,
+            ..RelayFeePolicy::default()
+        };
+        assert!(policy.check(&payment(rate * size)).is_ok());
+        assert!(policy.check(&payment(rate * size + 500)).is_ok());
+        assert!(matches!(
+            policy.check(&payment(rate * size - 1)),
+            Err(BlockchainError::FeeTooLow { min_fee, .. }) if min_fee == rate * size
+        ));
+    }
+
+    #[test]
+    fn test_fee_never_goes_below_the_minimum() {
+        let policy = RelayFeePolicy::default();
+        assert!(policy.check(&payment(MIN_TRANSACTION_FEE)).is_ok());
+        assert!(matches!(
+            policy.check(&payment(MIN_TRANSACTION_FEE - 1)),
+            Err(BlockchainError::FeeTooLow { min_fee, .. }) if min_fee == MIN_TRANSACTION_FEE
+        ));
+    }
+
+    #[test]
+    fn test_free_transactions_only_when_allowed_and_small() {
+        let free = payment(0);
+        // Not even the default rate of zero lets one through
+        assert!(RelayFeePolicy::default().check(&free).is_err());
+
+        let allowing = RelayFeePolicy {
+            allow_free_transactions: true,
+            ..RelayFeePolicy::default()
+        };
+        assert!(allowing.check(&free).is_ok());
+
+        let oversized = MAX_FREE_TRANSACTION_SIZE + 1;
+        assert!(allowing.check_fee(Amount::ZERO, oversized).is_err());
+        // A fee lifts the size cap
+        assert!(allowing
+            .check_fee(Amount::from_satoshis(MIN_TRANSACTION_FEE), oversized)
+            .is_ok());
+    }
+}
@@ -0,0 +1,365 @@
+//! Genesis blocks built from a configuration
+//!
+//! Nodes that only share a `GenesisConfig` still build the very same genesis block, so
+//! they can sync with each other without one of them having created the chain first. The
+//! block's timestamp and difficulty come from the configuration, its coinbase carries the
+//! message where other coinbases carry random bytes and pays one output per allocation,
+//! and proof-of-work tries nonces from zero on a single thread.
+//!
+//! A genesis file is the configuration as TOML, along with the hash of the block it builds:
+//!
+//! ```toml
+//! timestamp = 1760000000000
+//! message = "architect-chain testnet"
+//! difficulty = 4
+//! hash = "00c5..."
+//!
+//! [[allocations]]
+//! address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"
+//! amount = 1000000000
+//! ```
+
+use crate::core::{Block, DifficultyAdjustment, TXOutput, Transaction, MAX_MONEY};
+use crate::error::{BlockchainError, Result};
+use crate::utils::current_timestamp;
+use crate::wallet::validate_address;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Longest message a genesis coinbase carries, in bytes
+pub const MAX_GENESIS_MESSAGE_LEN: usize = 256;
+
+/// Coins the genesis coinbase pays to one address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisAllocation {
+    pub address: String,
+    /// In satoshis
+    pub amount: u64,
+}
+
+/// Everything that goes into a genesis block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisConfig {
+    /// Milliseconds since the epoch, not in the future
+    pub timestamp: i64,
+    pub message: String,
+    pub difficulty: u32,
+    /// Paid out in this order by the genesis coinbase; there has to be at least one
+    pub allocations: Vec<GenesisAllocation>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GenesisFile {
+    #[serde(flatten)]
+    config: GenesisConfig,
+    /// Hash of the block the configuration builds, checked on load
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+impl GenesisConfig {
+    /// A configuration paying everything to `address`, timestamped now
+    pub fn new(message: &str, address: &str, amount: u64) -> Result<GenesisConfig> {
+        Ok(GenesisConfig {
+            timestamp: current_timestamp()?,
+            message: message.to_string(),
+            difficulty: DifficultyAdjustment::get_initial_difficulty(),
+            allocations: vec![GenesisAllocation {
+                address: address.to_string(),
+                amount,
+            }],
+        })
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(BlockchainError::Config(format!("Genesis {reason}")));
+
+        if self.timestamp <= 0 || self.timestamp > current_timestamp()? {
+            return invalid(format!(
+                "timestamp {} is not between the epoch and now",
+                self.timestamp
+            ));
+        }
+        if self.message.len() > MAX_GENESIS_MESSAGE_LEN {
+            return invalid(format!(
+                "message is {} bytes, at most {MAX_GENESIS_MESSAGE_LEN} are allowed",
+                self.message.len()
+            ));
+        }
+        if DifficultyAdjustment::validate_difficulty(self.difficulty).is_err() {
+            return invalid(format!(
+                "difficulty {} is outside {}..={}",
+                self.difficulty,
+                DifficultyAdjustment::get_min_difficulty(),
+                DifficultyAdjustment::get_max_difficulty()
+            ));
+        }
+        if self.allocations.is_empty() {
+            return invalid("has no allocations, the coinbase would pay nobody".to_string());
+        }
+
+        let mut total = 0u64;
+        for allocation in &self.allocations {
+            if validate_address(&allocation.address).is_err() {
+                return invalid(format!(
+                    "allocation to invalid address {}",
+                    allocation.address
+                ));
+            }
+            if allocation.amount == 0 {
+                return invalid(format!("allocation to {} is empty", allocation.address));
+            }
+            total = match total.checked_add(allocation.amount) {
+                Some(total) if total <= MAX_MONEY => total,
+                _ => {
+                    return invalid(format!(
+                        "allocations add up to more than the {MAX_MONEY} satoshis that can exist"
+                    ))
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Build the genesis block, the same one on every node
+    pub fn build_block(&self) -> Result<Block> {
+        self.validate()?;
+        let outputs = self
+            .allocations
+            .iter()
+            .map(|allocation| TXOutput::new(allocation.amount, &allocation.address))
+            .collect::<Result<Vec<_>>>()?;
+        let coinbase = Transaction::new_genesis_coinbase_tx(self.message.as_bytes(), outputs);
+
+        let mut block = Block::new_template_at(
+            self.timestamp,
+            String::from("None"),
+            &[coinbase],
+            0,
+            self.difficulty,
+        )?;
+        // Several threads could each find a different valid nonce, one always finds the first
+        block.run_proof_of_work();
+        Ok(block)
+    }
+
+    /// Read a genesis file, making sure it still builds the block whose hash it records
+    pub fn load(path: &Path) -> Result<GenesisConfig> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            BlockchainError::Io(format!(
+                "Failed to read genesis file {}: {e}",
+                path.display()
+            ))
+        })?;
+        let file: GenesisFile = toml::from_str(&text).map_err(|e| {
+            BlockchainError::Config(format!("Invalid genesis file {}: {e}", path.display()))
+        })?;
+
+        if let Some(expected) = &file.hash {
+            let hash = file.config.build_block()?.get_hash().to_string();
+            if hash != *expected {
+                return Err(BlockchainError::Config(format!(
+                    "Genesis file {} builds block {hash}, not the {expected} it records",
+                    path.display()
+                )));
+            }
+        } else {
+            file.config.validate()?;
+        }
+        Ok(file.config)
+    }
+
+    /// Build the genesis block and write the configuration, with its hash, to `path`
+    pub fn save(&self, path: &Path) -> Result<Block> {
+        let block = self.build_block()?;
+        let file = GenesisFile {
+            config: self.clone(),
+            hash: Some(block.get_hash().to_string()),
+        };
+        let text = toml::to_string_pretty(&file).map_err(|e| {
+            BlockchainError::Serialization(format!("Failed to encode genesis file: {e}"))
+        })?;
+        fs::write(path, text).map_err(|e| {
+            BlockchainError::Io(format!(
+                "Failed to write genesis file {}: {e}",
+                path.display()
+            ))
+        })?;
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{BlockAddResult, Blockchain, FeeMode, NodeContext};
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+    const OTHER_ADDRESS: &str = "1BoatSLRHtKNngkdXEeobR76b53LETtpyT";
+
+    fn test_config(message: &str) -> GenesisConfig {
+        GenesisConfig {
+            // A second back, so blocks mined right away come after it
+            timestamp: current_timestamp().unwrap() - 1_000,
+            allocations: vec![
+                GenesisAllocation {
+                    address: TEST_ADDRESS.to_string(),
+                    amount: 5_000_000,
+                },
+                GenesisAllocation {
+                    address: OTHER_ADDRESS.to_string(),
+                    amount: 1_000,
+                },
+            ],
+            ..GenesisConfig::new(message, TEST_ADDRESS, 1).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_nodes_sharing_a_genesis_file_sync() {
+        let temp_dir = tempdir().unwrap();
+        let path = |name: &str| temp_dir.path().join(name).to_str().unwrap().to_string();
+        let file = temp_dir.path().join("genesis.toml");
+        let saved = test_config("shared network").save(&file).unwrap();
+
+        let first = Blockchain::create_blockchain_with_genesis_path(
+            &GenesisConfig::load(&file).unwrap(),
+            &path("first"),
+        )
+        .unwrap();
+        let second = Blockchain::create_blockchain_with_genesis_path(
+            &GenesisConfig::load(&file).unwrap(),
+            &path("second"),
+        )
+        .unwrap();
+        assert_eq!(first.get_genesis_hash().unwrap(), saved.get_hash());
+        assert_eq!(second.get_genesis_hash().unwrap(), saved.get_hash());
+        let coinbase = &saved.get_transactions()[0];
+        assert!(coinbase.is_coinbase());
+        let paid: Vec<(String, u64)> = coinbase
+            .get_vout()
+            .iter()
+            .map(|output| (output.get_address(), output.get_value().to_satoshis()))
+            .collect();
+        assert_eq!(
+            paid,
+            vec![
+                (TEST_ADDRESS.to_string(), 5_000_000),
+                (OTHER_ADDRESS.to_string(), 1_000)
+            ]
+        );
+
+        // The second node takes the first one's blocks as they are
+        let context = NodeContext::new(FeeMode::Fixed { amount: 1 }).unwrap();
+        for blockchain in [&first, &second] {
+            blockchain.set_force_difficulty(Some(1));
+        }
+        first.generate_blocks(3, TEST_ADDRESS, &context).unwrap();
+        for height in 0..=3 {
+            let block = first.get_block_at_height(height).unwrap().unwrap();
+            let expected = if height == 0 {
+                BlockAddResult::AlreadyKnown
+            } else {
+                BlockAddResult::TipAdvanced
+            };
+            assert_eq!(second.validate_and_add_block(&block).unwrap(), expected);
+        }
+        assert_eq!(second.get_tip_hash(), first.get_tip_hash());
+
+        // Reopening with the same file keeps the chain
+        drop(second);
+        let reopened = Blockchain::create_blockchain_with_genesis_path(
+            &GenesisConfig::load(&file).unwrap(),
+            &path("second"),
+        )
+        .unwrap();
+        assert_eq!(reopened.get_best_height().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_other_genesis_blocks_belong_to_other_networks() {
+        let temp_dir = tempdir().unwrap();
+        let path = |name: &str| temp_dir.path().join(name).to_str().unwrap().to_string();
+        let ours = test_config("our network");
+        let theirs = GenesisConfig {
+            message: "their network".to_string(),
+            ..ours.clone()
+        };
+        assert_ne!(
+            ours.build_block().unwrap().get_hash(),
+            theirs.build_block().unwrap().get_hash()
+        );
+
+        let blockchain =
+            Blockchain::create_blockchain_with_genesis_path(&ours, &path("ours")).unwrap();
+        let other =
+            Blockchain::create_blockchain_with_genesis_path(&theirs, &path("theirs")).unwrap();
+        let context = NodeContext::new(FeeMode::Fixed { amount: 1 }).unwrap();
+        other.set_force_difficulty(Some(1));
+        other.generate_blocks(1, TEST_ADDRESS, &context).unwrap();
+
+        let their_genesis = other.get_block_at_height(0).unwrap().unwrap();
+        assert!(matches!(
+            blockchain.validate_and_add_block(&their_genesis),
+            Err(BlockchainError::DifferentNetwork { genesis, expected })
+                if genesis == their_genesis.get_hash() && expected == blockchain.get_genesis_hash().unwrap()
+        ));
+        // Their blocks build on a genesis I don't have, so they never leave the orphans
+        let their_block = other.get_block_at_height(1).unwrap().unwrap();
+        assert_eq!(
+            blockchain.validate_and_add_block(&their_block).unwrap(),
+            BlockAddResult::Orphan
+        );
+        assert_eq!(blockchain.get_best_height().unwrap(), 0);
+
+        // Nor is a database that started elsewhere opened as if it were ours
+        drop(other);
+        assert!(matches!(
+            Blockchain::create_blockchain_with_genesis_path(&ours, &path("theirs")),
+            Err(BlockchainError::DifferentNetwork { .. })
+        ));
+    }
+
+    #[test]
+    fn test_genesis_files_are_checked_on_load() {
+        let temp_dir = tempdir().unwrap();
+        let file = temp_dir.path().join("genesis.toml");
+        test_config("checked").save(&file).unwrap();
+
+        // Editing anything without updating the hash is caught
+        let text = fs::read_to_string(&file).unwrap();
+        fs::write(&file, text.replace("checked", "edited")).unwrap();
+        let err = GenesisConfig::load(&file).unwrap_err();
+        assert!(err.to_string().contains("not the"), "{err}");
+
+        let valid = test_config("valid");
+        for broken in [
+            GenesisConfig {
+                allocations: vec![],
+                ..valid.clone()
+            },
+            GenesisConfig {
+                difficulty: DifficultyAdjustment::get_max_difficulty() + 1,
+                ..valid.clone()
+            },
+            GenesisConfig {
+                timestamp: current_timestamp().unwrap() + 60_000,
+                ..valid.clone()
+            },
+            GenesisConfig {
+                allocations: vec![GenesisAllocation {
+                    address: TEST_ADDRESS.to_string(),
+                    amount: MAX_MONEY + 1,
+                }],
+                ..valid.clone()
+            },
+        ] {
+            assert!(matches!(
+                broken.build_block(),
+                Err(BlockchainError::Config(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! The current difficulty and the hashrate it implies
+//!
+//! A block at difficulty d needs a hash below `ProofOfWork::target_for_difficulty(d)`,
+//! which one hash in `ProofOfWork::expected_hashes(d)` = 2^d meets on average. Over a
+//! window of recent blocks, the network did about that many hashes for every block that
+//! ends an interval, in the time the intervals took. The first block of the window only
+//! starts the clock, so a window reaching back to genesis doesn't count genesis's work.
+//!
+//! Intervals are clamped the way a retarget clamps them, so blocks with identical
+//! timestamps can't make the estimate infinite and one stamped far off can't shrink it to
+//! nothing. A window of a single block, as on a chain holding only genesis, has no
+//! interval and no estimate.
+
+use crate::core::{Block, Blockchain, DifficultyAdjustment, ProofOfWork};
+use crate::error::{BlockchainError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Where the difficulty stands and how fast the last blocks came
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DifficultyReport {
+    /// Height of the tip
+    pub height: usize,
+    /// What the next block has to meet
+    pub current_difficulty: u32,
+    pub next_retarget_height: usize,
+    /// Blocks still to be mined, the retargeted one included
+    pub blocks_until_retarget: usize,
+    pub window_from_height: usize,
+    pub window_blocks: usize,
+    /// `None` when the window holds a single block, like the hashrate
+    pub average_block_time_ms: Option<f64>,
+    pub target_block_time_ms: u64,
+    /// Hashes per second
+    pub hashrate: Option<f64>,
+}
+
+/// Average block time and hashrate over a run of consecutive blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct WindowRate {
+    pub average_block_time_ms: f64,
+    pub hashrate: f64,
+}
+
+/// The rate `blocks` were mined at, `None` without an interval between them
+pub(crate) fn window_rate(blocks: &[Block]) -> Option<WindowRate> {
+    let span_ms = DifficultyAdjustment::calculate_time_span(blocks)?;
+    let work: f64 = blocks[1..]
+        .iter()
+        .map(|block| ProofOfWork::expected_hashes(block.get_difficulty()) as f64)
+        .sum();
+    Some(WindowRate {
+        average_block_time_ms: span_ms as f64 / (blocks.len() - 1) as f64,
+        hashrate: work * 1_000.0 / span_ms as f64,
+    })
+}
+
+/// The last `window` main-chain blocks, at least the tip and at most the whole chain
+pub(crate) fn recent_blocks(blockchain: &Blockchain, window: usize) -> Result<Vec<Block>> {
+    let tip = blockchain.get_best_height()?;
+    let from_height = tip + 1 - window.clamp(1, tip + 1);
+    (from_height..=tip)
+        .map(|height| {
+            blockchain.get_block_at_height(height)?.ok_or_else(|| {
+                BlockchainError::Database(format!("No main-chain block at height {height}"))
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn difficulty_report(
+    blockchain: &Blockchain,
+    window: usize,
+) -> Result<DifficultyReport> {
+    let blocks = recent_blocks(blockchain, window)?;
+    let height = blocks[blocks.len() - 1].get_height();
+    let next_retarget_height = DifficultyAdjustment::next_retarget_height(height + 1);
+    let rate = window_rate(&blocks);
+    Ok(DifficultyReport {
+        height,
+        current_difficulty: blockchain.get_current_difficulty()?,
+        next_retarget_height,
+        blocks_until_retarget: next_retarget_height - height,
+        window_from_height: blocks[0].get_height(),
+        window_blocks: blocks.len(),
+        average_block_time_ms: rate.map(|rate| rate.average_block_time_ms),
+        target_block_time_ms: DifficultyAdjustment::get_target_block_time(),
+        hashrate: rate.map(|rate| rate.hashrate),
+    })
+}
+
+/// `hashes_per_second` in the largest unit that keeps it at one or more
+pub fn format_hashrate(hashes_per_second: f64) -> String {
+    const UNITS: [&str; 5] = ["H/s", "kH/s", "MH/s", "GH/s", "TH/s"];
+    let mut value = hashes_per_second;
+    let mut unit = 0;
+    while value >= 1_000.0 && unit < UNITS.len() - 1 {
+        value /= 1_000.0;
+        unit += 1;
+    }
+    format!("{value:.2} {}", UNITS[unit])
+}
+
+impl fmt::Display for DifficultyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Difficulty:           {} (for the block at height {})",
+            self.current_difficulty,
+            self.height + 1
+        )?;
+        writeln!(
+            f,
+            "Next retarget:        height {} ({} blocks to go)",
+            self.next_retarget_height, self.blocks_until_retarget
+        )?;
+        let target_secs = self.target_block_time_ms as f64 / 1_000.0;
+        match self.average_block_time_ms {
+            Some(average) => writeln!(
+                f,
+                "Average block time:   {:.1} s over heights {} to {} (target {target_secs:.0} s)",
+                average / 1_000.0,
+                self.window_from_height,
+                self.height
+            )?,
+            None => writeln!(
+                f,
+                "Average block time:   n/a (a single block, target {target_secs:.0} s)"
+            )?,
+        }
+        match self.hashrate {
+            Some(hashrate) => writeln!(f, "Network hashrate:     {}", format_hashrate(hashrate)),
+            None => writeln!(f, "Network hashrate:     n/a"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, NodeContext, Transaction};
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use tempfile::tempdir;
+
+    fn block_at(height: usize, timestamp: i64, difficulty: u32) -> Block {
+        let coinbase = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        Block::new_test_block(
+            timestamp,
+            "test_prev_hash".to_string(),
+            &[coinbase],
+            height,
+            difficulty,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rate_of_known_timestamps_and_difficulties() {
+        let blocks = [
+            block_at(0, 0, 1),
+            block_at(1, 1_000, 2),
+            block_at(2, 3_000, 3),
+            block_at(3, 6_000, 4),
+        ];
+        // 4 + 8 + 16 hashes in six seconds; the first block's work falls before the window
+        let rate = window_rate(&blocks).unwrap();
+        assert_eq!(rate.average_block_time_ms, 2_000.0);
+        assert_eq!(rate.hashrate, 28.0 / 6.0);
+
+        assert_eq!(window_rate(&blocks[..1]), None);
+        assert_eq!(window_rate(&[]), None);
+    }
+
+    #[test]
+    fn test_rate_with_identical_and_far_off_timestamps() {
+        // Each interval counts for at least a millisecond
+        let identical = [
+            block_at(5, 50_000, 3),
+            block_at(6, 50_000, 3),
+            block_at(7, 50_000, 3),
+        ];
+        let rate = window_rate(&identical).unwrap();
+        assert_eq!(rate.average_block_time_ms, 1.0);
+        assert_eq!(rate.hashrate, 16.0 * 1_000.0 / 2.0);
+
+        // And for at most ten target block times
+        let max_interval = 10 * DifficultyAdjustment::get_target_block_time();
+        let stalled = [block_at(0, 0, 2), block_at(1, 1_000_000_000, 2)];
+        let rate = window_rate(&stalled).unwrap();
+        assert_eq!(rate.average_block_time_ms, max_interval as f64);
+        assert_eq!(rate.hashrate, 4.0 * 1_000.0 / max_interval as f64);
+    }
+
+    #[test]
+    fn test_hashrate_units() {
+        assert_eq!(format_hashrate(0.0), "0.00 H/s");
+        assert_eq!(format_hashrate(999.0), "999.00 H/s");
+        assert_eq!(format_hashrate(1_500.0), "1.50 kH/s");
+        assert_eq!(format_hashrate(2_000_000.0), "2.00 MH/s");
+        assert_eq!(format_hashrate(5e18), "5000000.00 TH/s");
+    }
+
+    #[test]
+    fn test_report_from_genesis_to_past_a_boundary() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let miner = Wallets::new().create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &miner,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            blockchain.get_current_difficulty().unwrap(),
+            DifficultyAdjustment::get_initial_difficulty()
+        );
+        let genesis_only = blockchain.get_difficulty_report(100).unwrap();
+        assert_eq!((genesis_only.height, genesis_only.window_blocks), (0, 1));
+        assert_eq!(
+            (
+                genesis_only.next_retarget_height,
+                genesis_only.blocks_until_retarget
+            ),
+            (10, 10)
+        );
+        assert_eq!(genesis_only.hashrate, None);
+        assert_eq!(blockchain.estimate_network_hashrate(100).unwrap(), 0.0);
+
+        blockchain.set_force_difficulty(Some(1));
+        assert_eq!(blockchain.get_current_difficulty().unwrap(), 1);
+        let context = NodeContext::new(FeeMode::Fixed { amount: 1 }).unwrap();
+        let mut countdown = Vec::new();
+        for _ in 0..11 {
+            blockchain.generate_blocks(1, &miner, &context).unwrap();
+            let report = blockchain.get_difficulty_report(5).unwrap();
+            countdown.push((report.next_retarget_height, report.blocks_until_retarget));
+        }
+        // Tips 1 to 11: the block at height 10 is the first retargeted one
+        let expected: Vec<(usize, usize)> = (1..=11)
+            .map(|tip: usize| {
+                if tip < 10 {
+                    (10, 10 - tip)
+                } else {
+                    (20, 20 - tip)
+                }
+            })
+            .collect();
+        assert_eq!(countdown, expected);
+
+        // A window longer than the chain starts at genesis
+        let whole = blockchain.get_difficulty_report(1_000).unwrap();
+        assert_eq!((whole.window_from_height, whole.window_blocks), (0, 12));
+        let blocks = recent_blocks(&blockchain, 1_000).unwrap();
+        let rate = window_rate(&blocks).unwrap();
+        assert_eq!(
+            whole.average_block_time_ms,
+            Some(rate.average_block_time_ms)
+        );
+        assert_eq!(
+            blockchain.estimate_network_hashrate(1_000).unwrap(),
+            rate.hashrate
+        );
+
+        let json = serde_json::to_value(&whole).unwrap();
+        assert_eq!(json["current_difficulty"], 1);
+        assert_eq!(json["blocks_until_retarget"], 9);
+    }
+}
@@ -10,7 +10,8 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MerkleTree {
     root: Option<MerkleNode>,
-    leaf_count: usize,
+    /// Transaction hashes in block order, needed to build proofs
+    leaves: Vec<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,7 +22,7 @@ struct MerkleNode {
 }
 
 /// Merkle proof for transaction verification
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub struct MerkleProof {
     /// Transaction hash being proven
     pub transaction_hash: Vec<u8>,
@@ -33,7 +34,7 @@ pub struct MerkleProof {
     pub transaction_index: usize,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub struct ProofElement {
     /// Sibling hash
     pub hash: Vec<u8>,
@@ -57,7 +58,7 @@ impl MerkleTree {
 
         Ok(MerkleTree {
             root: Some(root),
-            leaf_count: transactions.len(),
+            leaves: leaf_hashes,
         })
     }
 
@@ -73,7 +74,7 @@ impl MerkleTree {
 
         Ok(MerkleTree {
             root: Some(root),
-            leaf_count: hashes.len(),
+            leaves: hashes.to_vec(),
         })
     }
 
@@ -89,11 +90,11 @@ impl MerkleTree {
 
     /// Generate a Merkle proof for a transaction at the given index
     pub fn generate_proof(&self, transaction_index: usize) -> Result<MerkleProof> {
-        if transaction_index >= self.leaf_count {
+        if transaction_index >= self.leaves.len() {
             return Err(BlockchainError::InvalidBlock(format!(
                 "Transaction index {} out of bounds (max: {})",
                 transaction_index,
-                self.leaf_count - 1
+                self.leaves.len().saturating_sub(1)
             )));
         }
 
@@ -102,10 +103,16 @@ impl MerkleTree {
             .as_ref()
             .ok_or_else(|| BlockchainError::InvalidBlock("Merkle tree has no root".to_string()))?;
 
-        let mut proof_path = Vec::new();
-        let transaction_hash = self.get_leaf_hash(transaction_index)?;
-
-        Self::build_proof_path(root, transaction_index, self.leaf_count, &mut proof_path)?;
+        let transaction_hash = self.leaves[transaction_index].clone();
+        let proof_path = if self.leaves.len() == 1 {
+            // A lone transaction is paired with itself, like in calculate_merkle_root
+            vec![ProofElement {
+                hash: transaction_hash.clone(),
+                is_right: true,
+            }]
+        } else {
+            Self::build_proof_path(&self.leaves, transaction_index)
+        };
 
         Ok(MerkleProof {
             transaction_hash,
@@ -116,10 +123,19 @@ impl MerkleTree {
     }
 
     /// Verify a Merkle proof
+    ///
+    /// Which side each sibling is on follows from the transaction index, so a proof whose
+    /// sides disagree with its index, or whose index doesn't fit a tree of its depth,
+    /// proves nothing about that position and is refused.
     pub fn verify_proof(proof: &MerkleProof) -> Result<bool> {
         let mut current_hash = proof.transaction_hash.clone();
+        let mut index = proof.transaction_index;
 
         for element in &proof.proof_path {
+            if element.is_right != index.is_multiple_of(2) {
+                return Ok(false);
+            }
+            index /= 2;
             current_hash = if element.is_right {
                 // Sibling is on the right, current hash is on the left
                 Self::hash_pair(&current_hash, &element.hash)
@@ -129,7 +145,7 @@ impl MerkleTree {
             };
         }
 
-        Ok(current_hash == proof.merkle_root)
+        Ok(index == 0 && current_hash == proof.merkle_root)
     }
 
     /// Build the Merkle tree recursively
@@ -287,45 +303,40 @@ impl MerkleTree {
         sha256_digest(&first_hash)
     }
 
-    /// Get the hash of a leaf at the given index
-    fn get_leaf_hash(&self, index: usize) -> Result<Vec<u8>> {
-        // This is a simplified implementation
-        // In a full implementation, we'd traverse the tree to find the leaf
-        if index >= self.leaf_count {
-            return Err(BlockchainError::InvalidBlock(
-                "Leaf index out of bounds".to_string(),
-            ));
-        }
+    /// Collect the sibling of the target on every level, from the leaves up
+    ///
+    /// Levels are paired the same way as in `calculate_merkle_root`, with the last hash of
+    /// an odd level paired with itself.
+    fn build_proof_path(leaves: &[Vec<u8>], target_index: usize) -> Vec<ProofElement> {
+        let mut proof_path = Vec::new();
+        let mut level = leaves.to_vec();
+        let mut index = target_index;
 
-        // For now, we'll need to store leaf hashes separately or traverse the tree
-        // This is a placeholder that would need the original transaction hashes
-        Err(BlockchainError::InvalidBlock(
-            "Leaf hash retrieval not implemented in this simplified version".to_string(),
-        ))
-    }
+        while level.len() > 1 {
+            let sibling_is_right = index.is_multiple_of(2);
+            let sibling_index = if sibling_is_right {
+                (index + 1).min(level.len() - 1)
+            } else {
+                index - 1
+            };
+            proof_path.push(ProofElement {
+                hash: level[sibling_index].clone(),
+                is_right: sibling_is_right,
+            });
 
-    /// Build proof path for a transaction
-    fn build_proof_path(
-        _node: &MerkleNode,
-        _target_index: usize,
-        _total_leaves: usize,
-        _proof_path: &mut [ProofElement],
-    ) -> Result<()> {
-        // This is a complex recursive function that would traverse the tree
-        // to build the proof path. For now, we'll implement a simplified version.
-
-        // In a full implementation, this would:
-        // 1. Determine which subtree contains the target index
-        // 2. Add the sibling hash to the proof path
-        // 3. Recursively traverse the correct subtree
-
-        // Placeholder implementation
-        Ok(())
+            level = level
+                .chunks(2)
+                .map(|pair| Self::hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+            index /= 2;
+        }
+
+        proof_path
     }
 
     /// Get the number of leaves in the tree
     pub fn leaf_count(&self) -> usize {
-        self.leaf_count
+        self.leaves.len()
     }
 
     /// Check if the tree is empty
@@ -441,10 +452,11 @@ mod tests {
         let test_hash = vec![1, 2, 3, 4];
 
         // Method 1: Using calculate_merkle_root
-        let root_from_calculate = MerkleTree::calculate_merkle_root(&[test_hash.clone()]).unwrap();
+        let root_from_calculate =
+            MerkleTree::calculate_merkle_root(std::slice::from_ref(&test_hash)).unwrap();
 
         // Method 2: Using build tree via from_hashes
-        let tree = MerkleTree::from_hashes(&[test_hash.clone()]).unwrap();
+        let tree = MerkleTree::from_hashes(std::slice::from_ref(&test_hash)).unwrap();
         let root_from_tree = tree.get_root_hash().unwrap();
 
         // These MUST be equal for consistency!
@@ -458,4 +470,44 @@ mod tests {
             "Single transaction Merkle root should be double SHA-256 of the transaction hash"
         );
     }
+
+    #[test]
+    fn test_proofs_verify_for_every_index() {
+        for count in 1..=7u8 {
+            let hashes: Vec<Vec<u8>> = (0..count).map(|i| vec![i; 32]).collect();
+            let root = MerkleTree::calculate_merkle_root(&hashes).unwrap();
+            let tree = MerkleTree::from_hashes(&hashes).unwrap();
+            assert_eq!(tree.get_root_hash().unwrap(), root);
+
+            for (index, hash) in hashes.iter().enumerate() {
+                let proof = tree.generate_proof(index).unwrap();
+                assert_eq!(&proof.transaction_hash, hash);
+                assert_eq!(proof.merkle_root, root);
+                assert!(MerkleTree::verify_proof(&proof).unwrap());
+
+                // A proof for a different transaction must not verify
+                let mut forged = proof.clone();
+                forged.transaction_hash = vec![0xff; 32];
+                assert!(!MerkleTree::verify_proof(&forged).unwrap());
+            }
+        }
+        assert!(MerkleTree::from_hashes(&[vec![1; 32]])
+            .unwrap()
+            .generate_proof(1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_proof_is_refused_at_another_index() {
+        let hashes: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 32]).collect();
+        let tree = MerkleTree::from_hashes(&hashes).unwrap();
+        let proof = tree.generate_proof(1).unwrap();
+
+        // The hashes still combine to the root, but not from where the index says
+        for index in [0, 3, 5] {
+            let mut moved = proof.clone();
+            moved.transaction_index = index;
+            assert!(!MerkleTree::verify_proof(&moved).unwrap(), "index {index}");
+        }
+    }
 }
@@ -0,0 +1,258 @@
+//! Proof-of-work on background threads
+//!
+//! `Miner::start` takes an unmined block template and hands back a `MiningHandle` right
+//! away. Worker threads split the nonce space between them and stop as soon as one finds
+//! a solution or the job is cancelled, e.g. because a competing block made it stale.
+
+use crate::core::{Block, PowSolution, ProofOfWork};
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+/// Runs proof-of-work for block templates on a fixed number of threads
+#[derive(Debug, Clone)]
+pub struct Miner {
+    threads: usize,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl Miner {
+    /// Create a miner using `threads` worker threads per job (at least one)
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: threads.max(1),
+        }
+    }
+
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Start mining `template` in the background
+    pub fn start(&self, template: Block) -> MiningHandle {
+        info!(
+            "Starting proof-of-work for block at height {} with difficulty {} on {} thread(s)",
+            template.get_height(),
+            template.get_difficulty(),
+            self.threads
+        );
+
+        let job = Arc::new(MiningJob {
+            template,
+            started: Instant::now(),
+            stop: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            state: Mutex::new(JobState {
+                running_workers: self.threads,
+                solution: None,
+            }),
+            finished: Condvar::new(),
+        });
+
+        let stride = self.threads as i64;
+        for worker in 0..self.threads {
+            let job = Arc::clone(&job);
+            thread::spawn(move || job.work(worker as i64, stride));
+        }
+
+        MiningHandle { job }
+    }
+}
+
+/// Handle to a running (or finished) mining job
+///
+/// Clones refer to the same job, so one thread can wait for the result while another
+/// cancels it.
+#[derive(Clone)]
+pub struct MiningHandle {
+    job: Arc<MiningJob>,
+}
+
+impl MiningHandle {
+    /// Height of the block being mined
+    pub fn height(&self) -> usize {
+        self.job.template.get_height()
+    }
+
+    /// Hash of the block the job builds on
+    pub fn parent_hash(&self) -> String {
+        self.job.template.get_pre_block_hash()
+    }
+
+    /// The unmined block this job is searching a nonce for
+    pub fn template(&self) -> &Block {
+        &self.job.template
+    }
+
+    /// Stop the workers; `wait` then returns `None`
+    pub fn cancel(&self) {
+        self.job.cancelled.store(true, Ordering::SeqCst);
+        self.job.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.job.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Whether every worker has stopped, either with a solution or because of `cancel`
+    pub fn is_finished(&self) -> bool {
+        self.job.lock_state().running_workers == 0
+    }
+
+    /// Whether two handles refer to the same job
+    pub fn is_same_job(&self, other: &MiningHandle) -> bool {
+        Arc::ptr_eq(&self.job, &other.job)
+    }
+
+    /// Block until the workers stop, returning the mined block unless the job was cancelled
+    pub fn wait(&self) -> Option<Block> {
+        self.wait_for_solution().map(|(block, _)| block)
+    }
+
+    /// Like `wait`, together with the nonces tried and the time it took
+    ///
+    /// The workers move through the nonces side by side, so the attempts are counted as
+    /// every nonce up to the winning one.
+    pub fn wait_for_solution(&self) -> Option<(Block, PowSolution)> {
+        let mut state = self.job.lock_state();
+        while state.running_workers > 0 {
+            state = self
+                .job
+                .finished
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+
+        if self.is_cancelled() {
+            return None;
+        }
+        let solution = state.solution.clone()?;
+        let mut block = self.job.template.clone();
+        block.set_proof(solution.nonce, solution.hash.clone());
+        info!(
+            "Proof-of-work completed for block: {} (difficulty: {})",
+            block.get_hash(),
+            block.get_difficulty()
+        );
+        Some((block, solution))
+    }
+}
+
+struct MiningJob {
+    template: Block,
+    started: Instant,
+    /// Set once a worker finds a solution or the job is cancelled
+    stop: AtomicBool,
+    cancelled: AtomicBool,
+    state: Mutex<JobState>,
+    finished: Condvar,
+}
+
+struct JobState {
+    running_workers: usize,
+    solution: Option<PowSolution>,
+}
+
+impl MiningJob {
+    fn work(&self, first_nonce: i64, stride: i64) {
+        let pow = ProofOfWork::for_block(&self.template);
+        let found = pow.search(first_nonce, stride, &self.stop);
+
+        let mut state = self.lock_state();
+        if let Some((nonce, hash)) = found {
+            if state.solution.is_none() {
+                state.solution = Some(PowSolution {
+                    nonce,
+                    hash,
+                    attempts: nonce.saturating_add(1) as u64,
+                    elapsed: self.started.elapsed(),
+                });
+            }
+            self.stop.store(true, Ordering::SeqCst);
+        }
+        state.running_workers -= 1;
+        self.finished.notify_all();
+    }
+
+    // The state is only a counter and an optional solution, both still meaningful after a
+    // worker panicked
+    fn lock_state(&self) -> MutexGuard<'_, JobState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{BlockAddResult, Blockchain, NodeContext, Transaction};
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn template(difficulty: u32) -> Block {
+        let coinbase = Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap();
+        Block::new_template("None".to_string(), &[coinbase], 0, difficulty).unwrap()
+    }
+
+    #[test]
+    fn test_cancel_returns_promptly() {
+        // Difficulty 10 is solved within microseconds, so I use a target no test run will
+        // hit to be sure the workers are still searching when the cancel arrives
+        for difficulty in [10, 64] {
+            let handle = Miner::new(2).start(template(difficulty));
+            thread::sleep(Duration::from_millis(100));
+            handle.cancel();
+
+            let cancelled_at = Instant::now();
+            assert!(handle.wait().is_none());
+            assert!(cancelled_at.elapsed() < Duration::from_secs(2));
+            assert!(handle.is_finished());
+        }
+    }
+
+    #[test]
+    fn test_mined_block_validates() {
+        let handle = Miner::default().start(template(1));
+        let block = handle.wait().unwrap();
+
+        assert!(!block.get_hash().is_empty());
+        assert!(ProofOfWork::validate(&block));
+        assert!(block.validate_block(None).unwrap());
+    }
+
+    #[test]
+    fn test_parallel_workers_extend_chain() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        let context = NodeContext::default();
+
+        let template = blockchain
+            .new_block_template(&[], TEST_ADDRESS, &context)
+            .unwrap();
+        let handle = Miner::new(4).start(template);
+        assert_eq!(handle.height(), 1);
+        assert_eq!(handle.parent_hash(), blockchain.get_tip_hash());
+
+        let block = handle.wait().unwrap();
+        assert_eq!(
+            blockchain
+                .validate_and_add_block_with_context(&block, &context)
+                .unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+    }
+}
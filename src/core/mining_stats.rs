@@ -0,0 +1,226 @@
+//! Who mined each block, and what mining its own blocks cost this node
+//!
+//! Every block stored through `validate_and_add_block`, or mined and committed here, is
+//! attributed in `block_miners` to the public key hash its coinbase pays. Blocks this node
+//! mined itself also get a `MiningStats` record in `mining_stats`, with the nonces tried and
+//! how long the proof-of-work took; a block from a peer has none, as nothing tells what
+//! finding it cost. Both trees are keyed by block hash and keep blocks that later left the
+//! main chain.
+//!
+//! `Blockchain::get_mining_report` reads them back for the last few blocks up to the tip.
+//! Blocks stored before the index existed are attributed from their coinbase instead, as
+//! long as their bodies aren't pruned.
+
+use crate::core::{Block, Blockchain, PowSolution};
+use crate::error::{BlockchainError, Result};
+use crate::utils::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+use std::collections::HashMap;
+
+// Block hash -> MiningStats, for blocks mined by this node
+const MINING_STATS_TREE: &str = "mining_stats";
+// Block hash -> public key hash the coinbase pays
+const BLOCK_MINERS_TREE: &str = "block_miners";
+
+/// What it took this node to mine one block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct MiningStats {
+    /// Nonces tried, the winning one included
+    pub attempts: u64,
+    pub elapsed_ms: u64,
+    pub miner_pub_key_hash: Vec<u8>,
+}
+
+impl MiningStats {
+    /// Hashes per second the proof-of-work ran at, `None` if it took under a millisecond
+    pub fn hashrate(&self) -> Option<f64> {
+        (self.elapsed_ms > 0).then(|| self.attempts as f64 * 1_000.0 / self.elapsed_ms as f64)
+    }
+}
+
+/// One main-chain block in a `MiningReport`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinedBlock {
+    pub height: usize,
+    pub hash: String,
+    pub difficulty: u32,
+    /// `None` for a pruned block mined before the index existed
+    pub miner_pub_key_hash: Option<Vec<u8>>,
+    /// Only for blocks this node mined
+    pub stats: Option<MiningStats>,
+}
+
+/// Mining over a range of main-chain blocks ending at the tip
+#[derive(Debug, Clone, PartialEq)]
+pub struct MiningReport {
+    pub from_height: usize,
+    pub to_height: usize,
+    pub blocks: Vec<MinedBlock>,
+    /// Blocks in the range per miner, most first
+    pub blocks_by_miner: Vec<(Vec<u8>, usize)>,
+}
+
+/// The public key hash `block`'s coinbase pays first, `None` if its body is pruned
+pub fn coinbase_pub_key_hash(block: &Block) -> Option<Vec<u8>> {
+    block
+        .get_transactions()
+        .iter()
+        .find(|tx| tx.is_coinbase())
+        .and_then(|tx| tx.get_vout().first())
+        .map(|output| output.get_pub_key_hash().to_vec())
+}
+
+fn open_tree(blockchain: &Blockchain, name: &str) -> Result<Tree> {
+    blockchain
+        .get_db()
+        .open_tree(name)
+        .map_err(|e| BlockchainError::Database(format!("Failed to open {name} tree: {e}")))
+}
+
+pub(crate) fn index_miner(blockchain: &Blockchain, block: &Block) -> Result<()> {
+    let Some(miner) = coinbase_pub_key_hash(block) else {
+        return Ok(());
+    };
+    open_tree(blockchain, BLOCK_MINERS_TREE)?
+        .insert(block.get_hash(), miner)
+        .map_err(|e| BlockchainError::Database(format!("Failed to index block miner: {e}")))?;
+    Ok(())
+}
+
+pub(crate) fn record(blockchain: &Blockchain, block: &Block, solution: &PowSolution) -> Result<()> {
+    let stats = MiningStats {
+        attempts: solution.attempts,
+        elapsed_ms: u64::try_from(solution.elapsed.as_millis()).unwrap_or(u64::MAX),
+        miner_pub_key_hash: coinbase_pub_key_hash(block).unwrap_or_default(),
+    };
+    open_tree(blockchain, MINING_STATS_TREE)?
+        .insert(block.get_hash(), serialize(&stats)?)
+        .map_err(|e| BlockchainError::Database(format!("Failed to record mining stats: {e}")))?;
+    Ok(())
+}
+
+pub(crate) fn get(blockchain: &Blockchain, hash: &str) -> Result<Option<MiningStats>> {
+    open_tree(blockchain, MINING_STATS_TREE)?
+        .get(hash)
+        .map_err(|e| BlockchainError::Database(format!("Failed to read mining stats: {e}")))?
+        .map(|bytes| deserialize(&bytes))
+        .transpose()
+}
+
+pub(crate) fn blocks_mined_by(blockchain: &Blockchain, pub_key_hash: &[u8]) -> Result<Vec<String>> {
+    let mut hashes = Vec::new();
+    for entry in open_tree(blockchain, BLOCK_MINERS_TREE)?.iter() {
+        let (hash, miner) = entry
+            .map_err(|e| BlockchainError::Database(format!("Failed to read block miners: {e}")))?;
+        if miner.as_ref() == pub_key_hash {
+            hashes.push(String::from_utf8_lossy(&hash).into_owned());
+        }
+    }
+    Ok(hashes)
+}
+
+pub(crate) fn mining_report(blockchain: &Blockchain, window: usize) -> Result<MiningReport> {
+    let tip = blockchain.get_best_height()?;
+    let from_height = tip + 1 - window.clamp(1, tip + 1);
+    let miners = open_tree(blockchain, BLOCK_MINERS_TREE)?;
+
+    let mut blocks = Vec::with_capacity(tip + 1 - from_height);
+    let mut counts: HashMap<Vec<u8>, usize> = HashMap::new();
+    for height in from_height..=tip {
+        let block = blockchain.get_block_at_height(height)?.ok_or_else(|| {
+            BlockchainError::Database(format!("No main-chain block at height {height}"))
+        })?;
+        let indexed = miners
+            .get(block.get_hash())
+            .map_err(|e| BlockchainError::Database(format!("Failed to read block miner: {e}")))?
+            .map(|miner| miner.to_vec());
+        let miner = indexed.or_else(|| coinbase_pub_key_hash(&block));
+        if let Some(miner) = &miner {
+            *counts.entry(miner.clone()).or_default() += 1;
+        }
+        blocks.push(MinedBlock {
+            height,
+            hash: block.get_hash().to_string(),
+            difficulty: block.get_difficulty(),
+            miner_pub_key_hash: miner,
+            stats: get(blockchain, block.get_hash())?,
+        });
+    }
+
+    let mut blocks_by_miner: Vec<(Vec<u8>, usize)> = counts.into_iter().collect();
+    blocks_by_miner.sort_by(|(a_miner, a), (b_miner, b)| b.cmp(a).then(a_miner.cmp(b_miner)));
+    Ok(MiningReport {
+        from_height,
+        to_height: tip,
+        blocks,
+        blocks_by_miner,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, NodeContext};
+    use crate::wallet::address_pub_key_hash;
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+    const MINER_ADDRESS: &str = "1BoatSLRHtKNngkdXEeobR76b53LETtpyT";
+
+    #[test]
+    fn test_own_blocks_have_stats_and_all_blocks_a_miner() {
+        let temp_dir = tempdir().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            TEST_ADDRESS,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(2));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        let miner = address_pub_key_hash(MINER_ADDRESS).unwrap();
+
+        let mined = blockchain
+            .generate_blocks(5, MINER_ADDRESS, &context)
+            .unwrap();
+        for block in &mined {
+            let stats = blockchain
+                .get_mining_stats(block.get_hash())
+                .unwrap()
+                .unwrap();
+            assert!(stats.attempts > 0);
+            assert_eq!(stats.miner_pub_key_hash, miner);
+        }
+
+        let report = blockchain.get_mining_report(5).unwrap();
+        assert_eq!((report.from_height, report.to_height), (1, 5));
+        assert_eq!(report.blocks_by_miner, vec![(miner.clone(), 5)]);
+        assert!(report.blocks.iter().all(|block| block.stats.is_some()));
+
+        // A block from a peer is attributed from its coinbase, with nothing on its cost
+        let mut synced = blockchain
+            .new_block_template(&[], TEST_ADDRESS, &context)
+            .unwrap();
+        synced.run_proof_of_work();
+        blockchain.validate_and_add_block(&synced).unwrap();
+        assert_eq!(
+            blockchain.get_mining_stats(synced.get_hash()).unwrap(),
+            None
+        );
+        let genesis_miner = address_pub_key_hash(TEST_ADDRESS).unwrap();
+        assert_eq!(
+            blockchain.blocks_mined_by(&genesis_miner).unwrap(),
+            vec![synced.get_hash().to_string()]
+        );
+
+        let mut mined_by_miner = blockchain.blocks_mined_by(&miner).unwrap();
+        mined_by_miner.sort();
+        let mut expected: Vec<String> = mined.iter().map(|b| b.get_hash().to_string()).collect();
+        expected.sort();
+        assert_eq!(mined_by_miner, expected);
+
+        // The whole chain counts the genesis block too, from its coinbase
+        let report = blockchain.get_mining_report(100).unwrap();
+        assert_eq!(report.blocks_by_miner, vec![(miner, 5), (genesis_miner, 2)]);
+    }
+}
@@ -3,22 +3,74 @@
 //! This module contains the fundamental blockchain components including
 //! blocks, transactions, blockchain management, and proof-of-work consensus.
 
+pub mod address_index;
 pub mod block;
+pub mod block_assembler;
+pub mod block_template;
 pub mod blockchain;
+pub mod chain_stats;
+pub mod checkpoints;
+pub mod coin_selection;
+pub mod context;
 pub mod difficulty;
+pub mod events;
 pub mod fees;
+pub mod genesis;
+pub mod hashrate;
 pub mod merkle;
+pub mod miner;
+pub mod mining_stats;
 pub mod monetary;
+pub mod network;
 pub mod proof_of_work;
+mod spend_view;
+pub mod summary;
 pub mod transaction;
+pub mod verify;
+pub mod watch;
 
-pub use block::Block;
-pub use blockchain::{Blockchain, BlockchainIterator};
-pub use difficulty::DifficultyAdjustment;
-pub use fees::{DynamicFeeConfig, FeeCalculator, FeeMode, FeePriority, FeeStatistics};
+pub use address_index::{
+    AddressIndex, AddressTx, RescanReport, UsageStats, RESCAN_PROGRESS_INTERVAL,
+};
+pub use block::{Block, BlockHeader, MAX_BLOCK_SIZE, MAX_TRANSACTION_SIZE, MEDIAN_TIME_SPAN};
+pub use block_assembler::{BlockAssembler, DEFAULT_BLOCK_SIZE_TARGET, TRANSACTION_OVERHEAD};
+pub use block_template::{BlockSubmission, BlockTemplate};
+pub use blockchain::{
+    BlockAddResult, Blockchain, BlockchainHeightIterator, BlockchainIterator, TransactionStatus,
+    COINBASE_MATURITY_WINDOW, DEFAULT_FINALITY_DEPTH,
+};
+pub use chain_stats::{ChainStats, LargestBlock};
+pub use checkpoints::{Checkpoint, BUILTIN_CHECKPOINTS};
+pub use coin_selection::{SelectionStrategy, MAX_BRANCH_AND_BOUND_TRIES};
+pub use context::{NodeContext, MAX_PEER_FILTERS, TX_REQUEST_TIMEOUT};
+pub use difficulty::{DifficultyAdjustment, RetargetRules};
+pub use events::{
+    ChainEvent, EventBus, EventSink, EvictionReason, SubscriptionId, DEFAULT_EVENT_BUFFER,
+};
+pub use fees::{
+    DynamicFeeConfig, FeeCalculator, FeeHistory, FeeMode, FeePriority, FeeStatistics,
+    RelayFeePolicy, DEFAULT_MIN_RELAY_FEE_RATE, MAX_FREE_TRANSACTION_SIZE,
+};
+pub use genesis::{GenesisAllocation, GenesisConfig, MAX_GENESIS_MESSAGE_LEN};
+pub use hashrate::{format_hashrate, DifficultyReport};
 pub use merkle::{MerkleProof, MerkleTree, ProofElement};
+pub use miner::{Miner, MiningHandle};
+pub use mining_stats::{MinedBlock, MiningReport, MiningStats};
 pub use monetary::{
-    DEFAULT_TRANSACTION_FEE, INITIAL_BLOCK_REWARD, MIN_TRANSACTION_FEE, SATOSHIS_PER_COIN,
+    block_subsidy, Amount, DEFAULT_TRANSACTION_FEE, HALVING_INTERVAL, INITIAL_BLOCK_REWARD,
+    MAX_MONEY, MAX_TRANSACTION_FEE, MIN_TRANSACTION_FEE, SATOSHIS_PER_COIN,
+};
+pub use network::{Network, NetworkMagic};
+pub use proof_of_work::{work_for_difficulty, PowSolution, ProofOfWork};
+pub use summary::{BlockSummarizer, BlockSummary, DecodedTransaction, TransactionSummary};
+pub use transaction::{
+    FeePolicy, LockingCondition, TXInput, TXOutput, Transaction, TransactionBuilder,
+    TransactionPreview, UnsignedTransaction, WalletProvider, DEFAULT_MAX_TRANSACTION_INPUTS,
+    MAX_DATA_CARRIER_SIZE, MAX_MULTISIG_KEYS,
+};
+pub use verify::{
+    BadBlock, ChainVerificationReport, ChainstateDiscrepancy, MAX_VERIFICATION_LEVEL,
+};
+pub use watch::{
+    Notification, NotificationEvent, Notifier, PaymentDirection, WatchList, NOTIFICATIONS_LOG,
 };
-pub use proof_of_work::ProofOfWork;
-pub use transaction::{TXInput, TXOutput, Transaction};
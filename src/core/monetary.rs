@@ -1,3 +1,7 @@
+use crate::error::{BlockchainError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
 /// Educational blockchain monetary system
 ///
 /// This module defines the monetary constants and utilities for the educational blockchain.
@@ -6,7 +10,7 @@
 /// ## Monetary Units
 /// - **Satoshi**: The smallest unit (like Bitcoin)
 /// - **Coin**: 100,000,000 satoshis (like Bitcoin)
-/// - **Block Reward**: 50 coins initially (will halve every 210,000 blocks)
+/// - **Block Reward**: 50 coins initially, halving every `HALVING_INTERVAL` blocks
 /// - **Minimum Fee**: 1,000 satoshis (0.00001 coins)
 ///
 /// ## Educational Considerations
@@ -21,6 +25,13 @@ pub const SATOSHIS_PER_COIN: u64 = 100_000_000;
 /// This is the same as Bitcoin's original block reward
 pub const INITIAL_BLOCK_REWARD: u64 = 50 * SATOSHIS_PER_COIN;
 
+/// Number of blocks between subsidy halvings
+/// Much shorter than Bitcoin's 210,000 so the schedule is observable on a small chain
+pub const HALVING_INTERVAL: usize = 1_000;
+
+/// Halvings after which the subsidy is zero (shifting a u64 by 64 or more is undefined)
+pub const MAX_HALVINGS: usize = 64;
+
 /// Minimum transaction fee in satoshis (0.00001 coins)
 /// This is reasonable for educational purposes
 pub const MIN_TRANSACTION_FEE: u64 = 1_000;
@@ -33,10 +44,198 @@ pub const DEFAULT_TRANSACTION_FEE: u64 = 10_000;
 /// This prevents accidentally setting extremely high fees
 pub const MAX_TRANSACTION_FEE: u64 = 1_000_000;
 
+/// Most satoshis that can ever exist (21 million coins, like Bitcoin)
+/// No output, and no transaction's outputs together, may be worth more; the subsidy
+/// schedule stays far below it, so anything above is a bug or an attack
+pub const MAX_MONEY: u64 = 21_000_000 * SATOSHIS_PER_COIN;
+
 /// Dust threshold in satoshis (0.00000546 coins)
 /// Outputs smaller than this are considered "dust" and discouraged
 pub const DUST_THRESHOLD: u64 = 546;
 
+/// Largest share of the merged value a consolidation may pay as its fee, in percent,
+/// unless the config sets its own
+pub const DEFAULT_MAX_CONSOLIDATION_FEE_PERCENT: u64 = 10;
+
+/// A number of satoshis
+///
+/// It encodes exactly like the bare `u64` it wraps, so stored blocks and peer messages
+/// don't change. Sums and differences are checked and fail with the amounts involved;
+/// `from_coins` parses decimal coins without going through a float.
+///
+/// # Examples
+/// ```
+/// use architect_chain::core::monetary::Amount;
+/// assert_eq!(Amount::from_coins("0.1").unwrap(), Amount::from_satoshis(10_000_000));
+/// assert_eq!(Amount::from_coins("0.1").unwrap().to_string(), "0.10000000 coins");
+/// ```
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX_MONEY: Amount = Amount(MAX_MONEY);
+
+    pub const fn from_satoshis(satoshis: u64) -> Amount {
+        Amount(satoshis)
+    }
+
+    pub const fn to_satoshis(self) -> u64 {
+        self.0
+    }
+
+    /// Parse a decimal number of coins with at most 8 decimal places, e.g. `"0.1"`
+    pub fn from_coins(coins: &str) -> Result<Amount> {
+        let invalid = |reason: &str| {
+            BlockchainError::Transaction(format!("Invalid amount {coins:?}: {reason}"))
+        };
+        let (whole, fraction) = coins.split_once('.').unwrap_or((coins, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(invalid("no digits"));
+        }
+        if !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid("only digits and one decimal point are allowed"));
+        }
+        if fraction.len() > 8 {
+            return Err(invalid("a satoshi is the smallest unit, 8 decimal places"));
+        }
+
+        let parse = |digits: &str| -> Result<u64> {
+            if digits.is_empty() {
+                return Ok(0);
+            }
+            digits
+                .parse::<u64>()
+                .map_err(|_| invalid("more than can exist"))
+        };
+        let fraction_satoshis = parse(fraction)? * 10u64.pow(8 - fraction.len() as u32);
+        let satoshis = parse(whole)?
+            .checked_mul(SATOSHIS_PER_COIN)
+            .and_then(|satoshis| satoshis.checked_add(fraction_satoshis))
+            .filter(|satoshis| *satoshis <= MAX_MONEY)
+            .ok_or_else(|| invalid("more than can exist"))?;
+        Ok(Amount(satoshis))
+    }
+
+    pub fn checked_add(self, other: Amount) -> Result<Amount> {
+        self.0.checked_add(other.0).map(Amount).ok_or_else(|| {
+            BlockchainError::Transaction(format!(
+                "Amount overflow: {} + {} satoshis",
+                self.0, other.0
+            ))
+        })
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Result<Amount> {
+        self.0.checked_sub(other.0).map(Amount).ok_or_else(|| {
+            BlockchainError::Transaction(format!(
+                "Amount underflow: {} - {} satoshis",
+                self.0, other.0
+            ))
+        })
+    }
+
+    /// Add up `amounts`, failing on the first sum that doesn't fit
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Result<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |sum, amount| sum.checked_add(amount))
+    }
+
+    pub fn saturating_add(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Amount) -> Amount {
+        Amount(self.0.saturating_sub(other.0))
+    }
+
+    /// Whether this is an amount that can exist at all
+    pub fn is_money_range(self) -> bool {
+        self <= Amount::MAX_MONEY
+    }
+}
+
+impl From<u64> for Amount {
+    fn from(satoshis: u64) -> Amount {
+        Amount(satoshis)
+    }
+}
+
+impl From<Amount> for u64 {
+    fn from(amount: Amount) -> u64 {
+        amount.0
+    }
+}
+
+impl PartialEq<u64> for Amount {
+    fn eq(&self, satoshis: &u64) -> bool {
+        self.0 == *satoshis
+    }
+}
+
+impl PartialOrd<u64> for Amount {
+    fn partial_cmp(&self, satoshis: &u64) -> Option<std::cmp::Ordering> {
+        self.0.partial_cmp(satoshis)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&conversions::format_satoshis(self.0))
+    }
+}
+
+/// Newly created coins a block at `height` may pay out, not counting fees
+pub fn block_subsidy(height: usize) -> u64 {
+    halved_subsidy(INITIAL_BLOCK_REWARD, height)
+}
+
+/// Apply the halving schedule to a starting subsidy of `initial_subsidy`
+pub fn halved_subsidy(initial_subsidy: u64, height: usize) -> u64 {
+    let halvings = height / HALVING_INTERVAL;
+    if halvings >= MAX_HALVINGS {
+        return 0;
+    }
+    initial_subsidy >> halvings
+}
+
+/// Total subsidy paid by every block from genesis up to and including `height`
+pub fn total_subsidy_through(height: usize) -> u64 {
+    // Every block in a halving era pays the same, so I add up whole eras at a time
+    let mut total: u64 = 0;
+    let mut era_start = 0;
+    while era_start <= height {
+        let era_end = (era_start + HALVING_INTERVAL - 1).min(height);
+        let subsidy = block_subsidy(era_start);
+        if subsidy == 0 {
+            break;
+        }
+        total += subsidy * (era_end - era_start + 1) as u64;
+        era_start += HALVING_INTERVAL;
+    }
+    total
+}
+
 /// Educational constants for easy understanding
 pub mod educational {
     use super::*;
@@ -91,11 +290,30 @@ pub mod conversions {
         format!("{:.8} coins", satoshis_to_coins(satoshis))
     }
 
+    /// Format satoshis in coins with the exact satoshi count after it, as every fee shown
+    /// to a user is
+    ///
+    /// # Examples
+    /// ```
+    /// use architect_chain::core::monetary::conversions::format_amount;
+    /// assert_eq!(format_amount(10_000), "0.00010000 coins (10000 satoshis)");
+    /// assert_eq!(format_amount(1), "0.00000001 coins (1 satoshi)");
+    /// ```
+    pub fn format_amount(satoshis: u64) -> String {
+        let unit = if satoshis == 1 { "satoshi" } else { "satoshis" };
+        format!("{} ({satoshis} {unit})", format_satoshis(satoshis))
+    }
+
     /// Validate that an amount is above the dust threshold
     pub fn is_above_dust_threshold(amount: u64) -> bool {
         amount >= DUST_THRESHOLD
     }
 
+    /// Validate that an amount is one that can exist at all
+    pub fn is_money_range(amount: u64) -> bool {
+        amount <= MAX_MONEY
+    }
+
     /// Validate that a fee is within reasonable bounds
     pub fn is_valid_fee(fee: u64) -> bool {
         (MIN_TRANSACTION_FEE..=MAX_TRANSACTION_FEE).contains(&fee)
@@ -116,6 +334,34 @@ mod tests {
         const _: () = assert!(DEFAULT_TRANSACTION_FEE < MAX_TRANSACTION_FEE);
     }
 
+    #[test]
+    fn test_block_subsidy_halving() {
+        assert_eq!(block_subsidy(0), INITIAL_BLOCK_REWARD);
+        assert_eq!(block_subsidy(HALVING_INTERVAL - 1), INITIAL_BLOCK_REWARD);
+        assert_eq!(block_subsidy(HALVING_INTERVAL), INITIAL_BLOCK_REWARD / 2);
+        assert_eq!(
+            block_subsidy(2 * HALVING_INTERVAL),
+            INITIAL_BLOCK_REWARD / 4
+        );
+        assert_eq!(block_subsidy(MAX_HALVINGS * HALVING_INTERVAL), 0);
+        assert_eq!(block_subsidy(usize::MAX), 0);
+    }
+
+    #[test]
+    fn test_total_subsidy() {
+        assert_eq!(total_subsidy_through(0), INITIAL_BLOCK_REWARD);
+        assert_eq!(
+            total_subsidy_through(HALVING_INTERVAL),
+            HALVING_INTERVAL as u64 * INITIAL_BLOCK_REWARD + INITIAL_BLOCK_REWARD / 2
+        );
+
+        // The sum matches adding up block by block, and the supply is bounded
+        let summed: u64 = (0..=2_500).map(block_subsidy).sum();
+        assert_eq!(total_subsidy_through(2_500), summed);
+        let cap = 2 * HALVING_INTERVAL as u64 * INITIAL_BLOCK_REWARD;
+        assert!(total_subsidy_through(MAX_HALVINGS * HALVING_INTERVAL * 2) < cap);
+    }
+
     #[test]
     fn test_conversions() {
         // Test coins to satoshis
@@ -150,6 +396,10 @@ mod tests {
         assert!(is_valid_fee(DEFAULT_TRANSACTION_FEE));
         assert!(is_valid_fee(MAX_TRANSACTION_FEE));
         assert!(!is_valid_fee(MAX_TRANSACTION_FEE + 1));
+
+        // Test the supply cap
+        assert!(is_money_range(MAX_MONEY));
+        assert!(!is_money_range(MAX_MONEY + 1));
     }
 
     #[test]
@@ -157,6 +407,93 @@ mod tests {
         assert_eq!(format_satoshis(SATOSHIS_PER_COIN), "1.00000000 coins");
         assert_eq!(format_satoshis(SATOSHIS_PER_COIN / 2), "0.50000000 coins");
         assert_eq!(format_satoshis(1_000), "0.00001000 coins");
+        assert_eq!(
+            format_amount(DEFAULT_TRANSACTION_FEE),
+            "0.00010000 coins (10000 satoshis)"
+        );
+        assert_eq!(
+            format_amount(SATOSHIS_PER_COIN + 5),
+            "1.00000005 coins (100000005 satoshis)"
+        );
+    }
+
+    #[test]
+    fn test_amount_from_coins() {
+        assert_eq!(Amount::from_coins("0.1").unwrap(), 10_000_000);
+        assert_eq!(Amount::from_coins("1").unwrap(), SATOSHIS_PER_COIN);
+        assert_eq!(Amount::from_coins(".5").unwrap(), SATOSHIS_PER_COIN / 2);
+        assert_eq!(Amount::from_coins("0.00000001").unwrap(), 1);
+        assert_eq!(Amount::from_coins("21000000").unwrap(), Amount::MAX_MONEY);
+
+        for refused in [
+            "",
+            ".",
+            "-1",
+            "1e3",
+            "0.1.2",
+            " 1",
+            "0.000000001",
+            "21000000.1",
+        ] {
+            assert!(Amount::from_coins(refused).is_err(), "{refused:?}");
+        }
+        let too_precise = Amount::from_coins("0.123456789").unwrap_err().to_string();
+        assert!(too_precise.contains("8 decimal places"), "{too_precise}");
+    }
+
+    #[test]
+    fn test_amount_arithmetic_is_checked() {
+        let one = Amount::from_satoshis(1);
+        let max = Amount::from_satoshis(u64::MAX);
+        assert_eq!(one.checked_add(one).unwrap(), 2);
+        assert_eq!(one.checked_sub(one).unwrap(), Amount::ZERO);
+
+        let overflow = max.checked_add(one).unwrap_err().to_string();
+        assert!(
+            overflow.contains(&format!("{} + 1", u64::MAX)),
+            "{overflow}"
+        );
+        let underflow = Amount::ZERO.checked_sub(one).unwrap_err().to_string();
+        assert!(underflow.contains("0 - 1"), "{underflow}");
+        assert!(Amount::checked_sum([max, one]).is_err());
+        assert_eq!(Amount::checked_sum([one, one, one]).unwrap(), 3);
+        assert_eq!(max.saturating_add(one), max);
+        assert_eq!(Amount::ZERO.saturating_sub(one), Amount::ZERO);
+
+        assert_eq!(u64::from(Amount::from(7)), 7);
+        assert_eq!(Amount::from_satoshis(1_000).to_string(), "0.00001000 coins");
+    }
+
+    #[test]
+    fn test_amounts_keep_the_block_encoding() {
+        use crate::core::{Block, TXOutput, Transaction};
+        use data_encoding::HEXLOWER;
+
+        // Stored before values were wrapped in `Amount`
+        let fixture = include_str!("../../tests/fixtures/block_v1.hex").trim();
+        let mut coinbase = Transaction::new_genesis_coinbase_tx(
+            b"fixture",
+            vec![
+                TXOutput::new(5_000_000_000, "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap(),
+                TXOutput::new(546, "1BoatSLRHtKNngkdXEeobR76b53LETtpyT").unwrap(),
+            ],
+        );
+        coinbase.set_fee(1_234);
+        let mut block =
+            Block::new_template_at(1_760_000_000_000, "None".to_string(), &[coinbase], 0, 4)
+                .unwrap();
+        block.set_proof(42, "00ab".to_string());
+
+        let bytes = block.serialize().unwrap();
+        assert_eq!(HEXLOWER.encode(&bytes), fixture);
+        let decoded = Block::deserialize(&HEXLOWER.decode(fixture.as_bytes()).unwrap()).unwrap();
+        let values: Vec<u64> = decoded.get_transactions()[0]
+            .get_vout()
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .collect();
+        assert_eq!(values, vec![5_000_000_000, 546]);
+        assert_eq!(decoded.get_transactions()[0].get_fee(), 1_234);
     }
 
     #[test]
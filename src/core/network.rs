@@ -0,0 +1,134 @@
+//! Which network a node and its data directory belong to
+//!
+//! Nodes of different networks mustn't exchange anything, yet nothing in a block or a
+//! package says where it came from. A `NetworkMagic` does: four bytes hashed from the
+//! network's name and its genesis block, so even two classroom networks that both call
+//! themselves testnet get different ones as long as they started from different genesis
+//! blocks. Nodes send it in their version message and drop peers whose magic isn't
+//! theirs, and a data directory records the network it was created for.
+
+use crate::error::{BlockchainError, Result};
+use crate::utils::sha256_digest;
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The network a node runs on, chosen with `--network`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    /// Local development and tests
+    Regtest,
+}
+
+impl Network {
+    pub const ALL: [Network; 3] = [Network::Mainnet, Network::Testnet, Network::Regtest];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    /// Height from which difficulty retargets clamp every block interval, see
+    /// `RetargetRules`
+    pub fn clamped_retarget_height(&self) -> usize {
+        match self {
+            Network::Mainnet => 40_000,
+            Network::Testnet => 4_000,
+            Network::Regtest => 0,
+        }
+    }
+
+    /// Height from which every transaction id leaves out the input keys; blocks below it
+    /// may still carry the ids they had before
+    pub fn trimmed_txid_height(&self) -> usize {
+        match self {
+            Network::Mainnet => 40_000,
+            Network::Testnet => 4_000,
+            Network::Regtest => 0,
+        }
+    }
+
+    /// The magic of this network when it starts from the block `genesis_hash`
+    pub fn magic(&self, genesis_hash: &str) -> NetworkMagic {
+        let digest = sha256_digest(format!("{}:{genesis_hash}", self.name()).as_bytes());
+        NetworkMagic([digest[0], digest[1], digest[2], digest[3]])
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Network> {
+        Network::ALL
+            .into_iter()
+            .find(|network| network.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                BlockchainError::Config(format!(
+                    "Unknown network {s}, expected mainnet, testnet or regtest"
+                ))
+            })
+    }
+}
+
+/// Four bytes naming one network, see the module docs
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+pub struct NetworkMagic(pub [u8; 4]);
+
+impl fmt::Display for NetworkMagic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&HEXLOWER.encode(&self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magic_differs_by_network_and_genesis() {
+        let genesis = "00ab";
+        let magics: Vec<NetworkMagic> = Network::ALL
+            .iter()
+            .map(|network| network.magic(genesis))
+            .collect();
+        assert_ne!(magics[0], magics[1]);
+        assert_ne!(magics[1], magics[2]);
+        assert_ne!(magics[0], magics[2]);
+        // A classroom testnet with its own genesis block is a network of its own
+        assert_ne!(Network::Testnet.magic("00cd"), magics[1]);
+        assert_eq!(Network::Testnet.magic(genesis), magics[1]);
+    }
+
+    #[test]
+    fn test_networks_parse_from_their_names() {
+        for network in Network::ALL {
+            assert_eq!(network.name().parse::<Network>().unwrap(), network);
+        }
+        assert_eq!("Regtest".parse::<Network>().unwrap(), Network::Regtest);
+        assert!("signet".parse::<Network>().is_err());
+    }
+}
@@ -1,35 +1,91 @@
 use crate::core::Block;
+use crate::network::metrics::METRICS;
 use crate::utils::sha256_digest;
 use data_encoding::HEXLOWER;
+use log::info;
 use num_bigint::{BigInt, Sign};
 use std::borrow::Borrow;
 use std::ops::ShlAssign;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct ProofOfWork {
-    block: Block,
+    // Everything the hash covers except the nonce, which comes last, so an attempt only
+    // rewrites the final eight bytes
+    header: Vec<u8>,
     target: BigInt,
     difficulty: u32,
 }
 
 // Removed hardcoded TARGET_BITS - now using dynamic difficulty
 
+/// Expected number of hashes to find a block at `difficulty`
+///
+/// A block needs a hash below 2^(256 - difficulty), which one hash in 2^difficulty meets on
+/// average. Summed along a chain this is the chain's work, which decides between forks.
+pub fn work_for_difficulty(difficulty: u32) -> u128 {
+    1u128.checked_shl(difficulty).unwrap_or(u128::MAX)
+}
+
+/// A nonce that meets the target, and what it took to find
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PowSolution {
+    pub nonce: i64,
+    pub hash: String,
+    /// Nonces tried, the winning one included
+    pub attempts: u64,
+    pub elapsed: Duration,
+}
+
 const MAX_NONCE: i64 = i64::MAX;
+/// How many nonces a search tries between looks at its stop flag
+const STOP_CHECK_INTERVAL: u64 = 1024;
 
 impl ProofOfWork {
     pub fn new_proof_of_work(block: Block) -> ProofOfWork {
+        Self::for_block(&block)
+    }
+
+    pub(crate) fn for_block(block: &Block) -> ProofOfWork {
         let difficulty = block.get_difficulty();
-        let mut target = BigInt::from(1);
-        target.shl_assign(256 - difficulty);
+        let target = Self::target_for_difficulty(difficulty);
+
+        let mut header = vec![];
+        header.extend(block.get_pre_block_hash().as_bytes());
+        header.extend(block.get_merkle_root()); // Proper Merkle root
+        header.extend(block.get_timestamp().to_be_bytes());
+        header.extend(block.get_height().to_be_bytes()); // Include height for completeness
+        header.extend(difficulty.to_be_bytes());
         ProofOfWork {
-            block,
+            header,
             target,
             difficulty,
         }
     }
 
+    pub fn get_difficulty(&self) -> u32 {
+        self.difficulty
+    }
+
+    /// The number a block's hash has to stay below at `difficulty`, 2^(256 - difficulty)
+    pub fn target_for_difficulty(difficulty: u32) -> BigInt {
+        let mut target = BigInt::from(1);
+        target.shl_assign(256 - difficulty);
+        target
+    }
+
+    /// Hashes it takes on average to find a block at `difficulty`
+    ///
+    /// A hash is uniform over 2^256 values, so it meets the target once in 2^256 / target
+    /// tries. That is `work_for_difficulty`, which estimates and chain work both go through.
+    pub fn expected_hashes(difficulty: u32) -> u128 {
+        work_for_difficulty(difficulty)
+    }
+
     /// Validate proof-of-work for a block
     pub fn validate(block: &Block) -> bool {
-        let pow = ProofOfWork::new_proof_of_work(block.clone());
+        let pow = ProofOfWork::for_block(block);
         let data = pow.prepare_data(block.get_nonce());
         let hash = sha256_digest(data.as_slice());
         let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
@@ -38,45 +94,151 @@ impl ProofOfWork {
         hash_int < pow.target
     }
 
+    /// The hash a block's header and nonce produce, which its stored hash has to equal
+    pub fn compute_hash(block: &Block) -> String {
+        let pow = ProofOfWork::for_block(block);
+        let data = pow.prepare_data(block.get_nonce());
+        HEXLOWER.encode(sha256_digest(data.as_slice()).as_slice())
+    }
+
     fn prepare_data(&self, nonce: i64) -> Vec<u8> {
-        let pre_block_hash = self.block.get_pre_block_hash();
-        let merkle_root = self.block.get_merkle_root(); // Use correct Merkle root!
-        let timestamp = self.block.get_timestamp();
-        let height = self.block.get_height();
-        let mut data_bytes = vec![];
-        data_bytes.extend(pre_block_hash.as_bytes());
-        data_bytes.extend(merkle_root); // Proper Merkle root
-        data_bytes.extend(timestamp.to_be_bytes());
-        data_bytes.extend(height.to_be_bytes()); // Include height for completeness
-        data_bytes.extend(self.difficulty.to_be_bytes());
-        data_bytes.extend(nonce.to_be_bytes());
-        data_bytes
-    }
-
-    pub fn run(&self) -> (i64, String) {
+        let mut data = Vec::with_capacity(self.header.len() + 8);
+        self.hash_with_nonce(&mut data, nonce);
+        data
+    }
+
+    // Hash the header with `nonce`, reusing `data` from the previous attempt
+    fn hash_with_nonce(&self, data: &mut Vec<u8>, nonce: i64) -> Vec<u8> {
+        if data.len() != self.header.len() + 8 {
+            data.clear();
+            data.extend_from_slice(&self.header);
+            data.extend(nonce.to_be_bytes());
+        } else {
+            data[self.header.len()..].copy_from_slice(&nonce.to_be_bytes());
+        }
+        sha256_digest(data.as_slice())
+    }
+
+    pub fn run(&self) -> PowSolution {
+        let started = Instant::now();
         let mut nonce = 0;
         let mut hash = Vec::new();
-        println!("Mining the block");
+        let mut data = Vec::new();
+        // Progress goes to the log, so stdout is left to the command's own output
+        info!("Mining the block");
         while nonce < MAX_NONCE {
-            let data = self.prepare_data(nonce);
-            hash = sha256_digest(data.as_slice());
+            hash = self.hash_with_nonce(&mut data, nonce);
             let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
 
             if hash_int.lt(self.target.borrow()) {
-                println!("{}", HEXLOWER.encode(hash.as_slice()));
+                info!("Mined {}", HEXLOWER.encode(hash.as_slice()));
                 break;
             }
             nonce += 1;
         }
-        println!();
-        (nonce, HEXLOWER.encode(hash.as_slice()))
+        // I count the hashes once per block so the loop itself stays untouched
+        METRICS.pow_hashes_total.add(nonce.saturating_add(1) as u64);
+        PowSolution {
+            nonce,
+            hash: HEXLOWER.encode(hash.as_slice()),
+            attempts: nonce.saturating_add(1) as u64,
+            elapsed: started.elapsed(),
+        }
+    }
+
+    /// Like `run`, but gives up and returns `None` once `cancel` is set
+    pub fn run_cancellable(&self, cancel: &AtomicBool) -> Option<(i64, String)> {
+        self.search(0, 1, cancel)
+    }
+
+    /// Like `run`, but searching on `threads` threads at once
+    ///
+    /// Each worker takes every `threads`-th nonce from its own starting point, so no two
+    /// try the same one, and all of them stop as soon as one finds a solution. When more
+    /// than one does in the same moment, the lowest nonce wins. Returns `None` only if the
+    /// whole nonce space is exhausted.
+    ///
+    /// The workers move through the nonces side by side, so the attempts are counted as
+    /// every nonce up to the winning one, which they had all but covered between them.
+    pub fn run_parallel(&self, threads: usize) -> Option<PowSolution> {
+        let started = Instant::now();
+        let threads = threads.max(1);
+        let found = AtomicBool::new(false);
+        let stride = threads as i64;
+
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..stride)
+                .map(|first_nonce| {
+                    let found = &found;
+                    scope.spawn(move || {
+                        let solution = self.search(first_nonce, stride, found);
+                        if solution.is_some() {
+                            found.store(true, Ordering::Relaxed);
+                        }
+                        solution
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .filter_map(|worker| worker.join().ok().flatten())
+                .min_by_key(|(nonce, _)| *nonce)
+                .map(|(nonce, hash)| PowSolution {
+                    nonce,
+                    hash,
+                    attempts: nonce.saturating_add(1) as u64,
+                    elapsed: started.elapsed(),
+                })
+        })
+    }
+
+    /// Try the nonces `first_nonce`, `first_nonce + stride`, ... until one meets the target
+    ///
+    /// Workers sharing a block each start at a different `first_nonce` with the same
+    /// `stride`, so their searches never overlap. Returns `None` once `stop` is set or the
+    /// nonce space is exhausted.
+    pub fn search(
+        &self,
+        first_nonce: i64,
+        stride: i64,
+        stop: &AtomicBool,
+    ) -> Option<(i64, String)> {
+        debug_assert!(stride > 0, "nonce stride must be positive");
+        let mut nonce = first_nonce;
+        let mut attempts: u64 = 0;
+        let mut data = Vec::new();
+        // Hashes are reported in batches at the stop checks, keeping the shared counter
+        // out of the hot loop
+        loop {
+            if attempts.is_multiple_of(STOP_CHECK_INTERVAL) {
+                if attempts > 0 {
+                    METRICS.pow_hashes_total.add(STOP_CHECK_INTERVAL);
+                }
+                if stop.load(Ordering::Relaxed) {
+                    return None;
+                }
+            }
+
+            let hash = self.hash_with_nonce(&mut data, nonce);
+            attempts += 1;
+            let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
+            if hash_int < self.target {
+                METRICS
+                    .pow_hashes_total
+                    .add((attempts - 1) % STOP_CHECK_INTERVAL + 1);
+                return Some((nonce, HEXLOWER.encode(hash.as_slice())));
+            }
+
+            nonce = nonce.checked_add(stride)?;
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::Transaction;
+    use crate::core::{DifficultyAdjustment, Transaction};
 
     fn create_test_block(difficulty: u32) -> Block {
         let test_address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
@@ -134,6 +296,62 @@ mod tests {
         assert!(hard_pow.target < easy_pow.target);
     }
 
+    #[test]
+    fn test_expected_hashes_match_the_target() {
+        let mut space = BigInt::from(1);
+        space.shl_assign(256u32);
+        for difficulty in 1..=DifficultyAdjustment::get_max_difficulty() {
+            let target = ProofOfWork::target_for_difficulty(difficulty);
+            assert_eq!(target * ProofOfWork::expected_hashes(difficulty), space);
+        }
+        let block = create_test_block(3);
+        assert_eq!(
+            ProofOfWork::new_proof_of_work(block).target,
+            ProofOfWork::target_for_difficulty(3)
+        );
+    }
+
+    #[test]
+    fn test_cancellable_run_finds_valid_nonce() {
+        let block = create_test_block(1);
+        let pow = ProofOfWork::new_proof_of_work(block.clone());
+
+        let (nonce, hash) = pow.run_cancellable(&AtomicBool::new(false)).unwrap();
+        let mut mined = block;
+        mined.set_proof(nonce, hash);
+        assert!(ProofOfWork::validate(&mined));
+
+        // A flag that is already set stops the search before the first attempt
+        assert!(pow.run_cancellable(&AtomicBool::new(true)).is_none());
+    }
+
+    #[test]
+    fn test_parallel_run_finds_valid_nonce() {
+        let block = Block::new_template(
+            "None".to_string(),
+            &[Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap()],
+            0,
+            6,
+        )
+        .unwrap();
+        let pow = ProofOfWork::for_block(&block);
+
+        for threads in [1, 4] {
+            let solution = pow.run_parallel(threads).unwrap();
+            let mut mined = block.clone();
+            mined.set_proof(solution.nonce, solution.hash.clone());
+            assert!(ProofOfWork::validate(&mined));
+            assert_eq!(ProofOfWork::compute_hash(&mined), solution.hash);
+            assert_eq!(solution.attempts, solution.nonce as u64 + 1);
+        }
+
+        // A single worker walks the nonces in order, so it lands on the first solution
+        let parallel = pow.run_parallel(1).unwrap();
+        let single = pow.run();
+        assert_eq!(parallel.nonce, single.nonce);
+        assert_eq!(parallel.attempts, single.attempts);
+    }
+
     #[test]
     fn test_prepare_data_consistency() {
         let block = create_test_block(2);
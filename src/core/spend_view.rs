@@ -0,0 +1,222 @@
+//! The unspent outputs as of any stored block
+//!
+//! The chainstate only describes the block it was last brought up to. A block is checked
+//! against its parent, which for a fork, or for the next block of a batch the chainstate
+//! hasn't followed yet, is another block. A `SpendView` keeps what changed between the two
+//! on top of the chainstate: it undoes the blocks from the chainstate's block down to the
+//! last one both chains share, then applies the blocks up to the one it's wanted at.
+
+use crate::core::{Block, Blockchain, TXOutput};
+use crate::error::{BlockchainError, Result};
+use crate::storage::UTXOSet;
+use data_encoding::HEXLOWER;
+use std::collections::{HashMap, HashSet};
+
+// What every genesis block names as its parent, so a view here has no outputs at all
+const BEFORE_GENESIS: &str = "None";
+
+type Outpoint = (Vec<u8>, usize);
+
+pub(crate) struct SpendView {
+    // The block the chainstate was at when the view was built on it
+    base: String,
+    // The block whose outputs this describes
+    at: String,
+    // Outputs unspent here but not in the chainstate, and the other way round
+    created: HashMap<Outpoint, TXOutput>,
+    spent: HashSet<Outpoint>,
+}
+
+impl SpendView {
+    /// The view at `block_hash`
+    pub(crate) fn at(blockchain: &Blockchain, block_hash: &str) -> Result<SpendView> {
+        let mut view = Self::of_chainstate(Self::chainstate_block(blockchain)?);
+        view.move_to(blockchain, block_hash)?;
+        Ok(view)
+    }
+
+    fn of_chainstate(base: String) -> SpendView {
+        SpendView {
+            at: base.clone(),
+            base,
+            created: HashMap::new(),
+            spent: HashSet::new(),
+        }
+    }
+
+    // The block the chainstate is at. One that was never built is before genesis; one
+    // built before it recorded its block is taken to be at the tip.
+    fn chainstate_block(blockchain: &Blockchain) -> Result<String> {
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        Ok(match utxo_set.last_applied()? {
+            Some(applied) => applied.hash,
+            None if utxo_set.is_empty()? => BEFORE_GENESIS.to_string(),
+            None => blockchain.get_tip_hash(),
+        })
+    }
+
+    /// Output `vout` of `txid`, if it's unspent as of the view's block
+    pub(crate) fn find(
+        &self,
+        blockchain: &Blockchain,
+        txid: &[u8],
+        vout: usize,
+    ) -> Result<Option<TXOutput>> {
+        let outpoint = (txid.to_vec(), vout);
+        if self.spent.contains(&outpoint) {
+            return Ok(None);
+        }
+        if let Some(output) = self.created.get(&outpoint) {
+            return Ok(Some(output.clone()));
+        }
+        blockchain.find_unspent_output(txid, vout)
+    }
+
+    /// Move the view to `block_hash`, undoing and applying the blocks in between
+    ///
+    /// A chainstate that moved since the view was built on it, e.g. because a block
+    /// arrived meanwhile, leaves nothing to build on, so the view starts over from it.
+    pub(crate) fn move_to(&mut self, blockchain: &Blockchain, block_hash: &str) -> Result<()> {
+        let base = Self::chainstate_block(blockchain)?;
+        if base != self.base {
+            *self = Self::of_chainstate(base);
+        }
+        if self.at == block_hash {
+            return Ok(());
+        }
+        let mut from = Self::load(blockchain, &self.at)?;
+        let mut to = Self::load(blockchain, block_hash)?;
+        let mut to_apply = Vec::new();
+        loop {
+            let (from_block, to_block) = match (from.take(), to.take()) {
+                (None, None) => break,
+                (Some(a), Some(b)) if a.get_hash() == b.get_hash() => break,
+                pair => pair,
+            };
+            // Whichever side is higher steps down; genesis sits above "before genesis"
+            if from_block.as_ref().map(Block::get_height)
+                >= to_block.as_ref().map(Block::get_height)
+            {
+                let block = from_block.expect("the higher side is a block");
+                self.undo(blockchain, &block)?;
+                from = Self::load(blockchain, &block.get_pre_block_hash())?;
+                to = to_block;
+            } else {
+                let block = to_block.expect("the higher side is a block");
+                to = Self::load(blockchain, &block.get_pre_block_hash())?;
+                to_apply.push(block);
+                from = from_block;
+            }
+        }
+        for block in to_apply.iter().rev() {
+            self.apply(block)?;
+        }
+        self.at = block_hash.to_string();
+        Ok(())
+    }
+
+    // Spend what `block`, a child of the view's block, spends and add what it creates
+    fn apply(&mut self, block: &Block) -> Result<()> {
+        Self::check_body(block)?;
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for vin in tx.get_vin() {
+                    self.spend((vin.get_txid().to_vec(), vin.get_vout()));
+                }
+            }
+            // Data outputs are unspendable and never enter the chainstate
+            for (idx, output) in tx.get_vout().iter().enumerate() {
+                if !output.is_data_carrier() {
+                    self.create((tx.get_id().to_vec(), idx), output.clone());
+                }
+            }
+        }
+        self.at = block.get_hash().to_string();
+        Ok(())
+    }
+
+    // Take back what `block`, the view's block, created and give back what it spent
+    fn undo(&mut self, blockchain: &Blockchain, block: &Block) -> Result<()> {
+        Self::check_body(block)?;
+        for tx in block.get_transactions().iter().rev() {
+            for (idx, output) in tx.get_vout().iter().enumerate() {
+                if !output.is_data_carrier() {
+                    self.spend((tx.get_id().to_vec(), idx));
+                }
+            }
+            if !tx.is_coinbase() {
+                for vin in tx.get_vin() {
+                    let output =
+                        Self::spent_output(blockchain, block, vin.get_txid(), vin.get_vout())?;
+                    self.create((vin.get_txid().to_vec(), vin.get_vout()), output);
+                }
+            }
+        }
+        self.at = block.get_pre_block_hash();
+        Ok(())
+    }
+
+    fn spend(&mut self, outpoint: Outpoint) {
+        if self.created.remove(&outpoint).is_none() {
+            self.spent.insert(outpoint);
+        }
+    }
+
+    fn create(&mut self, outpoint: Outpoint, output: TXOutput) {
+        self.spent.remove(&outpoint);
+        self.created.insert(outpoint, output);
+    }
+
+    // The output an input of `block` spent. Blocks the chainstate followed off the main
+    // chain aren't in the transaction index, so their ancestors are searched too.
+    fn spent_output(
+        blockchain: &Blockchain,
+        block: &Block,
+        txid: &[u8],
+        vout: usize,
+    ) -> Result<TXOutput> {
+        let mut source = blockchain.find_transaction(txid)?;
+        let mut ancestors = blockchain.iterator_from(&block.get_pre_block_hash());
+        while source.is_none() {
+            let Some(ancestor) = ancestors.try_next()? else {
+                break;
+            };
+            source = ancestor
+                .get_transactions()
+                .iter()
+                .find(|tx| tx.get_id() == txid)
+                .cloned();
+        }
+        source
+            .and_then(|tx| tx.get_vout().get(vout).cloned())
+            .ok_or_else(|| {
+                BlockchainError::Database(format!(
+                    "Output {}:{vout} spent by block {} not found",
+                    HEXLOWER.encode(txid),
+                    block.get_hash()
+                ))
+            })
+    }
+
+    fn load(blockchain: &Blockchain, block_hash: &str) -> Result<Option<Block>> {
+        if block_hash == BEFORE_GENESIS {
+            return Ok(None);
+        }
+        blockchain
+            .get_block(block_hash)?
+            .map(Some)
+            .ok_or_else(|| BlockchainError::Database(format!("Block {block_hash} not found")))
+    }
+
+    // A pruned block no longer says what it spent and created
+    fn check_body(block: &Block) -> Result<()> {
+        if block.is_pruned() {
+            return Err(BlockchainError::Pruned(format!(
+                "block {} at height {} has no transactions to replay",
+                block.get_hash(),
+                block.get_height()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+//! Readable summaries of blocks and their transactions
+//!
+//! `printchain` prints these either as text or as JSON. Inputs are resolved to the
+//! address and value of the output they spend, which needs the previous transaction, so
+//! a `BlockSummarizer` remembers every transaction it has looked up or seen.
+//! `decoderawtransaction` has no chain to look in and prints a `DecodedTransaction`.
+
+use crate::core::{Block, Blockchain, TXOutput, Transaction};
+use crate::error::Result;
+use crate::wallet::{convert_address, hash_pub_key, ADDRESS_VERSION};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// One block with its transactions spelled out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct BlockSummary {
+    pub height: usize,
+    pub hash: String,
+    pub pre_block_hash: String,
+    pub timestamp: i64,
+    pub difficulty: u32,
+    pub nonce: i64,
+    /// Fees paid by the block's transactions, in satoshis
+    pub total_fees: u64,
+    pub transactions: Vec<TransactionSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct TransactionSummary {
+    pub txid: String,
+    pub coinbase: bool,
+    pub fee: u64,
+    /// Empty for coinbase transactions
+    pub inputs: Vec<InputSummary>,
+    pub outputs: Vec<OutputSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct InputSummary {
+    pub txid: String,
+    pub vout: usize,
+    /// Address of the spent output, or derived from the input's public key when the
+    /// previous transaction can't be found
+    pub from: String,
+    /// Value of the spent output, if the previous transaction was found
+    pub value: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct OutputSummary {
+    /// Empty for data outputs, which nobody can spend
+    pub to: String,
+    pub value: u64,
+    /// Hex of the data a data output records
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+}
+
+/// A transaction read without a chain, so its inputs only show what they claim
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecodedTransaction {
+    pub txid: String,
+    pub coinbase: bool,
+    pub fee: u64,
+    /// Empty for coinbase transactions
+    pub inputs: Vec<DecodedInput>,
+    pub outputs: Vec<OutputSummary>,
+    /// What `Transaction::check_structure` found wrong, if anything
+    pub structure_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecodedInput {
+    pub txid: String,
+    pub vout: usize,
+    /// Hex of the public key the input claims, empty until it is signed
+    pub pub_key: String,
+    /// Address derived from the claimed key; `None` without a key and for multisig inputs
+    pub from: Option<String>,
+    pub signed: bool,
+}
+
+impl DecodedTransaction {
+    pub fn decode(tx: &Transaction) -> DecodedTransaction {
+        // A raw transaction with a single input has no key before it's signed either,
+        // but unlike a coinbase it names the output it spends
+        let coinbase = tx.is_coinbase() && tx.get_vin()[0].get_txid().is_empty();
+        let inputs = if coinbase {
+            Vec::new()
+        } else {
+            tx.get_vin()
+                .iter()
+                .map(|input| {
+                    let pub_key = input.get_pub_key();
+                    let (from, signed) = match input.get_multisig_signatures() {
+                        Some(signatures) => (None, !signatures.is_empty()),
+                        None if pub_key.is_empty() => (None, input.is_signed()),
+                        None => (
+                            convert_address(ADDRESS_VERSION, &hash_pub_key(pub_key)).ok(),
+                            input.is_signed(),
+                        ),
+                    };
+                    DecodedInput {
+                        txid: HEXLOWER.encode(input.get_txid()),
+                        vout: input.get_vout(),
+                        pub_key: HEXLOWER.encode(pub_key),
+                        from,
+                        signed,
+                    }
+                })
+                .collect()
+        };
+
+        DecodedTransaction {
+            txid: HEXLOWER.encode(tx.get_id()),
+            coinbase,
+            fee: tx.get_fee().to_satoshis(),
+            inputs,
+            outputs: tx.get_vout().iter().map(summarize_output).collect(),
+            structure_error: tx.check_structure().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+fn summarize_output(output: &TXOutput) -> OutputSummary {
+    match output.get_data() {
+        Some(data) => OutputSummary {
+            to: String::new(),
+            value: output.get_value().to_satoshis(),
+            memo: Some(HEXLOWER.encode(&data)),
+        },
+        None => OutputSummary {
+            to: output.get_address(),
+            value: output.get_value().to_satoshis(),
+            memo: None,
+        },
+    }
+}
+
+/// Builds `BlockSummary`s, caching previous transactions across blocks
+pub struct BlockSummarizer<'a> {
+    blockchain: &'a Blockchain,
+    transactions: HashMap<Vec<u8>, Option<Transaction>>,
+}
+
+impl<'a> BlockSummarizer<'a> {
+    pub fn new(blockchain: &'a Blockchain) -> BlockSummarizer<'a> {
+        BlockSummarizer {
+            blockchain,
+            transactions: HashMap::new(),
+        }
+    }
+
+    pub fn summarize(&mut self, block: &Block) -> BlockSummary {
+        // Inputs often spend outputs from the same block or the blocks summarized just
+        // before, so I remember every transaction seen here first
+        for tx in block.get_transactions() {
+            self.transactions
+                .insert(tx.get_id().to_vec(), Some(tx.clone()));
+        }
+
+        BlockSummary {
+            height: block.get_height(),
+            hash: block.get_hash().to_string(),
+            pre_block_hash: block.get_pre_block_hash(),
+            timestamp: block.get_timestamp(),
+            difficulty: block.get_difficulty(),
+            nonce: block.get_nonce(),
+            total_fees: block.get_total_fees().to_satoshis(),
+            transactions: block
+                .get_transactions()
+                .iter()
+                .map(|tx| self.summarize_transaction(tx))
+                .collect(),
+        }
+    }
+
+    /// Main-chain blocks from `from_height` to `to_height`, or to the tip, newest first
+    ///
+    /// With a `limit`, only that many of the highest heights in the range.
+    pub fn summarize_main_chain(
+        &mut self,
+        from_height: Option<usize>,
+        to_height: Option<usize>,
+        limit: Option<usize>,
+    ) -> Result<Vec<BlockSummary>> {
+        let to = to_height.unwrap_or(usize::MAX);
+        let mut from = from_height.unwrap_or(0);
+        // I count the limit down from the top of the range, so paging goes tip first
+        if let Some(limit) = limit {
+            let top = to.min(self.blockchain.get_best_height()?);
+            from = from.max((top + 1).saturating_sub(limit));
+        }
+
+        // I summarize oldest first so spent outputs are usually cached already
+        let mut summaries: Vec<BlockSummary> = self
+            .blockchain
+            .blocks_in_range(from, to)?
+            .iter()
+            .map(|block| self.summarize(block))
+            .collect();
+        summaries.reverse();
+        Ok(summaries)
+    }
+
+    /// Summarize one transaction, confirmed or not
+    pub fn summarize_transaction(&mut self, tx: &Transaction) -> TransactionSummary {
+        let inputs = if tx.is_coinbase() {
+            Vec::new()
+        } else {
+            tx.get_vin()
+                .iter()
+                .map(|input| {
+                    let spent = self
+                        .previous_transaction(input.get_txid())
+                        .and_then(|prev| prev.get_vout().get(input.get_vout()));
+                    let from = match spent {
+                        Some(output) => output.get_address(),
+                        None => {
+                            convert_address(ADDRESS_VERSION, &hash_pub_key(input.get_pub_key()))
+                                .unwrap_or_default()
+                        }
+                    };
+                    InputSummary {
+                        txid: HEXLOWER.encode(input.get_txid()),
+                        vout: input.get_vout(),
+                        from,
+                        value: spent.map(|output| output.get_value().to_satoshis()),
+                    }
+                })
+                .collect()
+        };
+
+        TransactionSummary {
+            txid: HEXLOWER.encode(tx.get_id()),
+            coinbase: tx.is_coinbase(),
+            fee: tx.get_fee().to_satoshis(),
+            inputs,
+            outputs: tx.get_vout().iter().map(summarize_output).collect(),
+        }
+    }
+
+    fn previous_transaction(&mut self, txid: &[u8]) -> Option<&Transaction> {
+        let blockchain = self.blockchain;
+        // A spent transaction forgotten by pruning leaves its input unattributed
+        self.transactions
+            .entry(txid.to_vec())
+            .or_insert_with(|| blockchain.find_transaction(txid).ok().flatten())
+            .as_ref()
+    }
+}
+
+impl fmt::Display for BlockSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Height: {}", self.height)?;
+        writeln!(f, "Pre block hash: {}", self.pre_block_hash)?;
+        writeln!(f, "Cur block hash: {}", self.hash)?;
+        writeln!(f, "Cur block Timestamp: {}", self.timestamp)?;
+        writeln!(f, "Fees: {} satoshis", self.total_fees)?;
+        for tx in &self.transactions {
+            writeln!(f, "- Transaction txid_hex: {}", tx.txid)?;
+            for input in &tx.inputs {
+                writeln!(
+                    f,
+                    "-- Input txid = {}, vout = {}, from = {}",
+                    input.txid, input.vout, input.from
+                )?;
+            }
+            for output in &tx.outputs {
+                match &output.memo {
+                    Some(memo) => writeln!(f, "-- Output value = {}, memo = {memo}", output.value)?,
+                    None => writeln!(f, "-- Output value = {}, to = {}", output.value, output.to)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DecodedTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Transaction txid_hex: {}", self.txid)?;
+        if self.coinbase {
+            writeln!(f, "Coinbase")?;
+        }
+        for input in &self.inputs {
+            let from = input.from.as_deref().unwrap_or("unknown");
+            let signed = if input.signed { "signed" } else { "unsigned" };
+            writeln!(
+                f,
+                "- Input txid = {}, vout = {}, from = {from}, {signed}",
+                input.txid, input.vout
+            )?;
+        }
+        for output in &self.outputs {
+            match &output.memo {
+                Some(memo) => writeln!(f, "- Output value = {}, memo = {memo}", output.value)?,
+                None => writeln!(f, "- Output value = {}, to = {}", output.value, output.to)?,
+            }
+        }
+        writeln!(f, "Fee: {} satoshis", self.fee)?;
+        match &self.structure_error {
+            Some(error) => writeln!(f, "Structure: invalid, {error}"),
+            None => writeln!(f, "Structure: valid"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, FeePriority, NodeContext};
+    use crate::storage::UTXOSet;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    #[test]
+    fn test_range_summaries_serialize_to_json() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+
+        let mut wallets = Wallets::new();
+        let owners: Vec<String> = (0..6).map(|_| wallets.create_wallet().unwrap()).collect();
+        let blockchain =
+            Blockchain::create_blockchain_with_path(&owners[0], db_path.to_str().unwrap()).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        let amount_at = |height: usize| 10_000 - height as u64 * 1_000;
+
+        // Five blocks, each handing the coins received in the previous block to the next owner
+        for height in 1..=5 {
+            utxo_set.reindex();
+            let tx = Transaction::new_utxo_transaction_with_context(
+                &owners[height - 1],
+                &owners[height],
+                amount_at(height),
+                FeePriority::Normal,
+                &utxo_set,
+                &context,
+            )
+            .unwrap();
+            blockchain
+                .mine_block_with_context(&[tx], TEST_ADDRESS, &context)
+                .unwrap();
+        }
+
+        let blocks = blockchain.blocks_in_range(2, 4).unwrap();
+        let mut summarizer = BlockSummarizer::new(&blockchain);
+        let summaries: Vec<BlockSummary> = blocks.iter().map(|b| summarizer.summarize(b)).collect();
+
+        let json = serde_json::to_string(&summaries).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let parsed = parsed.as_array().unwrap();
+        assert_eq!(parsed.len(), 3);
+
+        for (block, height) in parsed.iter().zip(2..) {
+            assert_eq!(block["height"], height);
+            assert_eq!(block["total_fees"], 3);
+            let transactions = block["transactions"].as_array().unwrap();
+            assert_eq!(transactions.len(), 2);
+            assert_eq!(transactions[0]["coinbase"], true);
+            assert_eq!(transactions[0]["outputs"][0]["to"], TEST_ADDRESS);
+            assert!(transactions[0]["inputs"].as_array().unwrap().is_empty());
+
+            // The input resolves to the output paid out one block earlier
+            let payment = &transactions[1];
+            assert_eq!(payment["fee"], 3);
+            assert_eq!(payment["inputs"][0]["from"], owners[height - 1].as_str());
+            assert_eq!(payment["inputs"][0]["value"], amount_at(height - 1));
+            assert_eq!(payment["outputs"][0]["to"], owners[height].as_str());
+            assert_eq!(payment["outputs"][0]["value"], amount_at(height));
+        }
+
+        // Out-of-range ends are clamped to the chain
+        assert_eq!(blockchain.blocks_in_range(4, usize::MAX).unwrap().len(), 2);
+        assert!(blockchain.blocks_in_range(6, 9).unwrap().is_empty());
+    }
+}
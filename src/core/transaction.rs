@@ -2,16 +2,28 @@
 // I'm following Bitcoin's UTXO (Unspent Transaction Output) model for maximum compatibility
 // Each transaction consumes previous outputs and creates new ones
 
-use crate::core::{Blockchain, FeeCalculator, FeePriority, INITIAL_BLOCK_REWARD};
+use crate::config::GLOBAL_CONFIG;
+use crate::core::coin_selection::{self, Coin, SelectionStrategy};
+use crate::core::monetary::{
+    Amount, DEFAULT_MAX_CONSOLIDATION_FEE_PERCENT, DUST_THRESHOLD, MAX_MONEY,
+};
+use crate::core::{
+    Blockchain, FeeCalculator, FeePriority, NodeContext, INITIAL_BLOCK_REWARD, MAX_TRANSACTION_FEE,
+    MAX_TRANSACTION_SIZE,
+};
 use crate::error::{BlockchainError, Result};
-use crate::storage::UTXOSet;
+use crate::storage::{MemoryPool, UTXOSet, GLOBAL_MEMORY_POOL};
 use crate::utils::{
-    base58_decode, deserialize, ecdsa_p256_sha256_sign_digest, ecdsa_p256_sha256_sign_verify,
-    serialize, sha256_digest,
+    deserialize_versioned, ecdsa_p256_sha256_sign_digest, ecdsa_p256_sha256_sign_verify, serialize,
+    serialize_as, serialize_versioned, sha256_digest,
+};
+use crate::wallet::{
+    convert_address, convert_multisig_address, decode_address, hash_pub_key, validate_address,
+    Wallet, Wallets, ADDRESS_VERSION, MULTISIG_ADDRESS_VERSION,
 };
-use crate::wallet::{hash_pub_key, validate_address, Wallets};
 use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 // I use this constant for the block reward in coinbase transactions
@@ -51,6 +63,38 @@ impl TXInput {
         self.pub_key.as_slice()
     }
 
+    /// Whether the input carries a signature yet
+    pub fn is_signed(&self) -> bool {
+        !self.signature.is_empty()
+    }
+
+    /// The (public key, signature) pairs of an input spending a multisig output, in the
+    /// order they were added; `None` for every other input
+    pub fn get_multisig_signatures(&self) -> Option<Vec<(&[u8], &[u8])>> {
+        let keys = match self.pub_key.split_first() {
+            Some((&MULTISIG_LOCK_TAG, keys)) if keys.len() % PUB_KEY_LEN == 0 => keys,
+            _ => return None,
+        };
+        if self.signature.len() != keys.len() / PUB_KEY_LEN * SIGNATURE_LEN {
+            return None;
+        }
+        Some(
+            keys.chunks(PUB_KEY_LEN)
+                .zip(self.signature.chunks(SIGNATURE_LEN))
+                .collect(),
+        )
+    }
+
+    // A multisig input keeps its keys back to back after the tag and its signatures back to
+    // back in the same order, so it fits the fields every input already has
+    fn add_multisig_signature(&mut self, pub_key: &[u8], signature: &[u8]) {
+        if self.pub_key.is_empty() {
+            self.pub_key.push(MULTISIG_LOCK_TAG);
+        }
+        self.pub_key.extend_from_slice(pub_key);
+        self.signature.extend_from_slice(signature);
+    }
+
     // I use this to check if this input belongs to a specific public key
     #[allow(dead_code)]
     fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -59,17 +103,66 @@ impl TXInput {
     }
 }
 
+// A P2PK output stores this byte followed by the raw public key where every other output
+// stores its 20-byte public key hash. Nothing that was ever a hash starts with it and is
+// longer, so outputs serialized before P2PK existed still read back as hash locks.
+const PUB_KEY_LOCK_TAG: u8 = 0xac;
+const PUB_KEY_HASH_LEN: usize = 20;
+// ECDSA P-256 public keys in the uncompressed form my wallets use
+const PUB_KEY_LEN: usize = 65;
+// Fixed-size P-256 signatures, r and s
+const SIGNATURE_LEN: usize = 64;
+// A multisig output stores this byte, how many signatures it needs, how many keys it names
+// and then their 20-byte hashes. That's 3 + 20n bytes, never the length of a single hash.
+// Inputs spending one start their key field with the same byte.
+const MULTISIG_LOCK_TAG: u8 = 0xae;
+/// Most keys a multisig output may name
+pub const MAX_MULTISIG_KEYS: usize = 16;
+// A data output stores this byte followed by its data and carries no value. Every other
+// output has to be worth something, which is what tells the two apart.
+const DATA_CARRIER_TAG: u8 = 0x6a;
+
+/// Most bytes a data output may carry
+pub const MAX_DATA_CARRIER_SIZE: usize = 80;
+
+/// Most inputs a transaction may spend to be built, pooled or relayed, unless the config
+/// file sets `node.max_tx_inputs`
+pub const DEFAULT_MAX_TRANSACTION_INPUTS: usize = 1_000;
+// What one signed input takes at most once serialized: the txid, the output index, the
+// signature and the key, each behind its length
+const SIGNED_INPUT_SIZE: usize = 180;
+// Room a sweep or consolidation split across transactions leaves in each for its id, fee
+// and output
+const SPLIT_OVERHEAD: usize = 1_000;
+
+// This is who can spend an output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockingCondition {
+    /// Whoever shows a public key with this hash and signs with it, i.e. an address
+    PubKeyHash(Vec<u8>),
+    /// Whoever signs with exactly this public key
+    PubKey(Vec<u8>),
+    /// Nobody: the output only records data on chain (OP_RETURN)
+    DataCarrier(Vec<u8>),
+    /// Any `required` of the keys with these hashes, each signing
+    MultiSig {
+        required: u8,
+        pub_key_hashes: Vec<Vec<u8>>,
+    },
+}
+
 // This represents a transaction output - it's like a "check" that can be cashed later
 // Think of it as "Pay 100 satoshis to whoever has the private key for address XYZ"
 #[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub struct TXOutput {
-    value: u64,            // How much cryptocurrency this output is worth (in satoshis)
-    pub_key_hash: Vec<u8>, // The hash of the public key that can spend this output
+    value: Amount,         // How much cryptocurrency this output is worth (in satoshis)
+    pub_key_hash: Vec<u8>, // The lock: a public key hash, or a tagged public key for P2PK
 }
 
 impl TXOutput {
-    pub fn new(value: u64, address: &str) -> Result<TXOutput> {
-        if value == 0 {
+    pub fn new(value: impl Into<Amount>, address: &str) -> Result<TXOutput> {
+        let value = value.into();
+        if value == Amount::ZERO {
             return Err(BlockchainError::Transaction(
                 "Transaction value must be positive".to_string(),
             ));
@@ -83,37 +176,224 @@ impl TXOutput {
         Ok(output)
     }
 
-    pub fn get_value(&self) -> u64 {
+    // When I pay straight to a public key instead of an address
+    pub fn new_p2pk(value: impl Into<Amount>, pub_key: &[u8]) -> Result<TXOutput> {
+        let value = value.into();
+        if value == Amount::ZERO {
+            return Err(BlockchainError::Transaction(
+                "Transaction value must be positive".to_string(),
+            ));
+        }
+        Self::check_pub_key(pub_key)?;
+
+        let mut lock = Vec::with_capacity(1 + pub_key.len());
+        lock.push(PUB_KEY_LOCK_TAG);
+        lock.extend_from_slice(pub_key);
+        Ok(TXOutput {
+            value,
+            pub_key_hash: lock,
+        })
+    }
+
+    // When I want to anchor data on chain; the output is worth nothing and can't be spent
+    pub fn new_data_carrier(data: &[u8]) -> Result<TXOutput> {
+        Self::check_data(data)?;
+
+        let mut lock = Vec::with_capacity(1 + data.len());
+        lock.push(DATA_CARRIER_TAG);
+        lock.extend_from_slice(data);
+        Ok(TXOutput {
+            value: Amount::ZERO,
+            pub_key_hash: lock,
+        })
+    }
+
+    /// An output that takes signatures from `required` of the keys behind `pub_key_hashes`
+    pub fn new_multisig(
+        value: impl Into<Amount>,
+        required: u8,
+        pub_key_hashes: Vec<Vec<u8>>,
+    ) -> Result<TXOutput> {
+        let value = value.into();
+        if value == Amount::ZERO {
+            return Err(BlockchainError::Transaction(
+                "Transaction value must be positive".to_string(),
+            ));
+        }
+
+        let mut lock = vec![MULTISIG_LOCK_TAG];
+        lock.extend(Self::multisig_policy(required, &pub_key_hashes)?);
+        Ok(TXOutput {
+            value,
+            pub_key_hash: lock,
+        })
+    }
+
+    /// The address that `required` of the keys behind `pub_key_hashes` spend from together
+    pub fn multisig_address(required: u8, pub_key_hashes: &[Vec<u8>]) -> Result<String> {
+        Ok(convert_multisig_address(&Self::multisig_policy(
+            required,
+            pub_key_hashes,
+        )?))
+    }
+
+    // `[required, key count, key hashes...]`, what a multisig address encodes
+    fn multisig_policy(required: u8, pub_key_hashes: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let key_count = pub_key_hashes.len();
+        if required == 0 || usize::from(required) > key_count || key_count > MAX_MULTISIG_KEYS {
+            return Err(BlockchainError::Transaction(format!(
+                "A multisig output needs 1..=n of n keys with n at most {MAX_MULTISIG_KEYS}, \
+                 not {required} of {key_count}"
+            )));
+        }
+        for (idx, hash) in pub_key_hashes.iter().enumerate() {
+            if hash.len() != PUB_KEY_HASH_LEN {
+                return Err(BlockchainError::Transaction(format!(
+                    "Multisig key {idx} is not a {PUB_KEY_HASH_LEN}-byte public key hash"
+                )));
+            }
+            if pub_key_hashes[..idx].contains(hash) {
+                return Err(BlockchainError::Transaction(format!(
+                    "Multisig key {idx} is named twice"
+                )));
+            }
+        }
+
+        let mut policy = Vec::with_capacity(2 + key_count * PUB_KEY_HASH_LEN);
+        policy.push(required);
+        policy.push(key_count as u8);
+        for hash in pub_key_hashes {
+            policy.extend_from_slice(hash);
+        }
+        Ok(policy)
+    }
+
+    // The inverse of `multisig_policy`, `None` unless the policy is well formed
+    fn parse_multisig_policy(policy: &[u8]) -> Option<(u8, Vec<Vec<u8>>)> {
+        let (&required, rest) = policy.split_first()?;
+        let (&key_count, hashes) = rest.split_first()?;
+        if hashes.len() != usize::from(key_count) * PUB_KEY_HASH_LEN {
+            return None;
+        }
+        let pub_key_hashes: Vec<Vec<u8>> = hashes
+            .chunks(PUB_KEY_HASH_LEN)
+            .map(<[u8]>::to_vec)
+            .collect();
+        Self::multisig_policy(required, &pub_key_hashes).ok()?;
+        Some((required, pub_key_hashes))
+    }
+
+    fn check_data(data: &[u8]) -> Result<()> {
+        if data.is_empty() || data.len() > MAX_DATA_CARRIER_SIZE {
+            return Err(BlockchainError::Transaction(format!(
+                "Data output carries {} bytes, allowed are 1..={MAX_DATA_CARRIER_SIZE}",
+                data.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_pub_key(pub_key: &[u8]) -> Result<()> {
+        if pub_key.len() != PUB_KEY_LEN || pub_key[0] != 0x04 {
+            return Err(BlockchainError::Transaction(format!(
+                "Not an uncompressed P-256 public key: {}",
+                HEXLOWER.encode(pub_key)
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn get_value(&self) -> Amount {
         self.value
     }
 
-    pub fn get_pub_key_hash(&self) -> &[u8] {
-        self.pub_key_hash.as_slice()
+    pub fn get_locking_condition(&self) -> LockingCondition {
+        match self.pub_key_hash.split_first() {
+            Some((&DATA_CARRIER_TAG, data)) if self.value == Amount::ZERO => {
+                LockingCondition::DataCarrier(data.to_vec())
+            }
+            Some((&PUB_KEY_LOCK_TAG, pub_key)) if self.pub_key_hash.len() != PUB_KEY_HASH_LEN => {
+                LockingCondition::PubKey(pub_key.to_vec())
+            }
+            Some((&MULTISIG_LOCK_TAG, policy)) if self.pub_key_hash.len() != PUB_KEY_HASH_LEN => {
+                match Self::parse_multisig_policy(policy) {
+                    Some((required, pub_key_hashes)) => LockingCondition::MultiSig {
+                        required,
+                        pub_key_hashes,
+                    },
+                    None => LockingCondition::PubKeyHash(self.pub_key_hash.clone()),
+                }
+            }
+            _ => LockingCondition::PubKeyHash(self.pub_key_hash.clone()),
+        }
+    }
+
+    // The hash of the key that can spend this output, for matching it against addresses;
+    // a P2PK output belongs to the address of its key and a data output to nobody. For a
+    // multisig output it's the policy its address encodes.
+    pub fn get_pub_key_hash(&self) -> Vec<u8> {
+        match self.get_locking_condition() {
+            LockingCondition::PubKeyHash(pub_key_hash) => pub_key_hash,
+            LockingCondition::PubKey(pub_key) => hash_pub_key(&pub_key),
+            LockingCondition::DataCarrier(_) => vec![],
+            LockingCondition::MultiSig { .. } => self.pub_key_hash[1..].to_vec(),
+        }
     }
 
-    fn lock(&mut self, address: &str) -> Result<()> {
-        if !validate_address(address) {
-            return Err(BlockchainError::InvalidAddress(address.to_string()));
+    /// The address this output pays, empty for a data output
+    pub fn get_address(&self) -> String {
+        match self.get_locking_condition() {
+            LockingCondition::DataCarrier(_) => String::new(),
+            LockingCondition::MultiSig { .. } => convert_multisig_address(&self.pub_key_hash[1..]),
+            // A hash no address can carry pays nobody I could name
+            _ => convert_address(ADDRESS_VERSION, &self.get_pub_key_hash()).unwrap_or_default(),
         }
+    }
 
-        let payload = base58_decode(address)?;
-        if payload.len() < crate::wallet::ADDRESS_CHECK_SUM_LEN + 1 {
-            return Err(BlockchainError::InvalidAddress(
-                "Address too short".to_string(),
-            ));
+    /// The data of a data output, `None` for every output that can be spent
+    pub fn get_data(&self) -> Option<Vec<u8>> {
+        match self.get_locking_condition() {
+            LockingCondition::DataCarrier(data) => Some(data),
+            _ => None,
         }
+    }
+
+    pub fn is_data_carrier(&self) -> bool {
+        matches!(
+            self.pub_key_hash.first(),
+            Some(&DATA_CARRIER_TAG) if self.value == 0
+        )
+    }
 
-        let pub_key_hash =
-            payload[1..payload.len() - crate::wallet::ADDRESS_CHECK_SUM_LEN].to_vec();
+    fn lock(&mut self, address: &str) -> Result<()> {
+        let (version, pub_key_hash) = decode_address(address)?;
+        if version == MULTISIG_ADDRESS_VERSION {
+            if Self::parse_multisig_policy(&pub_key_hash).is_none() {
+                return Err(BlockchainError::InvalidAddress(format!(
+                    "Malformed multisig address: {address}"
+                )));
+            }
+            self.pub_key_hash = vec![MULTISIG_LOCK_TAG];
+            self.pub_key_hash.extend(pub_key_hash);
+            return Ok(());
+        }
         self.pub_key_hash = pub_key_hash;
         Ok(())
     }
 
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash.eq(pub_key_hash)
+        !self.is_data_carrier() && self.get_pub_key_hash().eq(pub_key_hash)
     }
 }
 
+// Whose coins a payment spends: coins of `address` are found by `lock` and every input
+// spending one carries `input_key`
+struct Spender<'a> {
+    address: &'a str,
+    lock: Vec<u8>,
+    input_key: Vec<u8>,
+}
+
 // This is the main transaction structure - it represents a transfer of value
 // A transaction takes some inputs (previous outputs) and creates new outputs
 #[derive(Debug, Clone, Default, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
@@ -121,7 +401,7 @@ pub struct Transaction {
     id: Vec<u8>,         // Unique identifier for this transaction (hash of its contents)
     vin: Vec<TXInput>,   // List of inputs (what I'm spending)
     vout: Vec<TXOutput>, // List of outputs (where the money is going)
-    fee: u64,            // Transaction fee in satoshis (paid to miners)
+    fee: Amount,         // Transaction fee in satoshis (paid to miners)
 }
 
 impl Transaction {
@@ -131,7 +411,7 @@ impl Transaction {
     }
 
     // When I create a coinbase transaction with a specific reward amount
-    pub fn new_coinbase_tx_with_reward(to: &str, reward: u64) -> Result<Transaction> {
+    pub fn new_coinbase_tx_with_reward(to: &str, reward: impl Into<Amount>) -> Result<Transaction> {
         // I create an output that pays the reward to the miner
         let txout = TXOutput::new(reward, to)?;
         // Coinbase transactions have a special input with no previous transaction
@@ -144,20 +424,38 @@ impl Transaction {
             id: vec![],
             vin: vec![tx_input],
             vout: vec![txout],
-            fee: 0, // Coinbase transactions don't pay fees (they create new money)
+            fee: Amount::ZERO, // Coinbase transactions don't pay fees (they create new money)
         };
 
         // I calculate the transaction ID by hashing its contents
-        tx.id = tx.hash();
+        tx.id = tx.compute_id();
         Ok(tx)
     }
 
-    /// Create a coinbase transaction with collected fees using the fee calculator
+    // A genesis block from a `GenesisConfig` has to come out the same on every node, so its
+    // coinbase carries the configured message where others carry random bytes
+    pub(crate) fn new_genesis_coinbase_tx(message: &[u8], outputs: Vec<TXOutput>) -> Transaction {
+        let tx_input = TXInput {
+            signature: message.to_vec(),
+            ..Default::default()
+        };
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![tx_input],
+            vout: outputs,
+            fee: Amount::ZERO,
+        };
+        tx.id = tx.compute_id();
+        tx
+    }
+
+    /// Create the coinbase for a block at `height` using the fee calculator
     pub fn new_coinbase_tx_with_collected_fees(
         to: &str,
-        collected_fees: u64,
+        collected_fees: impl Into<Amount>,
+        height: usize,
     ) -> Result<Transaction> {
-        let total_reward = FeeCalculator::calculate_coinbase_reward(collected_fees);
+        let total_reward = FeeCalculator::calculate_coinbase_reward(collected_fees, height);
         Self::new_coinbase_tx_with_reward(to, total_reward)
     }
 
@@ -166,62 +464,307 @@ impl Transaction {
         to: &str,
         amount: u64,
         utxo_set: &UTXOSet,
+        wallets: &Wallets,
     ) -> Result<Transaction> {
         // Use normal priority for backward compatibility
-        Self::new_utxo_transaction_with_priority(from, to, amount, FeePriority::Normal, utxo_set)
+        Self::new_utxo_transaction_with_priority(
+            from,
+            to,
+            amount,
+            FeePriority::Normal,
+            utxo_set,
+            wallets,
+        )
     }
 
     /// Create a UTXO transaction with a specific priority (new dynamic fee system)
+    ///
+    /// The fee comes from the global fee calculator and memory pool.
     pub fn new_utxo_transaction_with_priority(
         from: &str,
         to: &str,
         amount: u64,
         priority: FeePriority,
         utxo_set: &UTXOSet,
+        wallets: &Wallets,
     ) -> Result<Transaction> {
-        // Validate inputs
-        if amount == 0 {
-            return Err(BlockchainError::Transaction(
-                "Amount must be positive".to_string(),
-            ));
-        }
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Priority(priority))
+            .build_and_sign(wallets)
+    }
 
-        if !validate_address(from) {
-            return Err(BlockchainError::InvalidAddress(format!(
-                "Invalid from address: {from}"
-            )));
-        }
+    /// Like `new_utxo_transaction_with_priority`, but pays the fee even when it's above
+    /// `MAX_TRANSACTION_FEE`
+    ///
+    /// Only for senders who insist; such a fee is usually a mistake that burns coins. Send
+    /// the result with `send_tx_allowing_high_fee` so memory pools accept it too.
+    pub fn new_utxo_transaction_allowing_high_fee(
+        from: &str,
+        to: &str,
+        amount: u64,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Priority(priority))
+            .allow_high_fee(true)
+            .build_and_sign(wallets)
+    }
 
-        if !validate_address(to) {
-            return Err(BlockchainError::InvalidAddress(format!(
-                "Invalid to address: {to}"
-            )));
-        }
+    /// Create a UTXO transaction priced by a node's own fee calculator and memory pool,
+    /// signed with the node's wallets
+    pub fn new_utxo_transaction_with_context(
+        from: &str,
+        to: &str,
+        amount: u64,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        context: &NodeContext,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Priority(priority))
+            .with_context(context)
+            .build_and_sign(context.wallets())
+    }
 
-        let wallets = Wallets::new();
-        let wallet = wallets.get_wallet(from).ok_or_else(|| {
-            BlockchainError::Wallet(format!("Wallet not found for address: {from}"))
-        })?;
-        let public_key_hash = hash_pub_key(wallet.get_public_key());
+    /// Like `new_utxo_transaction_with_priority`, spending the coins of `wallet` instead of
+    /// those of a wallet looked up in the wallet file
+    pub fn new_utxo_transaction_from_wallet(
+        wallet: &Wallet,
+        to: &str,
+        amount: u64,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new(utxo_set)
+            .from(&wallet.get_address())
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Priority(priority))
+            .build_and_sign(wallet)
+    }
 
-        let (accumulated, valid_outputs) =
-            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount);
+    /// Like `new_utxo_transaction_with_context`, spending the coins of `wallet`, which may
+    /// come from any wallet file
+    pub fn new_utxo_transaction_from_wallet_with_context(
+        wallet: &Wallet,
+        to: &str,
+        amount: u64,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        context: &NodeContext,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new(utxo_set)
+            .from(&wallet.get_address())
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Priority(priority))
+            .with_context(context)
+            .build_and_sign(wallet)
+    }
 
-        // Calculate fee using the new fee system
-        let estimated_size = FeeCalculator::estimate_transaction_size(valid_outputs.len(), 2); // Estimate 2 outputs (to + change)
-        let fee_amount = FeeCalculator::calculate_fee(estimated_size, Some(priority));
+    /// Pay `amount` from the addresses of account `account` in `wallets`
+    ///
+    /// Coins are selected across every member, so the payment may be larger than any one
+    /// address holds; each input is signed with the key of the address whose output it
+    /// spends. The change goes to the account's change address. The coins are picked by
+    /// the wallet file's default strategy, if it has one.
+    pub fn new_account_transaction(
+        account: &str,
+        to: &str,
+        amount: u64,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        Self::new_account_transaction_with_coin_selection(
+            account,
+            to,
+            amount,
+            priority,
+            wallets.default_coin_selection(),
+            utxo_set,
+            wallets,
+        )
+    }
 
-        // Check if we have enough funds for amount + fee
-        let total_needed = amount + fee_amount;
-        if accumulated < total_needed {
-            return Err(BlockchainError::InsufficientFunds {
-                required: total_needed,
-                available: accumulated,
-            });
+    /// Pay `amount` from account `account` like `new_account_transaction`, picking the
+    /// coins by `coin_selection`, or in chainstate order for `None`
+    pub fn new_account_transaction_with_coin_selection(
+        account: &str,
+        to: &str,
+        amount: u64,
+        priority: FeePriority,
+        coin_selection: Option<SelectionStrategy>,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        let members = wallets.get_account(account)?;
+        let change_address = members.change_address().ok_or_else(|| {
+            BlockchainError::Wallet(format!(
+                "Account '{account}' has no addresses to spend from"
+            ))
+        })?;
+        let mut builder = TransactionBuilder::new(utxo_set)
+            .from_addresses(members.addresses())
+            .change_to(change_address)
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Priority(priority));
+        if let Some(strategy) = coin_selection {
+            builder = builder.coin_selection(strategy);
         }
+        builder.build_and_sign(wallets)
+    }
+
+    /// Create a UTXO transaction paying `amount` straight to a public key (P2PK)
+    ///
+    /// Only the holder of the matching private key can spend the output; it still shows up
+    /// under the address of that key.
+    pub fn new_utxo_transaction_to_pub_key(
+        from: &str,
+        to_pub_key: &[u8],
+        amount: u64,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output_to_pub_key(to_pub_key, amount)
+            .fee_policy(FeePolicy::Priority(priority))
+            .build_and_sign(wallets)
+    }
+
+    /// Create a UTXO transaction that also records `memo` on chain in a data output
+    ///
+    /// The memo is at most `MAX_DATA_CARRIER_SIZE` bytes and adds its size to the fee.
+    pub fn new_utxo_transaction_with_memo(
+        from: &str,
+        to: &str,
+        amount: u64,
+        memo: &[u8],
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, amount)
+            .memo(memo)
+            .fee_policy(FeePolicy::Priority(priority))
+            .build_and_sign(wallets)
+    }
+
+    /// Create the transactions sending everything `from` can spend to `to`, with no change
+    ///
+    /// The fee is priced for the actual number of inputs and taken out of the amount sent,
+    /// so the source ends up empty. Coins too many for one transaction are split evenly
+    /// across as few as fit `MAX_TRANSACTION_SIZE` and the input limit, each paying its own
+    /// fee. Refused if what any of them leaves is dust.
+    pub fn new_sweep_transaction(
+        from: &str,
+        to: &str,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Vec<Transaction>> {
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, 0)
+            .fee_policy(FeePolicy::SweepAll(priority))
+            .build_and_sign_all(wallets)
+    }
+
+    /// Like `new_sweep_transaction`, each transaction paying exactly `fee_amount`
+    pub fn new_sweep_transaction_with_explicit_fee(
+        from: &str,
+        to: &str,
+        fee_amount: u64,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Vec<Transaction>> {
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, 0)
+            .fee_policy(FeePolicy::SweepAllWithFee(fee_amount.into()))
+            .build_and_sign_all(wallets)
+    }
+
+    /// Merge up to `max_inputs` of the smallest outputs of `address` into one output
+    /// back to it
+    ///
+    /// Fewer, larger outputs make later payments smaller and cheaper. More outputs than fit
+    /// one transaction are merged by several, split evenly. Refused when the fee of any of
+    /// them would take more than the configured share of the value it merges,
+    /// `DEFAULT_MAX_CONSOLIDATION_FEE_PERCENT` unless the config file sets
+    /// `fees.max_consolidation_fee_percent`.
+    pub fn new_consolidation_transaction(
+        address: &str,
+        max_inputs: usize,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Vec<Transaction>> {
+        let max_fee_percent = GLOBAL_CONFIG
+            .get_max_consolidation_fee_percent()
+            .unwrap_or(DEFAULT_MAX_CONSOLIDATION_FEE_PERCENT);
+        TransactionBuilder::new(utxo_set)
+            .from(address)
+            .add_output(address, 0)
+            .fee_policy(FeePolicy::SweepAll(priority))
+            .consolidate(max_inputs, max_fee_percent)
+            .build_and_sign_all(wallets)
+    }
+
+    /// Like `new_consolidation_transaction`, priced by a node's own fee calculator, leaving
+    /// out the outputs its memory pool already spends and signed with the node's wallets
+    pub fn new_consolidation_transaction_with_context(
+        address: &str,
+        max_inputs: usize,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        context: &NodeContext,
+    ) -> Result<Vec<Transaction>> {
+        let max_fee_percent = context
+            .config()
+            .get_max_consolidation_fee_percent()
+            .unwrap_or(DEFAULT_MAX_CONSOLIDATION_FEE_PERCENT);
+        TransactionBuilder::new(utxo_set)
+            .from(address)
+            .add_output(address, 0)
+            .fee_policy(FeePolicy::SweepAll(priority))
+            .consolidate(max_inputs, max_fee_percent)
+            .with_context(context)
+            .build_and_sign_all(context.wallets())
+    }
+
+    // How many signed inputs fit one transaction under both limits, next to `extra` bytes
+    // of data
+    fn inputs_per_transaction(extra: usize) -> usize {
+        let by_size =
+            MAX_TRANSACTION_SIZE.saturating_sub(SPLIT_OVERHEAD + extra) / SIGNED_INPUT_SIZE;
+        by_size.min(Self::max_inputs()).max(1)
+    }
 
+    // The input limit this node holds transactions to
+    fn max_inputs() -> usize {
+        GLOBAL_CONFIG
+            .get_max_transaction_inputs()
+            .unwrap_or(DEFAULT_MAX_TRANSACTION_INPUTS)
+    }
+
+    // Unsigned inputs spending the selected outputs, keyed by transaction id in hex
+    fn spending_inputs(
+        spender: &Spender,
+        selected: HashMap<String, Vec<usize>>,
+    ) -> Result<Vec<TXInput>> {
         let mut inputs = vec![];
-        for (txid_hex, outs) in valid_outputs {
+        for (txid_hex, outs) in selected {
             let txid = HEXLOWER.decode(txid_hex.as_bytes()).map_err(|e| {
                 BlockchainError::Transaction(format!("Invalid transaction ID: {e}"))
             })?;
@@ -230,134 +773,593 @@ impl Transaction {
                     txid: txid.clone(),
                     vout: out,
                     signature: vec![],
-                    pub_key: wallet.get_public_key().to_vec(),
+                    pub_key: spender.input_key.clone(),
                 };
                 inputs.push(input);
             }
         }
-
-        let mut outputs = vec![TXOutput::new(amount, to)?];
-
-        // Calculate change after deducting amount and fee
-        let change = accumulated - amount - fee_amount;
-        if change > 0 {
-            outputs.push(TXOutput::new(change, from)?); // Change output
-        }
-
-        let mut tx = Transaction {
-            id: vec![],
-            vin: inputs,
-            vout: outputs,
-            fee: fee_amount,
-        };
-
-        tx.id = tx.hash();
-
-        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8())?;
-        Ok(tx)
+        Ok(inputs)
     }
 
-    /// Create a UTXO transaction with a specific fee rate (legacy compatibility)
-    pub fn new_utxo_transaction_with_fee(
+    /// Start a payment from a multisig address; it spends nothing until enough of the
+    /// address's keys have signed it with `add_signature` and it's been `finalize`d
+    ///
+    /// Between signers the transaction travels as the hex of its serialized bytes.
+    pub fn create_unsigned(
         from: &str,
         to: &str,
         amount: u64,
-        fee_rate: u64,
+        priority: FeePriority,
         utxo_set: &UTXOSet,
     ) -> Result<Transaction> {
-        // For backward compatibility, validate the fee rate and use legacy calculation
-        FeeCalculator::validate_fee_rate(fee_rate)?;
-
-        // Estimate transaction size and calculate legacy fee
-        let estimated_size = FeeCalculator::estimate_transaction_size(2, 2); // Rough estimate
-        let legacy_fee = FeeCalculator::calculate_legacy_fee(estimated_size, fee_rate)?;
+        if !matches!(
+            TXOutput::new(1, from)?.get_locking_condition(),
+            LockingCondition::MultiSig { .. }
+        ) {
+            return Err(BlockchainError::InvalidAddress(format!(
+                "Not a multisig address: {from}"
+            )));
+        }
+        let builder = TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, amount);
+        Ok(builder
+            .fee_policy(FeePolicy::Priority(priority))
+            .build_unsigned()?
+            .0)
+    }
 
-        // Create transaction using the new priority system but with calculated legacy fee
-        Self::new_utxo_transaction_with_explicit_fee(from, to, amount, legacy_fee, utxo_set)
+    /// Build a payment from a watch-only address, to be signed where its key is kept
+    ///
+    /// Coins are selected and the fee priced as by `new_utxo_transaction_with_priority`,
+    /// but the inputs carry neither a key nor a signature.
+    pub fn new_unsigned_utxo_transaction(
+        from: &str,
+        to: &str,
+        amount: u64,
+        priority: FeePriority,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<UnsignedTransaction> {
+        Self::check_watch_only(from, wallets)?;
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Priority(priority))
+            .build_unsigned()
     }
 
-    /// Create a UTXO transaction with an explicit fee amount
-    pub fn new_utxo_transaction_with_explicit_fee(
+    /// Like `new_unsigned_utxo_transaction`, paying exactly `fee_amount`
+    pub fn new_unsigned_utxo_transaction_with_explicit_fee(
         from: &str,
         to: &str,
         amount: u64,
         fee_amount: u64,
         utxo_set: &UTXOSet,
-    ) -> Result<Transaction> {
-        // Validate inputs
-        if amount == 0 {
-            return Err(BlockchainError::Transaction(
-                "Amount must be positive".to_string(),
-            ));
+        wallets: &Wallets,
+    ) -> Result<UnsignedTransaction> {
+        Self::check_watch_only(from, wallets)?;
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Explicit(fee_amount.into()))
+            .build_unsigned()
+    }
+
+    // Unsigned payments are for addresses this wallet only watches; one it holds the key
+    // of is paid from with the signing constructors
+    fn check_watch_only(from: &str, wallets: &Wallets) -> Result<()> {
+        if wallets.is_watch_only(from) {
+            return Ok(());
         }
+        Err(BlockchainError::Wallet(
+            if wallets.get_wallet(from).is_some() {
+                format!("Address {from} has its key in this wallet, pay from it with send")
+            } else {
+                format!("Address {from} is not watched by this wallet")
+            },
+        ))
+    }
 
-        if !validate_address(from) {
-            return Err(BlockchainError::InvalidAddress(format!(
-                "Invalid from address: {from}"
-            )));
+    /// Sign every input of a multisig payment that `wallet`'s key may sign for
+    ///
+    /// Each signer signs the same digest whoever signed before, so the order doesn't matter.
+    /// Signing twice with one key changes nothing. Returns how many inputs were signed.
+    pub fn add_signature(&mut self, wallet: &Wallet, blockchain: &Blockchain) -> Result<usize> {
+        let pub_key = wallet.get_public_key();
+        let pub_key_hash = hash_pub_key(pub_key);
+        let spent_outputs = self.spent_outputs(blockchain)?;
+
+        let mut tx_copy = self.trimmed_copy();
+        let mut is_member = false;
+        let mut signed = 0;
+        for (idx, spent) in spent_outputs.iter().enumerate() {
+            let LockingCondition::MultiSig { pub_key_hashes, .. } = spent.get_locking_condition()
+            else {
+                return Err(BlockchainError::Transaction(format!(
+                    "Input {idx} doesn't spend a multisig output"
+                )));
+            };
+            if !pub_key_hashes.contains(&pub_key_hash) {
+                continue;
+            }
+            is_member = true;
+            let signers = self.vin[idx].get_multisig_signatures().ok_or_else(|| {
+                BlockchainError::Transaction(format!("Input {idx} is not a multisig input"))
+            })?;
+            if signers.iter().any(|(key, _)| *key == pub_key) {
+                continue;
+            }
+
+            let digest = Self::signing_digest(&mut tx_copy, idx, spent);
+            let signature = ecdsa_p256_sha256_sign_digest(wallet.get_pkcs8(), &digest)?;
+            self.vin[idx].add_multisig_signature(pub_key, &signature);
+            signed += 1;
         }
 
-        if !validate_address(to) {
-            return Err(BlockchainError::InvalidAddress(format!(
-                "Invalid to address: {to}"
+        if !is_member {
+            return Err(BlockchainError::Wallet(format!(
+                "{} is not one of the keys that can spend this transaction's inputs",
+                wallet.get_address()
             )));
         }
+        Ok(signed)
+    }
 
-        let wallets = Wallets::new();
-        let wallet = wallets.get_wallet(from).ok_or_else(|| {
-            BlockchainError::Wallet(format!("Wallet not found for address: {from}"))
-        })?;
-        let public_key_hash = hash_pub_key(wallet.get_public_key());
-
-        let (accumulated, valid_outputs) =
-            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount);
-
-        // Check if we have enough funds for amount + fee
-        let total_needed = amount + fee_amount;
-        if accumulated < total_needed {
-            return Err(BlockchainError::InsufficientFunds {
-                required: total_needed,
-                available: accumulated,
-            });
+    /// Take over the signatures of another copy of the same multisig payment
+    ///
+    /// Signers who each signed their own copy end up with one transaction this way.
+    pub fn combine(&mut self, other: &Transaction) -> Result<()> {
+        if self.trimmed_copy().hash() != other.trimmed_copy().hash() {
+            return Err(BlockchainError::Transaction(
+                "Partially signed transactions spend or pay differently".to_string(),
+            ));
         }
 
-        let mut inputs = vec![];
-        for (txid_hex, outs) in valid_outputs {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).map_err(|e| {
-                BlockchainError::Transaction(format!("Invalid transaction ID: {e}"))
-            })?;
-            for out in outs {
-                let input = TXInput {
-                    txid: txid.clone(),
-                    vout: out,
-                    signature: vec![],
-                    pub_key: wallet.get_public_key().to_vec(),
-                };
-                inputs.push(input);
+        for (idx, (vin, other_vin)) in self.vin.iter_mut().zip(&other.vin).enumerate() {
+            let not_multisig =
+                || BlockchainError::Transaction(format!("Input {idx} is not a multisig input"));
+            let known: Vec<Vec<u8>> = vin
+                .get_multisig_signatures()
+                .ok_or_else(not_multisig)?
+                .iter()
+                .map(|(key, _)| key.to_vec())
+                .collect();
+            for (key, signature) in other_vin
+                .get_multisig_signatures()
+                .ok_or_else(not_multisig)?
+            {
+                if !known.iter().any(|known_key| known_key.as_slice() == key) {
+                    vin.add_multisig_signature(key, signature);
+                }
             }
         }
+        Ok(())
+    }
 
-        let mut outputs = vec![TXOutput::new(amount, to)?];
+    /// How many more signatures each input of a multisig payment needs
+    pub fn missing_signatures(&self, blockchain: &Blockchain) -> Result<Vec<usize>> {
+        let spent_outputs = self.spent_outputs(blockchain)?;
+        self.vin
+            .iter()
+            .zip(&spent_outputs)
+            .enumerate()
+            .map(|(idx, (vin, spent))| {
+                match (spent.get_locking_condition(), vin.get_multisig_signatures()) {
+                    (LockingCondition::MultiSig { required, .. }, Some(signers)) => {
+                        Ok(usize::from(required).saturating_sub(signers.len()))
+                    }
+                    _ => Err(BlockchainError::Transaction(format!(
+                        "Input {idx} is not a multisig input"
+                    ))),
+                }
+            })
+            .collect()
+    }
 
-        // Calculate change after deducting amount and fee
-        let change = accumulated - amount - fee_amount;
-        if change > 0 {
-            outputs.push(TXOutput::new(change, from)?); // Change output
+    /// Turn a multisig payment with enough signatures into one that can be sent
+    pub fn finalize(mut self, blockchain: &Blockchain) -> Result<Transaction> {
+        let missing: usize = self.missing_signatures(blockchain)?.iter().sum();
+        if missing > 0 {
+            return Err(BlockchainError::Transaction(format!(
+                "Transaction still needs {missing} signatures"
+            )));
+        }
+
+        self.id = self.compute_id();
+
+        if !self.verify_signatures(&self.spent_outputs(blockchain)?) {
+            return Err(BlockchainError::Transaction(
+                "Transaction carries a signature that doesn't verify".to_string(),
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Assemble a transaction spending the given outpoints and paying `outputs`, with
+    /// nothing looked up
+    ///
+    /// The outpoints are taken on faith, so this works without a chain or wallet, e.g. on
+    /// the offline half of a cold-storage setup. The result carries no keys, signatures or
+    /// fee until `sign_raw` or `sign_with_outputs`; it travels as `to_hex`.
+    pub fn new_raw(inputs: &[(Vec<u8>, usize)], outputs: Vec<TXOutput>) -> Result<Transaction> {
+        let mut tx = Transaction {
+            id: vec![],
+            vin: inputs
+                .iter()
+                .map(|(txid, vout)| TXInput::new(txid, *vout))
+                .collect(),
+            vout: outputs,
+            fee: Amount::ZERO,
+        };
+        tx.id = tx.compute_id();
+        tx.check_structure()?;
+        Ok(tx)
+    }
+
+    /// Sign every input with `wallet`'s key, looking up the outputs they spend in
+    /// `blockchain`
+    pub fn sign_raw(&mut self, wallet: &Wallet, blockchain: &Blockchain) -> Result<()> {
+        let spent_outputs = self.spent_outputs(blockchain)?;
+        self.sign_with_outputs(wallet, &spent_outputs)
+    }
+
+    /// Sign every input with `wallet`'s key, given the outputs they spend in input order
+    ///
+    /// Nothing is looked up, so the outputs may come from any snapshot the signer trusts.
+    /// Each has to be locked to the wallet's key. The fee becomes whatever the inputs are
+    /// worth beyond the outputs.
+    pub fn sign_with_outputs(&mut self, wallet: &Wallet, spent_outputs: &[TXOutput]) -> Result<()> {
+        if spent_outputs.len() != self.vin.len() {
+            return Err(BlockchainError::Transaction(format!(
+                "{} inputs but {} outputs they spend",
+                self.vin.len(),
+                spent_outputs.len()
+            )));
+        }
+        let pub_key = wallet.get_public_key();
+        let pub_key_hash = hash_pub_key(pub_key);
+        for (idx, spent) in spent_outputs.iter().enumerate() {
+            let ours = match spent.get_locking_condition() {
+                LockingCondition::PubKeyHash(hash) => hash == pub_key_hash,
+                LockingCondition::PubKey(key) => key == pub_key,
+                LockingCondition::DataCarrier(_) | LockingCondition::MultiSig { .. } => false,
+            };
+            if !ours {
+                return Err(BlockchainError::Wallet(format!(
+                    "Input {idx} spends an output {} can't sign for",
+                    wallet.get_address()
+                )));
+            }
+        }
+
+        let input_value = Amount::checked_sum(spent_outputs.iter().map(TXOutput::get_value))?;
+        let output_value = self.get_output_value()?;
+        let fee = input_value.checked_sub(output_value).map_err(|_| {
+            BlockchainError::Transaction(format!(
+                "Outputs are worth {} satoshis, more than the {} the inputs hold",
+                output_value.to_satoshis(),
+                input_value.to_satoshis()
+            ))
+        })?;
+        Self::check_fee_limit(fee)?;
+
+        for vin in &mut self.vin {
+            vin.pub_key = pub_key.to_vec();
+            vin.signature = vec![];
+        }
+        self.fee = fee;
+        self.id = self.compute_id();
+        self.sign_spending(spent_outputs, wallet.get_pkcs8())
+    }
+
+    /// Check what can be checked without the chain: there are inputs and outputs, no
+    /// outpoint is spent twice, the outputs are well-formed and within the money range,
+    /// and a transaction whose inputs are all signed has the id its contents hash to
+    pub fn check_structure(&self) -> Result<()> {
+        if self.vin.is_empty() || self.vout.is_empty() {
+            return Err(BlockchainError::Transaction(
+                "A transaction needs inputs and outputs".to_string(),
+            ));
+        }
+        self.check_outputs()?;
+        self.check_money_range()?;
+        if self.is_coinbase() {
+            return Ok(());
+        }
+
+        for (idx, vin) in self.vin.iter().enumerate() {
+            if self.vin[..idx]
+                .iter()
+                .any(|other| other.txid == vin.txid && other.vout == vin.vout)
+            {
+                return Err(BlockchainError::Transaction(format!(
+                    "Input {idx} spends {}:{} again",
+                    HEXLOWER.encode(&vin.txid),
+                    vin.vout
+                )));
+            }
+        }
+        // One decoded from an old block may still carry an id from before they were trimmed
+        self.check_id(true)
+    }
+
+    /// Check the limits a node holds every transaction to before spending any effort on
+    /// it: there are inputs and outputs, no more inputs than the configured maximum
+    /// (`DEFAULT_MAX_TRANSACTION_INPUTS` unless the config file sets `node.max_tx_inputs`)
+    /// and no more than `MAX_TRANSACTION_SIZE` bytes serialized
+    ///
+    /// Nothing here needs the chain or a signature check, so pools and peers run it first.
+    pub fn check_limits(&self) -> Result<()> {
+        if self.vin.is_empty() || self.vout.is_empty() {
+            return Err(BlockchainError::Transaction(
+                "A transaction needs inputs and outputs".to_string(),
+            ));
+        }
+        let max_inputs = Self::max_inputs();
+        if self.vin.len() > max_inputs {
+            return Err(BlockchainError::TransactionTooLarge(format!(
+                "it spends {} outputs, more than the {max_inputs} allowed; split the payment \
+                 into several transactions or consolidate the sender's outputs first",
+                self.vin.len()
+            )));
+        }
+        let size = self.serialize()?.len();
+        if size > MAX_TRANSACTION_SIZE {
+            return Err(BlockchainError::TransactionTooLarge(format!(
+                "it takes {size} bytes, more than the {MAX_TRANSACTION_SIZE} allowed; split \
+                 the payment into several transactions or consolidate the sender's outputs \
+                 first"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether this transaction spends more inputs than this node's configured maximum
+    ///
+    /// The maximum is local policy, so a peer relaying such a transaction did nothing wrong.
+    pub fn exceeds_input_limit(&self) -> bool {
+        self.vin.len() > Self::max_inputs()
+    }
+
+    fn spent_outputs(&self, blockchain: &Blockchain) -> Result<Vec<TXOutput>> {
+        self.vin
+            .iter()
+            .map(|vin| {
+                let prev_tx = blockchain
+                    .find_transaction(vin.get_txid())?
+                    .ok_or_else(|| {
+                        BlockchainError::Transaction("Previous transaction not found".to_string())
+                    })?;
+                prev_tx
+                    .vout
+                    .get(vin.vout)
+                    .cloned()
+                    .ok_or_else(|| BlockchainError::Transaction("Invalid output index".to_string()))
+            })
+            .collect()
+    }
+
+    // A fee above the maximum is almost always a mistake that burns coins, so the
+    // constructors refuse it unless the sender insists
+    fn check_fee_limit(fee: Amount) -> Result<()> {
+        if fee > MAX_TRANSACTION_FEE {
+            return Err(BlockchainError::FeeTooHigh {
+                fee: fee.to_satoshis(),
+                max_fee: MAX_TRANSACTION_FEE,
+            });
+        }
+        Ok(())
+    }
+
+    // The change output, right after the `payments` outputs unless the change was too
+    // little to keep, moved to an address from `wallets` before anything is signed. The
+    // new output is as large as the old one, so the fee still fits the size.
+    fn pay_change_to_new_address(
+        &mut self,
+        payments: usize,
+        wallets: &impl WalletProvider,
+    ) -> Result<()> {
+        let Some(change) = self
+            .vout
+            .get(payments)
+            .filter(|output| !output.is_data_carrier())
+        else {
+            return Ok(());
+        };
+        let value = change.get_value();
+        if let Some(address) = wallets.new_change_address()? {
+            self.vout[payments] = TXOutput::new(value, &address)?;
+            self.id = self.compute_id();
+        }
+        Ok(())
+    }
+
+    /// Whether this transaction pays more than `MAX_TRANSACTION_FEE`, which memory pools
+    /// only accept from senders who insisted on it
+    pub fn exceeds_max_fee(&self) -> bool {
+        !self.is_coinbase() && self.fee > MAX_TRANSACTION_FEE
+    }
+
+    /// Check that no output, and not all outputs together, are worth more than `MAX_MONEY`
+    pub fn check_money_range(&self) -> Result<()> {
+        let mut total = Amount::ZERO;
+        for (idx, output) in self.vout.iter().enumerate() {
+            if !output.value.is_money_range() {
+                return Err(BlockchainError::Transaction(format!(
+                    "Output {idx} is worth {} satoshis, more than the {MAX_MONEY} that can exist",
+                    output.value.to_satoshis()
+                )));
+            }
+            total = total
+                .checked_add(output.value)
+                .ok()
+                .filter(|total| total.is_money_range())
+                .ok_or_else(|| {
+                    BlockchainError::Transaction(format!(
+                        "Outputs are worth more than the {MAX_MONEY} satoshis that can exist"
+                    ))
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Create a UTXO transaction with a specific fee rate (legacy compatibility)
+    pub fn new_utxo_transaction_with_fee(
+        from: &str,
+        to: &str,
+        amount: u64,
+        fee_rate: u64,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        // For backward compatibility, validate the fee rate and use legacy calculation
+        FeeCalculator::validate_fee_rate(fee_rate)?;
+
+        // Estimate transaction size and calculate legacy fee
+        let estimated_size = FeeCalculator::estimate_transaction_size(2, 2); // Rough estimate
+        let legacy_fee = FeeCalculator::calculate_legacy_fee(estimated_size, fee_rate)?;
+
+        // Create transaction using the new priority system but with calculated legacy fee
+        Self::new_utxo_transaction_with_explicit_fee(
+            from, to, amount, legacy_fee, utxo_set, wallets,
+        )
+    }
+
+    /// Create a UTXO transaction with an explicit fee amount
+    pub fn new_utxo_transaction_with_explicit_fee(
+        from: &str,
+        to: &str,
+        amount: u64,
+        fee_amount: u64,
+        utxo_set: &UTXOSet,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        TransactionBuilder::new(utxo_set)
+            .from(from)
+            .add_output(to, amount)
+            .fee_policy(FeePolicy::Explicit(fee_amount.into()))
+            .build_and_sign(wallets)
+    }
+
+    // When my transaction is stuck in the mempool, I rebuild it with the same inputs and a
+    // higher fee. The extra fee comes out of the change output, so the recipient still gets
+    // exactly what the original promised them.
+    pub fn bump_fee(
+        &self,
+        new_fee: impl Into<Amount>,
+        blockchain: &Blockchain,
+        wallets: &Wallets,
+    ) -> Result<Transaction> {
+        let new_fee = new_fee.into();
+        if self.is_coinbase() {
+            return Err(BlockchainError::Transaction(
+                "Cannot bump the fee of a coinbase transaction".to_string(),
+            ));
+        }
+        if new_fee <= self.fee {
+            return Err(BlockchainError::Transaction(format!(
+                "New fee {} must be higher than the current fee {}",
+                new_fee.to_satoshis(),
+                self.fee.to_satoshis()
+            )));
         }
+        Self::check_fee_limit(new_fee)?;
+
+        // All inputs I create are signed by a single key, so the first one tells me who I am
+        let sender_pub_key = self.vin[0].get_pub_key().to_vec();
+        if self
+            .vin
+            .iter()
+            .any(|vin| vin.get_pub_key() != sender_pub_key)
+        {
+            return Err(BlockchainError::Transaction(
+                "Cannot bump a transaction whose inputs belong to different keys".to_string(),
+            ));
+        }
+        let sender_pub_key_hash = hash_pub_key(&sender_pub_key);
+        let sender_address = convert_address(ADDRESS_VERSION, &sender_pub_key_hash)?;
+
+        let wallet = wallets.signing_wallet(&sender_address)?;
+
+        // The change output is the one paying back to the sender
+        let change_index = self
+            .vout
+            .iter()
+            .rposition(|out| out.is_locked_with_key(&sender_pub_key_hash))
+            .ok_or_else(|| {
+                BlockchainError::Transaction(
+                    "Transaction has no change output to take the extra fee from".to_string(),
+                )
+            })?;
+
+        let extra_fee = new_fee.checked_sub(self.fee)?;
+        let change_value = self.vout[change_index].get_value();
+        if change_value < extra_fee {
+            // Nothing new is sent, the change only has to cover the extra fee
+            return Err(BlockchainError::insufficient_funds(
+                Amount::ZERO,
+                extra_fee,
+                change_value,
+            ));
+        }
+
+        let mut outputs = self.vout.clone();
+        if change_value == extra_fee {
+            outputs.remove(change_index);
+        } else {
+            outputs[change_index].value = change_value.checked_sub(extra_fee)?;
+        }
+
+        let inputs = self
+            .vin
+            .iter()
+            .map(|vin| TXInput {
+                txid: vin.txid.clone(),
+                vout: vin.vout,
+                signature: vec![],
+                pub_key: sender_pub_key.clone(),
+            })
+            .collect();
 
         let mut tx = Transaction {
             id: vec![],
             vin: inputs,
             vout: outputs,
-            fee: fee_amount,
+            fee: new_fee,
         };
 
-        tx.id = tx.hash();
+        tx.id = tx.compute_id();
 
-        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8())?;
+        tx.sign(blockchain, wallet.get_pkcs8())?;
         Ok(tx)
     }
 
+    // I use this to pick the fee for a replacement: whatever the priority asks for, but never
+    // less than the current fee plus the increment a memory pool needs to accept the swap
+    pub fn replacement_fee(&self, priority: FeePriority, min_increment: u64) -> Amount {
+        let estimated_size =
+            FeeCalculator::estimate_transaction_size(self.vin.len(), self.vout.len());
+        let priority_fee = FeeCalculator::calculate_fee(estimated_size, Some(priority));
+        priority_fee.max(self.fee.saturating_add(min_increment.into()))
+    }
+
+    // I use this to find transactions that try to spend any of the same outputs as another
+    // transaction - the memory pool needs it for replace-by-fee and mining needs it to avoid
+    // packing two conflicting spends into one block
+    pub fn conflicts_with(&self, other: &Transaction) -> bool {
+        if self.is_coinbase() || other.is_coinbase() {
+            return false;
+        }
+
+        self.vin.iter().any(|input| {
+            other
+                .vin
+                .iter()
+                .any(|other_input| input.txid == other_input.txid && input.vout == other_input.vout)
+        })
+    }
+
     fn trimmed_copy(&self) -> Transaction {
         let mut inputs = vec![];
         let mut outputs = vec![];
@@ -377,34 +1379,47 @@ impl Transaction {
     }
 
     fn sign(&mut self, blockchain: &Blockchain, pkcs8: &[u8]) -> Result<()> {
-        let mut tx_copy = self.trimmed_copy();
-
-        for (idx, vin) in self.vin.iter_mut().enumerate() {
-            let prev_tx = blockchain.find_transaction(vin.get_txid()).ok_or_else(|| {
-                BlockchainError::Transaction("Previous transaction not found".to_string())
-            })?;
-
-            if vin.vout >= prev_tx.vout.len() {
-                return Err(BlockchainError::Transaction(
-                    "Invalid output index".to_string(),
-                ));
-            }
+        let spent_outputs = self.spent_outputs(blockchain)?;
+        self.sign_spending(&spent_outputs, pkcs8)
+    }
 
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[idx].pub_key = vec![];
+    fn sign_spending(&mut self, spent_outputs: &[TXOutput], pkcs8: &[u8]) -> Result<()> {
+        self.sign_inputs(spent_outputs, |_| pkcs8)
+    }
 
-            let signature = ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id())?;
-            vin.signature = signature;
+    // I sign every input against the output it spends, which the caller looked up already,
+    // in input order, with the key `key_for` gives for the input's index. The digests leave
+    // out every key, so inputs of different addresses can share a transaction.
+    fn sign_inputs<'k>(
+        &mut self,
+        spent_outputs: &[TXOutput],
+        key_for: impl Fn(usize) -> &'k [u8],
+    ) -> Result<()> {
+        let mut tx_copy = self.trimmed_copy();
+        for (idx, (vin, spent)) in self.vin.iter_mut().zip(spent_outputs).enumerate() {
+            let digest = Self::signing_digest(&mut tx_copy, idx, spent);
+            vin.signature = ecdsa_p256_sha256_sign_digest(key_for(idx), &digest)?;
         }
         Ok(())
     }
 
+    // What input `idx` signs: the trimmed copy with the lock of the output it spends in
+    // place of its key. Multisig signers all sign this same digest.
+    fn signing_digest(tx_copy: &mut Transaction, idx: usize, spent: &TXOutput) -> Vec<u8> {
+        tx_copy.vin[idx].signature = vec![];
+        tx_copy.vin[idx].pub_key = spent.pub_key_hash.clone();
+        tx_copy.id = tx_copy.hash();
+        tx_copy.vin[idx].pub_key = vec![];
+        tx_copy.id.clone()
+    }
+
+    /// Check the transaction against `blockchain` as a new one: its id is the one its
+    /// contents give it, it spends unspent outputs, it balances and its signatures verify
     pub fn verify(&self, blockchain: &Blockchain) -> bool {
         // If this is a coinbase transaction, I need to verify it differently
         if self.is_coinbase() {
-            return self.verify_coinbase();
+            return self.verify_spending(&[], true, false)
+                && self.verify_coinbase_in_chain(blockchain);
         }
 
         // Critical: I need to check that none of my inputs have already been spent
@@ -413,47 +1428,172 @@ impl Transaction {
             log::error!("Transaction input validation failed: {e}");
             return false;
         }
+        let spent_outputs = match self.spent_outputs(blockchain) {
+            Ok(spent_outputs) => spent_outputs,
+            Err(e) => {
+                log::error!("Previous transaction can't be looked up during verification: {e}");
+                return false;
+            }
+        };
+        self.verify_spending(&spent_outputs, true, false)
+    }
+
+    // Everything `verify` checks once the outputs the inputs spend are looked up, in
+    // input order: nothing here reads the chain, so a block's transactions can go through
+    // it on several threads. A coinbase spends nothing and its reward is the caller's to
+    // check against its block.
+    pub(crate) fn verify_spending(
+        &self,
+        spent_outputs: &[TXOutput],
+        check_signatures: bool,
+        allow_legacy_id: bool,
+    ) -> bool {
+        // A peer can't pass a transaction off under another one's id
+        if let Err(e) = self.check_id(allow_legacy_id) {
+            log::error!("{e}");
+            return false;
+        }
+        if !self.verify_outputs() {
+            return false;
+        }
+        if self.is_coinbase() {
+            return self.verify_coinbase();
+        }
 
         // This is the most critical check - I need to make sure no value is created or destroyed
         // The fundamental rule of blockchain: what goes in must equal what goes out plus fees
-        if !self.verify_balance(blockchain) {
+        if !self.verify_balance(spent_outputs) {
             log::error!(
                 "Transaction balance validation failed - this is a critical blockchain violation"
             );
             return false;
         }
 
+        if !check_signatures {
+            return true;
+        }
+
         // Now I verify the cryptographic signatures to make sure the spender owns the inputs
+        self.verify_signatures(spent_outputs)
+    }
+
+    // I check every input's signature against the output it spends, which the caller
+    // looked up already, in input order
+    pub(crate) fn verify_signatures(&self, spent_outputs: &[TXOutput]) -> bool {
+        if spent_outputs.len() != self.vin.len() {
+            log::error!("Every input needs the output it spends to verify its signature");
+            return false;
+        }
+
         let mut tx_copy = self.trimmed_copy();
-        for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx = match blockchain.find_transaction(vin.get_txid()) {
-                Some(tx) => tx,
-                None => {
-                    log::error!("Previous transaction not found during verification");
-                    return false;
-                }
+        for (idx, (vin, spent)) in self.vin.iter().zip(spent_outputs).enumerate() {
+            let digest = Self::signing_digest(&mut tx_copy, idx, spent);
+            let verifies = |pub_key: &[u8], signature: &[u8]| {
+                ecdsa_p256_sha256_sign_verify(pub_key, signature, &digest)
             };
 
-            if vin.vout >= prev_tx.vout.len() {
-                log::error!("Invalid output index during verification");
-                return false;
+            // An address lock only says which key hash may spend, so the key the input
+            // brings has to match it; a P2PK lock names the key itself
+            match spent.get_locking_condition() {
+                LockingCondition::PubKeyHash(pub_key_hash) => {
+                    if hash_pub_key(vin.pub_key.as_slice()) != pub_key_hash {
+                        log::error!(
+                            "Input {idx} brings a key that doesn't match the output's address"
+                        );
+                        return false;
+                    }
+                    if !verifies(&vin.pub_key, &vin.signature) {
+                        return false;
+                    }
+                }
+                LockingCondition::PubKey(pub_key) => {
+                    if !verifies(&pub_key, &vin.signature) {
+                        return false;
+                    }
+                }
+                LockingCondition::DataCarrier(_) => {
+                    log::error!("Input {idx} spends a data output, which nobody can spend");
+                    return false;
+                }
+                // Every key the input brings has to be a different member that signed, and
+                // there have to be enough of them
+                LockingCondition::MultiSig {
+                    required,
+                    pub_key_hashes,
+                } => {
+                    let Some(signatures) = vin.get_multisig_signatures() else {
+                        log::error!("Input {idx} spends a multisig output without its signatures");
+                        return false;
+                    };
+                    let mut signers: Vec<Vec<u8>> = Vec::with_capacity(signatures.len());
+                    for (pub_key, signature) in signatures {
+                        let pub_key_hash = hash_pub_key(pub_key);
+                        if !pub_key_hashes.contains(&pub_key_hash)
+                            || signers.contains(&pub_key_hash)
+                        {
+                            log::error!(
+                                "Input {idx} brings a key that isn't a distinct member of the multisig output"
+                            );
+                            return false;
+                        }
+                        if !verifies(pub_key, signature) {
+                            return false;
+                        }
+                        signers.push(pub_key_hash);
+                    }
+                    if signers.len() < usize::from(required) {
+                        log::error!(
+                            "Input {idx} has {} of the {required} signatures its output needs",
+                            signers.len()
+                        );
+                        return false;
+                    }
+                }
             }
+        }
+        true
+    }
 
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[idx].pub_key = vec![];
+    // Only data outputs may be worth nothing, and they go after every spendable output:
+    // the chainstate leaves them out, and it still locates the other outputs by position
+    fn verify_outputs(&self) -> bool {
+        match self.check_outputs() {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("{e}");
+                false
+            }
+        }
+    }
 
-            let verify = ecdsa_p256_sha256_sign_verify(
-                vin.pub_key.as_slice(),
-                vin.signature.as_slice(),
-                tx_copy.get_id(),
-            );
-            if !verify {
-                return false;
+    fn check_outputs(&self) -> Result<()> {
+        let invalid = |message: String| Err(BlockchainError::Transaction(message));
+        let mut seen_data = false;
+        for (idx, vout) in self.vout.iter().enumerate() {
+            match vout.get_data() {
+                Some(data) => {
+                    if TXOutput::check_data(&data).is_err() {
+                        return invalid(format!(
+                            "Output {idx} carries {} bytes of data",
+                            data.len()
+                        ));
+                    }
+                    seen_data = true;
+                }
+                None if vout.get_value() == Amount::ZERO => {
+                    return invalid(format!(
+                        "Output {idx} is worth nothing but isn't a data output"
+                    ));
+                }
+                None if seen_data => {
+                    return invalid(format!(
+                        "Output {idx} can be spent but comes after a data output"
+                    ));
+                }
+                None => {}
             }
         }
-        true
+        Ok(())
     }
 
     // I need to verify coinbase transactions have the right structure
@@ -484,65 +1624,91 @@ impl Transaction {
         true
     }
 
-    // This is THE most important validation in my entire blockchain
-    // If I get this wrong, people can create money out of thin air
-    fn verify_balance(&self, blockchain: &Blockchain) -> bool {
-        let mut input_value = 0u64;
-        let mut output_value = 0u64;
-
-        // I need to calculate how much value is coming into this transaction
-        for vin in &self.vin {
-            // I look up the previous transaction to see how much this input is worth
-            let prev_tx = match blockchain.find_transaction(vin.get_txid()) {
-                Some(tx) => tx,
-                None => {
-                    log::error!("Previous transaction not found - this input doesn't exist!");
-                    return false;
-                }
-            };
-
-            // I make sure the output index is valid
-            if vin.vout >= prev_tx.vout.len() {
-                log::error!("Invalid output index - trying to spend an output that doesn't exist");
+    // A coinbase is only worth the subsidy for its block's height plus the fees the rest of
+    // that block pays. One the chain already holds I check against its own block; one on its
+    // way into a new block gets the same check against that block before the block is taken
+    pub(crate) fn verify_coinbase_in_chain(&self, blockchain: &Blockchain) -> bool {
+        let block = blockchain
+            .find_transaction_block(&self.id)
+            .and_then(|hash| match hash {
+                Some(hash) => blockchain.get_block(&hash),
+                None => Ok(None),
+            });
+        let block = match block {
+            Ok(Some(block)) => block,
+            Ok(None) => return true,
+            Err(e) => {
+                log::error!(
+                    "Could not look up the block of coinbase {}: {e}",
+                    HEXLOWER.encode(&self.id)
+                );
                 return false;
             }
+        };
+        // The genesis block pays whatever its configuration allocates
+        if block.get_height() == 0 {
+            return true;
+        }
+        let expected =
+            FeeCalculator::calculate_coinbase_reward(block.get_total_fees(), block.get_height());
+        self.verify_coinbase_reward(expected)
+    }
 
-            // I add up the value from this input
-            let prev_output = &prev_tx.vout[vin.vout];
-            input_value = match input_value.checked_add(prev_output.get_value()) {
-                Some(sum) => sum,
-                None => {
-                    log::error!("Input value overflow - someone is trying to break my math!");
-                    return false;
-                }
-            };
+    /// Whether this coinbase pays out exactly `expected_reward`
+    pub fn verify_coinbase_reward(&self, expected_reward: Amount) -> bool {
+        match self.get_output_value() {
+            Ok(value) if value == expected_reward => true,
+            Ok(value) => {
+                log::error!(
+                    "Invalid coinbase reward: {} (expected: {})",
+                    value.to_satoshis(),
+                    expected_reward.to_satoshis()
+                );
+                false
+            }
+            Err(e) => {
+                log::error!("Coinbase outputs overflow: {e}");
+                false
+            }
         }
+    }
 
-        // Now I calculate how much value is going out of this transaction
-        for vout in &self.vout {
-            output_value = match output_value.checked_add(vout.get_value()) {
-                Some(sum) => sum,
-                None => {
-                    log::error!("Output value overflow - the numbers are too big!");
-                    return false;
-                }
-            };
+    // This is THE most important validation in my entire blockchain
+    // If I get this wrong, people can create money out of thin air
+    fn verify_balance(&self, spent_outputs: &[TXOutput]) -> bool {
+        // Every input is worth the output it spends
+        if spent_outputs.len() != self.vin.len() {
+            log::error!("Every input needs the output it spends to be valued");
+            return false;
         }
+        let input_values = spent_outputs.iter().map(TXOutput::get_value);
 
-        // Here's the fundamental rule: inputs must equal outputs plus fees
-        // If this doesn't balance, someone is trying to create or destroy value
-        let total_spent = match output_value.checked_add(self.fee) {
-            Some(sum) => sum,
-            None => {
-                log::error!("Total spent overflow - the math doesn't work");
+        // Amount refuses any sum that doesn't fit, so nobody can wrap their way into balance
+        let sums = Amount::checked_sum(input_values).and_then(|input_value| {
+            let output_value = self.get_output_value()?;
+            Ok((
+                input_value,
+                output_value,
+                output_value.checked_add(self.fee)?,
+            ))
+        });
+        let (input_value, output_value, total_spent) = match sums {
+            Ok(sums) => sums,
+            Err(e) => {
+                log::error!("The values don't add up - someone is trying to break my math: {e}");
                 return false;
             }
         };
 
+        // Here's the fundamental rule: inputs must equal outputs plus fees
+        // If this doesn't balance, someone is trying to create or destroy value
         if input_value != total_spent {
             log::error!(
                 "CRITICAL: Transaction balance violation! inputs={}, outputs={}, fees={}, total_spent={}",
-                input_value, output_value, self.fee, total_spent
+                input_value.to_satoshis(),
+                output_value.to_satoshis(),
+                self.fee.to_satoshis(),
+                total_spent.to_satoshis()
             );
             return false;
         }
@@ -555,15 +1721,53 @@ impl Transaction {
         self.vin.len() == 1 && self.vin[0].pub_key.is_empty()
     }
 
-    fn hash(&mut self) -> Vec<u8> {
+    /// The id this transaction's contents give it: the SHA-256 of its trimmed form, with
+    /// every input cut down to the outpoint it spends
+    ///
+    /// Keys and signatures are left out, so the id is settled before anything is signed
+    /// and anyone can recompute it from the signed transaction; the signatures commit to
+    /// everything the id covers. A coinbase is hashed whole, as the data in its input is
+    /// what tells apart coinbases paying the same reward to the same address.
+    pub fn compute_id(&self) -> Vec<u8> {
+        if self.is_coinbase() {
+            return self.hash();
+        }
+        self.trimmed_copy().hash()
+    }
+
+    // The id transactions were given before it left the keys out: the hash of the
+    // transaction with its signatures blank. Blocks stored back then still carry them.
+    fn legacy_id(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        for vin in &mut unsigned.vin {
+            vin.signature = vec![];
+        }
+        unsigned.hash()
+    }
+
+    /// Check that the id is the one `compute_id` gives, or with `allow_legacy` the one a
+    /// block stored before ids left the keys out would have recorded
+    pub fn check_id(&self, allow_legacy: bool) -> Result<()> {
+        if self.id == self.compute_id()
+            || (allow_legacy && !self.is_coinbase() && self.id == self.legacy_id())
+        {
+            return Ok(());
+        }
+        Err(BlockchainError::Transaction(format!(
+            "The id {} doesn't match the transaction's contents",
+            HEXLOWER.encode(&self.id)
+        )))
+    }
+
+    fn hash(&self) -> Vec<u8> {
         let tx_copy = Transaction {
             id: vec![],
             vin: self.vin.clone(),
             vout: self.vout.clone(),
             fee: self.fee,
         };
-        // Use proper error handling instead of expect
-        match tx_copy.serialize() {
+        // The txid covers the bare layout, so it doesn't depend on the format version
+        match serialize(&tx_copy) {
             Ok(serialized) => sha256_digest(&serialized),
             Err(_) => {
                 // Fallback hash for serialization errors
@@ -589,40 +1793,97 @@ impl Transaction {
         self.vout.as_slice()
     }
 
-    pub fn get_fee(&self) -> u64 {
+    pub fn get_fee(&self) -> Amount {
         self.fee
     }
 
-    pub fn set_fee(&mut self, fee: u64) {
-        self.fee = fee;
+    pub fn set_fee(&mut self, fee: impl Into<Amount>) {
+        self.fee = fee.into();
+    }
+
+    /// The same transaction paying `fee` instead, under the id that gives it (for testing
+    /// only)
+    #[cfg(test)]
+    pub fn with_fee(mut self, fee: impl Into<Amount>) -> Transaction {
+        self.fee = fee.into();
+        self.id = self.compute_id();
+        self
+    }
+
+    /// Overwrite every input signature, invalidating them (for testing only)
+    #[cfg(test)]
+    pub fn corrupt_signatures(&mut self) {
+        for vin in &mut self.vin {
+            vin.signature = vec![0; vin.signature.len().max(1)];
+        }
+    }
+
+    /// An unsigned spend of a made-up output paying `to`, to fill memory pools that never
+    /// check inputs (for testing only)
+    #[cfg(test)]
+    pub fn new_placeholder_tx(to: &str) -> Transaction {
+        let tx_input = TXInput {
+            pub_key: vec![1; 33],
+            ..TXInput::new(Uuid::new_v4().as_bytes(), 0)
+        };
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![tx_input],
+            vout: vec![TXOutput::new(SUBSIDY, to).unwrap()],
+            fee: Amount::ZERO,
+        };
+        tx.id = tx.compute_id();
+        tx
     }
 
     /// Calculate the fee rate (satoshis per byte) for this transaction
     pub fn calculate_fee_rate(&self) -> Result<u64> {
         let size = self.serialize()?.len();
-        crate::core::FeeCalculator::calculate_fee_rate(self.fee, size)
+        crate::core::FeeCalculator::calculate_fee_rate(self.fee.to_satoshis(), size)
     }
 
+    /// The transaction in the current format version, see `utils::serialization`
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        serialize(self)
+        serialize_versioned(self)
+    }
+
+    /// The transaction in format `version`, for peers that only read older formats
+    pub fn serialize_as(&self, version: u8) -> Result<Vec<u8>> {
+        serialize_as(self, version)
     }
 
+    /// Read a transaction in any format up to `FORMAT_VERSION`
     pub fn deserialize(bytes: &[u8]) -> Result<Transaction> {
-        deserialize(bytes)
+        deserialize_versioned(bytes)
+    }
+
+    /// The serialized transaction as lowercase hex, the way raw transactions travel
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(HEXLOWER.encode(&self.serialize()?))
+    }
+
+    /// Read a transaction from `to_hex`, in either case and with surrounding whitespace
+    pub fn from_hex(hex: &str) -> Result<Transaction> {
+        let bytes = HEXLOWER
+            .decode(hex.trim().to_lowercase().as_bytes())
+            .map_err(|e| BlockchainError::Serialization(format!("Invalid transaction hex: {e}")))?;
+        Self::deserialize(&bytes)
     }
 
     // I want to be able to get the total input value for analysis and debugging
-    pub fn get_input_value(&self, blockchain: &Blockchain) -> Result<u64> {
+    pub fn get_input_value(&self, blockchain: &Blockchain) -> Result<Amount> {
         if self.is_coinbase() {
-            return Ok(0); // Coinbase transactions don't have real inputs
+            return Ok(Amount::ZERO); // Coinbase transactions don't have real inputs
         }
 
-        let mut total = 0u64;
+        let mut total = Amount::ZERO;
         for vin in &self.vin {
             // I look up each previous transaction to get the input values
-            let prev_tx = blockchain.find_transaction(vin.get_txid()).ok_or_else(|| {
-                BlockchainError::Transaction("Previous transaction not found".to_string())
-            })?;
+            let prev_tx = blockchain
+                .find_transaction(vin.get_txid())?
+                .ok_or_else(|| {
+                    BlockchainError::Transaction("Previous transaction not found".to_string())
+                })?;
 
             if vin.vout >= prev_tx.vout.len() {
                 return Err(BlockchainError::Transaction(
@@ -630,23 +1891,14 @@ impl Transaction {
                 ));
             }
 
-            let prev_output = &prev_tx.vout[vin.vout];
-            total = total
-                .checked_add(prev_output.get_value())
-                .ok_or_else(|| BlockchainError::Transaction("Input value overflow".to_string()))?;
+            total = total.checked_add(prev_tx.vout[vin.vout].get_value())?;
         }
         Ok(total)
     }
 
     // I want to be able to get the total output value easily
-    pub fn get_output_value(&self) -> Result<u64> {
-        let mut total = 0u64;
-        for vout in &self.vout {
-            total = total
-                .checked_add(vout.get_value())
-                .ok_or_else(|| BlockchainError::Transaction("Output value overflow".to_string()))?;
-        }
-        Ok(total)
+    pub fn get_output_value(&self) -> Result<Amount> {
+        Amount::checked_sum(self.vout.iter().map(TXOutput::get_value))
     }
 
     // I want a detailed balance verification that gives me specific error messages
@@ -657,17 +1909,2744 @@ impl Transaction {
 
         let input_value = self.get_input_value(blockchain)?;
         let output_value = self.get_output_value()?;
-        let total_spent = output_value
-            .checked_add(self.fee)
-            .ok_or_else(|| BlockchainError::Transaction("Total spent overflow".to_string()))?;
+        let total_spent = output_value.checked_add(self.fee)?;
 
         if input_value != total_spent {
             return Err(BlockchainError::Transaction(format!(
                 "Transaction balance violation: inputs={}, outputs={}, fees={}, total_spent={}",
-                input_value, output_value, self.fee, total_spent
+                input_value.to_satoshis(),
+                output_value.to_satoshis(),
+                self.fee.to_satoshis(),
+                total_spent.to_satoshis()
             )));
         }
 
         Ok(true)
     }
 }
+
+/// A payment nobody has signed yet, as built for a watch-only address
+///
+/// It can't be sent or mined as it is. Its `to_hex` goes to the machine holding the key,
+/// where `signrawtransaction` signs it, or `sign` does here once the key is at hand.
+#[derive(Debug, Clone)]
+pub struct UnsignedTransaction(Transaction);
+
+impl UnsignedTransaction {
+    /// The transaction as it stands, with empty keys and signatures
+    pub fn transaction(&self) -> &Transaction {
+        &self.0
+    }
+
+    pub fn get_fee(&self) -> Amount {
+        self.0.fee
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        self.0.to_hex()
+    }
+
+    /// Sign every input with `wallet`'s key, looking up the outputs they spend in
+    /// `blockchain`
+    pub fn sign(self, wallet: &Wallet, blockchain: &Blockchain) -> Result<Transaction> {
+        let mut tx = self.0;
+        tx.sign_raw(wallet, blockchain)?;
+        Ok(tx)
+    }
+}
+
+/// How a `TransactionBuilder` prices the fee
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeePolicy {
+    /// By the estimated size, at this priority
+    Priority(FeePriority),
+    /// Exactly this fee
+    Explicit(Amount),
+    /// Spend every coin of the sender into the one output, less a fee priced by size at
+    /// this priority
+    SweepAll(FeePriority),
+    /// Like `SweepAll`, paying exactly this fee
+    SweepAllWithFee(Amount),
+}
+
+impl FeePolicy {
+    fn is_sweep(&self) -> bool {
+        matches!(self, FeePolicy::SweepAll(_) | FeePolicy::SweepAllWithFee(_))
+    }
+}
+
+/// Where `TransactionBuilder::build_and_sign` finds the key of the address it spends from
+///
+/// The wallet comes back as a copy, so a provider behind a lock, like `SharedWallets`,
+/// doesn't hold it while the payment is built.
+pub trait WalletProvider {
+    fn signing_wallet(&self, address: &str) -> Result<Wallet>;
+
+    /// A new address of this wallet to take a payment's change, `None` to send the change
+    /// back to the first sender
+    fn new_change_address(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+impl WalletProvider for Wallets {
+    fn signing_wallet(&self, address: &str) -> Result<Wallet> {
+        Wallets::signing_wallet(self, address).cloned()
+    }
+
+    /// One when the wallet file's policy asks for change addresses
+    ///
+    /// It is saved through a handle of its own, as signing only needs the senders' keys.
+    fn new_change_address(&self) -> Result<Option<String>> {
+        if !self.uses_change_addresses() {
+            return Ok(None);
+        }
+        self.reopen().create_change_address().map(Some)
+    }
+}
+
+/// A single wallet only signs for its own address
+impl WalletProvider for Wallet {
+    fn signing_wallet(&self, address: &str) -> Result<Wallet> {
+        if self.get_address() == address {
+            Ok(self.clone())
+        } else {
+            Err(BlockchainError::Wallet(format!(
+                "Wallet not found for address: {address}"
+            )))
+        }
+    }
+}
+
+// Spends the coins locked to `address` without its key: a multisig address's inputs carry
+// its tag until they are finalized, any other's an empty key until they are signed
+fn address_spender(address: &str) -> Result<Spender<'_>> {
+    let lock = TXOutput::new(1, address)?;
+    let input_key = match lock.get_locking_condition() {
+        LockingCondition::MultiSig { .. } => vec![MULTISIG_LOCK_TAG],
+        _ => vec![],
+    };
+    Ok(Spender {
+        address,
+        lock: lock.get_pub_key_hash(),
+        input_key,
+    })
+}
+
+/// What `TransactionBuilder::build_unsigned_preview` expects a payment to look like
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionPreview {
+    pub input_count: usize,
+    /// Change and memo outputs included
+    pub output_count: usize,
+    /// Estimated size in bytes once signed, the one the fee is priced by
+    pub size: usize,
+    pub fee: Amount,
+    /// What the payment's outputs send; for a sweep, what is left after the fee
+    pub amount: Amount,
+    /// Every spendable coin of the senders
+    pub balance: Amount,
+    /// Whether the balance covers the amount and the fee
+    pub sufficient: bool,
+}
+
+impl TransactionPreview {
+    /// What leaves the senders' balance, the amount and the fee
+    pub fn total_debit(&self) -> Amount {
+        self.amount.saturating_add(self.fee)
+    }
+}
+
+// An output the builder was asked for, checked once it builds
+enum Payment {
+    Address { address: String, amount: Amount },
+    PubKey { pub_key: Vec<u8>, amount: Amount },
+    Output(TXOutput),
+}
+
+impl Payment {
+    fn amount(&self) -> Amount {
+        match self {
+            Payment::Address { amount, .. } | Payment::PubKey { amount, .. } => *amount,
+            Payment::Output(output) => output.value,
+        }
+    }
+}
+
+/// Puts together a payment from one address, or from several that sign together
+///
+/// The payment constructors on `Transaction` are shorthands for this. It selects the
+/// senders' coins for what the outputs and the fee need, in the order `coin_selection` asks
+/// for if it's set, re-pricing the fee as inputs are added, sends the rest back as change
+/// unless that's too little to pay for its own output,
+/// and refuses fees above `MAX_TRANSACTION_FEE` unless `allow_high_fee` is set. Unless
+/// `change_to` names where it goes, `build_and_sign` pays the change to a new address when
+/// the wallet provider hands one out, see `WalletProvider::new_change_address`. Mistakes
+/// such as a missing sender, no outputs or two different fee policies are reported when it
+/// builds.
+pub struct TransactionBuilder<'a> {
+    utxo_set: &'a UTXOSet,
+    from: Vec<String>,
+    change_address: Option<String>,
+    payments: Vec<Payment>,
+    memo: Option<Vec<u8>>,
+    fee_policies: Vec<FeePolicy>,
+    context: Option<&'a NodeContext>,
+    allow_high_fee: bool,
+    coin_selection: Option<SelectionStrategy>,
+    consolidation: Option<Consolidation>,
+    // Prices by any function of the size instead of a policy, for tests
+    fee_for_size: Option<Box<dyn Fn(usize) -> Amount + 'a>>,
+}
+
+// Which coins a sweep back to its sender merges, see `TransactionBuilder::consolidate`
+#[derive(Clone, Copy)]
+struct Consolidation {
+    max_inputs: usize,
+    max_fee_percent: u64,
+}
+
+// Coins picked from one address: output indices keyed by transaction id in hex
+type Selection = HashMap<String, Vec<usize>>;
+
+// What a builder checked before touching any coins
+struct PaymentPlan {
+    policy: FeePolicy,
+    outputs: Vec<TXOutput>,
+    memo_output: Option<TXOutput>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub fn new(utxo_set: &'a UTXOSet) -> TransactionBuilder<'a> {
+        TransactionBuilder {
+            utxo_set,
+            from: Vec::new(),
+            change_address: None,
+            payments: Vec::new(),
+            memo: None,
+            fee_policies: Vec::new(),
+            context: None,
+            allow_high_fee: false,
+            coin_selection: None,
+            consolidation: None,
+            fee_for_size: None,
+        }
+    }
+
+    /// Spend the coins of `address`, which also gets the change
+    pub fn from(mut self, address: &str) -> Self {
+        self.from = vec![address.to_string()];
+        self
+    }
+
+    /// Spend the coins of all of `addresses`, taken in order until the payment is covered
+    ///
+    /// The change goes to the first one unless `change_to` says otherwise. Only
+    /// `build_and_sign` takes more than one sender.
+    pub fn from_addresses<S: AsRef<str>>(mut self, addresses: impl IntoIterator<Item = S>) -> Self {
+        self.from = addresses
+            .into_iter()
+            .map(|address| address.as_ref().to_string())
+            .collect();
+        self
+    }
+
+    /// Send the change to `address` instead of the first sender
+    pub fn change_to(mut self, address: &str) -> Self {
+        self.change_address = Some(address.to_string());
+        self
+    }
+
+    /// Pay `amount` to `address`; a sweep's one output is added with an amount of 0
+    pub fn add_output(mut self, address: &str, amount: impl Into<Amount>) -> Self {
+        self.payments.push(Payment::Address {
+            address: address.to_string(),
+            amount: amount.into(),
+        });
+        self
+    }
+
+    /// Pay `amount` straight to a public key (P2PK)
+    pub fn add_output_to_pub_key(mut self, pub_key: &[u8], amount: impl Into<Amount>) -> Self {
+        self.payments.push(Payment::PubKey {
+            pub_key: pub_key.to_vec(),
+            amount: amount.into(),
+        });
+        self
+    }
+
+    /// Also record `data` on chain in a data output after the others
+    pub fn memo(mut self, data: &[u8]) -> Self {
+        self.memo = Some(data.to_vec());
+        self
+    }
+
+    /// Price the fee by `policy`, `FeePolicy::Priority(FeePriority::Normal)` unless set
+    pub fn fee_policy(mut self, policy: FeePolicy) -> Self {
+        self.fee_policies.push(policy);
+        self
+    }
+
+    /// Price by priority with `context`'s fee calculator and memory pool rather than the
+    /// global ones
+    pub fn with_context(mut self, context: &'a NodeContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Pay the fee even when it's above `MAX_TRANSACTION_FEE`
+    pub fn allow_high_fee(mut self, allow: bool) -> Self {
+        self.allow_high_fee = allow;
+        self
+    }
+
+    /// Pick the coins by `strategy` rather than in chainstate order, see `core::coin_selection`
+    ///
+    /// A sweep spends every coin anyway, so it ignores this.
+    pub fn coin_selection(mut self, strategy: SelectionStrategy) -> Self {
+        self.coin_selection = Some(strategy);
+        self
+    }
+
+    /// Make the sweep a consolidation back to the sender, merging only its `max_inputs`
+    /// smallest coins and refused if a transaction's fee takes more than `max_fee_percent`
+    /// of what it merges
+    pub fn consolidate(mut self, max_inputs: usize, max_fee_percent: u64) -> Self {
+        self.consolidation = Some(Consolidation {
+            max_inputs,
+            max_fee_percent,
+        });
+        self
+    }
+
+    /// Pay an output built elsewhere, e.g. one locked to a multisig policy
+    pub fn add_txoutput(mut self, output: TXOutput) -> Self {
+        self.payments.push(Payment::Output(output));
+        self
+    }
+
+    #[cfg(test)]
+    fn with_fee_for_size(mut self, fee_for_size: impl Fn(usize) -> Amount + 'a) -> Self {
+        self.fee_for_size = Some(Box::new(fee_for_size));
+        self
+    }
+
+    /// Build the payment and sign it with the senders' keys from `wallets`
+    ///
+    /// A sweep too large for one transaction is refused; `build_and_sign_all` splits it.
+    pub fn build_and_sign(self, wallets: &impl WalletProvider) -> Result<Transaction> {
+        let mut transactions = self.build_and_sign_all(wallets)?;
+        if transactions.len() > 1 {
+            return Err(BlockchainError::TransactionTooLarge(format!(
+                "sweeping every coin takes {} transactions, build it with build_and_sign_all",
+                transactions.len()
+            )));
+        }
+        Ok(transactions.remove(0))
+    }
+
+    /// Like `build_and_sign`, but a sweep spending more coins than fit one transaction is
+    /// split evenly across as few as fit, each paying everything it spends less its fee
+    pub fn build_and_sign_all(self, wallets: &impl WalletProvider) -> Result<Vec<Transaction>> {
+        let senders = self.senders()?;
+        let change = self.change(&senders)?;
+        let plan = self.plan(&senders)?;
+        let spenders = senders
+            .iter()
+            .map(|from| {
+                let wallet = wallets.signing_wallet(from)?;
+                Ok(Spender {
+                    address: from,
+                    lock: hash_pub_key(wallet.get_public_key()),
+                    input_key: wallet.get_public_key().to_vec(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let payments = plan.outputs.len();
+        let mut transactions = self.fund(&spenders, change, plan)?;
+        if self.change_address.is_none() {
+            for tx in &mut transactions {
+                tx.pay_change_to_new_address(payments, wallets)?;
+            }
+        }
+
+        for tx in &mut transactions {
+            // Each input is signed by the wallet of the address whose output it spends
+            let spent_outputs = tx.spent_outputs(self.utxo_set.get_blockchain())?;
+            let keys = spent_outputs
+                .iter()
+                .map(|spent| wallets.signing_wallet(&spent.get_address()))
+                .collect::<Result<Vec<_>>>()?;
+            tx.sign_inputs(&spent_outputs, |idx| keys[idx].get_pkcs8())?;
+            // Signatures are most of an input's bytes, so only now is the size final
+            tx.check_limits()?;
+        }
+        Ok(transactions)
+    }
+
+    /// Build the payment without signing it, for a sender whose key is elsewhere or a
+    /// multisig address whose keys sign one by one
+    pub fn build_unsigned(self) -> Result<UnsignedTransaction> {
+        let senders = self.senders()?;
+        let [from] = senders[..] else {
+            return Err(BlockchainError::Transaction(format!(
+                "An unsigned payment spends from one address, but {} were given",
+                senders.len()
+            )));
+        };
+        let change = self.change(&senders)?;
+        let plan = self.plan(&senders)?;
+        let mut transactions = self.fund(&[address_spender(from)?], change, plan)?;
+        if transactions.len() > 1 {
+            return Err(BlockchainError::TransactionTooLarge(format!(
+                "sweeping every coin takes {} transactions, but an unsigned payment is one",
+                transactions.len()
+            )));
+        }
+        Ok(UnsignedTransaction(transactions.remove(0)))
+    }
+
+    /// Select coins and price the fee as `build_and_sign_all` would, without any key
+    ///
+    /// Nothing is signed and no wallet is read, so any sender works, a watch-only one too.
+    /// When the senders' coins don't cover the payment and its fee, the preview prices
+    /// spending all of them instead of failing and says it falls short. A sweep too large
+    /// for one transaction is previewed as the transactions it splits into, added up.
+    pub fn build_unsigned_preview(self) -> Result<TransactionPreview> {
+        let senders = self.senders()?;
+        let change = self.change(&senders)?;
+        let plan = self.plan(&senders)?;
+        let spenders = senders
+            .iter()
+            .map(|from| address_spender(from))
+            .collect::<Result<Vec<_>>>()?;
+        let memo_len = plan
+            .memo_output
+            .as_ref()
+            .and_then(TXOutput::get_data)
+            .map_or(0, |data| data.len());
+        let amount = if plan.policy.is_sweep() {
+            Amount::ZERO
+        } else {
+            Amount::checked_sum(plan.outputs.iter().map(TXOutput::get_value))?
+        };
+        let mut coins = 0;
+        let mut balance = Amount::ZERO;
+        for spender in &spenders {
+            for (_, _, output) in self.utxo_set.list_unspent_safe(spender.lock.as_slice())? {
+                coins += 1;
+                balance = balance.checked_add(output.get_value())?;
+            }
+        }
+
+        let payments = plan.outputs.len() + usize::from(plan.memo_output.is_some());
+        let policy = plan.policy;
+        let transactions = match self.fund(&spenders, change, plan) {
+            Ok(transactions) => transactions,
+            Err(BlockchainError::InsufficientFundsDetailed { .. }) => {
+                // Spending every coin without change is the least it could take
+                let size =
+                    FeeCalculator::estimate_transaction_size_with_data(coins, payments, memo_len);
+                return Ok(TransactionPreview {
+                    input_count: coins,
+                    output_count: payments,
+                    size,
+                    fee: self.price(policy, size),
+                    amount,
+                    balance,
+                    sufficient: false,
+                });
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut preview = TransactionPreview {
+            input_count: 0,
+            output_count: 0,
+            size: 0,
+            fee: Amount::ZERO,
+            amount,
+            balance,
+            sufficient: true,
+        };
+        for tx in &transactions {
+            preview.input_count += tx.vin.len();
+            preview.output_count += tx.vout.len();
+            preview.size += FeeCalculator::estimate_transaction_size_with_data(
+                tx.vin.len(),
+                tx.vout.len(),
+                memo_len,
+            );
+            preview.fee = preview.fee.checked_add(tx.fee)?;
+            if policy.is_sweep() {
+                preview.amount = preview.amount.checked_add(tx.vout[0].value)?;
+            }
+        }
+        Ok(preview)
+    }
+
+    fn senders(&self) -> Result<Vec<&str>> {
+        if self.from.is_empty() {
+            return Err(BlockchainError::Transaction(
+                "The transaction has no address to spend from, set one with from".to_string(),
+            ));
+        }
+        let mut senders: Vec<&str> = Vec::with_capacity(self.from.len());
+        for from in &self.from {
+            validate_address(from)?;
+            // The same coins would be selected twice
+            if senders.contains(&from.as_str()) {
+                return Err(BlockchainError::Transaction(format!(
+                    "Address {from} is given twice to spend from"
+                )));
+            }
+            senders.push(from);
+        }
+        Ok(senders)
+    }
+
+    fn change<'s>(&'s self, senders: &[&'s str]) -> Result<&'s str> {
+        match &self.change_address {
+            Some(address) => {
+                validate_address(address)?;
+                Ok(address)
+            }
+            None => Ok(senders[0]),
+        }
+    }
+
+    fn plan(&self, senders: &[&str]) -> Result<PaymentPlan> {
+        let policy = self.policy()?;
+        if self.payments.is_empty() {
+            return Err(BlockchainError::Transaction(
+                "The transaction has no outputs, add one with add_output".to_string(),
+            ));
+        }
+        if self.consolidation.is_some() && !policy.is_sweep() {
+            return Err(BlockchainError::Transaction(format!(
+                "A consolidation merges its coins whole, so it needs a sweep fee policy, not {policy:?}"
+            )));
+        }
+        if policy.is_sweep() && self.payments.len() > 1 {
+            return Err(BlockchainError::Transaction(format!(
+                "A sweep pays everything to one output, but {} were added",
+                self.payments.len()
+            )));
+        }
+
+        let outputs = self
+            .payments
+            .iter()
+            .map(|payment| {
+                // A sweep's output gets its value once the inputs are known
+                let amount = if policy.is_sweep() {
+                    if payment.amount() != Amount::ZERO {
+                        return Err(BlockchainError::Transaction(
+                            "A sweep pays whatever the fee leaves, so its output's amount must be 0"
+                                .to_string(),
+                        ));
+                    }
+                    Amount::from(1)
+                } else if payment.amount() == Amount::ZERO {
+                    return Err(BlockchainError::Transaction(
+                        "Amount must be positive".to_string(),
+                    ));
+                } else {
+                    payment.amount()
+                };
+                match payment {
+                    Payment::Address { address, .. } => {
+                        validate_address(address)?;
+                        // Paying myself would only split my coins and burn a fee;
+                        // consolidation merges them
+                        if senders.contains(&address.as_str()) && self.consolidation.is_none() {
+                            return Err(BlockchainError::SelfTransfer(address.clone()));
+                        }
+                        TXOutput::new(amount, address)
+                    }
+                    Payment::PubKey { pub_key, .. } => TXOutput::new_p2pk(amount, pub_key),
+                    Payment::Output(output) => Ok(output.clone()),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PaymentPlan {
+            policy,
+            outputs,
+            memo_output: self
+                .memo
+                .as_deref()
+                .map(TXOutput::new_data_carrier)
+                .transpose()?,
+        })
+    }
+
+    fn policy(&self) -> Result<FeePolicy> {
+        let mut policies = self.fee_policies.iter();
+        let policy = policies
+            .next()
+            .copied()
+            .unwrap_or(FeePolicy::Priority(FeePriority::Normal));
+        if let Some(other) = policies.find(|other| **other != policy) {
+            return Err(BlockchainError::Transaction(format!(
+                "Conflicting fee policies {policy:?} and {other:?}, set only one"
+            )));
+        }
+        if self.fee_for_size.is_some() && !self.fee_policies.is_empty() {
+            return Err(BlockchainError::Transaction(format!(
+                "Conflicting fee policies {policy:?} and a fee function, set only one"
+            )));
+        }
+        Ok(policy)
+    }
+
+    fn price(&self, policy: FeePolicy, size: usize) -> Amount {
+        if let Some(fee_for_size) = &self.fee_for_size {
+            return fee_for_size(size);
+        }
+        match policy {
+            FeePolicy::Priority(priority) | FeePolicy::SweepAll(priority) => match self.context {
+                Some(context) => context.calculate_fee(size, Some(priority)),
+                None => FeeCalculator::calculate_fee(size, Some(priority)),
+            },
+            FeePolicy::Explicit(fee) | FeePolicy::SweepAllWithFee(fee) => fee,
+        }
+    }
+
+    // Selects the spenders' coins and returns the payment with its id but no signatures;
+    // only a sweep may come out as more than one transaction
+    fn fund(
+        &self,
+        spenders: &[Spender],
+        change: &str,
+        plan: PaymentPlan,
+    ) -> Result<Vec<Transaction>> {
+        // A memo travels in one more output, worth nothing but paid for by its size
+        let (memo_outputs, memo_len) = plan
+            .memo_output
+            .as_ref()
+            .and_then(TXOutput::get_data)
+            .map_or((0, 0), |data| (1, data.len()));
+        let size_for = |input_count: usize, output_count: usize| {
+            FeeCalculator::estimate_transaction_size_with_data(
+                input_count,
+                output_count + memo_outputs,
+                memo_len,
+            )
+        };
+
+        let mut transactions = if plan.policy.is_sweep() {
+            self.sweep(spenders, plan.policy, plan.outputs, memo_len, size_for)?
+        } else {
+            vec![self.pay(spenders, change, plan.policy, plan.outputs, size_for)?]
+        };
+        for tx in &mut transactions {
+            tx.vout.extend(plan.memo_output.clone());
+            if !self.allow_high_fee {
+                Transaction::check_fee_limit(tx.fee)?;
+            }
+            // Too many inputs is known before any signing is spent on them
+            tx.check_limits()?;
+            tx.id = tx.compute_id();
+        }
+        Ok(transactions)
+    }
+
+    // The fee depends on the size and the size on how many inputs I pick, so I select coins
+    // for amount + fee, price the selection, and select again until the two agree
+    fn pay(
+        &self,
+        spenders: &[Spender],
+        change_address: &str,
+        policy: FeePolicy,
+        mut outputs: Vec<TXOutput>,
+        size_for: impl Fn(usize, usize) -> usize,
+    ) -> Result<Transaction> {
+        let amount = Amount::checked_sum(outputs.iter().map(TXOutput::get_value))?;
+        let with_fee = |fee: Amount| amount.checked_add(fee);
+        let payments = outputs.len();
+        let coins = self
+            .coin_selection
+            .map(|strategy| self.ordered_coins(spenders, strategy))
+            .transpose()?;
+        // Once a strategy picks the coins, change worth no more than dust goes to the fee
+        let least_change = match coins {
+            Some(_) => Amount::from_satoshis(DUST_THRESHOLD + 1),
+            None => Amount::from(1),
+        };
+
+        if let (Some(SelectionStrategy::BranchAndBound), Some(coins)) =
+            (self.coin_selection, &coins)
+        {
+            // Only mature coins, which the ordering put first
+            let mature = &coins[..coins.partition_point(|coin| coin.mature)];
+            let changeless = coin_selection::branch_and_bound(mature, |input_count| {
+                let low = with_fee(self.price(policy, size_for(input_count, payments)))?;
+                let high = with_fee(self.price(policy, size_for(input_count, payments + 1)))?
+                    .checked_add(least_change)?
+                    .checked_sub(Amount::from(1))?;
+                Ok((low, high))
+            })?;
+            if let Some(positions) = changeless {
+                let picked: Vec<Coin> = positions.into_iter().map(|i| mature[i].clone()).collect();
+                let accumulated = Amount::checked_sum(picked.iter().map(|coin| coin.value))?;
+                return Ok(Transaction {
+                    id: vec![],
+                    vin: Self::inputs(spenders, Self::selections(spenders, &picked))?,
+                    vout: outputs,
+                    fee: accumulated.checked_sub(amount)?,
+                });
+            }
+        }
+
+        // Every pass either settles on a fee or raises the target above what is already
+        // selected, so this ends once the coins cover the fee or run out
+        let mut target = with_fee(self.price(policy, size_for(1, payments + 1)))?;
+        let (accumulated, valid_outputs, fee_amount) = loop {
+            let (accumulated, valid_outputs) = match &coins {
+                Some(coins) => {
+                    let (accumulated, taken) = coin_selection::take_until(coins, target)?;
+                    (accumulated, Self::selections(spenders, taken))
+                }
+                None => self.select(spenders, target)?,
+            };
+            if accumulated < target {
+                return Err(BlockchainError::insufficient_funds(
+                    amount,
+                    target.saturating_sub(amount),
+                    accumulated,
+                ));
+            }
+
+            let input_count = valid_outputs
+                .iter()
+                .flat_map(HashMap::values)
+                .map(Vec::len)
+                .sum();
+            let fee_with_change = self.price(policy, size_for(input_count, payments + 1));
+            let fee_without_change = self.price(policy, size_for(input_count, payments));
+
+            if accumulated >= with_fee(fee_with_change)?.checked_add(least_change)? {
+                break (accumulated, valid_outputs, fee_with_change);
+            }
+            if accumulated >= with_fee(fee_without_change)? {
+                // Whatever is left can't pay for its own change output, so it goes to the fee
+                break (accumulated, valid_outputs, accumulated.checked_sub(amount)?);
+            }
+            target = with_fee(fee_with_change)?;
+        };
+
+        let change = accumulated.checked_sub(amount)?.checked_sub(fee_amount)?;
+        if change > Amount::ZERO {
+            outputs.push(TXOutput::new(change, change_address)?);
+        }
+        Ok(Transaction {
+            id: vec![],
+            vin: Self::inputs(spenders, valid_outputs)?,
+            vout: outputs,
+            fee: fee_amount,
+        })
+    }
+
+    // Coins worth at least `target` if the spenders have that much, taking from each in
+    // turn what the ones before it left short; the selections are in spender order
+    fn select(&self, spenders: &[Spender], target: Amount) -> Result<(Amount, Vec<Selection>)> {
+        let mut accumulated = Amount::ZERO;
+        let mut selected = Vec::with_capacity(spenders.len());
+        for spender in spenders {
+            let mut outputs: Selection = HashMap::new();
+            for (txid_hex, vout, output) in self.unclaimed_coins(spender)? {
+                if accumulated >= target {
+                    break;
+                }
+                accumulated = accumulated.checked_add(output.get_value())?;
+                outputs.entry(txid_hex).or_default().push(vout);
+            }
+            selected.push(outputs);
+        }
+        Ok((accumulated, selected))
+    }
+
+    // The memory pool whose pending transactions already spend some of the coins
+    fn memory_pool(&self) -> &MemoryPool {
+        match self.context {
+            Some(context) => context.memory_pool(),
+            None => &GLOBAL_MEMORY_POOL,
+        }
+    }
+
+    // Whether a pending transaction already spends output `vout` of `txid_hex`
+    fn is_claimed(&self, txid_hex: &str, vout: usize) -> bool {
+        HEXLOWER
+            .decode(txid_hex.as_bytes())
+            .is_ok_and(|txid| self.memory_pool().claimed_by(&txid, vout).is_some())
+    }
+
+    // The spender's coins no pending transaction spends yet, by txid and then index
+    fn unclaimed_coins(&self, spender: &Spender) -> Result<Vec<(String, usize, TXOutput)>> {
+        let mut coins = self.utxo_set.list_unspent_safe(spender.lock.as_slice())?;
+        coins.retain(|(txid_hex, vout, _)| !self.is_claimed(txid_hex, *vout));
+        Ok(coins)
+    }
+
+    // Every coin of the spenders, in the order `strategy` spends them
+    fn ordered_coins(
+        &self,
+        spenders: &[Spender],
+        strategy: SelectionStrategy,
+    ) -> Result<Vec<Coin>> {
+        let mut coins = vec![];
+        for (idx, spender) in spenders.iter().enumerate() {
+            for unspent in self.utxo_set.list_unspent_for(spender.lock.as_slice())? {
+                if self.is_claimed(&unspent.txid_hex, unspent.vout) {
+                    continue;
+                }
+                coins.push(Coin {
+                    spender: idx,
+                    txid_hex: unspent.txid_hex,
+                    vout: unspent.vout,
+                    value: unspent.value,
+                    confirmations: unspent.confirmations,
+                    mature: unspent.spendable_now,
+                });
+            }
+        }
+        strategy.order(&mut coins);
+        Ok(coins)
+    }
+
+    // `coins` as each spender's selection, in spender order
+    fn selections(spenders: &[Spender], coins: &[Coin]) -> Vec<Selection> {
+        let mut selected: Vec<Selection> = vec![HashMap::new(); spenders.len()];
+        for coin in coins {
+            selected[coin.spender]
+                .entry(coin.txid_hex.clone())
+                .or_default()
+                .push(coin.vout);
+        }
+        selected
+    }
+
+    fn inputs(spenders: &[Spender], selected: Vec<Selection>) -> Result<Vec<TXInput>> {
+        let mut inputs = vec![];
+        for (spender, outputs) in spenders.iter().zip(selected) {
+            inputs.extend(Transaction::spending_inputs(spender, outputs)?);
+        }
+        Ok(inputs)
+    }
+
+    // A sweep spends every coin the sender has, so its size, and with it the fee, is known
+    // before anything is built; the fee comes out of the single output. Coins too many for
+    // one transaction are dealt out evenly over as few as hold them.
+    fn sweep(
+        &self,
+        spenders: &[Spender],
+        policy: FeePolicy,
+        outputs: Vec<TXOutput>,
+        memo_len: usize,
+        size_for: impl Fn(usize, usize) -> usize,
+    ) -> Result<Vec<Transaction>> {
+        let mut coins = vec![];
+        for (idx, spender) in spenders.iter().enumerate() {
+            for (txid_hex, vout, output) in self.unclaimed_coins(spender)? {
+                coins.push((idx, txid_hex, vout, output.get_value()));
+            }
+        }
+        if let Some(consolidation) = self.consolidation {
+            // The smallest coins cost the most to spend one by one, so they go first
+            coins.sort_by_key(|(_, _, _, value)| *value);
+            coins.truncate(consolidation.max_inputs);
+            if coins.len() < 2 {
+                let from: Vec<&str> = spenders.iter().map(|spender| spender.address).collect();
+                return Err(BlockchainError::Transaction(format!(
+                    "Nothing to consolidate, {} has {} spendable output(s) and up to {} may be merged",
+                    from.join(", "),
+                    coins.len(),
+                    consolidation.max_inputs
+                )));
+            }
+        }
+        if coins.is_empty() {
+            let from: Vec<&str> = spenders.iter().map(|spender| spender.address).collect();
+            return Err(BlockchainError::Transaction(format!(
+                "Nothing to sweep, {} has no spendable outputs",
+                from.join(", ")
+            )));
+        }
+
+        let mut transactions = vec![];
+        for chunk in split_evenly(coins, Transaction::inputs_per_transaction(memo_len)) {
+            let total = Amount::checked_sum(chunk.iter().map(|(_, _, _, value)| *value))?;
+            let fee = self.price(policy, size_for(chunk.len(), 1));
+            Transaction::check_fee_limit(fee)?;
+            if let Some(Consolidation {
+                max_fee_percent, ..
+            }) = self.consolidation
+            {
+                // Compared without dividing, so small totals don't round the limit down to
+                // nothing
+                if u128::from(fee.to_satoshis()) * 100
+                    > u128::from(total.to_satoshis()) * u128::from(max_fee_percent)
+                {
+                    return Err(BlockchainError::Transaction(format!(
+                        "Consolidating {} outputs worth {} satoshis would pay a {} satoshi fee, \
+                         more than {max_fee_percent}% of their value",
+                        chunk.len(),
+                        total.to_satoshis(),
+                        fee.to_satoshis()
+                    )));
+                }
+            }
+            let value = total.saturating_sub(fee);
+            if value <= DUST_THRESHOLD {
+                return Err(BlockchainError::Transaction(format!(
+                    "Sweeping {} satoshis leaves {} after the {} satoshi fee, \
+                     not more than the dust threshold of {DUST_THRESHOLD}",
+                    total.to_satoshis(),
+                    value.to_satoshis(),
+                    fee.to_satoshis()
+                )));
+            }
+
+            let mut selected: Vec<Selection> = vec![HashMap::new(); spenders.len()];
+            for (idx, txid_hex, vout, _) in chunk {
+                selected[idx].entry(txid_hex).or_default().push(vout);
+            }
+            let mut vout = outputs.clone();
+            vout[0].value = value;
+            transactions.push(Transaction {
+                id: vec![],
+                vin: Self::inputs(spenders, selected)?,
+                vout,
+                fee,
+            });
+        }
+        Ok(transactions)
+    }
+}
+
+// `items` dealt into as few runs of at most `most` as hold them, all about the same length
+// so no run is left with a sliver whose fee eats it
+fn split_evenly<T>(items: Vec<T>, most: usize) -> Vec<Vec<T>> {
+    let runs = items.len().div_ceil(most.max(1)).max(1);
+    let (base, longer) = (items.len() / runs, items.len() % runs);
+    let mut items = items.into_iter();
+    (0..runs)
+        .map(|run| {
+            items
+                .by_ref()
+                .take(base + usize::from(run < longer))
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        blockchain, Block, DynamicFeeConfig, FeeMode, Network, NodeContext, SATOSHIS_PER_COIN,
+    };
+    use crate::storage::MemoryPool;
+    use crate::utils::deserialize;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::{SharedWallets, Wallet};
+    use std::collections::HashSet;
+    use tempfile::{tempdir, TempDir};
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    // A chain where `owner` holds `count` separate outputs of `value` satoshis each
+    fn chain_with_small_coins(owner: &str, count: usize, value: u64) -> (TempDir, UTXOSet) {
+        chain_with_coins(owner, &vec![value; count])
+    }
+
+    // A chain where `owner` holds one output for each of `values`
+    fn chain_with_coins(owner: &str, values: &[u64]) -> (TempDir, UTXOSet) {
+        let payments: Vec<(&str, u64)> = values.iter().map(|value| (owner, *value)).collect();
+        chain_paying(&payments)
+    }
+
+    // A chain with one block for each of `payments`, whose coinbase pays the address
+    fn chain_paying(payments: &[(&str, u64)]) -> (TempDir, UTXOSet) {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let mut tip = blockchain
+            .get_block(&blockchain.get_tip_hash())
+            .unwrap()
+            .unwrap();
+        for ((owner, value), height) in payments.iter().zip(1..) {
+            let coinbase = Transaction::new_coinbase_tx_with_reward(owner, *value).unwrap();
+            let block = Block::new_test_block(
+                tip.get_timestamp() + 1_000,
+                tip.get_hash().to_string(),
+                &[coinbase],
+                height,
+                1,
+            )
+            .unwrap();
+            blockchain.add_block(&block).unwrap();
+            tip = block;
+        }
+
+        let utxo_set = UTXOSet::new(blockchain);
+        utxo_set.reindex();
+        (temp_dir, utxo_set)
+    }
+
+    // The one transaction a sweep or consolidation small enough for one came out as
+    fn only(mut transactions: Vec<Transaction>) -> Transaction {
+        assert_eq!(transactions.len(), 1);
+        transactions.remove(0)
+    }
+
+    fn input_total(tx: &Transaction, utxo_set: &UTXOSet) -> u64 {
+        tx.get_input_value(utxo_set.get_blockchain())
+            .unwrap()
+            .to_satoshis()
+    }
+
+    #[test]
+    fn test_coin_selection_covers_amount_plus_fee() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&sender, 10, 1_000);
+
+        // Nine coins already cover the amount, the fee needs the tenth
+        let tx = Transaction::new_utxo_transaction_with_explicit_fee(
+            &sender,
+            TEST_ADDRESS,
+            9_000,
+            600,
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap();
+        assert_eq!(tx.get_vin().len(), 10);
+        assert_eq!(tx.get_fee(), 600);
+        assert_eq!(tx.get_vout()[1].get_value(), 400);
+        assert!(tx.verify(utxo_set.get_blockchain()));
+
+        // Spending everything leaves no change output
+        let tx = Transaction::new_utxo_transaction_with_explicit_fee(
+            &sender,
+            TEST_ADDRESS,
+            9_500,
+            500,
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap();
+        assert_eq!(tx.get_vin().len(), 10);
+        assert_eq!(tx.get_vout().len(), 1);
+
+        let err = Transaction::new_utxo_transaction_with_explicit_fee(
+            &sender,
+            TEST_ADDRESS,
+            9_500,
+            501,
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            BlockchainError::InsufficientFundsDetailed {
+                amount,
+                fee,
+                required,
+                available
+            } if amount == 9_500 && fee == 501 && required == 10_001 && available == 10_000
+        ));
+        // The message is in coins and tells the fee apart from the amount
+        let message = err.to_string();
+        assert!(message.contains("sending 0.00009500 coins"), "{message}");
+        assert!(message.contains("needs 0.00010001 coins"), "{message}");
+        assert!(
+            message.contains("including a fee of 0.00000501 coins"),
+            "{message}"
+        );
+        assert!(
+            message.contains("only 0.00010000 coins is available"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_size_based_fee_follows_final_selection() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&sender, 10, 1_000);
+
+        // Ten satoshis per estimated byte, so every extra input needs most of another coin
+        let per_byte = |size: usize| Amount::from(size as u64 * 10);
+        let tx = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output(TEST_ADDRESS, 3_000)
+            .with_fee_for_size(per_byte)
+            .build_and_sign(&wallets)
+            .unwrap();
+
+        let inputs = tx.get_vin().len();
+        let outputs = tx.get_vout().len();
+        let priced = per_byte(FeeCalculator::estimate_transaction_size(inputs, outputs));
+        assert!(tx.get_fee() >= priced);
+        assert_eq!(
+            input_total(&tx, &utxo_set),
+            3_000
+                + tx.get_fee().to_satoshis()
+                + tx.get_vout()
+                    .get(1)
+                    .map_or(0, |change| change.get_value().to_satoshis())
+        );
+        // Seven coins pay 3,000 plus the 3,880 fee for seven inputs and no change; the 120
+        // left over can't pay for a change output and goes to the fee. Selecting for the
+        // amount alone would have stopped at three coins.
+        assert_eq!(inputs, 7);
+        assert_eq!(outputs, 1);
+        assert_eq!(priced, 3_880);
+        assert_eq!(tx.get_fee(), 4_000);
+    }
+
+    // Mine `tx` on top of the tip without proof of work and bring the chainstate along
+    fn confirm(utxo_set: &UTXOSet, tx: &Transaction) -> Block {
+        let blockchain = utxo_set.get_blockchain();
+        let tip = blockchain
+            .get_block(&blockchain.get_tip_hash())
+            .unwrap()
+            .unwrap();
+        let block_txs = vec![
+            Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap(),
+            tx.clone(),
+        ];
+        let block = Block::new_test_block(
+            tip.get_timestamp() + 1_000,
+            tip.get_hash().to_string(),
+            &block_txs,
+            tip.get_height() + 1,
+            1,
+        )
+        .unwrap();
+        blockchain.add_block(&block).unwrap();
+        utxo_set.update_safe(&block).unwrap();
+        block
+    }
+
+    // A transaction spending output `vout` of `txid`, signed with `signer`'s key
+    fn forged_spend(
+        utxo_set: &UTXOSet,
+        txid: &[u8],
+        vout: usize,
+        value: u64,
+        pub_key: &[u8],
+        signer: &Wallet,
+    ) -> Transaction {
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput {
+                txid: txid.to_vec(),
+                vout,
+                signature: vec![],
+                pub_key: pub_key.to_vec(),
+            }],
+            vout: vec![TXOutput::new(value - 100, TEST_ADDRESS).unwrap()],
+            fee: Amount::from(100),
+        };
+        tx.id = tx.compute_id();
+        tx.sign(utxo_set.get_blockchain(), signer.get_pkcs8())
+            .unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_p2pk_output_is_spent_only_by_its_key() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let attacker = wallets.create_wallet().unwrap();
+        let recipient_key = wallets
+            .get_wallet(&recipient)
+            .unwrap()
+            .get_public_key()
+            .to_vec();
+        let attacker_wallet = wallets.get_wallet(&attacker).unwrap().clone();
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&sender, 3, 1_000);
+
+        let payment = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output_to_pub_key(&recipient_key, 2_000)
+            .fee_policy(FeePolicy::Explicit(Amount::from(100)))
+            .build_and_sign(&wallets)
+            .unwrap();
+        assert_eq!(
+            payment.get_vout()[0].get_locking_condition(),
+            LockingCondition::PubKey(recipient_key.clone())
+        );
+        assert!(payment.verify(utxo_set.get_blockchain()));
+        confirm(&utxo_set, &payment);
+
+        // Balances count the output under the address of the key
+        let recipient_hash = hash_pub_key(&recipient_key);
+        let balance: u64 = utxo_set
+            .find_utxo(&recipient_hash)
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .sum();
+        assert_eq!(balance, 2_000);
+
+        // Signing with another key fails, whichever key the input claims
+        let claims_own_key = forged_spend(
+            &utxo_set,
+            payment.get_id(),
+            0,
+            2_000,
+            attacker_wallet.get_public_key(),
+            &attacker_wallet,
+        );
+        assert!(!claims_own_key.verify(utxo_set.get_blockchain()));
+        let claims_recipient_key = forged_spend(
+            &utxo_set,
+            payment.get_id(),
+            0,
+            2_000,
+            &recipient_key,
+            &attacker_wallet,
+        );
+        assert!(!claims_recipient_key.verify(utxo_set.get_blockchain()));
+
+        // The recipient spends it like any other coin of theirs
+        let spend = Transaction::new_utxo_transaction_with_explicit_fee(
+            &recipient,
+            TEST_ADDRESS,
+            1_500,
+            100,
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap();
+        assert_eq!(spend.get_vin()[0].get_txid(), payment.get_id());
+        assert!(spend.verify(utxo_set.get_blockchain()));
+
+        // An address lock still needs the key behind the address, not just a valid signature
+        assert_eq!(payment.get_vout()[1].get_value(), 900);
+        let steals_change = forged_spend(
+            &utxo_set,
+            payment.get_id(),
+            1,
+            900,
+            attacker_wallet.get_public_key(),
+            &attacker_wallet,
+        );
+        assert!(!steals_change.verify(utxo_set.get_blockchain()));
+    }
+
+    #[test]
+    fn test_outputs_serialized_before_p2pk_still_decode() {
+        // The layout TXOutput had before it could lock to a public key
+        #[derive(Serialize, bincode::Encode)]
+        struct LegacyTXOutput {
+            value: u64,
+            pub_key_hash: Vec<u8>,
+        }
+
+        let hash = hash_pub_key(b"legacy key");
+        let legacy = LegacyTXOutput {
+            value: 50,
+            pub_key_hash: hash.clone(),
+        };
+        let decoded: TXOutput = deserialize(&serialize(&legacy).unwrap()).unwrap();
+        assert_eq!(decoded.get_value(), 50);
+        assert_eq!(
+            decoded.get_locking_condition(),
+            LockingCondition::PubKeyHash(hash.clone())
+        );
+        assert!(decoded.is_locked_with_key(&hash));
+
+        // A hash that happens to start with the P2PK tag is still a hash
+        let mut tagged_hash = hash;
+        tagged_hash[0] = PUB_KEY_LOCK_TAG;
+        let output = TXOutput {
+            value: Amount::from(50),
+            pub_key_hash: tagged_hash.clone(),
+        };
+        assert_eq!(
+            output.get_locking_condition(),
+            LockingCondition::PubKeyHash(tagged_hash)
+        );
+
+        let key = [0x04; PUB_KEY_LEN];
+        let p2pk = TXOutput::new_p2pk(50, &key).unwrap();
+        let decoded: TXOutput = deserialize(&serialize(&p2pk).unwrap()).unwrap();
+        assert_eq!(
+            decoded.get_locking_condition(),
+            LockingCondition::PubKey(key.to_vec())
+        );
+        assert!(TXOutput::new_p2pk(50, &key[1..]).is_err());
+    }
+
+    #[test]
+    fn test_memo_is_anchored_without_entering_the_chainstate() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&sender, 3, 1_000);
+        let document_hash = sha256_digest(b"contract.pdf");
+        assert_eq!(document_hash.len(), 32);
+
+        // The memo's size is priced in, and it rides behind the payment and change
+        let per_byte = |size: usize| Amount::from(size as u64);
+        let tx = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_txoutput(TXOutput::new(500, &sender).unwrap())
+            .memo(&document_hash)
+            .with_fee_for_size(per_byte)
+            .build_and_sign(&wallets)
+            .unwrap();
+        let outputs = tx.get_vout();
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[2].get_value(), 0);
+        assert_eq!(outputs[2].get_data(), Some(document_hash.clone()));
+        assert!(!outputs[2].is_locked_with_key(&hash_pub_key(&[])));
+        assert_eq!(
+            tx.get_fee(),
+            FeeCalculator::estimate_transaction_size_with_data(1, 3, 32) as u64
+        );
+        assert!(tx.verify(utxo_set.get_blockchain()));
+
+        let entries_before = utxo_set.count_transactions();
+        let block = confirm(&utxo_set, &tx);
+        let block = utxo_set
+            .get_blockchain()
+            .get_block(block.get_hash())
+            .unwrap()
+            .unwrap();
+        let stored = &block.get_transactions()[1];
+        assert_eq!(
+            stored.get_vout()[2].get_locking_condition(),
+            LockingCondition::DataCarrier(document_hash.clone())
+        );
+
+        // The coinbase and the transaction add an entry each, the spent coin's goes away,
+        // and the transaction's entry only holds its two spendable outputs
+        let stored_outputs = |utxo_set: &UTXOSet| {
+            let tree = utxo_set
+                .get_blockchain()
+                .get_db()
+                .open_tree("chainstate")
+                .unwrap();
+            let outs: Vec<(usize, TXOutput)> =
+                deserialize(&tree.get(tx.get_id()).unwrap().unwrap()).unwrap();
+            outs
+        };
+        assert_eq!(utxo_set.count_transactions(), entries_before + 1);
+        assert_eq!(stored_outputs(&utxo_set).len(), 2);
+        // Rebuilding the chainstate from the blocks leaves the data output out as well
+        utxo_set.reindex();
+        assert_eq!(utxo_set.count_transactions(), entries_before + 1);
+        assert_eq!(stored_outputs(&utxo_set).len(), 2);
+
+        // Spending the data output is refused
+        let mut steals_data = tx.clone();
+        steals_data.vin = vec![TXInput::new(tx.get_id(), 2)];
+        assert!(!steals_data.verify_signatures(&[outputs[2].clone()]));
+
+        let oversized = [0u8; 200];
+        assert!(TXOutput::new_data_carrier(&oversized).is_err());
+        assert!(TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output(TEST_ADDRESS, 100)
+            .memo(&oversized)
+            .fee_policy(FeePolicy::Explicit(Amount::from(10)))
+            .build_and_sign(&wallets)
+            .is_err());
+
+        // A data output can't hold value back from the balance or hide before the change
+        let mut reordered = tx.clone();
+        reordered.vout.swap(1, 2);
+        assert!(!reordered.verify_outputs());
+        let mut valueless = tx.clone();
+        valueless.vout[1].value = Amount::ZERO;
+        assert!(!valueless.verify_outputs());
+    }
+
+    #[test]
+    fn test_two_of_three_multisig_needs_two_member_signatures() {
+        let signers: Vec<Wallet> = (0..3).map(|_| Wallet::new().unwrap()).collect();
+        let outsider = Wallet::new().unwrap();
+        let pub_key_hashes: Vec<Vec<u8>> = signers
+            .iter()
+            .map(|wallet| hash_pub_key(wallet.get_public_key()))
+            .collect();
+        let address = TXOutput::multisig_address(2, &pub_key_hashes).unwrap();
+        assert!(TXOutput::multisig_address(4, &pub_key_hashes).is_err());
+
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&address, 1, 100_000);
+        let blockchain = utxo_set.get_blockchain();
+        let funding = TXOutput::new(100_000, &address).unwrap();
+        assert_eq!(
+            funding.get_locking_condition(),
+            LockingCondition::MultiSig {
+                required: 2,
+                pub_key_hashes: pub_key_hashes.clone(),
+            }
+        );
+        assert_eq!(funding.get_address(), address);
+
+        let unsigned = Transaction::create_unsigned(
+            &address,
+            TEST_ADDRESS,
+            60_000,
+            FeePriority::Normal,
+            &utxo_set,
+        )
+        .unwrap();
+        assert_eq!(unsigned.missing_signatures(blockchain).unwrap(), vec![2]);
+        // The signers hand the transaction around as hex
+        let hand_over = |tx: &Transaction| {
+            let hex = HEXLOWER.encode(&tx.serialize().unwrap());
+            Transaction::deserialize(&HEXLOWER.decode(hex.as_bytes()).unwrap()).unwrap()
+        };
+
+        let mut first = hand_over(&unsigned);
+        assert_eq!(first.add_signature(&signers[0], blockchain).unwrap(), 1);
+        assert_eq!(first.add_signature(&signers[0], blockchain).unwrap(), 0);
+        assert!(first.add_signature(&outsider, blockchain).is_err());
+
+        // One signature isn't enough, neither when finalizing nor when verifying
+        assert!(first.clone().finalize(blockchain).is_err());
+        assert!(!first.verify(blockchain));
+
+        // Nor is a second one from a key outside the set, even if it signs correctly
+        let mut with_outsider = first.clone();
+        let spent = with_outsider.spent_outputs(blockchain).unwrap();
+        let digest = Transaction::signing_digest(&mut with_outsider.trimmed_copy(), 0, &spent[0]);
+        let signature = ecdsa_p256_sha256_sign_digest(outsider.get_pkcs8(), &digest).unwrap();
+        with_outsider.vin[0].add_multisig_signature(outsider.get_public_key(), &signature);
+        assert!(!with_outsider.verify_signatures(&spent));
+        assert!(with_outsider.finalize(blockchain).is_err());
+
+        // Two members signing their own copies make a spendable transaction together
+        let mut second = hand_over(&unsigned);
+        second.add_signature(&signers[2], blockchain).unwrap();
+        first.combine(&hand_over(&second)).unwrap();
+        assert_eq!(first.missing_signatures(blockchain).unwrap(), vec![0]);
+        let complete = first.finalize(blockchain).unwrap();
+        assert!(complete.verify(blockchain));
+        assert!(!complete.is_coinbase());
+
+        // The change goes back to the multisig address
+        confirm(&utxo_set, &complete);
+        let change: u64 = utxo_set
+            .find_utxo(&funding.get_pub_key_hash())
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .sum();
+        assert_eq!(change, 100_000 - 60_000 - complete.get_fee().to_satoshis());
+    }
+
+    #[test]
+    fn test_fees_above_the_maximum_need_an_override() {
+        let _guard = lock_wallet_file();
+        let sender = Wallets::new().create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&sender, 1, 10 * MAX_TRANSACTION_FEE);
+        let absurd_fee = MAX_TRANSACTION_FEE + 1;
+
+        let refused = Transaction::new_utxo_transaction_with_explicit_fee(
+            &sender,
+            TEST_ADDRESS,
+            1_000,
+            absurd_fee,
+            &utxo_set,
+            &Wallets::new(),
+        );
+        assert!(matches!(
+            refused,
+            Err(BlockchainError::FeeTooHigh { fee, .. }) if fee == absurd_fee
+        ));
+        assert!(Transaction::new_utxo_transaction_with_explicit_fee(
+            &sender,
+            TEST_ADDRESS,
+            1_000,
+            MAX_TRANSACTION_FEE,
+            &utxo_set,
+            &Wallets::new(),
+        )
+        .is_ok());
+
+        // When the sender insists the transaction is built, and it's still valid
+        let insisted = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output(TEST_ADDRESS, 1_000)
+            .fee_policy(FeePolicy::Explicit(Amount::from(absurd_fee)))
+            .allow_high_fee(true)
+            .build_and_sign(&Wallets::new())
+            .unwrap();
+        assert_eq!(insisted.get_fee(), absurd_fee);
+        assert!(insisted.exceeds_max_fee());
+        assert!(insisted.verify(utxo_set.get_blockchain()));
+    }
+
+    #[test]
+    fn test_sweep_empties_the_source() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let values = [700, 1_500, 3_000, 12_345, 80_000];
+        let (_temp_dir, utxo_set) = chain_with_coins(&sender, &values);
+        let total: u64 = values.iter().sum();
+        let balance = |address: &str| -> u64 {
+            let pub_key_hash = crate::wallet::address_pub_key_hash(address).unwrap();
+            utxo_set
+                .find_utxo(&pub_key_hash)
+                .iter()
+                .map(|output| output.get_value().to_satoshis())
+                .sum()
+        };
+
+        let tx = only(
+            Transaction::new_sweep_transaction(
+                &sender,
+                &recipient,
+                FeePriority::High,
+                &utxo_set,
+                &wallets,
+            )
+            .unwrap(),
+        );
+        assert_eq!(tx.get_vin().len(), values.len());
+        assert_eq!(tx.get_vout().len(), 1);
+        let expected_fee = FeeCalculator::calculate_fee(
+            FeeCalculator::estimate_transaction_size(values.len(), 1),
+            Some(FeePriority::High),
+        );
+        assert_eq!(tx.get_fee(), expected_fee);
+        assert_eq!(input_total(&tx, &utxo_set), total);
+
+        confirm(&utxo_set, &tx);
+        assert_eq!(balance(&sender), 0);
+        assert_eq!(balance(&recipient), total - expected_fee.to_satoshis());
+
+        // Nothing is left to sweep, and a fee eating the coins down to dust is refused
+        let empty = Transaction::new_sweep_transaction(
+            &sender,
+            &recipient,
+            FeePriority::Low,
+            &utxo_set,
+            &wallets,
+        );
+        assert!(
+            matches!(&empty, Err(BlockchainError::Transaction(message)) if message.contains("Nothing to sweep")),
+            "{empty:?}"
+        );
+        let swept = balance(&recipient);
+        let dust = Transaction::new_sweep_transaction_with_explicit_fee(
+            &recipient,
+            &sender,
+            swept - DUST_THRESHOLD,
+            &utxo_set,
+            &wallets,
+        );
+        assert!(dust.unwrap_err().to_string().contains("dust threshold"));
+        let tx = Transaction::new_sweep_transaction_with_explicit_fee(
+            &recipient,
+            &sender,
+            swept - DUST_THRESHOLD - 1,
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap();
+        let tx = only(tx);
+        assert_eq!(tx.get_vout()[0].get_value(), DUST_THRESHOLD + 1);
+        assert!(tx.verify(utxo_set.get_blockchain()));
+    }
+
+    #[test]
+    fn test_consolidation_merges_small_outputs() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&owner, 20, 6_000);
+        let pub_key_hash = crate::wallet::address_pub_key_hash(&owner).unwrap();
+        let outputs = || utxo_set.find_utxo(&pub_key_hash);
+        assert_eq!(outputs().len(), 20);
+
+        let tx = only(
+            Transaction::new_consolidation_transaction(
+                &owner,
+                50,
+                FeePriority::Normal,
+                &utxo_set,
+                &wallets,
+            )
+            .unwrap(),
+        );
+        assert_eq!(tx.get_vin().len(), 20);
+        assert_eq!(tx.get_vout().len(), 1);
+        assert_eq!(tx.get_vout()[0].get_address(), owner);
+        let expected_fee = FeeCalculator::calculate_fee(
+            FeeCalculator::estimate_transaction_size(20, 1),
+            Some(FeePriority::Normal),
+        );
+        assert_eq!(tx.get_fee(), expected_fee);
+        assert!(tx.verify(utxo_set.get_blockchain()));
+
+        confirm(&utxo_set, &tx);
+        let merged = outputs();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(
+            merged[0].get_value(),
+            20 * 6_000 - expected_fee.to_satoshis()
+        );
+
+        // One output is nothing to merge
+        let single = Transaction::new_consolidation_transaction(
+            &owner,
+            50,
+            FeePriority::Normal,
+            &utxo_set,
+            &wallets,
+        );
+        assert!(single
+            .unwrap_err()
+            .to_string()
+            .contains("Nothing to consolidate"));
+
+        // A fee worth more than the allowed share of the coins is refused
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&owner, 3, 2_000);
+        let consolidate_paying = |fee: u64| {
+            TransactionBuilder::new(&utxo_set)
+                .from(&owner)
+                .add_output(&owner, 0)
+                .fee_policy(FeePolicy::SweepAllWithFee(fee.into()))
+                .consolidate(50, 10)
+                .build_and_sign_all(&wallets)
+        };
+        let costly = consolidate_paying(601);
+        assert!(
+            costly
+                .as_ref()
+                .unwrap_err()
+                .to_string()
+                .contains("more than 10%"),
+            "{costly:?}"
+        );
+        let tx = only(consolidate_paying(600).unwrap());
+        assert_eq!(tx.get_vout()[0].get_value(), 3 * 2_000 - 600);
+    }
+
+    #[test]
+    fn test_consolidation_takes_the_smallest_outputs_first() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let values = [90_000, 30_000, 70_000, 20_000, 80_000];
+        let (_temp_dir, utxo_set) = chain_with_coins(&owner, &values);
+
+        let tx = only(
+            Transaction::new_consolidation_transaction(
+                &owner,
+                3,
+                FeePriority::Low,
+                &utxo_set,
+                &wallets,
+            )
+            .unwrap(),
+        );
+        assert_eq!(tx.get_vin().len(), 3);
+        assert_eq!(input_total(&tx, &utxo_set), 20_000 + 30_000 + 70_000);
+
+        // A node leaves out the outputs its pending transactions already spend
+        let context = NodeContext::default().with_wallets(SharedWallets::from(wallets));
+        context.memory_pool().add_safe(tx.clone()).unwrap();
+        let rest = only(
+            Transaction::new_consolidation_transaction_with_context(
+                &owner,
+                3,
+                FeePriority::Low,
+                &utxo_set,
+                &context,
+            )
+            .unwrap(),
+        );
+        assert_eq!(rest.get_vin().len(), 2);
+        assert_eq!(input_total(&rest, &utxo_set), 80_000 + 90_000);
+    }
+
+    // A chain where `owner` holds `count` outputs of `value` satoshis, all paid by one
+    // confirmed transaction rather than a block each
+    fn chain_with_many_coins(owner: &str, count: usize, value: u64) -> (TempDir, UTXOSet) {
+        let funder = Wallets::new().create_wallet().unwrap();
+        let (temp_dir, utxo_set) = chain_paying(&[(funder.as_str(), count as u64 * value + 1_000)]);
+        let mut builder = TransactionBuilder::new(&utxo_set)
+            .from(&funder)
+            .fee_policy(FeePolicy::Explicit(Amount::from(1_000)));
+        for _ in 0..count {
+            builder = builder.add_output(owner, value);
+        }
+        let funding = builder.build_and_sign(&Wallets::new()).unwrap();
+        confirm(&utxo_set, &funding);
+        (temp_dir, utxo_set)
+    }
+
+    #[test]
+    fn test_oversized_transactions_are_refused_before_verification() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&owner, 1, 50_000);
+        let txid = utxo_set
+            .list_unspent_safe(&crate::wallet::address_pub_key_hash(&owner).unwrap())
+            .unwrap()[0]
+            .0
+            .clone();
+
+        // A key just long enough to push the transaction past the cap
+        let mut tx = Transaction {
+            id: vec![],
+            vin: vec![TXInput {
+                txid: HEXLOWER.decode(txid.as_bytes()).unwrap(),
+                vout: 0,
+                signature: vec![0; SIGNATURE_LEN],
+                pub_key: vec![0; MAX_TRANSACTION_SIZE],
+            }],
+            vout: vec![TXOutput::new(40_000, TEST_ADDRESS).unwrap()],
+            fee: Amount::from(10_000),
+        };
+        tx.id = tx.compute_id();
+        let size = tx.serialize().unwrap().len();
+        assert!(size > MAX_TRANSACTION_SIZE && size < MAX_TRANSACTION_SIZE + 200);
+        let err = tx.check_limits().unwrap_err();
+        assert!(
+            matches!(&err, BlockchainError::TransactionTooLarge(message) if message.contains("consolidate")),
+            "{err:?}"
+        );
+        let pooled = crate::storage::MemoryPool::new().add_safe(tx.clone());
+        assert!(
+            matches!(pooled, Err(BlockchainError::TransactionTooLarge(_))),
+            "{pooled:?}"
+        );
+
+        // Nothing to spend or nowhere to pay is malformed whatever the size
+        let mut empty = tx.clone();
+        empty.vin.clear();
+        assert!(empty
+            .check_limits()
+            .unwrap_err()
+            .to_string()
+            .contains("inputs and outputs"));
+        let mut empty = tx;
+        empty.vout.clear();
+        assert!(empty
+            .check_limits()
+            .unwrap_err()
+            .to_string()
+            .contains("inputs and outputs"));
+    }
+
+    #[test]
+    fn test_payment_over_the_input_limit_is_refused_and_a_sweep_splits() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let count = 2_000;
+        let (_temp_dir, utxo_set) = chain_with_many_coins(&owner, count, 10_000);
+
+        // Paying nearly everything needs more inputs than one transaction may spend
+        let err = Transaction::new_utxo_transaction_with_explicit_fee(
+            &owner,
+            &recipient,
+            (count as u64 - 10) * 10_000,
+            1_000,
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&err, BlockchainError::TransactionTooLarge(message) if message.contains("consolidate")),
+            "{err:?}"
+        );
+
+        let sweeps = Transaction::new_sweep_transaction(
+            &owner,
+            &recipient,
+            FeePriority::Low,
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap();
+        assert!(sweeps.len() > 1, "{}", sweeps.len());
+        let mut spent = HashSet::new();
+        for tx in &sweeps {
+            tx.check_limits().unwrap();
+            for input in tx.get_vin() {
+                assert!(spent.insert((input.get_txid().to_vec(), input.get_vout())));
+            }
+        }
+        assert_eq!(spent.len(), count);
+
+        let blockchain = utxo_set.get_blockchain();
+        let block = blockchain
+            .mine_block_with_fees(&sweeps, TEST_ADDRESS)
+            .unwrap();
+        utxo_set.update_safe(&block).unwrap();
+        assert_eq!(block.get_transactions().len(), sweeps.len() + 1);
+        assert!(utxo_set
+            .find_utxo(&crate::wallet::address_pub_key_hash(&owner).unwrap())
+            .is_empty());
+        let fees: u64 = sweeps.iter().map(|tx| tx.get_fee().to_satoshis()).sum();
+        let received: u64 = utxo_set
+            .find_utxo(&crate::wallet::address_pub_key_hash(&recipient).unwrap())
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .sum();
+        assert_eq!(received, count as u64 * 10_000 - fees);
+    }
+
+    #[test]
+    fn test_payment_to_the_senders_own_address_is_refused() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_small_coins(&owner, 2, 50_000);
+
+        let to_self = Transaction::new_utxo_transaction(&owner, &owner, 1_000, &utxo_set, &wallets);
+        assert!(
+            matches!(&to_self, Err(BlockchainError::SelfTransfer(address)) if *address == owner),
+            "{to_self:?}"
+        );
+        assert!(to_self
+            .unwrap_err()
+            .to_string()
+            .contains(&format!("consolidate {owner}")));
+        let with_memo = Transaction::new_utxo_transaction_with_memo(
+            &owner,
+            &owner,
+            1_000,
+            b"note",
+            FeePriority::Normal,
+            &utxo_set,
+            &wallets,
+        );
+        assert!(matches!(with_memo, Err(BlockchainError::SelfTransfer(_))));
+    }
+
+    // The coinbase outpoint paying the block at `height`
+    fn coinbase_outpoint(utxo_set: &UTXOSet, height: usize) -> (Vec<u8>, usize) {
+        let block = utxo_set
+            .get_blockchain()
+            .get_block_at_height(height)
+            .unwrap()
+            .unwrap();
+        (block.get_transactions()[0].get_id().to_vec(), 0)
+    }
+
+    #[test]
+    fn test_id_is_the_hash_of_the_trimmed_form_before_and_after_signing() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_coins(&sender, &[50_000]);
+
+        let raw = Transaction::new_raw(
+            &[coinbase_outpoint(&utxo_set, 1)],
+            vec![TXOutput::new(49_000, &recipient).unwrap()],
+        )
+        .unwrap();
+        let mut tx = raw.clone();
+        tx.sign_raw(
+            wallets.get_wallet(&sender).unwrap(),
+            utxo_set.get_blockchain(),
+        )
+        .unwrap();
+
+        // Recomputed from the signed bytes, the id comes out as the trimmed form's hash
+        let received = Transaction::deserialize(&tx.serialize().unwrap()).unwrap();
+        assert_eq!(received.compute_id(), received.get_id());
+        assert_eq!(received.trimmed_copy().hash(), received.get_id());
+        // Signing only set the fee, which the unsigned copy didn't have yet
+        assert_eq!(raw.with_fee(1_000).get_id(), tx.get_id());
+
+        // Neither keys nor signatures move it, but everything else does
+        let mut resigned = tx.clone();
+        resigned.corrupt_signatures();
+        assert!(resigned.check_id(false).is_ok());
+        let mut repriced = tx.clone();
+        repriced.set_fee(2_000);
+        assert!(repriced.check_id(false).is_err());
+        assert!(!repriced.verify(utxo_set.get_blockchain()));
+        assert!(tx.verify(utxo_set.get_blockchain()));
+    }
+
+    #[test]
+    fn test_legacy_ids_are_accepted_only_in_blocks_that_may_carry_them() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_coins(&sender, &[50_000]);
+        let blockchain = utxo_set.get_blockchain();
+        let context = NodeContext::default();
+
+        // Stored the way transactions were before their ids left the keys out
+        let signed = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output(&recipient, 1_000)
+            .build_and_sign(&wallets)
+            .unwrap();
+        let mut legacy = signed.clone();
+        legacy.id = legacy.legacy_id();
+        assert!(legacy.check_id(false).is_err());
+        assert!(legacy.check_id(true).is_ok());
+        // New transactions are held to the trimmed id
+        assert!(!legacy.verify(blockchain));
+        assert!(MemoryPool::new().add_safe(legacy.clone()).is_err());
+
+        let mut block = blockchain
+            .new_block_template(std::slice::from_ref(&signed), TEST_ADDRESS, &context)
+            .unwrap();
+        let coinbase = block.get_transactions()[0].clone();
+        block.set_transactions(&[coinbase, legacy.clone()]).unwrap();
+        block.run_proof_of_work();
+        let record_network = |network: Network| {
+            blockchain
+                .get_db()
+                .insert(blockchain::NETWORK_KEY, network.name())
+                .unwrap();
+        };
+        record_network(Network::Regtest);
+        assert!(!blockchain.allows_legacy_txids(block.get_height()).unwrap());
+        assert!(blockchain.validate_and_add_block(&block).is_err());
+
+        // A network whose history predates trimmed ids still takes its old blocks
+        record_network(Network::Mainnet);
+        assert!(block.get_height() < Network::Mainnet.trimmed_txid_height());
+        blockchain.validate_and_add_block(&block).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+        assert!(blockchain
+            .find_transaction(legacy.get_id())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_raw_transaction_is_signed_and_mined() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_coins(&sender, &[50_000, 20_000]);
+
+        let inputs = [
+            coinbase_outpoint(&utxo_set, 1),
+            coinbase_outpoint(&utxo_set, 2),
+        ];
+        let outputs = vec![
+            TXOutput::new(60_000, &recipient).unwrap(),
+            TXOutput::new(9_000, &sender).unwrap(),
+        ];
+        let raw = Transaction::new_raw(&inputs, outputs).unwrap();
+        let mut tx = Transaction::from_hex(&raw.to_hex().unwrap()).unwrap();
+        assert_eq!(tx.get_id(), raw.get_id());
+        assert!(tx.get_vin().iter().all(|input| !input.is_signed()));
+        assert!(!tx.verify(utxo_set.get_blockchain()));
+
+        let wallet = wallets.get_wallet(&sender).unwrap();
+        tx.sign_raw(wallet, utxo_set.get_blockchain()).unwrap();
+        assert_eq!(tx.get_fee(), 1_000);
+        assert_ne!(tx.get_id(), raw.get_id());
+        tx.check_structure().unwrap();
+        assert!(tx.verify(utxo_set.get_blockchain()));
+
+        confirm(&utxo_set, &tx);
+        let pub_key_hash = crate::wallet::address_pub_key_hash(&recipient).unwrap();
+        let received: u64 = utxo_set
+            .find_utxo(&pub_key_hash)
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .sum();
+        assert_eq!(received, 60_000);
+    }
+
+    #[test]
+    fn test_raw_transaction_signing_checks_owner_and_value() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let stranger = wallets.create_wallet().unwrap();
+        let (_temp_dir, utxo_set) = chain_with_coins(&sender, &[10_000]);
+        let input = coinbase_outpoint(&utxo_set, 1);
+
+        let tx = Transaction::new_raw(
+            std::slice::from_ref(&input),
+            vec![TXOutput::new(5_000, &stranger).unwrap()],
+        )
+        .unwrap();
+        let mut signed = tx.clone();
+        let err = signed
+            .sign_raw(
+                wallets.get_wallet(&stranger).unwrap(),
+                utxo_set.get_blockchain(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("can't sign for"), "{err}");
+
+        let mut overspent = Transaction::new_raw(
+            std::slice::from_ref(&input),
+            vec![TXOutput::new(10_001, &stranger).unwrap()],
+        )
+        .unwrap();
+        let err = overspent
+            .sign_raw(
+                wallets.get_wallet(&sender).unwrap(),
+                utxo_set.get_blockchain(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("more than the 10000"), "{err}");
+
+        // The same outpoint twice would spend it twice
+        let doubled = Transaction::new_raw(
+            &[input.clone(), input],
+            vec![TXOutput::new(5_000, &stranger).unwrap()],
+        );
+        assert!(doubled.is_err());
+    }
+
+    #[test]
+    fn test_malformed_transaction_hex_is_refused() {
+        let tx = Transaction::new_raw(
+            &[(vec![7; 32], 0)],
+            vec![TXOutput::new(5_000, TEST_ADDRESS).unwrap()],
+        )
+        .unwrap();
+        let hex = tx.to_hex().unwrap();
+        assert_eq!(
+            Transaction::from_hex(&format!("  {}\n", hex.to_uppercase()))
+                .unwrap()
+                .get_id(),
+            tx.get_id()
+        );
+
+        let garbage = Transaction::from_hex("not hex at all").unwrap_err();
+        assert!(
+            garbage.to_string().contains("Invalid transaction hex"),
+            "{garbage}"
+        );
+        assert!(Transaction::from_hex("abc").is_err());
+        assert!(Transaction::from_hex(&hex[..hex.len() / 2]).is_err());
+        assert!(Transaction::from_hex("").is_err());
+    }
+
+    #[test]
+    fn test_outputs_stay_within_max_money() {
+        let output = |value: u64| TXOutput {
+            value: value.into(),
+            pub_key_hash: vec![0; PUB_KEY_HASH_LEN],
+        };
+        let with_outputs = |values: &[u64]| Transaction {
+            id: vec![],
+            vin: vec![],
+            vout: values.iter().copied().map(output).collect(),
+            fee: Amount::ZERO,
+        };
+
+        assert!(with_outputs(&[MAX_MONEY]).check_money_range().is_ok());
+        assert!(with_outputs(&[MAX_MONEY + 1]).check_money_range().is_err());
+        // Each output alone is fine, together they're worth more than can exist
+        let halves = with_outputs(&[MAX_MONEY / 2 + 1, MAX_MONEY / 2 + 1]);
+        assert!(halves.check_money_range().is_err());
+        assert!(with_outputs(&[u64::MAX, u64::MAX])
+            .check_money_range()
+            .is_err());
+    }
+
+    #[test]
+    fn test_unversioned_transactions_and_blocks_still_load() {
+        let coinbase = Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap();
+        let block = Block::generate_genesis_block(&coinbase).unwrap();
+
+        // The bare layout earlier releases stored and sent
+        let legacy_tx = serialize(&coinbase).unwrap();
+        let legacy_block = serialize(&block).unwrap();
+        assert_eq!(coinbase.serialize_as(0).unwrap(), legacy_tx);
+        assert_ne!(coinbase.serialize().unwrap(), legacy_tx);
+        let loaded_tx = Transaction::deserialize(&legacy_tx).unwrap();
+        let loaded_block = Block::deserialize(&legacy_block).unwrap();
+        assert_eq!(
+            loaded_tx.serialize().unwrap(),
+            coinbase.serialize().unwrap()
+        );
+        assert_eq!(
+            loaded_block.serialize().unwrap(),
+            block.serialize().unwrap()
+        );
+
+        let round_trip = Block::deserialize(&block.serialize().unwrap()).unwrap();
+        assert_eq!(round_trip.get_hash(), block.get_hash());
+
+        // The txid is taken over the bare layout, whatever format the bytes came in
+        let id_less = Transaction {
+            id: vec![],
+            ..coinbase.clone()
+        };
+        assert_eq!(
+            coinbase.get_id(),
+            sha256_digest(&serialize(&id_less).unwrap()).as_slice()
+        );
+
+        let newer_version = crate::utils::FORMAT_VERSION + 1;
+        let mut newer = block.serialize().unwrap();
+        newer[1] = newer_version;
+        let err = Block::deserialize(&newer).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("unsupported format version {newer_version}")),
+            "{err}"
+        );
+    }
+
+    // Builders on a chain where a fresh wallet holds coins of `values`
+    fn funded_wallet(values: &[u64]) -> (Wallet, TempDir, UTXOSet) {
+        let wallet = Wallet::new().unwrap();
+        let (temp_dir, utxo_set) = chain_with_coins(&wallet.get_address(), values);
+        (wallet, temp_dir, utxo_set)
+    }
+
+    #[test]
+    fn test_builder_pays_every_output_and_the_change() {
+        let (wallet, _temp_dir, utxo_set) = funded_wallet(&[5_000, 5_000]);
+        let other = Wallet::new().unwrap().get_address();
+        let tx = TransactionBuilder::new(&utxo_set)
+            .from(&wallet.get_address())
+            .add_output(TEST_ADDRESS, 3_000)
+            .add_output(&other, 4_000)
+            .fee_policy(FeePolicy::Explicit(Amount::from(100)))
+            .build_and_sign(&wallet)
+            .unwrap();
+
+        let values: Vec<u64> = tx
+            .get_vout()
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .collect();
+        assert_eq!(values, vec![3_000, 4_000, 2_900]);
+        assert_eq!(tx.get_vout()[2].get_address(), wallet.get_address());
+        assert_eq!(tx.get_fee(), 100);
+        assert!(tx.verify(utxo_set.get_blockchain()));
+    }
+
+    #[test]
+    fn test_builder_prices_by_priority_from_the_final_counts() {
+        let (wallet, _temp_dir, utxo_set) = funded_wallet(&[2_000, 2_000, 2_000]);
+        let tx = TransactionBuilder::new(&utxo_set)
+            .from(&wallet.get_address())
+            .add_output(TEST_ADDRESS, 3_000)
+            .fee_policy(FeePolicy::Priority(FeePriority::Low))
+            .build_and_sign(&wallet)
+            .unwrap();
+
+        let size =
+            FeeCalculator::estimate_transaction_size(tx.get_vin().len(), tx.get_vout().len());
+        assert_eq!(
+            tx.get_fee(),
+            FeeCalculator::calculate_fee(size, Some(FeePriority::Low))
+        );
+        assert!(tx.verify(utxo_set.get_blockchain()));
+    }
+
+    #[test]
+    fn test_preview_prices_the_payment_that_gets_built() {
+        let (wallet, _temp_dir, utxo_set) = funded_wallet(&[1_000, 1_000, 1_000, 1_000]);
+        let fixed = NodeContext::new(FeeMode::Fixed { amount: 7 }).unwrap();
+        let dynamic = NodeContext::new(FeeMode::Dynamic {
+            config: DynamicFeeConfig::with_base_fee(5),
+        })
+        .unwrap();
+        let builder = |context, priority| {
+            TransactionBuilder::new(&utxo_set)
+                .from(&wallet.get_address())
+                .add_output(TEST_ADDRESS, 2_500)
+                .fee_policy(FeePolicy::Priority(priority))
+                .with_context(context)
+        };
+
+        for context in [&fixed, &dynamic] {
+            for priority in FeePriority::ALL {
+                let preview = builder(context, priority).build_unsigned_preview().unwrap();
+                let tx = builder(context, priority).build_and_sign(&wallet).unwrap();
+                assert_eq!(preview.fee, tx.get_fee());
+                assert_eq!(preview.input_count, tx.get_vin().len());
+                assert_eq!(preview.output_count, tx.get_vout().len());
+                assert_eq!(
+                    preview.size,
+                    FeeCalculator::estimate_transaction_size(
+                        tx.get_vin().len(),
+                        tx.get_vout().len()
+                    )
+                );
+                assert_eq!(preview.amount, 2_500);
+                assert_eq!(
+                    preview.total_debit(),
+                    tx.get_fee().saturating_add(2_500.into())
+                );
+                assert_eq!(preview.balance, 4_000);
+                assert!(preview.sufficient);
+            }
+        }
+    }
+
+    #[test]
+    fn test_preview_of_a_payment_above_the_balance_falls_short() {
+        let (wallet, _temp_dir, utxo_set) = funded_wallet(&[1_000, 1_000]);
+        let context = NodeContext::new(FeeMode::Fixed { amount: 10 }).unwrap();
+        let preview = |amount: u64| {
+            TransactionBuilder::new(&utxo_set)
+                .from(&wallet.get_address())
+                .add_output(TEST_ADDRESS, amount)
+                .with_context(&context)
+                .build_unsigned_preview()
+                .unwrap()
+        };
+
+        // Exactly the balance once the fee is in
+        let covered = preview(1_990);
+        assert!(covered.sufficient);
+        assert_eq!(covered.total_debit(), covered.balance);
+
+        // One satoshi more and the preview spends every coin, still without failing
+        let short = preview(1_991);
+        assert!(!short.sufficient);
+        assert_eq!((short.input_count, short.output_count), (2, 1));
+        assert_eq!(short.fee, 10);
+        assert_eq!(short.total_debit(), 2_001);
+        assert!(short.total_debit() > short.balance);
+    }
+
+    // A wallet holding one 10-coin output and fifty of 0.001 coins from one payment, mature
+    // coinbases of 20, 30 and 40 coins, oldest first, and a fresh coinbase of 100 coins
+    fn wallet_with_mixed_coins() -> (Wallet, TempDir, UTXOSet) {
+        let wallet = Wallet::new().unwrap();
+        let owner = wallet.get_address();
+        let funder = Wallet::new().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+
+        let mut tip = blockchain
+            .get_block(&blockchain.get_tip_hash())
+            .unwrap()
+            .unwrap();
+        let mut mine = |coinbase_to: &str, reward: u64, payments: Vec<Transaction>| {
+            let mut transactions =
+                vec![Transaction::new_coinbase_tx_with_reward(coinbase_to, reward).unwrap()];
+            transactions.extend(payments);
+            let block = Block::new_test_block(
+                tip.get_timestamp() + 1_000,
+                tip.get_hash().to_string(),
+                &transactions,
+                tip.get_height() + 1,
+                1,
+            )
+            .unwrap();
+            blockchain.add_block(&block).unwrap();
+            utxo_set.update(&block);
+            tip = block;
+        };
+
+        mine(&funder.get_address(), 11 * SATOSHIS_PER_COIN, vec![]);
+        for coins in [20, 30, 40] {
+            mine(&owner, coins * SATOSHIS_PER_COIN, vec![]);
+        }
+        let mut funding = TransactionBuilder::new(&utxo_set)
+            .from(&funder.get_address())
+            .add_output(&owner, 10 * SATOSHIS_PER_COIN);
+        for _ in 0..50 {
+            funding = funding.add_output(&owner, SATOSHIS_PER_COIN / 1_000);
+        }
+        let funding = funding
+            .fee_policy(FeePolicy::Explicit(Amount::from(100_000)))
+            .build_and_sign(&funder)
+            .unwrap();
+        mine(TEST_ADDRESS, SATOSHIS_PER_COIN, vec![funding]);
+        // Enough blocks on top for the coinbase at height 4 to mature, but not the last one
+        for _ in 0..9 {
+            mine(TEST_ADDRESS, SATOSHIS_PER_COIN, vec![]);
+        }
+        mine(&owner, 100 * SATOSHIS_PER_COIN, vec![]);
+        (wallet, temp_dir, utxo_set)
+    }
+
+    // What each input of `tx` spends, in satoshis, smallest first
+    fn spent_values(tx: &Transaction, utxo_set: &UTXOSet) -> Vec<u64> {
+        let mut values: Vec<u64> = tx
+            .spent_outputs(utxo_set.get_blockchain())
+            .unwrap()
+            .iter()
+            .map(|output| output.get_value().to_satoshis())
+            .collect();
+        values.sort_unstable();
+        values
+    }
+
+    fn pay_by_strategy(
+        wallet: &Wallet,
+        utxo_set: &UTXOSet,
+        amount: u64,
+        strategy: SelectionStrategy,
+    ) -> Transaction {
+        let tx = TransactionBuilder::new(utxo_set)
+            .from(&wallet.get_address())
+            .add_output(TEST_ADDRESS, amount)
+            .coin_selection(strategy)
+            .with_fee_for_size(|size| Amount::from_satoshis(size as u64))
+            .build_and_sign(wallet)
+            .unwrap();
+        assert!(tx.verify(utxo_set.get_blockchain()));
+        tx
+    }
+
+    #[test]
+    fn test_coin_selection_strategies_pick_different_coins() {
+        let (wallet, _temp_dir, utxo_set) = wallet_with_mixed_coins();
+        let small = SATOSHIS_PER_COIN / 1_000;
+        let amount = 20 * small;
+
+        // The fresh 100-coin coinbase is the largest and the newest, and neither takes it
+        let largest = pay_by_strategy(&wallet, &utxo_set, amount, SelectionStrategy::LargestFirst);
+        assert_eq!(spent_values(&largest, &utxo_set), [40 * SATOSHIS_PER_COIN]);
+        let oldest = pay_by_strategy(&wallet, &utxo_set, amount, SelectionStrategy::OldestFirst);
+        assert_eq!(spent_values(&oldest, &utxo_set), [20 * SATOSHIS_PER_COIN]);
+
+        // Twenty of the small coins pay the amount, the fee needs a twenty-first
+        let smallest =
+            pay_by_strategy(&wallet, &utxo_set, amount, SelectionStrategy::SmallestFirst);
+        assert_eq!(spent_values(&smallest, &utxo_set), vec![small; 21]);
+        assert_eq!(
+            smallest.get_vout()[1].get_value(),
+            21 * small - amount - smallest.get_fee().to_satoshis()
+        );
+        // More inputs, more bytes, a larger fee
+        assert!(smallest.get_fee() > largest.get_fee());
+        assert_eq!(largest.get_fee(), oldest.get_fee());
+
+        // Change of no more than dust goes to the fee rather than into an output
+        let size_with_change = FeeCalculator::estimate_transaction_size_with_data(2, 2, 0) as u64;
+        let amount = 2 * small - size_with_change - 100;
+        let folded = pay_by_strategy(&wallet, &utxo_set, amount, SelectionStrategy::SmallestFirst);
+        assert_eq!(folded.get_vout().len(), 1);
+        assert_eq!(folded.get_fee(), size_with_change + 100);
+    }
+
+    #[test]
+    fn test_branch_and_bound_avoids_change_or_falls_back_to_largest() {
+        let (wallet, _temp_dir, utxo_set) = wallet_with_mixed_coins();
+        let small = SATOSHIS_PER_COIN / 1_000;
+
+        // Three small coins pay this and the fee of a transaction without change exactly
+        let fee = FeeCalculator::estimate_transaction_size_with_data(3, 1, 0) as u64;
+        let exact = pay_by_strategy(
+            &wallet,
+            &utxo_set,
+            3 * small - fee,
+            SelectionStrategy::BranchAndBound,
+        );
+        assert_eq!(spent_values(&exact, &utxo_set), vec![small; 3]);
+        assert_eq!(exact.get_vout().len(), 1);
+        assert_eq!(exact.get_fee(), fee);
+
+        // Every coin is a multiple of the small ones, so nothing lands this close to 1.8 of
+        // them; the largest mature coin pays and takes the change
+        let fallback = pay_by_strategy(
+            &wallet,
+            &utxo_set,
+            18 * small / 10,
+            SelectionStrategy::BranchAndBound,
+        );
+        assert_eq!(spent_values(&fallback, &utxo_set), [40 * SATOSHIS_PER_COIN]);
+        assert_eq!(fallback.get_vout().len(), 2);
+    }
+
+    #[test]
+    fn test_builder_sweeps_everything_into_one_output() {
+        let (wallet, _temp_dir, utxo_set) = funded_wallet(&[1_000, 2_000, 3_000]);
+        let sweep = || {
+            TransactionBuilder::new(&utxo_set)
+                .from(&wallet.get_address())
+                .fee_policy(FeePolicy::SweepAllWithFee(Amount::from(600)))
+        };
+
+        let tx = sweep()
+            .add_output(TEST_ADDRESS, 0)
+            .build_and_sign(&wallet)
+            .unwrap();
+        assert_eq!(tx.get_vin().len(), 3);
+        assert_eq!(tx.get_vout().len(), 1);
+        assert_eq!(tx.get_vout()[0].get_value(), 5_400);
+        assert!(tx.verify(utxo_set.get_blockchain()));
+
+        let err = sweep()
+            .add_output(TEST_ADDRESS, 100)
+            .build_and_sign(&wallet)
+            .unwrap_err();
+        assert!(err.to_string().contains("amount must be 0"), "{err}");
+        let other = Wallet::new().unwrap().get_address();
+        let err = sweep()
+            .add_output(TEST_ADDRESS, 0)
+            .add_output(&other, 0)
+            .build_and_sign(&wallet)
+            .unwrap_err();
+        assert!(err.to_string().contains("2 were added"), "{err}");
+    }
+
+    #[test]
+    fn test_builder_leaves_coins_pending_transactions_spend() {
+        let (wallet, _temp_dir, utxo_set) = funded_wallet(&[1_000, 2_000, 3_000]);
+        let context = NodeContext::default();
+        let pay = |amount: u64| {
+            TransactionBuilder::new(&utxo_set)
+                .from(&wallet.get_address())
+                .add_output(TEST_ADDRESS, amount)
+                .fee_policy(FeePolicy::Explicit(Amount::from(100)))
+                .with_context(&context)
+                .build_and_sign(&wallet)
+        };
+        let first = pay(500).unwrap();
+        context.memory_pool().add_safe(first.clone()).unwrap();
+
+        // A second payment funds itself from the other coins, as a node would accept it
+        let second = pay(500).unwrap();
+        for input in second.get_vin() {
+            assert!(first
+                .get_vin()
+                .iter()
+                .all(|spent| (spent.get_txid(), spent.get_vout())
+                    != (input.get_txid(), input.get_vout())));
+        }
+        context.memory_pool().add_safe(second.clone()).unwrap();
+
+        // A sweep takes only the coins neither of them spends
+        let left = 6_000 - input_total(&first, &utxo_set) - input_total(&second, &utxo_set);
+        let sweep = TransactionBuilder::new(&utxo_set)
+            .from(&wallet.get_address())
+            .add_output(TEST_ADDRESS, 0)
+            .fee_policy(FeePolicy::SweepAllWithFee(Amount::from(100)))
+            .with_context(&context)
+            .build_and_sign(&wallet);
+        match sweep {
+            Ok(sweep) => assert_eq!(input_total(&sweep, &utxo_set), left),
+            Err(e) => assert_eq!(left, 0, "{e}"),
+        }
+    }
+
+    #[test]
+    fn test_builder_misuse_is_reported() {
+        let (wallet, _temp_dir, utxo_set) = funded_wallet(&[5_000]);
+        let from = wallet.get_address();
+        let message = |builder: TransactionBuilder| {
+            builder
+                .build_and_sign(&wallet)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        };
+
+        let no_sender = TransactionBuilder::new(&utxo_set).add_output(TEST_ADDRESS, 1_000);
+        assert!(message(no_sender).contains("no address to spend from"));
+        let no_outputs = TransactionBuilder::new(&utxo_set).from(&from);
+        assert!(message(no_outputs).contains("no outputs"));
+        let conflicting = TransactionBuilder::new(&utxo_set)
+            .from(&from)
+            .add_output(TEST_ADDRESS, 1_000)
+            .fee_policy(FeePolicy::Priority(FeePriority::High))
+            .fee_policy(FeePolicy::Explicit(Amount::from(100)));
+        let conflict = message(conflicting);
+        assert!(conflict.contains("Conflicting fee policies"), "{conflict}");
+        // Repeating the same policy is no conflict
+        assert!(TransactionBuilder::new(&utxo_set)
+            .from(&from)
+            .add_output(TEST_ADDRESS, 1_000)
+            .fee_policy(FeePolicy::Explicit(Amount::from(100)))
+            .fee_policy(FeePolicy::Explicit(Amount::from(100)))
+            .build_and_sign(&wallet)
+            .is_ok());
+
+        let zero = TransactionBuilder::new(&utxo_set)
+            .from(&from)
+            .add_output(TEST_ADDRESS, 0);
+        assert!(message(zero).contains("must be positive"));
+        let to_self = TransactionBuilder::new(&utxo_set)
+            .from(&from)
+            .add_output(&from, 1_000);
+        assert!(matches!(
+            to_self.build_and_sign(&wallet),
+            Err(BlockchainError::SelfTransfer(_))
+        ));
+        // A lone wallet signs for nobody else
+        let stranger = Wallet::new().unwrap();
+        let unsigned = TransactionBuilder::new(&utxo_set)
+            .from(&from)
+            .add_output(TEST_ADDRESS, 1_000)
+            .build_and_sign(&stranger)
+            .map(|_| ())
+            .unwrap_err();
+        assert!(
+            unsigned.to_string().contains("Wallet not found"),
+            "{unsigned}"
+        );
+    }
+
+    #[test]
+    fn test_account_payment_picks_coins_by_the_wallet_default() {
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::open(&temp_dir.path().join("accounts.dat")).unwrap();
+        let members: Vec<String> = (0..3).map(|_| wallets.create_wallet().unwrap()).collect();
+        wallets.create_account("spending").unwrap();
+        for member in &members {
+            wallets.assign_address("spending", member).unwrap();
+        }
+        let (_chain_dir, utxo_set) = chain_paying(&[
+            (&members[0], 5_000),
+            (&members[1], 4_000),
+            (&members[2], 3_000),
+        ]);
+        let pay = |wallets: &Wallets| {
+            Transaction::new_account_transaction(
+                "spending",
+                TEST_ADDRESS,
+                1_000,
+                FeePriority::Normal,
+                &utxo_set,
+                wallets,
+            )
+            .unwrap()
+        };
+
+        wallets
+            .set_default_coin_selection(Some(SelectionStrategy::LargestFirst))
+            .unwrap();
+        assert_eq!(spent_values(&pay(&wallets), &utxo_set), [5_000]);
+        wallets
+            .set_default_coin_selection(Some(SelectionStrategy::SmallestFirst))
+            .unwrap();
+        assert_eq!(spent_values(&pay(&wallets), &utxo_set), [3_000]);
+        // A strategy given for the payment beats the default
+        let largest = Transaction::new_account_transaction_with_coin_selection(
+            "spending",
+            TEST_ADDRESS,
+            1_000,
+            FeePriority::Normal,
+            Some(SelectionStrategy::LargestFirst),
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap();
+        assert_eq!(spent_values(&largest, &utxo_set), [5_000]);
+    }
+
+    #[test]
+    fn test_account_payment_combines_coins_of_several_keys() {
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::open(&temp_dir.path().join("accounts.dat")).unwrap();
+        let members: Vec<String> = (0..3).map(|_| wallets.create_wallet().unwrap()).collect();
+        wallets.create_account("spending").unwrap();
+        for member in &members {
+            wallets.assign_address("spending", member).unwrap();
+        }
+        let change = wallets
+            .get_account("spending")
+            .unwrap()
+            .change_address()
+            .unwrap()
+            .to_string();
+        let (_chain_dir, utxo_set) = chain_paying(&[
+            (&members[0], 5_000),
+            (&members[1], 4_000),
+            (&members[2], 3_000),
+        ]);
+        assert_eq!(
+            wallets.account_balance("spending", &utxo_set).unwrap(),
+            12_000
+        );
+
+        // No address holds 7,000 on its own
+        let tx = Transaction::new_account_transaction(
+            "spending",
+            TEST_ADDRESS,
+            7_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &wallets,
+        )
+        .unwrap();
+        let keys: HashSet<&[u8]> = tx.get_vin().iter().map(TXInput::get_pub_key).collect();
+        assert!(keys.len() >= 2, "spent from {} key(s)", keys.len());
+        assert_eq!(tx.get_vout()[0].get_value(), 7_000);
+        assert_eq!(tx.get_vout()[1].get_address(), change);
+        assert_eq!(
+            input_total(&tx, &utxo_set),
+            7_000 + tx.get_vout()[1].get_value().to_satoshis() + tx.get_fee().to_satoshis()
+        );
+        assert!(tx.verify(utxo_set.get_blockchain()));
+
+        // Read back from the chain, every input still checks against its own key
+        let blockchain = utxo_set.get_blockchain();
+        let block = confirm(&utxo_set, &tx);
+        let stored = blockchain.get_block(block.get_hash()).unwrap().unwrap();
+        let mined = &stored.get_transactions()[1];
+        assert_eq!(mined.get_id(), tx.get_id());
+        assert!(mined.verify_signatures(&mined.spent_outputs(blockchain).unwrap()));
+        assert_eq!(
+            wallets.account_balance("spending", &utxo_set).unwrap(),
+            12_000 - 7_000 - tx.get_fee().to_satoshis()
+        );
+
+        // More than the whole account holds is refused like any short payment
+        assert!(matches!(
+            Transaction::new_account_transaction(
+                "spending",
+                TEST_ADDRESS,
+                50_000,
+                FeePriority::Normal,
+                &utxo_set,
+                &wallets,
+            ),
+            Err(BlockchainError::InsufficientFundsDetailed { .. })
+        ));
+        assert!(matches!(
+            Transaction::new_account_transaction(
+                "spending",
+                &members[2],
+                1_000,
+                FeePriority::Normal,
+                &utxo_set,
+                &wallets,
+            ),
+            Err(BlockchainError::SelfTransfer(_))
+        ));
+    }
+
+    #[test]
+    fn test_account_members_are_checked() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("accounts.dat");
+        let mut wallets = Wallets::open(&path).unwrap();
+        let funded = wallets.create_wallet().unwrap();
+        let empty = wallets.create_wallet().unwrap();
+        let watched = Wallet::new().unwrap().get_address();
+        wallets.add_watch_only(&watched, None).unwrap();
+        wallets.create_account("mining").unwrap();
+        wallets.create_account("donations").unwrap();
+
+        wallets.assign_address("mining", &funded).unwrap();
+        wallets.assign_address("mining", &empty).unwrap();
+        assert!(wallets.assign_address("donations", &funded).is_err());
+        let err = wallets.assign_address("donations", &watched).unwrap_err();
+        assert!(err.to_string().contains("watch-only"), "{err}");
+        assert!(wallets.assign_address("donations", TEST_ADDRESS).is_err());
+        // An account with nothing in it has nothing to pay from
+        let (_chain_dir, utxo_set) = chain_paying(&[(&funded, 5_000)]);
+        assert!(Transaction::new_account_transaction(
+            "donations",
+            TEST_ADDRESS,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &wallets,
+        )
+        .is_err());
+
+        // Accounts are kept in the wallet file
+        let reopened = Wallets::open(&path).unwrap();
+        assert_eq!(reopened.accounts().account_of(&funded), Some("mining"));
+        assert_eq!(
+            reopened.account_balance("mining", &utxo_set).unwrap(),
+            5_000
+        );
+        assert!(reopened.account_balance("savings", &utxo_set).is_err());
+
+        // Removing a funded address reports what it takes with it
+        assert_eq!(
+            wallets.remove_address("mining", &empty, &utxo_set).unwrap(),
+            Amount::ZERO
+        );
+        assert_eq!(
+            wallets
+                .remove_address("mining", &funded, &utxo_set)
+                .unwrap(),
+            5_000
+        );
+        assert_eq!(wallets.account_balance("mining", &utxo_set).unwrap(), 0);
+        wallets.assign_address("donations", &funded).unwrap();
+    }
+
+    #[test]
+    fn test_change_goes_to_a_new_address_when_the_policy_asks() {
+        use crate::core::PaymentDirection;
+        use crate::wallet::address_pub_key_hash;
+
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("change.dat");
+        let mut wallets = Wallets::open(&path).unwrap();
+        let sender = wallets.create_wallet().unwrap();
+        wallets.set_use_change_addresses(true).unwrap();
+        let (_chain_dir, utxo_set) = chain_paying(&[(&sender, 5_000), (&sender, 5_000)]);
+        let blockchain = utxo_set.get_blockchain();
+        let sender_hash = address_pub_key_hash(&sender).unwrap();
+        assert_eq!(wallets.balance(&utxo_set).unwrap(), 10_000);
+        assert_eq!(
+            blockchain
+                .address_usage(&sender_hash)
+                .unwrap()
+                .reuse_warning(&sender),
+            None
+        );
+
+        let pay = |wallets: &Wallets| {
+            TransactionBuilder::new(&utxo_set)
+                .from(&sender)
+                .add_output(TEST_ADDRESS, 1_000)
+                .build_and_sign(wallets)
+                .unwrap()
+        };
+        let tx = pay(&wallets);
+        let reloaded = Wallets::open(&path).unwrap();
+        let change = tx.get_vout()[1].get_address();
+        assert_ne!(change, sender);
+        assert!(reloaded.is_change_address(&change));
+        assert!(tx.verify(blockchain));
+        confirm(&utxo_set, &tx);
+
+        // The change counts toward the wallet, which only lost the payment and the fee
+        assert_eq!(
+            reloaded.balance(&utxo_set).unwrap(),
+            10_000 - 1_000 - tx.get_fee().to_satoshis()
+        );
+        let history = reloaded.history(blockchain).unwrap();
+        let last = history.last().unwrap();
+        assert_eq!(last.txid, HEXLOWER.encode(tx.get_id()));
+        assert_eq!(last.direction, PaymentDirection::Outgoing);
+        assert_eq!(last.amount, 1_000 + tx.get_fee().to_satoshis());
+
+        // Spending from the sender a second time is reuse
+        let usage = blockchain.address_usage(&sender_hash).unwrap();
+        assert_eq!(usage.times_received, 2);
+        assert_eq!(usage.times_spent_from, 1);
+        let warning = usage.reuse_warning(&sender).unwrap();
+        assert!(warning.contains("1 time(s)"), "{warning}");
+        assert!(warning.contains("links all of its payments"), "{warning}");
+        let second = pay(&reloaded);
+        assert_ne!(second.get_vout()[1].get_address(), change);
+    }
+}
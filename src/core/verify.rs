@@ -0,0 +1,503 @@
+//! Integrity checks over a whole database
+//!
+//! `Blockchain::verify_chain` re-checks what was already validated when each block was
+//! stored, to find damage done afterwards by a crash or a bad disk. Each level includes the
+//! ones below it:
+//!
+//! 1. Walk from the tip to genesis, checking that every referenced block exists, decodes,
+//!    is stored under its own hash and sits one height below its child.
+//! 2. Check each block's timestamp, merkle root and proof of work. Pruned blocks only have
+//!    their header left, so their merkle root is taken as covered by the proof of work.
+//! 3. Replay every transaction from genesis, checking signatures and balances, and compare
+//!    the resulting UTXO set with the chainstate. This needs every block body, so a
+//!    pruned chain, or one loaded from a snapshot, can't be checked at this level.
+
+use crate::core::{Amount, Block, Blockchain, ProofOfWork, TXOutput};
+use crate::error::{BlockchainError, Result};
+use crate::storage::utxo_set::{chainstate_entries, UTXO_TREE};
+use crate::utils::serialize;
+use data_encoding::HEXLOWER;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The deepest check `verify_chain` knows
+pub const MAX_VERIFICATION_LEVEL: u32 = 3;
+
+// The level 3 UTXO set is rebuilt here, next to the chainstate, and dropped afterwards
+const VERIFY_TREE: &str = "verifychain";
+
+/// What `Blockchain::verify_chain` found
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChainVerificationReport {
+    pub level: u32,
+    /// Blocks that passed every check of the requested level
+    pub blocks_checked: usize,
+    /// The first block that failed, if any; nothing past it is checked
+    pub first_bad_block: Option<BadBlock>,
+    /// Chainstate entries that disagree with the replayed chain, level 3 only
+    pub chainstate_discrepancies: Vec<ChainstateDiscrepancy>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BadBlock {
+    pub hash: String,
+    /// Unknown when the block is missing or can't be decoded
+    pub height: Option<usize>,
+    pub reason: String,
+}
+
+/// A chainstate entry that differs from the UTXO set replayed from the chain
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChainstateDiscrepancy {
+    /// The chain leaves outputs of this transaction unspent, but the chainstate has none
+    Missing { txid: String },
+    /// The chainstate has outputs the chain doesn't leave unspent
+    Orphaned { txid: String },
+    /// Both have unspent outputs for the transaction, but not the same ones
+    Mismatched { txid: String },
+}
+
+impl ChainVerificationReport {
+    pub fn is_ok(&self) -> bool {
+        self.first_bad_block.is_none() && self.chainstate_discrepancies.is_empty()
+    }
+}
+
+impl fmt::Display for ChainVerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Verification level: {}", self.level)?;
+        writeln!(f, "Blocks checked: {}", self.blocks_checked)?;
+        match &self.first_bad_block {
+            Some(bad) => {
+                let height = bad
+                    .height
+                    .map_or_else(|| "unknown".to_string(), |h| h.to_string());
+                writeln!(
+                    f,
+                    "First bad block: {} (height {height}): {}",
+                    bad.hash, bad.reason
+                )?;
+            }
+            None => writeln!(f, "First bad block: none")?,
+        }
+        writeln!(
+            f,
+            "Chainstate discrepancies: {}",
+            self.chainstate_discrepancies.len()
+        )?;
+        for discrepancy in &self.chainstate_discrepancies {
+            writeln!(f, "- {discrepancy}")?;
+        }
+        write!(f, "Result: {}", if self.is_ok() { "OK" } else { "FAILED" })
+    }
+}
+
+impl fmt::Display for ChainstateDiscrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainstateDiscrepancy::Missing { txid } => {
+                write!(f, "{txid}: unspent outputs missing from the chainstate")
+            }
+            ChainstateDiscrepancy::Orphaned { txid } => {
+                write!(f, "{txid}: chainstate entry the chain doesn't account for")
+            }
+            ChainstateDiscrepancy::Mismatched { txid } => {
+                write!(f, "{txid}: chainstate outputs differ from the chain")
+            }
+        }
+    }
+}
+
+pub(crate) fn verify_chain(blockchain: &Blockchain, level: u32) -> Result<ChainVerificationReport> {
+    if !(1..=MAX_VERIFICATION_LEVEL).contains(&level) {
+        return Err(BlockchainError::Config(format!(
+            "Verification level {level} is outside 1..={MAX_VERIFICATION_LEVEL}"
+        )));
+    }
+    if level >= 3 {
+        if let Some(height) = blockchain.get_snapshot_height()? {
+            return Err(BlockchainError::Pruned(format!(
+                "level 3 replays every transaction, but this chain was loaded from a snapshot \
+                 at height {height} and has no history below it"
+            )));
+        }
+    }
+    if level >= 3 && blockchain.is_pruned()? {
+        return Err(BlockchainError::Pruned(
+            "level 3 replays every transaction, which a pruned chain no longer has".to_string(),
+        ));
+    }
+
+    let mut report = ChainVerificationReport {
+        level,
+        blocks_checked: 0,
+        first_bad_block: None,
+        chainstate_discrepancies: Vec::new(),
+    };
+
+    let blocks = match walk_to_genesis(blockchain)? {
+        Ok(blocks) => blocks,
+        Err(bad) => {
+            report.first_bad_block = Some(bad);
+            return Ok(report);
+        }
+    };
+    if level == 1 {
+        report.blocks_checked = blocks.len();
+        return Ok(report);
+    }
+
+    let mut replay = (level >= 3).then(Replay::default);
+    for (i, block) in blocks.iter().enumerate() {
+        let parent = i.checked_sub(1).map(|p| &blocks[p]);
+        let mut failure = check_block(blockchain, block, parent)?;
+        if failure.is_none() {
+            if let Some(replay) = replay.as_mut() {
+                failure = replay.apply(block);
+            }
+        }
+        if let Some(reason) = failure {
+            report.first_bad_block = Some(BadBlock {
+                hash: block.get_hash().to_string(),
+                height: Some(block.get_height()),
+                reason,
+            });
+            return Ok(report);
+        }
+        report.blocks_checked += 1;
+    }
+
+    if let Some(replay) = replay {
+        report.chainstate_discrepancies = compare_chainstate(blockchain, replay)?;
+    }
+    Ok(report)
+}
+
+// Level 1: the main chain in genesis-to-tip order, or the first block on the way down
+// that can't be part of it
+fn walk_to_genesis(blockchain: &Blockchain) -> Result<std::result::Result<Vec<Block>, BadBlock>> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut hash = blockchain.get_tip_hash();
+
+    loop {
+        let bad = |height: Option<usize>, reason: String| BadBlock {
+            hash: hash.clone(),
+            height,
+            reason,
+        };
+        let expected_height = blocks.last().map(|child| child.get_height() - 1);
+
+        let block = match blockchain.get_block(&hash) {
+            Ok(Some(block)) => block,
+            Ok(None) => {
+                return Ok(Err(bad(
+                    expected_height,
+                    "referenced block is missing".to_string(),
+                )))
+            }
+            Err(BlockchainError::Database(e)) => return Err(BlockchainError::Database(e)),
+            Err(e) => {
+                return Ok(Err(bad(
+                    expected_height,
+                    format!("stored block can't be decoded: {e}"),
+                )))
+            }
+        };
+
+        if block.get_hash() != hash {
+            return Ok(Err(bad(
+                Some(block.get_height()),
+                format!("stored under {hash} but claims hash {}", block.get_hash()),
+            )));
+        }
+        if let Some(expected) = expected_height {
+            if block.get_height() != expected {
+                return Ok(Err(bad(
+                    Some(block.get_height()),
+                    format!(
+                        "height {} where {expected} was expected",
+                        block.get_height()
+                    ),
+                )));
+            }
+        }
+
+        let pre_block_hash = block.get_pre_block_hash();
+        let is_genesis = pre_block_hash == "None";
+        if is_genesis != (block.get_height() == 0) {
+            return Ok(Err(bad(
+                Some(block.get_height()),
+                format!(
+                    "height {} with previous block {pre_block_hash}",
+                    block.get_height()
+                ),
+            )));
+        }
+
+        blocks.push(block);
+        if is_genesis {
+            break;
+        }
+        hash = pre_block_hash;
+    }
+
+    blocks.reverse();
+    Ok(Ok(blocks))
+}
+
+// Level 2: the checks a block passed on its own when it was stored
+fn check_block(
+    blockchain: &Blockchain,
+    block: &Block,
+    parent: Option<&Block>,
+) -> Result<Option<String>> {
+    if ProofOfWork::compute_hash(block) != block.get_hash() {
+        return Ok(Some("hash does not match the block's contents".to_string()));
+    }
+    let median_time_past = parent.map(|p| blockchain.median_time_past(p)).transpose()?;
+    if block.is_pruned() {
+        if !block.validate_pruned_header(median_time_past)? {
+            return Ok(Some(
+                "timestamp or proof of work of the pruned block is invalid".to_string(),
+            ));
+        }
+    } else {
+        // Size limits come back as errors rather than false
+        let valid = block.validate_block(median_time_past).unwrap_or(false);
+        if !valid {
+            return Ok(Some(
+                "timestamp, merkle root, proof of work or coinbase placement is invalid"
+                    .to_string(),
+            ));
+        }
+    }
+    if let Some(parent) = parent {
+        let expected = blockchain.calculate_next_difficulty_for_parent(parent)?;
+        if block.get_difficulty() != expected {
+            return Ok(Some(format!(
+                "difficulty {} does not match expected {expected}",
+                block.get_difficulty()
+            )));
+        }
+    }
+    Ok(None)
+}
+
+// Level 3: the unspent outputs left by every block applied so far
+#[derive(Default)]
+struct Replay {
+    unspent: BTreeMap<(Vec<u8>, usize), TXOutput>,
+}
+
+impl Replay {
+    // I can't use Transaction::verify here: it looks for spends across the whole chain,
+    // so every historical input would count as spent by its own transaction
+    fn apply(&mut self, block: &Block) -> Option<String> {
+        for (i, tx) in block.get_transactions().iter().enumerate() {
+            let txid = HEXLOWER.encode(tx.get_id());
+            if tx.is_coinbase() {
+                if i != 0 {
+                    return Some(format!("transaction {txid} is a second coinbase"));
+                }
+            } else {
+                let mut spent = Vec::with_capacity(tx.get_vin().len());
+                for vin in tx.get_vin() {
+                    let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+                    match self.unspent.remove(&outpoint) {
+                        Some(output) => spent.push(output),
+                        None => {
+                            return Some(format!(
+                                "transaction {txid} spends {}:{}, which is spent or never existed",
+                                HEXLOWER.encode(vin.get_txid()),
+                                vin.get_vout()
+                            ))
+                        }
+                    }
+                }
+
+                let input_value = Amount::checked_sum(spent.iter().map(TXOutput::get_value));
+                let output_value = tx
+                    .get_output_value()
+                    .and_then(|outputs| outputs.checked_add(tx.get_fee()));
+                if !matches!((input_value, output_value), (Ok(input), Ok(output)) if input == output)
+                {
+                    return Some(format!(
+                        "transaction {txid} does not balance its inputs against outputs and fee"
+                    ));
+                }
+                if !tx.verify_signatures(&spent) {
+                    return Some(format!("transaction {txid} has an invalid signature"));
+                }
+            }
+
+            for (vout, output) in tx.get_vout().iter().enumerate() {
+                if output.is_data_carrier() {
+                    continue;
+                }
+                self.unspent
+                    .insert((tx.get_id().to_vec(), vout), output.clone());
+            }
+        }
+        None
+    }
+}
+
+// The replayed outputs are grouped per transaction the way reindex stores them, so
+// matching entries serialize to the same bytes
+fn compare_chainstate(
+    blockchain: &Blockchain,
+    replay: Replay,
+) -> Result<Vec<ChainstateDiscrepancy>> {
+    let db = blockchain.get_db();
+    let open = |name: &str| {
+        db.open_tree(name)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open {name} tree: {e}")))
+    };
+    let chainstate = open(UTXO_TREE)?;
+    let expected = open(VERIFY_TREE)?;
+    expected.clear().map_err(|e| {
+        BlockchainError::Database(format!("Failed to clear {VERIFY_TREE} tree: {e}"))
+    })?;
+
+    let mut grouped: BTreeMap<Vec<u8>, Vec<(usize, TXOutput)>> = BTreeMap::new();
+    for ((txid, vout), output) in replay.unspent {
+        grouped.entry(txid).or_default().push((vout, output));
+    }
+    for (txid, outputs) in &grouped {
+        expected
+            .insert(txid.as_slice(), serialize(outputs)?)
+            .map_err(|e| BlockchainError::Database(format!("Failed to insert UTXO: {e}")))?;
+    }
+
+    let read_error =
+        |e: sled::Error| BlockchainError::Database(format!("Failed to iterate UTXO tree: {e}"));
+    let mut discrepancies = Vec::new();
+    for item in expected.iter() {
+        let (txid, outputs) = item.map_err(read_error)?;
+        let txid_hex = HEXLOWER.encode(&txid);
+        match chainstate.get(&txid).map_err(read_error)? {
+            None => discrepancies.push(ChainstateDiscrepancy::Missing { txid: txid_hex }),
+            Some(stored) if stored != outputs => {
+                discrepancies.push(ChainstateDiscrepancy::Mismatched { txid: txid_hex })
+            }
+            Some(_) => {}
+        }
+    }
+    for item in chainstate_entries(&chainstate) {
+        let (txid, _) = item.map_err(read_error)?;
+        if !expected.contains_key(&txid).map_err(read_error)? {
+            discrepancies.push(ChainstateDiscrepancy::Orphaned {
+                txid: HEXLOWER.encode(&txid),
+            });
+        }
+    }
+
+    db.drop_tree(VERIFY_TREE).map_err(|e| {
+        BlockchainError::Database(format!("Failed to drop {VERIFY_TREE} tree: {e}"))
+    })?;
+    Ok(discrepancies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, FeePriority, NodeContext, Transaction};
+    use crate::storage::UTXOSet;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use tempfile::tempdir;
+
+    // A few mined blocks, each handing coins to a new owner, with an up-to-date chainstate
+    fn funded_chain(db_path: &str) -> Blockchain {
+        let mut wallets = Wallets::new();
+        let owners: Vec<String> = (0..4).map(|_| wallets.create_wallet().unwrap()).collect();
+        let blockchain = Blockchain::create_blockchain_with_path(&owners[0], db_path).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+
+        for height in 1..owners.len() {
+            utxo_set.reindex();
+            let tx = Transaction::new_utxo_transaction_with_context(
+                &owners[height - 1],
+                &owners[height],
+                1_000 * (10 - height as u64),
+                FeePriority::Normal,
+                &utxo_set,
+                &context,
+            )
+            .unwrap();
+            blockchain
+                .mine_block_with_context(&[tx], &owners[0], &context)
+                .unwrap();
+        }
+        utxo_set.reindex();
+        blockchain
+    }
+
+    #[test]
+    fn test_level_2_detects_corrupted_block_bytes() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let blockchain = funded_chain(temp_dir.path().join("chain").to_str().unwrap());
+
+        for level in 1..=3 {
+            let report = blockchain.verify_chain(level).unwrap();
+            assert!(report.is_ok(), "{report}");
+            assert_eq!(report.blocks_checked, 4);
+        }
+        assert!(blockchain.verify_chain(0).is_err());
+        assert!(blockchain.verify_chain(4).is_err());
+
+        // Nudge the timestamp, the first field of a stored block after the format
+        // envelope. The bytes still decode and the linkage is intact, but the proof of
+        // work no longer matches.
+        let corrupted = blockchain.get_block_at_height(2).unwrap().unwrap();
+        let blocks_tree = blockchain.get_db().open_tree("blocks").unwrap();
+        let mut bytes = blocks_tree
+            .get(corrupted.get_hash())
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        bytes[4] ^= 1;
+        blocks_tree.insert(corrupted.get_hash(), bytes).unwrap();
+
+        assert!(blockchain.verify_chain(1).unwrap().is_ok());
+        let report = blockchain.verify_chain(2).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.blocks_checked, 2);
+        let bad = report.first_bad_block.unwrap();
+        assert_eq!(bad.hash, corrupted.get_hash());
+        assert_eq!(bad.height, Some(2));
+    }
+
+    #[test]
+    fn test_level_3_flags_missing_chainstate_entry() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let blockchain = funded_chain(temp_dir.path().join("chain").to_str().unwrap());
+
+        let chainstate = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+        let (txid, _) = chainstate.iter().next().unwrap().unwrap();
+        chainstate.remove(&txid).unwrap();
+
+        // Levels 1 and 2 don't look at the chainstate
+        assert!(blockchain.verify_chain(2).unwrap().is_ok());
+        let report = blockchain.verify_chain(3).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.blocks_checked, 4);
+        assert!(report.first_bad_block.is_none());
+        assert_eq!(
+            report.chainstate_discrepancies,
+            vec![ChainstateDiscrepancy::Missing {
+                txid: HEXLOWER.encode(&txid)
+            }]
+        );
+        assert!(!blockchain
+            .get_db()
+            .tree_names()
+            .iter()
+            .any(|name| name.as_ref() == VERIFY_TREE.as_bytes()));
+    }
+}
@@ -0,0 +1,505 @@
+//! Watched addresses and payment notifications
+//!
+//! A node keeps a list of addresses to watch in its database. Whenever a block joins the
+//! main chain, and whenever a transaction enters the memory pool, every output paying a
+//! watched address and every input spending from one becomes a `Notification`. Each one
+//! is appended as a JSON line to `notifications.log` in the database directory and, when
+//! a notify command is configured, also handed to that command on stdin, much like
+//! bitcoind's `walletnotify`.
+//!
+//! Later on, each of those transactions is reported again as `confirmed-final` once its
+//! block is `Blockchain::get_finality_depth` deep, or as `reorged-out` if a reorg takes
+//! its block off the main chain and the new branch doesn't hold it.
+//!
+//! Spends are recognised by looking up the output they spend on the main chain, so a
+//! memory pool transaction spending another unconfirmed one is only reported as outgoing
+//! once it's mined.
+
+use crate::core::{Blockchain, TXOutput, Transaction};
+use crate::error::{BlockchainError, Result};
+use data_encoding::HEXLOWER;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sled::Tree;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// File the notifications are appended to, inside the database directory
+pub const NOTIFICATIONS_LOG: &str = "notifications.log";
+
+// Public key hash -> address; the key is what `TXOutput::is_locked_with_key` compares
+const WATCH_TREE: &str = "watched_addresses";
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PaymentDirection {
+    /// A watched address was paid
+    Incoming,
+    /// A watched address spent some of its coins
+    Outgoing,
+}
+
+/// What happened to the transaction a notification is about
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotificationEvent {
+    /// It entered the memory pool, or a block holding it joined the main chain
+    #[default]
+    Payment,
+    /// Its block reached the finality depth
+    ConfirmedFinal,
+    /// Its block left the main chain, and no block on the new one holds it
+    ReorgedOut,
+}
+
+/// Coins moving to or from one watched address in one transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    pub txid: String,
+    pub address: String,
+    /// Satoshis paid to the address, or spent from it, by this transaction
+    pub amount: u64,
+    pub direction: PaymentDirection,
+    /// Height of the block the transaction is in; none while it's in the memory pool.
+    /// For `ReorgedOut`, the height of the block it was in.
+    pub height: Option<usize>,
+    pub confirmed: bool,
+    // Lines logged before events existed were all payments
+    #[serde(default)]
+    pub event: NotificationEvent,
+}
+
+/// The addresses a node watches, kept in its database
+pub struct WatchList {
+    tree: Tree,
+}
+
+impl WatchList {
+    pub fn open(blockchain: &Blockchain) -> Result<WatchList> {
+        let tree = blockchain
+            .get_db()
+            .open_tree(WATCH_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open watch list: {e}")))?;
+        Ok(WatchList { tree })
+    }
+
+    /// Start watching `address`; false if it was already watched
+    pub fn watch(&self, address: &str) -> Result<bool> {
+        let previous = self
+            .tree
+            .insert(Self::key(address)?, address.as_bytes())
+            .map_err(|e| BlockchainError::Database(format!("Failed to update watch list: {e}")))?;
+        Ok(previous.is_none())
+    }
+
+    /// Stop watching `address`; false if it wasn't watched
+    pub fn unwatch(&self, address: &str) -> Result<bool> {
+        let previous = self
+            .tree
+            .remove(Self::key(address)?)
+            .map_err(|e| BlockchainError::Database(format!("Failed to update watch list: {e}")))?;
+        Ok(previous.is_some())
+    }
+
+    pub fn addresses(&self) -> Result<Vec<String>> {
+        self.tree
+            .iter()
+            .values()
+            .map(|address| {
+                address
+                    .map(|address| String::from_utf8_lossy(&address).into_owned())
+                    .map_err(|e| {
+                        BlockchainError::Database(format!("Failed to read watch list: {e}"))
+                    })
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// What `tx` moves to or from watched addresses, one entry per address and direction
+    ///
+    /// `height` is the height of the block holding `tx`, if it's confirmed.
+    pub fn notifications_for(
+        &self,
+        blockchain: &Blockchain,
+        tx: &Transaction,
+        height: Option<usize>,
+    ) -> Result<Vec<Notification>> {
+        let mut notifications: Vec<Notification> = Vec::new();
+        let mut add =
+            |address: String, amount: u64, direction: PaymentDirection| match notifications
+                .iter_mut()
+                .find(|n| n.address == address && n.direction == direction)
+            {
+                Some(existing) => existing.amount += amount,
+                None => notifications.push(Notification {
+                    txid: HEXLOWER.encode(tx.get_id()),
+                    address,
+                    amount,
+                    direction,
+                    height,
+                    confirmed: height.is_some(),
+                    event: NotificationEvent::Payment,
+                }),
+            };
+
+        if !tx.is_coinbase() {
+            for input in tx.get_vin() {
+                let spent = blockchain
+                    .find_transaction(input.get_txid())?
+                    .and_then(|prev| prev.get_vout().get(input.get_vout()).cloned());
+                if let Some(output) = spent {
+                    if let Some(address) = self.watched_address(&output)? {
+                        add(
+                            address,
+                            output.get_value().to_satoshis(),
+                            PaymentDirection::Outgoing,
+                        );
+                    }
+                }
+            }
+        }
+        for output in tx.get_vout() {
+            if let Some(address) = self.watched_address(output)? {
+                add(
+                    address,
+                    output.get_value().to_satoshis(),
+                    PaymentDirection::Incoming,
+                );
+            }
+        }
+        Ok(notifications)
+    }
+
+    fn watched_address(&self, output: &TXOutput) -> Result<Option<String>> {
+        if output.is_data_carrier() {
+            return Ok(None);
+        }
+        let address = self
+            .tree
+            .get(output.get_pub_key_hash())
+            .map_err(|e| BlockchainError::Database(format!("Failed to read watch list: {e}")))?;
+        Ok(address.map(|address| String::from_utf8_lossy(&address).into_owned()))
+    }
+
+    // Any output paying `address` carries exactly these bytes as its public key hash
+    fn key(address: &str) -> Result<Vec<u8>> {
+        Ok(TXOutput::new(1, address)?.get_pub_key_hash())
+    }
+}
+
+/// Writes notifications out: to the log file, and to the notify command if there is one
+pub struct Notifier {
+    log_path: PathBuf,
+    command: Option<String>,
+}
+
+impl Notifier {
+    pub fn new(log_path: &Path, command: Option<String>) -> Notifier {
+        Notifier {
+            log_path: log_path.to_path_buf(),
+            command,
+        }
+    }
+
+    pub fn deliver(&self, notifications: &[Notification]) -> Result<()> {
+        if notifications.is_empty() {
+            return Ok(());
+        }
+
+        let lines: Vec<String> = notifications
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|e| {
+                BlockchainError::Serialization(format!("Failed to encode notification: {e}"))
+            })?;
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)
+            .map_err(|e| {
+                BlockchainError::Io(format!("Failed to open {}: {e}", self.log_path.display()))
+            })?;
+        for line in &lines {
+            writeln!(log, "{line}")
+                .map_err(|e| BlockchainError::Io(format!("Failed to append notification: {e}")))?;
+        }
+
+        if let Some(command) = &self.command {
+            for line in lines {
+                run_notify_command(command, line);
+            }
+        }
+        Ok(())
+    }
+}
+
+// The command runs in the background, so a slow script never holds up block processing
+fn run_notify_command(command: &str, json: String) {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let spawned = shell
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to run notify command '{command}': {e}");
+            return;
+        }
+    };
+
+    let command = command.to_string();
+    std::thread::spawn(move || {
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(e) = writeln!(stdin, "{json}") {
+                warn!("Failed to pass a notification to '{command}': {e}");
+            }
+        }
+        match child.wait() {
+            Ok(status) if !status.success() => {
+                warn!("Notify command '{command}' exited with {status}")
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to wait for notify command '{command}': {e}"),
+        }
+    });
+}
+
+// Tip changes and transactions entering the memory pool end up here. Failing only costs
+// the notification, never the block or the transaction.
+//
+// `connected` lists the blocks that joined the main chain lowest first, `disconnected`
+// the (hash, height) of those that left it highest first.
+pub(crate) fn notify_tip_change(
+    blockchain: &Blockchain,
+    connected: &[String],
+    disconnected: &[(String, usize)],
+) {
+    let result = (|| {
+        let watch_list = WatchList::open(blockchain)?;
+        if watch_list.is_empty() {
+            return Ok(());
+        }
+        let mut notifications = Vec::new();
+        for (hash, height) in disconnected {
+            // A block removed outright can't be read any more, and has nothing to report
+            let Some(block) = blockchain.get_block(hash)? else {
+                continue;
+            };
+            for tx in block.get_transactions() {
+                if blockchain.find_transaction_block(tx.get_id())?.is_some() {
+                    continue;
+                }
+                let reorged = watch_list.notifications_for(blockchain, tx, Some(*height))?;
+                notifications.extend(reorged.into_iter().map(|mut n| {
+                    n.confirmed = false;
+                    n.event = NotificationEvent::ReorgedOut;
+                    n
+                }));
+            }
+        }
+        for hash in connected {
+            let block = blockchain
+                .get_block(hash)?
+                .ok_or_else(|| BlockchainError::Database(format!("Block {hash} not found")))?;
+            for tx in block.get_transactions() {
+                notifications.extend(watch_list.notifications_for(
+                    blockchain,
+                    tx,
+                    Some(block.get_height()),
+                )?);
+            }
+        }
+        for height in newly_final_heights(blockchain, connected, disconnected)?
+            .into_iter()
+            .flatten()
+        {
+            let Some(block) = blockchain.get_block_at_height(height)? else {
+                continue;
+            };
+            for tx in block.get_transactions() {
+                let final_ones = watch_list.notifications_for(blockchain, tx, Some(height))?;
+                notifications.extend(final_ones.into_iter().map(|mut n| {
+                    n.event = NotificationEvent::ConfirmedFinal;
+                    n
+                }));
+            }
+        }
+        notifier(blockchain).deliver(&notifications)
+    })();
+    if let Err(e) = result {
+        warn!("Failed to send payment notifications: {e}");
+    }
+}
+
+// Heights of the main-chain blocks that reached the finality depth with this tip change.
+// Blocks at most `depth - 1` below the old tip become final as the tip rises; after a reorg
+// deeper than that, the new branch's blocks down to the fork are final straight away.
+fn newly_final_heights(
+    blockchain: &Blockchain,
+    connected: &[String],
+    disconnected: &[(String, usize)],
+) -> Result<Option<RangeInclusive<usize>>> {
+    let Some(first_hash) = connected.first() else {
+        return Ok(None);
+    };
+    let first_connected = match blockchain.get_block(first_hash)? {
+        Some(block) => block.get_height(),
+        None => return Ok(None),
+    };
+    let old_tip = match disconnected.first() {
+        Some((_, height)) => *height,
+        None => first_connected.saturating_sub(1),
+    };
+    let depth = blockchain.get_finality_depth();
+    let new_tip = blockchain.get_best_height()?;
+    let Some(deepest) = (new_tip + 1).checked_sub(depth) else {
+        return Ok(None);
+    };
+    // Blocks at `old_tip + 1 - depth` or below were final before
+    let first = (old_tip + 2).saturating_sub(depth).min(first_connected);
+    Ok(Some(first..=deepest))
+}
+
+pub(crate) fn notify_unconfirmed(blockchain: &Blockchain, tx: &Transaction) {
+    let result = (|| {
+        let watch_list = WatchList::open(blockchain)?;
+        if watch_list.is_empty() {
+            return Ok(());
+        }
+        let notifications = watch_list.notifications_for(blockchain, tx, None)?;
+        notifier(blockchain).deliver(&notifications)
+    })();
+    if let Err(e) = result {
+        warn!("Failed to send payment notifications: {e}");
+    }
+}
+
+fn notifier(blockchain: &Blockchain) -> Notifier {
+    Notifier::new(
+        &blockchain.get_db_path().join(NOTIFICATIONS_LOG),
+        blockchain.get_notify_command(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, FeePriority, NodeContext};
+    use crate::storage::UTXOSet;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn logged(blockchain: &Blockchain) -> Vec<Notification> {
+        std::fs::read_to_string(blockchain.get_db_path().join(NOTIFICATIONS_LOG))
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_payments_to_and_from_watched_address_are_logged() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+
+        let mut wallets = Wallets::new();
+        let watched = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        let watch_list = WatchList::open(&blockchain).unwrap();
+        assert!(watch_list.watch(&watched).unwrap());
+        assert!(!watch_list.watch(&watched).unwrap());
+        assert_eq!(watch_list.addresses().unwrap(), vec![watched.clone()]);
+
+        // Blocks that don't touch the address leave no trace
+        blockchain.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        assert!(logged(&blockchain).is_empty());
+
+        let reward_block = blockchain.mine_block_with_fees(&[], &watched).unwrap();
+        let reward = &reward_block.get_transactions()[0];
+        assert_eq!(
+            logged(&blockchain),
+            vec![Notification {
+                txid: HEXLOWER.encode(reward.get_id()),
+                address: watched.clone(),
+                amount: reward.get_vout()[0].get_value().to_satoshis(),
+                direction: PaymentDirection::Incoming,
+                height: Some(2),
+                confirmed: true,
+                event: NotificationEvent::Payment,
+            }]
+        );
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe().unwrap();
+        let context = NodeContext::new(FeeMode::Fixed { amount: 2 }).unwrap();
+        let payment = Transaction::new_utxo_transaction_with_context(
+            &watched,
+            &recipient,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+
+        // Seen in the memory pool first, then again once mined
+        blockchain.notify_unconfirmed(&payment);
+        blockchain
+            .mine_block_with_fees(std::slice::from_ref(&payment), TEST_ADDRESS)
+            .unwrap();
+
+        let spent = reward.get_vout()[0].get_value().to_satoshis();
+        let change = spent - 1_000 - payment.get_fee().to_satoshis();
+        let txid = HEXLOWER.encode(payment.get_id());
+        let payment_entries: Vec<(PaymentDirection, u64, Option<usize>)> = logged(&blockchain)
+            .into_iter()
+            .filter(|n| n.txid == txid)
+            .inspect(|n| assert_eq!(n.address, watched))
+            .inspect(|n| assert_eq!(n.confirmed, n.height.is_some()))
+            .map(|n| (n.direction, n.amount, n.height))
+            .collect();
+        assert_eq!(
+            payment_entries,
+            vec![
+                (PaymentDirection::Outgoing, spent, None),
+                (PaymentDirection::Incoming, change, None),
+                (PaymentDirection::Outgoing, spent, Some(3)),
+                (PaymentDirection::Incoming, change, Some(3)),
+            ]
+        );
+
+        assert!(watch_list.unwatch(&watched).unwrap());
+        assert!(!watch_list.unwatch(&watched).unwrap());
+        let before = logged(&blockchain).len();
+        blockchain.mine_block_with_fees(&[], &watched).unwrap();
+        assert_eq!(logged(&blockchain).len(), before);
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! This module provides comprehensive error types for all blockchain operations.
 
+use crate::core::monetary::conversions::format_amount;
+use crate::core::monetary::Amount;
 use std::fmt;
 
 /// Result type alias for blockchain operations
@@ -12,6 +14,8 @@ pub type Result<T> = std::result::Result<T, BlockchainError>;
 pub enum BlockchainError {
     /// Database-related errors
     Database(String),
+    /// The database at this path is held open by another process, normally a running node
+    DatabaseLocked(String),
     /// Cryptographic operation errors
     Crypto(String),
     /// Network communication errors
@@ -29,19 +33,65 @@ pub enum BlockchainError {
     /// Invalid address format
     InvalidAddress(String),
     /// Insufficient funds for transaction
-    InsufficientFunds { required: u64, available: u64 },
+    #[deprecated(note = "transaction constructors return `InsufficientFundsDetailed` instead")]
+    InsufficientFunds { required: Amount, available: Amount },
+    /// The spendable coins don't cover the amount plus its fee
+    ///
+    /// `required` is `amount + fee`; all values are in satoshis.
+    InsufficientFundsDetailed {
+        amount: Amount,
+        fee: Amount,
+        required: Amount,
+        available: Amount,
+    },
+    /// Replace-by-fee transaction rejected because it doesn't pay enough
+    ReplacementRejected {
+        txid: String,
+        required_fee: u64,
+        offered_fee: u64,
+    },
+    /// A transaction would pay more than `MAX_TRANSACTION_FEE` without the sender insisting
+    FeeTooHigh { fee: u64, max_fee: u64 },
+    /// A transaction pays less than the node's relay policy asks for its size
+    FeeTooLow { fee: u64, min_fee: u64 },
     /// Block validation errors
     InvalidBlock(String),
     /// Mining errors
     Mining(String),
     /// Encryption/decryption errors
     Encryption(String),
+    /// The data asked for was in a block body this node has pruned
+    Pruned(String),
+    /// A block or chain starts from another genesis block than this chain's
+    DifferentNetwork { genesis: String, expected: String },
+    /// A data directory opened with a `--network` other than the one it was created for
+    WrongNetwork { datadir: String, expected: String },
+    /// A payment from the address to itself, which only pays a fee; consolidate instead
+    SelfTransfer(String),
+    /// A coinbase transaction offered anywhere but as the first transaction of a mined block
+    CoinbaseNotAllowed(String),
+    /// A transaction its sender abandoned, offered to the memory pool again
+    TransactionAbandoned { txid: String },
+    /// A transaction spending more outputs or taking more bytes than a node handles
+    TransactionTooLarge(String),
+    /// A wallet address created against one chain used with a chain from another genesis
+    WalletNetworkMismatch {
+        address: String,
+        created_on: String,
+        chain: String,
+    },
 }
 
+// The deprecated variant still has to be displayed
+#[allow(deprecated)]
 impl fmt::Display for BlockchainError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             BlockchainError::Database(msg) => write!(f, "Database error: {msg}"),
+            BlockchainError::DatabaseLocked(path) => write!(
+                f,
+                "The database is locked by a running node — use --remote or stop the node ({path})"
+            ),
             BlockchainError::Crypto(msg) => write!(f, "Cryptographic error: {msg}"),
             BlockchainError::Network(msg) => write!(f, "Network error: {msg}"),
             BlockchainError::Transaction(msg) => write!(f, "Transaction error: {msg}"),
@@ -59,15 +109,141 @@ impl fmt::Display for BlockchainError {
                     "Insufficient funds: required {required}, available {available}"
                 )
             }
+            BlockchainError::InsufficientFundsDetailed {
+                amount,
+                fee,
+                required,
+                available,
+            } => {
+                write!(
+                    f,
+                    "Insufficient funds: sending {amount} needs {required} including a fee of {fee}, but only {available} is available"
+                )
+            }
+            BlockchainError::ReplacementRejected {
+                txid,
+                required_fee,
+                offered_fee,
+            } => {
+                write!(
+                    f,
+                    "Replacement transaction {txid} rejected: fee of {} is below the required {}",
+                    format_amount(*offered_fee),
+                    format_amount(*required_fee)
+                )
+            }
+            BlockchainError::FeeTooHigh { fee, max_fee } => {
+                write!(
+                    f,
+                    "Fee of {} is above the maximum of {}; high priorities and a congested memory pool raise fees, so try a lower priority or, to pay it anyway, send with --allow-high-fee",
+                    format_amount(*fee),
+                    format_amount(*max_fee)
+                )
+            }
+            BlockchainError::FeeTooLow { fee, min_fee } => {
+                write!(
+                    f,
+                    "Fee of {} is below the {} this node relays a transaction of its size for",
+                    format_amount(*fee),
+                    format_amount(*min_fee)
+                )
+            }
             BlockchainError::InvalidBlock(msg) => write!(f, "Invalid block: {msg}"),
             BlockchainError::Mining(msg) => write!(f, "Mining error: {msg}"),
             BlockchainError::Encryption(msg) => write!(f, "Encryption error: {msg}"),
+            BlockchainError::Pruned(msg) => write!(f, "Pruned: {msg}"),
+            BlockchainError::DifferentNetwork { genesis, expected } => write!(
+                f,
+                "Different network: genesis block {genesis} is not this chain's genesis {expected}"
+            ),
+            BlockchainError::WrongNetwork { datadir, expected } => write!(
+                f,
+                "This datadir belongs to network {datadir}, not {expected}; open it with --network {datadir}"
+            ),
+            BlockchainError::SelfTransfer(address) => write!(
+                f,
+                "Sending from {address} to itself only pays a fee and splits its coins; to merge its outputs, use `consolidate {address}` instead"
+            ),
+            BlockchainError::CoinbaseNotAllowed(msg) => write!(f, "Coinbase not allowed: {msg}"),
+            BlockchainError::TransactionAbandoned { txid } => write!(
+                f,
+                "Transaction {txid} was abandoned on this node, so it only comes back in a block"
+            ),
+            BlockchainError::TransactionTooLarge(msg) => {
+                write!(f, "Transaction too large: {msg}")
+            }
+            BlockchainError::WalletNetworkMismatch {
+                address,
+                created_on,
+                chain,
+            } => write!(
+                f,
+                "Wallet address {address} was created for the chain with genesis {created_on}, not this one ({chain}); pass --allow-network-mismatch to use it anyway"
+            ),
         }
     }
 }
 
 impl std::error::Error for BlockchainError {}
 
+/// Start of the `Mining` message for a block whose parent stopped being the tip
+pub const STALE_TIP: &str = "stale tip";
+
+impl BlockchainError {
+    /// `amount` plus `fee` is more than the `available` spendable coins
+    pub fn insufficient_funds(amount: Amount, fee: Amount, available: Amount) -> BlockchainError {
+        BlockchainError::InsufficientFundsDetailed {
+            amount,
+            fee,
+            required: amount.saturating_add(fee),
+            available,
+        }
+    }
+
+    /// The name of the variant, as scripts reading `--output json` errors match on it
+    ///
+    /// Both insufficient funds variants are `InsufficientFunds`.
+    #[allow(deprecated)]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BlockchainError::Database(_) => "Database",
+            BlockchainError::DatabaseLocked(_) => "DatabaseLocked",
+            BlockchainError::Crypto(_) => "Crypto",
+            BlockchainError::Network(_) => "Network",
+            BlockchainError::Transaction(_) => "Transaction",
+            BlockchainError::Wallet(_) => "Wallet",
+            BlockchainError::Config(_) => "Config",
+            BlockchainError::Serialization(_) => "Serialization",
+            BlockchainError::Io(_) => "Io",
+            BlockchainError::InvalidAddress(_) => "InvalidAddress",
+            BlockchainError::InsufficientFunds { .. }
+            | BlockchainError::InsufficientFundsDetailed { .. } => "InsufficientFunds",
+            BlockchainError::ReplacementRejected { .. } => "ReplacementRejected",
+            BlockchainError::FeeTooHigh { .. } => "FeeTooHigh",
+            BlockchainError::FeeTooLow { .. } => "FeeTooLow",
+            BlockchainError::InvalidBlock(_) => "InvalidBlock",
+            BlockchainError::Mining(_) => "Mining",
+            BlockchainError::Encryption(_) => "Encryption",
+            BlockchainError::Pruned(_) => "Pruned",
+            BlockchainError::DifferentNetwork { .. } => "DifferentNetwork",
+            BlockchainError::WrongNetwork { .. } => "WrongNetwork",
+            BlockchainError::SelfTransfer(_) => "SelfTransfer",
+            BlockchainError::CoinbaseNotAllowed(_) => "CoinbaseNotAllowed",
+            BlockchainError::TransactionAbandoned { .. } => "TransactionAbandoned",
+            BlockchainError::TransactionTooLarge(_) => "TransactionTooLarge",
+            BlockchainError::WalletNetworkMismatch { .. } => "WalletNetworkMismatch",
+        }
+    }
+
+    /// Whether a freshly mined block was refused because another block took the tip first
+    ///
+    /// The block itself was fine; rebuilding the template on the new tip and mining again
+    /// is the way out.
+    pub fn is_stale_tip(&self) -> bool {
+        matches!(self, BlockchainError::Mining(msg) if msg.starts_with(STALE_TIP))
+    }
+}
+
 impl From<std::io::Error> for BlockchainError {
     fn from(err: std::io::Error) -> Self {
         BlockchainError::Io(err.to_string())
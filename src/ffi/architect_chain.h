@@ -0,0 +1,74 @@
+/*
+ * architect_chain.h - C interface to the architect-chain library
+ *
+ * Build the shared library with `make ffi` (the `ffi` cargo feature). Declarations here
+ * mirror src/ffi/mod.rs, which documents each function in full.
+ *
+ * Ownership: every char * returned by a function here must be freed with ac_string_free,
+ * exactly once. Strings passed in are borrowed for the call and must be NUL-terminated
+ * UTF-8.
+ *
+ * Errors: every function clears the calling thread's last error on entry and sets it on
+ * failure; read it with ac_last_error_message. Functions returning int return AC_OK or a
+ * negative AC_ERR_* code, the others NULL, false or 0.
+ *
+ * A data directory holds wallet.dat and the chain in data/, as the command line leaves
+ * it. A chain stays open, locked against other processes, from the first call reading it
+ * until ac_datadir_close.
+ */
+
+#ifndef ARCHITECT_CHAIN_H
+#define ARCHITECT_CHAIN_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define AC_OK 0
+#define AC_ERR_INVALID_ARGUMENT (-1)
+#define AC_ERR_FAILED (-2)
+#define AC_ERR_BUFFER_TOO_SMALL (-3)
+#define AC_ERR_PANIC (-4)
+
+#define AC_PRIORITY_LOW 0
+#define AC_PRIORITY_NORMAL 1
+#define AC_PRIORITY_HIGH 2
+#define AC_PRIORITY_URGENT 3
+
+/* Create a wallet in the wallet file at path and return its address, NULL on failure */
+char *ac_wallet_create(const char *path);
+
+/* Whether address is well-formed for this network */
+bool ac_validate_address(const char *address);
+
+/* Confirmed balance of address in satoshis by the chain in datadir; 0 on failure */
+uint64_t ac_get_balance(const char *datadir, const char *address);
+
+/* Fee a typical transaction pays at priority, in satoshis; 0 on failure */
+uint64_t ac_estimate_fee(int priority);
+
+/* Build and sign a payment from the wallet `from` in datadir, without sending it, and
+ * write it to out_hex_buf as NUL-terminated hex. Payments from's spend policy refuses,
+ * or wants confirmed, fail. */
+int ac_create_and_sign_tx(const char *datadir, const char *from, const char *to,
+                          uint64_t amount, int priority, char *out_hex_buf,
+                          size_t buf_len);
+
+/* Close the chain in datadir so other processes can open it */
+int ac_datadir_close(const char *datadir);
+
+/* Copy of the calling thread's last error, NULL if the last call succeeded */
+char *ac_last_error_message(void);
+
+/* Free a string returned by this library; NULL is ignored */
+void ac_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ARCHITECT_CHAIN_H */
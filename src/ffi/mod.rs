@@ -0,0 +1,383 @@
+//! C-compatible functions for embedding the chain in programs not written in Rust
+//!
+//! Built with the `ffi` feature, as a shared library with `make ffi`. `architect_chain.h`
+//! next to this file declares everything here for C callers; Python and C# bind to the
+//! same symbols through ctypes and P/Invoke.
+//!
+//! Ownership: every `char *` a function returns was allocated here and must be passed to
+//! `ac_string_free` exactly once. Strings passed in are only borrowed for the call, and
+//! must be NUL-terminated UTF-8.
+//!
+//! Errors: each function clears the calling thread's last error on entry and sets it when
+//! it fails, so after a failure `ac_last_error_message` says what went wrong. Functions
+//! returning `int` return `AC_OK` or one of the negative `AC_ERR_*` codes; the others
+//! return NULL, false or 0 on failure. A panic never unwinds into the caller, it's
+//! reported as `AC_ERR_PANIC`.
+//!
+//! A data directory is laid out as the command line leaves it: the wallet file
+//! `wallet.dat` and the chain in `data/`. A chain stays open, and locked against other
+//! processes, from the first call that reads it until `ac_datadir_close`.
+
+use crate::core::{Amount, Blockchain, FeeCalculator, FeePriority, Transaction};
+use crate::error::{BlockchainError, Result};
+use crate::storage::UTXOSet;
+use crate::wallet::{address_pub_key_hash, validate_address, Wallets, WALLET_FILE};
+use data_encoding::HEXLOWER;
+use once_cell::sync::Lazy;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Mutex;
+
+pub const AC_OK: c_int = 0;
+/// A pointer was NULL, a string wasn't UTF-8 or a number was out of range
+pub const AC_ERR_INVALID_ARGUMENT: c_int = -1;
+/// The operation itself failed, as the last error message explains
+pub const AC_ERR_FAILED: c_int = -2;
+/// The output buffer can't hold the result and its terminating NUL
+pub const AC_ERR_BUFFER_TOO_SMALL: c_int = -3;
+/// Something panicked; the library is still usable but the operation didn't happen
+pub const AC_ERR_PANIC: c_int = -4;
+
+/// Fee priorities, as `ac_estimate_fee` and `ac_create_and_sign_tx` take them
+pub const AC_PRIORITY_LOW: c_int = 0;
+pub const AC_PRIORITY_NORMAL: c_int = 1;
+pub const AC_PRIORITY_HIGH: c_int = 2;
+pub const AC_PRIORITY_URGENT: c_int = 3;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Chains opened so far, by data directory
+static OPEN_CHAINS: Lazy<Mutex<HashMap<PathBuf, Blockchain>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+enum FfiError {
+    InvalidArgument(String),
+    BufferTooSmall { needed: usize },
+    Failed(BlockchainError),
+}
+
+impl From<BlockchainError> for FfiError {
+    fn from(e: BlockchainError) -> Self {
+        FfiError::Failed(e)
+    }
+}
+
+impl FfiError {
+    fn code(&self) -> c_int {
+        match self {
+            FfiError::InvalidArgument(_) => AC_ERR_INVALID_ARGUMENT,
+            FfiError::BufferTooSmall { .. } => AC_ERR_BUFFER_TOO_SMALL,
+            FfiError::Failed(_) => AC_ERR_FAILED,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            FfiError::InvalidArgument(message) => message.clone(),
+            FfiError::BufferTooSmall { needed } => {
+                format!("The output buffer needs {needed} bytes")
+            }
+            FfiError::Failed(e) => e.to_string(),
+        }
+    }
+}
+
+type FfiResult<T> = std::result::Result<T, FfiError>;
+
+fn set_last_error(message: &str) {
+    // A message can't carry a NUL through a C string, so any inside it are dropped
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// Runs one call: clears the last error, and turns failures and panics into an error code
+// with the last error set
+fn call<T>(body: impl FnOnce() -> FfiResult<T>) -> std::result::Result<T, c_int> {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => {
+            set_last_error(&e.message());
+            Err(e.code())
+        }
+        Err(payload) => {
+            set_last_error(&format!("Panicked: {}", panic_message(payload.as_ref())));
+            Err(AC_ERR_PANIC)
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+/// # Safety
+///
+/// `ptr` is NULL or points to a NUL-terminated string that outlives `'a`
+unsafe fn read_str<'a>(ptr: *const c_char, name: &str) -> FfiResult<&'a str> {
+    if ptr.is_null() {
+        return Err(FfiError::InvalidArgument(format!("{name} is NULL")));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| FfiError::InvalidArgument(format!("{name} is not valid UTF-8")))
+}
+
+fn into_c_string(value: String) -> FfiResult<*mut c_char> {
+    CString::new(value)
+        .map(CString::into_raw)
+        .map_err(|_| FfiError::InvalidArgument("Result contains a NUL byte".to_string()))
+}
+
+fn priority_from(priority: c_int) -> FfiResult<FeePriority> {
+    usize::try_from(priority)
+        .ok()
+        .and_then(|index| FeePriority::ALL.get(index).copied())
+        .ok_or_else(|| {
+            FfiError::InvalidArgument(format!(
+                "Priority {priority} is not one of AC_PRIORITY_LOW to AC_PRIORITY_URGENT"
+            ))
+        })
+}
+
+// The chain in `datadir`, opening it on first use
+fn chain_in(datadir: &Path) -> Result<Blockchain> {
+    let mut chains = OPEN_CHAINS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(blockchain) = chains.get(datadir) {
+        return Ok(blockchain.clone());
+    }
+    let db_path = datadir.join("data");
+    let blockchain = Blockchain::new_blockchain_with_path(&db_path.to_string_lossy())?;
+    chains.insert(datadir.to_path_buf(), blockchain.clone());
+    Ok(blockchain)
+}
+
+/// Create a wallet in the wallet file at `path`, creating the file if needed, and return
+/// its address
+///
+/// # Safety
+///
+/// `path` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ac_wallet_create(path: *const c_char) -> *mut c_char {
+    call(|| {
+        let path = read_str(path, "path")?;
+        let address = Wallets::open(Path::new(path))?.create_wallet()?;
+        into_c_string(address)
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Whether `address` is a well-formed address for this network
+///
+/// # Safety
+///
+/// `address` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ac_validate_address(address: *const c_char) -> bool {
+    call(|| {
+        let address = read_str(address, "address")?;
+        // An invalid address is an answer, not a failure, but the reason is still kept
+        if let Err(e) = validate_address(address) {
+            set_last_error(&e.to_string());
+            return Ok(false);
+        }
+        Ok(true)
+    })
+    .unwrap_or(false)
+}
+
+/// The confirmed balance of `address` in satoshis, by the chain in `datadir`
+///
+/// 0 is also returned on failure; the last error tells the two apart.
+///
+/// # Safety
+///
+/// `datadir` and `address` are NULL or NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn ac_get_balance(datadir: *const c_char, address: *const c_char) -> u64 {
+    call(|| {
+        let datadir = read_str(datadir, "datadir")?;
+        let address = read_str(address, "address")?;
+        let pub_key_hash = address_pub_key_hash(address)?;
+        let utxos = UTXOSet::new(chain_in(Path::new(datadir))?).find_utxo_safe(&pub_key_hash)?;
+        let balance = Amount::checked_sum(utxos.iter().map(|output| output.get_value()))?;
+        Ok(balance.to_satoshis())
+    })
+    .unwrap_or(0)
+}
+
+/// What a typical transaction pays at `priority`, in satoshis, or 0 if the priority isn't
+/// one of `AC_PRIORITY_*`
+#[no_mangle]
+pub extern "C" fn ac_estimate_fee(priority: c_int) -> u64 {
+    call(|| Ok(FeeCalculator::estimate_fee(priority_from(priority)?).to_satoshis())).unwrap_or(0)
+}
+
+/// Build and sign a payment of `amount` satoshis from `from`, a wallet in `datadir`, to
+/// `to`, writing it to `out_hex_buf` as NUL-terminated hex
+///
+/// The transaction is only built, not sent; `sendrawtransaction` takes the hex as it is.
+/// `from`'s spend policy applies as it does to `send`, except that a payment it wants
+/// confirmed is refused.
+/// Returns `AC_ERR_BUFFER_TOO_SMALL` without writing anything if `buf_len` bytes can't
+/// hold it, and the last error says how many are needed.
+///
+/// # Safety
+///
+/// `datadir`, `from` and `to` are NULL or NUL-terminated strings, and `out_hex_buf` is
+/// NULL or valid for writing `buf_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ac_create_and_sign_tx(
+    datadir: *const c_char,
+    from: *const c_char,
+    to: *const c_char,
+    amount: u64,
+    priority: c_int,
+    out_hex_buf: *mut c_char,
+    buf_len: usize,
+) -> c_int {
+    let result = call(|| {
+        let datadir = Path::new(read_str(datadir, "datadir")?);
+        let from = read_str(from, "from")?;
+        let to = read_str(to, "to")?;
+        let priority = priority_from(priority)?;
+        if out_hex_buf.is_null() {
+            return Err(FfiError::InvalidArgument("out_hex_buf is NULL".to_string()));
+        }
+
+        let wallets = Wallets::open(&datadir.join(WALLET_FILE))?;
+        // There's no one here to type the amount back, so a payment needing that is refused
+        if wallets.get_policy(from).check(amount)? {
+            return Err(FfiError::Failed(BlockchainError::Wallet(format!(
+                "Sending {amount} satoshis from {from} has to be confirmed, which only `send` \
+                 can ask for"
+            ))));
+        }
+        let wallet = wallets.signing_wallet(from)?;
+        let utxo_set = UTXOSet::new(chain_in(datadir)?);
+        let tx =
+            Transaction::new_utxo_transaction_from_wallet(wallet, to, amount, priority, &utxo_set)?;
+
+        let hex = HEXLOWER.encode(&tx.serialize()?);
+        if hex.len() >= buf_len {
+            return Err(FfiError::BufferTooSmall {
+                needed: hex.len() + 1,
+            });
+        }
+        ptr::copy_nonoverlapping(hex.as_ptr(), out_hex_buf.cast::<u8>(), hex.len());
+        *out_hex_buf.add(hex.len()) = 0;
+        Ok(())
+    });
+    match result {
+        Ok(()) => AC_OK,
+        Err(code) => code,
+    }
+}
+
+/// Close the chain in `datadir` if a call opened it, so other processes can use it
+///
+/// # Safety
+///
+/// `datadir` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ac_datadir_close(datadir: *const c_char) -> c_int {
+    let result = call(|| {
+        let datadir = read_str(datadir, "datadir")?;
+        OPEN_CHAINS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(Path::new(datadir));
+        Ok(())
+    });
+    match result {
+        Ok(()) => AC_OK,
+        Err(code) => code,
+    }
+}
+
+/// The calling thread's last error, or NULL if the last call succeeded
+///
+/// The message is a copy for the caller to free with `ac_string_free`.
+#[no_mangle]
+pub extern "C" fn ac_last_error_message() -> *mut c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null_mut(), |message| message.clone().into_raw())
+    })
+}
+
+/// Free a string returned by this library; NULL is ignored
+///
+/// # Safety
+///
+/// `s` is NULL or a string returned by this library that hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ac_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> Option<String> {
+        let message = ac_last_error_message();
+        if message.is_null() {
+            return None;
+        }
+        // SAFETY: the message came from ac_last_error_message and is freed once
+        unsafe {
+            let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+            ac_string_free(message);
+            Some(text)
+        }
+    }
+
+    #[test]
+    fn test_panics_become_error_codes() {
+        let result: std::result::Result<(), c_int> = call(|| panic!("boom"));
+        assert_eq!(result, Err(AC_ERR_PANIC));
+        assert_eq!(last_error().as_deref(), Some("Panicked: boom"));
+
+        // The next call starts with a clean slate
+        assert_eq!(call(|| Ok(1)), Ok(1));
+        assert_eq!(last_error(), None);
+    }
+
+    #[test]
+    fn test_bad_arguments_are_reported() {
+        // SAFETY: NULL and NUL-terminated literals are both allowed
+        unsafe {
+            assert!(!ac_validate_address(ptr::null()));
+            assert_eq!(last_error().as_deref(), Some("address is NULL"));
+            assert!(!ac_validate_address(c"not an address".as_ptr()));
+            assert!(last_error().is_some());
+            assert!(ac_validate_address(
+                c"1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".as_ptr()
+            ));
+            assert_eq!(last_error(), None);
+        }
+        assert_eq!(ac_estimate_fee(AC_PRIORITY_URGENT + 1), 0);
+        assert!(last_error().unwrap().contains("Priority 4"));
+        assert!(ac_estimate_fee(AC_PRIORITY_NORMAL) > 0);
+    }
+}
@@ -19,6 +19,7 @@
 //! - `config/`: Configuration management and feature flags
 //! - `utils/`: Cryptographic functions and utility helpers
 //! - `cli/`: Command-line interface for all blockchain operations
+//! - `ffi/`: C functions for embedding, behind the `ffi` feature
 //!
 //! ## Key Design Decisions I Made
 //! - Used Sled embedded database for simplicity and reliability
@@ -41,6 +42,8 @@ pub mod cli;
 pub mod config;
 pub mod core;
 pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod network;
 pub mod storage;
 pub mod utils;
@@ -53,16 +56,24 @@ pub mod testnet;
 pub use cli::{Command, Opt};
 pub use config::{Config, GLOBAL_CONFIG};
 pub use core::{
-    Block, Blockchain, DynamicFeeConfig, FeeCalculator, FeeMode, FeePriority, FeeStatistics,
-    ProofOfWork, TXInput, TXOutput, Transaction,
+    Block, BlockAddResult, BlockSubmission, BlockTemplate, Blockchain, ChainEvent,
+    DynamicFeeConfig, EventBus, FeeCalculator, FeeMode, FeePriority, FeeStatistics, Miner,
+    MiningHandle, NodeContext, ProofOfWork, TXInput, TXOutput, Transaction,
 };
 pub use error::{BlockchainError, Result};
-pub use network::{send_tx, Node, Nodes, Server, SimplePeerManager, CENTRAL_NODE};
+pub use network::{
+    request_abandon, request_balance, request_ban, request_bans, request_block_template,
+    request_chain, request_disconnect, request_mempool_tx, request_message_trace,
+    request_node_status, request_peer_info, request_transaction_status, request_unban, send_tx,
+    send_tx_allowing_high_fee, submit_block_to, DnsSeeder, Node, NodeStatus, Nodes, Server,
+    SimplePeerManager, CENTRAL_NODE,
+};
 pub use storage::{BlockInTransit, MemoryPool, UTXOSet};
 pub use utils::{
     base58_decode, base58_encode, current_timestamp, ecdsa_p256_sha256_sign_digest,
     ecdsa_p256_sha256_sign_verify, new_key_pair, ripemd160_digest, sha256_digest,
 };
 pub use wallet::{
-    convert_address, hash_pub_key, validate_address, Wallet, Wallets, ADDRESS_CHECK_SUM_LEN,
+    convert_address, decode_address, hash_pub_key, validate_address, Wallet, Wallets,
+    ADDRESS_CHECK_SUM_LEN, ADDRESS_VERSION,
 };
@@ -1,18 +1,70 @@
 // This is my main entry point for the blockchain CLI application
 // I'm importing all the core components I built for this blockchain
-use architect_chain::cli::{FeeModeArg, FeePriorityArg};
+use architect_chain::cli::responses::{
+    AbandonTransactionResponse, AccountBalanceResponse, AccountCreatedResponse, AccountEntry,
+    AccountListResponse, AddressAssignedResponse, AddressEntry, AddressRemovedResponse,
+    BackupWalletResponse, BalanceResponse, BanListResponse, BanPeerResponse, BumpFeeResponse,
+    ChangeAddressesResponse, CheckpointResponse, CoinSelectionResponse, ConsolidateResponse,
+    CreateBlockchainResponse, CreateGenesisResponse, CreateWalletResponse, DisconnectPeerResponse,
+    DraftDeletedResponse, DraftEntry, DraftListResponse, DraftSavedResponse, DumpConfigResponse,
+    EstimateFeeResponse, ExportChainResponse, ExportSnapshotResponse, FeeModeResponse,
+    FeeStatusResponse, GenerateResponse, HistoryResponse, ImportAddressResponse,
+    ImportChainResponse, ImportSnapshotResponse, ListAddressesResponse, ListUnspentResponse,
+    ListWatchedResponse, MigrateDbResponse, MiningStatsResponse, MultisigAddressResponse,
+    NodeStatusResponse, NodeStoppedResponse, PaymentFeeEstimateResponse, PaymentRequestResponse,
+    PeerListResponse, PrintchainResponse, ReindexResponse, RescanResponse, RestoreWalletResponse,
+    SendResponse, SentResponse, SupplyResponse, TransactionHexResponse, TransactionStatusResponse,
+    UnbanPeerResponse, UnwatchAddressResponse, WalletBalanceResponse, WalletPolicyResponse,
+    WatchAddressResponse,
+};
+use architect_chain::cli::{
+    AccountCommand, CommandResponse, DraftCommand, ErrorResponse, FeeModeArg, FeePriorityArg,
+    OutputFormat,
+};
+use architect_chain::config::file::{
+    DynamicFeesSection, FeeModeName, FeesSection, MiningSection, NetworkSection, NodeSection,
+};
+use architect_chain::config::{ConfigFile, CONFIG_FILE_NAME};
+use architect_chain::core::monetary::conversions::format_amount;
+use architect_chain::core::monetary::DEFAULT_MAX_CONSOLIDATION_FEE_PERCENT;
+use architect_chain::core::{
+    block_subsidy, Amount, BlockSummarizer, DecodedTransaction, DifficultyAdjustment, FeePolicy,
+    GenesisAllocation, GenesisConfig, Miner, Network, NodeContext, TransactionBuilder, WatchList,
+    COINBASE_MATURITY_WINDOW, DEFAULT_FINALITY_DEPTH, DEFAULT_MAX_TRANSACTION_INPUTS,
+    DEFAULT_MIN_RELAY_FEE_RATE, HALVING_INTERVAL, NOTIFICATIONS_LOG,
+};
+use architect_chain::network::{
+    parse_ban_address, Ban, Netmask, NodeIdentity, SimplePeerManager, DEFAULT_MAX_INV_ITEMS,
+    DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_OUTBOUND_CONNECTIONS, DEFAULT_MAX_PEERS,
+    OPERATOR_BAN_REASON, TRANSACTION_THRESHOLD,
+};
+use architect_chain::storage::UnspentOutput;
+use architect_chain::utils::FORMAT_VERSION;
+use architect_chain::wallet::{
+    address_pub_key_hash, hash_pub_key, payment_uri, wallet_path, Draft, DraftState,
+    PaymentRequest, SharedWallets, SpendPolicy,
+};
 use architect_chain::{
-    convert_address, hash_pub_key, send_tx, utils, validate_address, Blockchain, Command,
-    DynamicFeeConfig, FeeCalculator, FeeMode, FeePriority, Opt, Server, Transaction, UTXOSet,
-    Wallets, ADDRESS_CHECK_SUM_LEN, CENTRAL_NODE, GLOBAL_CONFIG,
+    current_timestamp, request_abandon, request_balance, request_ban, request_bans,
+    request_block_template, request_chain, request_disconnect, request_mempool_tx,
+    request_message_trace, request_node_status, request_peer_info, request_transaction_status,
+    request_unban, send_tx, send_tx_allowing_high_fee, storage, validate_address, Blockchain,
+    BlockchainError, Command, DnsSeeder, DynamicFeeConfig, FeeCalculator, FeeMode, FeePriority,
+    MemoryPool, NodeStatus, Opt, Server, TXOutput, Transaction, UTXOSet, Wallets, CENTRAL_NODE,
+    GLOBAL_CONFIG,
 };
 use clap::Parser;
 use data_encoding::HEXLOWER;
-use log::{error, LevelFilter};
+use log::{error, info, warn, LevelFilter};
+use std::io::{IsTerminal, Write};
+use std::net::IpAddr;
+use std::path::Path;
 use std::process;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
-// I use this constant to check if the user wants to mine immediately after sending a transaction
-const MINE_TRUE: usize = 1;
+// The deprecated positional <mine> argument of send asked for immediate mining with this value
+const LEGACY_MINE_TRUE: usize = 1;
 
 fn main() {
     // I initialize logging so I can see what's happening in my blockchain
@@ -22,97 +74,660 @@ fn main() {
     // I parse the command line arguments using clap - this gives me a nice CLI interface
     let opt = Opt::parse();
 
+    // A --datadir flag wins over ARCHITECT_DATADIR, which GLOBAL_CONFIG already picked up
+    if let Some(datadir) = opt.datadir {
+        GLOBAL_CONFIG.set_data_dir(datadir);
+    }
+    if let Some(wallet) = opt.wallet {
+        GLOBAL_CONFIG.set_wallet_file(wallet);
+    }
+    if let Some(network) = opt.network {
+        GLOBAL_CONFIG.set_network(network);
+    }
+    if opt.allow_network_mismatch {
+        GLOBAL_CONFIG.set_allow_network_mismatch(true);
+    }
+
     // I run the actual command and handle any errors that might occur
-    // If something goes wrong, I log the error and exit with code 1
-    if let Err(e) = run_command(opt.command) {
-        error!("Error: {e}");
+    // If something goes wrong, I report the error and exit with code 1. A config file is
+    // read before anything else, so every command sees its settings, and the startnode
+    // flags applied later win over it.
+    // The older --json flags of single commands ask for the same thing as --output json
+    let output = if opt.command.prints_json() {
+        OutputFormat::Json
+    } else {
+        opt.output
+    };
+    let result = load_config_file(opt.config.as_deref())
+        .and_then(|()| log_data_paths())
+        .and_then(|()| run_command(opt.command, output))
+        .and_then(|response| {
+            print_response(response.as_ref(), output)?;
+            match response.failure() {
+                Some(failure) => Err(CommandFailed(failure).into()),
+                None => Ok(()),
+            }
+        });
+    if let Err(e) = result {
+        match output {
+            // A result that was printed already is the one JSON object; the exit code alone
+            // tells its failure apart
+            OutputFormat::Json if e.downcast_ref::<CommandFailed>().is_some() => {}
+            OutputFormat::Json => {
+                match serde_json::to_string_pretty(&ErrorResponse::new(e.as_ref())) {
+                    Ok(json) => println!("{json}"),
+                    Err(json_error) => error!("Error: {e} ({json_error})"),
+                }
+            }
+            OutputFormat::Text => error!("Error: {e}"),
+        }
         process::exit(1);
     }
 }
 
+// A command that printed its result and still failed, like verifychain finding a bad block
+#[derive(Debug)]
+struct CommandFailed(String);
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+// Text looks the way the commands always printed it; JSON is a single object on stdout
+fn print_response(
+    response: &dyn CommandResponse,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match output {
+        OutputFormat::Json => println!("{}", response.to_json()?),
+        OutputFormat::Text => {
+            let text = response.to_string();
+            if !text.is_empty() {
+                print!("{text}");
+                if !text.ends_with('\n') {
+                    println!();
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Progress along the way goes to stdout as it always did, unless stdout is kept for JSON
+fn note(output: OutputFormat, line: impl std::fmt::Display) {
+    match output {
+        OutputFormat::Text => println!("{line}"),
+        OutputFormat::Json => eprintln!("{line}"),
+    }
+}
+
+// Advice for the user added to an error, which keeps the error's kind in JSON output
+#[derive(Debug)]
+struct WithHint {
+    error: BlockchainError,
+    hint: String,
+}
+
+impl std::fmt::Display for WithHint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}. {}", self.error, self.hint)
+    }
+}
+
+impl std::error::Error for WithHint {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// I take settings from --config, or from architect.toml in the data directory if there is one
+fn load_config_file(explicit: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match explicit {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let path = GLOBAL_CONFIG.data_dir()?.join(CONFIG_FILE_NAME);
+            if !path.exists() {
+                return Ok(());
+            }
+            path
+        }
+    };
+    let file = ConfigFile::load(&path)?;
+    GLOBAL_CONFIG.apply_file(&file);
+    if let Some(mode) = file.fee_mode() {
+        FeeCalculator::initialize(mode)?;
+    }
+    info!("Loaded node settings from {}", path.display());
+    GLOBAL_CONFIG.set_config_file(path);
+    Ok(())
+}
+
+// I say where files will go up front, since a wrong working directory otherwise only shows
+// up as a mysteriously empty chain
+fn log_data_paths() -> Result<(), Box<dyn std::error::Error>> {
+    info!(
+        "Data directory: {}, chain database: {}, wallet file: {}",
+        GLOBAL_CONFIG.data_dir()?.display(),
+        Blockchain::default_db_path()?,
+        wallet_path()?.display()
+    );
+    Ok(())
+}
+
 // This is where I handle all the different CLI commands
 // Each command corresponds to a different blockchain operation I want to perform
-fn run_command(command: Command) -> Result<(), Box<dyn std::error::Error>> {
-    match command {
+fn run_command(
+    command: Command,
+    output: OutputFormat,
+) -> Result<Box<dyn CommandResponse>, Box<dyn std::error::Error>> {
+    let response: Box<dyn CommandResponse> = match command {
         // When I want to create a new blockchain, this is the genesis block creation
-        Command::Createblockchain { address } => {
-            // First, I validate that the address format is correct (Bitcoin-compatible)
-            if !validate_address(&address) {
-                return Err(format!("Invalid address: {address}").into());
-            }
-            // I create the blockchain with this address receiving the genesis block reward
-            let blockchain = Blockchain::create_blockchain(&address)?;
+        Command::Createblockchain {
+            address,
+            genesis_file,
+        } => {
+            let blockchain = match (address, genesis_file) {
+                // Every node given the same file ends up with the same genesis block
+                (_, Some(path)) => {
+                    Blockchain::create_blockchain_with_genesis(&GenesisConfig::load(&path)?)?
+                }
+                (Some(address), None) => {
+                    // First, I validate that the address format is correct (Bitcoin-compatible)
+                    validate_address(&address)?;
+                    // I create the blockchain with this address receiving the genesis block reward
+                    Blockchain::create_blockchain(&address)?
+                }
+                (None, None) => return Err("Give an address or --genesis-file".into()),
+            };
+            let genesis_hash = blockchain.get_genesis_hash()?;
             // I need to build the UTXO set from the blockchain for efficient balance lookups
             let utxo_set = UTXOSet::new(blockchain);
             utxo_set.reindex();
-            println!("Done!");
+            Box::new(CreateBlockchainResponse { genesis_hash })
+        }
+        // When I'm starting a new network whose nodes all have to agree on its first block
+        Command::CreateGenesis {
+            out,
+            message,
+            allocate,
+            timestamp,
+            difficulty,
+        } => {
+            let allocations = allocate
+                .iter()
+                .map(|allocation| {
+                    let (address, amount) = allocation
+                        .rsplit_once(':')
+                        .ok_or_else(|| format!("Expected ADDRESS:SATOSHIS, got {allocation}"))?;
+                    let amount = amount
+                        .parse()
+                        .map_err(|e| format!("Invalid amount in {allocation}: {e}"))?;
+                    Ok(GenesisAllocation {
+                        address: address.to_string(),
+                        amount,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let config = GenesisConfig {
+                timestamp: match timestamp {
+                    Some(timestamp) => timestamp,
+                    None => current_timestamp()?,
+                },
+                message,
+                difficulty: difficulty.unwrap_or(DifficultyAdjustment::get_initial_difficulty()),
+                allocations,
+            };
+
+            let genesis = config.save(&out)?;
+            Box::new(CreateGenesisResponse {
+                genesis_hash: genesis.get_hash().to_string(),
+                path: out,
+            })
         }
         // When I want to create a new wallet for storing my cryptocurrency
-        Command::Createwallet => {
+        Command::Createwallet { label, notes } | Command::NewAddress { label, notes } => {
             // I load the wallet collection (or create it if it doesn't exist)
-            let mut wallet = Wallets::new();
-            // I generate a new ECDSA key pair and derive a Bitcoin-compatible address
-            let address = wallet.create_wallet()?;
-            println!("Your new address: {address}")
+            let mut wallet = cli_wallets().write();
+            // I generate a new ECDSA key pair and derive a Bitcoin-compatible address. With
+            // a chain in the data directory the key remembers it, so it isn't used with
+            // another chain by mistake
+            let address = match (existing_blockchain(), &label) {
+                (Some(blockchain), label) => {
+                    wallet.create_wallet_for_chain(label.as_deref(), &blockchain)?
+                }
+                (None, Some(label)) => wallet.create_wallet_with_label(label)?,
+                (None, None) => wallet.create_wallet()?,
+            };
+            if let Some(notes) = &notes {
+                wallet.set_notes(&address, Some(notes))?;
+            }
+            Box::new(CreateWalletResponse { address, label })
+        }
+        // When I want to be paid, I hand out a URI the payer can feed to `send --uri`
+        Command::PaymentRequest {
+            address,
+            amount,
+            message,
+        } => {
+            let amount = amount
+                .as_deref()
+                .map(payment_uri::parse_coins)
+                .transpose()?;
+            let request = PaymentRequest::new(&address, amount, message)?;
+            Box::new(PaymentRequestResponse {
+                uri: request.to_string(),
+            })
         }
         // When I want to check how much cryptocurrency an address has
-        Command::GetBalance { address } => {
+        Command::GetBalance {
+            address,
+            whole_wallet,
+            include_pending,
+            remote,
+        } => {
+            // The whole wallet file also counts the change its payments sent to new addresses
+            let Some(address) = address.filter(|_| !whole_wallet) else {
+                let wallets = cli_wallets().read();
+                let blockchain = Blockchain::new_blockchain()?;
+                check_wallet_network(&wallets, &wallets.get_addresses(), &blockchain)?;
+                let utxo_set = UTXOSet::new(blockchain);
+                return Ok(Box::new(WalletBalanceResponse {
+                    addresses: wallets.get_addresses().len(),
+                    confirmed: wallets.balance(&utxo_set)?.to_satoshis(),
+                }));
+            };
+
             // First, I validate the address format
-            if !validate_address(&address) {
-                return Err(format!("Invalid address: {address}").into());
+            validate_address(&address)?;
+
+            if include_pending {
+                // Pending transactions only exist inside a running node, so a local read
+                // has none to count
+                let balance = match &remote {
+                    Some(addr) => request_balance(addr, &address)?,
+                    None => UTXOSet::new(Blockchain::new_blockchain()?).balance_with_pending(
+                        &address_pub_key_hash(&address)?,
+                        &MemoryPool::new(),
+                    )?,
+                };
+                return Ok(Box::new(BalanceResponse {
+                    confirmed: balance.confirmed.to_satoshis(),
+                    pending_in: Some(balance.pending_incoming.to_satoshis()),
+                    pending_out: Some(balance.pending_outgoing.to_satoshis()),
+                    available: Some(balance.available().to_satoshis()),
+                    address,
+                }));
             }
 
-            // I decode the Base58 address to get the public key hash
-            let payload = utils::base58_decode(&address)?;
-            if payload.len() < ADDRESS_CHECK_SUM_LEN + 1 {
-                return Err("Address too short".into());
+            // A running node holds the database, so it has to read the balance for me
+            if let Some(addr) = &remote {
+                return Ok(Box::new(BalanceResponse {
+                    confirmed: request_balance(addr, &address)?.confirmed.to_satoshis(),
+                    pending_in: None,
+                    pending_out: None,
+                    available: None,
+                    address,
+                }));
             }
-            // I extract the public key hash from the address (removing version byte and checksum)
-            let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+
+            // I decode the Base58 address to get the public key hash
+            let pub_key_hash = address_pub_key_hash(&address)?;
 
             // I load the blockchain and build the UTXO set for efficient lookups
             let blockchain = Blockchain::new_blockchain()?;
             let utxo_set = UTXOSet::new(blockchain);
             // I find all unspent transaction outputs belonging to this address
-            let utxos = utxo_set.find_utxo(pub_key_hash);
+            let utxos = utxo_set.find_utxo(&pub_key_hash);
             // I sum up all the values to get the total balance
-            let mut balance = 0;
-            for utxo in utxos {
-                balance += utxo.get_value();
+            let balance = Amount::checked_sum(utxos.iter().map(TXOutput::get_value))?;
+            Box::new(BalanceResponse {
+                address,
+                confirmed: balance.to_satoshis(),
+                pending_in: None,
+                pending_out: None,
+                available: None,
+            })
+        }
+        Command::ListUnspent {
+            address, min_value, ..
+        } => {
+            validate_address(&address)?;
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain()?);
+            let unspent: Vec<UnspentOutput> = utxo_set
+                .list_unspent_for(&address_pub_key_hash(&address)?)?
+                .into_iter()
+                .filter(|output| output.value.to_satoshis() >= min_value)
+                .collect();
+            Box::new(ListUnspentResponse { address, unspent })
+        }
+        // Watching needs nothing but the address, its balance comes straight from the chain.
+        // Its past transactions don't, so I rescan for them unless told not to.
+        Command::ImportAddress {
+            address,
+            label,
+            birthday_height,
+            no_rescan,
+        } => {
+            let mut wallets = cli_wallets().write();
+            wallets.add_watch_only(&address, label.as_deref())?;
+            if let Some(height) = birthday_height {
+                wallets.set_birthday_height(&address, height)?;
+            }
+            note(output, format!("Watching {address}"));
+            let mut response = ImportAddressResponse {
+                address,
+                rescan: None,
+                rescan_skipped: None,
+            };
+            if !no_rescan {
+                match Blockchain::new_blockchain() {
+                    Ok(blockchain) => {
+                        response.rescan = Some(blockchain.rescan_for_addresses(
+                            &[address_pub_key_hash(&response.address)?],
+                            birthday_height.unwrap_or(0),
+                            |height| note(output, format!("Rescanned up to block {height}")),
+                        )?);
+                    }
+                    Err(e) => response.rescan_skipped = Some(e.to_string()),
+                }
             }
-            println!("Balance of {address}: {balance}");
+            Box::new(response)
         }
+        // Without a starting height I go back to the earliest birthday, which is genesis as
+        // soon as one of the addresses has none
+        Command::Rescan {
+            addresses,
+            from_height,
+        } => {
+            let wallets = cli_wallets().read();
+            let addresses = if addresses.is_empty() {
+                let mut all = wallets.get_addresses();
+                all.extend(wallets.get_watch_only_addresses());
+                all.sort();
+                all
+            } else {
+                for address in &addresses {
+                    validate_address(address)?;
+                }
+                addresses
+            };
+            if addresses.is_empty() {
+                return Err("The wallet file has no addresses to rescan for".into());
+            }
+            let blockchain = Blockchain::new_blockchain()?;
+            check_wallet_network(&wallets, &addresses, &blockchain)?;
+            let pub_key_hashes = addresses
+                .iter()
+                .map(|address| address_pub_key_hash(address))
+                .collect::<architect_chain::Result<Vec<_>>>()?;
+            let report = blockchain.rescan_for_addresses(
+                &pub_key_hashes,
+                from_height.unwrap_or_else(|| wallets.rescan_height(&addresses)),
+                |height| note(output, format!("Rescanned up to block {height}")),
+            )?;
+            Box::new(RescanResponse {
+                addresses: addresses.len(),
+                report,
+            })
+        }
+        // Addresses a rescan went through read their history from the address index
+        Command::History {
+            address,
+            whole_wallet,
+            ..
+        } => match address.filter(|_| !whole_wallet) {
+            Some(address) => {
+                validate_address(&address)?;
+                let blockchain = Blockchain::new_blockchain()?;
+                let entries = blockchain.address_history(&address_pub_key_hash(&address)?)?;
+                Box::new(HistoryResponse { address, entries })
+            }
+            None => {
+                let blockchain = Blockchain::new_blockchain()?;
+                Box::new(HistoryResponse {
+                    address: "the wallet".to_string(),
+                    entries: cli_wallets().read().history(&blockchain)?,
+                })
+            }
+        },
         // When I want to see all the wallet addresses I have created
-        Command::ListAddresses => {
+        Command::ListAddresses {
+            with_balance,
+            with_usage,
+        } => {
             // I load my wallet collection
-            let wallets = Wallets::new();
-            // I iterate through all addresses and print them
-            for address in wallets.get_addresses() {
-                println!("{address}")
+            let wallets = cli_wallets().read();
+            // Balances and usage need the chain, so I only open it when asked to
+            let blockchain = if with_balance || with_usage {
+                Some(Blockchain::new_blockchain()?)
+            } else {
+                None
+            };
+            let utxo_set = blockchain
+                .as_ref()
+                .filter(|_| with_balance)
+                .map(|blockchain| UTXOSet::new(blockchain.clone()));
+
+            // I sort so the output is stable between runs
+            let mut addresses = wallets.get_addresses();
+            addresses.extend(wallets.get_watch_only_addresses());
+            addresses.sort();
+            let mut entries = Vec::with_capacity(addresses.len());
+            for address in addresses {
+                let balance = match &utxo_set {
+                    Some(utxo_set) => Some(
+                        Amount::checked_sum(
+                            utxo_set
+                                .find_utxo(&address_pub_key_hash(&address)?)
+                                .iter()
+                                .map(TXOutput::get_value),
+                        )?
+                        .to_satoshis(),
+                    ),
+                    None => None,
+                };
+                let usage = match blockchain.as_ref().filter(|_| with_usage) {
+                    Some(blockchain) => {
+                        Some(blockchain.address_usage(&address_pub_key_hash(&address)?)?)
+                    }
+                    None => None,
+                };
+                entries.push(AddressEntry {
+                    label: wallets.get_label(&address).map(str::to_string),
+                    watch_only: wallets.is_watch_only(&address),
+                    change: wallets.is_change_address(&address),
+                    balance,
+                    usage,
+                    address,
+                });
+            }
+            Box::new(ListAddressesResponse { addresses: entries })
+        }
+        Command::Account { action } => {
+            let mut wallets = cli_wallets().write();
+            match action {
+                AccountCommand::Create { name } => {
+                    wallets.create_account(&name)?;
+                    Box::new(AccountCreatedResponse { account: name })
+                }
+                AccountCommand::Assign {
+                    name,
+                    address,
+                    change,
+                } => {
+                    validate_address(&address)?;
+                    wallets.assign_address(&name, &address)?;
+                    if change {
+                        wallets.set_change_address(&name, &address)?;
+                    }
+                    let change_address =
+                        wallets.get_account(&name)?.change_address() == Some(address.as_str());
+                    Box::new(AddressAssignedResponse {
+                        account: name,
+                        address,
+                        change_address,
+                    })
+                }
+                AccountCommand::Remove { name, address } => {
+                    let utxo_set = UTXOSet::new(Blockchain::new_blockchain()?);
+                    let left = wallets.remove_address(&name, &address, &utxo_set)?;
+                    // The coins are still mine, but the account's balance no longer shows them
+                    if left > Amount::ZERO {
+                        eprintln!(
+                            "warning: {address} still holds {} satoshis, which no longer count \
+                             toward '{name}'",
+                            left.to_satoshis()
+                        );
+                    }
+                    Box::new(AddressRemovedResponse {
+                        account: name,
+                        address,
+                        remaining_balance: left.to_satoshis(),
+                    })
+                }
+                AccountCommand::List => Box::new(AccountListResponse {
+                    accounts: wallets
+                        .accounts()
+                        .iter()
+                        .map(|(name, account)| AccountEntry {
+                            name: name.to_string(),
+                            addresses: account.addresses().iter().cloned().collect(),
+                            change_address: account.change_address().map(str::to_string),
+                        })
+                        .collect(),
+                }),
+                AccountCommand::Balance { name } => {
+                    let utxo_set = UTXOSet::new(Blockchain::new_blockchain()?);
+                    let balance = wallets.account_balance(&name, &utxo_set)?;
+                    Box::new(AccountBalanceResponse {
+                        account: name,
+                        balance: balance.to_satoshis(),
+                    })
+                }
             }
         }
+        // Backups carry the labels too, and can be encrypted even though wallet.dat isn't
+        Command::BackupWallet { out, password } => {
+            let header = cli_wallets().write().backup(&out, password.as_deref())?;
+            Box::new(BackupWalletResponse {
+                wallets: header.wallet_count,
+                path: out,
+                encrypted: header.is_encrypted(),
+            })
+        }
+        // The backup is checked before wallet.dat is touched, so a bad file changes nothing
+        Command::RestoreWallet {
+            input,
+            password,
+            merge,
+        } => {
+            let summary = cli_wallets()
+                .write()
+                .restore(&input, password.as_deref(), merge)?;
+            Box::new(RestoreWalletResponse {
+                restored: summary.added,
+                backed_up: summary.header.wallet_count,
+                merged: merge,
+                wallet_file: wallet_path()?,
+                safety_copy: summary.safety_copy,
+            })
+        }
         // When I want to send cryptocurrency from one address to another
         Command::Send {
             from,
             to,
             amount,
+            all,
             mine,
+            legacy_mine,
             priority,
+            fee,
+            from_label,
+            from_account,
+            to_pubkey,
+            uri,
+            memo_hex,
+            allow_high_fee,
+            yes,
+            save_draft,
+            coin_selection,
+            from_draft,
+            node,
         } => {
-            // I validate both addresses to make sure they're properly formatted
-            if !validate_address(&from) {
-                return Err(format!("Invalid sender address: {from}").into());
+            let node_addr = node.unwrap_or_else(|| CENTRAL_NODE.to_string());
+            if let Some(name) = from_draft {
+                return send_draft(&name, &node_addr);
             }
-            if !validate_address(&to) {
-                return Err(format!("Invalid recipient address: {to}").into());
+            // clap asks for both unless --from-draft is given
+            let (Some(from), Some(to)) = (from, to) else {
+                return Err("A payment needs a sender and a recipient".into());
+            };
+            // With --from-label I look the sender up in my wallet file by its label
+            let from = if from_label {
+                cli_wallets().read().resolve_label(&from)?
+            } else {
+                from
+            };
+
+            // With --uri the recipient, and possibly the amount, come from a payment request
+            // clap makes sure there is an amount unless --all is given
+            let amount = amount.unwrap_or_default();
+            let (to, amount) = if uri {
+                let request = payment_uri::parse(&to)?;
+                let amount = request.resolve_amount((amount != 0).then_some(amount))?;
+                (request.address, amount)
+            } else {
+                (to, amount)
+            };
+
+            // I validate both addresses to make sure they're properly formatted; an account's
+            // addresses were checked when they were assigned
+            if !from_account {
+                validate_address(&from)?;
             }
-            if amount == 0 {
+            // With --to-pubkey the recipient is a raw public key rather than an address
+            let to_pub_key = if to_pubkey {
+                let pub_key = HEXLOWER
+                    .decode(to.as_bytes())
+                    .map_err(|e| format!("Invalid recipient public key {to}: {e}"))?;
+                Some(pub_key)
+            } else {
+                validate_address(&to)?;
+                None
+            };
+            if amount == 0 && !all {
                 return Err("Amount must be positive".into());
             }
+            let memo = match &memo_hex {
+                Some(hex) => Some(
+                    HEXLOWER
+                        .decode(hex.to_lowercase().as_bytes())
+                        .map_err(|e| format!("Invalid memo hex {hex}: {e}"))?,
+                ),
+                None => None,
+            };
 
             // I load the blockchain and create the UTXO set for transaction validation
             let blockchain = Blockchain::new_blockchain()?;
             let utxo_set = UTXOSet::new(blockchain.clone());
+            {
+                let wallets = cli_wallets().read();
+                let spending_from: Vec<String> = if from_account {
+                    wallets
+                        .get_account(&from)?
+                        .addresses()
+                        .iter()
+                        .cloned()
+                        .collect()
+                } else {
+                    vec![from.clone()]
+                };
+                check_wallet_network(&wallets, &spending_from, &blockchain)?;
+            }
 
             // I convert the CLI priority argument to my internal priority enum
             let fee_priority = match priority {
@@ -123,92 +738,772 @@ fn run_command(command: Command) -> Result<(), Box<dyn std::error::Error>> {
                 None => FeePriority::Normal, // Default to normal priority
             };
 
-            // I create the transaction with the appropriate fee calculation method
-            let transaction = if priority.is_some() {
-                // If priority is specified, I use the priority-based fee calculation
-                Transaction::new_utxo_transaction_with_priority(
+            // Without the key I can only hand the payment over for signing, so I print it
+            // rather than fail on the missing key
+            if !from_account && cli_wallets().read().is_watch_only(&from) {
+                if mine || legacy_mine == Some(LEGACY_MINE_TRUE) {
+                    return Err(format!(
+                        "{from} is watch-only, so its payments can't be mined or sent before \
+                         they are signed where its key is kept"
+                    )
+                    .into());
+                }
+                if all
+                    || to_pub_key.is_some()
+                    || memo.is_some()
+                    || allow_high_fee
+                    || coin_selection.is_some()
+                {
+                    return Err("Payments from a watch-only address don't support --all, \
+                                --to-pubkey, --memo-hex, --allow-high-fee or --coin-selection"
+                        .into());
+                }
+                let unsigned = match fee {
+                    Some(fee) => Transaction::new_unsigned_utxo_transaction_with_explicit_fee(
+                        &from,
+                        &to,
+                        amount,
+                        fee,
+                        &utxo_set,
+                        &cli_wallets().read(),
+                    )?,
+                    None => Transaction::new_unsigned_utxo_transaction(
+                        &from,
+                        &to,
+                        amount,
+                        fee_priority,
+                        &utxo_set,
+                        &cli_wallets().read(),
+                    )?,
+                };
+                eprintln!(
+                    "{from} is watch-only, nothing was sent. Sign this transaction with \
+                     signrawtransaction where the key is kept, then send it with \
+                     sendrawtransaction"
+                );
+                if let Some(name) = &save_draft {
+                    save_cli_draft(name, unsigned.transaction(), DraftState::Unsigned)?;
+                }
+                return Ok(Box::new(TransactionHexResponse::new(unsigned.to_hex()?)));
+            }
+            if save_draft.is_some() {
+                return Err(
+                    "--save-draft only keeps the unsigned payment of a watch-only \
+                            address; a signed payment is sent right away"
+                        .into(),
+                );
+            }
+
+            // The flag beats the wallet's default; a sweep takes every coin anyway
+            let coin_selection = if all {
+                None
+            } else {
+                coin_selection.or_else(|| cli_wallets().read().default_coin_selection())
+            };
+
+            // I build the transaction with the fee policy the flags ask for, signing with the
+            // wallet file this command loaded; only a sweep too large for one transaction
+            // comes out as several
+            let transactions = if from_account {
+                Transaction::new_account_transaction_with_coin_selection(
                     &from,
                     &to,
                     amount,
                     fee_priority,
+                    coin_selection,
                     &utxo_set,
-                )?
+                    &cli_wallets().read(),
+                )
+                .map(|tx| vec![tx])
             } else {
-                // Otherwise, I use the default fee calculation
-                Transaction::new_utxo_transaction(&from, &to, amount, &utxo_set)?
+                // clap keeps the flags that don't go together apart
+                let mut builder = TransactionBuilder::new(&utxo_set)
+                    .from(&from)
+                    .allow_high_fee(allow_high_fee);
+                builder = match &to_pub_key {
+                    Some(to_pub_key) => builder.add_output_to_pub_key(to_pub_key, amount),
+                    None => builder.add_output(&to, amount),
+                };
+                if let Some(memo) = &memo {
+                    builder = builder.memo(memo);
+                }
+                if let Some(strategy) = coin_selection {
+                    builder = builder.coin_selection(strategy);
+                }
+                // A sweep spends every coin, so the fee comes out of what is sent
+                builder
+                    .fee_policy(match (all, fee) {
+                        (true, Some(fee)) => FeePolicy::SweepAllWithFee(fee.into()),
+                        (true, None) => FeePolicy::SweepAll(fee_priority),
+                        (false, Some(fee)) => FeePolicy::Explicit(fee.into()),
+                        (false, None) => FeePolicy::Priority(fee_priority),
+                    })
+                    .build_and_sign_all(cli_wallets())
+            };
+            // Running short is the most common way for a send to fail, so I tell the user
+            // which knobs they have left
+            let transactions = match transactions {
+                Ok(transactions) => transactions,
+                Err(e @ BlockchainError::InsufficientFundsDetailed { .. }) => {
+                    let hint = if fee.is_some() {
+                        "lower the amount or the --fee"
+                    } else if matches!(priority, Some(FeePriorityArg::Low)) {
+                        "lower the amount"
+                    } else {
+                        "lower the amount or pay a smaller fee with --priority low"
+                    };
+                    return Err(WithHint {
+                        error: e,
+                        hint: format!("To send anyway, {hint}"),
+                    }
+                    .into());
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            // I hold the payment to the sender's spend policy before anything leaves the machine
+            let sent = if all {
+                transactions
+                    .iter()
+                    .map(|tx| tx.get_vout()[0].get_value().to_satoshis())
+                    .sum()
+            } else {
+                amount
+            };
+            let total_fee = transactions
+                .iter()
+                .map(|tx| tx.get_fee().to_satoshis())
+                .sum();
+            // A payment from an account answers to the policy of every address it spends from
+            let wallets = cli_wallets().read();
+            let spenders = if from_account {
+                wallets
+                    .get_account(&from)?
+                    .addresses()
+                    .iter()
+                    .filter(|address| transactions.iter().any(|tx| spends_from(tx, address)))
+                    .cloned()
+                    .collect()
+            } else {
+                vec![from.clone()]
+            };
+            let mut needs_confirm = false;
+            for address in &spenders {
+                needs_confirm |= wallets.get_policy(address).check(sent)?;
+            }
+            if needs_confirm && !yes {
+                confirm_send(sent, &to, total_fee)?;
+            }
+            // Any spend before this one already tied the sender's payments together
+            let mut reuse_warnings = Vec::new();
+            for address in &spenders {
+                let usage = blockchain.address_usage(&address_pub_key_hash(address)?)?;
+                reuse_warnings.extend(usage.reuse_warning(address));
+            }
+
+            // The old positional form still works for now, but only a 1 meant "mine"
+            let mine = match legacy_mine {
+                Some(value) => {
+                    eprintln!(
+                        "warning: the positional <mine> argument is deprecated and will be \
+                         removed in the next release, pass --mine instead"
+                    );
+                    mine || value == LEGACY_MINE_TRUE
+                }
+                None => mine,
             };
 
             // I decide whether to mine the transaction immediately or send it to the network
-            if mine == MINE_TRUE {
-                // If mining immediately, I create a new block with this transaction
-                let block = blockchain.mine_block_with_fees(&[transaction], &from)?;
-                // I update the UTXO set with the new block
-                utxo_set.update(&block);
+            let mined_block = if mine {
+                // If mining immediately, I build the block from this transaction and whatever
+                // else is waiting in the pool, using every core like a mining node does. The
+                // chainstate is updated along with the block.
+                let miner = if from_account {
+                    wallets
+                        .get_account(&from)?
+                        .change_address()
+                        .unwrap_or_default()
+                        .to_string()
+                } else {
+                    from.clone()
+                };
+                // Outside a running node the memory pool starts out empty
+                let context = mining_context();
+                let mut block = None;
+                for transaction in &transactions {
+                    block = Some(blockchain.mine_transaction_with_context(
+                        transaction,
+                        &miner,
+                        &context,
+                    )?);
+                }
+                block.map(|block| block.get_hash().to_string())
             } else {
                 // Otherwise, I broadcast the transaction to the P2P network
-                send_tx(CENTRAL_NODE, &transaction);
+                for transaction in &transactions {
+                    if allow_high_fee {
+                        send_tx_allowing_high_fee(&node_addr, transaction);
+                    } else {
+                        send_tx(&node_addr, transaction);
+                    }
+                }
+                None
+            };
+            let mut txids = transactions.iter().map(|tx| HEXLOWER.encode(tx.get_id()));
+            Box::new(SendResponse {
+                txid: txids.next().unwrap_or_default(),
+                other_txids: txids.collect(),
+                to,
+                amount: sent,
+                fee: total_fee,
+                broadcast: mined_block.is_none(),
+                mined_block,
+                swept: all,
+                reuse_warnings,
+                input_count: transactions.iter().map(|tx| tx.get_vin().len()).sum(),
+                coin_selection,
+            })
+        }
+        Command::SetWalletPolicy {
+            address,
+            confirm_above,
+            max_per_tx,
+            clear,
+        } => {
+            let mut wallets = cli_wallets().write();
+            let mut policy = if clear {
+                SpendPolicy::default()
+            } else {
+                wallets.get_policy(&address)
+            };
+            if confirm_above.is_some() {
+                policy.max_send_without_confirm = confirm_above;
             }
-            println!("Success!")
+            if max_per_tx.is_some() {
+                policy.spend_limit_per_tx = max_per_tx;
+            }
+            wallets.set_policy(&address, policy)?;
+            Box::new(WalletPolicyResponse {
+                address,
+                confirm_above: policy.max_send_without_confirm,
+                max_per_tx: policy.spend_limit_per_tx,
+            })
         }
-        // When I want to see the entire blockchain history (useful for debugging)
-        Command::Printchain => {
-            // I create an iterator that walks through the blockchain from newest to oldest
-            let mut block_iterator = Blockchain::new_blockchain()?.iterator();
-            loop {
-                let option = block_iterator.next();
-                if let Some(block) = option {
-                    // I print the block header information
-                    println!("Pre block hash: {}", block.get_pre_block_hash());
-                    println!("Cur block hash: {}", block.get_hash());
-                    println!("Cur block Timestamp: {}", block.get_timestamp());
-
-                    // I iterate through all transactions in this block
-                    for tx in block.get_transactions() {
-                        let cur_txid_hex = HEXLOWER.encode(tx.get_id());
-                        println!("- Transaction txid_hex: {cur_txid_hex}");
-
-                        // For regular transactions (not coinbase), I show the inputs
-                        if !tx.is_coinbase() {
-                            for input in tx.get_vin() {
-                                let txid_hex = HEXLOWER.encode(input.get_txid());
-                                // I convert the public key to an address for readability
-                                let pub_key_hash = hash_pub_key(input.get_pub_key());
-                                let address = convert_address(pub_key_hash.as_slice());
-                                println!(
-                                    "-- Input txid = {}, vout = {}, from = {}",
-                                    txid_hex,
-                                    input.get_vout(),
-                                    address,
-                                )
-                            }
-                        }
-                        // I show all outputs (where the money is going)
-                        for output in tx.get_vout() {
-                            let pub_key_hash = output.get_pub_key_hash();
-                            let address = convert_address(pub_key_hash);
-                            println!("-- Output value = {}, to = {}", output.get_value(), address,)
-                        }
-                    }
-                    println!()
-                } else {
-                    // No more blocks to iterate through
-                    break;
+        Command::UseChangeAddresses { enabled } => {
+            cli_wallets().write().set_use_change_addresses(enabled)?;
+            Box::new(ChangeAddressesResponse {
+                use_change_addresses: enabled,
+            })
+        }
+        Command::SetCoinSelection { strategy, .. } => {
+            // clap only lets --clear through without a strategy
+            cli_wallets().write().set_default_coin_selection(strategy)?;
+            Box::new(CoinSelectionResponse {
+                coin_selection: strategy,
+            })
+        }
+        // When my transaction is stuck, I replace it with a higher-fee copy spending the same inputs
+        Command::BumpFee {
+            txid,
+            priority,
+            node,
+        } => {
+            let node_addr = node.unwrap_or_else(|| CENTRAL_NODE.to_string());
+            let txid_bytes = HEXLOWER
+                .decode(txid.as_bytes())
+                .map_err(|e| format!("Invalid transaction id {txid}: {e}"))?;
+
+            // The pending transaction lives in the running node's memory pool, not in this process
+            let original = request_mempool_tx(&node_addr, &txid_bytes)?.ok_or_else(|| {
+                format!("Transaction {txid} is not pending in the memory pool of {node_addr}")
+            })?;
+
+            let fee_priority = match priority {
+                FeePriorityArg::Low => FeePriority::Low,
+                FeePriorityArg::Normal => FeePriority::Normal,
+                FeePriorityArg::High => FeePriority::High,
+                FeePriorityArg::Urgent => FeePriority::Urgent,
+            };
+            let new_fee = original
+                .replacement_fee(fee_priority, storage::DEFAULT_MIN_REPLACEMENT_FEE_INCREMENT);
+
+            // I need the chain to look up the outputs being spent while re-signing
+            let blockchain = Blockchain::new_blockchain()?;
+            let wallets = cli_wallets().read();
+            let signers: Vec<String> = wallets
+                .get_addresses()
+                .into_iter()
+                .filter(|address| spends_from(&original, address))
+                .collect();
+            check_wallet_network(&wallets, &signers, &blockchain)?;
+            let replacement = original.bump_fee(new_fee, &blockchain, &wallets)?;
+
+            send_tx(&node_addr, &replacement);
+            Box::new(BumpFeeResponse {
+                replaced: txid,
+                txid: HEXLOWER.encode(replacement.get_id()),
+                old_fee: original.get_fee().to_satoshis(),
+                new_fee: new_fee.to_satoshis(),
+            })
+        }
+        // When my transaction won't confirm and I'd rather spend its coins differently, I
+        // drop it from the node's pool instead of paying more for it
+        Command::AbandonTransaction { txid, node, local } => {
+            let txid_bytes = HEXLOWER
+                .decode(txid.as_bytes())
+                .map_err(|e| format!("Invalid transaction id {txid}: {e}"))?;
+
+            let pending = if local {
+                abandon_local_transaction(&txid)?
+            } else {
+                let node_addr = node.unwrap_or_else(|| CENTRAL_NODE.to_string());
+                let pending = request_mempool_tx(&node_addr, &txid_bytes)?.ok_or_else(|| {
+                    format!("Transaction {txid} is not pending in the memory pool of {node_addr}")
+                })?;
+                // Someone else's payment isn't mine to take back
+                cli_wallets().read().check_spends_own_coins(&pending)?;
+                request_abandon(&node_addr, &txid_bytes)?;
+                pending
+            };
+            Box::new(AbandonTransactionResponse {
+                txid,
+                inputs_freed: pending.get_vin().len(),
+            })
+        }
+        // Lots of tiny outputs make every payment big, so I merge them while fees are cheap
+        Command::Consolidate {
+            address,
+            max_inputs,
+            priority,
+            mine,
+        } => {
+            let fee_priority = match priority {
+                FeePriorityArg::Low => FeePriority::Low,
+                FeePriorityArg::Normal => FeePriority::Normal,
+                FeePriorityArg::High => FeePriority::High,
+                FeePriorityArg::Urgent => FeePriority::Urgent,
+            };
+            let blockchain = Blockchain::new_blockchain()?;
+            check_wallet_network(
+                &cli_wallets().read(),
+                std::slice::from_ref(&address),
+                &blockchain,
+            )?;
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            // Priced and signed the way --mine mines it, with the wallet file this command loaded
+            let context = mining_context().with_wallets(cli_wallets().clone());
+            let transactions = Transaction::new_consolidation_transaction_with_context(
+                &address,
+                max_inputs,
+                fee_priority,
+                &utxo_set,
+                &context,
+            )?;
+
+            let mined_block = if mine {
+                let mut block = None;
+                for transaction in &transactions {
+                    block = Some(blockchain.mine_transaction_with_context(
+                        transaction,
+                        &address,
+                        &context,
+                    )?);
+                }
+                block.map(|block| block.get_hash().to_string())
+            } else {
+                for transaction in &transactions {
+                    send_tx(CENTRAL_NODE, transaction);
                 }
+                None
+            };
+            let mut txids = transactions.iter().map(|tx| HEXLOWER.encode(tx.get_id()));
+            Box::new(ConsolidateResponse {
+                txid: txids.next().unwrap_or_default(),
+                other_txids: txids.collect(),
+                inputs: transactions.iter().map(|tx| tx.get_vin().len()).sum(),
+                amount: transactions
+                    .iter()
+                    .map(|tx| tx.get_vout()[0].get_value().to_satoshis())
+                    .sum(),
+                fee: transactions
+                    .iter()
+                    .map(|tx| tx.get_fee().to_satoshis())
+                    .sum(),
+                broadcast: mined_block.is_none(),
+                mined_block,
+            })
+        }
+        // When several keys guard one address, I print the address they share
+        Command::CreateMultisig {
+            required,
+            addresses,
+        } => {
+            let mut pub_key_hashes = Vec::with_capacity(addresses.len());
+            for address in &addresses {
+                validate_address(address)?;
+                pub_key_hashes.push(address_pub_key_hash(address)?);
+            }
+            let address = TXOutput::multisig_address(required, &pub_key_hashes)?;
+            Box::new(MultisigAddressResponse {
+                address,
+                required,
+                keys: addresses.len(),
+            })
+        }
+        // A multisig payment starts out unsigned and goes round the signers as hex
+        Command::SendMultisig {
+            from,
+            to,
+            amount,
+            priority,
+        } => {
+            let fee_priority = match priority {
+                Some(FeePriorityArg::Low) => FeePriority::Low,
+                Some(FeePriorityArg::Normal) | None => FeePriority::Normal,
+                Some(FeePriorityArg::High) => FeePriority::High,
+                Some(FeePriorityArg::Urgent) => FeePriority::Urgent,
+            };
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain()?);
+            let transaction =
+                Transaction::create_unsigned(&from, &to, amount, fee_priority, &utxo_set)?;
+            Box::new(TransactionHexResponse::new(
+                HEXLOWER.encode(&transaction.serialize()?),
+            ))
+        }
+        Command::SignPartial {
+            transaction,
+            signer,
+            save_draft,
+        } => {
+            let mut transaction = decode_partial_transaction(&transaction)?;
+            let wallets = cli_wallets().read();
+            let wallet = wallets.signing_wallet(&signer)?;
+
+            let blockchain = Blockchain::new_blockchain()?;
+            check_wallet_network(&wallets, std::slice::from_ref(&signer), &blockchain)?;
+            let signed = transaction.add_signature(wallet, &blockchain)?;
+            let missing: usize = transaction.missing_signatures(&blockchain)?.iter().sum();
+            eprintln!("Signed {signed} inputs, {missing} signatures still missing");
+            if let Some(name) = &save_draft {
+                drop(wallets);
+                let state = DraftState::of(&transaction, &blockchain)?;
+                save_cli_draft(name, &transaction, state)?;
+            }
+            Box::new(TransactionHexResponse {
+                signed_inputs: Some(signed),
+                missing_signatures: Some(missing),
+                ..TransactionHexResponse::new(HEXLOWER.encode(&transaction.serialize()?))
+            })
+        }
+        // Once the copies together carry enough signatures, I send the payment off
+        Command::CombinePartial {
+            transactions,
+            mine,
+            node,
+        } => {
+            let mut copies = transactions
+                .iter()
+                .map(|hex| decode_partial_transaction(hex));
+            let mut transaction = copies.next().ok_or("No transactions to combine")??;
+            for copy in copies {
+                transaction.combine(&copy?)?;
+            }
+
+            let blockchain = Blockchain::new_blockchain()?;
+            let missing: usize = transaction.missing_signatures(&blockchain)?.iter().sum();
+            if missing > 0 {
+                eprintln!("{missing} signatures still missing");
+                return Ok(Box::new(TransactionHexResponse {
+                    missing_signatures: Some(missing),
+                    ..TransactionHexResponse::new(HEXLOWER.encode(&transaction.serialize()?))
+                }));
+            }
+
+            let transaction = transaction.finalize(&blockchain)?;
+            let txid = HEXLOWER.encode(transaction.get_id());
+            let mined_block = if let Some(miner) = mine {
+                validate_address(&miner)?;
+                let block = blockchain.mine_block_with_context(
+                    &[transaction],
+                    &miner,
+                    &mining_context(),
+                )?;
+                UTXOSet::new(blockchain).update(&block);
+                Some(block.get_hash().to_string())
+            } else {
+                send_tx(node.as_deref().unwrap_or(CENTRAL_NODE), &transaction);
+                None
+            };
+            Box::new(SentResponse {
+                txid,
+                broadcast: mined_block.is_none(),
+                mined_block,
+            })
+        }
+        // A raw transaction names its inputs outright, so I need neither a wallet nor the chain
+        Command::CreateRawTransaction {
+            inputs,
+            outputs,
+            save_draft,
+        } => {
+            let inputs = inputs
+                .iter()
+                .map(|input| parse_outpoint(input))
+                .collect::<Result<Vec<_>, _>>()?;
+            let outputs = outputs
+                .iter()
+                .map(|output| parse_payment(output))
+                .collect::<Result<Vec<_>, _>>()?;
+            let transaction = Transaction::new_raw(&inputs, outputs)?;
+            if let Some(name) = &save_draft {
+                save_cli_draft(name, &transaction, DraftState::Unsigned)?;
+            }
+            Box::new(TransactionHexResponse::new(transaction.to_hex()?))
+        }
+        Command::DecodeRawTransaction { transaction, .. } => Box::new(DecodedTransaction::decode(
+            &Transaction::from_hex(&transaction)?,
+        )),
+        Command::GetTransaction { txid, remote } => {
+            let txid_bytes = HEXLOWER
+                .decode(txid.as_bytes())
+                .map_err(|e| format!("Invalid transaction id {txid}: {e}"))?;
+            let status = match remote {
+                Some(addr) => request_transaction_status(&addr, &txid_bytes)?,
+                None => Blockchain::new_blockchain()?.get_transaction_status(&txid_bytes)?,
+            };
+            Box::new(TransactionStatusResponse {
+                txid,
+                is_final: status.is_final(),
+                block: status.block,
+                confirmations: status.confirmations,
+                blocks_to_final: status.blocks_to_final,
+            })
+        }
+        // Whatever the inputs hold beyond the outputs becomes the fee, as the outputs were
+        // written down without knowing the inputs' values
+        Command::SignRawTransaction {
+            transaction,
+            wallet_address,
+        } => {
+            let mut transaction = Transaction::from_hex(&transaction)?;
+            let wallets = cli_wallets().read();
+            let wallet = wallets.signing_wallet(&wallet_address)?;
+            let blockchain = Blockchain::new_blockchain()?;
+            check_wallet_network(&wallets, std::slice::from_ref(&wallet_address), &blockchain)?;
+            transaction.sign_raw(wallet, &blockchain)?;
+            eprintln!(
+                "Signed {} inputs, fee {}",
+                transaction.get_vin().len(),
+                format_amount(transaction.get_fee().to_satoshis())
+            );
+            Box::new(TransactionHexResponse {
+                signed_inputs: Some(transaction.get_vin().len()),
+                fee: Some(transaction.get_fee().to_satoshis()),
+                ..TransactionHexResponse::new(transaction.to_hex()?)
+            })
+        }
+        Command::SendRawTransaction {
+            transaction,
+            mine,
+            node,
+        } => {
+            let transaction = Transaction::from_hex(&transaction)?;
+            transaction.check_structure()?;
+            // An unsigned payment from a watch-only address would only be turned away
+            if let Some(idx) = transaction
+                .get_vin()
+                .iter()
+                .position(|input| !input.is_signed())
+            {
+                return Err(format!(
+                    "Input {idx} is unsigned, sign the transaction with signrawtransaction first"
+                )
+                .into());
+            }
+            let txid = HEXLOWER.encode(transaction.get_id());
+            let mined_block = if let Some(miner) = mine {
+                validate_address(&miner)?;
+                let blockchain = Blockchain::new_blockchain()?;
+                let block = blockchain.mine_block_with_context(
+                    &[transaction],
+                    &miner,
+                    &mining_context(),
+                )?;
+                UTXOSet::new(blockchain).update(&block);
+                Some(block.get_hash().to_string())
+            } else {
+                send_tx(node.as_deref().unwrap_or(CENTRAL_NODE), &transaction);
+                None
+            };
+            Box::new(SentResponse {
+                txid,
+                broadcast: mined_block.is_none(),
+                mined_block,
+            })
+        }
+        // Staleness needs the chain, everything else only the wallet file
+        Command::Draft { action } => match action {
+            DraftCommand::Save { name, transaction } => {
+                let transaction = Transaction::from_hex(&transaction)?;
+                let state = DraftState::of(&transaction, &Blockchain::new_blockchain()?)?;
+                let size = save_cli_draft(&name, &transaction, state)?;
+                Box::new(DraftSavedResponse { name, state, size })
+            }
+            DraftCommand::List => {
+                let blockchain = Blockchain::new_blockchain()?;
+                let wallets = cli_wallets().read();
+                let drafts = wallets
+                    .list_drafts()
+                    .map(|(name, draft)| draft_entry(name, draft, &blockchain, false))
+                    .collect::<Result<_, _>>()?;
+                Box::new(DraftListResponse { drafts })
+            }
+            DraftCommand::Show { name } => {
+                let blockchain = Blockchain::new_blockchain()?;
+                let wallets = cli_wallets().read();
+                Box::new(draft_entry(
+                    &name,
+                    wallets.load_draft(&name)?,
+                    &blockchain,
+                    true,
+                )?)
             }
+            DraftCommand::Delete { name } => {
+                cli_wallets().write().delete_draft(&name)?;
+                Box::new(DraftDeletedResponse { name })
+            }
+        },
+        // When I want to see the entire blockchain history (useful for debugging)
+        Command::Printchain {
+            from_height,
+            to_height,
+            limit,
+            remote,
+            ..
+        } => {
+            let blocks =
+                match remote {
+                    Some(addr) => request_chain(&addr, from_height, to_height, limit)?,
+                    None => BlockSummarizer::new(&Blockchain::new_blockchain()?)
+                        .summarize_main_chain(from_height, to_height, limit)?,
+                };
+            Box::new(PrintchainResponse { blocks })
+        }
+        // When I want to back up the chain or hand it to a new node as a bootstrap file
+        Command::ExportChain { path } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let header = storage::export_chain(&blockchain, Path::new(&path))?;
+            Box::new(ExportChainResponse {
+                path,
+                blocks: header.block_count,
+                tip: header.tip_hash.to_string(),
+            })
+        }
+        // When I want to bootstrap this node from an exported chain file
+        Command::ImportChain {
+            path,
+            force,
+            assume_valid,
+        } => {
+            // Every block is validated as it's read, and the UTXO set is rebuilt at the end
+            let db_path = Blockchain::default_db_path()?;
+            let blockchain =
+                storage::import_chain(Path::new(&path), &db_path, force, assume_valid)?;
+            Box::new(ImportChainResponse {
+                height: blockchain.get_best_height()?,
+                tip: blockchain.get_tip_hash().to_string(),
+            })
+        }
+        // When I want to hand a new node a working chainstate instead of the whole chain
+        Command::ExportSnapshot { path } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let meta = UTXOSet::export_snapshot(Path::new(&path), &blockchain)?;
+            Box::new(ExportSnapshotResponse {
+                path,
+                height: meta.height,
+                tip: meta.tip_hash,
+                transactions: meta.transactions,
+                hash: meta.hash,
+            })
+        }
+        // The file and its headers are checked against this chain before anything changes
+        Command::ImportSnapshot { path } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            UTXOSet::import_snapshot(Path::new(&path), &blockchain)?;
+            Box::new(ImportSnapshotResponse {
+                height: blockchain.get_best_height()?,
+                tip: blockchain.get_tip_hash(),
+            })
+        }
+        // The watch list lives in the database `startnode` opens, so a running node picks
+        // up the change the next time it starts
+        Command::WatchAddress { address } => {
+            validate_address(&address)?;
+            let blockchain = node_blockchain()?;
+            let newly_watched = WatchList::open(&blockchain)?.watch(&address)?;
+            Box::new(WatchAddressResponse {
+                address,
+                newly_watched,
+                notifications: blockchain.get_db_path().join(NOTIFICATIONS_LOG),
+            })
         }
+        Command::UnwatchAddress { address } => {
+            validate_address(&address)?;
+            let was_watched = WatchList::open(&node_blockchain()?)?.unwatch(&address)?;
+            Box::new(UnwatchAddressResponse {
+                address,
+                was_watched,
+            })
+        }
+        Command::ListWatched => Box::new(ListWatchedResponse {
+            addresses: WatchList::open(&node_blockchain()?)?.addresses()?,
+        }),
         // When I want to rebuild the UTXO index (useful if it gets corrupted)
         Command::Reindexutxo => {
             // I load the blockchain
             let blockchain = Blockchain::new_blockchain()?;
+            // The chainstate answers spent-output lookups through the transaction index,
+            // so I rebuild that first
+            let indexed = blockchain.reindex_transactions()?;
             // I create a new UTXO set and rebuild it from scratch
             let utxo_set = UTXOSet::new(blockchain);
             utxo_set.reindex();
             // I count how many transactions are in the UTXO set for verification
-            let count = utxo_set.count_transactions();
-            println!("Done! There are {count} transactions in the UTXO set.");
+            Box::new(ReindexResponse {
+                transactions: indexed,
+                with_unspent_outputs: utxo_set.count_transactions(),
+            })
+        }
+        // When I want to know whether my database survived a crash intact
+        Command::MigrateDb => {
+            let blockchain = Blockchain::new_blockchain()?;
+            // Blocks already in the current format are left alone, so this can be rerun
+            Box::new(MigrateDbResponse {
+                rewritten: blockchain.migrate_block_format()?,
+                format_version: FORMAT_VERSION,
+            })
+        }
+        // A failed check still prints its report, and main turns it into the exit code
+        Command::VerifyChain { level } => {
+            Box::new(Blockchain::new_blockchain()?.verify_chain(level)?)
         }
         // When I want to start a blockchain node (either as a miner or validator)
-        Command::StartNode { miner } => {
+        Command::StartNode {
+            miner,
+            genesis_file,
+            assume_valid,
+            metrics_addr,
+            explorer_addr,
+            block_interval_secs,
+            tx_threshold,
+            mining_threads,
+            dns_seed,
+            allow_peer,
+            notify_cmd,
+            prune,
+            sync_only,
+            sync_settle_secs,
+        } => {
             // I configure the node based on the network address it should listen on
             let socket_addr = GLOBAL_CONFIG.get_node_addr();
             let node_id = GLOBAL_CONFIG.extract_node_id_from_addr();
@@ -216,24 +1511,53 @@ fn run_command(command: Command) -> Result<(), Box<dyn std::error::Error>> {
 
             // If a miner address is provided, this node will participate in mining
             if let Some(addr) = miner {
-                if !validate_address(&addr) {
-                    return Err(format!("Invalid miner address: {addr}").into());
-                }
-                println!("Mining is on. Address to receive rewards: {addr}");
+                validate_address(&addr)?;
                 GLOBAL_CONFIG.set_mining_addr(addr);
             }
+            if let Some(addr) = GLOBAL_CONFIG.get_mining_addr() {
+                note(
+                    output,
+                    format!("Mining is on. Address to receive rewards: {addr}"),
+                );
+            }
+            if let Some(threshold) = tx_threshold {
+                if threshold == 0 {
+                    return Err("The transaction threshold must be at least 1".into());
+                }
+                GLOBAL_CONFIG.set_transaction_threshold(threshold);
+            }
+            if let Some(secs) = block_interval_secs {
+                GLOBAL_CONFIG.set_block_interval(Duration::from_secs(secs));
+            }
+            // The config file may already have named a thread count
+            match mining_threads {
+                Some(threads) => GLOBAL_CONFIG.set_mining_threads(threads as usize),
+                None if GLOBAL_CONFIG.get_mining_threads().is_none() => {
+                    GLOBAL_CONFIG.set_mining_threads(default_mining_threads())
+                }
+                None => {}
+            }
 
             // I need to load the blockchain for this specific node
             // Each node has its own database to ensure proper isolation
-            let blockchain = if let Some(existing_node_id) = GLOBAL_CONFIG.get_node_id() {
+            let genesis = genesis_file
+                .map(|path| GenesisConfig::load(&path))
+                .transpose()?;
+            let blockchain = if let Some(genesis) = &genesis {
+                // A node joining a network this way syncs everything after the genesis block
+                let db_path = match GLOBAL_CONFIG.get_node_id() {
+                    Some(node_id) => Blockchain::node_db_path(&node_id)?,
+                    None => Blockchain::default_db_path()?,
+                };
+                Blockchain::create_blockchain_with_genesis_path(genesis, &db_path)?
+            } else if let Some(existing_node_id) = GLOBAL_CONFIG.get_node_id() {
                 match Blockchain::new_blockchain_with_node_id(&existing_node_id) {
                     Ok(bc) => bc,
                     Err(_) => {
                         // If no blockchain exists for this node, I need to either:
                         // 1. Create a new one (if this is the first node)
                         // 2. Sync from other nodes (if this is a joining node)
-                        println!("No blockchain found for node {existing_node_id}. Use 'createblockchain' first or sync from network.");
-                        return Err("No blockchain found for this node".into());
+                        return Err(format!("No blockchain found for node {existing_node_id}. Use 'createblockchain' first or sync from network.").into());
                     }
                 }
             } else {
@@ -241,38 +1565,419 @@ fn run_command(command: Command) -> Result<(), Box<dyn std::error::Error>> {
                 Blockchain::new_blockchain()?
             };
 
+            blockchain.set_assume_valid_below_checkpoints(assume_valid);
+            blockchain.set_notify_command(notify_cmd);
+            if let Some(keep_blocks) = prune {
+                note(
+                    output,
+                    format!(
+                        "Pruning is on, keeping the last {} blocks",
+                        keep_blocks.max(COINBASE_MATURITY_WINDOW)
+                    ),
+                );
+                blockchain.set_prune_keep_blocks(Some(keep_blocks));
+                // Whatever is already buried deep enough goes right away
+                blockchain.prune_if_enabled();
+            }
+
+            // Ranges given with --allow-peer add to the config file's; with none anywhere
+            // every peer is welcome
+            if !allow_peer.is_empty() {
+                let mut allowed = GLOBAL_CONFIG.get_allowed_peers();
+                for range in &allow_peer {
+                    allowed.push(range.parse::<Netmask>()?);
+                }
+                GLOBAL_CONFIG.set_allowed_peers(&allowed);
+            }
+            let allowed = GLOBAL_CONFIG.get_allowed_peers();
+            if !allowed.is_empty() {
+                let ranges: Vec<String> = allowed.iter().map(Netmask::to_string).collect();
+                note(
+                    output,
+                    format!("Only talking to peers in {}", ranges.join(", ")),
+                );
+            }
+
             // I create the P2P server and start listening for connections
-            let server = Server::new(blockchain);
+            // I add any extra DNS seeds, from the config file and then --dns-seed, to the
+            // ones for the network I'm joining
+            let mut dns_seeder = DnsSeeder::for_network(GLOBAL_CONFIG.get_network(), &socket_addr);
+            for seed in GLOBAL_CONFIG.get_dns_seeds().into_iter().chain(dns_seed) {
+                dns_seeder.add_seed(seed);
+            }
+            // I sign everything the node sends with a key kept across restarts, so peers
+            // know it by that key instead of whatever address a package claims
+            let identity = NodeIdentity::load_or_create(&NodeIdentity::node_path(&node_id)?)?;
+            note(output, format!("Node identity: {}", identity.peer_id()));
+            let context = NodeContext::from_globals().with_identity(identity);
+            let mut server =
+                Server::with_context(blockchain, Arc::new(context)).with_dns_seeder(dns_seeder);
+            if let Some(metrics_addr) = metrics_addr {
+                server = server.with_metrics_addr(metrics_addr);
+            }
+            if let Some(explorer_addr) = explorer_addr {
+                server = server.with_explorer_addr(explorer_addr);
+            }
+            // A sync-only node is a backup or CI job: it stops by itself once caught up
+            if sync_only {
+                server = server.with_sync_only(Duration::from_secs(sync_settle_secs));
+            }
             server
                 .run(&socket_addr)
-                .map_err(|e| format!("Server error: {e}"))?
+                .map_err(|e| format!("Server error: {e}"))?;
+            let synced_height = sync_only.then(|| {
+                server
+                    .get_context()
+                    .sync_state()
+                    .progress(Instant::now())
+                    .current_height
+            });
+            Box::new(NodeStoppedResponse { synced_height })
+        }
+        // When I want the whole picture of a node in one place
+        Command::NodeStatus { remote, trace, .. } => {
+            // Mempool and peers only exist inside a running node, so the local read omits them
+            let status = match &remote {
+                Some(addr) => request_node_status(addr)?,
+                None => NodeStatus::collect_local(&Blockchain::new_blockchain()?)?,
+            };
+            // clap only lets --trace through together with --remote
+            let messages = match (&remote, trace) {
+                (Some(addr), true) => Some(request_message_trace(addr)?),
+                _ => None,
+            };
+            Box::new(NodeStatusResponse { status, messages })
+        }
+        // When a peer keeps misbehaving, or was banned by mistake
+        Command::BanPeer {
+            address,
+            duration,
+            remote,
+        } => {
+            let duration = Duration::from_secs(duration);
+            let bans = match remote {
+                Some(addr) => request_ban(&addr, &address, duration)?,
+                None => with_local_bans(|peer_manager| {
+                    peer_manager.ban(
+                        parse_ban_address(&address)?,
+                        duration,
+                        OPERATOR_BAN_REASON,
+                    )?;
+                    Ok(())
+                })?,
+            };
+            Box::new(BanPeerResponse {
+                address,
+                duration_secs: duration.as_secs(),
+                bans: BanListResponse::new(bans)?,
+            })
+        }
+        Command::UnbanPeer { address, remote } => {
+            // A remote node only sends back its ban list, so only a local unban can tell
+            let mut was_banned = None;
+            let bans = match remote {
+                Some(addr) => request_unban(&addr, &address)?,
+                None => with_local_bans(|peer_manager| {
+                    was_banned = Some(peer_manager.unban(parse_ban_address(&address)?)?);
+                    Ok(())
+                })?,
+            };
+            Box::new(UnbanPeerResponse {
+                address,
+                was_banned,
+                bans: BanListResponse::new(bans)?,
+            })
+        }
+        Command::ListBans { remote, .. } => {
+            let bans = match remote {
+                Some(addr) => request_bans(&addr)?,
+                None => with_local_bans(|_| Ok(()))?,
+            };
+            Box::new(BanListResponse::new(bans)?)
+        }
+        // When a peer hogs bandwidth or misbehaves without quite earning a ban
+        Command::GetPeerInfo { node, .. } => {
+            let node_addr = node.unwrap_or_else(|| CENTRAL_NODE.to_string());
+            Box::new(PeerListResponse {
+                peers: request_peer_info(&node_addr)?,
+            })
+        }
+        Command::DisconnectPeer { address, ban, node } => {
+            let node_addr = node.unwrap_or_else(|| CENTRAL_NODE.to_string());
+            let closed = request_disconnect(&node_addr, &address, ban.map(Duration::from_secs))?;
+            Box::new(DisconnectPeerResponse {
+                address,
+                closed,
+                ban_secs: ban,
+            })
+        }
+        // When an external miner wants work; a local template has no memory pool to draw on
+        Command::GetBlockTemplate {
+            miner_address,
+            remote,
+            ..
+        } => {
+            validate_address(&miner_address)?;
+            let template = match remote {
+                Some(addr) => request_block_template(&addr, &miner_address)?,
+                None => Blockchain::new_blockchain()?
+                    .build_block_template(&miner_address, &MemoryPool::new())?,
+            };
+            Box::new(template)
+        }
+        // When I want to pin a known block so forks contradicting it are refused
+        Command::AddCheckpoint { height, hash } => {
+            if GLOBAL_CONFIG.get_network() != Network::Regtest {
+                return Err(
+                    "addcheckpoint is only for development chains; pass --network regtest to use it"
+                        .into(),
+                );
+            }
+            let blockchain = Blockchain::new_blockchain()?;
+            blockchain.add_checkpoint(height, &hash)?;
+            Box::new(CheckpointResponse { height, hash })
+        }
+        // When a test script needs blocks now rather than at the real difficulty
+        Command::Generate {
+            count,
+            address,
+            dev,
+        } => {
+            if GLOBAL_CONFIG.get_network() != Network::Regtest {
+                return Err(
+                    "generate is only for development chains; pass --network regtest to use it"
+                        .into(),
+                );
+            }
+            if !dev {
+                return Err(
+                    "generate ignores the difficulty rules and is only for development chains; pass --dev to use it"
+                        .into(),
+                );
+            }
+            validate_address(&address)?;
+            let blockchain = Blockchain::new_blockchain()?;
+            blockchain.set_force_difficulty(Some(DifficultyAdjustment::get_min_difficulty()));
+            // Outside a running node the memory pool starts out empty
+            let context = NodeContext::from_globals();
+            let blocks = blockchain
+                .generate_blocks(count, &address, &context)?
+                .iter()
+                .map(|block| block.get_hash().to_string())
+                .collect();
+            Box::new(GenerateResponse {
+                blocks,
+                height: blockchain.get_best_height()?,
+            })
+        }
+        // Every setting as a node started now would use it, defaults included
+        Command::DumpConfig => {
+            let node_addr = GLOBAL_CONFIG.get_node_addr();
+            let mut dns_seeder = DnsSeeder::for_network(GLOBAL_CONFIG.get_network(), &node_addr);
+            for seed in GLOBAL_CONFIG.get_dns_seeds() {
+                dns_seeder.add_seed(seed);
+            }
+            let (mode, fixed_amount, base_fee, max_fee) = match FeeCalculator::get_fee_mode() {
+                FeeMode::Fixed { amount } => (FeeModeName::Fixed, Some(amount), None, None),
+                FeeMode::Dynamic { config } => (
+                    FeeModeName::Dynamic,
+                    None,
+                    Some(config.base_fee),
+                    Some(config.max_fee),
+                ),
+            };
+            let effective = ConfigFile {
+                node: NodeSection {
+                    listen_addr: Some(node_addr),
+                    finality_depth: Some(
+                        GLOBAL_CONFIG
+                            .get_finality_depth()
+                            .unwrap_or(DEFAULT_FINALITY_DEPTH),
+                    ),
+                    max_tx_inputs: Some(
+                        GLOBAL_CONFIG
+                            .get_max_transaction_inputs()
+                            .unwrap_or(DEFAULT_MAX_TRANSACTION_INPUTS),
+                    ),
+                    verify_threads: Some(
+                        GLOBAL_CONFIG
+                            .get_verify_threads()
+                            .unwrap_or_else(Blockchain::default_verification_threads),
+                    ),
+                },
+                mining: MiningSection {
+                    address: GLOBAL_CONFIG.get_mining_addr(),
+                    threads: Some(
+                        GLOBAL_CONFIG
+                            .get_mining_threads()
+                            .unwrap_or_else(default_mining_threads),
+                    ),
+                    block_interval_secs: GLOBAL_CONFIG
+                        .get_block_interval()
+                        .map(|interval| interval.as_secs()),
+                    tx_threshold: Some(
+                        GLOBAL_CONFIG
+                            .get_transaction_threshold()
+                            .unwrap_or(TRANSACTION_THRESHOLD),
+                    ),
+                },
+                fees: FeesSection {
+                    mode: Some(mode),
+                    fixed_amount,
+                    dynamic: DynamicFeesSection { base_fee, max_fee },
+                    max_consolidation_fee_percent: Some(
+                        GLOBAL_CONFIG
+                            .get_max_consolidation_fee_percent()
+                            .unwrap_or(DEFAULT_MAX_CONSOLIDATION_FEE_PERCENT),
+                    ),
+                    min_relay_fee_rate: Some(
+                        GLOBAL_CONFIG
+                            .get_min_relay_fee_rate()
+                            .unwrap_or(DEFAULT_MIN_RELAY_FEE_RATE),
+                    ),
+                    allow_free_transactions: Some(GLOBAL_CONFIG.allow_free_transactions()),
+                },
+                network: NetworkSection {
+                    seeds: dns_seeder.get_seeds().to_vec(),
+                    max_peers: Some(GLOBAL_CONFIG.get_max_peers().unwrap_or(DEFAULT_MAX_PEERS)),
+                    max_outbound_connections: Some(
+                        GLOBAL_CONFIG
+                            .get_max_outbound_connections()
+                            .unwrap_or(DEFAULT_MAX_OUTBOUND_CONNECTIONS),
+                    ),
+                    max_message_size: Some(
+                        GLOBAL_CONFIG
+                            .get_max_message_size()
+                            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+                    ),
+                    max_inv_items: Some(
+                        GLOBAL_CONFIG
+                            .get_max_inv_items()
+                            .unwrap_or(DEFAULT_MAX_INV_ITEMS),
+                    ),
+                    allow_unsigned_peers: Some(GLOBAL_CONFIG.allow_unsigned_peers()),
+                    allow_unversioned_network: Some(GLOBAL_CONFIG.allow_unversioned_network()),
+                    allowed_peers: GLOBAL_CONFIG.get_allowed_peers(),
+                },
+            };
+            Box::new(DumpConfigResponse::new(
+                GLOBAL_CONFIG.get_config_file(),
+                GLOBAL_CONFIG.data_dir()?,
+                effective,
+            )?)
+        }
+        // When I want to see how many coins exist and where the halving schedule stands
+        Command::Supply => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let height = blockchain.get_best_height()?;
+            Box::new(SupplyResponse {
+                height,
+                total_supply: blockchain.get_total_supply()?,
+                next_subsidy: block_subsidy(height + 1),
+                next_halving: (height / HALVING_INTERVAL + 1) * HALVING_INTERVAL,
+            })
+        }
+        // When I want to see how the chain has been doing, e.g. to tune the difficulty
+        Command::ChainStats { window, all, .. } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let stats = if all {
+                blockchain.get_lifetime_chain_stats()?
+            } else {
+                blockchain.get_chain_stats(usize::try_from(window).unwrap_or(usize::MAX))?
+            };
+            Box::new(stats)
+        }
+        // When I want to see who has been mining and how fast my own blocks came
+        Command::MiningStats { window, .. } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let report =
+                blockchain.get_mining_report(usize::try_from(window).unwrap_or(usize::MAX))?;
+            Box::new(MiningStatsResponse::new(report)?)
+        }
+        // When I want to see what the next block has to meet and how hard the network is mining
+        Command::GetDifficulty { window, .. } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let report =
+                blockchain.get_difficulty_report(usize::try_from(window).unwrap_or(usize::MAX))?;
+            Box::new(report)
         }
         // When I want to estimate how much fee I should pay for a transaction
-        Command::EstimateFee { priority } => {
+        Command::EstimateFee {
+            priority,
+            legacy_priority,
+            from,
+            amount,
+            coin_selection,
+        } => {
             // I convert the CLI priority to my internal enum
-            let fee_priority = match priority {
-                FeePriorityArg::Low => FeePriority::Low,
-                FeePriorityArg::Normal => FeePriority::Normal,
-                FeePriorityArg::High => FeePriority::High,
-                FeePriorityArg::Urgent => FeePriority::Urgent,
+            let fee_priority = match priority.or(legacy_priority) {
+                Some(FeePriorityArg::Low) => FeePriority::Low,
+                Some(FeePriorityArg::Normal) | None => FeePriority::Normal,
+                Some(FeePriorityArg::High) => FeePriority::High,
+                Some(FeePriorityArg::Urgent) => FeePriority::Urgent,
             };
 
             // I use my fee calculator to estimate the appropriate fee
-            let estimated_fee = FeeCalculator::estimate_fee(fee_priority);
-            println!("Estimated fee for {priority} priority: {estimated_fee} coins");
+            let (Some(from), Some(amount)) = (from, amount) else {
+                let context = fee_context();
+                let typical_size = FeeCalculator::estimate_transaction_size(2, 2);
+                let typical_fees: Vec<_> = FeePriority::ALL
+                    .into_iter()
+                    .map(|priority| context.calculate_fee(typical_size, Some(priority)))
+                    .collect();
+                return Ok(Box::new(EstimateFeeResponse::new(
+                    fee_priority,
+                    context.estimate_fee(fee_priority),
+                    &typical_fees,
+                    context.get_fee_statistics().as_ref(),
+                )));
+            };
+
+            // With a payment to price I select its coins as send would, at every priority;
+            // the amount goes to an output like any other address's, as only its size counts
+            validate_address(&from)?;
+            if amount == 0 {
+                return Err("Amount must be positive".into());
+            }
+            let utxo_set = UTXOSet::new(Blockchain::new_blockchain()?);
+            let coin_selection =
+                coin_selection.or_else(|| cli_wallets().read().default_coin_selection());
+            let previews = FeePriority::ALL
+                .into_iter()
+                .map(|priority| {
+                    let mut builder = TransactionBuilder::new(&utxo_set)
+                        .from(&from)
+                        .add_txoutput(TXOutput::new(amount, &from)?)
+                        .fee_policy(FeePolicy::Priority(priority));
+                    if let Some(strategy) = coin_selection {
+                        builder = builder.coin_selection(strategy);
+                    }
+                    Ok((priority, builder.build_unsigned_preview()?))
+                })
+                .collect::<Result<Vec<_>, BlockchainError>>()?;
+            Box::new(PaymentFeeEstimateResponse::new(
+                from,
+                fee_priority,
+                &previews,
+            )?)
         }
         // When I want to check the current fee system configuration and statistics
         Command::FeeStatus => {
             // I get a summary of the current fee configuration
-            let config_summary = FeeCalculator::get_config_summary();
-            println!("Fee System Status:");
-            println!("  {config_summary}");
+            let summary = FeeCalculator::get_config_summary();
 
             // I also show fee statistics if available
-            if let Some(stats) = FeeCalculator::get_fee_statistics() {
-                println!();
-                print!("{stats}");
-            }
+            let context = fee_context();
+            let statistics = context.get_fee_statistics();
+            // Fixed fees don't depend on them, but what blocks confirmed is still worth seeing
+            let recent_rates = statistics
+                .is_none()
+                .then(|| context.fee_history().recent_rates());
+            Box::new(FeeStatusResponse {
+                summary,
+                statistics,
+                recent_rates,
+            })
         }
         // When I want to change how fees are calculated (fixed vs dynamic)
         Command::SetFeeMode { mode } => {
@@ -286,9 +1991,249 @@ fn run_command(command: Command) -> Result<(), Box<dyn std::error::Error>> {
 
             // I switch the fee calculator to the new mode
             FeeCalculator::switch_fee_mode(new_mode)?;
-            println!("Fee mode updated successfully");
-            println!("New configuration: {}", FeeCalculator::get_config_summary());
+            Box::new(FeeModeResponse {
+                configuration: FeeCalculator::get_config_summary(),
+            })
+        }
+    };
+    Ok(response)
+}
+
+// Estimates blend in what recent blocks confirmed, so I read those from the local chain if
+// there is one; without it (or while a node holds the database) I estimate from fees alone
+fn fee_context() -> NodeContext {
+    let context = NodeContext::from_globals();
+    let Ok(db_path) = Blockchain::default_db_path() else {
+        return context;
+    };
+    if !Path::new(&db_path).exists() {
+        return context;
+    }
+    match Blockchain::new_blockchain() {
+        Ok(blockchain) => {
+            if let Err(e) = context.follow_fee_history(&blockchain) {
+                eprintln!("warning: couldn't read the fee history: {e}");
+            }
+        }
+        Err(e) => eprintln!("warning: couldn't open the blockchain for the fee history: {e}"),
+    }
+    context
+}
+
+// The chain in the data directory, if there is one; a missing or locked one is left alone
+fn existing_blockchain() -> Option<Blockchain> {
+    let path = Blockchain::default_db_path().ok()?;
+    if !Path::new(&path).exists() {
+        return None;
+    }
+    match Blockchain::new_blockchain() {
+        Ok(blockchain) => Some(blockchain),
+        Err(e) => {
+            warn!("The new key won't record which chain it is for: {e}");
+            None
+        }
+    }
+}
+
+// The wallet file, read once however many times the command consults it
+fn cli_wallets() -> &'static SharedWallets {
+    static WALLETS: OnceLock<SharedWallets> = OnceLock::new();
+    WALLETS.get_or_init(SharedWallets::new)
+}
+
+// Keys created for another chain are refused, unless --allow-network-mismatch says the
+// pairing is deliberate
+fn check_wallet_network(
+    wallets: &Wallets,
+    addresses: &[String],
+    blockchain: &Blockchain,
+) -> architect_chain::Result<()> {
+    match wallets.check_network(addresses, blockchain) {
+        Err(BlockchainError::WalletNetworkMismatch {
+            address,
+            created_on,
+            ..
+        }) if GLOBAL_CONFIG.allow_network_mismatch() => {
+            warn!("Using {address}, created for the chain with genesis {created_on}, anyway");
+            Ok(())
         }
+        result => result,
+    }
+}
+
+// The database `startnode` would open for the node listening on NODE_ADDRESS
+fn node_blockchain() -> Result<Blockchain, Box<dyn std::error::Error>> {
+    Ok(Blockchain::new_blockchain_with_node_id(
+        &GLOBAL_CONFIG.extract_node_id_from_addr(),
+    )?)
+}
+
+// The ban list lives in the node's database, which a running node keeps locked, so this
+// only works while the node is stopped; it picks the changes up when it next starts
+fn with_local_bans(
+    change: impl FnOnce(&SimplePeerManager) -> architect_chain::Result<()>,
+) -> Result<Vec<(IpAddr, Ban)>, Box<dyn std::error::Error>> {
+    let blockchain = node_blockchain()?;
+    let peer_manager = SimplePeerManager::new(DEFAULT_MAX_PEERS, 2001);
+    peer_manager.load_bans_from(blockchain.get_db())?;
+    change(&peer_manager)?;
+    peer_manager.save_bans_to(blockchain.get_db())?;
+    Ok(peer_manager.list_bans()?)
+}
+
+// The memory pool lives in the node's database too, so like the ban list it can only be
+// changed here while the node is stopped; the node leaves the transaction out when it
+// next loads its pool
+fn abandon_local_transaction(txid: &str) -> Result<Transaction, Box<dyn std::error::Error>> {
+    // The database startnode loads the pool from
+    let blockchain = match GLOBAL_CONFIG.get_node_id() {
+        Some(node_id) => Blockchain::new_blockchain_with_node_id(&node_id)?,
+        None => Blockchain::new_blockchain()?,
+    };
+    // Edited in place, so the other saved transactions stay for the node to judge
+    let pending = MemoryPool::saved_transaction(blockchain.get_db(), txid)?
+        .ok_or_else(|| format!("Transaction {txid} is not pending in the saved memory pool"))?;
+    cli_wallets().read().check_spends_own_coins(&pending)?;
+    MemoryPool::abandon_saved(blockchain.get_db(), txid)?;
+    Ok(pending)
+}
+
+// I make the user type a large payment's amount back before it's sent; without a terminal to
+// ask on I refuse instead of waiting for an answer that never comes
+fn confirm_send(amount: u64, to: &str, fee: u64) -> Result<(), Box<dyn std::error::Error>> {
+    if !std::io::stdin().is_terminal() {
+        return Err(format!(
+            "Sending {amount} satoshis needs confirmation under the wallet policy; pass --yes to send without a terminal"
+        )
+        .into());
+    }
+    eprintln!(
+        "About to send {} to {to}, paying a fee of {}",
+        format_amount(amount),
+        format_amount(fee)
+    );
+    eprint!("Type the amount in satoshis to confirm: ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if answer.trim() != amount.to_string() {
+        return Err("The amount typed didn't match, nothing was sent".into());
     }
     Ok(())
 }
+
+// I tell whether one of the transaction's inputs carries the key of `address`
+fn spends_from(transaction: &Transaction, address: &str) -> bool {
+    let Ok(pub_key_hash) = address_pub_key_hash(address) else {
+        return false;
+    };
+    transaction
+        .get_vin()
+        .iter()
+        .any(|input| hash_pub_key(input.get_pub_key()) == pub_key_hash)
+}
+
+// I read a partially signed transaction passed between multisig signers
+fn decode_partial_transaction(hex: &str) -> Result<Transaction, Box<dyn std::error::Error>> {
+    Ok(Transaction::from_hex(hex)?)
+}
+
+// I keep a transaction as a wallet draft, returning how many bytes it took
+fn save_cli_draft(
+    name: &str,
+    transaction: &Transaction,
+    state: DraftState,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let bytes = transaction.serialize()?;
+    cli_wallets().write().save_draft(name, &bytes, state)?;
+    eprintln!("Saved draft '{name}' ({state})");
+    Ok(bytes.len())
+}
+
+fn draft_entry(
+    name: &str,
+    draft: &Draft,
+    blockchain: &Blockchain,
+    with_transaction: bool,
+) -> architect_chain::Result<DraftEntry> {
+    Ok(DraftEntry {
+        name: name.to_string(),
+        state: draft.state(),
+        saved_at: draft.saved_at(),
+        size: draft.size(),
+        spent_inputs: draft.spent_inputs(blockchain)?,
+        transaction: with_transaction.then(|| draft.to_hex()),
+    })
+}
+
+// A draft only goes out once it has every signature it needs and the chain hasn't spent
+// its inputs. It stays in the wallet file, since the node may still turn it away.
+fn send_draft(
+    name: &str,
+    node_addr: &str,
+) -> Result<Box<dyn CommandResponse>, Box<dyn std::error::Error>> {
+    let draft = cli_wallets().read().load_draft(name)?.clone();
+    let blockchain = Blockchain::new_blockchain()?;
+    let spent = draft.spent_inputs(&blockchain)?;
+    if !spent.is_empty() {
+        return Err(format!(
+            "Draft '{name}' is stale, the chain already spent {}",
+            spent.join(", ")
+        )
+        .into());
+    }
+    let transaction = match draft.state() {
+        DraftState::SignedUnbroadcast => draft.transaction()?,
+        DraftState::PartiallySigned { have, need } if have >= need => {
+            draft.transaction()?.finalize(&blockchain)?
+        }
+        state => return Err(format!("Draft '{name}' is {state}, sign it first").into()),
+    };
+    transaction.check_structure()?;
+    send_tx(node_addr, &transaction);
+    Ok(Box::new(SentResponse {
+        txid: HEXLOWER.encode(transaction.get_id()),
+        broadcast: true,
+        mined_block: None,
+    }))
+}
+
+// I read a createrawtransaction input, txid:vout
+fn parse_outpoint(input: &str) -> Result<(Vec<u8>, usize), Box<dyn std::error::Error>> {
+    let (txid, vout) = input
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Input {input} is not txid:vout"))?;
+    let txid = HEXLOWER
+        .decode(txid.to_lowercase().as_bytes())
+        .map_err(|e| format!("Invalid txid in input {input}: {e}"))?;
+    let vout = vout
+        .parse()
+        .map_err(|e| format!("Invalid vout in input {input}: {e}"))?;
+    Ok((txid, vout))
+}
+
+// I read a createrawtransaction output, address:amount
+fn parse_payment(output: &str) -> Result<TXOutput, Box<dyn std::error::Error>> {
+    let (address, amount) = output
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Output {output} is not address:amount"))?;
+    validate_address(address).map_err(|e| format!("{e} in output {output}"))?;
+    let amount: u64 = amount
+        .parse()
+        .map_err(|e| format!("Invalid amount in output {output}: {e}"))?;
+    Ok(TXOutput::new(amount, address)?)
+}
+
+// Without --mining-threads I search for proof-of-work on every core the machine offers
+fn default_mining_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |cores| cores.get())
+}
+
+// The node a `--mine` flag mines on, searching on the configured number of threads, or on
+// every core if none is configured
+fn mining_context() -> NodeContext {
+    let threads = GLOBAL_CONFIG
+        .get_mining_threads()
+        .unwrap_or_else(default_mining_threads);
+    NodeContext::from_globals().with_miner(Miner::new(threads))
+}
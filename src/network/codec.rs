@@ -0,0 +1,700 @@
+//! How packages are written on a connection
+//!
+//! Every node reads and writes packages as JSON values, one after another. Nodes that also
+//! list `WIRE_FORMAT_BINCODE` in their version message take frames instead: a 4-byte
+//! big-endian length followed by the bincode encoding of the package, which carries byte
+//! vectors as they are instead of as arrays of numbers. A node only sends frames to a peer
+//! whose version said it reads them, so older nodes keep getting JSON.
+//!
+//! `PackageReader` tells the two apart by the first byte of each package: frames are kept
+//! under 16 MB, so their length starts with a zero byte, which JSON never does. A frame
+//! whose length is over the limit is refused before anything is allocated for it, and one
+//! that doesn't decode is skipped whole, so the package after it can still be read.
+//!
+//! What a connection's packages take on the wire is counted here too, see `WireBytes`, so
+//! frames and JSON are counted alike.
+
+use crate::error::{BlockchainError, Result};
+use crate::network::framing::{LimitedReader, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::network::server::Package;
+use crate::utils::{deserialize_limited, serialize};
+use serde::Deserialize;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The format id a version message lists for length-prefixed bincode frames
+pub const WIRE_FORMAT_BINCODE: u8 = 1;
+/// The formats this node reads besides JSON, as its version message lists them
+pub const SUPPORTED_WIRE_FORMATS: [u8; 1] = [WIRE_FORMAT_BINCODE];
+/// Most format ids a version message may list
+pub const MAX_WIRE_FORMATS: usize = 16;
+const LENGTH_PREFIX_SIZE: usize = 4;
+/// Longest frame payload, short enough for the length's first byte to be zero
+pub const MAX_FRAME_LENGTH: usize = (1 << 24) - 1;
+// Bincode counts containers at their size in memory, a few times their encoded size
+const DECODE_LIMIT: usize = 4 * DEFAULT_MAX_MESSAGE_SIZE;
+
+/// The encoding of packages on one connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Bincode,
+}
+
+impl WireFormat {
+    /// The format to write to a peer whose version listed `peer_formats`
+    pub fn negotiate(peer_formats: &[u8]) -> WireFormat {
+        if peer_formats.contains(&WIRE_FORMAT_BINCODE) {
+            WireFormat::Bincode
+        } else {
+            WireFormat::Json
+        }
+    }
+
+    /// The id a signed package names its payload's format by, 0 for JSON
+    pub fn id(self) -> u8 {
+        match self {
+            WireFormat::Json => 0,
+            WireFormat::Bincode => WIRE_FORMAT_BINCODE,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<WireFormat> {
+        match id {
+            0 => Ok(WireFormat::Json),
+            WIRE_FORMAT_BINCODE => Ok(WireFormat::Bincode),
+            other => Err(BlockchainError::Network(format!(
+                "Unknown wire format {other}"
+            ))),
+        }
+    }
+
+    /// `pkg` by itself, without the length a frame puts in front of it
+    pub fn encode_payload(self, pkg: &Package) -> Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(pkg)
+                .map_err(|e| BlockchainError::Network(format!("Failed to encode package: {e}"))),
+            WireFormat::Bincode => serialize(pkg),
+        }
+    }
+
+    /// Read a package `encode_payload` wrote
+    pub fn decode_payload(self, payload: &[u8]) -> Result<Package> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(payload)
+                .map_err(|e| BlockchainError::Network(format!("Failed to decode package: {e}"))),
+            WireFormat::Bincode => deserialize_limited::<Package, DECODE_LIMIT>(payload),
+        }
+    }
+
+    pub fn encode(self, pkg: &Package) -> Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => self.encode_payload(pkg),
+            WireFormat::Bincode => {
+                let payload = self.encode_payload(pkg)?;
+                if payload.len() > MAX_FRAME_LENGTH {
+                    return Err(BlockchainError::Network(format!(
+                        "Package of {} bytes is too long for a frame",
+                        payload.len()
+                    )));
+                }
+                let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + payload.len());
+                frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                frame.extend(payload);
+                Ok(frame)
+            }
+        }
+    }
+
+    /// Like `encode`, adding the encoded size to what `bytes` counts as sent
+    pub fn encode_counted(self, pkg: &Package, bytes: &WireBytes) -> Result<Vec<u8>> {
+        let data = self.encode(pkg)?;
+        bytes.sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(data)
+    }
+
+    /// Encode `pkg` and write it to `writer`
+    pub fn write(self, mut writer: impl Write, pkg: &Package) -> Result<()> {
+        let data = self.encode(pkg)?;
+        writer
+            .write_all(&data)
+            .map_err(|e| BlockchainError::Network(format!("Failed to send data: {e}")))
+    }
+}
+
+/// Bytes one connection's packages took on the wire, in either format
+///
+/// Only the codec adds to them: `WireFormat::encode_counted` every package encoded for the
+/// connection, and a `PackageReader::with_byte_counter` every byte read off it, whether it
+/// decoded or not.
+#[derive(Debug, Default)]
+pub struct WireBytes {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl WireBytes {
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+// Adds every byte read through it to `bytes`, if there are any to add to
+struct Counted<R> {
+    inner: R,
+    bytes: Option<Arc<WireBytes>>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(bytes) = &self.bytes {
+            bytes.received.fetch_add(read as u64, Ordering::Relaxed);
+        }
+        Ok(read)
+    }
+}
+
+/// Why the next package couldn't be read
+#[derive(Debug)]
+pub enum ReadError {
+    /// The package is longer than the limit; nothing after it can be read
+    Oversized { limit: usize },
+    /// The package doesn't decode. After a frame the next package can still be read;
+    /// after JSON there is no telling where it ends.
+    Malformed { message: String, resync: bool },
+    /// The connection failed, timed out or ended halfway through a package
+    Io(io::Error),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Oversized { limit } => {
+                write!(f, "message exceeds maximum size of {limit} bytes")
+            }
+            ReadError::Malformed { message, .. } => write!(f, "malformed package: {message}"),
+            ReadError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// A package as read, with its encoded size and the format it came in
+#[derive(Debug)]
+pub struct ReceivedPackage {
+    pub package: Package,
+    pub size: usize,
+    pub format: WireFormat,
+}
+
+/// Reads packages in either format from one connection
+pub struct PackageReader<R> {
+    inner: BufReader<Counted<R>>,
+    max_message_size: usize,
+}
+
+impl<R: Read> PackageReader<R> {
+    /// Read from `inner`, allowing `max_message_size` bytes per package
+    pub fn new(inner: R, max_message_size: usize) -> Self {
+        PackageReader {
+            inner: BufReader::new(Counted { inner, bytes: None }),
+            max_message_size,
+        }
+    }
+
+    /// Count what is read from here on in `bytes`
+    pub fn with_byte_counter(mut self, bytes: Arc<WireBytes>) -> Self {
+        self.inner.get_mut().bytes = Some(bytes);
+        self
+    }
+
+    /// The next package, or None once the peer hung up between packages
+    pub fn read_package(&mut self) -> Option<std::result::Result<ReceivedPackage, ReadError>> {
+        match self.first_byte() {
+            Ok(None) => None,
+            Ok(Some(0)) => Some(self.read_frame()),
+            Ok(Some(_)) => Some(self.read_json()),
+            Err(e) => Some(Err(ReadError::Io(e))),
+        }
+    }
+
+    // Skips whitespace between JSON packages and looks at what follows without consuming it
+    fn first_byte(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            let buffered = self.inner.fill_buf()?;
+            match buffered.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(start) => {
+                    let first = buffered[start];
+                    self.inner.consume(start);
+                    return Ok(Some(first));
+                }
+                None if buffered.is_empty() => return Ok(None),
+                None => {
+                    let skipped = buffered.len();
+                    self.inner.consume(skipped);
+                }
+            }
+        }
+    }
+
+    fn read_json(&mut self) -> std::result::Result<ReceivedPackage, ReadError> {
+        // Counts what the deserializer consumed, which reads exactly up to the closing brace
+        let limited = LimitedReader::new(&mut self.inner, self.max_message_size);
+        let budget = limited.budget();
+        let mut deserializer = serde_json::Deserializer::from_reader(limited);
+        match Package::deserialize(&mut deserializer) {
+            Ok(package) => Ok(ReceivedPackage {
+                package,
+                size: budget.used(),
+                format: WireFormat::Json,
+            }),
+            Err(_) if budget.exceeded() => Err(ReadError::Oversized {
+                limit: self.max_message_size,
+            }),
+            Err(e) if e.is_syntax() || e.is_data() => Err(ReadError::Malformed {
+                message: e.to_string(),
+                resync: false,
+            }),
+            Err(e) => Err(ReadError::Io(e.into())),
+        }
+    }
+
+    fn read_frame(&mut self) -> std::result::Result<ReceivedPackage, ReadError> {
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+        self.inner.read_exact(&mut prefix).map_err(ReadError::Io)?;
+        let length = u32::from_be_bytes(prefix) as usize;
+        if length > self.max_message_size.min(MAX_FRAME_LENGTH) {
+            return Err(ReadError::Oversized {
+                limit: self.max_message_size,
+            });
+        }
+        let mut payload = vec![0u8; length];
+        self.inner.read_exact(&mut payload).map_err(ReadError::Io)?;
+        match deserialize_limited::<Package, DECODE_LIMIT>(&payload) {
+            Ok(package) => Ok(ReceivedPackage {
+                package,
+                size: LENGTH_PREFIX_SIZE + length,
+                format: WireFormat::Bincode,
+            }),
+            Err(e) => Err(ReadError::Malformed {
+                message: e.to_string(),
+                resync: true,
+            }),
+        }
+    }
+}
+
+impl<R: Read> Iterator for PackageReader<R> {
+    type Item = std::result::Result<ReceivedPackage, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_package()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::summary::OutputSummary;
+    use crate::core::{
+        Amount, BlockSummary, BlockTemplate, NetworkMagic, Transaction, TransactionStatus,
+        TransactionSummary,
+    };
+    use crate::network::server::OpType;
+    use crate::network::trace::{Direction, TraceEntry};
+    use crate::network::{Ban, NodeIdentity, NodeStatus, PeerInfo};
+    use crate::storage::AddressBalance;
+    use std::io::Cursor;
+
+    const ADDR: &str = "127.0.0.1:2001";
+    const MINER: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn addr() -> String {
+        ADDR.to_string()
+    }
+
+    fn every_package() -> Vec<Package> {
+        let status = NodeStatus {
+            best_height: 7,
+            tip_hash: "00ab".to_string(),
+            difficulty: 4,
+            utxo_count: 3,
+            mempool_size: Some(1),
+            fee_mode: "fixed".to_string(),
+            miner_address: None,
+            node_address: addr(),
+            connected_peers: Some(2),
+            sync: None,
+        };
+        let template = BlockTemplate {
+            pre_block_hash: "00ab".to_string(),
+            height: 8,
+            difficulty: 4,
+            timestamp: 1_760_000_000_000,
+            coinbase: Transaction::new_coinbase_tx(MINER).unwrap(),
+            transactions: Vec::new(),
+            total_fees: Amount::ZERO,
+            merkle_root: vec![9; 32],
+        };
+        let balance = AddressBalance {
+            confirmed: Amount::from_satoshis(5_000),
+            pending_incoming: Amount::ZERO,
+            pending_outgoing: Amount::from_satoshis(10),
+        };
+        let ban = Ban {
+            banned_until: 1_760_000_000_000,
+            reason: "spam".to_string(),
+            offenses: 1,
+        };
+        let summary = BlockSummary {
+            height: 7,
+            hash: "00ab".to_string(),
+            pre_block_hash: "00aa".to_string(),
+            timestamp: 1_760_000_000_000,
+            difficulty: 4,
+            nonce: 12,
+            total_fees: 3,
+            transactions: vec![TransactionSummary {
+                txid: "cd".to_string(),
+                coinbase: true,
+                fee: 0,
+                inputs: Vec::new(),
+                outputs: vec![OutputSummary {
+                    to: MINER.to_string(),
+                    value: 5_000,
+                    memo: None,
+                }],
+            }],
+        };
+        let signed = NodeIdentity::generate()
+            .unwrap()
+            .sign(
+                &Package::GetBlocks { addr_from: addr() },
+                WireFormat::Bincode,
+            )
+            .unwrap();
+
+        vec![
+            Package::Block {
+                addr_from: addr(),
+                block: vec![1, 2, 3],
+            },
+            Package::GetBlocks { addr_from: addr() },
+            Package::GetData {
+                addr_from: addr(),
+                op_type: OpType::Block,
+                id: vec![7; 32],
+            },
+            Package::Inv {
+                addr_from: addr(),
+                op_type: OpType::Tx,
+                items: vec![vec![1; 32], vec![2; 32]],
+            },
+            Package::Tx {
+                addr_from: addr(),
+                transaction: vec![4; 100],
+                allow_high_fee: true,
+                confirmed: false,
+            },
+            Package::Version {
+                addr_from: addr(),
+                version: 1,
+                best_height: 7,
+                chain_work: 1 << 100,
+                format_version: 1,
+                genesis_hash: "00ab".to_string(),
+                timestamp: -5,
+                wire_formats: SUPPORTED_WIRE_FORMATS.to_vec(),
+                network_magic: Some(NetworkMagic([0xf9, 0xbe, 0xb4, 0xd9])),
+            },
+            Package::GetMempoolTx {
+                addr_from: addr(),
+                txid: vec![3; 32],
+            },
+            Package::MempoolTx {
+                addr_from: addr(),
+                transaction: None,
+            },
+            Package::GetStatus { addr_from: addr() },
+            Package::Status {
+                addr_from: addr(),
+                status,
+            },
+            Package::FilterLoad {
+                addr_from: addr(),
+                filter: vec![0xff; 16],
+                hash_funcs: 5,
+                tweak: 42,
+            },
+            Package::MerkleBlock {
+                addr_from: addr(),
+                header: vec![1; 80],
+                proofs: vec![2; 64],
+            },
+            Package::GetBlockTemplate {
+                addr_from: addr(),
+                miner_address: MINER.to_string(),
+            },
+            Package::BlockTemplate {
+                addr_from: addr(),
+                template: Ok(template),
+            },
+            Package::SubmitBlock {
+                addr_from: addr(),
+                block: vec![5; 10],
+            },
+            Package::SubmitBlockResult {
+                addr_from: addr(),
+                result: Err("stale".to_string()),
+            },
+            Package::GetMessageTrace { addr_from: addr() },
+            Package::MessageTrace {
+                addr_from: addr(),
+                entries: vec![TraceEntry {
+                    timestamp: 1,
+                    direction: Direction::Inbound,
+                    peer: addr(),
+                    kind: "Inv".to_string(),
+                    size: 120,
+                }],
+            },
+            Package::GetBalance {
+                addr_from: addr(),
+                address: MINER.to_string(),
+            },
+            Package::Balance {
+                addr_from: addr(),
+                balance: Ok(balance),
+            },
+            Package::BanPeer {
+                addr_from: addr(),
+                address: "10.0.0.1".to_string(),
+                duration_secs: 60,
+            },
+            Package::UnbanPeer {
+                addr_from: addr(),
+                address: "10.0.0.1".to_string(),
+            },
+            Package::ListBans { addr_from: addr() },
+            Package::Bans {
+                addr_from: addr(),
+                bans: Ok(vec![("10.0.0.1".parse().unwrap(), ban)]),
+            },
+            Package::AbandonTx {
+                addr_from: addr(),
+                txid: vec![3; 32],
+            },
+            Package::Abandoned {
+                addr_from: addr(),
+                result: Err("not pending".to_string()),
+            },
+            Package::Rejected {
+                addr_from: addr(),
+                reason: "banned".to_string(),
+            },
+            Package::NotFound {
+                addr_from: addr(),
+                op_type: OpType::Tx,
+                id: vec![6; 32],
+            },
+            signed,
+            Package::GetChain {
+                addr_from: addr(),
+                from_height: Some(2),
+                to_height: None,
+                limit: Some(10),
+            },
+            Package::Chain {
+                addr_from: addr(),
+                blocks: Ok(vec![summary]),
+            },
+            Package::GetTxStatus {
+                addr_from: addr(),
+                txid: vec![8; 32],
+            },
+            Package::TxStatus {
+                addr_from: addr(),
+                status: Ok(TransactionStatus {
+                    block: Some("00ab".to_string()),
+                    confirmations: Some(2),
+                    blocks_to_final: Some(4),
+                }),
+            },
+            Package::GetPeerInfo { addr_from: addr() },
+            Package::Peers {
+                addr_from: addr(),
+                peers: Ok(vec![PeerInfo {
+                    address: "10.0.0.1:51000".to_string(),
+                    direction: Direction::Inbound,
+                    pooled: false,
+                    listen_address: Some("10.0.0.1:2001".to_string()),
+                    version: Some(1),
+                    best_height: Some(7),
+                    connected_since: 1_760_000_000_000,
+                    last_message: Some(1_760_000_000_500),
+                    bytes_sent: 300,
+                    bytes_received: 1_200,
+                    messages_sent: [("Inv".to_string(), 2)].into(),
+                    messages_received: [("Version".to_string(), 1)].into(),
+                    misbehavior_score: 5,
+                }]),
+            },
+            Package::DisconnectPeer {
+                addr_from: addr(),
+                address: "10.0.0.1".to_string(),
+                ban_secs: Some(60),
+            },
+            Package::Disconnected {
+                addr_from: addr(),
+                result: Ok(vec!["10.0.0.1:51000".to_string()]),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_every_package_round_trips_through_both_formats() {
+        let packages = every_package();
+        for format in [WireFormat::Json, WireFormat::Bincode] {
+            let mut stream = Vec::new();
+            for pkg in &packages {
+                stream.extend(format.encode(pkg).unwrap());
+            }
+            let decoded: Vec<ReceivedPackage> = PackageReader::new(Cursor::new(stream), 1 << 20)
+                .map(|read| read.unwrap())
+                .collect();
+
+            assert_eq!(decoded.len(), packages.len());
+            for (pkg, received) in packages.iter().zip(&decoded) {
+                assert_eq!(received.format, format);
+                assert_eq!(received.package.kind(), pkg.kind());
+                let encoded = format.encode(pkg).unwrap();
+                assert_eq!(received.size, encoded.len());
+                assert_eq!(format.encode(&received.package).unwrap(), encoded);
+            }
+        }
+
+        // Frames are the smaller encoding of a block
+        let block = &packages[0];
+        let json = WireFormat::Json.encode(block).unwrap();
+        assert!(WireFormat::Bincode.encode(block).unwrap().len() < json.len());
+    }
+
+    #[test]
+    fn test_formats_can_alternate_on_one_connection() {
+        let packages = every_package();
+        let mut stream = Vec::new();
+        for (i, pkg) in packages.iter().enumerate() {
+            let format = if i % 2 == 0 {
+                WireFormat::Json
+            } else {
+                WireFormat::Bincode
+            };
+            stream.extend(format.encode(pkg).unwrap());
+            stream.extend(b"\n");
+        }
+        let kinds: Vec<&str> = PackageReader::new(Cursor::new(stream), 1 << 20)
+            .map(|read| read.unwrap().package.kind())
+            .collect();
+        let expected: Vec<&str> = packages.iter().map(|pkg| pkg.kind()).collect();
+        assert_eq!(kinds, expected);
+    }
+
+    #[test]
+    fn test_oversized_frame_is_refused_from_its_length() {
+        // Claims 16 MB but sends none of it; reading the payload would block or fail
+        let mut stream = vec![0x00, 0xff, 0xff, 0xff];
+        stream.extend(WireFormat::Bincode.encode(&every_package()[1]).unwrap());
+        let mut reader = PackageReader::new(Cursor::new(stream), 1024);
+        assert!(matches!(
+            reader.read_package(),
+            Some(Err(ReadError::Oversized { limit: 1024 }))
+        ));
+
+        let json = WireFormat::Json.encode(&every_package()[0]).unwrap();
+        let mut reader = PackageReader::new(Cursor::new(json), 8);
+        assert!(matches!(
+            reader.read_package(),
+            Some(Err(ReadError::Oversized { limit: 8 }))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_frame_is_skipped_and_the_next_one_read() {
+        let packages = every_package();
+        let whole = WireFormat::Bincode.encode(&packages[4]).unwrap();
+        // The length matches what was sent, but the package inside is cut short
+        let payload = &whole[LENGTH_PREFIX_SIZE..whole.len() - 20];
+        let mut stream = (payload.len() as u32).to_be_bytes().to_vec();
+        stream.extend(payload);
+        stream.extend(WireFormat::Bincode.encode(&packages[1]).unwrap());
+        // And a last frame whose length runs past the end of the stream
+        stream.extend(&whole[..whole.len() - 1]);
+
+        let mut reader = PackageReader::new(Cursor::new(stream), 1 << 20);
+        assert!(matches!(
+            reader.read_package(),
+            Some(Err(ReadError::Malformed { resync: true, .. }))
+        ));
+        assert_eq!(
+            reader.read_package().unwrap().unwrap().package.kind(),
+            "GetBlocks"
+        );
+        assert!(matches!(reader.read_package(), Some(Err(ReadError::Io(_)))));
+
+        // Broken JSON can't be resynced
+        let mut reader = PackageReader::new(Cursor::new(b"{\"GetBlocks\":[}".to_vec()), 1024);
+        assert!(matches!(
+            reader.read_package(),
+            Some(Err(ReadError::Malformed { resync: false, .. }))
+        ));
+        assert!(PackageReader::new(Cursor::new(b" \n".to_vec()), 1024)
+            .read_package()
+            .is_none());
+    }
+
+    #[test]
+    fn test_peers_without_frames_get_json() {
+        assert_eq!(WireFormat::negotiate(&[]), WireFormat::Json);
+        assert_eq!(WireFormat::negotiate(&[7, 200]), WireFormat::Json);
+        assert_eq!(
+            WireFormat::negotiate(&[7, WIRE_FORMAT_BINCODE]),
+            WireFormat::Bincode
+        );
+        assert_eq!(WireFormat::default(), WireFormat::Json);
+    }
+
+    #[test]
+    fn test_bytes_are_counted_in_both_formats() {
+        let packages = every_package();
+        let bytes = Arc::new(WireBytes::default());
+        let mut stream = Vec::new();
+        for (i, pkg) in packages.iter().enumerate() {
+            let format = if i % 2 == 0 {
+                WireFormat::Json
+            } else {
+                WireFormat::Bincode
+            };
+            stream.extend(format.encode_counted(pkg, &bytes).unwrap());
+        }
+        assert_eq!(bytes.sent(), stream.len() as u64);
+        assert_eq!(bytes.received(), 0);
+
+        // Reading counts what came off the connection, a frame that didn't decode included
+        let mut garbled = 3u32.to_be_bytes().to_vec();
+        garbled.extend([0xff; 3]);
+        stream.extend(&garbled);
+        let read = PackageReader::new(Cursor::new(stream.clone()), 1 << 20)
+            .with_byte_counter(Arc::clone(&bytes))
+            .count();
+        assert_eq!(read, packages.len() + 1);
+        assert_eq!(bytes.received(), stream.len() as u64);
+        assert_eq!(bytes.sent(), (stream.len() - garbled.len()) as u64);
+    }
+}
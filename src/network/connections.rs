@@ -0,0 +1,441 @@
+//! Connections this node opened to its peers, kept for the packages that follow
+//!
+//! The first package for a peer opens a connection, preceded by the handshake the
+//! `ConnectionHandler` gives, and later packages reuse it. A connection idle for
+//! `IDLE_TIMEOUT`, shorter than the read timeout peers hang up after, or one the peer already
+//! closed, is replaced rather than written to. A write that fails drops the connection and
+//! the package goes out on a new one, up to `MAX_SEND_ATTEMPTS` tries with doubling pauses.
+//! At most `max_connections` stay open; opening one more closes the one used longest ago.
+//!
+//! Peers may write back on a connection this node opened, so every new one is also handed
+//! to the handler's `serve` on a thread of its own, which reads it like an inbound one.
+
+use crate::error::{BlockchainError, Result};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Most connections kept open to peers unless the config says otherwise
+pub const DEFAULT_MAX_OUTBOUND_CONNECTIONS: usize = 16;
+/// Tries a package gets before sending it fails, each on a new connection
+pub const MAX_SEND_ATTEMPTS: u32 = 3;
+/// How long a connection may sit unused before it is replaced; peers stop reading after 60s
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Pause after the first failed write, doubling after each one
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(5000);
+const WRITE_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// What a node does with the connections it opens
+pub trait ConnectionHandler: Send + Sync {
+    /// Bytes to write on a new connection to `addr` before anything else, if any
+    fn handshake(&self, addr: SocketAddr) -> Result<Option<Vec<u8>>>;
+
+    /// Read what `addr` writes on a connection this node opened, until either side hangs up
+    fn serve(&self, stream: TcpStream, addr: SocketAddr);
+}
+
+// Writers take `writing` so two packages never interleave on the wire
+struct Link {
+    stream: TcpStream,
+    writing: Mutex<()>,
+}
+
+impl Link {
+    fn close(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    // Whether the peer closed its end, without blocking. A link busy writing counts as
+    // open, the write will tell.
+    fn is_closed_by_peer(&self) -> bool {
+        let Ok(_writing) = self.writing.try_lock() else {
+            return false;
+        };
+        if self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed = match self.stream.peek(&mut [0u8; 1]) {
+            Ok(read) => read == 0,
+            Err(e) => e.kind() != ErrorKind::WouldBlock,
+        };
+        self.stream.set_nonblocking(false).is_err() || closed
+    }
+}
+
+struct Connection {
+    id: u64,
+    link: Arc<Link>,
+    last_used: Instant,
+    /// A `serve` thread reads the stream, and drops the connection once it ends
+    served: bool,
+}
+
+impl Connection {
+    // A served stream is never peeked: it shares the blocking mode with the reader
+    fn is_usable(&self, now: Instant) -> bool {
+        now.duration_since(self.last_used) < IDLE_TIMEOUT
+            && (self.served || !self.link.is_closed_by_peer())
+    }
+}
+
+type Pool = Arc<Mutex<HashMap<SocketAddr, Connection>>>;
+
+/// Open connections to peers, one per address
+pub struct OutboundConnections {
+    max_connections: usize,
+    connections: Pool,
+    handler: RwLock<Option<Arc<dyn ConnectionHandler>>>,
+    opened: AtomicU64,
+}
+
+impl OutboundConnections {
+    /// Keep at most `max_connections` connections open, at least one
+    pub fn new(max_connections: usize) -> OutboundConnections {
+        OutboundConnections {
+            max_connections: max_connections.max(1),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            handler: RwLock::new(None),
+            opened: AtomicU64::new(0),
+        }
+    }
+
+    /// Start new connections with `handler`'s handshake and have it read them
+    ///
+    /// Connections opened before keep going without a reader.
+    pub fn set_handler(&self, handler: Arc<dyn ConnectionHandler>) {
+        *self.handler.write().unwrap_or_else(PoisonError::into_inner) = Some(handler);
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// Write `data` to `addr`, on the open connection if there is one
+    pub fn send(&self, addr: SocketAddr, data: &[u8]) -> Result<()> {
+        self.send_with(addr, data, false)
+    }
+
+    /// Like `send`, for a package that is a handshake itself, so a new connection starts
+    /// with it instead of the handler's
+    pub fn send_handshake(&self, addr: SocketAddr, data: &[u8]) -> Result<()> {
+        self.send_with(addr, data, true)
+    }
+
+    /// Whether a connection to `addr` is open
+    pub fn is_connected(&self, addr: SocketAddr) -> bool {
+        self.lock().contains_key(&addr)
+    }
+
+    /// How many connections are open
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many connections were opened so far, replacements included
+    pub fn opened(&self) -> u64 {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    /// Close every connection
+    pub fn close_all(&self) {
+        for (_, connection) in self.lock().drain() {
+            connection.link.close();
+        }
+    }
+
+    fn send_with(&self, addr: SocketAddr, data: &[u8], is_handshake: bool) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            // A peer that can't be reached at all is left to the caller's own backoff
+            let (id, link, fresh) = self.connection(addr, is_handshake)?;
+            let written = {
+                let _writing = link.writing.lock().unwrap_or_else(PoisonError::into_inner);
+                (&link.stream).write_all(data)
+            };
+            match written {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    Self::discard(&self.connections, addr, id);
+                    attempt += 1;
+                    if attempt >= MAX_SEND_ATTEMPTS {
+                        return Err(BlockchainError::Network(format!(
+                            "Failed to send data to {addr}: {e}"
+                        )));
+                    }
+                    if !fresh {
+                        debug!("Connection to {addr} broke ({e}), reconnecting");
+                    }
+                    thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1));
+                }
+            }
+        }
+    }
+
+    // The open connection to `addr`, or a new one; also whether it is new
+    fn connection(&self, addr: SocketAddr, is_handshake: bool) -> Result<(u64, Arc<Link>, bool)> {
+        let now = Instant::now();
+        {
+            let mut connections = self.lock();
+            if let Some(connection) = connections.get_mut(&addr) {
+                if connection.is_usable(now) {
+                    connection.last_used = now;
+                    return Ok((connection.id, Arc::clone(&connection.link), false));
+                }
+                if let Some(stale) = connections.remove(&addr) {
+                    stale.link.close();
+                }
+            }
+        }
+
+        // Connecting can take a while, so the pool isn't locked meanwhile
+        let handler = self
+            .handler
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let link = Arc::new(Self::open(addr, handler.as_deref(), is_handshake)?);
+        let id = self.opened.fetch_add(1, Ordering::Relaxed);
+
+        let mut connections = self.lock();
+        if let Some(connection) = connections.get_mut(&addr) {
+            // Another sender connected first; theirs is kept
+            link.close();
+            connection.last_used = now;
+            return Ok((connection.id, Arc::clone(&connection.link), false));
+        }
+        while connections.len() >= self.max_connections {
+            let Some(oldest) = connections
+                .iter()
+                .min_by_key(|(_, connection)| connection.last_used)
+                .map(|(addr, _)| *addr)
+            else {
+                break;
+            };
+            if let Some(evicted) = connections.remove(&oldest) {
+                debug!("Closing the connection to {oldest} to make room for {addr}");
+                evicted.link.close();
+            }
+        }
+
+        let reader = match &handler {
+            Some(handler) => link
+                .stream
+                .try_clone()
+                .map(|stream| (Arc::clone(handler), stream))
+                .map_err(|e| warn!("Not reading the connection to {addr}: {e}"))
+                .ok(),
+            None => None,
+        };
+        connections.insert(
+            addr,
+            Connection {
+                id,
+                link: Arc::clone(&link),
+                last_used: now,
+                served: reader.is_some(),
+            },
+        );
+        drop(connections);
+
+        if let Some((handler, stream)) = reader {
+            let pool = Arc::clone(&self.connections);
+            thread::spawn(move || {
+                handler.serve(stream, addr);
+                Self::discard(&pool, addr, id);
+            });
+        }
+        Ok((id, link, true))
+    }
+
+    fn open(
+        addr: SocketAddr,
+        handler: Option<&dyn ConnectionHandler>,
+        is_handshake: bool,
+    ) -> Result<Link> {
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .map_err(|e| BlockchainError::Network(format!("Failed to connect to {addr}: {e}")))?;
+        stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .map_err(|e| BlockchainError::Network(format!("Failed to set write timeout: {e}")))?;
+        // Packages are written whole, there is nothing to gain from waiting for more
+        let _ = stream.set_nodelay(true);
+
+        if let (Some(handler), false) = (handler, is_handshake) {
+            if let Some(handshake) = handler.handshake(addr)? {
+                (&stream).write_all(&handshake).map_err(|e| {
+                    BlockchainError::Network(format!("Failed to send handshake to {addr}: {e}"))
+                })?;
+            }
+        }
+        Ok(Link {
+            stream,
+            writing: Mutex::new(()),
+        })
+    }
+
+    // Forget the connection `id` to `addr`, unless it was replaced already
+    fn discard(pool: &Pool, addr: SocketAddr, id: u64) {
+        let mut connections = pool.lock().unwrap_or_else(PoisonError::into_inner);
+        if connections.get(&addr).is_some_and(|c| c.id == id) {
+            if let Some(connection) = connections.remove(&addr) {
+                connection.link.close();
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, Connection>> {
+        // Entries are only inserted and removed whole, so a poisoned pool is still consistent
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for OutboundConnections {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_OUTBOUND_CONNECTIONS)
+    }
+}
+
+impl Drop for OutboundConnections {
+    fn drop(&mut self) {
+        self.close_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    // Writes a greeting on every new connection and passes on the lines peers send back
+    struct Greeter {
+        replies: Mutex<mpsc::Sender<String>>,
+    }
+
+    impl ConnectionHandler for Greeter {
+        fn handshake(&self, _addr: SocketAddr) -> Result<Option<Vec<u8>>> {
+            Ok(Some(b"hello\n".to_vec()))
+        }
+
+        fn serve(&self, stream: TcpStream, _addr: SocketAddr) {
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else { return };
+                let _ = self.replies.lock().unwrap().send(line);
+            }
+        }
+    }
+
+    fn listener() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    fn read_lines(stream: &TcpStream, count: usize) -> Vec<String> {
+        let mut reader = BufReader::new(stream);
+        (0..count)
+            .map(|_| {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_messages_to_one_peer_share_a_connection() {
+        let (peer, addr) = listener();
+        let (sender, replies) = mpsc::channel();
+        let pool = OutboundConnections::new(4);
+        pool.set_handler(Arc::new(Greeter {
+            replies: Mutex::new(sender),
+        }));
+
+        for i in 0..5 {
+            pool.send(addr, format!("message {i}\n").as_bytes())
+                .unwrap();
+        }
+        let (stream, _) = peer.accept().unwrap();
+        let expected: Vec<String> = std::iter::once("hello".to_string())
+            .chain((0..5).map(|i| format!("message {i}")))
+            .collect();
+        assert_eq!(read_lines(&stream, 6), expected);
+        assert_eq!(pool.opened(), 1);
+        peer.set_nonblocking(true).unwrap();
+        assert!(peer.accept().is_err());
+
+        // What the peer writes back reaches the handler
+        (&stream).write_all(b"pong\n").unwrap();
+        assert_eq!(
+            replies.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "pong"
+        );
+        assert!(pool.is_connected(addr));
+    }
+
+    #[test]
+    fn test_reconnects_once_the_peer_hung_up() {
+        let (peer, addr) = listener();
+        let pool = OutboundConnections::new(4);
+
+        pool.send(addr, b"first\n").unwrap();
+        let (stream, _) = peer.accept().unwrap();
+        assert_eq!(read_lines(&stream, 1), ["first"]);
+        stream.shutdown(Shutdown::Both).unwrap();
+        drop(stream);
+        // Give the hang-up time to arrive
+        thread::sleep(Duration::from_millis(100));
+
+        pool.send(addr, b"second\n").unwrap();
+        let (stream, _) = peer.accept().unwrap();
+        assert_eq!(read_lines(&stream, 1), ["second"]);
+        assert_eq!(pool.opened(), 2);
+        assert_eq!(pool.len(), 1);
+
+        // Nobody listening any more is an error right away, and nothing is kept
+        drop(stream);
+        drop(peer);
+        thread::sleep(Duration::from_millis(100));
+        assert!(pool.send(addr, b"third\n").is_err());
+        assert!(!pool.is_connected(addr));
+    }
+
+    #[test]
+    fn test_least_recently_used_connection_is_closed_past_the_cap() {
+        let peers: Vec<(TcpListener, SocketAddr)> = (0..3).map(|_| listener()).collect();
+        let [a, b, c] = [peers[0].1, peers[1].1, peers[2].1];
+        let pool = OutboundConnections::new(2);
+
+        pool.send(a, b"a\n").unwrap();
+        pool.send(b, b"b\n").unwrap();
+        pool.send(a, b"a again\n").unwrap();
+        pool.send(c, b"c\n").unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(pool.is_connected(a));
+        assert!(!pool.is_connected(b));
+        assert!(pool.is_connected(c));
+
+        // The evicted connection was closed after what it carried
+        let (stream, _) = peers[1].0.accept().unwrap();
+        let mut received = String::new();
+        (&stream).read_to_string(&mut received).unwrap();
+        assert_eq!(received, "b\n");
+
+        pool.send(b, b"b again\n").unwrap();
+        assert!(!pool.is_connected(a));
+        assert_eq!(pool.opened(), 4);
+    }
+}
@@ -0,0 +1,243 @@
+//! How the node finds and reaches peers, behind a trait tests can script
+//!
+//! Peer discovery resolves seed hostnames, probes each address it gets back and then sends
+//! the reachable ones a handshake. `TcpConnectivity` does all three over the network.
+//! `MockConnectivity` answers from a script instead and records every call, so a test can
+//! say "these hosts resolve to these addresses and these are reachable" and follow the
+//! whole pipeline without opening a socket. Whatever the script leaves out goes to its
+//! fallback if it has one; the development seeder relies on that to simulate its seeds while
+//! probing and dialing the local network for real.
+
+use crate::error::{BlockchainError, Result};
+use crate::network::connections::OutboundConnections;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// How long `TcpConnectivity` waits on a peer unless told otherwise
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolving, probing and dialing peers
+pub trait PeerConnectivity: Send + Sync {
+    /// Whether a connection to `addr` can be opened
+    fn is_reachable(&self, addr: SocketAddr) -> bool;
+
+    /// The addresses `host` resolves to; one whose port is 0 takes the network's default
+    fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>>;
+
+    /// Open a connection to `addr` in `connections`, writing `handshake` first
+    fn send_handshake(
+        &self,
+        connections: &OutboundConnections,
+        addr: SocketAddr,
+        handshake: &[u8],
+    ) -> Result<()> {
+        connections.send_handshake(addr, handshake)
+    }
+}
+
+/// The network itself: DNS lookups and TCP connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpConnectivity {
+    timeout: Duration,
+}
+
+impl TcpConnectivity {
+    /// Give up on a peer that hasn't accepted within `timeout`
+    pub fn new(timeout: Duration) -> TcpConnectivity {
+        TcpConnectivity { timeout }
+    }
+}
+
+impl Default for TcpConnectivity {
+    fn default() -> TcpConnectivity {
+        TcpConnectivity::new(DEFAULT_PROBE_TIMEOUT)
+    }
+}
+
+impl PeerConnectivity for TcpConnectivity {
+    fn is_reachable(&self, addr: SocketAddr) -> bool {
+        match TcpStream::connect_timeout(&addr, self.timeout) {
+            Ok(_) => {
+                info!("Peer {addr} is reachable");
+                true
+            }
+            Err(e) => {
+                warn!("Peer {addr} is not reachable: {e}");
+                false
+            }
+        }
+    }
+
+    fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>> {
+        (host, 0)
+            .to_socket_addrs()
+            .map(Iterator::collect)
+            .map_err(|e| {
+                BlockchainError::Network(format!("DNS resolution failed for '{host}': {e}"))
+            })
+    }
+}
+
+/// One call a `MockConnectivity` answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectivityCall {
+    Resolve(String),
+    Probe(SocketAddr),
+    Handshake(SocketAddr),
+}
+
+/// Connectivity answered from a script, for tests and simulated networks
+///
+/// Hosts it resolves and addresses it reaches are declared up front. Without a fallback,
+/// any other host fails to resolve and any other address is unreachable, handshakes
+/// included, and nothing ever touches the network. A handshake to a reachable address is
+/// recorded but never sent.
+#[derive(Default)]
+pub struct MockConnectivity {
+    resolutions: HashMap<String, Vec<SocketAddr>>,
+    reachable: HashSet<SocketAddr>,
+    fallback: Option<Arc<dyn PeerConnectivity>>,
+    calls: Mutex<Vec<ConnectivityCall>>,
+}
+
+impl MockConnectivity {
+    pub fn new() -> MockConnectivity {
+        MockConnectivity::default()
+    }
+
+    /// Resolve `host` to `addrs`
+    pub fn resolves(mut self, host: &str, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.resolutions
+            .entry(host.to_string())
+            .or_default()
+            .extend(addrs);
+        self
+    }
+
+    /// Accept probes of and handshakes to `addrs`
+    pub fn reachable(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.reachable.extend(addrs);
+        self
+    }
+
+    /// Hand hosts and addresses the script doesn't name to `fallback`
+    pub fn with_fallback(mut self, fallback: Arc<dyn PeerConnectivity>) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Every call so far, oldest first, those passed to the fallback included
+    pub fn calls(&self) -> Vec<ConnectivityCall> {
+        self.lock().clone()
+    }
+
+    /// Addresses handshakes went to, in order
+    pub fn handshakes(&self) -> Vec<SocketAddr> {
+        self.lock()
+            .iter()
+            .filter_map(|call| match call {
+                ConnectivityCall::Handshake(addr) => Some(*addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn record(&self, call: ConnectivityCall) {
+        self.lock().push(call);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<ConnectivityCall>> {
+        self.calls.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PeerConnectivity for MockConnectivity {
+    fn is_reachable(&self, addr: SocketAddr) -> bool {
+        self.record(ConnectivityCall::Probe(addr));
+        if self.reachable.contains(&addr) {
+            return true;
+        }
+        self.fallback
+            .as_ref()
+            .is_some_and(|fallback| fallback.is_reachable(addr))
+    }
+
+    fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>> {
+        self.record(ConnectivityCall::Resolve(host.to_string()));
+        if let Some(addrs) = self.resolutions.get(host) {
+            return Ok(addrs.clone());
+        }
+        match &self.fallback {
+            Some(fallback) => fallback.resolve(host),
+            None => Err(BlockchainError::Network(format!(
+                "DNS resolution failed for '{host}': not a scripted host"
+            ))),
+        }
+    }
+
+    fn send_handshake(
+        &self,
+        connections: &OutboundConnections,
+        addr: SocketAddr,
+        handshake: &[u8],
+    ) -> Result<()> {
+        self.record(ConnectivityCall::Handshake(addr));
+        if self.reachable.contains(&addr) {
+            return Ok(());
+        }
+        match &self.fallback {
+            Some(fallback) => fallback.send_handshake(connections, addr, handshake),
+            None => Err(BlockchainError::Network(format!(
+                "Failed to connect to {addr}: not a reachable address"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_mock_never_opens_a_socket() {
+        // A peer really listens, but the script doesn't say it's reachable
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let listening = listener.local_addr().unwrap();
+        let scripted: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+        let mock = MockConnectivity::new()
+            .resolves("seed.test", [scripted])
+            .reachable([scripted]);
+        let connections = OutboundConnections::new(4);
+
+        assert_eq!(mock.resolve("seed.test").unwrap(), vec![scripted]);
+        assert!(mock.resolve("localhost").is_err());
+        assert!(mock.is_reachable(scripted));
+        assert!(!mock.is_reachable(listening));
+        mock.send_handshake(&connections, scripted, b"hello")
+            .unwrap();
+        assert!(mock
+            .send_handshake(&connections, listening, b"hello")
+            .is_err());
+
+        assert_eq!(
+            mock.calls(),
+            vec![
+                ConnectivityCall::Resolve("seed.test".to_string()),
+                ConnectivityCall::Resolve("localhost".to_string()),
+                ConnectivityCall::Probe(scripted),
+                ConnectivityCall::Probe(listening),
+                ConnectivityCall::Handshake(scripted),
+                ConnectivityCall::Handshake(listening),
+            ]
+        );
+        assert_eq!(mock.handshakes(), vec![scripted, listening]);
+        assert_eq!(connections.opened(), 0);
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+}
@@ -1,13 +1,21 @@
+use crate::core::Network;
 use crate::error::{BlockchainError, Result};
+use crate::network::connectivity::{MockConnectivity, PeerConnectivity, TcpConnectivity};
 use log::{info, warn};
 use std::collections::HashSet;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+/// Ports the simulated development seeds hand out on the loopback address
+const DEVELOPMENT_PEER_PORTS: [u16; 3] = [2001, 2002, 2003];
+
 /// DNS seeding configuration and implementation
 ///
 /// This module provides Bitcoin-compatible DNS seeding functionality
 /// to discover initial peers without relying on hardcoded addresses.
+/// Seeds are resolved and peers probed through a `PeerConnectivity`, the network itself
+/// unless `with_connectivity` gives another.
 pub struct DnsSeeder {
     /// List of DNS seed hostnames
     dns_seeds: Vec<String>,
@@ -17,6 +25,8 @@ pub struct DnsSeeder {
     resolution_timeout: Duration,
     /// Maximum number of addresses to return
     max_addresses: usize,
+    /// How seeds are resolved and peers reached; TCP with `resolution_timeout` if `None`
+    connectivity: Option<Arc<dyn PeerConnectivity>>,
 }
 
 /// Represents a discovered peer address with metadata
@@ -38,6 +48,7 @@ impl DnsSeeder {
             default_port,
             resolution_timeout: Duration::from_secs(10),
             max_addresses: 100,
+            connectivity: None,
         }
     }
 
@@ -48,10 +59,26 @@ impl DnsSeeder {
             default_port,
             resolution_timeout: Duration::from_secs(10),
             max_addresses: 100,
+            connectivity: None,
+        }
+    }
+
+    /// Resolve seeds and reach peers through `connectivity`, e.g. a `MockConnectivity`
+    pub fn with_connectivity(mut self, connectivity: Arc<dyn PeerConnectivity>) -> Self {
+        self.connectivity = Some(connectivity);
+        self
+    }
+
+    /// How this seeder resolves seeds and reaches peers
+    pub fn connectivity(&self) -> Arc<dyn PeerConnectivity> {
+        match &self.connectivity {
+            Some(connectivity) => Arc::clone(connectivity),
+            None => Arc::new(TcpConnectivity::new(self.resolution_timeout)),
         }
     }
 
-    /// Set the DNS resolution timeout
+    /// Set the DNS resolution timeout, which also bounds each probe unless
+    /// `with_connectivity` took over
     pub fn with_timeout(mut self, timeout: Duration) -> Self {
         self.resolution_timeout = timeout;
         self
@@ -81,7 +108,8 @@ impl DnsSeeder {
             self.dns_seeds.len()
         );
 
-        let mut all_peers = HashSet::new();
+        let mut all_peers = Vec::new();
+        let mut seen_addresses = HashSet::new();
         let mut successful_seeds = 0;
 
         for seed in &self.dns_seeds {
@@ -89,7 +117,12 @@ impl DnsSeeder {
                 Ok(peers) => {
                     successful_seeds += 1;
                     info!("DNS seed '{}' returned {} peers", seed, peers.len());
-                    all_peers.extend(peers);
+                    // Seeds often overlap; the first one to name an address is its source
+                    all_peers.extend(
+                        peers
+                            .into_iter()
+                            .filter(|peer| seen_addresses.insert(peer.address)),
+                    );
                 }
                 Err(e) => {
                     warn!("Failed to resolve DNS seed '{seed}': {e}");
@@ -103,7 +136,7 @@ impl DnsSeeder {
             ));
         }
 
-        let mut peers: Vec<DiscoveredPeer> = all_peers.into_iter().collect();
+        let mut peers = all_peers;
 
         // Limit the number of returned peers
         if peers.len() > self.max_addresses {
@@ -123,77 +156,21 @@ impl DnsSeeder {
     fn resolve_seed(&self, seed: &str) -> Result<Vec<DiscoveredPeer>> {
         info!("Resolving DNS seed: {seed}");
 
-        // For development/testing, we'll simulate DNS resolution
-        // In production, this would use actual DNS resolution
-        if self.is_development_mode() {
-            return self.simulate_dns_resolution(seed);
-        }
-
-        // Actual DNS resolution implementation
-        self.perform_dns_resolution(seed)
-    }
-
-    /// Check if we're in development mode (no real DNS seeds available)
-    fn is_development_mode(&self) -> bool {
-        // Check if any of the seeds are localhost or development domains
-        self.dns_seeds.iter().any(|seed| {
-            seed.contains("localhost")
-                || seed.contains("127.0.0.1")
-                || seed.contains("architect-chain.org") // Our example domain
-        })
-    }
-
-    /// Simulate DNS resolution for development/testing
-    fn simulate_dns_resolution(&self, seed: &str) -> Result<Vec<DiscoveredPeer>> {
-        info!("Simulating DNS resolution for development seed: {seed}");
-
-        // Return some simulated peer addresses for testing
-        let simulated_peers = vec![
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 2001),
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 2002),
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 2003),
-            SocketAddr::new(
-                IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)),
-                self.default_port,
-            ),
-            SocketAddr::new(
-                IpAddr::V4(Ipv4Addr::new(192, 168, 1, 101)),
-                self.default_port,
-            ),
-        ];
-
-        let discovered_peers = simulated_peers
+        let discovered_at = Instant::now();
+        let addresses = self.connectivity().resolve(seed)?;
+        Ok(addresses
             .into_iter()
-            .map(|addr| DiscoveredPeer {
-                address: addr,
-                discovered_at: Instant::now(),
-                source: seed.to_string(),
+            .map(|mut address| {
+                if address.port() == 0 {
+                    address.set_port(self.default_port);
+                }
+                DiscoveredPeer {
+                    address,
+                    discovered_at,
+                    source: seed.to_string(),
+                }
             })
-            .collect();
-
-        Ok(discovered_peers)
-    }
-
-    /// Perform actual DNS resolution
-    fn perform_dns_resolution(&self, seed: &str) -> Result<Vec<DiscoveredPeer>> {
-        let seed_with_port = format!("{}:{}", seed, self.default_port);
-
-        match seed_with_port.to_socket_addrs() {
-            Ok(addresses) => {
-                let discovered_peers = addresses
-                    .map(|addr| DiscoveredPeer {
-                        address: addr,
-                        discovered_at: Instant::now(),
-                        source: seed.to_string(),
-                    })
-                    .collect();
-
-                Ok(discovered_peers)
-            }
-            Err(e) => Err(BlockchainError::Network(format!(
-                "DNS resolution failed for '{seed}': {e}"
-            ))),
-        }
+            .collect())
     }
 
     /// Add a custom DNS seed
@@ -215,27 +192,17 @@ impl DnsSeeder {
 
     /// Test connectivity to a discovered peer
     pub fn test_peer_connectivity(&self, peer: &DiscoveredPeer) -> bool {
-        use std::net::TcpStream;
-
-        match TcpStream::connect_timeout(&peer.address, self.resolution_timeout) {
-            Ok(_) => {
-                info!("Peer {} is reachable", peer.address);
-                true
-            }
-            Err(e) => {
-                warn!("Peer {} is not reachable: {}", peer.address, e);
-                false
-            }
-        }
+        self.connectivity().is_reachable(peer.address)
     }
 
     /// Filter peers by reachability
     pub fn filter_reachable_peers(&self, peers: Vec<DiscoveredPeer>) -> Vec<DiscoveredPeer> {
         info!("Testing connectivity to {} discovered peers", peers.len());
 
+        let connectivity = self.connectivity();
         let reachable_peers: Vec<DiscoveredPeer> = peers
             .into_iter()
-            .filter(|peer| self.test_peer_connectivity(peer))
+            .filter(|peer| connectivity.is_reachable(peer.address))
             .collect();
 
         info!("Found {} reachable peers", reachable_peers.len());
@@ -261,10 +228,35 @@ impl DnsSeeder {
     }
 
     /// Create a seeder for development/local testing
+    ///
+    /// Its seeds are simulated: `localhost` and `127.0.0.1` both hand out the nodes a local
+    /// network runs on `DEVELOPMENT_PEER_PORTS`. Those are probed and dialed for real, as
+    /// are any seeds added on top.
     pub fn development() -> Self {
-        let mut seeder = Self::new(2001);
-        seeder.dns_seeds = vec!["localhost".to_string(), "127.0.0.1".to_string()];
-        seeder
+        let peers = DEVELOPMENT_PEER_PORTS
+            .map(|port| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port));
+        let simulated = MockConnectivity::new()
+            .resolves("localhost", peers)
+            .resolves("127.0.0.1", peers)
+            .with_fallback(Arc::new(TcpConnectivity::default()));
+        Self::with_seeds(vec!["localhost".to_string(), "127.0.0.1".to_string()], 2001)
+            .with_connectivity(Arc::new(simulated))
+    }
+
+    /// Pick the seeder for a node on `network` listening on `listen_addr`
+    ///
+    /// A node on a loopback address or on regtest is part of a local development network;
+    /// any other uses the seeds of the network it was configured for.
+    pub fn for_network(network: Network, listen_addr: &str) -> Self {
+        let loopback = listen_addr
+            .parse::<SocketAddr>()
+            .is_ok_and(|addr| addr.ip().is_loopback());
+        match network {
+            Network::Regtest => Self::development(),
+            _ if loopback => Self::development(),
+            Network::Testnet => Self::testnet(),
+            Network::Mainnet => Self::mainnet(),
+        }
     }
 }
 
@@ -290,16 +282,32 @@ mod tests {
     }
 
     #[test]
-    fn test_development_mode_detection() {
-        let seeder = DnsSeeder::development();
-        assert!(seeder.is_development_mode());
+    fn test_seeder_follows_network_and_listen_addr() {
+        let local = DnsSeeder::for_network(Network::Mainnet, "127.0.0.1:2005");
+        assert_eq!(local.get_seeds(), DnsSeeder::development().get_seeds());
+        let regtest = DnsSeeder::for_network(Network::Regtest, "10.0.0.7:2001");
+        assert_eq!(regtest.get_seeds(), DnsSeeder::development().get_seeds());
+        // The port says nothing about the network
+        let testnet = DnsSeeder::for_network(Network::Testnet, "10.0.0.7:2001");
+        assert_eq!(testnet.get_seeds(), DnsSeeder::testnet().get_seeds());
+        let mainnet = DnsSeeder::for_network(Network::Mainnet, "10.0.0.7:12001");
+        assert_eq!(mainnet.get_seeds(), DnsSeeder::mainnet().get_seeds());
+
+        // A custom seed next to the simulated ones is resolved for real
+        let mut seeder = DnsSeeder::development();
+        seeder.add_seed("seed.example.invalid".to_string());
+        assert!(seeder.resolve_seed("seed.example.invalid").is_err());
     }
 
     #[test]
     fn test_simulated_dns_resolution() {
         let seeder = DnsSeeder::development();
-        let peers = seeder.simulate_dns_resolution("localhost").unwrap();
-        assert!(!peers.is_empty());
+        let peers = seeder.resolve_seed("localhost").unwrap();
+        let addresses: Vec<SocketAddr> = peers.iter().map(|peer| peer.address).collect();
+        assert_eq!(
+            addresses,
+            DEVELOPMENT_PEER_PORTS.map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+        );
 
         // Check that all peers have the correct source
         for peer in &peers {
@@ -307,11 +315,64 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_scripted_seeds_are_resolved_and_probed_offline() {
+        let addr = |last: u8, port: u16| SocketAddr::from(([10, 0, 0, last], port));
+        let mock = Arc::new(
+            MockConnectivity::new()
+                .resolves("seed1.test", [addr(1, 0), addr(2, 0)])
+                // Seeds overlap, and one may name its own port
+                .resolves("seed2.test", [addr(2, 0), addr(3, 9333)])
+                .reachable([addr(1, 8333), addr(3, 9333)]),
+        );
+        let seeder = DnsSeeder::with_seeds(
+            vec![
+                "seed1.test".to_string(),
+                "seed2.test".to_string(),
+                "down.test".to_string(),
+            ],
+            8333,
+        )
+        .with_connectivity(mock.clone());
+
+        let peers = seeder.discover_peers().unwrap();
+        let found: Vec<(SocketAddr, &str)> = peers
+            .iter()
+            .map(|peer| (peer.address, peer.source.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (addr(1, 8333), "seed1.test"),
+                (addr(2, 8333), "seed1.test"),
+                (addr(3, 9333), "seed2.test"),
+            ]
+        );
+
+        let reachable: Vec<SocketAddr> = seeder
+            .filter_reachable_peers(peers)
+            .iter()
+            .map(|peer| peer.address)
+            .collect();
+        assert_eq!(reachable, vec![addr(1, 8333), addr(3, 9333)]);
+        assert_eq!(mock.calls().len(), 6);
+        assert!(mock.handshakes().is_empty());
+
+        // Every seed failing is an error
+        let seeder =
+            DnsSeeder::with_seeds(vec!["down.test".to_string()], 8333).with_connectivity(mock);
+        assert!(seeder.discover_peers().is_err());
+    }
+
     #[test]
     fn test_peer_discovery() {
         let seeder = DnsSeeder::development();
         let peers = seeder.discover_peers().unwrap();
         assert!(!peers.is_empty());
+
+        // Both development seeds hand out the same addresses, which are listed once
+        let addresses: HashSet<SocketAddr> = peers.iter().map(|peer| peer.address).collect();
+        assert_eq!(addresses.len(), peers.len());
     }
 
     #[test]
@@ -0,0 +1,562 @@
+//! A read-only block explorer serving the chain as JSON over HTTP
+//!
+//! A node started with `--explorer-addr` answers GET requests on a minimal HTTP/1.1
+//! listener, so a static web page can browse its chain:
+//!
+//! - `/blocks?limit=N`: the latest N main-chain blocks, newest first (10 unless given,
+//!   at most `MAX_BLOCK_LIMIT`)
+//! - `/block/<hash>` and `/block/height/<n>`: one block with its transactions
+//! - `/tx/<txid>`: a transaction from the main chain or the memory pool
+//! - `/address/<address>`: balance, unspent outputs and every transaction touching it
+//! - `/mempool`: the pending transactions
+//!
+//! Every response is JSON and allows any origin, so the page can be opened straight from
+//! disk. Errors are `{"error": "..."}` with a 400, 404 or 405 status. An address's history
+//! comes from walking the whole main chain, which is fine for chains of the size a
+//! classroom node has.
+
+use crate::core::{
+    Amount, BlockSummarizer, BlockSummary, Blockchain, NodeContext, TransactionSummary,
+};
+use crate::error::{BlockchainError, Result};
+use crate::network::metrics::read_request_line;
+use crate::storage::{AddressBalance, UTXOSet};
+use crate::wallet::address_pub_key_hash;
+use data_encoding::HEXLOWER;
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_BLOCK_LIMIT: usize = 10;
+/// Most blocks one `/blocks` page returns; a larger limit is cut down to this
+pub const MAX_BLOCK_LIMIT: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the explorer for `blockchain` and the memory pool of `context` on `addr` from a
+/// background thread
+///
+/// Returns the bound address, which differs from `addr` when it asks for port 0.
+pub fn spawn_explorer_server(
+    addr: &str,
+    blockchain: Blockchain,
+    context: Arc<NodeContext>,
+) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        BlockchainError::Network(format!("Failed to bind explorer listener to {addr}: {e}"))
+    })?;
+    let local_addr = listener.local_addr().map_err(|e| {
+        BlockchainError::Network(format!("Failed to read explorer listener address: {e}"))
+    })?;
+    info!("Serving the block explorer on http://{local_addr}/blocks");
+
+    let explorer = Explorer {
+        blockchain,
+        context,
+    };
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = explorer.answer(stream) {
+                        warn!("Failed to answer explorer request: {e}");
+                    }
+                }
+                Err(e) => warn!("Error accepting explorer connection: {e}"),
+            }
+        }
+    });
+
+    Ok(local_addr)
+}
+
+#[derive(Debug, Serialize)]
+struct BlockPage {
+    tip_height: usize,
+    blocks: Vec<BlockSummary>,
+}
+
+#[derive(Debug, Serialize)]
+struct BlockDetails {
+    #[serde(flatten)]
+    block: BlockSummary,
+    in_main_chain: bool,
+    /// 1 for the tip, 0 for a block off the main chain
+    confirmations: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct TransactionDetails {
+    #[serde(flatten)]
+    transaction: TransactionSummary,
+    /// `false` while it waits in the memory pool
+    confirmed: bool,
+    block_hash: Option<String>,
+    height: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct AddressDetails {
+    address: String,
+    balance: AddressBalance,
+    /// What the address can spend once its pending payments are taken out
+    available: Amount,
+    utxos: Vec<UnspentOutput>,
+    /// Newest first
+    history: Vec<HistoryEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct UnspentOutput {
+    txid: String,
+    vout: usize,
+    value: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryEntry {
+    txid: String,
+    height: usize,
+    block_hash: String,
+    /// Paid to the address, including change
+    received: u64,
+    /// Spent from the address
+    sent: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct MempoolPage {
+    count: usize,
+    transactions: Vec<TransactionSummary>,
+}
+
+/// Why a request got no data, which decides its status
+#[derive(Debug)]
+enum RouteError {
+    BadRequest(String),
+    NotFound(String),
+    Internal(BlockchainError),
+}
+
+impl From<BlockchainError> for RouteError {
+    fn from(error: BlockchainError) -> RouteError {
+        match error {
+            // What pruning threw away isn't coming back
+            BlockchainError::Pruned(message) => RouteError::NotFound(message),
+            error => RouteError::Internal(error),
+        }
+    }
+}
+
+type RouteResult = std::result::Result<Value, RouteError>;
+
+fn to_json(value: impl Serialize) -> RouteResult {
+    serde_json::to_value(value).map_err(|e| {
+        RouteError::Internal(BlockchainError::Serialization(format!(
+            "Failed to encode explorer response: {e}"
+        )))
+    })
+}
+
+struct Explorer {
+    blockchain: Blockchain,
+    context: Arc<NodeContext>,
+}
+
+impl Explorer {
+    fn answer(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let request_line = read_request_line(&mut stream)?;
+        let mut request_line = request_line.split_whitespace();
+        let (status, body) = match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some(target)) => match self.route(target) {
+                Ok(body) => ("200 OK", body),
+                Err(RouteError::BadRequest(message)) => {
+                    ("400 Bad Request", json!({ "error": message }))
+                }
+                Err(RouteError::NotFound(message)) => {
+                    ("404 Not Found", json!({ "error": message }))
+                }
+                Err(RouteError::Internal(e)) => {
+                    warn!("Explorer failed to answer {target}: {e}");
+                    (
+                        "500 Internal Server Error",
+                        json!({ "error": e.to_string() }),
+                    )
+                }
+            },
+            (Some(_), Some(_)) => (
+                "405 Method Not Allowed",
+                json!({ "error": "Only GET is supported" }),
+            ),
+            _ => (
+                "400 Bad Request",
+                json!({ "error": "Malformed request line" }),
+            ),
+        };
+
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nAccess-Control-Allow-Origin: *\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes())?;
+        stream.flush()
+    }
+
+    fn route(&self, target: &str) -> RouteResult {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["blocks"] => self.blocks(query),
+            ["block", "height", height] => self.block_at_height(height),
+            ["block", hash] => self.block(hash),
+            ["tx", txid] => self.transaction(txid),
+            ["address", address] => self.address(address),
+            ["mempool"] => self.mempool(),
+            _ => Err(RouteError::NotFound(format!("No such page: {path}"))),
+        }
+    }
+
+    fn blocks(&self, query: &str) -> RouteResult {
+        let mut limit = DEFAULT_BLOCK_LIMIT;
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            if key == "limit" {
+                limit = match value.parse::<usize>() {
+                    Ok(limit) if limit > 0 => limit.min(MAX_BLOCK_LIMIT),
+                    _ => {
+                        return Err(RouteError::BadRequest(format!(
+                            "limit must be a positive number, not {value}"
+                        )))
+                    }
+                };
+            }
+        }
+
+        let tip_height = self.blockchain.get_best_height()?;
+        let mut summarizer = BlockSummarizer::new(&self.blockchain);
+        let mut blocks = Vec::new();
+        let mut iterator = self.blockchain.iterator();
+        while blocks.len() < limit {
+            let Some(block) = iterator.try_next()? else {
+                break;
+            };
+            blocks.push(summarizer.summarize(&block));
+        }
+        to_json(BlockPage { tip_height, blocks })
+    }
+
+    fn block(&self, hash: &str) -> RouteResult {
+        if hash.len() != 64 || HEXLOWER.decode(hash.as_bytes()).is_err() {
+            return Err(RouteError::BadRequest(format!(
+                "{hash} is not a block hash, which is 64 lowercase hex digits"
+            )));
+        }
+        let block = self
+            .blockchain
+            .get_block(hash)?
+            .ok_or_else(|| RouteError::NotFound(format!("No block {hash}")))?;
+        let in_main_chain = self.blockchain.is_in_main_chain(hash)?;
+        let confirmations = if in_main_chain {
+            self.blockchain.get_best_height()? + 1 - block.get_height()
+        } else {
+            0
+        };
+        to_json(BlockDetails {
+            block: BlockSummarizer::new(&self.blockchain).summarize(&block),
+            in_main_chain,
+            confirmations,
+        })
+    }
+
+    fn block_at_height(&self, height: &str) -> RouteResult {
+        let height: usize = height
+            .parse()
+            .map_err(|_| RouteError::BadRequest(format!("{height} is not a block height")))?;
+        let hash = self
+            .blockchain
+            .get_block_hash_at_height(height)?
+            .ok_or_else(|| {
+                RouteError::NotFound(format!("No main-chain block at height {height}"))
+            })?;
+        self.block(&hash)
+    }
+
+    fn transaction(&self, txid_hex: &str) -> RouteResult {
+        let txid = HEXLOWER.decode(txid_hex.as_bytes()).map_err(|_| {
+            RouteError::BadRequest(format!("{txid_hex} is not a transaction id in hex"))
+        })?;
+        let mut summarizer = BlockSummarizer::new(&self.blockchain);
+        if let Some(tx) = self.context.memory_pool().get(txid_hex) {
+            return to_json(TransactionDetails {
+                transaction: summarizer.summarize_transaction(&tx),
+                confirmed: false,
+                block_hash: None,
+                height: None,
+            });
+        }
+
+        let tx = self
+            .blockchain
+            .find_transaction(&txid)?
+            .ok_or_else(|| RouteError::NotFound(format!("No transaction {txid_hex}")))?;
+        let block_hash = self.blockchain.find_transaction_block(&txid)?;
+        let height = match &block_hash {
+            Some(hash) => Some(self.blockchain.get_block_height(hash)?),
+            None => None,
+        };
+        to_json(TransactionDetails {
+            transaction: summarizer.summarize_transaction(&tx),
+            confirmed: true,
+            block_hash,
+            height,
+        })
+    }
+
+    fn address(&self, address: &str) -> RouteResult {
+        let pub_key_hash = address_pub_key_hash(address)
+            .map_err(|_| RouteError::BadRequest(format!("{address} is not a valid address")))?;
+        let utxo_set = UTXOSet::new(self.blockchain.clone());
+        let balance = utxo_set.balance_with_pending(&pub_key_hash, self.context.memory_pool())?;
+        let utxos = utxo_set
+            .list_unspent_safe(&pub_key_hash)?
+            .into_iter()
+            .map(|(txid, vout, output)| UnspentOutput {
+                txid,
+                vout,
+                value: output.get_value().to_satoshis(),
+            })
+            .collect();
+
+        let mut history = Vec::new();
+        let mut summarizer = BlockSummarizer::new(&self.blockchain);
+        let mut iterator = self.blockchain.iterator();
+        while let Some(block) = iterator.try_next()? {
+            let summary = summarizer.summarize(&block);
+            for tx in summary.transactions {
+                let received = tx
+                    .outputs
+                    .iter()
+                    .filter(|output| output.to == address)
+                    .map(|output| output.value)
+                    .sum();
+                let sent = tx
+                    .inputs
+                    .iter()
+                    .filter(|input| input.from == address)
+                    .filter_map(|input| input.value)
+                    .sum();
+                if received > 0 || sent > 0 {
+                    history.push(HistoryEntry {
+                        txid: tx.txid,
+                        height: summary.height,
+                        block_hash: summary.hash.clone(),
+                        received,
+                        sent,
+                    });
+                }
+            }
+        }
+
+        to_json(AddressDetails {
+            address: address.to_string(),
+            available: balance.available(),
+            balance,
+            utxos,
+            history,
+        })
+    }
+
+    fn mempool(&self) -> RouteResult {
+        let mut summarizer = BlockSummarizer::new(&self.blockchain);
+        let transactions: Vec<TransactionSummary> = self
+            .context
+            .memory_pool()
+            .get_all()
+            .iter()
+            .map(|tx| summarizer.summarize_transaction(tx))
+            .collect();
+        to_json(MempoolPage {
+            count: transactions.len(),
+            transactions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, FeePriority, Transaction};
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    // The status line and the parsed body
+    fn fetch(addr: SocketAddr, path: &str) -> (String, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(
+            head.contains("\r\nContent-Type: application/json\r\n"),
+            "{head}"
+        );
+        assert!(
+            head.contains("\r\nAccess-Control-Allow-Origin: *\r\n"),
+            "{head}"
+        );
+        let status = head.lines().next().unwrap().to_string();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_routes_serve_chain_data() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let payer = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &owner,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = Arc::new(NodeContext::new(FeeMode::Fixed { amount: 5 }).unwrap());
+        blockchain.generate_blocks(1, &payer, &context).unwrap();
+        blockchain
+            .generate_blocks(1, TEST_ADDRESS, &context)
+            .unwrap();
+
+        // The owner's payment is confirmed at height 3, the payer's is left pending
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let confirmed = Transaction::new_utxo_transaction_with_context(
+            &owner,
+            TEST_ADDRESS,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        context.memory_pool().add(confirmed.clone());
+        blockchain
+            .generate_blocks(1, TEST_ADDRESS, &context)
+            .unwrap();
+        let pending = Transaction::new_utxo_transaction_with_context(
+            &payer,
+            TEST_ADDRESS,
+            2_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )
+        .unwrap();
+        context.memory_pool().add(pending.clone());
+
+        let addr =
+            spawn_explorer_server("127.0.0.1:0", blockchain.clone(), Arc::clone(&context)).unwrap();
+
+        let (status, page) = fetch(addr, "/blocks?limit=2");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(page["tip_height"], 3);
+        let blocks = page["blocks"].as_array().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            (blocks[0]["height"].clone(), blocks[1]["height"].clone()),
+            (json!(3), json!(2))
+        );
+        assert_eq!(
+            fetch(addr, "/blocks").1["blocks"].as_array().unwrap().len(),
+            4
+        );
+        assert_eq!(
+            fetch(addr, "/blocks?limit=zero").0,
+            "HTTP/1.1 400 Bad Request"
+        );
+
+        let tip_hash = blockchain.get_tip_hash();
+        let (status, block) = fetch(addr, &format!("/block/{tip_hash}"));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(block["hash"], tip_hash.as_str());
+        assert_eq!(block["in_main_chain"], true);
+        assert_eq!(block["confirmations"], 1);
+        assert_eq!(block["transactions"].as_array().unwrap().len(), 2);
+        let (_, genesis) = fetch(addr, "/block/height/0");
+        assert_eq!(genesis["height"], 0);
+        assert_eq!(genesis["confirmations"], 4);
+        assert_eq!(fetch(addr, "/block/height/9").0, "HTTP/1.1 404 Not Found");
+        assert_eq!(
+            fetch(addr, "/block/height/-1").0,
+            "HTTP/1.1 400 Bad Request"
+        );
+        let (status, missing) = fetch(addr, &format!("/block/{}", "0".repeat(64)));
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        assert!(missing["error"].as_str().unwrap().contains("No block"));
+        assert_eq!(fetch(addr, "/block/xyz").0, "HTTP/1.1 400 Bad Request");
+
+        let confirmed_id = HEXLOWER.encode(confirmed.get_id());
+        let (status, tx) = fetch(addr, &format!("/tx/{confirmed_id}"));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(tx["txid"], confirmed_id.as_str());
+        assert_eq!(tx["confirmed"], true);
+        assert_eq!(tx["height"], 3);
+        assert_eq!(tx["block_hash"], tip_hash.as_str());
+        let pending_id = HEXLOWER.encode(pending.get_id());
+        let (_, tx) = fetch(addr, &format!("/tx/{pending_id}"));
+        assert_eq!(tx["confirmed"], false);
+        assert_eq!(tx["height"], Value::Null);
+        assert_eq!(
+            fetch(addr, &format!("/tx/{}", "ab".repeat(32))).0,
+            "HTTP/1.1 404 Not Found"
+        );
+
+        let (status, account) = fetch(addr, &format!("/address/{owner}"));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let unspent: u64 = account["utxos"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|utxo| utxo["value"].as_u64().unwrap())
+            .sum();
+        assert_eq!(account["balance"]["confirmed"], unspent);
+        assert_eq!(account["available"], unspent);
+        // The payment at height 3 and the genesis reward, newest first
+        let history = account["history"].as_array().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0]["txid"], confirmed_id.as_str());
+        assert_eq!(history[0]["height"], 3);
+        assert!(history[0]["sent"].as_u64().unwrap() > history[0]["received"].as_u64().unwrap());
+        assert_eq!(history[1]["height"], 0);
+        assert_eq!(history[1]["sent"], 0);
+        let (_, payer_account) = fetch(addr, &format!("/address/{payer}"));
+        assert!(
+            payer_account["balance"]["pending_outgoing"]
+                .as_u64()
+                .unwrap()
+                > 0
+        );
+        assert_eq!(payer_account["history"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            fetch(addr, "/address/nonsense").0,
+            "HTTP/1.1 400 Bad Request"
+        );
+
+        let (status, mempool) = fetch(addr, "/mempool");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(mempool["count"], 1);
+        assert_eq!(mempool["transactions"][0]["txid"], pending_id.as_str());
+
+        assert_eq!(fetch(addr, "/nowhere").0, "HTTP/1.1 404 Not Found");
+    }
+}
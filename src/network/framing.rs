@@ -0,0 +1,210 @@
+//! Limits applied to messages read from peer connections
+//!
+//! JSON packages arrive as a stream of values with no length prefix to check up front.
+//! `LimitedReader` instead counts the bytes handed to the deserializer and fails the read
+//! once a single message grows past the configured maximum. Bincode frames state their
+//! length, which `codec` checks against the same maximum before reading the payload.
+
+use crate::config::Config;
+use crate::core::{MAX_BLOCK_SIZE, MAX_TRANSACTION_SIZE};
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+/// Default maximum size of a single JSON-encoded package, unless the config file sets
+/// `network.max_message_size`
+///
+/// Byte vectors are encoded as JSON number arrays, which take up to four bytes per
+/// payload byte, so this leaves room for a full block.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 5 * 1024 * 1024;
+/// Default maximum number of hashes in a single `Inv` package, unless the config file sets
+/// `network.max_inv_items`
+pub const DEFAULT_MAX_INV_ITEMS: usize = 50_000;
+/// Room for the block header fields on top of the transactions
+const BLOCK_PAYLOAD_OVERHEAD: usize = 10_000;
+/// Longest hash or txid a peer may ask about
+const MAX_ID_LENGTH: usize = 128;
+/// Longest `addr_from` a peer may send
+const MAX_ADDR_LENGTH: usize = 256;
+
+/// Size limits enforced on every package received from a peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageLimits {
+    /// Maximum size of one JSON-encoded package in bytes
+    pub max_message_size: usize,
+    /// Maximum number of items in an `Inv` package
+    pub max_inv_items: usize,
+    /// Maximum length of a serialized block payload
+    pub max_block_payload: usize,
+    /// Maximum length of a serialized transaction payload
+    pub max_tx_payload: usize,
+    /// Maximum length of a block hash or txid
+    pub max_id_length: usize,
+    /// Maximum length of a peer address string
+    pub max_addr_length: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_inv_items: DEFAULT_MAX_INV_ITEMS,
+            max_block_payload: MAX_BLOCK_SIZE + BLOCK_PAYLOAD_OVERHEAD,
+            max_tx_payload: MAX_TRANSACTION_SIZE,
+            max_id_length: MAX_ID_LENGTH,
+            max_addr_length: MAX_ADDR_LENGTH,
+        }
+    }
+}
+
+impl MessageLimits {
+    /// The default limits, with the package size and inventory length `config` sets
+    pub fn from_config(config: &Config) -> Self {
+        let defaults = Self::default();
+        Self {
+            max_message_size: config
+                .get_max_message_size()
+                .unwrap_or(defaults.max_message_size),
+            max_inv_items: config.get_max_inv_items().unwrap_or(defaults.max_inv_items),
+            ..defaults
+        }
+    }
+
+    /// Set the maximum size of a single package
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Set the maximum number of items in an `Inv` package
+    pub fn with_max_inv_items(mut self, max_inv_items: usize) -> Self {
+        self.max_inv_items = max_inv_items;
+        self
+    }
+}
+
+/// Shared handle to the byte budget of the message currently being read
+#[derive(Debug, Clone)]
+pub struct MessageBudget {
+    limit: usize,
+    remaining: Rc<Cell<usize>>,
+    exceeded: Rc<Cell<bool>>,
+}
+
+impl MessageBudget {
+    /// Start the budget over for the next message
+    pub fn reset(&self) {
+        self.remaining.set(self.limit);
+    }
+
+    /// Whether a read failed because the message outgrew the limit
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.get()
+    }
+
+    /// Bytes read since the last `reset`, i.e. the size of the message just read
+    pub fn used(&self) -> usize {
+        self.limit - self.remaining.get()
+    }
+}
+
+/// Reader that fails once more than `limit` bytes are read without a `reset`
+///
+/// Wrap it around a `BufReader`, not inside one, so the count reflects what the
+/// deserializer consumed rather than what was buffered ahead.
+pub struct LimitedReader<R> {
+    inner: R,
+    budget: MessageBudget,
+}
+
+impl<R: Read> LimitedReader<R> {
+    /// Create a reader allowing `limit` bytes per message
+    pub fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            budget: MessageBudget {
+                limit,
+                remaining: Rc::new(Cell::new(limit)),
+                exceeded: Rc::new(Cell::new(false)),
+            },
+        }
+    }
+
+    /// Handle used to reset the budget after each complete message
+    pub fn budget(&self) -> MessageBudget {
+        self.budget.clone()
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let remaining = self.budget.remaining.get();
+        if remaining == 0 {
+            self.budget.exceeded.set(true);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "message exceeds maximum size of {} bytes",
+                    self.budget.limit
+                ),
+            ));
+        }
+
+        let max = buf.len().min(remaining);
+        let read = self.inner.read(&mut buf[..max])?;
+        self.budget.remaining.set(remaining - read);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    #[test]
+    fn test_limited_reader_rejects_oversized_message() {
+        let data = vec![b' '; 64];
+        let mut reader = LimitedReader::new(BufReader::new(Cursor::new(data)), 16);
+        let budget = reader.budget();
+
+        let mut sink = Vec::new();
+        assert!(reader.read_to_end(&mut sink).is_err());
+        assert_eq!(sink.len(), 16);
+        assert!(budget.exceeded());
+    }
+
+    #[test]
+    fn test_limits_come_from_the_config() {
+        let config = Config::new();
+        assert_eq!(
+            MessageLimits::from_config(&config),
+            MessageLimits::default()
+        );
+
+        config.set_max_message_size(1_024);
+        config.set_max_inv_items(10);
+        let limits = MessageLimits::from_config(&config);
+        assert_eq!(limits.max_message_size, 1_024);
+        assert_eq!(limits.max_inv_items, 10);
+        assert_eq!(limits.max_tx_payload, MAX_TRANSACTION_SIZE);
+    }
+
+    #[test]
+    fn test_limited_reader_reset_allows_next_message() {
+        let data = vec![b' '; 24];
+        let mut reader = LimitedReader::new(Cursor::new(data), 16);
+        let budget = reader.budget();
+
+        let mut buf = [0u8; 12];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(budget.used(), 12);
+        budget.reset();
+        reader.read_exact(&mut buf).unwrap();
+        assert!(!budget.exceeded());
+    }
+}
@@ -0,0 +1,308 @@
+//! Node identities and signed packages
+//!
+//! A package's `addr_from` is only what the sender claims, so anything keyed by it can be
+//! poisoned by a peer claiming someone else's address. A node with a `NodeIdentity` wraps
+//! every package it sends in a `SignedPackage`: the package, encoded in the format the
+//! connection negotiated, together with the time it was signed, signed with the node's key. The receiver attributes the package to the
+//! key's `PeerId` instead, wherever it connected from.
+//!
+//! The identity is an ECDSA P-256 key pair like a wallet's, kept in its own file in the
+//! data directory so it survives restarts and with it the peer's reputation. Timestamps
+//! only bound how long a captured package can be replayed, to `MAX_PACKAGE_AGE`.
+
+use crate::config::GLOBAL_CONFIG;
+use crate::error::{BlockchainError, Result};
+use crate::network::codec::WireFormat;
+use crate::network::server::Package;
+use crate::utils::{
+    current_timestamp, deserialize, ecdsa_p256_sha256_sign_digest, ecdsa_p256_sha256_sign_verify,
+    serialize,
+};
+use crate::wallet::wallet::hash_pub_key;
+use crate::wallet::Wallet;
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Signed packages older than this, or this far ahead of the local clock, are refused
+pub const MAX_PACKAGE_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Who signed a package: the hex-encoded hash of the signer's public key
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PeerId(String);
+
+impl PeerId {
+    pub fn from_public_key(public_key: &[u8]) -> PeerId {
+        PeerId(HEXLOWER.encode(&hash_pub_key(public_key)))
+    }
+
+    /// Read a peer id as `Display` writes it
+    pub fn parse(hex: &str) -> Result<PeerId> {
+        match HEXLOWER.decode(hex.as_bytes()) {
+            Ok(hash) if hash.len() == 20 => Ok(PeerId(hex.to_string())),
+            _ => Err(BlockchainError::Network(format!("Not a peer id: {hex}"))),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The key pair a node signs its packages with
+#[derive(Clone)]
+pub struct NodeIdentity {
+    key: Wallet,
+}
+
+impl NodeIdentity {
+    /// A fresh identity, known to no peer yet
+    pub fn generate() -> Result<NodeIdentity> {
+        Ok(NodeIdentity {
+            key: Wallet::new()?,
+        })
+    }
+
+    /// Where the node with `node_id` keeps its identity (<datadir>/node_2001_identity.dat)
+    ///
+    /// Every node on a machine gets its own, as with its database.
+    pub fn node_path(node_id: &str) -> Result<PathBuf> {
+        Ok(GLOBAL_CONFIG
+            .data_dir()?
+            .join(format!("node_{node_id}_identity.dat")))
+    }
+
+    /// Load the identity stored at `path`, creating and storing a new one if there is none
+    pub fn load_or_create(path: &Path) -> Result<NodeIdentity> {
+        if path.exists() {
+            let bytes = fs::read(path).map_err(|e| {
+                BlockchainError::Io(format!(
+                    "Failed to read node identity {}: {e}",
+                    path.display()
+                ))
+            })?;
+            return Ok(NodeIdentity {
+                key: deserialize(&bytes)?,
+            });
+        }
+
+        let identity = Self::generate()?;
+        Self::write_private(path, &serialize(&identity.key)?).map_err(|e| {
+            BlockchainError::Io(format!(
+                "Failed to write node identity {}: {e}",
+                path.display()
+            ))
+        })?;
+        Ok(identity)
+    }
+
+    // The file holds the private key, so only its owner may read it
+    fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        file.write_all(bytes)?;
+        file.sync_all()
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(self.key.get_public_key())
+    }
+
+    /// Wrap `pkg`, encoded in `format`, in an envelope signed now
+    pub fn sign(&self, pkg: &Package, format: WireFormat) -> Result<Package> {
+        self.sign_at(pkg, format, current_timestamp()?)
+    }
+
+    /// Wrap `pkg`, encoded in `format`, in an envelope signed at `timestamp` (ms since
+    /// the epoch)
+    pub fn sign_at(&self, pkg: &Package, format: WireFormat, timestamp: i64) -> Result<Package> {
+        let payload = format.encode_payload(pkg)?;
+        let signature = ecdsa_p256_sha256_sign_digest(
+            self.key.get_pkcs8(),
+            &SignedPackage::signed_bytes(format.id(), &payload, timestamp),
+        )?;
+        Ok(Package::Signed {
+            envelope: SignedPackage {
+                format: format.id(),
+                payload,
+                timestamp,
+                pub_key: self.key.get_public_key().to_vec(),
+                signature,
+            },
+        })
+    }
+}
+
+/// A package as sent by a node with an identity
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct SignedPackage {
+    /// The id of the format the payload is in, see `WireFormat::id`
+    pub format: u8,
+    /// The package
+    pub payload: Vec<u8>,
+    /// When it was signed (ms since the epoch)
+    pub timestamp: i64,
+    pub pub_key: Vec<u8>,
+    /// Covers the format, the payload and the timestamp, in that order
+    pub signature: Vec<u8>,
+}
+
+impl SignedPackage {
+    /// Check the signature and age as of `now`, returning the signer and the package
+    pub fn open(&self, now: i64) -> Result<(PeerId, Package)> {
+        let message = Self::signed_bytes(self.format, &self.payload, self.timestamp);
+        if !ecdsa_p256_sha256_sign_verify(&self.pub_key, &self.signature, &message) {
+            return Err(BlockchainError::Network(
+                "Signed package has an invalid signature".to_string(),
+            ));
+        }
+        let age = now.saturating_sub(self.timestamp).unsigned_abs();
+        if age > MAX_PACKAGE_AGE.as_millis() as u64 {
+            return Err(BlockchainError::Network(format!(
+                "Signed package is {}s away from the local clock",
+                age / 1000
+            )));
+        }
+
+        let pkg = WireFormat::from_id(self.format)?.decode_payload(&self.payload)?;
+        if matches!(pkg, Package::Signed { .. }) {
+            return Err(BlockchainError::Network(
+                "Signed package wraps another signed package".to_string(),
+            ));
+        }
+        Ok((PeerId::from_public_key(&self.pub_key), pkg))
+    }
+
+    fn signed_bytes(format: u8, payload: &[u8], timestamp: i64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + payload.len() + 8);
+        bytes.push(format);
+        bytes.extend_from_slice(payload);
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_blocks(addr_from: &str) -> Package {
+        Package::GetBlocks {
+            addr_from: addr_from.to_string(),
+        }
+    }
+
+    fn envelope(pkg: Package) -> SignedPackage {
+        match pkg {
+            Package::Signed { envelope } => envelope,
+            other => panic!("expected a signed package, got {}", other.kind()),
+        }
+    }
+
+    #[test]
+    fn test_signed_package_opens_to_its_signer() {
+        let identity = NodeIdentity::generate().unwrap();
+        let now = current_timestamp().unwrap();
+        for format in [WireFormat::Json, WireFormat::Bincode] {
+            let signed = envelope(
+                identity
+                    .sign_at(&get_blocks("127.0.0.1:2001"), format, now)
+                    .unwrap(),
+            );
+            assert_eq!(
+                signed.payload,
+                format
+                    .encode_payload(&get_blocks("127.0.0.1:2001"))
+                    .unwrap()
+            );
+
+            let (peer_id, pkg) = signed.open(now + 1_000).unwrap();
+            assert_eq!(peer_id, identity.peer_id());
+            assert!(
+                matches!(pkg, Package::GetBlocks { addr_from } if addr_from == "127.0.0.1:2001")
+            );
+        }
+        let peer_id = identity.peer_id();
+        assert_eq!(PeerId::parse(peer_id.as_str()).unwrap(), peer_id);
+        assert!(PeerId::parse("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_tampered_or_stale_packages_are_refused() {
+        let identity = NodeIdentity::generate().unwrap();
+        let now = current_timestamp().unwrap();
+        let signed = envelope(
+            identity
+                .sign_at(&get_blocks("127.0.0.1:2001"), WireFormat::Json, now)
+                .unwrap(),
+        );
+
+        let mut spoofed = signed.clone();
+        spoofed.payload = serde_json::to_vec(&get_blocks("10.0.0.1:2001")).unwrap();
+        assert!(spoofed.open(now).is_err());
+        let mut reformatted = signed.clone();
+        reformatted.format = WireFormat::Bincode.id();
+        assert!(reformatted.open(now).is_err());
+        let mut redated = signed.clone();
+        redated.timestamp += 1;
+        assert!(redated.open(now).is_err());
+        // Someone else's valid signature doesn't vouch for this key
+        let mut stolen = signed.clone();
+        stolen.pub_key = NodeIdentity::generate()
+            .unwrap()
+            .key
+            .get_public_key()
+            .to_vec();
+        assert!(stolen.open(now).is_err());
+
+        let max_age = MAX_PACKAGE_AGE.as_millis() as i64;
+        assert!(signed.open(now + max_age).is_ok());
+        assert!(signed.open(now + max_age + 1).is_err());
+        assert!(signed.open(now - max_age - 1).is_err());
+
+        // Nesting would let a replayed envelope hide inside a fresh one
+        let nested = envelope(
+            identity
+                .sign_at(&Package::Signed { envelope: signed }, WireFormat::Json, now)
+                .unwrap(),
+        );
+        assert!(nested.open(now).is_err());
+    }
+
+    #[test]
+    fn test_identity_survives_restarts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("node_identity.dat");
+
+        let created = NodeIdentity::load_or_create(&path).unwrap();
+        assert!(path.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = NodeIdentity::load_or_create(&path).unwrap();
+        assert_eq!(loaded.peer_id(), created.peer_id());
+        assert_ne!(
+            NodeIdentity::generate().unwrap().peer_id(),
+            created.peer_id()
+        );
+    }
+}
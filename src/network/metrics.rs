@@ -0,0 +1,297 @@
+//! Node metrics in the Prometheus text exposition format
+//!
+//! Counters are plain relaxed atomics in the process-wide `METRICS` registry, cheap enough
+//! to bump from the proof-of-work loop and from block validation, which don't see a node's
+//! context. A node started with `--metrics-addr` serves them over a minimal HTTP listener;
+//! gauges such as the peer count are refreshed right before each scrape.
+
+use crate::error::{BlockchainError, Result};
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Largest request head read from a scraper before answering anyway
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The registry every node in this process reports to
+pub static METRICS: Metrics = Metrics::new();
+
+/// A value that only goes up
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that is overwritten with the latest reading
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub const fn new() -> Gauge {
+        Gauge(AtomicU64::new(0))
+    }
+
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Everything a node reports to monitoring
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub blocks_accepted: Counter,
+    pub blocks_rejected: Counter,
+    pub txs_received: Counter,
+    pub txs_relayed: Counter,
+    pub pow_hashes_total: Counter,
+    pub chainstate_reindexes: Counter,
+    pub peers_connected: Gauge,
+    pub db_size_bytes: Gauge,
+    pub block_height: Gauge,
+    pub mempool_size: Gauge,
+}
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            blocks_accepted: Counter::new(),
+            blocks_rejected: Counter::new(),
+            txs_received: Counter::new(),
+            txs_relayed: Counter::new(),
+            pow_hashes_total: Counter::new(),
+            chainstate_reindexes: Counter::new(),
+            peers_connected: Gauge::new(),
+            db_size_bytes: Gauge::new(),
+            block_height: Gauge::new(),
+            mempool_size: Gauge::new(),
+        }
+    }
+
+    /// Render every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "architect_blocks_accepted_total",
+                "Blocks that passed validation and were stored",
+                &self.blocks_accepted,
+            ),
+            (
+                "architect_blocks_rejected_total",
+                "Blocks that failed validation",
+                &self.blocks_rejected,
+            ),
+            (
+                "architect_txs_received_total",
+                "Transactions received from peers",
+                &self.txs_received,
+            ),
+            (
+                "architect_txs_relayed_total",
+                "Transactions announced or sent to peers",
+                &self.txs_relayed,
+            ),
+            (
+                "architect_pow_hashes_total",
+                "Proof-of-work hashes computed",
+                &self.pow_hashes_total,
+            ),
+            (
+                "architect_chainstate_reindexes_total",
+                "Full rebuilds of the UTXO chainstate",
+                &self.chainstate_reindexes,
+            ),
+        ];
+        let gauges = [
+            (
+                "architect_peers_connected",
+                "Peers currently connected",
+                &self.peers_connected,
+            ),
+            (
+                "architect_db_size_bytes",
+                "Size of the blockchain database on disk",
+                &self.db_size_bytes,
+            ),
+            (
+                "architect_block_height",
+                "Height of the best chain tip",
+                &self.block_height,
+            ),
+            (
+                "architect_mempool_size",
+                "Transactions waiting in the memory pool",
+                &self.mempool_size,
+            ),
+        ];
+
+        let mut text = String::new();
+        for (name, help, counter) in counters {
+            Self::write_metric(&mut text, name, help, "counter", counter.get());
+        }
+        for (name, help, gauge) in gauges {
+            Self::write_metric(&mut text, name, help, "gauge", gauge.get());
+        }
+        text
+    }
+
+    fn write_metric(text: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+        // Writing to a String can't fail
+        let _ = writeln!(text, "# HELP {name} {help}");
+        let _ = writeln!(text, "# TYPE {name} {kind}");
+        let _ = writeln!(text, "{name} {value}");
+    }
+}
+
+/// Serve `metrics` over HTTP on `addr` from a background thread
+///
+/// `refresh` runs before every scrape to update gauges that are cheaper to read on demand
+/// than to track. Returns the bound address, which differs from `addr` when it asks for
+/// port 0.
+pub fn spawn_metrics_server<F>(
+    addr: &str,
+    metrics: &'static Metrics,
+    refresh: F,
+) -> Result<SocketAddr>
+where
+    F: Fn(&Metrics) + Send + 'static,
+{
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        BlockchainError::Network(format!("Failed to bind metrics listener to {addr}: {e}"))
+    })?;
+    let local_addr = listener.local_addr().map_err(|e| {
+        BlockchainError::Network(format!("Failed to read metrics listener address: {e}"))
+    })?;
+    info!("Serving metrics on http://{local_addr}/metrics");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    refresh(metrics);
+                    if let Err(e) = answer_scrape(stream, metrics) {
+                        warn!("Failed to answer metrics request: {e}");
+                    }
+                }
+                Err(e) => warn!("Error accepting metrics connection: {e}"),
+            }
+        }
+    });
+
+    Ok(local_addr)
+}
+
+/// Read the head of an HTTP request from `stream` and return its request line
+///
+/// Only the request line matters, but the whole head is read so the client doesn't see a
+/// reset for unread data when the connection closes.
+pub(crate) fn read_request_line(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&head);
+    Ok(request.lines().next().unwrap_or("").to_string())
+}
+
+fn answer_scrape(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+
+    let request_line = read_request_line(&mut stream)?;
+    let mut request_line = request_line.split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics" | "/")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A registry of its own, so counters bumped by other tests don't leak into the
+    // assertions
+    static TEST_METRICS: Metrics = Metrics::new();
+
+    fn fetch(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nAccept: text/plain\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_endpoint_serves_counters() {
+        TEST_METRICS.blocks_accepted.add(3);
+        TEST_METRICS.blocks_rejected.inc();
+        TEST_METRICS.txs_received.add(7);
+        TEST_METRICS.pow_hashes_total.add(4096);
+
+        let addr = spawn_metrics_server("127.0.0.1:0", &TEST_METRICS, |metrics| {
+            metrics.peers_connected.set(2);
+            metrics.block_height.set(11);
+        })
+        .unwrap();
+
+        let response = fetch(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE architect_blocks_accepted_total counter\n"));
+        assert!(response.contains("\narchitect_blocks_accepted_total 3\n"));
+        assert!(response.contains("\narchitect_blocks_rejected_total 1\n"));
+        assert!(response.contains("\narchitect_txs_received_total 7\n"));
+        assert!(response.contains("\narchitect_txs_relayed_total 0\n"));
+        assert!(response.contains("\narchitect_pow_hashes_total 4096\n"));
+        // Gauges come from the refresh hook
+        assert!(response.contains("# TYPE architect_peers_connected gauge\n"));
+        assert!(response.contains("\narchitect_peers_connected 2\n"));
+        assert!(response.contains("\narchitect_block_height 11\n"));
+
+        assert!(fetch(addr, "/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
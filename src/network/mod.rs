@@ -5,13 +5,50 @@
 //!
 //! Simplified to focus on blockchain essentials without unnecessary complexity.
 
+pub mod codec;
+pub mod connections;
+pub mod connectivity;
 pub mod dns_seeding;
+pub mod explorer;
+pub mod framing;
+pub mod identity;
+pub mod metrics;
+pub mod netmask;
 pub mod node;
+pub mod peer_info;
 pub mod server;
 pub mod simple_peer_manager;
+pub mod spv;
+pub mod status;
+pub mod sync;
+pub mod trace;
 
 pub use crate::storage::BlockInTransit;
+pub use codec::{
+    PackageReader, ReadError, ReceivedPackage, WireBytes, WireFormat, SUPPORTED_WIRE_FORMATS,
+};
+pub use connections::{ConnectionHandler, OutboundConnections, DEFAULT_MAX_OUTBOUND_CONNECTIONS};
+pub use connectivity::{ConnectivityCall, MockConnectivity, PeerConnectivity, TcpConnectivity};
 pub use dns_seeding::{DiscoveredPeer, DnsSeeder};
+pub use explorer::spawn_explorer_server;
+pub use framing::{MessageLimits, DEFAULT_MAX_INV_ITEMS, DEFAULT_MAX_MESSAGE_SIZE};
+pub use identity::{NodeIdentity, PeerId, SignedPackage};
+pub use metrics::{spawn_metrics_server, Metrics, METRICS};
+pub use netmask::Netmask;
 pub use node::{Node, Nodes};
-pub use server::{send_tx, Server, CENTRAL_NODE};
-pub use simple_peer_manager::SimplePeerManager;
+pub use peer_info::{PeerConnections, PeerInfo};
+pub use server::{
+    request_abandon, request_balance, request_ban, request_bans, request_block_template,
+    request_chain, request_disconnect, request_mempool_tx, request_message_trace,
+    request_node_status, request_peer_info, request_transaction_status, request_unban, send_tx,
+    send_tx_allowing_high_fee, submit_block_to, Server, CENTRAL_NODE, DEFAULT_MAX_PEERS,
+    TRANSACTION_THRESHOLD,
+};
+pub use simple_peer_manager::{
+    parse_ban_address, Ban, KnownPeer, Misbehavior, PeerKey, PeerPolicy, Penalties,
+    SimplePeerManager, OPERATOR_BAN_REASON,
+};
+pub use spv::{verify_merkle_block, VerifiedMerkleBlock};
+pub use status::NodeStatus;
+pub use sync::{SyncProgress, SyncState};
+pub use trace::{Direction, MessageTrace, TraceEntry, MESSAGE_TRACE_CAPACITY};
@@ -0,0 +1,191 @@
+//! IP address ranges in CIDR notation
+//!
+//! A `Netmask` is written `192.168.1.0/24`, or as a bare address for that address alone.
+//! Host bits below the prefix are ignored when parsing, so `10.1.2.3/8` is the same range
+//! as `10.0.0.0/8`. IPv4 addresses mapped into IPv6 (`::ffff:10.0.0.1`) match IPv4 ranges.
+
+use crate::error::{BlockchainError, Result};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of IP addresses: every address sharing the first `prefix_len` bits of `network`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Netmask {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Netmask {
+    /// The range of the first `prefix_len` bits of `addr`
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Netmask> {
+        let addr = addr.to_canonical();
+        let max_len = Self::max_prefix_len(addr);
+        if prefix_len > max_len {
+            return Err(BlockchainError::Network(format!(
+                "Prefix length {prefix_len} is longer than the {max_len} bits of {addr}"
+            )));
+        }
+        let network = match addr {
+            IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & Self::mask_v4(prefix_len)).into()),
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & Self::mask_v6(prefix_len)).into()),
+        };
+        Ok(Netmask {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// The range holding only `addr`
+    pub fn host(addr: IpAddr) -> Netmask {
+        let addr = addr.to_canonical();
+        Netmask {
+            network: addr,
+            prefix_len: Self::max_prefix_len(addr),
+        }
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                u32::from(ip) & Self::mask_v4(self.prefix_len) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                u128::from(ip) & Self::mask_v6(self.prefix_len) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix_len(addr: IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn mask_v4(prefix_len: u8) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0)
+    }
+
+    fn mask_v6(prefix_len: u8) -> u128 {
+        u128::MAX
+            .checked_shl(128 - u32::from(prefix_len))
+            .unwrap_or(0)
+    }
+}
+
+impl FromStr for Netmask {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || BlockchainError::Network(format!("Not an address or CIDR range: {s}"));
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => {
+                let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+                // Only plain digits, so "+8" or " 8" are refused like any other typo
+                if prefix_len.is_empty() || !prefix_len.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                let prefix_len = prefix_len.parse::<u8>().map_err(|_| invalid())?;
+                Netmask::new(addr, prefix_len).map_err(|_| invalid())
+            }
+            None => s
+                .parse::<IpAddr>()
+                .map(Netmask::host)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Netmask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+// Written out the way it is parsed, as config files list them
+impl Serialize for Netmask {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn netmask(s: &str) -> Netmask {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ranges_contain_their_addresses() {
+        let lan = netmask("192.168.1.0/24");
+        assert!(lan.contains(ip("192.168.1.0")));
+        assert!(lan.contains(ip("192.168.1.255")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        assert!(netmask("10.0.0.0/8").contains(ip("10.255.255.255")));
+        assert!(netmask("172.16.0.0/16").contains(ip("172.16.200.3")));
+        assert!(!netmask("172.16.0.0/16").contains(ip("172.17.0.1")));
+
+        // Host bits are dropped, and /0 takes every address of its family
+        assert_eq!(netmask("10.1.2.3/8"), netmask("10.0.0.0/8"));
+        assert_eq!(netmask("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert!(netmask("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(!netmask("0.0.0.0/0").contains(ip("::1")));
+
+        assert!(netmask("fd00::/8").contains(ip("fd12:3456::1")));
+        assert!(!netmask("fd00::/8").contains(ip("fe80::1")));
+        // A peer reaching a dual-stack socket over IPv4 shows up as a mapped address
+        assert!(lan.contains(ip("::ffff:192.168.1.7")));
+    }
+
+    #[test]
+    fn test_bare_addresses_and_slash_32_match_one_host() {
+        let host = netmask("192.168.1.20");
+        assert_eq!(host, netmask("192.168.1.20/32"));
+        assert_eq!(host.prefix_len(), 32);
+        assert!(host.contains(ip("192.168.1.20")));
+        assert!(!host.contains(ip("192.168.1.21")));
+
+        let broadcast = netmask("255.255.255.255/32");
+        assert!(broadcast.contains(ip("255.255.255.255")));
+        assert!(!broadcast.contains(ip("255.255.255.254")));
+        assert_eq!(netmask("::1").to_string(), "::1/128");
+    }
+
+    #[test]
+    fn test_invalid_ranges_are_refused() {
+        for invalid in [
+            "",
+            "not-an-ip",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0.0/+8",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "256.0.0.0/8",
+            "::/129",
+            "10.0.0.1:2001",
+        ] {
+            assert!(invalid.parse::<Netmask>().is_err(), "{invalid} parsed");
+        }
+    }
+}
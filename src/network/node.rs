@@ -1,29 +1,90 @@
-use std::net::SocketAddr;
-use std::sync::RwLock;
+//! The address book of peers a node knows about
+//!
+//! `Nodes` holds every peer address learned through DNS seeding or from the peers
+//! themselves, with what the node remembers about each one. `SimplePeerManager` keeps its
+//! known peers here and picks whom to connect to from it. The book has a fixed capacity;
+//! when it is full, a new address pushes out the node trusted least, which is the one with
+//! the highest misbehavior score and, among equals, the one not seen for longest.
 
-#[derive(Clone)]
+use crate::network::simple_peer_manager::KnownPeer;
+use rand::seq::SliceRandom;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Most addresses an address book keeps by default
+pub const MAX_KNOWN_NODES: usize = 1_000;
+/// No services are known yet, e.g. for an address that only came from DNS seeding
+pub const NODE_NONE: u64 = 0;
+/// A full node that serves blocks and relays transactions
+pub const NODE_NETWORK: u64 = 1;
+
+/// A peer address and what is known about it
+#[derive(Debug, Clone, PartialEq)]
 pub struct Node {
-    addr: String,
+    addr: SocketAddr,
+    /// Service bits the peer is known to offer
+    services: u64,
+    /// Misbehavior reported for the peer's IP address while it was known
+    misbehavior_score: u32,
+    /// Connection history, which is what gets persisted
+    peer: KnownPeer,
 }
 
 impl Node {
-    fn new(addr: String) -> Node {
-        Node { addr }
+    pub fn new(addr: SocketAddr, peer: KnownPeer) -> Node {
+        Node {
+            addr,
+            services: NODE_NONE,
+            misbehavior_score: 0,
+            peer,
+        }
     }
 
-    pub fn get_addr(&self) -> String {
-        self.addr.clone()
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
     }
 
-    pub fn parse_socket_addr(&self) -> SocketAddr {
-        self.addr
-            .parse()
-            .expect("Failed to parse node address - address should be valid")
+    /// When the peer was learned about or last answered (ms since the epoch)
+    pub fn get_last_seen(&self) -> i64 {
+        self.peer.last_seen
+    }
+
+    pub fn get_services(&self) -> u64 {
+        self.services
+    }
+
+    pub fn get_misbehavior_score(&self) -> u32 {
+        self.misbehavior_score
+    }
+
+    pub fn get_peer(&self) -> &KnownPeer {
+        &self.peer
+    }
+
+    pub(crate) fn add_services(&mut self, services: u64) {
+        self.services |= services;
+    }
+
+    pub(crate) fn peer_mut(&mut self) -> &mut KnownPeer {
+        &mut self.peer
+    }
+
+    // The node to evict first sorts last
+    fn eviction_key(&self) -> (u32, Reverse<i64>, SocketAddr) {
+        (
+            self.misbehavior_score,
+            Reverse(self.peer.last_seen),
+            self.addr,
+        )
     }
 }
 
+/// A bounded, thread-safe set of known peers keyed by the address they listen on
 pub struct Nodes {
-    inner: RwLock<Vec<Node>>,
+    inner: RwLock<HashMap<SocketAddr, Node>>,
+    capacity: usize,
 }
 
 impl Default for Nodes {
@@ -34,71 +95,221 @@ impl Default for Nodes {
 
 impl Nodes {
     pub fn new() -> Nodes {
+        Self::with_capacity(MAX_KNOWN_NODES)
+    }
+
+    /// An address book holding at most `capacity` nodes (at least one)
+    pub fn with_capacity(capacity: usize) -> Nodes {
         Nodes {
-            inner: RwLock::new(vec![]),
+            inner: RwLock::new(HashMap::new()),
+            capacity: capacity.max(1),
         }
     }
 
-    pub fn add_node(&self, addr: String) {
-        let mut inner = self
-            .inner
-            .write()
-            .expect("Failed to acquire write lock on nodes - this should never happen");
-        if !inner.iter().any(|x| x.get_addr().eq(addr.as_str())) {
-            inner.push(Node::new(addr));
-        }
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
     }
 
-    pub fn evict_node(&self, addr: &str) {
-        let mut inner = self
-            .inner
-            .write()
-            .expect("Failed to acquire write lock on nodes - this should never happen");
-        if let Some(idx) = inner.iter().position(|x| x.get_addr().eq(addr)) {
-            inner.remove(idx);
+    /// Remember `addr` as first seen at `last_seen`, returning `false` if it was known
+    ///
+    /// A known node keeps its history. A new one may evict another node when the book is
+    /// full, but is never evicted itself on the way in.
+    pub fn add_node(&self, addr: SocketAddr, last_seen: i64) -> bool {
+        let mut inner = self.write();
+        if inner.contains_key(&addr) {
+            return false;
         }
+        Self::make_room(&mut inner, self.capacity);
+        inner.insert(addr, Node::new(addr, KnownPeer::new(last_seen)));
+        true
     }
 
-    pub fn first(&self) -> Option<Node> {
-        let inner = self
-            .inner
-            .read()
-            .expect("Failed to acquire read lock on nodes - this should never happen");
-        if let Some(node) = inner.first() {
-            return Some(node.clone());
+    /// Store `node`, replacing whatever was known about its address
+    pub fn insert(&self, node: Node) {
+        let mut inner = self.write();
+        if !inner.contains_key(&node.addr) {
+            Self::make_room(&mut inner, self.capacity);
         }
-        None
+        inner.insert(node.addr, node);
+    }
+
+    /// Forget `addr`, returning what was known about it
+    pub fn evict(&self, addr: &SocketAddr) -> Option<Node> {
+        self.write().remove(addr)
+    }
+
+    pub fn get_node(&self, addr: &SocketAddr) -> Option<Node> {
+        self.read().get(addr).cloned()
     }
 
     pub fn get_nodes(&self) -> Vec<Node> {
-        self.inner
-            .read()
-            .expect("Failed to acquire read lock on nodes - this should never happen")
-            .to_vec()
+        self.read().values().cloned().collect()
+    }
+
+    /// Up to `n` known nodes picked uniformly at random
+    pub fn get_random_subset(&self, n: usize) -> Vec<Node> {
+        let inner = self.read();
+        let nodes: Vec<&Node> = inner.values().collect();
+        nodes
+            .choose_multiple(&mut rand::thread_rng(), n)
+            .map(|node| (*node).clone())
+            .collect()
     }
 
     pub fn len(&self) -> usize {
-        self.inner
-            .read()
-            .expect("Failed to acquire read lock on nodes - this should never happen")
-            .len()
+        self.read().len()
     }
 
     pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    pub fn node_is_known(&self, addr: &SocketAddr) -> bool {
+        self.read().contains_key(addr)
+    }
+
+    /// Add `score` to every known node at `ip`, since misbehavior is tracked per host
+    pub fn add_misbehavior(&self, ip: IpAddr, score: u32) {
+        for node in self.write().values_mut() {
+            if node.addr.ip() == ip {
+                node.misbehavior_score = node.misbehavior_score.saturating_add(score);
+            }
+        }
+    }
+
+    /// Change the node at `addr`, adding it as first seen at `last_seen` if it is new
+    pub(crate) fn update<R>(
+        &self,
+        addr: SocketAddr,
+        last_seen: i64,
+        change: impl FnOnce(&mut Node) -> R,
+    ) -> R {
+        let mut inner = self.write();
+        if !inner.contains_key(&addr) {
+            Self::make_room(&mut inner, self.capacity);
+        }
+        let node = inner
+            .entry(addr)
+            .or_insert_with(|| Node::new(addr, KnownPeer::new(last_seen)));
+        change(node)
+    }
+
+    fn make_room(inner: &mut HashMap<SocketAddr, Node>, capacity: usize) {
+        while inner.len() >= capacity {
+            let Some(worst) = inner
+                .values()
+                .max_by_key(|node| node.eviction_key())
+                .map(|node| node.addr)
+            else {
+                return;
+            };
+            inner.remove(&worst);
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<SocketAddr, Node>> {
         self.inner
             .read()
             .expect("Failed to acquire read lock on nodes - this should never happen")
-            .is_empty()
     }
 
-    pub fn node_is_known(&self, addr: &str) -> bool {
-        let inner = self
-            .inner
-            .read()
-            .expect("Failed to acquire read lock on nodes - this should never happen");
-        if inner.iter().any(|x| x.get_addr().eq(addr)) {
-            return true;
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<SocketAddr, Node>> {
+        self.inner
+            .write()
+            .expect("Failed to acquire write lock on nodes - this should never happen")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn addr(last_octet: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last_octet], 2001))
+    }
+
+    #[test]
+    fn test_eviction_prefers_misbehaving_then_oldest() {
+        let nodes = Nodes::with_capacity(3);
+        assert!(nodes.add_node(addr(1), 100));
+        assert!(nodes.add_node(addr(2), 300));
+        assert!(nodes.add_node(addr(3), 200));
+        assert!(!nodes.add_node(addr(1), 400));
+
+        // Full: the node seen longest ago makes room
+        assert!(nodes.add_node(addr(4), 50));
+        assert!(!nodes.node_is_known(&addr(1)));
+        assert_eq!(nodes.len(), 3);
+
+        // Misbehavior outweighs age, even for the node seen most recently
+        nodes.add_misbehavior(addr(2).ip(), 10);
+        assert_eq!(
+            nodes.get_node(&addr(2)).unwrap().get_misbehavior_score(),
+            10
+        );
+        nodes.add_node(addr(5), 0);
+        assert!(!nodes.node_is_known(&addr(2)));
+
+        // Then age again, and the newcomer itself is never the one evicted
+        nodes.add_node(addr(6), 0);
+        let known: HashSet<SocketAddr> = nodes.get_nodes().iter().map(Node::get_addr).collect();
+        assert_eq!(known, HashSet::from([addr(3), addr(4), addr(6)]));
+
+        assert_eq!(nodes.evict(&addr(3)).unwrap().get_last_seen(), 200);
+        assert!(nodes.evict(&addr(3)).is_none());
+        assert_eq!(nodes.len(), 2);
+    }
+
+    #[test]
+    fn test_random_subset_samples_distinct_nodes() {
+        let nodes = Nodes::new();
+        for last_octet in 1..=20 {
+            nodes.add_node(addr(last_octet), 0);
+        }
+
+        let mut seen = HashSet::new();
+        for _ in 0..50 {
+            let subset = nodes.get_random_subset(5);
+            let distinct: HashSet<SocketAddr> = subset.iter().map(Node::get_addr).collect();
+            assert_eq!(subset.len(), 5);
+            assert_eq!(distinct.len(), 5);
+            seen.extend(distinct);
+        }
+        // 250 draws out of 20 nodes reach all of them unless sampling is stuck
+        assert_eq!(seen.len(), 20);
+
+        assert_eq!(nodes.get_random_subset(100).len(), 20);
+        assert!(Nodes::new().get_random_subset(3).is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_access_stays_within_capacity() {
+        let nodes = Arc::new(Nodes::with_capacity(64));
+        let handles: Vec<_> = (0..8u16)
+            .map(|worker| {
+                let nodes = Arc::clone(&nodes);
+                thread::spawn(move || {
+                    for i in 0..100u16 {
+                        let addr = SocketAddr::from(([10, 0, 0, 1], worker * 1_000 + i));
+                        nodes.add_node(addr, i64::from(i));
+                        nodes.update(addr, 0, |node| node.add_services(NODE_NETWORK));
+                        assert!(nodes.get_random_subset(4).len() <= 4);
+                        assert!(nodes.len() <= 64);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
         }
-        false
+
+        assert_eq!(nodes.len(), 64);
+        assert!(nodes
+            .get_nodes()
+            .iter()
+            .all(|node| node.get_services() == NODE_NETWORK));
     }
 }
@@ -0,0 +1,387 @@
+//! The connections a running node has open, and what went over each of them
+//!
+//! Every connection `Server::handle_connection` reads gets an entry while it lasts: the
+//! ones peers open to this node, and the ones this node's `OutboundConnections` pool opens
+//! and keeps for later packages. A pooled connection is entered as soon as its handshake is
+//! written, so the version message counts too. Bytes are counted by the codec, see
+//! `WireBytes`, packages by type as the server reads and writes them.
+//!
+//! Entries are keyed by the other end's socket address, like the pool's, and carry an id so
+//! the reader of a replaced connection can't remove its successor's entry. `getpeerinfo`
+//! fetches them from a running node along with each peer's misbehavior score;
+//! `disconnectpeer` shuts a connection's stream down, which ends its reader.
+
+use crate::error::{BlockchainError, Result};
+use crate::network::codec::WireBytes;
+use crate::network::simple_peer_manager::{PeerKey, SimplePeerManager};
+use crate::network::trace::Direction;
+use crate::utils::current_timestamp;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// One open connection as `getpeerinfo` shows it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct PeerInfo {
+    /// The other end of the connection
+    pub address: String,
+    pub direction: Direction,
+    /// The connection is one of this node's pool, kept open for later packages; any other
+    /// lasts only as long as the peer keeps it open
+    pub pooled: bool,
+    /// Where the peer listens, once its version message said
+    pub listen_address: Option<String>,
+    pub version: Option<usize>,
+    /// The height the peer's version message advertised
+    pub best_height: Option<usize>,
+    /// Milliseconds since the epoch
+    pub connected_since: i64,
+    /// When a package last went either way, in milliseconds since the epoch
+    pub last_message: Option<i64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Packages by type, e.g. `Inv`
+    pub messages_sent: BTreeMap<String, u64>,
+    pub messages_received: BTreeMap<String, u64>,
+    /// Of the key or address the peer's packages are charged to
+    pub misbehavior_score: u32,
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn counts(messages: &BTreeMap<String, u64>) -> String {
+            if messages.is_empty() {
+                return "none".to_string();
+            }
+            messages
+                .iter()
+                .map(|(kind, count)| format!("{kind}={count}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+
+        let direction = match self.direction {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        };
+        let kept = if self.pooled { "pooled" } else { "ephemeral" };
+        writeln!(f, "{} ({direction}, {kept})", self.address)?;
+        if let Some(listen_address) = &self.listen_address {
+            writeln!(f, "  Listening on:      {listen_address}")?;
+        }
+        match (self.version, self.best_height) {
+            (Some(version), Some(height)) => {
+                writeln!(f, "  Version:           {version} at height {height}")?
+            }
+            _ => writeln!(f, "  Version:           not sent")?,
+        }
+        writeln!(f, "  Connected since:   {}", self.connected_since)?;
+        match self.last_message {
+            Some(last_message) => writeln!(f, "  Last message:      {last_message}")?,
+            None => writeln!(f, "  Last message:      none")?,
+        }
+        writeln!(
+            f,
+            "  Bytes:             {} sent, {} received",
+            self.bytes_sent, self.bytes_received
+        )?;
+        writeln!(f, "  Sent:              {}", counts(&self.messages_sent))?;
+        writeln!(
+            f,
+            "  Received:          {}",
+            counts(&self.messages_received)
+        )?;
+        writeln!(f, "  Misbehavior score: {}", self.misbehavior_score)
+    }
+}
+
+struct LiveConnection {
+    id: u64,
+    direction: Direction,
+    pooled: bool,
+    /// A clone of the stream its reader reads, to shut down; `None` until the reader starts
+    stream: Option<TcpStream>,
+    bytes: Arc<WireBytes>,
+    /// Who the last package read was charged to
+    sender: PeerKey,
+    listen_address: Option<String>,
+    version: Option<usize>,
+    best_height: Option<usize>,
+    connected_since: i64,
+    last_message: Option<i64>,
+    messages_sent: BTreeMap<String, u64>,
+    messages_received: BTreeMap<String, u64>,
+}
+
+/// The connections a node has open, see the module docs
+#[derive(Default)]
+pub struct PeerConnections {
+    connections: Mutex<HashMap<SocketAddr, LiveConnection>>,
+    opened: AtomicU64,
+}
+
+impl PeerConnections {
+    pub fn new() -> PeerConnections {
+        Self::default()
+    }
+
+    /// Track the connection with `addr` whose reader reads `stream`, returning its id
+    ///
+    /// An entry for `addr` still waiting for its stream, made by a handshake, is taken over
+    /// with what it counted so far; any other one belongs to a connection this one replaces.
+    /// Without a stream, as for a handshake, an existing entry is left as it is.
+    pub fn open(
+        &self,
+        addr: SocketAddr,
+        direction: Direction,
+        pooled: bool,
+        stream: Option<TcpStream>,
+    ) -> u64 {
+        let mut connections = self.lock();
+        if let Some(connection) = connections.get_mut(&addr) {
+            match (&stream, &connection.stream) {
+                (None, _) => return connection.id,
+                (Some(_), None) => {
+                    connection.stream = stream;
+                    return connection.id;
+                }
+                (Some(_), Some(_)) => {}
+            }
+        }
+        let id = self.opened.fetch_add(1, Ordering::Relaxed);
+        connections.insert(
+            addr,
+            LiveConnection {
+                id,
+                direction,
+                pooled,
+                stream,
+                bytes: Arc::new(WireBytes::default()),
+                sender: PeerKey::Ip(addr.ip()),
+                listen_address: None,
+                version: None,
+                best_height: None,
+                connected_since: current_timestamp().unwrap_or_default(),
+                last_message: None,
+                messages_sent: BTreeMap::new(),
+                messages_received: BTreeMap::new(),
+            },
+        );
+        id
+    }
+
+    /// Stop tracking the connection `id` with `addr`, unless it was replaced already
+    pub fn close(&self, addr: SocketAddr, id: u64) {
+        let mut connections = self.lock();
+        if connections.get(&addr).is_some_and(|c| c.id == id) {
+            connections.remove(&addr);
+        }
+    }
+
+    /// Where the codec counts the bytes of the connection with `addr`; a counter nobody
+    /// reads when there is no such connection
+    pub fn wire_bytes(&self, addr: SocketAddr) -> Arc<WireBytes> {
+        self.lock()
+            .get(&addr)
+            .map(|connection| Arc::clone(&connection.bytes))
+            .unwrap_or_default()
+    }
+
+    /// Count a `kind` package read from `addr` and charged to `sender`
+    pub fn record_received(&self, addr: SocketAddr, kind: &str, sender: &PeerKey) {
+        if let Some(connection) = self.lock().get_mut(&addr) {
+            *connection
+                .messages_received
+                .entry(kind.to_string())
+                .or_default() += 1;
+            connection.sender = sender.clone();
+            connection.last_message = current_timestamp().ok();
+        }
+    }
+
+    /// Count a `kind` package written to `addr`
+    pub fn record_sent(&self, addr: SocketAddr, kind: &str) {
+        if let Some(connection) = self.lock().get_mut(&addr) {
+            *connection
+                .messages_sent
+                .entry(kind.to_string())
+                .or_default() += 1;
+            connection.last_message = current_timestamp().ok();
+        }
+    }
+
+    /// Remember what the version message read from `addr` said
+    pub fn record_version(
+        &self,
+        addr: SocketAddr,
+        listen_address: &str,
+        version: usize,
+        best_height: usize,
+    ) {
+        if let Some(connection) = self.lock().get_mut(&addr) {
+            connection.listen_address = Some(listen_address.to_string());
+            connection.version = Some(version);
+            connection.best_height = Some(best_height);
+        }
+    }
+
+    /// How many connections are open
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every open connection, by address, with the misbehavior score `peer_manager` keeps
+    pub fn peer_info(&self, peer_manager: &SimplePeerManager) -> Result<Vec<PeerInfo>> {
+        let connections = self.lock();
+        let mut peers = Vec::with_capacity(connections.len());
+        for (addr, connection) in connections.iter() {
+            peers.push(PeerInfo {
+                address: addr.to_string(),
+                direction: connection.direction,
+                pooled: connection.pooled,
+                listen_address: connection.listen_address.clone(),
+                version: connection.version,
+                best_height: connection.best_height,
+                connected_since: connection.connected_since,
+                last_message: connection.last_message,
+                bytes_sent: connection.bytes.sent(),
+                bytes_received: connection.bytes.received(),
+                messages_sent: connection.messages_sent.clone(),
+                messages_received: connection.messages_received.clone(),
+                misbehavior_score: peer_manager.peer_score(&connection.sender)?,
+            });
+        }
+        peers.sort_by(|a, b| a.address.cmp(&b.address));
+        Ok(peers)
+    }
+
+    /// Close every connection with `address` and stop tracking it, returning the addresses
+    /// of the connections closed
+    ///
+    /// An address with a port matches the connection's own or the one its peer listens on;
+    /// an IP address alone matches every connection from that host.
+    pub fn disconnect(&self, address: &str) -> Result<Vec<SocketAddr>> {
+        let socket = address.parse::<SocketAddr>().ok();
+        let host = match socket {
+            Some(socket) => socket.ip(),
+            None => address
+                .parse::<IpAddr>()
+                .map_err(|_| BlockchainError::Network(format!("Not a peer address: {address}")))?,
+        };
+        let matches = |addr: &SocketAddr, connection: &LiveConnection| match socket {
+            Some(socket) => {
+                *addr == socket || connection.listen_address.as_deref() == Some(address)
+            }
+            None => addr.ip() == host,
+        };
+
+        let mut connections = self.lock();
+        let mut closed: Vec<SocketAddr> = connections
+            .iter()
+            .filter(|(addr, connection)| matches(addr, connection))
+            .map(|(addr, _)| *addr)
+            .collect();
+        closed.sort();
+        for addr in &closed {
+            if let Some(connection) = connections.remove(addr) {
+                if let Some(stream) = connection.stream {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                info!("Disconnected {addr} on the operator's request");
+            }
+        }
+        Ok(closed)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, LiveConnection>> {
+        // Entries are only changed under the lock field by field, so a poisoned table is
+        // still consistent
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::codec::WireFormat;
+    use crate::network::server::Package;
+    use std::net::TcpListener;
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_handshake_entry_is_taken_over_and_replacements_survive_old_readers() {
+        let connections = PeerConnections::new();
+        let addr: SocketAddr = "10.0.0.5:2001".parse().unwrap();
+
+        // The handshake is counted before the pool's reader starts
+        let id = connections.open(addr, Direction::Outbound, true, None);
+        let ping = Package::GetBlocks {
+            addr_from: "127.0.0.1:2001".to_string(),
+        };
+        let size = WireFormat::Json
+            .encode_counted(&ping, &connections.wire_bytes(addr))
+            .unwrap()
+            .len() as u64;
+        connections.record_sent(addr, ping.kind());
+
+        let (first, _peer) = connected_pair();
+        assert_eq!(
+            connections.open(addr, Direction::Outbound, true, Some(first)),
+            id
+        );
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let peers = connections.peer_info(&peer_manager).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].bytes_sent, size);
+        assert_eq!(peers[0].messages_sent["GetBlocks"], 1);
+        assert!(peers[0].last_message.is_some());
+
+        // A replacing connection starts over, and the old reader ending leaves it alone
+        let (second, _peer) = connected_pair();
+        let replaced = connections.open(addr, Direction::Outbound, true, Some(second));
+        assert_ne!(replaced, id);
+        connections.close(addr, id);
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections.wire_bytes(addr).sent(), 0);
+        connections.close(addr, replaced);
+        assert!(connections.is_empty());
+
+        // Nothing is counted for connections that aren't tracked
+        connections.record_sent(addr, "Inv");
+        assert!(connections.is_empty());
+    }
+
+    #[test]
+    fn test_disconnect_matches_ports_listen_addresses_and_hosts() {
+        let connections = PeerConnections::new();
+        let inbound: SocketAddr = "10.0.0.7:51000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.7:51001".parse().unwrap();
+        let elsewhere: SocketAddr = "10.0.0.8:51000".parse().unwrap();
+        for addr in [inbound, other, elsewhere] {
+            connections.open(addr, Direction::Inbound, false, None);
+        }
+        connections.record_version(inbound, "10.0.0.7:2001", 1, 12);
+
+        assert!(connections.disconnect("peer").is_err());
+        assert_eq!(connections.disconnect("10.0.0.9:2001").unwrap(), []);
+        assert_eq!(connections.disconnect("10.0.0.7:2001").unwrap(), [inbound]);
+        assert_eq!(connections.disconnect("10.0.0.7").unwrap(), [other]);
+        assert_eq!(connections.len(), 1);
+    }
+}
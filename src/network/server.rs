@@ -1,23 +1,69 @@
 use crate::config::GLOBAL_CONFIG;
-use crate::core::{Block, Blockchain, Transaction};
+use crate::core::{
+    Block, BlockAddResult, BlockAssembler, BlockHeader, BlockSummarizer, BlockSummary,
+    BlockTemplate, Blockchain, EvictionReason, MiningHandle, Network, NetworkMagic, NodeContext,
+    TXOutput, Transaction, TransactionStatus,
+};
 use crate::error::{BlockchainError, Result};
-use crate::network::SimplePeerManager;
-use crate::storage::{BlockInTransit, MemoryPool, UTXOSet};
+use crate::network::codec::{PackageReader, ReadError, ReceivedPackage, MAX_WIRE_FORMATS};
+use crate::network::explorer::spawn_explorer_server;
+use crate::network::framing::MessageLimits;
+use crate::network::identity::{PeerId, SignedPackage};
+use crate::network::metrics::{spawn_metrics_server, METRICS};
+use crate::network::peer_info::PeerInfo;
+use crate::network::simple_peer_manager::{
+    parse_ban_address, Ban, Misbehavior, PeerKey, OPERATOR_BAN_REASON,
+};
+use crate::network::spv::{build_merkle_block, verify_merkle_block};
+use crate::network::trace::{Direction, TraceEntry};
+use crate::network::{
+    ConnectionHandler, DnsSeeder, NodeStatus, SimplePeerManager, SyncState, WireFormat,
+    SUPPORTED_WIRE_FORMATS,
+};
+use crate::storage::{is_chainstate_inconsistent, AddressBalance, UTXOSet};
+use crate::utils::bloom::{MAX_FILTER_SIZE, MAX_HASH_FUNCS};
+use crate::utils::{current_timestamp, BloomFilter, FORMAT_VERSION};
+use crate::wallet::address_pub_key_hash;
 use data_encoding::HEXLOWER;
 use log::{error, info, warn};
-use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use std::io::{BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::Arc;
+use std::io::Write;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const NODE_VERSION: usize = 1;
 pub const CENTRAL_NODE: &str = "127.0.0.1:2001";
+/// Pending transactions that start a block, unless the node's config sets its own
 pub const TRANSACTION_THRESHOLD: usize = 10;
+/// Peers connected at once, unless the node's config sets its own limit
+pub const DEFAULT_MAX_PEERS: usize = 8;
 const TCP_WRITE_TIMEOUT: u64 = 5000;
+/// Time between peer discovery rounds once enough peers are known
+const PEER_DISCOVERY_INTERVAL: Duration = Duration::from_secs(300);
+/// First wait before asking the DNS seeds again when too few peers are known
+const DNS_RETRY_DELAY: Duration = Duration::from_secs(15);
+/// Time between saves of the memory pool while the node runs
+const MEMPOOL_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// How often a sync-only node checks whether it is done
+const SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a sync-only node waits for any peer to tell its height before giving up
+const SYNC_PEER_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most pending transactions announced to a peer when it says hello, best fee rates first
+pub const MEMPOOL_ANNOUNCE_LIMIT: usize = 500;
+/// Most block summaries one `Chain` reply carries; `request_chain` pages through longer
+/// ranges
+pub const MAX_CHAIN_SUMMARIES: usize = 100;
+/// Most connections one `Peers` reply describes, in address order
+pub const MAX_PEER_INFOS: usize = 1_000;
+/// How long a peer has to send a requested block before it is asked of another peer
+pub const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often block downloads are checked for stalls
+const DOWNLOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often a thread waiting out an interval checks whether the node stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Simplified server for blockchain P2P networking
 pub struct Server {
@@ -25,22 +71,107 @@ pub struct Server {
     blockchain: Blockchain,
     /// Simple peer manager
     peer_manager: Arc<SimplePeerManager>,
+    /// Size limits applied to incoming packages
+    message_limits: MessageLimits,
+    /// Memory pool, fee calculator and config of this node
+    context: Arc<NodeContext>,
+    /// Where to serve Prometheus metrics, if anywhere
+    metrics_addr: Option<String>,
+    /// Where to serve the JSON block explorer, if anywhere
+    explorer_addr: Option<String>,
+    /// Stop once synced and settled for this long, see `with_sync_only`
+    sync_only: Option<Duration>,
+    /// The node a new node introduces itself to, `CENTRAL_NODE` unless set
+    seed_node: String,
+    stop_handle: StopHandle,
+    /// Where `run` learns that it should stop, and with what outcome
+    stop: Mutex<mpsc::Receiver<Result<()>>>,
 }
 
-/// Global memory pool
-static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(MemoryPool::new);
+/// Stops a running `Server` from another thread, see `Server::stop_handle`
+#[derive(Clone)]
+pub struct StopHandle {
+    sender: mpsc::Sender<Result<()>>,
+    stopped: Arc<AtomicBool>,
+    /// Where the accept loop listens, once it does
+    wake_addr: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl StopHandle {
+    fn new(sender: mpsc::Sender<Result<()>>) -> StopHandle {
+        StopHandle {
+            sender,
+            stopped: Arc::new(AtomicBool::new(false)),
+            wake_addr: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Make `run` save the node's state and return `Ok`
+    ///
+    /// The background threads of the node finish their current round and exit.
+    pub fn stop(&self) {
+        self.stop_with(Ok(()));
+    }
+
+    /// Whether `stop` was called, or the node stopped by itself
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for `duration` or until the node stops, returning whether it stopped
+    fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.is_stopped() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            thread::sleep(STOP_CHECK_INTERVAL.min(deadline - now));
+        }
+    }
+
+    // The accept loop only looks at the channel between connections, so I wake it with one
+    // of my own
+    fn stop_with(&self, outcome: Result<()>) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if self.sender.send(outcome).is_ok() {
+            let wake_addr = *self
+                .wake_addr
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(wake_addr) = wake_addr {
+                let _ = TcpStream::connect(wake_addr);
+            }
+        }
+    }
 
-/// Global blocks in transit
-static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(BlockInTransit::new);
+    fn set_wake_addr(&self, listen_addr: SocketAddr) {
+        let wake_addr = match listen_addr.ip() {
+            ip if ip.is_unspecified() => {
+                SocketAddr::new(IpAddr::from([127, 0, 0, 1]), listen_addr.port())
+            }
+            _ => listen_addr,
+        };
+        *self
+            .wake_addr
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(wake_addr);
+    }
+}
 
 /// P2P message types
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub enum OpType {
     Tx,
     Block,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub enum Package {
     Block {
         addr_from: String,
@@ -62,42 +193,717 @@ pub enum Package {
     Tx {
         addr_from: String,
         transaction: Vec<u8>,
+        /// The sender insists on a fee above `MAX_TRANSACTION_FEE`; only taken from the
+        /// node's own host, and never set on relays
+        #[serde(default)]
+        allow_high_fee: bool,
+        /// The transaction is already in a block, sent in answer to GetData after it left
+        /// the memory pool; older peers only ever send pending ones
+        #[serde(default)]
+        confirmed: bool,
     },
     Version {
         addr_from: String,
         version: usize,
         best_height: usize,
+        /// Total work of the sender's chain; peers that predate it send none
+        #[serde(default)]
+        chain_work: u128,
+        /// Newest block and transaction format the sender reads, see `FORMAT_VERSION`;
+        /// peers that predate formats send none and only read the unversioned layout
+        #[serde(default)]
+        format_version: u8,
+        /// Hash of the sender's genesis block; peers that predate it send none
+        #[serde(default)]
+        genesis_hash: String,
+        /// The sender's clock when it sent this (ms since the epoch); peers that predate it
+        /// send none
+        #[serde(default)]
+        timestamp: i64,
+        /// Encodings the sender reads besides JSON, see `codec`; peers that predate them
+        /// send none
+        #[serde(default)]
+        wire_formats: Vec<u8>,
+        /// The sender's network, see `core::network`; peers that predate it send none
+        #[serde(default)]
+        network_magic: Option<NetworkMagic>,
+    },
+    /// Ask a node for one of its pending transactions; answered on the same connection
+    GetMempoolTx {
+        addr_from: String,
+        txid: Vec<u8>,
+    },
+    /// Reply to `GetMempoolTx`, `None` when the transaction isn't in the pool
+    MempoolTx {
+        addr_from: String,
+        transaction: Option<Vec<u8>>,
+    },
+    /// Ask a running node for its status; answered on the same connection
+    GetStatus {
+        addr_from: String,
+    },
+    /// Reply to `GetStatus`
+    Status {
+        addr_from: String,
+        status: NodeStatus,
+    },
+    /// Sent by a light client: answer its block requests with `MerkleBlock` from now on
+    FilterLoad {
+        addr_from: String,
+        filter: Vec<u8>,
+        hash_funcs: u32,
+        tweak: u32,
+    },
+    /// A block header with Merkle proofs for the transactions matching a loaded filter
+    MerkleBlock {
+        addr_from: String,
+        header: Vec<u8>,
+        proofs: Vec<u8>,
+    },
+    /// Ask a node for work paying `miner_address`; answered on the same connection
+    GetBlockTemplate {
+        addr_from: String,
+        miner_address: String,
+    },
+    /// Reply to `GetBlockTemplate`, or why no template could be built
+    BlockTemplate {
+        addr_from: String,
+        template: std::result::Result<BlockTemplate, String>,
+    },
+    /// Hand a node a block solved from one of its templates; answered on the same connection
+    SubmitBlock {
+        addr_from: String,
+        block: Vec<u8>,
+    },
+    /// Reply to `SubmitBlock`, or why the block was refused
+    SubmitBlockResult {
+        addr_from: String,
+        result: std::result::Result<(), String>,
+    },
+    /// Ask a running node for the packages it sent and received last; answered on the
+    /// same connection
+    GetMessageTrace {
+        addr_from: String,
+    },
+    /// Reply to `GetMessageTrace`, oldest first
+    MessageTrace {
+        addr_from: String,
+        entries: Vec<TraceEntry>,
+    },
+    /// Ask a running node for an address's balance counting its memory pool; answered on
+    /// the same connection
+    GetBalance {
+        addr_from: String,
+        address: String,
+    },
+    /// Reply to `GetBalance`, or why the balance couldn't be worked out
+    Balance {
+        addr_from: String,
+        balance: std::result::Result<AddressBalance, String>,
+    },
+    /// Have a running node refuse an address for `duration_secs`; only taken from the
+    /// node's own host and answered with `Bans`
+    BanPeer {
+        addr_from: String,
+        address: String,
+        duration_secs: u64,
+    },
+    /// Have a running node lift a ban; only taken from the node's own host and answered
+    /// with `Bans`
+    UnbanPeer {
+        addr_from: String,
+        address: String,
+    },
+    /// Ask a running node for its bans; only taken from the node's own host
+    ListBans {
+        addr_from: String,
+    },
+    /// Reply to `BanPeer`, `UnbanPeer` and `ListBans`: the bans in force afterwards, or
+    /// why the request was refused
+    Bans {
+        addr_from: String,
+        bans: std::result::Result<Vec<(IpAddr, Ban)>, String>,
+    },
+    /// Answer to GetData for a block or transaction the node doesn't have, so the asker
+    /// stops waiting for it
+    NotFound {
+        addr_from: String,
+        op_type: OpType,
+        id: Vec<u8>,
     },
+    /// Sent instead of an answer when a node refuses the connection, and why
+    Rejected {
+        addr_from: String,
+        reason: String,
+    },
+    /// Any other package, signed by the sending node's identity
+    Signed {
+        envelope: SignedPackage,
+    },
+    /// Ask a running node for main-chain blocks as `printchain` prints them; answered on
+    /// the same connection
+    GetChain {
+        addr_from: String,
+        from_height: Option<usize>,
+        to_height: Option<usize>,
+        limit: Option<usize>,
+    },
+    /// Reply to `GetChain`, newest first, or why the blocks couldn't be read
+    Chain {
+        addr_from: String,
+        blocks: std::result::Result<Vec<BlockSummary>, String>,
+    },
+    /// Ask a running node where a transaction stands on its main chain; answered on the
+    /// same connection
+    GetTxStatus {
+        addr_from: String,
+        txid: Vec<u8>,
+    },
+    /// Reply to `GetTxStatus`, or why the transaction index couldn't be read
+    TxStatus {
+        addr_from: String,
+        status: std::result::Result<TransactionStatus, String>,
+    },
+    /// Have a running node drop one of its pending transactions for good, see
+    /// `MemoryPool::abandon`; only taken from the node's own host and answered with
+    /// `Abandoned`
+    AbandonTx {
+        addr_from: String,
+        txid: Vec<u8>,
+    },
+    /// Reply to `AbandonTx`, or why the transaction wasn't abandoned
+    Abandoned {
+        addr_from: String,
+        result: std::result::Result<(), String>,
+    },
+    /// Ask a running node for the connections it has open; only taken from the node's own
+    /// host and answered on the same connection
+    GetPeerInfo {
+        addr_from: String,
+    },
+    /// Reply to `GetPeerInfo`: the first `MAX_PEER_INFOS` connections by address, or why
+    /// the request was refused
+    Peers {
+        addr_from: String,
+        peers: std::result::Result<Vec<PeerInfo>, String>,
+    },
+    /// Have a running node close its connections with `address`, and ban it for
+    /// `ban_secs` if set; only taken from the node's own host and answered with
+    /// `Disconnected`
+    DisconnectPeer {
+        addr_from: String,
+        address: String,
+        ban_secs: Option<u64>,
+    },
+    /// Reply to `DisconnectPeer`: the connections closed, or why none were
+    Disconnected {
+        addr_from: String,
+        result: std::result::Result<Vec<String>, String>,
+    },
+}
+
+impl Package {
+    /// The package type as it appears in logs and message traces
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Package::Block { .. } => "Block",
+            Package::GetBlocks { .. } => "GetBlocks",
+            Package::GetData { .. } => "GetData",
+            Package::Inv { .. } => "Inv",
+            Package::Tx { .. } => "Tx",
+            Package::Version { .. } => "Version",
+            Package::GetMempoolTx { .. } => "GetMempoolTx",
+            Package::MempoolTx { .. } => "MempoolTx",
+            Package::GetStatus { .. } => "GetStatus",
+            Package::Status { .. } => "Status",
+            Package::FilterLoad { .. } => "FilterLoad",
+            Package::MerkleBlock { .. } => "MerkleBlock",
+            Package::GetBlockTemplate { .. } => "GetBlockTemplate",
+            Package::BlockTemplate { .. } => "BlockTemplate",
+            Package::SubmitBlock { .. } => "SubmitBlock",
+            Package::SubmitBlockResult { .. } => "SubmitBlockResult",
+            Package::GetMessageTrace { .. } => "GetMessageTrace",
+            Package::MessageTrace { .. } => "MessageTrace",
+            Package::GetBalance { .. } => "GetBalance",
+            Package::Balance { .. } => "Balance",
+            Package::BanPeer { .. } => "BanPeer",
+            Package::UnbanPeer { .. } => "UnbanPeer",
+            Package::ListBans { .. } => "ListBans",
+            Package::Bans { .. } => "Bans",
+            Package::NotFound { .. } => "NotFound",
+            Package::Rejected { .. } => "Rejected",
+            Package::Signed { .. } => "Signed",
+            Package::GetChain { .. } => "GetChain",
+            Package::Chain { .. } => "Chain",
+            Package::GetTxStatus { .. } => "GetTxStatus",
+            Package::TxStatus { .. } => "TxStatus",
+            Package::AbandonTx { .. } => "AbandonTx",
+            Package::Abandoned { .. } => "Abandoned",
+            Package::GetPeerInfo { .. } => "GetPeerInfo",
+            Package::Peers { .. } => "Peers",
+            Package::DisconnectPeer { .. } => "DisconnectPeer",
+            Package::Disconnected { .. } => "Disconnected",
+        }
+    }
+
+    /// The few fields worth logging, as ` key=value` pairs; payloads are never included
+    pub fn summary(&self) -> String {
+        let block_fields = |bytes: &[u8]| match Block::deserialize(bytes) {
+            Ok(block) => format!(" hash={} height={}", block.get_hash(), block.get_height()),
+            Err(_) => " hash=undecodable".to_string(),
+        };
+        match self {
+            Package::Block { block, .. } | Package::SubmitBlock { block, .. } => {
+                block_fields(block)
+            }
+            Package::Tx {
+                transaction,
+                confirmed,
+                ..
+            } => match Transaction::deserialize(transaction) {
+                Ok(tx) => format!(
+                    " txid={} confirmed={confirmed}",
+                    HEXLOWER.encode(tx.get_id())
+                ),
+                Err(_) => " txid=undecodable".to_string(),
+            },
+            Package::GetData { op_type, id, .. } | Package::NotFound { op_type, id, .. } => {
+                format!(" op={op_type:?} id={}", HEXLOWER.encode(id))
+            }
+            Package::Inv { op_type, items, .. } => format!(" op={op_type:?} items={}", items.len()),
+            Package::Version {
+                version,
+                best_height,
+                format_version,
+                ..
+            } => format!(" version={version} best_height={best_height} format={format_version}"),
+            Package::GetMempoolTx { txid, .. }
+            | Package::GetTxStatus { txid, .. }
+            | Package::AbandonTx { txid, .. } => format!(" txid={}", HEXLOWER.encode(txid)),
+            Package::MempoolTx { transaction, .. } => format!(" found={}", transaction.is_some()),
+            Package::MerkleBlock { header, .. } => match BlockHeader::deserialize(header) {
+                Ok(header) => format!(" hash={} height={}", header.hash, header.height),
+                Err(_) => " hash=undecodable".to_string(),
+            },
+            Package::BlockTemplate { template, .. } => match template {
+                Ok(template) => format!(" height={}", template.height),
+                Err(_) => " error=true".to_string(),
+            },
+            Package::SubmitBlockResult { result, .. } => format!(" accepted={}", result.is_ok()),
+            Package::MessageTrace { entries, .. } => format!(" entries={}", entries.len()),
+            Package::GetBalance { address, .. } => format!(" address={address}"),
+            Package::BanPeer {
+                address,
+                duration_secs,
+                ..
+            } => format!(" address={address} duration_secs={duration_secs}"),
+            Package::UnbanPeer { address, .. } => format!(" address={address}"),
+            Package::Bans { bans, .. } => match bans {
+                Ok(bans) => format!(" bans={}", bans.len()),
+                Err(_) => " error=true".to_string(),
+            },
+            Package::Abandoned { result, .. } => format!(" abandoned={}", result.is_ok()),
+            Package::Peers { peers, .. } => match peers {
+                Ok(peers) => format!(" peers={}", peers.len()),
+                Err(_) => " error=true".to_string(),
+            },
+            Package::DisconnectPeer {
+                address, ban_secs, ..
+            } => format!(" address={address} ban_secs={ban_secs:?}"),
+            Package::Disconnected { result, .. } => match result {
+                Ok(closed) => format!(" closed={}", closed.len()),
+                Err(_) => " error=true".to_string(),
+            },
+            Package::Chain { blocks, .. } => match blocks {
+                Ok(blocks) => format!(" blocks={}", blocks.len()),
+                Err(_) => " error=true".to_string(),
+            },
+            Package::TxStatus { status, .. } => match status {
+                Ok(status) => format!(" confirmations={:?}", status.confirmations),
+                Err(_) => " error=true".to_string(),
+            },
+            Package::Rejected { reason, .. } => format!(" reason={reason:?}"),
+            Package::Signed { envelope } => {
+                format!(" peer_id={}", PeerId::from_public_key(&envelope.pub_key))
+            }
+            Package::GetBlocks { .. }
+            | Package::GetStatus { .. }
+            | Package::Status { .. }
+            | Package::FilterLoad { .. }
+            | Package::GetBlockTemplate { .. }
+            | Package::GetMessageTrace { .. }
+            | Package::Balance { .. }
+            | Package::ListBans { .. }
+            | Package::GetChain { .. }
+            | Package::GetPeerInfo { .. } => String::new(),
+        }
+    }
+
+    /// Whether the package is taken from senders that don't sign, even with
+    /// `allow_unsigned_peers` off
+    fn is_taken_unsigned(&self) -> bool {
+        matches!(
+            self,
+            Package::Tx { .. }
+                | Package::GetMempoolTx { .. }
+                | Package::GetStatus { .. }
+                | Package::GetBlockTemplate { .. }
+                | Package::SubmitBlock { .. }
+                | Package::GetMessageTrace { .. }
+                | Package::GetBalance { .. }
+                | Package::BanPeer { .. }
+                | Package::UnbanPeer { .. }
+                | Package::ListBans { .. }
+                | Package::GetChain { .. }
+                | Package::GetTxStatus { .. }
+                | Package::AbandonTx { .. }
+                | Package::GetPeerInfo { .. }
+                | Package::DisconnectPeer { .. }
+        )
+    }
+
+    /// Check the package's fields against the configured limits
+    pub fn validate(&self, limits: &MessageLimits) -> Result<()> {
+        fn check(field: &str, len: usize, max: usize) -> Result<()> {
+            if len > max {
+                return Err(BlockchainError::Network(format!(
+                    "Package field {field} too large: {len} (max: {max})"
+                )));
+            }
+            Ok(())
+        }
+
+        let addr_from = match self {
+            Package::Block { addr_from, block } => {
+                check("block", block.len(), limits.max_block_payload)?;
+                addr_from
+            }
+            Package::GetData { addr_from, id, .. } | Package::NotFound { addr_from, id, .. } => {
+                check("id", id.len(), limits.max_id_length)?;
+                addr_from
+            }
+            Package::Inv {
+                addr_from, items, ..
+            } => {
+                check("items", items.len(), limits.max_inv_items)?;
+                for item in items {
+                    check("item", item.len(), limits.max_id_length)?;
+                }
+                addr_from
+            }
+            Package::Tx {
+                addr_from,
+                transaction,
+                ..
+            } => {
+                check("transaction", transaction.len(), limits.max_tx_payload)?;
+                addr_from
+            }
+            Package::GetMempoolTx { addr_from, txid }
+            | Package::GetTxStatus { addr_from, txid }
+            | Package::AbandonTx { addr_from, txid } => {
+                check("txid", txid.len(), limits.max_id_length)?;
+                addr_from
+            }
+            Package::MempoolTx {
+                addr_from,
+                transaction,
+            } => {
+                if let Some(transaction) = transaction {
+                    check("transaction", transaction.len(), limits.max_tx_payload)?;
+                }
+                addr_from
+            }
+            Package::FilterLoad {
+                addr_from,
+                filter,
+                hash_funcs,
+                ..
+            } => {
+                check("filter", filter.len(), MAX_FILTER_SIZE)?;
+                check("hash_funcs", *hash_funcs as usize, MAX_HASH_FUNCS as usize)?;
+                addr_from
+            }
+            Package::MerkleBlock {
+                addr_from,
+                header,
+                proofs,
+            } => {
+                check("header", header.len(), limits.max_block_payload)?;
+                check("proofs", proofs.len(), limits.max_block_payload)?;
+                addr_from
+            }
+            Package::GetBlockTemplate {
+                addr_from,
+                miner_address,
+            } => {
+                check("miner_address", miner_address.len(), limits.max_addr_length)?;
+                addr_from
+            }
+            Package::SubmitBlock { addr_from, block } => {
+                check("block", block.len(), limits.max_block_payload)?;
+                addr_from
+            }
+            Package::GetBalance { addr_from, address }
+            | Package::BanPeer {
+                addr_from, address, ..
+            }
+            | Package::UnbanPeer { addr_from, address }
+            | Package::DisconnectPeer {
+                addr_from, address, ..
+            } => {
+                check("address", address.len(), limits.max_addr_length)?;
+                addr_from
+            }
+            Package::Peers {
+                addr_from,
+                peers: Ok(peers),
+            } => {
+                check("peers", peers.len(), limits.max_inv_items)?;
+                addr_from
+            }
+            Package::Rejected { addr_from, reason } => {
+                check("reason", reason.len(), limits.max_addr_length)?;
+                addr_from
+            }
+            Package::Bans {
+                addr_from,
+                bans: Ok(bans),
+            } => {
+                check("bans", bans.len(), limits.max_inv_items)?;
+                addr_from
+            }
+            Package::Version {
+                addr_from,
+                wire_formats,
+                ..
+            } => {
+                check("wire_formats", wire_formats.len(), MAX_WIRE_FORMATS)?;
+                addr_from
+            }
+            Package::GetBlocks { addr_from }
+            | Package::GetStatus { addr_from }
+            | Package::Status { addr_from, .. }
+            | Package::BlockTemplate { addr_from, .. }
+            | Package::SubmitBlockResult { addr_from, .. }
+            | Package::GetMessageTrace { addr_from }
+            | Package::MessageTrace { addr_from, .. }
+            | Package::Balance { addr_from, .. }
+            | Package::ListBans { addr_from }
+            | Package::Bans { addr_from, .. }
+            | Package::GetChain { addr_from, .. }
+            | Package::Chain { addr_from, .. }
+            | Package::TxStatus { addr_from, .. }
+            | Package::Abandoned { addr_from, .. }
+            | Package::GetPeerInfo { addr_from }
+            | Package::Peers { addr_from, .. }
+            | Package::Disconnected { addr_from, .. } => addr_from,
+            // The wrapped package is checked once handle_connection unwrapped it
+            Package::Signed { .. } => return Ok(()),
+        };
+        check("addr_from", addr_from.len(), limits.max_addr_length)
+    }
 }
 
 impl Server {
-    /// Create a new simplified server
+    /// Create a new simplified server using copies of the global fee calculator and config
     pub fn new(blockchain: Blockchain) -> Self {
-        let peer_manager = Arc::new(SimplePeerManager::new(8, 2001));
+        Self::with_context(blockchain, Arc::new(NodeContext::from_globals()))
+    }
+
+    /// Create a server that keeps its memory pool, fees and config in `context`
+    ///
+    /// Peers remembered from earlier runs are loaded from the blockchain's database. DNS
+    /// seeds are picked to match the configured network and node address, see
+    /// `with_dns_seeder`.
+    pub fn with_context(blockchain: Blockchain, context: Arc<NodeContext>) -> Self {
+        let dns_seeder = DnsSeeder::for_network(
+            context.config().get_network(),
+            &context.config().get_node_addr(),
+        );
+        let peer_manager = Arc::new(
+            SimplePeerManager::new(Self::max_peers(&context), 2001)
+                .with_dns_seeder(dns_seeder)
+                .with_allowed_peers(context.config().get_allowed_peers()),
+        );
+        if let Err(e) = peer_manager.load_from(blockchain.get_db()) {
+            warn!("Failed to load known peers: {e}");
+        }
+        // One bus for the chain and the pool, so subscribers see their events in order
+        context
+            .memory_pool()
+            .set_event_bus(blockchain.events().clone());
+        // However a block gets replaced, its transactions come back to the pool
+        context.follow_reorgs(&blockchain);
 
+        let (stop_sender, stop) = mpsc::channel();
         Self {
             blockchain,
             peer_manager,
+            message_limits: MessageLimits::from_config(context.config()),
+            context,
+            metrics_addr: None,
+            explorer_addr: None,
+            sync_only: None,
+            seed_node: CENTRAL_NODE.to_string(),
+            stop_handle: StopHandle::new(stop_sender),
+            stop: Mutex::new(stop),
         }
     }
 
+    fn max_peers(context: &NodeContext) -> usize {
+        context
+            .config()
+            .get_max_peers()
+            .unwrap_or(DEFAULT_MAX_PEERS)
+    }
+
+    pub fn get_context(&self) -> &Arc<NodeContext> {
+        &self.context
+    }
+
+    pub fn get_peer_manager(&self) -> &Arc<SimplePeerManager> {
+        &self.peer_manager
+    }
+
+    pub fn get_blockchain(&self) -> &Blockchain {
+        &self.blockchain
+    }
+
+    /// Use custom size limits for incoming packages instead of the configured ones
+    pub fn with_message_limits(mut self, message_limits: MessageLimits) -> Self {
+        self.message_limits = message_limits;
+        self
+    }
+
+    /// Discover peers through `dns_seeder` while too few are known
+    pub fn with_dns_seeder(mut self, dns_seeder: DnsSeeder) -> Self {
+        // The known peers loaded so far carry over to the new manager
+        let nodes = Arc::clone(self.peer_manager.get_nodes());
+        self.peer_manager = Arc::new(
+            SimplePeerManager::new(Self::max_peers(&self.context), 2001)
+                .with_dns_seeder(dns_seeder)
+                .with_allowed_peers(self.peer_manager.get_allowed_peers().to_vec())
+                .with_nodes(nodes),
+        );
+        self
+    }
+
+    /// Serve metrics over HTTP on `addr` once the node runs
+    pub fn with_metrics_addr(mut self, addr: impl Into<String>) -> Self {
+        self.metrics_addr = Some(addr.into());
+        self
+    }
+
+    /// Serve the read-only JSON block explorer over HTTP on `addr` once the node runs
+    pub fn with_explorer_addr(mut self, addr: impl Into<String>) -> Self {
+        self.explorer_addr = Some(addr.into());
+        self
+    }
+
+    /// Join the network through `addr` instead of `CENTRAL_NODE`
+    ///
+    /// A node listening on the seed address itself doesn't introduce itself to anyone.
+    pub fn with_seed_node(mut self, addr: impl Into<String>) -> Self {
+        self.seed_node = addr.into();
+        self
+    }
+
+    /// A handle that stops `run` from another thread
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    /// Stop `run` once the node has been within a block of its best peer for `settle`
+    ///
+    /// `run` then returns `Ok`, or an error if no peer told its height within
+    /// `SYNC_PEER_TIMEOUT`.
+    pub fn with_sync_only(mut self, settle: Duration) -> Self {
+        self.sync_only = Some(settle);
+        self
+    }
+
     /// Run the server
+    ///
+    /// Transactions still pending when the node last stopped are re-admitted first, see
+    /// `MemoryPool::load_from`. The pool is saved every `MEMPOOL_SAVE_INTERVAL` and once
+    /// more when the listener stops.
     pub fn run(&self, addr: &str) -> Result<()> {
         let listener = TcpListener::bind(addr)
             .map_err(|e| BlockchainError::Network(format!("Failed to bind to {addr}: {e}")))?;
+        self.run_with_listener(listener)
+    }
+
+    /// Like `run`, accepting peers on a listener that is already bound
+    ///
+    /// Binding port 0 first lets the caller learn the port before the node starts, to put
+    /// it in the node's config as the address it tells peers.
+    pub fn run_with_listener(&self, listener: TcpListener) -> Result<()> {
+        let listen_addr = listener
+            .local_addr()
+            .map_err(|e| BlockchainError::Network(format!("Failed to get listen address: {e}")))?;
+        info!("Server listening on {listen_addr}");
+        self.stop_handle.set_wake_addr(listen_addr);
+        // Only one accept loop may own the stop channel
+        let stop = self
+            .stop
+            .try_lock()
+            .map_err(|_| BlockchainError::Network("The server is already running".to_string()))?;
+
+        let (best_height, _) = Self::local_tip(&self.blockchain)?;
+        *self.context.sync_state() = SyncState::new(best_height, Instant::now());
+        if let Err(e) = self.context.follow_fee_history(&self.blockchain) {
+            warn!("Failed to load the fee history: {e}");
+        }
+        if let Some(settle) = self.sync_only {
+            self.start_sync_watch(settle);
+        }
+
+        if let Some(metrics_addr) = &self.metrics_addr {
+            self.start_metrics_server(metrics_addr)?;
+        }
+        if let Some(explorer_addr) = &self.explorer_addr {
+            spawn_explorer_server(
+                explorer_addr,
+                self.blockchain.clone(),
+                Arc::clone(&self.context),
+            )?;
+        }
 
-        info!("Server listening on {addr}");
+        // Peers may answer on the connections this node opens to them
+        self.context
+            .outbound_connections()
+            .set_handler(Arc::new(PooledPeers {
+                blockchain: self.blockchain.clone(),
+                context: Arc::downgrade(&self.context),
+                peer_manager: Arc::clone(&self.peer_manager),
+                limits: self.message_limits.clone(),
+            }));
 
-        // If not central node, connect to network
-        if addr != CENTRAL_NODE {
+        // If not the seed node, connect to network
+        if listen_addr.to_string() != self.seed_node {
             self.connect_to_network()?;
         }
 
+        match self.context.restore_memory_pool(&self.blockchain) {
+            Ok(0) => {}
+            Ok(loaded) => info!("Restored {loaded} pending transactions"),
+            Err(e) => warn!("Failed to restore pending transactions: {e}"),
+        }
+
         // Start peer discovery in background
         self.start_peer_discovery();
+        let block_timer = self.start_block_timer();
+        self.start_mempool_saver();
+        self.start_download_watch();
 
         // Accept incoming connections
+        let mut outcome = Ok(());
         for stream in listener.incoming() {
+            if let Ok(stopped) = stop.try_recv() {
+                outcome = stopped;
+                break;
+            }
             match stream {
                 Ok(stream) => {
                     let peer_addr = match stream.peer_addr() {
@@ -109,12 +915,13 @@ impl Server {
                     };
 
                     // Check if we should accept this connection
-                    if !self
+                    let refusal = self
                         .peer_manager
-                        .should_accept_connection()
-                        .unwrap_or(false)
-                    {
-                        warn!("Rejecting connection from {peer_addr}: connection limit reached");
+                        .connection_refusal(peer_addr)
+                        .unwrap_or_else(|e| Some(format!("could not check the peer: {e}")));
+                    if let Some(reason) = refusal {
+                        warn!("Rejecting connection from {peer_addr}: {reason}");
+                        self.send_rejection(&stream, &reason);
                         continue;
                     }
 
@@ -122,18 +929,30 @@ impl Server {
                     if let Err(e) = self.peer_manager.record_connection(peer_addr) {
                         warn!("Failed to record connection: {e}");
                     }
+                    Self::update_peer_gauge(&self.peer_manager);
 
                     // Spawn handler thread
                     let blockchain = self.blockchain.clone();
                     let peer_manager = Arc::clone(&self.peer_manager);
+                    let message_limits = self.message_limits.clone();
+                    let context = Arc::clone(&self.context);
 
                     thread::spawn(move || {
-                        let result = Self::handle_connection(blockchain, stream, peer_addr);
+                        let result = Self::handle_connection(
+                            blockchain,
+                            &context,
+                            &peer_manager,
+                            &message_limits,
+                            stream,
+                            peer_addr,
+                            Direction::Inbound,
+                        );
 
                         // Remove connection when done
                         if let Err(e) = peer_manager.record_disconnection(peer_addr) {
                             warn!("Failed to record disconnection: {e}");
                         }
+                        Self::update_peer_gauge(&peer_manager);
 
                         if let Err(e) = result {
                             error!("Error handling connection from {peer_addr}: {e}");
@@ -146,150 +965,1222 @@ impl Server {
             }
         }
 
+        if let Some(block_timer) = block_timer {
+            if block_timer.join().is_err() {
+                error!("The block timer thread panicked");
+            }
+        }
+        Self::save_peers(&self.peer_manager, &self.blockchain);
+        Self::save_memory_pool(&self.context, &self.blockchain);
+        self.context.outbound_connections().close_all();
+        outcome
+    }
+
+    /// Mine `count` blocks to `miner_address` right away and announce them to this node's
+    /// peers
+    ///
+    /// The blocks take what they can from the memory pool, like `Blockchain::generate_blocks`
+    /// does for development chains.
+    pub fn generate_blocks(&self, count: usize, miner_address: &str) -> Result<Vec<Block>> {
+        let blocks = self
+            .blockchain
+            .generate_blocks(count, miner_address, &self.context)?;
+        for block in &blocks {
+            Self::announce(
+                &self.context,
+                OpType::Block,
+                &[block.get_hash_bytes()],
+                None,
+            );
+        }
+        Ok(blocks)
+    }
+
+    fn save_memory_pool(context: &NodeContext, blockchain: &Blockchain) {
+        if let Err(e) = context.memory_pool().save_to(blockchain.get_db()) {
+            warn!("Failed to save pending transactions: {e}");
+        }
+    }
+
+    fn save_peers(peer_manager: &SimplePeerManager, blockchain: &Blockchain) {
+        if let Err(e) = peer_manager.save_to(blockchain.get_db()) {
+            warn!("Failed to save known peers: {e}");
+        }
+    }
+
+    /// Send our version to `peer_addr` through the peer manager's connectivity, keeping
+    /// score of whether it could be reached
+    fn contact_peer(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        peer_manager: &SimplePeerManager,
+        peer_addr: SocketAddr,
+        best_height: usize,
+        chain_work: u128,
+    ) {
+        let sent = Self::version_handshake(blockchain, context, peer_addr, best_height, chain_work)
+            .and_then(|data| {
+                peer_manager.connectivity().send_handshake(
+                    context.outbound_connections(),
+                    peer_addr,
+                    &data,
+                )
+            });
+        let outcome = match sent {
+            Ok(()) => {
+                context.add_relay_peer(&peer_addr.to_string());
+                peer_manager.record_peer_success(peer_addr)
+            }
+            Err(e) => {
+                error!("Failed to connect to peer {peer_addr}: {e}");
+                peer_manager.record_peer_failure(peer_addr)
+            }
+        };
+        if let Err(e) = outcome {
+            warn!("Failed to update peer {peer_addr}: {e}");
+        }
+    }
+
+    fn update_peer_gauge(peer_manager: &SimplePeerManager) {
+        if let Ok(peers) = peer_manager.get_connected_count() {
+            METRICS.peers_connected.set(peers as u64);
+        }
+    }
+
+    /// Serve metrics, refreshing the gauges from this node's state on every scrape
+    fn start_metrics_server(&self, metrics_addr: &str) -> Result<()> {
+        let blockchain = self.blockchain.clone();
+        let peer_manager = Arc::clone(&self.peer_manager);
+        let context = Arc::clone(&self.context);
+
+        spawn_metrics_server(metrics_addr, &METRICS, move |metrics| {
+            if let Ok(height) = blockchain.get_best_height() {
+                metrics.block_height.set(height as u64);
+            }
+            if let Ok(size) = blockchain.get_db().size_on_disk() {
+                metrics.db_size_bytes.set(size);
+            }
+            if let Ok(peers) = peer_manager.get_connected_count() {
+                metrics.peers_connected.set(peers as u64);
+            }
+            metrics.mempool_size.set(context.memory_pool().len() as u64);
+        })?;
         Ok(())
     }
 
+    // Tell a refused peer why before the stream is dropped; it may not be listening
+    fn send_rejection(&self, stream: &TcpStream, reason: &str) {
+        let pkg = Package::Rejected {
+            addr_from: self.context.config().get_node_addr(),
+            reason: reason.to_string(),
+        };
+        let _ = stream.set_write_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)));
+        let _ = WireFormat::Json.write(stream, &pkg);
+        let _ = stream.shutdown(Shutdown::Write);
+    }
+
     /// Connect to the network on startup
+    ///
+    /// Only the seed node is contacted here; known peers and those found through DNS
+    /// seeding get their handshakes from the first round of peer discovery.
     fn connect_to_network(&self) -> Result<()> {
-        if let Ok(best_height) = self.blockchain.get_best_height() {
-            Self::send_version(CENTRAL_NODE, best_height)?;
+        if let Ok(seed) = self.seed_node.parse::<SocketAddr>() {
+            if !self.peer_manager.is_allowed(seed.ip()) {
+                info!("Not contacting seed node {seed}: it is not on the allow-list");
+                return Ok(());
+            }
+        }
+        if let Ok((best_height, chain_work)) = Self::local_tip(&self.blockchain) {
+            Self::send_version(
+                &self.blockchain,
+                &self.context,
+                &self.seed_node,
+                best_height,
+                chain_work,
+            )?;
         }
         Ok(())
     }
 
     /// Start peer discovery in background
+    ///
+    /// The first round runs right away, asking the DNS seeds for peers if too few are
+    /// known. While that stays the case the rounds repeat after `DNS_RETRY_DELAY`, backing
+    /// off to `PEER_DISCOVERY_INTERVAL`. Seeding waits on connection attempts, which is
+    /// why it never runs on the accept thread. Known peers are saved after every round, so
+    /// a restart picks up where this left off.
     fn start_peer_discovery(&self) {
         let peer_manager = Arc::clone(&self.peer_manager);
+        let context = Arc::clone(&self.context);
+        let blockchain = self.blockchain.clone();
+        let seed_node = self.seed_node.clone();
+        let stop = self.stop_handle.clone();
 
         thread::spawn(move || {
+            let mut delay = Duration::ZERO;
             loop {
-                // Perform peer discovery every 5 minutes
-                thread::sleep(Duration::from_secs(300));
-
-                if let Ok(peers) = peer_manager.get_peers_to_connect() {
-                    for peer_addr in peers {
-                        // Try to connect to discovered peers
-                        if let Err(e) = Self::send_version(&peer_addr.to_string(), 0) {
-                            error!("Failed to connect to peer {peer_addr}: {e}");
-                        }
-                    }
+                thread::sleep(delay);
+                if stop.is_stopped() {
+                    return;
                 }
+
+                Self::discover_and_contact_peers(&blockchain, &context, &peer_manager, &seed_node);
+
+                delay = if peer_manager.needs_seeding() {
+                    (delay * 2).clamp(DNS_RETRY_DELAY, PEER_DISCOVERY_INTERVAL)
+                } else {
+                    PEER_DISCOVERY_INTERVAL
+                };
             }
         });
     }
 
-    /// Handle an individual connection
-    fn handle_connection(
-        blockchain: Blockchain,
-        stream: TcpStream,
-        peer_addr: SocketAddr,
-    ) -> Result<()> {
-        // Set connection timeout
-        stream
-            .set_read_timeout(Some(Duration::from_secs(60)))
-            .map_err(|e| BlockchainError::Network(format!("Failed to set read timeout: {e}")))?;
-
-        let reader = BufReader::new(&stream);
-        let pkg_reader = Deserializer::from_reader(reader).into_iter::<Package>();
-
-        for pkg in pkg_reader {
-            let pkg = pkg.map_err(|e| {
-                BlockchainError::Network(format!("Failed to deserialize package: {e}"))
-            })?;
+    /// One round of peer discovery: seed if too few peers are known, say hello to the ones
+    /// worth trying other than the seed node and this node, then save the known peers
+    fn discover_and_contact_peers(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        peer_manager: &SimplePeerManager,
+        seed_node: &str,
+    ) {
+        let own_addr = context.config().get_node_addr();
+        let (best_height, chain_work) = Self::local_tip(blockchain).unwrap_or((0, 0));
+        if let Ok(peers) = peer_manager.get_peers_to_connect() {
+            for peer_addr in peers {
+                let peer = peer_addr.to_string();
+                if peer != seed_node && peer != own_addr {
+                    Self::contact_peer(
+                        blockchain,
+                        context,
+                        peer_manager,
+                        peer_addr,
+                        best_height,
+                        chain_work,
+                    );
+                }
+            }
+        }
+        Self::save_peers(peer_manager, blockchain);
+    }
 
-            info!("Received request from {peer_addr}: {pkg:?}");
+    /// Mine whatever is waiting in the memory pool on the configured block interval
+    ///
+    /// Timed blocks go through `try_mine_block` like the ones triggered by the
+    /// transaction threshold, so the node's single mining slot keeps the two from mining
+    /// the same transactions at once. The thread exits as soon as the node stops, rather
+    /// than at the end of the interval, and `run` joins it before returning.
+    fn start_block_timer(&self) -> Option<thread::JoinHandle<()>> {
+        let interval = self.context.config().get_block_interval()?;
+        let context = Arc::clone(&self.context);
+        let blockchain = self.blockchain.clone();
+        let stop = self.stop_handle.clone();
+        info!("Mining pending transactions every {}s", interval.as_secs());
 
-            // Process the message
-            if let Err(e) = Self::process_message(&blockchain, pkg) {
-                error!("Error processing message from {peer_addr}: {e}");
+        Some(thread::spawn(move || loop {
+            if stop.sleep(interval) {
+                return;
             }
-        }
 
-        let _ = stream.shutdown(Shutdown::Both);
-        Ok(())
+            if context.config().is_miner() && !context.memory_pool().is_empty() {
+                if let Err(e) = Self::try_mine_block(&blockchain, &context) {
+                    error!("Failed to start timed mining: {e}");
+                }
+            }
+        }))
     }
 
-    /// Process an incoming message
-    fn process_message(blockchain: &Blockchain, pkg: Package) -> Result<()> {
-        match pkg {
-            Package::Block { addr_from, block } => {
-                Self::handle_block_message(blockchain, addr_from, block)
+    /// Tell the accept loop to stop once the sync is done, or once no peer told its height
+    /// within `SYNC_PEER_TIMEOUT`
+    fn start_sync_watch(&self, settle: Duration) {
+        let context = Arc::clone(&self.context);
+        let started = Instant::now();
+        let stop = self.stop_handle.clone();
+
+        thread::spawn(move || {
+            let outcome = loop {
+                thread::sleep(SYNC_CHECK_INTERVAL);
+                if stop.is_stopped() {
+                    return;
+                }
+                let now = Instant::now();
+                let mut sync = context.sync_state();
+                if sync.is_synced(now, settle) {
+                    info!("Sync complete: {}", sync.progress(now));
+                    break Ok(());
+                }
+                if !sync.has_peers() && now.duration_since(started) >= SYNC_PEER_TIMEOUT {
+                    break Err(BlockchainError::Network(format!(
+                        "No peer could be reached to sync from within {}s",
+                        SYNC_PEER_TIMEOUT.as_secs()
+                    )));
+                }
+            };
+            stop.stop_with(outcome);
+        });
+    }
+
+    /// Save the memory pool every `MEMPOOL_SAVE_INTERVAL`, so a node that is killed loses
+    /// at most that much of it
+    fn start_mempool_saver(&self) {
+        let context = Arc::clone(&self.context);
+        let blockchain = self.blockchain.clone();
+        let stop = self.stop_handle.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(MEMPOOL_SAVE_INTERVAL);
+            if stop.is_stopped() {
+                return;
+            }
+            Self::save_memory_pool(&context, &blockchain);
+        });
+    }
+
+    /// Every `DOWNLOAD_CHECK_INTERVAL`, ask another peer for the blocks not received
+    /// within `BLOCK_REQUEST_TIMEOUT`
+    fn start_download_watch(&self) {
+        let context = Arc::clone(&self.context);
+        let peer_manager = Arc::clone(&self.peer_manager);
+        let stop = self.stop_handle.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(DOWNLOAD_CHECK_INTERVAL);
+            if stop.is_stopped() {
+                return;
+            }
+            Self::requeue_stalled_blocks(&context, &peer_manager, Instant::now());
+        });
+    }
+
+    /// Penalize the peers that didn't send a requested block within
+    /// `BLOCK_REQUEST_TIMEOUT` of `now` and ask another relay peer for it
+    ///
+    /// The stalling peer is only asked again when no other unbanned peer is known. A block
+    /// that stalled more than `MAX_BLOCK_REQUEST_RETRIES` times is given up on; a later
+    /// announcement queues it again.
+    fn requeue_stalled_blocks(
+        context: &NodeContext,
+        peer_manager: &SimplePeerManager,
+        now: Instant,
+    ) {
+        for stalled in context
+            .blocks_in_transit()
+            .requeue_stalled(BLOCK_REQUEST_TIMEOUT, now)
+        {
+            let hash = String::from_utf8_lossy(&stalled.hash).into_owned();
+            // The peer asked is only who the message said to ask; its sender is charged
+            if let Err(e) =
+                peer_manager.penalize(stalled.requested_for, Misbehavior::StalledDownload)
+            {
+                warn!("Failed to penalize {}: {e}", stalled.requested_for);
+            }
+            if stalled.dropped {
+                warn!(
+                    "Giving up on block {hash} after {} stalled requests, the last to {}",
+                    stalled.retries, stalled.peer
+                );
+                continue;
+            }
+
+            let banned = |peer: &String| {
+                peer.parse::<SocketAddr>()
+                    .is_ok_and(|addr| peer_manager.is_banned(addr.ip()).unwrap_or(false))
+            };
+            // Relay peers are known by the address they connected from, so the one asked
+            // next answers for its own stall
+            let (peer, requested_for) = context
+                .relay_peers()
+                .into_iter()
+                .filter(|peer| *peer != stalled.peer && !banned(peer))
+                .find_map(|peer| Some((peer.clone(), peer.parse::<SocketAddr>().ok()?)))
+                .unwrap_or((stalled.peer, stalled.requested_for));
+            info!("Block {hash} stalled, asking {peer} for it");
+            if context
+                .blocks_in_transit()
+                .request_from(&stalled.hash, &peer, requested_for, now)
+            {
+                if let Err(e) = Self::send_get_data(context, &peer, OpType::Block, &stalled.hash) {
+                    warn!("Failed to ask {peer} for block {hash}: {e}");
+                }
+            }
+        }
+    }
+
+    /// Handle an individual connection
+    ///
+    /// Oversized, malformed or out-of-limit packages and peers sending too fast get a
+    /// misbehavior score and the connection is dropped. Signed packages are charged to the
+    /// key that signed them rather than the address they came from; one whose signature
+    /// doesn't hold costs its address the same as a package beyond the limits. With
+    /// `allow_unsigned_peers` off, unsigned gossip is ignored, but queries and transactions,
+    /// which carry their own signatures, are still taken from wallets and other clients.
+    ///
+    /// The connection is in the node's `PeerConnections` for as long as this reads it, as
+    /// pooled when it is one this node opened.
+    fn handle_connection(
+        blockchain: Blockchain,
+        context: &Arc<NodeContext>,
+        peer_manager: &SimplePeerManager,
+        limits: &MessageLimits,
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        direction: Direction,
+    ) -> Result<()> {
+        let connections = context.peer_connections();
+        let pooled = direction == Direction::Outbound;
+        let id = connections.open(peer_addr, direction, pooled, stream.try_clone().ok());
+        let result =
+            Self::read_connection(blockchain, context, peer_manager, limits, stream, peer_addr);
+        connections.close(peer_addr, id);
+        context.remove_peer_filter(peer_addr);
+        result
+    }
+
+    /// The address a package says its sender listens on, if it is on the host the package
+    /// came in from
+    ///
+    /// Anyone can write any `addr_from`, so one on another host proves nothing about that
+    /// host and mustn't be credited or blamed for what the package says.
+    fn verified_addr(peer_addr: SocketAddr, addr_from: &str) -> Option<SocketAddr> {
+        addr_from
+            .parse::<SocketAddr>()
+            .ok()
+            .filter(|listen_addr| listen_addr.ip() == peer_addr.ip())
+    }
+
+    fn read_connection(
+        blockchain: Blockchain,
+        context: &Arc<NodeContext>,
+        peer_manager: &SimplePeerManager,
+        limits: &MessageLimits,
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+    ) -> Result<()> {
+        // Set connection timeout
+        stream
+            .set_read_timeout(Some(Duration::from_secs(60)))
+            .map_err(|e| BlockchainError::Network(format!("Failed to set read timeout: {e}")))?;
+
+        let bytes = context.peer_connections().wire_bytes(peer_addr);
+        let mut pkg_reader = PackageReader::new(&stream, limits.max_message_size)
+            .with_byte_counter(Arc::clone(&bytes));
+        while let Some(read) = pkg_reader.read_package() {
+            let ReceivedPackage {
+                package: pkg,
+                size,
+                format,
+            } = match read {
+                Ok(received) => received,
+                Err(e) => {
+                    match &e {
+                        ReadError::Oversized { .. } => {
+                            peer_manager.penalize(peer_addr, Misbehavior::OversizedMessage)?;
+                        }
+                        ReadError::Malformed { resync, .. } => {
+                            peer_manager.penalize(peer_addr, Misbehavior::MalformedMessage)?;
+                            // A frame that didn't decode was still read whole
+                            if *resync {
+                                warn!("Skipped a package from {peer_addr}: {e}");
+                                continue;
+                            }
+                        }
+                        ReadError::Io(_) => {}
+                    }
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return Err(BlockchainError::Network(format!(
+                        "Failed to deserialize package: {e}"
+                    )));
+                }
+            };
+
+            let (pkg, sender) = match pkg {
+                Package::Signed { envelope } => match envelope.open(current_timestamp()?) {
+                    Ok((peer_id, pkg)) => (pkg, PeerKey::Identity(peer_id)),
+                    Err(e) => {
+                        peer_manager.penalize(peer_addr, Misbehavior::InvalidPackage)?;
+                        let _ = stream.shutdown(Shutdown::Both);
+                        return Err(e);
+                    }
+                },
+                pkg => (pkg, PeerKey::Ip(peer_addr.ip())),
+            };
+            let identity = match &sender {
+                PeerKey::Identity(peer_id) => Some(peer_id),
+                PeerKey::Ip(_) => None,
+            };
+            if identity.is_some() && peer_manager.is_peer_banned(&sender)? {
+                let _ = stream.shutdown(Shutdown::Both);
+                return Err(BlockchainError::Network(format!(
+                    "Peer {sender} at {peer_addr} is banned"
+                )));
+            }
+
+            if !peer_manager.record_message_at(&sender, peer_addr)? {
+                peer_manager.penalize_peer_at(
+                    &sender,
+                    peer_addr,
+                    Misbehavior::RateLimitExceeded,
+                )?;
+                let _ = stream.shutdown(Shutdown::Both);
+                return Err(BlockchainError::Network(format!(
+                    "Peer {sender} at {peer_addr} exceeded the message rate limit"
+                )));
+            }
+
+            if let Err(e) = pkg.validate(limits) {
+                peer_manager.penalize_peer_at(&sender, peer_addr, Misbehavior::InvalidPackage)?;
+                let _ = stream.shutdown(Shutdown::Both);
+                return Err(e);
+            }
+
+            context
+                .message_trace()
+                .record(Direction::Inbound, &peer_addr.to_string(), &pkg, size);
+            context
+                .peer_connections()
+                .record_received(peer_addr, pkg.kind(), &sender);
+
+            if identity.is_none()
+                && !context.config().allow_unsigned_peers()
+                && !pkg.is_taken_unsigned()
+            {
+                warn!("Ignored unsigned {} from {peer_addr}", pkg.kind());
+                continue;
+            }
+
+            // A version message names the address the peer listens on, unlike peer_addr.
+            // Once a key has announced an address, only that key may speak for it.
+            if let Package::Version {
+                addr_from,
+                version,
+                best_height,
+                timestamp,
+                network_magic,
+                ..
+            } = &pkg
+            {
+                // Before anything of another network's peer is taken in
+                if let Some(refusal) = Self::network_refusal(&blockchain, context, *network_magic)?
+                {
+                    warn!("Closing connection from {peer_addr} announcing {addr_from}: {refusal}");
+                    // Only an address on the host that connected, or anyone could get any
+                    // node shunned by announcing it from another network
+                    if let Some(listen_addr) = Self::verified_addr(peer_addr, addr_from) {
+                        peer_manager.record_other_network(listen_addr)?;
+                    }
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return Err(BlockchainError::Network(format!(
+                        "Peer {addr_from} at {peer_addr} {refusal}"
+                    )));
+                }
+                if !context.claim_peer_addr(addr_from, identity) {
+                    warn!("Ignored version from {peer_addr} claiming another key's {addr_from}");
+                    continue;
+                }
+                context.peer_connections().record_version(
+                    peer_addr,
+                    addr_from,
+                    *version,
+                    *best_height,
+                );
+                if let Ok(listen_addr) = addr_from.parse::<SocketAddr>() {
+                    // Or the peer would be relayed to and gossiped about from here on
+                    if !peer_manager.is_allowed(listen_addr.ip()) {
+                        warn!(
+                            "Ignored version from {peer_addr} announcing {addr_from}: address not on the allow-list"
+                        );
+                        continue;
+                    }
+                    // Another host's address may be worth trying, but it hasn't answered yet
+                    match Self::verified_addr(peer_addr, addr_from) {
+                        Some(listen_addr) => peer_manager.record_peer_success(listen_addr)?,
+                        None => peer_manager.add_known_peer(listen_addr)?,
+                    }
+                }
+                // Block timestamps are judged by the peers' clocks as much as by mine
+                if *timestamp > 0 {
+                    let offset = peer_manager.record_peer_time(peer_addr.ip(), *timestamp)?;
+                    context.set_network_time_offset(offset);
+                }
+            }
+
+            // Queries are answered on the same stream so short-lived CLI clients can read them
+            let reply =
+                match Self::answer_admin(&blockchain, context, peer_manager, peer_addr, &pkg)? {
+                    Some(reply) => Some(reply),
+                    None => Self::answer_query(&blockchain, context, peer_manager, &pkg)?,
+                };
+            // In the format it was asked in, which a client that never sent a version reads
+            if let Some(reply) = reply {
+                let data = format.encode_counted(&reply, &bytes)?;
+                context.message_trace().record(
+                    Direction::Outbound,
+                    &peer_addr.to_string(),
+                    &reply,
+                    data.len(),
+                );
+                context
+                    .peer_connections()
+                    .record_sent(peer_addr, reply.kind());
+                (&stream)
+                    .write_all(&data)
+                    .map_err(|e| BlockchainError::Network(format!("Failed to send reply: {e}")))?;
+                continue;
+            }
+
+            // Process the message
+            match Self::process_message(&blockchain, context, peer_addr, pkg) {
+                Ok(Some(misbehavior)) => {
+                    peer_manager.penalize_peer_at(&sender, peer_addr, misbehavior)?;
+                }
+                Ok(None) => {}
+                Err(e) => error!("Error processing message from {peer_addr}: {e}"),
+            }
+        }
+
+        let _ = stream.shutdown(Shutdown::Both);
+        Ok(())
+    }
+
+    /// Carry out the operator's ban, disconnect and abandon commands and list the node's
+    /// connections, or `None` if the package isn't one of those
+    ///
+    /// Only connections from the node's own host may give them. Changes are saved to the
+    /// database right away.
+    fn answer_admin(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        peer_manager: &SimplePeerManager,
+        peer_addr: SocketAddr,
+        pkg: &Package,
+    ) -> Result<Option<Package>> {
+        let change = match pkg {
+            Package::BanPeer {
+                address,
+                duration_secs,
+                ..
+            } => Some((address, Some(Duration::from_secs(*duration_secs)))),
+            Package::UnbanPeer { address, .. } => Some((address, None)),
+            Package::ListBans { .. } => None,
+            Package::GetPeerInfo { .. } => {
+                return Ok(Some(Self::peer_info(context, peer_manager, peer_addr)?))
+            }
+            Package::AbandonTx { txid, .. } => {
+                return Ok(Some(Self::abandon_transaction(
+                    blockchain, context, peer_addr, txid,
+                )))
+            }
+            Package::DisconnectPeer {
+                address, ban_secs, ..
+            } => {
+                return Ok(Some(Self::disconnect_peer(
+                    blockchain,
+                    context,
+                    peer_manager,
+                    peer_addr,
+                    address,
+                    *ban_secs,
+                )))
+            }
+            _ => return Ok(None),
+        };
+
+        let bans = if !peer_addr.ip().is_loopback() {
+            warn!("Refused ban command from {peer_addr}, which isn't local");
+            Err(format!("{peer_addr} may not manage bans"))
+        } else {
+            let applied = match change {
+                Some((address, duration)) => parse_ban_address(address).and_then(|ip| {
+                    match duration {
+                        Some(duration) => {
+                            peer_manager.ban(ip, duration, OPERATOR_BAN_REASON)?;
+                        }
+                        None => {
+                            peer_manager.unban(ip)?;
+                        }
+                    }
+                    peer_manager.save_bans_to(blockchain.get_db())
+                }),
+                None => Ok(()),
+            };
+            applied
+                .and_then(|()| peer_manager.list_bans())
+                .map_err(|e| e.to_string())
+        };
+        Ok(Some(Package::Bans {
+            addr_from: context.config().get_node_addr(),
+            bans,
+        }))
+    }
+
+    // Drop the pending transaction `txid` for the operator, saving the pool so it stays
+    // abandoned across a restart
+    fn abandon_transaction(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        peer_addr: SocketAddr,
+        txid: &[u8],
+    ) -> Package {
+        let txid_hex = HEXLOWER.encode(txid);
+        let not_pending = || format!("Transaction {txid_hex} is not pending");
+        let abandon = || -> std::result::Result<(), String> {
+            let pending = context
+                .memory_pool()
+                .get(&txid_hex)
+                .ok_or_else(not_pending)?;
+            // Only the node's own payments are the operator's to take back
+            context
+                .wallets()
+                .read()
+                .check_spends_own_coins(&pending)
+                .map_err(|e| e.to_string())?;
+            context
+                .memory_pool()
+                .abandon(&txid_hex)
+                .ok_or_else(not_pending)?;
+            info!("Abandoned transaction {txid_hex} on the operator's request");
+            context
+                .memory_pool()
+                .save_to(blockchain.get_db())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        };
+        let result = if peer_addr.ip().is_loopback() {
+            abandon()
+        } else {
+            warn!("Refused abandon command from {peer_addr}, which isn't local");
+            Err(format!("{peer_addr} may not abandon transactions"))
+        };
+        Package::Abandoned {
+            addr_from: context.config().get_node_addr(),
+            result,
+        }
+    }
+
+    // The node's connections, which say who it talks to and how, so only for the operator
+    fn peer_info(
+        context: &NodeContext,
+        peer_manager: &SimplePeerManager,
+        peer_addr: SocketAddr,
+    ) -> Result<Package> {
+        let peers = if peer_addr.ip().is_loopback() {
+            let mut peers = context.peer_connections().peer_info(peer_manager)?;
+            peers.truncate(MAX_PEER_INFOS);
+            Ok(peers)
+        } else {
+            warn!("Refused peer info request from {peer_addr}, which isn't local");
+            Err(format!("{peer_addr} may not list this node's connections"))
+        };
+        Ok(Package::Peers {
+            addr_from: context.config().get_node_addr(),
+            peers,
+        })
+    }
+
+    // Close the connections with `address` for the operator, banning it first if asked to
+    // so it can't come straight back
+    fn disconnect_peer(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        peer_manager: &SimplePeerManager,
+        peer_addr: SocketAddr,
+        address: &str,
+        ban_secs: Option<u64>,
+    ) -> Package {
+        let disconnect = || -> Result<Vec<String>> {
+            if let Some(ban_secs) = ban_secs {
+                let ip = parse_ban_address(address)?;
+                peer_manager.ban(ip, Duration::from_secs(ban_secs), OPERATOR_BAN_REASON)?;
+                peer_manager.save_bans_to(blockchain.get_db())?;
+            }
+            let closed = context.peer_connections().disconnect(address)?;
+            Ok(closed.iter().map(SocketAddr::to_string).collect())
+        };
+        let result = if !peer_addr.ip().is_loopback() {
+            warn!("Refused disconnect command from {peer_addr}, which isn't local");
+            Err(format!("{peer_addr} may not disconnect peers"))
+        } else {
+            disconnect().map_err(|e| e.to_string())
+        };
+        Package::Disconnected {
+            addr_from: context.config().get_node_addr(),
+            result,
+        }
+    }
+
+    /// Build the reply for query packages, or `None` if the package isn't a query
+    fn answer_query(
+        blockchain: &Blockchain,
+        context: &Arc<NodeContext>,
+        peer_manager: &SimplePeerManager,
+        pkg: &Package,
+    ) -> Result<Option<Package>> {
+        let addr_from = context.config().get_node_addr();
+        let reply =
+            match pkg {
+                Package::GetMempoolTx { txid, .. } => Package::MempoolTx {
+                    addr_from,
+                    transaction: context
+                        .memory_pool()
+                        .get(&HEXLOWER.encode(txid))
+                        .and_then(|tx| tx.serialize().ok()),
+                },
+                Package::GetStatus { .. } => Package::Status {
+                    addr_from,
+                    status: NodeStatus::collect(blockchain, context, peer_manager)?,
+                },
+                Package::GetBlockTemplate { miner_address, .. } => Package::BlockTemplate {
+                    addr_from,
+                    template: blockchain
+                        .build_block_template_with_context(miner_address, context)
+                        .map_err(|e| e.to_string()),
+                },
+                Package::SubmitBlock { block, .. } => Package::SubmitBlockResult {
+                    addr_from,
+                    result: Self::handle_submitted_block(blockchain, context, block)
+                        .map_err(|e| e.to_string()),
+                },
+                Package::GetMessageTrace { .. } => Package::MessageTrace {
+                    addr_from,
+                    entries: context.message_trace().entries(),
+                },
+                Package::GetBalance { address, .. } => Package::Balance {
+                    addr_from,
+                    balance: address_pub_key_hash(address)
+                        .and_then(|pub_key_hash| {
+                            UTXOSet::new(blockchain.clone())
+                                .balance_with_pending(&pub_key_hash, context.memory_pool())
+                        })
+                        .map_err(|e| e.to_string()),
+                },
+                Package::GetChain {
+                    from_height,
+                    to_height,
+                    limit,
+                    ..
+                } => Package::Chain {
+                    addr_from,
+                    blocks: BlockSummarizer::new(blockchain)
+                        .summarize_main_chain(
+                            *from_height,
+                            *to_height,
+                            Some(limit.map_or(MAX_CHAIN_SUMMARIES, |limit| {
+                                limit.min(MAX_CHAIN_SUMMARIES)
+                            })),
+                        )
+                        .map_err(|e| e.to_string()),
+                },
+                Package::GetTxStatus { txid, .. } => Package::TxStatus {
+                    addr_from,
+                    status: blockchain
+                        .get_transaction_status(txid)
+                        .map_err(|e| e.to_string()),
+                },
+                _ => return Ok(None),
+            };
+        Ok(Some(reply))
+    }
+
+    /// Process an incoming message, returning how the sender misbehaved if it did
+    fn process_message(
+        blockchain: &Blockchain,
+        context: &Arc<NodeContext>,
+        peer_addr: SocketAddr,
+        pkg: Package,
+    ) -> Result<Option<Misbehavior>> {
+        let handled = match pkg {
+            Package::Block { addr_from, block } => {
+                return Self::handle_block_message(blockchain, context, peer_addr, addr_from, block)
             }
             Package::GetBlocks { addr_from } => {
-                Self::handle_get_blocks_message(blockchain, addr_from)
+                Self::handle_get_blocks_message(blockchain, context, addr_from)
             }
             Package::GetData {
                 addr_from,
                 op_type,
                 id,
-            } => Self::handle_get_data_message(blockchain, addr_from, op_type, id),
+            } => Self::handle_get_data_message(
+                blockchain, context, peer_addr, addr_from, op_type, id,
+            ),
             Package::Inv {
                 addr_from,
                 op_type,
                 items,
-            } => Self::handle_inv_message(addr_from, op_type, items),
+            } => {
+                Self::handle_inv_message(blockchain, context, peer_addr, addr_from, op_type, items)
+            }
             Package::Tx {
-                addr_from: _,
+                addr_from,
                 transaction,
-            } => Self::handle_tx_message(blockchain, transaction),
+                allow_high_fee,
+                confirmed,
+            } => {
+                // Only the node's operator may insist on a fee above the maximum; taking a
+                // peer's word for it would let anyone fill the pool with coin-burning fees
+                let allow_high_fee = allow_high_fee && peer_addr.ip().is_loopback();
+                return Self::handle_tx_message(
+                    blockchain,
+                    context,
+                    &addr_from,
+                    transaction,
+                    allow_high_fee,
+                    confirmed,
+                );
+            }
+            Package::NotFound {
+                addr_from,
+                op_type,
+                id,
+            } => Self::handle_not_found_message(
+                context,
+                &addr_from,
+                Self::verified_addr(peer_addr, &addr_from),
+                op_type,
+                &id,
+            ),
             Package::Version {
                 addr_from,
                 version: _,
                 best_height,
-            } => Self::handle_version_message(blockchain, addr_from, best_height),
-        }
+                chain_work,
+                format_version,
+                genesis_hash,
+                timestamp: _,
+                wire_formats,
+                network_magic: _,
+            } => {
+                let verified = Self::verified_addr(peer_addr, &addr_from);
+                // Only a peer connecting from the address it claims says how to write to it
+                if let Some(listen_addr) = verified {
+                    let listen_addr = listen_addr.to_string();
+                    context.set_peer_format_version(&listen_addr, format_version);
+                    context
+                        .set_peer_wire_format(&listen_addr, WireFormat::negotiate(&wire_formats));
+                }
+                Self::handle_version_message(
+                    blockchain,
+                    context,
+                    addr_from,
+                    verified,
+                    best_height,
+                    chain_work,
+                    &genesis_hash,
+                )
+            }
+            Package::FilterLoad {
+                addr_from,
+                filter,
+                hash_funcs,
+                tweak,
+            } => {
+                let filter = BloomFilter::from_parts(filter, hash_funcs, tweak)?;
+                if context.set_peer_filter(peer_addr, filter) {
+                    info!("Peer {addr_from} at {peer_addr} loaded a bloom filter, sending it filtered blocks");
+                } else {
+                    warn!("Ignored the bloom filter of {addr_from} at {peer_addr}: too many light clients");
+                }
+                Ok(())
+            }
+            Package::MerkleBlock {
+                addr_from,
+                header,
+                proofs,
+            } => {
+                let merkle_block = verify_merkle_block(&header, &proofs)?;
+                info!(
+                    "Filtered block {} from {addr_from} proves {} transaction(s)",
+                    merkle_block.header.hash,
+                    merkle_block.txids.len()
+                );
+                Ok(())
+            }
+            Package::GetMempoolTx { .. }
+            | Package::GetStatus { .. }
+            | Package::GetBlockTemplate { .. }
+            | Package::SubmitBlock { .. }
+            | Package::GetMessageTrace { .. }
+            | Package::GetBalance { .. }
+            | Package::BanPeer { .. }
+            | Package::UnbanPeer { .. }
+            | Package::ListBans { .. }
+            | Package::GetChain { .. }
+            | Package::GetTxStatus { .. }
+            | Package::AbandonTx { .. }
+            | Package::GetPeerInfo { .. }
+            | Package::DisconnectPeer { .. } => {
+                // Queries are answered directly in handle_connection
+                Ok(())
+            }
+            // Replies only ever go back to whoever asked, on the connection they asked on
+            Package::MempoolTx { .. }
+            | Package::Status { .. }
+            | Package::BlockTemplate { .. }
+            | Package::SubmitBlockResult { .. }
+            | Package::MessageTrace { .. }
+            | Package::Balance { .. }
+            | Package::Bans { .. }
+            | Package::Chain { .. }
+            | Package::TxStatus { .. }
+            | Package::Abandoned { .. }
+            | Package::Peers { .. }
+            | Package::Disconnected { .. } => return Ok(Some(Misbehavior::UnsolicitedMessage)),
+            // What a peer says before closing a connection this node opened to it
+            Package::Rejected { addr_from, reason } => {
+                warn!("{addr_from} refused the connection: {reason}");
+                Ok(())
+            }
+            // handle_connection unwraps these, so one getting here was nested in another
+            Package::Signed { .. } => return Ok(Some(Misbehavior::InvalidPackage)),
+        };
+        handled.map(|()| None)
     }
 
     /// Handle incoming block message
+    ///
+    /// A block that can't be decoded or is proven invalid is the sender's misbehavior. One
+    /// whose parent is missing is kept as an orphan, since honest peers send out of order,
+    /// and one this node failed to store for its own reasons holds nothing against them.
     fn handle_block_message(
         blockchain: &Blockchain,
+        context: &Arc<NodeContext>,
+        peer_addr: SocketAddr,
         addr_from: String,
         block_data: Vec<u8>,
-    ) -> Result<()> {
-        let block = Block::deserialize(&block_data)
-            .map_err(|e| BlockchainError::Network(format!("Failed to deserialize block: {e}")))?;
+    ) -> Result<Option<Misbehavior>> {
+        let block = match Block::deserialize(&block_data) {
+            Ok(block) => block,
+            Err(e) => {
+                warn!("Undecodable block from {addr_from}: {e}");
+                return Ok(Some(Misbehavior::InvalidBlock));
+            }
+        };
+        context
+            .blocks_in_transit()
+            .mark_received(&block.get_hash_bytes());
+
+        // Whether the block simply extends the chain or triggers a reorg is only visible by
+        // comparing against the tip from before it was added
+        let previous_tip = blockchain.get_tip_hash();
+
+        // Blocks from peers are fully validated before they touch the database
+        let added = match blockchain.validate_and_add_block_with_context(&block, context) {
+            Ok(added) => added,
+            Err(
+                e @ (BlockchainError::InvalidBlock(_)
+                | BlockchainError::Transaction(_)
+                | BlockchainError::TransactionTooLarge(_)),
+            ) => {
+                warn!("Rejected block {} from {addr_from}: {e}", block.get_hash());
+                return Ok(Some(Misbehavior::InvalidBlock));
+            }
+            // A database or pruning failure here says nothing about the block
+            Err(e) => {
+                warn!(
+                    "Failed to add block {} from {addr_from}: {e}",
+                    block.get_hash()
+                );
+                return Ok(None);
+            }
+        };
+        match added {
+            BlockAddResult::AlreadyKnown => {
+                info!("Already have block {} from {}", block.get_hash(), addr_from)
+            }
+            BlockAddResult::Orphan => {
+                // Relayed blocks can overtake their parents; the sender has them, so I ask
+                // for its chain to fill the gap
+                info!(
+                    "Holding orphan block {} from {addr_from} until its parent arrives",
+                    block.get_hash()
+                );
+                Self::send_get_blocks(context, &addr_from)?;
+            }
+            result => {
+                info!(
+                    "Added block {} from {} ({result:?})",
+                    block.get_hash(),
+                    addr_from
+                );
+                if result == BlockAddResult::TipAdvanced {
+                    Self::update_chainstate(blockchain, context, &block, &previous_tip)?;
+                    context.return_reorged_transactions(blockchain);
+                    blockchain.prune_if_enabled();
+                    Self::restart_stale_mining(blockchain, context)?;
+                    // The sender has the block already
+                    Self::announce(
+                        context,
+                        OpType::Block,
+                        &[block.get_hash_bytes()],
+                        Some(&addr_from),
+                    );
+                }
+                Self::record_sync_progress(blockchain, context, &addr_from, &block);
+            }
+        }
+
+        // The sender answered, so it is asked for the next block to download
+        if let Some(block_hash) =
+            context
+                .blocks_in_transit()
+                .next_to_request(&addr_from, peer_addr, Instant::now())
+        {
+            Self::send_get_data(context, &addr_from, OpType::Block, &block_hash)?;
+        }
+
+        Ok(None)
+    }
 
-        // Add block to blockchain
-        blockchain
-            .add_block(&block)
-            .map_err(|e| BlockchainError::Network(format!("Failed to add block: {e}")))?;
+    /// Count `block` towards the sync, logging the progress every few seconds while behind
+    ///
+    /// The peer that sent the block has a chain at least that high.
+    fn record_sync_progress(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        addr_from: &str,
+        block: &Block,
+    ) {
+        let now = Instant::now();
+        let mut sync = context.sync_state();
+        sync.record_peer_height(addr_from, block.get_height(), now);
+        match blockchain.get_best_height() {
+            Ok(height) => sync.record_block(height, now),
+            Err(e) => warn!("Failed to read the best height for sync progress: {e}"),
+        }
+        if let Some(line) = sync.progress_line(now) {
+            info!("{line}");
+        }
+    }
 
-        info!("Added block {} from {}", block.get_hash(), addr_from);
+    /// Bring the chainstate up to date after `block` became the tip
+    ///
+    /// A block connected directly onto `previous_tip` is applied on its own. A reorg, a
+    /// chainstate that was never built, or a failed update falls back to a full reindex,
+    /// which a pruned chain refuses. A chainstate found inconsistent is reindexed in the
+    /// background instead, and blocks arriving meanwhile wait for that to finish.
+    fn update_chainstate(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        block: &Block,
+        previous_tip: &str,
+    ) -> Result<()> {
+        let utxo_set = UTXOSet::new(blockchain.clone());
 
-        // Handle blocks in transit
-        if !GLOBAL_BLOCKS_IN_TRANSIT.is_empty() {
-            if let Some(block_hash) = GLOBAL_BLOCKS_IN_TRANSIT.first() {
-                Self::send_get_data(&addr_from, OpType::Block, &block_hash)?;
-                GLOBAL_BLOCKS_IN_TRANSIT.remove(&block_hash);
+        {
+            let rebuild = context.chainstate_rebuild();
+            let mut rebuild = rebuild
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if rebuild.running {
+                rebuild.again = true;
+                return Ok(());
             }
+        }
+
+        if block.get_pre_block_hash() != previous_tip {
+            info!(
+                "Block {} reorganized the chain away from {previous_tip}, reindexing the chainstate",
+                block.get_hash()
+            );
+        } else if blockchain.get_tip_hash() != block.get_hash() {
+            info!(
+                "Orphans connected on top of block {}, reindexing the chainstate",
+                block.get_hash()
+            );
+        } else if utxo_set.is_empty()? {
+            info!("Chainstate is missing, reindexing");
         } else {
-            let utxo_set = UTXOSet::new(blockchain.clone());
-            utxo_set.reindex();
+            match utxo_set.update_safe(block) {
+                Ok(()) => return Ok(()),
+                Err(e) if is_chainstate_inconsistent(&e) => {
+                    warn!(
+                        "Block {} doesn't fit the chainstate, reindexing in the background: {e}",
+                        block.get_hash()
+                    );
+                    Self::start_chainstate_rebuild(utxo_set, context);
+                    return Ok(());
+                }
+                Err(e) => warn!(
+                    "Failed to apply block {} to the chainstate, reindexing: {e}",
+                    block.get_hash()
+                ),
+            }
         }
 
-        Ok(())
+        utxo_set.reindex_safe()
+    }
+
+    // Reindex until a pass finishes with no block having arrived during it
+    fn start_chainstate_rebuild(utxo_set: UTXOSet, context: &NodeContext) {
+        let rebuild = context.chainstate_rebuild();
+        {
+            let mut state = rebuild
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if state.running {
+                state.again = true;
+                return;
+            }
+            state.running = true;
+        }
+
+        thread::spawn(move || loop {
+            match utxo_set.reindex_safe() {
+                Ok(()) => info!("Chainstate rebuilt"),
+                Err(e) => error!("Failed to rebuild the chainstate: {e}"),
+            }
+            let mut state = rebuild
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if !state.again {
+                state.running = false;
+                break;
+            }
+            state.again = false;
+        });
     }
 
     /// Handle get blocks message
-    fn handle_get_blocks_message(blockchain: &Blockchain, addr_from: String) -> Result<()> {
-        let blocks = blockchain.get_block_hashes();
-        Self::send_inv(&addr_from, OpType::Block, &blocks)
+    fn handle_get_blocks_message(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        addr_from: String,
+    ) -> Result<()> {
+        // Oldest first, so every block the peer asks for arrives after its parent
+        let mut blocks = blockchain.get_block_hashes();
+        blocks.reverse();
+        Self::send_inv(context, &addr_from, OpType::Block, &blocks)
     }
 
     /// Handle get data message
+    ///
+    /// A transaction that left the memory pool for a block is still served from the
+    /// transaction index, marked confirmed. Anything the node doesn't have, or only has
+    /// the pruned header of, is answered with NotFound. Blocks go out as `MerkleBlock` to a
+    /// light client that loaded a filter over the connection the request came in on.
     fn handle_get_data_message(
         blockchain: &Blockchain,
+        context: &NodeContext,
+        peer_addr: SocketAddr,
         addr_from: String,
         op_type: OpType,
         id: Vec<u8>,
     ) -> Result<()> {
         match op_type {
             OpType::Block => match blockchain.get_block_by_bytes(&id) {
-                Ok(Some(block)) => {
-                    Self::send_block(&addr_from, &block)?;
+                // Only the header is left, which a peer asking for the block can't use
+                Ok(Some(block)) if block.is_pruned() => {
+                    info!(
+                        "Not sending pruned block {} to {addr_from}",
+                        block.get_hash()
+                    );
+                    Self::send_not_found(context, &addr_from, op_type, &id)?;
                 }
+                Ok(Some(block)) => match context.get_peer_filter(peer_addr) {
+                    Some(filter) => Self::send_merkle_block(context, &addr_from, &block, &filter)?,
+                    None => Self::send_block(context, &addr_from, &block)?,
+                },
                 Ok(None) => {
                     info!("Block not found for requested hash");
+                    Self::send_not_found(context, &addr_from, op_type, &id)?;
                 }
                 Err(e) => {
                     error!("Failed to get block: {e}");
@@ -297,8 +2188,69 @@ impl Server {
             },
             OpType::Tx => {
                 let txid_hex = HEXLOWER.encode(&id);
-                if let Some(tx) = GLOBAL_MEMORY_POOL.get(&txid_hex) {
-                    Self::send_tx(&addr_from, &tx)?;
+                if let Some(tx) = context.memory_pool().get(&txid_hex) {
+                    Self::send_tx(context, &addr_from, &tx, false)?;
+                    return Ok(());
+                }
+                match blockchain.find_transaction(&id) {
+                    Ok(Some(tx)) => Self::send_tx(context, &addr_from, &tx, true)?,
+                    // Unknown, or in a block pruned since
+                    Ok(None) | Err(BlockchainError::Pruned(_)) => {
+                        info!("Transaction {txid_hex} requested by {addr_from} not found");
+                        Self::send_not_found(context, &addr_from, op_type, &id)?;
+                    }
+                    Err(e) => {
+                        error!("Failed to look up transaction {txid_hex}: {e}");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a peer not having what this node asked it for
+    ///
+    /// A missing transaction is no longer waited for, so the next peer announcing it gets
+    /// asked. A missing block leaves the download queue, and the next queued one is asked
+    /// for instead, since blocks are only requested as earlier ones arrive.
+    ///
+    /// Only the peer something was asked from, connecting from the address it was asked
+    /// at, can report it missing; anything else is ignored, so nobody can cancel another
+    /// peer's downloads.
+    fn handle_not_found_message(
+        context: &NodeContext,
+        addr_from: &str,
+        verified: Option<SocketAddr>,
+        op_type: OpType,
+        id: &[u8],
+    ) -> Result<()> {
+        let asked = match op_type {
+            OpType::Tx => context.tx_requested_from(id),
+            OpType::Block => context.blocks_in_transit().requested_from(id),
+        };
+        let Some(connection) = verified.filter(|_| asked.as_deref() == Some(addr_from)) else {
+            info!("Ignoring NotFound from {addr_from} for something it wasn't asked for");
+            return Ok(());
+        };
+        match op_type {
+            OpType::Tx => {
+                info!(
+                    "{addr_from} doesn't have transaction {}",
+                    HEXLOWER.encode(id)
+                );
+                context.forget_tx_request(id);
+            }
+            OpType::Block => {
+                info!(
+                    "{addr_from} doesn't have block {}",
+                    String::from_utf8_lossy(id)
+                );
+                let blocks_in_transit = context.blocks_in_transit();
+                blocks_in_transit.mark_received(id);
+                if let Some(block_hash) =
+                    blocks_in_transit.next_to_request(addr_from, connection, Instant::now())
+                {
+                    Self::send_get_data(context, addr_from, OpType::Block, &block_hash)?;
                 }
             }
         }
@@ -306,20 +2258,49 @@ impl Server {
     }
 
     /// Handle inventory message
-    fn handle_inv_message(addr_from: String, op_type: OpType, items: Vec<Vec<u8>>) -> Result<()> {
+    ///
+    /// Blocks the node already has aren't asked for again, which keeps a peer's whole
+    /// inventory, sent to fill a gap, down to the missing blocks.
+    fn handle_inv_message(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        peer_addr: SocketAddr,
+        addr_from: String,
+        op_type: OpType,
+        items: Vec<Vec<u8>>,
+    ) -> Result<()> {
         match op_type {
             OpType::Block => {
-                GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(&items);
-                if let Some(block_hash) = items.first() {
-                    Self::send_get_data(&addr_from, OpType::Block, block_hash)?;
-                    GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash);
+                let mut missing = Vec::with_capacity(items.len());
+                for hash in items {
+                    let known = match std::str::from_utf8(&hash) {
+                        Ok(hash) => blockchain.block_exists(hash)?,
+                        Err(_) => false,
+                    };
+                    if !known {
+                        missing.push(hash);
+                    }
+                }
+                // Blocks already queued or in flight keep their place
+                let blocks_in_transit = context.blocks_in_transit();
+                blocks_in_transit.add_blocks(&missing);
+                if let Some(block_hash) =
+                    blocks_in_transit.next_to_request(&addr_from, peer_addr, Instant::now())
+                {
+                    Self::send_get_data(context, &addr_from, OpType::Block, &block_hash)?;
                 }
             }
+            // Each one not pending or abandoned yet, unless another peer was already asked
+            // for it
             OpType::Tx => {
-                if let Some(txid) = items.first() {
+                for txid in &items {
                     let txid_hex = HEXLOWER.encode(txid);
-                    if !GLOBAL_MEMORY_POOL.contains(&txid_hex) {
-                        Self::send_get_data(&addr_from, OpType::Tx, txid)?;
+                    let pool = context.memory_pool();
+                    if !pool.contains(&txid_hex)
+                        && !pool.is_abandoned(&txid_hex)
+                        && context.request_tx(txid, &addr_from)
+                    {
+                        Self::send_get_data(context, &addr_from, OpType::Tx, txid)?;
                     }
                 }
             }
@@ -328,116 +2309,561 @@ impl Server {
     }
 
     /// Handle transaction message
-    fn handle_tx_message(blockchain: &Blockchain, transaction_data: Vec<u8>) -> Result<()> {
-        let tx = Transaction::deserialize(&transaction_data).map_err(|e| {
-            BlockchainError::Network(format!("Failed to deserialize transaction: {e}"))
-        })?;
+    ///
+    /// An undecodable transaction, or one spending outputs the chain doesn't have, counts
+    /// against the sender; one the pool won't take for its fee or input count doesn't. A `confirmed` one
+    /// only answers an earlier request and stays out of the pool.
+    fn handle_tx_message(
+        blockchain: &Blockchain,
+        context: &Arc<NodeContext>,
+        addr_from: &str,
+        transaction_data: Vec<u8>,
+        allow_high_fee: bool,
+        confirmed: bool,
+    ) -> Result<Option<Misbehavior>> {
+        let tx = match Transaction::deserialize(&transaction_data) {
+            Ok(tx) => tx,
+            Err(e) => {
+                warn!("Undecodable transaction: {e}");
+                return Ok(Some(Misbehavior::InvalidTransaction));
+            }
+        };
+        METRICS.txs_received.inc();
+        context.forget_tx_request(tx.get_id());
 
-        GLOBAL_MEMORY_POOL.add(tx);
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        if confirmed {
+            info!("Transaction {txid_hex} from {addr_from} is already in a block");
+            return Ok(None);
+        }
+        // Size, shape and id are checked before any signature, so junk costs no verification.
+        // The input limit is only this node's policy and holds nothing against the sender.
+        if tx.exceeds_input_limit() {
+            warn!(
+                "Rejected transaction {txid_hex} from {addr_from}: {} inputs are over the limit",
+                tx.get_vin().len()
+            );
+            return Ok(None);
+        }
+        if let Err(e) = tx.check_limits().and_then(|()| tx.check_id(false)) {
+            warn!("Rejected transaction {txid_hex} from {addr_from}: {e}");
+            return Ok(Some(Misbehavior::InvalidTransaction));
+        }
+        // Inputs are looked up in the chainstate, then among the pending transactions. One
+        // naming an output that can never exist is the sender's fault; one that is missing
+        // or already spent may only be from a parent not seen yet or lost to a block that
+        // just arrived, so it is dropped without holding it against them. A coinbase
+        // spends nothing and is left to the pool to refuse.
+        let inputs = if tx.is_coinbase() {
+            &[][..]
+        } else {
+            tx.get_vin()
+        };
+        let mut spent_outputs = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let (txid, vout) = (input.get_txid(), input.get_vout());
+            if let Some(output) = blockchain.find_unspent_output(txid, vout)? {
+                spent_outputs.push(output);
+                continue;
+            }
+            let pending = context.memory_pool().get(&HEXLOWER.encode(txid));
+            let source = match pending.clone() {
+                Some(parent) => Some(parent),
+                None => blockchain.find_transaction(txid)?,
+            };
+            let outpoint = format!("{}:{vout}", HEXLOWER.encode(txid));
+            match source.as_ref().map(|parent| parent.get_vout().get(vout)) {
+                Some(None) => {
+                    warn!("Rejected transaction {txid_hex} from {addr_from}: output {outpoint} does not exist");
+                    return Ok(Some(Misbehavior::InvalidTransaction));
+                }
+                Some(Some(output)) if output.is_data_carrier() => {
+                    warn!("Rejected transaction {txid_hex} from {addr_from}: output {outpoint} is a data output");
+                    return Ok(Some(Misbehavior::InvalidTransaction));
+                }
+                Some(Some(output)) if pending.is_some() => spent_outputs.push(output.clone()),
+                Some(Some(_)) => {
+                    info!("Dropped transaction {txid_hex} from {addr_from}: output {outpoint} is already spent");
+                    return Ok(None);
+                }
+                None => {
+                    info!("Dropped transaction {txid_hex} from {addr_from}: output {outpoint} is unknown");
+                    return Ok(None);
+                }
+            }
+        }
+        // The declared fee decides replacements, so a spend nobody signed, or one claiming
+        // more than its inputs hold, must never reach the pool and push out an honest one
+        if !tx.is_coinbase() && !tx.verify_spending(&spent_outputs, true, false) {
+            warn!("Rejected transaction {txid_hex} from {addr_from}: its signatures or balance don't hold");
+            return Ok(Some(Misbehavior::InvalidTransaction));
+        }
+        let already_pending = context.memory_pool().contains(&txid_hex);
+        match context.admit_transaction(tx.clone(), allow_high_fee) {
+            Ok(replaced) if !replaced.is_empty() => {
+                info!(
+                    "Transaction {txid_hex} replaced {} pending transaction(s)",
+                    replaced.len()
+                );
+            }
+            Ok(_) => {}
+            // Nobody relays a coinbase by mistake, it only ever lives in its miner's block
+            Err(e @ BlockchainError::CoinbaseNotAllowed(_)) => {
+                warn!("Rejected transaction {txid_hex} from {addr_from}: {e}");
+                return Ok(Some(Misbehavior::InvalidTransaction));
+            }
+            Err(e) => {
+                warn!("Rejected transaction {txid_hex}: {e}");
+                return Ok(None);
+            }
+        }
+        // Relays of a transaction the pool already holds were reported the first time
+        if !already_pending {
+            blockchain.notify_unconfirmed(&tx);
+            Self::announce(
+                context,
+                OpType::Tx,
+                &[tx.get_id().to_vec()],
+                Some(addr_from),
+            );
+        }
 
         // Check if we should mine a block
-        if GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD && GLOBAL_CONFIG.is_miner() {
-            Self::try_mine_block(blockchain)?;
+        let threshold = context
+            .config()
+            .get_transaction_threshold()
+            .unwrap_or(TRANSACTION_THRESHOLD);
+        if context.memory_pool().len() >= threshold && context.config().is_miner() {
+            Self::try_mine_block(blockchain, context)?;
         }
 
-        Ok(())
+        Ok(None)
     }
 
     /// Handle version message
+    ///
+    /// Whichever side has the heavier chain catches the other up, unless the two chains
+    /// start from different genesis blocks and so can never share a block. Only a sender
+    /// whose `addr_from` is on the host the message came from, `verified`, is announced
+    /// to from then on, so nobody can have this node relay to a third party.
     fn handle_version_message(
         blockchain: &Blockchain,
+        context: &NodeContext,
         addr_from: String,
+        verified: Option<SocketAddr>,
         best_height: usize,
+        chain_work: u128,
+        genesis_hash: &str,
     ) -> Result<()> {
-        info!("Version message from {addr_from}, best_height={best_height}");
+        info!(
+            "Version message from {addr_from}, best_height={best_height}, chain_work={chain_work}"
+        );
+        let local_genesis = blockchain.get_genesis_hash()?;
+        if !genesis_hash.is_empty() && genesis_hash != local_genesis {
+            warn!(
+                "Not syncing with {addr_from}: {}",
+                BlockchainError::DifferentNetwork {
+                    genesis: genesis_hash.to_string(),
+                    expected: local_genesis,
+                }
+            );
+            return Ok(());
+        }
+        match verified {
+            Some(verified) if !context.add_relay_peer(&verified.to_string()) => {
+                info!("Not relaying to {verified}, already relaying to as many peers as allowed")
+            }
+            // Only a peer relayed to hears about the memory pool, at the address it
+            // connected from
+            Some(verified) => Self::announce_memory_pool(context, &verified.to_string()),
+            None => info!("Not relaying to {addr_from}, which the message didn't come from"),
+        }
+        context
+            .sync_state()
+            .record_peer_height(&addr_from, best_height, Instant::now());
 
         // Handle blockchain synchronization
-        match blockchain.get_best_height() {
-            Ok(local_best_height) => {
-                if local_best_height < best_height {
-                    Self::send_get_blocks(&addr_from)?;
+        match Self::local_tip(blockchain) {
+            Ok((local_best_height, local_chain_work)) => {
+                // A peer that doesn't advertise work can only be compared by height
+                let (local, remote) = if chain_work == 0 {
+                    (local_best_height as u128, best_height as u128)
+                } else {
+                    (local_chain_work, chain_work)
+                };
+                if local < remote {
+                    Self::send_get_blocks(context, &addr_from)?;
                 }
-                if local_best_height > best_height {
-                    Self::send_version(&addr_from, local_best_height)?;
+                if local > remote {
+                    Self::send_version(
+                        blockchain,
+                        context,
+                        &addr_from,
+                        local_best_height,
+                        local_chain_work,
+                    )?;
                 }
             }
             Err(e) => {
-                error!("Failed to get local best height: {e}");
+                error!("Failed to get local chain tip: {e}");
             }
         }
 
         Ok(())
     }
 
-    /// Try to mine a block with current transactions
-    fn try_mine_block(blockchain: &Blockchain) -> Result<()> {
-        let mining_address = GLOBAL_CONFIG
-            .get_mining_addr()
-            .ok_or_else(|| BlockchainError::Network("Mining address not configured".to_string()))?;
-
-        let coinbase_tx = Transaction::new_coinbase_tx(&mining_address).map_err(|e| {
-            BlockchainError::Network(format!("Failed to create coinbase transaction: {e}"))
-        })?;
-
-        let mut txs = GLOBAL_MEMORY_POOL.get_all();
-        txs.push(coinbase_tx);
-
-        let new_block = blockchain
-            .mine_block(&txs)
-            .map_err(|e| BlockchainError::Network(format!("Failed to mine block: {e}")))?;
-
-        let utxo_set = UTXOSet::new(blockchain.clone());
-        utxo_set.reindex();
-        info!("New block {} is mined!", new_block.get_hash());
+    /// Tell a peer that just said hello about the best-paying pending transactions it
+    /// hasn't heard of from this node yet
+    ///
+    /// Transactions relayed before it connected would otherwise only reach it in a block.
+    fn announce_memory_pool(context: &NodeContext, addr: &str) {
+        if addr == context.config().get_node_addr() {
+            return;
+        }
+        let txids = context
+            .memory_pool()
+            .txids_by_fee_rate(MEMPOOL_ANNOUNCE_LIMIT);
+        let fresh = context.unannounced_txs(addr, &txids);
+        if fresh.is_empty() {
+            return;
+        }
+        if let Err(e) = Self::send_inv(context, addr, OpType::Tx, &fresh) {
+            warn!("Failed to announce the memory pool to {addr}: {e}");
+            context.remove_relay_peer(addr);
+        }
+    }
 
-        // Clear mined transactions from memory pool
-        for tx in &txs {
-            let txid_hex = HEXLOWER.encode(tx.get_id());
-            GLOBAL_MEMORY_POOL.remove(&txid_hex);
+    /// Height and total work of the local chain
+    fn local_tip(blockchain: &Blockchain) -> Result<(usize, u128)> {
+        let best_height = blockchain.get_best_height()?;
+        let chain_work = blockchain.get_chain_work(&blockchain.get_tip_hash())?;
+        Ok((best_height, chain_work))
+    }
+
+    /// Start mining the memory pool's transactions unless a mining job is already running
+    fn try_mine_block(blockchain: &Blockchain, context: &Arc<NodeContext>) -> Result<()> {
+        let mut job = context.mining_job();
+        if job.as_ref().is_some_and(|handle| !handle.is_finished()) {
+            info!("Already mining a block, new transactions wait for the next one");
+            return Ok(());
+        }
+
+        *job = Some(Self::start_mining_job(blockchain, context, true)?);
+        Ok(())
+    }
+
+    /// Cancel a mining job left behind by a new tip and start over on top of that tip
+    ///
+    /// Transactions the new tip confirmed or conflicts with are dropped from the memory
+    /// pool first, so the fresh template only carries ones that are still valid. That check
+    /// runs on a snapshot of the pool after the job slot is released, so other handlers
+    /// aren't held up while every pooled transaction is verified.
+    fn restart_stale_mining(blockchain: &Blockchain, context: &Arc<NodeContext>) -> Result<()> {
+        {
+            let mut job = context.mining_job();
+            let Some(handle) = job.as_ref() else {
+                return Ok(());
+            };
+            if handle.is_finished() || handle.parent_hash() == blockchain.get_tip_hash() {
+                return Ok(());
+            }
+
+            info!(
+                "Mining job for height {} is stale, restarting on the new tip",
+                handle.height()
+            );
+            handle.cancel();
+            *job = None;
+        }
+
+        Self::drop_invalidated_transactions(blockchain, context);
+
+        if context.config().is_miner() {
+            // Another handler may have started a job in the meantime
+            Self::try_mine_block(blockchain, context)?;
+        }
+        Ok(())
+    }
+
+    // A pending transaction stays while each input is unspent in the chainstate or an
+    // output of another pending one, so a child waits for its parent to be mined. Once a
+    // block spent an input or took its source away, it goes, and then whatever spent it.
+    fn drop_invalidated_transactions(blockchain: &Blockchain, context: &NodeContext) {
+        let pool = context.memory_pool();
+        let mut dropped_any = true;
+        while dropped_any {
+            dropped_any = false;
+            for tx in pool.get_all() {
+                let txid_hex = HEXLOWER.encode(tx.get_id());
+                let valid = match Self::pending_spent_outputs(blockchain, context, &tx) {
+                    Ok(Some(spent_outputs)) => tx.verify_spending(&spent_outputs, true, false),
+                    Ok(None) => false,
+                    Err(e) => {
+                        // A failed lookup says nothing against the transaction
+                        warn!("Failed to look up the inputs of {txid_hex}: {e}");
+                        true
+                    }
+                };
+                if !valid {
+                    info!("Dropping transaction {txid_hex} invalidated by the new tip");
+                    dropped_any |= pool.evict(&txid_hex, EvictionReason::Invalidated);
+                }
+            }
+        }
+    }
+
+    // The outputs `tx`'s inputs spend, in input order, from the chainstate or a pending
+    // parent; `None` if one of them is spent or doesn't exist
+    fn pending_spent_outputs(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        tx: &Transaction,
+    ) -> Result<Option<Vec<TXOutput>>> {
+        let mut spent_outputs = Vec::with_capacity(tx.get_vin().len());
+        for input in tx.get_vin() {
+            let (txid, vout) = (input.get_txid(), input.get_vout());
+            let output = match blockchain.find_unspent_output(txid, vout)? {
+                Some(output) => Some(output),
+                None => context
+                    .memory_pool()
+                    .get(&HEXLOWER.encode(txid))
+                    .and_then(|parent| parent.get_vout().get(vout).cloned()),
+            };
+            match output {
+                Some(output) => spent_outputs.push(output),
+                None => return Ok(None),
+            }
         }
+        Ok(Some(spent_outputs))
+    }
+
+    // The proof-of-work runs on the node's miner; a waiter thread stores the block once a
+    // nonce is found, so message handling never blocks on mining. If a peer's block took
+    // the tip between the solution and storing it, the job starts over on the new tip,
+    // once when `retry_stale` is set.
+    fn start_mining_job(
+        blockchain: &Blockchain,
+        context: &Arc<NodeContext>,
+        retry_stale: bool,
+    ) -> Result<MiningHandle> {
+        let mining_address = context
+            .config()
+            .get_mining_addr()
+            .ok_or_else(|| BlockchainError::Network("Mining address not configured".to_string()))?;
+
+        // The miner path builds the coinbase itself, paying out the collected fees
+        let txs = BlockAssembler::new(blockchain)
+            .select(&mining_address, context.memory_pool().get_all())
+            .map_err(|e| {
+                BlockchainError::Network(format!("Failed to pick block transactions: {e}"))
+            })?;
+        let template = blockchain
+            .new_block_template(&txs, &mining_address, context)
+            .map_err(|e| {
+                BlockchainError::Network(format!("Failed to build block template: {e}"))
+            })?;
+        let handle = context.miner().start(template);
+
+        let waiter = handle.clone();
+        let blockchain = blockchain.clone();
+        let context = Arc::clone(context);
+        thread::spawn(move || {
+            let stale = match waiter.wait_for_solution() {
+                Some((block, solution)) => {
+                    match Self::store_mined_block(&blockchain, &context, &block) {
+                        Ok(()) => {
+                            blockchain.record_mining_stats(&block, &solution);
+                            false
+                        }
+                        Err(e) if e.is_stale_tip() => {
+                            warn!("Mined block {} lost the race for the tip", block.get_hash());
+                            true
+                        }
+                        Err(e) => {
+                            error!("Failed to store mined block: {e}");
+                            false
+                        }
+                    }
+                }
+                None => false,
+            };
+
+            let mut job = context.mining_job();
+            if job
+                .as_ref()
+                .is_some_and(|current| current.is_same_job(&waiter))
+            {
+                *job = None;
+                if stale && retry_stale {
+                    Self::drop_invalidated_transactions(&blockchain, &context);
+                    match Self::start_mining_job(&blockchain, &context, false) {
+                        Ok(handle) => *job = Some(handle),
+                        Err(e) => error!("Failed to restart mining on the new tip: {e}"),
+                    }
+                }
+            }
+        });
+
+        Ok(handle)
+    }
+
+    /// Connect a block an external miner solved from one of this node's templates
+    ///
+    /// The node's own mining job builds on the old tip afterwards, so it starts over.
+    fn handle_submitted_block(
+        blockchain: &Blockchain,
+        context: &Arc<NodeContext>,
+        block_data: &[u8],
+    ) -> Result<()> {
+        let block = Block::deserialize(block_data)
+            .map_err(|e| BlockchainError::Network(format!("Failed to deserialize block: {e}")))?;
+        blockchain.submit_block_with_context(block.clone(), context)?;
+        Self::connect_own_block(blockchain, context, &block);
+        Self::restart_stale_mining(blockchain, context)
+    }
 
+    /// Add a block this node mined to the chain and clear its transactions from the pool
+    ///
+    /// Fails with a stale-tip error if the block no longer builds on the tip.
+    fn store_mined_block(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        block: &Block,
+    ) -> Result<()> {
+        blockchain.add_mined_block_with_context(block, context)?;
+        Self::connect_own_block(blockchain, context, block);
         Ok(())
     }
 
+    // A block this node committed onto the tip, either mined here or submitted by an
+    // external miner
+    fn connect_own_block(blockchain: &Blockchain, context: &NodeContext, block: &Block) {
+        // The block was committed onto its own parent, so this never counts as a reorg
+        match Self::update_chainstate(blockchain, context, block, &block.get_pre_block_hash()) {
+            Ok(()) => blockchain.prune_if_enabled(),
+            Err(e) => error!("Failed to update the chainstate for mined block: {e}"),
+        }
+        info!("New block {} is mined!", block.get_hash());
+
+        // Clear mined transactions from memory pool
+        for tx in block.get_transactions() {
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            context.memory_pool().remove(&txid_hex);
+        }
+        Self::announce(context, OpType::Block, &[block.get_hash_bytes()], None);
+    }
+
     /// Send version message
-    fn send_version(addr: &str, height: usize) -> Result<()> {
+    fn send_version(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        addr: &str,
+        height: usize,
+        chain_work: u128,
+    ) -> Result<()> {
         let socket_addr = addr
             .parse::<SocketAddr>()
             .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
 
-        let node_addr = GLOBAL_CONFIG.get_node_addr();
+        let data = Self::version_handshake(blockchain, context, socket_addr, height, chain_work)?;
+        context
+            .outbound_connections()
+            .send_handshake(socket_addr, &data)?;
+        context.add_relay_peer(addr);
+        Ok(())
+    }
 
-        let pkg = Package::Version {
-            addr_from: node_addr,
+    // A version opens a new connection by itself, so it isn't preceded by another one, and
+    // goes as JSON as it is how peers learn what else this node reads
+    fn version_handshake(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        addr: SocketAddr,
+        height: usize,
+        chain_work: u128,
+    ) -> Result<Vec<u8>> {
+        let pkg = Self::version_package(blockchain, context, height, chain_work)?;
+        Self::encode_package(context, addr, &pkg, WireFormat::Json)
+    }
+
+    // This node's network magic, which every peer's version message has to carry: the one
+    // its database was created with, or the configured network's for a database from
+    // before magics were recorded
+    fn network_magic(blockchain: &Blockchain, context: &NodeContext) -> Result<NetworkMagic> {
+        match blockchain.get_network_magic()? {
+            Some(magic) => Ok(magic),
+            None => Ok(Self::network(blockchain, context)?.magic(&blockchain.get_genesis_hash()?)),
+        }
+    }
+
+    // The network this node's database was created for, or the configured one
+    fn network(blockchain: &Blockchain, context: &NodeContext) -> Result<Network> {
+        Ok(blockchain
+            .get_network()?
+            .unwrap_or_else(|| context.config().get_network()))
+    }
+
+    // Why a peer whose version message carries `magic` can't be dealt with, or `None` if
+    // it is on this node's network. One too old to send a magic is taken while
+    // `allow_unversioned_network` is on; the genesis check still keeps it from syncing.
+    fn network_refusal(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        magic: Option<NetworkMagic>,
+    ) -> Result<Option<String>> {
+        let own = Self::network_magic(blockchain, context)?;
+        Ok(match magic {
+            Some(magic) if magic == own => None,
+            Some(magic) => Some(format!(
+                "is on network {magic}, not this node's {} ({own})",
+                Self::network(blockchain, context)?
+            )),
+            None if context.config().allow_unversioned_network() => None,
+            None => Some("doesn't say which network it is on".to_string()),
+        })
+    }
+
+    fn version_package(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        height: usize,
+        chain_work: u128,
+    ) -> Result<Package> {
+        Ok(Package::Version {
+            addr_from: context.config().get_node_addr(),
             version: NODE_VERSION,
             best_height: height,
-        };
-
-        Self::send_data(socket_addr, pkg)
+            chain_work,
+            format_version: FORMAT_VERSION,
+            genesis_hash: blockchain.get_genesis_hash()?,
+            timestamp: current_timestamp()?,
+            wire_formats: SUPPORTED_WIRE_FORMATS.to_vec(),
+            network_magic: Some(Self::network_magic(blockchain, context)?),
+        })
     }
 
     /// Send get blocks message
-    fn send_get_blocks(addr: &str) -> Result<()> {
+    fn send_get_blocks(context: &NodeContext, addr: &str) -> Result<()> {
         let socket_addr = addr
             .parse::<SocketAddr>()
             .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
 
-        let node_addr = GLOBAL_CONFIG.get_node_addr();
+        let node_addr = context.config().get_node_addr();
 
         let pkg = Package::GetBlocks {
             addr_from: node_addr,
         };
 
-        Self::send_data(socket_addr, pkg)
+        Self::send_data(context, socket_addr, pkg)
     }
 
     /// Send get data message
-    fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) -> Result<()> {
+    fn send_get_data(context: &NodeContext, addr: &str, op_type: OpType, id: &[u8]) -> Result<()> {
         let socket_addr = addr
             .parse::<SocketAddr>()
             .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
 
-        let node_addr = GLOBAL_CONFIG.get_node_addr();
+        let node_addr = context.config().get_node_addr();
 
         let pkg = Package::GetData {
             addr_from: node_addr,
@@ -445,16 +2871,36 @@ impl Server {
             id: id.to_vec(),
         };
 
-        Self::send_data(socket_addr, pkg)
+        Self::send_data(context, socket_addr, pkg)
+    }
+
+    /// Tell the peer at `addr` this node doesn't have what it asked for
+    fn send_not_found(context: &NodeContext, addr: &str, op_type: OpType, id: &[u8]) -> Result<()> {
+        let socket_addr = addr
+            .parse::<SocketAddr>()
+            .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
+
+        let pkg = Package::NotFound {
+            addr_from: context.config().get_node_addr(),
+            op_type,
+            id: id.to_vec(),
+        };
+
+        Self::send_data(context, socket_addr, pkg)
     }
 
     /// Send inventory message
-    fn send_inv(addr: &str, op_type: OpType, items: &[Vec<u8>]) -> Result<()> {
+    fn send_inv(
+        context: &NodeContext,
+        addr: &str,
+        op_type: OpType,
+        items: &[Vec<u8>],
+    ) -> Result<()> {
         let socket_addr = addr
             .parse::<SocketAddr>()
             .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
 
-        let node_addr = GLOBAL_CONFIG.get_node_addr();
+        let node_addr = context.config().get_node_addr();
 
         let pkg = Package::Inv {
             addr_from: node_addr,
@@ -462,18 +2908,39 @@ impl Server {
             items: items.to_vec(),
         };
 
-        Self::send_data(socket_addr, pkg)
+        Self::send_data(context, socket_addr, pkg)
+    }
+
+    /// Tell every relay peer but `except` about new blocks or transactions
+    ///
+    /// Peers that can't be reached are forgotten until they send a version message again.
+    fn announce(context: &NodeContext, op_type: OpType, items: &[Vec<u8>], except: Option<&str>) {
+        let own_addr = context.config().get_node_addr();
+        for peer in context.relay_peers() {
+            if peer == own_addr || except == Some(peer.as_str()) {
+                continue;
+            }
+            // Remembered, so the peer's next handshake doesn't announce them again
+            if let OpType::Tx = op_type {
+                context.unannounced_txs(&peer, items);
+            }
+            if let Err(e) = Self::send_inv(context, &peer, op_type, items) {
+                warn!("Failed to announce {op_type:?} to {peer}: {e}");
+                context.remove_relay_peer(&peer);
+            }
+        }
     }
 
     /// Send block message
-    fn send_block(addr: &str, block: &Block) -> Result<()> {
+    fn send_block(context: &NodeContext, addr: &str, block: &Block) -> Result<()> {
         let socket_addr = addr
             .parse::<SocketAddr>()
             .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
 
-        let node_addr = GLOBAL_CONFIG.get_node_addr();
+        let node_addr = context.config().get_node_addr();
+        // In a format the peer said it reads, the unversioned one if it never said
         let block_data = block
-            .serialize()
+            .serialize_as(context.peer_format_version(addr))
             .map_err(|e| BlockchainError::Network(format!("Failed to serialize block: {e}")))?;
 
         let pkg = Package::Block {
@@ -481,48 +2948,141 @@ impl Server {
             block: block_data,
         };
 
-        Self::send_data(socket_addr, pkg)
+        Self::send_data(context, socket_addr, pkg)
     }
 
-    /// Send transaction message
-    fn send_tx(addr: &str, tx: &Transaction) -> Result<()> {
+    /// Send a light client the header of `block` and proofs for the transactions it watches
+    fn send_merkle_block(
+        context: &NodeContext,
+        addr: &str,
+        block: &Block,
+        filter: &BloomFilter,
+    ) -> Result<()> {
         let socket_addr = addr
             .parse::<SocketAddr>()
             .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
 
-        let node_addr = GLOBAL_CONFIG.get_node_addr();
-        let tx_data = tx.serialize().map_err(|e| {
-            BlockchainError::Network(format!("Failed to serialize transaction: {e}"))
-        })?;
+        let (header, proofs) = build_merkle_block(block, filter)?;
+        let pkg = Package::MerkleBlock {
+            addr_from: context.config().get_node_addr(),
+            header,
+            proofs,
+        };
+
+        Self::send_data(context, socket_addr, pkg)
+    }
+
+    /// Send transaction message, `confirmed` when it comes from a block rather than the pool
+    fn send_tx(context: &NodeContext, addr: &str, tx: &Transaction, confirmed: bool) -> Result<()> {
+        let socket_addr = addr
+            .parse::<SocketAddr>()
+            .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
+
+        let node_addr = context.config().get_node_addr();
+        let tx_data = tx
+            .serialize_as(context.peer_format_version(addr))
+            .map_err(|e| {
+                BlockchainError::Network(format!("Failed to serialize transaction: {e}"))
+            })?;
 
+        // Peers don't take the high fee override from anyone but their own host
         let pkg = Package::Tx {
             addr_from: node_addr,
             transaction: tx_data,
+            allow_high_fee: false,
+            confirmed,
         };
 
-        Self::send_data(socket_addr, pkg)
+        Self::send_data(context, socket_addr, pkg)?;
+        METRICS.txs_relayed.inc();
+        Ok(())
     }
 
-    /// Send data to a peer
-    fn send_data(addr: SocketAddr, pkg: Package) -> Result<()> {
-        info!("Sending package to {addr}: {pkg:?}");
+    /// Send `pkg` to `addr` over the node's connection to it, opening one if needed
+    ///
+    /// Peers whose version listed a more compact format than JSON get it.
+    fn send_data(context: &NodeContext, addr: SocketAddr, pkg: Package) -> Result<()> {
+        let format = context.peer_wire_format(&addr.to_string());
+        let data = Self::encode_package(context, addr, &pkg, format)?;
+        context.outbound_connections().send(addr, &data)
+    }
 
-        let stream = TcpStream::connect_timeout(&addr, Duration::from_millis(TCP_WRITE_TIMEOUT))
-            .map_err(|e| BlockchainError::Network(format!("Failed to connect to {addr}: {e}")))?;
+    // Signs `pkg` if the node has an identity, and traces and counts it as sent to `addr`
+    fn encode_package(
+        context: &NodeContext,
+        addr: SocketAddr,
+        pkg: &Package,
+        format: WireFormat,
+    ) -> Result<Vec<u8>> {
+        let bytes = context.peer_connections().wire_bytes(addr);
+        let data = match context.identity() {
+            Some(identity) => format.encode_counted(&identity.sign(pkg, format)?, &bytes)?,
+            None => format.encode_counted(pkg, &bytes)?,
+        };
+        // The trace shows what was said rather than the envelope it went in
+        context
+            .message_trace()
+            .record(Direction::Outbound, &addr.to_string(), pkg, data.len());
+        context.peer_connections().record_sent(addr, pkg.kind());
+        Ok(data)
+    }
+}
 
-        stream
-            .set_write_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)))
-            .map_err(|e| BlockchainError::Network(format!("Failed to set write timeout: {e}")))?;
+/// Opens the connections a node makes with a version message, and reads them like the
+/// ones it accepts
+///
+/// The context is held weakly, as it owns the pool holding this handler.
+struct PooledPeers {
+    blockchain: Blockchain,
+    context: Weak<NodeContext>,
+    peer_manager: Arc<SimplePeerManager>,
+    limits: MessageLimits,
+}
 
-        serde_json::to_writer(&stream, &pkg)
-            .map_err(|e| BlockchainError::Network(format!("Failed to send data: {e}")))?;
+impl ConnectionHandler for PooledPeers {
+    fn handshake(&self, addr: SocketAddr) -> Result<Option<Vec<u8>>> {
+        let Some(context) = self.context.upgrade() else {
+            return Ok(None);
+        };
+        let (best_height, chain_work) = Server::local_tip(&self.blockchain)?;
+        let pkg = Server::version_package(&self.blockchain, &context, best_height, chain_work)?;
+        // Before `serve` reads the connection, so its version message is counted
+        context
+            .peer_connections()
+            .open(addr, Direction::Outbound, true, None);
+        Server::encode_package(&context, addr, &pkg, WireFormat::Json).map(Some)
+    }
 
-        Ok(())
+    fn serve(&self, stream: TcpStream, addr: SocketAddr) {
+        let Some(context) = self.context.upgrade() else {
+            return;
+        };
+        if let Err(e) = Server::handle_connection(
+            self.blockchain.clone(),
+            &context,
+            &self.peer_manager,
+            &self.limits,
+            stream,
+            addr,
+            Direction::Outbound,
+        ) {
+            info!("Connection to {addr} ended: {e}");
+        }
     }
 }
 
 /// Standalone function to send a transaction to a specific address
 pub fn send_tx(addr: &str, tx: &Transaction) {
+    send_tx_with_fee_policy(addr, tx, false)
+}
+
+/// Send a transaction whose fee is above `MAX_TRANSACTION_FEE` because the sender insists,
+/// asking the node to accept it anyway
+pub fn send_tx_allowing_high_fee(addr: &str, tx: &Transaction) {
+    send_tx_with_fee_policy(addr, tx, true)
+}
+
+fn send_tx_with_fee_policy(addr: &str, tx: &Transaction, allow_high_fee: bool) {
     let socket_addr = match addr.parse::<SocketAddr>() {
         Ok(addr) => addr,
         Err(e) => {
@@ -543,6 +3103,8 @@ pub fn send_tx(addr: &str, tx: &Transaction) {
     let pkg = Package::Tx {
         addr_from: node_addr,
         transaction: tx_data,
+        allow_high_fee,
+        confirmed: false,
     };
 
     if let Err(e) = send_data_simple(socket_addr, pkg) {
@@ -550,6 +3112,298 @@ pub fn send_tx(addr: &str, tx: &Transaction) {
     }
 }
 
+/// Fetch a pending transaction from a running node's memory pool
+pub fn request_mempool_tx(addr: &str, txid: &[u8]) -> Result<Option<Transaction>> {
+    let pkg = Package::GetMempoolTx {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        txid: txid.to_vec(),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::MempoolTx {
+            transaction: Some(bytes),
+            ..
+        } => Ok(Some(Transaction::deserialize(&bytes)?)),
+        Package::MempoolTx {
+            transaction: None, ..
+        } => Ok(None),
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Ask a running node for its status
+pub fn request_node_status(addr: &str) -> Result<NodeStatus> {
+    let pkg = Package::GetStatus {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::Status { status, .. } => Ok(status),
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Ask a running node for `address`'s balance, counting the transactions in its memory pool
+pub fn request_balance(addr: &str, address: &str) -> Result<AddressBalance> {
+    let pkg = Package::GetBalance {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        address: address.to_string(),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::Balance { balance, .. } => balance.map_err(|e| {
+            BlockchainError::Network(format!("{addr} could not work out the balance: {e}"))
+        }),
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Ask a running node for main-chain block summaries, newest first, as `printchain` reads
+/// them from a local database
+///
+/// A node sends at most `MAX_CHAIN_SUMMARIES` at a time, so a longer range is asked for
+/// one page after another, each ending below the lowest block of the last.
+pub fn request_chain(
+    addr: &str,
+    from_height: Option<usize>,
+    mut to_height: Option<usize>,
+    limit: Option<usize>,
+) -> Result<Vec<BlockSummary>> {
+    let mut blocks: Vec<BlockSummary> = Vec::new();
+    loop {
+        let pkg = Package::GetChain {
+            addr_from: GLOBAL_CONFIG.get_node_addr(),
+            from_height,
+            to_height,
+            limit: limit.map(|limit| limit - blocks.len()),
+        };
+        let page = match send_request(addr, pkg)? {
+            Package::Chain { blocks, .. } => blocks.map_err(|e| {
+                BlockchainError::Network(format!("{addr} could not read its chain: {e}"))
+            })?,
+            other => {
+                return Err(BlockchainError::Network(format!(
+                    "Unexpected {} reply from {addr}",
+                    other.kind()
+                )))
+            }
+        };
+        let lowest = page.last().map(|block| block.height);
+        blocks.extend(page);
+        match lowest {
+            Some(height)
+                if height > from_height.unwrap_or(0)
+                    && limit.is_none_or(|limit| blocks.len() < limit) =>
+            {
+                to_height = Some(height - 1)
+            }
+            _ => return Ok(blocks),
+        }
+    }
+}
+
+/// Ask a running node where transaction `txid` stands on its main chain
+pub fn request_transaction_status(addr: &str, txid: &[u8]) -> Result<TransactionStatus> {
+    let pkg = Package::GetTxStatus {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        txid: txid.to_vec(),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::TxStatus { status, .. } => status.map_err(|e| {
+            BlockchainError::Network(format!("{addr} could not look the transaction up: {e}"))
+        }),
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Have the running node at `addr` refuse `address` for `duration`, returning its bans
+pub fn request_ban(addr: &str, address: &str, duration: Duration) -> Result<Vec<(IpAddr, Ban)>> {
+    let pkg = Package::BanPeer {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        address: address.to_string(),
+        duration_secs: duration.as_secs(),
+    };
+    send_ban_request(addr, pkg)
+}
+
+/// Have the running node at `addr` lift its ban on `address`, returning its bans
+pub fn request_unban(addr: &str, address: &str) -> Result<Vec<(IpAddr, Ban)>> {
+    let pkg = Package::UnbanPeer {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        address: address.to_string(),
+    };
+    send_ban_request(addr, pkg)
+}
+
+/// Ask the running node at `addr` which addresses it refuses
+pub fn request_bans(addr: &str) -> Result<Vec<(IpAddr, Ban)>> {
+    let pkg = Package::ListBans {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+    };
+    send_ban_request(addr, pkg)
+}
+
+fn send_ban_request(addr: &str, pkg: Package) -> Result<Vec<(IpAddr, Ban)>> {
+    match send_request(addr, pkg)? {
+        Package::Bans { bans, .. } => {
+            bans.map_err(|e| BlockchainError::Network(format!("{addr} refused the request: {e}")))
+        }
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Have the running node at `addr` drop its pending transaction `txid`, see
+/// `MemoryPool::abandon`
+pub fn request_abandon(addr: &str, txid: &[u8]) -> Result<()> {
+    let pkg = Package::AbandonTx {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        txid: txid.to_vec(),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::Abandoned { result, .. } => {
+            result.map_err(|e| BlockchainError::Network(format!("{addr} refused the request: {e}")))
+        }
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Ask a running node for a block template paying `miner_address`
+pub fn request_block_template(addr: &str, miner_address: &str) -> Result<BlockTemplate> {
+    let pkg = Package::GetBlockTemplate {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        miner_address: miner_address.to_string(),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::BlockTemplate { template, .. } => template.map_err(|e| {
+            BlockchainError::Network(format!("{addr} could not build a template: {e}"))
+        }),
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Hand a block solved from a template to the running node at `addr`
+pub fn submit_block_to(addr: &str, block: &Block) -> Result<()> {
+    let pkg = Package::SubmitBlock {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        block: block.serialize()?,
+    };
+
+    match send_request(addr, pkg)? {
+        Package::SubmitBlockResult { result, .. } => result.map_err(|e| {
+            BlockchainError::Network(format!("{addr} refused block {}: {e}", block.get_hash()))
+        }),
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Fetch the packages a running node sent and received last, oldest first
+pub fn request_message_trace(addr: &str) -> Result<Vec<TraceEntry>> {
+    let pkg = Package::GetMessageTrace {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::MessageTrace { entries, .. } => Ok(entries),
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Ask a running node for the connections it has open
+pub fn request_peer_info(addr: &str) -> Result<Vec<PeerInfo>> {
+    let pkg = Package::GetPeerInfo {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::Peers { peers, .. } => {
+            peers.map_err(|e| BlockchainError::Network(format!("{addr} refused the request: {e}")))
+        }
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Have the running node at `addr` close its connections with `address`, banning it for
+/// `ban` first if given, and return the connections closed
+pub fn request_disconnect(addr: &str, address: &str, ban: Option<Duration>) -> Result<Vec<String>> {
+    let pkg = Package::DisconnectPeer {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        address: address.to_string(),
+        ban_secs: ban.map(|ban| ban.as_secs()),
+    };
+
+    match send_request(addr, pkg)? {
+        Package::Disconnected { result, .. } => {
+            result.map_err(|e| BlockchainError::Network(format!("{addr} refused the request: {e}")))
+        }
+        other => Err(BlockchainError::Network(format!(
+            "Unexpected {} reply from {addr}",
+            other.kind()
+        ))),
+    }
+}
+
+/// Send a query package and wait for the reply on the same connection
+fn send_request(addr: &str, pkg: Package) -> Result<Package> {
+    let socket_addr = addr
+        .parse::<SocketAddr>()
+        .map_err(|e| BlockchainError::Network(format!("Invalid address {addr}: {e}")))?;
+
+    let stream = TcpStream::connect_timeout(&socket_addr, Duration::from_millis(TCP_WRITE_TIMEOUT))
+        .map_err(|e| BlockchainError::Network(format!("Failed to connect to {addr}: {e}")))?;
+    stream
+        .set_read_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)))
+        .map_err(|e| BlockchainError::Network(format!("Failed to set read timeout: {e}")))?;
+
+    // Clients never send a version, so they talk JSON
+    WireFormat::Json.write(&stream, &pkg)?;
+
+    let reply = PackageReader::new(&stream, MessageLimits::default().max_message_size)
+        .read_package()
+        .ok_or_else(|| BlockchainError::Network(format!("No reply from {addr}")))?
+        .map_err(|e| BlockchainError::Network(format!("Failed to deserialize reply: {e}")))?
+        .package;
+    let _ = stream.shutdown(Shutdown::Both);
+    if let Package::Rejected { reason, .. } = reply {
+        return Err(BlockchainError::Network(format!(
+            "{addr} refused the connection: {reason}"
+        )));
+    }
+    Ok(reply)
+}
+
 /// Simple data sending function for standalone usage
 fn send_data_simple(addr: SocketAddr, pkg: Package) -> Result<()> {
     let mut stream = TcpStream::connect_timeout(&addr, Duration::from_millis(TCP_WRITE_TIMEOUT))
@@ -559,8 +3413,7 @@ fn send_data_simple(addr: SocketAddr, pkg: Package) -> Result<()> {
         .set_write_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)))
         .map_err(|e| BlockchainError::Network(format!("Failed to set write timeout: {e}")))?;
 
-    serde_json::to_writer(&stream, &pkg)
-        .map_err(|e| BlockchainError::Network(format!("Failed to send data: {e}")))?;
+    WireFormat::Json.write(&stream, &pkg)?;
 
     let _ = stream.flush();
     Ok(())
@@ -569,8 +3422,27 @@ fn send_data_simple(addr: SocketAddr, pkg: Package) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::{Amount, FeePriority, Network, ProofOfWork, MIN_TRANSACTION_FEE};
+    use crate::network::codec::WIRE_FORMAT_BINCODE;
+    use crate::network::identity::{NodeIdentity, MAX_PACKAGE_AGE};
+    use crate::network::simple_peer_manager::{
+        INVALID_PACKAGE_PENALTY, INVALID_TRANSACTION_PENALTY, OVERSIZED_MESSAGE_PENALTY,
+        STALLED_DOWNLOAD_PENALTY, UNSOLICITED_MESSAGE_PENALTY,
+    };
+    use serde_json::Deserializer;
+    use std::io::Read;
     use tempfile::tempdir;
 
+    /// The first package the node sends to a peer listening on `listener`
+    fn next_package(listener: &TcpListener) -> Result<Package> {
+        let (stream, _) = listener.accept()?;
+        Deserializer::from_reader(stream)
+            .into_iter()
+            .next()
+            .unwrap()
+            .map_err(|e| BlockchainError::Network(format!("Failed to read reply: {e}")))
+    }
+
     fn create_test_blockchain() -> Result<Blockchain> {
         let temp_dir = tempdir().map_err(|e| BlockchainError::Io(e.to_string()))?;
         let db_path = temp_dir.path().join("test_blockchain");
@@ -597,9 +3469,2291 @@ mod tests {
             addr_from: "127.0.0.1:2001".to_string(),
             version: 1,
             best_height: 0,
+            chain_work: 1 << 100,
+            format_version: FORMAT_VERSION,
+            genesis_hash: "00ab".to_string(),
+            timestamp: 1_760_000_000_000,
+            wire_formats: SUPPORTED_WIRE_FORMATS.to_vec(),
+            network_magic: Some(Network::Mainnet.magic("00ab")),
         };
 
         let serialized = serde_json::to_string(&pkg).unwrap();
-        let _deserialized: Package = serde_json::from_str(&serialized).unwrap();
+        let deserialized: Package = serde_json::from_str(&serialized).unwrap();
+        assert!(matches!(
+            deserialized,
+            Package::Version { chain_work, .. } if chain_work == 1 << 100
+        ));
+
+        // Versions from peers that don't advertise chain work still decode
+        let legacy = r#"{"Version":{"addr_from":"127.0.0.1:2001","version":1,"best_height":7}}"#;
+        assert!(matches!(
+            serde_json::from_str::<Package>(legacy).unwrap(),
+            Package::Version {
+                best_height: 7,
+                chain_work: 0,
+                format_version: 0,
+                ref genesis_hash,
+                timestamp: 0,
+                ref wire_formats,
+                network_magic: None,
+                ..
+            } if genesis_hash.is_empty() && wire_formats.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_only_the_host_a_version_came_from_is_relayed_to() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+        let (best_height, chain_work) = Server::local_tip(&blockchain)?;
+        let version = |addr_from: &str| -> Result<Package> {
+            Ok(Package::Version {
+                addr_from: addr_from.to_string(),
+                version: NODE_VERSION,
+                best_height,
+                chain_work,
+                format_version: FORMAT_VERSION,
+                genesis_hash: blockchain.get_genesis_hash()?,
+                timestamp: current_timestamp()?,
+                wire_formats: SUPPORTED_WIRE_FORMATS.to_vec(),
+                network_magic: Some(Server::network_magic(&blockchain, &context)?),
+            })
+        };
+
+        // Sent from 127.0.0.1, but naming another host to relay to
+        Server::process_message(
+            &blockchain,
+            &context,
+            test_peer(),
+            version("10.0.0.5:2001")?,
+        )?;
+        assert!(context.relay_peers().is_empty());
+
+        Server::process_message(
+            &blockchain,
+            &context,
+            test_peer(),
+            version("127.0.0.1:2001")?,
+        )?;
+        assert_eq!(context.relay_peers(), vec!["127.0.0.1:2001".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_peers_are_sent_formats_they_advertised() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+        let (best_height, chain_work) = Server::local_tip(&blockchain)?;
+        let genesis = blockchain.get_block(&blockchain.get_tip_hash())?.unwrap();
+        let old_peer = "127.0.0.1:3001";
+        let new_peer = "127.0.0.1:3002";
+
+        // Level with this chain, so neither version message starts a sync
+        let legacy = format!(
+            r#"{{"Version":{{"addr_from":"{old_peer}","version":1,"best_height":{best_height},"chain_work":{chain_work}}}}}"#
+        );
+        Server::process_message(
+            &blockchain,
+            &context,
+            test_peer(),
+            serde_json::from_str(&legacy).unwrap(),
+        )?;
+        let current = |addr_from: &str| -> Result<Package> {
+            Ok(Package::Version {
+                addr_from: addr_from.to_string(),
+                version: NODE_VERSION,
+                best_height,
+                chain_work,
+                format_version: FORMAT_VERSION + 1,
+                genesis_hash: blockchain.get_genesis_hash()?,
+                timestamp: current_timestamp()?,
+                wire_formats: vec![WIRE_FORMAT_BINCODE, 200],
+                network_magic: Some(Server::network_magic(&blockchain, &context)?),
+            })
+        };
+        Server::process_message(&blockchain, &context, test_peer(), current(new_peer)?)?;
+        // A peer claiming another host's address doesn't choose what that host is sent
+        Server::process_message(
+            &blockchain,
+            &context,
+            test_peer(),
+            current("10.0.0.5:2001")?,
+        )?;
+        assert_eq!(context.peer_format_version("10.0.0.5:2001"), 0);
+        assert_eq!(context.peer_wire_format("10.0.0.5:2001"), WireFormat::Json);
+
+        assert_eq!(context.peer_format_version(old_peer), 0);
+        assert_eq!(context.peer_format_version("127.0.0.1:3003"), 0);
+        // A newer peer gets the newest format this node writes
+        assert_eq!(context.peer_format_version(new_peer), FORMAT_VERSION);
+        let for_old_peer = genesis.serialize_as(context.peer_format_version(old_peer))?;
+        assert_eq!(for_old_peer, crate::utils::serialize(&genesis)?);
+
+        // Packages go as frames only to the peer whose version listed them
+        assert_eq!(context.peer_wire_format(old_peer), WireFormat::Json);
+        assert_eq!(context.peer_wire_format("127.0.0.1:3003"), WireFormat::Json);
+        assert_eq!(context.peer_wire_format(new_peer), WireFormat::Bincode);
+        for (peer, format) in [
+            (old_peer, WireFormat::Json),
+            (new_peer, WireFormat::Bincode),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            context.set_peer_wire_format(&addr.to_string(), context.peer_wire_format(peer));
+            Server::send_data(&context, addr, unsolicited_reply())?;
+            let (stream, _) = listener.accept().unwrap();
+            let received = PackageReader::new(stream, 1024)
+                .read_package()
+                .unwrap()
+                .unwrap();
+            assert_eq!(received.format, format);
+            assert_eq!(received.package.kind(), "MessageTrace");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_validation_limits() {
+        let limits = MessageLimits::default().with_max_inv_items(2);
+
+        let inv = Package::Inv {
+            addr_from: CENTRAL_NODE.to_string(),
+            op_type: OpType::Block,
+            items: vec![vec![0u8; 64]; 3],
+        };
+        assert!(inv.validate(&limits).is_err());
+
+        let block = Package::Block {
+            addr_from: CENTRAL_NODE.to_string(),
+            block: vec![0u8; limits.max_block_payload + 1],
+        };
+        assert!(block.validate(&limits).is_err());
+
+        let ok = Package::Inv {
+            addr_from: CENTRAL_NODE.to_string(),
+            op_type: OpType::Tx,
+            items: vec![vec![0u8; 32]; 2],
+        };
+        assert!(ok.validate(&limits).is_ok());
+    }
+
+    #[test]
+    fn test_queries_answered_from_own_context() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let tx = Transaction::new_placeholder_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+
+        // Two servers in one process no longer share a memory pool
+        let busy = Server::with_context(blockchain.clone(), Arc::new(NodeContext::default()));
+        let idle = Server::with_context(blockchain, Arc::new(NodeContext::default()));
+        busy.get_context().memory_pool().add(tx.clone());
+
+        let query = Package::GetMempoolTx {
+            addr_from: CENTRAL_NODE.to_string(),
+            txid: tx.get_id().to_vec(),
+        };
+        for (server, expect_tx) in [(&busy, true), (&idle, false)] {
+            match Server::answer_query(
+                &server.blockchain,
+                server.get_context(),
+                &peer_manager,
+                &query,
+            )? {
+                Some(Package::MempoolTx { transaction, .. }) => {
+                    assert_eq!(transaction.is_some(), expect_tx)
+                }
+                other => panic!("unexpected reply: {other:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_miner_round_trip() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let context = Arc::new(NodeContext::default());
+        let miner_address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_string();
+
+        let query = Package::GetBlockTemplate {
+            addr_from: CENTRAL_NODE.to_string(),
+            miner_address,
+        };
+        let template = match Server::answer_query(&blockchain, &context, &peer_manager, &query)? {
+            Some(Package::BlockTemplate {
+                template: Ok(template),
+                ..
+            }) => template,
+            other => panic!("unexpected reply: {other:?}"),
+        };
+
+        let block = (0..)
+            .map(|nonce| template.solve(nonce))
+            .find(|block| block.as_ref().map_or(true, ProofOfWork::validate))
+            .unwrap()?;
+        let submit = |block: &Block| -> Result<std::result::Result<(), String>> {
+            let pkg = Package::SubmitBlock {
+                addr_from: CENTRAL_NODE.to_string(),
+                block: block.serialize()?,
+            };
+            match Server::answer_query(&blockchain, &context, &peer_manager, &pkg)? {
+                Some(Package::SubmitBlockResult { result, .. }) => Ok(result),
+                other => panic!("unexpected reply: {other:?}"),
+            }
+        };
+
+        assert_eq!(submit(&block)?, Ok(()));
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+        assert!(submit(&block)?.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_balance_counts_pending_transactions_until_mined() -> Result<()> {
+        use crate::core::{block_subsidy, FeeMode, FeePriority};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().map_err(|e| BlockchainError::Io(e.to_string()))?;
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let recipient = wallets.create_wallet()?;
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &sender,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )?;
+        blockchain.set_force_difficulty(Some(1));
+        let context = Arc::new(NodeContext::new(FeeMode::Fixed { amount: 3 })?);
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe()?;
+
+        let payment = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &recipient,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )?;
+        context.memory_pool().add(payment);
+        let balance_of = |address: &str| -> Result<AddressBalance> {
+            let query = Package::GetBalance {
+                addr_from: CENTRAL_NODE.to_string(),
+                address: address.to_string(),
+            };
+            match Server::answer_query(&blockchain, &context, &peer_manager, &query)? {
+                Some(Package::Balance {
+                    balance: Ok(balance),
+                    ..
+                }) => Ok(balance),
+                other => panic!("unexpected reply: {other:?}"),
+            }
+        };
+
+        // The sender's coinbase is on its way out and the change on its way back
+        let subsidy = block_subsidy(0);
+        let change = subsidy - 1_000 - 3;
+        let sent = balance_of(&sender)?;
+        assert_eq!(
+            (sent.confirmed, sent.pending_incoming, sent.pending_outgoing),
+            (subsidy.into(), change.into(), subsidy.into())
+        );
+        assert_eq!(sent.available(), 0);
+        let received = balance_of(&recipient)?;
+        assert_eq!(
+            (
+                received.confirmed,
+                received.pending_incoming,
+                received.pending_outgoing
+            ),
+            (Amount::ZERO, 1_000.into(), Amount::ZERO)
+        );
+        assert_eq!(
+            utxo_set.pending_delta_for(&address_pub_key_hash(&recipient)?, context.memory_pool()),
+            (1_000.into(), Amount::ZERO)
+        );
+        match Server::answer_query(
+            &blockchain,
+            &context,
+            &peer_manager,
+            &Package::GetBalance {
+                addr_from: CENTRAL_NODE.to_string(),
+                address: "not an address".to_string(),
+            },
+        )? {
+            Some(Package::Balance { balance, .. }) => assert!(balance.is_err()),
+            other => panic!("unexpected reply: {other:?}"),
+        }
+
+        blockchain.generate_blocks(1, "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", &context)?;
+        let settled = |confirmed: u64| AddressBalance {
+            confirmed: confirmed.into(),
+            pending_incoming: Amount::ZERO,
+            pending_outgoing: Amount::ZERO,
+        };
+        assert_eq!(balance_of(&sender)?, settled(change));
+        assert_eq!(balance_of(&recipient)?, settled(1_000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_replies_are_capped() -> Result<()> {
+        use crate::core::FeeMode;
+
+        let blockchain = create_test_blockchain()?;
+        blockchain.set_force_difficulty(Some(1));
+        let context = Arc::new(NodeContext::new(FeeMode::Fixed { amount: 3 })?);
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        blockchain.generate_blocks(
+            MAX_CHAIN_SUMMARIES + 1,
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            &context,
+        )?;
+
+        for limit in [None, Some(MAX_CHAIN_SUMMARIES + 2)] {
+            let chain = Server::answer_query(
+                &blockchain,
+                &context,
+                &peer_manager,
+                &Package::GetChain {
+                    addr_from: CENTRAL_NODE.to_string(),
+                    from_height: None,
+                    to_height: None,
+                    limit,
+                },
+            )?;
+            let Some(Package::Chain {
+                blocks: Ok(blocks), ..
+            }) = chain
+            else {
+                panic!("unexpected reply: {chain:?}");
+            };
+            // The newest page; the older blocks are left for the next one
+            assert_eq!(blocks.len(), MAX_CHAIN_SUMMARIES);
+            assert_eq!(blocks[0].height, blockchain.get_best_height()?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_and_transaction_status_are_served_to_remote_queries() -> Result<()> {
+        use crate::core::FeeMode;
+
+        let blockchain = create_test_blockchain()?;
+        blockchain.set_force_difficulty(Some(1));
+        let context = Arc::new(NodeContext::new(FeeMode::Fixed { amount: 3 })?);
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let mined =
+            blockchain.generate_blocks(3, "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", &context)?;
+        let ask =
+            |query: Package| Server::answer_query(&blockchain, &context, &peer_manager, &query);
+
+        let chain = ask(Package::GetChain {
+            addr_from: CENTRAL_NODE.to_string(),
+            from_height: None,
+            to_height: Some(2),
+            limit: Some(2),
+        })?;
+        let Some(Package::Chain {
+            blocks: Ok(blocks), ..
+        }) = chain
+        else {
+            panic!("unexpected reply: {chain:?}");
+        };
+        // The same blocks printchain reads locally, newest first
+        assert_eq!(
+            blocks,
+            BlockSummarizer::new(&blockchain).summarize_main_chain(None, Some(2), Some(2))?
+        );
+        let heights: Vec<usize> = blocks.iter().map(|block| block.height).collect();
+        assert_eq!(heights, vec![2, 1]);
+
+        let coinbase = mined[0].get_transactions()[0].get_id().to_vec();
+        let status_of = |txid: Vec<u8>| -> Result<TransactionStatus> {
+            let reply = ask(Package::GetTxStatus {
+                addr_from: CENTRAL_NODE.to_string(),
+                txid,
+            })?;
+            match reply {
+                Some(Package::TxStatus {
+                    status: Ok(status), ..
+                }) => Ok(status),
+                other => panic!("unexpected reply: {other:?}"),
+            }
+        };
+        let status = status_of(coinbase.clone())?;
+        assert_eq!(status, blockchain.get_transaction_status(&coinbase)?);
+        assert_eq!(status.block.as_deref(), Some(mined[0].get_hash()));
+        assert_eq!(status.confirmations, Some(3));
+        assert_eq!(status_of(vec![7; 32])?.confirmations, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inconsistent_chainstate_is_rebuilt_in_the_background() -> Result<()> {
+        use crate::storage::utxo_set::UTXO_TREE;
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+        use std::time::Instant;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().map_err(|e| BlockchainError::Io(e.to_string()))?;
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet()?;
+        let recipient = wallets.create_wallet()?;
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &owner,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )?;
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe()?;
+        let payment =
+            Transaction::new_utxo_transaction(&owner, &recipient, 1_000, &utxo_set, &wallets)?;
+        let genesis_coinbase = payment.get_vin()[0].get_txid().to_vec();
+
+        // The genesis coinbase goes missing, and a block without ancestry spends it, so
+        // there is nothing to rebuild the entry from
+        let utxo_tree = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+        utxo_tree.remove(&genesis_coinbase).unwrap();
+        let block = Block::new_template("None".to_string(), &[payment], 1, 1)?;
+        let context = NodeContext::default();
+        Server::update_chainstate(&blockchain, &context, &block, "None")?;
+
+        let started = Instant::now();
+        while context.is_rebuilding_chainstate() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(blockchain
+            .find_unspent_output(&genesis_coinbase, 0)?
+            .is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_traced_blocks_are_logged_by_hash() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let genesis = blockchain.get_block(&blockchain.get_tip_hash())?.unwrap();
+        let pkg = Package::Block {
+            addr_from: CENTRAL_NODE.to_string(),
+            block: genesis.serialize()?,
+        };
+        assert_eq!(pkg.kind(), "Block");
+        assert_eq!(
+            pkg.summary(),
+            format!(" hash={} height=0", genesis.get_hash())
+        );
+
+        let context = Arc::new(NodeContext::default());
+        context
+            .message_trace()
+            .record(Direction::Inbound, CENTRAL_NODE, &pkg, 1234);
+        let query = Package::GetMessageTrace {
+            addr_from: CENTRAL_NODE.to_string(),
+        };
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        match Server::answer_query(&blockchain, &context, &peer_manager, &query)? {
+            Some(Package::MessageTrace { entries, .. }) => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].kind, "Block");
+                assert_eq!(entries[0].size, 1234);
+            }
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        Ok(())
+    }
+
+    // The connection test packages are taken to have come in on
+    fn test_peer() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    // Feeds `payload` to handle_connection over a real socket and returns its result
+    fn drive_connection(
+        peer_manager: &SimplePeerManager,
+        limits: &MessageLimits,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        drive_connection_with(
+            &Arc::new(NodeContext::default()),
+            peer_manager,
+            limits,
+            payload,
+        )
+    }
+
+    fn drive_connection_with(
+        context: &Arc<NodeContext>,
+        peer_manager: &SimplePeerManager,
+        limits: &MessageLimits,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(server_addr).unwrap();
+            // The server may hang up mid-write, which is the point of these tests
+            let _ = stream.write_all(&payload);
+            let _ = stream.shutdown(Shutdown::Write);
+        });
+
+        let (stream, peer_addr) = listener.accept().unwrap();
+        let result = Server::handle_connection(
+            blockchain,
+            context,
+            peer_manager,
+            limits,
+            stream,
+            peer_addr,
+            Direction::Inbound,
+        );
+        client.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn test_oversized_message_disconnects_and_penalizes() {
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let limits = MessageLimits::default().with_max_message_size(1024);
+        let pkg = Package::Tx {
+            addr_from: CENTRAL_NODE.to_string(),
+            transaction: vec![7u8; 4096],
+            allow_high_fee: false,
+            confirmed: false,
+        };
+
+        let result = drive_connection(&peer_manager, &limits, serde_json::to_vec(&pkg).unwrap());
+        assert!(result.is_err());
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            peer_manager.get_misbehavior_score(ip).unwrap(),
+            OVERSIZED_MESSAGE_PENALTY
+        );
+    }
+
+    #[test]
+    fn test_oversized_inv_disconnects_and_penalizes() {
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let limits = MessageLimits::default().with_max_inv_items(10);
+        let pkg = Package::Inv {
+            addr_from: CENTRAL_NODE.to_string(),
+            op_type: OpType::Block,
+            items: vec![vec![1u8; 32]; 11],
+        };
+
+        let result = drive_connection(&peer_manager, &limits, serde_json::to_vec(&pkg).unwrap());
+        assert!(result.is_err());
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            peer_manager.get_misbehavior_score(ip).unwrap(),
+            INVALID_PACKAGE_PENALTY
+        );
+    }
+
+    #[test]
+    fn test_only_the_connecting_host_counts_as_reached() -> Result<()> {
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let version = |addr_from: &str| Package::Version {
+            addr_from: addr_from.to_string(),
+            version: 1,
+            best_height: 0,
+            chain_work: 0,
+            format_version: FORMAT_VERSION,
+            genesis_hash: String::new(),
+            timestamp: 0,
+            wire_formats: SUPPORTED_WIRE_FORMATS.to_vec(),
+            network_magic: None,
+        };
+        // The connection comes from 127.0.0.1, which can't vouch for ::1
+        let mut payload = serde_json::to_vec(&version("127.0.0.1:4001")).unwrap();
+        payload.extend(serde_json::to_vec(&version("[::1]:4001")).unwrap());
+        drive_connection(&peer_manager, &MessageLimits::default(), payload)?;
+
+        let known = peer_manager.get_known_peers()?;
+        assert_eq!(known[&"127.0.0.1:4001".parse().unwrap()].successes, 1);
+        assert_eq!(known[&"[::1]:4001".parse().unwrap()].successes, 0);
+        Ok(())
+    }
+
+    // A reply nobody asked for, which costs its sender a little
+    fn unsolicited_reply() -> Package {
+        Package::MessageTrace {
+            addr_from: CENTRAL_NODE.to_string(),
+            entries: Vec::new(),
+        }
+    }
+
+    fn signed_envelope(identity: &NodeIdentity, pkg: &Package, timestamp: i64) -> SignedPackage {
+        match identity.sign_at(pkg, WireFormat::Json, timestamp).unwrap() {
+            Package::Signed { envelope } => envelope,
+            other => panic!("expected a signed package, got {}", other.kind()),
+        }
+    }
+
+    #[test]
+    fn test_signed_packages_are_charged_to_their_key_and_host() {
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let identity = NodeIdentity::generate().unwrap();
+        let signed = identity
+            .sign(&unsolicited_reply(), WireFormat::Json)
+            .unwrap();
+
+        drive_connection(
+            &peer_manager,
+            &MessageLimits::default(),
+            serde_json::to_vec(&signed).unwrap(),
+        )
+        .unwrap();
+        let key = PeerKey::Identity(identity.peer_id());
+        assert_eq!(
+            peer_manager.peer_score(&key).unwrap(),
+            UNSOLICITED_MESSAGE_PENALTY
+        );
+        // A new key is free, so the host it came from pays as well
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            peer_manager.get_misbehavior_score(ip).unwrap(),
+            UNSOLICITED_MESSAGE_PENALTY
+        );
+
+        // A node with an identity signs what it sends, and traces it unwrapped
+        let context = NodeContext::default().with_identity(identity);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        Server::send_data(&context, addr, unsolicited_reply()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let received: Package = Deserializer::from_reader(stream)
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.kind(), "Signed");
+        assert_eq!(context.message_trace().entries()[0].kind, "MessageTrace");
+    }
+
+    #[test]
+    fn test_forged_or_stale_signatures_are_refused() {
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let identity = NodeIdentity::generate().unwrap();
+        let now = current_timestamp().unwrap();
+        let ip = "127.0.0.1".parse().unwrap();
+
+        // Claiming to come from another node doesn't survive the signature check
+        let mut forged = signed_envelope(&identity, &unsolicited_reply(), now);
+        forged.payload = String::from_utf8(forged.payload)
+            .unwrap()
+            .replace(CENTRAL_NODE, "10.0.0.1:2001")
+            .into_bytes();
+        let stale = signed_envelope(
+            &identity,
+            &unsolicited_reply(),
+            now - MAX_PACKAGE_AGE.as_millis() as i64 - 1_000,
+        );
+        for envelope in [forged, stale] {
+            let result = drive_connection(
+                &peer_manager,
+                &MessageLimits::default(),
+                serde_json::to_vec(&Package::Signed { envelope }).unwrap(),
+            );
+            assert!(result.is_err());
+        }
+        assert_eq!(
+            peer_manager.get_misbehavior_score(ip).unwrap(),
+            2 * INVALID_PACKAGE_PENALTY
+        );
+        let key = PeerKey::Identity(identity.peer_id());
+        assert_eq!(peer_manager.peer_score(&key).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_unsigned_gossip_follows_the_config() {
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let context = Arc::new(NodeContext::default());
+        let payload = serde_json::to_vec(&unsolicited_reply()).unwrap();
+        let ip = "127.0.0.1".parse().unwrap();
+
+        context.config().set_allow_unsigned_peers(false);
+        drive_connection_with(
+            &context,
+            &peer_manager,
+            &MessageLimits::default(),
+            payload.clone(),
+        )
+        .unwrap();
+        assert_eq!(peer_manager.get_misbehavior_score(ip).unwrap(), 0);
+        // Transactions still get through, as wallets don't sign
+        let tx = Package::Tx {
+            addr_from: CENTRAL_NODE.to_string(),
+            transaction: vec![7u8; 64],
+            allow_high_fee: false,
+            confirmed: false,
+        };
+        drive_connection_with(
+            &context,
+            &peer_manager,
+            &MessageLimits::default(),
+            serde_json::to_vec(&tx).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            peer_manager.get_misbehavior_score(ip).unwrap(),
+            INVALID_TRANSACTION_PENALTY
+        );
+
+        context.config().set_allow_unsigned_peers(true);
+        drive_connection_with(&context, &peer_manager, &MessageLimits::default(), payload).unwrap();
+        assert_eq!(
+            peer_manager.get_misbehavior_score(ip).unwrap(),
+            INVALID_TRANSACTION_PENALTY + UNSOLICITED_MESSAGE_PENALTY
+        );
+
+        // Only the key that first announced an address may announce it again
+        let first = NodeIdentity::generate().unwrap().peer_id();
+        let second = NodeIdentity::generate().unwrap().peer_id();
+        assert!(context.claim_peer_addr("10.0.0.1:2001", None));
+        assert!(context.claim_peer_addr("10.0.0.1:2001", Some(&first)));
+        assert!(context.claim_peer_addr("10.0.0.1:2001", Some(&first)));
+        assert!(!context.claim_peer_addr("10.0.0.1:2001", Some(&second)));
+        assert!(!context.claim_peer_addr("10.0.0.1:2001", None));
+    }
+
+    #[test]
+    fn test_repeat_offender_is_banned() {
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let limits = MessageLimits::default().with_max_message_size(1024);
+        let pkg = Package::Tx {
+            addr_from: CENTRAL_NODE.to_string(),
+            transaction: vec![7u8; 4096],
+            allow_high_fee: false,
+            confirmed: false,
+        };
+        let payload = serde_json::to_vec(&pkg).unwrap();
+
+        drive_connection(&peer_manager, &limits, payload.clone()).unwrap_err();
+        drive_connection(&peer_manager, &limits, payload).unwrap_err();
+
+        let next_connection = "127.0.0.1:50000".parse().unwrap();
+        assert!(!peer_manager
+            .should_accept_connection(next_connection)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_undecodable_transaction_is_penalized() {
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let pkg = Package::Tx {
+            addr_from: CENTRAL_NODE.to_string(),
+            transaction: vec![7u8; 64],
+            allow_high_fee: false,
+            confirmed: false,
+        };
+
+        drive_connection(
+            &peer_manager,
+            &MessageLimits::default(),
+            serde_json::to_vec(&pkg).unwrap(),
+        )
+        .unwrap();
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            peer_manager.get_misbehavior_score(ip).unwrap(),
+            INVALID_TRANSACTION_PENALTY
+        );
+    }
+
+    #[test]
+    fn test_relayed_coinbase_is_penalized() {
+        let context = Arc::new(NodeContext::default());
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let coinbase = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        let pkg = Package::Tx {
+            addr_from: CENTRAL_NODE.to_string(),
+            transaction: coinbase.serialize().unwrap(),
+            allow_high_fee: true,
+            confirmed: false,
+        };
+
+        drive_connection_with(
+            &context,
+            &peer_manager,
+            &MessageLimits::default(),
+            serde_json::to_vec(&pkg).unwrap(),
+        )
+        .unwrap();
+        assert!(context.memory_pool().is_empty());
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            peer_manager.get_misbehavior_score(ip).unwrap(),
+            INVALID_TRANSACTION_PENALTY
+        );
+    }
+
+    #[test]
+    fn test_block_whose_coinbase_overpays_is_penalized() -> Result<()> {
+        use crate::core::block_subsidy;
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let recipient = wallets.create_wallet()?;
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &sender,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )?;
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+        let context = Arc::new(NodeContext::default());
+
+        let payment =
+            Transaction::new_utxo_transaction(&sender, &recipient, 1_000, &utxo_set, &wallets)?;
+        let reward = Amount::from(block_subsidy(1)).saturating_add(payment.get_fee());
+        let tip = blockchain.get_block(&blockchain.get_tip_hash())?.unwrap();
+        let difficulty = blockchain.calculate_next_difficulty_for_parent(&tip)?;
+        let block_paying = |reward: Amount| -> Result<Vec<u8>> {
+            let coinbase = Transaction::new_coinbase_tx_with_reward(&recipient, reward)?;
+            Block::new_block(
+                tip.get_hash().to_string(),
+                &[coinbase, payment.clone()],
+                1,
+                difficulty,
+            )?
+            .serialize()
+        };
+
+        // One satoshi over the subsidy plus the payment's fee
+        let overpaid = block_paying(reward.saturating_add(Amount::from(1)))?;
+        assert_eq!(
+            Server::handle_block_message(
+                &blockchain,
+                &context,
+                test_peer(),
+                CENTRAL_NODE.to_string(),
+                overpaid
+            )?,
+            Some(Misbehavior::InvalidBlock)
+        );
+        assert_eq!(blockchain.get_best_height()?, 0);
+
+        let exact = block_paying(reward)?;
+        assert_eq!(
+            Server::handle_block_message(
+                &blockchain,
+                &context,
+                test_peer(),
+                CENTRAL_NODE.to_string(),
+                exact
+            )?,
+            None
+        );
+        assert_eq!(blockchain.get_best_height()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forged_spends_from_peers_never_push_out_pending_ones() -> Result<()> {
+        use crate::core::{Amount, FeePolicy, TransactionBuilder};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let recipient = wallets.create_wallet()?;
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &sender,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )?;
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+        let context = Arc::new(NodeContext::default());
+        let relay = |tx: &Transaction| {
+            Server::handle_tx_message(
+                &blockchain,
+                &context,
+                "10.0.0.2:2001",
+                tx.serialize()?,
+                false,
+                false,
+            )
+        };
+
+        let honest = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &recipient,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )?;
+        let honest_txid = HEXLOWER.encode(honest.get_id());
+        assert_eq!(relay(&honest)?, None);
+        assert!(context.memory_pool().contains(&honest_txid));
+
+        // A spend of the same coin paying far more, but not signed by its owner
+        let mut forged = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output(&recipient, 1_000)
+            .fee_policy(FeePolicy::Explicit(Amount::from(
+                honest.get_fee().to_satoshis() + 50_000,
+            )))
+            .build_and_sign(&wallets)?;
+        forged.corrupt_signatures();
+        assert_eq!(relay(&forged)?, Some(Misbehavior::InvalidTransaction));
+
+        // The honest one claiming a fee its inputs don't hold
+        let inflated = honest
+            .clone()
+            .with_fee(honest.get_fee().to_satoshis() + 50_000);
+        assert_eq!(relay(&inflated)?, Some(Misbehavior::InvalidTransaction));
+
+        assert_eq!(context.memory_pool().len(), 1);
+        assert!(context.memory_pool().contains(&honest_txid));
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_tip_keeps_children_of_pending_transactions() -> Result<()> {
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let recipient = wallets.create_wallet()?;
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &sender,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )?;
+        blockchain.set_force_difficulty(Some(1));
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+        let context = Arc::new(NodeContext::default());
+        let mine = |txs: &[Transaction]| -> Result<()> {
+            let block = blockchain.mine_block_with_context(txs, &sender, &context)?;
+            utxo_set.update_safe(&block)?;
+            Server::drop_invalidated_transactions(&blockchain, &context);
+            Ok(())
+        };
+
+        let parent = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &recipient,
+            5_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )?;
+        let (vout, paid) = parent
+            .get_vout()
+            .iter()
+            .enumerate()
+            .find(|(_, output)| output.get_address() == recipient)
+            .unwrap();
+        let mut child = Transaction::new_raw(
+            &[(parent.get_id().to_vec(), vout)],
+            vec![TXOutput::new(
+                paid.get_value().to_satoshis() - 1_000,
+                &recipient,
+            )?],
+        )?;
+        child.sign_with_outputs(
+            wallets.get_wallet(&recipient).unwrap(),
+            std::slice::from_ref(paid),
+        )?;
+        context.admit_transaction(parent.clone(), false)?;
+        context.admit_transaction(child.clone(), false)?;
+
+        // The child spends nothing in the chainstate yet, but its parent is still pending
+        mine(&[])?;
+        assert_eq!(context.memory_pool().len(), 2);
+
+        // A block spending the parent's coin elsewhere leaves neither of them a source
+        let coin = &parent.get_vin()[0];
+        let spent = blockchain
+            .find_unspent_output(coin.get_txid(), coin.get_vout())?
+            .unwrap();
+        let mut double_spend = Transaction::new_raw(
+            &[(coin.get_txid().to_vec(), coin.get_vout())],
+            vec![TXOutput::new(
+                spent.get_value().to_satoshis() - 1_000,
+                &wallets.create_wallet()?,
+            )?],
+        )?;
+        double_spend.sign_with_outputs(wallets.get_wallet(&sender).unwrap(), &[spent])?;
+        mine(&[double_spend])?;
+        assert!(context.memory_pool().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_abandoned_transaction_is_not_taken_back_from_peers() -> Result<()> {
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::{SharedWallets, Wallets};
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let recipient = wallets.create_wallet()?;
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &sender,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )?;
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+        let context = Arc::new(NodeContext::default());
+        let payment = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &recipient,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )?;
+        let txid_hex = HEXLOWER.encode(payment.get_id());
+        context.admit_transaction(payment.clone(), false)?;
+        // Only the sender's wallet may ask for it
+        wallets.check_spends_own_coins(&payment)?;
+        let stranger = Wallets::open(&temp_dir.path().join("stranger.dat"))?;
+        assert!(stranger.check_spends_own_coins(&payment).is_err());
+
+        let abandon = Package::AbandonTx {
+            addr_from: CENTRAL_NODE.to_string(),
+            txid: payment.get_id().to_vec(),
+        };
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        // Nor may the operator of a node without the sender's key, even from loopback
+        let stranger_node = NodeContext::default().with_wallets(SharedWallets::from(stranger));
+        stranger_node.admit_transaction(payment.clone(), false)?;
+        let local: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        match Server::answer_admin(&blockchain, &stranger_node, &peer_manager, local, &abandon)? {
+            Some(Package::Abandoned { result: Err(_), .. }) => {}
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        assert!(stranger_node.memory_pool().contains(&txid_hex));
+        let remote: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        match Server::answer_admin(&blockchain, &context, &peer_manager, remote, &abandon)? {
+            Some(Package::Abandoned { result: Err(_), .. }) => {}
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        assert!(context.memory_pool().contains(&txid_hex));
+
+        match Server::answer_admin(&blockchain, &context, &peer_manager, local, &abandon)? {
+            Some(Package::Abandoned { result: Ok(()), .. }) => {}
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        assert!(context.memory_pool().is_empty());
+        // Asking twice finds nothing left to abandon
+        match Server::answer_admin(&blockchain, &context, &peer_manager, local, &abandon)? {
+            Some(Package::Abandoned { result: Err(_), .. }) => {}
+            other => panic!("unexpected reply: {other:?}"),
+        }
+
+        // A peer relaying it back isn't at fault, but it stays out
+        assert_eq!(
+            Server::handle_tx_message(
+                &blockchain,
+                &context,
+                "10.0.0.2:2001",
+                payment.serialize()?,
+                false,
+                false
+            )?,
+            None
+        );
+        assert!(context.memory_pool().is_empty());
+        // And a restarted node remembers
+        let restarted = crate::storage::MemoryPool::new();
+        restarted.load_from(&blockchain, &context.relay_fee_policy())?;
+        assert!(restarted.is_abandoned(&txid_hex));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bans_are_managed_from_loopback_only() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let context = NodeContext::default();
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let ban = Package::BanPeer {
+            addr_from: CENTRAL_NODE.to_string(),
+            address: "10.0.0.9:2001".to_string(),
+            duration_secs: 60,
+        };
+        let banned: IpAddr = "10.0.0.9".parse().unwrap();
+
+        let remote: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        match Server::answer_admin(&blockchain, &context, &peer_manager, remote, &ban)? {
+            Some(Package::Bans { bans: Err(_), .. }) => {}
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        assert!(!peer_manager.is_banned(banned)?);
+
+        let local: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        match Server::answer_admin(&blockchain, &context, &peer_manager, local, &ban)? {
+            Some(Package::Bans { bans: Ok(bans), .. }) => {
+                assert_eq!(bans.len(), 1);
+                assert_eq!(bans[0].0, banned);
+                assert_eq!(bans[0].1.reason, OPERATOR_BAN_REASON);
+            }
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        // The ban was written through, so a restarted node still refuses the address
+        let restarted = SimplePeerManager::new(8, 2001);
+        assert_eq!(restarted.load_bans_from(blockchain.get_db())?, 1);
+
+        let unban = Package::UnbanPeer {
+            addr_from: CENTRAL_NODE.to_string(),
+            address: "10.0.0.9".to_string(),
+        };
+        Server::answer_admin(&blockchain, &context, &peer_manager, local, &unban)?;
+        assert!(!peer_manager.is_banned(banned)?);
+        Ok(())
+    }
+
+    // Polls `condition` until it holds, for at most ten seconds
+    fn wait_for(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition never held");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_peer_info_counts_traffic_until_the_operator_disconnects() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+        let peer_manager = Arc::new(SimplePeerManager::new(8, 2001));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut readers = Vec::new();
+        let mut accept = |listener: &TcpListener| {
+            let (stream, peer_addr) = listener.accept().unwrap();
+            let (blockchain, context, peer_manager) = (
+                blockchain.clone(),
+                Arc::clone(&context),
+                Arc::clone(&peer_manager),
+            );
+            readers.push(thread::spawn(move || {
+                Server::handle_connection(
+                    blockchain,
+                    &context,
+                    &peer_manager,
+                    &MessageLimits::default(),
+                    stream,
+                    peer_addr,
+                    Direction::Inbound,
+                )
+            }));
+            peer_addr
+        };
+        let info =
+            |context: &NodeContext| context.peer_connections().peer_info(&peer_manager).unwrap();
+
+        // A peer saying hello in JSON, at the height of this chain so nothing gets synced
+        let mut gossip = TcpStream::connect(server_addr).unwrap();
+        let gossip_addr = accept(&listener);
+        let version = Package::Version {
+            addr_from: "127.0.0.1:4001".to_string(),
+            version: 1,
+            best_height: 0,
+            chain_work: 0,
+            format_version: FORMAT_VERSION,
+            genesis_hash: String::new(),
+            timestamp: 0,
+            wire_formats: SUPPORTED_WIRE_FORMATS.to_vec(),
+            network_magic: None,
+        };
+        let hello = WireFormat::Json.encode(&version)?;
+        gossip.write_all(&hello).unwrap();
+
+        // And a client asking for the status in frames, twice
+        let client = TcpStream::connect(server_addr).unwrap();
+        let client_addr = accept(&listener);
+        let mut replies = PackageReader::new(&client, 1 << 20);
+        let query = WireFormat::Bincode.encode(&Package::GetStatus {
+            addr_from: CENTRAL_NODE.to_string(),
+        })?;
+        (&client).write_all(&query).unwrap();
+        let first_reply = replies.read_package().unwrap().unwrap();
+        assert_eq!(first_reply.format, WireFormat::Bincode);
+
+        wait_for(|| {
+            let peers = info(&context);
+            peers.len() == 2 && peers.iter().all(|peer| peer.last_message.is_some())
+        });
+        let peers = info(&context);
+        let gossip_info = peers
+            .iter()
+            .find(|peer| peer.address == gossip_addr.to_string())
+            .unwrap();
+        assert_eq!(gossip_info.direction, Direction::Inbound);
+        assert!(!gossip_info.pooled);
+        assert_eq!(
+            gossip_info.listen_address.as_deref(),
+            Some("127.0.0.1:4001")
+        );
+        assert_eq!(
+            (gossip_info.version, gossip_info.best_height),
+            (Some(1), Some(0))
+        );
+        assert_eq!(gossip_info.bytes_received, hello.len() as u64);
+        assert_eq!(gossip_info.messages_received["Version"], 1);
+        assert_eq!(gossip_info.misbehavior_score, 0);
+        let client_info = peers
+            .iter()
+            .find(|peer| peer.address == client_addr.to_string())
+            .unwrap()
+            .clone();
+        assert_eq!(client_info.bytes_received, query.len() as u64);
+        assert_eq!(client_info.bytes_sent, first_reply.size as u64);
+        assert_eq!(client_info.messages_sent["Status"], 1);
+        assert!(client_info.connected_since <= client_info.last_message.unwrap());
+
+        thread::sleep(Duration::from_millis(5));
+        (&client).write_all(&query).unwrap();
+        let second_reply = replies.read_package().unwrap().unwrap();
+        wait_for(|| {
+            info(&context)
+                .iter()
+                .any(|peer| peer.messages_sent.get("Status") == Some(&2))
+        });
+        let later = info(&context)
+            .into_iter()
+            .find(|peer| peer.address == client_addr.to_string())
+            .unwrap();
+        assert_eq!(later.bytes_received, 2 * query.len() as u64);
+        assert_eq!(
+            later.bytes_sent,
+            (first_reply.size + second_reply.size) as u64
+        );
+        assert_eq!(later.messages_received["GetStatus"], 2);
+        assert_eq!(later.connected_since, client_info.connected_since);
+        assert!(later.last_message > client_info.last_message);
+
+        // The list survives being printed as JSON
+        let json = serde_json::to_string(&info(&context)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<PeerInfo>>(&json).unwrap(),
+            info(&context)
+        );
+        // Only the operator may see them
+        let list = Package::GetPeerInfo {
+            addr_from: CENTRAL_NODE.to_string(),
+        };
+        let remote: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        match Server::answer_admin(&blockchain, &context, &peer_manager, remote, &list)? {
+            Some(Package::Peers { peers: Err(_), .. }) => {}
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        let local: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        match Server::answer_admin(&blockchain, &context, &peer_manager, local, &list)? {
+            Some(Package::Peers {
+                peers: Ok(peers), ..
+            }) => assert_eq!(peers.len(), 2),
+            other => panic!("unexpected reply: {other:?}"),
+        }
+
+        // Only the operator may disconnect, and the peer sees its connection end
+        let disconnect = Package::DisconnectPeer {
+            addr_from: CENTRAL_NODE.to_string(),
+            address: gossip_addr.to_string(),
+            ban_secs: None,
+        };
+        match Server::answer_admin(&blockchain, &context, &peer_manager, remote, &disconnect)? {
+            Some(Package::Disconnected { result: Err(_), .. }) => {}
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        assert_eq!(context.peer_connections().len(), 2);
+        match Server::answer_admin(&blockchain, &context, &peer_manager, local, &disconnect)? {
+            Some(Package::Disconnected {
+                result: Ok(closed), ..
+            }) => assert_eq!(closed, [gossip_addr.to_string()]),
+            other => panic!("unexpected reply: {other:?}"),
+        }
+        gossip
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(gossip.read(&mut [0u8; 16]).unwrap(), 0);
+        let remaining = info(&context);
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].address, client_addr.to_string());
+        assert!(!peer_manager.is_banned(gossip_addr.ip())?);
+
+        // Banning along the way refuses the host afterwards
+        let disconnect_and_ban = Package::DisconnectPeer {
+            addr_from: CENTRAL_NODE.to_string(),
+            address: client_addr.to_string(),
+            ban_secs: Some(60),
+        };
+        Server::answer_admin(
+            &blockchain,
+            &context,
+            &peer_manager,
+            local,
+            &disconnect_and_ban,
+        )?;
+        assert!(peer_manager.is_banned(client_addr.ip())?);
+        assert!(context.peer_connections().is_empty());
+
+        for reader in readers {
+            let _ = reader.join().unwrap();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_tip_restarts_stale_mining_job() -> Result<()> {
+        let temp_dir = tempdir().map_err(|e| BlockchainError::Io(e.to_string()))?;
+        let db_path = temp_dir.path().join("test_blockchain");
+        let miner_address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let blockchain =
+            Blockchain::create_blockchain_with_path(miner_address, db_path.to_str().unwrap())?;
+        let context = Arc::new(NodeContext::default());
+        context.config().set_mining_addr(miner_address.to_string());
+
+        // A job on the genesis tip that would never finish on its own
+        let coinbase = Transaction::new_coinbase_tx(miner_address)?;
+        let template = Block::new_template(blockchain.get_tip_hash(), &[coinbase], 1, 64)?;
+        let stale = context.miner().start(template);
+        *context.mining_job() = Some(stale.clone());
+
+        // A competing block moves the tip on
+        let competitor = blockchain.mine_block_with_fees(&[], miner_address)?;
+        Server::restart_stale_mining(&blockchain, &context)?;
+
+        assert!(stale.wait().is_none());
+
+        // The fresh job mines on the competitor and its waiter stores the result
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while blockchain.get_best_height()? < 2 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "fresh job never finished"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        let tip = blockchain
+            .get_block(&blockchain.get_tip_hash())?
+            .expect("tip block should exist");
+        assert_eq!(tip.get_pre_block_hash(), competitor.get_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn test_light_client_gets_proofs_for_filtered_transactions() -> Result<()> {
+        use crate::wallet::{hash_pub_key, Wallet};
+
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+        let watched = Wallet::new()?;
+        let other = Wallet::new()?.get_address();
+
+        // Five transactions, of which the second and fourth pay the watched address
+        let payees = [
+            &other,
+            &watched.get_address(),
+            &other,
+            &watched.get_address(),
+            &other,
+        ];
+        let txs = payees
+            .iter()
+            .map(|payee| Transaction::new_coinbase_tx(payee))
+            .collect::<Result<Vec<_>>>()?;
+        let tip = blockchain
+            .get_block(&blockchain.get_tip_hash())?
+            .expect("tip block");
+        let block = Block::new_test_block(
+            tip.get_timestamp() + 1_000,
+            tip.get_hash().to_string(),
+            &txs,
+            1,
+            tip.get_difficulty(),
+        )?;
+        blockchain.add_block(&block)?;
+
+        let client = TcpListener::bind("127.0.0.1:0")?;
+        let client_addr = client.local_addr()?.to_string();
+        let mut filter = BloomFilter::new(128, 5, 42)?;
+        filter.insert(&hash_pub_key(watched.get_public_key()));
+
+        Server::process_message(
+            &blockchain,
+            &context,
+            test_peer(),
+            Package::FilterLoad {
+                addr_from: client_addr.clone(),
+                filter: filter.get_bits().to_vec(),
+                hash_funcs: filter.get_hash_funcs(),
+                tweak: filter.get_tweak(),
+            },
+        )?;
+        Server::process_message(
+            &blockchain,
+            &context,
+            test_peer(),
+            Package::GetData {
+                addr_from: client_addr,
+                op_type: OpType::Block,
+                id: block.get_hash_bytes(),
+            },
+        )?;
+
+        // The connection stays open for whatever the node sends next
+        let (stream, _) = client.accept()?;
+        let reply: Package = Deserializer::from_reader(stream)
+            .into_iter()
+            .next()
+            .unwrap()
+            .map_err(|e| BlockchainError::Network(format!("Failed to read reply: {e}")))?;
+        let Package::MerkleBlock { header, proofs, .. } = reply else {
+            panic!("expected a filtered block, got {reply:?}");
+        };
+
+        let verified = verify_merkle_block(&header, &proofs)?;
+        assert_eq!(verified.header.hash, block.get_hash());
+        assert_eq!(verified.header.merkle_root, block.get_merkle_root());
+        assert_eq!(verified.header.transaction_count, 5);
+        assert_eq!(
+            verified.txids,
+            vec![txs[1].get_id().to_vec(), txs[3].get_id().to_vec()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_initial_sync_applies_blocks_without_reindexing() -> Result<()> {
+        use crate::core::{FeeMode, FeePriority, TXOutput};
+        use crate::utils::serialize;
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::{decode_address, Wallets};
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let path = |name: &str| temp_dir.path().join(name).to_str().unwrap().to_string();
+        let balance_of = |utxo_set: &UTXOSet, address: &str| -> u64 {
+            let (_, pub_key_hash) = decode_address(address).unwrap();
+            utxo_set
+                .find_utxo(&pub_key_hash)
+                .iter()
+                .map(|out| out.get_value().to_satoshis())
+                .sum()
+        };
+
+        let mut wallets = Wallets::new();
+        let owners: Vec<String> = (0..=50).map(|_| wallets.create_wallet().unwrap()).collect();
+        let source = Blockchain::create_blockchain_with_path(&owners[0], &path("source"))?;
+        let source_utxo_set = UTXOSet::new(source.clone());
+        let context = Arc::new(NodeContext::new(FeeMode::Fixed { amount: 3 })?);
+        let amount_at = |height: usize| 10_000 - height as u64 * 100;
+
+        // Fifty blocks, each handing the coins received in the previous block to the next owner
+        for height in 1..=50 {
+            source_utxo_set.reindex();
+            let tx = Transaction::new_utxo_transaction_with_context(
+                &owners[height - 1],
+                &owners[height],
+                amount_at(height),
+                FeePriority::Normal,
+                &source_utxo_set,
+                &context,
+            )?;
+            source.mine_block_with_context(&[tx], &owners[0], &context)?;
+        }
+        source_utxo_set.reindex();
+
+        let genesis = source.get_block_at_height(0)?.unwrap();
+        let node = Blockchain::create_blockchain_from_genesis(&genesis, &path("node"), false)?;
+        let utxo_set = UTXOSet::new(node.clone());
+        utxo_set.reindex_safe()?;
+
+        // A reindex clears the whole tree, so this entry only survives if none happens
+        let chainstate = node.get_db().open_tree("chainstate").unwrap();
+        let sentinel = serialize(&vec![(0usize, TXOutput::new(1, &owners[0])?)])?;
+        chainstate.insert(b"reindex sentinel", sentinel).unwrap();
+
+        // The peer announced blocks 1..=50; the node asks for each remaining one by
+        // GetData as the previous one arrives, all on the one connection it opened
+        let peer = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap().to_string();
+        let requests = thread::spawn(move || {
+            let (stream, _) = peer.accept().unwrap();
+            let mut requested = Vec::new();
+            for pkg in Deserializer::from_reader(stream).into_iter().take(49) {
+                match pkg.unwrap() {
+                    Package::GetData { id, .. } => requested.push(id),
+                    other => panic!("unexpected package: {other:?}"),
+                }
+            }
+            peer.set_nonblocking(true).unwrap();
+            assert!(peer.accept().is_err());
+            requested
+        });
+
+        let blocks = source.blocks_in_range(1, 50)?;
+        let hashes: Vec<Vec<u8>> = blocks.iter().map(|b| b.get_hash_bytes()).collect();
+        context.blocks_in_transit().add_blocks(&hashes[1..]);
+        for (block, height) in blocks.iter().zip(1..) {
+            Server::handle_block_message(
+                &node,
+                &context,
+                test_peer(),
+                peer_addr.clone(),
+                block.serialize()?,
+            )?;
+            // Each block reaches the chainstate as it is connected, not after the last one
+            assert_eq!(balance_of(&utxo_set, &owners[height]), amount_at(height));
+        }
+        assert_eq!(requests.join().unwrap(), hashes[1..]);
+        assert!(context.blocks_in_transit().is_empty());
+
+        assert_eq!(node.get_best_height()?, 50);
+        assert!(chainstate.get(b"reindex sentinel").unwrap().is_some());
+        chainstate.remove(b"reindex sentinel").unwrap();
+        for owner in &owners {
+            assert_eq!(
+                balance_of(&utxo_set, owner),
+                balance_of(&source_utxo_set, owner)
+            );
+        }
+        assert_eq!(balance_of(&utxo_set, &owners[50]), amount_at(50));
+        assert_eq!(
+            balance_of(&utxo_set, &owners[20]),
+            amount_at(20) - amount_at(21) - 3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_timer_mines_a_single_pending_transaction() -> Result<()> {
+        use crate::core::{FeeMode, FeePriority};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let receiver = wallets.create_wallet()?;
+        let blockchain =
+            Blockchain::create_blockchain_with_path(&sender, db_path.to_str().unwrap())?;
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe()?;
+
+        let context = Arc::new(NodeContext::new(FeeMode::Fixed { amount: 3 })?);
+        context.config().set_mining_addr(receiver.clone());
+        context.config().set_block_interval(Duration::from_secs(1));
+        let tx = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &receiver,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )?;
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        context.memory_pool().add(tx);
+
+        // One transaction is far below the threshold, so only the timer can mine it
+        let server = Server::with_context(blockchain.clone(), Arc::clone(&context));
+        let timer = server.start_block_timer().expect("a block interval is set");
+
+        let mut waited = Duration::ZERO;
+        // The block is stored before its transactions leave the pool, so wait for both
+        while (blockchain.get_best_height()? == 0 || !context.memory_pool().is_empty())
+            && waited < Duration::from_secs(10)
+        {
+            thread::sleep(Duration::from_millis(100));
+            waited += Duration::from_millis(100);
+        }
+        assert_eq!(blockchain.get_best_height()?, 1);
+
+        let tip = blockchain.get_block(&blockchain.get_tip_hash())?.unwrap();
+        assert_eq!(tip.get_transactions().len(), 2);
+        assert_eq!(
+            HEXLOWER.encode(tip.get_transactions()[1].get_id()),
+            txid_hex
+        );
+        assert!(!context.memory_pool().contains(&txid_hex));
+
+        // Stopping doesn't wait out the rest of the interval
+        context
+            .config()
+            .set_block_interval(Duration::from_secs(3600));
+        let long_timer = server.start_block_timer().expect("a block interval is set");
+        let stopping = Instant::now();
+        server.stop_handle().stop();
+        timer.join().unwrap();
+        long_timer.join().unwrap();
+        assert!(stopping.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_relayed_inputs_are_looked_up_in_the_chainstate_and_the_pool() -> Result<()> {
+        use crate::core::{FeeMode, FeePriority, TXOutput};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let receiver = wallets.create_wallet()?;
+        let blockchain =
+            Blockchain::create_blockchain_with_path(&sender, db_path.to_str().unwrap())?;
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe()?;
+        let context = Arc::new(NodeContext::new(FeeMode::Fixed {
+            amount: MIN_TRANSACTION_FEE,
+        })?);
+        let relay = |tx: &Transaction| {
+            Server::handle_tx_message(
+                &blockchain,
+                &context,
+                CENTRAL_NODE,
+                tx.serialize().unwrap(),
+                false,
+                false,
+            )
+            .unwrap()
+        };
+
+        // Both spend the genesis coinbase; the first one gets confirmed
+        let pay = |amount| {
+            Transaction::new_utxo_transaction_with_context(
+                &sender,
+                &receiver,
+                amount,
+                FeePriority::Normal,
+                &utxo_set,
+                &context,
+            )
+        };
+        let confirmed = pay(100_000)?;
+        let double_spend = pay(200_000)?;
+        let block = blockchain.mine_block_with_context(
+            std::slice::from_ref(&confirmed),
+            &sender,
+            &context,
+        )?;
+        utxo_set.update_safe(&block)?;
+
+        let err = blockchain
+            .validate_transaction_inputs(&double_spend)
+            .unwrap_err();
+        assert!(err.to_string().contains("already spent"), "{err}");
+
+        // It may only have lost the race to the block, so the sender isn't penalized
+        assert!(relay(&double_spend).is_none());
+        assert!(context.memory_pool().is_empty());
+
+        // An output of a pending transaction can be spent before it's mined
+        let parent = pay(100_000)?;
+        assert!(relay(&parent).is_none());
+        let paid = parent
+            .get_vout()
+            .iter()
+            .position(|output| output.get_value() == 100_000u64)
+            .unwrap();
+        let mut child = Transaction::new_raw(
+            &[(parent.get_id().to_vec(), paid)],
+            vec![TXOutput::new(90_000, &sender)?],
+        )?;
+        child.sign_with_outputs(
+            wallets.get_wallet(&receiver).unwrap(),
+            &[parent.get_vout()[paid].clone()],
+        )?;
+        assert!(relay(&child).is_none());
+        assert!(context
+            .memory_pool()
+            .contains(&HEXLOWER.encode(child.get_id())));
+
+        // A parent nobody has seen yet is no proof of anything either, but an output
+        // past the end of a known transaction is
+        let mut orphan =
+            Transaction::new_raw(&[(vec![7; 32], 0)], vec![TXOutput::new(1_000, &sender)?])?;
+        orphan.sign_with_outputs(
+            wallets.get_wallet(&sender).unwrap(),
+            &[TXOutput::new(5_000, &sender)?],
+        )?;
+        assert!(relay(&orphan).is_none());
+        let mut past_the_end = Transaction::new_raw(
+            &[(confirmed.get_id().to_vec(), confirmed.get_vout().len())],
+            vec![TXOutput::new(1_000, &sender)?],
+        )?;
+        past_the_end.sign_with_outputs(
+            wallets.get_wallet(&sender).unwrap(),
+            &[TXOutput::new(5_000, &sender)?],
+        )?;
+        assert!(matches!(
+            relay(&past_the_end),
+            Some(Misbehavior::InvalidTransaction)
+        ));
+        Ok(())
+    }
+
+    // A node on a fresh chain paying the genesis coinbase to `sender`, with its UTXO set
+    fn relay_policy_node(
+        temp_dir: &tempfile::TempDir,
+        sender: &str,
+        fee_mode: crate::core::FeeMode,
+    ) -> Result<(Blockchain, UTXOSet, Arc<NodeContext>)> {
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(sender, db_path.to_str().unwrap())?;
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe()?;
+        Ok((blockchain, utxo_set, Arc::new(NodeContext::new(fee_mode)?)))
+    }
+
+    #[test]
+    fn test_high_fee_override_is_only_taken_from_the_local_host() -> Result<()> {
+        use crate::core::{FeeMode, FeePolicy, TransactionBuilder, MAX_TRANSACTION_FEE};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let receiver = wallets.create_wallet()?;
+        let fee_mode = FeeMode::Fixed {
+            amount: MIN_TRANSACTION_FEE,
+        };
+        let (blockchain, utxo_set, context) = relay_policy_node(&temp_dir, &sender, fee_mode)?;
+        let tx = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output(&receiver, 1_000)
+            .fee_policy(FeePolicy::Explicit(Amount::from_satoshis(
+                MAX_TRANSACTION_FEE + 1,
+            )))
+            .allow_high_fee(true)
+            .with_context(&context)
+            .build_and_sign(&wallets)?;
+        let pkg = || -> Result<Package> {
+            Ok(Package::Tx {
+                addr_from: CENTRAL_NODE.to_string(),
+                transaction: tx.serialize()?,
+                allow_high_fee: true,
+                confirmed: false,
+            })
+        };
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+
+        let remote: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        assert_eq!(
+            Server::process_message(&blockchain, &context, remote, pkg()?)?,
+            None
+        );
+        assert!(!context.memory_pool().contains(&txid_hex));
+
+        Server::process_message(&blockchain, &context, test_peer(), pkg()?)?;
+        assert!(context.memory_pool().contains(&txid_hex));
+        Ok(())
+    }
+
+    #[test]
+    fn test_overpaying_transaction_is_admitted_and_mined_in_dynamic_mode() -> Result<()> {
+        use crate::core::{DynamicFeeConfig, FeeMode, FeePolicy, TransactionBuilder};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let receiver = wallets.create_wallet()?;
+        let fee_mode = FeeMode::Dynamic {
+            config: DynamicFeeConfig::with_base_fee(MIN_TRANSACTION_FEE),
+        };
+        let (blockchain, utxo_set, context) = relay_policy_node(&temp_dir, &sender, fee_mode)?;
+
+        let asked = context.calculate_fee(250, None);
+        let overpaid = asked.saturating_add(Amount::from_satoshis(40));
+        assert!(context.validate_fee(overpaid, None).is_ok());
+        let tx = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output(&receiver, 1_000)
+            .fee_policy(FeePolicy::Explicit(overpaid))
+            .with_context(&context)
+            .build_and_sign(&wallets)?;
+
+        Server::handle_tx_message(
+            &blockchain,
+            &context,
+            CENTRAL_NODE,
+            tx.serialize()?,
+            false,
+            false,
+        )?;
+        assert!(context
+            .memory_pool()
+            .contains(&HEXLOWER.encode(tx.get_id())));
+
+        let block = &blockchain.generate_blocks(1, &receiver, &context)?[0];
+        assert!(block
+            .get_transactions()
+            .iter()
+            .any(|mined| mined.get_id() == tx.get_id()));
+        assert_eq!(block.get_total_fees(), overpaid);
+        assert!(context.memory_pool().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_below_the_relay_rate_is_refused_but_valid_in_a_block() -> Result<()> {
+        use crate::core::{FeeMode, FeePriority};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let receiver = wallets.create_wallet()?;
+        let (blockchain, utxo_set, context) =
+            relay_policy_node(&temp_dir, &sender, FeeMode::Fixed { amount: 3 })?;
+        context.config().set_min_relay_fee_rate(1);
+
+        let tx = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &receiver,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )?;
+        assert!(matches!(
+            context.relay_fee_policy().check(&tx),
+            Err(BlockchainError::FeeTooLow { fee: 3, .. })
+        ));
+        let misbehavior = Server::handle_tx_message(
+            &blockchain,
+            &context,
+            CENTRAL_NODE,
+            tx.serialize()?,
+            false,
+            false,
+        )?;
+        // Policy isn't consensus, so the peer did nothing wrong either
+        assert_eq!(misbehavior, None);
+        assert!(context.memory_pool().is_empty());
+
+        // A miner that doesn't share the policy can still put it in a block
+        let block =
+            blockchain.mine_block_with_context(std::slice::from_ref(&tx), &receiver, &context)?;
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+        assert!(block
+            .get_transactions()
+            .iter()
+            .any(|mined| mined.get_id() == tx.get_id()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_free_transactions_are_relayed_only_when_allowed() -> Result<()> {
+        use crate::core::{FeeMode, FeePolicy, TransactionBuilder};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let receiver = wallets.create_wallet()?;
+        let (blockchain, utxo_set, context) =
+            relay_policy_node(&temp_dir, &sender, FeeMode::Fixed { amount: 3 })?;
+
+        let free = TransactionBuilder::new(&utxo_set)
+            .from(&sender)
+            .add_output(&receiver, 1_000)
+            .fee_policy(FeePolicy::Explicit(Amount::ZERO))
+            .with_context(&context)
+            .build_and_sign(&wallets)?;
+        let txid_hex = HEXLOWER.encode(free.get_id());
+        let relay = || {
+            Server::handle_tx_message(
+                &blockchain,
+                &context,
+                CENTRAL_NODE,
+                free.serialize().unwrap(),
+                false,
+                false,
+            )
+        };
+
+        relay()?;
+        assert!(!context.memory_pool().contains(&txid_hex));
+
+        context.config().set_allow_free_transactions(true);
+        relay()?;
+        assert!(context.memory_pool().contains(&txid_hex));
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_whose_id_does_not_match_is_refused_as_misbehavior() -> Result<()> {
+        use crate::core::{FeeMode, FeePriority};
+        use crate::wallet::wallets::lock_wallet_file;
+        use crate::wallet::Wallets;
+
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet()?;
+        let receiver = wallets.create_wallet()?;
+        let (blockchain, utxo_set, context) =
+            relay_policy_node(&temp_dir, &sender, FeeMode::Fixed { amount: 3 })?;
+
+        // Repriced after signing: the id gives it away before any signature is checked
+        let mut tx = Transaction::new_utxo_transaction_with_context(
+            &sender,
+            &receiver,
+            1_000,
+            FeePriority::Normal,
+            &utxo_set,
+            &context,
+        )?;
+        tx.set_fee(2);
+        let misbehavior = Server::handle_tx_message(
+            &blockchain,
+            &context,
+            CENTRAL_NODE,
+            tx.serialize()?,
+            false,
+            false,
+        )?;
+        assert_eq!(misbehavior, Some(Misbehavior::InvalidTransaction));
+        assert!(context.memory_pool().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_over_the_input_limit_is_refused_without_penalty() -> Result<()> {
+        use crate::core::{FeeMode, TXOutput, DEFAULT_MAX_TRANSACTION_INPUTS};
+
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain = Blockchain::create_blockchain_with_path(
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            db_path.to_str().unwrap(),
+        )?;
+        let context = Arc::new(NodeContext::new(FeeMode::Fixed { amount: 3 })?);
+
+        let inputs: Vec<(Vec<u8>, usize)> = (0..=DEFAULT_MAX_TRANSACTION_INPUTS)
+            .map(|vout| (vec![7; 32], vout))
+            .collect();
+        let outputs = vec![TXOutput::new(1_000, "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")?];
+        let tx = Transaction::new_raw(&inputs, outputs)?;
+        assert!(matches!(
+            tx.check_limits(),
+            Err(BlockchainError::TransactionTooLarge(_))
+        ));
+
+        let misbehavior = Server::handle_tx_message(
+            &blockchain,
+            &context,
+            CENTRAL_NODE,
+            tx.serialize()?,
+            false,
+            false,
+        )?;
+        assert_eq!(misbehavior, None);
+        assert!(context.memory_pool().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mined_transaction_is_served_as_confirmed() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+        let genesis = blockchain
+            .get_block(&blockchain.get_tip_hash())?
+            .expect("genesis block");
+        let coinbase = genesis.get_transactions()[0].clone();
+        assert!(context
+            .memory_pool()
+            .get(&HEXLOWER.encode(coinbase.get_id()))
+            .is_none());
+
+        let peer = TcpListener::bind("127.0.0.1:0")?;
+        Server::process_message(
+            &blockchain,
+            &context,
+            test_peer(),
+            Package::GetData {
+                addr_from: peer.local_addr()?.to_string(),
+                op_type: OpType::Tx,
+                id: coinbase.get_id().to_vec(),
+            },
+        )?;
+
+        let reply = next_package(&peer)?;
+        let Package::Tx {
+            transaction,
+            confirmed,
+            ..
+        } = reply
+        else {
+            panic!("expected the transaction, got {reply:?}");
+        };
+        assert!(confirmed);
+        assert_eq!(
+            Transaction::deserialize(&transaction)?.get_id(),
+            coinbase.get_id()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_transaction_is_answered_with_not_found() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+
+        let peer = TcpListener::bind("127.0.0.1:0")?;
+        Server::process_message(
+            &blockchain,
+            &context,
+            test_peer(),
+            Package::GetData {
+                addr_from: peer.local_addr()?.to_string(),
+                op_type: OpType::Tx,
+                id: vec![9; 32],
+            },
+        )?;
+
+        let reply = next_package(&peer)?;
+        assert!(
+            matches!(&reply, Package::NotFound { op_type: OpType::Tx, id, .. } if id == &vec![9; 32]),
+            "expected NotFound, got {reply:?}"
+        );
+
+        // The asking side stops waiting, so another peer can be asked, but only when the
+        // peer it asked says so
+        let Package::NotFound { addr_from, .. } = &reply else {
+            unreachable!()
+        };
+        assert!(context.request_tx(&[9; 32], addr_from));
+        let unsolicited = Package::NotFound {
+            addr_from: "127.0.0.1:2999".to_string(),
+            op_type: OpType::Tx,
+            id: vec![9; 32],
+        };
+        Server::process_message(&blockchain, &context, test_peer(), unsolicited)?;
+        assert!(context.is_tx_requested(&[9; 32]));
+        Server::process_message(&blockchain, &context, test_peer(), reply)?;
+        assert!(!context.is_tx_requested(&[9; 32]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_peer_is_told_about_pending_transactions() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+        let mut pending = Vec::new();
+        for fee in [2, 7, 4] {
+            let tx =
+                Transaction::new_placeholder_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").with_fee(fee);
+            pending.push(tx.get_id().to_vec());
+            context.memory_pool().add(tx);
+        }
+
+        let peer = TcpListener::bind("127.0.0.1:0")?;
+        let peer_addr = peer.local_addr()?.to_string();
+        let (height, chain_work) = Server::local_tip(&blockchain)?;
+        let hello = || -> Result<Package> {
+            Ok(Package::Version {
+                addr_from: peer_addr.clone(),
+                version: NODE_VERSION,
+                best_height: height,
+                chain_work,
+                format_version: FORMAT_VERSION,
+                genesis_hash: blockchain.get_genesis_hash()?,
+                timestamp: current_timestamp()?,
+                wire_formats: Vec::new(),
+                network_magic: Some(Server::network_magic(&blockchain, &context)?),
+            })
+        };
+        // Someone else claiming the peer's address doesn't get it sent anything
+        let elsewhere: SocketAddr = "10.0.0.9:40000".parse().unwrap();
+        Server::process_message(&blockchain, &context, elsewhere, hello()?)?;
+        peer.set_nonblocking(true)?;
+        assert!(peer.accept().is_err());
+        peer.set_nonblocking(false)?;
+        Server::process_message(&blockchain, &context, test_peer(), hello()?)?;
+
+        let reply = next_package(&peer)?;
+        let Package::Inv {
+            op_type: OpType::Tx,
+            items,
+            ..
+        } = reply
+        else {
+            panic!("expected an inventory of transactions, got {reply:?}");
+        };
+        // Same sizes, so the best fee rate is the highest fee
+        assert_eq!(
+            items,
+            vec![pending[1].clone(), pending[2].clone(), pending[0].clone()]
+        );
+
+        // Already told, so nothing is left for its next hello
+        assert!(context.unannounced_txs(&peer_addr, &pending).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stalled_block_is_asked_of_another_peer() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        let slow = TcpListener::bind("127.0.0.1:0")?;
+        let slow_addr = slow.local_addr()?.to_string();
+        let other = TcpListener::bind("127.0.0.1:0")?;
+        let other_addr = other.local_addr()?.to_string();
+        context.add_relay_peer(&slow_addr);
+        context.add_relay_peer(&other_addr);
+
+        let hash = b"0000missing".to_vec();
+        // Announced by another host in the slow peer's name
+        let announcer: SocketAddr = "10.0.0.9:40000".parse().unwrap();
+        let inv = || Package::Inv {
+            addr_from: slow_addr.clone(),
+            op_type: OpType::Block,
+            items: vec![hash.clone()],
+        };
+        Server::process_message(&blockchain, &context, announcer, inv())?;
+        assert!(matches!(next_package(&slow)?, Package::GetData { id, .. } if id == hash));
+        // Announced again while in flight, it isn't asked for twice
+        Server::process_message(&blockchain, &context, announcer, inv())?;
+        assert_eq!(context.blocks_in_transit().len(), 1);
+        assert_eq!(
+            context.blocks_in_transit().requested_from(&hash),
+            Some(slow_addr)
+        );
+
+        let asked = Instant::now();
+        Server::requeue_stalled_blocks(&context, &peer_manager, asked);
+        assert_eq!(
+            peer_manager.get_misbehavior_score(IpAddr::from([127, 0, 0, 1]))?,
+            0
+        );
+
+        Server::requeue_stalled_blocks(&context, &peer_manager, asked + BLOCK_REQUEST_TIMEOUT);
+        assert!(matches!(next_package(&other)?, Package::GetData { id, .. } if id == hash));
+        assert_eq!(
+            context.blocks_in_transit().requested_from(&hash),
+            Some(other_addr.clone())
+        );
+        // The announcer answers for the stall, not the peer it named
+        assert_eq!(
+            peer_manager.get_misbehavior_score(IpAddr::from([127, 0, 0, 1]))?,
+            0
+        );
+        assert_eq!(
+            peer_manager.get_misbehavior_score(announcer.ip())?,
+            STALLED_DOWNLOAD_PENALTY
+        );
+
+        // A relay peer asked in its place answers for its own
+        Server::requeue_stalled_blocks(&context, &peer_manager, asked + BLOCK_REQUEST_TIMEOUT * 2);
+        assert_eq!(
+            peer_manager.get_misbehavior_score(IpAddr::from([127, 0, 0, 1]))?,
+            STALLED_DOWNLOAD_PENALTY
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_discovery_dials_only_reachable_seeded_peers() -> Result<()> {
+        use crate::network::connectivity::{ConnectivityCall, MockConnectivity};
+
+        let blockchain = create_test_blockchain()?;
+        let context = Arc::new(NodeContext::default());
+        let resolved: Vec<SocketAddr> = ["10.0.0.1:2001", "10.0.0.2:2001", "10.0.0.3:2001"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let reachable = [resolved[0], resolved[2]];
+        let mock = Arc::new(
+            MockConnectivity::new()
+                .resolves("seed.test", resolved.clone())
+                .reachable(reachable),
+        );
+        let server = Server::with_context(blockchain.clone(), Arc::clone(&context))
+            .with_dns_seeder(
+                DnsSeeder::with_seeds(vec!["seed.test".to_string()], 2001)
+                    .with_connectivity(mock.clone()),
+            );
+
+        Server::discover_and_contact_peers(
+            &blockchain,
+            &context,
+            &server.peer_manager,
+            &server.seed_node,
+        );
+
+        // Seeds, then probes of everything they resolved to, then hellos to the reachable
+        let mut expected = vec![ConnectivityCall::Resolve("seed.test".to_string())];
+        expected.extend(resolved.iter().map(|addr| ConnectivityCall::Probe(*addr)));
+        let calls = mock.calls();
+        assert_eq!(calls[..4], expected[..]);
+        let mut dialed = mock.handshakes();
+        dialed.sort();
+        assert_eq!(dialed, reachable);
+        assert_eq!(calls.len(), 6);
+
+        let known = server.peer_manager.get_known_peers()?;
+        assert_eq!(known.len(), 2);
+        assert!(reachable.iter().all(|addr| known[addr].successes == 1));
+        // The mock took the handshakes, so no connection was opened
+        assert_eq!(context.outbound_connections().opened(), 0);
+        assert!(context.outbound_connections().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_peers_of_another_network_are_refused() -> Result<()> {
+        let blockchain = create_test_blockchain()?;
+        blockchain.record_network(Network::Testnet)?;
+        // The database decides, whatever the node was configured with
+        let context = NodeContext::default();
+        context.config().set_network(Network::Regtest);
+        let genesis = blockchain.get_genesis_hash()?;
+        let own = Network::Testnet.magic(&genesis);
+
+        assert_eq!(
+            Server::network_refusal(&blockchain, &context, Some(own))?,
+            None
+        );
+        // Same name, other genesis block; other name, same genesis block
+        for magic in [
+            Network::Testnet.magic("00ff"),
+            Network::Regtest.magic(&genesis),
+        ] {
+            assert!(Server::network_refusal(&blockchain, &context, Some(magic))?.is_some());
+        }
+
+        // Peers from before the magic pass until the operator stops allowing them
+        assert_eq!(Server::network_refusal(&blockchain, &context, None)?, None);
+        context.config().set_allow_unversioned_network(false);
+        assert!(Server::network_refusal(&blockchain, &context, None)?.is_some());
+        Ok(())
     }
 }
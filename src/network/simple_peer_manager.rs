@@ -1,16 +1,352 @@
 use crate::error::{BlockchainError, Result};
+use crate::network::connectivity::PeerConnectivity;
 use crate::network::dns_seeding::DnsSeeder;
-use log::info;
-use std::collections::HashSet;
-use std::net::SocketAddr;
+use crate::network::identity::PeerId;
+use crate::network::netmask::Netmask;
+use crate::network::node::{Node, Nodes, NODE_NETWORK};
+use crate::utils::{current_timestamp, deserialize, serialize};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Default number of messages a peer may send per second
+pub const DEFAULT_MAX_MESSAGES_PER_SECOND: u32 = 50;
+/// Default misbehavior score at which a peer gets banned
+pub const DEFAULT_BAN_THRESHOLD: u32 = 100;
+/// Default ban duration (24 hours)
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// Default longest ban that repeat offenses double up to (30 days)
+pub const DEFAULT_MAX_BAN_DURATION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Default time it takes for one point of misbehavior score to be forgiven
+pub const DEFAULT_SCORE_DECAY: Duration = Duration::from_secs(60);
+
+/// Misbehavior score for a message larger than the size limit
+pub const OVERSIZED_MESSAGE_PENALTY: u32 = 50;
+/// Misbehavior score for a package that breaks a field limit
+pub const INVALID_PACKAGE_PENALTY: u32 = 20;
+/// Misbehavior score for exceeding the message rate
+pub const RATE_LIMIT_PENALTY: u32 = 20;
+/// Misbehavior score for data that isn't a valid package at all
+pub const MALFORMED_MESSAGE_PENALTY: u32 = 10;
+/// Misbehavior score for a block that fails validation
+pub const INVALID_BLOCK_PENALTY: u32 = 50;
+/// Misbehavior score for a transaction that can't be decoded or spends what it can't
+pub const INVALID_TRANSACTION_PENALTY: u32 = 10;
+/// Misbehavior score for a reply to a query this node never sent
+pub const UNSOLICITED_MESSAGE_PENALTY: u32 = 5;
+/// Misbehavior score for not sending a requested block in time
+pub const STALLED_DOWNLOAD_PENALTY: u32 = 10;
+
+/// Most signing keys tracked at once; keys cost nothing to make, so past it the one heard
+/// from longest ago is forgotten, or the ban ending soonest once that many are banned
+pub const MAX_TRACKED_IDENTITIES: usize = 1_000;
+/// Tree in the node's database holding known peers: address -> `KnownPeer`
+pub const PEERS_TREE: &str = "peers";
+/// Tree in the node's database holding bans: `PeerKey` -> `Ban`
+pub const BANS_TREE: &str = "bans";
+/// Reason recorded for bans placed with `banpeer`
+pub const OPERATOR_BAN_REASON: &str = "banned by the operator";
+/// Known peers not seen for this long are dropped when loading (14 days)
+pub const PEER_STALE_AFTER: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+/// Wait after the first failed attempt on a peer; doubles with every further failure
+pub const PEER_BASE_BACKOFF: Duration = Duration::from_secs(60);
+/// Longest wait between attempts on a failing peer
+pub const PEER_MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+/// Known peers below which the DNS seeds are asked for more
+pub const MIN_KNOWN_PEERS: usize = 8;
+/// Furthest the network's clock may move this node's, either way (70 minutes, in ms)
+pub const MAX_TIME_OFFSET: i64 = 70 * 60 * 1000;
+/// Offset from the peers' clocks beyond which the local clock is reported as wrong
+/// (5 minutes, in ms)
+pub const CLOCK_WARNING_THRESHOLD: i64 = 5 * 60 * 1000;
+/// Peers whose clocks must be known before their median moves this node's
+pub const MIN_TIME_SAMPLES: usize = 5;
+/// Most peer clocks remembered; the oldest sample makes room for a new peer's
+pub const MAX_TIME_SAMPLES: usize = 200;
+/// Most peers of other networks remembered; the one found first makes room for a new one
+pub const MAX_OTHER_NETWORKS: usize = 1_000;
+
+/// The IP address to ban for `address`, which may also carry a port
+pub fn parse_ban_address(address: &str) -> Result<IpAddr> {
+    address
+        .parse::<IpAddr>()
+        .or_else(|_| address.parse::<SocketAddr>().map(|socket| socket.ip()))
+        .map_err(|_| BlockchainError::Network(format!("Not an IP address: {address}")))
+}
+
+/// Who rate limits, misbehavior scores and bans are charged to
+///
+/// A peer signing its packages is known by its key wherever it connects from, any other
+/// peer by its IP address. What a signed package costs is charged to the address it came
+/// from as well, see `record_message_at`, or fresh keys would dodge every limit.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PeerKey {
+    Ip(IpAddr),
+    Identity(PeerId),
+}
+
+impl From<IpAddr> for PeerKey {
+    fn from(ip: IpAddr) -> Self {
+        PeerKey::Ip(ip)
+    }
+}
+
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerKey::Ip(ip) => write!(f, "{ip}"),
+            PeerKey::Identity(peer_id) => write!(f, "key:{peer_id}"),
+        }
+    }
+}
+
+impl FromStr for PeerKey {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("key:") {
+            Some(peer_id) => Ok(PeerKey::Identity(PeerId::parse(peer_id)?)),
+            None => s
+                .parse::<IpAddr>()
+                .map(PeerKey::Ip)
+                .map_err(|_| BlockchainError::Network(format!("Not a peer: {s}"))),
+        }
+    }
+}
+
+/// What a node remembers about a peer address across restarts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct KnownPeer {
+    /// When the peer was learned about or last answered (ms since the epoch)
+    pub last_seen: i64,
+    /// Attempts to reach the peer that worked
+    pub successes: u32,
+    /// Attempts to reach the peer that failed
+    pub failures: u32,
+    /// Failures since the last success, which set the reconnect backoff
+    pub consecutive_failures: u32,
+    /// When the last attempt failed (ms since the epoch)
+    pub last_failure: Option<i64>,
+}
+
+impl KnownPeer {
+    pub(crate) fn new(now: i64) -> KnownPeer {
+        KnownPeer {
+            last_seen: now,
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            last_failure: None,
+        }
+    }
+
+    /// Successful minus failed attempts; higher-scoring peers are tried first
+    pub fn score(&self) -> i64 {
+        i64::from(self.successes) - i64::from(self.failures)
+    }
+
+    /// How long to wait after the last failure before trying the peer again
+    pub fn backoff(&self) -> Duration {
+        if self.consecutive_failures == 0 {
+            return Duration::ZERO;
+        }
+        let doublings = (self.consecutive_failures - 1).min(31);
+        PEER_BASE_BACKOFF
+            .saturating_mul(1 << doublings)
+            .min(PEER_MAX_BACKOFF)
+    }
+
+    fn is_backing_off(&self, now: i64) -> bool {
+        match self.last_failure {
+            Some(failed_at) => now < failed_at.saturating_add(self.backoff().as_millis() as i64),
+            None => false,
+        }
+    }
+
+    fn is_stale(&self, now: i64) -> bool {
+        now.saturating_sub(self.last_seen) > PEER_STALE_AFTER.as_millis() as i64
+    }
+}
+
+/// Ways a peer can misbehave, each with its own weight in `Penalties`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    OversizedMessage,
+    MalformedMessage,
+    RateLimitExceeded,
+    InvalidPackage,
+    InvalidBlock,
+    InvalidTransaction,
+    UnsolicitedMessage,
+    StalledDownload,
+}
+
+impl Misbehavior {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Misbehavior::OversizedMessage => "oversized message",
+            Misbehavior::MalformedMessage => "malformed message",
+            Misbehavior::RateLimitExceeded => "message rate exceeded",
+            Misbehavior::InvalidPackage => "package exceeds limits",
+            Misbehavior::InvalidBlock => "invalid block",
+            Misbehavior::InvalidTransaction => "invalid transaction",
+            Misbehavior::UnsolicitedMessage => "unsolicited message",
+            Misbehavior::StalledDownload => "stalled block download",
+        }
+    }
+}
+
+/// Misbehavior score added for each kind of misbehavior
+#[derive(Debug, Clone, PartialEq)]
+pub struct Penalties {
+    pub oversized_message: u32,
+    pub malformed_message: u32,
+    pub rate_limit_exceeded: u32,
+    pub invalid_package: u32,
+    pub invalid_block: u32,
+    pub invalid_transaction: u32,
+    pub unsolicited_message: u32,
+    pub stalled_download: u32,
+}
+
+impl Default for Penalties {
+    fn default() -> Self {
+        Self {
+            oversized_message: OVERSIZED_MESSAGE_PENALTY,
+            malformed_message: MALFORMED_MESSAGE_PENALTY,
+            rate_limit_exceeded: RATE_LIMIT_PENALTY,
+            invalid_package: INVALID_PACKAGE_PENALTY,
+            invalid_block: INVALID_BLOCK_PENALTY,
+            invalid_transaction: INVALID_TRANSACTION_PENALTY,
+            unsolicited_message: UNSOLICITED_MESSAGE_PENALTY,
+            stalled_download: STALLED_DOWNLOAD_PENALTY,
+        }
+    }
+}
+
+impl Penalties {
+    pub fn weight(&self, misbehavior: Misbehavior) -> u32 {
+        match misbehavior {
+            Misbehavior::OversizedMessage => self.oversized_message,
+            Misbehavior::MalformedMessage => self.malformed_message,
+            Misbehavior::RateLimitExceeded => self.rate_limit_exceeded,
+            Misbehavior::InvalidPackage => self.invalid_package,
+            Misbehavior::InvalidBlock => self.invalid_block,
+            Misbehavior::InvalidTransaction => self.invalid_transaction,
+            Misbehavior::UnsolicitedMessage => self.unsolicited_message,
+            Misbehavior::StalledDownload => self.stalled_download,
+        }
+    }
+}
+
+/// Rate limiting and banning thresholds for peers
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerPolicy {
+    /// Messages allowed from one address within a second
+    pub max_messages_per_second: u32,
+    /// Misbehavior score that triggers a ban
+    pub ban_threshold: u32,
+    /// How long an address is refused after its first ban; every further one doubles it
+    pub ban_duration: Duration,
+    /// Longest ban the doubling goes up to
+    pub max_ban_duration: Duration,
+    pub penalties: Penalties,
+    /// One point of misbehavior score is forgiven every `score_decay`; zero keeps
+    /// scores until a ban
+    pub score_decay: Duration,
+}
+
+impl Default for PeerPolicy {
+    fn default() -> Self {
+        Self {
+            max_messages_per_second: DEFAULT_MAX_MESSAGES_PER_SECOND,
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            ban_duration: DEFAULT_BAN_DURATION,
+            max_ban_duration: DEFAULT_MAX_BAN_DURATION,
+            penalties: Penalties::default(),
+            score_decay: DEFAULT_SCORE_DECAY,
+        }
+    }
+}
+
+impl PeerPolicy {
+    /// Length of an automatic ban for an address banned `offenses` times before
+    fn ban_duration_after(&self, offenses: u32) -> Duration {
+        self.ban_duration
+            .saturating_mul(1 << offenses.min(31))
+            .min(self.max_ban_duration)
+    }
+}
+
+/// An address refused by this node, automatically or by the operator
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct Ban {
+    /// When the ban ends (ms since the epoch)
+    pub banned_until: i64,
+    pub reason: String,
+    /// Automatic bans so far, each one twice as long as the one before
+    pub offenses: u32,
+}
+
+impl Ban {
+    pub fn is_active(&self, now: i64) -> bool {
+        now < self.banned_until
+    }
+
+    // Ended bans are kept to remember offenses, until they are as old as a stale peer
+    fn is_forgotten(&self, now: i64) -> bool {
+        now.saturating_sub(self.banned_until) > PEER_STALE_AFTER.as_millis() as i64
+    }
+}
+
+/// Per-peer bookkeeping for rate limiting and misbehavior
+#[derive(Debug, Default)]
+struct PeerBehavior {
+    /// Start of the current one-second rate window
+    window_start: Option<Instant>,
+    /// Messages received in the current window
+    messages_in_window: u32,
+    /// Accumulated misbehavior score, as of `scored_at`
+    misbehavior_score: u32,
+    scored_at: Option<Instant>,
+    /// The score got the address banned; it is wiped once the ban ends
+    banned: bool,
+}
+
+impl PeerBehavior {
+    // The score after forgiving one point per `decay` since it was last updated
+    fn current_score(&self, now: Instant, decay: Duration) -> u32 {
+        match self.scored_at {
+            Some(scored_at) if !decay.is_zero() => {
+                let forgiven = now.duration_since(scored_at).as_nanos() / decay.as_nanos();
+                self.misbehavior_score
+                    .saturating_sub(u32::try_from(forgiven).unwrap_or(u32::MAX))
+            }
+            _ => self.misbehavior_score,
+        }
+    }
+}
 
 /// Simple peer manager for blockchain networking
 ///
 /// This provides basic peer management without unnecessary complexity:
 /// - Simple peer discovery via DNS seeding
 /// - Basic connection tracking
-/// - No peer reputation, banning, or complex retry logic
+/// - Per-address message rate limiting, and bans for misbehavior or by the operator
+/// - Known peers with a quality score, persisted in the node's database
+///
+/// Rate limits and bans are tracked per IP address rather than per socket, since every
+/// message from a peer usually arrives on a fresh connection with a new source port, or
+/// per key for peers that sign their packages, see `PeerKey`.
+/// Known peers live in a `Nodes` address book keyed by the address they listen on instead.
+/// Misbehavior scores fade over time, see `PeerPolicy::score_decay`, so only a peer that
+/// keeps misbehaving gets banned.
 pub struct SimplePeerManager {
     /// DNS seeder for discovering peers
     dns_seeder: DnsSeeder,
@@ -18,6 +354,39 @@ pub struct SimplePeerManager {
     connected_peers: Arc<RwLock<HashSet<SocketAddr>>>,
     /// Maximum number of connections
     max_connections: usize,
+    /// Rate limiting and banning thresholds
+    policy: PeerPolicy,
+    /// Rate and misbehavior state per peer
+    behavior: Arc<RwLock<HashMap<PeerKey, PeerBehavior>>>,
+    /// Current bans, and ended ones still counting towards the next ban's length
+    bans: Arc<RwLock<HashMap<PeerKey, Ban>>>,
+    /// Peers learned from discovery or from their version messages
+    nodes: Arc<Nodes>,
+    /// How far each peer's clock was ahead of this node's, oldest sample first
+    time_samples: Arc<RwLock<TimeSamples>>,
+    /// The only addresses peers may connect from or be found at; empty allows every one
+    allowed_peers: Vec<Netmask>,
+    /// Peers found to be on another network, never learned about or dialed again
+    other_networks: Arc<RwLock<VecDeque<SocketAddr>>>,
+}
+
+#[derive(Debug, Default)]
+struct TimeSamples {
+    offsets: VecDeque<(IpAddr, i64)>,
+    /// Whether the current offset has been warned about
+    warned: bool,
+}
+
+/// The median of `offsets`, capped to `MAX_TIME_OFFSET` either way
+///
+/// Fewer than `MIN_TIME_SAMPLES` offsets say nothing about the network and give 0.
+pub fn median_time_offset(offsets: &[i64]) -> i64 {
+    if offsets.len() < MIN_TIME_SAMPLES {
+        return 0;
+    }
+    let mut sorted = offsets.to_vec();
+    sorted.sort_unstable();
+    sorted[sorted.len() / 2].clamp(-MAX_TIME_OFFSET, MAX_TIME_OFFSET)
 }
 
 impl SimplePeerManager {
@@ -27,19 +396,90 @@ impl SimplePeerManager {
             dns_seeder: DnsSeeder::new(default_port),
             connected_peers: Arc::new(RwLock::new(HashSet::new())),
             max_connections,
+            policy: PeerPolicy::default(),
+            behavior: Arc::new(RwLock::new(HashMap::new())),
+            bans: Arc::new(RwLock::new(HashMap::new())),
+            nodes: Arc::new(Nodes::new()),
+            time_samples: Arc::new(RwLock::new(TimeSamples::default())),
+            allowed_peers: Vec::new(),
+            other_networks: Arc::new(RwLock::new(VecDeque::new())),
         }
     }
 
+    /// Use a custom rate limiting and banning policy
+    pub fn with_policy(mut self, policy: PeerPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Discover peers through `dns_seeder` instead of the default seeds
+    pub fn with_dns_seeder(mut self, dns_seeder: DnsSeeder) -> Self {
+        self.dns_seeder = dns_seeder;
+        self
+    }
+
+    /// How peers are resolved, probed and dialed, the DNS seeder's connectivity
+    pub fn connectivity(&self) -> Arc<dyn PeerConnectivity> {
+        self.dns_seeder.connectivity()
+    }
+
+    /// Only deal with peers inside `allowed_peers`, e.g. for a classroom network
+    ///
+    /// Connections from other addresses are refused, and other addresses are never
+    /// learned about or dialed. Bans still apply inside the list. An empty list allows
+    /// every address.
+    pub fn with_allowed_peers(mut self, allowed_peers: Vec<Netmask>) -> Self {
+        self.allowed_peers = allowed_peers;
+        self
+    }
+
+    /// The ranges peers are restricted to, empty when there is no restriction
+    pub fn get_allowed_peers(&self) -> &[Netmask] {
+        &self.allowed_peers
+    }
+
+    /// Whether the allow-list, if there is one, lets `ip` in
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowed_peers.is_empty() || self.allowed_peers.iter().any(|net| net.contains(ip))
+    }
+
+    /// Get the rate limiting and banning policy
+    pub fn get_policy(&self) -> &PeerPolicy {
+        &self.policy
+    }
+
     /// Create a peer manager for development
     pub fn for_development() -> Self {
         Self {
             dns_seeder: DnsSeeder::development(),
             connected_peers: Arc::new(RwLock::new(HashSet::new())),
             max_connections: 8,
+            policy: PeerPolicy::default(),
+            behavior: Arc::new(RwLock::new(HashMap::new())),
+            bans: Arc::new(RwLock::new(HashMap::new())),
+            nodes: Arc::new(Nodes::new()),
+            time_samples: Arc::new(RwLock::new(TimeSamples::default())),
+            allowed_peers: Vec::new(),
+            other_networks: Arc::new(RwLock::new(VecDeque::new())),
         }
     }
 
+    /// Keep known peers in `nodes`, e.g. a book shared with other parts of the node
+    pub fn with_nodes(mut self, nodes: Arc<Nodes>) -> Self {
+        self.nodes = nodes;
+        self
+    }
+
+    /// Get the address book of known peers
+    pub fn get_nodes(&self) -> &Arc<Nodes> {
+        &self.nodes
+    }
+
     /// Get peers to connect to
+    ///
+    /// While fewer than `MIN_KNOWN_PEERS` are known, reachable peers found through DNS
+    /// seeding join them first. Then the best-scoring known peers that aren't connected or
+    /// backing off after a failure are returned.
     pub fn get_peers_to_connect(&self) -> Result<Vec<SocketAddr>> {
         let connected_count = self.get_connected_count()?;
 
@@ -47,23 +487,252 @@ impl SimplePeerManager {
             return Ok(vec![]); // Already have enough connections
         }
 
-        let needed = self.max_connections - connected_count;
+        // Known peers keep the node connected even when every seed is down
+        if self.needs_seeding() {
+            if let Err(e) = self.seed_from_dns() {
+                warn!("DNS peer discovery failed: {e}");
+            }
+        }
 
-        // Discover peers via DNS seeding
-        let discovered_peers = self.dns_seeder.discover_peers()?;
+        let available_peers = self.get_known_peers_to_connect()?;
+        info!("Found {} peers to connect to", available_peers.len());
+        Ok(available_peers)
+    }
+
+    /// Whether too few peers are known to do without the DNS seeds
+    pub fn needs_seeding(&self) -> bool {
+        self.nodes.len() < MIN_KNOWN_PEERS
+    }
 
-        // Filter out already connected peers
+    /// Ask the DNS seeds for peers and remember the ones that accept a connection
+    ///
+    /// Every discovered address gets a connection attempt bounded by the seeder's timeout,
+    /// so this blocks and belongs on a background thread. Returns how many reachable peers
+    /// were found.
+    pub fn seed_from_dns(&self) -> Result<usize> {
+        let mut discovered = self.dns_seeder.discover_peers()?;
+        discovered.retain(|peer| self.is_allowed(peer.address.ip()));
+        let reachable = self.dns_seeder.filter_reachable_peers(discovered);
+        for peer in &reachable {
+            self.add_known_peer(peer.address)?;
+        }
+        Ok(reachable.len())
+    }
+
+    /// Known peers worth trying now, best first, without running DNS discovery
+    pub fn get_known_peers_to_connect(&self) -> Result<Vec<SocketAddr>> {
+        let needed = self
+            .max_connections
+            .saturating_sub(self.get_connected_count()?);
         let connected_addrs = self.get_connected_addresses()?;
+        let now = current_timestamp()?;
 
-        let available_peers: Vec<SocketAddr> = discovered_peers
+        let mut candidates: Vec<Node> = self
+            .nodes
+            .get_nodes()
             .into_iter()
-            .map(|peer| peer.address)
-            .filter(|addr| !connected_addrs.contains(addr))
-            .take(needed)
+            .filter(|node| {
+                !connected_addrs.contains(&node.get_addr())
+                    && self.is_allowed(node.get_addr().ip())
+                    && !node.get_peer().is_backing_off(now)
+                    && !self.is_banned_at(&PeerKey::Ip(node.get_addr().ip()), now)
+            })
             .collect();
+        candidates.sort_by(|a, b| {
+            b.get_peer()
+                .score()
+                .cmp(&a.get_peer().score())
+                .then(b.get_last_seen().cmp(&a.get_last_seen()))
+                .then(a.get_addr().cmp(&b.get_addr()))
+        });
 
-        info!("Found {} peers to connect to", available_peers.len());
-        Ok(available_peers)
+        Ok(candidates
+            .into_iter()
+            .take(needed)
+            .map(|node| node.get_addr())
+            .collect())
+    }
+
+    /// Remember `address` as a peer, leaving its history alone if it is already known
+    ///
+    /// Addresses outside the allow-list are not remembered.
+    pub fn add_known_peer(&self, address: SocketAddr) -> Result<()> {
+        if !self.is_allowed(address.ip()) || self.is_other_network(address)? {
+            return Ok(());
+        }
+        self.nodes.add_node(address, current_timestamp()?);
+        Ok(())
+    }
+
+    /// Record that the peer listening at `address` was reached or announced itself
+    ///
+    /// Either way it speaks the protocol, so it counts as a full node from then on.
+    pub fn record_peer_success(&self, address: SocketAddr) -> Result<()> {
+        if !self.is_allowed(address.ip()) || self.is_other_network(address)? {
+            return Ok(());
+        }
+        let now = current_timestamp()?;
+        self.nodes.update(address, now, |node| {
+            node.add_services(NODE_NETWORK);
+            let peer = node.peer_mut();
+            peer.last_seen = now;
+            peer.successes = peer.successes.saturating_add(1);
+            peer.consecutive_failures = 0;
+        });
+        Ok(())
+    }
+
+    /// Record the clock of the peer at `ip`, as its version message gave it (ms)
+    ///
+    /// Returns the median offset of the peers' clocks from this node's, see
+    /// `median_time_offset`. Each peer counts once, with its latest sample. Once the
+    /// median is off by more than `CLOCK_WARNING_THRESHOLD`, the local clock is assumed
+    /// wrong and a warning is logged.
+    pub fn record_peer_time(&self, ip: IpAddr, peer_time: i64) -> Result<i64> {
+        let offset = peer_time - current_timestamp()?;
+        let mut samples = self
+            .time_samples
+            .write()
+            .map_err(|e| BlockchainError::Network(format!("Failed to track peer clocks: {e}")))?;
+        samples.offsets.retain(|(sampled, _)| *sampled != ip);
+        if samples.offsets.len() >= MAX_TIME_SAMPLES {
+            samples.offsets.pop_front();
+        }
+        samples.offsets.push_back((ip, offset));
+
+        let offsets: Vec<i64> = samples.offsets.iter().map(|(_, offset)| *offset).collect();
+        let median = median_time_offset(&offsets);
+        let off = median.abs() > CLOCK_WARNING_THRESHOLD;
+        if off && !samples.warned {
+            warn!(
+                "This machine's clock is {} seconds {} its peers', please check the system time",
+                median.abs() / 1000,
+                if median > 0 { "behind" } else { "ahead of" }
+            );
+        }
+        samples.warned = off;
+        Ok(median)
+    }
+
+    /// The median offset of the peers' clocks from this node's (ms), see `record_peer_time`
+    pub fn time_offset(&self) -> Result<i64> {
+        let samples = self
+            .time_samples
+            .read()
+            .map_err(|e| BlockchainError::Network(format!("Failed to read peer clocks: {e}")))?;
+        let offsets: Vec<i64> = samples.offsets.iter().map(|(_, offset)| *offset).collect();
+        Ok(median_time_offset(&offsets))
+    }
+
+    /// Forget the peer listening at `address`, which turned out to be on another network,
+    /// and don't learn about it again for as long as this node runs
+    pub fn record_other_network(&self, address: SocketAddr) -> Result<()> {
+        let mut other_networks = self
+            .other_networks
+            .write()
+            .map_err(|e| BlockchainError::Network(format!("Failed to acquire peer lock: {e}")))?;
+        if !other_networks.contains(&address) {
+            if other_networks.len() >= MAX_OTHER_NETWORKS {
+                other_networks.pop_front();
+            }
+            other_networks.push_back(address);
+        }
+        drop(other_networks);
+        self.nodes.evict(&address);
+        Ok(())
+    }
+
+    /// Whether the peer listening at `address` was found to be on another network
+    pub fn is_other_network(&self, address: SocketAddr) -> Result<bool> {
+        let other_networks = self
+            .other_networks
+            .read()
+            .map_err(|e| BlockchainError::Network(format!("Failed to acquire peer lock: {e}")))?;
+        Ok(other_networks.contains(&address))
+    }
+
+    /// Record a failed attempt to reach `address`, starting or extending its backoff
+    pub fn record_peer_failure(&self, address: SocketAddr) -> Result<()> {
+        let now = current_timestamp()?;
+        self.nodes.update(address, now, |node| {
+            let peer = node.peer_mut();
+            peer.failures = peer.failures.saturating_add(1);
+            peer.consecutive_failures = peer.consecutive_failures.saturating_add(1);
+            peer.last_failure = Some(now);
+        });
+        Ok(())
+    }
+
+    /// Get every known peer with its history
+    pub fn get_known_peers(&self) -> Result<HashMap<SocketAddr, KnownPeer>> {
+        Ok(self
+            .nodes
+            .get_nodes()
+            .into_iter()
+            .map(|node| (node.get_addr(), node.get_peer().clone()))
+            .collect())
+    }
+
+    /// Replace the peers and bans stored in `db` with the ones known now
+    pub fn save_to(&self, db: &Db) -> Result<()> {
+        let known = self.get_known_peers()?;
+        let tree = Self::peers_tree(db)?;
+
+        tree.clear()
+            .map_err(|e| BlockchainError::Database(format!("Failed to clear peers: {e}")))?;
+        for (address, peer) in &known {
+            tree.insert(address.to_string().as_bytes(), serialize(peer)?)
+                .map_err(|e| BlockchainError::Database(format!("Failed to store peer: {e}")))?;
+        }
+        tree.flush()
+            .map_err(|e| BlockchainError::Database(format!("Failed to flush peers: {e}")))?;
+
+        info!("Saved {} known peers", known.len());
+        self.save_bans_to(db)
+    }
+
+    /// Add the peers stored in `db` to the known peers, returning how many were loaded
+    ///
+    /// Peers not seen within `PEER_STALE_AFTER` are removed from the database instead.
+    /// Unreadable entries are skipped and removed as well. Stored bans are loaded too, see
+    /// `load_bans_from`.
+    pub fn load_from(&self, db: &Db) -> Result<usize> {
+        let tree = Self::peers_tree(db)?;
+        let now = current_timestamp()?;
+        let mut loaded = 0;
+        let mut discarded = Vec::new();
+
+        for entry in tree.iter() {
+            let (key, value) = entry
+                .map_err(|e| BlockchainError::Database(format!("Failed to read peers: {e}")))?;
+            let address = String::from_utf8_lossy(&key).parse::<SocketAddr>().ok();
+            match (address, deserialize::<KnownPeer>(&value)) {
+                (Some(address), Ok(peer)) if !peer.is_stale(now) => {
+                    self.nodes.insert(Node::new(address, peer));
+                    loaded += 1;
+                }
+                _ => discarded.push(key),
+            }
+        }
+
+        for key in &discarded {
+            tree.remove(key)
+                .map_err(|e| BlockchainError::Database(format!("Failed to prune peer: {e}")))?;
+        }
+        info!(
+            "Loaded {loaded} known peers, pruned {} stale entries",
+            discarded.len()
+        );
+        let banned = self.load_bans_from(db)?;
+        if banned > 0 {
+            info!("Loaded {banned} bans");
+        }
+        Ok(loaded)
+    }
+
+    fn peers_tree(db: &Db) -> Result<sled::Tree> {
+        db.open_tree(PEERS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open peers tree: {e}")))
     }
 
     /// Record a successful connection
@@ -108,10 +777,371 @@ impl SimplePeerManager {
         Ok(connected.len())
     }
 
-    /// Check if we should accept more connections
-    pub fn should_accept_connection(&self) -> Result<bool> {
-        let connected_count = self.get_connected_count()?;
-        Ok(connected_count < self.max_connections)
+    /// Check if we should accept a new connection from `address`
+    pub fn should_accept_connection(&self, address: SocketAddr) -> Result<bool> {
+        Ok(self.connection_refusal(address)?.is_none())
+    }
+
+    /// Why a new connection from `address` would be refused, `None` if it is welcome
+    ///
+    /// A ban is reported ahead of the allow-list, so a banned address inside it stays out.
+    pub fn connection_refusal(&self, address: SocketAddr) -> Result<Option<String>> {
+        if self.is_banned(address.ip())? {
+            return Ok(Some("banned".to_string()));
+        }
+        if !self.is_allowed(address.ip()) {
+            return Ok(Some("not on this node's allow-list".to_string()));
+        }
+        if self.get_connected_count()? >= self.max_connections {
+            return Ok(Some(format!(
+                "connection limit of {} reached",
+                self.max_connections
+            )));
+        }
+        Ok(None)
+    }
+
+    /// Count a message from `address`, returning `false` once it exceeds the rate limit
+    pub fn record_message(&self, address: SocketAddr) -> Result<bool> {
+        self.record_message_from(&PeerKey::Ip(address.ip()))
+    }
+
+    /// Count a message from `peer`, returning `false` once it exceeds the rate limit
+    pub fn record_message_from(&self, peer: &PeerKey) -> Result<bool> {
+        let mut behavior = self.behavior_write()?;
+        Self::make_room_for(&mut behavior, peer);
+        let entry = behavior.entry(peer.clone()).or_default();
+
+        let now = Instant::now();
+        match entry.window_start {
+            Some(start) if now.duration_since(start) < Duration::from_secs(1) => {
+                entry.messages_in_window += 1;
+            }
+            _ => {
+                entry.window_start = Some(now);
+                entry.messages_in_window = 1;
+            }
+        }
+
+        Ok(entry.messages_in_window <= self.policy.max_messages_per_second)
+    }
+
+    /// Count a message from `peer` that arrived from `address`, see `record_message_from`
+    ///
+    /// A signed message counts against the host it came from as well as against its key,
+    /// since a fresh key is free; both have to stay within the limit.
+    pub fn record_message_at(&self, peer: &PeerKey, address: SocketAddr) -> Result<bool> {
+        let mut within = true;
+        for charged in Self::charged_for(peer, address) {
+            within &= self.record_message_from(&charged)?;
+        }
+        Ok(within)
+    }
+
+    /// Penalize `peer` for `misbehavior` in a message that arrived from `address`, and
+    /// the host as well if the message was signed, returning `true` if either got banned
+    pub fn penalize_peer_at(
+        &self,
+        peer: &PeerKey,
+        address: SocketAddr,
+        misbehavior: Misbehavior,
+    ) -> Result<bool> {
+        let mut banned = false;
+        for charged in Self::charged_for(peer, address) {
+            banned |= self.penalize_peer(&charged, misbehavior)?;
+        }
+        Ok(banned)
+    }
+
+    // Who pays for a message from `peer` arriving from `address`
+    fn charged_for(peer: &PeerKey, address: SocketAddr) -> Vec<PeerKey> {
+        let host = PeerKey::Ip(address.ip());
+        if *peer == host {
+            vec![host]
+        } else {
+            vec![peer.clone(), host]
+        }
+    }
+
+    /// Add the policy's penalty for `misbehavior` to the score of `address`, returning
+    /// `true` if this got it banned
+    pub fn penalize(&self, address: SocketAddr, misbehavior: Misbehavior) -> Result<bool> {
+        self.penalize_peer(&PeerKey::Ip(address.ip()), misbehavior)
+    }
+
+    /// Add the policy's penalty for `misbehavior` to the score of `peer`, returning `true`
+    /// if this got it banned
+    pub fn penalize_peer(&self, peer: &PeerKey, misbehavior: Misbehavior) -> Result<bool> {
+        self.record_misbehavior_of(
+            peer,
+            self.policy.penalties.weight(misbehavior),
+            misbehavior.description(),
+        )
+    }
+
+    /// Add to the misbehavior score of `address`, returning `true` if this got it banned
+    pub fn record_misbehavior(
+        &self,
+        address: SocketAddr,
+        score: u32,
+        reason: &str,
+    ) -> Result<bool> {
+        self.record_misbehavior_of(&PeerKey::Ip(address.ip()), score, reason)
+    }
+
+    /// Add to the misbehavior score of `peer`, returning `true` if this got it banned
+    ///
+    /// Every automatic ban of the same peer lasts twice as long as the one before, up to
+    /// `PeerPolicy::max_ban_duration`.
+    pub fn record_misbehavior_of(&self, peer: &PeerKey, score: u32, reason: &str) -> Result<bool> {
+        let now = current_timestamp()?;
+        if self.is_banned_at(peer, now) {
+            return Ok(false);
+        }
+
+        let mut behavior = self.behavior_write()?;
+        if behavior.get(peer).is_some_and(|entry| entry.banned) {
+            // An ended ban gives the peer a clean slate
+            behavior.remove(peer);
+        }
+        Self::make_room_for(&mut behavior, peer);
+        let entry = behavior.entry(peer.clone()).or_default();
+        let instant = Instant::now();
+        entry.misbehavior_score = entry
+            .current_score(instant, self.policy.score_decay)
+            .saturating_add(score);
+        entry.scored_at = Some(instant);
+        // The address book only knows peers by where they listen
+        if let PeerKey::Ip(ip) = peer {
+            self.nodes.add_misbehavior(*ip, score);
+        }
+        warn!(
+            "Peer {peer} misbehaved ({reason}), score now {}",
+            entry.misbehavior_score
+        );
+        if entry.misbehavior_score < self.policy.ban_threshold {
+            return Ok(false);
+        }
+        entry.banned = true;
+        drop(behavior);
+
+        let mut bans = self.bans_write()?;
+        let released = Self::ban_to_drop_for(&bans, peer);
+        if let Some(released) = &released {
+            bans.remove(released);
+        }
+        let offenses = bans.get(peer).map_or(0, |ban| ban.offenses);
+        let duration = self.policy.ban_duration_after(offenses);
+        bans.insert(
+            peer.clone(),
+            Ban {
+                banned_until: now.saturating_add(duration.as_millis() as i64),
+                reason: reason.to_string(),
+                offenses: offenses.saturating_add(1),
+            },
+        );
+        drop(bans);
+        if let Some(released) = released {
+            self.behavior_write()?.remove(&released);
+        }
+        warn!("Banning {peer} for {}s", duration.as_secs());
+        Ok(true)
+    }
+
+    /// Get the current misbehavior score of an address
+    pub fn get_misbehavior_score(&self, address: IpAddr) -> Result<u32> {
+        self.peer_score(&PeerKey::Ip(address))
+    }
+
+    /// Get the current misbehavior score of `peer`
+    pub fn peer_score(&self, peer: &PeerKey) -> Result<u32> {
+        let behavior = self
+            .behavior
+            .read()
+            .map_err(|e| BlockchainError::Network(format!("Failed to acquire peer lock: {e}")))?;
+        let now = current_timestamp()?;
+        Ok(match behavior.get(peer) {
+            Some(entry) if entry.banned && !self.is_banned_at(peer, now) => 0,
+            Some(entry) => entry.current_score(Instant::now(), self.policy.score_decay),
+            None => 0,
+        })
+    }
+
+    /// Check whether an address is currently banned
+    ///
+    /// Once a ban ends the address starts over with a clean score, though a later
+    /// automatic ban still counts the earlier ones.
+    pub fn is_banned(&self, address: IpAddr) -> Result<bool> {
+        self.is_peer_banned(&PeerKey::Ip(address))
+    }
+
+    /// Check whether `peer` is currently banned
+    pub fn is_peer_banned(&self, peer: &PeerKey) -> Result<bool> {
+        Ok(self.is_banned_at(peer, current_timestamp()?))
+    }
+
+    fn is_banned_at(&self, peer: &PeerKey, now: i64) -> bool {
+        let Ok(bans) = self.bans.read() else {
+            // Refusing everyone is worse than missing a ban
+            return false;
+        };
+        bans.get(peer).is_some_and(|ban| ban.is_active(now))
+    }
+
+    /// Refuse `address` for `duration` on the operator's behalf, replacing any ban it has
+    pub fn ban(&self, address: IpAddr, duration: Duration, reason: &str) -> Result<Ban> {
+        let now = current_timestamp()?;
+        let mut bans = self.bans_write()?;
+        let peer = PeerKey::Ip(address);
+        let offenses = bans.get(&peer).map_or(0, |ban| ban.offenses);
+        let ban = Ban {
+            banned_until: now.saturating_add(duration.as_millis() as i64),
+            reason: reason.to_string(),
+            offenses,
+        };
+        bans.insert(peer, ban.clone());
+        info!("Banned {address} for {}s: {reason}", duration.as_secs());
+        Ok(ban)
+    }
+
+    /// Lift the ban on `address` and forget its misbehavior, returning whether it was
+    /// banned
+    pub fn unban(&self, address: IpAddr) -> Result<bool> {
+        let now = current_timestamp()?;
+        let peer = PeerKey::Ip(address);
+        let lifted = self
+            .bans_write()?
+            .remove(&peer)
+            .is_some_and(|ban| ban.is_active(now));
+        self.behavior_write()?.remove(&peer);
+        if lifted {
+            info!("Lifted the ban on {address}");
+        }
+        Ok(lifted)
+    }
+
+    /// Addresses banned right now, soonest to be let back in first
+    ///
+    /// Banned keys are left out, since there's no command to manage them.
+    pub fn list_bans(&self) -> Result<Vec<(IpAddr, Ban)>> {
+        let now = current_timestamp()?;
+        let bans = self
+            .bans
+            .read()
+            .map_err(|e| BlockchainError::Network(format!("Failed to acquire peer lock: {e}")))?;
+        let mut active: Vec<(IpAddr, Ban)> = bans
+            .iter()
+            .filter_map(|(peer, ban)| match peer {
+                PeerKey::Ip(ip) if ban.is_active(now) => Some((*ip, ban.clone())),
+                _ => None,
+            })
+            .collect();
+        active.sort_by(|a, b| a.1.banned_until.cmp(&b.1.banned_until).then(a.0.cmp(&b.0)));
+        Ok(active)
+    }
+
+    /// Replace the bans stored in `db` with the ones known now
+    pub fn save_bans_to(&self, db: &Db) -> Result<()> {
+        let bans = self
+            .bans
+            .read()
+            .map_err(|e| BlockchainError::Network(format!("Failed to acquire peer lock: {e}")))?
+            .clone();
+        let tree = Self::bans_tree(db)?;
+
+        tree.clear()
+            .map_err(|e| BlockchainError::Database(format!("Failed to clear bans: {e}")))?;
+        for (peer, ban) in &bans {
+            tree.insert(peer.to_string().as_bytes(), serialize(ban)?)
+                .map_err(|e| BlockchainError::Database(format!("Failed to store ban: {e}")))?;
+        }
+        tree.flush()
+            .map_err(|e| BlockchainError::Database(format!("Failed to flush bans: {e}")))?;
+        Ok(())
+    }
+
+    /// Add the bans stored in `db`, returning how many of them are still in force
+    ///
+    /// Bans that ended longer than `PEER_STALE_AFTER` ago are removed from the database,
+    /// along with unreadable entries.
+    pub fn load_bans_from(&self, db: &Db) -> Result<usize> {
+        let tree = Self::bans_tree(db)?;
+        let now = current_timestamp()?;
+        let mut bans = self.bans_write()?;
+        let mut discarded = Vec::new();
+
+        for entry in tree.iter() {
+            let (key, value) = entry
+                .map_err(|e| BlockchainError::Database(format!("Failed to read bans: {e}")))?;
+            let peer = String::from_utf8_lossy(&key).parse::<PeerKey>().ok();
+            match (peer, deserialize::<Ban>(&value)) {
+                (Some(peer), Ok(ban)) if !ban.is_forgotten(now) => {
+                    bans.insert(peer, ban);
+                }
+                _ => discarded.push(key),
+            }
+        }
+
+        for key in &discarded {
+            tree.remove(key)
+                .map_err(|e| BlockchainError::Database(format!("Failed to prune ban: {e}")))?;
+        }
+        Ok(bans.values().filter(|ban| ban.is_active(now)).count())
+    }
+
+    fn bans_tree(db: &Db) -> Result<sled::Tree> {
+        db.open_tree(BANS_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open bans tree: {e}")))
+    }
+
+    // Forget the unbanned key heard from longest ago when `peer` is a key about to be
+    // tracked and `MAX_TRACKED_IDENTITIES` already are
+    fn make_room_for(behavior: &mut HashMap<PeerKey, PeerBehavior>, peer: &PeerKey) {
+        let is_identity = |key: &PeerKey| matches!(key, PeerKey::Identity(_));
+        if !is_identity(peer)
+            || behavior.contains_key(peer)
+            || behavior.keys().filter(|key| is_identity(key)).count() < MAX_TRACKED_IDENTITIES
+        {
+            return;
+        }
+        let oldest = behavior
+            .iter()
+            .filter(|(key, entry)| is_identity(key) && !entry.banned)
+            .min_by_key(|(_, entry)| entry.window_start.max(entry.scored_at))
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            behavior.remove(&oldest);
+        }
+    }
+
+    // The key ban to drop before banning `peer`, a key without one, once
+    // `MAX_TRACKED_IDENTITIES` keys are banned: the one ending soonest
+    fn ban_to_drop_for(bans: &HashMap<PeerKey, Ban>, peer: &PeerKey) -> Option<PeerKey> {
+        let is_identity = |key: &PeerKey| matches!(key, PeerKey::Identity(_));
+        if !is_identity(peer)
+            || bans.contains_key(peer)
+            || bans.keys().filter(|key| is_identity(key)).count() < MAX_TRACKED_IDENTITIES
+        {
+            return None;
+        }
+        bans.iter()
+            .filter(|(key, _)| is_identity(key))
+            .min_by_key(|(_, ban)| ban.banned_until)
+            .map(|(key, _)| key.clone())
+    }
+
+    fn bans_write(&self) -> Result<std::sync::RwLockWriteGuard<'_, HashMap<PeerKey, Ban>>> {
+        self.bans
+            .write()
+            .map_err(|e| BlockchainError::Network(format!("Failed to acquire peer lock: {e}")))
+    }
+
+    fn behavior_write(
+        &self,
+    ) -> Result<std::sync::RwLockWriteGuard<'_, HashMap<PeerKey, PeerBehavior>>> {
+        self.behavior
+            .write()
+            .map_err(|e| BlockchainError::Network(format!("Failed to acquire peer lock: {e}")))
     }
 }
 
@@ -119,6 +1149,35 @@ impl SimplePeerManager {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_median_of_peer_clocks_moves_the_local_one() {
+        // Too few peers to trust
+        assert_eq!(median_time_offset(&[60_000, 60_000, 60_000, 60_000]), 0);
+        assert_eq!(
+            median_time_offset(&[-20_000, 90_000, 5_000, 3_000_000, 10_000]),
+            10_000
+        );
+        // A clock hours away is capped
+        assert_eq!(median_time_offset(&[-3 * 3_600_000; 5]), -MAX_TIME_OFFSET);
+
+        // Repeated versions from one peer replace its sample rather than add to it
+        let manager = SimplePeerManager::new(8, 2001);
+        let now = current_timestamp().unwrap();
+        for _ in 0..10 {
+            manager
+                .record_peer_time("10.0.0.1".parse().unwrap(), now + 3_600_000)
+                .unwrap();
+        }
+        assert_eq!(manager.time_offset().unwrap(), 0);
+        for (i, offset) in [30_000, 40_000, 20_000, 50_000].into_iter().enumerate() {
+            let ip = IpAddr::from([10, 0, 0, i as u8 + 2]);
+            manager.record_peer_time(ip, now + offset).unwrap();
+        }
+        let offset = manager.time_offset().unwrap();
+        // Samples are taken against the clock a little later, so allow for that
+        assert!((39_000..=40_000).contains(&offset), "{offset}");
+    }
+
     #[test]
     fn test_simple_peer_manager_creation() {
         let manager = SimplePeerManager::new(8, 2001);
@@ -144,15 +1203,472 @@ mod tests {
         let manager = SimplePeerManager::new(2, 2001);
         let addr1 = "127.0.0.1:2001".parse().unwrap();
         let addr2 = "127.0.0.1:2002".parse().unwrap();
+        let addr3 = "127.0.0.1:2003".parse().unwrap();
 
         // Should accept connections up to limit
-        assert!(manager.should_accept_connection().unwrap());
+        assert!(manager.should_accept_connection(addr3).unwrap());
         manager.record_connection(addr1).unwrap();
 
-        assert!(manager.should_accept_connection().unwrap());
+        assert!(manager.should_accept_connection(addr3).unwrap());
         manager.record_connection(addr2).unwrap();
 
         // Should not accept more connections
-        assert!(!manager.should_accept_connection().unwrap());
+        assert!(!manager.should_accept_connection(addr3).unwrap());
+    }
+
+    #[test]
+    fn test_allow_list_limits_who_connects_and_who_is_dialed() {
+        let open = SimplePeerManager::new(8, 2001);
+        let outsider: SocketAddr = "203.0.113.9:2001".parse().unwrap();
+        assert!(open.should_accept_connection(outsider).unwrap());
+
+        let classroom = SimplePeerManager::new(8, 2001).with_allowed_peers(vec![
+            "192.168.1.0/24".parse().unwrap(),
+            "10.0.0.5".parse().unwrap(),
+        ]);
+        let student: SocketAddr = "192.168.1.42:2001".parse().unwrap();
+        let teacher: SocketAddr = "10.0.0.5:2001".parse().unwrap();
+        assert!(classroom.should_accept_connection(student).unwrap());
+        assert!(classroom.should_accept_connection(teacher).unwrap());
+        assert!(!classroom
+            .should_accept_connection("10.0.0.6:2001".parse().unwrap())
+            .unwrap());
+        assert_eq!(
+            classroom.connection_refusal(outsider).unwrap().as_deref(),
+            Some("not on this node's allow-list")
+        );
+
+        // Addresses outside the list are neither remembered nor dialed
+        classroom.add_known_peer(outsider).unwrap();
+        classroom.record_peer_success(outsider).unwrap();
+        classroom.add_known_peer(student).unwrap();
+        classroom.add_known_peer(teacher).unwrap();
+        assert_eq!(classroom.get_nodes().len(), 2);
+        let mut dialed = classroom.get_known_peers_to_connect().unwrap();
+        dialed.sort();
+        assert_eq!(dialed, vec![teacher, student]);
+
+        // A ban wins over the allow-list
+        classroom
+            .ban(student.ip(), Duration::from_secs(60), "test")
+            .unwrap();
+        assert_eq!(
+            classroom.connection_refusal(student).unwrap().as_deref(),
+            Some("banned")
+        );
+        assert_eq!(
+            classroom.get_known_peers_to_connect().unwrap(),
+            vec![teacher]
+        );
+    }
+
+    #[test]
+    fn test_misbehavior_bans_peer() {
+        let manager = SimplePeerManager::new(8, 2001);
+        let addr: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+        let reconnect: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+
+        assert!(!manager
+            .record_misbehavior(addr, OVERSIZED_MESSAGE_PENALTY, "test")
+            .unwrap());
+        assert!(manager.should_accept_connection(reconnect).unwrap());
+
+        assert!(manager
+            .record_misbehavior(addr, OVERSIZED_MESSAGE_PENALTY, "test")
+            .unwrap());
+        assert_eq!(manager.get_misbehavior_score(addr.ip()).unwrap(), 100);
+
+        // The ban covers the whole address, not just the port that misbehaved
+        assert!(!manager.should_accept_connection(reconnect).unwrap());
+        assert!(manager
+            .should_accept_connection("10.0.0.2:2001".parse().unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_ban_expires() {
+        let manager = SimplePeerManager::new(8, 2001).with_policy(PeerPolicy {
+            ban_duration: Duration::ZERO,
+            ..PeerPolicy::default()
+        });
+        let addr: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+
+        manager
+            .record_misbehavior(addr, DEFAULT_BAN_THRESHOLD, "test")
+            .unwrap();
+        assert!(!manager.is_banned(addr.ip()).unwrap());
+        assert_eq!(manager.get_misbehavior_score(addr.ip()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_scores_decay_and_repeat_bans_double() {
+        let manager = SimplePeerManager::new(8, 2001).with_policy(PeerPolicy {
+            ban_duration: Duration::from_secs(60 * 60),
+            max_ban_duration: Duration::from_secs(3 * 60 * 60),
+            score_decay: Duration::from_millis(10),
+            ..PeerPolicy::default()
+        });
+        let addr: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+
+        manager.penalize(addr, Misbehavior::InvalidBlock).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(manager.get_misbehavior_score(addr.ip()).unwrap() < INVALID_BLOCK_PENALTY);
+        // What was forgiven keeps a second invalid block short of a ban
+        assert!(!manager.penalize(addr, Misbehavior::InvalidBlock).unwrap());
+
+        let mut lengths = Vec::new();
+        for _ in 0..3 {
+            assert!(manager
+                .record_misbehavior(addr, DEFAULT_BAN_THRESHOLD, "test")
+                .unwrap());
+            let mut bans = manager.bans_write().unwrap();
+            let ban = bans.get_mut(&PeerKey::Ip(addr.ip())).unwrap();
+            let now = current_timestamp().unwrap();
+            lengths.push(Duration::from_millis((ban.banned_until - now) as u64));
+            // End the ban early to offend again
+            ban.banned_until = now - 1;
+        }
+        let hours: Vec<u64> = lengths
+            .iter()
+            .map(|length| (length.as_secs() + 60) / (60 * 60))
+            .collect();
+        assert_eq!(hours, vec![1, 2, 3]);
+        assert_eq!(
+            manager.bans_write().unwrap()[&PeerKey::Ip(addr.ip())].offenses,
+            3
+        );
+        assert_eq!(manager.get_misbehavior_score(addr.ip()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_operator_bans_survive_reload() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = sled::open(temp_dir.path().join("bans_db")).unwrap();
+        let manager = offline_manager(8);
+        let banned: IpAddr = parse_ban_address("10.0.0.1:2001").unwrap();
+        let misbehaving: SocketAddr = "10.0.0.2:2001".parse().unwrap();
+        let forgotten: IpAddr = parse_ban_address("10.0.0.3").unwrap();
+        assert!(parse_ban_address("not an address").is_err());
+
+        manager
+            .ban(banned, Duration::from_secs(60), OPERATOR_BAN_REASON)
+            .unwrap();
+        manager
+            .record_misbehavior(misbehaving, DEFAULT_BAN_THRESHOLD, "test")
+            .unwrap();
+        manager.bans_write().unwrap().insert(
+            PeerKey::Ip(forgotten),
+            Ban {
+                banned_until: current_timestamp().unwrap()
+                    - PEER_STALE_AFTER.as_millis() as i64
+                    - 1,
+                reason: "test".to_string(),
+                offenses: 1,
+            },
+        );
+        assert!(!manager
+            .should_accept_connection("10.0.0.1:50000".parse().unwrap())
+            .unwrap());
+        let listed = manager.list_bans().unwrap();
+        assert_eq!(
+            listed.iter().map(|(ip, _)| *ip).collect::<Vec<_>>(),
+            vec![banned, misbehaving.ip()]
+        );
+
+        manager.save_bans_to(&db).unwrap();
+        let reloaded = offline_manager(8);
+        assert_eq!(reloaded.load_bans_from(&db).unwrap(), 2);
+        assert_eq!(reloaded.list_bans().unwrap(), listed);
+        assert!(reloaded.is_banned(banned).unwrap());
+        let tree = db.open_tree(BANS_TREE).unwrap();
+        assert!(tree
+            .get(forgotten.to_string().as_bytes())
+            .unwrap()
+            .is_none());
+
+        assert!(reloaded.unban(banned).unwrap());
+        assert!(!reloaded.unban(banned).unwrap());
+        assert!(!reloaded.is_banned(banned).unwrap());
+        assert_eq!(reloaded.list_bans().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_message_rate_limit() {
+        let manager = SimplePeerManager::new(8, 2001).with_policy(PeerPolicy {
+            max_messages_per_second: 3,
+            ..PeerPolicy::default()
+        });
+        let addr: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(manager.record_message(addr).unwrap());
+        }
+        assert!(!manager.record_message(addr).unwrap());
+
+        // Other addresses have their own budget
+        assert!(manager
+            .record_message("10.0.0.2:2001".parse().unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_signing_peers_are_tracked_by_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = sled::open(temp_dir.path().join("bans_db")).unwrap();
+        let manager = offline_manager(8).with_policy(PeerPolicy {
+            max_messages_per_second: 1,
+            ..PeerPolicy::default()
+        });
+        let shared_ip: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+        let signer = PeerKey::Identity(PeerId::from_public_key(b"first key"));
+        let other = PeerKey::Identity(PeerId::from_public_key(b"second key"));
+
+        // Keys behind the same address neither share a rate budget nor a score
+        assert!(manager.record_message(shared_ip).unwrap());
+        assert!(manager.record_message_from(&signer).unwrap());
+        assert!(manager.record_message_from(&other).unwrap());
+        assert!(!manager.record_message_from(&signer).unwrap());
+        assert!(manager
+            .record_misbehavior_of(&signer, DEFAULT_BAN_THRESHOLD, "test")
+            .unwrap());
+        assert!(manager.is_peer_banned(&signer).unwrap());
+        assert!(!manager.is_peer_banned(&other).unwrap());
+        assert!(!manager.is_banned(shared_ip.ip()).unwrap());
+        assert!(manager.list_bans().unwrap().is_empty());
+
+        manager.save_bans_to(&db).unwrap();
+        let reloaded = offline_manager(8);
+        assert_eq!(reloaded.load_bans_from(&db).unwrap(), 1);
+        assert!(reloaded.is_peer_banned(&signer).unwrap());
+        assert_eq!(signer.to_string().parse::<PeerKey>().unwrap(), signer);
+        assert_eq!(
+            "10.0.0.1".parse::<PeerKey>().unwrap(),
+            PeerKey::Ip(shared_ip.ip())
+        );
+    }
+
+    #[test]
+    fn test_signed_messages_are_charged_to_their_host_too() {
+        let manager = offline_manager(8).with_policy(PeerPolicy {
+            max_messages_per_second: 2,
+            ..PeerPolicy::default()
+        });
+        let host: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+        let key = |n: usize| PeerKey::Identity(PeerId::from_public_key(&n.to_le_bytes()));
+
+        // A fresh key for every message doesn't get past the host's budget
+        assert!(manager.record_message_at(&key(0), host).unwrap());
+        assert!(manager.record_message_at(&key(1), host).unwrap());
+        assert!(!manager.record_message_at(&key(2), host).unwrap());
+
+        manager
+            .penalize_peer_at(&key(3), host, Misbehavior::InvalidBlock)
+            .unwrap();
+        assert_eq!(manager.peer_score(&key(3)).unwrap(), INVALID_BLOCK_PENALTY);
+        assert_eq!(
+            manager.get_misbehavior_score(host.ip()).unwrap(),
+            INVALID_BLOCK_PENALTY
+        );
+    }
+
+    #[test]
+    fn test_tracked_keys_are_capped() {
+        let manager = offline_manager(8);
+        let key = |n: usize| PeerKey::Identity(PeerId::from_public_key(&n.to_le_bytes()));
+
+        for n in 0..=MAX_TRACKED_IDENTITIES {
+            manager.record_message_from(&key(n)).unwrap();
+        }
+        assert_eq!(
+            manager.behavior.read().unwrap().len(),
+            MAX_TRACKED_IDENTITIES
+        );
+        for n in 0..=MAX_TRACKED_IDENTITIES {
+            manager
+                .record_misbehavior_of(&key(n), DEFAULT_BAN_THRESHOLD, "test")
+                .unwrap();
+        }
+        assert_eq!(manager.bans.read().unwrap().len(), MAX_TRACKED_IDENTITIES);
+        assert!(manager
+            .is_peer_banned(&key(MAX_TRACKED_IDENTITIES))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_peers_of_other_networks_are_capped() {
+        let manager = offline_manager(8);
+        let addr = |n: usize| SocketAddr::from(([10, 0, (n / 256) as u8, (n % 256) as u8], 2001));
+
+        for n in 0..=MAX_OTHER_NETWORKS {
+            manager.record_other_network(addr(n)).unwrap();
+        }
+        assert_eq!(
+            manager.other_networks.read().unwrap().len(),
+            MAX_OTHER_NETWORKS
+        );
+        // The one found first made room for the last
+        assert!(!manager.is_other_network(addr(0)).unwrap());
+        assert!(manager.is_other_network(addr(1)).unwrap());
+        assert!(manager.is_other_network(addr(MAX_OTHER_NETWORKS)).unwrap());
+    }
+
+    fn offline_manager(max_connections: usize) -> SimplePeerManager {
+        SimplePeerManager::new(max_connections, 2001)
+            .with_dns_seeder(DnsSeeder::with_seeds(vec![], 2001))
+    }
+
+    #[test]
+    fn test_dns_seeding_keeps_only_reachable_peers() {
+        use crate::network::connectivity::{ConnectivityCall, MockConnectivity};
+
+        let resolved: Vec<SocketAddr> = ["10.0.0.1:2001", "10.0.0.2:2001", "10.0.0.3:2001"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let outside: SocketAddr = "192.168.1.1:2001".parse().unwrap();
+        let mock = Arc::new(
+            MockConnectivity::new()
+                .resolves("seed.test", resolved.iter().copied().chain([outside]))
+                .reachable([resolved[0], resolved[2], outside]),
+        );
+        let seeder = DnsSeeder::with_seeds(vec!["seed.test".to_string()], 2001)
+            .with_connectivity(mock.clone());
+        let manager = SimplePeerManager::new(8, 2001)
+            .with_dns_seeder(seeder)
+            .with_allowed_peers(vec!["10.0.0.0/8".parse().unwrap()]);
+        assert!(manager.needs_seeding());
+
+        assert_eq!(manager.seed_from_dns().unwrap(), 2);
+        let known = manager.get_known_peers().unwrap();
+        assert_eq!(known.len(), 2);
+        assert!(known.contains_key(&resolved[0]) && known.contains_key(&resolved[2]));
+
+        // Addresses outside the allow-list aren't even probed
+        assert!(!mock.calls().contains(&ConnectivityCall::Probe(outside)));
+        assert!(mock.handshakes().is_empty());
+    }
+
+    #[test]
+    fn test_known_peers_survive_reload() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = sled::open(temp_dir.path().join("peers_db")).unwrap();
+        let manager = offline_manager(8);
+        let reliable: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+        let flaky: SocketAddr = "10.0.0.2:2001".parse().unwrap();
+        let fresh: SocketAddr = "10.0.0.3:2001".parse().unwrap();
+        let stale: SocketAddr = "10.0.0.4:2001".parse().unwrap();
+
+        for _ in 0..3 {
+            manager.record_peer_success(reliable).unwrap();
+        }
+        manager.record_peer_failure(flaky).unwrap();
+        manager.record_peer_success(flaky).unwrap();
+        manager.add_known_peer(fresh).unwrap();
+        manager.add_known_peer(stale).unwrap();
+        manager.nodes.update(stale, 0, |node| {
+            node.peer_mut().last_seen -= PEER_STALE_AFTER.as_millis() as i64 + 1
+        });
+
+        manager.save_to(&db).unwrap();
+        let reloaded = offline_manager(8);
+        assert_eq!(reloaded.load_from(&db).unwrap(), 3);
+
+        let mut expected = manager.get_known_peers().unwrap();
+        expected.remove(&stale);
+        let known = reloaded.get_known_peers().unwrap();
+        assert_eq!(known, expected);
+        assert_eq!(known[&reliable].score(), 3);
+        assert_eq!(known[&flaky].score(), 0);
+        assert_eq!(known[&flaky].consecutive_failures, 0);
+
+        // The stale peer is gone from the database too
+        let tree = db.open_tree(PEERS_TREE).unwrap();
+        assert!(tree.get(stale.to_string().as_bytes()).unwrap().is_none());
+        assert_eq!(tree.len(), 3);
+    }
+
+    #[test]
+    fn test_peer_selection_prefers_score_and_backs_off() {
+        let manager = offline_manager(8);
+        let best: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+        let good: SocketAddr = "10.0.0.2:2001".parse().unwrap();
+        let untried: SocketAddr = "10.0.0.3:2001".parse().unwrap();
+        let failing: SocketAddr = "10.0.0.4:2001".parse().unwrap();
+
+        manager.record_peer_success(best).unwrap();
+        manager.record_peer_success(best).unwrap();
+        manager.record_peer_success(good).unwrap();
+        manager.add_known_peer(untried).unwrap();
+        manager.record_peer_failure(failing).unwrap();
+
+        // DNS is unavailable, yet the known peers are still offered
+        assert_eq!(
+            manager.get_peers_to_connect().unwrap(),
+            vec![best, good, untried]
+        );
+        manager.record_connection(best).unwrap();
+        assert_eq!(
+            manager.get_known_peers_to_connect().unwrap(),
+            vec![good, untried]
+        );
+
+        // Once the backoff has passed the failing peer is tried again, last
+        let backoff = manager.get_known_peers().unwrap()[&failing].backoff();
+        assert_eq!(backoff, PEER_BASE_BACKOFF);
+        manager.nodes.update(failing, 0, |node| {
+            let failed_at = node.peer_mut().last_failure.as_mut().unwrap();
+            *failed_at -= backoff.as_millis() as i64;
+        });
+        assert_eq!(
+            manager.get_known_peers_to_connect().unwrap(),
+            vec![good, untried, failing]
+        );
+
+        // Every further failure doubles the wait, up to the cap
+        manager.record_peer_failure(failing).unwrap();
+        manager.record_peer_failure(failing).unwrap();
+        let peer = &manager.get_known_peers().unwrap()[&failing];
+        assert_eq!(peer.backoff(), PEER_BASE_BACKOFF * 4);
+        assert!(!manager
+            .get_known_peers_to_connect()
+            .unwrap()
+            .contains(&failing));
+
+        let hopeless = KnownPeer {
+            consecutive_failures: 40,
+            ..peer.clone()
+        };
+        assert_eq!(hopeless.backoff(), PEER_MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_selection_skips_banned_peers() {
+        let manager = offline_manager(8);
+        let honest: SocketAddr = "10.0.0.1:2001".parse().unwrap();
+        let banned: SocketAddr = "10.0.0.2:2001".parse().unwrap();
+        manager.record_peer_success(banned).unwrap();
+        manager.record_peer_success(banned).unwrap();
+        manager.record_peer_success(honest).unwrap();
+
+        manager
+            .ban(banned.ip(), Duration::from_secs(60), OPERATOR_BAN_REASON)
+            .unwrap();
+        assert_eq!(manager.get_known_peers_to_connect().unwrap(), vec![honest]);
+        manager.unban(banned.ip()).unwrap();
+        assert_eq!(
+            manager.get_known_peers_to_connect().unwrap(),
+            vec![banned, honest]
+        );
+    }
+
+    #[test]
+    fn test_selection_respects_connection_limit() {
+        let manager = offline_manager(2);
+        for port in 3000..3005 {
+            manager
+                .record_peer_success(SocketAddr::from(([10, 0, 0, 1], port)))
+                .unwrap();
+        }
+        assert_eq!(manager.get_known_peers_to_connect().unwrap().len(), 2);
     }
 }
@@ -0,0 +1,100 @@
+//! Filtered blocks for light clients
+//!
+//! A light client sends a `FilterLoad` with a bloom filter over the txids and public key
+//! hashes it watches. Blocks it requests afterwards come back as a `MerkleBlock`: the
+//! header plus a Merkle proof for every matching transaction, which the client checks
+//! against the header's Merkle root with `verify_merkle_block`.
+
+use crate::core::{Block, BlockHeader, MerkleProof, MerkleTree, Transaction};
+use crate::error::{BlockchainError, Result};
+use crate::utils::{deserialize, serialize, BloomFilter};
+
+/// A `MerkleBlock` whose proofs all check out
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedMerkleBlock {
+    pub header: BlockHeader,
+    /// Proven transactions, in block order
+    pub txids: Vec<Vec<u8>>,
+}
+
+/// Whether `filter` matches the transaction's id or the public key hash of any output
+pub fn transaction_matches(tx: &Transaction, filter: &BloomFilter) -> bool {
+    filter.contains(tx.get_id())
+        || tx
+            .get_vout()
+            .iter()
+            .any(|output| !output.is_data_carrier() && filter.contains(&output.get_pub_key_hash()))
+}
+
+/// Build the serialized header and proofs of a `MerkleBlock` for `block`
+pub fn build_merkle_block(block: &Block, filter: &BloomFilter) -> Result<(Vec<u8>, Vec<u8>)> {
+    let tree = MerkleTree::new(block.get_transactions())?;
+    let proofs = block
+        .get_transactions()
+        .iter()
+        .enumerate()
+        .filter(|(_, tx)| transaction_matches(tx, filter))
+        .map(|(index, _)| tree.generate_proof(index))
+        .collect::<Result<Vec<MerkleProof>>>()?;
+
+    Ok((block.get_header().serialize()?, serialize(&proofs)?))
+}
+
+/// Check every proof of a received `MerkleBlock` against its header
+///
+/// Only the proofs are checked here; whether the header belongs to the best chain is up
+/// to the client.
+pub fn verify_merkle_block(header: &[u8], proofs: &[u8]) -> Result<VerifiedMerkleBlock> {
+    let header = BlockHeader::deserialize(header)?;
+    let proofs: Vec<MerkleProof> = deserialize(proofs)?;
+
+    let mut txids = Vec::with_capacity(proofs.len());
+    for proof in proofs {
+        if proof.merkle_root != header.merkle_root
+            || proof.transaction_index >= header.transaction_count
+            || !MerkleTree::verify_proof(&proof)?
+        {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Merkle proof for transaction {} does not match block {}",
+                proof.transaction_index, header.hash
+            )));
+        }
+        txids.push(proof.transaction_hash);
+    }
+
+    Ok(VerifiedMerkleBlock { header, txids })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn test_block(transaction_count: usize) -> Block {
+        let txs: Vec<Transaction> = (0..transaction_count)
+            .map(|_| Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap())
+            .collect();
+        Block::new_test_block(0, "None".to_string(), &txs, 0, 1).unwrap()
+    }
+
+    #[test]
+    fn test_proofs_must_match_their_header() {
+        let block = test_block(3);
+        let other_block = test_block(3);
+        let mut filter = BloomFilter::new(64, 3, 0).unwrap();
+        filter.insert(block.get_transactions()[2].get_id());
+
+        let (header, proofs) = build_merkle_block(&block, &filter).unwrap();
+        let verified = verify_merkle_block(&header, &proofs).unwrap();
+        assert_eq!(
+            verified.txids,
+            vec![block.get_transactions()[2].get_id().to_vec()]
+        );
+
+        // Proofs lifted from another block don't verify against this header
+        filter.insert(other_block.get_transactions()[0].get_id());
+        let (_, foreign_proofs) = build_merkle_block(&other_block, &filter).unwrap();
+        assert!(verify_merkle_block(&header, &foreign_proofs).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::config::{Config, GLOBAL_CONFIG};
+use crate::core::{Blockchain, FeeCalculator, NodeContext};
+use crate::error::{BlockchainError, Result};
+use crate::network::{SimplePeerManager, SyncProgress};
+use crate::storage::UTXOSet;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Instant;
+
+/// A snapshot of everything an operator usually wants to know about a node
+///
+/// Fields that only exist inside a running node process (mempool size, peer count and
+/// sync progress) are `None` when the status is collected locally from the database.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct NodeStatus {
+    pub best_height: usize,
+    pub tip_hash: String,
+    pub difficulty: u32,
+    pub utxo_count: u64,
+    pub mempool_size: Option<usize>,
+    pub fee_mode: String,
+    pub miner_address: Option<String>,
+    pub node_address: String,
+    pub connected_peers: Option<usize>,
+    /// Nodes that predate sync tracking send none
+    #[serde(default)]
+    pub sync: Option<SyncProgress>,
+}
+
+impl NodeStatus {
+    /// Collect the status straight from the database, without a running node
+    pub fn collect_local(blockchain: &Blockchain) -> Result<NodeStatus> {
+        Self::collect_from_chain(
+            blockchain,
+            FeeCalculator::get_config_summary(),
+            &GLOBAL_CONFIG,
+        )
+    }
+
+    /// Collect the full status from inside a running node
+    pub fn collect(
+        blockchain: &Blockchain,
+        context: &NodeContext,
+        peer_manager: &SimplePeerManager,
+    ) -> Result<NodeStatus> {
+        let mut status = Self::collect_from_chain(
+            blockchain,
+            context.get_fee_config_summary(),
+            context.config(),
+        )?;
+        status.mempool_size = Some(context.memory_pool().len());
+        status.connected_peers = Some(peer_manager.get_connected_count()?);
+        status.sync = Some(context.sync_state().progress(Instant::now()));
+        Ok(status)
+    }
+
+    fn collect_from_chain(
+        blockchain: &Blockchain,
+        fee_mode: String,
+        config: &Config,
+    ) -> Result<NodeStatus> {
+        let tip_hash = blockchain.get_tip_hash();
+        let tip_block = blockchain
+            .get_block(&tip_hash)?
+            .ok_or_else(|| BlockchainError::Database(format!("Tip block {tip_hash} not found")))?;
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+
+        Ok(NodeStatus {
+            best_height: tip_block.get_height(),
+            tip_hash,
+            difficulty: tip_block.get_difficulty(),
+            utxo_count: utxo_set.count_transactions_safe()?,
+            mempool_size: None,
+            fee_mode,
+            miner_address: config.get_mining_addr(),
+            node_address: config.get_node_addr(),
+            connected_peers: None,
+            sync: None,
+        })
+    }
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn or_unavailable<T: fmt::Display>(value: &Option<T>) -> String {
+            match value {
+                Some(value) => value.to_string(),
+                None => "n/a (node not running)".to_string(),
+            }
+        }
+
+        writeln!(f, "Node Status:")?;
+        writeln!(f, "  Node address:    {}", self.node_address)?;
+        writeln!(f, "  Best height:     {}", self.best_height)?;
+        writeln!(f, "  Tip hash:        {}", self.tip_hash)?;
+        writeln!(f, "  Difficulty:      {}", self.difficulty)?;
+        writeln!(f, "  UTXO entries:    {}", self.utxo_count)?;
+        writeln!(
+            f,
+            "  Mempool size:    {}",
+            or_unavailable(&self.mempool_size)
+        )?;
+        writeln!(
+            f,
+            "  Connected peers: {}",
+            or_unavailable(&self.connected_peers)
+        )?;
+        writeln!(f, "  Sync:            {}", or_unavailable(&self.sync))?;
+        writeln!(f, "  Fee mode:        {}", self.fee_mode)?;
+        writeln!(
+            f,
+            "  Miner address:   {}",
+            self.miner_address.as_deref().unwrap_or("not mining")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, Transaction};
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    #[test]
+    fn test_local_status_assembly() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+        blockchain.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        UTXOSet::new(blockchain.clone()).reindex();
+
+        let status = NodeStatus::collect_local(&blockchain).unwrap();
+        assert_eq!(status.best_height, 1);
+        assert_eq!(status.tip_hash, blockchain.get_tip_hash());
+        assert_eq!(status.difficulty, 4);
+        assert_eq!(status.utxo_count, 2);
+        assert_eq!(status.mempool_size, None);
+        assert_eq!(status.connected_peers, None);
+        assert_eq!(status.sync, None);
+        assert_eq!(status.node_address, GLOBAL_CONFIG.get_node_addr());
+    }
+
+    #[test]
+    fn test_full_status_includes_runtime_fields() {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, db_path.to_str().unwrap())
+                .unwrap();
+
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        context
+            .memory_pool()
+            .add(Transaction::new_placeholder_tx(TEST_ADDRESS));
+        context.config().set_mining_addr(TEST_ADDRESS.to_string());
+        let peer_manager = SimplePeerManager::new(8, 2001);
+        peer_manager
+            .record_connection("127.0.0.1:3000".parse().unwrap())
+            .unwrap();
+
+        let status = NodeStatus::collect(&blockchain, &context, &peer_manager).unwrap();
+        assert_eq!(status.best_height, 0);
+        assert_eq!(status.mempool_size, Some(1));
+        assert_eq!(status.connected_peers, Some(1));
+        context
+            .sync_state()
+            .record_peer_height("127.0.0.1:3000", 5, Instant::now());
+        let status = NodeStatus::collect(&blockchain, &context, &peer_manager).unwrap();
+        let sync = status.sync.clone().unwrap();
+        assert_eq!((sync.current_height, sync.target_height), (0, Some(5)));
+        assert!(!sync.synced);
+        assert!(status
+            .to_string()
+            .contains("Sync:            height 0 of 5"));
+        // Fee mode and miner come from the node's context, not the globals
+        assert_eq!(status.fee_mode, "Fixed fee: 0.00000003 coins (3 satoshis)");
+        assert_eq!(status.miner_address.as_deref(), Some(TEST_ADDRESS));
+
+        // The JSON form has to round-trip for scripting
+        let json = serde_json::to_string(&status).unwrap();
+        let parsed: NodeStatus = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, status);
+    }
+}
@@ -0,0 +1,277 @@
+//! Progress of the node's catch-up with its peers
+//!
+//! Peers advertise their best height in their version messages, and a block a peer sends
+//! proves it has at least that one. The highest of those is the sync target. Every block
+//! connected moves the current height along; the download rate is measured over the
+//! last `RATE_WINDOW`, which is also what the ETA is based on. The node counts as synced
+//! once its height has been within one block of the target for a while, see `is_synced`.
+//!
+//! Time is passed in rather than read, so the state can be driven by tests.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Blocks connected within this long count towards the download rate
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+/// Minimum time between two progress lines in the log
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the node knows about its sync, kept in its `NodeContext`
+#[derive(Debug)]
+pub struct SyncState {
+    started: Instant,
+    current_height: usize,
+    peer_heights: HashMap<String, usize>,
+    blocks_downloaded: usize,
+    /// When each block in the rate window was connected, oldest first
+    recent_blocks: VecDeque<Instant>,
+    last_logged: Option<Instant>,
+    /// Since when the current height has been within one block of the target
+    caught_up_since: Option<Instant>,
+}
+
+/// A snapshot of the sync, as `nodestatus` shows it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct SyncProgress {
+    pub current_height: usize,
+    /// Best height any peer advertised, `None` until one did
+    pub target_height: Option<usize>,
+    pub blocks_downloaded: usize,
+    /// Over the last 30 seconds
+    pub blocks_per_second: f64,
+    /// `None` while no blocks arrive or none are missing
+    pub eta_secs: Option<u64>,
+    pub synced: bool,
+}
+
+impl SyncState {
+    /// Start tracking from `height`, the local tip when the node starts
+    pub fn new(height: usize, now: Instant) -> SyncState {
+        SyncState {
+            started: now,
+            current_height: height,
+            peer_heights: HashMap::new(),
+            blocks_downloaded: 0,
+            recent_blocks: VecDeque::new(),
+            last_logged: None,
+            caught_up_since: None,
+        }
+    }
+
+    /// Remember that `peer` has a chain of at least `height` blocks
+    ///
+    /// A peer only ever moves forward here; an older version message or block arriving
+    /// late doesn't lower what it already proved.
+    pub fn record_peer_height(&mut self, peer: &str, height: usize, now: Instant) {
+        let known = self.peer_heights.entry(peer.to_string()).or_insert(height);
+        *known = (*known).max(height);
+        self.update_caught_up(now);
+    }
+
+    /// A block was connected and the local tip is now at `height`
+    pub fn record_block(&mut self, height: usize, now: Instant) {
+        self.current_height = height;
+        self.blocks_downloaded += 1;
+        self.recent_blocks.push_back(now);
+        self.update_caught_up(now);
+    }
+
+    /// The best height any peer advertised
+    pub fn target_height(&self) -> Option<usize> {
+        self.peer_heights.values().copied().max()
+    }
+
+    /// Whether any peer got as far as telling its height
+    pub fn has_peers(&self) -> bool {
+        !self.peer_heights.is_empty()
+    }
+
+    /// Whether the node has stayed within one block of the target for `settle`
+    pub fn is_synced(&self, now: Instant, settle: Duration) -> bool {
+        self.caught_up_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= settle)
+    }
+
+    /// The figures as of `now`, forgetting blocks that fell out of the rate window
+    pub fn progress(&mut self, now: Instant) -> SyncProgress {
+        while self
+            .recent_blocks
+            .front()
+            .is_some_and(|&at| now.saturating_duration_since(at) > RATE_WINDOW)
+        {
+            self.recent_blocks.pop_front();
+        }
+        // A node that started moments ago has only been downloading that long
+        let span = now
+            .saturating_duration_since(self.started)
+            .min(RATE_WINDOW)
+            .as_secs_f64();
+        let blocks_per_second = if span > 0.0 {
+            self.recent_blocks.len() as f64 / span
+        } else {
+            0.0
+        };
+
+        let target_height = self.target_height();
+        let missing = target_height.map_or(0, |target| target.saturating_sub(self.current_height));
+        let eta_secs = (missing > 0 && blocks_per_second > 0.0)
+            .then(|| (missing as f64 / blocks_per_second).ceil() as u64);
+
+        SyncProgress {
+            current_height: self.current_height,
+            target_height,
+            blocks_downloaded: self.blocks_downloaded,
+            blocks_per_second,
+            eta_secs,
+            synced: self.caught_up_since.is_some(),
+        }
+    }
+
+    /// The progress line to log, if the node is behind and the last one was long enough ago
+    pub fn progress_line(&mut self, now: Instant) -> Option<String> {
+        if self.caught_up_since.is_some()
+            || self
+                .last_logged
+                .is_some_and(|at| now.saturating_duration_since(at) < LOG_INTERVAL)
+        {
+            return None;
+        }
+        self.last_logged = Some(now);
+        Some(format!("Syncing: {}", self.progress(now)))
+    }
+
+    fn update_caught_up(&mut self, now: Instant) {
+        let caught_up = self
+            .target_height()
+            .is_some_and(|target| self.current_height + 1 >= target);
+        match (caught_up, self.caught_up_since) {
+            (true, None) => self.caught_up_since = Some(now),
+            (false, Some(_)) => self.caught_up_since = None,
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for SyncProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(target) = self.target_height else {
+            return write!(f, "height {}, no peer heights yet", self.current_height);
+        };
+        let percent = if target == 0 {
+            100.0
+        } else {
+            (self.current_height.min(target) as f64 / target as f64) * 100.0
+        };
+        write!(
+            f,
+            "height {} of {target} ({percent:.1}%), {} blocks downloaded, {:.1} blocks/s",
+            self.current_height, self.blocks_downloaded, self.blocks_per_second
+        )?;
+        match (self.synced, self.eta_secs) {
+            (true, _) => write!(f, ", synced"),
+            (false, Some(eta)) => write!(f, ", ETA {eta}s"),
+            (false, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_progress_follows_connected_blocks() {
+        let start = Instant::now();
+        let mut sync = SyncState::new(10, start);
+        let progress = sync.progress(start);
+        assert_eq!(progress.target_height, None);
+        assert!(!progress.synced);
+        assert_eq!(progress.to_string(), "height 10, no peer heights yet");
+
+        sync.record_peer_height("127.0.0.1:3001", 100, start);
+        sync.record_peer_height("127.0.0.1:3002", 70, start);
+        // A peer that later proves a block beyond what it advertised raises the target
+        sync.record_peer_height("127.0.0.1:3002", 110, start);
+        sync.record_peer_height("127.0.0.1:3001", 90, start);
+        assert_eq!(sync.target_height(), Some(110));
+
+        // Two blocks a second for ten seconds
+        for i in 1..=20 {
+            sync.record_block(10 + i, start + Duration::from_millis(500 * i as u64));
+        }
+        let progress = sync.progress(start + secs(10));
+        assert_eq!(progress.current_height, 30);
+        assert_eq!(progress.blocks_downloaded, 20);
+        assert_eq!(progress.blocks_per_second, 2.0);
+        assert_eq!(progress.eta_secs, Some(40));
+        assert!(!progress.synced);
+        assert_eq!(
+            progress.to_string(),
+            "height 30 of 110 (27.3%), 20 blocks downloaded, 2.0 blocks/s, ETA 40s"
+        );
+
+        // Once the window has passed without blocks, the rate drops to nothing
+        let stalled = sync.progress(start + secs(60));
+        assert_eq!(stalled.blocks_per_second, 0.0);
+        assert_eq!(stalled.eta_secs, None);
+        assert_eq!(stalled.blocks_downloaded, 20);
+    }
+
+    #[test]
+    fn test_sync_completes_after_settling_near_the_target() {
+        let start = Instant::now();
+        let mut sync = SyncState::new(0, start);
+        let settle = secs(5);
+        assert!(!sync.has_peers());
+        assert!(!sync.is_synced(start + secs(100), settle));
+
+        sync.record_peer_height("peer", 3, start);
+        assert!(sync.has_peers());
+        sync.record_block(1, start + secs(1));
+        assert!(!sync.is_synced(start + secs(100), settle));
+
+        // One block short is close enough, but only after it stays that way
+        sync.record_block(2, start + secs(2));
+        assert!(!sync.is_synced(start + secs(6), settle));
+        assert!(sync.is_synced(start + secs(7), settle));
+
+        // The peer mines further ahead, which starts the wait over
+        sync.record_peer_height("peer", 6, start + secs(8));
+        assert!(!sync.is_synced(start + secs(20), settle));
+        sync.record_block(6, start + secs(21));
+        assert!(!sync.is_synced(start + secs(25), settle));
+        assert!(sync.is_synced(start + secs(26), settle));
+        assert!(sync.progress(start + secs(26)).synced);
+
+        // A node already ahead of its peers has nothing to download
+        let mut ahead = SyncState::new(50, start);
+        ahead.record_peer_height("peer", 40, start);
+        assert!(ahead.is_synced(start + settle, settle));
+        assert_eq!(ahead.progress(start + settle).eta_secs, None);
+    }
+
+    #[test]
+    fn test_progress_lines_are_rate_limited() {
+        let start = Instant::now();
+        let mut sync = SyncState::new(0, start);
+        sync.record_peer_height("peer", 1_000, start);
+
+        sync.record_block(1, start);
+        assert!(sync.progress_line(start).is_some());
+        sync.record_block(2, start + secs(1));
+        assert_eq!(sync.progress_line(start + secs(1)), None);
+        sync.record_block(3, start + secs(5));
+        let line = sync.progress_line(start + secs(5)).unwrap();
+        assert!(line.starts_with("Syncing: height 3 of 1000"), "{line}");
+
+        // Nothing more is logged once the node caught up
+        sync.record_block(999, start + secs(20));
+        assert_eq!(sync.progress_line(start + secs(20)), None);
+    }
+}
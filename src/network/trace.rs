@@ -0,0 +1,221 @@
+//! A short history of the packages a node sent and received
+//!
+//! Every package going through the server is written to the log as one line of
+//! `key=value` fields and remembered in a bounded ring buffer. Payloads never make it into
+//! either: a block shows up as its hash and height, a transaction as its id. During a sync
+//! the log would still fill quickly, so only a limited number of lines per second are
+//! logged at info level and the rest go to debug, with a count of what was held back. The
+//! buffer keeps every message regardless and can be fetched from a running node with
+//! `GetMessageTrace`.
+
+use crate::network::server::Package;
+use crate::utils::current_timestamp;
+use log::{debug, info, log_enabled, Level};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Messages a trace remembers by default
+pub const MESSAGE_TRACE_CAPACITY: usize = 500;
+/// Message log lines written at info level per second; the rest are logged at debug
+const MAX_INFO_LINES_PER_SECOND: u32 = 20;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Inbound => "recv",
+            Direction::Outbound => "send",
+        })
+    }
+}
+
+/// One package as the trace remembers it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct TraceEntry {
+    /// Milliseconds since the epoch
+    pub timestamp: i64,
+    pub direction: Direction,
+    pub peer: String,
+    /// The package type, e.g. `Block`
+    pub kind: String,
+    /// Encoded size in bytes
+    pub size: usize,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} peer={} type={} size={}",
+            self.timestamp, self.direction, self.peer, self.kind, self.size
+        )
+    }
+}
+
+/// Ring buffer of the most recent packages, shared by all of a node's connections
+pub struct MessageTrace {
+    entries: Mutex<VecDeque<TraceEntry>>,
+    capacity: usize,
+    log_window: Mutex<LogWindow>,
+}
+
+struct LogWindow {
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl Default for MessageTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageTrace {
+    pub fn new() -> MessageTrace {
+        Self::with_capacity(MESSAGE_TRACE_CAPACITY)
+    }
+
+    /// A trace remembering the last `capacity` messages (at least one)
+    pub fn with_capacity(capacity: usize) -> MessageTrace {
+        let capacity = capacity.max(1);
+        MessageTrace {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            log_window: Mutex::new(LogWindow {
+                started: Instant::now(),
+                logged: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Log `pkg` and remember it, evicting the oldest entry when the trace is full
+    pub fn record(&self, direction: Direction, peer: &str, pkg: &Package, size: usize) {
+        self.log(direction, peer, pkg, size);
+
+        let entry = TraceEntry {
+            timestamp: current_timestamp().unwrap_or_default(),
+            direction,
+            peer: peer.to_string(),
+            kind: pkg.kind().to_string(),
+            size,
+        };
+        let mut entries = lock(&self.entries);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The remembered messages, oldest first
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        lock(&self.entries).iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        lock(&self.entries).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        lock(&self.entries).is_empty()
+    }
+
+    fn log(&self, direction: Direction, peer: &str, pkg: &Package, size: usize) {
+        let at_info = {
+            let mut window = lock(&self.log_window);
+            if window.started.elapsed() >= Duration::from_secs(1) {
+                if window.suppressed > 0 {
+                    info!(
+                        "p2p suppressed={} (message lines logged at debug level only)",
+                        window.suppressed
+                    );
+                }
+                *window = LogWindow {
+                    started: Instant::now(),
+                    logged: 0,
+                    suppressed: 0,
+                };
+            }
+            if window.logged < MAX_INFO_LINES_PER_SECOND {
+                window.logged += 1;
+                true
+            } else {
+                window.suppressed += 1;
+                false
+            }
+        };
+
+        // Summaries of blocks decode the payload, so they're only built for lines written
+        if at_info && log_enabled!(Level::Info) {
+            info!(
+                "p2p {direction} peer={peer} type={} size={size}{}",
+                pkg.kind(),
+                pkg.summary()
+            );
+        } else if log_enabled!(Level::Debug) {
+            debug!(
+                "p2p {direction} peer={peer} type={} size={size}{}",
+                pkg.kind(),
+                pkg.summary()
+            );
+        }
+    }
+}
+
+// Entries and counters stay meaningful after a panicking holder
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_blocks(addr_from: &str) -> Package {
+        Package::GetBlocks {
+            addr_from: addr_from.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_oldest_entries_are_evicted() {
+        let trace = MessageTrace::with_capacity(3);
+        for peer in 0..5 {
+            let pkg = get_blocks(&format!("127.0.0.1:{peer}"));
+            trace.record(Direction::Inbound, &format!("127.0.0.1:{peer}"), &pkg, peer);
+        }
+
+        let entries = trace.entries();
+        assert_eq!(trace.len(), 3);
+        assert_eq!(
+            entries.iter().map(|e| e.size).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert!(entries
+            .iter()
+            .all(|e| e.kind == "GetBlocks" && e.direction == Direction::Inbound));
+
+        trace.record(Direction::Outbound, "10.0.0.1:2001", &get_blocks("x"), 99);
+        let newest = trace.entries().pop().unwrap();
+        assert_eq!(newest.peer, "10.0.0.1:2001");
+        assert_eq!(newest.direction, Direction::Outbound);
+        assert_eq!(trace.entries()[0].size, 3);
+    }
+}
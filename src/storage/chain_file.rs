@@ -0,0 +1,423 @@
+//! Portable blockchain dump files
+//!
+//! A chain file is a header followed by every block from genesis to tip. Each record
+//! (header included) is written as a 4-byte little-endian length followed by the
+//! bincode bytes produced by `utils::serialize`, so a reader never has to guess
+//! where one block ends and the next begins.
+
+use crate::core::{Block, BlockAddResult, Blockchain};
+use crate::error::{BlockchainError, Result};
+use crate::storage::UTXOSet;
+use crate::utils::{deserialize, serialize};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes identifying an architect-chain export file
+pub const CHAIN_FILE_MAGIC: [u8; 4] = *b"ACHF";
+/// Current version of the chain file layout
+pub const CHAIN_FILE_VERSION: u32 = 1;
+
+/// How often progress is logged while exporting or importing
+const PROGRESS_INTERVAL: u64 = 100;
+/// Upper bound for a single record, so a corrupt length can't trigger a huge allocation
+const MAX_RECORD_SIZE: usize = 2_000_000;
+
+/// Header written at the start of every chain file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct ChainFileHeader {
+    pub magic: [u8; 4],
+    pub version: u32,
+    pub block_count: u64,
+    pub tip_hash: String,
+}
+
+/// Export every block of the chain, genesis first, into a chain file at `path`
+pub fn export_chain(blockchain: &Blockchain, path: &Path) -> Result<ChainFileHeader> {
+    // Whoever imports the file replays every block, so the bodies all have to be there
+    if blockchain.is_pruned()? {
+        return Err(BlockchainError::Pruned(
+            "a pruned chain can't be exported, since some blocks only have their headers left"
+                .to_string(),
+        ));
+    }
+
+    // Only one block at a time is held in memory while writing
+    let mut blocks = blockchain.iter_from_genesis()?;
+    let header = ChainFileHeader {
+        magic: CHAIN_FILE_MAGIC,
+        version: CHAIN_FILE_VERSION,
+        block_count: blocks.remaining() as u64,
+        tip_hash: blockchain.get_tip_hash(),
+    };
+
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+    write_record(&mut writer, &serialize(&header)?)?;
+
+    let mut exported = 0u64;
+    while let Some(block) = blocks.try_next()? {
+        write_record(&mut writer, &block.serialize()?)?;
+
+        exported += 1;
+        if exported.is_multiple_of(PROGRESS_INTERVAL) {
+            info!("Exported {exported}/{} blocks", header.block_count);
+        }
+    }
+
+    writer.flush()?;
+    info!(
+        "Exported {} blocks to {} (tip: {})",
+        header.block_count,
+        path.display(),
+        header.tip_hash
+    );
+    Ok(header)
+}
+
+/// Import a chain file into the database at `db_path`, validating every block on the way in
+///
+/// The target database must be empty unless `force` is set, in which case the existing
+/// chain is replaced. With `assume_valid`, signatures below the latest checkpoint are not
+/// checked. The UTXO set is rebuilt once all blocks are stored.
+///
+/// The blocks go into a fresh database next to `db_path`, which only takes its place once
+/// the whole file is in, so a file that fails halfway leaves the target as it was.
+pub fn import_chain(
+    path: &Path,
+    db_path: &str,
+    force: bool,
+    assume_valid: bool,
+) -> Result<Blockchain> {
+    let target = Path::new(db_path);
+    // Opening the target also refuses it while a running node holds it
+    if target.exists() && Blockchain::has_blockchain_at(db_path)? && !force {
+        return Err(BlockchainError::Database(format!(
+            "Database at {db_path} already contains a blockchain (use --force to overwrite)"
+        )));
+    }
+
+    let staging = sibling_path(target, "importing");
+    remove_dir_if_exists(&staging)?;
+    let staging_str = staging.to_string_lossy().into_owned();
+    let imported = import_into(path, &staging_str, assume_valid).and_then(|blockchain| {
+        // Every handle on the staged database has to be gone before it can be moved
+        blockchain.get_db().flush().map_err(|e| {
+            BlockchainError::Database(format!("Failed to flush the imported chain: {e}"))
+        })?;
+        Ok(())
+    });
+    if let Err(e) = imported {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+
+    if target.exists() {
+        let replaced = sibling_path(target, "replaced");
+        remove_dir_if_exists(&replaced)?;
+        fs::rename(target, &replaced)?;
+        fs::rename(&staging, target)?;
+        fs::remove_dir_all(&replaced)?;
+    } else {
+        fs::rename(&staging, target)?;
+    }
+    Blockchain::new_blockchain_with_path(db_path)
+}
+
+// Read the chain file at `path` into a new database at `db_path`
+fn import_into(path: &Path, db_path: &str, assume_valid: bool) -> Result<Blockchain> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(file);
+
+    let header_bytes = read_record(&mut reader)?
+        .ok_or_else(|| BlockchainError::Serialization("Chain file is empty".to_string()))?;
+    let header: ChainFileHeader = deserialize(&header_bytes)?;
+
+    if header.magic != CHAIN_FILE_MAGIC {
+        return Err(BlockchainError::Serialization(
+            "Not an architect-chain export file (bad magic)".to_string(),
+        ));
+    }
+    if header.version != CHAIN_FILE_VERSION {
+        return Err(BlockchainError::Serialization(format!(
+            "Unsupported chain file version {} (expected {CHAIN_FILE_VERSION})",
+            header.version
+        )));
+    }
+    if header.block_count == 0 {
+        return Err(BlockchainError::Serialization(
+            "Chain file contains no blocks".to_string(),
+        ));
+    }
+
+    let genesis = read_block(&mut reader)?.ok_or_else(|| {
+        BlockchainError::Serialization("Chain file ended before genesis block".to_string())
+    })?;
+    if genesis.get_pre_block_hash() != "None" || genesis.get_height() != 0 {
+        return Err(BlockchainError::InvalidBlock(
+            "First block in chain file is not a genesis block".to_string(),
+        ));
+    }
+    if !genesis.validate_block(None)? {
+        return Err(BlockchainError::InvalidBlock(format!(
+            "Genesis block {} failed validation",
+            genesis.get_hash()
+        )));
+    }
+
+    let blockchain = Blockchain::create_blockchain_from_genesis(&genesis, db_path, false)?;
+    blockchain.set_assume_valid_below_checkpoints(assume_valid);
+
+    let mut previous = genesis;
+    let mut imported: u64 = 1;
+    while let Some(block) = read_block(&mut reader)? {
+        if block.is_pruned() {
+            return Err(BlockchainError::Pruned(format!(
+                "block {} at height {} in the chain file has no transactions",
+                block.get_hash(),
+                block.get_height()
+            )));
+        }
+        check_linkage(&block, &previous)?;
+        if blockchain.validate_and_add_block(&block)? != BlockAddResult::TipAdvanced {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Block {} at height {} did not extend the imported chain",
+                block.get_hash(),
+                block.get_height()
+            )));
+        }
+
+        imported += 1;
+        if imported.is_multiple_of(PROGRESS_INTERVAL) {
+            info!("Imported {imported}/{} blocks", header.block_count);
+        }
+        previous = block;
+    }
+
+    if imported != header.block_count {
+        return Err(BlockchainError::Serialization(format!(
+            "Chain file header promised {} blocks but contained {imported}",
+            header.block_count
+        )));
+    }
+    if blockchain.get_tip_hash() != header.tip_hash {
+        return Err(BlockchainError::InvalidBlock(format!(
+            "Imported tip {} does not match the tip recorded in the file ({})",
+            blockchain.get_tip_hash(),
+            header.tip_hash
+        )));
+    }
+
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    utxo_set.reindex_safe()?;
+
+    info!(
+        "Imported {imported} blocks from {} (tip: {})",
+        path.display(),
+        header.tip_hash
+    );
+    Ok(blockchain)
+}
+
+// The chain checks in validate_and_add_block accept forks too, so I make sure the file
+// really is one unbroken chain
+fn check_linkage(block: &Block, previous: &Block) -> Result<()> {
+    if block.get_pre_block_hash() != previous.get_hash() {
+        return Err(BlockchainError::InvalidBlock(format!(
+            "Block {} does not link to previous block {}",
+            block.get_hash(),
+            previous.get_hash()
+        )));
+    }
+    if block.get_height() != previous.get_height() + 1 {
+        return Err(BlockchainError::InvalidBlock(format!(
+            "Block {} has height {} but follows height {}",
+            block.get_hash(),
+            block.get_height(),
+            previous.get_height()
+        )));
+    }
+    Ok(())
+}
+
+// `<db_path>.<suffix>`, on the same filesystem as the database so it can be renamed into
+// place
+fn sibling_path(db_path: &Path, suffix: &str) -> PathBuf {
+    let mut name = db_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{suffix}"));
+    db_path.with_file_name(name)
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(|_| {
+        BlockchainError::Serialization(format!("Record too large: {} bytes", bytes.len()))
+    })?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+// Returns Ok(None) on a clean end of file, i.e. when no length prefix could be read at all
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 4];
+    match reader.read_exact(&mut len_bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Err(BlockchainError::Serialization(format!(
+            "Record length {len} exceeds maximum of {MAX_RECORD_SIZE} bytes"
+        )));
+    }
+
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).map_err(|e| {
+        BlockchainError::Serialization(format!("Chain file truncated mid-record: {e}"))
+    })?;
+    Ok(Some(bytes))
+}
+
+fn read_block<R: Read>(reader: &mut R) -> Result<Option<Block>> {
+    match read_record(reader)? {
+        Some(bytes) => Ok(Some(Block::deserialize(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::decode_address;
+    use tempfile::tempdir;
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn balance_of(utxo_set: &UTXOSet, address: &str) -> u64 {
+        let (_, pub_key_hash) = decode_address(address).unwrap();
+        utxo_set
+            .find_utxo(&pub_key_hash)
+            .iter()
+            .map(|out| out.get_value().to_satoshis())
+            .sum()
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let temp_dir = tempdir().unwrap();
+        let source_path = temp_dir.path().join("source");
+        let target_path = temp_dir.path().join("target");
+        let export_path = temp_dir.path().join("chain.dat");
+
+        let source =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, source_path.to_str().unwrap())
+                .unwrap();
+        for _ in 0..20 {
+            source.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        }
+        let source_utxo = UTXOSet::new(source.clone());
+        source_utxo.reindex();
+
+        let header = export_chain(&source, &export_path).unwrap();
+        assert_eq!(header.block_count, 21);
+        assert_eq!(header.tip_hash, source.get_tip_hash());
+
+        let imported =
+            import_chain(&export_path, target_path.to_str().unwrap(), false, false).unwrap();
+        assert_eq!(imported.get_tip_hash(), source.get_tip_hash());
+        assert_eq!(imported.get_best_height().unwrap(), 20);
+
+        let imported_utxo = UTXOSet::new(imported);
+        assert_eq!(
+            balance_of(&imported_utxo, TEST_ADDRESS),
+            balance_of(&source_utxo, TEST_ADDRESS)
+        );
+    }
+
+    #[test]
+    fn test_import_refuses_non_empty_database_without_force() {
+        let temp_dir = tempdir().unwrap();
+        let source_path = temp_dir.path().join("source");
+        let target_path = temp_dir.path().join("target");
+        let export_path = temp_dir.path().join("chain.dat");
+
+        let source =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, source_path.to_str().unwrap())
+                .unwrap();
+        source.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        export_chain(&source, &export_path).unwrap();
+
+        {
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, target_path.to_str().unwrap())
+                .unwrap();
+        }
+
+        assert!(import_chain(&export_path, target_path.to_str().unwrap(), false, false).is_err());
+
+        let imported =
+            import_chain(&export_path, target_path.to_str().unwrap(), true, false).unwrap();
+        assert_eq!(imported.get_tip_hash(), source.get_tip_hash());
+    }
+
+    #[test]
+    fn test_failed_import_leaves_the_target_untouched() {
+        let temp_dir = tempdir().unwrap();
+        let source_path = temp_dir.path().join("source");
+        let target_path = temp_dir.path().join("target");
+        let export_path = temp_dir.path().join("chain.dat");
+
+        let source =
+            Blockchain::create_blockchain_with_path(TEST_ADDRESS, source_path.to_str().unwrap())
+                .unwrap();
+        for _ in 0..3 {
+            source.mine_block_with_fees(&[], TEST_ADDRESS).unwrap();
+        }
+        export_chain(&source, &export_path).unwrap();
+        // The last block is cut off halfway
+        let bytes = std::fs::read(&export_path).unwrap();
+        std::fs::write(&export_path, &bytes[..bytes.len() - 10]).unwrap();
+
+        let tip = {
+            let target = Blockchain::create_blockchain_with_path(
+                TEST_ADDRESS,
+                target_path.to_str().unwrap(),
+            )
+            .unwrap();
+            target.get_tip_hash()
+        };
+        assert!(import_chain(&export_path, target_path.to_str().unwrap(), true, false).is_err());
+
+        let target = Blockchain::new_blockchain_with_path(target_path.to_str().unwrap()).unwrap();
+        assert_eq!(target.get_tip_hash(), tip);
+        assert!(!temp_dir.path().join("target.importing").exists());
+    }
+
+    #[test]
+    fn test_import_rejects_bad_magic() {
+        let temp_dir = tempdir().unwrap();
+        let export_path = temp_dir.path().join("chain.dat");
+        let header = ChainFileHeader {
+            magic: *b"NOPE",
+            version: CHAIN_FILE_VERSION,
+            block_count: 1,
+            tip_hash: String::new(),
+        };
+        let mut file = File::create(&export_path).unwrap();
+        write_record(&mut file, &serialize(&header).unwrap()).unwrap();
+        drop(file);
+
+        let target_path = temp_dir.path().join("target");
+        assert!(import_chain(&export_path, target_path.to_str().unwrap(), false, false).is_err());
+    }
+}
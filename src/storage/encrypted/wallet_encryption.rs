@@ -1,13 +1,15 @@
+use crate::config::GLOBAL_CONFIG;
 use crate::error::{BlockchainError, Result};
 use crate::storage::encrypted::cipher::{Aes256GcmCipher, SecureKey};
-use crate::utils::{deserialize, serialize};
-use crate::wallet::{Wallet, WALLET_FILE};
+use crate::utils::{current_timestamp, deserialize, serialize};
+use crate::wallet::backup::{encode_backup, WalletBackupHeader};
+use crate::wallet::file_lock::{write_atomically, WalletFileLock};
+use crate::wallet::wallets::WalletFile;
+use crate::wallet::{wallet_path, Draft, DraftState, Wallet, WalletMetadata, WALLET_FILE};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::env::current_dir;
-use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Read, Write};
-// Path import removed as not needed
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 /// Simple configuration for wallet encryption
 #[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
@@ -18,7 +20,7 @@ pub struct WalletEncryptionConfig {
     pub wallet_file: String,
     /// Whether to create encrypted backups
     pub backup_enabled: bool,
-    /// Backup directory
+    /// Backup directory, relative to the directory of the wallet file
     pub backup_dir: String,
     /// Minimum password length
     pub min_password_length: usize,
@@ -28,7 +30,11 @@ impl Default for WalletEncryptionConfig {
     fn default() -> Self {
         Self {
             enabled: false,
-            wallet_file: WALLET_FILE.to_string(),
+            // The file `Wallets` would use, relative to the data directory if unresolvable
+            wallet_file: wallet_path().map_or_else(
+                |_| WALLET_FILE.to_string(),
+                |path| path.to_string_lossy().into_owned(),
+            ),
             backup_enabled: true,
             backup_dir: "wallet_backups".to_string(),
             min_password_length: 8,
@@ -55,9 +61,31 @@ pub struct EncryptedWalletData {
     pub modified_at: u64,
 }
 
+/// Derive a wallet encryption key from a password with Argon2id
+pub(crate) fn derive_key_from_password(password: &str, salt: &[u8]) -> Result<SecureKey> {
+    use argon2::{Algorithm, Argon2, Params, Version};
+
+    // Simple Argon2 parameters
+    let params = Params::new(65536, 3, 1, Some(32))
+        .map_err(|e| BlockchainError::Encryption(format!("Invalid Argon2 parameters: {e}")))?;
+
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = vec![0u8; 32];
+    argon2
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| BlockchainError::Encryption(format!("Key derivation failed: {e}")))?;
+
+    Ok(SecureKey::new(key))
+}
+
 /// Simplified encrypted wallets manager
+///
+/// The ciphertext holds the wallet file in its plain layout, so labels and everything
+/// else `Wallets` keeps survive encryption; files that encrypted a bare map of wallets
+/// still load.
 pub struct EncryptedWallets {
-    wallets: HashMap<String, Wallet>,
+    file: WalletFile,
     config: WalletEncryptionConfig,
     master_key: Option<SecureKey>,
     current_salt: Option<Vec<u8>>,
@@ -69,7 +97,7 @@ impl EncryptedWallets {
     /// Create a new encrypted wallets manager
     pub fn new(config: WalletEncryptionConfig) -> Self {
         Self {
-            wallets: HashMap::new(),
+            file: WalletFile::empty(),
             config,
             master_key: None,
             current_salt: None,
@@ -80,6 +108,9 @@ impl EncryptedWallets {
 
     /// Initialize encryption with a password
     pub fn initialize_encryption(&mut self, password: &str) -> Result<()> {
+        let wallet_path = self.wallet_path()?;
+        let _lock = WalletFileLock::acquire(&wallet_path)?;
+
         if !self.config.enabled {
             self.load_unencrypted()?;
             return Ok(());
@@ -88,8 +119,6 @@ impl EncryptedWallets {
         // Validate password
         self.validate_password(password)?;
 
-        let wallet_path = current_dir()?.join(&self.config.wallet_file);
-
         if wallet_path.exists() {
             // Load existing encrypted wallet
             self.load_encrypted(password)?;
@@ -129,25 +158,12 @@ impl EncryptedWallets {
 
     /// Simple key derivation from password and salt
     fn derive_key_from_password(&self, password: &str, salt: &[u8]) -> Result<SecureKey> {
-        use argon2::{Algorithm, Argon2, Params, Version};
-
-        // Simple Argon2 parameters
-        let params = Params::new(65536, 3, 1, Some(32))
-            .map_err(|e| BlockchainError::Encryption(format!("Invalid Argon2 parameters: {e}")))?;
-
-        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
-
-        let mut key = vec![0u8; 32];
-        argon2
-            .hash_password_into(password.as_bytes(), salt, &mut key)
-            .map_err(|e| BlockchainError::Encryption(format!("Key derivation failed: {e}")))?;
-
-        Ok(SecureKey::new(key))
+        derive_key_from_password(password, salt)
     }
 
     /// Load existing encrypted wallet file
     fn load_encrypted(&mut self, password: &str) -> Result<()> {
-        let wallet_path = current_dir()?.join(&self.config.wallet_file);
+        let wallet_path = self.wallet_path()?;
 
         if !wallet_path.exists() {
             return Err(BlockchainError::Wallet(
@@ -177,7 +193,7 @@ impl EncryptedWallets {
             cipher.decrypt(&encrypted_wallet.ciphertext, &encrypted_wallet.nonce)?;
 
         // Deserialize wallets
-        self.wallets = deserialize(&decrypted_data)
+        self.file = WalletFile::decode(&decrypted_data)
             .map_err(|e| BlockchainError::Wallet(format!("Failed to deserialize wallets: {e}")))?;
 
         self.master_key = Some(master_key);
@@ -187,14 +203,14 @@ impl EncryptedWallets {
 
         log::info!(
             "Loaded encrypted wallet file with {} wallets",
-            self.wallets.len()
+            self.file.base.wallets.len()
         );
         Ok(())
     }
 
     /// Load unencrypted wallet file (legacy support)
     fn load_unencrypted(&mut self) -> Result<()> {
-        let wallet_path = current_dir()?.join(&self.config.wallet_file);
+        let wallet_path = self.wallet_path()?;
 
         if !wallet_path.exists() {
             self.is_loaded = true;
@@ -212,13 +228,14 @@ impl EncryptedWallets {
         file.read_exact(&mut buf)
             .map_err(|e| BlockchainError::Wallet(format!("Failed to read wallet file: {e}")))?;
 
-        self.wallets = deserialize(&buf)
+        // The plain wallet file may be in either the labeled or the legacy layout
+        self.file = WalletFile::decode(&buf)
             .map_err(|e| BlockchainError::Wallet(format!("Failed to deserialize wallets: {e}")))?;
 
         self.is_loaded = true;
         log::info!(
             "Loaded unencrypted wallet file with {} wallets",
-            self.wallets.len()
+            self.file.base.wallets.len()
         );
         Ok(())
     }
@@ -235,7 +252,9 @@ impl EncryptedWallets {
             .ok_or_else(|| BlockchainError::Wallet("No master key available".to_string()))?;
 
         // Serialize wallets
-        let wallet_data = serialize(&self.wallets)
+        let wallet_data = self
+            .file
+            .encode()
             .map_err(|e| BlockchainError::Wallet(format!("Failed to serialize wallets: {e}")))?;
 
         // Encrypt wallet data
@@ -254,8 +273,8 @@ impl EncryptedWallets {
             ciphertext: encryption_result.ciphertext,
             nonce: encryption_result.nonce,
             salt,
-            wallet_count: self.wallets.len(),
-            addresses: self.wallets.keys().cloned().collect(),
+            wallet_count: self.file.base.wallets.len(),
+            addresses: self.file.base.wallets.keys().cloned().collect(),
             created_at: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
@@ -267,27 +286,12 @@ impl EncryptedWallets {
         };
 
         // Write to file
-        let wallet_path = current_dir()?.join(&self.config.wallet_file);
-        let file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(&wallet_path)
-            .map_err(|e| BlockchainError::Wallet(format!("Failed to create wallet file: {e}")))?;
-
-        let mut writer = BufWriter::new(file);
         let encrypted_bytes = serialize(&encrypted_wallet).map_err(|e| {
             BlockchainError::Wallet(format!("Failed to serialize encrypted wallet: {e}"))
         })?;
-
-        writer
-            .write_all(&encrypted_bytes)
+        write_atomically(&self.wallet_path()?, &encrypted_bytes)
             .map_err(|e| BlockchainError::Wallet(format!("Failed to write wallet file: {e}")))?;
 
-        writer
-            .flush()
-            .map_err(|e| BlockchainError::Wallet(format!("Failed to flush wallet file: {e}")))?;
-
         // Create backup if enabled
         if self.config.backup_enabled {
             self.create_backup()?;
@@ -295,43 +299,34 @@ impl EncryptedWallets {
 
         log::info!(
             "Saved encrypted wallet file with {} wallets",
-            self.wallets.len()
+            self.file.base.wallets.len()
         );
         Ok(())
     }
 
     /// Save unencrypted wallet file (legacy support)
     fn save_unencrypted(&self) -> Result<()> {
-        let wallet_path = current_dir()?.join(&self.config.wallet_file);
-        let file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(&wallet_path)
-            .map_err(|e| BlockchainError::Wallet(format!("Failed to create wallet file: {e}")))?;
-
-        let mut writer = BufWriter::new(file);
-        let wallet_bytes = serialize(&self.wallets)
+        let wallet_bytes = self
+            .file
+            .encode()
             .map_err(|e| BlockchainError::Wallet(format!("Failed to serialize wallets: {e}")))?;
-
-        writer
-            .write_all(&wallet_bytes)
+        write_atomically(&self.wallet_path()?, &wallet_bytes)
             .map_err(|e| BlockchainError::Wallet(format!("Failed to write wallet file: {e}")))?;
 
-        writer
-            .flush()
-            .map_err(|e| BlockchainError::Wallet(format!("Failed to flush wallet file: {e}")))?;
-
         log::info!(
             "Saved unencrypted wallet file with {} wallets",
-            self.wallets.len()
+            self.file.base.wallets.len()
         );
         Ok(())
     }
 
     /// Create a backup of the wallet file
     fn create_backup(&self) -> Result<()> {
-        let backup_dir = current_dir()?.join(&self.config.backup_dir);
+        let source_file = self.wallet_path()?;
+        let backup_dir = source_file
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(&self.config.backup_dir);
         std::fs::create_dir_all(&backup_dir).map_err(|e| {
             BlockchainError::Wallet(format!("Failed to create backup directory: {e}"))
         })?;
@@ -342,7 +337,6 @@ impl EncryptedWallets {
             .as_secs();
 
         let backup_file = backup_dir.join(format!("wallet_backup_{timestamp}.dat"));
-        let source_file = current_dir()?.join(&self.config.wallet_file);
 
         std::fs::copy(&source_file, &backup_file)
             .map_err(|e| BlockchainError::Wallet(format!("Failed to create backup: {e}")))?;
@@ -353,40 +347,186 @@ impl EncryptedWallets {
 
     /// Create a new wallet
     pub fn create_wallet(&mut self) -> Result<String> {
+        let wallet = Wallet::new()?;
+        let address = wallet.get_address();
+        let created_at = current_timestamp()?;
+        self.update(|file| {
+            file.metadata.insert(
+                address.clone(),
+                WalletMetadata {
+                    created_at,
+                    genesis_hash: None,
+                    notes: None,
+                },
+            );
+            file.base.wallets.insert(address.clone(), wallet);
+            Ok(())
+        })?;
+
+        log::info!("Created new wallet with address: {address}");
+        Ok(address)
+    }
+
+    /// Apply `change` to the wallets as they are on disk and save the result
+    ///
+    /// The wallet file stays locked from the reload to the save, so wallets another process
+    /// added since this set was loaded are kept rather than overwritten. Nothing is saved
+    /// if `change` fails.
+    fn update<T>(&mut self, change: impl FnOnce(&mut WalletFile) -> Result<T>) -> Result<T> {
         if !self.is_loaded {
             return Err(BlockchainError::Wallet(
                 "Wallets not loaded. Call initialize_encryption first.".to_string(),
             ));
         }
 
-        let wallet = Wallet::new()?;
-        let address = wallet.get_address();
-        self.wallets.insert(address.clone(), wallet);
-
-        // Save immediately
+        let _lock = WalletFileLock::acquire(&self.wallet_path()?)?;
+        self.reload()?;
+        let value = change(&mut self.file)?;
         if self.is_encrypted {
             self.save_encrypted()?;
         } else {
             self.save_unencrypted()?;
         }
+        Ok(value)
+    }
 
-        log::info!("Created new wallet with address: {address}");
-        Ok(address)
+    // Read the file again with the key it was opened with; a missing file leaves the
+    // wallets in memory as they are
+    fn reload(&mut self) -> Result<()> {
+        let wallet_path = self.wallet_path()?;
+        if !wallet_path.exists() {
+            return Ok(());
+        }
+        if !self.is_encrypted {
+            return self.load_unencrypted();
+        }
+
+        let contents = std::fs::read(&wallet_path)
+            .map_err(|e| BlockchainError::Wallet(format!("Failed to read wallet file: {e}")))?;
+        let encrypted_wallet: EncryptedWalletData = deserialize(&contents).map_err(|e| {
+            BlockchainError::Wallet(format!("Failed to deserialize wallet data: {e}"))
+        })?;
+        // Another password was set since, which this handle doesn't know
+        if self.current_salt.as_ref() != Some(&encrypted_wallet.salt) {
+            return Err(BlockchainError::Wallet(
+                "Wallet file was re-encrypted since it was opened, open it again".to_string(),
+            ));
+        }
+        let master_key = self
+            .master_key
+            .as_ref()
+            .ok_or_else(|| BlockchainError::Wallet("No master key available".to_string()))?;
+        let cipher = Aes256GcmCipher::new(master_key.clone())?;
+        let decrypted_data =
+            cipher.decrypt(&encrypted_wallet.ciphertext, &encrypted_wallet.nonce)?;
+        self.file = WalletFile::decode(&decrypted_data)
+            .map_err(|e| BlockchainError::Wallet(format!("Failed to deserialize wallets: {e}")))?;
+        Ok(())
     }
 
     /// Get wallet by address
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
-        self.wallets.get(address)
+        self.file.base.wallets.get(address)
+    }
+
+    /// What was recorded about the key of `address` when it was created
+    pub fn get_metadata(&self, address: &str) -> Option<&WalletMetadata> {
+        self.file.metadata.get(address)
     }
 
     /// Get all wallet addresses
     pub fn get_addresses(&self) -> Vec<String> {
-        self.wallets.keys().cloned().collect()
+        self.file.base.wallets.keys().cloned().collect()
     }
 
     /// Get number of wallets
     pub fn wallet_count(&self) -> usize {
-        self.wallets.len()
+        self.file.base.wallets.len()
+    }
+
+    /// Label one of the wallets, replacing any previous label
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<()> {
+        self.update(|file| {
+            if !file.base.wallets.contains_key(address) {
+                return Err(BlockchainError::Wallet(format!(
+                    "Address {address} is not in this wallet"
+                )));
+            }
+            if label.trim().is_empty() {
+                return Err(BlockchainError::Wallet(
+                    "Label must not be empty".to_string(),
+                ));
+            }
+            if let Some((owner, _)) = file
+                .base
+                .labels
+                .iter()
+                .find(|(owner, existing)| *existing == label && owner.as_str() != address)
+            {
+                return Err(BlockchainError::Wallet(format!(
+                    "Label '{label}' is already used by {owner}"
+                )));
+            }
+
+            file.base
+                .labels
+                .insert(address.to_string(), label.to_string());
+            Ok(())
+        })
+    }
+
+    /// The label of `address`, if it has one
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        self.file.base.labels.get(address).map(String::as_str)
+    }
+
+    /// Keep the serialized `transaction` under `name`, see `Wallets::save_draft`
+    pub fn save_draft(&mut self, name: &str, transaction: &[u8], state: DraftState) -> Result<()> {
+        let saved_at = current_timestamp()?;
+        self.update(|file| file.drafts.save(name, transaction, state, saved_at))
+    }
+
+    /// Every draft, sorted by name
+    pub fn list_drafts(&self) -> impl Iterator<Item = (&str, &Draft)> {
+        self.file.drafts.iter()
+    }
+
+    pub fn load_draft(&self, name: &str) -> Result<&Draft> {
+        self.file
+            .drafts
+            .get(name)
+            .ok_or_else(|| BlockchainError::Wallet(format!("No draft named '{name}'")))
+    }
+
+    /// Forget draft `name`, returning it
+    pub fn delete_draft(&mut self, name: &str) -> Result<Draft> {
+        self.update(|file| file.drafts.delete(name))
+    }
+
+    /// Write every wallet and label to a backup at `path`, see `Wallets::backup`
+    ///
+    /// The backup is encrypted with `password`, which need not be the wallet file's. An
+    /// encrypted wallet file is never backed up in the clear, so it needs one.
+    pub fn backup(&mut self, path: &Path, password: Option<&str>) -> Result<WalletBackupHeader> {
+        if !self.is_loaded {
+            return Err(BlockchainError::Wallet(
+                "Wallets not loaded. Call initialize_encryption first.".to_string(),
+            ));
+        }
+        if self.is_encrypted && password.is_none() {
+            return Err(BlockchainError::Encryption(
+                "An encrypted wallet file is only backed up encrypted, a password is needed"
+                    .to_string(),
+            ));
+        }
+
+        let _lock = WalletFileLock::acquire(&self.wallet_path()?)?;
+        self.reload()?;
+        let (header, bytes) =
+            encode_backup(&self.file.encode()?, self.file.base.wallets.len(), password)?;
+        write_atomically(path, &bytes)
+            .map_err(|e| BlockchainError::Wallet(format!("Failed to write wallet backup: {e}")))?;
+        Ok(header)
     }
 
     /// Check if encryption is enabled
@@ -394,6 +534,10 @@ impl EncryptedWallets {
         self.is_encrypted
     }
 
+    fn wallet_path(&self) -> Result<PathBuf> {
+        Ok(GLOBAL_CONFIG.data_dir()?.join(&self.config.wallet_file))
+    }
+
     /// Get current salt for key derivation
     fn get_current_salt(&self) -> Option<Vec<u8>> {
         self.current_salt.clone()
@@ -403,13 +547,14 @@ impl EncryptedWallets {
 impl Drop for EncryptedWallets {
     fn drop(&mut self) {
         // Clear sensitive data
-        self.wallets.clear();
+        self.file.base.wallets.clear();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::Transaction;
     use tempfile::tempdir;
 
     #[test]
@@ -466,6 +611,9 @@ mod tests {
         let address = wallets.create_wallet().unwrap();
         assert_eq!(wallets.wallet_count(), 1);
         assert!(wallets.get_wallet(&address).is_some());
+        let metadata = wallets.get_metadata(&address).unwrap();
+        assert!(metadata.created_at > 0);
+        assert_eq!(metadata.genesis_hash, None);
 
         // Test addresses
         let addresses = wallets.get_addresses();
@@ -502,5 +650,176 @@ mod tests {
 
         assert_eq!(wallets2.wallet_count(), 1);
         assert!(wallets2.get_wallet(&address).is_some());
+        assert!(wallets2.get_metadata(&address).is_some());
+
+        // Backups go next to the wallet file, not into the working directory
+        let backups = std::fs::read_dir(temp_dir.path().join("wallet_backups")).unwrap();
+        assert_eq!(backups.count(), 1);
+    }
+
+    #[test]
+    fn test_labels_survive_reloads() {
+        let temp_dir = tempdir().unwrap();
+        let config = WalletEncryptionConfig {
+            wallet_file: temp_dir
+                .path()
+                .join("test_wallet.dat")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            enabled: true,
+            backup_enabled: false,
+            ..Default::default()
+        };
+
+        let mut wallets = EncryptedWallets::new(config.clone());
+        wallets.initialize_encryption("TestPassword123").unwrap();
+        let address = wallets.create_wallet().unwrap();
+        wallets.set_label(&address, "savings").unwrap();
+        assert!(wallets.set_label("unknown", "checking").is_err());
+
+        let mut reloaded = EncryptedWallets::new(config);
+        reloaded.initialize_encryption("TestPassword123").unwrap();
+        assert_eq!(reloaded.get_label(&address), Some("savings"));
+    }
+
+    #[test]
+    fn test_drafts_survive_reloads() {
+        let temp_dir = tempdir().unwrap();
+        let config = WalletEncryptionConfig {
+            wallet_file: temp_dir
+                .path()
+                .join("test_wallet.dat")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            enabled: true,
+            backup_enabled: false,
+            ..Default::default()
+        };
+
+        let mut wallets = EncryptedWallets::new(config.clone());
+        wallets.initialize_encryption("TestPassword123").unwrap();
+        let tx = Transaction::new_placeholder_tx(&wallets.create_wallet().unwrap());
+        wallets
+            .save_draft("payroll", &tx.serialize().unwrap(), DraftState::Unsigned)
+            .unwrap();
+
+        let mut reloaded = EncryptedWallets::new(config.clone());
+        reloaded.initialize_encryption("TestPassword123").unwrap();
+        let draft = reloaded.load_draft("payroll").unwrap();
+        assert_eq!(draft.transaction().unwrap().get_id(), tx.get_id());
+        assert_eq!(draft.state(), DraftState::Unsigned);
+        assert_eq!(reloaded.list_drafts().count(), 1);
+
+        reloaded.delete_draft("payroll").unwrap();
+        let mut reloaded = EncryptedWallets::new(config);
+        reloaded.initialize_encryption("TestPassword123").unwrap();
+        assert!(reloaded.load_draft("payroll").is_err());
+    }
+
+    #[test]
+    fn test_two_handles_keep_each_others_wallets() {
+        let temp_dir = tempdir().unwrap();
+        let config = WalletEncryptionConfig {
+            wallet_file: temp_dir
+                .path()
+                .join("test_wallet.dat")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            enabled: true,
+            backup_enabled: false,
+            ..Default::default()
+        };
+
+        let mut first = EncryptedWallets::new(config.clone());
+        first.initialize_encryption("TestPassword123").unwrap();
+        let kept = first.create_wallet().unwrap();
+
+        // Opened before the second wallet was added, and adding one of its own
+        let mut second = EncryptedWallets::new(config.clone());
+        second.initialize_encryption("TestPassword123").unwrap();
+        let added = first.create_wallet().unwrap();
+        let own = second.create_wallet().unwrap();
+
+        let mut reloaded = EncryptedWallets::new(config);
+        reloaded.initialize_encryption("TestPassword123").unwrap();
+        assert_eq!(reloaded.wallet_count(), 3);
+        for address in [&kept, &added, &own] {
+            assert!(reloaded.get_wallet(address).is_some());
+        }
+    }
+
+    #[test]
+    fn test_encrypted_wallet_file_backs_up() {
+        let _guard = crate::wallet::wallets::lock_wallet_file();
+        let _ = std::fs::remove_file(wallet_path().unwrap());
+        let temp_dir = tempdir().unwrap();
+        let backup_path = temp_dir.path().join("wallets.bak");
+        let config = WalletEncryptionConfig {
+            wallet_file: temp_dir
+                .path()
+                .join("test_wallet.dat")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            enabled: true,
+            backup_enabled: false,
+            ..Default::default()
+        };
+
+        let mut wallets = EncryptedWallets::new(config);
+        wallets.initialize_encryption("TestPassword123").unwrap();
+        let address = wallets.create_wallet().unwrap();
+        wallets.set_label(&address, "savings").unwrap();
+        assert!(wallets.backup(&backup_path, None).is_err());
+        let header = wallets.backup(&backup_path, Some("correct horse")).unwrap();
+        assert_eq!(header.wallet_count, 1);
+        assert!(header.is_encrypted());
+
+        // It restores into a plain wallet file like any other backup
+        let summary = crate::wallet::Wallets::new()
+            .restore(&backup_path, Some("correct horse"), false)
+            .unwrap();
+        assert_eq!(summary.added, 1);
+        let restored = crate::wallet::Wallets::new();
+        assert!(restored.get_wallet(&address).is_some());
+        assert_eq!(restored.get_label(&address), Some("savings"));
+        let _ = std::fs::remove_file(wallet_path().unwrap());
+    }
+
+    #[test]
+    fn test_files_encrypting_a_bare_map_still_load() {
+        let temp_dir = tempdir().unwrap();
+        let wallet_file = temp_dir.path().join("test_wallet.dat");
+        let wallet = Wallet::new().unwrap();
+        let address = wallet.get_address();
+        let legacy = std::collections::HashMap::from([(address.clone(), wallet)]);
+
+        let salt = crate::storage::encrypted::generate_random_bytes(32).unwrap();
+        let cipher =
+            Aes256GcmCipher::new(derive_key_from_password("TestPassword123", &salt).unwrap())
+                .unwrap();
+        let encrypted = cipher.encrypt(&serialize(&legacy).unwrap()).unwrap();
+        let data = EncryptedWalletData {
+            ciphertext: encrypted.ciphertext,
+            nonce: encrypted.nonce,
+            salt,
+            wallet_count: 1,
+            addresses: vec![address.clone()],
+            created_at: 0,
+            modified_at: 0,
+        };
+        std::fs::write(&wallet_file, serialize(&data).unwrap()).unwrap();
+
+        let mut wallets = EncryptedWallets::new(WalletEncryptionConfig {
+            wallet_file: wallet_file.to_str().unwrap().to_string(),
+            enabled: true,
+            ..Default::default()
+        });
+        wallets.initialize_encryption("TestPassword123").unwrap();
+        assert!(wallets.get_wallet(&address).is_some());
+        assert_eq!(wallets.get_label(&address), None);
     }
 }
@@ -1,11 +1,120 @@
-use crate::core::Transaction;
+use crate::core::{
+    Amount, Blockchain, ChainEvent, EventBus, EvictionReason, RelayFeePolicy, Transaction,
+    MAX_TRANSACTION_FEE,
+};
+use crate::error::{BlockchainError, Result};
+use crate::utils::{current_timestamp, deserialize, serialize};
 use data_encoding::HEXLOWER;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// Minimum amount (in satoshis) a replacement must pay on top of the fees it replaces
+pub const DEFAULT_MIN_REPLACEMENT_FEE_INCREMENT: u64 = 1;
+/// Tree in the node's database holding the memory pool saved by `MemoryPool::save_to`
+pub const MEMPOOL_TREE: &str = "mempool";
+const MEMPOOL_KEY: &[u8] = b"transactions";
+const ABANDONED_KEY: &[u8] = b"abandoned";
+/// Abandoned txids a pool remembers; past it the oldest is forgotten and may be admitted again
+pub const MAX_ABANDONED_TXS: usize = 100;
+
+// An input's reference to an output: (txid, vout)
+type Outpoint = (Vec<u8>, usize);
 
 /// ( K -> txid_hex, V => Transaction )
 pub struct MemoryPool {
-    inner: RwLock<HashMap<String, Transaction>>,
+    inner: RwLock<PoolEntries>,
+    // Pending transactions above `MAX_TRANSACTION_FEE` whose senders insisted on the fee,
+    // so relaying them tells the next pool the same
+    high_fee_allowed: RwLock<HashSet<String>>,
+    min_replacement_fee_increment: u64,
+    // Where transactions entering and leaving the pool are announced, see `core::events`
+    events: RwLock<EventBus>,
+    // Txids their senders gave up on, oldest first, which peers can't relay back in
+    abandoned: RwLock<Vec<String>>,
+}
+
+// The pending transactions and, for every output they spend, which of them spends it, so
+// conflicts are found per input instead of by comparing against the whole pool
+#[derive(Default)]
+struct PoolEntries {
+    transactions: HashMap<String, Transaction>,
+    claimed: HashMap<Outpoint, String>,
+    // When each transaction first reached this pool, in milliseconds since the epoch
+    received_at: HashMap<String, i64>,
+}
+
+impl PoolEntries {
+    fn insert(&mut self, txid_hex: String, tx: Transaction, received_at: i64) {
+        for outpoint in Self::outpoints(&tx) {
+            self.claimed.insert(outpoint, txid_hex.clone());
+        }
+        // A relay of a transaction already held keeps its original time
+        self.received_at
+            .entry(txid_hex.clone())
+            .or_insert(received_at);
+        self.transactions.insert(txid_hex, tx);
+    }
+
+    fn remove(&mut self, txid_hex: &str) -> Option<Transaction> {
+        let tx = self.transactions.remove(txid_hex)?;
+        self.received_at.remove(txid_hex);
+        for outpoint in Self::outpoints(&tx) {
+            if self.claimed.get(&outpoint).is_some_and(|id| id == txid_hex) {
+                self.claimed.remove(&outpoint);
+            }
+        }
+        Some(tx)
+    }
+
+    // The pending transactions spending an output of `txid_hex`, of those, and so on, each
+    // once; they can only be mined after it
+    fn descendants(&self, txid_hex: &str) -> Vec<String> {
+        let mut found: Vec<String> = Vec::new();
+        let mut parents = vec![txid_hex.to_string()];
+        while let Some(parent) = parents.pop() {
+            let Some(tx) = self.transactions.get(&parent) else {
+                continue;
+            };
+            for vout in 0..tx.get_vout().len() {
+                if let Some(child) = self.claimed.get(&(tx.get_id().to_vec(), vout)) {
+                    if *child != txid_hex && !found.contains(child) {
+                        found.push(child.clone());
+                        parents.push(child.clone());
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    fn clear(&mut self) {
+        self.transactions.clear();
+        self.claimed.clear();
+        self.received_at.clear();
+    }
+
+    // A coinbase's input refers to nothing, so it never claims an output
+    fn outpoints(tx: &Transaction) -> Vec<Outpoint> {
+        if tx.is_coinbase() {
+            return Vec::new();
+        }
+        tx.get_vin()
+            .iter()
+            .map(|input| (input.get_txid().to_vec(), input.get_vout()))
+            .collect()
+    }
+}
+
+/// A pending transaction as saved across restarts
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct SavedTransaction {
+    transaction: Vec<u8>,
+    received_at: i64,
+    allow_high_fee: bool,
 }
 
 impl Default for MemoryPool {
@@ -16,14 +125,46 @@ impl Default for MemoryPool {
 
 impl MemoryPool {
     pub fn new() -> MemoryPool {
+        Self::with_min_replacement_fee_increment(DEFAULT_MIN_REPLACEMENT_FEE_INCREMENT)
+    }
+
+    /// Create a memory pool that requires replacements to add at least `increment` satoshis
+    pub fn with_min_replacement_fee_increment(increment: u64) -> MemoryPool {
         MemoryPool {
-            inner: RwLock::new(HashMap::new()),
+            inner: RwLock::new(PoolEntries::default()),
+            high_fee_allowed: RwLock::new(HashSet::new()),
+            min_replacement_fee_increment: increment,
+            events: RwLock::new(EventBus::new()),
+            abandoned: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// The bus accepted and evicted transactions are announced on
+    pub fn event_bus(&self) -> EventBus {
+        match self.events.read() {
+            Ok(events) => events.clone(),
+            Err(_) => {
+                log::error!("Failed to acquire read lock on memory pool events");
+                EventBus::new()
+            }
         }
     }
 
+    /// Announce on `events` from now on, e.g. the bus of the chain this pool belongs to
+    pub fn set_event_bus(&self, events: EventBus) {
+        match self.events.write() {
+            Ok(mut current) => *current = events,
+            Err(_) => log::error!("Failed to acquire write lock on memory pool events"),
+        }
+    }
+
+    pub fn get_min_replacement_fee_increment(&self) -> u64 {
+        self.min_replacement_fee_increment
+    }
+
     pub fn get(&self, txid: &str) -> Option<Transaction> {
         match self.inner.read() {
-            Ok(pool) => pool.get(txid).cloned(),
+            Ok(pool) => pool.transactions.get(txid).cloned(),
             Err(_) => {
                 log::error!("Failed to acquire read lock on memory pool");
                 None
@@ -32,19 +173,386 @@ impl MemoryPool {
     }
 
     pub fn add(&self, tx: Transaction) {
-        match self.inner.write() {
-            Ok(mut pool) => {
-                pool.insert(HEXLOWER.encode(tx.get_id()), tx);
+        // For backward compatibility, log rejected replacements instead of returning them
+        if let Err(e) = self.add_safe(tx) {
+            log::warn!("Transaction not added to memory pool: {e}");
+        }
+    }
+
+    /// Add a transaction, applying replace-by-fee rules against conflicting pool entries
+    ///
+    /// A transaction spending any outpoint already spent by a pool entry is only accepted if
+    /// its fee covers the combined fees of every entry it conflicts with, and of the entries
+    /// spending their outputs, plus the minimum replacement increment. Accepted replacements
+    /// evict all of those, which are returned to the caller. Fees above
+    /// `MAX_TRANSACTION_FEE` are refused, and so are coinbase transactions, any breaking
+    /// `Transaction::check_limits`, any whose id isn't the one `Transaction::compute_id`
+    /// gives and any that was abandoned.
+    pub fn add_safe(&self, tx: Transaction) -> Result<Vec<Transaction>> {
+        self.admit(tx, false, current_timestamp()?)
+    }
+
+    /// Like `add_safe`, but a fee above `MAX_TRANSACTION_FEE` is accepted because the
+    /// sender insisted on it
+    pub fn add_allowing_high_fee(&self, tx: Transaction) -> Result<Vec<Transaction>> {
+        self.admit(tx, true, current_timestamp()?)
+    }
+
+    /// Whether the pending transaction `txid` was admitted despite a fee above the maximum
+    pub fn allows_high_fee(&self, txid: &str) -> bool {
+        match self.high_fee_allowed.read() {
+            Ok(allowed) => allowed.contains(txid),
+            Err(_) => {
+                log::error!("Failed to acquire read lock on memory pool");
+                false
+            }
+        }
+    }
+
+    fn admit(
+        &self,
+        tx: Transaction,
+        allow_high_fee: bool,
+        received_at: i64,
+    ) -> Result<Vec<Transaction>> {
+        // Oversized or empty transactions never reach a block, so they don't wait for one
+        tx.check_limits()?;
+        // Entries are keyed by id, so one that doesn't match the contents could stand in
+        // for another transaction
+        tx.check_id(false)?;
+        // Only a miner writes a coinbase, straight into its own block
+        if tx.is_coinbase() {
+            return Err(BlockchainError::CoinbaseNotAllowed(format!(
+                "transaction {} is a coinbase and cannot wait in the memory pool",
+                HEXLOWER.encode(tx.get_id())
+            )));
+        }
+        let txid_hex = HEXLOWER.encode(tx.get_id());
+        if self.is_abandoned(&txid_hex) {
+            return Err(BlockchainError::TransactionAbandoned { txid: txid_hex });
+        }
+        if tx.exceeds_max_fee() && !allow_high_fee {
+            return Err(BlockchainError::FeeTooHigh {
+                fee: tx.get_fee().to_satoshis(),
+                max_fee: MAX_TRANSACTION_FEE,
+            });
+        }
+
+        let mut pool = self.inner.write().map_err(|_| {
+            BlockchainError::Database("Failed to acquire write lock on memory pool".to_string())
+        })?;
+
+        let mut conflicting: Vec<String> = Vec::new();
+        for outpoint in PoolEntries::outpoints(&tx) {
+            if let Some(id) = pool.claimed.get(&outpoint) {
+                if *id != txid_hex && !conflicting.contains(id) {
+                    conflicting.push(id.clone());
+                }
+            }
+        }
+
+        // What spends a conflicting entry's outputs can't be mined without it, so it goes too
+        for id in conflicting.clone() {
+            for descendant in pool.descendants(&id) {
+                if !conflicting.contains(&descendant) {
+                    conflicting.push(descendant);
+                }
+            }
+        }
+        if let Some(input) = tx
+            .get_vin()
+            .iter()
+            .find(|input| conflicting.contains(&HEXLOWER.encode(input.get_txid())))
+        {
+            return Err(BlockchainError::Transaction(format!(
+                "transaction {} spends {}, which it would replace",
+                txid_hex,
+                HEXLOWER.encode(input.get_txid())
+            )));
+        }
+
+        if !conflicting.is_empty() {
+            let replaced_fees = conflicting
+                .iter()
+                .filter_map(|id| pool.transactions.get(id))
+                .map(|existing| existing.get_fee())
+                .fold(Amount::ZERO, Amount::saturating_add);
+            let required_fee =
+                replaced_fees.saturating_add(self.min_replacement_fee_increment.into());
+
+            if tx.get_fee() < required_fee {
+                return Err(BlockchainError::ReplacementRejected {
+                    txid: txid_hex,
+                    required_fee: required_fee.to_satoshis(),
+                    offered_fee: tx.get_fee().to_satoshis(),
+                });
             }
+        }
+
+        let replaced: Vec<Transaction> = conflicting
+            .iter()
+            .filter_map(|id| pool.remove(id))
+            .collect();
+        for old in &replaced {
+            log::info!(
+                "Transaction {} replaced by {} (fee {} -> {})",
+                HEXLOWER.encode(old.get_id()),
+                txid_hex,
+                old.get_fee().to_satoshis(),
+                tx.get_fee().to_satoshis()
+            );
+        }
+
+        let mut high_fee_allowed = self.high_fee_allowed.write().map_err(|_| {
+            BlockchainError::Database("Failed to acquire write lock on memory pool".to_string())
+        })?;
+        for id in &conflicting {
+            high_fee_allowed.remove(id);
+        }
+        if tx.exceeds_max_fee() {
+            high_fee_allowed.insert(txid_hex.clone());
+        }
+
+        let newly_pending = !pool.transactions.contains_key(&txid_hex);
+        pool.insert(txid_hex.clone(), tx, received_at);
+        drop(high_fee_allowed);
+        drop(pool);
+
+        let events = self.event_bus();
+        for old in &replaced {
+            events.publish(ChainEvent::TxEvicted {
+                txid: HEXLOWER.encode(old.get_id()),
+                reason: EvictionReason::Replaced {
+                    by: txid_hex.clone(),
+                },
+            });
+        }
+        if newly_pending {
+            events.publish(ChainEvent::TxAccepted { txid: txid_hex });
+        }
+        Ok(replaced)
+    }
+
+    /// When the pending transaction `txid` first reached this pool, in milliseconds since
+    /// the epoch, counting from before any restart it was saved across
+    pub fn received_at(&self, txid: &str) -> Option<i64> {
+        match self.inner.read() {
+            Ok(pool) => pool.received_at.get(txid).copied(),
             Err(_) => {
-                log::error!("Failed to acquire write lock on memory pool");
+                log::error!("Failed to acquire read lock on memory pool");
+                None
+            }
+        }
+    }
+
+    /// Encode every pending transaction along with when it arrived, for `load_and_validate`
+    pub fn serialize_all(&self) -> Result<Vec<u8>> {
+        let pool = self.inner.read().map_err(|_| {
+            BlockchainError::Database("Failed to acquire read lock on memory pool".to_string())
+        })?;
+        let mut saved = Vec::with_capacity(pool.transactions.len());
+        for (txid_hex, tx) in &pool.transactions {
+            saved.push(SavedTransaction {
+                transaction: tx.serialize()?,
+                received_at: pool.received_at.get(txid_hex).copied().unwrap_or_default(),
+                allow_high_fee: self.allows_high_fee(txid_hex),
+            });
+        }
+        serialize(&saved)
+    }
+
+    /// Re-admit transactions encoded by `serialize_all`, returning how many made it back
+    ///
+    /// Each one is verified against `blockchain` first, so those whose inputs were spent
+    /// on the chain in the meantime are dropped, and has to meet `policy` like any
+    /// transaction offered to the node. They are admitted in the order they first arrived,
+    /// keeping that time, so conflicts between them resolve as they did before.
+    pub fn load_and_validate(
+        &self,
+        bytes: &[u8],
+        blockchain: &Blockchain,
+        policy: &RelayFeePolicy,
+    ) -> Result<usize> {
+        let mut saved: Vec<SavedTransaction> = deserialize(bytes)?;
+        saved.sort_by_key(|entry| entry.received_at);
+
+        let mut loaded = 0;
+        for entry in saved {
+            let tx = match Transaction::deserialize(&entry.transaction) {
+                Ok(tx) => tx,
+                Err(e) => {
+                    log::warn!("Skipping unreadable saved transaction: {e}");
+                    continue;
+                }
+            };
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            if self.contains(&txid_hex) {
+                continue;
+            }
+            if !tx.verify(blockchain) {
+                log::info!("Dropping saved transaction {txid_hex}, no longer valid");
+                continue;
+            }
+            if let Err(e) = policy.check(&tx) {
+                log::info!("Dropping saved transaction {txid_hex}: {e}");
+                continue;
+            }
+            match self.admit(tx, entry.allow_high_fee, entry.received_at) {
+                Ok(_) => loaded += 1,
+                Err(e) => log::info!("Dropping saved transaction {txid_hex}: {e}"),
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Replace the memory pool stored in `db` with the transactions pending now and the
+    /// ones abandoned
+    pub fn save_to(&self, db: &Db) -> Result<usize> {
+        let bytes = self.serialize_all()?;
+        let tree = Self::mempool_tree(db)?;
+        tree.insert(MEMPOOL_KEY, bytes)
+            .map_err(|e| BlockchainError::Database(format!("Failed to store memory pool: {e}")))?;
+        tree.insert(ABANDONED_KEY, serialize(&self.abandoned())?)
+            .map_err(|e| {
+                BlockchainError::Database(format!("Failed to store abandoned transactions: {e}"))
+            })?;
+        tree.flush()
+            .map_err(|e| BlockchainError::Database(format!("Failed to flush memory pool: {e}")))?;
+        Ok(self.len())
+    }
+
+    /// Re-admit the transactions `save_to` stored in the blockchain's database
+    ///
+    /// The abandoned ones are remembered again first, so they stay out. A database without
+    /// a saved pool loads nothing. See `load_and_validate`.
+    pub fn load_from(&self, blockchain: &Blockchain, policy: &RelayFeePolicy) -> Result<usize> {
+        let tree = Self::mempool_tree(blockchain.get_db())?;
+        let abandoned = tree.get(ABANDONED_KEY).map_err(|e| {
+            BlockchainError::Database(format!("Failed to read abandoned transactions: {e}"))
+        })?;
+        if let Some(bytes) = abandoned {
+            let txids: Vec<String> = deserialize(&bytes)?;
+            for txid in txids {
+                self.remember_abandoned(txid);
+            }
+        }
+        let saved = tree
+            .get(MEMPOOL_KEY)
+            .map_err(|e| BlockchainError::Database(format!("Failed to read memory pool: {e}")))?;
+        match saved {
+            Some(bytes) => self.load_and_validate(&bytes, blockchain, policy),
+            None => Ok(0),
+        }
+    }
+
+    /// The pending transaction `txid` as `save_to` stored it in `db`, read without loading
+    /// the pool
+    pub fn saved_transaction(db: &Db, txid: &str) -> Result<Option<Transaction>> {
+        let tree = Self::mempool_tree(db)?;
+        for entry in Self::read_saved(&tree)? {
+            if let Ok(tx) = Transaction::deserialize(&entry.transaction) {
+                if HEXLOWER.encode(tx.get_id()) == txid {
+                    return Ok(Some(tx));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Abandon `txid` in the pool `save_to` stored in `db`, for a node that isn't running,
+    /// returning the txids abandoned; none if it wasn't saved
+    ///
+    /// The saved transactions spending its outputs go with it, as `abandon` takes them.
+    /// Every other entry is written back as it was, unlike a round trip through
+    /// `load_from`, which would drop the ones the current policy no longer admits.
+    pub fn abandon_saved(db: &Db, txid: &str) -> Result<Vec<String>> {
+        let tree = Self::mempool_tree(db)?;
+        let mut kept = Self::read_saved(&tree)?;
+        // Unreadable entries are left for `load_and_validate` to skip
+        let saved_txid = |entry: &SavedTransaction| {
+            Transaction::deserialize(&entry.transaction)
+                .ok()
+                .map(|tx| (HEXLOWER.encode(tx.get_id()), tx))
+        };
+        let Some(position) = kept
+            .iter()
+            .position(|entry| saved_txid(entry).is_some_and(|(id, _)| id == txid))
+        else {
+            return Ok(Vec::new());
+        };
+        kept.remove(position);
+        let mut abandoned = vec![txid.to_string()];
+        loop {
+            let found = abandoned.len();
+            kept.retain(|entry| match saved_txid(entry) {
+                Some((id, tx))
+                    if tx
+                        .get_vin()
+                        .iter()
+                        .any(|input| abandoned.contains(&HEXLOWER.encode(input.get_txid()))) =>
+                {
+                    abandoned.push(id);
+                    false
+                }
+                _ => true,
+            });
+            if abandoned.len() == found {
+                break;
+            }
+        }
+
+        let remembered = MemoryPool::new();
+        if let Some(bytes) = tree.get(ABANDONED_KEY).map_err(|e| {
+            BlockchainError::Database(format!("Failed to read abandoned transactions: {e}"))
+        })? {
+            let txids: Vec<String> = deserialize(&bytes)?;
+            for id in txids {
+                remembered.remember_abandoned(id);
+            }
+        }
+        for id in &abandoned {
+            remembered.remember_abandoned(id.clone());
+        }
+        tree.insert(MEMPOOL_KEY, serialize(&kept)?)
+            .map_err(|e| BlockchainError::Database(format!("Failed to store memory pool: {e}")))?;
+        tree.insert(ABANDONED_KEY, serialize(&remembered.abandoned())?)
+            .map_err(|e| {
+                BlockchainError::Database(format!("Failed to store abandoned transactions: {e}"))
+            })?;
+        tree.flush()
+            .map_err(|e| BlockchainError::Database(format!("Failed to flush memory pool: {e}")))?;
+        Ok(abandoned)
+    }
+
+    fn read_saved(tree: &sled::Tree) -> Result<Vec<SavedTransaction>> {
+        let saved = tree
+            .get(MEMPOOL_KEY)
+            .map_err(|e| BlockchainError::Database(format!("Failed to read memory pool: {e}")))?;
+        match saved {
+            Some(bytes) => deserialize(&bytes),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn mempool_tree(db: &Db) -> Result<sled::Tree> {
+        db.open_tree(MEMPOOL_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open mempool tree: {e}")))
+    }
+
+    /// The pending transaction spending output `vout` of `txid`, if any
+    pub fn claimed_by(&self, txid: &[u8], vout: usize) -> Option<String> {
+        match self.inner.read() {
+            Ok(pool) => pool.claimed.get(&(txid.to_vec(), vout)).cloned(),
+            Err(_) => {
+                log::error!("Failed to acquire read lock on memory pool");
+                None
             }
         }
     }
 
     pub fn contains(&self, txid: &str) -> bool {
         match self.inner.read() {
-            Ok(pool) => pool.contains_key(txid),
+            Ok(pool) => pool.transactions.contains_key(txid),
             Err(_) => {
                 log::error!("Failed to acquire read lock on memory pool");
                 false
@@ -56,6 +564,9 @@ impl MemoryPool {
         match self.inner.write() {
             Ok(mut pool) => {
                 pool.remove(txid);
+                if let Ok(mut allowed) = self.high_fee_allowed.write() {
+                    allowed.remove(txid);
+                }
             }
             Err(_) => {
                 log::error!("Failed to acquire write lock on memory pool");
@@ -63,9 +574,107 @@ impl MemoryPool {
         }
     }
 
+    /// Remove the pending transaction `txid` because it can no longer be mined, announcing
+    /// that it left; false if it wasn't pending
+    ///
+    /// Transactions leaving because a block mined them go through `remove` instead, the
+    /// chain announces those as confirmed.
+    pub fn evict(&self, txid: &str, reason: EvictionReason) -> bool {
+        let removed = match self.inner.write() {
+            Ok(mut pool) => pool.remove(txid).is_some(),
+            Err(_) => {
+                log::error!("Failed to acquire write lock on memory pool");
+                return false;
+            }
+        };
+        if let Ok(mut allowed) = self.high_fee_allowed.write() {
+            allowed.remove(txid);
+        }
+        if removed {
+            self.event_bus().publish(ChainEvent::TxEvicted {
+                txid: txid.to_string(),
+                reason,
+            });
+        }
+        removed
+    }
+
+    /// Take the pending transaction `txid` out of the pool for good, returning it, or
+    /// `None` if it wasn't pending
+    ///
+    /// The outputs it spent are free for another transaction straight away. It is announced
+    /// as evicted and remembered, so the pool refuses it when a peer relays it again; only a
+    /// block can still confirm it. The pending transactions spending its outputs, which
+    /// can't be mined without it, are abandoned along with it.
+    pub fn abandon(&self, txid: &str) -> Option<Transaction> {
+        let (removed, descendants) = match self.inner.write() {
+            Ok(mut pool) => {
+                let descendants = pool.descendants(txid);
+                let removed = pool.remove(txid)?;
+                for id in &descendants {
+                    pool.remove(id);
+                }
+                (removed, descendants)
+            }
+            Err(_) => {
+                log::error!("Failed to acquire write lock on memory pool");
+                return None;
+            }
+        };
+        if let Ok(mut allowed) = self.high_fee_allowed.write() {
+            allowed.remove(txid);
+            for id in &descendants {
+                allowed.remove(id);
+            }
+        }
+        let events = self.event_bus();
+        for id in std::iter::once(txid.to_string()).chain(descendants) {
+            self.remember_abandoned(id.clone());
+            events.publish(ChainEvent::TxEvicted {
+                txid: id,
+                reason: EvictionReason::Abandoned,
+            });
+        }
+        Some(removed)
+    }
+
+    /// Whether `txid` was abandoned and is refused if offered again
+    pub fn is_abandoned(&self, txid: &str) -> bool {
+        match self.abandoned.read() {
+            Ok(abandoned) => abandoned.iter().any(|id| id == txid),
+            Err(_) => {
+                log::error!("Failed to acquire read lock on abandoned transactions");
+                false
+            }
+        }
+    }
+
+    /// The abandoned txids remembered, oldest first
+    pub fn abandoned(&self) -> Vec<String> {
+        match self.abandoned.read() {
+            Ok(abandoned) => abandoned.clone(),
+            Err(_) => {
+                log::error!("Failed to acquire read lock on abandoned transactions");
+                Vec::new()
+            }
+        }
+    }
+
+    fn remember_abandoned(&self, txid: String) {
+        match self.abandoned.write() {
+            Ok(mut abandoned) => {
+                abandoned.retain(|id| *id != txid);
+                abandoned.push(txid);
+                let excess = abandoned.len().saturating_sub(MAX_ABANDONED_TXS);
+                abandoned.drain(..excess);
+            }
+            Err(_) => log::error!("Failed to acquire write lock on abandoned transactions"),
+        }
+    }
+
     pub fn len(&self) -> usize {
         match self.inner.read() {
-            Ok(pool) => pool.len(),
+            Ok(pool) => pool.transactions.len(),
             Err(_) => {
                 log::error!("Failed to acquire read lock on memory pool");
                 0
@@ -75,7 +684,7 @@ impl MemoryPool {
 
     pub fn get_all(&self) -> Vec<Transaction> {
         match self.inner.read() {
-            Ok(pool) => pool.values().cloned().collect(),
+            Ok(pool) => pool.transactions.values().cloned().collect(),
             Err(_) => {
                 log::error!("Failed to acquire read lock on memory pool");
                 Vec::new()
@@ -83,10 +692,34 @@ impl MemoryPool {
         }
     }
 
+    /// The txids of the `limit` pending transactions paying the highest fee rates, best
+    /// first
+    pub fn txids_by_fee_rate(&self, limit: usize) -> Vec<Vec<u8>> {
+        let mut ranked: Vec<(usize, Transaction)> = self
+            .get_all()
+            .into_iter()
+            .map(|tx| (tx.serialize().map_or(usize::MAX, |bytes| bytes.len()), tx))
+            .collect();
+        // Comparing fee * size crosswise so rates stay exact, as `BlockAssembler` does
+        ranked.sort_by(|(a_size, a), (b_size, b)| {
+            (u128::from(b.get_fee().to_satoshis()) * *a_size as u128)
+                .cmp(&(u128::from(a.get_fee().to_satoshis()) * *b_size as u128))
+                .then_with(|| a.get_id().cmp(b.get_id()))
+        });
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(_, tx)| tx.get_id().to_vec())
+            .collect()
+    }
+
     pub fn clear(&self) {
         match self.inner.write() {
             Ok(mut pool) => {
                 pool.clear();
+                if let Ok(mut allowed) = self.high_fee_allowed.write() {
+                    allowed.clear();
+                }
             }
             Err(_) => {
                 log::error!("Failed to acquire write lock on memory pool");
@@ -96,7 +729,7 @@ impl MemoryPool {
 
     pub fn is_empty(&self) -> bool {
         match self.inner.read() {
-            Ok(pool) => pool.is_empty(),
+            Ok(pool) => pool.transactions.is_empty(),
             Err(_) => {
                 log::error!("Failed to acquire read lock on memory pool");
                 true // Conservative default
@@ -105,8 +738,45 @@ impl MemoryPool {
     }
 }
 
+/// Times a block is asked for without an answer in time before it is given up on
+pub const MAX_BLOCK_REQUEST_RETRIES: u32 = 3;
+
+// Where the download of one block stands
+struct BlockDownload {
+    hash: Vec<u8>,
+    // The peer it was asked from and when, `None` while it waits its turn
+    requested: Option<(String, Instant)>,
+    // The connection whose message it was asked for in answer to, which answers for it
+    requested_for: Option<SocketAddr>,
+    // Times it was asked for and not sent in time
+    retries: u32,
+    // The peer it last stalled at, which isn't offered it again
+    stalled_at: Option<String>,
+}
+
+/// A download `BlockInTransit::requeue_stalled` found overdue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StalledBlock {
+    pub hash: Vec<u8>,
+    /// The peer that was asked for it and didn't answer
+    pub peer: String,
+    /// The connection whose message it was asked for in answer to. `peer` is only what
+    /// that message claimed, so this is the one charged for the stall.
+    pub requested_for: SocketAddr,
+    /// Times it has stalled so far, this one included
+    pub retries: u32,
+    /// Whether it stalled more than `MAX_BLOCK_REQUEST_RETRIES` times and was given up on
+    pub dropped: bool,
+}
+
+/// The blocks a node is downloading, in the order they were announced
+///
+/// A block waits its turn until `next_to_request` hands it out for one peer, and leaves
+/// once `mark_received` says it arrived. One not received within a timeout is put back by
+/// `requeue_stalled` for another peer to be asked, up to `MAX_BLOCK_REQUEST_RETRIES`
+/// times.
 pub struct BlockInTransit {
-    inner: RwLock<Vec<Vec<u8>>>,
+    inner: RwLock<Vec<BlockDownload>>,
 }
 
 impl Default for BlockInTransit {
@@ -122,70 +792,797 @@ impl BlockInTransit {
         }
     }
 
+    /// Queue `blocks` for download, after the ones already waiting
+    ///
+    /// A hash already waiting or being downloaded keeps its place.
     pub fn add_blocks(&self, blocks: &[Vec<u8>]) {
-        match self.inner.write() {
-            Ok(mut inner) => {
-                for hash in blocks {
-                    inner.push(hash.to_vec());
-                }
-            }
-            Err(_) => {
-                log::error!("Failed to acquire write lock on block transit");
+        let mut inner = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for hash in blocks {
+            if !inner.iter().any(|download| download.hash == *hash) {
+                inner.push(BlockDownload {
+                    hash: hash.to_vec(),
+                    requested: None,
+                    requested_for: None,
+                    retries: 0,
+                    stalled_at: None,
+                });
             }
         }
     }
 
-    pub fn first(&self) -> Option<Vec<u8>> {
-        match self.inner.read() {
-            Ok(inner) => inner.first().map(|h| h.to_vec()),
-            Err(_) => {
-                log::error!("Failed to acquire read lock on block transit");
-                None
+    /// The first waiting block `peer` didn't already stall on, recorded as asked from it
+    /// at `now` for a message that came over `connection`
+    pub fn next_to_request(
+        &self,
+        peer: &str,
+        connection: SocketAddr,
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        let mut inner = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let download = inner.iter_mut().find(|download| {
+            download.requested.is_none() && download.stalled_at.as_deref() != Some(peer)
+        })?;
+        download.requested = Some((peer.to_string(), now));
+        download.requested_for = Some(connection);
+        Some(download.hash.clone())
+    }
+
+    /// Record `hash`, which must be waiting, as asked from `peer` at `now` for a message
+    /// that came over `connection`, even if it stalled there before; `false` if it isn't
+    /// waiting
+    pub fn request_from(
+        &self,
+        hash: &[u8],
+        peer: &str,
+        connection: SocketAddr,
+        now: Instant,
+    ) -> bool {
+        let mut inner = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match inner
+            .iter_mut()
+            .find(|download| download.hash == hash && download.requested.is_none())
+        {
+            Some(download) => {
+                download.requested = Some((peer.to_string(), now));
+                download.requested_for = Some(connection);
+                true
             }
+            None => false,
         }
     }
 
-    pub fn remove(&self, block_hash: &[u8]) {
-        match self.inner.write() {
-            Ok(mut inner) => {
-                if let Some(idx) = inner.iter().position(|x| x.eq(block_hash)) {
-                    inner.remove(idx);
-                }
-            }
-            Err(_) => {
-                log::error!("Failed to acquire write lock on block transit");
+    /// Forget `hash` now that it arrived, returning whether it was being downloaded
+    pub fn mark_received(&self, hash: &[u8]) -> bool {
+        let mut inner = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let before = inner.len();
+        inner.retain(|download| download.hash != hash);
+        inner.len() < before
+    }
+
+    /// Put every block asked for more than `timeout` before `now` back to wait for
+    /// another peer, or give up on those that stalled too often
+    pub fn requeue_stalled(&self, timeout: Duration, now: Instant) -> Vec<StalledBlock> {
+        let mut inner = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut stalled = Vec::new();
+        for download in inner.iter_mut() {
+            let (Some((peer, asked)), Some(requested_for)) =
+                (&download.requested, download.requested_for)
+            else {
+                continue;
+            };
+            if now.saturating_duration_since(*asked) < timeout {
+                continue;
             }
+            download.retries += 1;
+            stalled.push(StalledBlock {
+                hash: download.hash.clone(),
+                peer: peer.clone(),
+                requested_for,
+                retries: download.retries,
+                dropped: download.retries > MAX_BLOCK_REQUEST_RETRIES,
+            });
+            download.stalled_at = Some(peer.clone());
+            download.requested = None;
+            download.requested_for = None;
         }
+        inner.retain(|download| download.retries <= MAX_BLOCK_REQUEST_RETRIES);
+        stalled
+    }
+
+    /// The peer `hash` is being downloaded from, `None` if it waits or isn't queued
+    pub fn requested_from(&self, hash: &[u8]) -> Option<String> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .find(|download| download.hash == hash)
+            .and_then(|download| download.requested.as_ref())
+            .map(|(peer, _)| peer.clone())
     }
 
     pub fn clear(&self) {
-        match self.inner.write() {
-            Ok(mut inner) => {
-                inner.clear();
-            }
-            Err(_) => {
-                log::error!("Failed to acquire write lock on block transit");
-            }
-        }
+        self.inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
     }
 
     pub fn len(&self) -> usize {
-        match self.inner.read() {
-            Ok(inner) => inner.len(),
-            Err(_) => {
-                log::error!("Failed to acquire read lock on block transit");
-                0
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        Blockchain, FeeMode, FeePolicy, FeePriority, NodeContext, TXOutput, TransactionBuilder,
+        MIN_TRANSACTION_FEE,
+    };
+    use crate::storage::UTXOSet;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::Wallets;
+    use std::sync::MutexGuard;
+    use tempfile::{tempdir, TempDir};
+
+    // A funded sender, a recipient, and a pending payment between them. The guard keeps
+    // other tests off wallet.dat until this one is done signing.
+    fn setup() -> (MutexGuard<'static, ()>, TempDir, Blockchain, Transaction) {
+        let wallet_file_guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("test_blockchain");
+
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+
+        let blockchain =
+            Blockchain::create_blockchain_with_path(&sender, db_path.to_str().unwrap()).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex();
+
+        let tx = Transaction::new_utxo_transaction(&sender, &recipient, 1_000, &utxo_set, &wallets)
+            .unwrap();
+        (wallet_file_guard, temp_dir, blockchain, tx)
+    }
+
+    // A spend of `parent`'s first output paying `fee`, back to the same address
+    fn child_of(parent: &Transaction, fee: u64) -> Transaction {
+        let spent = parent.get_vout()[0].clone();
+        let wallets = Wallets::new();
+        let owner = wallets.get_wallet(&spent.get_address()).unwrap();
+        let value = spent.get_value().to_satoshis() - fee;
+        let mut child = Transaction::new_raw(
+            &[(parent.get_id().to_vec(), 0)],
+            vec![TXOutput::new(value, &spent.get_address()).unwrap()],
+        )
+        .unwrap();
+        child.sign_with_outputs(owner, &[spent]).unwrap();
+        child
+    }
+
+    #[test]
+    fn test_replacement_with_higher_fee_is_accepted() {
+        let (_guard, _temp_dir, blockchain, original) = setup();
+        let pool = MemoryPool::new();
+        pool.add_safe(original.clone()).unwrap();
+
+        let replacement = original
+            .bump_fee(
+                original.get_fee().to_satoshis() + 10,
+                &blockchain,
+                &Wallets::new(),
+            )
+            .unwrap();
+        assert!(replacement.conflicts_with(&original));
+
+        let replaced = pool.add_safe(replacement.clone()).unwrap();
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(replaced[0].get_id(), original.get_id());
+        assert_eq!(pool.len(), 1);
+        assert!(!pool.contains(&HEXLOWER.encode(original.get_id())));
+        assert!(pool.contains(&HEXLOWER.encode(replacement.get_id())));
+    }
+
+    #[test]
+    fn test_replacement_takes_the_descendants_of_what_it_replaces() {
+        let (_guard, _temp_dir, blockchain, original) = setup();
+        let pool = MemoryPool::new();
+        let child = child_of(&original, 10);
+        let grandchild = child_of(&child, 10);
+        for tx in [&original, &child, &grandchild] {
+            pool.add_safe(tx.clone()).unwrap();
+        }
+
+        // Outbidding the parent alone isn't enough, the fees of its descendants go too
+        let bump = |extra: u64| {
+            original
+                .bump_fee(
+                    original.get_fee().to_satoshis() + extra,
+                    &blockchain,
+                    &Wallets::new(),
+                )
+                .unwrap()
+        };
+        match pool.add_safe(bump(5)) {
+            Err(BlockchainError::ReplacementRejected { required_fee, .. }) => {
+                assert_eq!(required_fee, original.get_fee().to_satoshis() + 21);
             }
+            other => panic!("Expected ReplacementRejected, got {other:?}"),
         }
+        assert_eq!(pool.len(), 3);
+
+        let replacement = bump(21);
+        let replaced = pool.add_safe(replacement.clone()).unwrap();
+        assert_eq!(replaced.len(), 3);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&HEXLOWER.encode(replacement.get_id())));
+        let orphaned = &child.get_vin()[0];
+        assert_eq!(
+            pool.claimed_by(orphaned.get_txid(), orphaned.get_vout()),
+            None
+        );
+
+        // Nor can a transaction replace the parent it spends
+        let spends_what_it_replaces = Transaction::new_raw(
+            &[
+                (replacement.get_id().to_vec(), 0),
+                (
+                    original.get_vin()[0].get_txid().to_vec(),
+                    original.get_vin()[0].get_vout(),
+                ),
+            ],
+            child.get_vout().to_vec(),
+        )
+        .unwrap()
+        .with_fee(MAX_TRANSACTION_FEE);
+        assert!(matches!(
+            pool.add_safe(spends_what_it_replaces),
+            Err(BlockchainError::Transaction(msg)) if msg.contains("which it would replace")
+        ));
+        assert_eq!(pool.len(), 1);
     }
 
-    pub fn is_empty(&self) -> bool {
-        match self.inner.read() {
-            Ok(inner) => inner.is_empty(),
-            Err(_) => {
-                log::error!("Failed to acquire read lock on block transit");
-                true // Conservative default
+    #[test]
+    fn test_abandoning_a_parent_abandons_its_descendants() {
+        let (_guard, _temp_dir, _blockchain, original) = setup();
+        let pool = MemoryPool::new();
+        let child = child_of(&original, 10);
+        pool.add_safe(original.clone()).unwrap();
+        pool.add_safe(child.clone()).unwrap();
+        let (_, events) = pool.event_bus().subscribe(16);
+
+        let original_id = HEXLOWER.encode(original.get_id());
+        let child_id = HEXLOWER.encode(child.get_id());
+        assert_eq!(
+            pool.abandon(&original_id).unwrap().get_id(),
+            original.get_id()
+        );
+        assert!(pool.is_empty());
+        assert_eq!(
+            pool.abandoned(),
+            vec![original_id.clone(), child_id.clone()]
+        );
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                ChainEvent::TxEvicted {
+                    txid: original_id,
+                    reason: EvictionReason::Abandoned,
+                },
+                ChainEvent::TxEvicted {
+                    txid: child_id,
+                    reason: EvictionReason::Abandoned,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_abandoning_in_a_saved_pool_leaves_the_other_entries() {
+        let (_guard, _temp_dir, blockchain, original) = setup();
+        let child = child_of(&original, 10);
+        // Spends an output no chain has, so loading the pool would drop it
+        let stranger = Transaction::new_placeholder_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        let pool = MemoryPool::new();
+        for tx in [&original, &child, &stranger] {
+            pool.add_safe(tx.clone()).unwrap();
+        }
+        pool.save_to(blockchain.get_db()).unwrap();
+
+        let db = blockchain.get_db();
+        let [original_id, child_id, stranger_id] =
+            [&original, &child, &stranger].map(|tx| HEXLOWER.encode(tx.get_id()));
+        assert!(MemoryPool::abandon_saved(db, "00").unwrap().is_empty());
+        assert_eq!(
+            MemoryPool::abandon_saved(db, &original_id).unwrap(),
+            vec![original_id.clone(), child_id.clone()]
+        );
+        assert!(MemoryPool::saved_transaction(db, &original_id)
+            .unwrap()
+            .is_none());
+        assert!(MemoryPool::saved_transaction(db, &child_id)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            MemoryPool::saved_transaction(db, &stranger_id)
+                .unwrap()
+                .map(|tx| tx.get_id().to_vec()),
+            Some(stranger.get_id().to_vec())
+        );
+
+        let restarted = MemoryPool::new();
+        restarted
+            .load_from(&blockchain, &RelayFeePolicy::default())
+            .unwrap();
+        assert_eq!(restarted.abandoned(), vec![original_id, child_id]);
+    }
+
+    #[test]
+    fn test_abandoned_transaction_frees_its_inputs_and_stays_out() {
+        let (_guard, _temp_dir, blockchain, original) = setup();
+        let pool = MemoryPool::new();
+        let (_, events) = pool.event_bus().subscribe(16);
+        pool.add_safe(original.clone()).unwrap();
+        let original_id = HEXLOWER.encode(original.get_id());
+
+        let abandoned = pool.abandon(&original_id).unwrap();
+        assert_eq!(abandoned.get_id(), original.get_id());
+        assert!(pool.is_empty());
+        assert!(pool.is_abandoned(&original_id));
+        assert!(pool.abandon(&original_id).is_none());
+        let input = &original.get_vin()[0];
+        assert_eq!(pool.claimed_by(input.get_txid(), input.get_vout()), None);
+
+        // Its inputs go to another payment without the replacement rules getting involved
+        let respend = original
+            .bump_fee(
+                original.get_fee().to_satoshis() + 1,
+                &blockchain,
+                &Wallets::new(),
+            )
+            .unwrap();
+        assert!(pool.add_safe(respend.clone()).unwrap().is_empty());
+        assert!(pool.evict(
+            &HEXLOWER.encode(respend.get_id()),
+            EvictionReason::Invalidated
+        ));
+
+        // Relayed back by a peer, it is refused
+        assert!(matches!(
+            pool.add_safe(original.clone()),
+            Err(BlockchainError::TransactionAbandoned { txid }) if txid == original_id
+        ));
+        assert_eq!(
+            events.try_iter().nth(1),
+            Some(ChainEvent::TxEvicted {
+                txid: original_id.clone(),
+                reason: EvictionReason::Abandoned,
+            })
+        );
+
+        // A restarted node still knows
+        pool.save_to(blockchain.get_db()).unwrap();
+        let restarted = MemoryPool::new();
+        restarted
+            .load_from(&blockchain, &RelayFeePolicy::default())
+            .unwrap();
+        assert_eq!(restarted.abandoned(), vec![original_id]);
+        assert!(restarted.add_safe(original).is_err());
+    }
+
+    #[test]
+    fn test_only_the_latest_abandoned_are_remembered() {
+        let pool = MemoryPool::new();
+        for n in 0..=MAX_ABANDONED_TXS {
+            pool.remember_abandoned(n.to_string());
+        }
+        assert_eq!(pool.abandoned().len(), MAX_ABANDONED_TXS);
+        assert!(!pool.is_abandoned("0"));
+        assert!(pool.is_abandoned(&MAX_ABANDONED_TXS.to_string()));
+    }
+
+    #[test]
+    fn test_pool_announces_what_enters_and_leaves_it() {
+        let (_guard, _temp_dir, blockchain, original) = setup();
+        let pool = MemoryPool::new();
+        let (_, events) = pool.event_bus().subscribe(16);
+        pool.add_safe(original.clone()).unwrap();
+        // A relay of a transaction already pending is nothing new
+        pool.add_safe(original.clone()).unwrap();
+
+        let replacement = original
+            .bump_fee(
+                original.get_fee().to_satoshis() + 10,
+                &blockchain,
+                &Wallets::new(),
+            )
+            .unwrap();
+        pool.add_safe(replacement.clone()).unwrap();
+        let replacement_id = HEXLOWER.encode(replacement.get_id());
+        assert!(pool.evict(&replacement_id, EvictionReason::Invalidated));
+        assert!(!pool.evict(&replacement_id, EvictionReason::Invalidated));
+
+        let original_id = HEXLOWER.encode(original.get_id());
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                ChainEvent::TxAccepted {
+                    txid: original_id.clone()
+                },
+                ChainEvent::TxEvicted {
+                    txid: original_id,
+                    reason: EvictionReason::Replaced {
+                        by: replacement_id.clone()
+                    },
+                },
+                ChainEvent::TxAccepted {
+                    txid: replacement_id.clone()
+                },
+                ChainEvent::TxEvicted {
+                    txid: replacement_id,
+                    reason: EvictionReason::Invalidated,
+                },
+            ]
+        );
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_replacement_below_increment_is_rejected() {
+        let (_guard, _temp_dir, blockchain, original) = setup();
+        let pool = MemoryPool::with_min_replacement_fee_increment(100);
+        pool.add_safe(original.clone()).unwrap();
+
+        let replacement = original
+            .bump_fee(
+                original.get_fee().to_satoshis() + 50,
+                &blockchain,
+                &Wallets::new(),
+            )
+            .unwrap();
+
+        match pool.add_safe(replacement) {
+            Err(BlockchainError::ReplacementRejected {
+                required_fee,
+                offered_fee,
+                ..
+            }) => {
+                assert_eq!(required_fee, original.get_fee().to_satoshis() + 100);
+                assert_eq!(offered_fee, original.get_fee().to_satoshis() + 50);
             }
+            other => panic!("Expected ReplacementRejected, got {other:?}"),
+        }
+
+        // The original stays in the pool untouched
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&HEXLOWER.encode(original.get_id())));
+    }
+
+    #[test]
+    fn test_bump_fee_end_to_end() {
+        let (_guard, _temp_dir, blockchain, original) = setup();
+        let pool = MemoryPool::new();
+        pool.add_safe(original.clone()).unwrap();
+
+        let new_fee =
+            original.replacement_fee(FeePriority::High, pool.get_min_replacement_fee_increment());
+        assert!(new_fee > original.get_fee());
+
+        let replacement = original
+            .bump_fee(new_fee, &blockchain, &Wallets::new())
+            .unwrap();
+        assert_eq!(replacement.get_fee(), new_fee);
+        assert!(replacement.verify(&blockchain));
+        // The recipient is still paid the same amount
+        assert_eq!(
+            replacement.get_vout()[0].get_value(),
+            original.get_vout()[0].get_value()
+        );
+
+        pool.add_safe(replacement).unwrap();
+        let pending = pool.get_all();
+        assert_eq!(pending.len(), 1);
+
+        let block = blockchain
+            .mine_block_with_fees(&pending, "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")
+            .unwrap();
+        assert_eq!(block.get_transactions()[1].get_fee(), new_fee);
+    }
+
+    #[test]
+    fn test_second_claim_on_an_outpoint_is_refused() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let sender = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &sender,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        let utxo_set = UTXOSet::new(blockchain);
+        utxo_set.reindex();
+
+        // Both spend the genesis coinbase, the only coin there is
+        let recipient = wallets.create_wallet().unwrap();
+        let pay =
+            |to: &str| Transaction::new_utxo_transaction(&sender, to, 1_000, &utxo_set, &wallets);
+        let first = pay(&recipient).unwrap();
+        let second = pay("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        assert!(second.conflicts_with(&first));
+        assert_eq!(second.get_fee(), first.get_fee());
+
+        let pool = MemoryPool::new();
+        pool.add_safe(first.clone()).unwrap();
+        assert!(matches!(
+            pool.add_safe(second.clone()),
+            Err(BlockchainError::ReplacementRejected { .. })
+        ));
+        let input = &first.get_vin()[0];
+        let first_id = HEXLOWER.encode(first.get_id());
+        assert_eq!(
+            pool.claimed_by(input.get_txid(), input.get_vout()),
+            Some(first_id.clone())
+        );
+
+        // The claim goes with the transaction holding it
+        pool.remove(&first_id);
+        assert_eq!(pool.claimed_by(input.get_txid(), input.get_vout()), None);
+        pool.add_safe(second.clone()).unwrap();
+        assert_eq!(
+            pool.claimed_by(input.get_txid(), input.get_vout()),
+            Some(HEXLOWER.encode(second.get_id()))
+        );
+    }
+
+    #[test]
+    fn test_non_conflicting_transactions_coexist() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let senders: Vec<String> = (0..2).map(|_| wallets.create_wallet().unwrap()).collect();
+        let recipient = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &senders[0],
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        blockchain
+            .generate_blocks(1, &senders[1], &context)
+            .unwrap();
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let first =
+            Transaction::new_utxo_transaction(&senders[0], &recipient, 1_000, &utxo_set, &wallets)
+                .unwrap();
+        let second =
+            Transaction::new_utxo_transaction(&senders[1], &recipient, 1_000, &utxo_set, &wallets)
+                .unwrap();
+        assert!(!first.conflicts_with(&second));
+
+        let pool = MemoryPool::new();
+        pool.add_safe(first).unwrap();
+        pool.add_safe(second).unwrap();
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn test_coinbase_transactions_are_refused() {
+        let pool = MemoryPool::new();
+        let coinbase = Transaction::new_coinbase_tx("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        assert!(matches!(
+            pool.add_safe(coinbase.clone()),
+            Err(BlockchainError::CoinbaseNotAllowed(_))
+        ));
+        assert!(matches!(
+            pool.add_allowing_high_fee(coinbase),
+            Err(BlockchainError::CoinbaseNotAllowed(_))
+        ));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_high_fee_needs_the_sender_to_insist() {
+        let (_guard, _temp_dir, _blockchain, original) = setup();
+        let expensive = original.clone().with_fee(MAX_TRANSACTION_FEE + 1);
+        let txid = HEXLOWER.encode(expensive.get_id());
+
+        let pool = MemoryPool::new();
+        assert!(matches!(
+            pool.add_safe(expensive.clone()),
+            Err(BlockchainError::FeeTooHigh { fee, max_fee: MAX_TRANSACTION_FEE })
+                if fee == MAX_TRANSACTION_FEE + 1
+        ));
+        assert!(pool.is_empty());
+
+        pool.add_allowing_high_fee(expensive).unwrap();
+        assert!(pool.contains(&txid));
+        assert!(pool.allows_high_fee(&txid));
+
+        // The exception leaves with the transaction
+        pool.remove(&txid);
+        assert!(!pool.allows_high_fee(&txid));
+        pool.add_allowing_high_fee(original).unwrap();
+        assert!(!pool.allows_high_fee(&txid));
+    }
+
+    #[test]
+    fn test_saved_pool_reloads_only_what_is_still_valid() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let senders: Vec<String> = (0..3).map(|_| wallets.create_wallet().unwrap()).collect();
+        let recipient = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &senders[0],
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        for sender in &senders[1..] {
+            blockchain.generate_blocks(1, sender, &context).unwrap();
+        }
+
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let payments: Vec<Transaction> = senders
+            .iter()
+            .map(|sender| {
+                // A fee of its own, whatever fee mode other tests leave behind
+                TransactionBuilder::new(&utxo_set)
+                    .from(sender)
+                    .add_output(&recipient, 1_000)
+                    .fee_policy(FeePolicy::Explicit(MIN_TRANSACTION_FEE.into()))
+                    .build_and_sign(&wallets)
+                    .unwrap()
+            })
+            .collect();
+        let pool = MemoryPool::new();
+        for tx in &payments {
+            pool.add_safe(tx.clone()).unwrap();
+        }
+        let txids: Vec<String> = payments
+            .iter()
+            .map(|tx| HEXLOWER.encode(tx.get_id()))
+            .collect();
+        let received_at = pool.received_at(&txids[2]).unwrap();
+        assert_eq!(pool.save_to(blockchain.get_db()).unwrap(), 3);
+
+        // While the node is down, the first payment is mined by someone else
+        context.memory_pool().add(payments[0].clone());
+        blockchain.generate_blocks(1, &recipient, &context).unwrap();
+
+        let restarted = MemoryPool::new();
+        assert_eq!(
+            restarted
+                .load_from(&blockchain, &RelayFeePolicy::default())
+                .unwrap(),
+            2
+        );
+        assert_eq!(restarted.len(), 2);
+        assert!(!restarted.contains(&txids[0]));
+        assert!(restarted.contains(&txids[1]) && restarted.contains(&txids[2]));
+        assert_eq!(restarted.received_at(&txids[2]), Some(received_at));
+
+        // Nor do the ones the relay policy would refuse now
+        let strict = RelayFeePolicy {
+            min_fee_rate: MAX_TRANSACTION_FEE,
+            ..RelayFeePolicy::default()
+        };
+        assert_eq!(
+            MemoryPool::new().load_from(&blockchain, &strict).unwrap(),
+            0
+        );
+
+        // A database that never saved a pool has nothing to restore
+        let other_dir = tempdir().unwrap();
+        let fresh = Blockchain::create_blockchain_with_path(
+            &senders[0],
+            other_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            MemoryPool::new()
+                .load_from(&fresh, &RelayFeePolicy::default())
+                .unwrap(),
+            0
+        );
+    }
+
+    // The connection a peer's message came over, on a port of its own
+    fn connection(port: u32) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 40_000 + port as u16))
+    }
+
+    #[test]
+    fn test_block_downloads_are_handed_out_once() {
+        let transit = BlockInTransit::new();
+        let now = Instant::now();
+        let hashes = vec![b"a".to_vec(), b"b".to_vec()];
+        transit.add_blocks(&hashes);
+
+        assert_eq!(
+            transit.next_to_request("peer-1", connection(1), now),
+            Some(hashes[0].clone())
+        );
+        // Announced again while in flight, it isn't queued a second time
+        transit.add_blocks(&hashes);
+        assert_eq!(transit.len(), 2);
+        assert_eq!(
+            transit.next_to_request("peer-2", connection(2), now),
+            Some(hashes[1].clone())
+        );
+        assert_eq!(transit.next_to_request("peer-2", connection(2), now), None);
+
+        assert!(transit.mark_received(&hashes[0]));
+        assert!(!transit.mark_received(&hashes[0]));
+        assert_eq!(transit.requested_from(&hashes[0]), None);
+        assert_eq!(transit.len(), 1);
+    }
+
+    #[test]
+    fn test_stalled_block_is_dropped_after_the_last_retry() {
+        let transit = BlockInTransit::new();
+        let timeout = Duration::from_secs(30);
+        let mut now = Instant::now();
+        let hash = b"stuck".to_vec();
+        transit.add_blocks(std::slice::from_ref(&hash));
+
+        for retry in 1..=MAX_BLOCK_REQUEST_RETRIES {
+            let peer = format!("peer-{retry}");
+            assert_eq!(
+                transit.next_to_request(&peer, connection(retry), now),
+                Some(hash.clone())
+            );
+            assert!(transit.requeue_stalled(timeout, now).is_empty());
+            now += timeout;
+            let stalled = transit.requeue_stalled(timeout, now);
+            assert_eq!(
+                stalled,
+                vec![StalledBlock {
+                    hash: hash.clone(),
+                    peer: peer.clone(),
+                    requested_for: connection(retry),
+                    retries: retry,
+                    dropped: false,
+                }]
+            );
+            // The peer that let it stall isn't offered it again
+            assert_eq!(transit.next_to_request(&peer, connection(retry), now), None);
         }
+
+        assert!(transit.request_from(&hash, "peer-1", connection(1), now));
+        now += timeout;
+        let stalled = transit.requeue_stalled(timeout, now);
+        assert!(stalled[0].dropped);
+        assert!(transit.is_empty());
     }
 }
@@ -4,13 +4,23 @@
 //! memory pools for pending transactions, blockchain data storage,
 //! and encrypted storage capabilities.
 
+pub mod chain_file;
 pub mod encrypted;
 pub mod memory_pool;
+pub mod snapshot;
 pub mod utxo_set;
 
+pub use chain_file::{export_chain, import_chain, ChainFileHeader};
 pub use encrypted::{EncryptedWallets, WalletEncryptionConfig, WalletEncryptionSettings};
-pub use memory_pool::{BlockInTransit, MemoryPool};
-pub use utxo_set::UTXOSet;
+pub use memory_pool::{
+    BlockInTransit, MemoryPool, StalledBlock, DEFAULT_MIN_REPLACEMENT_FEE_INCREMENT,
+    MAX_BLOCK_REQUEST_RETRIES, MEMPOOL_TREE,
+};
+pub use snapshot::{SnapshotMeta, SNAPSHOT_MAGIC, SNAPSHOT_VERSION};
+pub use utxo_set::{
+    is_chainstate_inconsistent, is_chainstate_out_of_order, AddressBalance, AppliedBlock, UTXOSet,
+    UnspentOutput, CHAINSTATE_INCONSISTENT, CHAINSTATE_OUT_OF_ORDER, CHAINSTATE_VERSION,
+};
 
 use once_cell::sync::Lazy;
 
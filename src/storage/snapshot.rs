@@ -0,0 +1,538 @@
+//! Chainstate snapshot files
+//!
+//! A snapshot carries what a node needs to start at a given height without replaying the
+//! blocks below it: the main chain's headers from genesis up to the tip's parent, the tip
+//! block itself and every chainstate entry, each with the transaction its outputs come
+//! from. The file is a header record, written like a chain file's as a 4-byte
+//! little-endian length followed by bincode, and then the body, which runs to the end of
+//! the file. The header holds the sha256 of the body bytes, so a damaged file is caught
+//! before anything in it is used.
+//!
+//! The hash only catches damage. A snapshot's outputs are taken on trust, like the
+//! person handing it over; what can be checked is that its headers link up from this
+//! chain's genesis block with valid proof of work at the difficulty they retarget to and
+//! agree with every checkpoint.
+
+use crate::core::{Block, BlockHeader, Blockchain, ProofOfWork, Transaction, MEDIAN_TIME_SPAN};
+use crate::error::{BlockchainError, Result};
+use crate::storage::utxo_set::{
+    chainstate_entries, decode_entry, ChainstateEntry, CHAINSTATE_VERSION, UTXO_TREE,
+};
+use crate::storage::UTXOSet;
+use crate::utils::{deserialize, serialize, sha256_digest};
+use data_encoding::HEXLOWER;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Magic bytes identifying an architect-chain snapshot file
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"ACSS";
+/// Current version of the snapshot file layout
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Upper bound for the header record, so a corrupt length can't trigger a huge allocation
+const MAX_HEADER_SIZE: usize = 4_096;
+
+/// What a snapshot file holds, as `exportsnapshot` reports it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct SnapshotMeta {
+    pub tip_hash: String,
+    pub height: usize,
+    /// Transactions with unspent outputs, one chainstate entry each
+    pub transactions: u64,
+    /// Hex sha256 of the body, see the module documentation
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct SnapshotHeader {
+    magic: [u8; 4],
+    version: u32,
+    // The entries are in this chainstate layout, see `UTXOSet::upgrade`
+    chainstate_version: u8,
+    meta: SnapshotMeta,
+}
+
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct SnapshotBody {
+    // Main-chain headers from genesis up to the tip's parent
+    headers: Vec<BlockHeader>,
+    // The tip block in full, as `Block::serialize` writes it
+    tip: Vec<u8>,
+    // Sorted by txid, the order the chainstate iterates in
+    entries: Vec<SnapshotEntry>,
+}
+
+/// A chainstate entry with the transaction its outputs come from and the block holding it
+#[derive(Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub(crate) struct SnapshotEntry {
+    pub txid: Vec<u8>,
+    pub outputs: ChainstateEntry,
+    pub block_hash: String,
+    pub transaction: Transaction,
+}
+
+/// A snapshot file that passed every check `read_snapshot` makes
+pub(crate) struct VerifiedSnapshot {
+    pub meta: SnapshotMeta,
+    pub headers: Vec<BlockHeader>,
+    pub tip: Block,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// Write `blockchain`'s chainstate at its current tip to a snapshot file at `path`
+///
+/// The chainstate has to have caught up with the tip, as it has whenever no block is
+/// being connected. A pruned chain can be snapshotted too, since its headers and the
+/// transactions of its unspent outputs are all still there.
+pub(crate) fn write_snapshot(path: &Path, blockchain: &Blockchain) -> Result<SnapshotMeta> {
+    let tip_hash = blockchain.get_tip_hash();
+    let tip = blockchain
+        .get_block(&tip_hash)?
+        .ok_or_else(|| BlockchainError::Database(format!("Tip block {tip_hash} not found")))?;
+    if tip.get_height() == 0 {
+        return Err(BlockchainError::Config(
+            "The chain only has its genesis block, there is nothing to snapshot".to_string(),
+        ));
+    }
+
+    let mut headers = Vec::with_capacity(tip.get_height());
+    for height in 0..tip.get_height() {
+        let block = blockchain.get_block_at_height(height)?.ok_or_else(|| {
+            BlockchainError::Database(format!("No main-chain block at height {height}"))
+        })?;
+        headers.push(block.get_header());
+    }
+
+    let utxo_tree = blockchain
+        .get_db()
+        .open_tree(UTXO_TREE)
+        .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
+    if chainstate_entries(&utxo_tree).next().is_none() {
+        return Err(BlockchainError::Database(
+            "The chainstate hasn't been built yet, run reindexutxo first".to_string(),
+        ));
+    }
+    if let Some(applied) = UTXOSet::new(blockchain.clone()).last_applied()? {
+        if applied.hash != tip_hash {
+            return Err(BlockchainError::Database(format!(
+                "The chainstate is at block {} (height {}), not at the tip {tip_hash}",
+                applied.hash, applied.height
+            )));
+        }
+    }
+    let mut entries = Vec::with_capacity(utxo_tree.len());
+    for item in chainstate_entries(&utxo_tree) {
+        let (txid, bytes) = item
+            .map_err(|e| BlockchainError::Database(format!("Failed to iterate UTXO tree: {e}")))?;
+        let missing = || {
+            BlockchainError::Database(format!(
+                "Chainstate entry {} has no indexed transaction",
+                HEXLOWER.encode(&txid)
+            ))
+        };
+        entries.push(SnapshotEntry {
+            txid: txid.to_vec(),
+            outputs: decode_entry(&bytes)?,
+            block_hash: blockchain
+                .find_transaction_block(&txid)?
+                .ok_or_else(missing)?,
+            transaction: blockchain.find_transaction(&txid)?.ok_or_else(missing)?,
+        });
+    }
+
+    let transactions = entries.len() as u64;
+    let body = serialize(&SnapshotBody {
+        headers,
+        tip: tip.serialize()?,
+        entries,
+    })?;
+    let meta = SnapshotMeta {
+        tip_hash,
+        height: tip.get_height(),
+        transactions,
+        hash: HEXLOWER.encode(&sha256_digest(&body)),
+    };
+    let header = serialize(&SnapshotHeader {
+        magic: SNAPSHOT_MAGIC,
+        version: SNAPSHOT_VERSION,
+        chainstate_version: CHAINSTATE_VERSION,
+        meta: meta.clone(),
+    })?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(&header)?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    info!(
+        "Exported a snapshot at height {} to {} ({} transactions with unspent outputs)",
+        meta.height,
+        path.display(),
+        meta.transactions
+    );
+    Ok(meta)
+}
+
+/// Read the snapshot file at `path` and check it can be loaded into `blockchain`
+///
+/// Besides the hash, its headers have to link up from `blockchain`'s genesis block with
+/// valid proof of work, timestamps and the difficulty `blockchain`'s retarget rules give
+/// them, agree with `blockchain`'s checkpoints and with every main-chain block it already
+/// has, and its tip block has to pass full validation.
+pub(crate) fn read_snapshot(path: &Path, blockchain: &Blockchain) -> Result<VerifiedSnapshot> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut len_bytes = [0u8; 4];
+    reader
+        .read_exact(&mut len_bytes)
+        .map_err(|e| BlockchainError::Serialization(format!("Snapshot file is too short: {e}")))?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > MAX_HEADER_SIZE {
+        return Err(BlockchainError::Serialization(format!(
+            "Snapshot header length {len} exceeds maximum of {MAX_HEADER_SIZE} bytes"
+        )));
+    }
+    let mut header_bytes = vec![0u8; len];
+    reader.read_exact(&mut header_bytes).map_err(|e| {
+        BlockchainError::Serialization(format!("Snapshot file truncated in its header: {e}"))
+    })?;
+    let header: SnapshotHeader = deserialize(&header_bytes)?;
+
+    if header.magic != SNAPSHOT_MAGIC {
+        return Err(BlockchainError::Serialization(
+            "Not an architect-chain snapshot file (bad magic)".to_string(),
+        ));
+    }
+    if header.version != SNAPSHOT_VERSION {
+        return Err(BlockchainError::Serialization(format!(
+            "Unsupported snapshot version {} (expected {SNAPSHOT_VERSION})",
+            header.version
+        )));
+    }
+    if header.chainstate_version != CHAINSTATE_VERSION {
+        return Err(BlockchainError::Serialization(format!(
+            "Snapshot chainstate is in version {}, this node keeps version {CHAINSTATE_VERSION}",
+            header.chainstate_version
+        )));
+    }
+
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    let hash = HEXLOWER.encode(&sha256_digest(&body));
+    if hash != header.meta.hash {
+        return Err(BlockchainError::Serialization(format!(
+            "Snapshot hash mismatch: the file says {} but its contents hash to {hash}; \
+             the file is damaged",
+            header.meta.hash
+        )));
+    }
+    let body: SnapshotBody = deserialize(&body)?;
+    let tip = Block::deserialize(&body.tip)?;
+    let meta = header.meta;
+    if tip.get_hash() != meta.tip_hash || tip.get_height() != meta.height {
+        return Err(BlockchainError::Serialization(format!(
+            "Snapshot tip {} at height {} is not the {} at height {} its header names",
+            tip.get_hash(),
+            tip.get_height(),
+            meta.tip_hash,
+            meta.height
+        )));
+    }
+    if body.headers.len() != meta.height || body.entries.len() as u64 != meta.transactions {
+        return Err(BlockchainError::Serialization(
+            "Snapshot contents don't match the counts in its header".to_string(),
+        ));
+    }
+
+    check_headers(blockchain, &body.headers, &tip)?;
+    check_entries(&body.headers, &tip, &body.entries)?;
+    Ok(VerifiedSnapshot {
+        meta,
+        headers: body.headers,
+        tip,
+        entries: body.entries,
+    })
+}
+
+// The headers and the tip form one chain from our genesis block, agreeing with our
+// checkpoints and our own main chain as far as it goes
+fn check_headers(blockchain: &Blockchain, headers: &[BlockHeader], tip: &Block) -> Result<()> {
+    let hash_at = |height: usize| {
+        headers
+            .get(height)
+            .map_or(tip.get_hash(), |header| header.hash.as_str())
+    };
+
+    let genesis = blockchain.get_genesis_hash()?;
+    if hash_at(0) != genesis {
+        return Err(BlockchainError::DifferentNetwork {
+            genesis: hash_at(0).to_string(),
+            expected: genesis,
+        });
+    }
+    let our_height = blockchain.get_best_height()?;
+    if our_height > tip.get_height() {
+        return Err(BlockchainError::Config(format!(
+            "This chain is already at height {our_height}, past the snapshot's {}",
+            tip.get_height()
+        )));
+    }
+    for height in 1..=our_height {
+        if blockchain.get_block_hash_at_height(height)?.as_deref() != Some(hash_at(height)) {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Snapshot block at height {height} differs from this chain's"
+            )));
+        }
+    }
+    for (height, hash) in blockchain.get_checkpoints()? {
+        if height <= tip.get_height() && hash_at(height) != hash {
+            return Err(BlockchainError::InvalidBlock(format!(
+                "Snapshot block at height {height} contradicts the checkpoint {hash}"
+            )));
+        }
+    }
+
+    let mut timestamps: Vec<i64> = Vec::with_capacity(headers.len());
+    let blocks: Vec<Block> = headers
+        .iter()
+        .map(|header| Block::from_pruned_header(header.clone()))
+        .chain(std::iter::once(tip.clone()))
+        .collect();
+    for (height, block) in blocks.iter().enumerate() {
+        let invalid = |reason: &str| {
+            Err(BlockchainError::InvalidBlock(format!(
+                "Snapshot block {} at height {height} {reason}",
+                block.get_hash()
+            )))
+        };
+        if block.get_height() != height {
+            return invalid(&format!("claims height {}", block.get_height()));
+        }
+        if height > 0 && block.get_pre_block_hash() != hash_at(height - 1) {
+            return invalid("does not link to the block before it");
+        }
+        if ProofOfWork::compute_hash(block) != block.get_hash() {
+            return invalid("is not stored under its own hash");
+        }
+        if height > 0 {
+            let expected = blockchain.calculate_next_difficulty_after(&blocks[..height])?;
+            if block.get_difficulty() != expected {
+                return invalid(&format!(
+                    "has difficulty {}, not the {expected} the blocks below it retarget to",
+                    block.get_difficulty()
+                ));
+            }
+        }
+
+        let median_time_past = (height > 0).then(|| {
+            let mut recent = timestamps[height.saturating_sub(MEDIAN_TIME_SPAN)..].to_vec();
+            recent.sort_unstable();
+            recent[recent.len() / 2]
+        });
+        let valid = if block.is_pruned() {
+            block.validate_pruned_header(median_time_past)?
+        } else {
+            block.validate_block(median_time_past)?
+        };
+        if !valid {
+            return invalid("failed validation");
+        }
+        timestamps.push(block.get_timestamp());
+    }
+    Ok(())
+}
+
+// Every entry is one main-chain transaction's outputs, each as the transaction has it
+fn check_entries(headers: &[BlockHeader], tip: &Block, entries: &[SnapshotEntry]) -> Result<()> {
+    let main_chain: HashSet<&str> = headers
+        .iter()
+        .map(|header| header.hash.as_str())
+        .chain(std::iter::once(tip.get_hash()))
+        .collect();
+
+    let mut previous: Option<&[u8]> = None;
+    for entry in entries {
+        let txid = HEXLOWER.encode(&entry.txid);
+        let invalid = |reason: &str| {
+            Err(BlockchainError::Serialization(format!(
+                "Snapshot entry {txid} {reason}"
+            )))
+        };
+        if previous.is_some_and(|previous| previous >= entry.txid.as_slice()) {
+            return invalid("is out of order");
+        }
+        previous = Some(&entry.txid);
+
+        if entry.transaction.get_id() != entry.txid.as_slice() {
+            return invalid("holds another transaction");
+        }
+        if !main_chain.contains(entry.block_hash.as_str()) {
+            return invalid("names a block off the snapshot's chain");
+        }
+        if entry.outputs.is_empty() {
+            return invalid("has no outputs");
+        }
+        for (vout, output) in &entry.outputs {
+            let matches = entry
+                .transaction
+                .get_vout()
+                .get(*vout)
+                .is_some_and(|source| serialize(source).ok() == serialize(output).ok());
+            if !matches {
+                return invalid(&format!("output {vout} differs from its transaction"));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{BlockAddResult, FeeMode, NodeContext};
+    use crate::storage::UTXOSet;
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::{address_pub_key_hash, Wallets};
+    use tempfile::{tempdir, TempDir};
+
+    fn balance_of(blockchain: &Blockchain, address: &str) -> u64 {
+        UTXOSet::new(blockchain.clone())
+            .find_utxo_safe(&address_pub_key_hash(address).unwrap())
+            .unwrap()
+            .iter()
+            .map(|out| out.get_value().to_satoshis())
+            .sum()
+    }
+
+    // A chain at height 30 whose second owner was paid enough to pay on at height 2 and
+    // whose third owner got every other block reward, and a fresh database holding only
+    // its genesis block
+    fn source_and_target(temp_dir: &TempDir) -> (Blockchain, Blockchain, Vec<String>) {
+        let mut wallets = Wallets::new();
+        let owners: Vec<String> = (0..3).map(|_| wallets.create_wallet().unwrap()).collect();
+        let source = Blockchain::create_blockchain_with_path(
+            &owners[0],
+            temp_dir.path().join("source").to_str().unwrap(),
+        )
+        .unwrap();
+        source.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        source.generate_blocks(1, &owners[2], &context).unwrap();
+        let utxo_set = UTXOSet::new(source.clone());
+        let payment =
+            Transaction::new_utxo_transaction(&owners[0], &owners[1], 3_000, &utxo_set, &wallets)
+                .unwrap();
+        let block = source
+            .mine_block_with_context(&[payment], &owners[2], &context)
+            .unwrap();
+        utxo_set.update_safe(&block).unwrap();
+        source.generate_blocks(28, &owners[2], &context).unwrap();
+        assert_eq!(source.get_best_height().unwrap(), 30);
+
+        let genesis = source.get_block_at_height(0).unwrap().unwrap();
+        let target = Blockchain::create_blockchain_from_genesis(
+            &genesis,
+            temp_dir.path().join("target").to_str().unwrap(),
+            false,
+        )
+        .unwrap();
+        target.set_force_difficulty(Some(1));
+        (source, target, owners)
+    }
+
+    #[test]
+    fn test_snapshot_starts_a_fresh_chain_at_its_tip() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let (source, target, owners) = source_and_target(&temp_dir);
+        let path = temp_dir.path().join("chain.snapshot");
+
+        let meta = UTXOSet::export_snapshot(&path, &source).unwrap();
+        assert_eq!(meta.height, 30);
+        assert_eq!(meta.tip_hash, source.get_tip_hash());
+        UTXOSet::import_snapshot(&path, &target).unwrap();
+
+        assert_eq!(target.get_tip_hash(), source.get_tip_hash());
+        assert_eq!(target.get_best_height().unwrap(), 30);
+        assert_eq!(target.get_snapshot_height().unwrap(), Some(30));
+        for owner in &owners {
+            assert_eq!(balance_of(&target, owner), balance_of(&source, owner));
+        }
+        assert!(matches!(
+            target.verify_chain(3),
+            Err(BlockchainError::Pruned(_))
+        ));
+        assert!(target.verify_chain(2).unwrap().is_ok());
+
+        // Blocks from the source chain connect on top of the snapshot
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        let next = source.generate_blocks(1, &owners[2], &context).unwrap();
+        assert_eq!(
+            target.validate_and_add_block(&next[0]).unwrap(),
+            BlockAddResult::TipAdvanced
+        );
+        let utxo_set = UTXOSet::new(target.clone());
+        utxo_set.update_safe(&next[0]).unwrap();
+
+        // And the output paid below the snapshot can be spent in a block mined here
+        let spend = Transaction::new_utxo_transaction(
+            &owners[1],
+            &owners[0],
+            400,
+            &utxo_set,
+            &Wallets::new(),
+        )
+        .unwrap();
+        let block = target
+            .mine_block_with_context(&[spend], &owners[2], &context)
+            .unwrap();
+        utxo_set.update_safe(&block).unwrap();
+        assert_eq!(target.get_best_height().unwrap(), 32);
+        assert_eq!(
+            balance_of(&target, &owners[0]),
+            balance_of(&source, &owners[0]) + 400
+        );
+    }
+
+    #[test]
+    fn test_damaged_snapshot_is_refused_by_its_hash() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let (source, target, _owners) = source_and_target(&temp_dir);
+        let path = temp_dir.path().join("chain.snapshot");
+        UTXOSet::export_snapshot(&path, &source).unwrap();
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 10;
+        bytes[last] ^= 0x01;
+        std::fs::write(&path, bytes).unwrap();
+
+        let error = UTXOSet::import_snapshot(&path, &target).unwrap_err();
+        assert!(error.to_string().contains("hash mismatch"), "{error}");
+        assert_eq!(target.get_best_height().unwrap(), 0);
+        assert_eq!(target.get_snapshot_height().unwrap(), None);
+    }
+
+    #[test]
+    fn test_snapshot_off_this_chains_difficulty_is_refused() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let (source, target, _owners) = source_and_target(&temp_dir);
+        let path = temp_dir.path().join("chain.snapshot");
+        UTXOSet::export_snapshot(&path, &source).unwrap();
+
+        // Headers mined at a difficulty this chain wouldn't have retargeted to
+        target.set_force_difficulty(Some(2));
+        let error = UTXOSet::import_snapshot(&path, &target).unwrap_err();
+        assert!(error.to_string().contains("retarget to"), "{error}");
+        assert_eq!(target.get_best_height().unwrap(), 0);
+        assert_eq!(target.get_snapshot_height().unwrap(), None);
+        assert!(UTXOSet::new(target.clone())
+            .last_applied()
+            .unwrap()
+            .is_none());
+    }
+}
@@ -1,15 +1,135 @@
-use crate::core::{Block, Blockchain, TXOutput};
+use crate::core::{Amount, Block, Blockchain, TXOutput, Transaction, COINBASE_MATURITY_WINDOW};
 use crate::error::{BlockchainError, Result};
+use crate::network::METRICS;
+use crate::storage::snapshot::{self, SnapshotMeta};
+use crate::storage::MemoryPool;
 use crate::utils::{deserialize, serialize};
 use data_encoding::HEXLOWER;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
-const UTXO_TREE: &str = "chainstate";
+pub(crate) const UTXO_TREE: &str = "chainstate";
+/// Layout of the chainstate's entries; a chainstate recorded with another one is rebuilt
+/// when its database is opened, see `UTXOSet::upgrade`
+///
+/// Version 1 kept a transaction's unspent outputs without their indices, so they could
+/// no longer be told apart once one was spent. Version 2 keeps `(vout, TXOutput)` pairs.
+pub const CHAINSTATE_VERSION: u8 = 2;
+// Key in the database's default tree holding the chainstate's version
+pub(crate) const CHAINSTATE_VERSION_KEY: &str = "chainstate_version";
+/// Start of the error `UTXOSet::update_safe` gives when the chainstate can't be repaired
+/// without a reindex
+pub const CHAINSTATE_INCONSISTENT: &str = "chainstate inconsistent at";
+/// Start of the error `UTXOSet::update_safe` gives for a block that doesn't build on the
+/// last one applied to the chainstate
+pub const CHAINSTATE_OUT_OF_ORDER: &str = "chainstate out of order:";
+// Key in the chainstate tree holding its `AppliedBlock`; txids are 32 bytes long, so no
+// entry can take it
+const LAST_APPLIED_KEY: &[u8] = b"last_applied_block";
 
 pub struct UTXOSet {
     blockchain: Blockchain,
 }
 
+/// The block the chainstate was last brought up to
+///
+/// It is kept in the chainstate tree, written in the same batch as the entries, so the two
+/// can't disagree after a crash. A chainstate built before it existed has none until the
+/// next block is applied or it is reindexed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct AppliedBlock {
+    pub hash: String,
+    pub height: usize,
+}
+
+impl AppliedBlock {
+    fn of(block: &Block) -> AppliedBlock {
+        AppliedBlock {
+            hash: block.get_hash().to_string(),
+            height: block.get_height(),
+        }
+    }
+}
+
+/// An address's confirmed balance and what pending transactions are about to change it
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub struct AddressBalance {
+    pub confirmed: Amount,
+    /// Paid to the address by pending transactions, including change
+    pub pending_incoming: Amount,
+    /// Confirmed outputs of the address that pending transactions spend
+    pub pending_outgoing: Amount,
+}
+
+impl AddressBalance {
+    /// What the address can still spend without waiting for a block
+    pub fn available(&self) -> Amount {
+        self.confirmed.saturating_sub(self.pending_outgoing)
+    }
+}
+
+/// An unspent output with where it sits on the chain, as `listunspent` shows it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnspentOutput {
+    pub txid_hex: String,
+    pub vout: usize,
+    pub value: Amount,
+    /// 1 in the tip's block, one more for every block on top
+    pub confirmations: u64,
+    pub is_coinbase: bool,
+    /// False for a coinbase less than `COINBASE_MATURITY_WINDOW` blocks deep, which a
+    /// short reorg could still take away along with everything spending it
+    pub spendable_now: bool,
+    /// `txid:vout`, as `createrawtransaction --input` takes it
+    pub outpoint: String,
+}
+
+/// Whether `error` says the chainstate is missing outputs a block spends, see
+/// `UTXOSet::update_safe`
+pub fn is_chainstate_inconsistent(error: &BlockchainError) -> bool {
+    matches!(error, BlockchainError::Database(message) if message.starts_with(CHAINSTATE_INCONSISTENT))
+}
+
+/// Whether `error` says a block didn't build on the last one applied to the chainstate,
+/// see `UTXOSet::update_safe`
+pub fn is_chainstate_out_of_order(error: &BlockchainError) -> bool {
+    matches!(error, BlockchainError::Database(message) if message.starts_with(CHAINSTATE_OUT_OF_ORDER))
+}
+
+/// The chainstate tree's entries, leaving out the `AppliedBlock` kept with them
+pub(crate) fn chainstate_entries(
+    utxo_tree: &sled::Tree,
+) -> impl Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>> {
+    utxo_tree
+        .iter()
+        .filter(|item| !matches!(item, Ok((key, _)) if key.as_ref() == LAST_APPLIED_KEY))
+}
+
+// A transaction's unspent outputs, each with its index in the transaction, lowest first
+pub(crate) type ChainstateEntry = Vec<(usize, TXOutput)>;
+
+pub(crate) fn decode_entry(bytes: &[u8]) -> Result<ChainstateEntry> {
+    deserialize(bytes).map_err(|e| {
+        BlockchainError::Serialization(format!("Failed to deserialize chainstate entry: {e}"))
+    })
+}
+
+fn encode_entry(entry: &ChainstateEntry) -> Result<Vec<u8>> {
+    serialize(entry).map_err(|e| {
+        BlockchainError::Serialization(format!("Failed to serialize chainstate entry: {e}"))
+    })
+}
+
+fn inconsistency(txid: &[u8], vout: usize) -> BlockchainError {
+    BlockchainError::Database(format!(
+        "{CHAINSTATE_INCONSISTENT} {}:{vout}",
+        HEXLOWER.encode(txid)
+    ))
+}
+
 impl UTXOSet {
     pub fn new(blockchain: Blockchain) -> UTXOSet {
         UTXOSet { blockchain }
@@ -22,40 +142,37 @@ impl UTXOSet {
     pub fn find_spendable_outputs(
         &self,
         pub_key_hash: &[u8],
-        amount: u64,
-    ) -> (u64, HashMap<String, Vec<usize>>) {
+        amount: impl Into<Amount>,
+    ) -> (Amount, HashMap<String, Vec<usize>>) {
         // For backward compatibility, wrap the Result version
         self.find_spendable_outputs_safe(pub_key_hash, amount)
             .unwrap_or_else(|e| {
                 log::error!("Error finding spendable outputs: {e}");
-                (0, HashMap::new())
+                (Amount::ZERO, HashMap::new())
             })
     }
 
     pub fn find_spendable_outputs_safe(
         &self,
         pub_key_hash: &[u8],
-        amount: u64,
-    ) -> Result<(u64, HashMap<String, Vec<usize>>)> {
+        amount: impl Into<Amount>,
+    ) -> Result<(Amount, HashMap<String, Vec<usize>>)> {
+        let amount = amount.into();
         let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
-        let mut accmulated = 0;
+        let mut accmulated = Amount::ZERO;
         let db = self.blockchain.get_db();
         let utxo_tree = db
             .open_tree(UTXO_TREE)
             .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
 
-        for item in utxo_tree.iter() {
+        for item in chainstate_entries(&utxo_tree) {
             let (k, v) = item.map_err(|e| {
                 BlockchainError::Database(format!("Failed to iterate UTXO tree: {e}"))
             })?;
             let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TXOutput> = deserialize(v.to_vec().as_slice()).map_err(|e| {
-                BlockchainError::Serialization(format!("Failed to deserialize TXOutput: {e}"))
-            })?;
-
-            for (idx, out) in outs.iter().enumerate() {
+            for (idx, out) in decode_entry(&v)? {
                 if out.is_locked_with_key(pub_key_hash) && accmulated < amount {
-                    accmulated += out.get_value();
+                    accmulated = accmulated.checked_add(out.get_value())?;
                     if let Some(output_list) = unspent_outputs.get_mut(txid_hex.as_str()) {
                         output_list.push(idx);
                     } else {
@@ -67,6 +184,54 @@ impl UTXOSet {
         Ok((accmulated, unspent_outputs))
     }
 
+    /// Every unspent output locked with `pub_key_hash`, with the id in hex of the
+    /// transaction it's in and its index there
+    pub fn list_unspent_safe(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, usize, TXOutput)>> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db
+            .open_tree(UTXO_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
+
+        let mut unspent = vec![];
+        for item in chainstate_entries(&utxo_tree) {
+            let (k, v) = item.map_err(|e| {
+                BlockchainError::Database(format!("Failed to iterate UTXO tree: {e}"))
+            })?;
+            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
+            for (idx, out) in decode_entry(&v)? {
+                if out.is_locked_with_key(pub_key_hash) {
+                    unspent.push((txid_hex.clone(), idx, out));
+                }
+            }
+        }
+        Ok(unspent)
+    }
+
+    /// Every unspent output locked with `pub_key_hash`, by txid and then index
+    pub fn list_unspent_for(&self, pub_key_hash: &[u8]) -> Result<Vec<UnspentOutput>> {
+        let mut unspent = Vec::new();
+        for (txid_hex, vout, output) in self.list_unspent_safe(pub_key_hash)? {
+            let txid = HEXLOWER.decode(txid_hex.as_bytes()).map_err(|e| {
+                BlockchainError::Serialization(format!("Failed to decode transaction ID: {e}"))
+            })?;
+            let confirmations = self.blockchain.get_confirmations(&txid)?.unwrap_or(0);
+            let is_coinbase = self
+                .blockchain
+                .find_transaction(&txid)?
+                .is_some_and(|tx| tx.is_coinbase());
+            unspent.push(UnspentOutput {
+                outpoint: format!("{txid_hex}:{vout}"),
+                txid_hex,
+                vout,
+                value: output.get_value(),
+                confirmations,
+                is_coinbase,
+                spendable_now: !is_coinbase || confirmations >= COINBASE_MATURITY_WINDOW as u64,
+            });
+        }
+        Ok(unspent)
+    }
+
     pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
         // For backward compatibility, wrap the Result version
         self.find_utxo_safe(pub_key_hash).unwrap_or_else(|e| {
@@ -82,23 +247,86 @@ impl UTXOSet {
             .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
         let mut utxos = vec![];
 
-        for item in utxo_tree.iter() {
+        for item in chainstate_entries(&utxo_tree) {
             let (_, v) = item.map_err(|e| {
                 BlockchainError::Database(format!("Failed to iterate UTXO tree: {e}"))
             })?;
-            let outs: Vec<TXOutput> = deserialize(v.to_vec().as_slice()).map_err(|e| {
-                BlockchainError::Serialization(format!("Failed to deserialize TXOutput: {e}"))
-            })?;
-
-            for out in outs.iter() {
+            for (_, out) in decode_entry(&v)? {
                 if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out.clone())
+                    utxos.push(out)
                 }
             }
         }
         Ok(utxos)
     }
 
+    /// How the pending transactions in `mempool` change `pub_key_hash`'s balance, as
+    /// `(incoming, outgoing)`
+    pub fn pending_delta_for(&self, pub_key_hash: &[u8], mempool: &MemoryPool) -> (Amount, Amount) {
+        // For backward compatibility, wrap the Result version
+        self.pending_delta_for_safe(pub_key_hash, mempool)
+            .unwrap_or_else(|e| {
+                log::error!("Error finding pending balance changes: {e}");
+                (Amount::ZERO, Amount::ZERO)
+            })
+    }
+
+    /// Outgoing adds up the confirmed outputs locked to `pub_key_hash` that pool
+    /// transactions spend, resolved against the chainstate. Incoming adds up the pool
+    /// outputs paying it that no other pool transaction spends already.
+    pub fn pending_delta_for_safe(
+        &self,
+        pub_key_hash: &[u8],
+        mempool: &MemoryPool,
+    ) -> Result<(Amount, Amount)> {
+        let mut incoming = Amount::ZERO;
+        let mut outgoing = Amount::ZERO;
+        for tx in mempool.get_all() {
+            if !tx.is_coinbase() {
+                for input in tx.get_vin() {
+                    // Inputs spending other pool transactions aren't in the chainstate
+                    // yet; their outputs just don't count as incoming below
+                    if let Some(output) = self
+                        .blockchain
+                        .find_unspent_output(input.get_txid(), input.get_vout())?
+                    {
+                        if output.is_locked_with_key(pub_key_hash) {
+                            outgoing = outgoing.checked_add(output.get_value())?;
+                        }
+                    }
+                }
+            }
+            for (vout, output) in tx.get_vout().iter().enumerate() {
+                if output.is_locked_with_key(pub_key_hash)
+                    && mempool.claimed_by(tx.get_id(), vout).is_none()
+                {
+                    incoming = incoming.checked_add(output.get_value())?;
+                }
+            }
+        }
+        Ok((incoming, outgoing))
+    }
+
+    /// `pub_key_hash`'s confirmed balance together with what `mempool` is about to change
+    pub fn balance_with_pending(
+        &self,
+        pub_key_hash: &[u8],
+        mempool: &MemoryPool,
+    ) -> Result<AddressBalance> {
+        let confirmed = Amount::checked_sum(
+            self.find_utxo_safe(pub_key_hash)?
+                .iter()
+                .map(TXOutput::get_value),
+        )?;
+        let (pending_incoming, pending_outgoing) =
+            self.pending_delta_for_safe(pub_key_hash, mempool)?;
+        Ok(AddressBalance {
+            confirmed,
+            pending_incoming,
+            pending_outgoing,
+        })
+    }
+
     pub fn count_transactions(&self) -> u64 {
         // For backward compatibility, return 0 on error
         self.count_transactions_safe().unwrap_or_else(|e| {
@@ -114,7 +342,7 @@ impl UTXOSet {
             .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
         let mut counter = 0;
 
-        for item in utxo_tree.iter() {
+        for item in chainstate_entries(&utxo_tree) {
             item.map_err(|e| {
                 BlockchainError::Database(format!("Failed to iterate UTXO tree: {e}"))
             })?;
@@ -123,6 +351,91 @@ impl UTXOSet {
         Ok(counter)
     }
 
+    /// Whether the chainstate has never been built for this database
+    ///
+    /// Even a chain holding only its genesis block leaves the genesis coinbase output here,
+    /// so an empty tree means there is nothing to update incrementally.
+    pub fn is_empty(&self) -> Result<bool> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db
+            .open_tree(UTXO_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
+        Ok(chainstate_entries(&utxo_tree).next().is_none())
+    }
+
+    /// The block the chainstate was last brought up to, `None` if it was never built or
+    /// predates the record
+    pub fn last_applied(&self) -> Result<Option<AppliedBlock>> {
+        let utxo_tree = self
+            .blockchain
+            .get_db()
+            .open_tree(UTXO_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
+        utxo_tree
+            .get(LAST_APPLIED_KEY)
+            .map_err(|e| {
+                BlockchainError::Database(format!("Failed to read the last applied block: {e}"))
+            })?
+            .map(|bytes| deserialize(&bytes))
+            .transpose()
+    }
+
+    /// Bring a chainstate left behind the tip, e.g. by a crash between storing a block and
+    /// applying it, up to the tip
+    ///
+    /// The blocks from the last applied one up to the tip are applied in order. If the
+    /// chain to the tip doesn't pass through that block, because a reorg moved away from
+    /// it, or the blocks in between are pruned or don't apply, the chainstate is reindexed
+    /// instead. A chainstate that was never built, or predates `AppliedBlock`, is left
+    /// alone. Returns whether anything had to change.
+    pub fn catch_up(&self) -> Result<bool> {
+        let Some(applied) = self.last_applied()? else {
+            return Ok(false);
+        };
+        let tip_hash = self.blockchain.get_tip_hash();
+        if applied.hash == tip_hash {
+            return Ok(false);
+        }
+
+        // Newest first, down to the block after the applied one
+        let mut missing = Vec::new();
+        let mut blocks = self.blockchain.iterator_from(&tip_hash);
+        let walked = loop {
+            match blocks.try_next()? {
+                Some(block) if block.get_hash() == applied.hash => break true,
+                Some(block) if block.get_height() > applied.height && !block.is_pruned() => {
+                    missing.push(block)
+                }
+                _ => break false,
+            }
+        };
+
+        if walked {
+            log::warn!(
+                "Chainstate is at block {} (height {}), {} block(s) behind the tip {tip_hash}; applying them",
+                applied.hash,
+                applied.height,
+                missing.len()
+            );
+            let applied_all = missing
+                .iter()
+                .rev()
+                .try_for_each(|block| self.update_safe(block));
+            match applied_all {
+                Ok(()) => return Ok(true),
+                Err(e) => log::warn!("Failed to catch the chainstate up, reindexing: {e}"),
+            }
+        } else {
+            log::warn!(
+                "Chainstate is at block {} (height {}), which can't be walked to from the tip {tip_hash}; reindexing",
+                applied.hash,
+                applied.height
+            );
+        }
+        self.reindex_safe()?;
+        Ok(true)
+    }
+
     pub fn reindex(&self) {
         // For backward compatibility, ignore errors but log them
         if let Err(e) = self.reindex_safe() {
@@ -130,7 +443,15 @@ impl UTXOSet {
         }
     }
 
+    /// Rebuild the chainstate by replaying the whole chain
+    ///
+    /// A pruned chain no longer has the transactions to replay, so this is refused there.
     pub fn reindex_safe(&self) -> Result<()> {
+        if self.blockchain.is_pruned()? {
+            return Err(BlockchainError::Pruned(
+                "the chainstate can't be rebuilt from a pruned chain".to_string(),
+            ));
+        }
         let db = self.blockchain.get_db();
         let utxo_tree = db
             .open_tree(UTXO_TREE)
@@ -139,22 +460,159 @@ impl UTXOSet {
         utxo_tree
             .clear()
             .map_err(|e| BlockchainError::Database(format!("Failed to clear UTXO tree: {e}")))?;
+        METRICS.chainstate_reindexes.inc();
 
-        let utxo_map = self.blockchain.find_utxo();
-        for (txid_hex, outs) in &utxo_map {
+        // One batch, so a crash leaves the chainstate either empty or whole
+        let tip_hash = self.blockchain.get_tip_hash();
+        let tip = self
+            .blockchain
+            .get_block(&tip_hash)?
+            .ok_or_else(|| BlockchainError::Database(format!("Tip block {tip_hash} not found")))?;
+        let mut batch = sled::Batch::default();
+        for (txid_hex, outs) in &self.blockchain.find_utxo_from(&tip_hash) {
             let txid = HEXLOWER.decode(txid_hex.as_bytes()).map_err(|e| {
                 BlockchainError::Serialization(format!("Failed to decode transaction ID: {e}"))
             })?;
-            let value = serialize(outs).map_err(|e| {
-                BlockchainError::Serialization(format!("Failed to serialize outputs: {e}"))
-            })?;
-            utxo_tree
-                .insert(txid.as_slice(), value)
-                .map_err(|e| BlockchainError::Database(format!("Failed to insert UTXO: {e}")))?;
+            batch.insert(txid, encode_entry(outs)?);
+        }
+        batch.insert(LAST_APPLIED_KEY, serialize(&AppliedBlock::of(&tip))?);
+        utxo_tree
+            .apply_batch(batch)
+            .map_err(|e| BlockchainError::Database(format!("Failed to insert UTXOs: {e}")))?;
+        self.record_version()
+    }
+
+    /// Write `blockchain`'s chainstate at its tip to a snapshot file at `path`, see
+    /// `storage::snapshot`
+    pub fn export_snapshot(path: &Path, blockchain: &Blockchain) -> Result<SnapshotMeta> {
+        snapshot::write_snapshot(path, blockchain)
+    }
+
+    /// Load the snapshot file at `path` into `blockchain`, which then starts at the
+    /// snapshot's tip without the history below it
+    ///
+    /// The file is checked first, see `storage::snapshot`, and nothing changes if it fails,
+    /// or if writing it does. The chainstate is replaced, the headers below the tip are
+    /// kept like pruned blocks' and the chain remembers the height it was loaded at, see
+    /// `Blockchain::get_snapshot_height`. Like a pruned chain, it can't be reindexed.
+    pub fn import_snapshot(path: &Path, blockchain: &Blockchain) -> Result<()> {
+        let snapshot = snapshot::read_snapshot(path, blockchain)?;
+
+        let mut chainstate = Vec::with_capacity(snapshot.entries.len() + 1);
+        for entry in &snapshot.entries {
+            chainstate.push((entry.txid.clone(), encode_entry(&entry.outputs)?));
         }
+        chainstate.push((
+            LAST_APPLIED_KEY.to_vec(),
+            serialize(&AppliedBlock::of(&snapshot.tip))?,
+        ));
+        let transactions: Vec<(String, Transaction)> = snapshot
+            .entries
+            .into_iter()
+            .map(|entry| (entry.block_hash, entry.transaction))
+            .collect();
+        blockchain.install_snapshot(
+            &snapshot.headers,
+            &snapshot.tip,
+            &transactions,
+            &chainstate,
+        )?;
+        log::info!(
+            "Loaded the snapshot at height {} (tip: {})",
+            snapshot.meta.height,
+            snapshot.meta.tip_hash
+        );
         Ok(())
     }
 
+    /// Bring a chainstate recorded in an older layout up to `CHAINSTATE_VERSION`
+    ///
+    /// An empty chainstate has nothing to convert and is just marked current. Otherwise
+    /// it is reindexed, except on a pruned chain, which can't be replayed: there every
+    /// entry's outputs are found again in their transactions, in the order they were
+    /// kept. Returns whether anything had to change.
+    pub fn upgrade(&self) -> Result<bool> {
+        let recorded = self
+            .blockchain
+            .get_db()
+            .get(CHAINSTATE_VERSION_KEY)
+            .map_err(|e| {
+                BlockchainError::Database(format!("Failed to read chainstate version: {e}"))
+            })?
+            .and_then(|bytes| bytes.first().copied())
+            .unwrap_or(1);
+        if recorded == CHAINSTATE_VERSION {
+            return Ok(false);
+        }
+        if self.is_empty()? {
+            self.record_version()?;
+            return Ok(false);
+        }
+
+        log::info!(
+            "Chainstate is in version {recorded}, upgrading it to version {CHAINSTATE_VERSION}"
+        );
+        if self.blockchain.is_pruned()? {
+            self.convert_unindexed_entries()?;
+            self.record_version()?;
+        } else {
+            self.reindex_safe()?;
+        }
+        Ok(true)
+    }
+
+    fn record_version(&self) -> Result<()> {
+        self.blockchain
+            .get_db()
+            .insert(CHAINSTATE_VERSION_KEY, &[CHAINSTATE_VERSION])
+            .map_err(|e| {
+                BlockchainError::Database(format!("Failed to record chainstate version: {e}"))
+            })?;
+        Ok(())
+    }
+
+    // Version 1 entries kept the unspent outputs in transaction order, so each one is the
+    // next output of its transaction with the same contents
+    fn convert_unindexed_entries(&self) -> Result<()> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db
+            .open_tree(UTXO_TREE)
+            .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
+
+        let mut batch = sled::Batch::default();
+        for item in chainstate_entries(&utxo_tree) {
+            let (txid, bytes) = item.map_err(|e| {
+                BlockchainError::Database(format!("Failed to iterate UTXO tree: {e}"))
+            })?;
+            let outs: Vec<TXOutput> = deserialize(&bytes).map_err(|e| {
+                BlockchainError::Serialization(format!("Failed to deserialize TXOutput: {e}"))
+            })?;
+            let source = self.blockchain.find_transaction(&txid)?.ok_or_else(|| {
+                BlockchainError::Database(format!(
+                    "Chainstate entry {} has no indexed transaction",
+                    HEXLOWER.encode(&txid)
+                ))
+            })?;
+
+            let mut candidates = source.get_vout().iter().enumerate();
+            let mut entry = ChainstateEntry::with_capacity(outs.len());
+            for out in outs {
+                let wanted = serialize(&out)?;
+                let found = candidates.find(|(_, candidate)| {
+                    serialize(*candidate).is_ok_and(|bytes| bytes == wanted)
+                });
+                let Some((vout, _)) = found else {
+                    return Err(inconsistency(&txid, entry.len()));
+                };
+                entry.push((vout, out));
+            }
+            batch.insert(txid, encode_entry(&entry)?);
+        }
+        utxo_tree
+            .apply_batch(batch)
+            .map_err(|e| BlockchainError::Database(format!("Failed to update UTXO set: {e}")))
+    }
+
     pub fn update(&self, block: &Block) {
         // For backward compatibility, ignore errors but log them
         if let Err(e) = self.update_safe(block) {
@@ -162,63 +620,466 @@ impl UTXOSet {
         }
     }
 
+    /// Apply `block`, which just became the tip, to the chainstate
+    ///
+    /// The whole block goes in one batch, together with the `AppliedBlock` it becomes, so a
+    /// failure leaves the chainstate as it was. A block that doesn't build on the last one
+    /// applied is refused, see `is_chainstate_out_of_order`, except the last one itself,
+    /// which is already in and left alone. An input whose transaction has no entry gets
+    /// one rebuilt from the transaction index and the blocks since, with a warning; if that
+    /// fails too the error is a chainstate inconsistency, see `is_chainstate_inconsistent`,
+    /// and only a reindex repairs it.
     pub fn update_safe(&self, block: &Block) -> Result<()> {
         let db = self.blockchain.get_db();
         let utxo_tree = db
             .open_tree(UTXO_TREE)
             .map_err(|e| BlockchainError::Database(format!("Failed to open UTXO tree: {e}")))?;
 
+        if let Some(applied) = self.last_applied()? {
+            if applied.hash == block.get_hash() {
+                return Ok(());
+            }
+            if applied.hash != block.get_pre_block_hash() {
+                return Err(BlockchainError::Database(format!(
+                    "{CHAINSTATE_OUT_OF_ORDER} block {} builds on {}, but the chainstate is at {} (height {})",
+                    block.get_hash(),
+                    block.get_pre_block_hash(),
+                    applied.hash,
+                    applied.height
+                )));
+            }
+        }
+
+        // Entries this block rewrites, `None` for the ones it empties
+        let mut changes: HashMap<Vec<u8>, Option<ChainstateEntry>> = HashMap::new();
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in tx.get_vin() {
-                    let mut updated_outs = vec![];
-
-                    let outs_bytes = utxo_tree
-                        .get(vin.get_txid())
-                        .map_err(|e| BlockchainError::Database(format!("Failed to get UTXO: {e}")))?
-                        .ok_or_else(|| BlockchainError::Database("UTXO not found".to_string()))?;
-
-                    let outs: Vec<TXOutput> = deserialize(outs_bytes.as_ref()).map_err(|e| {
-                        BlockchainError::Serialization(format!(
-                            "Failed to deserialize TXOutput: {e}"
-                        ))
-                    })?;
-
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != vin.get_vout() {
-                            updated_outs.push(out.clone())
-                        }
-                    }
+                    let outs = match changes.get(vin.get_txid()) {
+                        Some(Some(outs)) => outs.clone(),
+                        // Spent down to nothing earlier in this very block
+                        Some(None) => return Err(inconsistency(vin.get_txid(), vin.get_vout())),
+                        None => match Self::read_entry(&utxo_tree, vin.get_txid())? {
+                            Some(outs) => outs,
+                            None => self.recover_entry(vin.get_txid(), vin.get_vout(), block)?,
+                        },
+                    };
 
-                    if updated_outs.is_empty() {
-                        utxo_tree.remove(vin.get_txid()).map_err(|e| {
-                            BlockchainError::Database(format!("Failed to remove UTXO: {e}"))
-                        })?;
-                    } else {
-                        let outs_bytes = serialize(&updated_outs).map_err(|e| {
-                            BlockchainError::Serialization(format!(
-                                "Failed to serialize TXOutput: {e}"
-                            ))
-                        })?;
-                        utxo_tree.insert(vin.get_txid(), outs_bytes).map_err(|e| {
-                            BlockchainError::Database(format!("Failed to update UTXO: {e}"))
-                        })?;
-                    }
+                    let updated_outs: ChainstateEntry = outs
+                        .into_iter()
+                        .filter(|(idx, _)| *idx != vin.get_vout())
+                        .collect();
+                    changes.insert(
+                        vin.get_txid().to_vec(),
+                        (!updated_outs.is_empty()).then_some(updated_outs),
+                    );
                 }
             }
 
-            let mut new_outputs = vec![];
-            for out in tx.get_vout() {
-                new_outputs.push(out.clone())
+            // Data outputs are unspendable and never enter the chainstate
+            let new_outputs: ChainstateEntry = tx
+                .get_vout()
+                .iter()
+                .enumerate()
+                .filter(|(_, out)| !out.is_data_carrier())
+                .map(|(idx, out)| (idx, out.clone()))
+                .collect();
+            if !new_outputs.is_empty() {
+                changes.insert(tx.get_id().to_vec(), Some(new_outputs));
             }
+        }
 
-            let outs_bytes = serialize(&new_outputs).map_err(|e| {
-                BlockchainError::Serialization(format!("Failed to serialize TXOutput: {e}"))
-            })?;
-            utxo_tree.insert(tx.get_id(), outs_bytes).map_err(|e| {
-                BlockchainError::Database(format!("Failed to insert new UTXO: {e}"))
-            })?;
+        let mut batch = sled::Batch::default();
+        for (txid, outs) in changes {
+            match outs {
+                Some(outs) => batch.insert(txid, encode_entry(&outs)?),
+                None => batch.remove(txid),
+            }
         }
-        Ok(())
+        batch.insert(LAST_APPLIED_KEY, serialize(&AppliedBlock::of(block))?);
+        utxo_tree
+            .apply_batch(batch)
+            .map_err(|e| BlockchainError::Database(format!("Failed to update UTXO set: {e}")))
+    }
+
+    fn read_entry(utxo_tree: &sled::Tree, txid: &[u8]) -> Result<Option<ChainstateEntry>> {
+        let Some(outs_bytes) = utxo_tree
+            .get(txid)
+            .map_err(|e| BlockchainError::Database(format!("Failed to get UTXO: {e}")))?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(decode_entry(&outs_bytes)?))
+    }
+
+    // The entry `txid` should have had before `block`: its transaction's outputs minus the
+    // ones spent by main-chain blocks from its own up to `block`
+    fn recover_entry(&self, txid: &[u8], vout: usize, block: &Block) -> Result<ChainstateEntry> {
+        let txid_hex = HEXLOWER.encode(txid);
+        log::warn!(
+            "Chainstate has no entry for {txid_hex}, spent by block {}; rebuilding it",
+            block.get_hash()
+        );
+        let rebuilt = self.rebuild_entry(txid, block).unwrap_or_else(|e| {
+            log::error!("Failed to rebuild the chainstate entry for {txid_hex}: {e}");
+            None
+        });
+        rebuilt.ok_or_else(|| inconsistency(txid, vout))
+    }
+
+    fn rebuild_entry(&self, txid: &[u8], block: &Block) -> Result<Option<ChainstateEntry>> {
+        let Some(source) = self.blockchain.find_transaction(txid)? else {
+            return Ok(None);
+        };
+
+        let mut spent = HashSet::new();
+        let mut earlier_blocks = self.blockchain.iterator_from(&block.get_pre_block_hash());
+        loop {
+            let Some(earlier) = earlier_blocks.try_next()? else {
+                // Genesis passed without meeting the transaction
+                return Ok(None);
+            };
+            // A pruned body can't say what it spent
+            if earlier.is_pruned() {
+                return Ok(None);
+            }
+            for tx in earlier.get_transactions() {
+                if tx.is_coinbase() {
+                    continue;
+                }
+                for vin in tx.get_vin() {
+                    if vin.get_txid() == txid {
+                        spent.insert(vin.get_vout());
+                    }
+                }
+            }
+            if earlier
+                .get_transactions()
+                .iter()
+                .any(|tx| tx.get_id() == txid)
+            {
+                break;
+            }
+        }
+
+        Ok(Some(
+            source
+                .get_vout()
+                .iter()
+                .enumerate()
+                .filter(|(idx, out)| !out.is_data_carrier() && !spent.contains(idx))
+                .map(|(idx, out)| (idx, out.clone()))
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FeeMode, NodeContext, Transaction};
+    use crate::wallet::wallets::lock_wallet_file;
+    use crate::wallet::{address_pub_key_hash, Wallets};
+    use tempfile::{tempdir, TempDir};
+
+    fn snapshot(blockchain: &Blockchain) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let utxo_tree = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+        chainstate_entries(&utxo_tree)
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                (key.to_vec(), value.to_vec())
+            })
+            .collect()
+    }
+
+    // Two funded wallets, the first holding the genesis coinbase, and a third to pay
+    fn funded_chain(temp_dir: &TempDir) -> (Blockchain, Vec<String>, NodeContext) {
+        let mut wallets = Wallets::new();
+        let owners: Vec<String> = (0..3).map(|_| wallets.create_wallet().unwrap()).collect();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &owners[0],
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        blockchain.generate_blocks(1, &owners[1], &context).unwrap();
+        (blockchain, owners, context)
+    }
+
+    #[test]
+    fn test_missing_entry_is_rebuilt_from_the_chain() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let (blockchain, owners, context) = funded_chain(&temp_dir);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+
+        let payment = Transaction::new_utxo_transaction(
+            &owners[0],
+            &owners[2],
+            1_000,
+            &utxo_set,
+            &Wallets::new(),
+        )
+        .unwrap();
+        let genesis_coinbase = payment.get_vin()[0].get_txid().to_vec();
+        let block = blockchain
+            .mine_block_with_context(&[payment], &owners[2], &context)
+            .unwrap();
+        // The block is on the chain, but the output it spends went missing before it
+        // reached the chainstate
+        let utxo_tree = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+        utxo_tree.remove(&genesis_coinbase).unwrap();
+        utxo_set.update_safe(&block).unwrap();
+
+        let updated = snapshot(&blockchain);
+        utxo_set.reindex_safe().unwrap();
+        assert_eq!(updated, snapshot(&blockchain));
+    }
+
+    #[test]
+    fn test_unrecoverable_block_leaves_the_chainstate_untouched() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let (blockchain, owners, _context) = funded_chain(&temp_dir);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+
+        let payments: Vec<Transaction> = owners[..2]
+            .iter()
+            .rev()
+            .map(|owner| {
+                Transaction::new_utxo_transaction(
+                    owner,
+                    &owners[2],
+                    1_000,
+                    &utxo_set,
+                    &Wallets::new(),
+                )
+                .unwrap()
+            })
+            .collect();
+        let genesis_coinbase = payments[1].get_vin()[0].get_txid().to_vec();
+        let utxo_tree = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+        utxo_tree.remove(&genesis_coinbase).unwrap();
+        // Without its last block recorded, the chainstate takes a block from anywhere
+        utxo_tree.remove(LAST_APPLIED_KEY).unwrap();
+        let before = snapshot(&blockchain);
+
+        // A block with no known ancestry gives nothing to rebuild the missing entry from,
+        // and its first payment, which did fit, must not be applied either
+        let block = Block::new_template("None".to_string(), &payments, 1, 1).unwrap();
+        let error = utxo_set.update_safe(&block).unwrap_err();
+        assert!(is_chainstate_inconsistent(&error), "{error}");
+        assert!(error
+            .to_string()
+            .contains(&format!("{}:0", HEXLOWER.encode(&genesis_coinbase))));
+        assert_eq!(before, snapshot(&blockchain));
+    }
+
+    #[test]
+    fn test_outputs_keep_their_index_after_a_sibling_is_spent() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::new();
+        let owner = wallets.create_wallet().unwrap();
+        let recipient = wallets.create_wallet().unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &owner,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        blockchain.set_force_difficulty(Some(1));
+        let context = NodeContext::new(FeeMode::Fixed { amount: 3 }).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        utxo_set.reindex_safe().unwrap();
+
+        // Output 0 pays the recipient, output 1 is the owner's change
+        let payment =
+            Transaction::new_utxo_transaction(&owner, &recipient, 1_000, &utxo_set, &wallets)
+                .unwrap();
+        let owner_hash = address_pub_key_hash(&owner).unwrap();
+        assert!(payment.get_vout()[1].is_locked_with_key(&owner_hash));
+        let block = blockchain
+            .mine_block_with_context(std::slice::from_ref(&payment), &recipient, &context)
+            .unwrap();
+        utxo_set.update_safe(&block).unwrap();
+
+        let mut spend = Transaction::new_raw(
+            &[(payment.get_id().to_vec(), 0)],
+            vec![TXOutput::new(900, &owner).unwrap()],
+        )
+        .unwrap();
+        spend
+            .sign_with_outputs(
+                wallets.get_wallet(&recipient).unwrap(),
+                &payment.get_vout()[..1],
+            )
+            .unwrap();
+        let block = blockchain
+            .mine_block_with_context(&[spend], &recipient, &context)
+            .unwrap();
+        utxo_set.update_safe(&block).unwrap();
+        let updated = snapshot(&blockchain);
+        utxo_set.reindex_safe().unwrap();
+        assert_eq!(updated, snapshot(&blockchain));
+
+        let payment_txid = HEXLOWER.encode(payment.get_id());
+        let from_payment: Vec<UnspentOutput> = utxo_set
+            .list_unspent_for(&owner_hash)
+            .unwrap()
+            .into_iter()
+            .filter(|unspent| unspent.txid_hex == payment_txid)
+            .collect();
+        assert_eq!(from_payment.len(), 1);
+        assert_eq!(from_payment[0].vout, 1);
+        assert_eq!(from_payment[0].value, payment.get_vout()[1].get_value());
+        assert_eq!(from_payment[0].confirmations, 2);
+        assert!(!from_payment[0].is_coinbase);
+        assert!(from_payment[0].spendable_now);
+        assert!(blockchain
+            .find_unspent_output(payment.get_id(), 1)
+            .unwrap()
+            .is_some());
+        assert!(blockchain
+            .find_unspent_output(payment.get_id(), 0)
+            .unwrap()
+            .is_none());
+
+        // The genesis coinbase is spent, the two newer ones are too young to count
+        let coinbases = utxo_set
+            .list_unspent_for(&address_pub_key_hash(&recipient).unwrap())
+            .unwrap();
+        assert_eq!(coinbases.len(), 2);
+        assert!(coinbases
+            .iter()
+            .all(|unspent| unspent.is_coinbase && !unspent.spendable_now));
+    }
+
+    #[test]
+    fn test_chainstate_without_indices_is_upgraded() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let (blockchain, owners, context) = funded_chain(&temp_dir);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let payment = Transaction::new_utxo_transaction(
+            &owners[0],
+            &owners[2],
+            1_000,
+            &utxo_set,
+            &Wallets::new(),
+        )
+        .unwrap();
+        let block = blockchain
+            .mine_block_with_context(&[payment], &owners[2], &context)
+            .unwrap();
+        utxo_set.update_safe(&block).unwrap();
+        assert!(!utxo_set.upgrade().unwrap());
+        let current = snapshot(&blockchain);
+
+        // Rewrite every entry the way version 1 kept it
+        let downgrade = || {
+            let utxo_tree = blockchain.get_db().open_tree(UTXO_TREE).unwrap();
+            for (txid, bytes) in snapshot(&blockchain) {
+                let outs: Vec<TXOutput> = decode_entry(&bytes)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(_, out)| out)
+                    .collect();
+                utxo_tree.insert(txid, serialize(&outs).unwrap()).unwrap();
+            }
+            blockchain.get_db().remove(CHAINSTATE_VERSION_KEY).unwrap();
+        };
+        downgrade();
+        assert!(utxo_set.upgrade().unwrap());
+        assert_eq!(current, snapshot(&blockchain));
+
+        // A pruned chain finds the indices in the transactions instead of replaying
+        downgrade();
+        utxo_set.convert_unindexed_entries().unwrap();
+        assert_eq!(current, snapshot(&blockchain));
+    }
+
+    #[test]
+    fn test_chainstate_left_behind_is_caught_up_on_open() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let (blockchain, owners, context) = funded_chain(&temp_dir);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let balance = |utxo_set: &UTXOSet, owner: &str| -> u64 {
+            utxo_set
+                .find_utxo_safe(&address_pub_key_hash(owner).unwrap())
+                .unwrap()
+                .iter()
+                .map(|out| out.get_value().to_satoshis())
+                .sum()
+        };
+        let payment = Transaction::new_utxo_transaction(
+            &owners[0],
+            &owners[2],
+            1_000,
+            &utxo_set,
+            &Wallets::new(),
+        )
+        .unwrap();
+
+        // The block is stored, and the node goes down before it reaches the chainstate
+        let block = blockchain
+            .mine_block_with_context(&[payment], &owners[1], &context)
+            .unwrap();
+        assert_ne!(
+            utxo_set.last_applied().unwrap().unwrap().hash,
+            block.get_hash()
+        );
+        assert_eq!(balance(&utxo_set, &owners[2]), 0);
+        let db_path = blockchain.get_db_path().to_str().unwrap().to_string();
+        drop(utxo_set);
+        drop(blockchain);
+
+        let reopened = Blockchain::new_blockchain_with_path(&db_path).unwrap();
+        let utxo_set = UTXOSet::new(reopened.clone());
+        assert_eq!(
+            utxo_set.last_applied().unwrap(),
+            Some(AppliedBlock {
+                hash: block.get_hash().to_string(),
+                height: 2,
+            })
+        );
+        assert_eq!(balance(&utxo_set, &owners[2]), 1_000);
+        let caught_up = snapshot(&reopened);
+        utxo_set.reindex_safe().unwrap();
+        assert_eq!(caught_up, snapshot(&reopened));
+    }
+
+    #[test]
+    fn test_block_not_on_the_last_applied_one_is_refused() {
+        let _guard = lock_wallet_file();
+        let temp_dir = tempdir().unwrap();
+        let (blockchain, owners, context) = funded_chain(&temp_dir);
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let first = blockchain
+            .mine_block_with_context(&[], &owners[1], &context)
+            .unwrap();
+        let second = blockchain
+            .mine_block_with_context(&[], &owners[1], &context)
+            .unwrap();
+        let before = snapshot(&blockchain);
+
+        let error = utxo_set.update_safe(&second).unwrap_err();
+        assert!(is_chainstate_out_of_order(&error), "{error}");
+        assert!(error
+            .to_string()
+            .contains(first.get_pre_block_hash().as_str()));
+        assert_eq!(before, snapshot(&blockchain));
+
+        utxo_set.update_safe(&first).unwrap();
+        // Already applied, so nothing changes
+        let after_first = snapshot(&blockchain);
+        utxo_set.update_safe(&first).unwrap();
+        assert_eq!(after_first, snapshot(&blockchain));
+        utxo_set.update_safe(&second).unwrap();
+        assert_eq!(
+            utxo_set.last_applied().unwrap().unwrap().hash,
+            second.get_hash()
+        );
     }
 }
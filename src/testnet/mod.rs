@@ -3,6 +3,8 @@
 //! This module provides a comprehensive testing framework for blockchain functionality
 //! including isolated test environments, deterministic testing, and consensus testing.
 
+pub mod network;
 pub mod test_utils;
 
+pub use network::{TestNetwork, TestNode};
 pub use test_utils::*;
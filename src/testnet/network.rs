@@ -0,0 +1,413 @@
+//! A local network of real nodes for end-to-end tests
+//!
+//! `TestNetwork::spawn` starts every node as a `Server` on its own ephemeral port, with its
+//! own database and `NodeContext`. All nodes share one genesis file and mine at difficulty
+//! 1, and every node after the first joins through the first one. Each node has a wallet
+//! its blocks pay to, so `TestNode::send_to` has something to spend. The wallets are kept
+//! in the network's temporary directory, one file per node, so a test network never
+//! touches the shared wallet file.
+
+use crate::core::{
+    Block, Blockchain, FeeMode, FeePriority, GenesisConfig, Network, NodeContext, Transaction,
+    MIN_TRANSACTION_FEE,
+};
+use crate::error::{BlockchainError, Result};
+use crate::network::server::{send_tx, StopHandle};
+use crate::network::{DnsSeeder, Server};
+use crate::storage::UTXOSet;
+use crate::wallet::{Wallet, Wallets};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Paid to the first node's wallet by the genesis block
+const GENESIS_AMOUNT: u64 = 5_000_000;
+/// Fee every node charges and expects, in satoshis: the least the relay policy takes
+const TEST_FEE: u64 = MIN_TRANSACTION_FEE;
+/// How long `spawn` and `add_node` wait for a new node to reach the first one
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub struct TestNetwork {
+    nodes: Vec<TestNode>,
+    genesis: GenesisConfig,
+    temp_dir: TempDir,
+}
+
+/// One running node of a `TestNetwork`
+pub struct TestNode {
+    server: Arc<Server>,
+    addr: String,
+    wallet: Wallet,
+    wallet_address: String,
+    stop: StopHandle,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl TestNetwork {
+    /// Start `nodes` nodes, returning once every one of them is known to the first
+    pub fn spawn(nodes: usize) -> Result<TestNetwork> {
+        let temp_dir = crate::testnet::create_temp_dir()?;
+        let first_wallet = Self::create_node_wallet(&temp_dir, 0)?;
+        let genesis = GenesisConfig::new(
+            "architect-chain test network",
+            &first_wallet.get_address(),
+            GENESIS_AMOUNT,
+        )?;
+
+        let mut network = TestNetwork {
+            nodes: Vec::with_capacity(nodes),
+            genesis,
+            temp_dir,
+        };
+        for index in 0..nodes {
+            let wallet = if index == 0 {
+                first_wallet.clone()
+            } else {
+                Self::create_node_wallet(&network.temp_dir, index)?
+            };
+            network.start_node(wallet, Network::default())?;
+        }
+        Ok(network)
+    }
+
+    /// Start one more node, which syncs from the first one like a node joining late
+    pub fn add_node(&mut self) -> Result<&TestNode> {
+        self.add_node_on(Network::default())
+    }
+
+    /// Start one more node that considers itself on `network`, from the same genesis file
+    ///
+    /// The first node refuses one on another network, so this only waits for it to join
+    /// when `network` is the default one every other node is on.
+    pub fn add_node_on(&mut self, network: Network) -> Result<&TestNode> {
+        let wallet = Self::create_node_wallet(&self.temp_dir, self.nodes.len())?;
+        self.start_node(wallet, network)
+    }
+
+    pub fn node(&self, index: usize) -> &TestNode {
+        &self.nodes[index]
+    }
+
+    pub fn nodes(&self) -> &[TestNode] {
+        &self.nodes
+    }
+
+    /// Wait until every node's chain is `height` blocks high on top of the same tip
+    pub fn wait_for_height(&self, height: usize, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let tips = self
+                .nodes
+                .iter()
+                .map(|node| Ok((node.height()?, node.blockchain().get_tip_hash())))
+                .collect::<Result<Vec<_>>>()?;
+            if tips.iter().all(|tip| tip.0 == height && tip.1 == tips[0].1) {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(BlockchainError::Network(format!(
+                    "Nodes didn't reach height {height} within {timeout:?}: {tips:?}"
+                )));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Stop every node and wait for it to finish
+    ///
+    /// Dropping the network does the same, but this reports what the nodes returned.
+    pub fn shutdown_all(&mut self) -> Result<()> {
+        let mut outcome = Ok(());
+        for node in &mut self.nodes {
+            let stopped = node.shutdown();
+            if outcome.is_ok() {
+                outcome = stopped;
+            }
+        }
+        outcome
+    }
+
+    // A new address in the wallet file of node `index`
+    fn create_node_wallet(temp_dir: &TempDir, index: usize) -> Result<Wallet> {
+        let path = temp_dir.path().join(format!("node_{index}_wallet.dat"));
+        let mut wallets = Wallets::new_with_path(&path);
+        let address = wallets.create_wallet()?;
+        wallets
+            .get_wallet(&address)
+            .cloned()
+            .ok_or_else(|| BlockchainError::Wallet(format!("Wallet {address} went missing")))
+    }
+
+    fn start_node(&mut self, wallet: Wallet, network: Network) -> Result<&TestNode> {
+        let index = self.nodes.len();
+        let db_path = self.temp_dir.path().join(format!("node_{index}"));
+        let db_path = db_path
+            .to_str()
+            .ok_or_else(|| BlockchainError::Io("Temporary path is not UTF-8".to_string()))?;
+        let blockchain = Blockchain::create_blockchain_with_genesis_path(&self.genesis, db_path)?;
+        blockchain.record_network(network)?;
+        blockchain.set_force_difficulty(Some(1));
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .map_err(|e| BlockchainError::Network(format!("Failed to bind a test port: {e}")))?;
+        let addr = listener
+            .local_addr()
+            .map_err(|e| BlockchainError::Network(format!("Failed to get listen address: {e}")))?
+            .to_string();
+        let context = NodeContext::new(FeeMode::Fixed { amount: TEST_FEE })?;
+        context.config().set_node_addr(addr.clone());
+        context.config().set_network(network);
+
+        // The first node is its own seed, so it waits for the others to introduce themselves
+        let seed = self
+            .nodes
+            .first()
+            .map_or(addr.clone(), |first| first.addr.clone());
+        // No DNS seeds, so the nodes only ever meet each other
+        let server = Server::with_context(blockchain, Arc::new(context))
+            .with_dns_seeder(DnsSeeder::with_seeds(Vec::new(), 0))
+            .with_seed_node(seed);
+        let server = Arc::new(server);
+        let stop = server.stop_handle();
+        let running = Arc::clone(&server);
+        let thread = thread::spawn(move || running.run_with_listener(listener));
+
+        self.nodes.push(TestNode {
+            server,
+            addr: addr.clone(),
+            wallet_address: wallet.get_address(),
+            wallet,
+            stop,
+            thread: Some(thread),
+        });
+        if index > 0 && network == Network::default() {
+            self.wait_until_known(&addr)?;
+        }
+        Ok(&self.nodes[index])
+    }
+
+    fn wait_until_known(&self, addr: &str) -> Result<()> {
+        let deadline = Instant::now() + JOIN_TIMEOUT;
+        while !self.nodes[0]
+            .context()
+            .relay_peers()
+            .iter()
+            .any(|peer| peer == addr)
+        {
+            if Instant::now() >= deadline {
+                return Err(BlockchainError::Network(format!(
+                    "Node {addr} didn't reach the first node within {JOIN_TIMEOUT:?}"
+                )));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TestNetwork {
+    fn drop(&mut self) {
+        let _ = self.shutdown_all();
+    }
+}
+
+impl TestNode {
+    /// The address the node listens on
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// The wallet the node's blocks pay to
+    pub fn wallet_address(&self) -> &str {
+        &self.wallet_address
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    pub fn blockchain(&self) -> &Blockchain {
+        self.server.get_blockchain()
+    }
+
+    pub fn context(&self) -> &NodeContext {
+        self.server.get_context()
+    }
+
+    pub fn height(&self) -> Result<usize> {
+        self.blockchain().get_best_height()
+    }
+
+    /// Mine a block with the node's pending transactions and announce it
+    pub fn mine_block(&self) -> Result<Block> {
+        let mut blocks = self.server.generate_blocks(1, &self.wallet_address)?;
+        blocks
+            .pop()
+            .ok_or_else(|| BlockchainError::Mining("No block was mined".to_string()))
+    }
+
+    /// Pay `amount` from the node's wallet to `to`, submitting it to the node like a
+    /// wallet would
+    ///
+    /// The inputs come from the node's confirmed outputs, so a second payment before the
+    /// first one is mined may spend the same ones.
+    pub fn send_to(&self, to: &str, amount: u64) -> Result<Transaction> {
+        let utxo_set = UTXOSet::new(self.blockchain().clone());
+        let tx = Transaction::new_utxo_transaction_from_wallet_with_context(
+            &self.wallet,
+            to,
+            amount,
+            FeePriority::Normal,
+            &utxo_set,
+            self.context(),
+        )?;
+        send_tx(&self.addr, &tx);
+        Ok(tx)
+    }
+
+    /// Mine on the node's own once `threshold` transactions are pending, paying its wallet
+    pub fn mine_at_threshold(&self, threshold: usize) {
+        let config = self.context().config();
+        config.set_mining_addr(self.wallet_address.clone());
+        config.set_transaction_threshold(threshold);
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.stop.stop();
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| BlockchainError::Network(format!("Node {} panicked", self.addr)))?,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::address_pub_key_hash;
+    use std::net::SocketAddr;
+
+    const TIMEOUT: Duration = Duration::from_secs(20);
+
+    #[test]
+    fn test_mined_block_reaches_every_peer() -> Result<()> {
+        let mut network = TestNetwork::spawn(3)?;
+
+        // The block goes from the second node through the first one to the third
+        let block = network.node(1).mine_block()?;
+        network.wait_for_height(1, TIMEOUT)?;
+        for node in network.nodes() {
+            assert_eq!(node.blockchain().get_tip_hash(), block.get_hash());
+        }
+
+        let block = network.node(0).mine_block()?;
+        network.wait_for_height(2, TIMEOUT)?;
+        assert_eq!(
+            network.node(2).blockchain().get_tip_hash(),
+            block.get_hash()
+        );
+        network.shutdown_all()
+    }
+
+    #[test]
+    fn test_transaction_propagates_and_is_mined_at_the_threshold() -> Result<()> {
+        let mut network = TestNetwork::spawn(3)?;
+        network.node(2).mine_at_threshold(1);
+        network.node(0).mine_block()?;
+        network.wait_for_height(1, TIMEOUT)?;
+
+        // Sent to the first node, mined by the third one once it hears of it
+        let recipient = network.node(1).wallet_address().to_string();
+        let tx = network.node(0).send_to(&recipient, 1_000)?;
+        network.wait_for_height(2, TIMEOUT)?;
+
+        let tip = network
+            .node(1)
+            .blockchain()
+            .get_block_at_height(2)?
+            .unwrap();
+        assert!(tip
+            .get_transactions()
+            .iter()
+            .any(|mined| mined.get_id() == tx.get_id()));
+        let coinbase = &tip.get_transactions()[0];
+        assert_eq!(
+            coinbase.get_vout()[0].get_address(),
+            network.node(2).wallet_address()
+        );
+
+        let recipient_hash = address_pub_key_hash(&recipient)?;
+        for node in network.nodes() {
+            let utxo_set = UTXOSet::new(node.blockchain().clone());
+            let balance =
+                utxo_set.balance_with_pending(&recipient_hash, node.context().memory_pool())?;
+            assert_eq!(balance.confirmed, 1_000);
+        }
+        network.shutdown_all()
+    }
+
+    #[test]
+    fn test_late_joiner_syncs_the_whole_chain() -> Result<()> {
+        let mut network = TestNetwork::spawn(2)?;
+        for _ in 0..5 {
+            network.node(0).mine_block()?;
+        }
+        network.wait_for_height(5, TIMEOUT)?;
+
+        network.add_node()?;
+        network.wait_for_height(5, TIMEOUT)?;
+        let late = network.node(2).blockchain();
+        for height in 0..=5 {
+            assert_eq!(
+                late.get_block_at_height(height)?
+                    .map(|block| block.get_hash().to_string()),
+                network
+                    .node(0)
+                    .blockchain()
+                    .get_block_at_height(height)?
+                    .map(|block| block.get_hash().to_string())
+            );
+        }
+
+        // It takes part in relaying from then on
+        network.node(2).mine_block()?;
+        network.wait_for_height(6, TIMEOUT)?;
+        network.shutdown_all()
+    }
+
+    #[test]
+    fn test_node_of_another_network_is_refused() -> Result<()> {
+        let mut network = TestNetwork::spawn(1)?;
+        let stranger = network.add_node_on(Network::Regtest)?.addr().to_string();
+        let stranger_addr: SocketAddr = stranger.parse().unwrap();
+
+        // Its handshake is refused and the first node won't dial it either
+        let peer_manager = network.node(0).server().get_peer_manager();
+        let deadline = Instant::now() + TIMEOUT;
+        while !peer_manager.is_other_network(stranger_addr)? {
+            assert!(Instant::now() < deadline, "{stranger} was never refused");
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert!(!peer_manager.get_known_peers()?.contains_key(&stranger_addr));
+        assert!(!network.node(0).context().relay_peers().contains(&stranger));
+
+        // Nodes of the same network still shake hands
+        network.add_node()?;
+        network.node(2).mine_block()?;
+        let deadline = Instant::now() + TIMEOUT;
+        while network.node(0).height()? < 1 {
+            assert!(
+                Instant::now() < deadline,
+                "the block never reached the first node"
+            );
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert_eq!(network.node(1).height()?, 0);
+        network.shutdown_all()
+    }
+}
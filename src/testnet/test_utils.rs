@@ -2,7 +2,7 @@
 
 use crate::core::{Block, Blockchain, Transaction};
 use crate::error::Result;
-use crate::wallet::Wallets;
+use crate::wallet::{Wallets, WALLET_FILE};
 use tempfile::TempDir;
 
 /// Test configuration for blockchain testing
@@ -57,9 +57,10 @@ pub fn create_test_network(node_count: usize) -> Result<Vec<(Blockchain, TempDir
     Ok(nodes)
 }
 
-/// Create test wallets for testing
-pub fn create_test_wallets(count: usize) -> Result<(Wallets, Vec<String>)> {
-    let mut wallets = Wallets::new();
+/// Create test wallets in a wallet file of their own, which lives as long as the directory
+pub fn create_test_wallets(count: usize) -> Result<(Wallets, Vec<String>, TempDir)> {
+    let temp_dir = create_temp_dir()?;
+    let mut wallets = Wallets::new_with_path(&temp_dir.path().join(WALLET_FILE));
     let mut addresses = Vec::new();
 
     for _ in 0..count {
@@ -67,20 +68,31 @@ pub fn create_test_wallets(count: usize) -> Result<(Wallets, Vec<String>)> {
         addresses.push(address);
     }
 
-    Ok((wallets, addresses))
+    Ok((wallets, addresses, temp_dir))
 }
 
-/// Create a test transaction
+/// Create a test transaction paying `amount` from `from`, one of `wallets`
 pub fn create_test_transaction(
+    wallets: &Wallets,
     from: &str,
     to: &str,
     amount: u64,
     blockchain: &Blockchain,
 ) -> Result<Transaction> {
+    use crate::core::FeePriority;
     use crate::storage::UTXOSet;
 
+    let wallet = wallets.get_wallet(from).ok_or_else(|| {
+        crate::error::BlockchainError::Wallet(format!("Wallet not found for address: {from}"))
+    })?;
     let utxo_set = UTXOSet::new(blockchain.clone());
-    Transaction::new_utxo_transaction(from, to, amount, &utxo_set)
+    Transaction::new_utxo_transaction_from_wallet(
+        wallet,
+        to,
+        amount,
+        FeePriority::Normal,
+        &utxo_set,
+    )
 }
 
 /// Mine a test block with custom difficulty
@@ -94,10 +106,10 @@ pub fn mine_test_block(
 
 /// Validate blockchain integrity
 pub fn validate_blockchain_integrity(blockchain: &Blockchain) -> Result<bool> {
-    let mut iterator = blockchain.iterator();
+    let mut blocks = blockchain.iter_from_genesis()?;
     let mut prev_hash = "None".to_string();
 
-    while let Some(block) = iterator.next() {
+    while let Some(block) = blocks.try_next()? {
         // Check block linkage
         if block.get_pre_block_hash() != prev_hash {
             return Ok(false);
@@ -191,7 +203,7 @@ mod tests {
         let result = create_test_wallets(5);
         assert!(result.is_ok());
 
-        let (_wallets, addresses) = result.unwrap();
+        let (wallets, addresses, temp_dir) = result.unwrap();
         assert_eq!(addresses.len(), 5);
 
         // All addresses should be unique
@@ -200,6 +212,12 @@ mod tests {
                 assert_ne!(addresses[i], addresses[j]);
             }
         }
+        // Kept in the temporary directory, which shares them with nobody
+        let reloaded = Wallets::new_with_path(&temp_dir.path().join(WALLET_FILE));
+        assert_eq!(reloaded.get_addresses().len(), 5);
+        assert!(addresses
+            .iter()
+            .all(|address| wallets.get_wallet(address).is_some()));
     }
 
     #[test]
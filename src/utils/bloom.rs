@@ -0,0 +1,120 @@
+//! Bloom filters for light clients
+//!
+//! A light client loads a filter into a full node to say which transactions it cares about
+//! without listing them outright. Each of the `hash_funcs` bit positions of an item comes
+//! from SHA-256 over the function index, the client's `tweak` and the item itself.
+
+use crate::error::{BlockchainError, Result};
+use crate::utils::sha256_digest;
+
+/// Largest filter a peer may load, in bytes
+pub const MAX_FILTER_SIZE: usize = 36_000;
+/// Most hash functions a peer's filter may use
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+}
+
+impl BloomFilter {
+    /// Create an empty filter of `size` bytes
+    pub fn new(size: usize, hash_funcs: u32, tweak: u32) -> Result<BloomFilter> {
+        Self::from_parts(vec![0; size], hash_funcs, tweak)
+    }
+
+    /// Rebuild a filter received from a peer
+    pub fn from_parts(bits: Vec<u8>, hash_funcs: u32, tweak: u32) -> Result<BloomFilter> {
+        if bits.is_empty() || bits.len() > MAX_FILTER_SIZE {
+            return Err(BlockchainError::Network(format!(
+                "Bloom filter size {} is outside 1..={MAX_FILTER_SIZE} bytes",
+                bits.len()
+            )));
+        }
+        if hash_funcs == 0 || hash_funcs > MAX_HASH_FUNCS {
+            return Err(BlockchainError::Network(format!(
+                "Bloom filter hash function count {hash_funcs} is outside 1..={MAX_HASH_FUNCS}"
+            )));
+        }
+        Ok(BloomFilter {
+            bits,
+            hash_funcs,
+            tweak,
+        })
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        for bit in self.bit_positions(data) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Whether `data` may have been inserted; false positives are possible, misses are not
+    pub fn contains(&self, data: &[u8]) -> bool {
+        self.bit_positions(data)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn get_bits(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn get_hash_funcs(&self) -> u32 {
+        self.hash_funcs
+    }
+
+    pub fn get_tweak(&self) -> u32 {
+        self.tweak
+    }
+
+    fn bit_positions<'a>(&self, data: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let bit_count = (self.bits.len() * 8) as u64;
+        let tweak = self.tweak;
+        (0..self.hash_funcs).map(move |index| {
+            let mut input = Vec::with_capacity(8 + data.len());
+            input.extend_from_slice(&index.to_le_bytes());
+            input.extend_from_slice(&tweak.to_le_bytes());
+            input.extend_from_slice(data);
+            let digest = sha256_digest(&input);
+            let mut word = [0u8; 8];
+            word.copy_from_slice(&digest[..8]);
+            (u64::from_le_bytes(word) % bit_count) as usize
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inserted_items_are_found() {
+        let mut filter = BloomFilter::new(64, 5, 7).unwrap();
+        let items: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 20]).collect();
+        for item in &items {
+            filter.insert(item);
+        }
+        assert!(items.iter().all(|item| filter.contains(item)));
+
+        // With 50 of 512 bits set, a sweep of other items stays mostly negative
+        let false_positives = (100..200u8).filter(|i| filter.contains(&[*i; 20])).count();
+        assert!(false_positives < 10, "{false_positives} false positives");
+
+        // The tweak changes which bits an item sets
+        let mut tweaked = BloomFilter::new(64, 5, 8).unwrap();
+        let mut untweaked = BloomFilter::new(64, 5, 7).unwrap();
+        tweaked.insert(&items[0]);
+        untweaked.insert(&items[0]);
+        assert_ne!(tweaked.get_bits(), untweaked.get_bits());
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_parameters() {
+        assert!(BloomFilter::new(0, 5, 0).is_err());
+        assert!(BloomFilter::new(MAX_FILTER_SIZE + 1, 5, 0).is_err());
+        assert!(BloomFilter::new(16, 0, 0).is_err());
+        assert!(BloomFilter::new(16, MAX_HASH_FUNCS + 1, 0).is_err());
+    }
+}
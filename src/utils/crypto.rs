@@ -20,6 +20,16 @@ pub fn current_timestamp() -> Result<i64> {
     Ok(duration as i64)
 }
 
+/// The current time as the network sees it (ms since the epoch)
+///
+/// This is the local clock moved by `offset`, the median offset of a node's peers' clocks,
+/// which it learns from their version messages (see `NodeContext::network_time_offset`).
+/// Block timestamps are checked against it and new blocks are stamped with it, so one
+/// machine with a wrong clock doesn't fork itself off.
+pub fn network_adjusted_time(offset: i64) -> Result<i64> {
+    Ok(current_timestamp()? + offset)
+}
+
 pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
     let mut context = Context::new(&SHA256);
     context.update(data);
@@ -20,6 +20,79 @@ where
     Ok(data)
 }
 
+/// Like `deserialize`, refusing data that claims more than `LIMIT` bytes
+///
+/// Bincode sizes containers from the length they are encoded with, so data from a peer
+/// could otherwise ask for any allocation. Containers count at their size in memory.
+pub fn deserialize_limited<T, const LIMIT: usize>(bytes: &[u8]) -> Result<T>
+where
+    T: for<'de> Deserialize<'de> + bincode::Decode<()>,
+{
+    let config = bincode::config::standard().with_limit::<LIMIT>();
+    let (data, _) = bincode::decode_from_slice(bytes, config)
+        .map_err(|e| BlockchainError::Serialization(format!("Deserialization failed: {e}")))?;
+    Ok(data)
+}
+
+/// Newest format `serialize_versioned` writes and `deserialize_versioned` can read
+///
+/// Version 0 is the bare bincode layout stored before formats were versioned.
+pub const FORMAT_VERSION: u8 = 1;
+
+// Leads every versioned encoding, followed by the version byte. Bincode's varints never
+// start with 0xFF, so unmarked bytes are always the version 0 layout.
+const FORMAT_MARKER: u8 = 0xFF;
+
+/// Serialize `data` in format `FORMAT_VERSION`: a marker byte and the version ahead of the
+/// bincode bytes, so older and newer layouts can be told apart when reading
+pub fn serialize_versioned<T: Serialize + bincode::Encode>(data: &T) -> Result<Vec<u8>> {
+    serialize_as(data, FORMAT_VERSION)
+}
+
+/// Serialize `data` in format `version`, for a reader that doesn't know newer ones
+pub fn serialize_as<T: Serialize + bincode::Encode>(data: &T, version: u8) -> Result<Vec<u8>> {
+    match version {
+        0 => serialize(data),
+        FORMAT_VERSION => {
+            let mut bytes = vec![FORMAT_MARKER, FORMAT_VERSION];
+            bytes.extend(serialize(data)?);
+            Ok(bytes)
+        }
+        _ => Err(unsupported_version(version)),
+    }
+}
+
+/// Deserialize bytes written by `serialize_versioned`, or in the unversioned layout
+pub fn deserialize_versioned<T>(bytes: &[u8]) -> Result<T>
+where
+    T: for<'de> Deserialize<'de> + bincode::Decode<()>,
+{
+    match format_version(bytes)? {
+        0 => deserialize(bytes),
+        _ => deserialize(&bytes[2..]),
+    }
+}
+
+/// The format version `bytes` are in, or an error for one newer than `FORMAT_VERSION`
+pub fn format_version(bytes: &[u8]) -> Result<u8> {
+    match bytes {
+        [FORMAT_MARKER, version, ..] if *version > FORMAT_VERSION => {
+            Err(unsupported_version(*version))
+        }
+        // Version 0 is never written with a marker
+        [FORMAT_MARKER, 0, ..] => Err(unsupported_version(0)),
+        [FORMAT_MARKER, version, ..] => Ok(*version),
+        [FORMAT_MARKER] => Err(BlockchainError::Serialization(
+            "Format marker without a version".to_string(),
+        )),
+        _ => Ok(0),
+    }
+}
+
+fn unsupported_version(version: u8) -> BlockchainError {
+    BlockchainError::Serialization(format!("unsupported format version {version}"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -61,4 +134,45 @@ mod tests {
         let result: Result<TestData> = deserialize(&invalid_bytes);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_versioned_round_trip_and_unversioned_bytes() {
+        let original = TestData {
+            id: 7,
+            name: "versioned".to_string(),
+            values: vec![-1, 0, 1],
+        };
+
+        let versioned = serialize_versioned(&original).unwrap();
+        assert_eq!(&versioned[..2], &[FORMAT_MARKER, FORMAT_VERSION]);
+        assert_eq!(format_version(&versioned).unwrap(), FORMAT_VERSION);
+        assert_eq!(
+            deserialize_versioned::<TestData>(&versioned).unwrap(),
+            original
+        );
+
+        let unversioned = serialize(&original).unwrap();
+        assert_eq!(serialize_as(&original, 0).unwrap(), unversioned);
+        assert_eq!(format_version(&unversioned).unwrap(), 0);
+        assert_eq!(
+            deserialize_versioned::<TestData>(&unversioned).unwrap(),
+            original
+        );
+    }
+
+    #[test]
+    fn test_newer_format_version_is_refused_by_number() {
+        let mut bytes = serialize_versioned(&42u64).unwrap();
+        bytes[1] = FORMAT_VERSION + 1;
+        match deserialize_versioned::<u64>(&bytes) {
+            Err(BlockchainError::Serialization(msg)) => {
+                assert_eq!(
+                    msg,
+                    format!("unsupported format version {}", FORMAT_VERSION + 1)
+                )
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(serialize_as(&42u64, FORMAT_VERSION + 1).is_err());
+    }
 }
@@ -0,0 +1,226 @@
+//! Named groups of wallet addresses
+//!
+//! An account gathers addresses kept for one purpose, such as "mining" or "spending", so
+//! their balances can be read together and a payment can spend from all of them at once.
+//! An address belongs to at most one account. Change from a payment out of an account goes
+//! to its change address, the first address assigned to it unless another member is picked.
+
+use crate::error::{BlockchainError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The addresses of one account
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub struct Account {
+    addresses: BTreeSet<String>,
+    change_address: Option<String>,
+}
+
+impl Account {
+    /// The member addresses, in order
+    pub fn addresses(&self) -> &BTreeSet<String> {
+        &self.addresses
+    }
+
+    /// Where payments from the account send their change, `None` while it has no address
+    pub fn change_address(&self) -> Option<&str> {
+        self.change_address.as_deref()
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        self.addresses.contains(address)
+    }
+}
+
+/// Every account of a wallet file, by name
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub struct Accounts {
+    accounts: BTreeMap<String, Account>,
+}
+
+impl Accounts {
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts.get(name)
+    }
+
+    /// The accounts sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Account)> {
+        self.accounts
+            .iter()
+            .map(|(name, account)| (name.as_str(), account))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// The name of the account `address` belongs to, if any
+    pub fn account_of(&self, address: &str) -> Option<&str> {
+        self.iter()
+            .find(|(_, account)| account.contains(address))
+            .map(|(name, _)| name)
+    }
+
+    pub(crate) fn create(&mut self, name: &str) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(BlockchainError::Wallet(
+                "Account name must not be empty".to_string(),
+            ));
+        }
+        if self.accounts.contains_key(name) {
+            return Err(BlockchainError::Wallet(format!(
+                "Account '{name}' already exists"
+            )));
+        }
+        self.accounts.insert(name.to_string(), Account::default());
+        Ok(())
+    }
+
+    pub(crate) fn assign(&mut self, name: &str, address: &str) -> Result<()> {
+        if let Some(owner) = self.account_of(address) {
+            return Err(BlockchainError::Wallet(format!(
+                "Address {address} already belongs to account '{owner}'"
+            )));
+        }
+        let account = self.get_mut(name)?;
+        account.addresses.insert(address.to_string());
+        account
+            .change_address
+            .get_or_insert_with(|| address.to_string());
+        Ok(())
+    }
+
+    /// Take `address` out of account `name`; if it was the change address, the first
+    /// remaining member takes over
+    pub(crate) fn remove_address(&mut self, name: &str, address: &str) -> Result<()> {
+        let account = self.get_mut(name)?;
+        if !account.addresses.remove(address) {
+            return Err(BlockchainError::Wallet(format!(
+                "Address {address} is not in account '{name}'"
+            )));
+        }
+        if account.change_address.as_deref() == Some(address) {
+            account.change_address = account.addresses.first().cloned();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_change_address(&mut self, name: &str, address: &str) -> Result<()> {
+        let account = self.get_mut(name)?;
+        if !account.contains(address) {
+            return Err(BlockchainError::Wallet(format!(
+                "Address {address} is not in account '{name}', assign it first"
+            )));
+        }
+        account.change_address = Some(address.to_string());
+        Ok(())
+    }
+
+    /// Add the accounts of `other` whose names are free, with only the members `keep`
+    /// accepts that belong to no account yet
+    pub(crate) fn merge(&mut self, other: Accounts, keep: impl Fn(&str) -> bool) {
+        for (name, account) in other.accounts {
+            if self.accounts.contains_key(&name) {
+                continue;
+            }
+            let addresses: BTreeSet<String> = account
+                .addresses
+                .into_iter()
+                .filter(|address| keep(address) && self.account_of(address).is_none())
+                .collect();
+            let change_address = account
+                .change_address
+                .filter(|address| addresses.contains(address))
+                .or_else(|| addresses.first().cloned());
+            self.accounts.insert(
+                name,
+                Account {
+                    addresses,
+                    change_address,
+                },
+            );
+        }
+    }
+
+    fn get_mut(&mut self, name: &str) -> Result<&mut Account> {
+        self.accounts
+            .get_mut(name)
+            .ok_or_else(|| BlockchainError::Wallet(format!("No account is named '{name}'")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_an_address_belongs_to_one_account() {
+        let mut accounts = Accounts::default();
+        accounts.create("mining").unwrap();
+        accounts.create("spending").unwrap();
+        assert!(accounts.create("mining").is_err());
+        assert!(accounts.create(" ").is_err());
+
+        accounts.assign("mining", "addr-a").unwrap();
+        accounts.assign("mining", "addr-b").unwrap();
+        let err = accounts.assign("spending", "addr-a").unwrap_err();
+        assert!(err.to_string().contains("account 'mining'"), "{err}");
+        assert!(accounts.assign("mining", "addr-a").is_err());
+        assert!(accounts.assign("donations", "addr-c").is_err());
+        assert_eq!(accounts.account_of("addr-b"), Some("mining"));
+        assert_eq!(accounts.account_of("addr-c"), None);
+    }
+
+    #[test]
+    fn test_change_address_follows_the_members() {
+        let mut accounts = Accounts::default();
+        accounts.create("spending").unwrap();
+        accounts.assign("spending", "addr-b").unwrap();
+        accounts.assign("spending", "addr-a").unwrap();
+        // The first address assigned keeps the change until another is picked
+        let change = |accounts: &Accounts| {
+            accounts
+                .get("spending")
+                .unwrap()
+                .change_address()
+                .map(str::to_string)
+        };
+        assert_eq!(change(&accounts).as_deref(), Some("addr-b"));
+        assert!(accounts.set_change_address("spending", "addr-c").is_err());
+        accounts.set_change_address("spending", "addr-a").unwrap();
+        assert_eq!(change(&accounts).as_deref(), Some("addr-a"));
+
+        accounts.remove_address("spending", "addr-a").unwrap();
+        assert_eq!(change(&accounts).as_deref(), Some("addr-b"));
+        assert!(accounts.remove_address("spending", "addr-a").is_err());
+        accounts.remove_address("spending", "addr-b").unwrap();
+        assert_eq!(change(&accounts), None);
+    }
+
+    #[test]
+    fn test_merge_keeps_existing_accounts_and_members() {
+        let mut accounts = Accounts::default();
+        accounts.create("mining").unwrap();
+        accounts.assign("mining", "addr-a").unwrap();
+
+        let mut restored = Accounts::default();
+        restored.create("mining").unwrap();
+        restored.create("savings").unwrap();
+        restored.assign("savings", "addr-a").unwrap();
+        restored.assign("savings", "addr-b").unwrap();
+        restored.assign("savings", "addr-gone").unwrap();
+        accounts.merge(restored, |address| address != "addr-gone");
+
+        assert_eq!(accounts.get("mining").unwrap().addresses().len(), 1);
+        let savings = accounts.get("savings").unwrap();
+        assert_eq!(
+            savings.addresses().iter().collect::<Vec<_>>(),
+            vec!["addr-b"]
+        );
+        assert_eq!(savings.change_address(), Some("addr-b"));
+    }
+}
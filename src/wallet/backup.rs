@@ -0,0 +1,158 @@
+//! Wallet backup files
+//!
+//! A backup holds the whole wallet file, keys and labels, behind a small header saying
+//! when it was taken and how many wallets it holds. A SHA-256 checksum over the header and
+//! payload is appended, so a corrupted or truncated backup is refused before anything on
+//! disk is touched. Given a password, the payload is encrypted with AES-256-GCM under an
+//! Argon2 key, the way encrypted wallet files are, whether or not the live wallet file is.
+
+use crate::error::{BlockchainError, Result};
+use crate::storage::encrypted::cipher::{Aes256GcmCipher, SecureMemory};
+use crate::storage::encrypted::wallet_encryption::derive_key_from_password;
+use crate::storage::encrypted::{generate_random_bytes, WalletEncryptionConfig};
+use crate::utils::{deserialize, serialize, sha256_digest};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Magic bytes identifying an architect-chain wallet backup
+pub const WALLET_BACKUP_MAGIC: [u8; 4] = *b"ACWB";
+/// Current version of the wallet backup layout
+pub const WALLET_BACKUP_VERSION: u32 = 1;
+
+/// Length of the SHA-256 checksum closing every backup
+const CHECKSUM_LEN: usize = 32;
+const SALT_LEN: usize = 32;
+
+/// Header at the start of every wallet backup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct WalletBackupHeader {
+    pub magic: [u8; 4],
+    pub version: u32,
+    /// Seconds since the epoch
+    pub created_at: u64,
+    pub wallet_count: u64,
+    /// How the payload was encrypted, or `None` for a plain backup
+    pub encryption: Option<BackupEncryption>,
+}
+
+impl WalletBackupHeader {
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct BackupEncryption {
+    /// Salt the key was derived from the password with
+    pub salt: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct BackupRecord {
+    header: WalletBackupHeader,
+    /// Wallet file bytes, encrypted if the header says so
+    payload: Vec<u8>,
+}
+
+/// Wrap wallet file bytes holding `wallet_count` wallets into a backup
+pub(crate) fn encode_backup(
+    wallet_file: &[u8],
+    wallet_count: usize,
+    password: Option<&str>,
+) -> Result<(WalletBackupHeader, Vec<u8>)> {
+    let (encryption, payload) = match password {
+        Some(password) => {
+            let min_length = WalletEncryptionConfig::default().min_password_length;
+            if password.len() < min_length {
+                return Err(BlockchainError::Encryption(format!(
+                    "Password must be at least {min_length} characters long"
+                )));
+            }
+            let salt = generate_random_bytes(SALT_LEN)?;
+            let cipher = Aes256GcmCipher::new(derive_key_from_password(password, &salt)?)?;
+            let encrypted = cipher.encrypt(wallet_file)?;
+            (
+                Some(BackupEncryption {
+                    salt,
+                    nonce: encrypted.nonce,
+                }),
+                encrypted.ciphertext,
+            )
+        }
+        None => (None, wallet_file.to_vec()),
+    };
+
+    let header = WalletBackupHeader {
+        magic: WALLET_BACKUP_MAGIC,
+        version: WALLET_BACKUP_VERSION,
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        wallet_count: wallet_count as u64,
+        encryption,
+    };
+    let mut bytes = serialize(&BackupRecord {
+        header: header.clone(),
+        payload,
+    })?;
+    let checksum = sha256_digest(&bytes);
+    bytes.extend_from_slice(&checksum);
+    Ok((header, bytes))
+}
+
+/// Check a backup's checksum and return its header and the wallet file bytes inside
+///
+/// An encrypted backup needs `password`; a plain one ignores it.
+pub(crate) fn decode_backup(
+    bytes: &[u8],
+    password: Option<&str>,
+) -> Result<(WalletBackupHeader, Vec<u8>)> {
+    if bytes.len() <= CHECKSUM_LEN {
+        return Err(BlockchainError::Wallet(
+            "Wallet backup is truncated".to_string(),
+        ));
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+    if !SecureMemory::constant_time_eq(&sha256_digest(body), checksum) {
+        return Err(BlockchainError::Wallet(
+            "Wallet backup is corrupted (checksum mismatch)".to_string(),
+        ));
+    }
+
+    let record: BackupRecord = deserialize(body)?;
+    let header = record.header;
+    if header.magic != WALLET_BACKUP_MAGIC {
+        return Err(BlockchainError::Wallet(
+            "Not an architect-chain wallet backup (bad magic)".to_string(),
+        ));
+    }
+    if header.version != WALLET_BACKUP_VERSION {
+        return Err(BlockchainError::Wallet(format!(
+            "Unsupported wallet backup version {} (expected {WALLET_BACKUP_VERSION})",
+            header.version
+        )));
+    }
+
+    let wallet_file = match &header.encryption {
+        Some(encryption) => {
+            let password = password.ok_or_else(|| {
+                BlockchainError::Encryption(
+                    "Wallet backup is encrypted, a password is needed".to_string(),
+                )
+            })?;
+            let cipher =
+                Aes256GcmCipher::new(derive_key_from_password(password, &encryption.salt)?)?;
+            cipher
+                .decrypt(&record.payload, &encryption.nonce)
+                .map_err(|_| {
+                    BlockchainError::Encryption(
+                        "Could not decrypt wallet backup, wrong password?".to_string(),
+                    )
+                })?
+        }
+        None => record.payload,
+    };
+    Ok((header, wallet_file))
+}
@@ -0,0 +1,381 @@
+//! Transactions a wallet keeps until they are finished
+//!
+//! A raw transaction waiting for its signature, a multisig payment going round its signers
+//! and the unsigned payment of a watch-only address all leave the command that built them
+//! as hex. A draft keeps one in the wallet file under a name, together with how far along
+//! it is, until it is sent or deleted. Nothing reserves a draft's inputs, so the chain may
+//! spend them in the meantime; such a draft is stale and can only be deleted.
+
+use crate::core::block::MAX_TRANSACTION_SIZE;
+use crate::core::{Blockchain, Transaction};
+use crate::error::{BlockchainError, Result};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Largest draft kept, in bytes: a larger transaction could never be mined
+pub const MAX_DRAFT_SIZE: usize = MAX_TRANSACTION_SIZE;
+
+/// How far along a draft is
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub enum DraftState {
+    Unsigned,
+    /// Signatures counted over every input; a multisig payment is complete once `have`
+    /// reaches `need`, though it still has to be finalized before it is sent
+    PartiallySigned {
+        have: usize,
+        need: usize,
+    },
+    /// Ready to be sent
+    SignedUnbroadcast,
+}
+
+impl DraftState {
+    /// The state `tx` is in; the chain says how many signatures its multisig inputs need
+    pub fn of(tx: &Transaction, blockchain: &Blockchain) -> Result<DraftState> {
+        let vin = tx.get_vin();
+        let have: usize = vin
+            .iter()
+            .filter_map(|input| input.get_multisig_signatures())
+            .map(|signatures| signatures.len())
+            .sum();
+        if have > 0 {
+            let missing: usize = tx.missing_signatures(blockchain)?.iter().sum();
+            return Ok(DraftState::PartiallySigned {
+                have,
+                need: have + missing,
+            });
+        }
+        let signed = vin.iter().filter(|input| input.is_signed()).count();
+        Ok(match signed {
+            0 => DraftState::Unsigned,
+            signed if signed == vin.len() => DraftState::SignedUnbroadcast,
+            signed => DraftState::PartiallySigned {
+                have: signed,
+                need: vin.len(),
+            },
+        })
+    }
+}
+
+impl fmt::Display for DraftState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DraftState::Unsigned => write!(f, "unsigned"),
+            DraftState::PartiallySigned { have, need } => {
+                write!(f, "partially signed, {have} of {need} signatures")
+            }
+            DraftState::SignedUnbroadcast => write!(f, "signed, not sent"),
+        }
+    }
+}
+
+/// One saved transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub struct Draft {
+    /// The transaction, serialized
+    transaction: Vec<u8>,
+    state: DraftState,
+    /// Milliseconds since the epoch
+    saved_at: i64,
+}
+
+impl Draft {
+    pub fn transaction(&self) -> Result<Transaction> {
+        Transaction::deserialize(&self.transaction)
+    }
+
+    pub fn to_hex(&self) -> String {
+        HEXLOWER.encode(&self.transaction)
+    }
+
+    pub fn state(&self) -> DraftState {
+        self.state
+    }
+
+    pub fn saved_at(&self) -> i64 {
+        self.saved_at
+    }
+
+    pub fn size(&self) -> usize {
+        self.transaction.len()
+    }
+
+    /// The inputs, as txid:vout, of outputs the main chain has spent since
+    ///
+    /// An input whose transaction isn't on the chain may still be waiting in a memory pool,
+    /// so it doesn't count.
+    pub fn spent_inputs(&self, blockchain: &Blockchain) -> Result<Vec<String>> {
+        let mut spent = Vec::new();
+        for input in self.transaction()?.get_vin() {
+            let (txid, vout) = (input.get_txid(), input.get_vout());
+            if !blockchain.is_output_unspent(txid, vout)?
+                && blockchain.find_transaction(txid)?.is_some()
+            {
+                spent.push(format!("{}:{vout}", HEXLOWER.encode(txid)));
+            }
+        }
+        Ok(spent)
+    }
+}
+
+/// Every draft of a wallet file, by name
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub struct Drafts {
+    drafts: BTreeMap<String, Draft>,
+}
+
+impl Drafts {
+    pub fn get(&self, name: &str) -> Option<&Draft> {
+        self.drafts.get(name)
+    }
+
+    /// The drafts sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Draft)> {
+        self.drafts
+            .iter()
+            .map(|(name, draft)| (name.as_str(), draft))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.drafts.is_empty()
+    }
+
+    pub(crate) fn save(
+        &mut self,
+        name: &str,
+        transaction: &[u8],
+        state: DraftState,
+        saved_at: i64,
+    ) -> Result<()> {
+        if name.trim().is_empty() {
+            return Err(BlockchainError::Wallet(
+                "Draft name must not be empty".to_string(),
+            ));
+        }
+        if self.drafts.contains_key(name) {
+            return Err(BlockchainError::Wallet(format!(
+                "Draft '{name}' already exists, delete it first or pick another name"
+            )));
+        }
+        if transaction.len() > MAX_DRAFT_SIZE {
+            return Err(BlockchainError::Wallet(format!(
+                "Draft '{name}' is {} bytes, more than the {MAX_DRAFT_SIZE} a transaction may have",
+                transaction.len()
+            )));
+        }
+        // Only a transaction can be resumed later
+        Transaction::deserialize(transaction)?;
+        self.drafts.insert(
+            name.to_string(),
+            Draft {
+                transaction: transaction.to_vec(),
+                state,
+                saved_at,
+            },
+        );
+        Ok(())
+    }
+
+    pub(crate) fn delete(&mut self, name: &str) -> Result<Draft> {
+        self.drafts
+            .remove(name)
+            .ok_or_else(|| BlockchainError::Wallet(format!("No draft named '{name}'")))
+    }
+
+    /// Take the drafts of `other` whose names aren't taken here
+    pub(crate) fn merge(&mut self, other: Drafts) {
+        for (name, draft) in other.drafts {
+            self.drafts.entry(name).or_insert(draft);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Block, FeePriority, TXOutput};
+    use crate::storage::UTXOSet;
+    use crate::wallet::{hash_pub_key, Wallet};
+    use tempfile::{tempdir, TempDir};
+
+    const TEST_ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    // A chain whose genesis pays `owner` and whose next block pays `multisig`
+    fn chain_paying(owner: &str, multisig: &str) -> (TempDir, UTXOSet) {
+        let temp_dir = tempdir().unwrap();
+        let db_path = temp_dir.path().join("chain");
+        let blockchain =
+            Blockchain::create_blockchain_with_path(owner, db_path.to_str().unwrap()).unwrap();
+        let utxo_set = UTXOSet::new(blockchain);
+        utxo_set.reindex();
+        let coinbase = Transaction::new_coinbase_tx_with_reward(multisig, 100_000).unwrap();
+        add_block(&utxo_set, coinbase, None);
+        (temp_dir, utxo_set)
+    }
+
+    fn add_block(utxo_set: &UTXOSet, coinbase: Transaction, tx: Option<&Transaction>) {
+        let blockchain = utxo_set.get_blockchain();
+        let tip = blockchain
+            .get_block(&blockchain.get_tip_hash())
+            .unwrap()
+            .unwrap();
+        let mut transactions = vec![coinbase];
+        transactions.extend(tx.cloned());
+        let block = Block::new_test_block(
+            tip.get_timestamp() + 1_000,
+            tip.get_hash().to_string(),
+            &transactions,
+            tip.get_height() + 1,
+            1,
+        )
+        .unwrap();
+        blockchain.add_block(&block).unwrap();
+        utxo_set.update_safe(&block).unwrap();
+    }
+
+    // An unsigned payment to `to` of the genesis coinbase less `fee`
+    fn genesis_spend(blockchain: &Blockchain, to: &str, fee: u64) -> Transaction {
+        let genesis = blockchain
+            .get_block_at_height(0)
+            .unwrap()
+            .unwrap()
+            .get_transactions()[0]
+            .clone();
+        Transaction::new_raw(
+            &[(genesis.get_id().to_vec(), 0)],
+            vec![TXOutput::new(genesis.get_vout()[0].get_value().to_satoshis() - fee, to).unwrap()],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_state_follows_the_signatures_a_transaction_carries() {
+        let owner = Wallet::new().unwrap();
+        let signers: Vec<Wallet> = (0..2).map(|_| Wallet::new().unwrap()).collect();
+        let pub_key_hashes: Vec<Vec<u8>> = signers
+            .iter()
+            .map(|wallet| hash_pub_key(wallet.get_public_key()))
+            .collect();
+        let multisig = TXOutput::multisig_address(2, &pub_key_hashes).unwrap();
+        let (_temp_dir, utxo_set) = chain_paying(&owner.get_address(), &multisig);
+        let blockchain = utxo_set.get_blockchain();
+
+        let mut raw = genesis_spend(blockchain, TEST_ADDRESS, 1_000);
+        assert_eq!(
+            DraftState::of(&raw, blockchain).unwrap(),
+            DraftState::Unsigned
+        );
+        raw.sign_raw(&owner, blockchain).unwrap();
+        assert_eq!(
+            DraftState::of(&raw, blockchain).unwrap(),
+            DraftState::SignedUnbroadcast
+        );
+
+        let mut payment = Transaction::create_unsigned(
+            &multisig,
+            TEST_ADDRESS,
+            60_000,
+            FeePriority::Normal,
+            &utxo_set,
+        )
+        .unwrap();
+        assert_eq!(
+            DraftState::of(&payment, blockchain).unwrap(),
+            DraftState::Unsigned
+        );
+        payment.add_signature(&signers[0], blockchain).unwrap();
+        assert_eq!(
+            DraftState::of(&payment, blockchain).unwrap(),
+            DraftState::PartiallySigned { have: 1, need: 2 }
+        );
+        payment.add_signature(&signers[1], blockchain).unwrap();
+        assert_eq!(
+            DraftState::of(&payment, blockchain).unwrap(),
+            DraftState::PartiallySigned { have: 2, need: 2 }
+        );
+    }
+
+    #[test]
+    fn test_draft_goes_stale_once_the_chain_spends_an_input() {
+        let owner = Wallet::new().unwrap();
+        let (_temp_dir, utxo_set) = chain_paying(&owner.get_address(), TEST_ADDRESS);
+        let blockchain = utxo_set.get_blockchain();
+
+        let mut drafts = Drafts::default();
+        let draft = genesis_spend(blockchain, TEST_ADDRESS, 1_000);
+        drafts
+            .save("rent", &draft.serialize().unwrap(), DraftState::Unsigned, 1)
+            .unwrap();
+        // A parent the chain hasn't seen may still be pending, so it doesn't make one stale
+        let unconfirmed = Transaction::new_raw(
+            &[(vec![7; 32], 0)],
+            vec![TXOutput::new(1_000, TEST_ADDRESS).unwrap()],
+        )
+        .unwrap();
+        drafts
+            .save(
+                "pending",
+                &unconfirmed.serialize().unwrap(),
+                DraftState::Unsigned,
+                1,
+            )
+            .unwrap();
+        assert!(drafts
+            .iter()
+            .all(|(_, draft)| draft.spent_inputs(blockchain).unwrap().is_empty()));
+
+        // Another payment out of the same coin gets mined first
+        let mut conflict = genesis_spend(blockchain, &owner.get_address(), 2_000);
+        conflict.sign_raw(&owner, blockchain).unwrap();
+        add_block(
+            &utxo_set,
+            Transaction::new_coinbase_tx(TEST_ADDRESS).unwrap(),
+            Some(&conflict),
+        );
+        let outpoint = format!("{}:0", HEXLOWER.encode(conflict.get_vin()[0].get_txid()));
+        assert_eq!(
+            drafts
+                .get("rent")
+                .unwrap()
+                .spent_inputs(blockchain)
+                .unwrap(),
+            vec![outpoint]
+        );
+        assert!(drafts
+            .get("pending")
+            .unwrap()
+            .spent_inputs(blockchain)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_names_are_unique_and_size_is_capped() {
+        let tx = Transaction::new_placeholder_tx(TEST_ADDRESS)
+            .serialize()
+            .unwrap();
+        let mut drafts = Drafts::default();
+        drafts.save("a", &tx, DraftState::Unsigned, 1).unwrap();
+        assert!(drafts
+            .save("a", &tx, DraftState::SignedUnbroadcast, 2)
+            .is_err());
+        assert_eq!(drafts.get("a").unwrap().state(), DraftState::Unsigned);
+        assert!(drafts.save(" ", &tx, DraftState::Unsigned, 1).is_err());
+        assert!(drafts
+            .save("big", &vec![0; MAX_DRAFT_SIZE + 1], DraftState::Unsigned, 1)
+            .is_err());
+        assert!(drafts
+            .save("junk", b"not a transaction", DraftState::Unsigned, 1)
+            .is_err());
+
+        assert_eq!(drafts.delete("a").unwrap().saved_at(), 1);
+        assert!(drafts.delete("a").is_err());
+        assert!(drafts.is_empty());
+    }
+}
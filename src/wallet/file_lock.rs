@@ -0,0 +1,155 @@
+//! Keeping wallet files consistent between processes
+//!
+//! Every read-modify-write of a wallet file happens while holding an exclusive advisory
+//! lock on a `<wallet file>.lock` next to it, so two CLI invocations or a CLI and a running
+//! node can't interleave their saves. The wallet file itself is only ever replaced whole: the
+//! new contents go to a temporary file that is renamed over the old one, which leaves the
+//! previous file in place if anything fails along the way.
+
+use crate::error::{BlockchainError, Result};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for another process to finish with the wallet file
+pub const WALLET_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// An exclusive lock on a wallet file, released when dropped
+#[derive(Debug)]
+pub(crate) struct WalletFileLock {
+    // The OS releases the lock when the handle closes. The lock file itself stays behind,
+    // since removing it could let a waiting process lock a file nobody else will open.
+    _file: File,
+}
+
+impl WalletFileLock {
+    pub(crate) fn acquire(wallet_path: &Path) -> Result<WalletFileLock> {
+        Self::acquire_with_timeout(wallet_path, WALLET_LOCK_TIMEOUT)
+    }
+
+    pub(crate) fn acquire_with_timeout(
+        wallet_path: &Path,
+        timeout: Duration,
+    ) -> Result<WalletFileLock> {
+        // A node's wallet file can come before the node's database directory does
+        if let Some(dir) = wallet_path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            fs::create_dir_all(dir).map_err(|e| {
+                BlockchainError::Wallet(format!(
+                    "Failed to create wallet directory {}: {e}",
+                    dir.display()
+                ))
+            })?;
+        }
+        let lock_path = sibling_path(wallet_path, "lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|e| {
+                BlockchainError::Wallet(format!(
+                    "Failed to open wallet lock file {}: {e}",
+                    lock_path.display()
+                ))
+            })?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(WalletFileLock { _file: file }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    thread::sleep(LOCK_RETRY_INTERVAL)
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(BlockchainError::Wallet(
+                        "wallet is locked by another process".to_string(),
+                    ))
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(BlockchainError::Wallet(format!(
+                        "Failed to lock wallet file: {e}"
+                    )))
+                }
+            }
+        }
+    }
+}
+
+/// Replace the file at `path` with `bytes` without ever leaving it half written
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    write_atomically_with(path, bytes, || Ok(()))
+}
+
+// `before_rename` runs once the new contents are durable but not yet in place
+fn write_atomically_with(
+    path: &Path,
+    bytes: &[u8],
+    before_rename: impl FnOnce() -> io::Result<()>,
+) -> io::Result<()> {
+    let temp_path = sibling_path(path, "tmp");
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        before_rename()?;
+        fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// `wallet.dat` becomes `wallet.dat.<extension>` in the same directory, so a rename never
+// crosses file systems
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_second_lock_times_out() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wallet.dat");
+
+        let held = WalletFileLock::acquire(&path).unwrap();
+        let err = WalletFileLock::acquire_with_timeout(&path, Duration::from_millis(100))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("wallet is locked by another process"), "{err}");
+
+        drop(held);
+        assert!(WalletFileLock::acquire_with_timeout(&path, Duration::ZERO).is_ok());
+    }
+
+    #[test]
+    fn test_failed_write_leaves_original_intact() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wallet.dat");
+        write_atomically(&path, b"original").unwrap();
+
+        let err =
+            write_atomically_with(&path, b"replacement", || Err(io::Error::other("disk full")))
+                .unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        assert!(!sibling_path(&path, "tmp").exists());
+
+        write_atomically(&path, b"replacement").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"replacement");
+    }
+}
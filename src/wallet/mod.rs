@@ -3,9 +3,27 @@
 //! This module handles wallet creation, key management, address generation,
 //! and cryptographic operations for the blockchain.
 
+pub mod accounts;
+pub mod backup;
+pub mod drafts;
+pub(crate) mod file_lock;
+pub mod payment_uri;
+pub mod shared;
 #[allow(clippy::module_inception)]
 pub mod wallet;
 pub mod wallets;
 
-pub use wallet::{convert_address, hash_pub_key, validate_address, Wallet, ADDRESS_CHECK_SUM_LEN};
-pub use wallets::{Wallets, WALLET_FILE};
+pub use accounts::{Account, Accounts};
+pub use backup::{WalletBackupHeader, WALLET_BACKUP_MAGIC, WALLET_BACKUP_VERSION};
+pub use drafts::{Draft, DraftState, Drafts, MAX_DRAFT_SIZE};
+pub use file_lock::WALLET_LOCK_TIMEOUT;
+pub use payment_uri::PaymentRequest;
+pub use shared::{SharedWallets, WalletsWriteGuard};
+pub use wallet::{
+    address_pub_key_hash, convert_address, convert_multisig_address, decode_address, hash_pub_key,
+    validate_address, Wallet, ADDRESS_CHECK_SUM_LEN, ADDRESS_VERSION, MULTISIG_ADDRESS_VERSION,
+};
+pub use wallets::{
+    decode_wallet_file, node_wallet_path, wallet_path, RestoreSummary, SpendPolicy, WalletMetadata,
+    Wallets, WALLET_BACKUP_DIR, WALLET_FILE, WALLET_FILE_VERSION,
+};
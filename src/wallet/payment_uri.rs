@@ -0,0 +1,264 @@
+//! Payment request URIs
+//!
+//! A payment request tells the payer where to send coins and, optionally, how many and what
+//! for, in one string that can be pasted or shown as a QR code:
+//! `architect:ADDRESS?amount=1.5&message=coffee`. The amount is in coins with at most eight
+//! decimal places and the message is percent-encoded. Parameters other than `amount` and
+//! `message` are ignored.
+
+use crate::core::SATOSHIS_PER_COIN;
+use crate::error::{BlockchainError, Result};
+use crate::wallet::validate_address;
+use std::fmt;
+
+pub const URI_SCHEME: &str = "architect";
+
+/// Decimal places of a coin amount
+const COIN_DECIMALS: usize = 8;
+
+/// What a payment URI asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentRequest {
+    pub address: String,
+    /// Requested amount in satoshis
+    pub amount: Option<u64>,
+    pub message: Option<String>,
+}
+
+impl PaymentRequest {
+    pub fn new(
+        address: &str,
+        amount: Option<u64>,
+        message: Option<String>,
+    ) -> Result<PaymentRequest> {
+        validate_address(address)?;
+        if amount == Some(0) {
+            return Err(BlockchainError::Wallet(
+                "Requested amount must be positive".to_string(),
+            ));
+        }
+        Ok(PaymentRequest {
+            address: address.to_string(),
+            amount,
+            message,
+        })
+    }
+
+    /// The amount to pay, given the amount the payer asked for on their side, if any
+    ///
+    /// Either side alone settles it; when both name one they must agree.
+    pub fn resolve_amount(&self, requested: Option<u64>) -> Result<u64> {
+        match (self.amount, requested) {
+            (Some(amount), None) | (None, Some(amount)) => Ok(amount),
+            (Some(amount), Some(requested)) if amount == requested => Ok(amount),
+            (Some(amount), Some(requested)) => Err(BlockchainError::Wallet(format!(
+                "Amount {requested} does not match the {amount} requested by the payment URI"
+            ))),
+            (None, None) => Err(BlockchainError::Wallet(
+                "The payment URI names no amount, so one must be given".to_string(),
+            )),
+        }
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(amount) = self.amount {
+            params.push(format!("amount={}", format_coins(amount)));
+        }
+        if let Some(message) = &self.message {
+            params.push(format!("message={}", percent_encode(message)));
+        }
+
+        let mut uri = format!("{URI_SCHEME}:{}", self.address);
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+}
+
+impl fmt::Display for PaymentRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_uri())
+    }
+}
+
+/// Read a payment URI, checking the address and amount it carries
+pub fn parse(uri: &str) -> Result<PaymentRequest> {
+    let rest = uri
+        .split_once(':')
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case(URI_SCHEME))
+        .map(|(_, rest)| rest)
+        .ok_or_else(|| {
+            BlockchainError::Wallet(format!("Payment URI must start with {URI_SCHEME}:"))
+        })?;
+    let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+    let mut amount = None;
+    let mut message = None;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let slot_taken = match key {
+            "amount" => amount.replace(parse_coins(value)?).is_some(),
+            "message" => message.replace(percent_decode(value)?).is_some(),
+            _ => false,
+        };
+        if slot_taken {
+            return Err(BlockchainError::Wallet(format!(
+                "Payment URI repeats the {key} parameter"
+            )));
+        }
+    }
+
+    PaymentRequest::new(address, amount, message)
+}
+
+/// Convert a decimal coin amount such as `1.5` to satoshis without rounding
+pub fn parse_coins(amount: &str) -> Result<u64> {
+    let malformed = || BlockchainError::Wallet(format!("Malformed coin amount '{amount}'"));
+
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let all_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty())
+        || !all_digits(whole)
+        || !all_digits(fraction)
+        || fraction.len() > COIN_DECIMALS
+    {
+        return Err(malformed());
+    }
+
+    let whole: u64 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().map_err(|_| malformed())?
+    };
+    let fraction: u64 = format!("{fraction:0<COIN_DECIMALS$}")
+        .parse()
+        .map_err(|_| malformed())?;
+    whole
+        .checked_mul(SATOSHIS_PER_COIN)
+        .and_then(|satoshis| satoshis.checked_add(fraction))
+        .ok_or_else(malformed)
+}
+
+/// Write satoshis as a coin amount, with no more decimal places than needed
+pub fn format_coins(satoshis: u64) -> String {
+    let whole = satoshis / SATOSHIS_PER_COIN;
+    let fraction = satoshis % SATOSHIS_PER_COIN;
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{fraction:0>COIN_DECIMALS$}");
+    format!("{whole}.{}", fraction.trim_end_matches('0'))
+}
+
+// Everything but the RFC 3986 unreserved characters is escaped
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(text: &str) -> Result<String> {
+    let malformed = || BlockchainError::Wallet(format!("Malformed percent-encoding in '{text}'"));
+
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = text.get(i + 1..i + 3).ok_or_else(malformed)?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| malformed())?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| malformed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn test_uri_round_trip() {
+        let address = Wallet::new().unwrap().get_address();
+        let request = PaymentRequest::new(
+            &address,
+            Some(150_000_000),
+            Some("coffee & cake, 100% üntaxed".to_string()),
+        )
+        .unwrap();
+
+        let uri = request.to_uri();
+        assert_eq!(
+            uri,
+            format!("architect:{address}?amount=1.5&message=coffee%20%26%20cake%2C%20100%25%20%C3%BCntaxed")
+        );
+        assert_eq!(parse(&uri).unwrap(), request);
+
+        let bare = PaymentRequest::new(&address, None, None).unwrap();
+        assert_eq!(bare.to_uri(), format!("architect:{address}"));
+        assert_eq!(parse(&bare.to_uri()).unwrap(), bare);
+
+        // The scheme is case-insensitive and unknown parameters are skipped
+        let parsed = parse(&format!("ARCHITECT:{address}?label=shop&amount=0.00000001")).unwrap();
+        assert_eq!(parsed.amount, Some(1));
+        assert_eq!(parsed.message, None);
+    }
+
+    #[test]
+    fn test_coin_amounts() {
+        assert_eq!(parse_coins("1.5").unwrap(), 150_000_000);
+        assert_eq!(parse_coins("21").unwrap(), 21 * SATOSHIS_PER_COIN);
+        assert_eq!(parse_coins(".25").unwrap(), 25_000_000);
+        assert_eq!(parse_coins("0.12345678").unwrap(), 12_345_678);
+        for malformed in [
+            "",
+            ".",
+            "-1",
+            "1e3",
+            "1.2.3",
+            "0.123456789",
+            " 1",
+            "184467440738",
+        ] {
+            assert!(parse_coins(malformed).is_err(), "{malformed:?} parsed");
+        }
+
+        assert_eq!(format_coins(150_000_000), "1.5");
+        assert_eq!(format_coins(SATOSHIS_PER_COIN), "1");
+        assert_eq!(format_coins(1), "0.00000001");
+    }
+
+    #[test]
+    fn test_rejects_bad_uris() {
+        let address = Wallet::new().unwrap().get_address();
+        assert!(matches!(
+            parse("architect:1NotAnAddress?amount=1"),
+            Err(BlockchainError::InvalidAddress(_))
+        ));
+        for bad in [
+            format!("bitcoin:{address}"),
+            address.clone(),
+            format!("architect:{address}?amount=1,5"),
+            format!("architect:{address}?amount="),
+            format!("architect:{address}?amount=0"),
+            format!("architect:{address}?amount=1&amount=2"),
+            format!("architect:{address}?message=%4"),
+            format!("architect:{address}?message=%FF"),
+        ] {
+            assert!(parse(&bad).is_err(), "{bad} parsed");
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! One wallet file shared by every thread of a process
+//!
+//! `Wallets::new` reads and decodes the whole file, so a process that loads it for every
+//! payment pays for that each time, and two threads each holding their own copy only stay
+//! consistent through the file lock. A `SharedWallets` is loaded once and cloned cheaply
+//! into every thread that needs it. Lookups take a read lock. Changes take the write lock and
+//! go through the `Wallets` methods, which reload the file and save it under the file lock,
+//! so nothing another thread or process saved in the meantime is lost.
+//!
+//! Another process may still change the file behind the handle's back; `reload` picks that
+//! up when the file's modification time or size moved since the handle last read or wrote it.
+
+use crate::core::WalletProvider;
+use crate::error::Result;
+use crate::wallet::{Wallet, Wallets};
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+
+// When the wallet file was last modified and how large it was
+type FileStamp = (SystemTime, u64);
+
+/// A wallet file loaded once and shared between threads, see the module docs
+#[derive(Clone)]
+pub struct SharedWallets {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    wallets: RwLock<Wallets>,
+    /// The file as this handle last saw it, `None` while there is no file
+    stamp: Mutex<Option<FileStamp>>,
+}
+
+impl Default for SharedWallets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Wallets> for SharedWallets {
+    fn from(wallets: Wallets) -> SharedWallets {
+        let stamp = file_stamp(&wallets);
+        SharedWallets {
+            inner: Arc::new(Inner {
+                wallets: RwLock::new(wallets),
+                stamp: Mutex::new(stamp),
+            }),
+        }
+    }
+}
+
+impl SharedWallets {
+    /// The wallets in the file `wallet_path` resolves to, like `Wallets::new`
+    pub fn new() -> SharedWallets {
+        Wallets::new().into()
+    }
+
+    /// The wallets in the file at `path`, an error if it exists but can't be read
+    pub fn open(path: &Path) -> Result<SharedWallets> {
+        Ok(Wallets::open(path)?.into())
+    }
+
+    /// Read access to the wallets until the guard is dropped
+    ///
+    /// Changes wait for the guard, so don't hold it while calling anything that changes the
+    /// wallets, building a payment that takes a change address included.
+    pub fn read(&self) -> RwLockReadGuard<'_, Wallets> {
+        // Changes are only kept once saved, so what a panicking writer left is still whole
+        self.inner
+            .wallets
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write access to the wallets until the guard is dropped
+    ///
+    /// Changes go through the `Wallets` methods, which save each one before they return.
+    /// Dropping the guard remembers the file as saved, so `reload` doesn't read it back.
+    pub fn write(&self) -> WalletsWriteGuard<'_> {
+        WalletsWriteGuard {
+            wallets: self
+                .inner
+                .wallets
+                .write()
+                .unwrap_or_else(PoisonError::into_inner),
+            stamp: &self.inner.stamp,
+        }
+    }
+
+    /// Apply `change` to the wallets under the write lock
+    pub fn update<T>(&self, change: impl FnOnce(&mut Wallets) -> Result<T>) -> Result<T> {
+        change(&mut self.write())
+    }
+
+    pub fn create_wallet(&self) -> Result<String> {
+        self.update(Wallets::create_wallet)
+    }
+
+    pub fn create_wallet_with_label(&self, label: &str) -> Result<String> {
+        self.update(|wallets| wallets.create_wallet_with_label(label))
+    }
+
+    pub fn set_label(&self, address: &str, label: &str) -> Result<()> {
+        self.update(|wallets| wallets.set_label(address, label))
+    }
+
+    /// Track `address` without its key, see `Wallets::add_watch_only`
+    pub fn add_watch_only(&self, address: &str, label: Option<&str>) -> Result<()> {
+        self.update(|wallets| wallets.add_watch_only(address, label))
+    }
+
+    /// A copy of the wallet holding the key of `address`
+    pub fn get_wallet(&self, address: &str) -> Option<Wallet> {
+        self.read().get_wallet(address).cloned()
+    }
+
+    pub fn get_addresses(&self) -> Vec<String> {
+        self.read().get_addresses()
+    }
+
+    /// Read the file again if another process changed it since this handle last did
+    ///
+    /// Returns whether it was read. A file that went missing leaves the wallets as they are.
+    pub fn reload(&self) -> Result<bool> {
+        let mut wallets = self.write();
+        let stamp = file_stamp(&wallets);
+        if stamp.is_none() || stamp == *lock_stamp(wallets.stamp) {
+            return Ok(false);
+        }
+        let path = wallets.file_path()?;
+        *wallets = Wallets::open(&path)?;
+        Ok(true)
+    }
+}
+
+/// Write access to a `SharedWallets`, see `SharedWallets::write`
+pub struct WalletsWriteGuard<'a> {
+    wallets: RwLockWriteGuard<'a, Wallets>,
+    stamp: &'a Mutex<Option<FileStamp>>,
+}
+
+impl Deref for WalletsWriteGuard<'_> {
+    type Target = Wallets;
+
+    fn deref(&self) -> &Wallets {
+        &self.wallets
+    }
+}
+
+impl DerefMut for WalletsWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Wallets {
+        &mut self.wallets
+    }
+}
+
+impl Drop for WalletsWriteGuard<'_> {
+    fn drop(&mut self) {
+        *lock_stamp(self.stamp) = file_stamp(&self.wallets);
+    }
+}
+
+/// Signs with a copy of the key, so the read lock is held no longer than the lookup. A key
+/// the handle doesn't know sends it back to the file first.
+impl WalletProvider for SharedWallets {
+    fn signing_wallet(&self, address: &str) -> Result<Wallet> {
+        if let Some(wallet) = self.get_wallet(address) {
+            return Ok(wallet);
+        }
+        // The key may have been created by another process since the file was read
+        self.reload()?;
+        self.read().signing_wallet(address).cloned()
+    }
+
+    /// One when the wallet file's policy asks for change addresses, saved under the write
+    /// lock so concurrent payments each get their own
+    fn new_change_address(&self) -> Result<Option<String>> {
+        self.update(|wallets| {
+            if !wallets.uses_change_addresses() {
+                return Ok(None);
+            }
+            wallets.create_change_address().map(Some)
+        })
+    }
+}
+
+fn lock_stamp(stamp: &Mutex<Option<FileStamp>>) -> MutexGuard<'_, Option<FileStamp>> {
+    stamp.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn file_stamp(wallets: &Wallets) -> Option<FileStamp> {
+    let metadata = fs::metadata(wallets.file_path().ok()?).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Blockchain, FeePolicy, TransactionBuilder};
+    use crate::storage::UTXOSet;
+    use crate::wallet::address_pub_key_hash;
+    use std::collections::HashSet;
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_threads_sharing_a_handle_lose_no_address() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("wallet.dat");
+        let shared = SharedWallets::open(&path).unwrap();
+        let funder = shared.create_wallet().unwrap();
+        // Every payment takes a new change address, so the threads race on the file
+        shared
+            .update(|wallets| wallets.set_use_change_addresses(true))
+            .unwrap();
+        let blockchain = Blockchain::create_blockchain_with_path(
+            &funder,
+            temp_dir.path().join("chain").to_str().unwrap(),
+        )
+        .unwrap();
+        let utxo_set = UTXOSet::new(blockchain);
+        utxo_set.reindex();
+
+        let (created, payments): (Vec<Vec<String>>, Vec<_>) = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let shared = shared.clone();
+                    let utxo_set = &utxo_set;
+                    let funder = &funder;
+                    scope.spawn(move || {
+                        let created: Vec<String> =
+                            (0..3).map(|_| shared.create_wallet().unwrap()).collect();
+                        let payment = TransactionBuilder::new(utxo_set)
+                            .from(funder)
+                            .add_output(&created[0], 1_000)
+                            .fee_policy(FeePolicy::Explicit(1_000.into()))
+                            .build_and_sign(&shared)
+                            .unwrap();
+                        (created, payment)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).unzip()
+        });
+
+        let reopened = Wallets::open(&path).unwrap();
+        let change_addresses: Vec<String> = reopened
+            .get_addresses()
+            .into_iter()
+            .filter(|address| reopened.is_change_address(address))
+            .collect();
+        assert_eq!(change_addresses.len(), 8);
+        assert_eq!(reopened.get_addresses().len(), 1 + 8 * 3 + 8);
+        for address in created.iter().flatten() {
+            assert!(reopened.get_wallet(address).is_some());
+            assert!(shared.get_wallet(address).is_some());
+        }
+
+        // Each payment's change went to an address of its own, all of them in the file
+        let change_hashes: HashSet<Vec<u8>> = change_addresses
+            .iter()
+            .map(|address| address_pub_key_hash(address).unwrap())
+            .collect();
+        let paid_change: HashSet<Vec<u8>> = payments
+            .iter()
+            .map(|payment| payment.get_vout()[1].get_pub_key_hash().to_vec())
+            .collect();
+        assert_eq!(paid_change, change_hashes);
+        assert!(payments
+            .iter()
+            .all(|payment| payment.verify(utxo_set.get_blockchain())));
+    }
+
+    #[test]
+    fn test_reload_picks_up_wallets_added_elsewhere() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("wallet.dat");
+        let shared = SharedWallets::open(&path).unwrap();
+        let own = shared.create_wallet().unwrap();
+        // The handle's own save isn't news to it
+        assert!(!shared.reload().unwrap());
+
+        // Another process adds a key behind the handle's back
+        let added = Wallets::open(&path).unwrap().create_wallet().unwrap();
+        assert!(shared.get_wallet(&added).is_none());
+        assert!(shared.reload().unwrap());
+        assert!(shared.get_wallet(&added).is_some());
+        assert!(shared.get_wallet(&own).is_some());
+        assert!(!shared.reload().unwrap());
+
+        // Signing with a key the handle hasn't seen yet reads the file again
+        let signer = Wallets::open(&path).unwrap().create_wallet().unwrap();
+        assert_eq!(
+            shared.signing_wallet(&signer).unwrap().get_address(),
+            signer
+        );
+    }
+}
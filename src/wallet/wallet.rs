@@ -1,10 +1,15 @@
 use crate::error::{BlockchainError, Result};
+use data_encoding::HEXLOWER;
 use ring::rand::SystemRandom;
 use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
 use serde::{Deserialize, Serialize};
 
-const VERSION: u8 = 0x00;
+/// Version byte of this network's single-key addresses
+pub const ADDRESS_VERSION: u8 = 0x00;
+/// Version byte of addresses that stand for an M-of-N multisig policy instead of one key
+pub const MULTISIG_ADDRESS_VERSION: u8 = 0x05;
 pub const ADDRESS_CHECK_SUM_LEN: usize = 4;
+const PUB_KEY_HASH_LEN: usize = 20;
 
 #[derive(Clone, Serialize, Deserialize, bincode::Encode, bincode::Decode)]
 pub struct Wallet {
@@ -32,14 +37,7 @@ impl Wallet {
     }
 
     pub fn get_address(&self) -> String {
-        let pub_key_hash = hash_pub_key(self.public_key.as_slice());
-        let mut payload: Vec<u8> = vec![];
-        payload.push(VERSION);
-        payload.extend(pub_key_hash.as_slice());
-        let checksum = checksum(payload.as_slice());
-        payload.extend(checksum.as_slice());
-        // version + pub_key_hash + checksum
-        crate::utils::base58_encode(payload.as_slice())
+        encode_address(ADDRESS_VERSION, &hash_pub_key(self.public_key.as_slice()))
     }
 
     pub fn get_public_key(&self) -> &[u8] {
@@ -62,33 +60,191 @@ fn checksum(payload: &[u8]) -> Vec<u8> {
     second_sha[0..ADDRESS_CHECK_SUM_LEN].to_vec()
 }
 
-pub fn validate_address(address: &str) -> bool {
-    let payload = match crate::utils::base58_decode(address) {
-        Ok(payload) => payload,
-        Err(_) => return false, // Invalid base58 encoding
-    };
+/// Split `address` into its version byte and the hash (or multisig policy) it carries
+///
+/// Fails unless the address is Base58, its checksum matches, its version is one this
+/// network uses and the payload is exactly as long as that version calls for.
+pub fn decode_address(address: &str) -> Result<(u8, Vec<u8>)> {
+    let payload = crate::utils::base58_decode(address)
+        .map_err(|_| BlockchainError::InvalidAddress(format!("{address} is not Base58")))?;
+    if payload.len() < 1 + ADDRESS_CHECK_SUM_LEN {
+        return Err(BlockchainError::InvalidAddress(format!(
+            "{address} is too short"
+        )));
+    }
 
-    // Check if payload is long enough
-    if payload.len() < ADDRESS_CHECK_SUM_LEN + 1 {
-        return false;
+    let (versioned, actual_checksum) = payload.split_at(payload.len() - ADDRESS_CHECK_SUM_LEN);
+    if checksum(versioned) != actual_checksum {
+        return Err(BlockchainError::InvalidAddress(format!(
+            "{address} has a wrong checksum"
+        )));
     }
+    let (version, body) = (versioned[0], &versioned[1..]);
+    check_body(version, body)
+        .map_err(|reason| BlockchainError::InvalidAddress(format!("{address} {reason}")))?;
+    Ok((version, body.to_vec()))
+}
+
+pub fn validate_address(address: &str) -> Result<()> {
+    decode_address(address).map(|_| ())
+}
+
+/// The hash outputs paying `address` are locked to: the payload between the version byte
+/// and the checksum
+pub fn address_pub_key_hash(address: &str) -> Result<Vec<u8>> {
+    Ok(decode_address(address)?.1)
+}
 
-    let actual_checksum = payload[payload.len() - ADDRESS_CHECK_SUM_LEN..].to_vec();
-    let version = payload[0];
-    let pub_key_hash = payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN].to_vec();
+/// The address with `version` for `pub_key_hash`, or for a multisig policy given as
+/// `[required, key count, key hashes...]` under `MULTISIG_ADDRESS_VERSION`
+pub fn convert_address(version: u8, pub_key_hash: &[u8]) -> Result<String> {
+    check_body(version, pub_key_hash).map_err(|reason| {
+        BlockchainError::InvalidAddress(format!(
+            "Cannot encode {} as an address: it {reason}",
+            HEXLOWER.encode(pub_key_hash)
+        ))
+    })?;
+    Ok(encode_address(version, pub_key_hash))
+}
 
-    let mut target_vec = vec![];
-    target_vec.push(version);
-    target_vec.extend(pub_key_hash);
-    let target_checksum = checksum(target_vec.as_slice());
-    actual_checksum.eq(target_checksum.as_slice())
+/// The address of a multisig policy, given as `[required, key count, key hashes...]`
+pub fn convert_multisig_address(policy: &[u8]) -> String {
+    encode_address(MULTISIG_ADDRESS_VERSION, policy)
 }
 
-pub fn convert_address(pub_hash_key: &[u8]) -> String {
+// Why an address with `version` can't carry `body`, if it can't
+fn check_body(version: u8, body: &[u8]) -> std::result::Result<(), String> {
+    let expected_len = match version {
+        ADDRESS_VERSION => PUB_KEY_HASH_LEN,
+        MULTISIG_ADDRESS_VERSION => match body {
+            [_, key_count, ..] => 2 + usize::from(*key_count) * PUB_KEY_HASH_LEN,
+            _ => 2,
+        },
+        _ => {
+            return Err(format!(
+                "has version 0x{version:02x}, unknown on this network"
+            ))
+        }
+    };
+    if body.len() != expected_len {
+        return Err(format!(
+            "carries {} bytes where version 0x{version:02x} calls for {expected_len}",
+            body.len()
+        ));
+    }
+    Ok(())
+}
+
+// version + payload + checksum
+fn encode_address(version: u8, body: &[u8]) -> String {
     let mut payload: Vec<u8> = vec![];
-    payload.push(VERSION);
-    payload.extend(pub_hash_key);
+    payload.push(version);
+    payload.extend(body);
     let checksum = checksum(payload.as_slice());
     payload.extend(checksum.as_slice());
     crate::utils::base58_encode(payload.as_slice())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::base58_encode;
+
+    // With a checksum that matches, so only the version and length can be wrong
+    fn checksummed(payload: &[u8]) -> String {
+        let mut payload = payload.to_vec();
+        payload.extend(checksum(&payload));
+        base58_encode(&payload)
+    }
+
+    #[test]
+    fn test_valid_addresses_decode_to_their_hash() {
+        let wallet = Wallet::new().unwrap();
+        let address = wallet.get_address();
+        let pub_key_hash = hash_pub_key(wallet.get_public_key());
+        assert!(validate_address(&address).is_ok());
+        assert_eq!(
+            decode_address(&address).unwrap(),
+            (ADDRESS_VERSION, pub_key_hash.clone())
+        );
+        assert_eq!(
+            convert_address(ADDRESS_VERSION, &pub_key_hash).unwrap(),
+            address
+        );
+        assert!(validate_address("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").is_ok());
+
+        let mut policy = vec![1, 2];
+        policy.extend(&pub_key_hash);
+        policy.extend([7; 20]);
+        let multisig = convert_multisig_address(&policy);
+        assert_eq!(
+            decode_address(&multisig).unwrap(),
+            (MULTISIG_ADDRESS_VERSION, policy)
+        );
+    }
+
+    #[test]
+    fn test_malformed_addresses_are_errors() {
+        let pub_key_hash = hash_pub_key(Wallet::new().unwrap().get_public_key());
+        let mut payload = vec![ADDRESS_VERSION];
+        payload.extend(&pub_key_hash);
+        payload.extend(checksum(&payload));
+
+        for len in 0..payload.len() {
+            let truncated = base58_encode(&payload[..len]);
+            assert!(decode_address(&truncated).is_err(), "{len} bytes");
+            // Still too short, even with a checksum of its own
+            assert!(decode_address(&checksummed(&payload[..len.min(20)])).is_err());
+        }
+        for byte in 0..payload.len() {
+            for bit in 0..8 {
+                let mut flipped = payload.clone();
+                flipped[byte] ^= 1 << bit;
+                assert!(decode_address(&base58_encode(&flipped)).is_err());
+            }
+        }
+
+        let mut testnet = vec![0x6f];
+        testnet.extend(&pub_key_hash);
+        assert!(decode_address(&checksummed(&testnet)).is_err());
+        assert!(convert_address(0x6f, &pub_key_hash).is_err());
+        assert!(convert_address(ADDRESS_VERSION, &pub_key_hash[1..]).is_err());
+        // A multisig address naming two keys but carrying one
+        let mut policy = vec![MULTISIG_ADDRESS_VERSION, 1, 2];
+        policy.extend(&pub_key_hash);
+        assert!(decode_address(&checksummed(&policy)).is_err());
+
+        for address in [
+            "",
+            " ",
+            "0OIl",
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfN0",
+            "адрес",
+        ] {
+            assert!(validate_address(address).is_err(), "{address:?}");
+        }
+    }
+
+    #[test]
+    fn test_random_input_never_panics() {
+        const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+        // A fixed linear congruential generator, so a failure can be replayed
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) as usize
+        };
+
+        for _ in 0..2_000 {
+            let len = next() % 40;
+            let address: String = (0..len)
+                .map(|_| ALPHABET[next() % ALPHABET.len()] as char)
+                .collect();
+            if let Ok((version, body)) = decode_address(&address) {
+                assert_eq!(convert_address(version, &body).unwrap(), address);
+            }
+        }
+    }
+}
@@ -1,15 +1,342 @@
-use crate::error::Result;
-use crate::utils::{deserialize, serialize};
+use crate::config::GLOBAL_CONFIG;
+use crate::core::{
+    AddressTx, Amount, Blockchain, PaymentDirection, SelectionStrategy, TXOutput, Transaction,
+};
+use crate::error::{BlockchainError, Result};
+use crate::storage::UTXOSet;
+use crate::utils::{current_timestamp, deserialize, serialize};
+use crate::wallet::accounts::{Account, Accounts};
+use crate::wallet::backup::{self, WalletBackupHeader};
+use crate::wallet::drafts::{Draft, DraftState, Drafts};
+use crate::wallet::file_lock::{write_atomically, WalletFileLock};
+use crate::wallet::wallet::{address_pub_key_hash, decode_address, ADDRESS_VERSION};
 use crate::wallet::Wallet;
-use std::collections::HashMap;
-use std::env::current_dir;
-use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Read, Write};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 pub const WALLET_FILE: &str = "wallet.dat";
+/// Directory next to the wallet file where a wallet file replaced by a restore is kept
+pub const WALLET_BACKUP_DIR: &str = "wallet_backups";
+
+/// Magic bytes at the start of a versioned wallet file
+const WALLET_FILE_MAGIC: [u8; 4] = *b"ACWF";
+/// Current version of the wallet file layout
+pub const WALLET_FILE_VERSION: u32 = 10;
+
+/// On-disk layout of the wallet file
+///
+/// Version 1 files are a bare bincode `HashMap<String, Wallet>` with no header, version 2
+/// files have no spend policies, version 3 files no watch-only addresses, version 4 files
+/// no birthday heights, version 5 files no accounts, version 6 files no change address
+/// policy, version 7 files no key metadata, version 8 files no drafts and version 9 files
+/// no coin selection; all are still read and get rewritten in this layout the next time
+/// the wallets are saved.
+///
+/// Encrypted wallet files keep this layout inside their ciphertext.
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub(crate) struct WalletFile {
+    pub(crate) base: WalletFileV7,
+    pub(crate) metadata: HashMap<String, WalletMetadata>,
+    pub(crate) drafts: Drafts,
+    coin_selection: Option<SelectionStrategy>,
+}
+
+/// Version 9 of the wallet file layout
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct WalletFileV9 {
+    base: WalletFileV7,
+    metadata: HashMap<String, WalletMetadata>,
+    drafts: Drafts,
+}
+
+/// Version 8 of the wallet file layout
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct WalletFileV8 {
+    base: WalletFileV7,
+    metadata: HashMap<String, WalletMetadata>,
+}
+
+/// Version 7 of the wallet file layout, which every later version starts with
+///
+/// Bincode writes the fields of a nested struct in place, so a later layout is this one
+/// followed by the fields it added.
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+pub(crate) struct WalletFileV7 {
+    magic: [u8; 4],
+    version: u32,
+    pub(crate) wallets: HashMap<String, Wallet>,
+    pub(crate) labels: HashMap<String, String>,
+    policies: HashMap<String, SpendPolicy>,
+    /// Public key hash of each watch-only address
+    watch_only: HashMap<String, Vec<u8>>,
+    birthdays: HashMap<String, usize>,
+    accounts: Accounts,
+    use_change_addresses: bool,
+    change_addresses: BTreeSet<String>,
+}
+
+/// Version 6 of the wallet file layout
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct WalletFileV6 {
+    magic: [u8; 4],
+    version: u32,
+    wallets: HashMap<String, Wallet>,
+    labels: HashMap<String, String>,
+    policies: HashMap<String, SpendPolicy>,
+    watch_only: HashMap<String, Vec<u8>>,
+    birthdays: HashMap<String, usize>,
+    accounts: Accounts,
+}
+
+/// Version 5 of the wallet file layout
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct WalletFileV5 {
+    magic: [u8; 4],
+    version: u32,
+    wallets: HashMap<String, Wallet>,
+    labels: HashMap<String, String>,
+    policies: HashMap<String, SpendPolicy>,
+    watch_only: HashMap<String, Vec<u8>>,
+    birthdays: HashMap<String, usize>,
+}
+
+/// Version 4 of the wallet file layout
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct WalletFileV4 {
+    magic: [u8; 4],
+    version: u32,
+    wallets: HashMap<String, Wallet>,
+    labels: HashMap<String, String>,
+    policies: HashMap<String, SpendPolicy>,
+    watch_only: HashMap<String, Vec<u8>>,
+}
+
+/// Version 3 of the wallet file layout
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct WalletFileV3 {
+    magic: [u8; 4],
+    version: u32,
+    wallets: HashMap<String, Wallet>,
+    labels: HashMap<String, String>,
+    policies: HashMap<String, SpendPolicy>,
+}
+
+/// Version 2 of the wallet file layout
+#[derive(Serialize, Deserialize, bincode::Encode, bincode::Decode)]
+struct WalletFileV2 {
+    magic: [u8; 4],
+    version: u32,
+    wallets: HashMap<String, Wallet>,
+    labels: HashMap<String, String>,
+}
+
+impl WalletFileV7 {
+    // The fields every layout has, in the current version with nothing in them
+    fn empty() -> WalletFileV7 {
+        WalletFileV7 {
+            magic: WALLET_FILE_MAGIC,
+            version: WALLET_FILE_VERSION,
+            wallets: HashMap::new(),
+            labels: HashMap::new(),
+            policies: HashMap::new(),
+            watch_only: HashMap::new(),
+            birthdays: HashMap::new(),
+            accounts: Accounts::default(),
+            use_change_addresses: false,
+            change_addresses: BTreeSet::new(),
+        }
+    }
+}
+
+impl WalletFile {
+    /// A file in the current layout with nothing in it
+    pub(crate) fn empty() -> WalletFile {
+        WalletFile::upgrade(WalletFileV7::empty())
+    }
+
+    // `base` in the current layout, with none of what later versions added
+    fn upgrade(base: WalletFileV7) -> WalletFile {
+        WalletFile {
+            base: WalletFileV7 {
+                version: WALLET_FILE_VERSION,
+                ..base
+            },
+            metadata: HashMap::new(),
+            drafts: Drafts::default(),
+            coin_selection: None,
+        }
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<WalletFile> {
+        if !bytes.starts_with(&WALLET_FILE_MAGIC) {
+            let wallets: HashMap<String, Wallet> = deserialize(bytes)?;
+            return Ok(WalletFile::upgrade(WalletFileV7 {
+                wallets,
+                ..WalletFileV7::empty()
+            }));
+        }
+
+        let (_, version): ([u8; 4], u32) = deserialize(bytes)?;
+        match version {
+            2 => {
+                let file: WalletFileV2 = deserialize(bytes)?;
+                Ok(WalletFile::upgrade(WalletFileV7 {
+                    wallets: file.wallets,
+                    labels: file.labels,
+                    ..WalletFileV7::empty()
+                }))
+            }
+            3 => {
+                let file: WalletFileV3 = deserialize(bytes)?;
+                Ok(WalletFile::upgrade(WalletFileV7 {
+                    wallets: file.wallets,
+                    labels: file.labels,
+                    policies: file.policies,
+                    ..WalletFileV7::empty()
+                }))
+            }
+            4 => {
+                let file: WalletFileV4 = deserialize(bytes)?;
+                Ok(WalletFile::upgrade(WalletFileV7 {
+                    wallets: file.wallets,
+                    labels: file.labels,
+                    policies: file.policies,
+                    watch_only: file.watch_only,
+                    ..WalletFileV7::empty()
+                }))
+            }
+            5 => {
+                let file: WalletFileV5 = deserialize(bytes)?;
+                Ok(WalletFile::upgrade(WalletFileV7 {
+                    wallets: file.wallets,
+                    labels: file.labels,
+                    policies: file.policies,
+                    watch_only: file.watch_only,
+                    birthdays: file.birthdays,
+                    ..WalletFileV7::empty()
+                }))
+            }
+            6 => {
+                let file: WalletFileV6 = deserialize(bytes)?;
+                Ok(WalletFile::upgrade(WalletFileV7 {
+                    wallets: file.wallets,
+                    labels: file.labels,
+                    policies: file.policies,
+                    watch_only: file.watch_only,
+                    birthdays: file.birthdays,
+                    accounts: file.accounts,
+                    ..WalletFileV7::empty()
+                }))
+            }
+            7 => Ok(WalletFile::upgrade(deserialize(bytes)?)),
+            8 => {
+                let file: WalletFileV8 = deserialize(bytes)?;
+                Ok(WalletFile {
+                    metadata: file.metadata,
+                    ..WalletFile::upgrade(file.base)
+                })
+            }
+            9 => {
+                let file: WalletFileV9 = deserialize(bytes)?;
+                Ok(WalletFile {
+                    metadata: file.metadata,
+                    drafts: file.drafts,
+                    ..WalletFile::upgrade(file.base)
+                })
+            }
+            WALLET_FILE_VERSION => deserialize(bytes),
+            _ => Err(BlockchainError::Wallet(format!(
+                "Unsupported wallet file version {version} (expected {WALLET_FILE_VERSION})"
+            ))),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        serialize(self)
+    }
+}
+
+/// Limits on what `send` pays from an address in one transaction, in satoshis
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    bincode::Encode,
+    bincode::Decode,
+)]
+pub struct SpendPolicy {
+    /// Larger payments have to be confirmed by typing the amount back, or with `--yes`
+    pub max_send_without_confirm: Option<u64>,
+    /// Larger payments are refused, confirmed or not
+    pub spend_limit_per_tx: Option<u64>,
+}
+
+impl SpendPolicy {
+    pub fn is_unrestricted(&self) -> bool {
+        self.max_send_without_confirm.is_none() && self.spend_limit_per_tx.is_none()
+    }
+
+    /// Check a payment of `amount` satoshis, returning whether it needs confirming
+    pub fn check(&self, amount: u64) -> Result<bool> {
+        if let Some(limit) = self.spend_limit_per_tx {
+            if amount > limit {
+                return Err(BlockchainError::Wallet(format!(
+                    "Sending {amount} satoshis exceeds this address's limit of {limit} per transaction"
+                )));
+            }
+        }
+        Ok(self
+            .max_send_without_confirm
+            .is_some_and(|threshold| amount > threshold))
+    }
+}
+
+/// What the wallet file recorded about a key when it was created
+///
+/// Keys from files written before this was recorded have the default: no creation time
+/// and no chain, so they pair with any chain.
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, bincode::Encode, bincode::Decode,
+)]
+pub struct WalletMetadata {
+    /// Milliseconds since the epoch, 0 if unknown
+    pub created_at: i64,
+    /// Genesis hash of the chain the key was created for, if one was open at the time
+    pub genesis_hash: Option<String>,
+    pub notes: Option<String>,
+}
 
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    /// Optional label per address
+    labels: HashMap<String, String>,
+    /// Spend policy per address; addresses without one are unrestricted
+    policies: HashMap<String, SpendPolicy>,
+    /// Addresses tracked without their key, with the public key hash each one pays
+    watch_only: HashMap<String, Vec<u8>>,
+    /// Height of the first block that could pay each address, for those that have one
+    birthdays: HashMap<String, usize>,
+    accounts: Accounts,
+    /// Whether payments send their change to a new address of this file instead of back
+    /// to the sender
+    use_change_addresses: bool,
+    /// The addresses created to take change
+    change_addresses: BTreeSet<String>,
+    /// When and for which chain each key was created; its birthday is in `birthdays`
+    metadata: HashMap<String, WalletMetadata>,
+    drafts: Drafts,
+    /// How payments pick their coins unless told otherwise, chainstate order if `None`
+    coin_selection: Option<SelectionStrategy>,
+    /// The wallet file, `None` for the one in the configured data directory
+    path: Option<PathBuf>,
 }
 
 impl Default for Wallets {
@@ -19,22 +346,148 @@ impl Default for Wallets {
 }
 
 impl Wallets {
+    /// The wallets in the file `wallet_path` resolves to
     pub fn new() -> Wallets {
-        let mut wallets = Wallets {
-            wallets: HashMap::new(),
-        };
+        let mut wallets = Self::empty(None);
+        wallets.load_from_file();
+        wallets
+    }
+
+    /// The wallets in the file at `path`, which is created with the first wallet
+    ///
+    /// Like `new`, a file that can't be read leaves the set empty.
+    pub fn new_with_path(path: &Path) -> Wallets {
+        let mut wallets = Self::empty(Some(path.to_path_buf()));
         wallets.load_from_file();
         wallets
     }
 
+    /// The wallets of node `node_id`, in the file `node_wallet_path` resolves to
+    pub fn default_for_node(node_id: &str) -> Result<Wallets> {
+        Ok(Self::new_with_path(&node_wallet_path(node_id)?))
+    }
+
+    /// Like `new_with_path`, but a file that can't be read is an error rather than an
+    /// empty set
+    pub fn open(path: &Path) -> Result<Wallets> {
+        let mut wallets = Self::empty(Some(path.to_path_buf()));
+        wallets.load_from_file_safe()?;
+        Ok(wallets)
+    }
+
+    // Another handle on the same wallet file, which reloads it before any change
+    pub(crate) fn reopen(&self) -> Wallets {
+        Self::empty(self.path.clone())
+    }
+
+    fn empty(path: Option<PathBuf>) -> Wallets {
+        Wallets {
+            wallets: HashMap::new(),
+            labels: HashMap::new(),
+            policies: HashMap::new(),
+            watch_only: HashMap::new(),
+            birthdays: HashMap::new(),
+            accounts: Accounts::default(),
+            use_change_addresses: false,
+            change_addresses: BTreeSet::new(),
+            metadata: HashMap::new(),
+            drafts: Drafts::default(),
+            coin_selection: None,
+            path,
+        }
+    }
+
     pub fn create_wallet(&mut self) -> Result<String> {
+        self.create(None, None)
+    }
+
+    /// Create a wallet and label it in one step
+    pub fn create_wallet_with_label(&mut self, label: &str) -> Result<String> {
+        self.create(Some(label), None)
+    }
+
+    /// Create a wallet for `blockchain`, optionally labeled
+    ///
+    /// The key records the chain's genesis hash, so using it with another chain is refused
+    /// by `check_network`. Nothing in the chain up to now can pay a key that didn't exist,
+    /// so its birthday is set a finality depth below the tip, in case the tip is replaced.
+    pub fn create_wallet_for_chain(
+        &mut self,
+        label: Option<&str>,
+        blockchain: &Blockchain,
+    ) -> Result<String> {
+        let birthday = blockchain
+            .get_best_height()?
+            .saturating_sub(blockchain.get_finality_depth());
+        self.create(label, Some((blockchain.get_genesis_hash()?, birthday)))
+    }
+
+    fn create(&mut self, label: Option<&str>, chain: Option<(String, usize)>) -> Result<String> {
         let wallet = Wallet::new()?;
         let address = wallet.get_address();
-        self.wallets.insert(address.clone(), wallet);
-        self.save_to_file();
+        let created_at = current_timestamp()?;
+        self.update(|wallets| {
+            // Check the label first so a bad label doesn't leave an unlabeled wallet
+            if let Some(label) = label {
+                wallets.check_label_available(label, None)?;
+                wallets.labels.insert(address.clone(), label.to_string());
+            }
+            let (genesis_hash, birthday) = chain.unzip();
+            if let Some(birthday) = birthday {
+                wallets.birthdays.insert(address.clone(), birthday);
+            }
+            wallets.metadata.insert(
+                address.clone(),
+                WalletMetadata {
+                    created_at,
+                    genesis_hash,
+                    notes: None,
+                },
+            );
+            wallets.wallets.insert(address.clone(), wallet);
+            Ok(())
+        })?;
         Ok(address)
     }
 
+    /// Track `address` without its key, e.g. one whose key is kept offline
+    ///
+    /// Its balance can be read and payments from it built like any other address's, but
+    /// they come out unsigned, see `Transaction::new_unsigned_utxo_transaction`.
+    pub fn add_watch_only(&mut self, address: &str, label: Option<&str>) -> Result<()> {
+        let (version, pub_key_hash) = decode_address(address)?;
+        if version != ADDRESS_VERSION {
+            return Err(BlockchainError::InvalidAddress(format!(
+                "Only single-key addresses can be watched, {address} is not one"
+            )));
+        }
+        self.update(|wallets| {
+            if wallets.wallets.contains_key(address) {
+                return Err(BlockchainError::Wallet(format!(
+                    "Address {address} is already in this wallet with its key"
+                )));
+            }
+            if let Some(label) = label {
+                wallets.check_label_available(label, Some(address))?;
+                wallets
+                    .labels
+                    .insert(address.to_string(), label.to_string());
+            }
+            wallets.watch_only.insert(address.to_string(), pub_key_hash);
+            Ok(())
+        })
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch_only.contains_key(address)
+    }
+
+    /// The watch-only addresses, whose keys this wallet file doesn't hold
+    pub fn get_watch_only_addresses(&self) -> Vec<String> {
+        self.watch_only.keys().cloned().collect()
+    }
+
+    /// Every address with a key in this wallet file; watch-only ones are not included
     pub fn get_addresses(&self) -> Vec<String> {
         let mut addresses = vec![];
         for address in self.wallets.keys() {
@@ -50,6 +503,532 @@ impl Wallets {
         None
     }
 
+    /// The wallet holding the key of `address`, or an error saying why there is none
+    pub fn signing_wallet(&self, address: &str) -> Result<&Wallet> {
+        if let Some(wallet) = self.wallets.get(address) {
+            return Ok(wallet);
+        }
+        if self.is_watch_only(address) {
+            return Err(BlockchainError::Wallet(format!(
+                "Address {address} is watch-only, this wallet has no key to sign for it"
+            )));
+        }
+        Err(BlockchainError::Wallet(format!(
+            "Wallet not found for address: {address}"
+        )))
+    }
+
+    /// Refuse `tx` unless every one of its inputs is signed with a key this wallet file holds
+    pub fn check_spends_own_coins(&self, tx: &Transaction) -> Result<()> {
+        let own = tx.get_vin().iter().all(|input| {
+            self.wallets
+                .values()
+                .any(|wallet| wallet.get_public_key() == input.get_pub_key())
+        });
+        if own {
+            return Ok(());
+        }
+        Err(BlockchainError::Wallet(format!(
+            "Transaction {} spends coins this wallet has no key of",
+            HEXLOWER.encode(tx.get_id())
+        )))
+    }
+
+    /// Label one of the addresses in this wallet file, replacing any previous label
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<()> {
+        self.update(|wallets| {
+            if !wallets.wallets.contains_key(address) && !wallets.is_watch_only(address) {
+                return Err(BlockchainError::Wallet(format!(
+                    "Address {address} is not in this wallet"
+                )));
+            }
+            wallets.check_label_available(label, Some(address))?;
+
+            wallets
+                .labels
+                .insert(address.to_string(), label.to_string());
+            Ok(())
+        })
+    }
+
+    /// The height of the first block that can hold payments to `address`, if it is known
+    ///
+    /// A rescan for an address with a birthday starts there instead of at genesis.
+    pub fn get_birthday_height(&self, address: &str) -> Option<usize> {
+        self.birthdays.get(address).copied()
+    }
+
+    /// Record that nothing before block `height` pays one of the addresses in this file
+    pub fn set_birthday_height(&mut self, address: &str, height: usize) -> Result<()> {
+        self.update(|wallets| {
+            if !wallets.wallets.contains_key(address) && !wallets.is_watch_only(address) {
+                return Err(BlockchainError::Wallet(format!(
+                    "Address {address} is not in this wallet"
+                )));
+            }
+            wallets.birthdays.insert(address.to_string(), height);
+            Ok(())
+        })
+    }
+
+    /// What was recorded about the key of `address` when it was created
+    pub fn get_metadata(&self, address: &str) -> Option<&WalletMetadata> {
+        self.metadata.get(address)
+    }
+
+    /// Attach `notes` to one of the keys in this file, or clear them with `None`
+    pub fn set_notes(&mut self, address: &str, notes: Option<&str>) -> Result<()> {
+        self.update(|wallets| {
+            if !wallets.wallets.contains_key(address) {
+                return Err(BlockchainError::Wallet(format!(
+                    "Address {address} is not in this wallet"
+                )));
+            }
+            wallets
+                .metadata
+                .entry(address.to_string())
+                .or_default()
+                .notes = notes.map(str::to_string);
+            Ok(())
+        })
+    }
+
+    /// Refuse to pair `blockchain` with any of `addresses` created for another chain
+    ///
+    /// Addresses created without a chain open, or before the chain was recorded, pass.
+    pub fn check_network(&self, addresses: &[String], blockchain: &Blockchain) -> Result<()> {
+        let mut chain = None;
+        for address in addresses {
+            let Some(created_on) = self
+                .metadata
+                .get(address)
+                .and_then(|metadata| metadata.genesis_hash.as_ref())
+            else {
+                continue;
+            };
+            let chain = match &chain {
+                Some(chain) => chain,
+                None => chain.insert(blockchain.get_genesis_hash()?),
+            };
+            if created_on != chain {
+                return Err(BlockchainError::WalletNetworkMismatch {
+                    address: address.clone(),
+                    created_on: created_on.clone(),
+                    chain: chain.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Where a rescan for `addresses` has to start: the earliest of their birthdays, or
+    /// genesis if any of them has none
+    pub fn rescan_height(&self, addresses: &[String]) -> usize {
+        addresses
+            .iter()
+            .map(|address| self.get_birthday_height(address).unwrap_or(0))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Get the spend policy of an address, unrestricted unless one was set
+    pub fn get_policy(&self, address: &str) -> SpendPolicy {
+        self.policies.get(address).copied().unwrap_or_default()
+    }
+
+    /// Set the spend policy of one of the addresses in this wallet file
+    ///
+    /// An unrestricted policy removes the address's entry.
+    pub fn set_policy(&mut self, address: &str, policy: SpendPolicy) -> Result<()> {
+        self.update(|wallets| {
+            if !wallets.wallets.contains_key(address) {
+                return Err(BlockchainError::Wallet(format!(
+                    "Address {address} is not in this wallet"
+                )));
+            }
+            if policy.is_unrestricted() {
+                wallets.policies.remove(address);
+            } else {
+                wallets.policies.insert(address.to_string(), policy);
+            }
+            Ok(())
+        })
+    }
+
+    /// Get the label of an address, if it has one
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        self.labels.get(address).map(String::as_str)
+    }
+
+    /// Find every address carrying `label`
+    pub fn find_by_label(&self, label: &str) -> Vec<String> {
+        let mut addresses: Vec<String> = self
+            .labels
+            .iter()
+            .filter(|(_, l)| l.as_str() == label)
+            .map(|(address, _)| address.clone())
+            .collect();
+        addresses.sort();
+        addresses
+    }
+
+    /// Resolve a label to exactly one address
+    pub fn resolve_label(&self, label: &str) -> Result<String> {
+        let mut addresses = self.find_by_label(label);
+        match addresses.len() {
+            0 => Err(BlockchainError::Wallet(format!(
+                "No address is labeled '{label}'"
+            ))),
+            1 => Ok(addresses.remove(0)),
+            _ => Err(BlockchainError::Wallet(format!(
+                "Label '{label}' is ambiguous, it matches {}",
+                addresses.join(", ")
+            ))),
+        }
+    }
+
+    // Labels must be non-empty and unique; `owner` may already hold the label
+    fn check_label_available(&self, label: &str, owner: Option<&str>) -> Result<()> {
+        if label.trim().is_empty() {
+            return Err(BlockchainError::Wallet(
+                "Label must not be empty".to_string(),
+            ));
+        }
+        if let Some(existing) = self
+            .find_by_label(label)
+            .into_iter()
+            .find(|address| Some(address.as_str()) != owner)
+        {
+            return Err(BlockchainError::Wallet(format!(
+                "Label '{label}' is already used by {existing}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// The accounts grouping the addresses of this wallet file
+    pub fn accounts(&self) -> &Accounts {
+        &self.accounts
+    }
+
+    /// The account named `name`, or an error if there is none
+    pub fn get_account(&self, name: &str) -> Result<&Account> {
+        self.accounts
+            .get(name)
+            .ok_or_else(|| BlockchainError::Wallet(format!("No account is named '{name}'")))
+    }
+
+    /// Start an account with no addresses
+    pub fn create_account(&mut self, name: &str) -> Result<()> {
+        self.update(|wallets| wallets.accounts.create(name))
+    }
+
+    /// Add one of the addresses this wallet file has the key of to account `account`
+    ///
+    /// Refused if the address is in another account already; the first address of an
+    /// account becomes its change address.
+    pub fn assign_address(&mut self, account: &str, address: &str) -> Result<()> {
+        self.update(|wallets| {
+            if !wallets.wallets.contains_key(address) {
+                return Err(BlockchainError::Wallet(if wallets.is_watch_only(address) {
+                    format!("Address {address} is watch-only, an account can only spend from addresses with their key")
+                } else {
+                    format!("Address {address} is not in this wallet")
+                }));
+            }
+            wallets.accounts.assign(account, address)
+        })
+    }
+
+    /// Take `address` out of account `account`, returning what it still holds
+    ///
+    /// The coins stay with the address, they just no longer count toward the account, so
+    /// callers should warn when the returned amount isn't zero.
+    pub fn remove_address(
+        &mut self,
+        account: &str,
+        address: &str,
+        utxo_set: &UTXOSet,
+    ) -> Result<Amount> {
+        let balance = address_balance(address, utxo_set)?;
+        self.update(|wallets| wallets.accounts.remove_address(account, address))?;
+        if balance > Amount::ZERO {
+            log::warn!(
+                "Address {address} left account '{account}' holding {} satoshis",
+                balance.to_satoshis()
+            );
+        }
+        Ok(balance)
+    }
+
+    /// Make `address`, already in account `account`, the one its change is paid to
+    pub fn set_change_address(&mut self, account: &str, address: &str) -> Result<()> {
+        self.update(|wallets| wallets.accounts.set_change_address(account, address))
+    }
+
+    /// The confirmed balance of every address in account `account` together
+    pub fn account_balance(&self, account: &str, utxo_set: &UTXOSet) -> Result<Amount> {
+        let mut total = Amount::ZERO;
+        for address in self.get_account(account)?.addresses() {
+            total = total.checked_add(address_balance(address, utxo_set)?)?;
+        }
+        Ok(total)
+    }
+
+    /// Whether payments send their change to a new address instead of back to the sender
+    pub fn uses_change_addresses(&self) -> bool {
+        self.use_change_addresses
+    }
+
+    /// Turn the change address policy on or off for every later payment from this file
+    ///
+    /// Sending change back to the address it came from spends from that address again
+    /// the next time, tying its payments together; a new address for each change doesn't.
+    pub fn set_use_change_addresses(&mut self, enabled: bool) -> Result<()> {
+        self.update(|wallets| {
+            wallets.use_change_addresses = enabled;
+            Ok(())
+        })
+    }
+
+    /// How payments from this file pick their coins when `send` isn't told, `None` for
+    /// chainstate order
+    pub fn default_coin_selection(&self) -> Option<SelectionStrategy> {
+        self.coin_selection
+    }
+
+    /// Set how every later payment from this file picks its coins, see `core::coin_selection`
+    pub fn set_default_coin_selection(
+        &mut self,
+        strategy: Option<SelectionStrategy>,
+    ) -> Result<()> {
+        self.update(|wallets| {
+            wallets.coin_selection = strategy;
+            Ok(())
+        })
+    }
+
+    /// Create a key to take a payment's change, saved before the payment is signed so the
+    /// change is never sent to an address the file doesn't hold
+    pub fn create_change_address(&mut self) -> Result<String> {
+        let wallet = Wallet::new()?;
+        let address = wallet.get_address();
+        self.update(|wallets| {
+            wallets.wallets.insert(address.clone(), wallet);
+            wallets.change_addresses.insert(address.clone());
+            Ok(())
+        })?;
+        Ok(address)
+    }
+
+    /// Whether `address` was created by `create_change_address`
+    pub fn is_change_address(&self, address: &str) -> bool {
+        self.change_addresses.contains(address)
+    }
+
+    /// The confirmed balance of every address this file has the key of together, change
+    /// addresses included
+    pub fn balance(&self, utxo_set: &UTXOSet) -> Result<Amount> {
+        let mut total = Amount::ZERO;
+        for address in self.wallets.keys() {
+            total = total.checked_add(address_balance(address, utxo_set)?)?;
+        }
+        Ok(total)
+    }
+
+    /// Every main-chain transaction that moved the coins of the addresses this file has
+    /// the key of, oldest first
+    ///
+    /// Each transaction shows up once with what it did to the file as a whole, so a
+    /// payment whose change went to another of its addresses shows the amount sent and
+    /// the fee, and one between two of its addresses only the fee.
+    pub fn history(&self, blockchain: &Blockchain) -> Result<Vec<AddressTx>> {
+        // Net satoshis in per transaction, in the order they were first seen
+        let mut net: Vec<(String, usize, i128)> = Vec::new();
+        for address in self.wallets.keys() {
+            for entry in blockchain.address_history(&address_pub_key_hash(address)?)? {
+                let amount = i128::from(entry.amount);
+                let amount = match entry.direction {
+                    PaymentDirection::Incoming => amount,
+                    PaymentDirection::Outgoing => -amount,
+                };
+                match net.iter_mut().find(|(txid, _, _)| *txid == entry.txid) {
+                    Some((_, _, total)) => *total += amount,
+                    None => net.push((entry.txid, entry.height, amount)),
+                }
+            }
+        }
+        net.sort_by_key(|(_, height, _)| *height);
+        Ok(net
+            .into_iter()
+            .map(|(txid, height, total)| AddressTx {
+                txid,
+                height,
+                direction: if total < 0 {
+                    PaymentDirection::Outgoing
+                } else {
+                    PaymentDirection::Incoming
+                },
+                amount: total.unsigned_abs() as u64,
+            })
+            .collect())
+    }
+
+    /// Keep the serialized `transaction` under `name` until it is finished, see `Drafts`
+    pub fn save_draft(&mut self, name: &str, transaction: &[u8], state: DraftState) -> Result<()> {
+        let saved_at = current_timestamp()?;
+        self.update(|wallets| wallets.drafts.save(name, transaction, state, saved_at))
+    }
+
+    /// Every draft, sorted by name
+    pub fn list_drafts(&self) -> impl Iterator<Item = (&str, &Draft)> {
+        self.drafts.iter()
+    }
+
+    pub fn load_draft(&self, name: &str) -> Result<&Draft> {
+        self.drafts
+            .get(name)
+            .ok_or_else(|| BlockchainError::Wallet(format!("No draft named '{name}'")))
+    }
+
+    /// Forget draft `name`, returning it
+    pub fn delete_draft(&mut self, name: &str) -> Result<Draft> {
+        self.update(|wallets| wallets.drafts.delete(name))
+    }
+
+    /// Write every wallet and label in the wallet file to a backup at `path`
+    ///
+    /// With a password the backup is encrypted, even when the wallet file itself isn't.
+    pub fn backup(&mut self, path: &Path, password: Option<&str>) -> Result<WalletBackupHeader> {
+        let wallet_path = self.file_path()?;
+        let _lock = WalletFileLock::acquire(&wallet_path)?;
+        self.read_from(&wallet_path)?;
+
+        let (header, bytes) = backup::encode_backup(&self.encode()?, self.wallets.len(), password)?;
+        write_atomically(path, &bytes)
+            .map_err(|e| BlockchainError::Wallet(format!("Failed to write wallet backup: {e}")))?;
+        Ok(header)
+    }
+
+    /// Restore the wallets in the backup at `path`
+    ///
+    /// The backup is checked and decrypted in full before the wallet file is touched.
+    /// Without `merge` the wallet file is replaced by the backup, after the current one is
+    /// copied to `WALLET_BACKUP_DIR`. With `merge` the backed up addresses missing from the
+    /// wallet file are added and every key already in it is kept.
+    pub fn restore(
+        &mut self,
+        path: &Path,
+        password: Option<&str>,
+        merge: bool,
+    ) -> Result<RestoreSummary> {
+        let bytes = fs::read(path)
+            .map_err(|e| BlockchainError::Wallet(format!("Failed to read wallet backup: {e}")))?;
+        let (header, wallet_file) = backup::decode_backup(&bytes, password)?;
+        let restored = WalletFile::decode(&wallet_file)?;
+        // The checksum only vouches for what was written; a header counting other wallets
+        // than the payload holds was written wrong, and so may the payload have been
+        if restored.base.wallets.len() as u64 != header.wallet_count {
+            return Err(BlockchainError::Wallet(format!(
+                "Wallet backup says it holds {} wallets but has {}",
+                header.wallet_count,
+                restored.base.wallets.len()
+            )));
+        }
+
+        if merge {
+            return self.update(|wallets| {
+                let mut added = 0;
+                for (address, wallet) in restored.base.wallets {
+                    if wallets.wallets.contains_key(&address) {
+                        continue;
+                    }
+                    wallets.wallets.insert(address, wallet);
+                    added += 1;
+                }
+                // A key restored for a watched address makes watching it redundant
+                for (address, pub_key_hash) in restored.base.watch_only {
+                    if !wallets.wallets.contains_key(&address) {
+                        wallets.watch_only.entry(address).or_insert(pub_key_hash);
+                    }
+                }
+                wallets
+                    .watch_only
+                    .retain(|address, _| !wallets.wallets.contains_key(address));
+                // A backed up label is only taken where it neither replaces nor duplicates one
+                for (address, label) in restored.base.labels {
+                    if (wallets.wallets.contains_key(&address) || wallets.is_watch_only(&address))
+                        && !wallets.labels.contains_key(&address)
+                        && wallets.find_by_label(&label).is_empty()
+                    {
+                        wallets.labels.insert(address, label);
+                    }
+                }
+                for (address, policy) in restored.base.policies {
+                    if wallets.wallets.contains_key(&address) {
+                        wallets.policies.entry(address).or_insert(policy);
+                    }
+                }
+                for (address, height) in restored.base.birthdays {
+                    if wallets.wallets.contains_key(&address) || wallets.is_watch_only(&address) {
+                        wallets.birthdays.entry(address).or_insert(height);
+                    }
+                }
+                for (address, metadata) in restored.metadata {
+                    if wallets.wallets.contains_key(&address) {
+                        wallets.metadata.entry(address).or_insert(metadata);
+                    }
+                }
+                let keys = &wallets.wallets;
+                wallets
+                    .accounts
+                    .merge(restored.base.accounts, |address| keys.contains_key(address));
+                // The policy stays as it is here; only which addresses took change is merged
+                wallets.change_addresses.extend(
+                    restored
+                        .base
+                        .change_addresses
+                        .into_iter()
+                        .filter(|address| keys.contains_key(address)),
+                );
+                wallets.drafts.merge(restored.drafts);
+                Ok(RestoreSummary {
+                    header,
+                    added,
+                    safety_copy: None,
+                })
+            });
+        }
+
+        let wallet_path = self.file_path()?;
+        let _lock = WalletFileLock::acquire(&wallet_path)?;
+        let safety_copy = if wallet_path.exists() {
+            let backup_dir = wallet_path
+                .parent()
+                .unwrap_or(Path::new(""))
+                .join(WALLET_BACKUP_DIR);
+            fs::create_dir_all(&backup_dir).map_err(|e| {
+                BlockchainError::Wallet(format!("Failed to create backup directory: {e}"))
+            })?;
+            let copy = backup_dir.join(format!("wallet_pre_restore_{}.dat", current_timestamp()?));
+            fs::copy(&wallet_path, &copy).map_err(|e| {
+                BlockchainError::Wallet(format!("Failed to copy the current wallet file: {e}"))
+            })?;
+            Some(copy)
+        } else {
+            None
+        };
+
+        self.take_file(restored);
+        write_atomically(&wallet_path, &self.encode()?)
+            .map_err(|e| BlockchainError::Wallet(format!("Could not save wallets to file: {e}")))?;
+        Ok(RestoreSummary {
+            header,
+            added: self.wallets.len(),
+            safety_copy,
+        })
+    }
+
     pub fn load_from_file(&mut self) {
         // Ignore errors during wallet loading - just start with empty wallet set
         if let Err(e) = self.load_from_file_safe() {
@@ -57,39 +1036,819 @@ impl Wallets {
         }
     }
 
-    fn load_from_file_safe(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let path = current_dir()?.join(WALLET_FILE);
+    fn load_from_file_safe(&mut self) -> Result<()> {
+        let path = self.file_path()?;
+        let _lock = WalletFileLock::acquire(&path)?;
+        self.read_from(&path)
+    }
+
+    /// Apply `change` to the wallets as they are on disk and save the result
+    ///
+    /// The wallet file stays locked from the reload to the save, so wallets another process
+    /// created since this set was loaded end up in the file too rather than being
+    /// overwritten. Nothing is saved if `change` fails.
+    fn update<T>(&mut self, change: impl FnOnce(&mut Wallets) -> Result<T>) -> Result<T> {
+        let path = self.file_path()?;
+        let _lock = WalletFileLock::acquire(&path)?;
+        self.read_from(&path)?;
+
+        let value = change(self)?;
+        write_atomically(&path, &self.encode()?)
+            .map_err(|e| BlockchainError::Wallet(format!("Could not save wallets to file: {e}")))?;
+        Ok(value)
+    }
+
+    pub(crate) fn file_path(&self) -> Result<PathBuf> {
+        match &self.path {
+            Some(path) => Ok(path.clone()),
+            None => wallet_path(),
+        }
+    }
+
+    // A missing file leaves the wallets in memory as they are
+    fn read_from(&mut self, path: &Path) -> Result<()> {
         if !path.exists() {
             return Ok(());
         }
 
-        let mut file = File::open(path)?;
-        let metadata = file.metadata()?;
-        let mut buf = vec![0; metadata.len() as usize];
-        file.read_exact(&mut buf)?;
-        let wallets = deserialize(&buf[..])?;
-        self.wallets = wallets;
+        let buf = fs::read(path)
+            .map_err(|e| BlockchainError::Wallet(format!("Failed to read wallet file: {e}")))?;
+        let wallet_file = WalletFile::decode(&buf[..])?;
+        self.take_file(wallet_file);
         Ok(())
     }
 
-    fn save_to_file(&self) {
-        // Ignore errors during wallet saving - log but don't crash
-        if let Err(e) = self.save_to_file_safe() {
-            log::error!("Could not save wallets to file: {e}");
+    fn take_file(&mut self, file: WalletFile) {
+        let base = file.base;
+        self.wallets = base.wallets;
+        self.labels = base.labels;
+        self.policies = base.policies;
+        self.watch_only = base.watch_only;
+        self.birthdays = base.birthdays;
+        self.accounts = base.accounts;
+        self.use_change_addresses = base.use_change_addresses;
+        self.change_addresses = base.change_addresses;
+        self.metadata = file.metadata;
+        self.drafts = file.drafts;
+        self.coin_selection = file.coin_selection;
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        serialize(&WalletFile {
+            base: WalletFileV7 {
+                magic: WALLET_FILE_MAGIC,
+                version: WALLET_FILE_VERSION,
+                wallets: self.wallets.clone(),
+                labels: self.labels.clone(),
+                policies: self.policies.clone(),
+                watch_only: self.watch_only.clone(),
+                birthdays: self.birthdays.clone(),
+                accounts: self.accounts.clone(),
+                use_change_addresses: self.use_change_addresses,
+                change_addresses: self.change_addresses.clone(),
+            },
+            metadata: self.metadata.clone(),
+            drafts: self.drafts.clone(),
+            coin_selection: self.coin_selection,
+        })
+    }
+}
+
+// What the chainstate holds for `address`
+fn address_balance(address: &str, utxo_set: &UTXOSet) -> Result<Amount> {
+    let pub_key_hash = address_pub_key_hash(address)?;
+    Amount::checked_sum(
+        utxo_set
+            .find_utxo_safe(&pub_key_hash)?
+            .iter()
+            .map(TXOutput::get_value),
+    )
+}
+
+/// What `Wallets::restore` did
+#[derive(Debug, Clone)]
+pub struct RestoreSummary {
+    pub header: WalletBackupHeader,
+    /// Addresses taken from the backup
+    pub added: usize,
+    /// Where the replaced wallet file was copied, if there was one
+    pub safety_copy: Option<PathBuf>,
+}
+
+/// Where the wallet file lives: the `--wallet` file if one was given, else `wallet.dat` in
+/// the database directory of the node `NODE_ID` names, else `wallet.dat` in the data
+/// directory
+pub fn wallet_path() -> Result<PathBuf> {
+    if let Some(node_id) = GLOBAL_CONFIG.get_node_id() {
+        return node_wallet_path(&node_id);
+    }
+    match GLOBAL_CONFIG.get_wallet_file() {
+        Some(path) => Ok(path),
+        None => Ok(wallet_dir()?.join(WALLET_FILE)),
+    }
+}
+
+/// Where node `node_id` keeps its wallets: `data/node_<id>/wallet.dat` in the data
+/// directory, next to the node's database, unless `--wallet` names a file
+///
+/// A node without a file of its own keeps using the data directory's `wallet.dat` if
+/// there is one, as every node did before each got its own.
+pub fn node_wallet_path(node_id: &str) -> Result<PathBuf> {
+    if let Some(path) = GLOBAL_CONFIG.get_wallet_file() {
+        return Ok(path);
+    }
+    let dir = wallet_dir()?;
+    let node_file = dir
+        .join("data")
+        .join(format!("node_{node_id}"))
+        .join(WALLET_FILE);
+    let shared_file = dir.join(WALLET_FILE);
+    if !node_file.exists() && shared_file.exists() {
+        return Ok(shared_file);
+    }
+    Ok(node_file)
+}
+
+#[cfg(not(test))]
+fn wallet_dir() -> Result<PathBuf> {
+    GLOBAL_CONFIG.data_dir()
+}
+
+// Unit tests share a wallet file in a directory made for the test run, never the one in
+// the working directory
+#[cfg(test)]
+fn wallet_dir() -> Result<PathBuf> {
+    static TEST_WALLET_DIR: once_cell::sync::Lazy<tempfile::TempDir> =
+        once_cell::sync::Lazy::new(|| {
+            tempfile::Builder::new()
+                .prefix("architect-chain-test-wallets")
+                .tempdir()
+                .expect("Failed to create the test wallet directory")
+        });
+    Ok(TEST_WALLET_DIR.path().to_path_buf())
+}
+
+/// Read the wallets out of wallet file bytes in either the current or the legacy layout
+pub fn decode_wallet_file(bytes: &[u8]) -> Result<HashMap<String, Wallet>> {
+    Ok(WalletFile::decode(bytes)?.base.wallets)
+}
+
+/// Take turns on the wallet file in tests, since every test resolves the same one
+#[cfg(test)]
+pub(crate) fn lock_wallet_file() -> std::sync::MutexGuard<'static, ()> {
+    static WALLET_FILE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    WALLET_FILE_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{ecdsa_p256_sha256_sign_digest, ecdsa_p256_sha256_sign_verify};
+    use tempfile::tempdir;
+
+    fn empty_wallets() -> Wallets {
+        Wallets {
+            wallets: HashMap::new(),
+            labels: HashMap::new(),
+            policies: HashMap::new(),
+            watch_only: HashMap::new(),
+            birthdays: HashMap::new(),
+            accounts: Accounts::default(),
+            use_change_addresses: false,
+            change_addresses: BTreeSet::new(),
+            metadata: HashMap::new(),
+            drafts: Drafts::default(),
+            coin_selection: None,
+            path: None,
         }
     }
 
-    fn save_to_file_safe(&self) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let path = current_dir()?.join(WALLET_FILE);
-        let file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(&path)?;
-        let mut writer = BufWriter::new(file);
-        let wallets_bytes = serialize(&self.wallets)?;
-        writer.write_all(wallets_bytes.as_slice())?;
-        writer.flush()?;
-        Ok(())
+    fn add_wallet(wallets: &mut Wallets) -> String {
+        let wallet = Wallet::new().unwrap();
+        let address = wallet.get_address();
+        wallets.wallets.insert(address.clone(), wallet);
+        address
+    }
+
+    #[test]
+    fn test_load_legacy_wallet_file() {
+        let wallet = Wallet::new().unwrap();
+        let address = wallet.get_address();
+        let mut legacy = HashMap::new();
+        legacy.insert(address.clone(), wallet);
+        let bytes = serialize(&legacy).unwrap();
+
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert_eq!(file.base.wallets.len(), 1);
+        assert!(file.base.wallets.contains_key(&address));
+        assert!(file.base.labels.is_empty());
+    }
+
+    #[test]
+    fn test_label_round_trip() {
+        let mut wallets = empty_wallets();
+        let address = add_wallet(&mut wallets);
+        wallets
+            .labels
+            .insert(address.clone(), "savings".to_string());
+
+        let file = WalletFile::decode(&wallets.encode().unwrap()).unwrap();
+        let reloaded = Wallets {
+            wallets: file.base.wallets,
+            labels: file.base.labels,
+            policies: file.base.policies,
+            watch_only: file.base.watch_only,
+            birthdays: file.base.birthdays,
+            accounts: file.base.accounts,
+            use_change_addresses: file.base.use_change_addresses,
+            change_addresses: file.base.change_addresses,
+            metadata: file.metadata,
+            drafts: file.drafts,
+            coin_selection: file.coin_selection,
+            path: None,
+        };
+        assert_eq!(reloaded.get_label(&address), Some("savings"));
+        assert_eq!(reloaded.resolve_label("savings").unwrap(), address);
+        assert!(reloaded.resolve_label("checking").is_err());
+    }
+
+    #[test]
+    fn test_duplicate_label_rejected() {
+        let mut wallets = empty_wallets();
+        let first = add_wallet(&mut wallets);
+        let second = add_wallet(&mut wallets);
+        wallets.labels.insert(first.clone(), "savings".to_string());
+
+        assert!(wallets.check_label_available("savings", None).is_err());
+        assert!(wallets
+            .check_label_available("savings", Some(&second))
+            .is_err());
+        // Relabeling an address with its own label is fine
+        assert!(wallets
+            .check_label_available("savings", Some(&first))
+            .is_ok());
+        assert!(wallets.check_label_available("  ", None).is_err());
+    }
+
+    #[test]
+    fn test_ambiguous_label_from_file() {
+        // Files written by hand or by other tools may still carry duplicates
+        let mut wallets = empty_wallets();
+        let first = add_wallet(&mut wallets);
+        let second = add_wallet(&mut wallets);
+        wallets.labels.insert(first, "savings".to_string());
+        wallets.labels.insert(second, "savings".to_string());
+
+        assert_eq!(wallets.find_by_label("savings").len(), 2);
+        assert!(wallets.resolve_label("savings").is_err());
+    }
+
+    #[test]
+    fn test_version_2_files_load_without_policies() {
+        let mut wallets = empty_wallets();
+        let address = add_wallet(&mut wallets);
+        let bytes = serialize(&WalletFileV2 {
+            magic: WALLET_FILE_MAGIC,
+            version: 2,
+            wallets: wallets.wallets.clone(),
+            labels: HashMap::from([(address.clone(), "savings".to_string())]),
+        })
+        .unwrap();
+
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert!(file.base.wallets.contains_key(&address));
+        assert_eq!(file.base.labels[&address], "savings");
+        assert!(file.base.policies.is_empty());
+    }
+
+    #[test]
+    fn test_watch_only_addresses_are_kept_without_keys() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("watching.dat");
+        let mut wallets = Wallets::open(&path).unwrap();
+        let owned = wallets.create_wallet().unwrap();
+        let cold = Wallet::new().unwrap().get_address();
+        wallets.add_watch_only(&cold, Some("cold")).unwrap();
+
+        let reopened = Wallets::open(&path).unwrap();
+        assert!(reopened.is_watch_only(&cold));
+        assert_eq!(reopened.get_watch_only_addresses(), vec![cold.clone()]);
+        assert_eq!(reopened.get_addresses(), vec![owned.clone()]);
+        assert_eq!(reopened.resolve_label("cold").unwrap(), cold);
+        let err = reopened.signing_wallet(&cold).err().unwrap();
+        assert!(err.to_string().contains("watch-only"), "{err}");
+        assert!(reopened.signing_wallet(&owned).is_ok());
+
+        assert!(wallets.add_watch_only(&owned, None).is_err());
+        assert!(wallets.add_watch_only("not an address", None).is_err());
+        let multisig = crate::wallet::convert_multisig_address(&[1, 2, 3]);
+        assert!(wallets.add_watch_only(&multisig, None).is_err());
+    }
+
+    #[test]
+    fn test_version_3_files_load_without_watch_only_addresses() {
+        let mut wallets = empty_wallets();
+        let address = add_wallet(&mut wallets);
+        let bytes = serialize(&WalletFileV3 {
+            magic: WALLET_FILE_MAGIC,
+            version: 3,
+            wallets: wallets.wallets.clone(),
+            labels: HashMap::new(),
+            policies: HashMap::from([(address.clone(), SpendPolicy::default())]),
+        })
+        .unwrap();
+
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert!(file.base.wallets.contains_key(&address));
+        assert!(file.base.policies.contains_key(&address));
+        assert!(file.base.watch_only.is_empty());
+    }
+
+    #[test]
+    fn test_birthday_heights_are_kept_and_version_4_files_have_none() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("birthdays.dat");
+        let mut wallets = Wallets::open(&path).unwrap();
+        let cold = Wallet::new().unwrap().get_address();
+        assert!(wallets.set_birthday_height(&cold, 120).is_err());
+        wallets.add_watch_only(&cold, None).unwrap();
+        wallets.set_birthday_height(&cold, 120).unwrap();
+        let reopened = Wallets::open(&path).unwrap();
+        assert_eq!(reopened.get_birthday_height(&cold), Some(120));
+
+        let bytes = serialize(&WalletFileV4 {
+            magic: WALLET_FILE_MAGIC,
+            version: 4,
+            wallets: HashMap::new(),
+            labels: HashMap::new(),
+            policies: HashMap::new(),
+            watch_only: reopened.watch_only.clone(),
+        })
+        .unwrap();
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert!(file.base.watch_only.contains_key(&cold));
+        assert!(file.base.birthdays.is_empty());
+    }
+
+    #[test]
+    fn test_version_5_files_load_without_accounts() {
+        let mut wallets = empty_wallets();
+        let address = add_wallet(&mut wallets);
+        let bytes = serialize(&WalletFileV5 {
+            magic: WALLET_FILE_MAGIC,
+            version: 5,
+            wallets: wallets.wallets.clone(),
+            labels: HashMap::new(),
+            policies: HashMap::new(),
+            watch_only: HashMap::new(),
+            birthdays: HashMap::from([(address.clone(), 12)]),
+        })
+        .unwrap();
+
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert_eq!(file.base.birthdays[&address], 12);
+        assert!(file.base.accounts.is_empty());
+    }
+
+    #[test]
+    fn test_change_address_policy_is_kept_and_version_6_files_have_none() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("change.dat");
+        let mut wallets = Wallets::open(&path).unwrap();
+        let sender = wallets.create_wallet().unwrap();
+        assert!(!wallets.uses_change_addresses());
+        wallets.set_use_change_addresses(true).unwrap();
+        let change = wallets.create_change_address().unwrap();
+
+        let reopened = Wallets::open(&path).unwrap();
+        assert!(reopened.uses_change_addresses());
+        assert!(reopened.is_change_address(&change));
+        assert!(!reopened.is_change_address(&sender));
+        assert!(reopened.signing_wallet(&change).is_ok());
+
+        let bytes = serialize(&WalletFileV6 {
+            magic: WALLET_FILE_MAGIC,
+            version: 6,
+            wallets: reopened.wallets.clone(),
+            labels: HashMap::new(),
+            policies: HashMap::new(),
+            watch_only: HashMap::new(),
+            birthdays: HashMap::new(),
+            accounts: Accounts::default(),
+        })
+        .unwrap();
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert_eq!(file.base.wallets.len(), 2);
+        assert!(!file.base.use_change_addresses);
+        assert!(file.base.change_addresses.is_empty());
+    }
+
+    #[test]
+    fn test_version_7_files_load_without_metadata() {
+        let mut wallets = empty_wallets();
+        let address = add_wallet(&mut wallets);
+        let bytes = serialize(&WalletFileV7 {
+            magic: WALLET_FILE_MAGIC,
+            version: 7,
+            wallets: wallets.wallets.clone(),
+            labels: HashMap::new(),
+            policies: HashMap::new(),
+            watch_only: HashMap::new(),
+            birthdays: HashMap::from([(address.clone(), 12)]),
+            accounts: Accounts::default(),
+            use_change_addresses: true,
+            change_addresses: BTreeSet::new(),
+        })
+        .unwrap();
+
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert_eq!(file.base.birthdays[&address], 12);
+        assert!(file.base.use_change_addresses);
+        assert!(file.metadata.is_empty());
+        // Without a recorded chain the key goes with any
+        wallets.wallets = file.base.wallets;
+        assert_eq!(wallets.get_metadata(&address), None);
+    }
+
+    #[test]
+    fn test_version_8_files_load_without_drafts() {
+        let mut wallets = empty_wallets();
+        let address = add_wallet(&mut wallets);
+        let bytes = serialize(&WalletFileV8 {
+            base: WalletFileV7 {
+                version: 8,
+                wallets: wallets.wallets.clone(),
+                labels: HashMap::from([(address.clone(), "savings".to_string())]),
+                ..WalletFileV7::empty()
+            },
+            metadata: HashMap::from([(address.clone(), WalletMetadata::default())]),
+        })
+        .unwrap();
+
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert_eq!(file.base.labels[&address], "savings");
+        assert!(file.metadata.contains_key(&address));
+        assert!(file.drafts.is_empty());
+    }
+
+    #[test]
+    fn test_drafts_survive_reloads() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("wallet.dat");
+        let mut wallets = Wallets::open(&path).unwrap();
+        let tx = Transaction::new_placeholder_tx(&wallets.create_wallet().unwrap());
+        let bytes = tx.serialize().unwrap();
+        let state = DraftState::PartiallySigned { have: 1, need: 2 };
+        wallets.save_draft("payroll", &bytes, state).unwrap();
+        wallets
+            .save_draft("rent", &bytes, DraftState::Unsigned)
+            .unwrap();
+        // A name is only taken once, by any process sharing the file
+        let mut other = Wallets::open(&path).unwrap();
+        assert!(other
+            .save_draft("rent", &bytes, DraftState::SignedUnbroadcast)
+            .is_err());
+
+        let reloaded = Wallets::open(&path).unwrap();
+        let names: Vec<&str> = reloaded.list_drafts().map(|(name, _)| name).collect();
+        assert_eq!(names, ["payroll", "rent"]);
+        let draft = reloaded.load_draft("payroll").unwrap();
+        assert_eq!(draft.state(), state);
+        assert_eq!(draft.transaction().unwrap().get_id(), tx.get_id());
+        assert_eq!(draft.to_hex(), tx.to_hex().unwrap());
+        assert!(draft.saved_at() > 0);
+
+        other.delete_draft("payroll").unwrap();
+        assert!(Wallets::open(&path).unwrap().load_draft("payroll").is_err());
+        assert!(other.delete_draft("payroll").is_err());
+    }
+
+    #[test]
+    fn test_version_9_files_load_without_coin_selection() {
+        let mut wallets = empty_wallets();
+        let address = add_wallet(&mut wallets);
+        let bytes = serialize(&WalletFileV9 {
+            base: WalletFileV7 {
+                version: 9,
+                wallets: wallets.wallets.clone(),
+                use_change_addresses: true,
+                ..WalletFileV7::empty()
+            },
+            metadata: HashMap::new(),
+            drafts: Drafts::default(),
+        })
+        .unwrap();
+
+        let file = WalletFile::decode(&bytes).unwrap();
+        assert!(file.base.wallets.contains_key(&address));
+        assert!(file.base.use_change_addresses);
+        assert_eq!(file.coin_selection, None);
+    }
+
+    #[test]
+    fn test_default_coin_selection_survives_reloads() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("wallet.dat");
+        let mut wallets = Wallets::open(&path).unwrap();
+        assert_eq!(wallets.default_coin_selection(), None);
+        wallets
+            .set_default_coin_selection(Some(SelectionStrategy::OldestFirst))
+            .unwrap();
+        assert_eq!(
+            Wallets::open(&path).unwrap().default_coin_selection(),
+            Some(SelectionStrategy::OldestFirst)
+        );
+        wallets.set_default_coin_selection(None).unwrap();
+        assert_eq!(Wallets::open(&path).unwrap().default_coin_selection(), None);
+    }
+
+    #[test]
+    fn test_key_created_for_one_chain_is_refused_by_another() {
+        let temp_dir = tempdir().unwrap();
+        let chain = |name: &str, address: &str| {
+            Blockchain::create_blockchain_with_path(
+                address,
+                temp_dir.path().join(name).to_str().unwrap(),
+            )
+            .unwrap()
+        };
+        let mut wallets = Wallets::open(&temp_dir.path().join("wallet.dat")).unwrap();
+        let genesis_owner = wallets.create_wallet().unwrap();
+        let ours = chain("ours", &genesis_owner);
+        let theirs = chain("theirs", "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+
+        let address = wallets
+            .create_wallet_for_chain(Some("savings"), &ours)
+            .unwrap();
+        wallets
+            .set_notes(&address, Some("paper copy in the safe"))
+            .unwrap();
+        let reopened = Wallets::open(&temp_dir.path().join("wallet.dat")).unwrap();
+        let metadata = reopened.get_metadata(&address).unwrap();
+        assert_eq!(
+            metadata.genesis_hash,
+            Some(ours.get_genesis_hash().unwrap())
+        );
+        assert_eq!(metadata.notes.as_deref(), Some("paper copy in the safe"));
+        assert!(metadata.created_at > 0);
+        assert_eq!(reopened.get_label(&address), Some("savings"));
+        assert_eq!(reopened.get_birthday_height(&address), Some(0));
+
+        let both = [genesis_owner.clone(), address.clone()];
+        reopened.check_network(&both, &ours).unwrap();
+        assert!(matches!(
+            reopened.check_network(&both, &theirs),
+            Err(BlockchainError::WalletNetworkMismatch { address: refused, .. }) if refused == address
+        ));
+        // A key made with no chain open pairs with either
+        reopened
+            .check_network(std::slice::from_ref(&genesis_owner), &theirs)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rescan_starts_at_the_earliest_birthday() {
+        let temp_dir = tempdir().unwrap();
+        let mut wallets = Wallets::open(&temp_dir.path().join("wallet.dat")).unwrap();
+        let older = wallets.create_wallet().unwrap();
+        let newer = wallets.create_wallet().unwrap();
+        let unknown = wallets.create_wallet().unwrap();
+        wallets.set_birthday_height(&older, 20).unwrap();
+        wallets.set_birthday_height(&newer, 30).unwrap();
+
+        assert_eq!(wallets.rescan_height(std::slice::from_ref(&newer)), 30);
+        assert_eq!(wallets.rescan_height(&[newer.clone(), older.clone()]), 20);
+        assert_eq!(wallets.rescan_height(&[newer, older, unknown]), 0);
+    }
+
+    #[test]
+    fn test_spend_policy_survives_reloads() {
+        let _guard = lock_wallet_file();
+        let mut wallets = Wallets::new();
+        let address = wallets.create_wallet().unwrap();
+        let policy = SpendPolicy {
+            max_send_without_confirm: Some(1_000),
+            spend_limit_per_tx: Some(50_000),
+        };
+        assert!(wallets.get_policy(&address).is_unrestricted());
+        wallets.set_policy(&address, policy).unwrap();
+        assert_eq!(Wallets::new().get_policy(&address), policy);
+
+        // Labels written since leave the policy as it is
+        Wallets::new().set_label(&address, "allowance").unwrap();
+        assert_eq!(Wallets::new().get_policy(&address), policy);
+        Wallets::new()
+            .set_policy(&address, SpendPolicy::default())
+            .unwrap();
+        assert!(Wallets::new().get_policy(&address).is_unrestricted());
+
+        let stranger = Wallet::new().unwrap().get_address();
+        assert!(wallets.set_policy(&stranger, policy).is_err());
+    }
+
+    #[test]
+    fn test_spend_policy_limits() {
+        let policy = SpendPolicy {
+            max_send_without_confirm: Some(1_000),
+            spend_limit_per_tx: Some(50_000),
+        };
+        assert!(!policy.check(1_000).unwrap());
+        assert!(policy.check(1_001).unwrap());
+        assert!(policy.check(50_000).unwrap());
+        let err = policy.check(50_001).unwrap_err();
+        assert!(err.to_string().contains("limit of 50000"), "{err}");
+        assert!(!SpendPolicy::default().check(u64::MAX).unwrap());
+    }
+
+    #[test]
+    fn test_wallets_opened_at_a_path_stay_there() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("notebook.dat");
+        let mut wallets = Wallets::open(&path).unwrap();
+        assert!(wallets.get_addresses().is_empty());
+        let address = wallets.create_wallet_with_label("notebook").unwrap();
+
+        let reopened = Wallets::open(&path).unwrap();
+        assert!(reopened.get_wallet(&address).is_some());
+        assert_eq!(reopened.get_label(&address), Some("notebook"));
+
+        fs::write(&path, b"not a wallet file").unwrap();
+        assert!(Wallets::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_backup_restores_a_deleted_wallet_file() {
+        let _guard = lock_wallet_file();
+        let _ = fs::remove_file(wallet_path().unwrap());
+        let dir = tempdir().unwrap();
+        let backup_path = dir.path().join("wallets.bak");
+
+        let mut wallets = Wallets::new();
+        let first = wallets.create_wallet_with_label("savings").unwrap();
+        let second = wallets.create_wallet().unwrap();
+        let header = wallets.backup(&backup_path, Some("correct horse")).unwrap();
+        assert_eq!(header.wallet_count, 2);
+        assert!(header.is_encrypted());
+
+        fs::remove_file(wallet_path().unwrap()).unwrap();
+        assert!(Wallets::new().restore(&backup_path, None, false).is_err());
+        assert!(Wallets::new()
+            .restore(&backup_path, Some("wrong password"), false)
+            .is_err());
+        assert!(!wallet_path().unwrap().exists());
+
+        let summary = Wallets::new()
+            .restore(&backup_path, Some("correct horse"), false)
+            .unwrap();
+        assert_eq!(summary.added, 2);
+        assert!(summary.safety_copy.is_none());
+
+        let restored = Wallets::new();
+        let mut addresses = restored.get_addresses();
+        addresses.sort();
+        let mut expected = vec![first.clone(), second.clone()];
+        expected.sort();
+        assert_eq!(addresses, expected);
+        assert_eq!(restored.get_label(&first), Some("savings"));
+
+        // The restored keys still sign for their addresses
+        let wallet = restored.get_wallet(&second).unwrap();
+        let signature = ecdsa_p256_sha256_sign_digest(wallet.get_pkcs8(), b"restored").unwrap();
+        assert!(ecdsa_p256_sha256_sign_verify(
+            wallet.get_public_key(),
+            &signature,
+            b"restored"
+        ));
+        let _ = fs::remove_file(wallet_path().unwrap());
+    }
+
+    #[test]
+    fn test_corrupted_backup_leaves_the_wallet_file_alone() {
+        let _guard = lock_wallet_file();
+        let _ = fs::remove_file(wallet_path().unwrap());
+        let dir = tempdir().unwrap();
+        let backup_path = dir.path().join("wallets.bak");
+
+        let mut wallets = Wallets::new();
+        wallets.create_wallet().unwrap();
+        wallets.backup(&backup_path, None).unwrap();
+        wallets.create_wallet().unwrap();
+        let live = fs::read(wallet_path().unwrap()).unwrap();
+
+        let mut corrupted = fs::read(&backup_path).unwrap();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0x01;
+        let corrupted_path = dir.path().join("corrupted.bak");
+        fs::write(&corrupted_path, &corrupted).unwrap();
+
+        let err = Wallets::new()
+            .restore(&corrupted_path, None, false)
+            .unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{err}");
+        assert!(Wallets::new().restore(&corrupted_path, None, true).is_err());
+        assert_eq!(fs::read(wallet_path().unwrap()).unwrap(), live);
+
+        // The intact backup replaces the file, keeping what it replaced
+        let summary = Wallets::new().restore(&backup_path, None, false).unwrap();
+        let copy = summary.safety_copy.unwrap();
+        assert_eq!(fs::read(&copy).unwrap(), live);
+        assert_eq!(Wallets::new().get_addresses().len(), 1);
+        let _ = fs::remove_file(copy);
+        let _ = fs::remove_file(wallet_path().unwrap());
+    }
+
+    #[test]
+    fn test_backup_miscounting_its_wallets_is_refused() {
+        let _guard = lock_wallet_file();
+        let _ = fs::remove_file(wallet_path().unwrap());
+        let dir = tempdir().unwrap();
+        let backup_path = dir.path().join("wallets.bak");
+
+        let mut backed_up = empty_wallets();
+        add_wallet(&mut backed_up);
+        let (_, bytes) = backup::encode_backup(&backed_up.encode().unwrap(), 2, None).unwrap();
+        fs::write(&backup_path, bytes).unwrap();
+
+        for merge in [false, true] {
+            let err = Wallets::new()
+                .restore(&backup_path, None, merge)
+                .unwrap_err();
+            assert!(
+                err.to_string().contains("holds 2 wallets but has 1"),
+                "{err}"
+            );
+        }
+        assert!(!wallet_path().unwrap().exists());
+    }
+
+    #[test]
+    fn test_merge_keeps_existing_keys() {
+        let _guard = lock_wallet_file();
+        let _ = fs::remove_file(wallet_path().unwrap());
+        let dir = tempdir().unwrap();
+        let backup_path = dir.path().join("wallets.bak");
+
+        let mut live = Wallets::new();
+        let existing = live.create_wallet_with_label("spending").unwrap();
+        let existing_key = live.get_wallet(&existing).unwrap().get_pkcs8().to_vec();
+
+        // A backup that has another key filed under the existing address, and a new one
+        let mut backed_up = empty_wallets();
+        let added = add_wallet(&mut backed_up);
+        backed_up
+            .wallets
+            .insert(existing.clone(), Wallet::new().unwrap());
+        backed_up.labels.insert(existing.clone(), "old".to_string());
+        backed_up
+            .labels
+            .insert(added.clone(), "savings".to_string());
+        let (_, bytes) = backup::encode_backup(&backed_up.encode().unwrap(), 2, None).unwrap();
+        fs::write(&backup_path, bytes).unwrap();
+
+        let summary = Wallets::new().restore(&backup_path, None, true).unwrap();
+        assert_eq!(summary.added, 1);
+        assert!(summary.safety_copy.is_none());
+
+        let merged = Wallets::new();
+        assert_eq!(merged.get_addresses().len(), 2);
+        assert_eq!(
+            merged.get_wallet(&existing).unwrap().get_pkcs8(),
+            existing_key.as_slice()
+        );
+        assert_eq!(merged.get_label(&existing), Some("spending"));
+        assert_eq!(merged.get_label(&added), Some("savings"));
+        let _ = fs::remove_file(wallet_path().unwrap());
+    }
+
+    #[test]
+    fn test_concurrent_creators_keep_every_wallet() {
+        let _guard = lock_wallet_file();
+        let _ = fs::remove_file(wallet_path().unwrap());
+
+        // Each thread works from its own copy, as two processes would
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let mut wallets = Wallets::new();
+                    (0..10)
+                        .map(|_| wallets.create_wallet().unwrap())
+                        .collect::<Vec<String>>()
+                })
+            })
+            .collect();
+        let created: Vec<String> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+
+        let reloaded = Wallets::new();
+        assert_eq!(created.len(), 20);
+        assert_eq!(reloaded.get_addresses().len(), 20);
+        assert!(created
+            .iter()
+            .all(|address| reloaded.get_wallet(address).is_some()));
+        let _ = fs::remove_file(wallet_path().unwrap());
     }
 }
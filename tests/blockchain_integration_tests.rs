@@ -3,9 +3,11 @@
 //! Tests the core blockchain functionality that was implemented,
 //! focusing on the critical features that make this a working blockchain.
 
-use architect_chain::core::{Block, Blockchain, ProofOfWork, Transaction};
+use architect_chain::core::{
+    Block, Blockchain, DifficultyAdjustment, FeePriority, ProofOfWork, Transaction,
+};
 use architect_chain::storage::UTXOSet;
-use architect_chain::wallet::Wallets;
+use architect_chain::wallet::{payment_uri, PaymentRequest, Wallets, WALLET_FILE};
 use tempfile::tempdir;
 
 #[test]
@@ -42,10 +44,7 @@ fn test_blockchain_creation_and_mining() {
     assert_eq!(blockchain.get_best_height().unwrap(), 0);
 
     // Mine a new block
-    let coinbase_tx = Transaction::new_coinbase_tx(test_address).unwrap();
-    let block = blockchain
-        .mine_block_with_fees(&[coinbase_tx], test_address)
-        .unwrap();
+    let block = blockchain.mine_block_with_fees(&[], test_address).unwrap();
 
     // Block should be valid and added to chain
     assert_eq!(block.get_height(), 1);
@@ -58,7 +57,7 @@ fn test_transaction_creation_and_validation() {
     let temp_dir = tempdir().unwrap();
     let db_path = temp_dir.path().join("test_blockchain");
 
-    let mut wallets = Wallets::new();
+    let mut wallets = Wallets::new_with_path(&temp_dir.path().join(WALLET_FILE));
     let sender_address = wallets.create_wallet().unwrap();
     let recipient_address = wallets.create_wallet().unwrap();
 
@@ -67,19 +66,19 @@ fn test_transaction_creation_and_validation() {
             .unwrap();
 
     // Mine initial block to create UTXOs
-    let coinbase_tx = Transaction::new_coinbase_tx(&sender_address).unwrap();
     blockchain
-        .mine_block_with_fees(&[coinbase_tx], &sender_address)
+        .mine_block_with_fees(&[], &sender_address)
         .unwrap();
 
     let utxo_set = UTXOSet::new(blockchain.clone());
     utxo_set.reindex();
 
     // Create a transaction
-    let tx = Transaction::new_utxo_transaction(
-        &sender_address,
+    let tx = Transaction::new_utxo_transaction_from_wallet(
+        wallets.get_wallet(&sender_address).unwrap(),
         &recipient_address,
         1000000, // 0.01 coins
+        FeePriority::Normal,
         &utxo_set,
     )
     .unwrap();
@@ -97,6 +96,45 @@ fn test_transaction_creation_and_validation() {
     assert_eq!(block.get_transactions().len(), 2); // coinbase + transaction
 }
 
+#[test]
+fn test_send_to_payment_uri() {
+    let temp_dir = tempdir().unwrap();
+    let db_path = temp_dir.path().join("test_blockchain");
+
+    let mut wallets = Wallets::new_with_path(&temp_dir.path().join(WALLET_FILE));
+    let sender_address = wallets.create_wallet().unwrap();
+    let recipient_address = wallets.create_wallet().unwrap();
+
+    let blockchain =
+        Blockchain::create_blockchain_with_path(&sender_address, db_path.to_str().unwrap())
+            .unwrap();
+    let utxo_set = UTXOSet::new(blockchain.clone());
+    utxo_set.reindex();
+
+    // The recipient asks for 0.25 coins; the payer only has the URI
+    let uri = PaymentRequest::new(&recipient_address, Some(25_000_000), Some("rent".into()))
+        .unwrap()
+        .to_uri();
+    let request = payment_uri::parse(&uri).unwrap();
+    assert!(request.resolve_amount(Some(1)).is_err());
+    let amount = request.resolve_amount(None).unwrap();
+
+    let tx = Transaction::new_utxo_transaction_from_wallet(
+        wallets.get_wallet(&sender_address).unwrap(),
+        &request.address,
+        amount,
+        FeePriority::Normal,
+        &utxo_set,
+    )
+    .unwrap();
+    let block = blockchain
+        .mine_block_with_fees(&[tx], &sender_address)
+        .unwrap();
+    utxo_set.update(&block);
+
+    assert_eq!(get_balance(&utxo_set, &recipient_address), 25_000_000);
+}
+
 #[test]
 fn test_blockchain_synchronization() {
     let temp_dir = tempdir().unwrap();
@@ -109,10 +147,7 @@ fn test_blockchain_synchronization() {
         Blockchain::create_blockchain_with_path(test_address, db_path.to_str().unwrap()).unwrap();
 
     // Mine initial block
-    let coinbase_tx = Transaction::new_coinbase_tx(test_address).unwrap();
-    let block1 = blockchain
-        .mine_block_with_fees(&[coinbase_tx], test_address)
-        .unwrap();
+    let block1 = blockchain.mine_block_with_fees(&[], test_address).unwrap();
     assert_eq!(blockchain.get_best_height().unwrap(), 1);
 
     // Create additional blocks to sync
@@ -125,7 +160,7 @@ fn test_blockchain_synchronization() {
             prev_hash,
             &[coinbase_tx],
             i,
-            1, // Easy difficulty
+            DifficultyAdjustment::get_initial_difficulty(), // Blocks get validated, so this must match the chain
         )
         .unwrap();
         prev_hash = block.get_hash().to_string();
@@ -148,10 +183,7 @@ fn test_fork_resolution() {
         Blockchain::create_blockchain_with_path(test_address, db_path.to_str().unwrap()).unwrap();
 
     // Mine initial block
-    let coinbase_tx = Transaction::new_coinbase_tx(test_address).unwrap();
-    blockchain
-        .mine_block_with_fees(&[coinbase_tx], test_address)
-        .unwrap();
+    blockchain.mine_block_with_fees(&[], test_address).unwrap();
     assert_eq!(blockchain.get_best_height().unwrap(), 1);
 
     // Create a longer competing chain
@@ -164,7 +196,7 @@ fn test_fork_resolution() {
             prev_hash,
             &[coinbase_tx],
             i,
-            1, // Easy difficulty
+            DifficultyAdjustment::get_initial_difficulty(), // Blocks get validated, so this must match the chain
         )
         .unwrap();
         prev_hash = block.get_hash().to_string();
@@ -188,12 +220,23 @@ fn test_block_validation_during_sync() {
 
     // Create a valid block
     let coinbase_tx = Transaction::new_coinbase_tx(test_address).unwrap();
-    let valid_block =
-        Block::new_block(blockchain.get_tip_hash(), &[coinbase_tx.clone()], 1, 1).unwrap();
+    let difficulty = DifficultyAdjustment::get_initial_difficulty();
+    let valid_block = Block::new_block(
+        blockchain.get_tip_hash(),
+        std::slice::from_ref(&coinbase_tx),
+        1,
+        difficulty,
+    )
+    .unwrap();
 
     // Create an invalid block (wrong previous hash)
-    let invalid_block =
-        Block::new_block("wrong_previous_hash".to_string(), &[coinbase_tx], 1, 1).unwrap();
+    let invalid_block = Block::new_block(
+        "wrong_previous_hash".to_string(),
+        &[coinbase_tx],
+        1,
+        difficulty,
+    )
+    .unwrap();
 
     // Valid block should sync successfully
     let valid_sync = blockchain.sync_with_peer(&[valid_block]).unwrap();
@@ -216,10 +259,7 @@ fn test_difficulty_adjustment() {
 
     // Mine blocks to trigger difficulty adjustment
     for i in 1..=12 {
-        let coinbase_tx = Transaction::new_coinbase_tx(test_address).unwrap();
-        let block = blockchain
-            .mine_block_with_fees(&[coinbase_tx], test_address)
-            .unwrap();
+        let block = blockchain.mine_block_with_fees(&[], test_address).unwrap();
 
         assert_eq!(block.get_height(), i);
         assert!(ProofOfWork::validate(&block));
@@ -237,7 +277,7 @@ fn test_fee_calculation() {
     let temp_dir = tempdir().unwrap();
     let db_path = temp_dir.path().join("test_blockchain");
 
-    let mut wallets = Wallets::new();
+    let mut wallets = Wallets::new_with_path(&temp_dir.path().join(WALLET_FILE));
     let sender_address = wallets.create_wallet().unwrap();
     let recipient_address = wallets.create_wallet().unwrap();
 
@@ -246,20 +286,19 @@ fn test_fee_calculation() {
             .unwrap();
 
     // Mine initial block
-    let coinbase_tx = Transaction::new_coinbase_tx(&sender_address).unwrap();
     blockchain
-        .mine_block_with_fees(&[coinbase_tx], &sender_address)
+        .mine_block_with_fees(&[], &sender_address)
         .unwrap();
 
     let utxo_set = UTXOSet::new(blockchain.clone());
     utxo_set.reindex();
 
     // Create transaction with high priority
-    let tx = Transaction::new_utxo_transaction_with_priority(
-        &sender_address,
+    let tx = Transaction::new_utxo_transaction_from_wallet(
+        wallets.get_wallet(&sender_address).unwrap(),
         &recipient_address,
         500000,
-        architect_chain::core::FeePriority::High,
+        FeePriority::High,
         &utxo_set,
     )
     .unwrap();
@@ -292,10 +331,7 @@ fn test_blockchain_persistence() {
             Blockchain::create_blockchain_with_path(test_address, db_path_str).unwrap();
 
         for _ in 1..=3 {
-            let coinbase_tx = Transaction::new_coinbase_tx(test_address).unwrap();
-            blockchain
-                .mine_block_with_fees(&[coinbase_tx], test_address)
-                .unwrap();
+            blockchain.mine_block_with_fees(&[], test_address).unwrap();
         }
 
         assert_eq!(blockchain.get_best_height().unwrap(), 3);
@@ -307,22 +343,17 @@ fn test_blockchain_persistence() {
         assert_eq!(blockchain.get_best_height().unwrap(), 3);
 
         // Continue mining
-        let coinbase_tx = Transaction::new_coinbase_tx(test_address).unwrap();
-        blockchain
-            .mine_block_with_fees(&[coinbase_tx], test_address)
-            .unwrap();
+        blockchain.mine_block_with_fees(&[], test_address).unwrap();
         assert_eq!(blockchain.get_best_height().unwrap(), 4);
     }
 }
 
 // Helper function
 fn get_balance(utxo_set: &UTXOSet, address: &str) -> u64 {
-    use architect_chain::utils;
-    use architect_chain::ADDRESS_CHECK_SUM_LEN;
+    use architect_chain::decode_address;
 
-    let payload = utils::base58_decode(address).unwrap();
-    let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
-    let utxos = utxo_set.find_utxo(pub_key_hash);
+    let (_, pub_key_hash) = decode_address(address).unwrap();
+    let utxos = utxo_set.find_utxo(&pub_key_hash);
 
-    utxos.iter().map(|utxo| utxo.get_value()).sum()
+    utxos.iter().map(|utxo| u64::from(utxo.get_value())).sum()
 }
@@ -0,0 +1,1266 @@
+//! Command line tests
+//!
+//! These run the `architect-chain` binary itself, each from its own empty working
+//! directory, so they can see which files a command leaves behind and where.
+
+use architect_chain::cli::responses::{
+    BalanceResponse, CreateWalletResponse, ErrorResponse, GenerateResponse, SendResponse,
+};
+use architect_chain::core::{Blockchain, RelayFeePolicy, SelectionStrategy, Transaction};
+use architect_chain::storage::MemoryPool;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::tempdir;
+
+fn run(cwd: &Path, args: &[&str], datadir_env: Option<&Path>) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_architect-chain"));
+    command.current_dir(cwd).args(args);
+    match datadir_env {
+        Some(dir) => command.env("ARCHITECT_DATADIR", dir),
+        None => command.env_remove("ARCHITECT_DATADIR"),
+    };
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// With --output json stdout has to be one JSON document and nothing else, so I parse all of it
+fn json_stdout<T: DeserializeOwned>(output: &Output) -> T {
+    let stdout = stdout(output);
+    serde_json::from_str(&stdout).unwrap_or_else(|e| panic!("{e}: {stdout}"))
+}
+
+fn is_empty_dir(dir: &Path) -> bool {
+    fs::read_dir(dir).unwrap().next().is_none()
+}
+
+#[test]
+fn test_datadir_flag_keeps_node_files_out_of_the_working_directory() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let datadir_arg = datadir.path().join("node");
+    let datadir_arg = datadir_arg.to_str().unwrap();
+
+    // The directory doesn't exist yet, so it has to be created on demand
+    let created = run(
+        cwd.path(),
+        &["--datadir", datadir_arg, "createwallet"],
+        None,
+    );
+    let address = stdout(&created)
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    run(
+        cwd.path(),
+        &["--datadir", datadir_arg, "createblockchain", &address],
+        None,
+    );
+    // The flag is global, so it may also follow the subcommand
+    let balance = run(
+        cwd.path(),
+        &["getbalance", &address, "--datadir", datadir_arg],
+        None,
+    );
+    let balance = stdout(&balance);
+    assert!(
+        balance.starts_with(&format!("Balance of {address}: ")),
+        "{balance}"
+    );
+    assert!(!balance.trim().ends_with(": 0"), "{balance}");
+
+    let node_dir = Path::new(datadir_arg);
+    assert!(node_dir.join("wallet.dat").is_file());
+    assert!(node_dir.join("data").is_dir());
+    assert!(is_empty_dir(cwd.path()));
+}
+
+#[test]
+fn test_datadir_flag_takes_precedence_over_environment() {
+    let cwd = tempdir().unwrap();
+    let from_env = tempdir().unwrap();
+    let from_flag = tempdir().unwrap();
+
+    run(cwd.path(), &["createwallet"], Some(from_env.path()));
+    assert!(from_env.path().join("wallet.dat").is_file());
+
+    run(
+        cwd.path(),
+        &[
+            "--datadir",
+            from_flag.path().to_str().unwrap(),
+            "createwallet",
+        ],
+        Some(from_env.path()),
+    );
+    assert!(from_flag.path().join("wallet.dat").is_file());
+    assert!(is_empty_dir(cwd.path()));
+
+    let listed = run(cwd.path(), &["listaddresses"], Some(from_env.path()));
+    assert_eq!(stdout(&listed).lines().count(), 1);
+}
+
+#[test]
+fn test_generate_mines_blocks_on_a_dev_chain() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+
+    let created = run(cwd.path(), &["createwallet"], dir);
+    let address = stdout(&created)
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    run(
+        cwd.path(),
+        &["--network", "regtest", "createblockchain", &address],
+        dir,
+    );
+
+    let generated = run(
+        cwd.path(),
+        &["--network", "regtest", "generate", "3", &address, "--dev"],
+        dir,
+    );
+    // Proof-of-work logs its progress to stderr, so only the hashes and height are printed
+    let output = stdout(&generated);
+    let lines: Vec<&str> = output.lines().collect();
+    let (height, hashes) = lines.split_last().unwrap();
+    assert_eq!(*height, "Height: 3");
+    assert_eq!(hashes.len(), 3, "{lines:?}");
+    assert!(hashes.iter().all(|hash| hash.len() == 64));
+
+    let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args(["--network", "regtest", "generate", "1", &address])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+
+    // --dev alone doesn't make a chain of any other network a development chain
+    let elsewhere = tempdir().unwrap();
+    run(
+        cwd.path(),
+        &["createblockchain", &address],
+        Some(elsewhere.path()),
+    );
+    let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", elsewhere.path())
+        .args(["generate", "1", &address, "--dev"])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("--network regtest"));
+}
+
+#[test]
+fn test_addcheckpoint_is_refused_outside_regtest() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let hash = "ab".repeat(32);
+
+    let created = run(cwd.path(), &["--network", "regtest", "createwallet"], dir);
+    let address = stdout(&created)
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    run(
+        cwd.path(),
+        &["--network", "regtest", "createblockchain", &address],
+        dir,
+    );
+    run(
+        cwd.path(),
+        &["--network", "regtest", "addcheckpoint", "100", &hash],
+        dir,
+    );
+
+    let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args(["addcheckpoint", "100", &hash])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("--network regtest"));
+}
+
+#[test]
+fn test_send_mine_flag_and_deprecated_positional_form() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let new_address = || {
+        stdout(&run(cwd.path(), &["createwallet"], dir))
+            .trim()
+            .strip_prefix("Your new address: ")
+            .unwrap()
+            .to_string()
+    };
+    let sender = new_address();
+    let recipient = new_address();
+    run(cwd.path(), &["createblockchain", &sender], dir);
+
+    let sent = run(
+        cwd.path(),
+        &["send", &sender, &recipient, "1000", "--mine"],
+        dir,
+    );
+    // The txid it prints is the one to look the payment up by
+    let txid = stdout(&sent)
+        .lines()
+        .find_map(|line| line.strip_prefix("Transaction "))
+        .unwrap()
+        .to_string();
+    assert_eq!(txid.len(), 64, "{}", stdout(&sent));
+    let status = run(cwd.path(), &["gettransaction", &txid], dir);
+    assert!(status.status.success(), "{}", stdout(&status));
+    assert!(
+        stdout(&status).contains("Confirmations: 1"),
+        "{}",
+        stdout(&status)
+    );
+    let balance = run(cwd.path(), &["getbalance", &recipient], dir);
+    assert!(
+        stdout(&balance).trim().ends_with(": 1000"),
+        "{}",
+        stdout(&balance)
+    );
+
+    // A trailing 1 still mines, with a warning to switch to the flag
+    let legacy = run(cwd.path(), &["send", &sender, &recipient, "500", "1"], dir);
+    assert!(String::from_utf8_lossy(&legacy.stderr).contains("--mine"));
+    let balance = run(cwd.path(), &["getbalance", &recipient], dir);
+    assert!(
+        stdout(&balance).trim().ends_with(": 1500"),
+        "{}",
+        stdout(&balance)
+    );
+}
+
+#[test]
+fn test_send_pays_a_payment_request_uri() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let new_address = || {
+        stdout(&run(cwd.path(), &["createwallet"], dir))
+            .trim()
+            .strip_prefix("Your new address: ")
+            .unwrap()
+            .to_string()
+    };
+    let sender = new_address();
+    let recipient = new_address();
+    run(cwd.path(), &["createblockchain", &sender], dir);
+
+    let requested = run(
+        cwd.path(),
+        &[
+            "paymentrequest",
+            &recipient,
+            "--amount",
+            "0.00001",
+            "--message",
+            "tea",
+        ],
+        dir,
+    );
+    let uri = stdout(&requested).trim().to_string();
+    assert!(uri.contains(&recipient), "{uri}");
+
+    // An amount of 0 takes the one the request asks for
+    run(
+        cwd.path(),
+        &["send", &sender, &uri, "0", "--uri", "--mine"],
+        dir,
+    );
+    let balance = run(cwd.path(), &["getbalance", &recipient], dir);
+    assert!(
+        stdout(&balance).trim().ends_with(": 1000"),
+        "{}",
+        stdout(&balance)
+    );
+
+    // A different amount than the request's is refused rather than sent
+    let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args(["send", &sender, &uri, "2000", "--uri", "--mine"])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+}
+
+#[test]
+fn test_send_all_sweeps_the_balance() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let new_address = || {
+        stdout(&run(cwd.path(), &["createwallet"], dir))
+            .trim()
+            .strip_prefix("Your new address: ")
+            .unwrap()
+            .to_string()
+    };
+    let sender = new_address();
+    let recipient = new_address();
+    run(cwd.path(), &["createblockchain", &sender], dir);
+
+    let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args(["send", &sender, &recipient, "1000", "--all"])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+
+    let swept = stdout(&run(
+        cwd.path(),
+        &["send", &sender, &recipient, "--all", "--fee", "7", "--mine"],
+        dir,
+    ));
+    let sent = swept
+        .lines()
+        .find_map(|line| line.strip_prefix("Swept "))
+        .and_then(|line| line.split_once(" ("))
+        .and_then(|(_, line)| line.split(' ').next())
+        .unwrap_or_else(|| panic!("{swept}"))
+        .to_string();
+    assert!(swept.contains("(7 satoshis) fee"), "{swept}");
+    let balance = stdout(&run(cwd.path(), &["getbalance", &recipient], dir));
+    assert!(balance.trim().ends_with(&format!(": {sent}")), "{balance}");
+}
+
+#[test]
+fn test_createblockchain_from_a_genesis_file() {
+    let cwd = tempdir().unwrap();
+    let first = tempdir().unwrap();
+    let second = tempdir().unwrap();
+    let address = stdout(&run(cwd.path(), &["createwallet"], Some(first.path())))
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    let genesis_file = cwd.path().join("genesis.toml");
+    let genesis_file = genesis_file.to_str().unwrap();
+    let allocation = format!("{address}:2500000");
+
+    let written = stdout(&run(
+        cwd.path(),
+        &[
+            "creategenesis",
+            "--out",
+            genesis_file,
+            "--message",
+            "test network",
+            "--allocate",
+            &allocation,
+        ],
+        None,
+    ));
+    let hash = written
+        .lines()
+        .find_map(|line| line.strip_prefix("Wrote genesis block "))
+        .and_then(|line| line.split(' ').next())
+        .unwrap_or_else(|| panic!("{written}"))
+        .to_string();
+
+    // Two nodes built from the file start from the same block, paying the premine
+    for datadir in [first.path(), second.path()] {
+        run(
+            cwd.path(),
+            &["createblockchain", "--genesis-file", genesis_file],
+            Some(datadir),
+        );
+        let chain = stdout(&run(cwd.path(), &["printchain", "--json"], Some(datadir)));
+        let blocks: serde_json::Value = serde_json::from_str(&chain).unwrap();
+        assert_eq!(blocks[0]["hash"], hash.as_str());
+        let balance = stdout(&run(cwd.path(), &["getbalance", &address], Some(datadir)));
+        assert!(balance.trim().ends_with(": 2500000"), "{balance}");
+    }
+}
+
+#[test]
+fn test_dumpconfig_shows_settings_from_the_config_file() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+
+    let defaults = stdout(&run(cwd.path(), &["dumpconfig"], dir));
+    assert!(defaults.contains("No config file"), "{defaults}");
+    assert!(defaults.contains("mode = \"fixed\""), "{defaults}");
+
+    fs::write(
+        datadir.path().join("architect.toml"),
+        "[mining]\nthreads = 3\n\n[fees]\nmode = \"dynamic\"\ndynamic.base_fee = 4\n",
+    )
+    .unwrap();
+    let dumped = stdout(&run(cwd.path(), &["dumpconfig"], dir));
+    assert!(dumped.contains("threads = 3"), "{dumped}");
+    assert!(dumped.contains("mode = \"dynamic\""), "{dumped}");
+    assert!(dumped.contains("base_fee = 4"), "{dumped}");
+
+    // A broken file stops every command, saying where it went wrong
+    let broken = cwd.path().join("broken.toml");
+    fs::write(&broken, "[mining]\naddress = \"not an address\"\n").unwrap();
+    let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .args(["--config", broken.to_str().unwrap(), "dumpconfig"])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+    let stderr = String::from_utf8_lossy(&refused.stderr);
+    assert!(stderr.contains("line 2: mining.address"), "{stderr}");
+}
+
+#[test]
+fn test_raw_transaction_is_created_decoded_signed_and_mined() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let new_address = || {
+        stdout(&run(cwd.path(), &["createwallet"], dir))
+            .trim()
+            .strip_prefix("Your new address: ")
+            .unwrap()
+            .to_string()
+    };
+    let sender = new_address();
+    let recipient = new_address();
+    run(cwd.path(), &["createblockchain", &sender], dir);
+
+    let chain: serde_json::Value =
+        serde_json::from_str(&stdout(&run(cwd.path(), &["printchain", "--json"], dir))).unwrap();
+    let genesis = &chain[0]["transactions"][0];
+    let txid = genesis["txid"].as_str().unwrap();
+    let value = genesis["outputs"][0]["value"].as_u64().unwrap();
+    let change = value - 2_000 - 100;
+
+    let input = format!("{txid}:0");
+    let payment = format!("{recipient}:2000");
+    let change_output = format!("{sender}:{change}");
+    let raw = stdout(&run(
+        cwd.path(),
+        &[
+            "createrawtransaction",
+            "--input",
+            &input,
+            "--output",
+            &payment,
+            "--output",
+            &change_output,
+        ],
+        dir,
+    ));
+    let raw = raw.trim();
+
+    let decoded: serde_json::Value = serde_json::from_str(&stdout(&run(
+        cwd.path(),
+        &["decoderawtransaction", raw, "--json"],
+        dir,
+    )))
+    .unwrap();
+    assert_eq!(decoded["inputs"][0]["txid"], txid);
+    assert_eq!(decoded["inputs"][0]["signed"], false);
+    assert_eq!(decoded["outputs"][0]["to"], recipient.as_str());
+    assert_eq!(decoded["outputs"][0]["value"], 2_000);
+    assert_eq!(decoded["structure_error"], serde_json::Value::Null);
+
+    let signed = stdout(&run(
+        cwd.path(),
+        &["signrawtransaction", raw, "--wallet-address", &sender],
+        dir,
+    ));
+    let signed = signed.trim();
+    let decoded = stdout(&run(cwd.path(), &["decoderawtransaction", signed], dir));
+    assert!(
+        decoded.contains(&format!("from = {sender}, signed")),
+        "{decoded}"
+    );
+    assert!(decoded.contains("Fee: 100 satoshis"), "{decoded}");
+
+    run(
+        cwd.path(),
+        &["sendrawtransaction", signed, "--mine", &sender],
+        dir,
+    );
+    let balance = stdout(&run(cwd.path(), &["getbalance", &recipient], dir));
+    assert!(balance.trim().ends_with(": 2000"), "{balance}");
+
+    // The change kept its index after the payment beside it paid out, and the listing
+    // feeds straight back into createrawtransaction
+    let unspent: serde_json::Value = serde_json::from_str(&stdout(&run(
+        cwd.path(),
+        &["listunspent", &sender, "--json"],
+        dir,
+    )))
+    .unwrap();
+    let mined: serde_json::Value = serde_json::from_str(&stdout(&run(
+        cwd.path(),
+        &["decoderawtransaction", signed, "--json"],
+        dir,
+    )))
+    .unwrap();
+    let change_output = unspent
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|output| output["txid_hex"] == mined["txid"])
+        .unwrap();
+    assert_eq!(change_output["vout"], 1);
+    assert_eq!(change_output["value"], change);
+    assert_eq!(change_output["is_coinbase"], false);
+    let outpoint = change_output["outpoint"].as_str().unwrap();
+    let payment = format!("{recipient}:{}", change - 100);
+    run(
+        cwd.path(),
+        &[
+            "createrawtransaction",
+            "--input",
+            outpoint,
+            "--output",
+            &payment,
+        ],
+        dir,
+    );
+    let listed = stdout(&run(
+        cwd.path(),
+        &["listunspent", &recipient, "--min-value", "2001"],
+        dir,
+    ));
+    assert!(listed.contains("No unspent outputs"), "{listed}");
+
+    let garbage = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args(["decoderawtransaction", &raw[..raw.len() / 2]])
+        .output()
+        .unwrap();
+    assert!(!garbage.status.success());
+}
+
+#[test]
+fn test_wallet_policy_holds_back_large_sends() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let new_address = || {
+        stdout(&run(cwd.path(), &["createwallet"], dir))
+            .trim()
+            .strip_prefix("Your new address: ")
+            .unwrap()
+            .to_string()
+    };
+    let sender = new_address();
+    let recipient = new_address();
+    run(cwd.path(), &["createblockchain", &sender], dir);
+    let policy = stdout(&run(
+        cwd.path(),
+        &[
+            "setwalletpolicy",
+            &sender,
+            "--confirm-above",
+            "1000",
+            "--max-per-tx",
+            "5000",
+        ],
+        dir,
+    ));
+    assert!(policy.contains("Confirm above: 1000 satoshis"), "{policy}");
+    let try_send = |extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+            .current_dir(cwd.path())
+            .env("ARCHITECT_DATADIR", datadir.path())
+            .args(["send", &sender, &recipient])
+            .args(extra)
+            .output()
+            .unwrap()
+    };
+
+    // Over the hard limit nothing gets through, --yes or not
+    let refused = try_send(&["6000", "--mine", "--yes"]);
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("limit of 5000"));
+    // Over the threshold a send without a terminal to confirm on fails instead of waiting
+    let unconfirmed = try_send(&["2000", "--mine"]);
+    assert!(!unconfirmed.status.success());
+    assert!(String::from_utf8_lossy(&unconfirmed.stderr).contains("--yes"));
+
+    run(
+        cwd.path(),
+        &["send", &sender, &recipient, "500", "--mine"],
+        dir,
+    );
+    run(
+        cwd.path(),
+        &["send", &sender, &recipient, "2000", "--mine", "--yes"],
+        dir,
+    );
+    let balance = stdout(&run(cwd.path(), &["getbalance", &recipient], dir));
+    assert!(balance.trim().ends_with(": 2500"), "{balance}");
+}
+
+#[test]
+fn test_nodes_keep_separate_wallets_unless_one_is_named() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let elsewhere = tempdir().unwrap();
+    let wallet_command = |node_id: &str, args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+            .current_dir(cwd.path())
+            .env("ARCHITECT_DATADIR", datadir.path())
+            .env("NODE_ID", node_id)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{args:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        stdout(&output)
+    };
+    let node_wallet = |node_id: &str| {
+        datadir
+            .path()
+            .join("data")
+            .join(format!("node_{node_id}"))
+            .join("wallet.dat")
+    };
+
+    let miner = wallet_command("2001", &["createwallet"]);
+    let user = wallet_command("2002", &["createwallet"]);
+    assert_ne!(miner, user);
+    assert!(node_wallet("2001").is_file());
+    assert!(node_wallet("2002").is_file());
+    let miner_address = miner.trim().strip_prefix("Your new address: ").unwrap();
+    let user_address = user.trim().strip_prefix("Your new address: ").unwrap();
+    let listed = wallet_command("2001", &["listaddresses"]);
+    assert!(listed.contains(miner_address), "{listed}");
+    assert!(!listed.contains(user_address), "{listed}");
+
+    // --wallet wins over the node's file and the data directory's
+    let named = elsewhere.path().join("named.dat");
+    let named_arg = named.to_str().unwrap();
+    let third = wallet_command("2001", &["--wallet", named_arg, "createwallet"]);
+    let third_address = third.trim().strip_prefix("Your new address: ").unwrap();
+    assert!(named.is_file());
+    let listed = wallet_command("2001", &["listaddresses", "--wallet", named_arg]);
+    assert!(listed.contains(third_address), "{listed}");
+    assert!(!listed.contains(miner_address), "{listed}");
+    assert!(!wallet_command("2001", &["listaddresses"]).contains(third_address));
+
+    assert!(!datadir.path().join("wallet.dat").exists());
+    assert!(is_empty_dir(cwd.path()));
+
+    // The data directory's file from before wallets were per node is still found by a
+    // node without one, but not by those that have their own
+    let shared = run(cwd.path(), &["createwallet"], Some(datadir.path()));
+    let shared_address = stdout(&shared)
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    assert!(datadir.path().join("wallet.dat").is_file());
+    assert!(wallet_command("2003", &["listaddresses"]).contains(&shared_address));
+    assert!(!node_wallet("2003").exists());
+    assert!(!wallet_command("2001", &["listaddresses"]).contains(&shared_address));
+}
+
+#[test]
+fn test_watch_only_address_pays_through_a_cold_signer() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let cold = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let cold_wallet = cold.path().join("cold.dat");
+    let cold_wallet = cold_wallet.to_str().unwrap();
+    let address_of = |output: Output| {
+        stdout(&output)
+            .trim()
+            .strip_prefix("Your new address: ")
+            .unwrap()
+            .to_string()
+    };
+    let try_run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+            .current_dir(cwd.path())
+            .env("ARCHITECT_DATADIR", datadir.path())
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    // The key only ever lives in the cold wallet file; the node's own file watches it
+    let watched = address_of(run(
+        cwd.path(),
+        &["createwallet", "--wallet", cold_wallet],
+        dir,
+    ));
+    let recipient = address_of(run(cwd.path(), &["createwallet"], dir));
+    run(
+        cwd.path(),
+        &["importaddress", &watched, "--label", "cold"],
+        dir,
+    );
+    run(cwd.path(), &["createblockchain", &watched], dir);
+
+    let listed = stdout(&run(cwd.path(), &["listaddresses", "--with-balance"], dir));
+    let line = listed.lines().find(|line| line.contains(&watched)).unwrap();
+    assert!(
+        line.starts_with("cold") && line.ends_with("watch-only"),
+        "{listed}"
+    );
+    let funded: u64 = stdout(&run(cwd.path(), &["getbalance", &watched], dir))
+        .trim()
+        .rsplit(' ')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(funded > 1_000);
+    let chain: serde_json::Value =
+        serde_json::from_str(&stdout(&run(cwd.path(), &["printchain", "--json"], dir))).unwrap();
+    let funding_txid = chain[0]["transactions"][0]["txid"].as_str().unwrap();
+    let funding = stdout(&run(cwd.path(), &["gettransaction", funding_txid], dir));
+    assert!(funding.contains("Confirmations: 1"), "{funding}");
+
+    // Without the key all the node can do is print the payment for signing
+    let mined = try_run(&["send", &watched, &recipient, "1000", "--mine"]);
+    assert!(!mined.status.success());
+    assert!(String::from_utf8_lossy(&mined.stderr).contains("watch-only"));
+    let unsigned = stdout(&run(
+        cwd.path(),
+        &["send", &watched, &recipient, "1000"],
+        dir,
+    ));
+    let unsigned = unsigned.trim();
+    let broadcast = try_run(&["sendrawtransaction", unsigned]);
+    assert!(!broadcast.status.success());
+    assert!(String::from_utf8_lossy(&broadcast.stderr).contains("unsigned"));
+    let refused = try_run(&["signrawtransaction", unsigned, "--wallet-address", &watched]);
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("no key to sign"));
+
+    let signed = stdout(&run(
+        cwd.path(),
+        &[
+            "signrawtransaction",
+            unsigned,
+            "--wallet-address",
+            &watched,
+            "--wallet",
+            cold_wallet,
+        ],
+        dir,
+    ));
+    run(
+        cwd.path(),
+        &["sendrawtransaction", signed.trim(), "--mine", &recipient],
+        dir,
+    );
+    let balance = stdout(&run(cwd.path(), &["getbalance", &watched], dir));
+    let left: u64 = balance.trim().rsplit(' ').next().unwrap().parse().unwrap();
+    assert!(left < funded - 1_000, "{balance}");
+    assert!(left > 0, "{balance}");
+
+    // Importing again rescans, after which the history comes from the address index
+    let rescanned = stdout(&run(cwd.path(), &["importaddress", &watched], dir));
+    assert!(rescanned.contains("Rescanned blocks 0 to 1"), "{rescanned}");
+    let history = stdout(&run(cwd.path(), &["history", &watched], dir));
+    let lines: Vec<&str> = history.lines().collect();
+    assert_eq!(lines.len(), 3, "{history}");
+    assert!(lines[0].starts_with(&format!("0 {funding_txid} received")));
+    assert!(
+        lines[1].starts_with("1 ") && lines[1].contains(" sent "),
+        "{history}"
+    );
+    assert!(lines[2].contains(" received "), "{history}");
+}
+
+#[test]
+fn test_output_json_prints_one_object_per_command() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    // generate only mines on regtest chains
+    let json = |args: &[&str]| {
+        let args = [&["--output", "json", "--network", "regtest"], args].concat();
+        run(cwd.path(), &args, dir)
+    };
+
+    let sender: CreateWalletResponse = json_stdout(&json(&["createwallet", "--label", "main"]));
+    assert_eq!(sender.label.as_deref(), Some("main"));
+    let recipient: CreateWalletResponse = json_stdout(&json(&["createwallet"]));
+    // The text form of the same command is unchanged
+    let text = stdout(&run(cwd.path(), &["createwallet"], dir));
+    assert!(text.starts_with("Your new address: "), "{text}");
+
+    json(&["createblockchain", &sender.address]);
+    let generated: GenerateResponse =
+        json_stdout(&json(&["generate", "2", &sender.address, "--dev"]));
+    assert_eq!(generated.blocks.len(), 2);
+    assert_eq!(generated.height, 2);
+
+    let sent: SendResponse = json_stdout(&json(&[
+        "send",
+        &sender.address,
+        &recipient.address,
+        "1000",
+        "--mine",
+    ]));
+    assert_eq!(sent.amount, 1000);
+    assert!(!sent.broadcast);
+    assert_eq!(sent.txid.len(), 64);
+    assert!(sent.mined_block.is_some());
+
+    let balance: BalanceResponse = json_stdout(&json(&["getbalance", &recipient.address]));
+    assert_eq!(balance.address, recipient.address);
+    assert_eq!(balance.confirmed, 1000);
+    assert_eq!(balance.pending_in, None);
+    let text = stdout(&run(
+        cwd.path(),
+        &["--network", "regtest", "getbalance", &recipient.address],
+        dir,
+    ));
+    assert_eq!(
+        text.trim(),
+        format!("Balance of {}: 1000", recipient.address)
+    );
+
+    // A failure is an error object too, with the exit code still saying so
+    let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args([
+            "--output",
+            "json",
+            "--network",
+            "regtest",
+            "send",
+            &recipient.address,
+            &sender.address,
+        ])
+        .args(["1000000", "--mine"])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+    let error: ErrorResponse = json_stdout(&refused);
+    assert_eq!(error.error.kind, "InsufficientFunds");
+    assert!(error.error.message.contains("--priority low"), "{error:?}");
+}
+
+#[test]
+fn test_database_held_by_a_node_is_reported_as_locked() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let address = stdout(&run(cwd.path(), &["createwallet"], dir))
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    run(cwd.path(), &["createblockchain", &address], dir);
+
+    // Standing in for a running node, which keeps the database open as long as it runs
+    let _node = Blockchain::new_blockchain_with_path(datadir.path().join("data").to_str().unwrap())
+        .unwrap_or_else(|e| panic!("{e}"));
+    let locked = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args(["--output", "json", "getbalance", &address])
+        .output()
+        .unwrap();
+    assert!(!locked.status.success());
+    let error: ErrorResponse = json_stdout(&locked);
+    assert_eq!(error.error.kind, "DatabaseLocked");
+    assert!(
+        error
+            .error
+            .message
+            .contains("use --remote or stop the node"),
+        "{error:?}"
+    );
+}
+
+#[test]
+fn test_wallet_keys_stay_with_the_chain_they_were_created_for() {
+    let cwd = tempdir().unwrap();
+    let ours = tempdir().unwrap();
+    let theirs = tempdir().unwrap();
+    let wallet = cwd.path().join("shared.dat");
+    let wallet = wallet.to_str().unwrap();
+    let new_address = |datadir: &Path| {
+        stdout(&run(
+            cwd.path(),
+            &["--network", "regtest", "--wallet", wallet, "createwallet"],
+            Some(datadir),
+        ))
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string()
+    };
+
+    // Made before any chain exists, so it pairs with either
+    let miner = new_address(ours.path());
+    run(
+        cwd.path(),
+        &["--network", "regtest", "createblockchain", &miner],
+        Some(ours.path()),
+    );
+    run(
+        cwd.path(),
+        &[
+            "--network",
+            "regtest",
+            "--wallet",
+            wallet,
+            "generate",
+            "8",
+            &miner,
+            "--dev",
+        ],
+        Some(ours.path()),
+    );
+    let saver = new_address(ours.path());
+    run(
+        cwd.path(),
+        &["--network", "regtest", "createblockchain", &miner],
+        Some(theirs.path()),
+    );
+
+    run(
+        cwd.path(),
+        &[
+            "--network",
+            "regtest",
+            "--wallet",
+            wallet,
+            "getbalance",
+            "--whole-wallet",
+        ],
+        Some(ours.path()),
+    );
+    let refused = |args: &[&str]| {
+        let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+            .current_dir(cwd.path())
+            .env("ARCHITECT_DATADIR", theirs.path())
+            .args([
+                "--output",
+                "json",
+                "--network",
+                "regtest",
+                "--wallet",
+                wallet,
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(!refused.status.success());
+        let error: ErrorResponse = json_stdout(&refused);
+        assert_eq!(error.error.kind, "WalletNetworkMismatch");
+        assert!(error.error.message.contains(&saver), "{error:?}");
+    };
+    refused(&["getbalance", "--whole-wallet"]);
+    // Signing with the key is refused as well, not only reading balances
+    refused(&["consolidate", &saver]);
+    run(
+        cwd.path(),
+        &[
+            "--network",
+            "regtest",
+            "--wallet",
+            wallet,
+            "getbalance",
+            "--whole-wallet",
+            "--allow-network-mismatch",
+        ],
+        Some(theirs.path()),
+    );
+
+    // The new key can't have been paid more than a finality depth below the tip it saw
+    let rescan = |addresses: &[&str]| {
+        let mut args = vec![
+            "--output",
+            "json",
+            "--network",
+            "regtest",
+            "--wallet",
+            wallet,
+            "rescan",
+        ];
+        args.extend(addresses);
+        let report: serde_json::Value = json_stdout(&run(cwd.path(), &args, Some(ours.path())));
+        report["from_height"].as_u64().unwrap()
+    };
+    assert_eq!(rescan(&[&saver]), 2);
+    assert_eq!(rescan(&[]), 0);
+}
+
+#[test]
+fn test_datadir_only_opens_on_its_network() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let address = stdout(&run(cwd.path(), &["createwallet"], Some(datadir.path())))
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    run(
+        cwd.path(),
+        &["--network", "regtest", "createblockchain", &address],
+        Some(datadir.path()),
+    );
+    run(
+        cwd.path(),
+        &["--network", "regtest", "getbalance", &address],
+        Some(datadir.path()),
+    );
+
+    // Leaving the flag out means mainnet, which this directory isn't either
+    for network in [&["--network", "testnet"][..], &[]] {
+        let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+            .current_dir(cwd.path())
+            .env("ARCHITECT_DATADIR", datadir.path())
+            .args(["--output", "json"])
+            .args(network)
+            .args(["getbalance", &address])
+            .output()
+            .unwrap();
+        assert!(!refused.status.success());
+        let error: ErrorResponse = json_stdout(&refused);
+        assert_eq!(error.error.kind, "WrongNetwork");
+        assert!(
+            error.error.message.contains("--network regtest"),
+            "{error:?}"
+        );
+    }
+}
+
+#[test]
+fn test_drafts_are_kept_until_sent_and_go_stale_once_mined() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let sender = stdout(&run(cwd.path(), &["createwallet"], dir))
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    run(cwd.path(), &["createblockchain", &sender], dir);
+    let chain: serde_json::Value =
+        serde_json::from_str(&stdout(&run(cwd.path(), &["printchain", "--json"], dir))).unwrap();
+    let genesis = &chain[0]["transactions"][0];
+    let input = format!("{}:0", genesis["txid"].as_str().unwrap());
+    let payment = format!(
+        "{sender}:{}",
+        genesis["outputs"][0]["value"].as_u64().unwrap() - 100
+    );
+
+    run(
+        cwd.path(),
+        &[
+            "createrawtransaction",
+            "--input",
+            &input,
+            "--output",
+            &payment,
+            "--save-draft",
+            "rent",
+        ],
+        dir,
+    );
+    let list = |dir| -> serde_json::Value {
+        json_stdout(&run(
+            cwd.path(),
+            &["--output", "json", "draft", "list"],
+            dir,
+        ))
+    };
+    let drafts = list(dir);
+    assert_eq!(drafts["drafts"][0]["name"], "rent");
+    assert_eq!(drafts["drafts"][0]["state"], "Unsigned");
+    assert_eq!(drafts["drafts"][0]["spent_inputs"], serde_json::json!([]));
+
+    // An unsigned draft can't be sent, but it can be picked up again to sign
+    let refused = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args(["send", "--from-draft", "rent"])
+        .output()
+        .unwrap();
+    assert!(!refused.status.success());
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("unsigned"));
+    let shown: serde_json::Value = json_stdout(&run(
+        cwd.path(),
+        &["--output", "json", "draft", "show", "rent"],
+        dir,
+    ));
+    let signed = stdout(&run(
+        cwd.path(),
+        &[
+            "signrawtransaction",
+            shown["transaction"].as_str().unwrap(),
+            "--wallet-address",
+            &sender,
+        ],
+        dir,
+    ));
+    let signed = signed.trim();
+    let saved = stdout(&run(
+        cwd.path(),
+        &["draft", "save", "rent-signed", signed],
+        dir,
+    ));
+    assert!(saved.contains("signed, not sent"), "{saved}");
+    let taken = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .current_dir(cwd.path())
+        .env("ARCHITECT_DATADIR", datadir.path())
+        .args(["draft", "save", "rent", signed])
+        .output()
+        .unwrap();
+    assert!(!taken.status.success());
+
+    // Once the chain spends the coin, both drafts are stale
+    run(
+        cwd.path(),
+        &["sendrawtransaction", signed, "--mine", &sender],
+        dir,
+    );
+    let drafts = list(dir);
+    for draft in drafts["drafts"].as_array().unwrap() {
+        assert_eq!(draft["spent_inputs"], serde_json::json!([input]), "{draft}");
+    }
+    let listed = stdout(&run(cwd.path(), &["draft", "list"], dir));
+    assert!(listed.contains("rent: unsigned"), "{listed}");
+    assert!(listed.contains("STALE"), "{listed}");
+
+    run(cwd.path(), &["draft", "delete", "rent"], dir);
+    run(cwd.path(), &["draft", "delete", "rent-signed"], dir);
+    let listed = stdout(&run(cwd.path(), &["draft", "list"], dir));
+    assert_eq!(listed.trim(), "No drafts");
+}
+
+#[test]
+fn test_send_reports_the_coin_selection_it_used() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let new_address = || {
+        stdout(&run(cwd.path(), &["createwallet"], dir))
+            .trim()
+            .strip_prefix("Your new address: ")
+            .unwrap()
+            .to_string()
+    };
+    let sender = new_address();
+    let recipient = new_address();
+    run(cwd.path(), &["createblockchain", &sender], dir);
+    let send = |extra: &[&str]| -> SendResponse {
+        let mut args = vec![
+            "--output", "json", "send", &sender, &recipient, "1000", "--mine",
+        ];
+        args.extend_from_slice(extra);
+        json_stdout(&run(cwd.path(), &args, dir))
+    };
+
+    // Chainstate order until the wallet or the flag asks for something else
+    let sent = send(&[]);
+    assert_eq!((sent.coin_selection, sent.input_count), (None, 1));
+
+    run(cwd.path(), &["setcoinselection", "oldest"], dir);
+    let sent = send(&[]);
+    assert_eq!(sent.coin_selection, Some(SelectionStrategy::OldestFirst));
+    assert_eq!(sent.input_count, 1);
+    let sent = send(&["--coin-selection", "bnb"]);
+    assert_eq!(sent.coin_selection, Some(SelectionStrategy::BranchAndBound));
+
+    run(cwd.path(), &["setcoinselection", "--clear"], dir);
+    assert_eq!(send(&[]).coin_selection, None);
+}
+
+#[test]
+fn test_abandontransaction_edits_the_pool_a_stopped_node_saved() {
+    let cwd = tempdir().unwrap();
+    let datadir = tempdir().unwrap();
+    let dir = Some(datadir.path());
+    let sender = stdout(&run(cwd.path(), &["createwallet"], dir))
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string();
+    run(cwd.path(), &["createblockchain", &sender], dir);
+    let chain: serde_json::Value =
+        serde_json::from_str(&stdout(&run(cwd.path(), &["printchain", "--json"], dir))).unwrap();
+    let genesis = &chain[0]["transactions"][0];
+    let input = format!("{}:0", genesis["txid"].as_str().unwrap());
+    let payment = format!(
+        "{sender}:{}",
+        genesis["outputs"][0]["value"].as_u64().unwrap() - 1_000
+    );
+    let raw = stdout(&run(
+        cwd.path(),
+        &[
+            "createrawtransaction",
+            "--input",
+            &input,
+            "--output",
+            &payment,
+        ],
+        dir,
+    ));
+    let signed = stdout(&run(
+        cwd.path(),
+        &[
+            "signrawtransaction",
+            raw.trim(),
+            "--wallet-address",
+            &sender,
+        ],
+        dir,
+    ));
+    let payment = Transaction::deserialize(&hex::decode(signed.trim()).unwrap()).unwrap();
+    let txid = hex::encode(payment.get_id());
+
+    // What a node stopped with the payment pending leaves behind
+    let db_path = datadir.path().join("data");
+    {
+        let blockchain = Blockchain::new_blockchain_with_path(db_path.to_str().unwrap()).unwrap();
+        let pool = MemoryPool::new();
+        pool.add_safe(payment).unwrap();
+        pool.save_to(blockchain.get_db()).unwrap();
+    }
+
+    let abandoned = stdout(&run(
+        cwd.path(),
+        &["abandontransaction", &txid, "--local"],
+        dir,
+    ));
+    assert!(abandoned.contains(&txid), "{abandoned}");
+
+    let blockchain = Blockchain::new_blockchain_with_path(db_path.to_str().unwrap()).unwrap();
+    let restarted = MemoryPool::new();
+    restarted
+        .load_from(&blockchain, &RelayFeePolicy::default())
+        .unwrap();
+    assert!(restarted.is_empty());
+    assert!(restarted.is_abandoned(&txid));
+}
@@ -0,0 +1,218 @@
+//! FFI tests
+//!
+//! These drive the `ffi` functions through extern declarations as a C caller sees them,
+//! freeing every string they hand out. The chain is created by the `architect-chain`
+//! binary, as a notebook would find a data directory the command line set up.
+
+use architect_chain::core::Transaction;
+use architect_chain::ffi::{
+    AC_ERR_BUFFER_TOO_SMALL, AC_ERR_FAILED, AC_ERR_INVALID_ARGUMENT, AC_OK, AC_PRIORITY_NORMAL,
+};
+use architect_chain::wallet::{SpendPolicy, Wallets};
+use data_encoding::HEXLOWER;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+use tempfile::tempdir;
+
+extern "C" {
+    fn ac_wallet_create(path: *const c_char) -> *mut c_char;
+    fn ac_validate_address(address: *const c_char) -> bool;
+    fn ac_get_balance(datadir: *const c_char, address: *const c_char) -> u64;
+    fn ac_estimate_fee(priority: c_int) -> u64;
+    fn ac_create_and_sign_tx(
+        datadir: *const c_char,
+        from: *const c_char,
+        to: *const c_char,
+        amount: u64,
+        priority: c_int,
+        out_hex_buf: *mut c_char,
+        buf_len: usize,
+    ) -> c_int;
+    fn ac_datadir_close(datadir: *const c_char) -> c_int;
+    fn ac_last_error_message() -> *mut c_char;
+    fn ac_string_free(s: *mut c_char);
+}
+
+// Copies a string the library returned and frees it, None for NULL
+fn take_string(s: *mut c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    // SAFETY: s came from the library and is freed exactly once, after the copy
+    unsafe {
+        let copy = CStr::from_ptr(s).to_str().unwrap().to_string();
+        ac_string_free(s);
+        Some(copy)
+    }
+}
+
+fn last_error() -> Option<String> {
+    take_string(unsafe { ac_last_error_message() })
+}
+
+fn c_string(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+fn create_wallet(wallet_file: &Path) -> String {
+    let path = c_string(wallet_file.to_str().unwrap());
+    take_string(unsafe { ac_wallet_create(path.as_ptr()) }).unwrap()
+}
+
+fn create_and_sign(
+    datadir: &CString,
+    from: &str,
+    to: &str,
+    amount: u64,
+    buffer: &mut [u8],
+) -> c_int {
+    let (from, to) = (c_string(from), c_string(to));
+    unsafe {
+        ac_create_and_sign_tx(
+            datadir.as_ptr(),
+            from.as_ptr(),
+            to.as_ptr(),
+            amount,
+            AC_PRIORITY_NORMAL,
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+        )
+    }
+}
+
+#[test]
+fn test_wallet_balance_and_payment_through_the_c_interface() {
+    let datadir = tempdir().unwrap();
+    let wallet_file = datadir.path().join("wallet.dat");
+    let from = create_wallet(&wallet_file);
+    let to = create_wallet(&wallet_file);
+    assert_ne!(from, to);
+    let created = Command::new(env!("CARGO_BIN_EXE_architect-chain"))
+        .args(["--datadir", datadir.path().to_str().unwrap()])
+        .args(["createblockchain", &from])
+        .output()
+        .unwrap();
+    assert!(
+        created.status.success(),
+        "{}",
+        String::from_utf8_lossy(&created.stderr)
+    );
+    let c_datadir = c_string(datadir.path().to_str().unwrap());
+
+    let c_from = c_string(&from);
+    assert!(unsafe { ac_validate_address(c_from.as_ptr()) });
+    let balance = unsafe { ac_get_balance(c_datadir.as_ptr(), c_from.as_ptr()) };
+    assert!(balance > 0);
+    assert_eq!(last_error(), None);
+    assert!(unsafe { ac_estimate_fee(AC_PRIORITY_NORMAL) } > 0);
+
+    // Too small a buffer is left alone, and the error says what would do
+    let mut small = [0xffu8; 16];
+    assert_eq!(
+        create_and_sign(&c_datadir, &from, &to, 1_000, &mut small),
+        AC_ERR_BUFFER_TOO_SMALL
+    );
+    assert_eq!(small, [0xff; 16]);
+    let message = last_error().unwrap();
+    let needed: usize = message
+        .trim_start_matches("The output buffer needs ")
+        .trim_end_matches(" bytes")
+        .parse()
+        .unwrap();
+
+    let mut buffer = vec![0xffu8; needed];
+    assert_eq!(
+        create_and_sign(&c_datadir, &from, &to, 1_000, &mut buffer),
+        AC_OK
+    );
+    let hex = CStr::from_bytes_with_nul(&buffer)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let tx = Transaction::deserialize(&HEXLOWER.decode(hex.as_bytes()).unwrap()).unwrap();
+    assert_eq!(tx.get_vout()[0].get_value().to_satoshis(), 1_000);
+    assert_eq!(tx.get_vout()[0].get_address(), to);
+    assert!(tx.get_vin().iter().all(|input| input.is_signed()));
+
+    assert_eq!(
+        create_and_sign(&c_datadir, &to, &from, 1_000, &mut buffer),
+        AC_ERR_FAILED
+    );
+    assert!(last_error().unwrap().contains("Insufficient"));
+
+    // The sender's spend policy holds here too; a payment it wants confirmed is refused
+    let policy = SpendPolicy {
+        max_send_without_confirm: Some(1_000),
+        spend_limit_per_tx: Some(5_000),
+    };
+    Wallets::open(&wallet_file)
+        .unwrap()
+        .set_policy(&from, policy)
+        .unwrap();
+    assert_eq!(
+        create_and_sign(&c_datadir, &from, &to, 1_000, &mut buffer),
+        AC_OK
+    );
+    assert_eq!(
+        create_and_sign(&c_datadir, &from, &to, 1_001, &mut buffer),
+        AC_ERR_FAILED
+    );
+    assert!(last_error().unwrap().contains("has to be confirmed"));
+    assert_eq!(
+        create_and_sign(&c_datadir, &from, &to, 5_001, &mut buffer),
+        AC_ERR_FAILED
+    );
+    assert!(last_error()
+        .unwrap()
+        .contains("exceeds this address's limit"));
+    assert_eq!(unsafe { ac_datadir_close(c_datadir.as_ptr()) }, AC_OK);
+}
+
+#[test]
+fn test_failures_return_codes_and_messages() {
+    let datadir = tempdir().unwrap();
+    let c_datadir = c_string(datadir.path().to_str().unwrap());
+    let address = c_string("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+
+    // No chain in the directory yet
+    assert_eq!(
+        unsafe { ac_get_balance(c_datadir.as_ptr(), address.as_ptr()) },
+        0
+    );
+    assert!(last_error().unwrap().contains("No existing blockchain"));
+
+    assert!(unsafe { ac_wallet_create(ptr::null()) }.is_null());
+    assert_eq!(last_error().as_deref(), Some("path is NULL"));
+    let invalid_utf8 = CString::new(vec![0xc3, 0x28]).unwrap();
+    assert!(!unsafe { ac_validate_address(invalid_utf8.as_ptr()) });
+    assert_eq!(last_error().as_deref(), Some("address is not valid UTF-8"));
+
+    let mut buffer = [0u8; 1024];
+    let status = unsafe {
+        ac_create_and_sign_tx(
+            c_datadir.as_ptr(),
+            address.as_ptr(),
+            address.as_ptr(),
+            1_000,
+            AC_PRIORITY_NORMAL,
+            ptr::null_mut(),
+            buffer.len(),
+        )
+    };
+    assert_eq!(status, AC_ERR_INVALID_ARGUMENT);
+    assert_eq!(
+        create_and_sign(
+            &c_datadir,
+            "nobody",
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            1,
+            &mut buffer
+        ),
+        AC_ERR_FAILED
+    );
+    assert!(last_error().unwrap().contains("Wallet not found"));
+    // Freeing NULL is allowed
+    unsafe { ac_string_free(ptr::null_mut()) };
+}